@@ -27,6 +27,11 @@ handlebars_helper!(functionIsToString: |function: Function| {
     if function.return_param != "void" {
         return Ok(FALSE);
     }
+    // only conversion functions have a fixed-size buffer constant
+    let fromto_pattern = Regex::new(r"^gosling_\w+_to_\w+$").unwrap();
+    if !fromto_pattern.is_match(&function.name) {
+        return Ok(FALSE);
+    }
     let input_params = &function.input_params;
     if input_params.len() != 4 {
         return Ok(FALSE);
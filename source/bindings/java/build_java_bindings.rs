@@ -60,7 +60,7 @@ handlebars_helper!(returnTypeToJavaType: |typename: String| {
     match typename.as_ref() {
         "void" => "void".to_string(),
         "bool" => "boolean".to_string(),
-        "size_t" => "long".to_string(),
+        "size_t" | "uint32_t" => "long".to_string(),
         "const char*" => "String".to_string(),
         "gosling_handshake_handle_t" => "long".to_string(),
         "gosling_circuit_token_t" => "long".to_string(),
@@ -128,6 +128,7 @@ handlebars_helper!(returnTypeToJNIType: |typename: String| {
     match typename.as_ref() {
         "void" => "void".to_string(),
         "bool" => "jboolean".to_string(),
+        "size_t" | "uint32_t" => "jlong".to_string(),
         "const char*" => "jstring".to_string(),
         "gosling_handshake_handle_t" | "gosling_circuit_token_t" => "jlong".to_string(),
         other => panic!("unhandled typename: {}", other),
@@ -141,6 +142,7 @@ handlebars_helper!(inputParamsToJNIParams: |params: Vec<Param>| {
     jni_args.push("jclass".to_string());
     for param in params {
         let jni_typename = match param.typename.as_ref() {
+            "bool" => "jboolean".to_string(),
             "uint8_t" => "jshort".to_string(),
             "uint16_t" => "jint".to_string(),
             "const uint16_t*" => "jintArray".to_string(),
@@ -200,6 +202,7 @@ handlebars_helper!(marshallJNIParams: |function_name: String, params: Vec<Param>
 
         match typename {
             // TODO: ensure the passed in values are in valid range for native type
+            "bool" => cpp_src!("const bool {name}_native = ({name} == JNI_TRUE);"),
             "uint8_t" => cpp_src!("const uint8_t {name}_native = static_cast<uint8_t>({name});"),
             "const uint16_t*" => {
                 // copy over jints to uint16_ts
@@ -212,17 +215,28 @@ handlebars_helper!(marshallJNIParams: |function_name: String, params: Vec<Param>
                 cpp_src!("const auto* {name}_native = {name}_count_native ? {name}_unique.get() : nullptr;");
             },
             "uint16_t" => cpp_src!("const uint16_t {name}_native = static_cast<uint16_t>({name});"),
+            "uint32_t" => cpp_src!("const uint32_t {name}_native = static_cast<uint32_t>({name});"),
             "const char*" => {
                 cpp_src!("const char* {name}_native = ({name} ? env->GetStringUTFChars({name}, nullptr) : nullptr);");
             },
             "char*" => {
                 // marshalled in as a jstring
                 let fromto_pattern = Regex::new(r"^gosling_(?P<from>\w+)_to_(?P<to>\w+)$").unwrap();
-                assert!(fromto_pattern.is_match(&function_name));
-                let caps = fromto_pattern.captures(&function_name).unwrap();
-                let (from, to) = (caps.name("from").unwrap().as_str(), caps.name("to").unwrap().as_str());
-                let buffer_size = format!("{}_{}_SIZE", from.to_uppercase(), to.to_uppercase());
-                cpp_src!("char {name}_native[{buffer_size}] = {{}};");
+                if let Some(caps) = fromto_pattern.captures(&function_name) {
+                    // conversion functions have a fixed-size buffer constant
+                    let (from, to) = (caps.name("from").unwrap().as_str(), caps.name("to").unwrap().as_str());
+                    let buffer_size = format!("{}_{}_SIZE", from.to_uppercase(), to.to_uppercase());
+                    cpp_src!("char {name}_native[{buffer_size}] = {{}};");
+                } else {
+                    // otherwise the required buffer size is queried from the
+                    // gosling_*_size() function paired with this one
+                    let object_name = &params[0].name;
+                    cpp_src!("gosling_error* {name}_size_error = nullptr;");
+                    cpp_src!("const size_t {name}_buffer_size = ::{function_name}_size({object_name}_native, &{name}_size_error);");
+                    cpp_src!("if ({name}_size_error != nullptr) ::gosling_error_free({name}_size_error);");
+                    cpp_src!("auto {name}_unique = std::make_unique<char[]>({name}_buffer_size + 1);");
+                    cpp_src!("char* {name}_native = {name}_unique.get();");
+                }
             },
             "size_t" => {
                 if name.ends_with("_length") {
@@ -232,15 +246,19 @@ handlebars_helper!(marshallJNIParams: |function_name: String, params: Vec<Param>
                 } else if name.ends_with("_size") {
                     // handle size param for an out char* utf8 string
                     let fromto_pattern = Regex::new(r"^gosling_(?P<from>\w+)_to_(?P<to>\w+)$").unwrap();
-                    assert!(fromto_pattern.is_match(&function_name));
-                    let caps = fromto_pattern.captures(&function_name).unwrap();
-                    let (from, to) = (caps.name("from").unwrap().as_str(), caps.name("to").unwrap().as_str());
-                    let buffer_size = format!("{}_{}_SIZE", from.to_uppercase(), to.to_uppercase());
-                    cpp_src!("constexpr size_t {name}_native = {buffer_size};");
+                    if let Some(caps) = fromto_pattern.captures(&function_name) {
+                        let (from, to) = (caps.name("from").unwrap().as_str(), caps.name("to").unwrap().as_str());
+                        let buffer_size = format!("{}_{}_SIZE", from.to_uppercase(), to.to_uppercase());
+                        cpp_src!("constexpr size_t {name}_native = {buffer_size};");
+                    } else {
+                        let out_name = format!("out_{}", &name[..name.len() - 5]);
+                        cpp_src!("const size_t {name}_native = {out_name}_buffer_size;");
+                    }
                 } else if name.ends_with("_count") {
                     // no-op for arrays of primitives
                 } else {
-                    panic!("unhandled argument => {name}: {typename}");
+                    // othewise it is a plain count or position
+                    cpp_src!("const size_t {name}_native = static_cast<size_t>({name});");
                 }
             },
             "gosling_handshake_handle_t" => cpp_src!("const gosling_handshake_handle_t {name}_native = static_cast<gosling_handshake_handle_t>({name});"),
@@ -341,7 +359,7 @@ handlebars_helper!(marshallNativeResults: |return_type: String, params: Vec<Para
         let typename: &str = param.typename.as_ref();
 
         match typename {
-            "uint8_t" | "uint16_t" | "size_t" | "gosling_handshake_handle_t" | "gosling_circuit_token_t" | "const uint16_t*" => (),
+            "bool" | "uint8_t" | "uint16_t" | "uint32_t" | "size_t" | "gosling_handshake_handle_t" | "gosling_circuit_token_t" | "const uint16_t*" => (),
             "const char*" => {
                 cpp_src!("env->ReleaseStringUTFChars({name}, {name}_native);");
             },
@@ -382,7 +400,7 @@ handlebars_helper!(marshallNativeResults: |return_type: String, params: Vec<Para
         marshall_lines.push("".to_string());
         match return_type.as_ref() {
             "bool" => cpp_src!("return result_native ? JNI_TRUE : JNI_FALSE;"),
-            "gosling_handshake_handle_t" | "gosling_circuit_token_t" => cpp_src!("return static_cast<jlong>(result_native);"),
+            "size_t" | "uint32_t" | "gosling_handshake_handle_t" | "gosling_circuit_token_t" => cpp_src!("return static_cast<jlong>(result_native);"),
             "const char*" => cpp_src!("return env->NewStringUTF(result_native);"),
             _ => panic!("unhandled return => {return_type}"),
         }
@@ -433,7 +451,8 @@ handlebars_helper!(marshallNativeParams: |input_params: Vec<Param>| {
                 if name.ends_with("_size") || name.ends_with("_length") {
                     continue;
                 } else {
-                    panic!("unexpected param -> {name} : {typename}");
+                    // othewise it is a plain count or position
+                    cpp_src!("const jlong {name}_jni = static_cast<jlong>({name});");
                 }
             },
             "const uint8_t*" => {
@@ -493,7 +512,7 @@ handlebars_helper!(callJavaCallback: |name: String, return_type: String, input_p
     match return_type.as_ref() {
         "void" => (),
         "bool" => cpp_src!("jboolean result_jni = JNI_FALSE;"),
-        "size_t" => cpp_src!("jlong result_jni = jlong(0);"),
+        "size_t" | "uint32_t" => cpp_src!("jlong result_jni = jlong(0);"),
         __ => panic!("unhandled return -> {return_type}"),
     };
     cpp_src!("if (std::lock_guard<std::mutex> lock(g_jni_glue->listener_map_mutex); true) {{");
@@ -522,7 +541,7 @@ handlebars_helper!(callJavaCallback: |name: String, return_type: String, input_p
                 if name.ends_with("_size") || name.ends_with("_length") {
                     continue;
                 } else {
-                    panic!("unhandled param -> {name}: {typename}");
+                    "J".to_string()
                 }
             }
             _ => {
@@ -544,7 +563,7 @@ handlebars_helper!(callJavaCallback: |name: String, return_type: String, input_p
     let method_return_signature = match return_type.as_ref() {
         "void" => "V",
         "bool" => "Z",
-        "size_t" => "J",
+        "size_t" | "uint32_t" => "J",
         _ => panic!("unhandled return -> {return_type}"),
     };
 
@@ -559,8 +578,7 @@ handlebars_helper!(callJavaCallback: |name: String, return_type: String, input_p
     for param in &input_params {
         let typename: &str = param.typename.as_ref();
         let name: &str = param.name.as_ref();
-        if typename == "size_t" {
-            assert!(name.ends_with("_length") || name.ends_with("_size"));
+        if typename == "size_t" && (name.ends_with("_length") || name.ends_with("_size")) {
             continue;
         } else {
             call_method_params.push(format!("{name}_jni"));
@@ -571,7 +589,7 @@ handlebars_helper!(callJavaCallback: |name: String, return_type: String, input_p
     match return_type.as_ref() {
         "void" => cpp_src!("    env->CallVoidMethod({call_method_params});"),
         "bool" => cpp_src!("    result_jni = env->CallBooleanMethod({call_method_params});"),
-        "size_t" => cpp_src!("    result_jni = env->CallLongMethod({call_method_params});"),
+        "size_t" | "uint32_t" => cpp_src!("    result_jni = env->CallLongMethod({call_method_params});"),
         _ => panic!("unhandled return -> {return_type}"),
     };
     cpp_src!("}}");
@@ -620,6 +638,7 @@ handlebars_helper!(marshallJNIResults: |return_type: String, input_params: Vec<P
         match return_type.as_ref() {
             "bool" => cpp_src!("return (result_jni == JNI_TRUE);"),
             "size_t" => cpp_src!("return static_cast<size_t>(result_jni);"),
+            "uint32_t" => cpp_src!("return static_cast<uint32_t>(result_jni);"),
             _ => panic!("unhandled return -> {return_type}"),
         }
     }
@@ -118,6 +118,9 @@ pub fn init_context(globals: &mut Globals, args: &Vec<String>) -> Result<()> {
         allowed_ports: None,
         pluggable_transports: None,
         bridge_lines: None,
+        sandbox: None,
+        security_config: None,
+        shared_network_cache: None,
     };
     let tor_client = Box::new(LegacyTorClient::new(tor_config)?);
 
@@ -196,7 +199,7 @@ pub fn request_endpoint(globals: &mut Globals, args: &Vec<String>) -> Result<()>
             let onion_service_id = V3OnionServiceId::from_string(&args[0])?;
             globals.term.write_line(format!("requesting endpoint from {onion_service_id}").as_str());
 
-            let endpoint_name = ENDPOINT_NAME.to_string();
+            let endpoint_name = ENDPOINT_NAME.parse()?;
             let _handshake_handle = context.identity_client_begin_handshake(onion_service_id, endpoint_name)?;
         }
     }
@@ -215,7 +218,7 @@ pub fn start_endpoint(globals: &mut Globals, args: &Vec<String>) -> Result<()> {
         None => bail!("context not yet initialised"),
         Some(context) => {
             if let Some((endpoint_private_key, client_service_id, x25519_public_key)) = globals.endpoint_server_credentials.get(client_service_id.as_str()) {
-                context.endpoint_server_start(endpoint_private_key.clone(), ENDPOINT_NAME.to_string(), client_service_id.clone(), x25519_public_key.clone())?;
+                context.endpoint_server_start(endpoint_private_key.clone(), ENDPOINT_NAME.parse()?, client_service_id.clone(), x25519_public_key.clone(), false)?;
             } else {
                 bail!("config for {client_service_id} not found");
             }
@@ -256,7 +259,7 @@ pub fn connect_endpoint(globals: &mut Globals, args: &Vec<String>) -> Result<()>
         None => bail!("context not yet initialised"),
         Some(context) => {
             if let Some((endpoint_service_id, client_auth_private)) = globals.endpoint_client_credentials.get(client_service_id.as_str()) {
-                let _handshake_handle = context.endpoint_client_begin_handshake(endpoint_service_id.clone(), client_auth_private.clone(), ENDPOINT_CHANNEL.to_string())?;
+                let _handshake_handle = context.endpoint_client_begin_handshake(endpoint_service_id.clone(), client_auth_private.clone(), ENDPOINT_CHANNEL.parse()?)?;
                 globals.term.write_line(format!("  connecting to endpoint {client_service_id}").as_str());
             }
         }
@@ -35,6 +35,8 @@ struct Alias {
 struct Param {
     name: String,
     typename: String,
+    // one of "borrowed", "consumed" or "out"; see param_ownership()
+    ownership: String,
 }
 
 #[derive(Serialize)]
@@ -47,6 +49,7 @@ struct Function {
 
 #[derive(Serialize)]
 struct Data {
+    version: String,
     config_flags: Vec<ConfigFlag>,
     constants: Vec<Constant>,
     aliases: Vec<Alias>,
@@ -113,13 +116,50 @@ fn preprocess_header(source: String) -> String {
     #[cfg(feature = "legacy-tor-provider")]
     features.push("GOSLING_HAVE_LEGACY_TOR_PROVIDER");
 
+    // optional apis
+    #[cfg(feature = "key-formats")]
+    features.push("GOSLING_HAVE_KEY_FORMATS");
+
     let source = preprocess_any(source.to_string(), &features);
     let source = preprocess_all(source, &features);
 
     source
 }
 
-fn parse_param(params_raw: &str) -> Vec<Param> {
+// Derive who owns the object passed through a parameter from cgosling's
+// naming and documentation conventions:
+// - "out": the function writes through the parameter; a returned handle is
+//   owned by the caller and must be freed
+// - "consumed": ownership passes to the function (in_ parameters) or, for a
+//   callback, to the callee (parameters documented as owned by the callee)
+// - "borrowed": the object is only valid for the duration of the call
+fn param_ownership(name: &str, comments: &[String]) -> String {
+    // a parameter's documentation is its @param line and any indented
+    // continuation lines
+    let mut param_comment = String::new();
+    let param_prefix = format!("@param {}:", name);
+    let mut in_param_comment = false;
+    for comment in comments {
+        if comment.starts_with(&param_prefix) {
+            in_param_comment = true;
+        } else if !comment.starts_with(' ') {
+            in_param_comment = false;
+        }
+        if in_param_comment {
+            param_comment.push_str(comment);
+        }
+    }
+
+    if name.starts_with("out_") {
+        "out".to_string()
+    } else if name.starts_with("in_") || param_comment.contains("owned by the callee") {
+        "consumed".to_string()
+    } else {
+        "borrowed".to_string()
+    }
+}
+
+fn parse_param(params_raw: &str, comments: &[String]) -> Vec<Param> {
     // function param
     let param_pattern = Regex::new(r"(?m)(?P<type>(\w+ \**)+)(?P<name>\w+)").unwrap();
     // pattern for our gosling structs
@@ -138,6 +178,7 @@ fn parse_param(params_raw: &str) -> Vec<Param> {
         params.push(Param {
             name: n.to_string(),
             typename: t.trim().to_string(),
+            ownership: param_ownership(n, comments),
         });
     }
     params
@@ -182,12 +223,17 @@ fn parse_header(source: &str) -> Data {
         name: "GOSLING_HAVE_LEGACY_TOR_PROVIDER".to_string(),
         enabled: cfg!(feature = "legacy-tor-provider"),
     });
+    config_flags.push(ConfigFlag {
+        comments: vec!["Defined if cgosling is built with key format support".to_string()],
+        name: "GOSLING_HAVE_KEY_FORMATS".to_string(),
+        enabled: cfg!(feature = "key-formats"),
+    });
 
     for commmented_source in commented_source_pattern.captures_iter(source) {
         let comments = &commmented_source["comments"];
         let comments = comment_pattern.replace_all(comments, "");
         let comments = comments.trim();
-        let comments = comments.split('\n').map(|s| s.to_string()).collect();
+        let comments: Vec<String> = comments.split('\n').map(|s| s.to_string()).collect();
 
         let source = &commmented_source["source"];
 
@@ -227,7 +273,7 @@ fn parse_header(source: &str) -> Data {
             // move the pointer char next to the type
             let r = r.trim().replace(" *", "*");
 
-            let params = parse_param(p);
+            let params = parse_param(p, &comments);
             callbacks.push(Function {
                 name: n.to_string(),
                 return_param: r,
@@ -243,7 +289,7 @@ fn parse_header(source: &str) -> Data {
             // move the pointer char next to the type
             let r = r.trim().replace(" *", "*");
 
-            let params = parse_param(p);
+            let params = parse_param(p, &comments);
             functions.push(Function {
                 name: n.to_string(),
                 return_param: r,
@@ -254,6 +300,8 @@ fn parse_header(source: &str) -> Data {
     }
 
     Data {
+        // set by cargo
+        version: std::env::var("CARGO_PKG_VERSION").unwrap(),
         config_flags,
         constants,
         aliases,
@@ -263,9 +311,44 @@ fn parse_header(source: &str) -> Data {
 }
 
 fn main() {
+    // set by cargo
+    let crate_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+    // set by cargo
+    let out_dir = PathBuf::from(std::env::var("OUT_DIR").unwrap());
+
+    // the json IDL is always generated, as it is embedded in the library and
+    // returned by gosling_library_get_api_spec()
+    let out_header_file_path = out_dir.join("cgosling.h");
+    let out_json_file_path = out_dir.join("cgosling.json");
+
+    // generate libgosling.h C header
+    match cbindgen::generate(&crate_dir) {
+        Ok(bindings) => bindings.write_to_file(out_header_file_path.clone().into_os_string()),
+        Err(cbindgen::Error::ParseSyntaxError { .. }) => {
+            // ignore in favor of cargo's syntax check, which still needs the
+            // embedded json IDL to exist
+            std::fs::write(out_json_file_path, "{}").unwrap();
+            return;
+        }
+        Err(err) => panic!("{:?}", err),
+    };
+
+    // pre-process and re-write header
+    let source = std::fs::read_to_string(out_header_file_path.clone()).unwrap();
+    let source = preprocess_header(source);
+    std::fs::write(out_header_file_path.clone(), source.clone()).unwrap();
+
+    // convert generated header to json IDL
+    let idl = parse_header(source.as_str());
+
+    // and write json IDL to disk
+    let mut json_file = match File::create(out_json_file_path.clone()) {
+        Ok(file) => file,
+        Err(err) => panic!("{:?}", err),
+    };
+    writeln!(json_file, "{}", serde_json::to_string_pretty(&idl).unwrap()).unwrap();
+
     if cfg!(not(feature = "impl-lib")) {
-        // set by cargo
-        let crate_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
         // set by cargo
         let profile = match std::env::var("PROFILE") {
             Ok(target) => target,
@@ -277,31 +360,13 @@ fn main() {
             Err(_) => panic!("CARGO_TARGET_DIR not set"),
         };
 
+        // copy the header and json IDL to where the bindings are built from
         let header_file_path = target_dir.join("cgosling.h");
         println!("cargo:rerun-if-changed={}", header_file_path.display());
+        std::fs::copy(out_header_file_path, header_file_path).unwrap();
 
-        // generate libgosling.h C header
-        match cbindgen::generate(&crate_dir) {
-            Ok(bindings) => bindings.write_to_file(header_file_path.clone().into_os_string()),
-            Err(cbindgen::Error::ParseSyntaxError { .. }) => return, // ignore in favor of cargo's syntax check
-            Err(err) => panic!("{:?}", err),
-        };
-
-        // pre-process and re-write header
-        let source = std::fs::read_to_string(header_file_path.clone()).unwrap();
-        let source = preprocess_header(source);
-        std::fs::write(header_file_path, source.clone()).unwrap();
-
-        // convert generated header to json IDL
-        let idl = parse_header(source.as_str());
-
-        // and write json IDL to disk
         let json_file_path = target_dir.join("cgosling.json");
         println!("cargo:rerun-if-changed={}", json_file_path.display());
-        let mut json_file = match File::create(json_file_path) {
-            Ok(file) => file,
-            Err(err) => panic!("{:?}", err),
-        };
-        writeln!(json_file, "{}", serde_json::to_string_pretty(&idl).unwrap()).unwrap();
+        std::fs::copy(out_json_file_path, json_file_path).unwrap();
     }
 }
@@ -515,6 +515,18 @@ fn buffer_to_size<T>(buffer: &Buffer<T>, buffer_size: &Primitive<usize>) -> usiz
     size
 }
 
+// string parameters with a length of 0 are read up to their null-terminator, so
+// make sure one exists past the end of the buffer
+fn null_terminated(buffer: Buffer<c_char>) -> Buffer<c_char> {
+    match buffer {
+        Buffer::Null => Buffer::Null,
+        Buffer::Valid(mut value) => {
+            value.push(0);
+            Buffer::Valid(value)
+        }
+    }
+}
+
 fn buffer_as_pointer<T>(buffer: &Buffer<T>) -> *const T {
     let pointer: *const T = match buffer {
         Buffer::Null => ptr::null(),
@@ -768,6 +780,7 @@ fuzz_target!(|data: Data| {
                 let mut private_key: *mut GoslingEd25519PrivateKey = ptr::null_mut();
                 let out_private_key = phandle_to_out_pointer(out_private_key, &mut private_key);
                 let key_blob_length = buffer_to_size(&key_blob, &key_blob_length);
+                let key_blob = null_terminated(key_blob);
                 let key_blob = buffer_as_pointer(&key_blob);
                 let mut error: *mut GoslingError = ptr::null_mut();
                 let out_error = phandle_to_out_pointer(out_error, &mut error);
@@ -808,6 +821,7 @@ fuzz_target!(|data: Data| {
                 let mut private_key: *mut GoslingX25519PrivateKey = ptr::null_mut();
                 let out_private_key = phandle_to_out_pointer(out_private_key, &mut private_key);
                 let base64_length = buffer_to_size(&base64, &base64_length);
+                let base64 = null_terminated(base64);
                 let base64 = buffer_as_pointer(&base64);
                 let mut error: *mut GoslingError = ptr::null_mut();
                 let out_error = phandle_to_out_pointer(out_error, &mut error);
@@ -848,6 +862,7 @@ fuzz_target!(|data: Data| {
                 let mut public_key: *mut GoslingX25519PublicKey = ptr::null_mut();
                 let out_public_key = phandle_to_out_pointer(out_public_key, &mut public_key);
                 let base32_length = buffer_to_size(&base32, &base32_length);
+                let base32 = null_terminated(base32);
                 let base32 = buffer_as_pointer(&base32);
                 let mut error: *mut GoslingError = ptr::null_mut();
                 let out_error = phandle_to_out_pointer(out_error, &mut error);
@@ -888,6 +903,7 @@ fuzz_target!(|data: Data| {
                 let mut service_id: *mut GoslingV3OnionServiceId = ptr::null_mut();
                 let out_service_id = phandle_to_out_pointer(out_service_id, &mut service_id);
                 let service_id_string_length = buffer_to_size(&service_id_string, &service_id_string_length);
+                let service_id_string = null_terminated(service_id_string);
                 let service_id_string = buffer_as_pointer(&service_id_string);
                 let mut error: *mut GoslingError = ptr::null_mut();
                 let out_error = phandle_to_out_pointer(out_error, &mut error);
@@ -926,6 +942,7 @@ fuzz_target!(|data: Data| {
             },
             Function::StringIsValidV3OnionServiceId{service_id_string, service_id_string_length, out_error} => {
                 let service_id_string_length = buffer_to_size(&service_id_string, &service_id_string_length);
+                let service_id_string = null_terminated(service_id_string);
                 let service_id_string = buffer_as_pointer(&service_id_string);
                 let mut error: *mut GoslingError = ptr::null_mut();
                 let out_error = phandle_to_out_pointer(out_error, &mut error);
@@ -1014,6 +1031,7 @@ fuzz_target!(|data: Data| {
                 let context = handle_as_pointer(context, &contexts);
                 let endpoint_private_key = handle_as_pointer(endpoint_private_key, &ed25519_private_keys);
                 let endpoint_name_length = buffer_to_size(&endpoint_name, &endpoint_name_length);
+                let endpoint_name = null_terminated(endpoint_name);
                 let endpoint_name = buffer_as_pointer(&endpoint_name);
                 let client_identity = handle_as_pointer(client_identity, &v3_onion_service_ids);
                 let client_auth_public_key = handle_as_pointer(client_auth_public_key, &x25519_public_keys);
@@ -1040,6 +1058,7 @@ fuzz_target!(|data: Data| {
                 let context = handle_as_pointer(context, &contexts);
                 let identity_service_id = handle_as_pointer(identity_service_id, &v3_onion_service_ids);
                 let endpoint_name_length = buffer_to_size(&endpoint_name, &endpoint_name_length);
+                let endpoint_name = null_terminated(endpoint_name);
                 let endpoint_name = buffer_as_pointer(&endpoint_name);
                 let mut error: *mut GoslingError = ptr::null_mut();
                 let out_error = phandle_to_out_pointer(out_error, &mut error);
@@ -1080,6 +1099,7 @@ fuzz_target!(|data: Data| {
                 let endpoint_service_id = handle_as_pointer(endpoint_service_id, &v3_onion_service_ids);
                 let client_auth_private_key = handle_as_pointer(client_auth_private_key, &x25519_private_keys);
                 let channel_name_length = buffer_to_size(&channel_name, &channel_name_length);
+                let channel_name = null_terminated(channel_name);
                 let channel_name = buffer_as_pointer(&channel_name);
                 let mut error: *mut GoslingError = ptr::null_mut();
                 let out_error = phandle_to_out_pointer(out_error, &mut error);
@@ -1307,6 +1327,7 @@ fuzz_target!(|data: Data| {
                 let mut dest: *mut GoslingTargetAddress = ptr::null_mut();
                 let out_target_address = phandle_to_out_pointer(out_target_address, &mut dest);
                 let domain_length = buffer_to_size(&domain, &domain_length);
+                let domain = null_terminated(domain);
                 let domain = buffer_as_pointer(&domain);
                 let mut error: *mut GoslingError = ptr::null_mut();
                 let out_error = phandle_to_out_pointer(out_error, &mut error);
@@ -1338,6 +1359,7 @@ fuzz_target!(|data: Data| {
                 let mut dest: *mut GoslingTargetAddress = ptr::null_mut();
                 let out_target_address = phandle_to_out_pointer(out_target_address, &mut dest);
                 let target_address_length = buffer_to_size(&target_address, &target_address_length);
+                let target_address = null_terminated(target_address);
                 let target_address = buffer_as_pointer(&target_address);
                 let mut error: *mut GoslingError = ptr::null_mut();
                 let out_error = phandle_to_out_pointer(out_error, &mut error);
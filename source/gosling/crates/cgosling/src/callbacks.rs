@@ -1,24 +1,44 @@
 // standard
+use std::ffi::CString;
 use std::os::raw::c_char;
 
 // extern crates
 use anyhow::bail;
 #[cfg(feature = "impl-lib")]
 use cgosling_proc_macros::*;
+use gosling::context::{ClientFilterVerdict, EndpointValidatorVerdict};
 
 // internal crates
 use crate::context::*;
 use crate::crypto::*;
+use crate::datagram_channel::*;
 use crate::error::*;
 use crate::macros::*;
+use crate::rpc_channel::*;
 
 #[derive(Default, Clone)]
 pub(crate) struct EventCallbacks {
     // tor events
     pub tor_bootstrap_status_received_callback: GoslingTorBootstrapStatusReceivedCallback,
     pub tor_bootstrap_completed_callback: GoslingTorBootstrapCompletedCallback,
+    pub tor_provider_changed_callback: GoslingTorProviderChangedCallback,
+    pub listener_start_failed_callback: GoslingListenerStartFailedCallback,
     pub tor_log_received_callback: GoslingTorLogReceivedCallback,
 
+    // event queue events
+    pub event_queue_overflowed_callback: GoslingEventQueueOverflowedCallback,
+    pub tor_log_lines_dropped_callback: GoslingTorLogLinesDroppedCallback,
+
+    // outbound queue events
+    pub outbound_handshake_queued_callback: GoslingOutboundHandshakeQueuedCallback,
+    pub outbound_handshake_started_callback: GoslingOutboundHandshakeStartedCallback,
+
+    // client authorization events
+    pub client_auth_added_callback: GoslingClientAuthAddedCallback,
+    pub client_auth_add_failed_callback: GoslingClientAuthAddFailedCallback,
+    pub client_auth_removed_callback: GoslingClientAuthRemovedCallback,
+    pub client_auth_remove_failed_callback: GoslingClientAuthRemoveFailedCallback,
+
     // identity client events
     pub identity_client_challenge_response_size_callback:
         GoslingIdentityClientHandshakeChallengeResponseSizeCallback,
@@ -27,13 +47,19 @@ pub(crate) struct EventCallbacks {
     pub identity_client_handshake_completed_callback:
         GoslingIdentityClientHandshakeCompletedCallback,
     pub identity_client_handshake_failed_callback: GoslingIdentityClientHandshakeFailedCallback,
+    pub identity_client_endpoint_pin_mismatched_callback:
+        GoslingIdentityClientEndpointPinMismatchedCallback,
 
     // identity server events
     pub identity_server_published_callback: GoslingIdentityServerPublishedCallback,
+    pub identity_server_descriptor_refreshed_callback:
+        GoslingIdentityServerDescriptorRefreshedCallback,
     pub identity_server_handshake_started_callback: GoslingIdentityServerHandshakeStartedCallback,
     pub identity_server_client_allowed_callback:
         GoslingIdentityServerHandshakeClientAllowedCallback,
     pub identity_server_endpoint_supported_callback: GoslingIdentityServerEndpointSupportedCallback,
+    pub identity_server_contact_request_received_callback:
+        GoslingIdentityServerContactRequestReceivedCallback,
     pub identity_server_challenge_size_callback:
         GoslingIdentityServerHandshakeChallengeSizeCallback,
     pub identity_server_build_challenge_callback:
@@ -48,14 +74,26 @@ pub(crate) struct EventCallbacks {
     // endpoint client events
     pub endpoint_client_handshake_completed_callback:
         GoslingEndpointClientHandshakeCompletedCallback,
+    pub endpoint_client_rpc_channel_opened_callback: GoslingEndpointClientRpcChannelOpenedCallback,
+    pub endpoint_client_datagram_channel_opened_callback:
+        GoslingEndpointClientDatagramChannelOpenedCallback,
     pub endpoint_client_handshake_failed_callback: GoslingEndpointClientHandshakeFailedCallback,
+    pub endpoint_client_endpoint_revoked_callback: GoslingEndpointClientEndpointRevokedCallback,
 
     // endpoint server events
     pub endpoint_server_published_callback: GoslingEndpointServerPublishedCallback,
+    pub endpoint_server_publish_completed_callback: GoslingEndpointServerPublishCompletedCallback,
+    pub endpoint_server_publish_timed_out_callback: GoslingEndpointServerPublishTimedOutCallback,
+    pub endpoint_server_descriptor_refreshed_callback:
+        GoslingEndpointServerDescriptorRefreshedCallback,
+    pub endpoint_server_connection_shed_callback: GoslingEndpointServerConnectionShedCallback,
     pub endpoint_server_handshake_started_callback: GoslingEndpointServerHandshakeStartedCallback,
     pub endpoint_server_channel_supported_callback: GoslingEndpointServerChannelSupportedCallback,
     pub endpoint_server_handshake_completed_callback:
         GoslingEndpointServerHandshakeCompletedCallback,
+    pub endpoint_server_rpc_channel_opened_callback: GoslingEndpointServerRpcChannelOpenedCallback,
+    pub endpoint_server_datagram_channel_opened_callback:
+        GoslingEndpointServerDatagramChannelOpenedCallback,
     pub endpoint_server_handshake_rejected_callback: GoslingEndpointServerHandshakeRejectedCallback,
     pub endpoint_server_handshake_failed_callback: GoslingEndpointServerHandshakeFailedCallback,
 }
@@ -88,6 +126,32 @@ pub type GoslingTorBootstrapStatusReceivedCallback = Option<
 pub type GoslingTorBootstrapCompletedCallback =
     Option<extern "C" fn(context: *mut GoslingContext) -> ()>;
 
+/// The function pointer type for the tor provider changed callback. This callback is called
+/// when the context has switched to the tor provider passed to
+/// gosling_context_set_tor_provider() and restarted its servers.
+///
+/// @param context: the context associated with this event
+pub type GoslingTorProviderChangedCallback =
+    Option<extern "C" fn(context: *mut GoslingContext) -> ()>;
+
+/// The function pointer type for the listener start failed callback. This callback is
+/// called when the context's identity server or one of its endpoint servers could not be
+/// restarted after switching to the tor provider passed to
+/// gosling_context_set_tor_provider(). The server is stopped.
+///
+/// @param context: the context associated with this event
+/// @param service_id: the onion service id of the server
+/// @param virt_port: the virt-port the server's onion service would have listened on
+/// @param error: error associated with this failure
+pub type GoslingListenerStartFailedCallback = Option<
+    extern "C" fn(
+        context: *mut GoslingContext,
+        service_id: *const GoslingV3OnionServiceId,
+        virt_port: u16,
+        error: *const GoslingError,
+    ) -> (),
+>;
+
 /// The function pointer type for the tor log received callback. This callback is called
 /// whenever the context's tor daemon prints new log lines.
 ///
@@ -98,6 +162,118 @@ pub type GoslingTorLogReceivedCallback = Option<
     extern "C" fn(context: *mut GoslingContext, line: *const c_char, line_length: usize) -> (),
 >;
 
+/// The function pointer type for the event queue overflowed callback. This
+/// callback is called when more events were produced than the capacity set with
+/// gosling_context_set_event_queue_capacity(), before the remaining events'
+/// callbacks are called.
+///
+/// @param context: the context associated with this event
+/// @param dropped_events: the number of lossy events which were discarded
+/// @param coalesced_tor_logs: the number of tor log lines which were merged into
+///  older tor log received callbacks
+pub type GoslingEventQueueOverflowedCallback = Option<
+    extern "C" fn(
+        context: *mut GoslingContext,
+        dropped_events: usize,
+        coalesced_tor_logs: usize,
+    ) -> (),
+>;
+
+/// The function pointer type for the tor log lines dropped callback. This
+/// callback is called when the tor log buffer set with
+/// gosling_context_set_tor_log_buffer() was full and its oldest lines were
+/// discarded to make room for newer ones.
+///
+/// @param context: the context associated with this event
+/// @param dropped_lines: the number of tor log lines which were discarded
+pub type GoslingTorLogLinesDroppedCallback =
+    Option<extern "C" fn(context: *mut GoslingContext, dropped_lines: usize) -> ()>;
+
+/// The function pointer type for the outbound handshake queued callback. This
+/// callback is called when an outgoing identity or endpoint handshake is waiting
+/// for an outbound connection slot, and again whenever its position in the queue
+/// changes.
+///
+/// @param context: the context associated with this event
+/// @param handshake_handle: the handshake handle this callback is associated with
+/// @param queue_position: the number of queued handshakes which will be started
+///  before this one
+pub type GoslingOutboundHandshakeQueuedCallback = Option<
+    extern "C" fn(
+        context: *mut GoslingContext,
+        handshake_handle: GoslingHandshakeHandle,
+        queue_position: usize,
+    ) -> (),
+>;
+
+/// The function pointer type for the outbound handshake started callback. This
+/// callback is called when an outgoing identity or endpoint handshake which was
+/// queued because the context's tor provider had not yet bootstrapped leaves the
+/// queue and begins connecting; see gosling_context_set_queue_until_bootstrapped().
+///
+/// @param context: the context associated with this event
+/// @param handshake_handle: the handshake handle this callback is associated with
+pub type GoslingOutboundHandshakeStartedCallback = Option<
+    extern "C" fn(context: *mut GoslingContext, handshake_handle: GoslingHandshakeHandle) -> (),
+>;
+
+/// The function pointer type for the client auth added callback. This callback is
+/// called when the client authorization key for an endpoint server has been added
+/// to the context's tor daemon ahead of connecting to the endpoint server.
+///
+/// @param context: the context associated with this event
+/// @param endpoint_service_id: the onion service id of the endpoint server
+pub type GoslingClientAuthAddedCallback = Option<
+    extern "C" fn(
+        context: *mut GoslingContext,
+        endpoint_service_id: *const GoslingV3OnionServiceId,
+    ) -> (),
+>;
+
+/// The function pointer type for the client auth add failed callback. This callback
+/// is called when the client authorization key for an endpoint server could not be
+/// added to the context's tor daemon. The endpoint handshake which required the key
+/// also fails.
+///
+/// @param context: the context associated with this event
+/// @param endpoint_service_id: the onion service id of the endpoint server
+/// @param error: error associated with this failure
+pub type GoslingClientAuthAddFailedCallback = Option<
+    extern "C" fn(
+        context: *mut GoslingContext,
+        endpoint_service_id: *const GoslingV3OnionServiceId,
+        error: *const GoslingError,
+    ) -> (),
+>;
+
+/// The function pointer type for the client auth removed callback. This callback is
+/// called when a client authorization key has been removed from the context's tor
+/// daemon with gosling_context_remove_client_auth().
+///
+/// @param context: the context associated with this event
+/// @param endpoint_service_id: the onion service id of the endpoint server
+pub type GoslingClientAuthRemovedCallback = Option<
+    extern "C" fn(
+        context: *mut GoslingContext,
+        endpoint_service_id: *const GoslingV3OnionServiceId,
+    ) -> (),
+>;
+
+/// The function pointer type for the client auth remove failed callback. This
+/// callback is called when a client authorization key could not be removed from the
+/// context's tor daemon with gosling_context_remove_client_auth().
+///
+/// @param context: the context associated with this event
+/// @param endpoint_service_id: the onion service id of the endpoint server
+/// @param error: error associated with this failure
+pub type GoslingClientAuthRemoveFailedCallback = Option<
+    extern "C" fn(
+        context: *mut GoslingContext,
+        endpoint_service_id: *const GoslingV3OnionServiceId,
+        error: *const GoslingError,
+    ) -> (),
+>;
+
 /// The function pointer type for the client handshake challenge response size
 /// callback. This callback is called when a client needs to know how much memory
 /// to allocate for a challenge response.
@@ -185,6 +361,32 @@ pub type GoslingIdentityClientHandshakeFailedCallback = Option<
     ) -> (),
 >;
 
+/// The function pointer type for the identity client endpoint pin mismatched callback. This
+/// callback is called when an identity server grants a different endpoint server than the one
+/// pinned for the endpoint (see gosling_context_set_endpoint_pinning_enabled()). It is called
+/// just before the identity client handshake completed callback.
+///
+/// @param context: the context associated with this event
+/// @param handshake_handle: the handshake handle this callback is associated with
+/// @param identity_service_id: the onion service id of the identity server which granted the
+///  endpoint server
+/// @param endpoint_name: the null-terminated name of the granted endpoint
+/// @param endpoint_name_length: the number of chars in endpoint_name string not including
+///  the null-terminator
+/// @param pinned_endpoint_service_id: the onion service id of the pinned endpoint server
+/// @param endpoint_service_id: the onion service id of the granted endpoint server
+pub type GoslingIdentityClientEndpointPinMismatchedCallback = Option<
+    extern "C" fn(
+        context: *mut GoslingContext,
+        handshake_handle: GoslingHandshakeHandle,
+        identity_service_id: *const GoslingV3OnionServiceId,
+        endpoint_name: *const c_char,
+        endpoint_name_length: usize,
+        pinned_endpoint_service_id: *const GoslingV3OnionServiceId,
+        endpoint_service_id: *const GoslingV3OnionServiceId,
+    ) -> (),
+>;
+
 /// The function pointer type for the identity server published callback. This callback
 /// is called whenever the onion service of the identity server associated with the given
 /// context is published and should be reachable by clients.
@@ -193,6 +395,15 @@ pub type GoslingIdentityClientHandshakeFailedCallback = Option<
 pub type GoslingIdentityServerPublishedCallback =
     Option<extern "C" fn(context: *mut GoslingContext) -> ()>;
 
+/// The function pointer type for the identity server descriptor refreshed callback. This
+/// callback is called whenever the already published onion service descriptor of the identity
+/// server associated with the given context has been refreshed; see
+/// gosling_context_refresh_identity_server_descriptor().
+///
+/// @param context: the context associated with this event
+pub type GoslingIdentityServerDescriptorRefreshedCallback =
+    Option<extern "C" fn(context: *mut GoslingContext) -> ()>;
+
 /// The function pointer type of the identity server handshake started callback. This callback
 /// is called whenever the identity server is initially connected to.
 ///
@@ -220,6 +431,83 @@ pub type GoslingIdentityServerHandshakeClientAllowedCallback = Option<
     ) -> bool,
 >;
 
+/// Client filter result allowing the identity handshake to continue; see
+/// gosling_context_set_identity_server_client_filter_callback()
+pub const CLIENT_FILTER_VERDICT_ALLOW: u32 = 0;
+/// Client filter result rejecting a client which has made too many requests; see
+/// gosling_context_set_identity_server_client_filter_callback()
+pub const CLIENT_FILTER_VERDICT_TOO_MANY_REQUESTS: u32 = 1;
+/// Client filter result rejecting a banned client; see
+/// gosling_context_set_identity_server_client_filter_callback()
+pub const CLIENT_FILTER_VERDICT_BANNED: u32 = 2;
+
+/// The function pointer type of the identity server client filter callback. This
+/// callback is called as soon as an identity client begins its handshake, before
+/// any of the other identity server callbacks, and may reject the client without
+/// any further work. Rejected clients are sent an error naming the reason and the
+/// handshake fails.
+///
+/// This callback is called from within gosling_context_poll_events() while the context
+/// is locked and must not call any gosling_context_* functions; such calls fail with
+/// ERROR_CODE_REENTRANT_CALL.
+///
+/// @param context: the context associated with this event
+/// @param client_service_id: the alleged v3 onion service id of the connected client;
+///  this object is only valid for the duration of the callback and must not be freed
+/// @param requested_endpoint: the null-terminated ASCII-encoded name of the requested
+///  endpoint
+/// @param requested_endpoint_length: the number of chars in requested_endpoint not
+///  including the null-terminator
+/// @return one of the CLIENT_FILTER_VERDICT_* constants; any other value rejects the
+///  client as banned
+pub type GoslingIdentityServerClientFilterCallback = Option<
+    extern "C" fn(
+        context: *mut GoslingContext,
+        client_service_id: *const GoslingV3OnionServiceId,
+        requested_endpoint: *const c_char,
+        requested_endpoint_length: usize,
+    ) -> u32,
+>;
+
+/// Endpoint validator result allowing the identity handshake to begin; see
+/// gosling_context_set_identity_client_endpoint_validator_callback()
+pub const ENDPOINT_VALIDATOR_VERDICT_ALLOW: u32 = 0;
+/// Endpoint validator result rejecting an endpoint name which does not follow the
+/// application's naming rules; see
+/// gosling_context_set_identity_client_endpoint_validator_callback()
+pub const ENDPOINT_VALIDATOR_VERDICT_MALFORMED: u32 = 1;
+/// Endpoint validator result rejecting an endpoint the application does not support;
+/// see gosling_context_set_identity_client_endpoint_validator_callback()
+pub const ENDPOINT_VALIDATOR_VERDICT_UNSUPPORTED: u32 = 2;
+
+/// The function pointer type of the identity client endpoint validator callback. This
+/// callback is called for the requested endpoint name of each new identity handshake
+/// before any connection to the identity server is made. Rejected endpoint names fail
+/// the gosling_context_begin_identity_handshake*() call with an error and no
+/// handshake is begun.
+///
+/// This callback is called from within the gosling_context_begin_identity_handshake*()
+/// functions while the context is locked and must not call any gosling_context_*
+/// functions; such calls fail with ERROR_CODE_REENTRANT_CALL.
+///
+/// @param context: the context associated with this event
+/// @param identity_service_id: the v3 onion service id of the identity server; this
+///  object is only valid for the duration of the callback and must not be freed
+/// @param endpoint_name: the null-terminated ASCII-encoded name of the requested
+///  endpoint
+/// @param endpoint_name_length: the number of chars in endpoint_name not including
+///  the null-terminator
+/// @return one of the ENDPOINT_VALIDATOR_VERDICT_* constants; any other value rejects
+///  the endpoint as malformed
+pub type GoslingIdentityClientEndpointValidatorCallback = Option<
+    extern "C" fn(
+        context: *mut GoslingContext,
+        identity_service_id: *const GoslingV3OnionServiceId,
+        endpoint_name: *const c_char,
+        endpoint_name_length: usize,
+    ) -> u32,
+>;
+
 /// The function pointer type of the identity server endpoint supported callback. This
 /// callback is called when the server needs to determine if the client's requested
 /// endpoint is supported. The result of this callback partially determines if an
@@ -242,6 +530,27 @@ pub type GoslingIdentityServerEndpointSupportedCallback = Option<
     ) -> bool,
 >;
 
+/// The function pointer type of the identity server contact request received callback.
+/// This optional callback is called when a client has attached a contact request (e.g. a
+/// petname or an introduction) to its endpoint request, before the client allowed and
+/// endpoint supported callbacks are called. The contact request is not authenticated
+/// until the handshake completes.
+///
+/// @param context: the context associated with this event
+/// @param handshake_handle: the handshake handle this callback is associated with
+/// @param contact_request: a null-terminated UTF-8 string containing the client's contact
+///  request
+/// @param contact_request_length: the number of bytes in contact_request, not including
+///  the null-terminator
+pub type GoslingIdentityServerContactRequestReceivedCallback = Option<
+    extern "C" fn(
+        context: *mut GoslingContext,
+        handshake_handle: GoslingHandshakeHandle,
+        contact_request: *const c_char,
+        contact_request_length: usize,
+    ),
+>;
+
 /// The function pointer type for the server handshake challenge size callback.
 /// This callback is called when a server needs to know how much memory to allocate
 /// for a challenge.
@@ -371,7 +680,7 @@ pub type GoslingIdentityServerHandshakeFailedCallback = Option<
 /// @param channel_name_length: the number of chars in channel_name not including the
 ///  null-terminator
 /// @param stream: os-specific tcp socket handle associated with the connection to the
-///  endpoint server
+///  endpoint server, owned by the callee
 pub type GoslingEndpointClientHandshakeCompletedCallback = Option<
     extern "C" fn(
         context: *mut GoslingContext,
@@ -383,6 +692,56 @@ pub type GoslingEndpointClientHandshakeCompletedCallback = Option<
     ),
 >;
 
+/// The function pointer type for the endpoint client rpc channel opened callback.
+/// This callback is called instead of the endpoint client handshake completed
+/// callback when the client completes a handshake for one of the channels registered
+/// with gosling_context_add_rpc_channel().
+///
+/// @param context: the context associated with this event
+/// @param handshake_handle: the handshake handle this callback is associated with
+/// @param endpoint_service_id: the onion service id of the endpoint server the client
+///  has connected to
+/// @param channel_name: the null-terminated name of the channel requested by the client
+/// @param channel_name_length: the number of chars in channel_name not including the
+///  null-terminator
+/// @param channel: the opened rpc channel, owned by the callee; must be freed with
+///  gosling_rpc_channel_free()
+pub type GoslingEndpointClientRpcChannelOpenedCallback = Option<
+    extern "C" fn(
+        context: *mut GoslingContext,
+        handshake_handle: GoslingHandshakeHandle,
+        endpoint_service_id: *const GoslingV3OnionServiceId,
+        channel_name: *const c_char,
+        channel_name_length: usize,
+        channel: *mut GoslingRpcChannel,
+    ),
+>;
+
+/// The function pointer type for the endpoint client datagram channel opened
+/// callback. This callback is called instead of the endpoint client handshake
+/// completed callback when the client completes a handshake for one of the channels
+/// registered with gosling_context_add_datagram_channel().
+///
+/// @param context: the context associated with this event
+/// @param handshake_handle: the handshake handle this callback is associated with
+/// @param endpoint_service_id: the onion service id of the endpoint server the client
+///  has connected to
+/// @param channel_name: the null-terminated name of the channel requested by the client
+/// @param channel_name_length: the number of chars in channel_name not including the
+///  null-terminator
+/// @param channel: the opened datagram channel, owned by the callee; must be freed
+///  with gosling_datagram_channel_free()
+pub type GoslingEndpointClientDatagramChannelOpenedCallback = Option<
+    extern "C" fn(
+        context: *mut GoslingContext,
+        handshake_handle: GoslingHandshakeHandle,
+        endpoint_service_id: *const GoslingV3OnionServiceId,
+        channel_name: *const c_char,
+        channel_name_length: usize,
+        channel: *mut GoslingDatagramChannel,
+    ),
+>;
+
 /// The function pointer type for the endpoint client handshake handshake failed
 /// callback. This callback is called when a client's endpoint handshake fails.
 ///
@@ -397,6 +756,23 @@ pub type GoslingEndpointClientHandshakeFailedCallback = Option<
     ) -> (),
 >;
 
+/// The function pointer type for the endpoint client endpoint revoked callback. This
+/// callback is called when an endpoint revocation has been handled with
+/// gosling_context_handle_endpoint_revocation().
+///
+/// @param context: the context associated with this event
+/// @param endpoint_service_id: the onion service id of the endpoint server which revoked
+///  access
+/// @param revoked_at: when access was revoked in seconds since the unix epoch, according to
+///  the endpoint server's clock
+pub type GoslingEndpointClientEndpointRevokedCallback = Option<
+    extern "C" fn(
+        context: *mut GoslingContext,
+        endpoint_service_id: *const GoslingV3OnionServiceId,
+        revoked_at: u64,
+    ) -> (),
+>;
+
 /// The function pointer type for the endpoint server published callback. This callbcak
 /// is called whenever the onion service of the indicated endpoint server associted with
 /// the given context is published and should be reachable by clients.
@@ -415,6 +791,80 @@ pub type GoslingEndpointServerPublishedCallback = Option<
     ) -> (),
 >;
 
+/// The function pointer type for the endpoint server publish completed callback. This
+/// callback is called after the endpoint server published callback when the endpoint
+/// server was started with gosling_context_start_endpoint_server_with_completion().
+///
+/// @param context: the context associated with this event
+/// @param publish_handle: the handle returned when the endpoint server was started
+/// @param endpoint_service_id: the onion service id of the published endpoint server
+/// @param endpoint_name: the null-terminated name of the endpoint server published
+/// @param endpoint_name_length: the number of chars in endpoint_name string not including the
+///  null-terminator
+pub type GoslingEndpointServerPublishCompletedCallback = Option<
+    extern "C" fn(
+        context: *mut GoslingContext,
+        publish_handle: GoslingPublishHandle,
+        endpoint_service_id: *const GoslingV3OnionServiceId,
+        endpoint_name: *const c_char,
+        endpoint_name_length: usize,
+    ) -> (),
+>;
+
+/// The function pointer type for the endpoint server publish timed out callback. This
+/// callback is called when an endpoint server started with
+/// gosling_context_start_endpoint_server_with_completion() has not been published within
+/// its timeout. The endpoint server keeps running and may still be published later.
+///
+/// @param context: the context associated with this event
+/// @param publish_handle: the handle returned when the endpoint server was started
+/// @param endpoint_service_id: the onion service id of the endpoint server
+/// @param endpoint_name: the null-terminated name of the endpoint server
+/// @param endpoint_name_length: the number of chars in endpoint_name string not including the
+///  null-terminator
+pub type GoslingEndpointServerPublishTimedOutCallback = Option<
+    extern "C" fn(
+        context: *mut GoslingContext,
+        publish_handle: GoslingPublishHandle,
+        endpoint_service_id: *const GoslingV3OnionServiceId,
+        endpoint_name: *const c_char,
+        endpoint_name_length: usize,
+    ) -> (),
+>;
+
+/// The function pointer type for the endpoint server descriptor refreshed callback. This
+/// callback is called whenever the already published onion service descriptor of the
+/// indicated endpoint server has been refreshed; see
+/// gosling_context_refresh_endpoint_server_descriptor().
+///
+/// @param context: the context associated with this event
+/// @param endpoint_service_id: the onion service id of the endpoint server
+/// @param endpoint_name: the null-terminated name of the endpoint server
+/// @param endpoint_name_length: the number of chars in endpoint_name string not including the
+///  null-terminator
+pub type GoslingEndpointServerDescriptorRefreshedCallback = Option<
+    extern "C" fn(
+        context: *mut GoslingContext,
+        endpoint_service_id: *const GoslingV3OnionServiceId,
+        endpoint_name: *const c_char,
+        endpoint_name_length: usize,
+    ) -> (),
+>;
+
+/// The function pointer type for the endpoint server connection shed callback. This
+/// callback is called whenever an endpoint server closes an incoming connection without
+/// beginning a handshake because its concurrency limit was reached; see
+/// gosling_context_set_endpoint_server_concurrency_limit().
+///
+/// @param context: the context associated with this event
+/// @param endpoint_service_id: the onion service id of the endpoint server
+pub type GoslingEndpointServerConnectionShedCallback = Option<
+    extern "C" fn(
+        context: *mut GoslingContext,
+        endpoint_service_id: *const GoslingV3OnionServiceId,
+    ) -> (),
+>;
+
 /// The function pointer type of the endpoint server handshake started callback. This
 /// callback is called whenever the endpoint server is initially connected to.
 ///
@@ -461,7 +911,7 @@ pub type GoslingEndpointServerChannelSupportedCallback = Option<
 /// @param channel_name_length: the number of chars in channel_name not including the
 ///  null-terminator
 /// @param stream: os-specific tcp socket handle associated with the connection to the
-///  endpoint client
+///  endpoint client, owned by the callee
 pub type GoslingEndpointServerHandshakeCompletedCallback = Option<
     extern "C" fn(
         context: *mut GoslingContext,
@@ -474,6 +924,60 @@ pub type GoslingEndpointServerHandshakeCompletedCallback = Option<
     ),
 >;
 
+/// The function pointer type for the endpoint server rpc channel opened callback.
+/// This callback is called instead of the endpoint server handshake completed
+/// callback when an endpoint server completes a handshake for one of the channels
+/// registered with gosling_context_add_rpc_channel().
+///
+/// @param context: the context associated with this event
+/// @param handshake_handle: the handshake handle this callback is associated with
+/// @param endpoint_service_id: the onion service id of the endpoint server the
+///  endpoint client has connected to
+/// @param client_service_id: the onion service id of the connected endpoint client
+/// @param channel_name: the null-terminated name of the channel requested by the client
+/// @param channel_name_length: the number of chars in channel_name not including the
+///  null-terminator
+/// @param channel: the opened rpc channel, owned by the callee; must be freed with
+///  gosling_rpc_channel_free()
+pub type GoslingEndpointServerRpcChannelOpenedCallback = Option<
+    extern "C" fn(
+        context: *mut GoslingContext,
+        handshake_handle: GoslingHandshakeHandle,
+        endpoint_service_id: *const GoslingV3OnionServiceId,
+        client_service_id: *const GoslingV3OnionServiceId,
+        channel_name: *const c_char,
+        channel_name_length: usize,
+        channel: *mut GoslingRpcChannel,
+    ),
+>;
+
+/// The function pointer type for the endpoint server datagram channel opened
+/// callback. This callback is called instead of the endpoint server handshake
+/// completed callback when an endpoint server completes a handshake for one of the
+/// channels registered with gosling_context_add_datagram_channel().
+///
+/// @param context: the context associated with this event
+/// @param handshake_handle: the handshake handle this callback is associated with
+/// @param endpoint_service_id: the onion service id of the endpoint server the
+///  endpoint client has connected to
+/// @param client_service_id: the onion service id of the connected endpoint client
+/// @param channel_name: the null-terminated name of the channel requested by the client
+/// @param channel_name_length: the number of chars in channel_name not including the
+///  null-terminator
+/// @param channel: the opened datagram channel, owned by the callee; must be freed
+///  with gosling_datagram_channel_free()
+pub type GoslingEndpointServerDatagramChannelOpenedCallback = Option<
+    extern "C" fn(
+        context: *mut GoslingContext,
+        handshake_handle: GoslingHandshakeHandle,
+        endpoint_service_id: *const GoslingV3OnionServiceId,
+        client_service_id: *const GoslingV3OnionServiceId,
+        channel_name: *const c_char,
+        channel_name_length: usize,
+        channel: *mut GoslingDatagramChannel,
+    ),
+>;
+
 /// The function pointer type of the endpoint server handshake rejected callback. This
 /// callback is called whenever the endpoint server has rejected an endpoint client's
 /// handshake.
@@ -513,7 +1017,7 @@ macro_rules! impl_callback_setter {
     ($callback_type:tt, $context:expr, $callback:expr, $error:expr) => {
         paste::paste! {
             translate_failures((), $error, || -> anyhow::Result<()> {
-                let mut context_tuple_registry = get_context_tuple_registry();
+                let mut context_tuple_registry = get_context_tuple_registry()?;
                 let context = match context_tuple_registry.get_mut($context as usize) {
                     Some(context) => context,
                     None => {
@@ -562,6 +1066,36 @@ pub extern "C" fn gosling_context_set_tor_bootstrap_completed_callback(
     impl_callback_setter!(tor_bootstrap_completed_callback, context, callback, error);
 }
 
+/// Set the tor provider changed callback for the specified context.
+///
+/// @param context: the context to register the callback to
+/// @param callback: the callback to register
+/// @param  error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_set_tor_provider_changed_callback(
+    context: *mut GoslingContext,
+    callback: GoslingTorProviderChangedCallback,
+    error: *mut *mut GoslingError,
+) {
+    impl_callback_setter!(tor_provider_changed_callback, context, callback, error);
+}
+
+/// Set the listener start failed callback for the specified context.
+///
+/// @param context: the context to register the callback to
+/// @param callback: the callback to register
+/// @param  error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_set_listener_start_failed_callback(
+    context: *mut GoslingContext,
+    callback: GoslingListenerStartFailedCallback,
+    error: *mut *mut GoslingError,
+) {
+    impl_callback_setter!(listener_start_failed_callback, context, callback, error);
+}
+
 /// Sets the tor log received callback for the specified context.
 ///
 /// @param context: the context to register the callback to
@@ -577,6 +1111,131 @@ pub extern "C" fn gosling_context_set_tor_log_received_callback(
     impl_callback_setter!(tor_log_received_callback, context, callback, error);
 }
 
+/// Sets the event queue overflowed callback for the specified context.
+///
+/// @param context: the context to register the callback to
+/// @param callback: the callback to register
+/// @param  error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_set_event_queue_overflowed_callback(
+    context: *mut GoslingContext,
+    callback: GoslingEventQueueOverflowedCallback,
+    error: *mut *mut GoslingError,
+) {
+    impl_callback_setter!(event_queue_overflowed_callback, context, callback, error);
+}
+
+/// Sets the tor log lines dropped callback for the specified context.
+///
+/// @param context: the context to register the callback to
+/// @param callback: the callback to register
+/// @param  error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_set_tor_log_lines_dropped_callback(
+    context: *mut GoslingContext,
+    callback: GoslingTorLogLinesDroppedCallback,
+    error: *mut *mut GoslingError,
+) {
+    impl_callback_setter!(tor_log_lines_dropped_callback, context, callback, error);
+}
+
+/// Sets the outbound handshake queued callback for the specified context.
+///
+/// @param context: the context to register the callback to
+/// @param callback: the callback to register
+/// @param  error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_set_outbound_handshake_queued_callback(
+    context: *mut GoslingContext,
+    callback: GoslingOutboundHandshakeQueuedCallback,
+    error: *mut *mut GoslingError,
+) {
+    impl_callback_setter!(outbound_handshake_queued_callback, context, callback, error);
+}
+
+/// Sets the outbound handshake started callback for the specified context.
+///
+/// @param context: the context to register the callback to
+/// @param callback: the callback to register
+/// @param  error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_set_outbound_handshake_started_callback(
+    context: *mut GoslingContext,
+    callback: GoslingOutboundHandshakeStartedCallback,
+    error: *mut *mut GoslingError,
+) {
+    impl_callback_setter!(
+        outbound_handshake_started_callback,
+        context,
+        callback,
+        error
+    );
+}
+
+/// Sets the client auth added callback for the specified context.
+///
+/// @param context: the context to register the callback to
+/// @param callback: the callback to register
+/// @param  error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_set_client_auth_added_callback(
+    context: *mut GoslingContext,
+    callback: GoslingClientAuthAddedCallback,
+    error: *mut *mut GoslingError,
+) {
+    impl_callback_setter!(client_auth_added_callback, context, callback, error);
+}
+
+/// Sets the client auth add failed callback for the specified context.
+///
+/// @param context: the context to register the callback to
+/// @param callback: the callback to register
+/// @param  error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_set_client_auth_add_failed_callback(
+    context: *mut GoslingContext,
+    callback: GoslingClientAuthAddFailedCallback,
+    error: *mut *mut GoslingError,
+) {
+    impl_callback_setter!(client_auth_add_failed_callback, context, callback, error);
+}
+
+/// Sets the client auth removed callback for the specified context.
+///
+/// @param context: the context to register the callback to
+/// @param callback: the callback to register
+/// @param  error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_set_client_auth_removed_callback(
+    context: *mut GoslingContext,
+    callback: GoslingClientAuthRemovedCallback,
+    error: *mut *mut GoslingError,
+) {
+    impl_callback_setter!(client_auth_removed_callback, context, callback, error);
+}
+
+/// Sets the client auth remove failed callback for the specified context.
+///
+/// @param context: the context to register the callback to
+/// @param callback: the callback to register
+/// @param  error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_set_client_auth_remove_failed_callback(
+    context: *mut GoslingContext,
+    callback: GoslingClientAuthRemoveFailedCallback,
+    error: *mut *mut GoslingError,
+) {
+    impl_callback_setter!(client_auth_remove_failed_callback, context, callback, error);
+}
+
 /// Sets the identity challenge challenge response size callback for the specified
 /// context
 ///
@@ -658,6 +1317,26 @@ pub extern "C" fn gosling_context_set_identity_client_handshake_failed_callback(
     );
 }
 
+/// Set the identity client endpoint pin mismatched callback for the specified context.
+///
+/// @param context: the context to register the callback to
+/// @param callback: the callback to register
+/// @param  error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_set_identity_client_endpoint_pin_mismatched_callback(
+    context: *mut GoslingContext,
+    callback: GoslingIdentityClientEndpointPinMismatchedCallback,
+    error: *mut *mut GoslingError,
+) {
+    impl_callback_setter!(
+        identity_client_endpoint_pin_mismatched_callback,
+        context,
+        callback,
+        error
+    );
+}
+
 /// Set the identity server published callback for the specified context.
 ///
 /// @param context: the context to register the callback to
@@ -673,6 +1352,26 @@ pub extern "C" fn gosling_context_set_identity_server_published_callback(
     impl_callback_setter!(identity_server_published_callback, context, callback, error);
 }
 
+/// Set the identity server descriptor refreshed callback for the specified context.
+///
+/// @param context: the context to register the callback to
+/// @param callback: the callback to register
+/// @param  error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_set_identity_server_descriptor_refreshed_callback(
+    context: *mut GoslingContext,
+    callback: GoslingIdentityServerDescriptorRefreshedCallback,
+    error: *mut *mut GoslingError,
+) {
+    impl_callback_setter!(
+        identity_server_descriptor_refreshed_callback,
+        context,
+        callback,
+        error
+    );
+}
+
 /// Set the identity server handshake started callback for the specified context.
 ///
 /// @param context: the context to register the callback to
@@ -693,6 +1392,128 @@ pub extern "C" fn gosling_context_set_identity_server_handshake_started_callback
     );
 }
 
+/// Sets the identity server client filter callback for the specified context. Unlike
+/// the other callbacks this callback is optional; when it is not set all clients
+/// proceed to the client allowed callback.
+///
+/// @param context: the context to register the callback to
+/// @param callback: the callback to register, or null to remove the filter
+/// @param  error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_set_identity_server_client_filter_callback(
+    context: *mut GoslingContext,
+    callback: GoslingIdentityServerClientFilterCallback,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        let context_handle = context as usize;
+        let mut context_tuple_registry = get_context_tuple_registry()?;
+        let context = match context_tuple_registry.get_mut(context_handle) {
+            Some(context) => context,
+            None => {
+                bail_invalid_handle!(context);
+            }
+        };
+
+        match callback {
+            Some(callback) => context.0.identity_server_set_client_filter(
+                move |client_service_id, requested_endpoint| {
+                    // callbacks are not called while this registry is held, but
+                    // fail closed if it somehow is
+                    let client_service_id = match get_v3_onion_service_id_registry() {
+                        Ok(mut registry) => registry.insert(client_service_id.clone()),
+                        Err(_) => return ClientFilterVerdict::Banned,
+                    };
+                    let requested_endpoint0 = CString::new(requested_endpoint).expect(
+                        "requested_endpoint should be a valid ASCII string and not have an intermediate null byte",
+                    );
+                    let verdict = callback(
+                        context_handle as *mut GoslingContext,
+                        client_service_id as *const GoslingV3OnionServiceId,
+                        requested_endpoint0.as_ptr(),
+                        requested_endpoint.len(),
+                    );
+                    if let Ok(mut registry) = get_v3_onion_service_id_registry() {
+                        registry.remove(client_service_id);
+                    }
+
+                    match verdict {
+                        CLIENT_FILTER_VERDICT_ALLOW => ClientFilterVerdict::Allow,
+                        CLIENT_FILTER_VERDICT_TOO_MANY_REQUESTS => {
+                            ClientFilterVerdict::TooManyRequests
+                        }
+                        _ => ClientFilterVerdict::Banned,
+                    }
+                },
+            ),
+            None => context.0.identity_server_clear_client_filter(),
+        }
+        Ok(())
+    })
+}
+
+/// Sets the identity client endpoint validator callback for the specified context.
+/// Unlike the other callbacks this callback is optional; when it is not set all
+/// endpoint names are requested from identity servers.
+///
+/// @param context: the context to register the callback to
+/// @param callback: the callback to register, or null to remove the validator
+/// @param  error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_set_identity_client_endpoint_validator_callback(
+    context: *mut GoslingContext,
+    callback: GoslingIdentityClientEndpointValidatorCallback,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        let context_handle = context as usize;
+        let mut context_tuple_registry = get_context_tuple_registry()?;
+        let context = match context_tuple_registry.get_mut(context_handle) {
+            Some(context) => context,
+            None => {
+                bail_invalid_handle!(context);
+            }
+        };
+
+        match callback {
+            Some(callback) => context.0.identity_client_set_endpoint_validator(
+                move |identity_service_id, endpoint_name| {
+                    // callbacks are not called while this registry is held, but
+                    // fail closed if it somehow is
+                    let identity_service_id = match get_v3_onion_service_id_registry() {
+                        Ok(mut registry) => registry.insert(identity_service_id.clone()),
+                        Err(_) => return EndpointValidatorVerdict::Unsupported,
+                    };
+                    let endpoint_name0 = CString::new(endpoint_name).expect(
+                        "endpoint_name should be a valid ASCII string and not have an intermediate null byte",
+                    );
+                    let verdict = callback(
+                        context_handle as *mut GoslingContext,
+                        identity_service_id as *const GoslingV3OnionServiceId,
+                        endpoint_name0.as_ptr(),
+                        endpoint_name.len(),
+                    );
+                    if let Ok(mut registry) = get_v3_onion_service_id_registry() {
+                        registry.remove(identity_service_id);
+                    }
+
+                    match verdict {
+                        ENDPOINT_VALIDATOR_VERDICT_ALLOW => EndpointValidatorVerdict::Allow,
+                        ENDPOINT_VALIDATOR_VERDICT_UNSUPPORTED => {
+                            EndpointValidatorVerdict::Unsupported
+                        }
+                        _ => EndpointValidatorVerdict::Malformed,
+                    }
+                },
+            ),
+            None => context.0.identity_client_clear_endpoint_validator(),
+        }
+        Ok(())
+    })
+}
+
 /// Sets the identity server client allowed callback for the specified context.
 ///
 /// @param context: the context to register the callback to
@@ -733,6 +1554,26 @@ pub extern "C" fn gosling_context_set_identity_server_endpoint_supported_callbac
     );
 }
 
+/// Sets the identity server contact request received callback for the specified context.
+///
+/// @param context: the context to register the callback to
+/// @param callback: the callback to register
+/// @param  error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_set_identity_server_contact_request_received_callback(
+    context: *mut GoslingContext,
+    callback: GoslingIdentityServerContactRequestReceivedCallback,
+    error: *mut *mut GoslingError,
+) {
+    impl_callback_setter!(
+        identity_server_contact_request_received_callback,
+        context,
+        callback,
+        error
+    );
+}
+
 /// Sets the identity server challenge size callback for the specified context.
 ///
 /// @param context: the context to register the callback to
@@ -873,6 +1714,46 @@ pub extern "C" fn gosling_context_set_endpoint_client_handshake_completed_callba
     );
 }
 
+/// Set the endpoint client rpc channel opened callback for the specified context.
+///
+/// @param context: the context to register the callback to
+/// @param callback: the callback to register
+/// @param  error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_set_endpoint_client_rpc_channel_opened_callback(
+    context: *mut GoslingContext,
+    callback: GoslingEndpointClientRpcChannelOpenedCallback,
+    error: *mut *mut GoslingError,
+) {
+    impl_callback_setter!(
+        endpoint_client_rpc_channel_opened_callback,
+        context,
+        callback,
+        error
+    );
+}
+
+/// Set the endpoint client datagram channel opened callback for the specified context.
+///
+/// @param context: the context to register the callback to
+/// @param callback: the callback to register
+/// @param  error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_set_endpoint_client_datagram_channel_opened_callback(
+    context: *mut GoslingContext,
+    callback: GoslingEndpointClientDatagramChannelOpenedCallback,
+    error: *mut *mut GoslingError,
+) {
+    impl_callback_setter!(
+        endpoint_client_datagram_channel_opened_callback,
+        context,
+        callback,
+        error
+    );
+}
+
 /// Set the endpoint client handshake failed callback for the specified context.
 ///
 /// @param context: the context to register the callback to
@@ -893,6 +1774,26 @@ pub extern "C" fn gosling_context_set_endpoint_client_handshake_failed_callback(
     );
 }
 
+/// Set the endpoint client endpoint revoked callback for the specified context.
+///
+/// @param context: the context to register the callback to
+/// @param callback: the callback to register
+/// @param  error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_set_endpoint_client_endpoint_revoked_callback(
+    context: *mut GoslingContext,
+    callback: GoslingEndpointClientEndpointRevokedCallback,
+    error: *mut *mut GoslingError,
+) {
+    impl_callback_setter!(
+        endpoint_client_endpoint_revoked_callback,
+        context,
+        callback,
+        error
+    );
+}
+
 /// Set the endpoint server published callback for the specified context.
 ///
 /// @param context: the context to register the callback to
@@ -908,6 +1809,86 @@ pub extern "C" fn gosling_context_set_endpoint_server_published_callback(
     impl_callback_setter!(endpoint_server_published_callback, context, callback, error);
 }
 
+/// Set the endpoint server publish completed callback for the specified context.
+///
+/// @param context: the context to register the callback to
+/// @param callback: the callback to register
+/// @param  error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_set_endpoint_server_publish_completed_callback(
+    context: *mut GoslingContext,
+    callback: GoslingEndpointServerPublishCompletedCallback,
+    error: *mut *mut GoslingError,
+) {
+    impl_callback_setter!(
+        endpoint_server_publish_completed_callback,
+        context,
+        callback,
+        error
+    );
+}
+
+/// Set the endpoint server publish timed out callback for the specified context.
+///
+/// @param context: the context to register the callback to
+/// @param callback: the callback to register
+/// @param  error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_set_endpoint_server_publish_timed_out_callback(
+    context: *mut GoslingContext,
+    callback: GoslingEndpointServerPublishTimedOutCallback,
+    error: *mut *mut GoslingError,
+) {
+    impl_callback_setter!(
+        endpoint_server_publish_timed_out_callback,
+        context,
+        callback,
+        error
+    );
+}
+
+/// Set the endpoint server descriptor refreshed callback for the specified context.
+///
+/// @param context: the context to register the callback to
+/// @param callback: the callback to register
+/// @param  error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_set_endpoint_server_descriptor_refreshed_callback(
+    context: *mut GoslingContext,
+    callback: GoslingEndpointServerDescriptorRefreshedCallback,
+    error: *mut *mut GoslingError,
+) {
+    impl_callback_setter!(
+        endpoint_server_descriptor_refreshed_callback,
+        context,
+        callback,
+        error
+    );
+}
+
+/// Set the endpoint server connection shed callback for the specified context.
+///
+/// @param context: the context to register the callback to
+/// @param callback: the callback to register
+/// @param  error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_set_endpoint_server_connection_shed_callback(
+    context: *mut GoslingContext,
+    callback: GoslingEndpointServerConnectionShedCallback,
+    error: *mut *mut GoslingError,
+) {
+    impl_callback_setter!(
+        endpoint_server_connection_shed_callback,
+        context,
+        callback,
+        error
+    );
+}
+
 /// Set the endpoint server handshake started callback for the specified context.
 ///
 /// @param context: the context to register the callback to
@@ -968,6 +1949,46 @@ pub extern "C" fn gosling_context_set_endpoint_server_handshake_completed_callba
     );
 }
 
+/// Set the endpoint server rpc channel opened callback for the specified context.
+///
+/// @param context: the context to register the callback to
+/// @param callback: the callback to register
+/// @param  error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_set_endpoint_server_rpc_channel_opened_callback(
+    context: *mut GoslingContext,
+    callback: GoslingEndpointServerRpcChannelOpenedCallback,
+    error: *mut *mut GoslingError,
+) {
+    impl_callback_setter!(
+        endpoint_server_rpc_channel_opened_callback,
+        context,
+        callback,
+        error
+    );
+}
+
+/// Set the endpoint server datagram channel opened callback for the specified context.
+///
+/// @param context: the context to register the callback to
+/// @param callback: the callback to register
+/// @param  error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_set_endpoint_server_datagram_channel_opened_callback(
+    context: *mut GoslingContext,
+    callback: GoslingEndpointServerDatagramChannelOpenedCallback,
+    error: *mut *mut GoslingError,
+) {
+    impl_callback_setter!(
+        endpoint_server_datagram_channel_opened_callback,
+        context,
+        callback,
+        error
+    );
+}
+
 /// Set the endpoint server channel request completed callback for the specified context.
 ///
 /// @param context: the context to register the callback to
@@ -1,12 +1,15 @@
 // standard
+use std::cell::RefCell;
 use std::collections::VecDeque;
 use std::ffi::CString;
 use std::io::Cursor;
+use std::net::TcpStream;
 use std::os::raw::c_char;
 #[cfg(unix)]
 use std::os::unix::io::{IntoRawFd, RawFd};
 #[cfg(windows)]
 use std::os::windows::io::{IntoRawSocket, RawSocket};
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
 use std::time::Duration;
 
 // extern crates
@@ -14,17 +17,155 @@ use anyhow::anyhow;
 use anyhow::bail;
 #[cfg(feature = "impl-lib")]
 use cgosling_proc_macros::*;
+use gosling::ascii_string::AsciiString;
+use gosling::bans::BanList;
+use gosling::channel_quota::ChannelQuota;
 use gosling::context::*;
+use gosling::endpoint_revocation::EndpointRevocation;
+use gosling::petnames::PetnameStore;
+use gosling::pinning::PinStore;
+use gosling::protocol::Capabilities;
 use tor_interface::tor_crypto::*;
+use tor_interface::tor_provider::PowDefenses;
 
 // internal
 use crate::callbacks::*;
 use crate::crypto::*;
+use crate::datagram_channel::*;
+use crate::endpoint_grant::*;
 use crate::error::Error;
 use crate::error::*;
 use crate::ffi::*;
 use crate::macros::*;
+use crate::object_registry::ReentrantCall;
+use crate::rpc_channel::*;
+use crate::stream::*;
 use crate::tor_provider::*;
+use crate::utils::GoslingCircuitToken;
+
+/// Overflow policy which discards the oldest lossy events; see
+/// gosling_context_set_event_queue_capacity()
+pub const EVENT_QUEUE_OVERFLOW_POLICY_DROP_OLDEST: u32 = 0;
+/// Overflow policy which merges tor logs before discarding the oldest lossy events; see
+/// gosling_context_set_event_queue_capacity()
+pub const EVENT_QUEUE_OVERFLOW_POLICY_COALESCE_LOGS: u32 = 1;
+/// Overflow policy which discards the newest lossy events and fails; see
+/// gosling_context_set_event_queue_capacity()
+pub const EVENT_QUEUE_OVERFLOW_POLICY_ERROR: u32 = 2;
+
+/// Pending-connection drop policy which closes the longest-waiting connection; see
+/// gosling_context_set_endpoint_server_concurrency_limit()
+pub const PENDING_CONNECTION_DROP_POLICY_DROP_OLDEST: u32 = 0;
+/// Pending-connection drop policy which closes the newly accepted connection; see
+/// gosling_context_set_endpoint_server_concurrency_limit()
+pub const PENDING_CONNECTION_DROP_POLICY_DROP_NEWEST: u32 = 1;
+
+/// Tor event verbosity which returns only bootstrap, log and onion service events; see
+/// gosling_context_set_tor_event_verbosity()
+pub const TOR_EVENT_VERBOSITY_NORMAL: u32 = 0;
+/// Tor event verbosity which additionally returns circuit, stream and descriptor upload
+/// events; see
+/// gosling_context_set_tor_event_verbosity()
+pub const TOR_EVENT_VERBOSITY_VERBOSE: u32 = 1;
+
+/// Client auth retention which keeps client authorization keys until they are removed with
+/// gosling_context_remove_client_auth(); see gosling_context_set_client_auth_retention()
+pub const CLIENT_AUTH_RETENTION_RETAIN: u32 = 0;
+/// Client auth retention which removes an endpoint server's client authorization key once a
+/// handshake with it fails; see gosling_context_set_client_auth_retention()
+pub const CLIENT_AUTH_RETENTION_REMOVE_ON_FAILURE: u32 = 1;
+/// Client auth retention which removes an endpoint server's client authorization key once a
+/// handshake with it finishes; see gosling_context_set_client_auth_retention()
+pub const CLIENT_AUTH_RETENTION_REMOVE_ON_COMPLETION: u32 = 2;
+
+/// Capability flag for continuing with an endpoint handshake over an identity handshake's
+/// connection; see gosling_context_set_capabilities()
+pub const CAPABILITY_ENDPOINT_UPGRADE: u32 = 1 << 0;
+/// Capability flag for requesting several endpoints in one identity handshake; see
+/// gosling_context_set_capabilities()
+pub const CAPABILITY_ADDITIONAL_ENDPOINTS: u32 = 1 << 1;
+/// Capability flag for attaching a contact request to an identity handshake's endpoint
+/// request; see gosling_context_set_capabilities()
+pub const CAPABILITY_CONTACT_REQUEST: u32 = 1 << 2;
+/// Capability flag for endpoint revocations; see gosling_context_set_capabilities()
+pub const CAPABILITY_ENDPOINT_REVOCATION: u32 = 1 << 3;
+/// Capability flag for keeping identity handshakes' connections open for follow-up handshakes;
+/// see gosling_context_set_identity_session_keep_open()
+pub const CAPABILITY_KEEP_OPEN: u32 = 1 << 4;
+
+/// The maximum length in bytes of an endpoint name; longer names fail with
+/// ERROR_CODE_NAME_TOO_LONG
+pub const MAX_ENDPOINT_NAME_LENGTH: usize = 255;
+/// The maximum length in bytes of a channel name; longer names fail with
+/// ERROR_CODE_NAME_TOO_LONG
+pub const MAX_CHANNEL_NAME_LENGTH: usize = 255;
+
+/// An endpoint client exceeded the maximum number of channels it may have open at once; see
+/// gosling_context_set_endpoint_server_channel_quota()
+pub const CHANNEL_QUOTA_OPEN_CHANNELS: u32 = 0;
+/// An endpoint client exceeded the maximum number of channels it may open within the time
+/// window; see gosling_context_set_endpoint_server_channel_quota()
+pub const CHANNEL_QUOTA_CHANNEL_REQUESTS: u32 = 1;
+
+/// A connectivity check was not attempted because the tor provider had not bootstrapped; see
+/// gosling_context_check_connectivity()
+pub const DESCRIPTOR_FETCH_NOT_ATTEMPTED: u32 = 0;
+/// A connectivity check received the identity server's descriptor; tor can reach the tor network
+/// and the identity server is published
+pub const DESCRIPTOR_FETCH_RECEIVED: u32 = 1;
+/// A hidden-service directory answered a connectivity check without the identity server's
+/// descriptor; tor can reach the tor network but the identity server is not (yet) published
+pub const DESCRIPTOR_FETCH_NOT_FOUND: u32 = 2;
+/// A connectivity check's descriptor fetch failed, e.g. because no hidden-service directory could
+/// be reached
+pub const DESCRIPTOR_FETCH_FAILED: u32 = 3;
+/// A connectivity check's descriptor fetch did not finish before its timeout
+pub const DESCRIPTOR_FETCH_TIMED_OUT: u32 = 4;
+
+/// A SOCKS request gave an IP address rather than a hostname, so the application may have
+/// resolved the hostname outside of the tor network; see
+/// gosling_tor_provider_config_set_socks_policy()
+pub const SOCKS_POLICY_VIOLATION_UNSAFE_ADDRESS: u32 = 0;
+/// A stream was requested to a port commonly used by plaintext protocols; see
+/// gosling_tor_provider_config_set_socks_policy()
+pub const SOCKS_POLICY_VIOLATION_PLAINTEXT_PORT: u32 = 1;
+/// A SOCKS request gave an invalid hostname
+pub const SOCKS_POLICY_VIOLATION_INVALID_HOSTNAME: u32 = 2;
+/// A connection to the tor provider's SOCKS port did not speak a SOCKS protocol
+pub const SOCKS_POLICY_VIOLATION_UNKNOWN_PROTOCOL: u32 = 3;
+
+/// Returned by gosling_context_get_next_deadline() when the context is idle
+pub const NEXT_DEADLINE_NONE: u32 = u32::MAX;
+
+/// Index of the number of endpoint requests received; see
+/// gosling_context_get_identity_server_endpoint_stats()
+pub const ENDPOINT_REQUEST_STAT_REQUESTS: usize = 0;
+/// Index of the number of handshakes which granted the endpoint; see
+/// gosling_context_get_identity_server_endpoint_stats()
+pub const ENDPOINT_REQUEST_STAT_GRANTS: usize = 1;
+/// Index of the number of handshakes which were rejected; see
+/// gosling_context_get_identity_server_endpoint_stats()
+pub const ENDPOINT_REQUEST_STAT_REJECTIONS: usize = 2;
+/// Index of the number of rejected handshakes whose client was not allowed; see
+/// gosling_context_get_identity_server_endpoint_stats()
+pub const ENDPOINT_REQUEST_STAT_CLIENT_NOT_ALLOWED: usize = 3;
+/// Index of the number of rejected handshakes whose requested endpoint was invalid; see
+/// gosling_context_get_identity_server_endpoint_stats()
+pub const ENDPOINT_REQUEST_STAT_REQUESTED_ENDPOINT_INVALID: usize = 4;
+/// Index of the number of rejected handshakes whose client proof signature was invalid; see
+/// gosling_context_get_identity_server_endpoint_stats()
+pub const ENDPOINT_REQUEST_STAT_CLIENT_PROOF_SIGNATURE_INVALID: usize = 5;
+/// Index of the number of rejected handshakes whose client authorization signature was
+/// invalid; see gosling_context_get_identity_server_endpoint_stats()
+pub const ENDPOINT_REQUEST_STAT_CLIENT_AUTH_SIGNATURE_INVALID: usize = 6;
+/// Index of the number of rejected handshakes whose challenge response was invalid; see
+/// gosling_context_get_identity_server_endpoint_stats()
+pub const ENDPOINT_REQUEST_STAT_CHALLENGE_RESPONSE_INVALID: usize = 7;
+/// Index of the number of handshakes which failed or were aborted after the endpoint was
+/// requested; see gosling_context_get_identity_server_endpoint_stats()
+pub const ENDPOINT_REQUEST_STAT_FAILURES: usize = 8;
+/// The number of ENDPOINT_REQUEST_STAT_* constants
+pub const ENDPOINT_REQUEST_STAT_COUNT: usize = 9;
 
 // empty bson document layout:
 // {
@@ -35,20 +176,116 @@ use crate::tor_provider::*;
 // };
 const SMALLEST_BSON_DOC_SIZE: usize = 5;
 
-/// A handle for an in-progress identity handhskae
+/// A handle for an in-progress identity or endpoint handshake. Handles are allocated
+/// by each context and are only unique within the context which allocated them, so
+/// every callback which receives a handle also receives its context.
 pub type GoslingHandshakeHandle = usize;
+/// A handle for an endpoint server started with
+/// gosling_context_start_endpoint_server_with_completion() which is waiting to be
+/// published. Handles are allocated by each context and are only unique within the
+/// context which allocated them.
+pub type GoslingPublishHandle = usize;
 #[cfg(any(target_os = "linux", target_os = "macos"))]
 /// A native TCP socket handle
 pub type GoslingTcpSocket = RawFd;
 #[cfg(any(target_os = "windows"))]
 /// A native TCP socket handle
 pub type GoslingTcpSocket = RawSocket;
-/// A context object associated with a single peer identity
+/// A context object associated with a single peer identity.
+///
+/// The gosling_context_* functions may be called from any thread, and a context may
+/// be used from several threads at once; calls are serialised internally. Each
+/// context's events are dispatched by one thread at a time, so its callbacks are
+/// called in order and never concurrently: a thread calling
+/// gosling_context_poll_events() or gosling_context_next_event() while another
+/// thread dispatches the same context's events waits for it to finish. Callbacks
+/// may call gosling functions, with two exceptions which fail with
+/// ERROR_CODE_REENTRANT_CALL rather than deadlock:
+/// - the identity server client filter and identity client endpoint validator
+///   callbacks are called while the context is locked, and must not call any
+///   gosling_context_* functions
+/// - callbacks must not poll their own context's events
+///
+/// The gosling_*_free() functions may be called from any callback; an object freed
+/// while the function which called the callback is still using objects of its type
+/// is released once that function returns.
+///
+/// Callbacks must not wait for another thread which is polling the same context.
 pub struct GoslingContext;
 /// cbindgen:ignore
-type ContextTuple = (Context, EventCallbacks, Option<VecDeque<ContextEvent>>);
+type ContextTuple = (
+    Context,
+    EventCallbacks,
+    Option<VecDeque<ContextEvent>>,
+    DispatchState,
+);
 define_registry! {ContextTuple}
 
+// whether a thread is dispatching a context's events, and a condition variable
+// signalled when it finishes
+type DispatchState = Arc<(Mutex<bool>, Condvar)>;
+
+std::thread_local! {
+    // the contexts whose events this thread is dispatching
+    static DISPATCHING_CONTEXTS: RefCell<Vec<usize>> = const { RefCell::new(Vec::new()) };
+}
+
+// Held while a thread dispatches a context's events, so that each context's events
+// are dispatched by one thread at a time and callbacks are called in order
+pub(crate) struct DispatchGuard {
+    context: usize,
+    dispatching: DispatchState,
+}
+
+impl DispatchGuard {
+    // wait for any other thread dispatching context's events to finish; fails if
+    // this thread is already dispatching them, i.e. from within one of the
+    // context's callbacks
+    pub fn begin(context: *mut GoslingContext) -> anyhow::Result<Self> {
+        let context = context as usize;
+        if DISPATCHING_CONTEXTS.with(|contexts| contexts.borrow().contains(&context)) {
+            return Err(ReentrantCall(
+                "gosling_context_poll_events() and gosling_context_next_event() must not be called from within the same context's callbacks".to_string(),
+            )
+            .into());
+        }
+
+        // the registry is not held while waiting, so the dispatching thread's
+        // callbacks may still use the context
+        let dispatching = match get_context_tuple_registry()?.get(context) {
+            Some(context) => context.3.clone(),
+            None => bail_invalid_handle!(context),
+        };
+        {
+            let (busy, idle) = &*dispatching;
+            let mut busy = busy.lock().unwrap_or_else(PoisonError::into_inner);
+            while *busy {
+                busy = idle.wait(busy).unwrap_or_else(PoisonError::into_inner);
+            }
+            *busy = true;
+        }
+        DISPATCHING_CONTEXTS.with(|contexts| contexts.borrow_mut().push(context));
+
+        Ok(Self {
+            context,
+            dispatching,
+        })
+    }
+}
+
+impl Drop for DispatchGuard {
+    fn drop(&mut self) {
+        DISPATCHING_CONTEXTS.with(|contexts| {
+            contexts
+                .borrow_mut()
+                .retain(|context| *context != self.context)
+        });
+        let (busy, idle) = &*self.dispatching;
+        *busy.lock().unwrap_or_else(PoisonError::into_inner) = false;
+        idle.notify_one();
+    }
+}
+
 /// Frees a gosling_context object
 ///
 /// @param in_context: the context object to free
@@ -63,8 +300,10 @@ pub extern "C" fn gosling_context_free(in_context: *mut GoslingContext) {
 /// @param out_context: returned initialied gosling context
 /// @param in_tor_provider: the tor client implementation to use; this function consumes the tor_provider
 ///  and it may not be re-used in subsequent gosling_* calls, and it does not need to be freed
-/// @param identity_port: the tor virtual port the identity server listens on
-/// @param endpoint_port: the tor virtual port endpoint servers listen on
+/// @param identity_port: the tor virtual port the identity server listens on, or 0 to derive it
+///  from the identity server's service id
+/// @param endpoint_port: the tor virtual port endpoint servers listen on, or 0 to derive each
+///  endpoint server's port from its service id; peers must agree on whether ports are derived
 /// @param identity_private_key: the e25519 private key used to start th identity server's onion service
 /// @param error: filled on error
 #[no_mangle]
@@ -81,21 +320,19 @@ pub unsafe extern "C" fn gosling_context_init(
     translate_failures((), error, || -> anyhow::Result<()> {
         ensure_not_null!(out_context);
         ensure_not_null!(in_tor_provider);
-        ensure_not_equal!(identity_port, 0);
-        ensure_not_equal!(endpoint_port, 0);
         ensure_not_null!(identity_private_key);
 
         // get our tor provider
-        let tor_provider = match get_tor_provider_registry().remove(in_tor_provider as usize) {
+        let tor_provider = match get_tor_provider_registry()?.remove(in_tor_provider as usize) {
             Some(tor_provider) => tor_provider,
             None => bail_invalid_handle!(tor_provider),
         };
 
-        // get our identity key
-        let ed25519_private_key_registry = get_ed25519_private_key_registry();
+        // get our identity key; the key registry is released before the context
+        // registry is locked, as other threads may lock them in the opposite order
         let identity_private_key =
-            match ed25519_private_key_registry.get(identity_private_key as usize) {
-                Some(identity_private_key) => identity_private_key,
+            match get_ed25519_private_key_registry()?.get(identity_private_key as usize) {
+                Some(identity_private_key) => identity_private_key.clone(),
                 None => bail_invalid_handle!(identity_private_key),
             };
 
@@ -107,10 +344,15 @@ pub unsafe extern "C" fn gosling_context_init(
             Duration::from_secs(60),
             4096,
             Some(Duration::from_secs(60)),
-            identity_private_key.clone(),
+            identity_private_key,
         )?;
 
-        let handle = get_context_tuple_registry().insert((context, Default::default(), None));
+        let handle = get_context_tuple_registry()?.try_insert((
+            context,
+            Default::default(),
+            None,
+            Default::default(),
+        ))?;
         *out_context = handle as *mut GoslingContext;
 
         Ok(())
@@ -130,7 +372,7 @@ pub extern "C" fn gosling_context_bootstrap_tor(
     translate_failures((), error, || -> anyhow::Result<()> {
         ensure_not_null!(context);
 
-        let mut context_tuple_registry = get_context_tuple_registry();
+        let mut context_tuple_registry = get_context_tuple_registry()?;
         let context = match context_tuple_registry.get_mut(context as usize) {
             Some(context) => context,
             None => bail_invalid_handle!(context),
@@ -139,6 +381,76 @@ pub extern "C" fn gosling_context_bootstrap_tor(
     });
 }
 
+/// Check whether the context's tor provider can reach the tor network, e.g. to tell whether a
+/// peer which cannot be connected to is offline or the context's own connectivity is broken. The
+/// check fetches the identity server's descriptor from the hidden-service directories without
+/// contacting any peer, and so works whether or not the identity server is started. Its results
+/// are returned by gosling_context_next_event() as an EVENT_TYPE_CONNECTIVITY_CHECKED event once
+/// the fetch has finished or timed out. Only one check may be in progress at a time.
+///
+/// @param context: the context whose connectivity to check
+/// @param timeout_milliseconds: how long to wait for the descriptor fetch to finish
+/// @param error: filled on error, e.g. if the tor provider cannot fetch descriptors
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_check_connectivity(
+    context: *mut GoslingContext,
+    timeout_milliseconds: u64,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(context);
+
+        let mut context_tuple_registry = get_context_tuple_registry()?;
+        let context = match context_tuple_registry.get_mut(context as usize) {
+            Some(context) => context,
+            None => bail_invalid_handle!(context),
+        };
+        Ok(context
+            .0
+            .check_connectivity(Duration::from_millis(timeout_milliseconds))?)
+    });
+}
+
+/// Switch a gosling_context to another tor provider while keeping its servers, client
+/// authorization keys and configuration. The switch is made by gosling_context_poll_events()
+/// once in-progress handshakes have finished; until then no new incoming connections are
+/// accepted and new outgoing handshakes are queued. The new tor provider is bootstrapped if
+/// gosling_context_bootstrap_tor() has been called, after which the identity server and
+/// endpoint servers which were running are restarted, client authorization keys are re-added
+/// and the tor provider changed event is emitted.
+///
+/// @param context: the gosling context whose tor provider to replace
+/// @param in_tor_provider: the tor client implementation to switch to; this function consumes
+///  the tor_provider and it may not be re-used in subsequent gosling_* calls, and it does not
+///  need to be freed
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_set_tor_provider(
+    context: *mut GoslingContext,
+    in_tor_provider: *mut GoslingTorProvider,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(context);
+        ensure_not_null!(in_tor_provider);
+
+        let mut context_tuple_registry = get_context_tuple_registry()?;
+        let context = match context_tuple_registry.get_mut(context as usize) {
+            Some(context) => context,
+            None => bail_invalid_handle!(context),
+        };
+
+        let tor_provider = match get_tor_provider_registry()?.remove(in_tor_provider as usize) {
+            Some(tor_provider) => tor_provider,
+            None => bail_invalid_handle!(tor_provider),
+        };
+
+        Ok(context.0.set_tor_provider(tor_provider)?)
+    });
+}
+
 /// Start the identity server so that clients may request endpoints
 ///
 /// @param context: the gosling context whose identity server to start
@@ -152,7 +464,7 @@ pub extern "C" fn gosling_context_start_identity_server(
     translate_failures((), error, || -> anyhow::Result<()> {
         ensure_not_null!(context);
 
-        let mut context_tuple_registry = get_context_tuple_registry();
+        let mut context_tuple_registry = get_context_tuple_registry()?;
         let context = match context_tuple_registry.get_mut(context as usize) {
             Some(context) => context,
             None => bail_invalid_handle!(context),
@@ -174,7 +486,7 @@ pub extern "C" fn gosling_context_stop_identity_server(
     translate_failures((), error, || -> anyhow::Result<()> {
         ensure_not_null!(context);
 
-        let mut context_tuple_registry = get_context_tuple_registry();
+        let mut context_tuple_registry = get_context_tuple_registry()?;
         let context = match context_tuple_registry.get_mut(context as usize) {
             Some(context) => context,
             None => bail_invalid_handle!(context),
@@ -183,19 +495,45 @@ pub extern "C" fn gosling_context_stop_identity_server(
     });
 }
 
+/// Force the tor provider to build and publish a fresh onion-service descriptor for the running
+/// identity server without stopping it. The identity server descriptor refreshed callback is
+/// called once the refreshed descriptor has been published, or the identity server published
+/// callback if the identity server had not yet been published.
+///
+/// @param context: the gosling context whose identity server descriptor to refresh
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_refresh_identity_server_descriptor(
+    context: *mut GoslingContext,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(context);
+
+        let mut context_tuple_registry = get_context_tuple_registry()?;
+        let context = match context_tuple_registry.get_mut(context as usize) {
+            Some(context) => context,
+            None => bail_invalid_handle!(context),
+        };
+        Ok(context.0.identity_server_refresh_descriptor()?)
+    });
+}
+
 /// Start an endpoint server so the confirmed contact may connect
 ///
 /// @param context: the gosling context with the given endpoint to start
 /// @param endpoint_private_key: the ed25519 private key needed to start the endpoint
 ///  onion service
 /// @param endpoint_name: the ascii-encoded name of the endpoint server
-/// @param endpoint_name_length: the number of chars in endpoint name not including any null-terminator
+/// @param endpoint_name_length: the number of chars in endpoint name not including any null-terminator,
+///  or 0 if endpoint_name is null-terminated
 /// @param client_identity: the v3 onion service id of the gosling client associated with this endpoint
 /// @param client_auth_public_key: the x25519 public key used to encrypt the onion service descriptor
 /// @param error: filled on error
 #[no_mangle]
 #[cfg_attr(feature = "impl-lib", rename_impl)]
-pub extern "C" fn gosling_context_start_endpoint_server(
+pub unsafe extern "C" fn gosling_context_start_endpoint_server(
     context: *mut GoslingContext,
     endpoint_private_key: *const GoslingEd25519PrivateKey,
     endpoint_name: *const c_char,
@@ -208,37 +546,34 @@ pub extern "C" fn gosling_context_start_endpoint_server(
         ensure_not_null!(context);
         ensure_not_null!(endpoint_private_key);
         ensure_not_null!(endpoint_name);
-        ensure_not_equal!(endpoint_name_length, 0);
         ensure_not_null!(client_identity);
         ensure_not_null!(client_auth_public_key);
 
-        let mut context_tuple_registry = get_context_tuple_registry();
+        let mut context_tuple_registry = get_context_tuple_registry()?;
         let context = match context_tuple_registry.get_mut(context as usize) {
             Some(context) => context,
             None => bail_invalid_handle!(context),
         };
 
         let endpoint_name =
-            unsafe { std::slice::from_raw_parts(endpoint_name as *const u8, endpoint_name_length) };
-        let endpoint_name = std::str::from_utf8(endpoint_name)?.to_string();
-        if !endpoint_name.is_ascii() {
-            bail!("endpoint_name must be an ascii string");
-        }
+            ascii_str_from_ffi(endpoint_name, endpoint_name_length, "endpoint_name")?;
+        ensure_not_empty!(endpoint_name);
+        let endpoint_name: AsciiString = endpoint_name.parse()?;
 
-        let ed25519_private_key_registry = get_ed25519_private_key_registry();
+        let ed25519_private_key_registry = get_ed25519_private_key_registry()?;
         let endpoint_private_key =
             match ed25519_private_key_registry.get(endpoint_private_key as usize) {
                 Some(ed25519_private_key) => ed25519_private_key,
                 None => bail_invalid_handle!(endpoint_private_key),
             };
 
-        let v3_onion_service_id_registry = get_v3_onion_service_id_registry();
+        let v3_onion_service_id_registry = get_v3_onion_service_id_registry()?;
         let client_identity = match v3_onion_service_id_registry.get(client_identity as usize) {
             Some(v3_onion_service_id) => v3_onion_service_id,
             None => bail_invalid_handle!(client_identity),
         };
 
-        let x25519_public_key_registry = get_x25519_public_key_registry();
+        let x25519_public_key_registry = get_x25519_public_key_registry()?;
         let client_auth_public_key =
             match x25519_public_key_registry.get(client_auth_public_key as usize) {
                 Some(x25519_public_key) => x25519_public_key,
@@ -250,6 +585,161 @@ pub extern "C" fn gosling_context_start_endpoint_server(
             endpoint_name,
             client_identity.clone(),
             client_auth_public_key.clone(),
+            false,
+        )?)
+    });
+}
+
+/// Start an endpoint server so the confirmed contact may connect, as with
+/// gosling_context_start_endpoint_server(), additionally tracking its publication. The
+/// endpoint server publish completed callback is called with the returned handle after the
+/// endpoint server published callback, or the endpoint server publish timed out callback is
+/// called if the endpoint server has not been published within the timeout. Stopping the
+/// endpoint server before either callback discards its completion.
+///
+/// @param context: the gosling context with the given endpoint to start
+/// @param endpoint_private_key: the ed25519 private key needed to start the endpoint
+///  onion service
+/// @param endpoint_name: the ascii-encoded name of the endpoint server
+/// @param endpoint_name_length: the number of chars in endpoint name not including any null-terminator,
+///  or 0 if endpoint_name is null-terminated
+/// @param client_identity: the v3 onion service id of the gosling client associated with this endpoint
+/// @param client_auth_public_key: the x25519 public key used to encrypt the onion service descriptor
+/// @param publish_timeout_milliseconds: the number of milliseconds the endpoint server has to be
+///  published, or 0 for no limit
+/// @param error: filled on error
+/// @return the handle passed to the endpoint server's publish callbacks and events; or !0
+///  (SIZE_MAX) on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_context_start_endpoint_server_with_completion(
+    context: *mut GoslingContext,
+    endpoint_private_key: *const GoslingEd25519PrivateKey,
+    endpoint_name: *const c_char,
+    endpoint_name_length: usize,
+    client_identity: *const GoslingV3OnionServiceId,
+    client_auth_public_key: *const GoslingX25519PublicKey,
+    publish_timeout_milliseconds: u32,
+    error: *mut *mut GoslingError,
+) -> GoslingPublishHandle {
+    translate_failures(
+        !0usize,
+        error,
+        || -> anyhow::Result<GoslingPublishHandle> {
+            ensure_not_null!(context);
+            ensure_not_null!(endpoint_private_key);
+            ensure_not_null!(endpoint_name);
+            ensure_not_null!(client_identity);
+            ensure_not_null!(client_auth_public_key);
+
+            let mut context_tuple_registry = get_context_tuple_registry()?;
+            let context = match context_tuple_registry.get_mut(context as usize) {
+                Some(context) => context,
+                None => bail_invalid_handle!(context),
+            };
+
+            let endpoint_name =
+                ascii_str_from_ffi(endpoint_name, endpoint_name_length, "endpoint_name")?;
+            ensure_not_empty!(endpoint_name);
+            let endpoint_name: AsciiString = endpoint_name.parse()?;
+
+            let ed25519_private_key_registry = get_ed25519_private_key_registry()?;
+            let endpoint_private_key =
+                match ed25519_private_key_registry.get(endpoint_private_key as usize) {
+                    Some(ed25519_private_key) => ed25519_private_key,
+                    None => bail_invalid_handle!(endpoint_private_key),
+                };
+
+            let v3_onion_service_id_registry = get_v3_onion_service_id_registry()?;
+            let client_identity = match v3_onion_service_id_registry.get(client_identity as usize) {
+                Some(v3_onion_service_id) => v3_onion_service_id,
+                None => bail_invalid_handle!(client_identity),
+            };
+
+            let x25519_public_key_registry = get_x25519_public_key_registry()?;
+            let client_auth_public_key =
+                match x25519_public_key_registry.get(client_auth_public_key as usize) {
+                    Some(x25519_public_key) => x25519_public_key,
+                    None => bail_invalid_handle!(client_auth_public_key),
+                };
+
+            let publish_timeout = match publish_timeout_milliseconds {
+                0 => None,
+                publish_timeout_milliseconds => {
+                    Some(Duration::from_millis(publish_timeout_milliseconds as u64))
+                }
+            };
+
+            Ok(context.0.endpoint_server_start_with_completion(
+                endpoint_private_key.clone(),
+                endpoint_name,
+                client_identity.clone(),
+                client_auth_public_key.clone(),
+                false,
+                publish_timeout,
+            )?)
+        },
+    )
+}
+
+/// Start an endpoint server on the identity server's onion-service rather than its own, so the
+/// confirmed contact may connect without client authorization. Shared endpoint servers must
+/// first be enabled with gosling_context_set_identity_server_shared_endpoints_enabled(). The
+/// client must be told the endpoint server is shared and connect with
+/// gosling_context_begin_shared_endpoint_handshake().
+///
+/// @param context: the gosling context with the given endpoint to start
+/// @param endpoint_private_key: the ed25519 private key whose v3 onion service id identifies the
+///  endpoint server to its client
+/// @param endpoint_name: the ascii-encoded name of the endpoint server
+/// @param endpoint_name_length: the number of chars in endpoint name not including any null-terminator,
+///  or 0 if endpoint_name is null-terminated
+/// @param client_identity: the v3 onion service id of the gosling client associated with this endpoint
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_context_start_shared_endpoint_server(
+    context: *mut GoslingContext,
+    endpoint_private_key: *const GoslingEd25519PrivateKey,
+    endpoint_name: *const c_char,
+    endpoint_name_length: usize,
+    client_identity: *const GoslingV3OnionServiceId,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(context);
+        ensure_not_null!(endpoint_private_key);
+        ensure_not_null!(endpoint_name);
+        ensure_not_null!(client_identity);
+
+        let mut context_tuple_registry = get_context_tuple_registry()?;
+        let context = match context_tuple_registry.get_mut(context as usize) {
+            Some(context) => context,
+            None => bail_invalid_handle!(context),
+        };
+
+        let endpoint_name =
+            ascii_str_from_ffi(endpoint_name, endpoint_name_length, "endpoint_name")?;
+        ensure_not_empty!(endpoint_name);
+        let endpoint_name: AsciiString = endpoint_name.parse()?;
+
+        let ed25519_private_key_registry = get_ed25519_private_key_registry()?;
+        let endpoint_private_key =
+            match ed25519_private_key_registry.get(endpoint_private_key as usize) {
+                Some(ed25519_private_key) => ed25519_private_key,
+                None => bail_invalid_handle!(endpoint_private_key),
+            };
+
+        let v3_onion_service_id_registry = get_v3_onion_service_id_registry()?;
+        let client_identity = match v3_onion_service_id_registry.get(client_identity as usize) {
+            Some(v3_onion_service_id) => v3_onion_service_id,
+            None => bail_invalid_handle!(client_identity),
+        };
+
+        Ok(context.0.endpoint_server_start_shared(
+            endpoint_private_key.clone(),
+            endpoint_name,
+            client_identity.clone(),
         )?)
     });
 }
@@ -270,13 +760,13 @@ pub extern "C" fn gosling_context_stop_endpoint_server(
         ensure_not_null!(context);
         ensure_not_null!(endpoint_private_key);
 
-        let mut context_tuple_registry = get_context_tuple_registry();
+        let mut context_tuple_registry = get_context_tuple_registry()?;
         let context = match context_tuple_registry.get_mut(context as usize) {
             Some(context) => context,
             None => bail_invalid_handle!(context),
         };
 
-        let ed25519_private_key_registry = get_ed25519_private_key_registry();
+        let ed25519_private_key_registry = get_ed25519_private_key_registry()?;
         let endpoint_private_key =
             match ed25519_private_key_registry.get(endpoint_private_key as usize) {
                 Some(ed25519_private_key) => ed25519_private_key,
@@ -288,149 +778,2878 @@ pub extern "C" fn gosling_context_stop_endpoint_server(
     });
 }
 
-/// Connect to and begin a handshake to request an endpoint from the given identity server
+/// Revokes the access granted to an endpoint server's client. The endpoint server is stopped
+/// as with gosling_context_stop_endpoint_server(), which removes its onion service along with
+/// the client's authorization key. An endpoint revocation followed by a newline is then written
+/// to each of the given channels as a best-effort notification to the client, and the channels
+/// are closed. The client passes the revocation to
+/// gosling_context_handle_endpoint_revocation().
 ///
-/// @param context: the context to request an endpoint server for
-/// @param identity_service_id: the service id of the identity server we want to request an endpoint server
-///  from
-/// @param endpoint_name: the name of the endpoint server to request
-/// @param endpoint_name_length: the number of chars in endpoin_name not including any null-terminator
+/// @param context: the gosling context associated with the endpoint server
+/// @param endpoint_private_key: the ed25519 private key associated with the endpoint server to
+///  revoke
+/// @param channels: an array of the client's live channels to the endpoint server; may be null
+///  if channels_count is 0. Once every stream handle has been validated the streams are
+///  consumed, even if the endpoint server cannot be revoked, and must not be used or freed
+///  afterwards
+/// @param channels_count: the number of streams in the channels array
+/// @param out_notified_channels_count: returned number of channels the revocation was written to
 /// @param error: filled on error
 #[no_mangle]
 #[cfg_attr(feature = "impl-lib", rename_impl)]
-pub extern "C" fn gosling_context_begin_identity_handshake(
+pub unsafe extern "C" fn gosling_context_revoke_endpoint_server(
     context: *mut GoslingContext,
-    identity_service_id: *const GoslingV3OnionServiceId,
-    endpoint_name: *const c_char,
-    endpoint_name_length: usize,
+    endpoint_private_key: *const GoslingEd25519PrivateKey,
+    channels: *const *mut GoslingStream,
+    channels_count: usize,
+    out_notified_channels_count: *mut usize,
     error: *mut *mut GoslingError,
-) -> GoslingHandshakeHandle {
-    translate_failures(
-        !0usize,
-        error,
-        || -> anyhow::Result<GoslingHandshakeHandle> {
-            ensure_not_null!(context);
-            ensure_not_null!(identity_service_id);
-            ensure_not_null!(endpoint_name);
-            ensure_not_equal!(endpoint_name_length, 0);
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(context);
+        ensure_not_null!(endpoint_private_key);
+        ensure_not_null!(out_notified_channels_count);
+        if channels_count > 0 {
+            ensure_not_null!(channels);
+        }
 
-            let mut context_tuple_registry = get_context_tuple_registry();
-            let context = match context_tuple_registry.get_mut(context as usize) {
-                Some(context) => context,
-                None => bail_invalid_handle!(context),
-            };
+        let mut context_tuple_registry = get_context_tuple_registry()?;
+        let context = match context_tuple_registry.get_mut(context as usize) {
+            Some(context) => context,
+            None => bail_invalid_handle!(context),
+        };
 
-            let v3_onion_service_id_registry = get_v3_onion_service_id_registry();
-            let identity_service_id =
-                match v3_onion_service_id_registry.get(identity_service_id as usize) {
-                    Some(v3_onion_service_id) => v3_onion_service_id,
-                    None => bail_invalid_handle!(identity_service_id),
-                };
+        let endpoint_identity = match get_ed25519_private_key_registry()?
+            .get(endpoint_private_key as usize)
+        {
+            Some(ed25519_private_key) => V3OnionServiceId::from_private_key(ed25519_private_key),
+            None => bail_invalid_handle!(endpoint_private_key),
+        };
 
-            let endpoint_name = unsafe {
-                std::slice::from_raw_parts(endpoint_name as *const u8, endpoint_name_length)
-            };
-            let endpoint_name = std::str::from_utf8(endpoint_name)?.to_string();
-            if !endpoint_name.is_ascii() {
-                bail!("endpoint_name must be an ascii string")
+        // every handle is checked before any stream is consumed
+        let channels: &[*mut GoslingStream] = match channels_count {
+            0 => &[],
+            channels_count => std::slice::from_raw_parts(channels, channels_count),
+        };
+        let mut tcp_stream_registry = get_tcp_stream_registry()?;
+        for channel in channels {
+            let channel = *channel;
+            if !tcp_stream_registry.contains_key(channel as usize) {
+                bail_invalid_handle!(channel);
             }
+        }
+        let channels: Vec<TcpStream> = channels
+            .iter()
+            .filter_map(|channel| tcp_stream_registry.remove(*channel as usize))
+            .collect();
 
-            Ok(context
-                .0
-                .identity_client_begin_handshake(identity_service_id.clone(), endpoint_name)?)
-        },
-    )
+        *out_notified_channels_count = context
+            .0
+            .endpoint_server_revoke(endpoint_identity, channels)?;
+        Ok(())
+    });
 }
 
-/// Abort an in-progress identity client handshake
+/// Stops all of a context's endpoint servers. With the legacy tor provider their
+/// onion-services are removed together during the next call to
+/// gosling_context_poll_events().
 ///
-/// @param context: the context associated with the identity client handshake handle
-/// @param handshake_handle: the handle associated with the identity client handshake
+/// @param context: the gosling context whose endpoint servers to stop
 /// @param error: filled on error
 #[no_mangle]
 #[cfg_attr(feature = "impl-lib", rename_impl)]
-pub extern "C" fn gosling_context_abort_identity_client_handshake(
+pub extern "C" fn gosling_context_stop_all_endpoint_servers(
     context: *mut GoslingContext,
-    handshake_handle: GoslingHandshakeHandle,
     error: *mut *mut GoslingError,
 ) {
     translate_failures((), error, || -> anyhow::Result<()> {
         ensure_not_null!(context);
 
-        let mut context_tuple_registry = get_context_tuple_registry();
+        let mut context_tuple_registry = get_context_tuple_registry()?;
         let context = match context_tuple_registry.get_mut(context as usize) {
             Some(context) => context,
             None => bail_invalid_handle!(context),
         };
 
-        Ok(context
-            .0
-            .identity_client_abort_handshake(handshake_handle)?)
-    })
+        context.0.endpoint_servers_stop_all()?;
+        Ok(())
+    });
 }
 
-/// Connect to and begin a handshake to request a channel from the given endpoint server
+/// Replace the client authorization keys used to encrypt a running endpoint server's
+/// onion-service descriptor without stopping the endpoint server. Several keys may be
+/// provided so that a previous key remains usable until the client has switched to the
+/// new one. An endpoint_server_published event is emitted again once the updated
+/// descriptor has been published.
 ///
-/// @param context: the context which will be opening the channel
-/// @param endpoint_service_id: the endpoint server to open a channel to
-/// @param client_auth_private_key: the x25519 clienth authorization key needed to decrypt the endpoint server's
+/// @param context: the gosling context with the given endpoint server
+/// @param endpoint_private_key: the ed25519 private key of the endpoint server to update
+/// @param client_auth_public_keys: an array of x25519 public keys used to encrypt the
 ///  onion service descriptor
-/// @param channel_name: the ascii-encoded name of the channel to open
-/// @param channel_name_length: the number of chars in channel name not including any null-terminator
+/// @param client_auth_public_keys_count: the number of keys in the client_auth_public_keys
+///  array; must not be 0
 /// @param error: filled on error
 #[no_mangle]
 #[cfg_attr(feature = "impl-lib", rename_impl)]
-pub extern "C" fn gosling_context_begin_endpoint_handshake(
+pub unsafe extern "C" fn gosling_context_set_endpoint_server_client_auth_keys(
     context: *mut GoslingContext,
-    endpoint_service_id: *const GoslingV3OnionServiceId,
-    client_auth_private_key: *const GoslingX25519PrivateKey,
-    channel_name: *const c_char,
-    channel_name_length: usize,
+    endpoint_private_key: *const GoslingEd25519PrivateKey,
+    client_auth_public_keys: *const *const GoslingX25519PublicKey,
+    client_auth_public_keys_count: usize,
     error: *mut *mut GoslingError,
-) -> GoslingHandshakeHandle {
-    translate_failures(
-        !0usize,
-        error,
-        || -> anyhow::Result<GoslingHandshakeHandle> {
-            ensure_not_null!(context);
-            ensure_not_null!(endpoint_service_id);
-            ensure_not_null!(client_auth_private_key);
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(context);
+        ensure_not_null!(endpoint_private_key);
+        ensure_not_null!(client_auth_public_keys);
+        ensure_not_equal!(client_auth_public_keys_count, 0);
+
+        let mut context_tuple_registry = get_context_tuple_registry()?;
+        let context = match context_tuple_registry.get_mut(context as usize) {
+            Some(context) => context,
+            None => bail_invalid_handle!(context),
+        };
+
+        let ed25519_private_key_registry = get_ed25519_private_key_registry()?;
+        let endpoint_private_key =
+            match ed25519_private_key_registry.get(endpoint_private_key as usize) {
+                Some(ed25519_private_key) => ed25519_private_key,
+                None => bail_invalid_handle!(endpoint_private_key),
+            };
+
+        let client_auth_public_keys_slice =
+            std::slice::from_raw_parts(client_auth_public_keys, client_auth_public_keys_count);
+        let x25519_public_key_registry = get_x25519_public_key_registry()?;
+        let mut client_auth_keys: Vec<X25519PublicKey> =
+            Vec::with_capacity(client_auth_public_keys_count);
+        for client_auth_public_key in client_auth_public_keys_slice {
+            let client_auth_public_key = *client_auth_public_key;
+            match x25519_public_key_registry.get(client_auth_public_key as usize) {
+                Some(x25519_public_key) => client_auth_keys.push(x25519_public_key.clone()),
+                None => bail_invalid_handle!(client_auth_public_key),
+            }
+        }
+
+        let endpoint_identity = V3OnionServiceId::from_private_key(endpoint_private_key);
+        Ok(context
+            .0
+            .endpoint_server_set_client_auth_keys(endpoint_identity, client_auth_keys)?)
+    });
+}
+
+/// Force the tor provider to build and publish a fresh onion-service descriptor for a running
+/// endpoint server without stopping it. The endpoint server descriptor refreshed callback is
+/// called once the refreshed descriptor has been published, or the endpoint server published
+/// callback if the endpoint server had not yet been published.
+///
+/// @param context: the gosling context with the given endpoint server
+/// @param endpoint_private_key: the ed25519 private key of the endpoint server to refresh
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_refresh_endpoint_server_descriptor(
+    context: *mut GoslingContext,
+    endpoint_private_key: *const GoslingEd25519PrivateKey,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(context);
+        ensure_not_null!(endpoint_private_key);
+
+        let mut context_tuple_registry = get_context_tuple_registry()?;
+        let context = match context_tuple_registry.get_mut(context as usize) {
+            Some(context) => context,
+            None => bail_invalid_handle!(context),
+        };
+
+        let ed25519_private_key_registry = get_ed25519_private_key_registry()?;
+        let endpoint_private_key =
+            match ed25519_private_key_registry.get(endpoint_private_key as usize) {
+                Some(ed25519_private_key) => ed25519_private_key,
+                None => bail_invalid_handle!(endpoint_private_key),
+            };
+
+        let endpoint_identity = V3OnionServiceId::from_private_key(endpoint_private_key);
+        Ok(context
+            .0
+            .endpoint_server_refresh_descriptor(endpoint_identity)?)
+    });
+}
+
+/// Limit the number of incoming handshakes an endpoint server runs at once. Connections
+/// accepted while the limit is reached wait in a pending-connection queue until an
+/// in-progress handshake finishes. When the queue is full a connection is shed according
+/// to the drop policy, and pending connections which wait longer than the endpoint timeout
+/// are also shed. The endpoint server connection shed callback is called for each shed
+/// connection.
+///
+/// Drop policies:
+/// - PENDING_CONNECTION_DROP_POLICY_DROP_OLDEST: the longest-waiting connection is closed
+///   to make room for the new one
+/// - PENDING_CONNECTION_DROP_POLICY_DROP_NEWEST: the new connection is closed
+///
+/// @param context: the gosling context with the given endpoint server
+/// @param endpoint_private_key: the ed25519 private key of the endpoint server to limit
+/// @param max_concurrent_handshakes: the maximum number of concurrent incoming handshakes,
+///  or 0 for no limit (the default)
+/// @param pending_queue_capacity: the maximum number of connections waiting for a
+///  handshake slot; may be 0 to shed every connection accepted while the limit is reached
+/// @param drop_policy: one of the PENDING_CONNECTION_DROP_POLICY_* constants
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_set_endpoint_server_concurrency_limit(
+    context: *mut GoslingContext,
+    endpoint_private_key: *const GoslingEd25519PrivateKey,
+    max_concurrent_handshakes: usize,
+    pending_queue_capacity: usize,
+    drop_policy: u32,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(context);
+        ensure_not_null!(endpoint_private_key);
+
+        let drop_policy = match drop_policy {
+            PENDING_CONNECTION_DROP_POLICY_DROP_OLDEST => PendingConnectionDropPolicy::DropOldest,
+            PENDING_CONNECTION_DROP_POLICY_DROP_NEWEST => PendingConnectionDropPolicy::DropNewest,
+            drop_policy => bail!("invalid drop_policy: {}", drop_policy),
+        };
+
+        let mut context_tuple_registry = get_context_tuple_registry()?;
+        let context = match context_tuple_registry.get_mut(context as usize) {
+            Some(context) => context,
+            None => bail_invalid_handle!(context),
+        };
+
+        let ed25519_private_key_registry = get_ed25519_private_key_registry()?;
+        let endpoint_private_key =
+            match ed25519_private_key_registry.get(endpoint_private_key as usize) {
+                Some(ed25519_private_key) => ed25519_private_key,
+                None => bail_invalid_handle!(endpoint_private_key),
+            };
+
+        let max_concurrent_handshakes = match max_concurrent_handshakes {
+            0 => None,
+            max_concurrent_handshakes => Some(max_concurrent_handshakes),
+        };
+        let endpoint_identity = V3OnionServiceId::from_private_key(endpoint_private_key);
+        Ok(context.0.endpoint_server_set_concurrency_limit(
+            endpoint_identity,
+            max_concurrent_handshakes,
+            pending_queue_capacity,
+            drop_policy,
+        )?)
+    });
+}
+
+/// Register a channel-name pattern which endpoint servers use to accept channel requests
+/// without consulting the application. Channel requests whose name matches a registered
+/// pattern are accepted automatically and the endpoint_server_channel_supported_callback is
+/// not invoked for them. Patterns apply to handshakes which begin after registration.
+///
+/// Patterns are glob-style: '*' matches any (possibly empty) sequence of characters, '?'
+/// matches exactly one character and all other characters match themselves (e.g.
+/// "file-transfer/*" matches "file-transfer/photos").
+///
+/// @param context: the context to configure
+/// @param pattern: a non-empty ascii-encoded channel-name pattern
+/// @param pattern_length: the number of chars in pattern not including any null-terminator, or 0 if
+///  pattern is null-terminated
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_context_add_endpoint_channel_pattern(
+    context: *mut GoslingContext,
+    pattern: *const c_char,
+    pattern_length: usize,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(context);
+        ensure_not_null!(pattern);
+
+        let mut context_tuple_registry = get_context_tuple_registry()?;
+        let context = match context_tuple_registry.get_mut(context as usize) {
+            Some(context) => context,
+            None => bail_invalid_handle!(context),
+        };
+
+        let pattern = str_from_ffi(pattern, pattern_length, "pattern")?;
+        ensure_not_empty!(pattern);
+        let pattern = pattern.to_string();
+
+        Ok(context.0.endpoint_server_add_channel_pattern(pattern)?)
+    });
+}
+
+/// Remove a channel-name pattern previously registered with
+/// gosling_context_add_endpoint_channel_pattern()
+///
+/// @param context: the context to configure
+/// @param pattern: the pattern to remove
+/// @param pattern_length: the number of chars in pattern not including any null-terminator, or 0 if
+///  pattern is null-terminated
+/// @param error: filled on error
+/// @return true if the pattern was registered
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_context_remove_endpoint_channel_pattern(
+    context: *mut GoslingContext,
+    pattern: *const c_char,
+    pattern_length: usize,
+    error: *mut *mut GoslingError,
+) -> bool {
+    translate_failures(false, error, || -> anyhow::Result<bool> {
+        ensure_not_null!(context);
+        ensure_not_null!(pattern);
+
+        let mut context_tuple_registry = get_context_tuple_registry()?;
+        let context = match context_tuple_registry.get_mut(context as usize) {
+            Some(context) => context,
+            None => bail_invalid_handle!(context),
+        };
+
+        let pattern = str_from_ffi(pattern, pattern_length, "pattern")?;
+
+        Ok(context.0.endpoint_server_remove_channel_pattern(pattern))
+    })
+}
+
+/// Register a channel whose handshakes keep their Honk-RPC session open for the
+/// application's own calls. Completed endpoint client and endpoint server handshakes for
+/// the channel are reported with the endpoint_client_rpc_channel_opened_callback and
+/// endpoint_server_rpc_channel_opened_callback in place of the handshake completed
+/// callbacks. Only applies to handshakes which begin after registration, and both peers
+/// must register the channel.
+///
+/// @param context: the context to configure
+/// @param channel_name: a non-empty ascii-encoded channel name
+/// @param channel_name_length: the number of chars in channel_name not including any
+///  null-terminator, or 0 if channel_name is null-terminated
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_context_add_rpc_channel(
+    context: *mut GoslingContext,
+    channel_name: *const c_char,
+    channel_name_length: usize,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(context);
+        ensure_not_null!(channel_name);
+
+        let mut context_tuple_registry = get_context_tuple_registry()?;
+        let context = match context_tuple_registry.get_mut(context as usize) {
+            Some(context) => context,
+            None => bail_invalid_handle!(context),
+        };
+
+        let channel_name = ascii_str_from_ffi(channel_name, channel_name_length, "channel_name")?;
+        ensure_not_empty!(channel_name);
+        let channel_name: AsciiString = channel_name.parse()?;
+
+        Ok(context.0.add_rpc_channel(channel_name)?)
+    });
+}
+
+/// Remove a channel previously registered with gosling_context_add_rpc_channel(). Rpc
+/// channels which are already open are unaffected.
+///
+/// @param context: the context to configure
+/// @param channel_name: the channel name to remove
+/// @param channel_name_length: the number of chars in channel_name not including any
+///  null-terminator, or 0 if channel_name is null-terminated
+/// @param error: filled on error
+/// @return true if the channel was registered
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_context_remove_rpc_channel(
+    context: *mut GoslingContext,
+    channel_name: *const c_char,
+    channel_name_length: usize,
+    error: *mut *mut GoslingError,
+) -> bool {
+    translate_failures(false, error, || -> anyhow::Result<bool> {
+        ensure_not_null!(context);
+        ensure_not_null!(channel_name);
+
+        let mut context_tuple_registry = get_context_tuple_registry()?;
+        let context = match context_tuple_registry.get_mut(context as usize) {
+            Some(context) => context,
+            None => bail_invalid_handle!(context),
+        };
+
+        let channel_name = ascii_str_from_ffi(channel_name, channel_name_length, "channel_name")?;
+        let channel_name: AsciiString = channel_name.parse()?;
+
+        Ok(context.0.remove_rpc_channel(&channel_name))
+    })
+}
+
+/// Register a channel whose handshakes open a datagram channel over the handshake's
+/// connection instead of handing it over as a stream. Completed endpoint client and
+/// endpoint server handshakes for the channel are reported with the
+/// endpoint_client_datagram_channel_opened_callback and
+/// endpoint_server_datagram_channel_opened_callback in place of the handshake completed
+/// callbacks. Both peers must register the channel, and a channel may not be registered
+/// as both an rpc channel and a datagram channel.
+///
+/// @param context: the context to configure
+/// @param channel_name: a non-empty ascii-encoded channel name
+/// @param channel_name_length: the number of chars in channel_name not including any
+///  null-terminator, or 0 if channel_name is null-terminated
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_context_add_datagram_channel(
+    context: *mut GoslingContext,
+    channel_name: *const c_char,
+    channel_name_length: usize,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(context);
+        ensure_not_null!(channel_name);
+
+        let mut context_tuple_registry = get_context_tuple_registry()?;
+        let context = match context_tuple_registry.get_mut(context as usize) {
+            Some(context) => context,
+            None => bail_invalid_handle!(context),
+        };
+
+        let channel_name = ascii_str_from_ffi(channel_name, channel_name_length, "channel_name")?;
+        ensure_not_empty!(channel_name);
+        let channel_name: AsciiString = channel_name.parse()?;
+
+        Ok(context.0.add_datagram_channel(channel_name)?)
+    });
+}
+
+/// Remove a channel previously registered with gosling_context_add_datagram_channel().
+/// Datagram channels which are already open are unaffected.
+///
+/// @param context: the context to configure
+/// @param channel_name: the channel name to remove
+/// @param channel_name_length: the number of chars in channel_name not including any
+///  null-terminator, or 0 if channel_name is null-terminated
+/// @param error: filled on error
+/// @return true if the channel was registered
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_context_remove_datagram_channel(
+    context: *mut GoslingContext,
+    channel_name: *const c_char,
+    channel_name_length: usize,
+    error: *mut *mut GoslingError,
+) -> bool {
+    translate_failures(false, error, || -> anyhow::Result<bool> {
+        ensure_not_null!(context);
+        ensure_not_null!(channel_name);
+
+        let mut context_tuple_registry = get_context_tuple_registry()?;
+        let context = match context_tuple_registry.get_mut(context as usize) {
+            Some(context) => context,
+            None => bail_invalid_handle!(context),
+        };
+
+        let channel_name = ascii_str_from_ffi(channel_name, channel_name_length, "channel_name")?;
+        let channel_name: AsciiString = channel_name.parse()?;
+
+        Ok(context.0.remove_datagram_channel(&channel_name))
+    })
+}
+
+/// Set the heartbeat of the rpc channels opened by the context's endpoint clients and servers;
+/// see gosling_rpc_channel_set_heartbeat(). Only applies to channels opened after this call.
+///
+/// @param context: the context to configure
+/// @param interval_milliseconds: how long the channels wait between heartbeats, or 0 to
+///  disable heartbeats (the default)
+/// @param miss_threshold: the number of consecutive unanswered heartbeats after which a
+///  channel's peer is reported unresponsive; must not be 0 unless heartbeats are disabled
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_set_rpc_channel_heartbeat(
+    context: *mut GoslingContext,
+    interval_milliseconds: u32,
+    miss_threshold: u32,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(context);
+
+        let mut context_tuple_registry = get_context_tuple_registry()?;
+        let context = match context_tuple_registry.get_mut(context as usize) {
+            Some(context) => context,
+            None => bail_invalid_handle!(context),
+        };
+
+        context
+            .0
+            .set_rpc_channel_heartbeat(heartbeat_from_ffi(interval_milliseconds, miss_threshold))?;
+        Ok(())
+    })
+}
+
+/// Set whether the context's endpoint servers accept handshakes from peers running the
+/// legacy protocol revision, which do not send their identity when beginning an endpoint
+/// handshake. Such clients are assumed to be the endpoint server's allowed client and
+/// their proof is verified as usual. Only applies to handshakes which begin after this
+/// call.
+///
+/// @param context: the context to configure
+/// @param allowed: whether legacy endpoint handshakes are accepted (the default is false)
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_set_endpoint_legacy_handshakes_allowed(
+    context: *mut GoslingContext,
+    allowed: bool,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(context);
+
+        let mut context_tuple_registry = get_context_tuple_registry()?;
+        let context = match context_tuple_registry.get_mut(context as usize) {
+            Some(context) => context,
+            None => bail_invalid_handle!(context),
+        };
+
+        context
+            .0
+            .endpoint_server_set_legacy_handshakes_allowed(allowed);
+        Ok(())
+    });
+}
+
+/// Set whether the context's endpoint servers are too busy to open new channels. While busy,
+/// endpoint clients' channel requests are turned away with an advisory error telling them how
+/// long to wait before retrying; the endpoint server channel supported callback is not called
+/// and the endpoint server handshake failed callback is called instead. A gosling endpoint
+/// client reports this through its endpoint client handshake failed callback. Only applies to
+/// handshakes which begin after this call.
+///
+/// @param context: the context to configure
+/// @param busy: whether channel requests are turned away (the default is false)
+/// @param retry_after_seconds: the number of seconds clients should wait before retrying;
+///  ignored if busy is false
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_set_endpoint_server_busy(
+    context: *mut GoslingContext,
+    busy: bool,
+    retry_after_seconds: u32,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(context);
+
+        let mut context_tuple_registry = get_context_tuple_registry()?;
+        let context = match context_tuple_registry.get_mut(context as usize) {
+            Some(context) => context,
+            None => bail_invalid_handle!(context),
+        };
+
+        let retry_after = match busy {
+            true => Some(Duration::from_secs(retry_after_seconds as u64)),
+            false => None,
+        };
+        context.0.endpoint_server_set_busy(retry_after);
+        Ok(())
+    });
+}
+
+/// Pause or resume one of the context's endpoint servers. A paused endpoint server keeps its
+/// onion-service published but turns away endpoint clients' channel requests as if busy (see
+/// gosling_context_set_endpoint_server_busy()), so it is reachable again as soon as it is
+/// resumed. A paused endpoint server's retry_after_seconds takes precedence over the one set
+/// with gosling_context_set_endpoint_server_busy(). Only applies to handshakes which begin
+/// after this call. The pause is discarded when the endpoint server is stopped. Shared endpoint
+/// servers cannot be paused.
+///
+/// @param context: the context running the endpoint server
+/// @param endpoint_private_key: the ed25519 private key of the endpoint server
+/// @param paused: whether channel requests are turned away (the default is false)
+/// @param retry_after_seconds: the number of seconds clients should wait before retrying;
+///  ignored if paused is false
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_set_endpoint_server_paused(
+    context: *mut GoslingContext,
+    endpoint_private_key: *const GoslingEd25519PrivateKey,
+    paused: bool,
+    retry_after_seconds: u32,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(context);
+        ensure_not_null!(endpoint_private_key);
+
+        let mut context_tuple_registry = get_context_tuple_registry()?;
+        let context = match context_tuple_registry.get_mut(context as usize) {
+            Some(context) => context,
+            None => bail_invalid_handle!(context),
+        };
+
+        let ed25519_private_key_registry = get_ed25519_private_key_registry()?;
+        let endpoint_private_key =
+            match ed25519_private_key_registry.get(endpoint_private_key as usize) {
+                Some(ed25519_private_key) => ed25519_private_key,
+                None => bail_invalid_handle!(endpoint_private_key),
+            };
+
+        let endpoint_identity = V3OnionServiceId::from_private_key(endpoint_private_key);
+        let retry_after = match paused {
+            true => Some(Duration::from_secs(retry_after_seconds as u64)),
+            false => None,
+        };
+        Ok(context
+            .0
+            .endpoint_server_set_paused(endpoint_identity, retry_after)?)
+    });
+}
+
+/// Set per-client limits on the channels opened through the context's endpoint servers. Once
+/// an endpoint client has proven its identity, a channel request which would exceed its quota
+/// is refused and the endpoint server handshake failed callback is called with an error whose
+/// code is ERROR_CODE_CHANNEL_QUOTA_EXCEEDED. A gosling endpoint client reports the refusal
+/// through its endpoint client handshake failed callback with the same error code. Accepted
+/// channels count as open until reported closed with
+/// gosling_context_endpoint_server_channel_closed(). Setting both limits to 0 removes the
+/// quota and forgets the channels counted against it.
+///
+/// @param context: the context to configure
+/// @param max_open_channels: the maximum number of channels each client may have open at
+///  once, or 0 for no limit
+/// @param max_channel_requests: the maximum number of channels each client may open within
+///  the time window, or 0 for no limit
+/// @param window_seconds: the length of the sliding time window in seconds; ignored if
+///  max_channel_requests is 0
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_set_endpoint_server_channel_quota(
+    context: *mut GoslingContext,
+    max_open_channels: usize,
+    max_channel_requests: u32,
+    window_seconds: u32,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(context);
+        if max_channel_requests > 0 && window_seconds == 0 {
+            bail!("window_seconds must not be 0 when max_channel_requests is limited");
+        }
+
+        let mut context_tuple_registry = get_context_tuple_registry()?;
+        let context = match context_tuple_registry.get_mut(context as usize) {
+            Some(context) => context,
+            None => bail_invalid_handle!(context),
+        };
+
+        let quota = ChannelQuota {
+            max_open_channels: (max_open_channels > 0).then_some(max_open_channels),
+            max_channel_requests: (max_channel_requests > 0).then(|| {
+                (
+                    max_channel_requests,
+                    Duration::from_secs(window_seconds as u64),
+                )
+            }),
+        };
+        let quota = match quota {
+            ChannelQuota {
+                max_open_channels: None,
+                max_channel_requests: None,
+            } => None,
+            quota => Some(quota),
+        };
+        context.0.endpoint_server_set_channel_quota(quota);
+        Ok(())
+    });
+}
+
+/// Report that a channel accepted by one of the context's endpoint servers has been closed,
+/// so it no longer counts against its client's channel quota; see
+/// gosling_context_set_endpoint_server_channel_quota(). Has no effect on channels which were
+/// not counted against a quota.
+///
+/// @param context: the context whose endpoint server accepted the channel
+/// @param handshake_handle: the handle of the endpoint server handshake which opened the
+///  channel
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_endpoint_server_channel_closed(
+    context: *mut GoslingContext,
+    handshake_handle: GoslingHandshakeHandle,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(context);
+
+        let mut context_tuple_registry = get_context_tuple_registry()?;
+        let context = match context_tuple_registry.get_mut(context as usize) {
+            Some(context) => context,
+            None => bail_invalid_handle!(context),
+        };
+
+        context.0.endpoint_server_channel_closed(handshake_handle);
+        Ok(())
+    });
+}
+
+/// Set the maximum number of outgoing identity and endpoint handshakes which may be in
+/// progress at once. Handshakes begun while this limit is reached are queued and started
+/// by gosling_context_poll_events() as connection slots become available.
+///
+/// @param context: the context to configure
+/// @param max_outbound_connections: the maximum number of concurrent outgoing handshakes,
+///  or 0 for no limit (the default)
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_set_max_outbound_connections(
+    context: *mut GoslingContext,
+    max_outbound_connections: usize,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(context);
+
+        let mut context_tuple_registry = get_context_tuple_registry()?;
+        let context = match context_tuple_registry.get_mut(context as usize) {
+            Some(context) => context,
+            None => bail_invalid_handle!(context),
+        };
+
+        let max_outbound_connections = match max_outbound_connections {
+            0 => None,
+            max_outbound_connections => Some(max_outbound_connections),
+        };
+        Ok(context
+            .0
+            .set_max_outbound_connections(max_outbound_connections)?)
+    });
+}
+
+/// Set whether outgoing identity and endpoint handshakes may be begun before the context's
+/// tor provider has bootstrapped. By default, beginning a handshake fails until the tor
+/// bootstrap completed callback has been called. When enabled, such handshakes are queued
+/// instead, as reported by the outbound handshake queued callback, and are started by the
+/// gosling_context_poll_events() call which reports bootstrap completion. Each is then
+/// reported by the outbound handshake started callback. Disabling queueing does not affect
+/// handshakes which are already queued.
+///
+/// @param context: the context to configure
+/// @param queue: whether handshakes begun before bootstrap completes are queued rather than
+///  rejected (the default is false)
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_set_queue_until_bootstrapped(
+    context: *mut GoslingContext,
+    queue: bool,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(context);
+
+        let mut context_tuple_registry = get_context_tuple_registry()?;
+        let context = match context_tuple_registry.get_mut(context as usize) {
+            Some(context) => context,
+            None => bail_invalid_handle!(context),
+        };
+
+        context.0.set_queue_until_bootstrapped(queue);
+        Ok(())
+    });
+}
+
+/// Set how long a failed connection to an identity server is remembered. While a failure is
+/// remembered, new identity handshakes with that server are not attempted and instead fail
+/// immediately through the identity client handshake failed callback, avoiding repeated tor
+/// circuit construction to an unreachable server.
+///
+/// @param context: the context to configure
+/// @param negative_ttl_seconds: the number of seconds failures are remembered, or 0 to never
+///  remember failures (the default)
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_set_identity_server_negative_ttl(
+    context: *mut GoslingContext,
+    negative_ttl_seconds: u32,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(context);
+
+        let mut context_tuple_registry = get_context_tuple_registry()?;
+        let context = match context_tuple_registry.get_mut(context as usize) {
+            Some(context) => context,
+            None => bail_invalid_handle!(context),
+        };
+
+        let negative_ttl = match negative_ttl_seconds {
+            0 => None,
+            negative_ttl_seconds => Some(Duration::from_secs(negative_ttl_seconds as u64)),
+        };
+        context.0.set_identity_server_negative_ttl(negative_ttl);
+        Ok(())
+    });
+}
+
+/// Set how long identity and endpoint handshakes may wait on their peer without receiving
+/// any bytes from it. Handshakes whose peer has disappeared mid-handshake fail through their
+/// handshake failed callback once this timeout passes, rather than once the identity or
+/// endpoint timeout runs out. Time spent waiting on the application's own callbacks is not
+/// counted. Only applies to handshakes which begin after it is set.
+///
+/// @param context: the context to configure
+/// @param timeout_seconds: the number of seconds to wait on a silent peer, or 0 for no
+///  limit (the default)
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_set_handshake_read_idle_timeout(
+    context: *mut GoslingContext,
+    timeout_seconds: u32,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(context);
+
+        let mut context_tuple_registry = get_context_tuple_registry()?;
+        let context = match context_tuple_registry.get_mut(context as usize) {
+            Some(context) => context,
+            None => bail_invalid_handle!(context),
+        };
+
+        let timeout = match timeout_seconds {
+            0 => None,
+            timeout_seconds => Some(Duration::from_secs(timeout_seconds as u64)),
+        };
+        context.0.set_handshake_read_idle_timeout(timeout);
+        Ok(())
+    });
+}
+
+/// Set whether the context's identity server agrees to identity clients' requests to
+/// continue with an endpoint handshake over the identity handshake's connection (see
+/// gosling_context_begin_identity_handshake_with_endpoint_upgrade()). The connection is held
+/// after the identity server handshake completed callback until the granted endpoint server
+/// is started with gosling_context_start_endpoint_server(), and is closed if the endpoint
+/// server is not started within the endpoint timeout. Only applies to handshakes which begin
+/// after this call.
+///
+/// @param context: the context to configure
+/// @param allowed: whether endpoint upgrades are agreed to (the default is false)
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_set_identity_server_endpoint_upgrade_allowed(
+    context: *mut GoslingContext,
+    allowed: bool,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(context);
+
+        let mut context_tuple_registry = get_context_tuple_registry()?;
+        let context = match context_tuple_registry.get_mut(context as usize) {
+            Some(context) => context,
+            None => bail_invalid_handle!(context),
+        };
+
+        context
+            .0
+            .identity_server_set_endpoint_upgrade_allowed(allowed);
+        Ok(())
+    });
+}
+
+/// Set whether the context's identity server derives the private keys of the endpoint servers
+/// it grants from its identity private key, the client's identity and the endpoint's name
+/// rather than generating them randomly. Granting the same endpoint to the same client again,
+/// e.g. after revoking it, then yields the same endpoint server, and lost endpoint keys may be
+/// recovered with gosling_context_derive_endpoint_private_key(). Only applies to handshakes
+/// which begin after this call.
+///
+/// @param context: the context to configure
+/// @param derive: whether endpoint keys are derived (the default is false)
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_set_identity_server_derive_endpoint_keys(
+    context: *mut GoslingContext,
+    derive: bool,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(context);
+
+        let mut context_tuple_registry = get_context_tuple_registry()?;
+        let context = match context_tuple_registry.get_mut(context as usize) {
+            Some(context) => context,
+            None => bail_invalid_handle!(context),
+        };
+
+        context.0.identity_server_set_derive_endpoint_keys(derive);
+        Ok(())
+    });
+}
+
+/// Derive the private key of the endpoint server the context's identity server grants to a
+/// client when deriving endpoint keys (see
+/// gosling_context_set_identity_server_derive_endpoint_keys())
+///
+/// @param context: the context whose identity private key to derive from
+/// @param client_identity: the v3 onion service id of the client the endpoint is granted to
+/// @param endpoint_name: the ascii-encoded name of the endpoint
+/// @param endpoint_name_length: the number of chars in endpoint name not including any null-terminator,
+///  or 0 if endpoint_name is null-terminated
+/// @param out_private_key: returned derived ed25519 private key
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_context_derive_endpoint_private_key(
+    context: *mut GoslingContext,
+    client_identity: *const GoslingV3OnionServiceId,
+    endpoint_name: *const c_char,
+    endpoint_name_length: usize,
+    out_private_key: *mut *mut GoslingEd25519PrivateKey,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(context);
+        ensure_not_null!(client_identity);
+        ensure_not_null!(endpoint_name);
+        ensure_not_null!(out_private_key);
+
+        let endpoint_name =
+            ascii_str_from_ffi(endpoint_name, endpoint_name_length, "endpoint_name")?;
+        ensure_not_empty!(endpoint_name);
+        let endpoint_name: AsciiString = endpoint_name.parse()?;
+
+        let endpoint_private_key = {
+            let context_tuple_registry = get_context_tuple_registry()?;
+            let context = match context_tuple_registry.get(context as usize) {
+                Some(context) => context,
+                None => bail_invalid_handle!(context),
+            };
+
+            let v3_onion_service_id_registry = get_v3_onion_service_id_registry()?;
+            let client_identity = match v3_onion_service_id_registry.get(client_identity as usize) {
+                Some(v3_onion_service_id) => v3_onion_service_id,
+                None => bail_invalid_handle!(client_identity),
+            };
+            context
+                .0
+                .identity_server_derive_endpoint_private_key(client_identity, &endpoint_name)
+        };
+
+        let handle = get_ed25519_private_key_registry()?.try_insert(endpoint_private_key)?;
+        *out_private_key = handle as *mut GoslingEd25519PrivateKey;
+        Ok(())
+    })
+}
+
+/// Set how long the context's identity server waits for an identity client to respond to its
+/// endpoint challenge. Clients which do not respond in time are sent an error and
+/// disconnected, and the identity server handshake failed callback is called. The deadline is
+/// measured from when the challenge built in the identity server endpoint request received
+/// callback is sent to the client. Only applies to handshakes which begin after this call.
+///
+/// @param context: the context to configure
+/// @param deadline_milliseconds: the number of milliseconds clients have to respond to the
+///  endpoint challenge, or 0 to only rely on the identity timeout (the default)
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_set_identity_server_challenge_response_deadline(
+    context: *mut GoslingContext,
+    deadline_milliseconds: u32,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(context);
+
+        let mut context_tuple_registry = get_context_tuple_registry()?;
+        let context = match context_tuple_registry.get_mut(context as usize) {
+            Some(context) => context,
+            None => bail_invalid_handle!(context),
+        };
+
+        let deadline = match deadline_milliseconds {
+            0 => None,
+            deadline_milliseconds => Some(Duration::from_millis(deadline_milliseconds as u64)),
+        };
+        context
+            .0
+            .identity_server_set_challenge_response_deadline(deadline);
+        Ok(())
+    });
+}
+
+/// Set the proof-of-work denial-of-service defenses enabled on the context's identity
+/// server's onion-service. Clients must solve a proof-of-work puzzle before connecting to a
+/// defended identity server. Only applies when the identity server is next started with
+/// gosling_context_start_identity_server(), which fails if the tor provider does not support
+/// proof-of-work defenses (the legacy tor daemon provider requires tor 0.4.8.1 or newer).
+///
+/// @param context: the context to configure
+/// @param enabled: whether proof-of-work defenses are enabled (the default is false)
+/// @param queue_rate: the rate at which introduction requests are handled in requests per
+///  second, or 0 for the tor daemon's default; ignored if enabled is false
+/// @param queue_burst: the number of introduction requests which may be handled in a single
+///  burst, or 0 for the tor daemon's default; ignored if enabled is false
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_set_identity_server_pow_defenses(
+    context: *mut GoslingContext,
+    enabled: bool,
+    queue_rate: u32,
+    queue_burst: u32,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(context);
+
+        let mut context_tuple_registry = get_context_tuple_registry()?;
+        let context = match context_tuple_registry.get_mut(context as usize) {
+            Some(context) => context,
+            None => bail_invalid_handle!(context),
+        };
+
+        let pow_defenses = enabled.then(|| PowDefenses {
+            queue_rate: (queue_rate != 0).then_some(queue_rate),
+            queue_burst: (queue_burst != 0).then_some(queue_burst),
+        });
+        context.0.identity_server_set_pow_defenses(pow_defenses);
+        Ok(())
+    });
+}
+
+/// Set whether the context's identity server also serves its shared endpoint servers (see
+/// gosling_context_start_shared_endpoint_server()). Shared endpoint servers are not protected
+/// by client authorization; anyone who knows the identity server's v3 onion service id may
+/// connect to them, so they are protected only by the endpoint handshake. Only applies when
+/// the identity server is next started with gosling_context_start_identity_server(), which
+/// fails if the tor provider does not support onion-services with several virt-ports.
+///
+/// @param context: the context to configure
+/// @param enabled: whether shared endpoint servers are served (the default is false)
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_set_identity_server_shared_endpoints_enabled(
+    context: *mut GoslingContext,
+    enabled: bool,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(context);
+
+        let mut context_tuple_registry = get_context_tuple_registry()?;
+        let context = match context_tuple_registry.get_mut(context as usize) {
+            Some(context) => context,
+            None => bail_invalid_handle!(context),
+        };
+
+        context
+            .0
+            .identity_server_set_shared_endpoints_enabled(enabled);
+        Ok(())
+    });
+}
+
+/// Set the maximum number of events whose callbacks are called by a single call to
+/// gosling_context_poll_events(), and how to handle events beyond that limit.
+///
+/// The limit is applied as events are produced. Only lossy events are merged or
+/// discarded: tor log received, tor bootstrap status received, tor circuit status
+/// changed, tor stream status changed, tor onion-service descriptor upload status
+/// and outbound handshake queued events. Other events are never discarded; any
+/// beyond the capacity are handled by later calls to gosling_context_poll_events().
+///
+/// Overflow policies:
+/// - EVENT_QUEUE_OVERFLOW_POLICY_DROP_OLDEST: the oldest lossy events are
+///   discarded to make room for new ones, and the event queue overflowed callback
+///   is called before the remaining events' callbacks
+/// - EVENT_QUEUE_OVERFLOW_POLICY_COALESCE_LOGS: new tor log lines are merged into
+///   the oldest pending tor log received callback, separated by newlines; other
+///   lossy events are discarded as with EVENT_QUEUE_OVERFLOW_POLICY_DROP_OLDEST. The
+///   event queue overflowed callback is called in either case
+/// - EVENT_QUEUE_OVERFLOW_POLICY_ERROR: the newest lossy events are discarded and
+///   gosling_context_poll_events() fails; the remaining events are handled by the
+///   next call to gosling_context_poll_events(), which collects no new events
+///
+/// @param context: the context to configure
+/// @param capacity: the maximum number of events handled per poll including any
+///  event queue overflowed event, or 0 for no limit (the default); must not be 1
+/// @param overflow_policy: one of the EVENT_QUEUE_OVERFLOW_POLICY_* constants
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_set_event_queue_capacity(
+    context: *mut GoslingContext,
+    capacity: usize,
+    overflow_policy: u32,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(context);
+
+        let overflow_policy = match overflow_policy {
+            EVENT_QUEUE_OVERFLOW_POLICY_DROP_OLDEST => EventQueueOverflowPolicy::DropOldest,
+            EVENT_QUEUE_OVERFLOW_POLICY_COALESCE_LOGS => EventQueueOverflowPolicy::CoalesceLogs,
+            EVENT_QUEUE_OVERFLOW_POLICY_ERROR => EventQueueOverflowPolicy::Error,
+            overflow_policy => bail!("invalid overflow_policy: {}", overflow_policy),
+        };
+
+        let mut context_tuple_registry = get_context_tuple_registry()?;
+        let context = match context_tuple_registry.get_mut(context as usize) {
+            Some(context) => context,
+            None => bail_invalid_handle!(context),
+        };
+
+        let capacity = match capacity {
+            0 => None,
+            capacity => Some(capacity),
+        };
+        Ok(context
+            .0
+            .set_event_queue_capacity(capacity, overflow_policy)?)
+    });
+}
+
+/// Deliver the tor provider's log lines through a bounded buffer rather than through the tor
+/// log received callback called by gosling_context_poll_events(). Tor logs heavily during
+/// bootstrap, so this keeps protocol event callbacks responsive in applications which are slow
+/// to handle events. Buffered lines are passed to the tor log received callback by
+/// gosling_context_poll_tor_log(). When the buffer is full its oldest lines are discarded and
+/// the tor log lines dropped callback is called.
+///
+/// @param context: the context to configure
+/// @param capacity: the maximum number of tor log lines held in the buffer, or 0 to pass log
+///  lines to the tor log received callback from gosling_context_poll_events() (the default)
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_set_tor_log_buffer(
+    context: *mut GoslingContext,
+    capacity: usize,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(context);
+
+        let mut context_tuple_registry = get_context_tuple_registry()?;
+        let context = match context_tuple_registry.get_mut(context as usize) {
+            Some(context) => context,
+            None => bail_invalid_handle!(context),
+        };
+
+        let capacity = match capacity {
+            0 => None,
+            capacity => Some(capacity),
+        };
+        Ok(context.0.set_tor_log_buffer(capacity)?)
+    });
+}
+
+/// Set how often the onion-service descriptors of the context's identity server and endpoint
+/// servers are refreshed. Each refresh is reported through the identity server or endpoint
+/// server descriptor refreshed callback. Fails if the tor provider cannot refresh descriptors
+/// on its own schedule.
+///
+/// @param context: the context to configure
+/// @param interval_seconds: the number of seconds after which a published descriptor is
+///  refreshed, or 0 to leave republishing to the tor provider (the default)
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_set_descriptor_republish_interval(
+    context: *mut GoslingContext,
+    interval_seconds: u32,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(context);
+
+        let mut context_tuple_registry = get_context_tuple_registry()?;
+        let context = match context_tuple_registry.get_mut(context as usize) {
+            Some(context) => context,
+            None => bail_invalid_handle!(context),
+        };
+
+        let interval = match interval_seconds {
+            0 => None,
+            interval_seconds => Some(Duration::from_secs(interval_seconds as u64)),
+        };
+        Ok(context.0.set_descriptor_republish_interval(interval)?)
+    });
+}
+
+/// Harden the circuits of the context's tor provider. Applies to circuits built after this
+/// call and to any tor provider later switched to with gosling_context_set_tor_provider().
+/// Fails if the tor provider does not support the security level, e.g. a legacy tor provider
+/// connected to a system tor daemon.
+///
+/// @param context: the context to configure
+/// @param security_level: one of the SECURITY_LEVEL_* constants (the default is
+///  SECURITY_LEVEL_STANDARD)
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_set_security_level(
+    context: *mut GoslingContext,
+    security_level: u32,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(context);
+
+        let security_level = security_level_from_ffi(security_level)?;
+
+        let mut context_tuple_registry = get_context_tuple_registry()?;
+        let context = match context_tuple_registry.get_mut(context as usize) {
+            Some(context) => context,
+            None => bail_invalid_handle!(context),
+        };
+
+        Ok(context.0.set_security_level(security_level)?)
+    });
+}
+
+/// Pass the log lines held in the tor log buffer set with gosling_context_set_tor_log_buffer()
+/// to the tor log received callback, oldest first, leaving the buffer empty.
+///
+/// @param context: the context whose tor log buffer to drain
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_poll_tor_log(
+    context: *mut GoslingContext,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(context);
+
+        // the registry's mutex must not be held while calling back into
+        // the application
+        let (lines, callback) = match get_context_tuple_registry()?.get_mut(context as usize) {
+            Some(context) => (
+                context.0.take_tor_log_lines(),
+                context.1.tor_log_received_callback,
+            ),
+            None => bail_invalid_handle!(context),
+        };
+
+        if let Some(callback) = callback {
+            for line in lines {
+                let line0 = CString::new(line.as_str())
+                    .expect("tor log line string should not have an intermediate null byte");
+                callback(context, line0.as_ptr(), line.len());
+            }
+        }
+        Ok(())
+    });
+}
+
+/// Set which of the tor provider's events a context returns. Circuit, stream and descriptor
+/// upload events are frequent and mostly of interest when diagnosing connectivity problems,
+/// so they are only returned with TOR_EVENT_VERBOSITY_VERBOSE. They have no callbacks and are
+/// only returned by gosling_context_next_event().
+///
+/// @param context: the context to configure
+/// @param verbosity: one of the TOR_EVENT_VERBOSITY_* constants (the default is
+///  TOR_EVENT_VERBOSITY_NORMAL)
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_set_tor_event_verbosity(
+    context: *mut GoslingContext,
+    verbosity: u32,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(context);
+
+        let verbosity = match verbosity {
+            TOR_EVENT_VERBOSITY_NORMAL => TorEventVerbosity::Normal,
+            TOR_EVENT_VERBOSITY_VERBOSE => TorEventVerbosity::Verbose,
+            verbosity => bail!("invalid verbosity: {}", verbosity),
+        };
+
+        let mut context_tuple_registry = get_context_tuple_registry()?;
+        let context = match context_tuple_registry.get_mut(context as usize) {
+            Some(context) => context,
+            None => bail_invalid_handle!(context),
+        };
+
+        context.0.set_tor_event_verbosity(verbosity);
+        Ok(())
+    });
+}
+
+/// Change the priority of a queued outgoing identity or endpoint handshake. Queued
+/// handshakes with a higher priority are started before those with a lower priority;
+/// handshakes with equal priority are started in the order they were begun. All
+/// handshakes are begun with a priority of 0.
+///
+/// @param context: the context associated with the handshake handle
+/// @param handshake_handle: the handle of the queued handshake
+/// @param priority: the handshake's new priority
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_set_outbound_handshake_priority(
+    context: *mut GoslingContext,
+    handshake_handle: GoslingHandshakeHandle,
+    priority: i32,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(context);
+
+        let mut context_tuple_registry = get_context_tuple_registry()?;
+        let context = match context_tuple_registry.get_mut(context as usize) {
+            Some(context) => context,
+            None => bail_invalid_handle!(context),
+        };
+
+        Ok(context
+            .0
+            .set_outbound_handshake_priority(handshake_handle, priority)?)
+    });
+}
+
+/// Change the priority of an in-progress or queued identity or endpoint handshake. When the
+/// number of handshakes updated per gosling_context_poll_events() is limited with
+/// gosling_context_set_handshake_update_budget(), in-progress handshakes with a higher priority
+/// are updated before those with a lower priority. Queued handshakes are reordered as with
+/// gosling_context_set_outbound_handshake_priority() and keep their priority once started.
+///
+/// @param context: the context associated with the handshake handle
+/// @param handshake_handle: the handle of the in-progress or queued handshake
+/// @param priority: the handshake's new priority
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_set_handshake_priority(
+    context: *mut GoslingContext,
+    handshake_handle: GoslingHandshakeHandle,
+    priority: i32,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(context);
+
+        let mut context_tuple_registry = get_context_tuple_registry()?;
+        let context = match context_tuple_registry.get_mut(context as usize) {
+            Some(context) => context,
+            None => bail_invalid_handle!(context),
+        };
+
+        Ok(context
+            .0
+            .set_handshake_priority(handshake_handle, priority)?)
+    });
+}
+
+/// Limit the number of in-progress handshakes updated by each call to
+/// gosling_context_poll_events(). Handshakes are updated in descending priority and
+/// round-robin within a priority, so every handshake is updated within a bounded number of
+/// calls.
+///
+/// @param context: the context to configure
+/// @param budget: the maximum number of handshakes updated per poll, or 0 to update every
+///  in-progress handshake (the default)
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_set_handshake_update_budget(
+    context: *mut GoslingContext,
+    budget: usize,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(context);
+
+        let mut context_tuple_registry = get_context_tuple_registry()?;
+        let context = match context_tuple_registry.get_mut(context as usize) {
+            Some(context) => context,
+            None => bail_invalid_handle!(context),
+        };
+
+        let budget = match budget {
+            0 => None,
+            budget => Some(budget),
+        };
+        Ok(context.0.set_handshake_update_budget(budget)?)
+    });
+}
+
+/// Set the priority with which an endpoint server's incoming handshakes are updated. Incoming
+/// handshakes accepted after the priority is set begin with it. The priority is discarded when
+/// the endpoint server is stopped.
+///
+/// @param context: the context running the endpoint server
+/// @param endpoint_private_key: the ed25519 private key of the endpoint server
+/// @param priority: the priority of the endpoint server's incoming handshakes (the default is 0)
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_set_endpoint_server_priority(
+    context: *mut GoslingContext,
+    endpoint_private_key: *const GoslingEd25519PrivateKey,
+    priority: i32,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(context);
+        ensure_not_null!(endpoint_private_key);
+
+        let mut context_tuple_registry = get_context_tuple_registry()?;
+        let context = match context_tuple_registry.get_mut(context as usize) {
+            Some(context) => context,
+            None => bail_invalid_handle!(context),
+        };
+
+        let ed25519_private_key_registry = get_ed25519_private_key_registry()?;
+        let endpoint_private_key =
+            match ed25519_private_key_registry.get(endpoint_private_key as usize) {
+                Some(ed25519_private_key) => ed25519_private_key,
+                None => bail_invalid_handle!(endpoint_private_key),
+            };
+
+        let endpoint_identity = V3OnionServiceId::from_private_key(endpoint_private_key);
+        Ok(context
+            .0
+            .endpoint_server_set_priority(endpoint_identity, priority)?)
+    });
+}
+
+/// Connect to and begin a handshake to request an endpoint from the given identity server
+///
+/// @param context: the context to request an endpoint server for
+/// @param identity_service_id: the service id of the identity server we want to request an endpoint server
+///  from
+/// @param endpoint_name: the name of the endpoint server to request
+/// @param endpoint_name_length: the number of chars in endpoint_name not including any null-terminator,
+///  or 0 if endpoint_name is null-terminated
+/// @param error: filled on error
+/// @return the handle of the new handshake, which is passed to the handshake's callbacks and
+///  events; or !0 (SIZE_MAX) on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_context_begin_identity_handshake(
+    context: *mut GoslingContext,
+    identity_service_id: *const GoslingV3OnionServiceId,
+    endpoint_name: *const c_char,
+    endpoint_name_length: usize,
+    error: *mut *mut GoslingError,
+) -> GoslingHandshakeHandle {
+    translate_failures(
+        !0usize,
+        error,
+        || -> anyhow::Result<GoslingHandshakeHandle> {
+            ensure_not_null!(context);
+            ensure_not_null!(identity_service_id);
+            ensure_not_null!(endpoint_name);
+
+            let mut context_tuple_registry = get_context_tuple_registry()?;
+            let context = match context_tuple_registry.get_mut(context as usize) {
+                Some(context) => context,
+                None => bail_invalid_handle!(context),
+            };
+
+            // the registry is not held while beginning the handshake, as the
+            // endpoint validator callback may insert into it
+            let identity_service_id =
+                match get_v3_onion_service_id_registry()?.get(identity_service_id as usize) {
+                    Some(v3_onion_service_id) => v3_onion_service_id.clone(),
+                    None => bail_invalid_handle!(identity_service_id),
+                };
+
+            let endpoint_name =
+                ascii_str_from_ffi(endpoint_name, endpoint_name_length, "endpoint_name")?;
+            ensure_not_empty!(endpoint_name);
+            let endpoint_name: AsciiString = endpoint_name.parse()?;
+
+            Ok(context
+                .0
+                .identity_client_begin_handshake(identity_service_id, endpoint_name)?)
+        },
+    )
+}
+
+/// Connect to and begin a handshake to request an endpoint from the given identity server,
+/// as with gosling_context_begin_identity_handshake(). The connection to the identity server
+/// is isolated using the given circuit token, so connections made with different circuit
+/// tokens (e.g. one per contact) do not share circuits through the tor network. If the
+/// circuit token is released before the connection is made, the handshake fails.
+///
+/// @param context: the context to request an endpoint server for
+/// @param identity_service_id: the service id of the identity server we want to request an endpoint server
+///  from
+/// @param endpoint_name: the name of the endpoint server to request
+/// @param endpoint_name_length: the number of chars in endpoint_name not including any null-terminator,
+///  or 0 if endpoint_name is null-terminated
+/// @param circuit_token: a circuit token from gosling_context_generate_circuit_token()
+/// @param error: filled on error
+/// @return the handle of the new handshake, which is passed to the handshake's callbacks and
+///  events; or !0 (SIZE_MAX) on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_context_begin_identity_handshake_with_circuit_token(
+    context: *mut GoslingContext,
+    identity_service_id: *const GoslingV3OnionServiceId,
+    endpoint_name: *const c_char,
+    endpoint_name_length: usize,
+    circuit_token: GoslingCircuitToken,
+    error: *mut *mut GoslingError,
+) -> GoslingHandshakeHandle {
+    translate_failures(
+        !0usize,
+        error,
+        || -> anyhow::Result<GoslingHandshakeHandle> {
+            ensure_not_null!(context);
+            ensure_not_null!(identity_service_id);
+            ensure_not_null!(endpoint_name);
+
+            let mut context_tuple_registry = get_context_tuple_registry()?;
+            let context = match context_tuple_registry.get_mut(context as usize) {
+                Some(context) => context,
+                None => bail_invalid_handle!(context),
+            };
+
+            // the registry is not held while beginning the handshake, as the
+            // endpoint validator callback may insert into it
+            let identity_service_id =
+                match get_v3_onion_service_id_registry()?.get(identity_service_id as usize) {
+                    Some(v3_onion_service_id) => v3_onion_service_id.clone(),
+                    None => bail_invalid_handle!(identity_service_id),
+                };
+
+            let endpoint_name =
+                ascii_str_from_ffi(endpoint_name, endpoint_name_length, "endpoint_name")?;
+            ensure_not_empty!(endpoint_name);
+            let endpoint_name: AsciiString = endpoint_name.parse()?;
+
+            Ok(context
+                .0
+                .identity_client_begin_handshake_with_circuit_token(
+                    identity_service_id,
+                    endpoint_name,
+                    circuit_token,
+                )?)
+        },
+    )
+}
+
+/// Connect to and begin a handshake to request an endpoint from the given identity server,
+/// followed by a handshake to open a channel on the granted endpoint server. If the identity
+/// server allows it, the endpoint handshake continues over the identity handshake's
+/// connection, skipping the connection to the endpoint server's onion service. Otherwise the
+/// endpoint server is connected to as with gosling_context_begin_endpoint_handshake().
+///
+/// The identity client handshake completed callback is called as usual, after which the
+/// endpoint client handshake callbacks are called with the same handshake handle. From then
+/// on the handshake must be aborted with gosling_context_abort_endpoint_client_handshake().
+///
+/// @param context: the context to request an endpoint server for
+/// @param identity_service_id: the service id of the identity server we want to request an endpoint server
+///  from
+/// @param endpoint_name: the name of the endpoint server to request
+/// @param endpoint_name_length: the number of chars in endpoint_name not including any null-terminator,
+///  or 0 if endpoint_name is null-terminated
+/// @param channel_name: the ascii-encoded name of the channel to open
+/// @param channel_name_length: the number of chars in channel name not including any null-terminator,
+///  or 0 if channel_name is null-terminated
+/// @param error: filled on error
+/// @return the handle of the new handshake, which is passed to the handshake's callbacks and
+///  events; or !0 (SIZE_MAX) on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_context_begin_identity_handshake_with_endpoint_upgrade(
+    context: *mut GoslingContext,
+    identity_service_id: *const GoslingV3OnionServiceId,
+    endpoint_name: *const c_char,
+    endpoint_name_length: usize,
+    channel_name: *const c_char,
+    channel_name_length: usize,
+    error: *mut *mut GoslingError,
+) -> GoslingHandshakeHandle {
+    translate_failures(
+        !0usize,
+        error,
+        || -> anyhow::Result<GoslingHandshakeHandle> {
+            ensure_not_null!(context);
+            ensure_not_null!(identity_service_id);
+            ensure_not_null!(endpoint_name);
+            ensure_not_null!(channel_name);
+
+            let mut context_tuple_registry = get_context_tuple_registry()?;
+            let context = match context_tuple_registry.get_mut(context as usize) {
+                Some(context) => context,
+                None => bail_invalid_handle!(context),
+            };
+
+            // the registry is not held while beginning the handshake, as the
+            // endpoint validator callback may insert into it
+            let identity_service_id =
+                match get_v3_onion_service_id_registry()?.get(identity_service_id as usize) {
+                    Some(v3_onion_service_id) => v3_onion_service_id.clone(),
+                    None => bail_invalid_handle!(identity_service_id),
+                };
+
+            let endpoint_name =
+                ascii_str_from_ffi(endpoint_name, endpoint_name_length, "endpoint_name")?;
+            ensure_not_empty!(endpoint_name);
+            let endpoint_name: AsciiString = endpoint_name.parse()?;
+
+            let channel_name =
+                ascii_str_from_ffi(channel_name, channel_name_length, "channel_name")?;
+            ensure_not_empty!(channel_name);
+            let channel_name: AsciiString = channel_name.parse()?;
+
+            Ok(context
+                .0
+                .identity_client_begin_handshake_with_endpoint_upgrade(
+                    identity_service_id,
+                    endpoint_name,
+                    channel_name,
+                )?)
+        },
+    )
+}
+
+/// Connect to and begin a handshake to request an endpoint from the given identity server,
+/// as with gosling_context_begin_identity_handshake(), attaching a contact request (e.g. a
+/// petname or an introduction) to the endpoint request. The identity server receives it in
+/// its identity server contact request received callback. Identity servers which do not
+/// support contact requests ignore it.
+///
+/// @param context: the context to request an endpoint server for
+/// @param identity_service_id: the service id of the identity server we want to request an endpoint server
+///  from
+/// @param endpoint_name: the name of the endpoint server to request
+/// @param endpoint_name_length: the number of chars in endpoint_name not including any null-terminator,
+///  or 0 if endpoint_name is null-terminated
+/// @param contact_request: the UTF-8 encoded contact request of at most 512 bytes
+/// @param contact_request_length: the number of bytes in contact_request not including any
+///  null-terminator, or 0 if contact_request is null-terminated
+/// @param error: filled on error
+/// @return the handle of the new handshake, which is passed to the handshake's callbacks and
+///  events; or !0 (SIZE_MAX) on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_context_begin_identity_handshake_with_contact_request(
+    context: *mut GoslingContext,
+    identity_service_id: *const GoslingV3OnionServiceId,
+    endpoint_name: *const c_char,
+    endpoint_name_length: usize,
+    contact_request: *const c_char,
+    contact_request_length: usize,
+    error: *mut *mut GoslingError,
+) -> GoslingHandshakeHandle {
+    translate_failures(
+        !0usize,
+        error,
+        || -> anyhow::Result<GoslingHandshakeHandle> {
+            ensure_not_null!(context);
+            ensure_not_null!(identity_service_id);
+            ensure_not_null!(endpoint_name);
+            ensure_not_null!(contact_request);
+
+            let mut context_tuple_registry = get_context_tuple_registry()?;
+            let context = match context_tuple_registry.get_mut(context as usize) {
+                Some(context) => context,
+                None => bail_invalid_handle!(context),
+            };
+
+            // the registry is not held while beginning the handshake, as the
+            // endpoint validator callback may insert into it
+            let identity_service_id =
+                match get_v3_onion_service_id_registry()?.get(identity_service_id as usize) {
+                    Some(v3_onion_service_id) => v3_onion_service_id.clone(),
+                    None => bail_invalid_handle!(identity_service_id),
+                };
+
+            let endpoint_name =
+                ascii_str_from_ffi(endpoint_name, endpoint_name_length, "endpoint_name")?;
+            ensure_not_empty!(endpoint_name);
+            let endpoint_name: AsciiString = endpoint_name.parse()?;
+
+            let contact_request =
+                str_from_ffi(contact_request, contact_request_length, "contact_request")?;
+            let contact_request = contact_request.to_string();
+
+            Ok(context
+                .0
+                .identity_client_begin_handshake_with_contact_request(
+                    identity_service_id,
+                    endpoint_name,
+                    contact_request,
+                )?)
+        },
+    )
+}
+
+/// Abort an in-progress identity client handshake
+///
+/// @param context: the context associated with the identity client handshake handle
+/// @param handshake_handle: the handle associated with the identity client handshake
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_abort_identity_client_handshake(
+    context: *mut GoslingContext,
+    handshake_handle: GoslingHandshakeHandle,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(context);
+
+        let mut context_tuple_registry = get_context_tuple_registry()?;
+        let context = match context_tuple_registry.get_mut(context as usize) {
+            Some(context) => context,
+            None => bail_invalid_handle!(context),
+        };
+
+        Ok(context
+            .0
+            .identity_client_abort_handshake(handshake_handle)?)
+    })
+}
+
+/// Abort an in-progress identity server handshake. The connection to the identity client is
+/// closed and the identity server handshake failed callback is invoked with the handle
+/// during the next call to gosling_context_poll_events().
+///
+/// @param context: the context associated with the identity server handshake handle
+/// @param handshake_handle: the handle associated with the identity server handshake
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_abort_identity_server_handshake(
+    context: *mut GoslingContext,
+    handshake_handle: GoslingHandshakeHandle,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(context);
+
+        let mut context_tuple_registry = get_context_tuple_registry()?;
+        let context = match context_tuple_registry.get_mut(context as usize) {
+            Some(context) => context,
+            None => bail_invalid_handle!(context),
+        };
+
+        Ok(context
+            .0
+            .identity_server_abort_handshake(handshake_handle)?)
+    })
+}
+
+/// Get the number of requests for an endpoint received by the context's identity server,
+/// and the number of those handshakes which were granted, rejected (in total and by each
+/// rejection reason) or which failed. Handshakes which fail before their endpoint request is
+/// received are not counted, and once 256 distinct endpoint names have been requested
+/// further names are not tracked and report all zeroes.
+///
+/// @param context: the context whose statistics to query
+/// @param endpoint_name: the ascii-encoded name of the requested endpoint
+/// @param endpoint_name_length: the number of chars in endpoint name not including any null-terminator,
+///  or 0 if endpoint_name is null-terminated
+/// @param out_stats: an array whose element at index i is set to the statistic whose
+///  ENDPOINT_REQUEST_STAT_* constant is i
+/// @param stats_count: the number of elements in out_stats; at most
+///  ENDPOINT_REQUEST_STAT_COUNT elements are set
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_context_get_identity_server_endpoint_stats(
+    context: *mut GoslingContext,
+    endpoint_name: *const c_char,
+    endpoint_name_length: usize,
+    out_stats: *mut u64,
+    stats_count: usize,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(context);
+        ensure_not_null!(endpoint_name);
+        ensure_not_null!(out_stats);
+
+        let endpoint_name =
+            ascii_str_from_ffi(endpoint_name, endpoint_name_length, "endpoint_name")?;
+        ensure_not_empty!(endpoint_name);
+
+        let endpoint_stats = {
+            let context_tuple_registry = get_context_tuple_registry()?;
+            let context = match context_tuple_registry.get(context as usize) {
+                Some(context) => context,
+                None => bail_invalid_handle!(context),
+            };
+            context.0.identity_server_stats().endpoint(endpoint_name)
+        };
+
+        let values: [u64; ENDPOINT_REQUEST_STAT_COUNT] = [
+            endpoint_stats.requests,
+            endpoint_stats.grants,
+            endpoint_stats.rejections,
+            endpoint_stats.client_not_allowed,
+            endpoint_stats.requested_endpoint_invalid,
+            endpoint_stats.client_proof_signature_invalid,
+            endpoint_stats.client_auth_signature_invalid,
+            endpoint_stats.challenge_response_invalid,
+            endpoint_stats.failures,
+        ];
+        let out_stats = std::slice::from_raw_parts_mut(
+            out_stats,
+            std::cmp::min(stats_count, ENDPOINT_REQUEST_STAT_COUNT),
+        );
+        out_stats.copy_from_slice(&values[..out_stats.len()]);
+        Ok(())
+    })
+}
+
+/// Discard the statistics returned by gosling_context_get_identity_server_endpoint_stats()
+///
+/// @param context: the context whose statistics to discard
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_reset_identity_server_stats(
+    context: *mut GoslingContext,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(context);
+
+        let mut context_tuple_registry = get_context_tuple_registry()?;
+        let context = match context_tuple_registry.get_mut(context as usize) {
+            Some(context) => context,
+            None => bail_invalid_handle!(context),
+        };
+        context.0.identity_server_reset_stats();
+        Ok(())
+    })
+}
+
+/// Set whether the endpoint servers granted by the context's completed identity handshakes
+/// are pinned on first use. Each granted endpoint server is then compared against the endpoint
+/// server pinned for its identity server's endpoint, and the identity client endpoint pin
+/// mismatched callback is called for each mismatch. Pins are held in memory and are discarded
+/// when pinning is disabled.
+///
+/// @param context: the context to configure
+/// @param enabled: whether granted endpoint servers are pinned (the default is false)
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_set_endpoint_pinning_enabled(
+    context: *mut GoslingContext,
+    enabled: bool,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(context);
+
+        let mut context_tuple_registry = get_context_tuple_registry()?;
+        let context = match context_tuple_registry.get_mut(context as usize) {
+            Some(context) => context,
+            None => bail_invalid_handle!(context),
+        };
+
+        match (enabled, context.0.identity_client_pin_store().is_some()) {
+            (true, false) => context
+                .0
+                .identity_client_set_pin_store(Some(PinStore::new())),
+            (false, true) => context.0.identity_client_set_pin_store(None),
+            _ => (),
+        }
+        Ok(())
+    });
+}
+
+/// Get the endpoint server pinned for an identity server's endpoint
+///
+/// @param context: the context whose pins to query; pinning must be enabled with
+///  gosling_context_set_endpoint_pinning_enabled()
+/// @param identity_service_id: the identity server which granted the endpoint
+/// @param endpoint_name: the ascii-encoded name of the endpoint
+/// @param endpoint_name_length: the number of chars in endpoint name not including any null-terminator,
+///  or 0 if endpoint_name is null-terminated
+/// @param out_service_id: returned service id object of the pinned endpoint server, or null if
+///  no endpoint server is pinned
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_context_get_pinned_endpoint(
+    context: *mut GoslingContext,
+    identity_service_id: *const GoslingV3OnionServiceId,
+    endpoint_name: *const c_char,
+    endpoint_name_length: usize,
+    out_service_id: *mut *mut GoslingV3OnionServiceId,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(context);
+        ensure_not_null!(identity_service_id);
+        ensure_not_null!(endpoint_name);
+        ensure_not_null!(out_service_id);
+
+        let endpoint_name =
+            ascii_str_from_ffi(endpoint_name, endpoint_name_length, "endpoint_name")?;
+        ensure_not_empty!(endpoint_name);
+
+        let pinned_endpoint_service_id = {
+            let context_tuple_registry = get_context_tuple_registry()?;
+            let context = match context_tuple_registry.get(context as usize) {
+                Some(context) => context,
+                None => bail_invalid_handle!(context),
+            };
+            let pin_store = match context.0.identity_client_pin_store() {
+                Some(pin_store) => pin_store,
+                None => bail!("endpoint pinning is not enabled"),
+            };
+
+            let v3_onion_service_id_registry = get_v3_onion_service_id_registry()?;
+            let identity_service_id =
+                match v3_onion_service_id_registry.get(identity_service_id as usize) {
+                    Some(v3_onion_service_id) => v3_onion_service_id,
+                    None => bail_invalid_handle!(identity_service_id),
+                };
+            pin_store
+                .get(identity_service_id, endpoint_name)
+                .map(|pin| pin.endpoint_service_id.clone())
+        };
+
+        *out_service_id = match pinned_endpoint_service_id {
+            Some(service_id) => {
+                let handle = get_v3_onion_service_id_registry()?.try_insert(service_id)?;
+                handle as *mut GoslingV3OnionServiceId
+            }
+            None => std::ptr::null_mut(),
+        };
+        Ok(())
+    })
+}
+
+// the context's petname store, or an error if petnames are not enabled
+fn petname_store(context: &Context) -> anyhow::Result<&PetnameStore> {
+    match context.petname_store() {
+        Some(petname_store) => Ok(petname_store),
+        None => bail!("petnames are not enabled"),
+    }
+}
+
+// mutable variant of petname_store()
+fn petname_store_mut(context: &mut Context) -> anyhow::Result<&mut PetnameStore> {
+    match context.petname_store_mut() {
+        Some(petname_store) => Ok(petname_store),
+        None => bail!("petnames are not enabled"),
+    }
+}
+
+/// Set whether the context keeps petnames for the identities known to the application. The
+/// endpoint servers granted by the context's completed identity handshakes with an identity
+/// which has a petname are recorded alongside its petname. Petnames enabled with this function
+/// are held in memory and are discarded when petnames are disabled; see
+/// gosling_context_open_petnames() to persist them.
+///
+/// @param context: the context to configure
+/// @param enabled: whether the context keeps petnames (the default is false)
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_set_petnames_enabled(
+    context: *mut GoslingContext,
+    enabled: bool,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(context);
+
+        let mut context_tuple_registry = get_context_tuple_registry()?;
+        let context = match context_tuple_registry.get_mut(context as usize) {
+            Some(context) => context,
+            None => bail_invalid_handle!(context),
+        };
+
+        match (enabled, context.0.petname_store().is_some()) {
+            (true, false) => context.0.set_petname_store(Some(PetnameStore::new())),
+            (false, true) => context.0.set_petname_store(None),
+            _ => (),
+        }
+        Ok(())
+    });
+}
+
+/// Enable petnames as with gosling_context_set_petnames_enabled(), keeping them in a file. The
+/// petnames previously saved in the file are loaded, and the file is updated whenever the
+/// context's petnames or their recorded endpoint grants change. The file contains the client
+/// authorization keys of the recorded endpoint grants, so it must be kept secret. Any petnames
+/// the context already has are replaced.
+///
+/// @param context: the context to configure
+/// @param path: the utf8-encoded path of the petnames file, which is created if it does not exist
+/// @param path_length: the number of chars in path not including any null-terminator, or 0 if
+///  path is null-terminated
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_context_open_petnames(
+    context: *mut GoslingContext,
+    path: *const c_char,
+    path_length: usize,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(context);
+        ensure_not_null!(path);
+
+        let path = str_from_ffi(path, path_length, "path")?;
+        ensure_not_empty!(path);
+
+        let mut context_tuple_registry = get_context_tuple_registry()?;
+        let context = match context_tuple_registry.get_mut(context as usize) {
+            Some(context) => context,
+            None => bail_invalid_handle!(context),
+        };
+
+        let petname_store = PetnameStore::open(path)?;
+        context.0.set_petname_store(Some(petname_store));
+        Ok(())
+    });
+}
+
+/// Give an identity a petname. Each petname names one identity and each identity has at most
+/// one petname.
+///
+/// @param context: the context whose petnames to update; petnames must be enabled with
+///  gosling_context_set_petnames_enabled() or gosling_context_open_petnames()
+/// @param petname: the utf8-encoded petname, at most 256 bytes long and not given to any other
+///  identity
+/// @param petname_length: the number of chars in petname not including any null-terminator, or 0
+///  if petname is null-terminated
+/// @param identity_service_id: the identity server to name; it must not already have a different
+///  petname
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_context_set_petname(
+    context: *mut GoslingContext,
+    petname: *const c_char,
+    petname_length: usize,
+    identity_service_id: *const GoslingV3OnionServiceId,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(context);
+        ensure_not_null!(petname);
+        ensure_not_null!(identity_service_id);
+
+        let petname = str_from_ffi(petname, petname_length, "petname")?;
+        ensure_not_empty!(petname);
+
+        let identity_service_id =
+            match get_v3_onion_service_id_registry()?.get(identity_service_id as usize) {
+                Some(identity_service_id) => identity_service_id.clone(),
+                None => bail_invalid_handle!(identity_service_id),
+            };
+
+        let mut context_tuple_registry = get_context_tuple_registry()?;
+        let context = match context_tuple_registry.get_mut(context as usize) {
+            Some(context) => context,
+            None => bail_invalid_handle!(context),
+        };
+
+        let petname_store = petname_store_mut(&mut context.0)?;
+        petname_store.set(petname.to_string(), identity_service_id)?;
+        petname_store.save()?;
+        Ok(())
+    });
+}
+
+/// Change a petname, keeping its identity and recorded endpoint grants
+///
+/// @param context: the context whose petnames to update; petnames must be enabled with
+///  gosling_context_set_petnames_enabled() or gosling_context_open_petnames()
+/// @param petname: the utf8-encoded current petname
+/// @param petname_length: the number of chars in petname not including any null-terminator, or 0
+///  if petname is null-terminated
+/// @param new_petname: the utf8-encoded new petname, at most 256 bytes long and not given to any
+///  other identity
+/// @param new_petname_length: the number of chars in new_petname not including any
+///  null-terminator, or 0 if new_petname is null-terminated
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_context_rename_petname(
+    context: *mut GoslingContext,
+    petname: *const c_char,
+    petname_length: usize,
+    new_petname: *const c_char,
+    new_petname_length: usize,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(context);
+        ensure_not_null!(petname);
+        ensure_not_null!(new_petname);
+
+        let petname = str_from_ffi(petname, petname_length, "petname")?;
+        ensure_not_empty!(petname);
+        let new_petname = str_from_ffi(new_petname, new_petname_length, "new_petname")?;
+        ensure_not_empty!(new_petname);
+
+        let mut context_tuple_registry = get_context_tuple_registry()?;
+        let context = match context_tuple_registry.get_mut(context as usize) {
+            Some(context) => context,
+            None => bail_invalid_handle!(context),
+        };
+
+        let petname_store = petname_store_mut(&mut context.0)?;
+        petname_store.rename(petname, new_petname.to_string())?;
+        petname_store.save()?;
+        Ok(())
+    });
+}
+
+/// Remove a petname along with its recorded endpoint grants. Removing a petname which does not
+/// exist is not an error.
+///
+/// @param context: the context whose petnames to update; petnames must be enabled with
+///  gosling_context_set_petnames_enabled() or gosling_context_open_petnames()
+/// @param petname: the utf8-encoded petname to remove
+/// @param petname_length: the number of chars in petname not including any null-terminator, or 0
+///  if petname is null-terminated
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_context_remove_petname(
+    context: *mut GoslingContext,
+    petname: *const c_char,
+    petname_length: usize,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(context);
+        ensure_not_null!(petname);
+
+        let petname = str_from_ffi(petname, petname_length, "petname")?;
+        ensure_not_empty!(petname);
+
+        let mut context_tuple_registry = get_context_tuple_registry()?;
+        let context = match context_tuple_registry.get_mut(context as usize) {
+            Some(context) => context,
+            None => bail_invalid_handle!(context),
+        };
+
+        let petname_store = petname_store_mut(&mut context.0)?;
+        if petname_store.remove(petname).is_some() {
+            petname_store.save()?;
+        }
+        Ok(())
+    });
+}
+
+/// Get the identity named by a petname
+///
+/// @param context: the context whose petnames to query; petnames must be enabled with
+///  gosling_context_set_petnames_enabled() or gosling_context_open_petnames()
+/// @param petname: the utf8-encoded petname
+/// @param petname_length: the number of chars in petname not including any null-terminator, or 0
+///  if petname is null-terminated
+/// @param out_identity_service_id: returned service id object of the named identity server, or
+///  null if no identity has the petname
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_context_get_petname_identity(
+    context: *mut GoslingContext,
+    petname: *const c_char,
+    petname_length: usize,
+    out_identity_service_id: *mut *mut GoslingV3OnionServiceId,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(context);
+        ensure_not_null!(petname);
+        ensure_not_null!(out_identity_service_id);
+
+        let petname = str_from_ffi(petname, petname_length, "petname")?;
+        ensure_not_empty!(petname);
+
+        let identity_service_id = {
+            let context_tuple_registry = get_context_tuple_registry()?;
+            let context = match context_tuple_registry.get(context as usize) {
+                Some(context) => context,
+                None => bail_invalid_handle!(context),
+            };
+            petname_store(&context.0)?
+                .lookup(petname)
+                .map(|contact| contact.identity_service_id.clone())
+        };
+
+        *out_identity_service_id = match identity_service_id {
+            Some(service_id) => {
+                let handle = get_v3_onion_service_id_registry()?.try_insert(service_id)?;
+                handle as *mut GoslingV3OnionServiceId
+            }
+            None => std::ptr::null_mut(),
+        };
+        Ok(())
+    })
+}
+
+/// Get the most recent endpoint grant recorded for a petname's endpoint
+///
+/// @param context: the context whose petnames to query; petnames must be enabled with
+///  gosling_context_set_petnames_enabled() or gosling_context_open_petnames()
+/// @param petname: the utf8-encoded petname
+/// @param petname_length: the number of chars in petname not including any null-terminator, or 0
+///  if petname is null-terminated
+/// @param endpoint_name: the ascii-encoded name of the endpoint
+/// @param endpoint_name_length: the number of chars in endpoint name not including any
+///  null-terminator, or 0 if endpoint_name is null-terminated
+/// @param out_endpoint_grant: returned endpoint grant object, or null if no grant of the endpoint
+///  is recorded for the petname
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_context_get_petname_endpoint_grant(
+    context: *mut GoslingContext,
+    petname: *const c_char,
+    petname_length: usize,
+    endpoint_name: *const c_char,
+    endpoint_name_length: usize,
+    out_endpoint_grant: *mut *mut GoslingEndpointGrant,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(context);
+        ensure_not_null!(petname);
+        ensure_not_null!(endpoint_name);
+        ensure_not_null!(out_endpoint_grant);
+
+        let petname = str_from_ffi(petname, petname_length, "petname")?;
+        ensure_not_empty!(petname);
+        let endpoint_name =
+            ascii_str_from_ffi(endpoint_name, endpoint_name_length, "endpoint_name")?;
+        ensure_not_empty!(endpoint_name);
+
+        let endpoint_grant = {
+            let context_tuple_registry = get_context_tuple_registry()?;
+            let context = match context_tuple_registry.get(context as usize) {
+                Some(context) => context,
+                None => bail_invalid_handle!(context),
+            };
+            petname_store(&context.0)?
+                .endpoint_grant(petname, endpoint_name)
+                .cloned()
+        };
+
+        *out_endpoint_grant = match endpoint_grant {
+            Some(endpoint_grant) => {
+                let handle = get_endpoint_grant_registry()?.try_insert(endpoint_grant)?;
+                handle as *mut GoslingEndpointGrant
+            }
+            None => std::ptr::null_mut(),
+        };
+        Ok(())
+    })
+}
+
+/// Get the size of the buffer required by gosling_context_get_petname()
+///
+/// @param context: the context whose petnames to query; petnames must be enabled with
+///  gosling_context_set_petnames_enabled() or gosling_context_open_petnames()
+/// @param identity_service_id: the identity server whose petname to query
+/// @param error: filled on error
+/// @return the number of bytes required to hold the petname including the null-terminator, or 0
+///  if the identity has no petname
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_get_petname_size(
+    context: *mut GoslingContext,
+    identity_service_id: *const GoslingV3OnionServiceId,
+    error: *mut *mut GoslingError,
+) -> usize {
+    translate_failures(0, error, || -> anyhow::Result<usize> {
+        ensure_not_null!(context);
+        ensure_not_null!(identity_service_id);
+
+        let context_tuple_registry = get_context_tuple_registry()?;
+        let context = match context_tuple_registry.get(context as usize) {
+            Some(context) => context,
+            None => bail_invalid_handle!(context),
+        };
+
+        let v3_onion_service_id_registry = get_v3_onion_service_id_registry()?;
+        let identity_service_id =
+            match v3_onion_service_id_registry.get(identity_service_id as usize) {
+                Some(identity_service_id) => identity_service_id,
+                None => bail_invalid_handle!(identity_service_id),
+            };
+
+        Ok(petname_store(&context.0)?
+            .petname_of(identity_service_id)
+            .map_or(0, |petname| petname.len() + 1))
+    })
+}
+
+/// Get the petname of an identity as a null-terminated string
+///
+/// @param context: the context whose petnames to query; petnames must be enabled with
+///  gosling_context_set_petnames_enabled() or gosling_context_open_petnames()
+/// @param identity_service_id: the identity server whose petname to query; it must have a petname
+/// @param out_petname: buffer to be filled with the utf8-encoded petname
+/// @param petname_size: size of out_petname buffer in bytes, must be at least the value returned
+///  by gosling_context_get_petname_size()
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_context_get_petname(
+    context: *mut GoslingContext,
+    identity_service_id: *const GoslingV3OnionServiceId,
+    out_petname: *mut c_char,
+    petname_size: usize,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(context);
+        ensure_not_null!(identity_service_id);
+        ensure_not_null!(out_petname);
+
+        let context_tuple_registry = get_context_tuple_registry()?;
+        let context = match context_tuple_registry.get(context as usize) {
+            Some(context) => context,
+            None => bail_invalid_handle!(context),
+        };
+
+        let v3_onion_service_id_registry = get_v3_onion_service_id_registry()?;
+        let identity_service_id =
+            match v3_onion_service_id_registry.get(identity_service_id as usize) {
+                Some(identity_service_id) => identity_service_id,
+                None => bail_invalid_handle!(identity_service_id),
+            };
+
+        let petname = match petname_store(&context.0)?.petname_of(identity_service_id) {
+            Some(petname) => petname,
+            None => bail!("identity_service_id has no petname"),
+        };
+
+        if petname_size < petname.len() + 1 {
+            bail!(
+                "petname_size must be at least '{}', received '{}'",
+                petname.len() + 1,
+                petname_size
+            );
+        }
+
+        copy_to_buffer(petname, out_petname, petname_size);
+
+        Ok(())
+    })
+}
+
+// the context's ban list, or an error if bans are not enabled
+fn ban_list(context: &Context) -> anyhow::Result<&BanList> {
+    match context.ban_list() {
+        Some(ban_list) => Ok(ban_list),
+        None => bail!("bans are not enabled"),
+    }
+}
+
+/// Set whether the context's identity and endpoint servers turn away banned peers. Banned
+/// identity clients are refused when they begin an identity handshake, before the identity
+/// server client allowed callback is called, and banned endpoint clients are refused once they
+/// have proven their identity; either failure is reported to the server's handshake failed
+/// callback with ERROR_CODE_CLIENT_BANNED. Bans enabled with this function are held in memory
+/// and are discarded when bans are disabled; see gosling_context_open_bans() to persist them.
+///
+/// @param context: the context to configure
+/// @param enabled: whether the context turns away banned peers (the default is false)
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_set_bans_enabled(
+    context: *mut GoslingContext,
+    enabled: bool,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(context);
+
+        let mut context_tuple_registry = get_context_tuple_registry()?;
+        let context = match context_tuple_registry.get_mut(context as usize) {
+            Some(context) => context,
+            None => bail_invalid_handle!(context),
+        };
+
+        match (enabled, context.0.ban_list().is_some()) {
+            (true, false) => context.0.set_ban_list(Some(BanList::new())),
+            (false, true) => context.0.set_ban_list(None),
+            _ => (),
+        }
+        Ok(())
+    });
+}
+
+/// Enable bans as with gosling_context_set_bans_enabled(), keeping them in a file. The bans
+/// previously saved in the file are loaded, and the file is updated whenever a peer is banned or
+/// unbanned or a ban expires. Any bans the context already has are replaced.
+///
+/// @param context: the context to configure
+/// @param path: the utf8-encoded path of the bans file, which is created if it does not exist
+/// @param path_length: the number of chars in path not including any null-terminator, or 0 if
+///  path is null-terminated
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_context_open_bans(
+    context: *mut GoslingContext,
+    path: *const c_char,
+    path_length: usize,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(context);
+        ensure_not_null!(path);
+
+        let path = str_from_ffi(path, path_length, "path")?;
+        ensure_not_empty!(path);
+
+        let mut context_tuple_registry = get_context_tuple_registry()?;
+        let context = match context_tuple_registry.get_mut(context as usize) {
+            Some(context) => context,
+            None => bail_invalid_handle!(context),
+        };
+
+        let ban_list = BanList::open(path)?;
+        context.0.set_ban_list(Some(ban_list));
+        Ok(())
+    });
+}
+
+/// Ban a peer from the context's identity and endpoint servers, replacing any existing ban of
+/// the same peer. The ban applies to handshakes which begin after this function returns.
+///
+/// @param context: the context whose bans to update; bans must be enabled with
+///  gosling_context_set_bans_enabled() or gosling_context_open_bans()
+/// @param client_service_id: the identity of the peer to ban
+/// @param duration_seconds: how long the ban lasts in seconds, or 0 for a permanent ban
+/// @param reason: the utf8-encoded reason for the ban, at most 1024 bytes long; it is reported
+///  with the ban's EVENT_TYPE_*_BANNED_CLIENT_REJECTED events but never sent to the peer
+/// @param reason_length: the number of chars in reason not including any null-terminator, or 0
+///  if reason is null-terminated
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_context_ban_peer(
+    context: *mut GoslingContext,
+    client_service_id: *const GoslingV3OnionServiceId,
+    duration_seconds: u64,
+    reason: *const c_char,
+    reason_length: usize,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(context);
+        ensure_not_null!(client_service_id);
+        ensure_not_null!(reason);
+
+        let reason = str_from_ffi(reason, reason_length, "reason")?;
+        if reason.contains('\0') {
+            bail!("reason must not contain a null byte");
+        }
+        let duration = match duration_seconds {
+            0 => None,
+            duration_seconds => Some(Duration::from_secs(duration_seconds)),
+        };
+
+        let context_tuple_registry = get_context_tuple_registry()?;
+        let context = match context_tuple_registry.get(context as usize) {
+            Some(context) => context,
+            None => bail_invalid_handle!(context),
+        };
+        let ban_list = ban_list(&context.0)?;
+
+        let client_service_id =
+            match get_v3_onion_service_id_registry()?.get(client_service_id as usize) {
+                Some(client_service_id) => client_service_id.clone(),
+                None => bail_invalid_handle!(client_service_id),
+            };
+
+        ban_list.ban(client_service_id, duration, reason.to_string())?;
+        ban_list.save()?;
+        Ok(())
+    });
+}
+
+/// Lift a peer's ban
+///
+/// @param context: the context whose bans to update; bans must be enabled with
+///  gosling_context_set_bans_enabled() or gosling_context_open_bans()
+/// @param client_service_id: the identity of the peer to unban
+/// @param error: filled on error
+/// @return true if the peer was banned
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_unban_peer(
+    context: *mut GoslingContext,
+    client_service_id: *const GoslingV3OnionServiceId,
+    error: *mut *mut GoslingError,
+) -> bool {
+    translate_failures(false, error, || -> anyhow::Result<bool> {
+        ensure_not_null!(context);
+        ensure_not_null!(client_service_id);
+
+        let context_tuple_registry = get_context_tuple_registry()?;
+        let context = match context_tuple_registry.get(context as usize) {
+            Some(context) => context,
+            None => bail_invalid_handle!(context),
+        };
+        let ban_list = ban_list(&context.0)?;
+
+        let v3_onion_service_id_registry = get_v3_onion_service_id_registry()?;
+        let client_service_id = match v3_onion_service_id_registry.get(client_service_id as usize) {
+            Some(client_service_id) => client_service_id,
+            None => bail_invalid_handle!(client_service_id),
+        };
+
+        let unbanned = ban_list.unban(client_service_id).is_some();
+        if unbanned {
+            ban_list.save()?;
+        }
+        Ok(unbanned)
+    })
+}
+
+/// Get whether a peer is banned
+///
+/// @param context: the context whose bans to query; bans must be enabled with
+///  gosling_context_set_bans_enabled() or gosling_context_open_bans()
+/// @param client_service_id: the identity of the peer to query
+/// @param out_expires_at: returned time the ban expires in seconds since the unix epoch, or 0 if
+///  the ban is permanent or the peer is not banned; may be null
+/// @param error: filled on error
+/// @return true if the peer is banned
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_context_get_peer_banned(
+    context: *mut GoslingContext,
+    client_service_id: *const GoslingV3OnionServiceId,
+    out_expires_at: *mut u64,
+    error: *mut *mut GoslingError,
+) -> bool {
+    translate_failures(false, error, || -> anyhow::Result<bool> {
+        ensure_not_null!(context);
+        ensure_not_null!(client_service_id);
+
+        let context_tuple_registry = get_context_tuple_registry()?;
+        let context = match context_tuple_registry.get(context as usize) {
+            Some(context) => context,
+            None => bail_invalid_handle!(context),
+        };
+        let ban_list = ban_list(&context.0)?;
+
+        let v3_onion_service_id_registry = get_v3_onion_service_id_registry()?;
+        let client_service_id = match v3_onion_service_id_registry.get(client_service_id as usize) {
+            Some(client_service_id) => client_service_id,
+            None => bail_invalid_handle!(client_service_id),
+        };
+
+        let ban = ban_list.get(client_service_id);
+        if !out_expires_at.is_null() {
+            *out_expires_at = ban
+                .as_ref()
+                .and_then(|ban| ban.expires_at)
+                .and_then(|expires_at| expires_at.duration_since(std::time::UNIX_EPOCH).ok())
+                .map_or(0, |since_epoch| since_epoch.as_secs());
+        }
+        Ok(ban.is_some())
+    })
+}
+
+/// Pin an endpoint server for an identity server's endpoint, replacing any existing pin, e.g.
+/// to accept the endpoint server reported by the identity client endpoint pin mismatched
+/// callback
+///
+/// @param context: the context whose pins to update; pinning must be enabled with
+///  gosling_context_set_endpoint_pinning_enabled()
+/// @param identity_service_id: the identity server which granted the endpoint
+/// @param endpoint_name: the ascii-encoded name of the endpoint
+/// @param endpoint_name_length: the number of chars in endpoint name not including any null-terminator,
+///  or 0 if endpoint_name is null-terminated
+/// @param endpoint_service_id: the endpoint server to pin, or null to remove the endpoint's pin
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_context_pin_endpoint(
+    context: *mut GoslingContext,
+    identity_service_id: *const GoslingV3OnionServiceId,
+    endpoint_name: *const c_char,
+    endpoint_name_length: usize,
+    endpoint_service_id: *const GoslingV3OnionServiceId,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(context);
+        ensure_not_null!(identity_service_id);
+        ensure_not_null!(endpoint_name);
+
+        let endpoint_name =
+            ascii_str_from_ffi(endpoint_name, endpoint_name_length, "endpoint_name")?;
+        ensure_not_empty!(endpoint_name);
+
+        let mut context_tuple_registry = get_context_tuple_registry()?;
+        let context = match context_tuple_registry.get_mut(context as usize) {
+            Some(context) => context,
+            None => bail_invalid_handle!(context),
+        };
+        let pin_store = match context.0.identity_client_pin_store_mut() {
+            Some(pin_store) => pin_store,
+            None => bail!("endpoint pinning is not enabled"),
+        };
+
+        let v3_onion_service_id_registry = get_v3_onion_service_id_registry()?;
+        let identity_service_id =
+            match v3_onion_service_id_registry.get(identity_service_id as usize) {
+                Some(v3_onion_service_id) => v3_onion_service_id,
+                None => bail_invalid_handle!(identity_service_id),
+            };
+        if endpoint_service_id.is_null() {
+            pin_store.unpin(identity_service_id, endpoint_name);
+        } else {
+            let endpoint_service_id =
+                match v3_onion_service_id_registry.get(endpoint_service_id as usize) {
+                    Some(v3_onion_service_id) => v3_onion_service_id,
+                    None => bail_invalid_handle!(endpoint_service_id),
+                };
+            pin_store.pin(
+                identity_service_id.clone(),
+                endpoint_name.to_string(),
+                endpoint_service_id.clone(),
+            );
+        }
+        Ok(())
+    })
+}
+
+/// Connect to and begin a handshake to request a channel from the given endpoint server
+///
+/// @param context: the context which will be opening the channel
+/// @param endpoint_service_id: the endpoint server to open a channel to
+/// @param client_auth_private_key: the x25519 clienth authorization key needed to decrypt the endpoint server's
+///  onion service descriptor
+/// @param channel_name: the ascii-encoded name of the channel to open
+/// @param channel_name_length: the number of chars in channel name not including any null-terminator,
+///  or 0 if channel_name is null-terminated
+/// @param error: filled on error
+/// @return the handle of the new handshake, which is passed to the handshake's callbacks and
+///  events; or !0 (SIZE_MAX) on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_context_begin_endpoint_handshake(
+    context: *mut GoslingContext,
+    endpoint_service_id: *const GoslingV3OnionServiceId,
+    client_auth_private_key: *const GoslingX25519PrivateKey,
+    channel_name: *const c_char,
+    channel_name_length: usize,
+    error: *mut *mut GoslingError,
+) -> GoslingHandshakeHandle {
+    translate_failures(
+        !0usize,
+        error,
+        || -> anyhow::Result<GoslingHandshakeHandle> {
+            ensure_not_null!(context);
+            ensure_not_null!(endpoint_service_id);
+            ensure_not_null!(client_auth_private_key);
+            ensure_not_null!(channel_name);
+
+            let mut context_tuple_registry = get_context_tuple_registry()?;
+            let context = match context_tuple_registry.get_mut(context as usize) {
+                Some(context) => context,
+                None => bail_invalid_handle!(context),
+            };
+
+            let v3_onion_service_id_registry = get_v3_onion_service_id_registry()?;
+            let endpoint_service_id =
+                match v3_onion_service_id_registry.get(endpoint_service_id as usize) {
+                    Some(v3_onion_service_id) => v3_onion_service_id,
+                    None => bail_invalid_handle!(endpoint_service_id),
+                };
+
+            let x25519_private_key_registry = get_x25519_private_key_registry()?;
+            let client_auth_private_key =
+                match x25519_private_key_registry.get(client_auth_private_key as usize) {
+                    Some(x25519_private_key) => x25519_private_key,
+                    None => bail_invalid_handle!(client_auth_private_key),
+                };
+
+            let channel_name =
+                ascii_str_from_ffi(channel_name, channel_name_length, "channel_name")?;
+            ensure_not_empty!(channel_name);
+            let channel_name: AsciiString = channel_name.parse()?;
+
+            Ok(context.0.endpoint_client_begin_handshake(
+                endpoint_service_id.clone(),
+                client_auth_private_key.clone(),
+                channel_name,
+            )?)
+        },
+    )
+}
+
+/// Connect to and begin a handshake to request a channel from the given endpoint server served
+/// on its identity server's onion-service (see gosling_context_start_shared_endpoint_server()).
+/// No client authorization key is needed. Handshake progression is communicated as with
+/// gosling_context_begin_endpoint_handshake().
+///
+/// @param context: the context which will be opening the channel
+/// @param identity_service_id: the identity server whose onion-service serves the endpoint server
+/// @param endpoint_service_id: the endpoint server to open a channel to
+/// @param channel_name: the ascii-encoded name of the channel to open
+/// @param channel_name_length: the number of chars in channel name not including any null-terminator,
+///  or 0 if channel_name is null-terminated
+/// @param error: filled on error
+/// @return the handle of the new handshake, which is passed to the handshake's callbacks and
+///  events; or !0 (SIZE_MAX) on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_context_begin_shared_endpoint_handshake(
+    context: *mut GoslingContext,
+    identity_service_id: *const GoslingV3OnionServiceId,
+    endpoint_service_id: *const GoslingV3OnionServiceId,
+    channel_name: *const c_char,
+    channel_name_length: usize,
+    error: *mut *mut GoslingError,
+) -> GoslingHandshakeHandle {
+    translate_failures(
+        !0usize,
+        error,
+        || -> anyhow::Result<GoslingHandshakeHandle> {
+            ensure_not_null!(context);
+            ensure_not_null!(identity_service_id);
+            ensure_not_null!(endpoint_service_id);
+            ensure_not_null!(channel_name);
+
+            let mut context_tuple_registry = get_context_tuple_registry()?;
+            let context = match context_tuple_registry.get_mut(context as usize) {
+                Some(context) => context,
+                None => bail_invalid_handle!(context),
+            };
+
+            let v3_onion_service_id_registry = get_v3_onion_service_id_registry()?;
+            let identity_service_id =
+                match v3_onion_service_id_registry.get(identity_service_id as usize) {
+                    Some(v3_onion_service_id) => v3_onion_service_id,
+                    None => bail_invalid_handle!(identity_service_id),
+                };
+            let endpoint_service_id =
+                match v3_onion_service_id_registry.get(endpoint_service_id as usize) {
+                    Some(v3_onion_service_id) => v3_onion_service_id,
+                    None => bail_invalid_handle!(endpoint_service_id),
+                };
+
+            let channel_name =
+                ascii_str_from_ffi(channel_name, channel_name_length, "channel_name")?;
+            ensure_not_empty!(channel_name);
+            let channel_name: AsciiString = channel_name.parse()?;
+
+            Ok(context.0.endpoint_client_begin_shared_handshake(
+                identity_service_id.clone(),
+                endpoint_service_id.clone(),
+                channel_name,
+            )?)
+        },
+    )
+}
+
+/// Connect to and begin a handshake to request a channel from the given endpoint server, as
+/// with gosling_context_begin_endpoint_handshake(). The connection to the endpoint server is
+/// isolated using the given circuit token (see
+/// gosling_context_begin_identity_handshake_with_circuit_token()). If the circuit token is
+/// released before the connection is made, the handshake fails.
+///
+/// @param context: the context which will be opening the channel
+/// @param endpoint_service_id: the endpoint server to open a channel to
+/// @param client_auth_private_key: the x25519 clienth authorization key needed to decrypt the endpoint server's
+///  onion service descriptor
+/// @param channel_name: the ascii-encoded name of the channel to open
+/// @param channel_name_length: the number of chars in channel name not including any null-terminator,
+///  or 0 if channel_name is null-terminated
+/// @param circuit_token: a circuit token from gosling_context_generate_circuit_token()
+/// @param error: filled on error
+/// @return the handle of the new handshake, which is passed to the handshake's callbacks and
+///  events; or !0 (SIZE_MAX) on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_context_begin_endpoint_handshake_with_circuit_token(
+    context: *mut GoslingContext,
+    endpoint_service_id: *const GoslingV3OnionServiceId,
+    client_auth_private_key: *const GoslingX25519PrivateKey,
+    channel_name: *const c_char,
+    channel_name_length: usize,
+    circuit_token: GoslingCircuitToken,
+    error: *mut *mut GoslingError,
+) -> GoslingHandshakeHandle {
+    translate_failures(
+        !0usize,
+        error,
+        || -> anyhow::Result<GoslingHandshakeHandle> {
+            ensure_not_null!(context);
+            ensure_not_null!(endpoint_service_id);
+            ensure_not_null!(client_auth_private_key);
             ensure_not_null!(channel_name);
-            ensure_not_equal!(channel_name_length, 0);
 
-            let mut context_tuple_registry = get_context_tuple_registry();
+            let mut context_tuple_registry = get_context_tuple_registry()?;
             let context = match context_tuple_registry.get_mut(context as usize) {
                 Some(context) => context,
                 None => bail_invalid_handle!(context),
             };
 
-            let v3_onion_service_id_registry = get_v3_onion_service_id_registry();
+            let v3_onion_service_id_registry = get_v3_onion_service_id_registry()?;
             let endpoint_service_id =
                 match v3_onion_service_id_registry.get(endpoint_service_id as usize) {
                     Some(v3_onion_service_id) => v3_onion_service_id,
                     None => bail_invalid_handle!(endpoint_service_id),
                 };
 
-            let x25519_private_key_registry = get_x25519_private_key_registry();
+            let x25519_private_key_registry = get_x25519_private_key_registry()?;
             let client_auth_private_key =
                 match x25519_private_key_registry.get(client_auth_private_key as usize) {
                     Some(x25519_private_key) => x25519_private_key,
                     None => bail_invalid_handle!(client_auth_private_key),
                 };
 
-            let channel_name = unsafe {
-                std::slice::from_raw_parts(channel_name as *const u8, channel_name_length)
-            };
-            let channel_name = std::str::from_utf8(channel_name)?.to_string();
-            if !channel_name.is_ascii() {
-                bail!("channel_name must be an ascii string");
-            }
+            let channel_name =
+                ascii_str_from_ffi(channel_name, channel_name_length, "channel_name")?;
+            ensure_not_empty!(channel_name);
+            let channel_name: AsciiString = channel_name.parse()?;
 
-            Ok(context.0.endpoint_client_begin_handshake(
-                endpoint_service_id.clone(),
-                client_auth_private_key.clone(),
-                channel_name,
-            )?)
+            Ok(context
+                .0
+                .endpoint_client_begin_handshake_with_circuit_token(
+                    endpoint_service_id.clone(),
+                    client_auth_private_key.clone(),
+                    channel_name,
+                    circuit_token,
+                )?)
         },
     )
 }
@@ -450,7 +3669,7 @@ pub extern "C" fn gosling_context_abort_endpoint_client_handshake(
     translate_failures((), error, || -> anyhow::Result<()> {
         ensure_not_null!(context);
 
-        let mut context_tuple_registry = get_context_tuple_registry();
+        let mut context_tuple_registry = get_context_tuple_registry()?;
         let context = match context_tuple_registry.get_mut(context as usize) {
             Some(context) => context,
             None => bail_invalid_handle!(context),
@@ -462,7 +3681,292 @@ pub extern "C" fn gosling_context_abort_endpoint_client_handshake(
     })
 }
 
-fn handle_context_event(
+/// Abort an in-progress endpoint server handshake. The connection to the endpoint client is
+/// closed and the endpoint server handshake failed callback is invoked with the handle
+/// during the next call to gosling_context_poll_events().
+///
+/// @param context: the context associated with the endpoint server handshake handle
+/// @param handshake_handle: the handle associated with the endpoint server handshake
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_abort_endpoint_server_handshake(
+    context: *mut GoslingContext,
+    handshake_handle: GoslingHandshakeHandle,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(context);
+
+        let mut context_tuple_registry = get_context_tuple_registry()?;
+        let context = match context_tuple_registry.get_mut(context as usize) {
+            Some(context) => context,
+            None => bail_invalid_handle!(context),
+        };
+
+        Ok(context
+            .0
+            .endpoint_server_abort_handshake(handshake_handle)?)
+    })
+}
+
+/// Set how long the context keeps the client authorization keys it adds to its tor daemon when
+/// endpoint client handshakes begin. Keys are only removed once none of the endpoint server's
+/// other handshakes are in progress, and removals are reported through the client auth removed
+/// or client auth remove failed callbacks. With any policy other than
+/// CLIENT_AUTH_RETENTION_RETAIN, the context's remaining keys are also removed when it is
+/// freed. Only applies to handshakes which finish after this call.
+///
+/// @param context: the context to configure
+/// @param retention: one of the CLIENT_AUTH_RETENTION_* constants (the default is
+///  CLIENT_AUTH_RETENTION_RETAIN)
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_set_client_auth_retention(
+    context: *mut GoslingContext,
+    retention: u32,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(context);
+
+        let retention = match retention {
+            CLIENT_AUTH_RETENTION_RETAIN => ClientAuthRetention::Retain,
+            CLIENT_AUTH_RETENTION_REMOVE_ON_FAILURE => ClientAuthRetention::RemoveOnFailure,
+            CLIENT_AUTH_RETENTION_REMOVE_ON_COMPLETION => ClientAuthRetention::RemoveOnCompletion,
+            retention => bail!("invalid client auth retention: {}", retention),
+        };
+
+        let mut context_tuple_registry = get_context_tuple_registry()?;
+        let context = match context_tuple_registry.get_mut(context as usize) {
+            Some(context) => context,
+            None => bail_invalid_handle!(context),
+        };
+
+        context.0.set_client_auth_retention(retention);
+        Ok(())
+    });
+}
+
+/// Set how long endpoint client handshakes may take to connect to the endpoint server's onion
+/// service. Handshakes which take longer fail, either with an error from the begin endpoint
+/// handshake function or through the endpoint client handshake failed callback if they were
+/// queued. The tor daemon is not interrupted while connecting, so a slow connection is closed
+/// once made. Only applies to handshakes which connect after this call.
+///
+/// @param context: the context to configure
+/// @param timeout_milliseconds: the number of milliseconds handshakes have to connect, or 0 for no limit
+///  (the default)
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_set_endpoint_client_connect_timeout(
+    context: *mut GoslingContext,
+    timeout_milliseconds: u32,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(context);
+
+        let mut context_tuple_registry = get_context_tuple_registry()?;
+        let context = match context_tuple_registry.get_mut(context as usize) {
+            Some(context) => context,
+            None => bail_invalid_handle!(context),
+        };
+
+        let timeout = match timeout_milliseconds {
+            0 => None,
+            timeout_milliseconds => Some(Duration::from_millis(timeout_milliseconds as u64)),
+        };
+        context.0.endpoint_client_set_connect_timeout(timeout);
+        Ok(())
+    });
+}
+
+/// Set how long endpoint client handshakes may take to complete once connected to the endpoint
+/// server. Unlike the 60 second endpoint timeout, which bounds each step of the handshake,
+/// this bounds the handshake as a whole. Handshakes which take longer fail
+/// through the endpoint client handshake failed callback. Only applies to handshakes which
+/// connect after this call.
+///
+/// @param context: the context to configure
+/// @param timeout_milliseconds: the number of milliseconds handshakes have to complete, or 0 for no limit
+///  (the default)
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_set_endpoint_client_handshake_timeout(
+    context: *mut GoslingContext,
+    timeout_milliseconds: u32,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(context);
+
+        let mut context_tuple_registry = get_context_tuple_registry()?;
+        let context = match context_tuple_registry.get_mut(context as usize) {
+            Some(context) => context,
+            None => bail_invalid_handle!(context),
+        };
+
+        let timeout = match timeout_milliseconds {
+            0 => None,
+            timeout_milliseconds => Some(Duration::from_millis(timeout_milliseconds as u64)),
+        };
+        context.0.endpoint_client_set_handshake_timeout(timeout);
+        Ok(())
+    });
+}
+
+/// Set the optional protocol features the context negotiates with its peers during identity and
+/// endpoint handshakes. Features disabled here are neither offered by outgoing handshakes nor
+/// accepted by incoming handshakes, and beginning an identity handshake which needs a disabled
+/// feature fails. The features agreed by both peers are reported with each handshake's completed
+/// event. Only applies to handshakes started after this call.
+///
+/// @param context: the context to configure
+/// @param capabilities: a bitwise-or of CAPABILITY_* flags (all are enabled by default)
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_set_capabilities(
+    context: *mut GoslingContext,
+    capabilities: u32,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(context);
+
+        let unknown = capabilities & !Capabilities::all().bits();
+        if unknown != 0 {
+            bail!("invalid capabilities: {:#x}", unknown);
+        }
+
+        let mut context_tuple_registry = get_context_tuple_registry()?;
+        let context = match context_tuple_registry.get_mut(context as usize) {
+            Some(context) => context,
+            None => bail_invalid_handle!(context),
+        };
+
+        context
+            .0
+            .set_capabilities(Capabilities::from_bits_truncate(capabilities));
+        Ok(())
+    });
+}
+
+/// Keep the connections of completed identity handshakes open for follow-up identity handshakes
+/// between the same peers, which then skip connecting to the identity server's onion-service.
+/// Both peers must set this, and a handshake's connection is only kept open if its completed
+/// event reports CAPABILITY_KEEP_OPEN as negotiated. The next identity handshake begun with
+/// the identity server continues over its kept-open connection; on the identity server, it
+/// begins with the identity server handshake started callback as if the client had connected
+/// anew. Connections over which no handshake begins in time are closed and reported with
+/// EVENT_TYPE_IDENTITY_CLIENT_SESSION_CLOSED or EVENT_TYPE_IDENTITY_SERVER_SESSION_CLOSED
+/// events. Identity servers should keep connections open for longer than their clients. Only
+/// applies to handshakes started after this call.
+///
+/// @param context: the context to configure
+/// @param keep_open_seconds: how long connections are kept open once their handshake
+///  completes, or 0 to close them (the default)
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_set_identity_session_keep_open(
+    context: *mut GoslingContext,
+    keep_open_seconds: u32,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(context);
+
+        let mut context_tuple_registry = get_context_tuple_registry()?;
+        let context = match context_tuple_registry.get_mut(context as usize) {
+            Some(context) => context,
+            None => bail_invalid_handle!(context),
+        };
+
+        let keep_open = match keep_open_seconds {
+            0 => None,
+            seconds => Some(Duration::from_secs(seconds as u64)),
+        };
+        context.0.set_identity_session_keep_open(keep_open);
+        Ok(())
+    });
+}
+
+/// Remove the client authorization key for an endpoint server from the context's tor daemon. The
+/// result is reported through the client auth removed or client auth remove failed callbacks.
+///
+/// @param context: the context which added the client authorization key
+/// @param endpoint_service_id: the endpoint server whose client authorization key to remove
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_remove_client_auth(
+    context: *mut GoslingContext,
+    endpoint_service_id: *const GoslingV3OnionServiceId,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(context);
+        ensure_not_null!(endpoint_service_id);
+
+        let mut context_tuple_registry = get_context_tuple_registry()?;
+        let context = match context_tuple_registry.get_mut(context as usize) {
+            Some(context) => context,
+            None => bail_invalid_handle!(context),
+        };
+
+        let v3_onion_service_id_registry = get_v3_onion_service_id_registry()?;
+        let endpoint_service_id =
+            match v3_onion_service_id_registry.get(endpoint_service_id as usize) {
+                Some(v3_onion_service_id) => v3_onion_service_id,
+                None => bail_invalid_handle!(endpoint_service_id),
+            };
+
+        context.0.remove_client_auth(endpoint_service_id);
+        Ok(())
+    })
+}
+
+/// Handle an endpoint revocation received from an endpoint server, e.g. as the last line
+/// received over one of its channels. The revocation's signature is verified and any client
+/// authorization key for the endpoint server is removed as with
+/// gosling_context_remove_client_auth(). The endpoint client endpoint revoked callback is then
+/// called during the next call to gosling_context_poll_events().
+///
+/// @param context: the context whose access was revoked
+/// @param revocation: the endpoint revocation, with or without its terminating newline
+/// @param revocation_length: the number of chars in revocation not including any
+///  null-terminator, or 0 if revocation is null-terminated
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_context_handle_endpoint_revocation(
+    context: *mut GoslingContext,
+    revocation: *const c_char,
+    revocation_length: usize,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(context);
+        ensure_not_null!(revocation);
+
+        let mut context_tuple_registry = get_context_tuple_registry()?;
+        let context = match context_tuple_registry.get_mut(context as usize) {
+            Some(context) => context,
+            None => bail_invalid_handle!(context),
+        };
+
+        let revocation = ascii_str_from_ffi(revocation, revocation_length, "revocation")?;
+        let revocation = EndpointRevocation::from_string(revocation)?;
+        Ok(context.0.endpoint_client_handle_revocation(&revocation)?)
+    })
+}
+
+pub(crate) fn handle_context_event(
     event: ContextEvent,
     context: *mut GoslingContext,
     callbacks: &EventCallbacks,
@@ -498,6 +4002,30 @@ fn handle_context_event(
                 callback(context);
             }
         }
+        ContextEvent::TorProviderChanged => {
+            if let Some(callback) = callbacks.tor_provider_changed_callback {
+                callback(context);
+            }
+        }
+        ContextEvent::ListenerStartFailed {
+            service_id,
+            virt_port,
+            reason,
+        } => {
+            if let Some(callback) = callbacks.listener_start_failed_callback {
+                let service_id = get_v3_onion_service_id_registry()?.insert(service_id);
+                let key =
+                    get_error_registry()?.insert(Error::new(format!("{:?}", reason).as_str()));
+                callback(
+                    context,
+                    service_id as *const GoslingV3OnionServiceId,
+                    virt_port,
+                    key as *const GoslingError,
+                );
+                get_v3_onion_service_id_registry()?.remove(service_id);
+                get_error_registry()?.remove(key);
+            }
+        }
         ContextEvent::TorLogReceived { line } => {
             if let Some(callback) = callbacks.tor_log_received_callback {
                 let line0 = CString::new(line.as_str())
@@ -505,6 +4033,113 @@ fn handle_context_event(
                 callback(context, line0.as_ptr(), line.len());
             }
         }
+        // circuit, stream, descriptor upload, SOCKS policy, connectivity check
+        // and kept-open identity connection events have no callbacks and are only returned
+        // by gosling_context_next_event()
+        ContextEvent::TorCircuitStatusChanged { .. }
+        | ContextEvent::TorStreamStatusChanged { .. }
+        | ContextEvent::TorOnionServiceDescriptorUploadStatus { .. }
+        | ContextEvent::TorSocksPolicyViolated { .. }
+        | ContextEvent::ConnectivityChecked { .. }
+        | ContextEvent::IdentityClientSessionClosed { .. }
+        | ContextEvent::IdentityServerSessionClosed { .. } => (),
+        //
+        // Event Queue Events
+        //
+        ContextEvent::EventQueueOverflowed {
+            dropped_events,
+            coalesced_tor_logs,
+        } => {
+            if let Some(callback) = callbacks.event_queue_overflowed_callback {
+                callback(context, dropped_events, coalesced_tor_logs);
+            }
+        }
+        ContextEvent::TorLogLinesDropped { dropped_lines } => {
+            if let Some(callback) = callbacks.tor_log_lines_dropped_callback {
+                callback(context, dropped_lines);
+            }
+        }
+        //
+        // Outbound Queue Events
+        //
+        ContextEvent::OutboundHandshakeQueued {
+            handle,
+            queue_position,
+        } => {
+            if let Some(callback) = callbacks.outbound_handshake_queued_callback {
+                callback(context, handle, queue_position);
+            }
+        }
+        ContextEvent::OutboundHandshakeStarted { handle } => {
+            if let Some(callback) = callbacks.outbound_handshake_started_callback {
+                callback(context, handle);
+            }
+        }
+        //
+        // Client Authorization Events
+        //
+        ContextEvent::ClientAuthAdded {
+            endpoint_service_id,
+        } => {
+            if let Some(callback) = callbacks.client_auth_added_callback {
+                let endpoint_service_id =
+                    get_v3_onion_service_id_registry()?.insert(endpoint_service_id);
+                callback(
+                    context,
+                    endpoint_service_id as *const GoslingV3OnionServiceId,
+                );
+                get_v3_onion_service_id_registry()?.remove(endpoint_service_id);
+            }
+        }
+        ContextEvent::ClientAuthAddFailed {
+            endpoint_service_id,
+            reason,
+        } => {
+            if let Some(callback) = callbacks.client_auth_add_failed_callback {
+                let endpoint_service_id =
+                    get_v3_onion_service_id_registry()?.insert(endpoint_service_id);
+                let key =
+                    get_error_registry()?.insert(Error::new(format!("{:?}", reason).as_str()));
+                callback(
+                    context,
+                    endpoint_service_id as *const GoslingV3OnionServiceId,
+                    key as *const GoslingError,
+                );
+                get_v3_onion_service_id_registry()?.remove(endpoint_service_id);
+                get_error_registry()?.remove(key);
+            }
+        }
+        ContextEvent::ClientAuthRemoved {
+            endpoint_service_id,
+        } => {
+            if let Some(callback) = callbacks.client_auth_removed_callback {
+                let endpoint_service_id =
+                    get_v3_onion_service_id_registry()?.insert(endpoint_service_id);
+                callback(
+                    context,
+                    endpoint_service_id as *const GoslingV3OnionServiceId,
+                );
+                get_v3_onion_service_id_registry()?.remove(endpoint_service_id);
+            }
+        }
+        ContextEvent::ClientAuthRemoveFailed {
+            endpoint_service_id,
+            reason,
+        } => {
+            if let Some(callback) = callbacks.client_auth_remove_failed_callback {
+                let endpoint_service_id =
+                    get_v3_onion_service_id_registry()?.insert(endpoint_service_id);
+                let key =
+                    get_error_registry()?.insert(Error::new(format!("{:?}", reason).as_str()));
+                callback(
+                    context,
+                    endpoint_service_id as *const GoslingV3OnionServiceId,
+                    key as *const GoslingError,
+                );
+                get_v3_onion_service_id_registry()?.remove(endpoint_service_id);
+                get_error_registry()?.remove(key);
+            }
+        }
         //
         // Identity Client Events
         //
@@ -556,7 +4191,7 @@ fn handle_context_event(
                 bail!("missing required identity_client_challenge_response_size() and identity_client_build_challenge_response() callbacks");
             };
 
-            match get_context_tuple_registry().get_mut(context as usize) {
+            match get_context_tuple_registry()?.get_mut(context as usize) {
                 Some(context) => context
                     .0
                     .identity_client_handle_challenge_received(handle, challenge_response)?,
@@ -569,10 +4204,14 @@ fn handle_context_event(
             endpoint_service_id,
             endpoint_name,
             client_auth_private_key,
+            additional_endpoints: _,
+            contact_request: _,
+            capabilities: _,
+            stats: _,
         } => {
             if let Some(callback) = callbacks.identity_client_handshake_completed_callback {
                 let (identity_service_id, endpoint_service_id) = {
-                    let mut v3_onion_service_id_registry = get_v3_onion_service_id_registry();
+                    let mut v3_onion_service_id_registry = get_v3_onion_service_id_registry()?;
                     let identity_service_id =
                         v3_onion_service_id_registry.insert(identity_service_id);
                     let endpoint_service_id =
@@ -584,7 +4223,7 @@ fn handle_context_event(
                     .expect("endpoint_name should be a valid ASCII string and not have an intermediate null byte");
 
                 let client_auth_private_key =
-                    get_x25519_private_key_registry().insert(client_auth_private_key);
+                    get_x25519_private_key_registry()?.insert(client_auth_private_key);
 
                 callback(
                     context,
@@ -598,20 +4237,69 @@ fn handle_context_event(
 
                 // cleanup
                 {
-                    let mut v3_onion_service_id_registry = get_v3_onion_service_id_registry();
+                    let mut v3_onion_service_id_registry = get_v3_onion_service_id_registry()?;
                     v3_onion_service_id_registry.remove(identity_service_id);
                     v3_onion_service_id_registry.remove(endpoint_service_id);
                 }
-                get_x25519_private_key_registry().remove(client_auth_private_key);
+                get_x25519_private_key_registry()?.remove(client_auth_private_key);
             } else {
                 bail!("missing required identity_client_handshake_completed() callback");
             }
         }
-        ContextEvent::IdentityClientHandshakeFailed { handle, reason } => {
+        ContextEvent::IdentityClientHandshakeFailed { handle, reason, .. } => {
+            if let Some(callback) = callbacks.identity_client_handshake_failed_callback {
+                let key =
+                    get_error_registry()?.insert(Error::new(format!("{:?}", reason).as_str()));
+                callback(context, handle, key as *const GoslingError);
+                get_error_registry()?.remove(key);
+            }
+        }
+        ContextEvent::IdentityClientHandshakeSuppressed {
+            handle,
+            identity_service_id,
+            retry_after,
+        } => {
             if let Some(callback) = callbacks.identity_client_handshake_failed_callback {
-                let key = get_error_registry().insert(Error::new(format!("{:?}", reason).as_str()));
+                let key = get_error_registry()?.insert(Error::new(
+                    format!(
+                        "identity server {} recently failed to connect, retry after {} seconds",
+                        identity_service_id,
+                        retry_after.as_secs()
+                    )
+                    .as_str(),
+                ));
                 callback(context, handle, key as *const GoslingError);
-                get_error_registry().remove(key);
+                get_error_registry()?.remove(key);
+            }
+        }
+        ContextEvent::IdentityClientEndpointPinMismatched {
+            handle,
+            identity_service_id,
+            endpoint_name,
+            pinned_endpoint_service_id,
+            endpoint_service_id,
+        } => {
+            if let Some(callback) = callbacks.identity_client_endpoint_pin_mismatched_callback {
+                let identity_service_id =
+                    get_v3_onion_service_id_registry()?.insert(identity_service_id);
+                let pinned_endpoint_service_id =
+                    get_v3_onion_service_id_registry()?.insert(pinned_endpoint_service_id);
+                let endpoint_service_id =
+                    get_v3_onion_service_id_registry()?.insert(endpoint_service_id);
+                let endpoint_name0 = CString::new(endpoint_name.as_str())
+                    .expect("endpoint_name should be a valid ASCII string and not have an intermediate null byte");
+                callback(
+                    context,
+                    handle,
+                    identity_service_id as *const GoslingV3OnionServiceId,
+                    endpoint_name0.as_ptr(),
+                    endpoint_name.len(),
+                    pinned_endpoint_service_id as *const GoslingV3OnionServiceId,
+                    endpoint_service_id as *const GoslingV3OnionServiceId,
+                );
+                get_v3_onion_service_id_registry()?.remove(identity_service_id);
+                get_v3_onion_service_id_registry()?.remove(pinned_endpoint_service_id);
+                get_v3_onion_service_id_registry()?.remove(endpoint_service_id);
             }
         }
         //
@@ -622,6 +4310,11 @@ fn handle_context_event(
                 callback(context);
             }
         }
+        ContextEvent::IdentityServerDescriptorRefreshed => {
+            if let Some(callback) = callbacks.identity_server_descriptor_refreshed_callback {
+                callback(context);
+            }
+        }
         ContextEvent::IdentityServerHandshakeStarted { handle } => {
             if let Some(callback) = callbacks.identity_server_handshake_started_callback {
                 callback(context, handle);
@@ -631,11 +4324,27 @@ fn handle_context_event(
             handle,
             client_service_id,
             requested_endpoint,
+            additional_endpoints: _,
+            contact_request,
         } => {
+            if let (Some(callback), Some(contact_request)) = (
+                callbacks.identity_server_contact_request_received_callback,
+                contact_request,
+            ) {
+                let contact_request0 = CString::new(contact_request.as_str())
+                    .expect("contact_request should not have an intermediate null byte");
+                callback(
+                    context,
+                    handle,
+                    contact_request0.as_ptr(),
+                    contact_request.len(),
+                );
+            }
+
             let client_allowed = match callbacks.identity_server_client_allowed_callback {
                 Some(callback) => {
                     let client_service_id =
-                        get_v3_onion_service_id_registry().insert(client_service_id);
+                        get_v3_onion_service_id_registry()?.insert(client_service_id);
                     callback(
                         context,
                         handle,
@@ -691,7 +4400,7 @@ fn handle_context_event(
                 bail!("missing required identity_server_challenge_size() and identity_server_build_challenge() callbacks");
             };
 
-            match get_context_tuple_registry().get_mut(context as usize) {
+            match get_context_tuple_registry()?.get_mut(context as usize) {
                 Some(context) => context.0.identity_server_handle_endpoint_request_received(
                     handle,
                     client_allowed,
@@ -726,7 +4435,7 @@ fn handle_context_event(
                 }
             };
 
-            match get_context_tuple_registry().get_mut(context as usize) {
+            match get_context_tuple_registry()?.get_mut(context as usize) {
                 Some(context) => context
                     .0
                     .identity_server_handle_challenge_response_received(
@@ -742,10 +4451,14 @@ fn handle_context_event(
             endpoint_name,
             client_service_id,
             client_auth_public_key,
+            additional_endpoints: _,
+            contact_request: _,
+            capabilities: _,
+            stats: _,
         } => {
             if let Some(callback) = callbacks.identity_server_handshake_completed_callback {
                 let endpoint_private_key = {
-                    let mut ed25519_private_key_registry = get_ed25519_private_key_registry();
+                    let mut ed25519_private_key_registry = get_ed25519_private_key_registry()?;
                     ed25519_private_key_registry.insert(endpoint_private_key)
                 };
 
@@ -753,12 +4466,12 @@ fn handle_context_event(
                     .expect("endpoint_name should be a valid ASCII string and not have an intermediate null byte");
 
                 let client_service_id = {
-                    let mut v3_onion_service_id_registry = get_v3_onion_service_id_registry();
+                    let mut v3_onion_service_id_registry = get_v3_onion_service_id_registry()?;
                     v3_onion_service_id_registry.insert(client_service_id)
                 };
 
                 let client_auth_public_key = {
-                    let mut x25519_public_key_registry = get_x25519_public_key_registry();
+                    let mut x25519_public_key_registry = get_x25519_public_key_registry()?;
                     x25519_public_key_registry.insert(client_auth_public_key)
                 };
 
@@ -773,9 +4486,9 @@ fn handle_context_event(
                 );
 
                 // cleanup
-                get_ed25519_private_key_registry().remove(endpoint_private_key);
-                get_v3_onion_service_id_registry().remove(client_service_id);
-                get_x25519_public_key_registry().remove(client_auth_public_key);
+                get_ed25519_private_key_registry()?.remove(endpoint_private_key);
+                get_v3_onion_service_id_registry()?.remove(client_service_id);
+                get_x25519_public_key_registry()?.remove(client_auth_public_key);
             } else {
                 bail!("missing required identity_server_handshake_completed_callback()");
             }
@@ -800,11 +4513,27 @@ fn handle_context_event(
                 );
             }
         }
-        ContextEvent::IdentityServerHandshakeFailed { handle, reason } => {
+        ContextEvent::IdentityServerBannedClientRejected {
+            handle,
+            client_service_id,
+            requested_endpoint: _,
+            ban,
+        } => {
+            if let Some(callback) = callbacks.identity_server_handshake_failed_callback {
+                let key = get_error_registry()?.insert(Error::with_code(
+                    ERROR_CODE_CLIENT_BANNED,
+                    format!("client {} is banned: {}", client_service_id, ban.reason).as_str(),
+                ));
+                callback(context, handle, key as *const GoslingError);
+                get_error_registry()?.remove(key);
+            }
+        }
+        ContextEvent::IdentityServerHandshakeFailed { handle, reason, .. } => {
             if let Some(callback) = callbacks.identity_server_handshake_failed_callback {
-                let key = get_error_registry().insert(Error::new(format!("{:?}", reason).as_str()));
+                let key =
+                    get_error_registry()?.insert(Error::new(format!("{:?}", reason).as_str()));
                 callback(context, handle, key as *const GoslingError);
-                get_error_registry().remove(key);
+                get_error_registry()?.remove(key);
             }
         }
         //
@@ -815,10 +4544,12 @@ fn handle_context_event(
             handle,
             channel_name,
             stream,
+            capabilities: _,
+            stats: _,
         } => {
             if let Some(callback) = callbacks.endpoint_client_handshake_completed_callback {
                 let endpoint_service_id = {
-                    let mut v3_onion_service_id_registry = get_v3_onion_service_id_registry();
+                    let mut v3_onion_service_id_registry = get_v3_onion_service_id_registry()?;
                     v3_onion_service_id_registry.insert(endpoint_service_id)
                 };
                 let channel_name0 = CString::new(channel_name.as_str())
@@ -839,16 +4570,120 @@ fn handle_context_event(
                 );
 
                 // cleanup
-                get_v3_onion_service_id_registry().remove(endpoint_service_id);
+                get_v3_onion_service_id_registry()?.remove(endpoint_service_id);
             } else {
                 bail!("missing required endpoint_client_handshake_completed() callback");
             }
         }
-        ContextEvent::EndpointClientHandshakeFailed { handle, reason } => {
+        ContextEvent::EndpointClientRpcChannelOpened {
+            handle,
+            endpoint_service_id,
+            channel_name,
+            channel,
+            capabilities: _,
+            stats: _,
+        } => {
+            if let Some(callback) = callbacks.endpoint_client_rpc_channel_opened_callback {
+                let endpoint_service_id = {
+                    let mut v3_onion_service_id_registry = get_v3_onion_service_id_registry()?;
+                    v3_onion_service_id_registry.insert(endpoint_service_id)
+                };
+                let channel_name0 = CString::new(channel_name.as_str())
+                    .expect("channel_name should be a valid ASCII string and not have an intermediate null byte");
+                // ownership of the channel passes to the callee
+                let channel = get_rpc_channel_tuple_registry()?.insert((channel, None, None));
+
+                callback(
+                    context,
+                    handle,
+                    endpoint_service_id as *const GoslingV3OnionServiceId,
+                    channel_name0.as_ptr(),
+                    channel_name.len(),
+                    channel as *mut GoslingRpcChannel,
+                );
+
+                // cleanup
+                get_v3_onion_service_id_registry()?.remove(endpoint_service_id);
+            } else {
+                bail!("missing required endpoint_client_rpc_channel_opened() callback");
+            }
+        }
+        ContextEvent::EndpointClientDatagramChannelOpened {
+            handle,
+            endpoint_service_id,
+            channel_name,
+            channel,
+            capabilities: _,
+            stats: _,
+        } => {
+            if let Some(callback) = callbacks.endpoint_client_datagram_channel_opened_callback {
+                let endpoint_service_id = {
+                    let mut v3_onion_service_id_registry = get_v3_onion_service_id_registry()?;
+                    v3_onion_service_id_registry.insert(endpoint_service_id)
+                };
+                let channel_name0 = CString::new(channel_name.as_str())
+                    .expect("channel_name should be a valid ASCII string and not have an intermediate null byte");
+                // ownership of the channel passes to the callee
+                let channel = get_datagram_channel_registry()?.insert(channel);
+
+                callback(
+                    context,
+                    handle,
+                    endpoint_service_id as *const GoslingV3OnionServiceId,
+                    channel_name0.as_ptr(),
+                    channel_name.len(),
+                    channel as *mut GoslingDatagramChannel,
+                );
+
+                // cleanup
+                get_v3_onion_service_id_registry()?.remove(endpoint_service_id);
+            } else {
+                bail!("missing required endpoint_client_datagram_channel_opened() callback");
+            }
+        }
+        ContextEvent::EndpointClientHandshakeFailed { handle, reason, .. } => {
+            if let Some(callback) = callbacks.endpoint_client_handshake_failed_callback {
+                let message = format!("{:?}", reason);
+                let key = get_error_registry()?.insert(Error::with_code(
+                    error_code(&reason.into()),
+                    message.as_str(),
+                ));
+                callback(context, handle, key as *const GoslingError);
+                get_error_registry()?.remove(key);
+            }
+        }
+        ContextEvent::EndpointClientHandshakeBusy {
+            handle,
+            endpoint_service_id,
+            channel_name: _,
+            retry_after,
+        } => {
             if let Some(callback) = callbacks.endpoint_client_handshake_failed_callback {
-                let key = get_error_registry().insert(Error::new(format!("{:?}", reason).as_str()));
+                let key = get_error_registry()?.insert(Error::new(
+                    format!(
+                        "endpoint server {} is busy, retry after {} seconds",
+                        endpoint_service_id,
+                        retry_after.as_secs()
+                    )
+                    .as_str(),
+                ));
                 callback(context, handle, key as *const GoslingError);
-                get_error_registry().remove(key);
+                get_error_registry()?.remove(key);
+            }
+        }
+        ContextEvent::EndpointClientEndpointRevoked {
+            endpoint_service_id,
+            revoked_at,
+        } => {
+            if let Some(callback) = callbacks.endpoint_client_endpoint_revoked_callback {
+                let endpoint_service_id =
+                    get_v3_onion_service_id_registry()?.insert(endpoint_service_id);
+                callback(
+                    context,
+                    endpoint_service_id as *const GoslingV3OnionServiceId,
+                    unix_seconds(revoked_at),
+                );
+                get_v3_onion_service_id_registry()?.remove(endpoint_service_id);
             }
         }
         //
@@ -860,7 +4695,81 @@ fn handle_context_event(
         } => {
             if let Some(callback) = callbacks.endpoint_server_published_callback {
                 let endpoint_service_id = {
-                    let mut v3_onion_service_id_registry = get_v3_onion_service_id_registry();
+                    let mut v3_onion_service_id_registry = get_v3_onion_service_id_registry()?;
+                    v3_onion_service_id_registry.insert(endpoint_service_id)
+                };
+                let endpoint_name0 = CString::new(endpoint_name.as_str())
+                    .expect("endpoint_name should be a valid ASCII string and not have an intermediate null byte");
+
+                callback(
+                    context,
+                    endpoint_service_id as *const GoslingV3OnionServiceId,
+                    endpoint_name0.as_ptr(),
+                    endpoint_name.len(),
+                );
+
+                // cleanup
+                get_v3_onion_service_id_registry()?.remove(endpoint_service_id);
+            }
+        }
+        ContextEvent::EndpointServerPublishCompleted {
+            handle,
+            endpoint_service_id,
+            endpoint_name,
+        } => {
+            if let Some(callback) = callbacks.endpoint_server_publish_completed_callback {
+                let endpoint_service_id = {
+                    let mut v3_onion_service_id_registry = get_v3_onion_service_id_registry()?;
+                    v3_onion_service_id_registry.insert(endpoint_service_id)
+                };
+                let endpoint_name0 = CString::new(endpoint_name.as_str())
+                    .expect("endpoint_name should be a valid ASCII string and not have an intermediate null byte");
+
+                callback(
+                    context,
+                    handle,
+                    endpoint_service_id as *const GoslingV3OnionServiceId,
+                    endpoint_name0.as_ptr(),
+                    endpoint_name.len(),
+                );
+
+                // cleanup
+                get_v3_onion_service_id_registry()?.remove(endpoint_service_id);
+            }
+        }
+        ContextEvent::EndpointServerPublishTimedOut {
+            handle,
+            endpoint_service_id,
+            endpoint_name,
+            timeout: _,
+        } => {
+            if let Some(callback) = callbacks.endpoint_server_publish_timed_out_callback {
+                let endpoint_service_id = {
+                    let mut v3_onion_service_id_registry = get_v3_onion_service_id_registry()?;
+                    v3_onion_service_id_registry.insert(endpoint_service_id)
+                };
+                let endpoint_name0 = CString::new(endpoint_name.as_str())
+                    .expect("endpoint_name should be a valid ASCII string and not have an intermediate null byte");
+
+                callback(
+                    context,
+                    handle,
+                    endpoint_service_id as *const GoslingV3OnionServiceId,
+                    endpoint_name0.as_ptr(),
+                    endpoint_name.len(),
+                );
+
+                // cleanup
+                get_v3_onion_service_id_registry()?.remove(endpoint_service_id);
+            }
+        }
+        ContextEvent::EndpointServerDescriptorRefreshed {
+            endpoint_service_id,
+            endpoint_name,
+        } => {
+            if let Some(callback) = callbacks.endpoint_server_descriptor_refreshed_callback {
+                let endpoint_service_id = {
+                    let mut v3_onion_service_id_registry = get_v3_onion_service_id_registry()?;
                     v3_onion_service_id_registry.insert(endpoint_service_id)
                 };
                 let endpoint_name0 = CString::new(endpoint_name.as_str())
@@ -874,7 +4783,20 @@ fn handle_context_event(
                 );
 
                 // cleanup
-                get_v3_onion_service_id_registry().remove(endpoint_service_id);
+                get_v3_onion_service_id_registry()?.remove(endpoint_service_id);
+            }
+        }
+        ContextEvent::EndpointServerConnectionShed {
+            endpoint_service_id,
+        } => {
+            if let Some(callback) = callbacks.endpoint_server_connection_shed_callback {
+                let endpoint_service_id =
+                    get_v3_onion_service_id_registry()?.insert(endpoint_service_id);
+                callback(
+                    context,
+                    endpoint_service_id as *const GoslingV3OnionServiceId,
+                );
+                get_v3_onion_service_id_registry()?.remove(endpoint_service_id);
             }
         }
         ContextEvent::EndpointServerHandshakeStarted { handle } => {
@@ -891,7 +4813,7 @@ fn handle_context_event(
             {
                 Some(callback) => {
                     let client_service_id = {
-                        let mut v3_onion_service_id_registry = get_v3_onion_service_id_registry();
+                        let mut v3_onion_service_id_registry = get_v3_onion_service_id_registry()?;
                         v3_onion_service_id_registry.insert(client_service_id)
                     };
                     let requested_channel0 = CString::new(requested_channel.as_str()).expect("requested_channel should be a valid ASCII string and not have an intermediate null byte",
@@ -905,13 +4827,13 @@ fn handle_context_event(
                     );
 
                     // cleanup
-                    get_v3_onion_service_id_registry().remove(client_service_id);
+                    get_v3_onion_service_id_registry()?.remove(client_service_id);
                     channel_supported
                 }
                 None => bail!("missing required endpoint_server_channel_supported() callback"),
             };
 
-            match get_context_tuple_registry().get_mut(context as usize) {
+            match get_context_tuple_registry()?.get_mut(context as usize) {
                 Some(context) => context
                     .0
                     .endpoint_server_handle_channel_request_received(handle, channel_supported)?,
@@ -924,10 +4846,12 @@ fn handle_context_event(
             client_service_id,
             channel_name,
             stream,
+            capabilities: _,
+            stats: _,
         } => {
             if let Some(callback) = callbacks.endpoint_server_handshake_completed_callback {
                 let (endpoint_service_id, client_service_id) = {
-                    let mut v3_onion_service_id_registry = get_v3_onion_service_id_registry();
+                    let mut v3_onion_service_id_registry = get_v3_onion_service_id_registry()?;
                     let endpoint_service_id =
                         v3_onion_service_id_registry.insert(endpoint_service_id);
                     let client_service_id = v3_onion_service_id_registry.insert(client_service_id);
@@ -954,7 +4878,7 @@ fn handle_context_event(
 
                 // cleanup
                 {
-                    let mut v3_onion_service_id_registry = get_v3_onion_service_id_registry();
+                    let mut v3_onion_service_id_registry = get_v3_onion_service_id_registry()?;
                     v3_onion_service_id_registry.remove(endpoint_service_id);
                     v3_onion_service_id_registry.remove(client_service_id);
                 }
@@ -962,6 +4886,90 @@ fn handle_context_event(
                 bail!("missing required endpoint_server_handshake_completed() callback");
             }
         }
+        ContextEvent::EndpointServerRpcChannelOpened {
+            handle,
+            endpoint_service_id,
+            client_service_id,
+            channel_name,
+            channel,
+            capabilities: _,
+            stats: _,
+        } => {
+            if let Some(callback) = callbacks.endpoint_server_rpc_channel_opened_callback {
+                let (endpoint_service_id, client_service_id) = {
+                    let mut v3_onion_service_id_registry = get_v3_onion_service_id_registry()?;
+                    let endpoint_service_id =
+                        v3_onion_service_id_registry.insert(endpoint_service_id);
+                    let client_service_id = v3_onion_service_id_registry.insert(client_service_id);
+                    (endpoint_service_id, client_service_id)
+                };
+                let channel_name0 = CString::new(channel_name.as_str())
+                    .expect("channel_name should be a valid ASCII string and not have an intermediate null byte");
+                // ownership of the channel passes to the callee
+                let channel = get_rpc_channel_tuple_registry()?.insert((channel, None, None));
+
+                callback(
+                    context,
+                    handle,
+                    endpoint_service_id as *const GoslingV3OnionServiceId,
+                    client_service_id as *const GoslingV3OnionServiceId,
+                    channel_name0.as_ptr(),
+                    channel_name.len(),
+                    channel as *mut GoslingRpcChannel,
+                );
+
+                // cleanup
+                {
+                    let mut v3_onion_service_id_registry = get_v3_onion_service_id_registry()?;
+                    v3_onion_service_id_registry.remove(endpoint_service_id);
+                    v3_onion_service_id_registry.remove(client_service_id);
+                }
+            } else {
+                bail!("missing required endpoint_server_rpc_channel_opened() callback");
+            }
+        }
+        ContextEvent::EndpointServerDatagramChannelOpened {
+            handle,
+            endpoint_service_id,
+            client_service_id,
+            channel_name,
+            channel,
+            capabilities: _,
+            stats: _,
+        } => {
+            if let Some(callback) = callbacks.endpoint_server_datagram_channel_opened_callback {
+                let (endpoint_service_id, client_service_id) = {
+                    let mut v3_onion_service_id_registry = get_v3_onion_service_id_registry()?;
+                    let endpoint_service_id =
+                        v3_onion_service_id_registry.insert(endpoint_service_id);
+                    let client_service_id = v3_onion_service_id_registry.insert(client_service_id);
+                    (endpoint_service_id, client_service_id)
+                };
+                let channel_name0 = CString::new(channel_name.as_str())
+                    .expect("channel_name should be a valid ASCII string and not have an intermediate null byte");
+                // ownership of the channel passes to the callee
+                let channel = get_datagram_channel_registry()?.insert(channel);
+
+                callback(
+                    context,
+                    handle,
+                    endpoint_service_id as *const GoslingV3OnionServiceId,
+                    client_service_id as *const GoslingV3OnionServiceId,
+                    channel_name0.as_ptr(),
+                    channel_name.len(),
+                    channel as *mut GoslingDatagramChannel,
+                );
+
+                // cleanup
+                {
+                    let mut v3_onion_service_id_registry = get_v3_onion_service_id_registry()?;
+                    v3_onion_service_id_registry.remove(endpoint_service_id);
+                    v3_onion_service_id_registry.remove(client_service_id);
+                }
+            } else {
+                bail!("missing required endpoint_server_datagram_channel_opened() callback");
+            }
+        }
         ContextEvent::EndpointServerHandshakeRejected {
             handle,
             client_allowed,
@@ -978,18 +4986,112 @@ fn handle_context_event(
                 );
             }
         }
-        ContextEvent::EndpointServerHandshakeFailed { handle, reason } => {
+        ContextEvent::EndpointServerHandshakeBusy {
+            handle,
+            client_service_id,
+            requested_channel: _,
+            retry_after,
+        } => {
+            if let Some(callback) = callbacks.endpoint_server_handshake_failed_callback {
+                let key = get_error_registry()?.insert(Error::new(
+                    format!(
+                        "endpoint server busy, told client {} to retry after {} seconds",
+                        client_service_id,
+                        retry_after.as_secs()
+                    )
+                    .as_str(),
+                ));
+                callback(context, handle, key as *const GoslingError);
+                get_error_registry()?.remove(key);
+            }
+        }
+        ContextEvent::EndpointServerChannelQuotaExceeded {
+            handle,
+            client_service_id,
+            requested_channel: _,
+            exceeded,
+        } => {
+            if let Some(callback) = callbacks.endpoint_server_handshake_failed_callback {
+                let key = get_error_registry()?.insert(Error::with_code(
+                    ERROR_CODE_CHANNEL_QUOTA_EXCEEDED,
+                    format!(
+                        "client {} exceeded its channel quota: {}",
+                        client_service_id, exceeded
+                    )
+                    .as_str(),
+                ));
+                callback(context, handle, key as *const GoslingError);
+                get_error_registry()?.remove(key);
+            }
+        }
+        ContextEvent::EndpointServerBannedClientRejected {
+            handle,
+            client_service_id,
+            requested_channel: _,
+            ban,
+        } => {
+            if let Some(callback) = callbacks.endpoint_server_handshake_failed_callback {
+                let key = get_error_registry()?.insert(Error::with_code(
+                    ERROR_CODE_CLIENT_BANNED,
+                    format!("client {} is banned: {}", client_service_id, ban.reason).as_str(),
+                ));
+                callback(context, handle, key as *const GoslingError);
+                get_error_registry()?.remove(key);
+            }
+        }
+        ContextEvent::EndpointServerHandshakeFailed { handle, reason, .. } => {
             if let Some(callback) = callbacks.endpoint_server_handshake_failed_callback {
-                let key = get_error_registry().insert(Error::new(format!("{:?}", reason).as_str()));
+                let key =
+                    get_error_registry()?.insert(Error::new(format!("{:?}", reason).as_str()));
                 callback(context, handle, key as *const GoslingError);
-                get_error_registry().remove(key);
+                get_error_registry()?.remove(key);
             }
         }
     }
     Ok(())
 }
 
-/// Update the internal gosling context state and process event callbacks
+/// Get how long the application may wait before calling gosling_context_poll_events() again
+/// without delaying the context's work. The result reflects the context's current state, so it
+/// should be read after the latest call to gosling_context_poll_events() and to any function which
+/// begins or continues a handshake.
+///
+/// @param context: the context to query
+/// @param error: filled on error
+/// @return the number of milliseconds to wait: 0 if the context has work to do immediately, a
+///  short interval while handshakes are in progress, a longer one while only listeners or tor
+///  bootstrap need attention, or NEXT_DEADLINE_NONE if the context is idle until another
+///  gosling_context function is called
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_context_get_next_deadline(
+    context: *mut GoslingContext,
+    error: *mut *mut GoslingError,
+) -> u32 {
+    translate_failures(NEXT_DEADLINE_NONE, error, || -> anyhow::Result<u32> {
+        ensure_not_null!(context);
+
+        let context_tuple_registry = get_context_tuple_registry()?;
+        let context = match context_tuple_registry.get(context as usize) {
+            Some(context) => context,
+            None => bail_invalid_handle!(context),
+        };
+
+        // events left over by a failed callback are handled by the next poll
+        if context.2.is_some() {
+            return Ok(0);
+        }
+        Ok(match context.0.next_deadline() {
+            Some(deadline) => deadline.as_millis().min(u128::from(NEXT_DEADLINE_NONE - 1)) as u32,
+            None => NEXT_DEADLINE_NONE,
+        })
+    })
+}
+
+/// Update the internal gosling context state and process event callbacks. This function may
+/// be called from any thread; if another thread is already processing the context's events this
+/// call waits for it to finish, so the context's callbacks are never called concurrently. It
+/// must not be called from within one of the context's own callbacks.
 ///
 /// @param context: the context object we are updating
 /// @param error: filled on error
@@ -1000,12 +5102,14 @@ pub extern "C" fn gosling_context_poll_events(
     error: *mut *mut GoslingError,
 ) {
     translate_failures((), error, || -> anyhow::Result<()> {
+        let _dispatching = DispatchGuard::begin(context)?;
+
         // we need to scope the context registry explicitly here
         // in case our callbacks want to call any gosling functions
         // to avoid deadlock (since a mutex is held while the context_tuple_registry
         // is accesible)
         let (mut context_events, callbacks) =
-            match get_context_tuple_registry().get_mut(context as usize) {
+            match get_context_tuple_registry()?.get_mut(context as usize) {
                 Some(context) => {
                     // get our new events
                     let mut new_events = context.0.update()?;
@@ -1036,7 +5140,7 @@ pub extern "C" fn gosling_context_poll_events(
                 // if we have remaining events to consume, save them off on
                 // the context
                 if !context_events.is_empty() {
-                    if let Some(context) = get_context_tuple_registry().get_mut(context as usize) {
+                    if let Some(context) = get_context_tuple_registry()?.get_mut(context as usize) {
                         context.2 = Some(context_events);
                     }
                 }
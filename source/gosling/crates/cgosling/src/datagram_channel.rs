@@ -0,0 +1,196 @@
+// standard
+use std::cmp::min;
+
+// extern crates
+use anyhow::bail;
+#[cfg(feature = "impl-lib")]
+use cgosling_proc_macros::*;
+use gosling::datagram_channel::{DatagramChannel, MAX_DATAGRAM_SIZE};
+
+// internal crates
+use crate::error::*;
+use crate::ffi::*;
+use crate::macros::*;
+
+/// The size of the largest datagram which may be sent over a datagram channel;
+/// a buffer of this size can receive any datagram without truncation
+pub const DATAGRAM_CHANNEL_MAX_DATAGRAM_SIZE: usize = 65535;
+static_assertions::const_assert_eq!(DATAGRAM_CHANNEL_MAX_DATAGRAM_SIZE, MAX_DATAGRAM_SIZE);
+
+/// The connection of a completed endpoint handshake, carrying the application's
+/// datagrams; see gosling_context_add_datagram_channel()
+pub struct GoslingDatagramChannel;
+define_registry! {DatagramChannel}
+
+/// Frees a gosling_datagram_channel object, closing its underlying connection.
+/// Datagrams which have not been sent are discarded.
+///
+/// @param in_channel: the datagram channel to free
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_datagram_channel_free(in_channel: *mut GoslingDatagramChannel) {
+    impl_registry_free!(in_channel, DatagramChannel);
+}
+
+/// Queue a datagram to be sent to the datagram channel's peer by the following
+/// calls to gosling_datagram_channel_update(). If the maximum number of outgoing
+/// datagrams are already queued, the oldest which has not started being sent is
+/// dropped.
+///
+/// @param channel: the datagram channel to send the datagram on
+/// @param datagram_buffer: a buffer containing the datagram
+/// @param datagram_buffer_size: the number of bytes in datagram_buffer; at most
+///  DATAGRAM_CHANNEL_MAX_DATAGRAM_SIZE
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_datagram_channel_send(
+    channel: *mut GoslingDatagramChannel,
+    datagram_buffer: *const u8,
+    datagram_buffer_size: usize,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(channel);
+        ensure_not_null!(datagram_buffer);
+
+        let datagram = std::slice::from_raw_parts(datagram_buffer, datagram_buffer_size);
+        match get_datagram_channel_registry()?.get_mut(channel as usize) {
+            Some(channel) => channel.send(datagram)?,
+            None => bail_invalid_handle!(channel),
+        }
+        Ok(())
+    })
+}
+
+/// Send as many of the datagram channel's queued datagrams as the connection
+/// accepts without blocking, and receive all datagrams the peer has sent. Fails
+/// once the connection has failed or been closed, after which the channel should
+/// be freed; datagrams received before then may still be taken with
+/// gosling_datagram_channel_receive().
+///
+/// @param channel: the datagram channel to update
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_datagram_channel_update(
+    channel: *mut GoslingDatagramChannel,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(channel);
+
+        match get_datagram_channel_registry()?.get_mut(channel as usize) {
+            Some(channel) => channel.update()?,
+            None => bail_invalid_handle!(channel),
+        }
+        Ok(())
+    })
+}
+
+/// Take the oldest datagram received by gosling_datagram_channel_update(), if any.
+/// A datagram larger than out_datagram_buffer is truncated to fit, which the caller
+/// may detect by comparing out_datagram_size to datagram_buffer_size.
+///
+/// @param channel: the datagram channel to receive the datagram from
+/// @param out_datagram_buffer: a buffer the datagram is written to
+/// @param datagram_buffer_size: the number of bytes in out_datagram_buffer
+/// @param out_datagram_size: returned size of the datagram in bytes, before any
+///  truncation
+/// @param error: filled on error
+/// @return true if a datagram was received, false if none were waiting
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_datagram_channel_receive(
+    channel: *mut GoslingDatagramChannel,
+    out_datagram_buffer: *mut u8,
+    datagram_buffer_size: usize,
+    out_datagram_size: *mut usize,
+    error: *mut *mut GoslingError,
+) -> bool {
+    translate_failures(false, error, || -> anyhow::Result<bool> {
+        ensure_not_null!(channel);
+        ensure_not_null!(out_datagram_buffer);
+        ensure_not_null!(out_datagram_size);
+
+        let datagram = match get_datagram_channel_registry()?.get_mut(channel as usize) {
+            Some(channel) => channel.recv(),
+            None => bail_invalid_handle!(channel),
+        };
+        let datagram = match datagram {
+            Some(datagram) => datagram,
+            None => return Ok(false),
+        };
+
+        let count = min(datagram.len(), datagram_buffer_size);
+        std::ptr::copy_nonoverlapping(datagram.as_ptr(), out_datagram_buffer, count);
+        *out_datagram_size = datagram.len();
+        Ok(true)
+    })
+}
+
+/// Set how many datagrams the datagram channel queues in each direction before
+/// the oldest are dropped. The default is 64.
+///
+/// @param channel: the datagram channel to configure
+/// @param max_queued_datagrams: the maximum number of queued datagrams; must not
+///  be 0
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_datagram_channel_set_max_queued_datagrams(
+    channel: *mut GoslingDatagramChannel,
+    max_queued_datagrams: usize,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(channel);
+
+        match get_datagram_channel_registry()?.get_mut(channel as usize) {
+            Some(channel) => channel.set_max_queued_datagrams(max_queued_datagrams)?,
+            None => bail_invalid_handle!(channel),
+        }
+        Ok(())
+    })
+}
+
+/// Get the counts of the datagrams sent, received and dropped by the datagram
+/// channel so far.
+///
+/// @param channel: the datagram channel to query
+/// @param out_datagrams_sent: returned number of datagrams fully sent
+/// @param out_datagrams_received: returned number of datagrams fully received
+/// @param out_outbound_dropped: returned number of queued outgoing datagrams
+///  dropped to make room for newer ones
+/// @param out_inbound_dropped: returned number of received datagrams dropped to
+///  make room for newer ones before being taken with
+///  gosling_datagram_channel_receive()
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_datagram_channel_get_stats(
+    channel: *mut GoslingDatagramChannel,
+    out_datagrams_sent: *mut u64,
+    out_datagrams_received: *mut u64,
+    out_outbound_dropped: *mut u64,
+    out_inbound_dropped: *mut u64,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(channel);
+        ensure_not_null!(out_datagrams_sent);
+        ensure_not_null!(out_datagrams_received);
+        ensure_not_null!(out_outbound_dropped);
+        ensure_not_null!(out_inbound_dropped);
+
+        let stats = match get_datagram_channel_registry()?.get(channel as usize) {
+            Some(channel) => channel.stats(),
+            None => bail_invalid_handle!(channel),
+        };
+        *out_datagrams_sent = stats.datagrams_sent;
+        *out_datagrams_received = stats.datagrams_received;
+        *out_outbound_dropped = stats.outbound_dropped;
+        *out_inbound_dropped = stats.inbound_dropped;
+        Ok(())
+    })
+}
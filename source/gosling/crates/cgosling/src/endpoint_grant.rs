@@ -0,0 +1,462 @@
+// standard
+use std::os::raw::c_char;
+
+// extern crates
+use anyhow::bail;
+#[cfg(feature = "impl-lib")]
+use cgosling_proc_macros::*;
+use gosling::endpoint_grant::*;
+
+// internal crates
+use crate::crypto::*;
+use crate::error::*;
+use crate::ffi::*;
+use crate::macros::*;
+
+/// A record of the access to an endpoint server granted to an identity client, which may be
+/// serialised to a string for backup or transfer between devices
+pub struct GoslingEndpointGrant;
+define_registry! {EndpointGrant}
+
+/// Frees a gosling_endpoint_grant object
+///
+/// @param in_endpoint_grant: the endpoint grant to free
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_endpoint_grant_free(in_endpoint_grant: *mut GoslingEndpointGrant) {
+    impl_registry_free!(in_endpoint_grant, EndpointGrant);
+}
+
+/// Copy method for gosling_endpoint_grant
+///
+/// @param out_endpoint_grant: returned copy
+/// @param endpoint_grant: original to copy
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_endpoint_grant_clone(
+    out_endpoint_grant: *mut *mut GoslingEndpointGrant,
+    endpoint_grant: *const GoslingEndpointGrant,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(out_endpoint_grant);
+        ensure_not_null!(endpoint_grant);
+
+        let endpoint_grant = match get_endpoint_grant_registry()?.get(endpoint_grant as usize) {
+            Some(endpoint_grant) => endpoint_grant.clone(),
+            None => bail_invalid_handle!(endpoint_grant),
+        };
+        let handle = get_endpoint_grant_registry()?.try_insert(endpoint_grant)?;
+        *out_endpoint_grant = handle as *mut GoslingEndpointGrant;
+
+        Ok(())
+    })
+}
+
+/// Create a new gosling_endpoint_grant from the results of a completed identity handshake
+///
+/// @param out_endpoint_grant: returned endpoint grant
+/// @param identity_service_id: the onion service id of the identity server which issued the grant
+/// @param client_service_id: the onion service id of the identity client the grant was issued to
+/// @param endpoint_service_id: the onion service id of the granted endpoint server
+/// @param endpoint_name: the ascii-encoded name of the granted endpoint server
+/// @param endpoint_name_length: the number of chars in endpoint_name not including any
+///  null-terminator, or 0 if endpoint_name is null-terminated
+/// @param client_auth_private_key: the x25519 private key required to access the granted
+///  endpoint server
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_endpoint_grant_new(
+    out_endpoint_grant: *mut *mut GoslingEndpointGrant,
+    identity_service_id: *const GoslingV3OnionServiceId,
+    client_service_id: *const GoslingV3OnionServiceId,
+    endpoint_service_id: *const GoslingV3OnionServiceId,
+    endpoint_name: *const c_char,
+    endpoint_name_length: usize,
+    client_auth_private_key: *const GoslingX25519PrivateKey,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(out_endpoint_grant);
+        ensure_not_null!(identity_service_id);
+        ensure_not_null!(client_service_id);
+        ensure_not_null!(endpoint_service_id);
+        ensure_not_null!(endpoint_name);
+        ensure_not_null!(client_auth_private_key);
+
+        let (identity_service_id, client_service_id, endpoint_service_id) = {
+            let v3_onion_service_id_registry = get_v3_onion_service_id_registry()?;
+            let identity_service_id =
+                match v3_onion_service_id_registry.get(identity_service_id as usize) {
+                    Some(identity_service_id) => identity_service_id.clone(),
+                    None => bail_invalid_handle!(identity_service_id),
+                };
+            let client_service_id =
+                match v3_onion_service_id_registry.get(client_service_id as usize) {
+                    Some(client_service_id) => client_service_id.clone(),
+                    None => bail_invalid_handle!(client_service_id),
+                };
+            let endpoint_service_id =
+                match v3_onion_service_id_registry.get(endpoint_service_id as usize) {
+                    Some(endpoint_service_id) => endpoint_service_id.clone(),
+                    None => bail_invalid_handle!(endpoint_service_id),
+                };
+            (identity_service_id, client_service_id, endpoint_service_id)
+        };
+
+        let client_auth_private_key =
+            match get_x25519_private_key_registry()?.get(client_auth_private_key as usize) {
+                Some(client_auth_private_key) => client_auth_private_key.clone(),
+                None => bail_invalid_handle!(client_auth_private_key),
+            };
+
+        let endpoint_name =
+            ascii_str_from_ffi(endpoint_name, endpoint_name_length, "endpoint_name")?;
+        ensure_not_empty!(endpoint_name);
+        let endpoint_name = endpoint_name.to_string();
+
+        let endpoint_grant = EndpointGrant::new(
+            identity_service_id,
+            client_service_id,
+            endpoint_service_id,
+            endpoint_name,
+            client_auth_private_key,
+        )?;
+
+        let handle = get_endpoint_grant_registry()?.try_insert(endpoint_grant)?;
+        *out_endpoint_grant = handle as *mut GoslingEndpointGrant;
+
+        Ok(())
+    })
+}
+
+/// Conversion method for parsing a string created by gosling_endpoint_grant_get_string() into a
+/// gosling_endpoint_grant
+///
+/// @param out_endpoint_grant: returned endpoint grant
+/// @param endpoint_grant_string: a string in the form "gosling-endpoint-grant:abcd1234..."
+/// @param endpoint_grant_string_length: the number of chars in endpoint_grant_string not
+///  including any null-terminator, or 0 if endpoint_grant_string is null-terminated
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_endpoint_grant_from_string(
+    out_endpoint_grant: *mut *mut GoslingEndpointGrant,
+    endpoint_grant_string: *const c_char,
+    endpoint_grant_string_length: usize,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(out_endpoint_grant);
+        ensure_not_null!(endpoint_grant_string);
+
+        let endpoint_grant_str = ascii_str_from_ffi(
+            endpoint_grant_string,
+            endpoint_grant_string_length,
+            "endpoint_grant_string",
+        )?;
+        ensure_not_empty!(endpoint_grant_str);
+        let endpoint_grant = EndpointGrant::from_string(endpoint_grant_str)?;
+
+        let handle = get_endpoint_grant_registry()?.try_insert(endpoint_grant)?;
+        *out_endpoint_grant = handle as *mut GoslingEndpointGrant;
+
+        Ok(())
+    })
+}
+
+/// Get the size of the buffer required by gosling_endpoint_grant_get_string()
+///
+/// @param endpoint_grant: the endpoint grant to query
+/// @param error: filled on error
+/// @return the number of bytes required to hold the encoded endpoint grant including the
+///  null-terminator
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_endpoint_grant_get_string_size(
+    endpoint_grant: *const GoslingEndpointGrant,
+    error: *mut *mut GoslingError,
+) -> usize {
+    translate_failures(0, error, || -> anyhow::Result<usize> {
+        ensure_not_null!(endpoint_grant);
+
+        match get_endpoint_grant_registry()?.get(endpoint_grant as usize) {
+            Some(endpoint_grant) => Ok(endpoint_grant.to_string().len() + 1),
+            None => bail_invalid_handle!(endpoint_grant),
+        }
+    })
+}
+
+/// Get an endpoint grant encoded as a null-terminated string suitable for backup or transfer to
+/// another device. This string contains the client's x25519 private key and must be kept secret.
+///
+/// @param endpoint_grant: the endpoint grant to encode
+/// @param out_endpoint_grant_string: buffer to be filled with the encoded endpoint grant in the
+///  form "gosling-endpoint-grant:abcd1234...\0"
+/// @param endpoint_grant_string_size: size of out_endpoint_grant_string buffer in bytes, must be
+///  at least the value returned by gosling_endpoint_grant_get_string_size()
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_endpoint_grant_get_string(
+    endpoint_grant: *const GoslingEndpointGrant,
+    out_endpoint_grant_string: *mut c_char,
+    endpoint_grant_string_size: usize,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(endpoint_grant);
+        ensure_not_null!(out_endpoint_grant_string);
+
+        let endpoint_grant_string =
+            match get_endpoint_grant_registry()?.get(endpoint_grant as usize) {
+                Some(endpoint_grant) => endpoint_grant.to_string(),
+                None => bail_invalid_handle!(endpoint_grant),
+            };
+
+        if endpoint_grant_string_size < endpoint_grant_string.len() + 1 {
+            bail!(
+                "endpoint_grant_string_size must be at least '{}', received '{}'",
+                endpoint_grant_string.len() + 1,
+                endpoint_grant_string_size
+            );
+        }
+
+        copy_to_buffer(
+            &endpoint_grant_string,
+            out_endpoint_grant_string,
+            endpoint_grant_string_size,
+        );
+
+        Ok(())
+    })
+}
+
+/// Get the onion service id of the identity server which issued an endpoint grant
+///
+/// @param endpoint_grant: the endpoint grant to query
+/// @param out_service_id: returned service id object
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_endpoint_grant_get_identity_service_id(
+    endpoint_grant: *const GoslingEndpointGrant,
+    out_service_id: *mut *mut GoslingV3OnionServiceId,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(endpoint_grant);
+        ensure_not_null!(out_service_id);
+
+        let service_id = match get_endpoint_grant_registry()?.get(endpoint_grant as usize) {
+            Some(endpoint_grant) => endpoint_grant.identity_service_id().clone(),
+            None => bail_invalid_handle!(endpoint_grant),
+        };
+        let handle = get_v3_onion_service_id_registry()?.try_insert(service_id)?;
+        *out_service_id = handle as *mut GoslingV3OnionServiceId;
+
+        Ok(())
+    })
+}
+
+/// Get the onion service id of the identity client an endpoint grant was issued to
+///
+/// @param endpoint_grant: the endpoint grant to query
+/// @param out_service_id: returned service id object
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_endpoint_grant_get_client_service_id(
+    endpoint_grant: *const GoslingEndpointGrant,
+    out_service_id: *mut *mut GoslingV3OnionServiceId,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(endpoint_grant);
+        ensure_not_null!(out_service_id);
+
+        let service_id = match get_endpoint_grant_registry()?.get(endpoint_grant as usize) {
+            Some(endpoint_grant) => endpoint_grant.client_service_id().clone(),
+            None => bail_invalid_handle!(endpoint_grant),
+        };
+        let handle = get_v3_onion_service_id_registry()?.try_insert(service_id)?;
+        *out_service_id = handle as *mut GoslingV3OnionServiceId;
+
+        Ok(())
+    })
+}
+
+/// Get the onion service id of the endpoint server granted by an endpoint grant
+///
+/// @param endpoint_grant: the endpoint grant to query
+/// @param out_service_id: returned service id object
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_endpoint_grant_get_endpoint_service_id(
+    endpoint_grant: *const GoslingEndpointGrant,
+    out_service_id: *mut *mut GoslingV3OnionServiceId,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(endpoint_grant);
+        ensure_not_null!(out_service_id);
+
+        let service_id = match get_endpoint_grant_registry()?.get(endpoint_grant as usize) {
+            Some(endpoint_grant) => endpoint_grant.endpoint_service_id().clone(),
+            None => bail_invalid_handle!(endpoint_grant),
+        };
+        let handle = get_v3_onion_service_id_registry()?.try_insert(service_id)?;
+        *out_service_id = handle as *mut GoslingV3OnionServiceId;
+
+        Ok(())
+    })
+}
+
+/// Get the size of the buffer required by gosling_endpoint_grant_get_endpoint_name()
+///
+/// @param endpoint_grant: the endpoint grant to query
+/// @param error: filled on error
+/// @return the number of bytes required to hold the endpoint name including the null-terminator
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_endpoint_grant_get_endpoint_name_size(
+    endpoint_grant: *const GoslingEndpointGrant,
+    error: *mut *mut GoslingError,
+) -> usize {
+    translate_failures(0, error, || -> anyhow::Result<usize> {
+        ensure_not_null!(endpoint_grant);
+
+        match get_endpoint_grant_registry()?.get(endpoint_grant as usize) {
+            Some(endpoint_grant) => Ok(endpoint_grant.endpoint_name().len() + 1),
+            None => bail_invalid_handle!(endpoint_grant),
+        }
+    })
+}
+
+/// Get the name of the endpoint server granted by an endpoint grant as a null-terminated string
+///
+/// @param endpoint_grant: the endpoint grant to query
+/// @param out_endpoint_name: buffer to be filled with the ascii-encoded endpoint name
+/// @param endpoint_name_size: size of out_endpoint_name buffer in bytes, must be at least the
+///  value returned by gosling_endpoint_grant_get_endpoint_name_size()
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_endpoint_grant_get_endpoint_name(
+    endpoint_grant: *const GoslingEndpointGrant,
+    out_endpoint_name: *mut c_char,
+    endpoint_name_size: usize,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(endpoint_grant);
+        ensure_not_null!(out_endpoint_name);
+
+        let registry = get_endpoint_grant_registry()?;
+        let endpoint_name = match registry.get(endpoint_grant as usize) {
+            Some(endpoint_grant) => endpoint_grant.endpoint_name(),
+            None => bail_invalid_handle!(endpoint_grant),
+        };
+
+        if endpoint_name_size < endpoint_name.len() + 1 {
+            bail!(
+                "endpoint_name_size must be at least '{}', received '{}'",
+                endpoint_name.len() + 1,
+                endpoint_name_size
+            );
+        }
+
+        copy_to_buffer(endpoint_name, out_endpoint_name, endpoint_name_size);
+
+        Ok(())
+    })
+}
+
+/// Get the x25519 private key required to access the endpoint server granted by an endpoint grant
+///
+/// @param endpoint_grant: the endpoint grant to query
+/// @param out_client_auth_private_key: returned x25519 private key
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_endpoint_grant_get_client_auth_private_key(
+    endpoint_grant: *const GoslingEndpointGrant,
+    out_client_auth_private_key: *mut *mut GoslingX25519PrivateKey,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(endpoint_grant);
+        ensure_not_null!(out_client_auth_private_key);
+
+        let client_auth_private_key =
+            match get_endpoint_grant_registry()?.get(endpoint_grant as usize) {
+                Some(endpoint_grant) => endpoint_grant.client_auth_private_key().clone(),
+                None => bail_invalid_handle!(endpoint_grant),
+            };
+        let handle = get_x25519_private_key_registry()?.try_insert(client_auth_private_key)?;
+        *out_client_auth_private_key = handle as *mut GoslingX25519PrivateKey;
+
+        Ok(())
+    })
+}
+
+/// Determine whether an endpoint grant was issued to the given client for the given endpoint
+/// server. Endpoint servers may use this to validate a client presenting a previously issued
+/// grant.
+///
+/// @param endpoint_grant: the endpoint grant to verify
+/// @param endpoint_service_id: the onion service id of the endpoint server
+/// @param client_service_id: the onion service id of the connecting client
+/// @param client_auth_public_key: the x25519 public key used to encrypt the endpoint server's
+///  onion service descriptor
+/// @param error: filled on error
+/// @return true if the endpoint grant matches the provided endpoint server and client
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_endpoint_grant_verify(
+    endpoint_grant: *const GoslingEndpointGrant,
+    endpoint_service_id: *const GoslingV3OnionServiceId,
+    client_service_id: *const GoslingV3OnionServiceId,
+    client_auth_public_key: *const GoslingX25519PublicKey,
+    error: *mut *mut GoslingError,
+) -> bool {
+    translate_failures(false, error, || -> anyhow::Result<bool> {
+        ensure_not_null!(endpoint_grant);
+        ensure_not_null!(endpoint_service_id);
+        ensure_not_null!(client_service_id);
+        ensure_not_null!(client_auth_public_key);
+
+        let endpoint_grant_registry = get_endpoint_grant_registry()?;
+        let endpoint_grant = match endpoint_grant_registry.get(endpoint_grant as usize) {
+            Some(endpoint_grant) => endpoint_grant,
+            None => bail_invalid_handle!(endpoint_grant),
+        };
+
+        let v3_onion_service_id_registry = get_v3_onion_service_id_registry()?;
+        let endpoint_service_id =
+            match v3_onion_service_id_registry.get(endpoint_service_id as usize) {
+                Some(endpoint_service_id) => endpoint_service_id,
+                None => bail_invalid_handle!(endpoint_service_id),
+            };
+        let client_service_id = match v3_onion_service_id_registry.get(client_service_id as usize) {
+            Some(client_service_id) => client_service_id,
+            None => bail_invalid_handle!(client_service_id),
+        };
+
+        let x25519_public_key_registry = get_x25519_public_key_registry()?;
+        let client_auth_public_key =
+            match x25519_public_key_registry.get(client_auth_public_key as usize) {
+                Some(client_auth_public_key) => client_auth_public_key,
+                None => bail_invalid_handle!(client_auth_public_key),
+            };
+
+        Ok(endpoint_grant.verify(
+            endpoint_service_id,
+            client_service_id,
+            client_auth_public_key,
+        ))
+    })
+}
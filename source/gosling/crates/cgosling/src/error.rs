@@ -6,29 +6,114 @@ use std::os::raw::c_char;
 use anyhow::bail;
 #[cfg(feature = "impl-lib")]
 use cgosling_proc_macros::*;
+use gosling::context::Error as ContextError;
 
 // internal crates
 use crate::ffi::*;
 use crate::macros::*;
+use crate::object_registry::{HandleLimitExceeded, ReentrantCall};
+
+/// The error has no more specific error code
+pub const ERROR_CODE_GENERIC: u32 = 0;
+/// The tor daemon is older than the minimum version supported by the legacy tor provider;
+/// the error message contains the found and required versions
+pub const ERROR_CODE_TOR_VERSION_TOO_OLD: u32 = 1;
+/// The tor daemon binary could not be found at the configured path
+pub const ERROR_CODE_TOR_BINARY_NOT_FOUND: u32 = 2;
+/// The tor daemon's data directory or its configuration files could not be created or written
+pub const ERROR_CODE_TOR_DATA_DIRECTORY_NOT_WRITABLE: u32 = 3;
+/// A new handle could not be returned because the limit set with
+/// gosling_library_set_handle_limit() for its type has been reached
+pub const ERROR_CODE_HANDLE_LIMIT_EXCEEDED: u32 = 4;
+/// An endpoint or channel name is longer than MAX_ENDPOINT_NAME_LENGTH or
+/// MAX_CHANNEL_NAME_LENGTH bytes
+pub const ERROR_CODE_NAME_TOO_LONG: u32 = 5;
+/// An endpoint server refused to open a channel because the client exceeded its channel
+/// quota; see gosling_context_set_endpoint_server_channel_quota()
+pub const ERROR_CODE_CHANNEL_QUOTA_EXCEEDED: u32 = 6;
+/// A server turned away a client because it is banned; see gosling_context_ban_peer()
+pub const ERROR_CODE_CLIENT_BANNED: u32 = 7;
+/// A function was called from within a callback which must not call it, e.g. a
+/// gosling_context_* function from within the identity server client filter callback;
+/// the call fails rather than deadlocking
+pub const ERROR_CODE_REENTRANT_CALL: u32 = 8;
 
 /// Error Handling
 #[derive(Clone)]
 pub struct Error {
+    code: u32,
     message: CString,
 }
 
 impl Error {
     pub fn new(message: &str) -> Error {
+        Self::with_code(ERROR_CODE_GENERIC, message)
+    }
+
+    pub fn with_code(code: u32, message: &str) -> Error {
         Error {
+            code,
             message: CString::new(message).unwrap_or_default(),
         }
     }
 
+    pub fn code(&self) -> u32 {
+        self.code
+    }
+
     pub fn message(&self) -> &CString {
         &self.message
     }
 }
 
+// the ERROR_CODE_* constant describing the failure behind err
+pub(crate) fn error_code(err: &anyhow::Error) -> u32 {
+    for cause in err.chain() {
+        if cause.downcast_ref::<HandleLimitExceeded>().is_some() {
+            return ERROR_CODE_HANDLE_LIMIT_EXCEEDED;
+        }
+        if cause.downcast_ref::<ReentrantCall>().is_some() {
+            return ERROR_CODE_REENTRANT_CALL;
+        }
+        if let Some(ContextError::EndpointNameTooLong(_) | ContextError::ChannelNameTooLong(_)) =
+            cause.downcast_ref()
+        {
+            return ERROR_CODE_NAME_TOO_LONG;
+        }
+        if let Some(ContextError::EndpointChannelQuotaExceeded(..)) = cause.downcast_ref() {
+            return ERROR_CODE_CHANNEL_QUOTA_EXCEEDED;
+        }
+
+        #[cfg(feature = "legacy-tor-provider")]
+        {
+            use tor_interface::legacy_tor_client::Error as LegacyTorClientError;
+            use tor_interface::legacy_tor_client::LegacyTorProcessError;
+
+            if let Some(LegacyTorClientError::LegacyTorProcessTooOld(_, _)) = cause.downcast_ref() {
+                return ERROR_CODE_TOR_VERSION_TOO_OLD;
+            }
+            match cause.downcast_ref() {
+                Some(LegacyTorProcessError::LegacyTorProcessStartFailed(err))
+                    if err.kind() == std::io::ErrorKind::NotFound =>
+                {
+                    return ERROR_CODE_TOR_BINARY_NOT_FOUND;
+                }
+                Some(
+                    LegacyTorProcessError::DataDirectoryCreationFailed(_)
+                    | LegacyTorProcessError::DataDirectoryPathExistsAsFile(_)
+                    | LegacyTorProcessError::DefaultTorrcFileCreationFailed(_)
+                    | LegacyTorProcessError::DefaultTorrcFileWriteFailed(_)
+                    | LegacyTorProcessError::TorrcFileCreationFailed(_),
+                ) => {
+                    return ERROR_CODE_TOR_DATA_DIRECTORY_NOT_WRITABLE;
+                }
+                _ => (),
+            }
+        }
+    }
+    ERROR_CODE_GENERIC
+}
+
 define_registry! {Error}
 
 /// A wrapper object containing an error message
@@ -45,8 +130,7 @@ pub extern "C" fn gosling_error_get_message(error: *const GoslingError) -> *cons
     if !error.is_null() {
         let key = error as usize;
 
-        let registry = get_error_registry();
-        if registry.contains_key(key) {
+        if let Ok(registry) = get_error_registry() {
             if let Some(x) = registry.get(key) {
                 return x.message().as_ptr();
             }
@@ -56,6 +140,26 @@ pub extern "C" fn gosling_error_get_message(error: *const GoslingError) -> *cons
     std::ptr::null()
 }
 
+/// Get the error code from gosling_error, allowing callers to distinguish failures which
+/// need specific remediation (e.g. updating the tor daemon) from generic failures
+///
+/// @param error: the error object to get the code from
+/// @return one of the ERROR_CODE_* constants, or ERROR_CODE_GENERIC if error is null or
+///  invalid
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_error_get_code(error: *const GoslingError) -> u32 {
+    if !error.is_null() {
+        if let Ok(registry) = get_error_registry() {
+            if let Some(x) = registry.get(error as usize) {
+                return x.code();
+            }
+        }
+    }
+
+    ERROR_CODE_GENERIC
+}
+
 /// Copy method for gosling_error
 ///
 /// @param out_error: returned copy
@@ -72,11 +176,11 @@ pub unsafe extern "C" fn gosling_error_clone(
         ensure_not_null!(out_error);
         ensure_not_null!(orig_error);
 
-        let orig_error = match get_error_registry().get(orig_error as usize) {
+        let orig_error = match get_error_registry()?.get(orig_error as usize) {
             Some(orig_error) => orig_error.clone(),
             None => bail_invalid_handle!(orig_error),
         };
-        let handle = get_error_registry().insert(orig_error);
+        let handle = get_error_registry()?.insert(orig_error);
         *out_error = handle as *mut GoslingError;
 
         Ok(())
@@ -116,21 +220,31 @@ where
         Ok(Err(err)) => {
             if !out_error.is_null() {
                 // populate error with runtime error message
-                let key = get_error_registry().insert(Error::new(format!("{:?}", err).as_str()));
-                unsafe {
-                    *out_error = key as *mut GoslingError;
-                };
+                if let Ok(mut registry) = get_error_registry() {
+                    let key = registry.insert(Error::with_code(
+                        error_code(&err),
+                        format!("{:?}", err).as_str(),
+                    ));
+                    unsafe {
+                        *out_error = key as *mut GoslingError;
+                    };
+                }
             }
             default
         }
         // handle panic
         Err(_) => {
+            if abort_on_panic() {
+                std::process::abort();
+            }
             if !out_error.is_null() {
                 // populate error with panic message
-                let key = get_error_registry().insert(Error::new("panic occurred"));
-                unsafe {
-                    *out_error = key as *mut GoslingError;
-                };
+                if let Ok(mut registry) = get_error_registry() {
+                    let key = registry.insert(Error::new("panic occurred"));
+                    unsafe {
+                        *out_error = key as *mut GoslingError;
+                    };
+                }
             }
             default
         }
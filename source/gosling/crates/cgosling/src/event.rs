@@ -0,0 +1,1496 @@
+// standard
+use std::ffi::CString;
+use std::net::TcpStream;
+use std::os::raw::c_char;
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+use std::os::unix::io::IntoRawFd;
+#[cfg(target_os = "windows")]
+use std::os::windows::io::IntoRawSocket;
+
+// extern crates
+use anyhow::bail;
+#[cfg(feature = "impl-lib")]
+use cgosling_proc_macros::*;
+use gosling::bans::Ban;
+use gosling::channel_quota::ChannelQuotaExceeded;
+use gosling::connectivity::DescriptorFetchOutcome;
+use gosling::context::*;
+use gosling::datagram_channel::DatagramChannel;
+use gosling::rpc_channel::RpcChannel;
+use gosling::timing::HandshakeStats;
+use tor_interface::tor_crypto::*;
+use tor_interface::tor_provider::SocksPolicyViolation;
+
+// internal crates
+use crate::context::*;
+use crate::crypto::*;
+use crate::datagram_channel::*;
+use crate::error::*;
+use crate::ffi::*;
+use crate::macros::*;
+use crate::rpc_channel::*;
+
+//
+// Event Types
+//
+// New event types may be added in future versions of cgosling; consumers
+// must ignore (and free) any events whose type they do not recognise.
+//
+
+/// Tor bootstrap progress
+///
+/// integer 0: bootstrap percent completion from 0 to 100
+/// string 0: a short string indicating the completed bootstrap step
+/// string 1: a longer human-readable summary of the bootstrap progress
+pub const EVENT_TYPE_TOR_BOOTSTRAP_STATUS_RECEIVED: u32 = 1;
+/// Tor bootstrap completed
+pub const EVENT_TYPE_TOR_BOOTSTRAP_COMPLETED: u32 = 2;
+/// The context switched to the tor provider passed to gosling_context_set_tor_provider();
+/// its identity server and endpoint servers have been restarted and its client authorization
+/// keys re-added
+pub const EVENT_TYPE_TOR_PROVIDER_CHANGED: u32 = 31;
+/// A log line from the tor daemon
+///
+/// string 0: the log line
+pub const EVENT_TYPE_TOR_LOG_RECEIVED: u32 = 3;
+/// The context's event queue overflowed and events were discarded or merged
+///
+/// integer 0: the number of discarded events
+/// integer 1: the number of tor log lines merged into a single event
+pub const EVENT_TYPE_EVENT_QUEUE_OVERFLOWED: u32 = 4;
+/// An outbound handshake was queued rather than started
+///
+/// handshake handle: the queued handshake
+/// integer 0: the number of queued handshakes which will be started first
+pub const EVENT_TYPE_OUTBOUND_HANDSHAKE_QUEUED: u32 = 5;
+/// An identity client handshake completed successfully
+///
+/// handshake handle: the completed handshake
+/// v3 onion service id 0: the identity server's service id
+/// v3 onion service id 1: the granted endpoint server's service id
+/// string 0: the name of the granted endpoint
+/// string 1: the contact request sent with the endpoint request; only present if one was sent
+/// x25519 private key: the client authorisation key for the granted endpoint server
+/// integer 0: the number of honk-rpc round-trips made
+/// integer 1: the number of bytes sent
+/// integer 2: the number of bytes received
+/// integer 3: the negotiated CAPABILITY_* flags
+pub const EVENT_TYPE_IDENTITY_CLIENT_HANDSHAKE_COMPLETED: u32 = 6;
+/// An identity client handshake failed
+///
+/// handshake handle: the failed handshake
+/// string 0: the failure reason
+/// integer 0: the number of honk-rpc round-trips made before the failure
+/// integer 1: the number of bytes sent before the failure
+/// integer 2: the number of bytes received before the failure
+pub const EVENT_TYPE_IDENTITY_CLIENT_HANDSHAKE_FAILED: u32 = 7;
+/// The identity server has been published
+pub const EVENT_TYPE_IDENTITY_SERVER_PUBLISHED: u32 = 8;
+/// An identity server handshake started
+///
+/// handshake handle: the started handshake
+pub const EVENT_TYPE_IDENTITY_SERVER_HANDSHAKE_STARTED: u32 = 9;
+/// An identity server handshake completed successfully
+///
+/// handshake handle: the completed handshake
+/// ed25519 private key: the private key of the newly granted endpoint server
+/// string 0: the name of the granted endpoint
+/// string 1: the client's contact request; only present if the client sent one
+/// v3 onion service id 0: the identity client's service id
+/// x25519 public key: the client authorisation key for the granted endpoint server
+/// integer 0: the number of honk-rpc round-trips made
+/// integer 1: the number of bytes sent
+/// integer 2: the number of bytes received
+/// integer 3: the negotiated CAPABILITY_* flags
+pub const EVENT_TYPE_IDENTITY_SERVER_HANDSHAKE_COMPLETED: u32 = 10;
+/// An identity server handshake was rejected
+///
+/// handshake handle: the rejected handshake
+/// bool 0: whether the client was allowed
+/// bool 1: whether the requested endpoint was valid
+/// bool 2: whether the client's proof signature was valid
+/// bool 3: whether the client's client authorisation signature was valid
+/// bool 4: whether the client's challenge response was valid
+pub const EVENT_TYPE_IDENTITY_SERVER_HANDSHAKE_REJECTED: u32 = 11;
+/// An identity server handshake failed
+///
+/// handshake handle: the failed handshake
+/// string 0: the failure reason
+/// integer 0: the number of honk-rpc round-trips made before the failure
+/// integer 1: the number of bytes sent before the failure
+/// integer 2: the number of bytes received before the failure
+pub const EVENT_TYPE_IDENTITY_SERVER_HANDSHAKE_FAILED: u32 = 12;
+/// An endpoint client handshake completed successfully
+///
+/// handshake handle: the completed handshake
+/// v3 onion service id 0: the endpoint server's service id
+/// string 0: the name of the opened channel
+/// tcp socket: the connected channel
+/// integer 0: the number of honk-rpc round-trips made
+/// integer 1: the number of bytes sent
+/// integer 2: the number of bytes received
+/// integer 3: the negotiated CAPABILITY_* flags
+pub const EVENT_TYPE_ENDPOINT_CLIENT_HANDSHAKE_COMPLETED: u32 = 13;
+/// An endpoint client handshake failed
+///
+/// handshake handle: the failed handshake
+/// string 0: the failure reason
+/// integer 0: the number of honk-rpc round-trips made before the failure
+/// integer 1: the number of bytes sent before the failure
+/// integer 2: the number of bytes received before the failure
+pub const EVENT_TYPE_ENDPOINT_CLIENT_HANDSHAKE_FAILED: u32 = 14;
+/// An endpoint server has been published
+///
+/// v3 onion service id 0: the endpoint server's service id
+/// string 0: the name of the endpoint
+pub const EVENT_TYPE_ENDPOINT_SERVER_PUBLISHED: u32 = 15;
+/// An endpoint server handshake started
+///
+/// handshake handle: the started handshake
+pub const EVENT_TYPE_ENDPOINT_SERVER_HANDSHAKE_STARTED: u32 = 16;
+/// An endpoint server handshake completed successfully
+///
+/// handshake handle: the completed handshake
+/// v3 onion service id 0: the endpoint server's service id
+/// v3 onion service id 1: the endpoint client's service id
+/// string 0: the name of the opened channel
+/// tcp socket: the connected channel
+/// integer 0: the number of honk-rpc round-trips made
+/// integer 1: the number of bytes sent
+/// integer 2: the number of bytes received
+/// integer 3: the negotiated CAPABILITY_* flags
+pub const EVENT_TYPE_ENDPOINT_SERVER_HANDSHAKE_COMPLETED: u32 = 17;
+/// An endpoint server handshake was rejected
+///
+/// handshake handle: the rejected handshake
+/// bool 0: whether the client was allowed
+/// bool 1: whether the requested channel was valid
+/// bool 2: whether the client's proof signature was valid
+pub const EVENT_TYPE_ENDPOINT_SERVER_HANDSHAKE_REJECTED: u32 = 18;
+/// An endpoint server handshake failed
+///
+/// handshake handle: the failed handshake
+/// string 0: the failure reason
+/// integer 0: the number of honk-rpc round-trips made before the failure
+/// integer 1: the number of bytes sent before the failure
+/// integer 2: the number of bytes received before the failure
+pub const EVENT_TYPE_ENDPOINT_SERVER_HANDSHAKE_FAILED: u32 = 19;
+/// An identity client handshake was not attempted because the identity server recently failed
+/// to connect; see gosling_context_set_identity_server_negative_ttl()
+///
+/// handshake handle: the suppressed handshake
+/// v3 onion service id 0: the identity server's service id
+/// integer 0: the number of seconds until connections to the identity server are attempted again
+pub const EVENT_TYPE_IDENTITY_CLIENT_HANDSHAKE_SUPPRESSED: u32 = 20;
+/// A client authorisation key was added to the tor daemon
+///
+/// v3 onion service id 0: the endpoint server's service id
+pub const EVENT_TYPE_CLIENT_AUTH_ADDED: u32 = 21;
+/// A client authorisation key could not be added to the tor daemon
+///
+/// v3 onion service id 0: the endpoint server's service id
+/// string 0: the failure reason
+pub const EVENT_TYPE_CLIENT_AUTH_ADD_FAILED: u32 = 22;
+/// A client authorisation key was removed from the tor daemon
+///
+/// v3 onion service id 0: the endpoint server's service id
+pub const EVENT_TYPE_CLIENT_AUTH_REMOVED: u32 = 23;
+/// A client authorisation key could not be removed from the tor daemon
+///
+/// v3 onion service id 0: the endpoint server's service id
+/// string 0: the failure reason
+pub const EVENT_TYPE_CLIENT_AUTH_REMOVE_FAILED: u32 = 24;
+/// An endpoint client handshake was turned away because the endpoint server is busy
+///
+/// handshake handle: the turned away handshake
+/// v3 onion service id 0: the endpoint server's service id
+/// string 0: the name of the requested channel
+/// integer 0: the number of seconds the endpoint server asked the client to wait before retrying
+pub const EVENT_TYPE_ENDPOINT_CLIENT_HANDSHAKE_BUSY: u32 = 25;
+/// An endpoint server handshake was turned away because the context's endpoint servers are
+/// busy; see gosling_context_set_endpoint_server_busy()
+///
+/// handshake handle: the turned away handshake
+/// v3 onion service id 0: the endpoint client's service id
+/// string 0: the name of the requested channel
+/// integer 0: the number of seconds the client was asked to wait before retrying
+pub const EVENT_TYPE_ENDPOINT_SERVER_HANDSHAKE_BUSY: u32 = 26;
+/// A circuit built by the context's tor provider changed status; only returned when
+/// TOR_EVENT_VERBOSITY_VERBOSE is set with gosling_context_set_tor_event_verbosity()
+///
+/// string 0: the tor provider's identifier for the circuit
+/// string 1: the circuit's new status, e.g. "BUILT" or "FAILED"
+/// string 2: the comma-separated relays the circuit has been extended through
+/// string 3: why the circuit was built, or an empty string if not reported
+/// string 4: why the circuit failed or was closed, or an empty string if not reported
+pub const EVENT_TYPE_TOR_CIRCUIT_STATUS_CHANGED: u32 = 27;
+/// A stream opened through the context's tor provider changed status; only returned when
+/// TOR_EVENT_VERBOSITY_VERBOSE is set with gosling_context_set_tor_event_verbosity()
+///
+/// string 0: the tor provider's identifier for the stream
+/// string 1: the stream's new status, e.g. "SUCCEEDED" or "FAILED"
+/// string 2: the tor provider's identifier for the circuit the stream is attached to
+/// string 3: the stream's target as 'address:port'
+/// string 4: why the stream failed, was detached, or was closed, or an empty string if not
+///  reported
+pub const EVENT_TYPE_TOR_STREAM_STATUS_CHANGED: u32 = 28;
+/// An endpoint server closed an incoming connection without beginning a handshake; see
+/// gosling_context_set_endpoint_server_concurrency_limit()
+///
+/// v3 onion service id 0: the endpoint server's service id
+pub const EVENT_TYPE_ENDPOINT_SERVER_CONNECTION_SHED: u32 = 29;
+/// An attempt to upload an identity or endpoint server's descriptor to a hidden-service directory
+/// finished; only returned when TOR_EVENT_VERBOSITY_VERBOSE is set with
+/// gosling_context_set_tor_event_verbosity()
+///
+/// v3 onion service id 0: the identity or endpoint server's service id
+/// bool 0: whether the hidden-service directory accepted the descriptor
+/// string 0: the tor provider's identifier for the hidden-service directory, or an empty string
+///  if not reported
+/// string 1: why the upload failed, or an empty string if not reported
+/// integer 0: the number of the server's descriptor uploads which have succeeded
+/// integer 1: the number of the server's descriptor uploads which have failed
+pub const EVENT_TYPE_TOR_ONION_SERVICE_DESCRIPTOR_UPLOAD_STATUS: u32 = 30;
+/// An endpoint server revoked the context's access; see
+/// gosling_context_handle_endpoint_revocation()
+///
+/// v3 onion service id 0: the endpoint server's service id
+/// integer 0: when access was revoked in seconds since the unix epoch, according to the endpoint
+///  server's clock
+pub const EVENT_TYPE_ENDPOINT_CLIENT_ENDPOINT_REVOKED: u32 = 32;
+/// The identity server or an endpoint server could not be restarted after the context switched
+/// to the tor provider passed to gosling_context_set_tor_provider(); the server is stopped
+///
+/// v3 onion service id 0: the server's service id
+/// integer 0: the virt-port the server's onion service would have listened on
+/// string 0: why the server's listener could not be started
+pub const EVENT_TYPE_LISTENER_START_FAILED: u32 = 33;
+/// The tor log buffer set with gosling_context_set_tor_log_buffer() was full and its oldest
+/// lines were discarded
+///
+/// integer 0: the number of discarded tor log lines
+pub const EVENT_TYPE_TOR_LOG_LINES_DROPPED: u32 = 34;
+/// An identity server granted a different endpoint server than the one pinned for the endpoint;
+/// see gosling_context_set_endpoint_pinning_enabled(). Returned just before the identity
+/// client handshake completed event
+///
+/// handshake handle: the completed handshake
+/// v3 onion service id 0: the identity server's service id
+/// v3 onion service id 1: the pinned endpoint server's service id
+/// v3 onion service id 2: the granted endpoint server's service id
+/// string 0: the name of the granted endpoint
+pub const EVENT_TYPE_IDENTITY_CLIENT_ENDPOINT_PIN_MISMATCHED: u32 = 35;
+/// The identity server's descriptor has been refreshed; see
+/// gosling_context_refresh_identity_server_descriptor()
+pub const EVENT_TYPE_IDENTITY_SERVER_DESCRIPTOR_REFRESHED: u32 = 36;
+/// An endpoint server's descriptor has been refreshed; see
+/// gosling_context_refresh_endpoint_server_descriptor()
+///
+/// v3 onion service id 0: the endpoint server's service id
+/// string 0: the name of the endpoint
+pub const EVENT_TYPE_ENDPOINT_SERVER_DESCRIPTOR_REFRESHED: u32 = 37;
+/// An endpoint client handshake for a channel registered with
+/// gosling_context_add_rpc_channel() completed successfully
+///
+/// handshake handle: the completed handshake
+/// v3 onion service id 0: the endpoint server's service id
+/// string 0: the name of the opened channel
+/// rpc channel: the opened rpc channel
+/// integer 0: the number of honk-rpc round-trips made
+/// integer 1: the number of bytes sent
+/// integer 2: the number of bytes received
+/// integer 3: the negotiated CAPABILITY_* flags
+pub const EVENT_TYPE_ENDPOINT_CLIENT_RPC_CHANNEL_OPENED: u32 = 38;
+/// An endpoint server handshake for a channel registered with
+/// gosling_context_add_rpc_channel() completed successfully
+///
+/// handshake handle: the completed handshake
+/// v3 onion service id 0: the endpoint server's service id
+/// v3 onion service id 1: the endpoint client's service id
+/// string 0: the name of the opened channel
+/// rpc channel: the opened rpc channel
+/// integer 0: the number of honk-rpc round-trips made
+/// integer 1: the number of bytes sent
+/// integer 2: the number of bytes received
+/// integer 3: the negotiated CAPABILITY_* flags
+pub const EVENT_TYPE_ENDPOINT_SERVER_RPC_CHANNEL_OPENED: u32 = 39;
+/// An endpoint server handshake was refused because the endpoint client exceeded its channel
+/// quota; see gosling_context_set_endpoint_server_channel_quota()
+///
+/// handshake handle: the refused handshake
+/// v3 onion service id 0: the endpoint client's service id
+/// string 0: the name of the requested channel
+/// integer 0: the exceeded CHANNEL_QUOTA_* limit
+/// integer 1: the value of the exceeded limit
+/// integer 2: the length of the time window in seconds; 0 for CHANNEL_QUOTA_OPEN_CHANNELS
+/// integer 3: the number of seconds before the client may open another channel; 0 for
+///  CHANNEL_QUOTA_OPEN_CHANNELS
+pub const EVENT_TYPE_ENDPOINT_SERVER_CHANNEL_QUOTA_EXCEEDED: u32 = 40;
+/// An identity server turned away a banned client; see gosling_context_ban_peer()
+///
+/// handshake handle: the refused handshake
+/// v3 onion service id 0: the identity client's service id
+/// string 0: the name of the requested endpoint
+/// string 1: the reason given for the ban
+/// integer 0: when the ban expires in seconds since the unix epoch; 0 for a permanent ban
+pub const EVENT_TYPE_IDENTITY_SERVER_BANNED_CLIENT_REJECTED: u32 = 41;
+/// An endpoint server turned away a banned client; see gosling_context_ban_peer()
+///
+/// handshake handle: the refused handshake
+/// v3 onion service id 0: the endpoint client's service id
+/// string 0: the name of the requested channel
+/// string 1: the reason given for the ban
+/// integer 0: when the ban expires in seconds since the unix epoch; 0 for a permanent ban
+pub const EVENT_TYPE_ENDPOINT_SERVER_BANNED_CLIENT_REJECTED: u32 = 42;
+/// A connectivity check begun with gosling_context_check_connectivity() finished
+///
+/// bool 0: whether the tor provider had bootstrapped when the check began
+/// bool 1: whether the check reached the tor network, i.e. a hidden-service directory answered
+/// integer 0: the DESCRIPTOR_FETCH_* outcome of fetching the identity server's descriptor
+/// integer 1: how long the check took in milliseconds
+/// integer 2: the number of circuits built while the check was in progress
+/// integer 3: the number of circuits which failed while the check was in progress
+/// string 0: the tor provider's identifier for the hidden-service directory, or an empty string
+///  if not reported
+/// string 1: why the descriptor fetch failed, or an empty string if not reported
+pub const EVENT_TYPE_CONNECTIVITY_CHECKED: u32 = 43;
+/// A connection kept open after an identity client handshake completed was closed because no
+/// further handshake began over it in time; see gosling_context_set_identity_session_keep_open()
+///
+/// handshake handle: the completed handshake whose connection was closed
+/// v3 onion service id 0: the identity server's service id
+pub const EVENT_TYPE_IDENTITY_CLIENT_SESSION_CLOSED: u32 = 44;
+/// A connection kept open after an identity server handshake completed was closed, either
+/// because no further handshake began over it in time or because the client closed it; see
+/// gosling_context_set_identity_session_keep_open()
+///
+/// handshake handle: the completed handshake whose connection was closed
+/// v3 onion service id 0: the identity client's service id
+pub const EVENT_TYPE_IDENTITY_SERVER_SESSION_CLOSED: u32 = 45;
+/// A SOCKS request made through the context's tor provider violated its SOCKS policy; see
+/// gosling_tor_provider_config_set_socks_policy()
+///
+/// integer 0: the SOCKS_POLICY_VIOLATION_* kind of violation
+/// integer 1: the requested port for SOCKS_POLICY_VIOLATION_PLAINTEXT_PORT, otherwise 0
+/// bool 0: whether the stream was refused rather than only warned about for
+///  SOCKS_POLICY_VIOLATION_PLAINTEXT_PORT, otherwise false
+/// string 0: the SOCKS protocol of the request for SOCKS_POLICY_VIOLATION_UNSAFE_ADDRESS,
+///  otherwise an empty string
+/// string 1: the requested target as 'address:port' for SOCKS_POLICY_VIOLATION_UNSAFE_ADDRESS,
+///  the requested hostname for SOCKS_POLICY_VIOLATION_INVALID_HOSTNAME, otherwise an empty
+///  string
+pub const EVENT_TYPE_TOR_SOCKS_POLICY_VIOLATED: u32 = 46;
+/// An outbound handshake which was queued because the tor provider had not yet bootstrapped
+/// was started; see gosling_context_set_queue_until_bootstrapped()
+///
+/// handshake handle: the started handshake
+pub const EVENT_TYPE_OUTBOUND_HANDSHAKE_STARTED: u32 = 47;
+/// An endpoint client handshake for a channel registered with
+/// gosling_context_add_datagram_channel() completed successfully
+///
+/// handshake handle: the completed handshake
+/// v3 onion service id 0: the endpoint server's service id
+/// string 0: the name of the opened channel
+/// datagram channel: the opened datagram channel
+/// integer 0: the number of honk-rpc round-trips made
+/// integer 1: the number of bytes sent
+/// integer 2: the number of bytes received
+/// integer 3: the negotiated CAPABILITY_* flags
+pub const EVENT_TYPE_ENDPOINT_CLIENT_DATAGRAM_CHANNEL_OPENED: u32 = 48;
+/// An endpoint server handshake for a channel registered with
+/// gosling_context_add_datagram_channel() completed successfully
+///
+/// handshake handle: the completed handshake
+/// v3 onion service id 0: the endpoint server's service id
+/// v3 onion service id 1: the endpoint client's service id
+/// string 0: the name of the opened channel
+/// datagram channel: the opened datagram channel
+/// integer 0: the number of honk-rpc round-trips made
+/// integer 1: the number of bytes sent
+/// integer 2: the number of bytes received
+/// integer 3: the negotiated CAPABILITY_* flags
+pub const EVENT_TYPE_ENDPOINT_SERVER_DATAGRAM_CHANNEL_OPENED: u32 = 49;
+/// An endpoint server started with gosling_context_start_endpoint_server_with_completion()
+/// has been published; follows its EVENT_TYPE_ENDPOINT_SERVER_PUBLISHED event
+///
+/// v3 onion service id 0: the endpoint server's service id
+/// string 0: the name of the endpoint
+/// integer 0: the publish handle returned when the endpoint server was started
+pub const EVENT_TYPE_ENDPOINT_SERVER_PUBLISH_COMPLETED: u32 = 50;
+/// An endpoint server started with gosling_context_start_endpoint_server_with_completion()
+/// was not published within its timeout; the endpoint server keeps running
+///
+/// v3 onion service id 0: the endpoint server's service id
+/// string 0: the name of the endpoint
+/// integer 0: the publish handle returned when the endpoint server was started
+/// integer 1: the timeout which elapsed in milliseconds
+pub const EVENT_TYPE_ENDPOINT_SERVER_PUBLISH_TIMED_OUT: u32 = 51;
+
+/// A context event returned by gosling_context_next_event(). An event's payload is accessed by
+/// index through the gosling_event_get_*() functions, with the available fields of each event
+/// type documented alongside its EVENT_TYPE_* constant.
+pub struct GoslingEvent;
+
+/// cbindgen:ignore
+pub(crate) struct Event {
+    event_type: u32,
+    handshake_handle: Option<GoslingHandshakeHandle>,
+    integers: Vec<usize>,
+    bools: Vec<bool>,
+    strings: Vec<CString>,
+    service_ids: Vec<V3OnionServiceId>,
+    ed25519_private_key: Option<Ed25519PrivateKey>,
+    x25519_private_key: Option<X25519PrivateKey>,
+    x25519_public_key: Option<X25519PublicKey>,
+    tcp_stream: Option<TcpStream>,
+    rpc_channel: Option<RpcChannel>,
+    datagram_channel: Option<DatagramChannel>,
+}
+define_registry! {Event}
+
+impl Event {
+    fn new(event_type: u32) -> Self {
+        Self {
+            event_type,
+            handshake_handle: None,
+            integers: Default::default(),
+            bools: Default::default(),
+            strings: Default::default(),
+            service_ids: Default::default(),
+            ed25519_private_key: None,
+            x25519_private_key: None,
+            x25519_public_key: None,
+            tcp_stream: None,
+            rpc_channel: None,
+            datagram_channel: None,
+        }
+    }
+
+    fn handle(mut self, handle: GoslingHandshakeHandle) -> Self {
+        self.handshake_handle = Some(handle);
+        self
+    }
+
+    fn integer(mut self, integer: usize) -> Self {
+        self.integers.push(integer);
+        self
+    }
+
+    fn boolean(mut self, value: bool) -> Self {
+        self.bools.push(value);
+        self
+    }
+
+    fn string(mut self, string: &str) -> Self {
+        self.strings.push(
+            CString::new(string).expect("event string should not have an intermediate null byte"),
+        );
+        self
+    }
+
+    fn service_id(mut self, service_id: V3OnionServiceId) -> Self {
+        self.service_ids.push(service_id);
+        self
+    }
+
+    fn stats(self, stats: HandshakeStats) -> Self {
+        let integer = |value: u64| usize::try_from(value).unwrap_or(usize::MAX);
+        self.integer(integer(stats.round_trips))
+            .integer(integer(stats.bytes_sent))
+            .integer(integer(stats.bytes_received))
+    }
+
+    fn ban(self, ban: Ban) -> Self {
+        let expires_at = ban.expires_at.map_or(0, |expires_at| {
+            let since_epoch = expires_at
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default();
+            usize::try_from(since_epoch.as_secs()).unwrap_or(usize::MAX)
+        });
+        self.string(&ban.reason).integer(expires_at)
+    }
+
+    fn failure(
+        event_type: u32,
+        handle: GoslingHandshakeHandle,
+        reason: gosling::context::Error,
+        stats: HandshakeStats,
+    ) -> Self {
+        Self::new(event_type)
+            .handle(handle)
+            .string(format!("{:?}", reason).as_str())
+            .stats(stats)
+    }
+
+    // Converts a ContextEvent into an Event. Events which require a synchronous response
+    // from the application are returned unconverted.
+    fn try_from_context_event(event: ContextEvent) -> Result<Self, Box<ContextEvent>> {
+        let event = match event {
+            ContextEvent::TorBootstrapStatusReceived {
+                progress,
+                tag,
+                summary,
+            } => Self::new(EVENT_TYPE_TOR_BOOTSTRAP_STATUS_RECEIVED)
+                .integer(progress as usize)
+                .string(&tag)
+                .string(&summary),
+            ContextEvent::TorBootstrapCompleted => Self::new(EVENT_TYPE_TOR_BOOTSTRAP_COMPLETED),
+            ContextEvent::TorProviderChanged => Self::new(EVENT_TYPE_TOR_PROVIDER_CHANGED),
+            ContextEvent::ListenerStartFailed {
+                service_id,
+                virt_port,
+                reason,
+            } => Self::new(EVENT_TYPE_LISTENER_START_FAILED)
+                .service_id(service_id)
+                .integer(virt_port as usize)
+                .string(format!("{:?}", reason).as_str()),
+            ContextEvent::TorLogReceived { line } => {
+                Self::new(EVENT_TYPE_TOR_LOG_RECEIVED).string(&line)
+            }
+            ContextEvent::TorCircuitStatusChanged {
+                circuit_id,
+                status,
+                path,
+                purpose,
+                reason,
+            } => Self::new(EVENT_TYPE_TOR_CIRCUIT_STATUS_CHANGED)
+                .string(&circuit_id)
+                .string(&status.to_string())
+                .string(&path.join(","))
+                .string(&purpose.unwrap_or_default())
+                .string(&reason.unwrap_or_default()),
+            ContextEvent::TorStreamStatusChanged {
+                stream_id,
+                status,
+                circuit_id,
+                target,
+                reason,
+            } => Self::new(EVENT_TYPE_TOR_STREAM_STATUS_CHANGED)
+                .string(&stream_id)
+                .string(&status.to_string())
+                .string(&circuit_id)
+                .string(&target)
+                .string(&reason.unwrap_or_default()),
+            ContextEvent::TorOnionServiceDescriptorUploadStatus {
+                service_id,
+                hs_dir,
+                succeeded,
+                reason,
+                uploads_succeeded,
+                uploads_failed,
+            } => Self::new(EVENT_TYPE_TOR_ONION_SERVICE_DESCRIPTOR_UPLOAD_STATUS)
+                .service_id(service_id)
+                .boolean(succeeded)
+                .string(&hs_dir.unwrap_or_default())
+                .string(&reason.unwrap_or_default())
+                .integer(uploads_succeeded)
+                .integer(uploads_failed),
+            ContextEvent::TorSocksPolicyViolated { violation } => {
+                let (kind, port, rejected, protocol, target) = match violation {
+                    SocksPolicyViolation::UnsafeAddress { protocol, address } => (
+                        SOCKS_POLICY_VIOLATION_UNSAFE_ADDRESS,
+                        0,
+                        false,
+                        protocol,
+                        address,
+                    ),
+                    SocksPolicyViolation::PlaintextPort { port, rejected } => (
+                        SOCKS_POLICY_VIOLATION_PLAINTEXT_PORT,
+                        port,
+                        rejected,
+                        String::default(),
+                        String::default(),
+                    ),
+                    // the hostname is whatever the application sent, so may
+                    // contain null bytes
+                    SocksPolicyViolation::InvalidHostname { hostname } => (
+                        SOCKS_POLICY_VIOLATION_INVALID_HOSTNAME,
+                        0,
+                        false,
+                        String::default(),
+                        hostname.replace('\0', ""),
+                    ),
+                    SocksPolicyViolation::UnknownProtocol => (
+                        SOCKS_POLICY_VIOLATION_UNKNOWN_PROTOCOL,
+                        0,
+                        false,
+                        String::default(),
+                        String::default(),
+                    ),
+                };
+                Self::new(EVENT_TYPE_TOR_SOCKS_POLICY_VIOLATED)
+                    .integer(kind as usize)
+                    .integer(port as usize)
+                    .boolean(rejected)
+                    .string(&protocol)
+                    .string(&target)
+            }
+            ContextEvent::ConnectivityChecked { report } => {
+                let (outcome, reason) = match &report.descriptor_fetch {
+                    None => (DESCRIPTOR_FETCH_NOT_ATTEMPTED, None),
+                    Some(DescriptorFetchOutcome::Received) => (DESCRIPTOR_FETCH_RECEIVED, None),
+                    Some(DescriptorFetchOutcome::NotFound) => (DESCRIPTOR_FETCH_NOT_FOUND, None),
+                    Some(DescriptorFetchOutcome::Failed { reason }) => {
+                        (DESCRIPTOR_FETCH_FAILED, reason.as_deref())
+                    }
+                    Some(DescriptorFetchOutcome::TimedOut) => (DESCRIPTOR_FETCH_TIMED_OUT, None),
+                };
+                Self::new(EVENT_TYPE_CONNECTIVITY_CHECKED)
+                    .boolean(report.bootstrapped)
+                    .boolean(report.tor_reachable())
+                    .integer(outcome as usize)
+                    .integer(usize::try_from(report.duration.as_millis()).unwrap_or(usize::MAX))
+                    .integer(report.circuits_built)
+                    .integer(report.circuits_failed)
+                    .string(report.hs_dir.as_deref().unwrap_or_default())
+                    .string(reason.unwrap_or_default())
+            }
+            ContextEvent::EventQueueOverflowed {
+                dropped_events,
+                coalesced_tor_logs,
+            } => Self::new(EVENT_TYPE_EVENT_QUEUE_OVERFLOWED)
+                .integer(dropped_events)
+                .integer(coalesced_tor_logs),
+            ContextEvent::TorLogLinesDropped { dropped_lines } => {
+                Self::new(EVENT_TYPE_TOR_LOG_LINES_DROPPED).integer(dropped_lines)
+            }
+            ContextEvent::OutboundHandshakeQueued {
+                handle,
+                queue_position,
+            } => Self::new(EVENT_TYPE_OUTBOUND_HANDSHAKE_QUEUED)
+                .handle(handle)
+                .integer(queue_position),
+            ContextEvent::OutboundHandshakeStarted { handle } => {
+                Self::new(EVENT_TYPE_OUTBOUND_HANDSHAKE_STARTED).handle(handle)
+            }
+            ContextEvent::ClientAuthAdded {
+                endpoint_service_id,
+            } => Self::new(EVENT_TYPE_CLIENT_AUTH_ADDED).service_id(endpoint_service_id),
+            ContextEvent::ClientAuthAddFailed {
+                endpoint_service_id,
+                reason,
+            } => Self::new(EVENT_TYPE_CLIENT_AUTH_ADD_FAILED)
+                .service_id(endpoint_service_id)
+                .string(format!("{:?}", reason).as_str()),
+            ContextEvent::ClientAuthRemoved {
+                endpoint_service_id,
+            } => Self::new(EVENT_TYPE_CLIENT_AUTH_REMOVED).service_id(endpoint_service_id),
+            ContextEvent::ClientAuthRemoveFailed {
+                endpoint_service_id,
+                reason,
+            } => Self::new(EVENT_TYPE_CLIENT_AUTH_REMOVE_FAILED)
+                .service_id(endpoint_service_id)
+                .string(format!("{:?}", reason).as_str()),
+            ContextEvent::IdentityClientHandshakeCompleted {
+                handle,
+                identity_service_id,
+                endpoint_service_id,
+                endpoint_name,
+                client_auth_private_key,
+                additional_endpoints: _,
+                contact_request,
+                capabilities,
+                stats,
+            } => {
+                let mut event = Self::new(EVENT_TYPE_IDENTITY_CLIENT_HANDSHAKE_COMPLETED)
+                    .handle(handle)
+                    .service_id(identity_service_id)
+                    .service_id(endpoint_service_id)
+                    .string(&endpoint_name)
+                    .stats(stats)
+                    .integer(capabilities.bits() as usize);
+                if let Some(contact_request) = contact_request {
+                    event = event.string(&contact_request);
+                }
+                event.x25519_private_key = Some(client_auth_private_key);
+                event
+            }
+            ContextEvent::IdentityClientHandshakeFailed {
+                handle,
+                reason,
+                stats,
+            } => Self::failure(
+                EVENT_TYPE_IDENTITY_CLIENT_HANDSHAKE_FAILED,
+                handle,
+                reason,
+                stats,
+            ),
+            ContextEvent::IdentityClientSessionClosed {
+                handle,
+                identity_service_id,
+            } => Self::new(EVENT_TYPE_IDENTITY_CLIENT_SESSION_CLOSED)
+                .handle(handle)
+                .service_id(identity_service_id),
+            ContextEvent::IdentityClientHandshakeSuppressed {
+                handle,
+                identity_service_id,
+                retry_after,
+            } => Self::new(EVENT_TYPE_IDENTITY_CLIENT_HANDSHAKE_SUPPRESSED)
+                .handle(handle)
+                .service_id(identity_service_id)
+                .integer(retry_after.as_secs() as usize),
+            ContextEvent::IdentityClientEndpointPinMismatched {
+                handle,
+                identity_service_id,
+                endpoint_name,
+                pinned_endpoint_service_id,
+                endpoint_service_id,
+            } => Self::new(EVENT_TYPE_IDENTITY_CLIENT_ENDPOINT_PIN_MISMATCHED)
+                .handle(handle)
+                .service_id(identity_service_id)
+                .service_id(pinned_endpoint_service_id)
+                .service_id(endpoint_service_id)
+                .string(&endpoint_name),
+            ContextEvent::IdentityServerPublished => {
+                Self::new(EVENT_TYPE_IDENTITY_SERVER_PUBLISHED)
+            }
+            ContextEvent::IdentityServerDescriptorRefreshed => {
+                Self::new(EVENT_TYPE_IDENTITY_SERVER_DESCRIPTOR_REFRESHED)
+            }
+            ContextEvent::IdentityServerHandshakeStarted { handle } => {
+                Self::new(EVENT_TYPE_IDENTITY_SERVER_HANDSHAKE_STARTED).handle(handle)
+            }
+            ContextEvent::IdentityServerHandshakeCompleted {
+                handle,
+                endpoint_private_key,
+                endpoint_name,
+                client_service_id,
+                client_auth_public_key,
+                additional_endpoints: _,
+                contact_request,
+                capabilities,
+                stats,
+            } => {
+                let mut event = Self::new(EVENT_TYPE_IDENTITY_SERVER_HANDSHAKE_COMPLETED)
+                    .handle(handle)
+                    .string(&endpoint_name)
+                    .service_id(client_service_id)
+                    .stats(stats)
+                    .integer(capabilities.bits() as usize);
+                if let Some(contact_request) = contact_request {
+                    event = event.string(&contact_request);
+                }
+                event.ed25519_private_key = Some(endpoint_private_key);
+                event.x25519_public_key = Some(client_auth_public_key);
+                event
+            }
+            ContextEvent::IdentityServerHandshakeRejected {
+                handle,
+                client_allowed,
+                client_requested_endpoint_valid,
+                client_proof_signature_valid,
+                client_auth_signature_valid,
+                challenge_response_valid,
+            } => Self::new(EVENT_TYPE_IDENTITY_SERVER_HANDSHAKE_REJECTED)
+                .handle(handle)
+                .boolean(client_allowed)
+                .boolean(client_requested_endpoint_valid)
+                .boolean(client_proof_signature_valid)
+                .boolean(client_auth_signature_valid)
+                .boolean(challenge_response_valid),
+            ContextEvent::IdentityServerHandshakeFailed {
+                handle,
+                reason,
+                stats,
+            } => Self::failure(
+                EVENT_TYPE_IDENTITY_SERVER_HANDSHAKE_FAILED,
+                handle,
+                reason,
+                stats,
+            ),
+            ContextEvent::IdentityServerSessionClosed {
+                handle,
+                client_service_id,
+            } => Self::new(EVENT_TYPE_IDENTITY_SERVER_SESSION_CLOSED)
+                .handle(handle)
+                .service_id(client_service_id),
+            ContextEvent::EndpointClientHandshakeCompleted {
+                endpoint_service_id,
+                handle,
+                channel_name,
+                stream,
+                capabilities,
+                stats,
+            } => {
+                let mut event = Self::new(EVENT_TYPE_ENDPOINT_CLIENT_HANDSHAKE_COMPLETED)
+                    .handle(handle)
+                    .service_id(endpoint_service_id)
+                    .string(&channel_name)
+                    .stats(stats)
+                    .integer(capabilities.bits() as usize);
+                event.tcp_stream = Some(stream);
+                event
+            }
+            ContextEvent::EndpointClientRpcChannelOpened {
+                handle,
+                endpoint_service_id,
+                channel_name,
+                channel,
+                capabilities,
+                stats,
+            } => {
+                let mut event = Self::new(EVENT_TYPE_ENDPOINT_CLIENT_RPC_CHANNEL_OPENED)
+                    .handle(handle)
+                    .service_id(endpoint_service_id)
+                    .string(&channel_name)
+                    .stats(stats)
+                    .integer(capabilities.bits() as usize);
+                event.rpc_channel = Some(channel);
+                event
+            }
+            ContextEvent::EndpointClientDatagramChannelOpened {
+                handle,
+                endpoint_service_id,
+                channel_name,
+                channel,
+                capabilities,
+                stats,
+            } => {
+                let mut event = Self::new(EVENT_TYPE_ENDPOINT_CLIENT_DATAGRAM_CHANNEL_OPENED)
+                    .handle(handle)
+                    .service_id(endpoint_service_id)
+                    .string(&channel_name)
+                    .stats(stats)
+                    .integer(capabilities.bits() as usize);
+                event.datagram_channel = Some(channel);
+                event
+            }
+            ContextEvent::EndpointClientHandshakeFailed {
+                handle,
+                reason,
+                stats,
+            } => Self::failure(
+                EVENT_TYPE_ENDPOINT_CLIENT_HANDSHAKE_FAILED,
+                handle,
+                reason,
+                stats,
+            ),
+            ContextEvent::EndpointClientHandshakeBusy {
+                handle,
+                endpoint_service_id,
+                channel_name,
+                retry_after,
+            } => Self::new(EVENT_TYPE_ENDPOINT_CLIENT_HANDSHAKE_BUSY)
+                .handle(handle)
+                .service_id(endpoint_service_id)
+                .string(&channel_name)
+                .integer(retry_after.as_secs() as usize),
+            ContextEvent::EndpointClientEndpointRevoked {
+                endpoint_service_id,
+                revoked_at,
+            } => Self::new(EVENT_TYPE_ENDPOINT_CLIENT_ENDPOINT_REVOKED)
+                .service_id(endpoint_service_id)
+                .integer(unix_seconds(revoked_at) as usize),
+            ContextEvent::EndpointServerPublished {
+                endpoint_service_id,
+                endpoint_name,
+            } => Self::new(EVENT_TYPE_ENDPOINT_SERVER_PUBLISHED)
+                .service_id(endpoint_service_id)
+                .string(&endpoint_name),
+            ContextEvent::EndpointServerPublishCompleted {
+                handle,
+                endpoint_service_id,
+                endpoint_name,
+            } => Self::new(EVENT_TYPE_ENDPOINT_SERVER_PUBLISH_COMPLETED)
+                .service_id(endpoint_service_id)
+                .string(&endpoint_name)
+                .integer(handle),
+            ContextEvent::EndpointServerPublishTimedOut {
+                handle,
+                endpoint_service_id,
+                endpoint_name,
+                timeout,
+            } => Self::new(EVENT_TYPE_ENDPOINT_SERVER_PUBLISH_TIMED_OUT)
+                .service_id(endpoint_service_id)
+                .string(&endpoint_name)
+                .integer(handle)
+                .integer(usize::try_from(timeout.as_millis()).unwrap_or(usize::MAX)),
+            ContextEvent::EndpointServerDescriptorRefreshed {
+                endpoint_service_id,
+                endpoint_name,
+            } => Self::new(EVENT_TYPE_ENDPOINT_SERVER_DESCRIPTOR_REFRESHED)
+                .service_id(endpoint_service_id)
+                .string(&endpoint_name),
+            ContextEvent::EndpointServerConnectionShed {
+                endpoint_service_id,
+            } => Self::new(EVENT_TYPE_ENDPOINT_SERVER_CONNECTION_SHED)
+                .service_id(endpoint_service_id),
+            ContextEvent::EndpointServerHandshakeStarted { handle } => {
+                Self::new(EVENT_TYPE_ENDPOINT_SERVER_HANDSHAKE_STARTED).handle(handle)
+            }
+            ContextEvent::EndpointServerHandshakeCompleted {
+                handle,
+                endpoint_service_id,
+                client_service_id,
+                channel_name,
+                stream,
+                capabilities,
+                stats,
+            } => {
+                let mut event = Self::new(EVENT_TYPE_ENDPOINT_SERVER_HANDSHAKE_COMPLETED)
+                    .handle(handle)
+                    .service_id(endpoint_service_id)
+                    .service_id(client_service_id)
+                    .string(&channel_name)
+                    .stats(stats)
+                    .integer(capabilities.bits() as usize);
+                event.tcp_stream = Some(stream);
+                event
+            }
+            ContextEvent::EndpointServerRpcChannelOpened {
+                handle,
+                endpoint_service_id,
+                client_service_id,
+                channel_name,
+                channel,
+                capabilities,
+                stats,
+            } => {
+                let mut event = Self::new(EVENT_TYPE_ENDPOINT_SERVER_RPC_CHANNEL_OPENED)
+                    .handle(handle)
+                    .service_id(endpoint_service_id)
+                    .service_id(client_service_id)
+                    .string(&channel_name)
+                    .stats(stats)
+                    .integer(capabilities.bits() as usize);
+                event.rpc_channel = Some(channel);
+                event
+            }
+            ContextEvent::EndpointServerDatagramChannelOpened {
+                handle,
+                endpoint_service_id,
+                client_service_id,
+                channel_name,
+                channel,
+                capabilities,
+                stats,
+            } => {
+                let mut event = Self::new(EVENT_TYPE_ENDPOINT_SERVER_DATAGRAM_CHANNEL_OPENED)
+                    .handle(handle)
+                    .service_id(endpoint_service_id)
+                    .service_id(client_service_id)
+                    .string(&channel_name)
+                    .stats(stats)
+                    .integer(capabilities.bits() as usize);
+                event.datagram_channel = Some(channel);
+                event
+            }
+            ContextEvent::EndpointServerHandshakeRejected {
+                handle,
+                client_allowed,
+                client_requested_channel_valid,
+                client_proof_signature_valid,
+            } => Self::new(EVENT_TYPE_ENDPOINT_SERVER_HANDSHAKE_REJECTED)
+                .handle(handle)
+                .boolean(client_allowed)
+                .boolean(client_requested_channel_valid)
+                .boolean(client_proof_signature_valid),
+            ContextEvent::EndpointServerHandshakeBusy {
+                handle,
+                client_service_id,
+                requested_channel,
+                retry_after,
+            } => Self::new(EVENT_TYPE_ENDPOINT_SERVER_HANDSHAKE_BUSY)
+                .handle(handle)
+                .service_id(client_service_id)
+                .string(&requested_channel)
+                .integer(retry_after.as_secs() as usize),
+            ContextEvent::EndpointServerChannelQuotaExceeded {
+                handle,
+                client_service_id,
+                requested_channel,
+                exceeded,
+            } => {
+                let event = Self::new(EVENT_TYPE_ENDPOINT_SERVER_CHANNEL_QUOTA_EXCEEDED)
+                    .handle(handle)
+                    .service_id(client_service_id)
+                    .string(&requested_channel);
+                match exceeded {
+                    ChannelQuotaExceeded::OpenChannels { limit } => event
+                        .integer(CHANNEL_QUOTA_OPEN_CHANNELS as usize)
+                        .integer(limit)
+                        .integer(0)
+                        .integer(0),
+                    ChannelQuotaExceeded::ChannelRequests {
+                        limit,
+                        window,
+                        retry_after,
+                    } => event
+                        .integer(CHANNEL_QUOTA_CHANNEL_REQUESTS as usize)
+                        .integer(limit as usize)
+                        .integer(window.as_secs() as usize)
+                        .integer(retry_after.as_secs_f64().ceil() as usize),
+                }
+            }
+            ContextEvent::IdentityServerBannedClientRejected {
+                handle,
+                client_service_id,
+                requested_endpoint,
+                ban,
+            } => Self::new(EVENT_TYPE_IDENTITY_SERVER_BANNED_CLIENT_REJECTED)
+                .handle(handle)
+                .service_id(client_service_id)
+                .string(&requested_endpoint)
+                .ban(ban),
+            ContextEvent::EndpointServerBannedClientRejected {
+                handle,
+                client_service_id,
+                requested_channel,
+                ban,
+            } => Self::new(EVENT_TYPE_ENDPOINT_SERVER_BANNED_CLIENT_REJECTED)
+                .handle(handle)
+                .service_id(client_service_id)
+                .string(&requested_channel)
+                .ban(ban),
+            ContextEvent::EndpointServerHandshakeFailed {
+                handle,
+                reason,
+                stats,
+            } => Self::failure(
+                EVENT_TYPE_ENDPOINT_SERVER_HANDSHAKE_FAILED,
+                handle,
+                reason,
+                stats,
+            ),
+            // challenge construction, verification and request filtering
+            // are answered synchronously by the application's callbacks
+            event @ (ContextEvent::IdentityClientChallengeReceived { .. }
+            | ContextEvent::IdentityServerEndpointRequestReceived { .. }
+            | ContextEvent::IdentityServerChallengeResponseReceived { .. }
+            | ContextEvent::EndpointServerChannelRequestReceived { .. }) => {
+                return Err(Box::new(event))
+            }
+        };
+        Ok(event)
+    }
+}
+
+/// Update the internal gosling context state and return its next event. This is an alternative
+/// to gosling_context_poll_events() for bindings which prefer pulling events to receiving them
+/// through callbacks. Events which require a response from the application (identity
+/// challenges, challenge responses, endpoint requests and channel requests) are still
+/// dispatched to their required callbacks before this function returns. As with
+/// gosling_context_poll_events(), this function may be called from any thread but not from
+/// within one of the context's own callbacks.
+///
+/// @param context: the context object we are updating
+/// @param out_event: returned event, or null if there are no pending events; must be freed with
+///  gosling_event_free()
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_context_next_event(
+    context: *mut GoslingContext,
+    out_event: *mut *mut GoslingEvent,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(context);
+        ensure_not_null!(out_event);
+
+        *out_event = std::ptr::null_mut();
+        let _dispatching = DispatchGuard::begin(context)?;
+        loop {
+            // fail before taking an event from the context if its handle
+            // could not be returned
+            get_event_registry()?.check_limit()?;
+
+            // the context registry must be released before any callbacks
+            // are called to avoid deadlock
+            let (event, callbacks) = match get_context_tuple_registry()?.get_mut(context as usize) {
+                Some(context) => {
+                    // only update once previously returned events are consumed
+                    match &context.2 {
+                        Some(pending_events) if !pending_events.is_empty() => (),
+                        _ => context.2 = Some(context.0.update()?),
+                    }
+                    match context.2.as_mut().and_then(|events| events.pop_front()) {
+                        Some(event) => (event, context.1.clone()),
+                        None => return Ok(()),
+                    }
+                }
+                None => bail_invalid_handle!(context),
+            };
+
+            match Event::try_from_context_event(event) {
+                Ok(event) => {
+                    let handle = get_event_registry()?.try_insert(event)?;
+                    *out_event = handle as *mut GoslingEvent;
+                    return Ok(());
+                }
+                Err(event) => handle_context_event(*event, context, &callbacks)?,
+            }
+        }
+    })
+}
+
+/// Frees a gosling_event object and any unclaimed resources it owns
+///
+/// @param in_event: the event to free
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_event_free(in_event: *mut GoslingEvent) {
+    impl_registry_free!(in_event, Event);
+}
+
+/// Get the type of an event
+///
+/// @param event: the event to query
+/// @param error: filled on error
+/// @return one of the EVENT_TYPE_* constants
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_event_get_type(
+    event: *const GoslingEvent,
+    error: *mut *mut GoslingError,
+) -> u32 {
+    translate_failures(0, error, || -> anyhow::Result<u32> {
+        ensure_not_null!(event);
+
+        match get_event_registry()?.get(event as usize) {
+            Some(event) => Ok(event.event_type),
+            None => bail_invalid_handle!(event),
+        }
+    })
+}
+
+/// Get the handshake handle associated with an event
+///
+/// @param event: the event to query
+/// @param error: filled on error, or if the event has no associated handshake
+/// @return the event's handshake handle
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_event_get_handshake_handle(
+    event: *const GoslingEvent,
+    error: *mut *mut GoslingError,
+) -> GoslingHandshakeHandle {
+    translate_failures(
+        !0usize,
+        error,
+        || -> anyhow::Result<GoslingHandshakeHandle> {
+            ensure_not_null!(event);
+
+            match get_event_registry()?.get(event as usize) {
+                Some(event) => match event.handshake_handle {
+                    Some(handshake_handle) => Ok(handshake_handle),
+                    None => bail!("event has no handshake handle"),
+                },
+                None => bail_invalid_handle!(event),
+            }
+        },
+    )
+}
+
+/// Get an unsigned integer field of an event
+///
+/// @param event: the event to query
+/// @param index: the index of the integer field
+/// @param error: filled on error
+/// @return the integer field's value
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_event_get_integer(
+    event: *const GoslingEvent,
+    index: usize,
+    error: *mut *mut GoslingError,
+) -> usize {
+    translate_failures(0, error, || -> anyhow::Result<usize> {
+        ensure_not_null!(event);
+
+        match get_event_registry()?.get(event as usize) {
+            Some(event) => match event.integers.get(index) {
+                Some(integer) => Ok(*integer),
+                None => bail!("event has no integer field at index {}", index),
+            },
+            None => bail_invalid_handle!(event),
+        }
+    })
+}
+
+/// Get a boolean field of an event
+///
+/// @param event: the event to query
+/// @param index: the index of the boolean field
+/// @param error: filled on error
+/// @return the boolean field's value
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_event_get_bool(
+    event: *const GoslingEvent,
+    index: usize,
+    error: *mut *mut GoslingError,
+) -> bool {
+    translate_failures(false, error, || -> anyhow::Result<bool> {
+        ensure_not_null!(event);
+
+        match get_event_registry()?.get(event as usize) {
+            Some(event) => match event.bools.get(index) {
+                Some(value) => Ok(*value),
+                None => bail!("event has no bool field at index {}", index),
+            },
+            None => bail_invalid_handle!(event),
+        }
+    })
+}
+
+/// Get a string field of an event
+///
+/// @param event: the event to query
+/// @param index: the index of the string field
+/// @param error: filled on error
+/// @return null-terminated string whose lifetime is tied to the event
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_event_get_string(
+    event: *const GoslingEvent,
+    index: usize,
+    error: *mut *mut GoslingError,
+) -> *const c_char {
+    translate_failures(
+        std::ptr::null(),
+        error,
+        || -> anyhow::Result<*const c_char> {
+            ensure_not_null!(event);
+
+            match get_event_registry()?.get(event as usize) {
+                Some(event) => match event.strings.get(index) {
+                    Some(string) => Ok(string.as_ptr()),
+                    None => bail!("event has no string field at index {}", index),
+                },
+                None => bail_invalid_handle!(event),
+            }
+        },
+    )
+}
+
+/// Get a v3 onion service id field of an event
+///
+/// @param event: the event to query
+/// @param index: the index of the service id field
+/// @param out_service_id: returned service id object
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_event_get_v3_onion_service_id(
+    event: *const GoslingEvent,
+    index: usize,
+    out_service_id: *mut *mut GoslingV3OnionServiceId,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(event);
+        ensure_not_null!(out_service_id);
+
+        let service_id = match get_event_registry()?.get(event as usize) {
+            Some(event) => match event.service_ids.get(index) {
+                Some(service_id) => service_id.clone(),
+                None => bail!("event has no service id field at index {}", index),
+            },
+            None => bail_invalid_handle!(event),
+        };
+        let handle = get_v3_onion_service_id_registry()?.try_insert(service_id)?;
+        *out_service_id = handle as *mut GoslingV3OnionServiceId;
+
+        Ok(())
+    })
+}
+
+/// Get the ed25519 private key field of an event
+///
+/// @param event: the event to query
+/// @param out_private_key: returned ed25519 private key object
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_event_get_ed25519_private_key(
+    event: *const GoslingEvent,
+    out_private_key: *mut *mut GoslingEd25519PrivateKey,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(event);
+        ensure_not_null!(out_private_key);
+
+        let private_key = match get_event_registry()?.get(event as usize) {
+            Some(event) => match &event.ed25519_private_key {
+                Some(private_key) => private_key.clone(),
+                None => bail!("event has no ed25519 private key"),
+            },
+            None => bail_invalid_handle!(event),
+        };
+        let handle = get_ed25519_private_key_registry()?.try_insert(private_key)?;
+        *out_private_key = handle as *mut GoslingEd25519PrivateKey;
+
+        Ok(())
+    })
+}
+
+/// Get the x25519 private key field of an event
+///
+/// @param event: the event to query
+/// @param out_private_key: returned x25519 private key object
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_event_get_x25519_private_key(
+    event: *const GoslingEvent,
+    out_private_key: *mut *mut GoslingX25519PrivateKey,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(event);
+        ensure_not_null!(out_private_key);
+
+        let private_key = match get_event_registry()?.get(event as usize) {
+            Some(event) => match &event.x25519_private_key {
+                Some(private_key) => private_key.clone(),
+                None => bail!("event has no x25519 private key"),
+            },
+            None => bail_invalid_handle!(event),
+        };
+        let handle = get_x25519_private_key_registry()?.try_insert(private_key)?;
+        *out_private_key = handle as *mut GoslingX25519PrivateKey;
+
+        Ok(())
+    })
+}
+
+/// Get the x25519 public key field of an event
+///
+/// @param event: the event to query
+/// @param out_public_key: returned x25519 public key object
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_event_get_x25519_public_key(
+    event: *const GoslingEvent,
+    out_public_key: *mut *mut GoslingX25519PublicKey,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(event);
+        ensure_not_null!(out_public_key);
+
+        let public_key = match get_event_registry()?.get(event as usize) {
+            Some(event) => match &event.x25519_public_key {
+                Some(public_key) => public_key.clone(),
+                None => bail!("event has no x25519 public key"),
+            },
+            None => bail_invalid_handle!(event),
+        };
+        let handle = get_x25519_public_key_registry()?.try_insert(public_key)?;
+        *out_public_key = handle as *mut GoslingX25519PublicKey;
+
+        Ok(())
+    })
+}
+
+/// Take ownership of the tcp socket field of an event. The socket may only be taken once; the
+/// caller is responsible for closing it. A socket which is never taken is closed when the event
+/// is freed.
+///
+/// @param event: the event to query
+/// @param out_tcp_socket: returned tcp socket
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_event_get_tcp_socket(
+    event: *mut GoslingEvent,
+    out_tcp_socket: *mut GoslingTcpSocket,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(event);
+        ensure_not_null!(out_tcp_socket);
+
+        let tcp_stream = match get_event_registry()?.get_mut(event as usize) {
+            Some(event) => match event.tcp_stream.take() {
+                Some(tcp_stream) => tcp_stream,
+                None => bail!("event has no tcp socket or it has already been taken"),
+            },
+            None => bail_invalid_handle!(event),
+        };
+
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        let tcp_socket = tcp_stream.into_raw_fd();
+        #[cfg(target_os = "windows")]
+        let tcp_socket = tcp_stream.into_raw_socket();
+
+        *out_tcp_socket = tcp_socket;
+
+        Ok(())
+    })
+}
+
+/// Take ownership of the rpc channel field of an event. The rpc channel may only be taken once;
+/// the caller is responsible for freeing it with gosling_rpc_channel_free(). An rpc channel which
+/// is never taken is closed when the event is freed.
+///
+/// @param event: the event to query
+/// @param out_channel: returned rpc channel
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_event_get_rpc_channel(
+    event: *mut GoslingEvent,
+    out_channel: *mut *mut GoslingRpcChannel,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(event);
+        ensure_not_null!(out_channel);
+
+        // check the limit first so a failure leaves the rpc channel in the event
+        let mut rpc_channel_tuple_registry = get_rpc_channel_tuple_registry()?;
+        rpc_channel_tuple_registry.check_limit()?;
+
+        let rpc_channel = match get_event_registry()?.get_mut(event as usize) {
+            Some(event) => match event.rpc_channel.take() {
+                Some(rpc_channel) => rpc_channel,
+                None => bail!("event has no rpc channel or it has already been taken"),
+            },
+            None => bail_invalid_handle!(event),
+        };
+        let handle = rpc_channel_tuple_registry.insert((rpc_channel, None, None));
+        *out_channel = handle as *mut GoslingRpcChannel;
+
+        Ok(())
+    })
+}
+
+/// Take ownership of the datagram channel field of an event. The datagram channel may only be
+/// taken once; the caller is responsible for freeing it with gosling_datagram_channel_free(). A
+/// datagram channel which is never taken is closed when the event is freed.
+///
+/// @param event: the event to query
+/// @param out_channel: returned datagram channel
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_event_get_datagram_channel(
+    event: *mut GoslingEvent,
+    out_channel: *mut *mut GoslingDatagramChannel,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(event);
+        ensure_not_null!(out_channel);
+
+        // check the limit first so a failure leaves the datagram channel in the event
+        let mut datagram_channel_registry = get_datagram_channel_registry()?;
+        datagram_channel_registry.check_limit()?;
+
+        let datagram_channel = match get_event_registry()?.get_mut(event as usize) {
+            Some(event) => match event.datagram_channel.take() {
+                Some(datagram_channel) => datagram_channel,
+                None => bail!("event has no datagram channel or it has already been taken"),
+            },
+            None => bail_invalid_handle!(event),
+        };
+        let handle = datagram_channel_registry.insert(datagram_channel);
+        *out_channel = handle as *mut GoslingDatagramChannel;
+
+        Ok(())
+    })
+}
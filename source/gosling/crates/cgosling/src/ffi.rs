@@ -1,5 +1,8 @@
 // standard
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::ffi::CStr;
+use std::os::raw::c_char;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 // extern crates
 use anyhow::bail;
@@ -9,11 +12,22 @@ use cgosling_proc_macros::*;
 // internal crates
 use crate::context::*;
 use crate::crypto::*;
+use crate::datagram_channel::*;
+use crate::endpoint_grant::*;
 use crate::error::*;
+use crate::event::*;
+use crate::identity_uri::*;
+use crate::logging::*;
 use crate::macros::*;
+use crate::object_registry::ReentrantCall;
+use crate::rpc_channel::*;
+use crate::stream::*;
 use crate::tor_provider::*;
 use crate::utils::*;
 
+// number of low bits of each ObjectRegistry key used for its registry's tag
+pub(crate) const REGISTRY_TAG_BITS: u32 = 5;
+
 // tags used for types we put in ObjectRegistrys
 pub(crate) const ERROR_TAG: usize = 0x1;
 pub(crate) const ED25519_PRIVATE_KEY_TAG: usize = 0x2;
@@ -31,6 +45,14 @@ pub(crate) const BRIDGE_LINE_TAG: usize = 0xA;
 pub(crate) const TOR_PROVIDER_CONFIG_TAG: usize = 0xB;
 pub(crate) const TOR_PROVIDER_TAG: usize = 0xC;
 pub(crate) const CONTEXT_TUPLE_TAG: usize = 0xD;
+pub(crate) const ENDPOINT_GRANT_TAG: usize = 0xE;
+pub(crate) const EVENT_TAG: usize = 0xF;
+pub(crate) const TCP_STREAM_TAG: usize = 0x10;
+pub(crate) const IDENTITY_URI_TAG: usize = 0x11;
+pub(crate) const ED25519_PUBLIC_KEY_TAG: usize = 0x12;
+pub(crate) const RPC_CHANNEL_TUPLE_TAG: usize = 0x13;
+pub(crate) const LIBRARY_OPTIONS_TAG: usize = 0x14;
+pub(crate) const DATAGRAM_CHANNEL_TAG: usize = 0x15;
 
 /// A handle for the gosling library
 pub struct GoslingLibrary;
@@ -41,8 +63,32 @@ const GOSLING_LIBRARY_HANDLE: usize = {
     (0x60 << ((std::mem::size_of::<usize>() - 1) * 8)) + 0x5E
 };
 
-/// Initializes the Gosling library. This function must be called before using any of the
-/// other Gosling functions.
+/// Panic behavior which fails the function in which a panic occurs with an error; see
+/// gosling_library_options_set_panic_behavior()
+pub const PANIC_BEHAVIOR_RETURN_ERROR: u32 = 0;
+/// Panic behavior which aborts the process when a panic occurs; see
+/// gosling_library_options_set_panic_behavior()
+pub const PANIC_BEHAVIOR_ABORT: u32 = 1;
+
+static PANIC_BEHAVIOR: AtomicU32 = AtomicU32::new(PANIC_BEHAVIOR_RETURN_ERROR);
+
+// whether a panic should abort the process rather than be returned as an error
+pub(crate) fn abort_on_panic() -> bool {
+    PANIC_BEHAVIOR.load(Ordering::Relaxed) == PANIC_BEHAVIOR_ABORT
+}
+
+/// The global options of the Gosling library; see gosling_library_init_with_options()
+pub struct GoslingLibraryOptions;
+#[derive(Clone, Default)]
+pub(crate) struct LibraryOptions {
+    panic_behavior: u32,
+    log_level: u32,
+    log_callback: GoslingLogCallback,
+}
+define_registry! {LibraryOptions}
+
+/// Initializes the Gosling library with the default options. This function must be called
+/// before using any of the other Gosling functions.
 ///
 /// @param out_library: returned gosling library handle
 /// @param error: filled on error
@@ -55,19 +101,175 @@ pub unsafe extern "C" fn gosling_library_init(
     translate_failures((), error, || -> anyhow::Result<()> {
         ensure_not_null!(out_library);
 
-        if GOSLING_LIBRARY_INITED.load(Ordering::Relaxed) {
-            // error handling
-            bail!("gosling is already initialized");
-        } else {
-            GOSLING_LIBRARY_INITED.store(true, Ordering::Relaxed);
-            *out_library = GOSLING_LIBRARY_HANDLE as *mut GoslingLibrary;
+        library_init(&Default::default())?;
+        *out_library = GOSLING_LIBRARY_HANDLE as *mut GoslingLibrary;
+        Ok(())
+    })
+}
+
+/// Initializes the Gosling library with the given options, as with gosling_library_init().
+/// The options apply until the library is freed with gosling_library_free().
+///
+/// @param options: the options to initialize the library with
+/// @param out_library: returned gosling library handle
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_library_init_with_options(
+    options: *const GoslingLibraryOptions,
+    out_library: *mut *mut GoslingLibrary,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(options);
+        ensure_not_null!(out_library);
+
+        let options = match get_library_options_registry()?.get(options as usize) {
+            Some(options) => options.clone(),
+            None => bail_invalid_handle!(options),
+        };
+        library_init(&options)?;
+        *out_library = GOSLING_LIBRARY_HANDLE as *mut GoslingLibrary;
+        Ok(())
+    })
+}
+
+fn library_init(options: &LibraryOptions) -> anyhow::Result<()> {
+    if GOSLING_LIBRARY_INITED.load(Ordering::Relaxed) {
+        // error handling
+        bail!("gosling is already initialized");
+    }
+    PANIC_BEHAVIOR.store(options.panic_behavior, Ordering::Relaxed);
+    set_log_callback(options.log_level, options.log_callback);
+    GOSLING_LIBRARY_INITED.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Create a new gosling_library_options object with the default options: panics are
+/// returned as errors and nothing is logged. Options may be created before the library is
+/// initialized.
+///
+/// @param out_options: returned library options
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_library_options_new(
+    out_options: *mut *mut GoslingLibraryOptions,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(out_options);
+
+        let handle = get_library_options_registry()?.try_insert(Default::default())?;
+        *out_options = handle as *mut GoslingLibraryOptions;
+        Ok(())
+    })
+}
+
+/// Frees a gosling_library_options object
+///
+/// @param in_options: the library options to free
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_library_options_free(in_options: *mut GoslingLibraryOptions) {
+    impl_registry_free!(in_options, LibraryOptions);
+}
+
+/// Set what happens when a panic occurs within a gosling function. By default, the function
+/// fails with an error describing the panic, which lets embedders recover but may leave the
+/// objects the function was using in an inconsistent state. Embedders which prefer to fail
+/// fast may instead abort the process.
+///
+/// @param options: the library options to configure
+/// @param panic_behavior: one of the PANIC_BEHAVIOR_* constants (the default is
+///  PANIC_BEHAVIOR_RETURN_ERROR)
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_library_options_set_panic_behavior(
+    options: *mut GoslingLibraryOptions,
+    panic_behavior: u32,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(options);
+
+        match panic_behavior {
+            PANIC_BEHAVIOR_RETURN_ERROR | PANIC_BEHAVIOR_ABORT => (),
+            panic_behavior => bail!("invalid panic behavior: {}", panic_behavior),
+        }
+        match get_library_options_registry()?.get_mut(options as usize) {
+            Some(options) => options.panic_behavior = panic_behavior,
+            None => bail_invalid_handle!(options),
         }
         Ok(())
     })
 }
 
-/// Frees all resources associated with the Gosling library. No-op if the library
-/// is not initialized or if it has already been freed
+/// Set the callback messages logged by the library and its dependencies are passed to, and
+/// the most verbose level of messages to pass. Nothing is logged by default. Messages are
+/// only passed to the callback if the process has no other global logger for Rust's tracing
+/// crate.
+///
+/// @param options: the library options to configure
+/// @param log_level: one of the LOG_LEVEL_* constants; messages more verbose than this level
+///  are discarded
+/// @param callback: the callback to pass messages to, or null to disable logging
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_library_options_set_log_callback(
+    options: *mut GoslingLibraryOptions,
+    log_level: u32,
+    callback: GoslingLogCallback,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(options);
+
+        if log_level > LOG_LEVEL_TRACE {
+            bail!("invalid log level: {}", log_level);
+        }
+        match get_library_options_registry()?.get_mut(options as usize) {
+            Some(options) => {
+                options.log_level = log_level;
+                options.log_callback = callback;
+            }
+            None => bail_invalid_handle!(options),
+        }
+        Ok(())
+    })
+}
+
+/// Get the version of this build of cgosling, which may be called before the library is
+/// initialized, e.g. to verify a dynamically loaded library is compatible
+///
+/// @param out_major: returned major version
+/// @param out_minor: returned minor version
+/// @param out_patch: returned patch version
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_library_get_version(
+    out_major: *mut u32,
+    out_minor: *mut u32,
+    out_patch: *mut u32,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(out_major);
+        ensure_not_null!(out_minor);
+        ensure_not_null!(out_patch);
+
+        *out_major = env!("CARGO_PKG_VERSION_MAJOR").parse()?;
+        *out_minor = env!("CARGO_PKG_VERSION_MINOR").parse()?;
+        *out_patch = env!("CARGO_PKG_VERSION_PATCH").parse()?;
+        Ok(())
+    })
+}
+
+/// Frees all resources associated with the Gosling library and resets the options it was
+/// initialized with. No-op if the library is not initialized or if it has already been freed
 /// @param in_library: gosling library handle to free
 #[no_mangle]
 #[allow(unused_variables)]
@@ -77,6 +279,7 @@ pub extern "C" fn gosling_library_free(in_library: *mut GoslingLibrary) {
         clear_error_registry();
 
         clear_ed25519_private_key_registry();
+        clear_ed25519_public_key_registry();
         clear_x25519_private_key_registry();
         clear_x25519_public_key_registry();
         clear_v3_onion_service_id_registry();
@@ -91,7 +294,308 @@ pub extern "C" fn gosling_library_free(in_library: *mut GoslingLibrary) {
         clear_tor_provider_registry();
         clear_tor_provider_config_registry();
         clear_context_tuple_registry();
+        clear_endpoint_grant_registry();
+        clear_event_registry();
+        clear_tcp_stream_registry();
+        clear_identity_uri_registry();
+        clear_rpc_channel_tuple_registry();
+        clear_library_options_registry();
+        clear_datagram_channel_registry();
 
+        PANIC_BEHAVIOR.store(PANIC_BEHAVIOR_RETURN_ERROR, Ordering::Relaxed);
+        set_log_callback(LOG_LEVEL_OFF, None);
         GOSLING_LIBRARY_INITED.store(false, Ordering::Relaxed);
     }
 }
+
+//
+// Handle Limits
+//
+
+/// gosling_error handles; their number cannot be limited so that failures can always be reported
+pub const HANDLE_TYPE_ERROR: u32 = 0;
+/// gosling_ed25519_private_key handles
+pub const HANDLE_TYPE_ED25519_PRIVATE_KEY: u32 = 1;
+/// gosling_x25519_private_key handles
+pub const HANDLE_TYPE_X25519_PRIVATE_KEY: u32 = 2;
+/// gosling_x25519_public_key handles
+pub const HANDLE_TYPE_X25519_PUBLIC_KEY: u32 = 3;
+/// gosling_v3_onion_service_id handles
+pub const HANDLE_TYPE_V3_ONION_SERVICE_ID: u32 = 4;
+/// gosling_ip_address handles
+pub const HANDLE_TYPE_IP_ADDRESS: u32 = 5;
+/// gosling_target_address handles
+pub const HANDLE_TYPE_TARGET_ADDRESS: u32 = 6;
+/// gosling_proxy_config handles; only available with the legacy tor provider
+pub const HANDLE_TYPE_PROXY_CONFIG: u32 = 7;
+/// gosling_pluggable_transport_config handles; only available with the legacy tor provider
+pub const HANDLE_TYPE_PLUGGABLE_TRANSPORT_CONFIG: u32 = 8;
+/// gosling_bridge_line handles; only available with the legacy tor provider
+pub const HANDLE_TYPE_BRIDGE_LINE: u32 = 9;
+/// gosling_tor_provider_config handles
+pub const HANDLE_TYPE_TOR_PROVIDER_CONFIG: u32 = 10;
+/// gosling_tor_provider handles
+pub const HANDLE_TYPE_TOR_PROVIDER: u32 = 11;
+/// gosling_context handles
+pub const HANDLE_TYPE_CONTEXT: u32 = 12;
+/// gosling_endpoint_grant handles
+pub const HANDLE_TYPE_ENDPOINT_GRANT: u32 = 13;
+/// gosling_event handles
+pub const HANDLE_TYPE_EVENT: u32 = 14;
+/// gosling_stream handles
+pub const HANDLE_TYPE_STREAM: u32 = 15;
+/// gosling_identity_uri handles
+pub const HANDLE_TYPE_IDENTITY_URI: u32 = 16;
+/// gosling_rpc_channel handles
+pub const HANDLE_TYPE_RPC_CHANNEL: u32 = 17;
+/// gosling_library_options handles
+pub const HANDLE_TYPE_LIBRARY_OPTIONS: u32 = 18;
+/// gosling_datagram_channel handles
+pub const HANDLE_TYPE_DATAGRAM_CHANNEL: u32 = 19;
+/// The number of HANDLE_TYPE_* constants
+pub const HANDLE_TYPE_COUNT: usize = 20;
+
+// ensure library is the handle returned by gosling_library_init()
+fn ensure_library_inited(library: *const GoslingLibrary) -> anyhow::Result<()> {
+    ensure_not_null!(library);
+    if library as usize != GOSLING_LIBRARY_HANDLE || !GOSLING_LIBRARY_INITED.load(Ordering::Relaxed)
+    {
+        bail_invalid_handle!(library);
+    }
+    Ok(())
+}
+
+// the number of live handles of the given HANDLE_TYPE_* type
+fn handle_count(handle_type: u32) -> Result<usize, ReentrantCall> {
+    Ok(match handle_type {
+        HANDLE_TYPE_ERROR => get_error_registry()?.count(),
+        HANDLE_TYPE_ED25519_PRIVATE_KEY => get_ed25519_private_key_registry()?.count(),
+        HANDLE_TYPE_X25519_PRIVATE_KEY => get_x25519_private_key_registry()?.count(),
+        HANDLE_TYPE_X25519_PUBLIC_KEY => get_x25519_public_key_registry()?.count(),
+        HANDLE_TYPE_V3_ONION_SERVICE_ID => get_v3_onion_service_id_registry()?.count(),
+        HANDLE_TYPE_IP_ADDRESS => get_ip_addr_registry()?.count(),
+        HANDLE_TYPE_TARGET_ADDRESS => get_target_addr_registry()?.count(),
+        #[cfg(feature = "legacy-tor-provider")]
+        HANDLE_TYPE_PROXY_CONFIG => get_proxy_config_registry()?.count(),
+        #[cfg(feature = "legacy-tor-provider")]
+        HANDLE_TYPE_PLUGGABLE_TRANSPORT_CONFIG => {
+            get_pluggable_transport_config_registry()?.count()
+        }
+        #[cfg(feature = "legacy-tor-provider")]
+        HANDLE_TYPE_BRIDGE_LINE => get_bridge_line_registry()?.count(),
+        HANDLE_TYPE_TOR_PROVIDER_CONFIG => get_tor_provider_config_registry()?.count(),
+        HANDLE_TYPE_TOR_PROVIDER => get_tor_provider_registry()?.count(),
+        HANDLE_TYPE_CONTEXT => get_context_tuple_registry()?.count(),
+        HANDLE_TYPE_ENDPOINT_GRANT => get_endpoint_grant_registry()?.count(),
+        HANDLE_TYPE_EVENT => get_event_registry()?.count(),
+        HANDLE_TYPE_STREAM => get_tcp_stream_registry()?.count(),
+        HANDLE_TYPE_IDENTITY_URI => get_identity_uri_registry()?.count(),
+        HANDLE_TYPE_RPC_CHANNEL => get_rpc_channel_tuple_registry()?.count(),
+        HANDLE_TYPE_LIBRARY_OPTIONS => get_library_options_registry()?.count(),
+        HANDLE_TYPE_DATAGRAM_CHANNEL => get_datagram_channel_registry()?.count(),
+        _ => 0,
+    })
+}
+
+/// Limit the number of live handles of a given type. Once the limit is reached,
+/// functions which would return a new handle of that type fail with
+/// ERROR_CODE_HANDLE_LIMIT_EXCEEDED until some are freed. Handles which are only
+/// valid for the duration of a callback do not count towards the limit and are
+/// always created. Limits are reset by gosling_library_free().
+///
+/// @param library: the gosling library handle returned by gosling_library_init()
+/// @param handle_type: one of the HANDLE_TYPE_* constants other than
+///  HANDLE_TYPE_ERROR
+/// @param handle_limit: the maximum number of live handles of the given type, or
+///  0 for no limit; handles already live are unaffected if there are more than
+///  the new limit
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_library_set_handle_limit(
+    library: *mut GoslingLibrary,
+    handle_type: u32,
+    handle_limit: usize,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_library_inited(library)?;
+
+        let handle_limit = match handle_limit {
+            0 => None,
+            handle_limit => Some(handle_limit),
+        };
+        match handle_type {
+            HANDLE_TYPE_ERROR => bail!("the number of error handles may not be limited"),
+            HANDLE_TYPE_ED25519_PRIVATE_KEY => {
+                get_ed25519_private_key_registry()?.set_limit(handle_limit)
+            }
+            HANDLE_TYPE_X25519_PRIVATE_KEY => {
+                get_x25519_private_key_registry()?.set_limit(handle_limit)
+            }
+            HANDLE_TYPE_X25519_PUBLIC_KEY => {
+                get_x25519_public_key_registry()?.set_limit(handle_limit)
+            }
+            HANDLE_TYPE_V3_ONION_SERVICE_ID => {
+                get_v3_onion_service_id_registry()?.set_limit(handle_limit)
+            }
+            HANDLE_TYPE_IP_ADDRESS => get_ip_addr_registry()?.set_limit(handle_limit),
+            HANDLE_TYPE_TARGET_ADDRESS => get_target_addr_registry()?.set_limit(handle_limit),
+            #[cfg(feature = "legacy-tor-provider")]
+            HANDLE_TYPE_PROXY_CONFIG => get_proxy_config_registry()?.set_limit(handle_limit),
+            #[cfg(feature = "legacy-tor-provider")]
+            HANDLE_TYPE_PLUGGABLE_TRANSPORT_CONFIG => {
+                get_pluggable_transport_config_registry()?.set_limit(handle_limit)
+            }
+            #[cfg(feature = "legacy-tor-provider")]
+            HANDLE_TYPE_BRIDGE_LINE => get_bridge_line_registry()?.set_limit(handle_limit),
+            HANDLE_TYPE_TOR_PROVIDER_CONFIG => {
+                get_tor_provider_config_registry()?.set_limit(handle_limit)
+            }
+            HANDLE_TYPE_TOR_PROVIDER => get_tor_provider_registry()?.set_limit(handle_limit),
+            HANDLE_TYPE_CONTEXT => get_context_tuple_registry()?.set_limit(handle_limit),
+            HANDLE_TYPE_ENDPOINT_GRANT => get_endpoint_grant_registry()?.set_limit(handle_limit),
+            HANDLE_TYPE_EVENT => get_event_registry()?.set_limit(handle_limit),
+            HANDLE_TYPE_STREAM => get_tcp_stream_registry()?.set_limit(handle_limit),
+            HANDLE_TYPE_IDENTITY_URI => get_identity_uri_registry()?.set_limit(handle_limit),
+            HANDLE_TYPE_RPC_CHANNEL => get_rpc_channel_tuple_registry()?.set_limit(handle_limit),
+            HANDLE_TYPE_LIBRARY_OPTIONS => get_library_options_registry()?.set_limit(handle_limit),
+            HANDLE_TYPE_DATAGRAM_CHANNEL => {
+                get_datagram_channel_registry()?.set_limit(handle_limit)
+            }
+            handle_type => bail!(
+                "handle_type must be a HANDLE_TYPE_* constant supported by this build; received {}",
+                handle_type
+            ),
+        }
+        Ok(())
+    })
+}
+
+/// Get the number of live handles of each type, e.g. to find handles leaked by
+/// a binding which never frees them
+///
+/// @param library: the gosling library handle returned by gosling_library_init()
+/// @param out_handle_counts: an array whose element at index i is set to the
+///  number of live handles of the type whose HANDLE_TYPE_* constant is i; types
+///  which are unavailable in this build always have 0 live handles
+/// @param handle_counts_count: the number of elements in out_handle_counts; at
+///  most HANDLE_TYPE_COUNT elements are set
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_library_get_stats(
+    library: *const GoslingLibrary,
+    out_handle_counts: *mut usize,
+    handle_counts_count: usize,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_library_inited(library)?;
+        ensure_not_null!(out_handle_counts);
+
+        let handle_counts = std::slice::from_raw_parts_mut(
+            out_handle_counts,
+            std::cmp::min(handle_counts_count, HANDLE_TYPE_COUNT),
+        );
+        for (handle_type, handle_count_out) in handle_counts.iter_mut().enumerate() {
+            *handle_count_out = handle_count(handle_type as u32)?;
+        }
+        Ok(())
+    })
+}
+
+// the json IDL generated from cgosling.h by build.rs
+const API_SPEC: &str = concat!(
+    include_str!(concat!(env!("OUT_DIR"), "/cgosling.json")),
+    "\0"
+);
+
+/// Get a machine-readable JSON description of the functions, callbacks, types and
+/// constants of this build of cgosling, e.g. for generating bindings for other
+/// languages or verifying existing bindings match the library they load.
+///
+/// The description is an object with the fields:
+/// - version: the cgosling version
+/// - config_flags: the optional features and whether they are enabled
+/// - constants, aliases: the constants and typedefs, with their documentation
+/// - callbacks, functions: each function pointer type and function, with its
+///   documentation, return type and parameters; each parameter has a name,
+///   typename and ownership, which is one of "borrowed" (only valid for the
+///   duration of the call), "consumed" (ownership passes to the function or
+///   callee) or "out" (returned by the function; returned handles are owned by
+///   the caller)
+///
+/// @param library: the gosling library handle returned by gosling_library_init()
+/// @param error: filled on error
+/// @return null-terminated JSON string which is valid for the lifetime of the
+///  process
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_library_get_api_spec(
+    library: *const GoslingLibrary,
+    error: *mut *mut GoslingError,
+) -> *const c_char {
+    translate_failures(
+        std::ptr::null(),
+        error,
+        || -> anyhow::Result<*const c_char> {
+            ensure_library_inited(library)?;
+            Ok(API_SPEC.as_ptr() as *const c_char)
+        },
+    )
+}
+
+//
+// String Parameters
+//
+
+// Converts a string parameter and its length into a str. Strings may be passed
+// either with their length in bytes, or null-terminated with a length of 0. In
+// either case the string must be valid utf8 and may not contain a null byte.
+// The caller must ensure string is not null.
+pub(crate) unsafe fn str_from_ffi<'a>(
+    string: *const c_char,
+    string_length: usize,
+    name: &str,
+) -> anyhow::Result<&'a str> {
+    let string = match string_length {
+        0 => CStr::from_ptr(string).to_bytes(),
+        string_length => std::slice::from_raw_parts(string as *const u8, string_length),
+    };
+    if string.contains(&0u8) {
+        bail!("{} must not contain a null byte", name);
+    }
+    match std::str::from_utf8(string) {
+        Ok(string) => Ok(string),
+        Err(_) => bail!("{} must be a valid utf8 string", name),
+    }
+}
+
+// Same as str_from_ffi() but the string must also be ascii
+pub(crate) unsafe fn ascii_str_from_ffi<'a>(
+    string: *const c_char,
+    string_length: usize,
+    name: &str,
+) -> anyhow::Result<&'a str> {
+    let string = str_from_ffi(string, string_length, name)?;
+    if !string.is_ascii() {
+        bail!("{} must be an ascii string", name);
+    }
+    Ok(string)
+}
+
+// Seconds since the unix epoch, or 0 for earlier times
+pub(crate) fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0u64, |elapsed| elapsed.as_secs())
+}
+
+// Copies a string into a null-terminated output buffer. The caller must ensure
+// out_buffer is not null and buffer_size is greater than src.len().
+pub(crate) unsafe fn copy_to_buffer(src: &str, out_buffer: *mut c_char, buffer_size: usize) {
+    let buffer_view = std::slice::from_raw_parts_mut(out_buffer as *mut u8, buffer_size);
+    std::ptr::copy(src.as_ptr(), buffer_view.as_mut_ptr(), src.len());
+    // add final null-terminator
+    buffer_view[src.len()] = 0u8;
+}
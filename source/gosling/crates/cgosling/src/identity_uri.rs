@@ -0,0 +1,442 @@
+// standard
+use std::os::raw::c_char;
+
+// extern crates
+use anyhow::bail;
+#[cfg(feature = "impl-lib")]
+use cgosling_proc_macros::*;
+use gosling::identity_uri::*;
+
+// internal crates
+use crate::crypto::*;
+use crate::error::*;
+use crate::ffi::*;
+use crate::macros::*;
+
+/// A link to a gosling identity server in the form
+/// "gosling://<identity service id>?endpoint=<endpoint name>", which applications may exchange
+/// (e.g. as text or a QR code) to share contact information
+pub struct GoslingIdentityUri;
+define_registry! {IdentityUri}
+
+/// Frees a gosling_identity_uri object
+///
+/// @param in_identity_uri: the identity uri to free
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_identity_uri_free(in_identity_uri: *mut GoslingIdentityUri) {
+    impl_registry_free!(in_identity_uri, IdentityUri);
+}
+
+/// Copy method for gosling_identity_uri
+///
+/// @param out_identity_uri: returned copy
+/// @param identity_uri: original to copy
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_identity_uri_clone(
+    out_identity_uri: *mut *mut GoslingIdentityUri,
+    identity_uri: *const GoslingIdentityUri,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(out_identity_uri);
+        ensure_not_null!(identity_uri);
+
+        let identity_uri = match get_identity_uri_registry()?.get(identity_uri as usize) {
+            Some(identity_uri) => identity_uri.clone(),
+            None => bail_invalid_handle!(identity_uri),
+        };
+        let handle = get_identity_uri_registry()?.try_insert(identity_uri)?;
+        *out_identity_uri = handle as *mut GoslingIdentityUri;
+
+        Ok(())
+    })
+}
+
+/// Create a new gosling_identity_uri
+///
+/// @param out_identity_uri: returned identity uri
+/// @param identity_service_id: the onion service id of the identity server
+/// @param endpoint_name: the ascii-encoded name of the endpoint to request from the identity
+///  server, or null to omit the endpoint
+/// @param endpoint_name_length: the number of chars in endpoint_name not including any
+///  null-terminator, or 0 if endpoint_name is null or null-terminated
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_identity_uri_new(
+    out_identity_uri: *mut *mut GoslingIdentityUri,
+    identity_service_id: *const GoslingV3OnionServiceId,
+    endpoint_name: *const c_char,
+    endpoint_name_length: usize,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(out_identity_uri);
+        ensure_not_null!(identity_service_id);
+        if endpoint_name.is_null() && endpoint_name_length != 0 {
+            bail!("endpoint_name is null so endpoint_name_length must be 0");
+        }
+
+        let identity_service_id =
+            match get_v3_onion_service_id_registry()?.get(identity_service_id as usize) {
+                Some(identity_service_id) => identity_service_id.clone(),
+                None => bail_invalid_handle!(identity_service_id),
+            };
+
+        let endpoint_name = if endpoint_name.is_null() {
+            None
+        } else {
+            let endpoint_name =
+                ascii_str_from_ffi(endpoint_name, endpoint_name_length, "endpoint_name")?;
+            ensure_not_empty!(endpoint_name);
+            Some(endpoint_name.to_string())
+        };
+
+        let identity_uri = IdentityUri::new(identity_service_id, endpoint_name)?;
+
+        let handle = get_identity_uri_registry()?.try_insert(identity_uri)?;
+        *out_identity_uri = handle as *mut GoslingIdentityUri;
+
+        Ok(())
+    })
+}
+
+/// Conversion method for parsing a string created by gosling_identity_uri_get_string() into a
+/// gosling_identity_uri. Unrecognised query parameters are preserved.
+///
+/// @param out_identity_uri: returned identity uri
+/// @param identity_uri_string: a string in the form "gosling://abcd1234...?endpoint=..."
+/// @param identity_uri_string_length: the number of chars in identity_uri_string not including
+///  any null-terminator, or 0 if identity_uri_string is null-terminated
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_identity_uri_from_string(
+    out_identity_uri: *mut *mut GoslingIdentityUri,
+    identity_uri_string: *const c_char,
+    identity_uri_string_length: usize,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(out_identity_uri);
+        ensure_not_null!(identity_uri_string);
+
+        let identity_uri_str = str_from_ffi(
+            identity_uri_string,
+            identity_uri_string_length,
+            "identity_uri_string",
+        )?;
+        let identity_uri = IdentityUri::from_string(identity_uri_str)?;
+
+        let handle = get_identity_uri_registry()?.try_insert(identity_uri)?;
+        *out_identity_uri = handle as *mut GoslingIdentityUri;
+
+        Ok(())
+    })
+}
+
+/// Get the size of the buffer required by gosling_identity_uri_get_string()
+///
+/// @param identity_uri: the identity uri to query
+/// @param error: filled on error
+/// @return the number of bytes required to hold the encoded identity uri including the
+///  null-terminator
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_identity_uri_get_string_size(
+    identity_uri: *const GoslingIdentityUri,
+    error: *mut *mut GoslingError,
+) -> usize {
+    translate_failures(0, error, || -> anyhow::Result<usize> {
+        ensure_not_null!(identity_uri);
+
+        match get_identity_uri_registry()?.get(identity_uri as usize) {
+            Some(identity_uri) => Ok(identity_uri.to_string().len() + 1),
+            None => bail_invalid_handle!(identity_uri),
+        }
+    })
+}
+
+/// Get an identity uri encoded as a null-terminated ascii string
+///
+/// @param identity_uri: the identity uri to encode
+/// @param out_identity_uri_string: buffer to be filled with the encoded identity uri in the
+///  form "gosling://abcd1234...?endpoint=...\0"
+/// @param identity_uri_string_size: size of out_identity_uri_string buffer in bytes, must be at
+///  least the value returned by gosling_identity_uri_get_string_size()
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_identity_uri_get_string(
+    identity_uri: *const GoslingIdentityUri,
+    out_identity_uri_string: *mut c_char,
+    identity_uri_string_size: usize,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(identity_uri);
+        ensure_not_null!(out_identity_uri_string);
+
+        let identity_uri_string = match get_identity_uri_registry()?.get(identity_uri as usize) {
+            Some(identity_uri) => identity_uri.to_string(),
+            None => bail_invalid_handle!(identity_uri),
+        };
+
+        if identity_uri_string_size < identity_uri_string.len() + 1 {
+            bail!(
+                "identity_uri_string_size must be at least '{}', received '{}'",
+                identity_uri_string.len() + 1,
+                identity_uri_string_size
+            );
+        }
+
+        copy_to_buffer(
+            &identity_uri_string,
+            out_identity_uri_string,
+            identity_uri_string_size,
+        );
+
+        Ok(())
+    })
+}
+
+/// Get the onion service id of the identity server an identity uri links to
+///
+/// @param identity_uri: the identity uri to query
+/// @param out_service_id: returned service id object
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_identity_uri_get_identity_service_id(
+    identity_uri: *const GoslingIdentityUri,
+    out_service_id: *mut *mut GoslingV3OnionServiceId,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(identity_uri);
+        ensure_not_null!(out_service_id);
+
+        let service_id = match get_identity_uri_registry()?.get(identity_uri as usize) {
+            Some(identity_uri) => identity_uri.identity_service_id().clone(),
+            None => bail_invalid_handle!(identity_uri),
+        };
+        let handle = get_v3_onion_service_id_registry()?.try_insert(service_id)?;
+        *out_service_id = handle as *mut GoslingV3OnionServiceId;
+
+        Ok(())
+    })
+}
+
+/// Get the size of the buffer required by gosling_identity_uri_get_endpoint_name()
+///
+/// @param identity_uri: the identity uri to query
+/// @param error: filled on error
+/// @return the number of bytes required to hold the endpoint name including the null-terminator
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_identity_uri_get_endpoint_name_size(
+    identity_uri: *const GoslingIdentityUri,
+    error: *mut *mut GoslingError,
+) -> usize {
+    translate_failures(0, error, || -> anyhow::Result<usize> {
+        ensure_not_null!(identity_uri);
+
+        match get_identity_uri_registry()?.get(identity_uri as usize) {
+            Some(identity_uri) => Ok(identity_uri.endpoint_name().unwrap_or_default().len() + 1),
+            None => bail_invalid_handle!(identity_uri),
+        }
+    })
+}
+
+/// Get the name of the endpoint an identity uri asks to request from the identity server as a
+/// null-terminated string. Endpoint names are never empty, so an empty string is returned if
+/// the identity uri has no endpoint.
+///
+/// @param identity_uri: the identity uri to query
+/// @param out_endpoint_name: buffer to be filled with the ascii-encoded endpoint name
+/// @param endpoint_name_size: size of out_endpoint_name buffer in bytes, must be at least the
+///  value returned by gosling_identity_uri_get_endpoint_name_size()
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_identity_uri_get_endpoint_name(
+    identity_uri: *const GoslingIdentityUri,
+    out_endpoint_name: *mut c_char,
+    endpoint_name_size: usize,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(identity_uri);
+        ensure_not_null!(out_endpoint_name);
+
+        let registry = get_identity_uri_registry()?;
+        let endpoint_name = match registry.get(identity_uri as usize) {
+            Some(identity_uri) => identity_uri.endpoint_name().unwrap_or_default(),
+            None => bail_invalid_handle!(identity_uri),
+        };
+
+        if endpoint_name_size < endpoint_name.len() + 1 {
+            bail!(
+                "endpoint_name_size must be at least '{}', received '{}'",
+                endpoint_name.len() + 1,
+                endpoint_name_size
+            );
+        }
+
+        copy_to_buffer(endpoint_name, out_endpoint_name, endpoint_name_size);
+
+        Ok(())
+    })
+}
+
+/// Set an additional query parameter on an identity uri, replacing any existing parameters
+/// with the same name. Applications may use these to attach their own data to an identity uri.
+///
+/// @param identity_uri: the identity uri to update
+/// @param key: the utf8-encoded name of the query parameter; must not be empty or "endpoint"
+/// @param key_length: the number of chars in key not including any null-terminator, or 0 if key
+///  is null-terminated
+/// @param value: the utf8-encoded value of the query parameter
+/// @param value_length: the number of chars in value not including any null-terminator, or 0 if
+///  value is null-terminated
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_identity_uri_set_parameter(
+    identity_uri: *mut GoslingIdentityUri,
+    key: *const c_char,
+    key_length: usize,
+    value: *const c_char,
+    value_length: usize,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(identity_uri);
+        ensure_not_null!(key);
+        ensure_not_null!(value);
+
+        let key = str_from_ffi(key, key_length, "key")?;
+        let value = str_from_ffi(value, value_length, "value")?;
+
+        match get_identity_uri_registry()?.get_mut(identity_uri as usize) {
+            Some(identity_uri) => {
+                Ok(identity_uri.set_parameter(key.to_string(), value.to_string())?)
+            }
+            None => bail_invalid_handle!(identity_uri),
+        }
+    })
+}
+
+/// Remove all query parameters with the given name from an identity uri
+///
+/// @param identity_uri: the identity uri to update
+/// @param key: the utf8-encoded name of the query parameter
+/// @param key_length: the number of chars in key not including any null-terminator, or 0 if key
+///  is null-terminated
+/// @param error: filled on error
+/// @return true if any parameters were removed
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_identity_uri_remove_parameter(
+    identity_uri: *mut GoslingIdentityUri,
+    key: *const c_char,
+    key_length: usize,
+    error: *mut *mut GoslingError,
+) -> bool {
+    translate_failures(false, error, || -> anyhow::Result<bool> {
+        ensure_not_null!(identity_uri);
+        ensure_not_null!(key);
+
+        let key = str_from_ffi(key, key_length, "key")?;
+
+        match get_identity_uri_registry()?.get_mut(identity_uri as usize) {
+            Some(identity_uri) => Ok(identity_uri.remove_parameter(key)),
+            None => bail_invalid_handle!(identity_uri),
+        }
+    })
+}
+
+/// Get the size of the buffer required by gosling_identity_uri_get_parameter()
+///
+/// @param identity_uri: the identity uri to query
+/// @param key: the utf8-encoded name of the query parameter
+/// @param key_length: the number of chars in key not including any null-terminator, or 0 if key
+///  is null-terminated
+/// @param error: filled on error
+/// @return the number of bytes required to hold the value of the first query parameter with the
+///  given name including the null-terminator, or 0 if the identity uri has no such parameter
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_identity_uri_get_parameter_size(
+    identity_uri: *const GoslingIdentityUri,
+    key: *const c_char,
+    key_length: usize,
+    error: *mut *mut GoslingError,
+) -> usize {
+    translate_failures(0, error, || -> anyhow::Result<usize> {
+        ensure_not_null!(identity_uri);
+        ensure_not_null!(key);
+
+        let key = str_from_ffi(key, key_length, "key")?;
+
+        match get_identity_uri_registry()?.get(identity_uri as usize) {
+            Some(identity_uri) => Ok(identity_uri
+                .parameter(key)
+                .map_or(0, |value| value.len() + 1)),
+            None => bail_invalid_handle!(identity_uri),
+        }
+    })
+}
+
+/// Get the value of the first query parameter with the given name as a null-terminated string
+///
+/// @param identity_uri: the identity uri to query
+/// @param key: the utf8-encoded name of the query parameter
+/// @param key_length: the number of chars in key not including any null-terminator, or 0 if key
+///  is null-terminated
+/// @param out_value: buffer to be filled with the utf8-encoded parameter value
+/// @param value_size: size of out_value buffer in bytes, must be at least the value returned by
+///  gosling_identity_uri_get_parameter_size()
+/// @param error: filled on error; the identity uri having no such parameter is an error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_identity_uri_get_parameter(
+    identity_uri: *const GoslingIdentityUri,
+    key: *const c_char,
+    key_length: usize,
+    out_value: *mut c_char,
+    value_size: usize,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(identity_uri);
+        ensure_not_null!(key);
+        ensure_not_null!(out_value);
+
+        let key = str_from_ffi(key, key_length, "key")?;
+
+        let registry = get_identity_uri_registry()?;
+        let value = match registry.get(identity_uri as usize) {
+            Some(identity_uri) => match identity_uri.parameter(key) {
+                Some(value) => value,
+                None => bail!("identity_uri has no parameter '{}'", key),
+            },
+            None => bail_invalid_handle!(identity_uri),
+        };
+
+        if value_size < value.len() + 1 {
+            bail!(
+                "value_size must be at least '{}', received '{}'",
+                value.len() + 1,
+                value_size
+            );
+        }
+
+        copy_to_buffer(value, out_value, value_size);
+
+        Ok(())
+    })
+}
@@ -8,9 +8,16 @@
 pub mod callbacks;
 pub mod context;
 pub mod crypto;
+pub mod datagram_channel;
+pub mod endpoint_grant;
 pub mod error;
+pub mod event;
 pub mod ffi;
+pub mod identity_uri;
+pub mod logging;
 mod macros;
 mod object_registry;
+pub mod rpc_channel;
+pub mod stream;
 pub mod tor_provider;
 pub mod utils;
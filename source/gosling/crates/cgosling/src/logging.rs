@@ -0,0 +1,155 @@
+// standard
+use std::ffi::CString;
+use std::fmt::Write;
+use std::os::raw::c_char;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Mutex, Once};
+
+// extern crates
+use tracing::field::{Field, Visit};
+use tracing::span;
+use tracing::subscriber::Interest;
+use tracing::{Event, Level, Metadata, Subscriber};
+
+/// Log level which disables logging; see gosling_library_options_set_log_callback()
+pub const LOG_LEVEL_OFF: u32 = 0;
+/// Log level for errors which the library recovered from
+pub const LOG_LEVEL_ERROR: u32 = 1;
+/// Log level for unexpected conditions, e.g. failing to save a ban list
+pub const LOG_LEVEL_WARN: u32 = 2;
+/// Log level for informational messages
+pub const LOG_LEVEL_INFO: u32 = 3;
+/// Log level for diagnostic messages, e.g. the progress of handshakes
+pub const LOG_LEVEL_DEBUG: u32 = 4;
+/// Log level for very verbose diagnostic messages
+pub const LOG_LEVEL_TRACE: u32 = 5;
+
+/// The function pointer type for the log callback, which is passed the messages
+/// logged by the library and its dependencies at or below the configured log
+/// level. The callback may be called from any thread on which a gosling function
+/// is running and must not call any gosling function.
+///
+/// @param log_level: the LOG_LEVEL_* constant of the message
+/// @param target: the null-terminated name of the module which logged the message
+/// @param target_length: the number of chars in target not including the
+///  null-terminator
+/// @param message: the null-terminated message
+/// @param message_length: the number of chars in message not including the
+///  null-terminator
+pub type GoslingLogCallback = Option<
+    extern "C" fn(
+        log_level: u32,
+        target: *const c_char,
+        target_length: usize,
+        message: *const c_char,
+        message_length: usize,
+    ) -> (),
+>;
+
+// the configured level, read on every event
+static LOG_LEVEL: AtomicU32 = AtomicU32::new(LOG_LEVEL_OFF);
+static LOG_CALLBACK: Mutex<GoslingLogCallback> = Mutex::new(None);
+static INSTALL_SUBSCRIBER: Once = Once::new();
+
+// install the subscriber which forwards events to the log callback, unless the
+// process already has a global subscriber
+pub(crate) fn set_log_callback(log_level: u32, callback: GoslingLogCallback) {
+    match LOG_CALLBACK.lock() {
+        Ok(mut log_callback) => *log_callback = callback,
+        Err(_) => unreachable!("another thread panicked while holding the log callback's mutex"),
+    }
+    let log_level = match callback {
+        Some(_) => log_level,
+        None => LOG_LEVEL_OFF,
+    };
+    LOG_LEVEL.store(log_level, Ordering::Relaxed);
+
+    if log_level != LOG_LEVEL_OFF {
+        INSTALL_SUBSCRIBER.call_once(|| {
+            let _ = tracing::subscriber::set_global_default(FfiSubscriber);
+        });
+    }
+}
+
+fn log_level(level: &Level) -> u32 {
+    match *level {
+        Level::ERROR => LOG_LEVEL_ERROR,
+        Level::WARN => LOG_LEVEL_WARN,
+        Level::INFO => LOG_LEVEL_INFO,
+        Level::DEBUG => LOG_LEVEL_DEBUG,
+        Level::TRACE => LOG_LEVEL_TRACE,
+    }
+}
+
+// formats an event's message followed by its other fields
+#[derive(Default)]
+struct MessageVisitor {
+    message: String,
+    fields: String,
+}
+
+impl Visit for MessageVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.message, "{:?}", value);
+        } else {
+            let _ = write!(self.fields, " {}={:?}", field.name(), value);
+        }
+    }
+}
+
+// forwards events to the log callback; spans are not recorded
+struct FfiSubscriber;
+
+impl Subscriber for FfiSubscriber {
+    // the level may change, so whether a callsite is enabled is never cached
+    fn register_callsite(&self, _metadata: &'static Metadata<'static>) -> Interest {
+        Interest::sometimes()
+    }
+
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.is_event() && log_level(metadata.level()) <= LOG_LEVEL.load(Ordering::Relaxed)
+    }
+
+    fn new_span(&self, _span: &span::Attributes<'_>) -> span::Id {
+        span::Id::from_u64(1)
+    }
+
+    fn record(&self, _span: &span::Id, _values: &span::Record<'_>) {}
+
+    fn record_follows_from(&self, _span: &span::Id, _follows: &span::Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let callback = match LOG_CALLBACK.lock() {
+            Ok(log_callback) => *log_callback,
+            Err(_) => return,
+        };
+        let callback = match callback {
+            Some(callback) => callback,
+            None => return,
+        };
+
+        let mut visitor: MessageVisitor = Default::default();
+        event.record(&mut visitor);
+        let message = visitor.message + &visitor.fields;
+        let metadata = event.metadata();
+        // messages which cannot be passed as C strings are dropped
+        let (Ok(target0), Ok(message0)) = (
+            CString::new(metadata.target()),
+            CString::new(message.as_str()),
+        ) else {
+            return;
+        };
+        callback(
+            log_level(metadata.level()),
+            target0.as_ptr(),
+            metadata.target().len(),
+            message0.as_ptr(),
+            message.len(),
+        );
+    }
+
+    fn enter(&self, _span: &span::Id) {}
+
+    fn exit(&self, _span: &span::Id) {}
+}
@@ -2,21 +2,29 @@
 macro_rules! define_registry {
     ($type:ty) => {
         paste::paste! {
-            // ensure tag fits in 4 bits
-            static_assertions::const_assert!([<$type:snake:upper _TAG>] <= 0b1111);
+            // ensure tag fits in REGISTRY_TAG_BITS bits
+            static_assertions::const_assert!([<$type:snake:upper _TAG>] < (1 << crate::ffi::REGISTRY_TAG_BITS));
 
-            static [<$type:snake:upper _REGISTRY>]: std::sync::Mutex<crate::object_registry::ObjectRegistry<$type, { [<$type:snake:upper _TAG>] }, 4>> = std::sync::Mutex::new(crate::object_registry::ObjectRegistry::new());
+            static [<$type:snake:upper _REGISTRY>]: std::sync::Mutex<crate::object_registry::ObjectRegistry<$type, { [<$type:snake:upper _TAG>] }, { crate::ffi::REGISTRY_TAG_BITS }>> = std::sync::Mutex::new(crate::object_registry::ObjectRegistry::new(stringify!($type)));
 
-            pub(crate) fn [<get_ $type:snake _registry>]<'a>() -> std::sync::MutexGuard<'a, crate::object_registry::ObjectRegistry<$type, { [<$type:snake:upper _TAG>] }, 4>> {
-                match [<$type:snake:upper _REGISTRY>].lock() {
-                    Ok(registry) => registry,
-                    Err(_) => unreachable!("another thread panicked while holding this registry's mutex"),
-                }
+            std::thread_local! {
+                // whether this thread holds the registry's mutex, and the objects it freed while it did
+                static [<$type:snake:upper _REGISTRY_STATE>]: crate::object_registry::RegistryThreadState = const { crate::object_registry::RegistryThreadState::new() };
+            }
+
+            // fails with a ReentrantCall if this thread already holds the registry
+            pub(crate) fn [<get_ $type:snake _registry>]<'a>() -> Result<crate::object_registry::RegistryGuard<'a, $type, { [<$type:snake:upper _TAG>] }, { crate::ffi::REGISTRY_TAG_BITS }>, crate::object_registry::ReentrantCall> {
+                crate::object_registry::RegistryGuard::lock(&[<$type:snake:upper _REGISTRY>], &[<$type:snake:upper _REGISTRY_STATE>], stringify!($type))
+            }
+
+            // removes the object immediately, or once the registry is released if this thread holds it
+            pub(crate) fn [<remove_ $type:snake _from_registry>](key: usize) {
+                crate::object_registry::RegistryGuard::remove_or_defer(&[<$type:snake:upper _REGISTRY>], &[<$type:snake:upper _REGISTRY_STATE>], stringify!($type), key)
             }
 
             pub(crate) fn [<clear_ $type:snake _registry>]() {
                 match [<$type:snake:upper _REGISTRY>].lock() {
-                    Ok(mut registry) => *registry = crate::object_registry::ObjectRegistry::new(),
+                    Ok(mut registry) => *registry = crate::object_registry::ObjectRegistry::new(stringify!($type)),
                     Err(_) => unreachable!("another thread panicked while holding this registry's mutex"),
                 }
             }
@@ -35,7 +43,9 @@ macro_rules! impl_registry_free {
 
         let key = $obj as usize;
         paste::paste! {
-            [<get_ $type:snake _registry>]().remove(key);
+            // objects freed from within a callback which holds their registry are
+            // removed once the function which called the callback returns
+            [<remove_ $type:snake _from_registry>](key);
         }
     };
 }
@@ -57,6 +67,18 @@ macro_rules! ensure_not_null {
 }
 pub(crate) use ensure_not_null;
 
+// ensure string is not empty
+macro_rules! ensure_not_empty {
+    ($string:ident) => {
+        paste::paste! {
+            if $string.is_empty() {
+                bail!(stringify!([<$string>] must not be empty));
+            }
+        }
+    };
+}
+pub(crate) use ensure_not_empty;
+
 macro_rules! ensure_not_equal {
     ($value:ident, $constant:literal) => {
         if $value == $constant {
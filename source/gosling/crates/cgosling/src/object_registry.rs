@@ -1,6 +1,43 @@
 // standard
+use std::cell::{Cell, RefCell};
 use std::collections::BTreeMap;
 use std::option::Option;
+use std::sync::{Mutex, MutexGuard};
+use std::thread::LocalKey;
+
+// Returned by ObjectRegistry::try_insert() when the registry already holds
+// as many objects as its limit allows
+#[derive(Debug)]
+pub struct HandleLimitExceeded {
+    name: &'static str,
+    limit: usize,
+}
+
+impl std::fmt::Display for HandleLimitExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "the limit of {} live {} handles has been reached; free unused handles or raise the limit",
+            self.limit, self.name
+        )
+    }
+}
+
+impl std::error::Error for HandleLimitExceeded {}
+
+// Returned when a thread calls a function from within a callback which must not
+// call it, e.g. because the callback is called while a registry it needs is
+// locked further up the thread's stack; the call fails instead of deadlocking
+#[derive(Debug)]
+pub struct ReentrantCall(pub(crate) String);
+
+impl std::fmt::Display for ReentrantCall {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for ReentrantCall {}
 
 // An ObjectRegistry<T> maintains ownership of objects and maps them to usize keys
 // which can be safely handed out to external consumers as opaque pointer.
@@ -13,11 +50,19 @@ use std::option::Option;
 // TAG: a usize constant which occupy the low bits of returned keys
 // TAG_BITS: the number of bits needed to store the tag (the remainder of the usize bits are used
 //   for the unique id portion of the returne dkeys)
+//
+// A registry may optionally be limited to a maximum number of live objects; the
+// limit is only enforced by try_insert() so that short-lived internal handles
+// (e.g. those passed to callbacks) can always be created.
 pub struct ObjectRegistry<T, const TAG: usize, const TAG_BITS: u32> {
     // our internal mapping from handles to Ts
     map: Option<BTreeMap<usize, T>>,
     // number of Ts registered to this registry over its lifetime
     counter: usize,
+    // human-readable name of T used in error messages
+    name: &'static str,
+    // maximum number of live Ts try_insert() allows
+    limit: Option<usize>,
 }
 
 // Rust only supports 8-bit bytes
@@ -36,16 +81,32 @@ impl<T, const TAG: usize, const TAG_BITS: u32> ObjectRegistry<T, TAG, TAG_BITS>
         (self.counter << TAG_BITS) | TAG
     }
 
-    // returns a new empty ObjectRegisry
-    pub const fn new() -> ObjectRegistry<T, TAG, TAG_BITS> {
+    // returns a new empty ObjectRegisry without a limit
+    pub const fn new(name: &'static str) -> ObjectRegistry<T, TAG, TAG_BITS> {
         assert!(TAG_BITS == 0 || (TAG << Self::COUNTER_BITS) >> Self::COUNTER_BITS == TAG);
 
         ObjectRegistry {
             map: None,
             counter: 0,
+            name,
+            limit: None,
         }
     }
 
+    // the number of objects currently in the registry
+    pub fn count(&self) -> usize {
+        match &self.map {
+            Some(map) => map.len(),
+            None => 0,
+        }
+    }
+
+    // set the maximum number of objects try_insert() allows; objects already in
+    // the registry are unaffected if there are more than the new limit
+    pub fn set_limit(&mut self, limit: Option<usize>) {
+        self.limit = limit;
+    }
+
     // determine if the registry has an object with the specified key
     pub fn contains_key(&self, key: usize) -> bool {
         match &self.map {
@@ -80,6 +141,25 @@ impl<T, const TAG: usize, const TAG_BITS: u32> ObjectRegistry<T, TAG, TAG_BITS>
         key
     }
 
+    // returns an error if try_insert() would fail because the registry has
+    // reached its limit
+    pub fn check_limit(&self) -> Result<(), HandleLimitExceeded> {
+        match self.limit {
+            Some(limit) if self.count() >= limit => Err(HandleLimitExceeded {
+                name: self.name,
+                limit,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    // add object into registry and return key to reference it unless the registry
+    // has reached its limit
+    pub fn try_insert(&mut self, val: T) -> Result<usize, HandleLimitExceeded> {
+        self.check_limit()?;
+        Ok(self.insert(val))
+    }
+
     // gets a reference to a value by the given key
     pub fn get(&self, key: usize) -> Option<&T> {
         match &self.map {
@@ -120,11 +200,107 @@ impl<T, const TAG: usize, const TAG_BITS: u32> ObjectRegistry<T, TAG, TAG_BITS>
     }
 }
 
+// Per-thread bookkeeping for a registry: whether the current thread holds its lock,
+// and the keys of objects freed while it did
+pub(crate) struct RegistryThreadState {
+    held: Cell<bool>,
+    deferred_removals: RefCell<Vec<usize>>,
+}
+
+impl RegistryThreadState {
+    pub const fn new() -> Self {
+        Self {
+            held: Cell::new(false),
+            deferred_removals: RefCell::new(Vec::new()),
+        }
+    }
+}
+
+// A locked ObjectRegistry. Each registry has a thread-local flag recording whether
+// the current thread holds its lock, so that a thread locking a registry it already
+// holds (e.g. from a callback called by a function which holds the registry) fails
+// with a ReentrantCall rather than deadlocking.
+pub(crate) struct RegistryGuard<'a, T, const TAG: usize, const TAG_BITS: u32> {
+    registry: MutexGuard<'a, ObjectRegistry<T, TAG, TAG_BITS>>,
+    state: &'static LocalKey<RegistryThreadState>,
+}
+
+impl<'a, T, const TAG: usize, const TAG_BITS: u32> RegistryGuard<'a, T, TAG, TAG_BITS> {
+    // lock registry, or fail with a ReentrantCall if this thread already holds it
+    pub fn lock(
+        registry: &'a Mutex<ObjectRegistry<T, TAG, TAG_BITS>>,
+        state: &'static LocalKey<RegistryThreadState>,
+        name: &str,
+    ) -> Result<Self, ReentrantCall> {
+        if state.with(|state| state.held.get()) {
+            return Err(ReentrantCall(format!(
+                "the {} registry is already locked by this thread; a gosling function was called from within a callback which must not call it",
+                name
+            )));
+        }
+        let registry = match registry.lock() {
+            Ok(registry) => registry,
+            Err(_) => unreachable!("another thread panicked while holding this registry's mutex"),
+        };
+        state.with(|state| state.held.set(true));
+        Ok(Self { registry, state })
+    }
+
+    // remove the object with the specified key from registry; if this thread already
+    // holds the registry (i.e. we are in a callback called by a function which holds it)
+    // the removal is deferred until that function releases the registry
+    pub fn remove_or_defer(
+        registry: &'a Mutex<ObjectRegistry<T, TAG, TAG_BITS>>,
+        state: &'static LocalKey<RegistryThreadState>,
+        name: &str,
+        key: usize,
+    ) {
+        match Self::lock(registry, state, name) {
+            Ok(mut registry) => {
+                registry.remove(key);
+            }
+            Err(_) => state.with(|state| state.deferred_removals.borrow_mut().push(key)),
+        }
+    }
+}
+
+impl<'a, T, const TAG: usize, const TAG_BITS: u32> std::ops::Deref
+    for RegistryGuard<'a, T, TAG, TAG_BITS>
+{
+    type Target = ObjectRegistry<T, TAG, TAG_BITS>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.registry
+    }
+}
+
+impl<'a, T, const TAG: usize, const TAG_BITS: u32> std::ops::DerefMut
+    for RegistryGuard<'a, T, TAG, TAG_BITS>
+{
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.registry
+    }
+}
+
+impl<'a, T, const TAG: usize, const TAG_BITS: u32> Drop for RegistryGuard<'a, T, TAG, TAG_BITS> {
+    fn drop(&mut self) {
+        // objects freed from within callbacks are removed once the registry is no longer in
+        // use further up the stack; dropping them may in turn free more objects
+        while let Some(key) = self
+            .state
+            .with(|state| state.deferred_removals.borrow_mut().pop())
+        {
+            self.registry.remove(key);
+        }
+        self.state.with(|state| state.held.set(false));
+    }
+}
+
 #[test]
 fn test_object_registry() -> anyhow::Result<()> {
     // create a new ObjectRegistry
     type Int32Registry0_16 = ObjectRegistry<i32, 1234usize, 16>;
-    let mut registry = Int32Registry0_16::new();
+    let mut registry = Int32Registry0_16::new("i32");
     assert_eq!(
         Int32Registry0_16::COUNTER_BITS,
         std::mem::size_of::<usize>() as u32 * BITS_PER_BYTE - 16
@@ -177,8 +353,8 @@ fn test_object_registry() -> anyhow::Result<()> {
 #[test]
 fn test_object_registry_key_collision() -> anyhow::Result<()> {
     // create two registries with different TAG values
-    let mut registry_a: ObjectRegistry<String, 1usize, 8> = ObjectRegistry::new();
-    let mut registry_b: ObjectRegistry<String, 2usize, 8> = ObjectRegistry::new();
+    let mut registry_a: ObjectRegistry<String, 1usize, 8> = ObjectRegistry::new("String");
+    let mut registry_b: ObjectRegistry<String, 2usize, 8> = ObjectRegistry::new("String");
 
     // insert objects into the registries
     let key_a_1 = registry_a.insert("a1".to_string());
@@ -207,7 +383,7 @@ fn test_object_registry_key_collision() -> anyhow::Result<()> {
 #[test]
 fn test_object_registry_empty_tag() -> anyhow::Result<()> {
     // create a registry with tag 0 and tag bits 0
-    let mut reg = ObjectRegistry::<i32, 0, 0>::new();
+    let mut reg = ObjectRegistry::<i32, 0, 0>::new("i32");
 
     // add some values and check their keys
     let key1 = reg.insert(1);
@@ -217,3 +393,71 @@ fn test_object_registry_empty_tag() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn test_object_registry_limit() -> anyhow::Result<()> {
+    let mut registry = ObjectRegistry::<i32, 1, 8>::new("i32");
+    assert_eq!(registry.count(), 0);
+
+    // registries without a limit accept any number of objects
+    let key1 = registry.try_insert(1)?;
+    let _key2 = registry.try_insert(2)?;
+    assert_eq!(registry.count(), 2);
+
+    // once the limit is reached try_insert() fails but insert() does not
+    registry.set_limit(Some(2));
+    assert!(registry.check_limit().is_err());
+    assert!(registry.try_insert(3).is_err());
+    let key3 = registry.insert(3);
+    assert_eq!(registry.count(), 3);
+
+    // removing objects makes room for new ones
+    registry.remove(key1);
+    registry.remove(key3);
+    assert_eq!(registry.count(), 1);
+    let _key4 = registry.try_insert(4)?;
+    assert!(registry.try_insert(5).is_err());
+
+    // removing the limit allows inserts again
+    registry.set_limit(None);
+    let _key5 = registry.try_insert(5)?;
+    assert_eq!(registry.count(), 3);
+
+    Ok(())
+}
+
+#[test]
+fn test_registry_guard_reentrant() -> Result<(), ReentrantCall> {
+    static REGISTRY: Mutex<ObjectRegistry<i32, 1, 8>> = Mutex::new(ObjectRegistry::new("i32"));
+    std::thread_local! {
+        static REGISTRY_STATE: RegistryThreadState = const { RegistryThreadState::new() };
+    }
+
+    let mut registry = RegistryGuard::lock(&REGISTRY, &REGISTRY_STATE, "i32")?;
+    let key = registry.insert(1);
+
+    // locking the registry again from the same thread fails instead of deadlocking
+    assert!(RegistryGuard::lock(&REGISTRY, &REGISTRY_STATE, "i32").is_err());
+
+    // the registry may be locked again once released, including from other threads
+    drop(registry);
+    std::thread::spawn(move || -> Result<(), ReentrantCall> {
+        assert_eq!(
+            RegistryGuard::lock(&REGISTRY, &REGISTRY_STATE, "i32")?.get(key),
+            Some(&1)
+        );
+        Ok(())
+    })
+    .join()
+    .unwrap()?;
+    assert!(RegistryGuard::lock(&REGISTRY, &REGISTRY_STATE, "i32")?.contains_key(key));
+
+    // objects removed while the registry is held are removed once it is released
+    let registry = RegistryGuard::lock(&REGISTRY, &REGISTRY_STATE, "i32")?;
+    RegistryGuard::remove_or_defer(&REGISTRY, &REGISTRY_STATE, "i32", key);
+    assert!(registry.contains_key(key));
+    drop(registry);
+    assert!(!RegistryGuard::lock(&REGISTRY, &REGISTRY_STATE, "i32")?.contains_key(key));
+
+    Ok(())
+}
@@ -0,0 +1,494 @@
+// standard
+use std::ffi::CString;
+use std::io::Cursor;
+use std::os::raw::c_char;
+use std::time::Duration;
+
+// extern crates
+use anyhow::bail;
+#[cfg(feature = "impl-lib")]
+use cgosling_proc_macros::*;
+use gosling::prelude::{ApiSet, ErrorCode, RequestCookie, Response};
+use gosling::rpc_channel::{Heartbeat, RpcChannel, RpcChannelEvent};
+
+// internal crates
+use crate::error::*;
+use crate::ffi::*;
+use crate::macros::*;
+
+/// The size of the buffer a gosling_rpc_channel_request_handler_t writes its
+/// result to; a result must fit in a Honk-RPC message, which is limited to 4096
+/// bytes by default
+pub const RPC_CHANNEL_RESULT_BUFFER_SIZE: usize = 4096;
+
+/// The Honk-RPC session of a completed endpoint handshake, kept open for the
+/// application's own calls; see gosling_context_add_rpc_channel()
+pub struct GoslingRpcChannel;
+// an rpc channel, the callback its responses are passed to and the callback
+// its peer's responsiveness is reported to
+pub(crate) type RpcChannelTuple = (
+    RpcChannel,
+    GoslingRpcChannelResponseCallback,
+    GoslingRpcChannelResponsivenessCallback,
+);
+define_registry! {RpcChannelTuple}
+
+/// The function pointer type for an rpc channel's request handler, which serves
+/// the peer's calls to the namespace it was registered with. Handlers are called
+/// from gosling_rpc_channel_update() and must not call any gosling_rpc_channel_*
+/// function.
+///
+/// @param channel: the rpc channel which received the call
+/// @param function: the null-terminated name of the called function
+/// @param function_length: the number of chars in function not including the
+///  null-terminator
+/// @param version: the version of the called function
+/// @param args_buffer: a buffer containing the call's arguments as a bson
+///  document
+/// @param args_buffer_size: the number of bytes in args_buffer
+/// @param out_result_buffer: a buffer of RPC_CHANNEL_RESULT_BUFFER_SIZE bytes the
+///  handler may write its result to as a bson document
+/// @param out_result_buffer_size: the number of bytes the handler wrote to
+///  out_result_buffer, initially 0 for a call without a result
+/// @return 0 on success, or the ERROR_CODE_* or positive application-specific
+///  Honk-RPC error code the call fails with
+pub type GoslingRpcChannelRequestHandler = Option<
+    extern "C" fn(
+        channel: *mut GoslingRpcChannel,
+        function: *const c_char,
+        function_length: usize,
+        version: i32,
+        args_buffer: *const u8,
+        args_buffer_size: usize,
+        out_result_buffer: *mut u8,
+        out_result_buffer_size: *mut usize,
+    ) -> i32,
+>;
+
+/// The function pointer type for an rpc channel's response callback, which is
+/// called from gosling_rpc_channel_update() with the response to each call made
+/// with gosling_rpc_channel_call().
+///
+/// @param channel: the rpc channel which received the response
+/// @param cookie: the cookie returned by gosling_rpc_channel_call() for the call
+/// @param error_code: 0 on success, or the Honk-RPC error code the call failed
+///  with
+/// @param result_buffer: a buffer containing the call's result as a bson
+///  document; results which are not documents are wrapped in a document as its
+///  "value" field
+/// @param result_buffer_size: the number of bytes in result_buffer, 0 if the
+///  call failed or had no result
+pub type GoslingRpcChannelResponseCallback = Option<
+    extern "C" fn(
+        channel: *mut GoslingRpcChannel,
+        cookie: i64,
+        error_code: i32,
+        result_buffer: *const u8,
+        result_buffer_size: usize,
+    ),
+>;
+
+/// The function pointer type for an rpc channel's responsiveness callback, which
+/// is called from gosling_rpc_channel_update() once the channel's peer has missed
+/// the configured number of consecutive heartbeats, and again once it answers a
+/// heartbeat; see gosling_rpc_channel_set_heartbeat().
+///
+/// @param channel: the rpc channel whose peer's responsiveness changed
+/// @param responsive: false if the peer stopped answering heartbeats, true if it
+///  answered again
+/// @param missed_heartbeats: the number of consecutive heartbeats the peer has
+///  not answered, 0 if responsive is true
+pub type GoslingRpcChannelResponsivenessCallback = Option<
+    extern "C" fn(channel: *mut GoslingRpcChannel, responsive: bool, missed_heartbeats: u32),
+>;
+
+// a heartbeat from its ffi representation, in which an interval of 0 disables
+// heartbeats
+pub(crate) fn heartbeat_from_ffi(
+    interval_milliseconds: u32,
+    miss_threshold: u32,
+) -> Option<Heartbeat> {
+    match interval_milliseconds {
+        0 => None,
+        interval_milliseconds => Some(Heartbeat {
+            interval: Duration::from_millis(interval_milliseconds as u64),
+            miss_threshold,
+        }),
+    }
+}
+
+// serves a namespace's calls with a GoslingRpcChannelRequestHandler
+struct FfiApiSet {
+    channel: usize,
+    namespace: String,
+    handler: extern "C" fn(
+        *mut GoslingRpcChannel,
+        *const c_char,
+        usize,
+        i32,
+        *const u8,
+        usize,
+        *mut u8,
+        *mut usize,
+    ) -> i32,
+}
+
+impl ApiSet for FfiApiSet {
+    fn namespace(&self) -> &str {
+        &self.namespace
+    }
+
+    fn exec_function(
+        &mut self,
+        name: &str,
+        version: i32,
+        args: bson::document::Document,
+        _request_cookie: Option<RequestCookie>,
+    ) -> Option<Result<Option<bson::Bson>, ErrorCode>> {
+        let mut args_buffer: Vec<u8> = Default::default();
+        args.to_writer(&mut args_buffer).expect(
+            "args should be a valid bson::document::Document and therefore serializable to Vec<u8>",
+        );
+        let name0 = CString::new(name).expect("name should not have an intermediate null byte");
+        let mut result_buffer = vec![0u8; RPC_CHANNEL_RESULT_BUFFER_SIZE];
+        let mut result_buffer_size = 0usize;
+
+        let error_code = (self.handler)(
+            self.channel as *mut GoslingRpcChannel,
+            name0.as_ptr(),
+            name.len(),
+            version,
+            args_buffer.as_ptr(),
+            args_buffer.len(),
+            result_buffer.as_mut_ptr(),
+            &mut result_buffer_size,
+        );
+
+        if error_code != 0 {
+            return Some(Err(ErrorCode::from(error_code)));
+        }
+        match result_buffer_size {
+            0 => Some(Ok(None)),
+            result_buffer_size if result_buffer_size > RPC_CHANNEL_RESULT_BUFFER_SIZE => {
+                Some(Err(ErrorCode::MessageTooBig))
+            }
+            result_buffer_size => {
+                result_buffer.truncate(result_buffer_size);
+                match bson::document::Document::from_reader(Cursor::new(result_buffer)) {
+                    Ok(result) => Some(Ok(Some(bson::Bson::Document(result)))),
+                    Err(_) => Some(Err(ErrorCode::BsonParseFailed)),
+                }
+            }
+        }
+    }
+}
+
+/// Frees a gosling_rpc_channel object, closing its underlying connection
+///
+/// @param in_channel: the rpc channel to free
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_rpc_channel_free(in_channel: *mut GoslingRpcChannel) {
+    impl_registry_free!(in_channel, RpcChannelTuple);
+}
+
+/// Set the callback responses to the rpc channel's calls are passed to. Responses
+/// received while no callback is set are discarded.
+///
+/// @param channel: the rpc channel to register the callback to
+/// @param callback: the callback to register
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_rpc_channel_set_response_callback(
+    channel: *mut GoslingRpcChannel,
+    callback: GoslingRpcChannelResponseCallback,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(channel);
+
+        match get_rpc_channel_tuple_registry()?.get_mut(channel as usize) {
+            Some(channel) => channel.1 = callback,
+            None => bail_invalid_handle!(channel),
+        }
+        Ok(())
+    })
+}
+
+/// Set the callback changes in the responsiveness of the rpc channel's peer are
+/// passed to; see gosling_rpc_channel_set_heartbeat(). Changes which occur while
+/// no callback is set are discarded.
+///
+/// @param channel: the rpc channel to register the callback to
+/// @param callback: the callback to register
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_rpc_channel_set_responsiveness_callback(
+    channel: *mut GoslingRpcChannel,
+    callback: GoslingRpcChannelResponsivenessCallback,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(channel);
+
+        match get_rpc_channel_tuple_registry()?.get_mut(channel as usize) {
+            Some(channel) => channel.2 = callback,
+            None => bail_invalid_handle!(channel),
+        }
+        Ok(())
+    })
+}
+
+/// Set how often the rpc channel sends heartbeats to its peer, which the peer's
+/// rpc channel answers without involving its application. Once miss_threshold
+/// consecutive heartbeats have gone unanswered, e.g. because the peer's circuit
+/// failed without the connection being closed, the peer is reported unresponsive
+/// to the channel's responsiveness callback, and reported responsive again if it
+/// answers a later heartbeat. Heartbeats are only sent and answered while the
+/// peers' channels are updated with gosling_rpc_channel_update(). Peers whose
+/// channels predate heartbeats answer them with an error, which still shows they
+/// are reachable.
+///
+/// @param channel: the rpc channel to configure
+/// @param interval_milliseconds: how long to wait between heartbeats, or 0 to
+///  disable heartbeats (the default unless set with
+///  gosling_context_set_rpc_channel_heartbeat())
+/// @param miss_threshold: the number of consecutive unanswered heartbeats after
+///  which the peer is reported unresponsive; must not be 0 unless heartbeats are
+///  disabled
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_rpc_channel_set_heartbeat(
+    channel: *mut GoslingRpcChannel,
+    interval_milliseconds: u32,
+    miss_threshold: u32,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(channel);
+
+        let heartbeat = heartbeat_from_ffi(interval_milliseconds, miss_threshold);
+        match get_rpc_channel_tuple_registry()?.get_mut(channel as usize) {
+            Some(channel) => channel.0.set_heartbeat(heartbeat)?,
+            None => bail_invalid_handle!(channel),
+        }
+        Ok(())
+    })
+}
+
+/// Register a request handler to serve the peer's calls to a namespace. Fails if
+/// a handler is already registered for the namespace or the namespace is used by
+/// the gosling handshakes.
+///
+/// @param channel: the rpc channel to register the handler to
+/// @param namespace: the namespace whose calls the handler serves
+/// @param namespace_length: the number of chars in namespace not including any
+///  null-terminator
+/// @param handler: the request handler to register
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_rpc_channel_register_handler(
+    channel: *mut GoslingRpcChannel,
+    namespace: *const c_char,
+    namespace_length: usize,
+    handler: GoslingRpcChannelRequestHandler,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(channel);
+        ensure_not_null!(namespace);
+
+        let namespace = str_from_ffi(namespace, namespace_length, "namespace")?;
+        ensure_not_empty!(namespace);
+        let handler = match handler {
+            Some(handler) => handler,
+            None => bail!("handler must not be null"),
+        };
+
+        let apiset = FfiApiSet {
+            channel: channel as usize,
+            namespace: namespace.to_string(),
+            handler,
+        };
+        match get_rpc_channel_tuple_registry()?.get_mut(channel as usize) {
+            Some(channel) => channel.0.register_apiset(Box::new(apiset))?,
+            None => bail_invalid_handle!(channel),
+        }
+        Ok(())
+    })
+}
+
+/// Unregister the request handler serving a namespace. The peer's later calls to
+/// the namespace fail as for any unknown namespace.
+///
+/// @param channel: the rpc channel to unregister the handler from
+/// @param namespace: the namespace whose handler to unregister
+/// @param namespace_length: the number of chars in namespace not including any
+///  null-terminator
+/// @param error: filled on error
+/// @return true if a handler was registered for the namespace
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_rpc_channel_unregister_handler(
+    channel: *mut GoslingRpcChannel,
+    namespace: *const c_char,
+    namespace_length: usize,
+    error: *mut *mut GoslingError,
+) -> bool {
+    translate_failures(false, error, || -> anyhow::Result<bool> {
+        ensure_not_null!(channel);
+        ensure_not_null!(namespace);
+
+        let namespace = str_from_ffi(namespace, namespace_length, "namespace")?;
+
+        match get_rpc_channel_tuple_registry()?.get_mut(channel as usize) {
+            Some(channel) => Ok(channel.0.unregister_apiset(namespace).is_some()),
+            None => bail_invalid_handle!(channel),
+        }
+    })
+}
+
+/// Call a function served by the rpc channel's peer. The call is sent by the next
+/// gosling_rpc_channel_update() and its response is passed to the channel's
+/// response callback.
+///
+/// @param channel: the rpc channel to make the call on
+/// @param namespace: the namespace of the function
+/// @param namespace_length: the number of chars in namespace not including any
+///  null-terminator
+/// @param function: the name of the function
+/// @param function_length: the number of chars in function not including any
+///  null-terminator
+/// @param version: the version of the function
+/// @param args_buffer: a buffer containing the call's arguments as a bson
+///  document
+/// @param args_buffer_size: the number of bytes in args_buffer
+/// @param out_cookie: returned cookie identifying the call's response
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+#[allow(clippy::too_many_arguments)]
+pub unsafe extern "C" fn gosling_rpc_channel_call(
+    channel: *mut GoslingRpcChannel,
+    namespace: *const c_char,
+    namespace_length: usize,
+    function: *const c_char,
+    function_length: usize,
+    version: i32,
+    args_buffer: *const u8,
+    args_buffer_size: usize,
+    out_cookie: *mut i64,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(channel);
+        ensure_not_null!(namespace);
+        ensure_not_null!(function);
+        ensure_not_null!(args_buffer);
+        ensure_not_null!(out_cookie);
+
+        let namespace = str_from_ffi(namespace, namespace_length, "namespace")?;
+        ensure_not_empty!(namespace);
+        let function = str_from_ffi(function, function_length, "function")?;
+        ensure_not_empty!(function);
+        let args_buffer = std::slice::from_raw_parts(args_buffer, args_buffer_size);
+        let args = match bson::document::Document::from_reader(Cursor::new(args_buffer)) {
+            Ok(args) => args,
+            Err(_) => bail!("args_buffer must contain a valid bson document"),
+        };
+
+        let cookie = match get_rpc_channel_tuple_registry()?.get_mut(channel as usize) {
+            Some(channel) => channel.0.call(namespace, function, version, args)?,
+            None => bail_invalid_handle!(channel),
+        };
+        *out_cookie = cookie;
+
+        Ok(())
+    })
+}
+
+/// Send the rpc channel's pending calls and responses, receive the peer's, serve
+/// the peer's calls with the registered request handlers and pass the responses
+/// to the channel's calls to its response callback. Heartbeats are also sent and
+/// answered, and changes in the peer's responsiveness passed to the channel's
+/// responsiveness callback. Fails once the connection has
+/// failed or been closed, after which the channel should be freed.
+///
+/// @param channel: the rpc channel to update
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_rpc_channel_update(
+    channel: *mut GoslingRpcChannel,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(channel);
+
+        // collect the responses and events so the callbacks are called without
+        // holding the registry's lock, allowing them to make further calls
+        let (callback, responses, responsiveness_callback, events) = {
+            let mut rpc_channel_tuple_registry = get_rpc_channel_tuple_registry()?;
+            let (rpc_channel, callback, responsiveness_callback) =
+                match rpc_channel_tuple_registry.get_mut(channel as usize) {
+                    Some(rpc_channel_tuple) => rpc_channel_tuple,
+                    None => bail_invalid_handle!(channel),
+                };
+            rpc_channel.update()?;
+
+            let mut responses: Vec<(RequestCookie, i32, Vec<u8>)> = Default::default();
+            while let Some(response) = rpc_channel.next_response() {
+                match response {
+                    Response::Pending { .. } => (),
+                    Response::Success { cookie, result } => {
+                        let result = match result {
+                            Some(bson::Bson::Document(result)) => Some(result),
+                            Some(result) => Some(bson::doc! {"value": result}),
+                            None => None,
+                        };
+                        let mut result_buffer: Vec<u8> = Default::default();
+                        if let Some(result) = result {
+                            result.to_writer(&mut result_buffer).expect("result should be a valid bson::document::Document and therefore serializable to Vec<u8>");
+                        }
+                        responses.push((cookie, 0, result_buffer));
+                    }
+                    Response::Error { cookie, error_code } => {
+                        responses.push((cookie, error_code.into(), Default::default()));
+                    }
+                }
+            }
+            let mut events: Vec<RpcChannelEvent> = Default::default();
+            while let Some(event) = rpc_channel.next_event() {
+                events.push(event);
+            }
+            (*callback, responses, *responsiveness_callback, events)
+        };
+
+        if let Some(callback) = callback {
+            for (cookie, error_code, result_buffer) in responses {
+                callback(
+                    channel,
+                    cookie,
+                    error_code,
+                    result_buffer.as_ptr(),
+                    result_buffer.len(),
+                );
+            }
+        }
+        if let Some(responsiveness_callback) = responsiveness_callback {
+            for event in events {
+                match event {
+                    RpcChannelEvent::Unresponsive { missed_heartbeats } => {
+                        responsiveness_callback(channel, false, missed_heartbeats)
+                    }
+                    RpcChannelEvent::Responsive => responsiveness_callback(channel, true, 0),
+                }
+            }
+        }
+        Ok(())
+    })
+}
@@ -0,0 +1,314 @@
+// standard
+use std::net::{Shutdown, TcpStream};
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd};
+#[cfg(windows)]
+use std::os::windows::io::{AsRawSocket, FromRawSocket, IntoRawSocket};
+use std::time::Duration;
+
+// extern
+use anyhow::bail;
+#[cfg(feature = "impl-lib")]
+use cgosling_proc_macros::*;
+
+// internal
+use crate::context::*;
+use crate::error::*;
+use crate::ffi::*;
+use crate::macros::*;
+
+/// Shut down the read half of a stream; see gosling_stream_shutdown()
+pub const STREAM_SHUTDOWN_READ: u32 = 0;
+/// Shut down the write half of a stream; see gosling_stream_shutdown()
+pub const STREAM_SHUTDOWN_WRITE: u32 = 1;
+/// Shut down both halves of a stream; see gosling_stream_shutdown()
+pub const STREAM_SHUTDOWN_BOTH: u32 = 2;
+
+/// A connected TCP stream owned by the gosling library
+pub struct GoslingStream;
+define_registry! {TcpStream}
+
+// convert an optional timeout in milliseconds where 0 means no timeout
+fn timeout_from_milliseconds(timeout_milliseconds: u32) -> Option<Duration> {
+    match timeout_milliseconds {
+        0 => None,
+        timeout_milliseconds => Some(Duration::from_millis(timeout_milliseconds as u64)),
+    }
+}
+
+//
+// Free Functions
+//
+
+/// Frees a gosling_stream object, closing its underlying connection. Any
+/// duplicates created with gosling_stream_clone() remain open.
+///
+/// @param in_stream: the stream to free
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_stream_free(in_stream: *mut GoslingStream) {
+    impl_registry_free!(in_stream, TcpStream);
+}
+
+//
+// Clone Functions
+//
+
+/// Duplicate a gosling_stream. The copy refers to the same underlying
+/// connection, so shutting down either stream affects both, but each must be
+/// freed separately.
+///
+/// @param out_stream: returned copy
+/// @param stream: original to copy
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_stream_clone(
+    out_stream: *mut *mut GoslingStream,
+    stream: *const GoslingStream,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(out_stream);
+        ensure_not_null!(stream);
+
+        let mut tcp_stream_registry = get_tcp_stream_registry()?;
+        let stream = match tcp_stream_registry.get(stream as usize) {
+            Some(stream) => stream.try_clone()?,
+            None => bail_invalid_handle!(stream),
+        };
+        let handle = tcp_stream_registry.try_insert(stream)?;
+        *out_stream = handle as *mut GoslingStream;
+
+        Ok(())
+    })
+}
+
+//
+// Conversion Functions
+//
+
+/// Adopt a connected TCP socket as a gosling_stream, e.g. a socket returned by
+/// gosling_context_connect() or passed to the endpoint client or server
+/// handshake completed callbacks. The stream takes ownership of the socket, so
+/// the caller must not use or close it afterwards. On failure the socket is
+/// closed.
+///
+/// @param out_stream: returned stream
+/// @param tcp_socket: a connected TCP socket
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_stream_from_tcp_socket(
+    out_stream: *mut *mut GoslingStream,
+    tcp_socket: GoslingTcpSocket,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(out_stream);
+
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        let tcp_stream = TcpStream::from_raw_fd(tcp_socket);
+        #[cfg(target_os = "windows")]
+        let tcp_stream = TcpStream::from_raw_socket(tcp_socket);
+
+        // only connected sockets have a peer
+        if let Err(err) = tcp_stream.peer_addr() {
+            bail!("tcp_socket must be a connected TCP socket: {}", err);
+        }
+
+        let handle = get_tcp_stream_registry()?.try_insert(tcp_stream)?;
+        *out_stream = handle as *mut GoslingStream;
+
+        Ok(())
+    })
+}
+
+/// Release a gosling_stream's underlying TCP socket to the caller, who
+/// becomes responsible for closing it. The stream is consumed and must not be
+/// used or freed afterwards.
+///
+/// @param out_tcp_socket: returned TCP socket
+/// @param in_stream: the stream to convert
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_stream_into_tcp_socket(
+    out_tcp_socket: *mut GoslingTcpSocket,
+    in_stream: *mut GoslingStream,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(out_tcp_socket);
+        ensure_not_null!(in_stream);
+
+        let tcp_stream = match get_tcp_stream_registry()?.remove(in_stream as usize) {
+            Some(tcp_stream) => tcp_stream,
+            None => bail_invalid_handle!(in_stream),
+        };
+
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        let tcp_socket = tcp_stream.into_raw_fd();
+        #[cfg(target_os = "windows")]
+        let tcp_socket = tcp_stream.into_raw_socket();
+
+        *out_tcp_socket = tcp_socket;
+        Ok(())
+    })
+}
+
+/// Get a gosling_stream's underlying TCP socket, e.g. to read, write, or wait
+/// on it with select() or poll(). The socket remains owned by the stream, so
+/// the caller must not close it and must not use it after the stream is freed.
+///
+/// @param out_tcp_socket: returned TCP socket
+/// @param stream: the stream whose socket to get
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_stream_get_tcp_socket(
+    out_tcp_socket: *mut GoslingTcpSocket,
+    stream: *const GoslingStream,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(out_tcp_socket);
+        ensure_not_null!(stream);
+
+        let tcp_stream_registry = get_tcp_stream_registry()?;
+        let tcp_stream = match tcp_stream_registry.get(stream as usize) {
+            Some(tcp_stream) => tcp_stream,
+            None => bail_invalid_handle!(stream),
+        };
+
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        let tcp_socket = tcp_stream.as_raw_fd();
+        #[cfg(target_os = "windows")]
+        let tcp_socket = tcp_stream.as_raw_socket();
+
+        *out_tcp_socket = tcp_socket;
+        Ok(())
+    })
+}
+
+//
+// Stream Methods
+//
+
+/// Set how long blocking reads on a gosling_stream may wait for data before
+/// failing.
+///
+/// @param stream: the stream to configure
+/// @param timeout_milliseconds: the read timeout in milliseconds, or 0 to
+///  block indefinitely (the default)
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_stream_set_read_timeout(
+    stream: *mut GoslingStream,
+    timeout_milliseconds: u32,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(stream);
+
+        let tcp_stream_registry = get_tcp_stream_registry()?;
+        let tcp_stream = match tcp_stream_registry.get(stream as usize) {
+            Some(tcp_stream) => tcp_stream,
+            None => bail_invalid_handle!(stream),
+        };
+
+        tcp_stream.set_read_timeout(timeout_from_milliseconds(timeout_milliseconds))?;
+        Ok(())
+    })
+}
+
+/// Set how long blocking writes on a gosling_stream may wait for the peer
+/// before failing.
+///
+/// @param stream: the stream to configure
+/// @param timeout_milliseconds: the write timeout in milliseconds, or 0 to
+///  block indefinitely (the default)
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_stream_set_write_timeout(
+    stream: *mut GoslingStream,
+    timeout_milliseconds: u32,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(stream);
+
+        let tcp_stream_registry = get_tcp_stream_registry()?;
+        let tcp_stream = match tcp_stream_registry.get(stream as usize) {
+            Some(tcp_stream) => tcp_stream,
+            None => bail_invalid_handle!(stream),
+        };
+
+        tcp_stream.set_write_timeout(timeout_from_milliseconds(timeout_milliseconds))?;
+        Ok(())
+    })
+}
+
+/// Move a gosling_stream into or out of non-blocking mode. This also affects
+/// any duplicates created with gosling_stream_clone().
+///
+/// @param stream: the stream to configure
+/// @param nonblocking: whether reads and writes should fail rather than block
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_stream_set_nonblocking(
+    stream: *mut GoslingStream,
+    nonblocking: bool,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(stream);
+
+        let tcp_stream_registry = get_tcp_stream_registry()?;
+        let tcp_stream = match tcp_stream_registry.get(stream as usize) {
+            Some(tcp_stream) => tcp_stream,
+            None => bail_invalid_handle!(stream),
+        };
+
+        tcp_stream.set_nonblocking(nonblocking)?;
+        Ok(())
+    })
+}
+
+/// Shut down the read half, write half, or both halves of a gosling_stream's
+/// connection. Shutting down the write half signals end-of-stream to the
+/// peer. The stream must still be freed with gosling_stream_free().
+///
+/// @param stream: the stream to shut down
+/// @param how: one of the STREAM_SHUTDOWN_* constants
+/// @param error: filled on error
+#[no_mangle]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_stream_shutdown(
+    stream: *mut GoslingStream,
+    how: u32,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(stream);
+
+        let how = match how {
+            STREAM_SHUTDOWN_READ => Shutdown::Read,
+            STREAM_SHUTDOWN_WRITE => Shutdown::Write,
+            STREAM_SHUTDOWN_BOTH => Shutdown::Both,
+            how => bail!("invalid how: {}", how),
+        };
+
+        let tcp_stream_registry = get_tcp_stream_registry()?;
+        let tcp_stream = match tcp_stream_registry.get(stream as usize) {
+            Some(tcp_stream) => tcp_stream,
+            None => bail_invalid_handle!(stream),
+        };
+
+        tcp_stream.shutdown(how)?;
+        Ok(())
+    })
+}
@@ -18,6 +18,7 @@ use tor_interface::legacy_tor_client::*;
 use tor_interface::mock_tor_client::*;
 #[cfg(feature = "legacy-tor-provider")]
 use tor_interface::proxy::*;
+use tor_interface::tor_provider::SecurityLevel;
 use tor_interface::*;
 
 // internal crates
@@ -27,6 +28,23 @@ use crate::macros::*;
 #[cfg(feature = "legacy-tor-provider")]
 use crate::utils::*;
 
+/// Security level which keeps the tor provider's default circuit-building behaviour; see
+/// gosling_context_set_security_level()
+pub const SECURITY_LEVEL_STANDARD: u32 = 0;
+/// Security level which always uses vanguards-lite and a single entry guard for onion-service
+/// circuits, making guard-discovery attacks against long-running servers more expensive; see
+/// gosling_context_set_security_level()
+pub const SECURITY_LEVEL_HARDENED: u32 = 1;
+
+// convert one of the SECURITY_LEVEL_* constants
+pub(crate) fn security_level_from_ffi(security_level: u32) -> anyhow::Result<SecurityLevel> {
+    match security_level {
+        SECURITY_LEVEL_STANDARD => Ok(SecurityLevel::Standard),
+        SECURITY_LEVEL_HARDENED => Ok(SecurityLevel::Hardened),
+        security_level => bail!("invalid security_level: {}", security_level),
+    }
+}
+
 /// Proxy settings object used by tor provider to connect to the tor network
 #[cfg(feature = "legacy-tor-provider")]
 pub struct GoslingProxyConfig;
@@ -138,13 +156,13 @@ pub unsafe extern "C" fn gosling_proxy_config_new_socks4(
         ensure_not_null!(out_proxy_config);
         ensure_not_null!(proxy_address);
 
-        let proxy_address = match get_target_addr_registry().get(proxy_address as usize) {
+        let proxy_address = match get_target_addr_registry()?.get(proxy_address as usize) {
             Some(target_address) => target_address.clone(),
             None => bail_invalid_handle!(proxy_address),
         };
         let proxy_config = Socks4ProxyConfig::new(proxy_address)?;
 
-        let handle = get_proxy_config_registry().insert(proxy_config.into());
+        let handle = get_proxy_config_registry()?.try_insert(proxy_config.into())?;
         *out_proxy_config = handle as *mut GoslingProxyConfig;
 
         Ok(())
@@ -155,12 +173,14 @@ pub unsafe extern "C" fn gosling_proxy_config_new_socks4(
 ///
 /// @param out_proxy_config: returned proxy config object
 /// @param proxy_address: the host address of the proxy, must not be an onion service
-/// @param username: username to authenticate with socks5 proxy
+/// @param username: username to authenticate with socks5 proxy; may be null or empty for no
+///  username
 /// @param username_length: number of characters in username, not counting any null-
-///  terminator
-/// @param password: password to authenticate with socks5 proxy
-/// @param password_length: number of characters in username, not counting any null-
-///  terminator
+///  terminator, or 0 if username is null-terminated
+/// @param password: password to authenticate with socks5 proxy; may be null or empty for no
+///  password
+/// @param password_length: number of characters in password, not counting any null-
+///  terminator, or 0 if password is null-terminated
 /// @param error: filled on error
 #[no_mangle]
 #[cfg(feature = "legacy-tor-provider")]
@@ -178,30 +198,32 @@ pub unsafe extern "C" fn gosling_proxy_config_new_socks5(
         ensure_not_null!(out_proxy_config);
         ensure_not_null!(proxy_address);
 
-        let proxy_address = match get_target_addr_registry().get(proxy_address as usize) {
+        let proxy_address = match get_target_addr_registry()?.get(proxy_address as usize) {
             Some(target_address) => target_address.clone(),
             None => bail_invalid_handle!(proxy_address),
         };
 
-        let username = if username.is_null() || username_length == 0 {
+        let username = if username.is_null() {
             None
         } else {
-            let username = std::slice::from_raw_parts(username as *const u8, username_length);
-            let username = std::str::from_utf8(username)?;
-            Some(username.to_string())
+            match str_from_ffi(username, username_length, "username")? {
+                "" => None,
+                username => Some(username.to_string()),
+            }
         };
 
-        let password = if password.is_null() || password_length == 0 {
+        let password = if password.is_null() {
             None
         } else {
-            let password = std::slice::from_raw_parts(password as *const u8, password_length);
-            let password = std::str::from_utf8(password)?;
-            Some(password.to_string())
+            match str_from_ffi(password, password_length, "password")? {
+                "" => None,
+                password => Some(password.to_string()),
+            }
         };
 
         let proxy_config = Socks5ProxyConfig::new(proxy_address, username, password)?;
 
-        let handle = get_proxy_config_registry().insert(proxy_config.into());
+        let handle = get_proxy_config_registry()?.try_insert(proxy_config.into())?;
         *out_proxy_config = handle as *mut GoslingProxyConfig;
 
         Ok(())
@@ -212,12 +234,14 @@ pub unsafe extern "C" fn gosling_proxy_config_new_socks5(
 ///
 /// @param out_proxy_config: returned proxy config object
 /// @param proxy_address: the host address of the proxy, must not be an onion service
-/// @param username: username to authenticate with https proxy
+/// @param username: username to authenticate with https proxy; may be null or empty for no
+///  username
 /// @param username_length: number of characters in username, not counting any null-
-///  terminator
-/// @param password: password to authenticate with https proxy
-/// @param password_length: number of characters in username, not counting any null-
-///  terminator
+///  terminator, or 0 if username is null-terminated
+/// @param password: password to authenticate with https proxy; may be null or empty for no
+///  password
+/// @param password_length: number of characters in password, not counting any null-
+///  terminator, or 0 if password is null-terminated
 /// @param error: filled on error
 #[no_mangle]
 #[cfg(feature = "legacy-tor-provider")]
@@ -235,30 +259,32 @@ pub unsafe extern "C" fn gosling_proxy_config_new_https(
         ensure_not_null!(out_proxy_config);
         ensure_not_null!(proxy_address);
 
-        let proxy_address = match get_target_addr_registry().get(proxy_address as usize) {
+        let proxy_address = match get_target_addr_registry()?.get(proxy_address as usize) {
             Some(target_address) => target_address.clone(),
             None => bail_invalid_handle!(proxy_address),
         };
 
-        let username = if username.is_null() || username_length == 0 {
+        let username = if username.is_null() {
             None
         } else {
-            let username = std::slice::from_raw_parts(username as *const u8, username_length);
-            let username = std::str::from_utf8(username)?;
-            Some(username.to_string())
+            match str_from_ffi(username, username_length, "username")? {
+                "" => None,
+                username => Some(username.to_string()),
+            }
         };
 
-        let password = if password.is_null() || password_length == 0 {
+        let password = if password.is_null() {
             None
         } else {
-            let password = std::slice::from_raw_parts(password as *const u8, password_length);
-            let password = std::str::from_utf8(password)?;
-            Some(password.to_string())
+            match str_from_ffi(password, password_length, "password")? {
+                "" => None,
+                password => Some(password.to_string()),
+            }
         };
 
         let proxy_config = HttpsProxyConfig::new(proxy_address, username, password)?;
 
-        let handle = get_proxy_config_registry().insert(proxy_config.into());
+        let handle = get_proxy_config_registry()?.try_insert(proxy_config.into())?;
         *out_proxy_config = handle as *mut GoslingProxyConfig;
 
         Ok(())
@@ -275,10 +301,11 @@ pub unsafe extern "C" fn gosling_proxy_config_new_https(
 /// @param transports: comma-delimited list of transports this pluggable-transport
 ///  supports
 /// @param transports_length: number of characters in transports, not counting any
-///  null-terminator
+///  null-terminator, or 0 if transports is null-terminated
 /// @param path_to_binary: path to the pluggable-transport binary, either absolute or
 ///  relative to the tor daemon process
-/// @param path_to_binary_length: number of characters in path_to_binary, not counting any null-terminator
+/// @param path_to_binary_length: number of characters in path_to_binary, not counting any null-terminator,
+///  or 0 if path_to_binary is null-terminated
 /// @param error: filled on error
 #[no_mangle]
 #[cfg(feature = "legacy-tor-provider")]
@@ -294,23 +321,21 @@ pub unsafe extern "C" fn gosling_pluggable_transport_config_new(
     translate_failures((), error, || -> anyhow::Result<()> {
         ensure_not_null!(out_pluggable_transport_config);
         ensure_not_null!(transports);
-        ensure_not_equal!(transports_length, 0);
         ensure_not_null!(path_to_binary);
-        ensure_not_equal!(path_to_binary_length, 0);
 
-        let transports = std::slice::from_raw_parts(transports as *const u8, transports_length);
-        let transports = std::str::from_utf8(transports)?;
+        let transports = ascii_str_from_ffi(transports, transports_length, "transports")?;
+        ensure_not_empty!(transports);
         let transports: Vec<String> = transports.split(',').map(|s| s.to_string()).collect();
 
-        let path_to_binary =
-            std::slice::from_raw_parts(path_to_binary as *const u8, path_to_binary_length);
-        let path_to_binary = std::str::from_utf8(path_to_binary)?;
+        let path_to_binary = str_from_ffi(path_to_binary, path_to_binary_length, "path_to_binary")?;
+        ensure_not_empty!(path_to_binary);
         let path_to_binary = Path::new(path_to_binary);
         path_to_binary.canonicalize()?;
 
         let pluggable_transport_config =
             PluggableTransportConfig::new(transports, path_to_binary.into())?;
-        let handle = get_pluggable_transport_config_registry().insert(pluggable_transport_config);
+        let handle =
+            get_pluggable_transport_config_registry()?.try_insert(pluggable_transport_config)?;
         *out_pluggable_transport_config = handle as *mut GoslingPluggableTransportConfig;
 
         Ok(())
@@ -322,7 +347,7 @@ pub unsafe extern "C" fn gosling_pluggable_transport_config_new(
 /// @param pluggable_transport_config: the pluggable-transport ocnfig object to update
 /// @param option: cmd-line option or flag to pass to the pluggable-transport on launch
 /// @param option_length: number of characters in option, not counting any null-
-///  terminator
+///  terminator, or 0 if option is null-terminated
 /// @param error: filled on error
 #[no_mangle]
 #[cfg(feature = "legacy-tor-provider")]
@@ -336,12 +361,12 @@ pub unsafe extern "C" fn gosling_pluggable_transport_config_add_cmdline_option(
     translate_failures((), error, || -> anyhow::Result<()> {
         ensure_not_null!(pluggable_transport_config);
         ensure_not_null!(option);
-        ensure_not_equal!(option_length, 0);
 
-        let option = std::slice::from_raw_parts(option as *const u8, option_length);
-        let option = std::str::from_utf8(option)?;
+        let option = str_from_ffi(option, option_length, "option")?;
+        ensure_not_empty!(option);
 
-        match get_pluggable_transport_config_registry().get_mut(pluggable_transport_config as usize)
+        match get_pluggable_transport_config_registry()?
+            .get_mut(pluggable_transport_config as usize)
         {
             Some(config) => config.add_option(option.to_string()),
             None => bail_invalid_handle!(pluggable_transport_config),
@@ -361,7 +386,7 @@ pub unsafe extern "C" fn gosling_pluggable_transport_config_add_cmdline_option(
 /// @param bridge_line: a bridge address to connect to using a pluggable-transport. For
 ///  more information, see: https://tb-manual.torproject.org/bridges/
 /// @param bridge_line_length: number of characters in bridge_line, not counting any
-///  null-terminator
+///  null-terminator, or 0 if bridge_line is null-terminated
 /// @param error: filled on error
 #[no_mangle]
 #[cfg(feature = "legacy-tor-provider")]
@@ -375,13 +400,12 @@ pub unsafe extern "C" fn gosling_bridge_line_from_string(
     translate_failures((), error, || -> anyhow::Result<()> {
         ensure_not_null!(out_bridge_line);
         ensure_not_null!(bridge_line);
-        ensure_not_equal!(bridge_line_length, 0);
 
-        let bridge_line = std::slice::from_raw_parts(bridge_line as *const u8, bridge_line_length);
-        let bridge_line = std::str::from_utf8(bridge_line)?;
+        let bridge_line = ascii_str_from_ffi(bridge_line, bridge_line_length, "bridge_line")?;
+        ensure_not_empty!(bridge_line);
         let bridge_line = BridgeLine::from_str(bridge_line)?;
 
-        let handle = get_bridge_line_registry().insert(bridge_line);
+        let handle = get_bridge_line_registry()?.try_insert(bridge_line)?;
         *out_bridge_line = handle as *mut GoslingBridgeLine;
 
         Ok(())
@@ -406,8 +430,8 @@ pub unsafe extern "C" fn gosling_tor_provider_config_new_mock_client_config(
     translate_failures((), error, || -> anyhow::Result<()> {
         ensure_not_null!(out_tor_provider_config);
 
-        let handle =
-            get_tor_provider_config_registry().insert(TorProviderConfig::MockTorClientConfig);
+        let handle = get_tor_provider_config_registry()?
+            .try_insert(TorProviderConfig::MockTorClientConfig)?;
         *out_tor_provider_config = handle as *mut GoslingTorProviderConfig;
 
         Ok(())
@@ -419,10 +443,11 @@ pub unsafe extern "C" fn gosling_tor_provider_config_new_mock_client_config(
 /// @param out_tor_provider_config: returned tor provider config
 /// @param tor_bin_path: the file system path to the tor binary; if this is null the tor executable
 ///  found in the system PATH variable is used
-/// @param tor_bin_path_length: the number of chars in tor_bin_path not including any null terminator
+/// @param tor_bin_path_length: the number of chars in tor_bin_path not including any null terminator,
+///  or 0 if tor_bin_path is null or null-terminated
 /// @param tor_working_directory: the file system path to store tor's data
 /// @param tor_working_directory_length: the number of chars in tor_working_directory not including any
-///  null-terminator
+///  null-terminator, or 0 if tor_working_directory is null-terminated
 /// @param error: filled on error
 #[no_mangle]
 #[cfg(feature = "legacy-tor-provider")]
@@ -440,29 +465,33 @@ pub unsafe extern "C" fn gosling_tor_provider_config_new_bundled_legacy_client_c
         if tor_bin_path.is_null() && tor_bin_path_length != 0 {
             bail!("tor_bin_path is null so tor_bin_path_length must be 0");
         }
-        if !tor_bin_path.is_null() && tor_bin_path_length == 0 {
-            bail!("tor_bin_path is not null so tor_bin_path_length must be greater than 0");
-        }
         ensure_not_null!(tor_working_directory);
-        ensure_not_equal!(tor_working_directory_length, 0);
 
         // tor bin
         let tor_bin_path = if tor_bin_path.is_null() {
             which::which(format!("tor{}", std::env::consts::EXE_SUFFIX))?
         } else {
-            let tor_bin_path =
-                std::slice::from_raw_parts(tor_bin_path as *const u8, tor_bin_path_length);
-            let tor_bin_path = std::str::from_utf8(tor_bin_path)?;
+            let tor_bin_path = str_from_ffi(tor_bin_path, tor_bin_path_length, "tor_bin_path")?;
+            ensure_not_empty!(tor_bin_path);
             let tor_bin_path = Path::new(tor_bin_path);
-            tor_bin_path.canonicalize()?
+            match tor_bin_path.canonicalize() {
+                Ok(tor_bin_path) => tor_bin_path,
+                // a missing binary is reported with its own error code when the tor provider is
+                // created, rather than as a generic error here
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                    tor_bin_path.to_path_buf()
+                }
+                Err(err) => return Err(err.into()),
+            }
         };
 
         // tor working dir
-        let tor_working_directory = std::slice::from_raw_parts(
-            tor_working_directory as *const u8,
+        let tor_working_directory = str_from_ffi(
+            tor_working_directory,
             tor_working_directory_length,
-        );
-        let tor_working_directory = std::str::from_utf8(tor_working_directory)?;
+            "tor_working_directory",
+        )?;
+        ensure_not_empty!(tor_working_directory);
         let tor_working_directory = Path::new(tor_working_directory).to_path_buf();
         let tor_config = LegacyTorClientConfig::BundledTor {
             tor_bin_path: tor_bin_path,
@@ -471,10 +500,14 @@ pub unsafe extern "C" fn gosling_tor_provider_config_new_bundled_legacy_client_c
             allowed_ports: None,
             pluggable_transports: None,
             bridge_lines: None,
+            sandbox: None,
+            security_config: None,
+            socks_policy: None,
+            shared_network_cache: None,
         };
 
-        let handle = get_tor_provider_config_registry()
-            .insert(TorProviderConfig::LegacyTorClientConfig(tor_config));
+        let handle = get_tor_provider_config_registry()?
+            .try_insert(TorProviderConfig::LegacyTorClientConfig(tor_config))?;
         *out_tor_provider_config = handle as *mut GoslingTorProviderConfig;
 
         Ok(())
@@ -490,7 +523,7 @@ pub unsafe extern "C" fn gosling_tor_provider_config_new_bundled_legacy_client_c
 /// @param tor_control_port: tor daemon control port
 /// @param tor_control_passwd: authentication password
 /// @param tor_control_passwd_length: the number of chars in tor_control_password not
-///  including any null-terminator
+///  including any null-terminator, or 0 if tor_control_passwd is null-terminated
 /// @param error: filled on error
 #[no_mangle]
 #[cfg(feature = "legacy-tor-provider")]
@@ -512,26 +545,29 @@ pub unsafe extern "C" fn gosling_tor_provider_config_new_system_legacy_client_co
         ensure_not_null!(tor_control_host);
         ensure_not_equal!(tor_control_port, 0);
         ensure_not_null!(tor_control_passwd);
-        ensure_not_equal!(tor_control_passwd_length, 0);
 
         // constructor tor_socks_addr
-        let tor_socks_host = match get_ip_addr_registry().get(tor_socks_host as usize) {
+        let tor_socks_host = match get_ip_addr_registry()?.get(tor_socks_host as usize) {
             Some(tor_socks_host) => tor_socks_host.clone(),
             None => bail_invalid_handle!(tor_socks_host),
         };
         let tor_socks_addr = std::net::SocketAddr::new(tor_socks_host, tor_socks_port);
 
         // construct tor_control_addr
-        let tor_control_host = match get_ip_addr_registry().get(tor_control_host as usize) {
+        let tor_control_host = match get_ip_addr_registry()?.get(tor_control_host as usize) {
             Some(tor_control_host) => tor_control_host.clone(),
             None => bail_invalid_handle!(tor_control_host),
         };
         let tor_control_addr = std::net::SocketAddr::new(tor_control_host, tor_control_port);
 
         // construct tor_control_password
-        let tor_control_passwd =
-            std::slice::from_raw_parts(tor_control_passwd as *const u8, tor_control_passwd_length);
-        let tor_control_passwd = std::str::from_utf8(tor_control_passwd)?.to_string();
+        let tor_control_passwd = str_from_ffi(
+            tor_control_passwd,
+            tor_control_passwd_length,
+            "tor_control_passwd",
+        )?;
+        ensure_not_empty!(tor_control_passwd);
+        let tor_control_passwd = tor_control_passwd.to_string();
 
         let tor_config = LegacyTorClientConfig::SystemTor {
             tor_socks_addr,
@@ -539,8 +575,8 @@ pub unsafe extern "C" fn gosling_tor_provider_config_new_system_legacy_client_co
             tor_control_passwd,
         };
 
-        let handle = get_tor_provider_config_registry()
-            .insert(TorProviderConfig::LegacyTorClientConfig(tor_config));
+        let handle = get_tor_provider_config_registry()?
+            .try_insert(TorProviderConfig::LegacyTorClientConfig(tor_config))?;
         *out_tor_provider_config = handle as *mut GoslingTorProviderConfig;
 
         Ok(())
@@ -571,13 +607,14 @@ pub unsafe extern "C" fn gosling_tor_provider_config_set_proxy_config(
         ensure_not_null!(tor_provider_config);
         ensure_not_null!(proxy_config);
 
-        match get_tor_provider_config_registry().get_mut(tor_provider_config as usize) {
+        match get_tor_provider_config_registry()?.get_mut(tor_provider_config as usize) {
             Some(tor_provider_config) => match tor_provider_config {
                 TorProviderConfig::LegacyTorClientConfig(LegacyTorClientConfig::BundledTor {
                     proxy_settings,
                     ..
                 }) => {
-                    *proxy_settings = match get_proxy_config_registry().get(proxy_config as usize) {
+                    *proxy_settings = match get_proxy_config_registry()?.get(proxy_config as usize)
+                    {
                         Some(proxy_config) => Some(proxy_config.clone()),
                         None => bail_invalid_handle!(proxy_config),
                     };
@@ -618,7 +655,7 @@ pub unsafe extern "C" fn gosling_tor_provider_config_set_allowed_ports(
 
         let allowed_ports_slice =
             std::slice::from_raw_parts(allowed_ports as *const u16, allowed_ports_count);
-        match get_tor_provider_config_registry().get_mut(tor_provider_config as usize) {
+        match get_tor_provider_config_registry()?.get_mut(tor_provider_config as usize) {
             Some(tor_provider_config) => match tor_provider_config {
                 TorProviderConfig::LegacyTorClientConfig(LegacyTorClientConfig::BundledTor {
                     allowed_ports,
@@ -634,6 +671,175 @@ pub unsafe extern "C" fn gosling_tor_provider_config_set_allowed_ports(
         Ok(())
     })
 }
+
+/// Set the circuit-hardening options a tor provider config's tor daemon is launched with.
+/// A tor provider config does not need to support security options, so this function
+/// may fail as a result. The currently supported tor provider configs are:
+/// - Legacy Bundled Client
+///
+/// The security level of a running tor provider may also be changed with
+/// gosling_context_set_security_level().
+///
+/// @param tor_provider_config: the tor provider config to update
+/// @param security_level: one of the SECURITY_LEVEL_* constants
+/// @param error: filled on error
+#[no_mangle]
+#[cfg(feature = "legacy-tor-provider")]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub extern "C" fn gosling_tor_provider_config_set_security_level(
+    tor_provider_config: *mut GoslingTorProviderConfig,
+    security_level: u32,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(tor_provider_config);
+
+        let security_level = security_level_from_ffi(security_level)?;
+        match get_tor_provider_config_registry()?.get_mut(tor_provider_config as usize) {
+            Some(tor_provider_config) => match tor_provider_config {
+                TorProviderConfig::LegacyTorClientConfig(LegacyTorClientConfig::BundledTor {
+                    security_config,
+                    ..
+                }) => {
+                    *security_config = Some(Box::new(security_level.into()));
+                }
+                _ => bail!("tor_provider_config does not support this operation"),
+            },
+            None => bail_invalid_handle!(tor_provider_config),
+        }
+
+        Ok(())
+    })
+}
+
+/// Set the SOCKS policy a tor provider config's tor daemon is launched with, guarding against
+/// applications proxying through the tor daemon's SOCKS port leaking DNS requests or plaintext
+/// traffic. Violations are returned as EVENT_TYPE_TOR_SOCKS_POLICY_VIOLATED events. A tor
+/// provider config does not need to support a SOCKS policy, so this function may fail as a
+/// result. The currently supported tor provider configs are:
+/// - Legacy Bundled Client
+///
+/// @param tor_provider_config: the tor provider config to update
+/// @param safe_socks: whether SOCKS requests which give an IP address rather than a hostname
+///  are refused, including connections made by the tor provider itself to an IP address
+/// @param test_socks: whether the tor daemon logs whether each SOCKS request is safe
+/// @param warn_plaintext_ports: an array of ports whose streams are warned about because they
+///  are commonly used by plaintext protocols, or null to use tor's default list
+/// @param warn_plaintext_ports_count: the number of ports in the warn_plaintext_ports array;
+///  must be 0 if warn_plaintext_ports is null
+/// @param reject_plaintext_ports: an array of ports whose streams are refused because they
+///  are commonly used by plaintext protocols; may be null if reject_plaintext_ports_count is 0
+/// @param reject_plaintext_ports_count: the number of ports in the reject_plaintext_ports array
+/// @param error: filled on error
+#[no_mangle]
+#[cfg(feature = "legacy-tor-provider")]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_tor_provider_config_set_socks_policy(
+    tor_provider_config: *mut GoslingTorProviderConfig,
+    safe_socks: bool,
+    test_socks: bool,
+    warn_plaintext_ports: *const u16,
+    warn_plaintext_ports_count: usize,
+    reject_plaintext_ports: *const u16,
+    reject_plaintext_ports_count: usize,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(tor_provider_config);
+        if warn_plaintext_ports.is_null() && warn_plaintext_ports_count != 0 {
+            bail!("warn_plaintext_ports is null so warn_plaintext_ports_count must be 0");
+        }
+        if reject_plaintext_ports.is_null() && reject_plaintext_ports_count != 0 {
+            bail!("reject_plaintext_ports is null so reject_plaintext_ports_count must be 0");
+        }
+
+        let ports = |ports: *const u16, count: usize| -> Vec<u16> {
+            if count == 0 {
+                Default::default()
+            } else {
+                std::slice::from_raw_parts(ports, count).into()
+            }
+        };
+        let policy = LegacyTorSocksPolicy {
+            safe_socks,
+            test_socks,
+            warn_plaintext_ports: (!warn_plaintext_ports.is_null())
+                .then(|| ports(warn_plaintext_ports, warn_plaintext_ports_count)),
+            reject_plaintext_ports: ports(reject_plaintext_ports, reject_plaintext_ports_count),
+        };
+
+        match get_tor_provider_config_registry()?.get_mut(tor_provider_config as usize) {
+            Some(tor_provider_config) => match tor_provider_config {
+                TorProviderConfig::LegacyTorClientConfig(LegacyTorClientConfig::BundledTor {
+                    socks_policy,
+                    ..
+                }) => {
+                    *socks_policy = Some(Box::new(policy));
+                }
+                _ => bail!("tor_provider_config does not support this operation"),
+            },
+            None => bail_invalid_handle!(tor_provider_config),
+        }
+
+        Ok(())
+    })
+}
+
+/// Set a directory in which a tor provider config's tor daemon shares its downloaded
+/// network consensus and descriptors with other tor daemons. The daemon starts from the
+/// newest documents in this directory and copies its own there once bootstrapped, so
+/// multiple contexts launched in parallel need not each download them. The tor daemons'
+/// working directories must still differ. A tor provider config does not need to support
+/// a shared network cache, so this function may fail as a result. The currently supported
+/// tor provider configs are:
+/// - Legacy Bundled Client
+///
+/// @param tor_provider_config: the tor provider config to update
+/// @param shared_network_cache: the absolute file system path of the shared directory,
+///  which is created if it does not exist
+/// @param shared_network_cache_length: the number of chars in shared_network_cache not
+///  including any null-terminator, or 0 if shared_network_cache is null-terminated
+/// @param error: filled on error
+#[no_mangle]
+#[cfg(feature = "legacy-tor-provider")]
+#[cfg_attr(feature = "impl-lib", rename_impl)]
+pub unsafe extern "C" fn gosling_tor_provider_config_set_shared_network_cache(
+    tor_provider_config: *mut GoslingTorProviderConfig,
+    shared_network_cache: *const c_char,
+    shared_network_cache_length: usize,
+    error: *mut *mut GoslingError,
+) {
+    translate_failures((), error, || -> anyhow::Result<()> {
+        ensure_not_null!(tor_provider_config);
+        ensure_not_null!(shared_network_cache);
+
+        let shared_network_cache = str_from_ffi(
+            shared_network_cache,
+            shared_network_cache_length,
+            "shared_network_cache",
+        )?;
+        ensure_not_empty!(shared_network_cache);
+        let shared_network_cache = Path::new(shared_network_cache).to_path_buf();
+        if shared_network_cache.is_relative() {
+            bail!("shared_network_cache must be an absolute path");
+        }
+
+        match get_tor_provider_config_registry()?.get_mut(tor_provider_config as usize) {
+            Some(tor_provider_config) => match tor_provider_config {
+                TorProviderConfig::LegacyTorClientConfig(LegacyTorClientConfig::BundledTor {
+                    shared_network_cache: config_shared_network_cache,
+                    ..
+                }) => {
+                    *config_shared_network_cache = Some(shared_network_cache);
+                }
+                _ => bail!("tor_provider_config does not support this operation"),
+            },
+            None => bail_invalid_handle!(tor_provider_config),
+        }
+
+        Ok(())
+    })
+}
 /// Add a pluggable-transport config to a tor provider config. A tor provider config
 /// does not need to support pluggable-transport configuration, so this function may
 /// fail as a result. The currently supported tor provider configs are:
@@ -658,18 +864,19 @@ pub unsafe extern "C" fn gosling_tor_provider_config_add_pluggable_transport_con
         ensure_not_null!(tor_provider_config);
         ensure_not_null!(pluggable_transport_config);
 
-        match get_tor_provider_config_registry().get_mut(tor_provider_config as usize) {
+        match get_tor_provider_config_registry()?.get_mut(tor_provider_config as usize) {
             Some(tor_provider_config) => match tor_provider_config {
                 TorProviderConfig::LegacyTorClientConfig(LegacyTorClientConfig::BundledTor {
                     pluggable_transports,
                     ..
                 }) => {
-                    let pluggable_transport_config = match get_pluggable_transport_config_registry()
-                        .get(pluggable_transport_config as usize)
-                    {
-                        Some(pluggable_transport_config) => pluggable_transport_config.clone(),
-                        None => bail_invalid_handle!(pluggable_transport_config),
-                    };
+                    let pluggable_transport_config =
+                        match get_pluggable_transport_config_registry()?
+                            .get(pluggable_transport_config as usize)
+                        {
+                            Some(pluggable_transport_config) => pluggable_transport_config.clone(),
+                            None => bail_invalid_handle!(pluggable_transport_config),
+                        };
 
                     match pluggable_transports {
                         None => *pluggable_transports = Some(vec![pluggable_transport_config]),
@@ -710,13 +917,13 @@ pub unsafe extern "C" fn gosling_tor_provider_config_add_bridge_line(
         ensure_not_null!(tor_provider_config);
         ensure_not_null!(bridge_line);
 
-        match get_tor_provider_config_registry().get_mut(tor_provider_config as usize) {
+        match get_tor_provider_config_registry()?.get_mut(tor_provider_config as usize) {
             Some(tor_provider_config) => match tor_provider_config {
                 TorProviderConfig::LegacyTorClientConfig(LegacyTorClientConfig::BundledTor {
                     bridge_lines,
                     ..
                 }) => {
-                    let bridge_line = match get_bridge_line_registry().get(bridge_line as usize) {
+                    let bridge_line = match get_bridge_line_registry()?.get(bridge_line as usize) {
                         Some(bridge_line) => bridge_line.clone(),
                         None => bail_invalid_handle!(bridge_line),
                     };
@@ -753,7 +960,7 @@ pub unsafe extern "C" fn gosling_tor_provider_from_tor_provider_config(
         ensure_not_null!(tor_provider_config);
 
         let tor_provider: Box<dyn tor_provider::TorProvider> =
-            match get_tor_provider_config_registry().get(tor_provider_config as usize) {
+            match get_tor_provider_config_registry()?.get(tor_provider_config as usize) {
                 Some(tor_provider_config) => match tor_provider_config {
                     #[cfg(feature = "mock-tor-provider")]
                     TorProviderConfig::MockTorClientConfig => {
@@ -771,7 +978,7 @@ pub unsafe extern "C" fn gosling_tor_provider_from_tor_provider_config(
                 None => bail_invalid_handle!(tor_provider_config),
             };
 
-        let handle = get_tor_provider_registry().insert(tor_provider);
+        let handle = get_tor_provider_registry()?.try_insert(tor_provider)?;
         *out_tor_provider = handle as *mut GoslingTorProvider;
 
         Ok(())
@@ -82,11 +82,11 @@ pub unsafe extern "C" fn gosling_ip_address_clone(
         ensure_not_null!(out_ip_address);
         ensure_not_null!(ip_address);
 
-        let ip_address = match get_ip_addr_registry().get(ip_address as usize) {
+        let ip_address = match get_ip_addr_registry()?.get(ip_address as usize) {
             Some(ip_address) => ip_address.clone(),
             None => bail_invalid_handle!(ip_address),
         };
-        let handle = get_ip_addr_registry().insert(ip_address);
+        let handle = get_ip_addr_registry()?.try_insert(ip_address)?;
         *out_ip_address = handle as *mut GoslingIpAddress;
 
         Ok(())
@@ -109,11 +109,11 @@ pub unsafe extern "C" fn gosling_target_address_clone(
         ensure_not_null!(out_target_address);
         ensure_not_null!(target_address);
 
-        let target_address = match get_target_addr_registry().get(target_address as usize) {
+        let target_address = match get_target_addr_registry()?.get(target_address as usize) {
             Some(target_address) => target_address.clone(),
             None => bail_invalid_handle!(target_address),
         };
-        let handle = get_target_addr_registry().insert(target_address);
+        let handle = get_target_addr_registry()?.try_insert(target_address)?;
         *out_target_address = handle as *mut GoslingTargetAddress;
 
         Ok(())
@@ -145,13 +145,13 @@ pub extern "C" fn gosling_context_connect(
         ensure_not_null!(out_tcp_socket);
         ensure_not_null!(target_address);
 
-        let mut context_tuple_registry = get_context_tuple_registry();
+        let mut context_tuple_registry = get_context_tuple_registry()?;
         let context = match context_tuple_registry.get_mut(context as usize) {
             Some(context) => context,
             None => bail_invalid_handle!(context),
         };
 
-        let target_address = match get_target_addr_registry().get(target_address as usize) {
+        let target_address = match get_target_addr_registry()?.get(target_address as usize) {
             Some(target_address) => target_address.clone(),
             None => bail_invalid_handle!(target_address),
         };
@@ -197,7 +197,7 @@ pub unsafe extern "C" fn gosling_ip_address_from_ipv4(
         ensure_not_null!(out_ip_address);
 
         let ip_addr = Ipv4Addr::new(a, b, c, d);
-        let handle = get_ip_addr_registry().insert(ip_addr.into());
+        let handle = get_ip_addr_registry()?.try_insert(ip_addr.into())?;
         *out_ip_address = handle as *mut GoslingIpAddress;
 
         Ok(())
@@ -233,7 +233,7 @@ pub unsafe extern "C" fn gosling_ip_address_from_ipv6(
     translate_failures((), error, || -> anyhow::Result<()> {
         ensure_not_null!(out_ip_address);
         let ip_addr = Ipv6Addr::new(a, b, c, d, e, f, g, h);
-        let handle = get_ip_addr_registry().insert(ip_addr.into());
+        let handle = get_ip_addr_registry()?.try_insert(ip_addr.into())?;
         *out_ip_address = handle as *mut GoslingIpAddress;
 
         Ok(())
@@ -262,14 +262,14 @@ pub unsafe extern "C" fn gosling_target_address_from_ip_address(
         ensure_not_null!(out_target_address);
         ensure_not_null!(ip_address);
 
-        let ip_address = match get_ip_addr_registry().get(ip_address as usize) {
+        let ip_address = match get_ip_addr_registry()?.get(ip_address as usize) {
             Some(ip_address) => ip_address.clone(),
             None => bail_invalid_handle!(ip_address),
         };
 
         let target_address = TargetAddr::Socket(SocketAddr::new(ip_address, port));
 
-        let handle = get_target_addr_registry().insert(target_address);
+        let handle = get_target_addr_registry()?.try_insert(target_address)?;
         *out_target_address = handle as *mut GoslingTargetAddress;
 
         Ok(())
@@ -281,7 +281,8 @@ pub unsafe extern "C" fn gosling_target_address_from_ip_address(
 ///
 /// @param out_target_address: returned target address
 /// @param domain: the target domain
-/// @param domain_length: the number of chars in domain not including any null-terminator
+/// @param domain_length: the number of chars in domain not including any null-terminator, or 0 if
+///  domain is null-terminated
 /// @param port: the target port
 /// @param error: filled on error
 #[no_mangle]
@@ -296,16 +297,13 @@ pub unsafe extern "C" fn gosling_target_address_from_domain(
     translate_failures((), error, || -> anyhow::Result<()> {
         ensure_not_null!(out_target_address);
         ensure_not_null!(domain);
-        if domain_length == 0 {
-            bail!("domain_length must be greater than 0");
-        }
 
-        let domain_view = std::slice::from_raw_parts(domain as *const u8, domain_length);
-        let domain_str = std::str::from_utf8(domain_view)?;
+        let domain_str = str_from_ffi(domain, domain_length, "domain")?;
+        ensure_not_empty!(domain_str);
 
         let target_address =
             TargetAddr::Domain(DomainAddr::try_from((domain_str.to_string(), port))?);
-        let handle = get_target_addr_registry().insert(target_address);
+        let handle = get_target_addr_registry()?.try_insert(target_address)?;
         *out_target_address = handle as *mut GoslingTargetAddress;
 
         Ok(())
@@ -330,14 +328,14 @@ pub unsafe extern "C" fn gosling_target_address_from_v3_onion_service_id(
         ensure_not_null!(out_target_address);
         ensure_not_null!(service_id);
 
-        let service_id = match get_v3_onion_service_id_registry().get(service_id as usize) {
+        let service_id = match get_v3_onion_service_id_registry()?.get(service_id as usize) {
             Some(service_id) => service_id.clone(),
             None => bail_invalid_handle!(service_id),
         };
 
         let target_address =
             TargetAddr::OnionService(OnionAddr::V3(OnionAddrV3::new(service_id, port)));
-        let handle = get_target_addr_registry().insert(target_address);
+        let handle = get_target_addr_registry()?.try_insert(target_address)?;
         *out_target_address = handle as *mut GoslingTargetAddress;
 
         Ok(())
@@ -348,7 +346,8 @@ pub unsafe extern "C" fn gosling_target_address_from_v3_onion_service_id(
 ///
 /// @param out_target_address: returned target address
 /// @param target_address: serialised target address
-/// @param target_address_length: the number of chars in string not including any null-terminator
+/// @param target_address_length: the number of chars in target_address not including any
+///  null-terminator, or 0 if target_address is null-terminated
 /// @param error: filled on error
 #[no_mangle]
 #[cfg_attr(feature = "impl-lib", rename_impl)]
@@ -361,16 +360,13 @@ pub unsafe extern "C" fn gosling_target_address_from_string(
     translate_failures((), error, || -> anyhow::Result<()> {
         ensure_not_null!(out_target_address);
         ensure_not_null!(target_address);
-        if target_address_length == 0 {
-            bail!("target_address_length must be greater than 0");
-        }
 
-        let target_address_view =
-            std::slice::from_raw_parts(target_address as *const u8, target_address_length);
-        let target_address_str = std::str::from_utf8(target_address_view)?;
+        let target_address_str =
+            str_from_ffi(target_address, target_address_length, "target_address")?;
+        ensure_not_empty!(target_address_str);
 
         let target_address = TargetAddr::from_str(target_address_str)?;
-        let handle = get_target_addr_registry().insert(target_address);
+        let handle = get_target_addr_registry()?.try_insert(target_address)?;
         *out_target_address = handle as *mut GoslingTargetAddress;
 
         Ok(())
@@ -396,7 +392,7 @@ pub unsafe extern "C" fn gosling_target_address_to_string(
         ensure_not_null!(target_address);
         ensure_not_null!(out_target_address_string);
 
-        let target_address_string = match get_target_addr_registry().get(target_address as usize) {
+        let target_address_string = match get_target_addr_registry()?.get(target_address as usize) {
             Some(target_address) => target_address.to_string(),
             None => bail_invalid_handle!(target_address),
         };
@@ -446,7 +442,7 @@ pub unsafe extern "C" fn gosling_context_generate_circuit_token(
     translate_failures(!0usize, error, || -> anyhow::Result<CircuitToken> {
         ensure_not_null!(context);
 
-        let mut context_tuple_registry = get_context_tuple_registry();
+        let mut context_tuple_registry = get_context_tuple_registry()?;
         let token = match context_tuple_registry.get_mut(context as usize) {
             Some(context) => context.0.generate_circuit_token(),
             None => bail_invalid_handle!(context),
@@ -470,7 +466,7 @@ pub unsafe extern "C" fn gosling_context_release_circuit_token(
     translate_failures((), error, || -> anyhow::Result<()> {
         ensure_not_null!(context);
 
-        let mut context_tuple_registry = get_context_tuple_registry();
+        let mut context_tuple_registry = get_context_tuple_registry()?;
         match context_tuple_registry.get_mut(context as usize) {
             Some(context) => context.0.release_circuit_token(circuit_token),
             None => bail_invalid_handle!(context),
@@ -1,7 +1,7 @@
 // standard
 use std::ffi::{CStr, CString};
-use std::io::{BufRead, BufReader, Write};
-use std::net::TcpStream;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
 use std::os::raw::c_char;
 #[cfg(unix)]
 use std::os::unix::io::{FromRawFd, RawFd};
@@ -9,6 +9,10 @@ use std::os::unix::io::{FromRawFd, RawFd};
 use std::os::windows::io::{FromRawSocket, RawSocket};
 use std::ptr;
 use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(feature = "mock-tor-provider")]
+use std::sync::atomic::{AtomicU32, AtomicUsize};
+#[cfg(feature = "mock-tor-provider")]
+use std::time::Duration;
 
 // external crates
 use anyhow::bail;
@@ -21,6 +25,8 @@ use cgosling::context::*;
 use cgosling::crypto::*;
 use cgosling::error::*;
 use cgosling::ffi::*;
+use cgosling::logging::*;
+use cgosling::stream::*;
 use cgosling::tor_provider::*;
 
 macro_rules! require_noerror {
@@ -292,6 +298,18 @@ fn test_gosling_ffi_handshake_legacy_client() -> anyhow::Result<()> {
         )
     );
 
+    // onion-service connections use hostnames so are allowed by a safe SOCKS policy
+    let reject_plaintext_ports: [u16; 2] = [23, 110];
+    require_noerror!(gosling_tor_provider_config_set_socks_policy(
+        alice_tor_provider_config,
+        true,
+        false,
+        ptr::null(),
+        0usize,
+        reject_plaintext_ports.as_ptr(),
+        reject_plaintext_ports.len()
+    ));
+
     let mut alice_tor_provider: *mut GoslingTorProvider = ptr::null_mut();
     require_noerror!(gosling_tor_provider_from_tor_provider_config(
         &mut alice_tor_provider,
@@ -761,13 +779,15 @@ fn test_gosling_ffi_handshake_impl(
         println!("--- pat begin identity handshake attempt {}", k);
 
         let mut error: *mut GoslingError = ptr::null_mut();
-        gosling_context_begin_identity_handshake(
-            pat_context,
-            alice_identity,
-            ENDPOINT_NAME.as_ptr(),
-            ENDPOINT_NAME.to_bytes().len(),
-            &mut error,
-        );
+        unsafe {
+            gosling_context_begin_identity_handshake(
+                pat_context,
+                alice_identity,
+                ENDPOINT_NAME.as_ptr(),
+                ENDPOINT_NAME.to_bytes().len(),
+                &mut error,
+            );
+        }
 
         if error.is_null() {
             pat_begin_identity_handshake_succeeded = true;
@@ -1032,3 +1052,576 @@ fn test_gosling_ffi_handshake_impl(
 
     Ok(())
 }
+
+#[test]
+#[serial]
+fn test_gosling_ffi_stream() -> anyhow::Result<()> {
+    let library = test_gosling_ffi_handshake_preamble()?;
+
+    let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0u16)))?;
+    let alice_stream = TcpStream::connect(listener.local_addr()?)?;
+    let (mut pat_stream, _socket_addr) = listener.accept()?;
+
+    println!("--- gosling adopts alice's socket");
+    #[cfg(unix)]
+    let alice_socket = std::os::unix::io::IntoRawFd::into_raw_fd(alice_stream);
+    #[cfg(windows)]
+    let alice_socket = std::os::windows::io::IntoRawSocket::into_raw_socket(alice_stream);
+
+    let mut alice: *mut GoslingStream = ptr::null_mut();
+    require_noerror!(gosling_stream_from_tcp_socket(&mut alice, alice_socket));
+    require_noerror!(gosling_stream_set_read_timeout(alice, 1000));
+    require_noerror!(gosling_stream_set_write_timeout(alice, 1000));
+
+    let mut socket: GoslingTcpSocket = Default::default();
+    require_noerror!(gosling_stream_get_tcp_socket(&mut socket, alice));
+    assert_eq!(socket, alice_socket);
+
+    println!("--- alice duplicates her stream");
+    let mut alice_clone: *mut GoslingStream = ptr::null_mut();
+    require_noerror!(gosling_stream_clone(&mut alice_clone, alice));
+    require_noerror!(gosling_stream_get_tcp_socket(&mut socket, alice_clone));
+    assert_ne!(socket, alice_socket);
+
+    println!("--- alice writes through the duplicate and shuts down");
+    let mut alice_clone_socket: GoslingTcpSocket = Default::default();
+    require_noerror!(gosling_stream_into_tcp_socket(
+        &mut alice_clone_socket,
+        alice_clone
+    ));
+    #[cfg(unix)]
+    let mut alice_clone_stream =
+        unsafe { <TcpStream as FromRawFd>::from_raw_fd(alice_clone_socket) };
+    #[cfg(windows)]
+    let mut alice_clone_stream =
+        unsafe { <TcpStream as FromRawSocket>::from_raw_socket(alice_clone_socket) };
+    alice_clone_stream.write_all(b"Hello Pat!")?;
+    require_noerror!(gosling_stream_shutdown(alice, STREAM_SHUTDOWN_WRITE));
+
+    println!("--- pat reads until end-of-stream");
+    let mut pat_read_string: String = Default::default();
+    pat_stream.read_to_string(&mut pat_read_string)?;
+    assert_eq!(pat_read_string, "Hello Pat!");
+
+    println!("--- invalid arguments are rejected");
+    let mut error: *mut GoslingError = ptr::null_mut();
+    gosling_stream_shutdown(alice, 3, &mut error);
+    assert!(!error.is_null());
+    gosling_error_free(error);
+
+    gosling_stream_free(alice);
+    gosling_library_free(library);
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_gosling_ffi_error_codes() -> anyhow::Result<()> {
+    let library = test_gosling_ffi_handshake_preamble()?;
+
+    println!("--- null and generic errors have the generic error code");
+    assert_eq!(gosling_error_get_code(ptr::null()), ERROR_CODE_GENERIC);
+    unsafe {
+        let mut service_id: *mut GoslingV3OnionServiceId = ptr::null_mut();
+        let mut error: *mut GoslingError = ptr::null_mut();
+        gosling_v3_onion_service_id_from_string(&mut service_id, ptr::null(), 0, &mut error);
+        assert!(!error.is_null());
+        assert_eq!(gosling_error_get_code(error), ERROR_CODE_GENERIC);
+        gosling_error_free(error);
+    }
+
+    #[cfg(feature = "legacy-tor-provider")]
+    {
+        println!("--- a missing tor binary has its own error code");
+        let tor_bin_path: CString = CString::new("/nonexistent/cgosling/tor")?;
+        let mut working_dir = std::env::temp_dir();
+        working_dir.push("cgosling_error_codes_test");
+        let working_dir: CString = CString::new(working_dir.to_str().unwrap())?;
+
+        let mut tor_provider_config: *mut GoslingTorProviderConfig = ptr::null_mut();
+        require_noerror!(
+            gosling_tor_provider_config_new_bundled_legacy_client_config(
+                &mut tor_provider_config,
+                tor_bin_path.as_ptr(),
+                tor_bin_path.as_bytes().len(),
+                working_dir.as_ptr(),
+                working_dir.as_bytes().len()
+            )
+        );
+
+        let mut tor_provider: *mut GoslingTorProvider = ptr::null_mut();
+        let mut error: *mut GoslingError = ptr::null_mut();
+        unsafe {
+            gosling_tor_provider_from_tor_provider_config(
+                &mut tor_provider,
+                tor_provider_config,
+                &mut error,
+            );
+        }
+        assert!(tor_provider.is_null());
+        assert!(!error.is_null());
+        assert_eq!(
+            gosling_error_get_code(error),
+            ERROR_CODE_TOR_BINARY_NOT_FOUND
+        );
+
+        // cloned errors keep their code
+        let mut cloned_error: *mut GoslingError = ptr::null_mut();
+        require_noerror!(gosling_error_clone(&mut cloned_error, error));
+        assert_eq!(
+            gosling_error_get_code(cloned_error),
+            ERROR_CODE_TOR_BINARY_NOT_FOUND
+        );
+        gosling_error_free(cloned_error);
+        gosling_error_free(error);
+        gosling_tor_provider_config_free(tor_provider_config);
+    }
+
+    gosling_library_free(library);
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_gosling_ffi_strings() -> anyhow::Result<()> {
+    let library = test_gosling_ffi_handshake_preamble()?;
+
+    let service_id_string = "6l62fw7tqctlu5fesdqukvpoxezkaxbzllrafa2ve6ewuhzphxczsjyd";
+    let service_id_cstring = CString::new(service_id_string)?;
+
+    println!("--- strings with explicit lengths are accepted");
+    let mut service_id: *mut GoslingV3OnionServiceId = ptr::null_mut();
+    require_noerror!(gosling_v3_onion_service_id_from_string(
+        &mut service_id,
+        service_id_string.as_ptr() as *const c_char,
+        service_id_string.len()
+    ));
+    gosling_v3_onion_service_id_free(service_id);
+
+    println!("--- null-terminated strings are accepted");
+    let mut service_id: *mut GoslingV3OnionServiceId = ptr::null_mut();
+    require_noerror!(gosling_v3_onion_service_id_from_string(
+        &mut service_id,
+        service_id_cstring.as_ptr(),
+        0
+    ));
+
+    let mut service_id_buffer = [0 as c_char; 57];
+    require_noerror!(gosling_v3_onion_service_id_to_string(
+        service_id,
+        service_id_buffer.as_mut_ptr(),
+        service_id_buffer.len()
+    ));
+    let service_id_buffer = unsafe { CStr::from_ptr(service_id_buffer.as_ptr()) };
+    assert_eq!(service_id_buffer.to_str()?, service_id_string);
+    gosling_v3_onion_service_id_free(service_id);
+
+    let mut error: *mut GoslingError = ptr::null_mut();
+    assert!(unsafe {
+        gosling_string_is_valid_v3_onion_service_id(service_id_cstring.as_ptr(), 0, &mut error)
+    });
+    assert!(error.is_null());
+
+    println!("--- strings with null bytes are rejected");
+    let service_id_bytes = service_id_cstring.as_bytes_with_nul();
+    unsafe {
+        let mut service_id: *mut GoslingV3OnionServiceId = ptr::null_mut();
+        let mut error: *mut GoslingError = ptr::null_mut();
+        gosling_v3_onion_service_id_from_string(
+            &mut service_id,
+            service_id_bytes.as_ptr() as *const c_char,
+            service_id_bytes.len(),
+            &mut error,
+        );
+        assert!(service_id.is_null());
+        assert!(!error.is_null());
+        gosling_error_free(error);
+    }
+
+    println!("--- non-ascii strings are rejected");
+    let non_ascii_cstring = CString::new("ED25519-V3:ö")?;
+    unsafe {
+        let mut private_key: *mut GoslingEd25519PrivateKey = ptr::null_mut();
+        let mut error: *mut GoslingError = ptr::null_mut();
+        gosling_ed25519_private_key_from_keyblob(
+            &mut private_key,
+            non_ascii_cstring.as_ptr(),
+            0,
+            &mut error,
+        );
+        assert!(private_key.is_null());
+        assert!(!error.is_null());
+        gosling_error_free(error);
+    }
+
+    gosling_library_free(library);
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_gosling_ffi_handle_limits() -> anyhow::Result<()> {
+    let library = test_gosling_ffi_handshake_preamble()?;
+
+    let handle_count = |handle_type: u32| -> anyhow::Result<usize> {
+        let mut handle_counts = [0usize; HANDLE_TYPE_COUNT];
+        require_noerror!(gosling_library_get_stats(
+            library,
+            handle_counts.as_mut_ptr(),
+            handle_counts.len()
+        ));
+        Ok(handle_counts[handle_type as usize])
+    };
+
+    println!("--- live handles are counted by type");
+    assert_eq!(handle_count(HANDLE_TYPE_ED25519_PRIVATE_KEY)?, 0);
+    let mut private_key1: *mut GoslingEd25519PrivateKey = ptr::null_mut();
+    require_noerror!(gosling_ed25519_private_key_generate(&mut private_key1));
+    let mut private_key2: *mut GoslingEd25519PrivateKey = ptr::null_mut();
+    require_noerror!(gosling_ed25519_private_key_generate(&mut private_key2));
+    assert_eq!(handle_count(HANDLE_TYPE_ED25519_PRIVATE_KEY)?, 2);
+    assert_eq!(handle_count(HANDLE_TYPE_V3_ONION_SERVICE_ID)?, 0);
+
+    println!("--- new handles fail once the limit is reached");
+    require_noerror!(gosling_library_set_handle_limit(
+        library,
+        HANDLE_TYPE_ED25519_PRIVATE_KEY,
+        2
+    ));
+    unsafe {
+        let mut private_key: *mut GoslingEd25519PrivateKey = ptr::null_mut();
+        let mut error: *mut GoslingError = ptr::null_mut();
+        gosling_ed25519_private_key_clone(&mut private_key, private_key1, &mut error);
+        assert!(private_key.is_null());
+        assert!(!error.is_null());
+        assert_eq!(
+            gosling_error_get_code(error),
+            ERROR_CODE_HANDLE_LIMIT_EXCEEDED
+        );
+        gosling_error_free(error);
+    }
+    assert_eq!(handle_count(HANDLE_TYPE_ED25519_PRIVATE_KEY)?, 2);
+
+    println!("--- freeing handles makes room for new ones");
+    gosling_ed25519_private_key_free(private_key2);
+    require_noerror!(gosling_ed25519_private_key_clone(
+        &mut private_key2,
+        private_key1
+    ));
+
+    println!("--- a limit of 0 removes the limit");
+    require_noerror!(gosling_library_set_handle_limit(
+        library,
+        HANDLE_TYPE_ED25519_PRIVATE_KEY,
+        0
+    ));
+    let mut private_key3: *mut GoslingEd25519PrivateKey = ptr::null_mut();
+    require_noerror!(gosling_ed25519_private_key_clone(
+        &mut private_key3,
+        private_key1
+    ));
+    assert_eq!(handle_count(HANDLE_TYPE_ED25519_PRIVATE_KEY)?, 3);
+
+    println!("--- error handles and unknown handle types cannot be limited");
+    for handle_type in [HANDLE_TYPE_ERROR, HANDLE_TYPE_COUNT as u32] {
+        let mut error: *mut GoslingError = ptr::null_mut();
+        gosling_library_set_handle_limit(library, handle_type, 1, &mut error);
+        assert!(!error.is_null());
+        gosling_error_free(error);
+    }
+
+    gosling_ed25519_private_key_free(private_key1);
+    gosling_ed25519_private_key_free(private_key2);
+    gosling_ed25519_private_key_free(private_key3);
+    assert_eq!(handle_count(HANDLE_TYPE_ED25519_PRIVATE_KEY)?, 0);
+
+    gosling_library_free(library);
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_gosling_ffi_api_spec() -> anyhow::Result<()> {
+    let library = test_gosling_ffi_handshake_preamble()?;
+
+    println!("--- api spec describes the exported functions");
+    let api_spec = unsafe {
+        let mut error: *mut GoslingError = ptr::null_mut();
+        let api_spec = gosling_library_get_api_spec(library, &mut error);
+        assert!(error.is_null());
+        assert!(!api_spec.is_null());
+        CStr::from_ptr(api_spec)
+    };
+    let api_spec = api_spec.to_str()?;
+    assert!(api_spec.starts_with('{'));
+    assert!(api_spec.contains("\"gosling_library_get_api_spec\""));
+    assert!(api_spec.contains("\"ownership\""));
+
+    gosling_library_free(library);
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn test_gosling_ffi_library_options() -> anyhow::Result<()> {
+    println!("--- version may be queried before init");
+    let (mut major, mut minor, mut patch) = (0u32, 0u32, 0u32);
+    require_noerror!(gosling_library_get_version(
+        &mut major, &mut minor, &mut patch
+    ));
+    assert_eq!(
+        format!("{}.{}.{}", major, minor, patch),
+        env!("CARGO_PKG_VERSION")
+    );
+
+    println!("--- options are validated");
+    let mut options: *mut GoslingLibraryOptions = ptr::null_mut();
+    require_noerror!(gosling_library_options_new(&mut options));
+    let mut error: *mut GoslingError = ptr::null_mut();
+    gosling_library_options_set_panic_behavior(options, PANIC_BEHAVIOR_ABORT + 1, &mut error);
+    assert!(!error.is_null());
+    gosling_error_free(error);
+
+    let mut error: *mut GoslingError = ptr::null_mut();
+    gosling_library_options_set_log_callback(options, LOG_LEVEL_TRACE + 1, None, &mut error);
+    assert!(!error.is_null());
+    gosling_error_free(error);
+
+    extern "C" fn log_callback(
+        log_level: u32,
+        target: *const c_char,
+        target_length: usize,
+        message: *const c_char,
+        message_length: usize,
+    ) {
+        assert!(log_level > LOG_LEVEL_OFF && log_level <= LOG_LEVEL_DEBUG);
+        assert_eq!(
+            unsafe { CStr::from_ptr(target) }.to_bytes().len(),
+            target_length
+        );
+        assert_eq!(
+            unsafe { CStr::from_ptr(message) }.to_bytes().len(),
+            message_length
+        );
+    }
+    require_noerror!(gosling_library_options_set_panic_behavior(
+        options,
+        PANIC_BEHAVIOR_RETURN_ERROR
+    ));
+    require_noerror!(gosling_library_options_set_log_callback(
+        options,
+        LOG_LEVEL_DEBUG,
+        Some(log_callback)
+    ));
+
+    println!("--- init with options");
+    let mut library: *mut GoslingLibrary = ptr::null_mut();
+    require_noerror!(gosling_library_init_with_options(options, &mut library));
+    gosling_library_options_free(options);
+    unsafe {
+        let mut second_library: *mut GoslingLibrary = ptr::null_mut();
+        let mut error: *mut GoslingError = ptr::null_mut();
+        gosling_library_init(&mut second_library, &mut error);
+        assert!(second_library.is_null());
+        assert!(!error.is_null());
+        gosling_error_free(error);
+    }
+
+    println!("--- options are reset once the library is freed");
+    gosling_library_free(library);
+    let library = test_gosling_ffi_handshake_preamble()?;
+    gosling_library_free(library);
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+#[cfg(feature = "mock-tor-provider")]
+fn test_gosling_ffi_threading() -> anyhow::Result<()> {
+    let library = test_gosling_ffi_handshake_preamble()?;
+
+    let mut tor_provider_config: *mut GoslingTorProviderConfig = ptr::null_mut();
+    require_noerror!(gosling_tor_provider_config_new_mock_client_config(
+        &mut tor_provider_config
+    ));
+    let mut tor_provider: *mut GoslingTorProvider = ptr::null_mut();
+    require_noerror!(gosling_tor_provider_from_tor_provider_config(
+        &mut tor_provider,
+        tor_provider_config
+    ));
+
+    let mut private_key: *mut GoslingEd25519PrivateKey = ptr::null_mut();
+    require_noerror!(gosling_ed25519_private_key_generate(&mut private_key));
+    let mut context: *mut GoslingContext = ptr::null_mut();
+    require_noerror!(gosling_context_init(
+        &mut context,
+        tor_provider,
+        420,
+        420,
+        private_key
+    ));
+
+    println!("--- calls from callbacks which hold the context fail rather than deadlock");
+    static VALIDATOR_ERROR_CODE: AtomicU32 = AtomicU32::new(ERROR_CODE_GENERIC);
+    extern "C" fn endpoint_validator_callback(
+        context: *mut GoslingContext,
+        _identity_service_id: *const GoslingV3OnionServiceId,
+        _endpoint_name: *const c_char,
+        _endpoint_name_length: usize,
+    ) -> u32 {
+        let mut error: *mut GoslingError = ptr::null_mut();
+        gosling_context_get_next_deadline(context, &mut error);
+        VALIDATOR_ERROR_CODE.store(gosling_error_get_code(error), Ordering::Relaxed);
+        gosling_error_free(error);
+        ENDPOINT_VALIDATOR_VERDICT_ALLOW
+    }
+    require_noerror!(
+        gosling_context_set_identity_client_endpoint_validator_callback(
+            context,
+            Some(endpoint_validator_callback)
+        )
+    );
+
+    let mut server_private_key: *mut GoslingEd25519PrivateKey = ptr::null_mut();
+    require_noerror!(gosling_ed25519_private_key_generate(
+        &mut server_private_key
+    ));
+    let mut server_identity: *mut GoslingV3OnionServiceId = ptr::null_mut();
+    require_noerror!(gosling_v3_onion_service_id_from_ed25519_private_key(
+        &mut server_identity,
+        server_private_key
+    ));
+    // the handshake itself fails as tor has not bootstrapped
+    let mut error: *mut GoslingError = ptr::null_mut();
+    unsafe {
+        gosling_context_begin_identity_handshake(
+            context,
+            server_identity,
+            ENDPOINT_NAME.as_ptr(),
+            0,
+            &mut error,
+        );
+    }
+    assert!(!error.is_null());
+    gosling_error_free(error);
+    assert_eq!(
+        VALIDATOR_ERROR_CODE.load(Ordering::Relaxed),
+        ERROR_CODE_REENTRANT_CALL
+    );
+
+    // the context is usable again once the callback returns
+    require_noerror!(
+        gosling_context_set_identity_client_endpoint_validator_callback(context, None)
+    );
+
+    println!("--- callbacks cannot poll their own context");
+    static BOOTSTRAP_COMPLETE: AtomicBool = AtomicBool::new(false);
+    static POLL_ERROR_CODE: AtomicU32 = AtomicU32::new(ERROR_CODE_GENERIC);
+    extern "C" fn bootstrap_completed_callback(context: *mut GoslingContext) -> () {
+        let mut error: *mut GoslingError = ptr::null_mut();
+        gosling_context_poll_events(context, &mut error);
+        POLL_ERROR_CODE.store(gosling_error_get_code(error), Ordering::Relaxed);
+        gosling_error_free(error);
+        BOOTSTRAP_COMPLETE.store(true, Ordering::Relaxed);
+    }
+    require_noerror!(gosling_context_set_tor_bootstrap_completed_callback(
+        context,
+        Some(bootstrap_completed_callback)
+    ));
+
+    println!("--- contexts may be polled from several threads at once");
+    static IN_CALLBACK: AtomicBool = AtomicBool::new(false);
+    static OVERLAPPING_CALLBACKS: AtomicUsize = AtomicUsize::new(0);
+    extern "C" fn bootstrap_status_received_callback(
+        _context: *mut GoslingContext,
+        _progress: u32,
+        _tag: *const c_char,
+        _tag_length: usize,
+        _summary: *const c_char,
+        _summary_length: usize,
+    ) -> () {
+        if IN_CALLBACK.swap(true, Ordering::SeqCst) {
+            OVERLAPPING_CALLBACKS.fetch_add(1, Ordering::SeqCst);
+        }
+        std::thread::yield_now();
+        IN_CALLBACK.store(false, Ordering::SeqCst);
+    }
+
+    require_noerror!(gosling_context_set_tor_bootstrap_status_received_callback(
+        context,
+        Some(bootstrap_status_received_callback)
+    ));
+    require_noerror!(gosling_context_bootstrap_tor(context));
+
+    let context_handle = context as usize;
+    let (done_sender, done_receiver) = std::sync::mpsc::channel();
+    let workers: Vec<_> = (0..4)
+        .map(|_| {
+            let done_sender = done_sender.clone();
+            std::thread::spawn(move || {
+                let context = context_handle as *mut GoslingContext;
+                let result = (|| -> anyhow::Result<()> {
+                    // keep polling for a while after bootstrap completes
+                    let mut iterations = 0;
+                    while !BOOTSTRAP_COMPLETE.load(Ordering::Relaxed) || iterations < 100 {
+                        require_noerror!(gosling_context_poll_events(context));
+                        require_noerror!(gosling_context_get_next_deadline(context));
+                        require_noerror!(
+                            gosling_context_set_tor_bootstrap_status_received_callback(
+                                context,
+                                Some(bootstrap_status_received_callback)
+                            )
+                        );
+                        iterations += 1;
+                    }
+                    Ok(())
+                })();
+                let _ = done_sender.send(result);
+            })
+        })
+        .collect();
+    drop(done_sender);
+
+    for _ in 0..workers.len() {
+        match done_receiver.recv_timeout(Duration::from_secs(60)) {
+            Ok(result) => result?,
+            Err(_) => bail!("threads polling the context deadlocked"),
+        }
+    }
+    for worker in workers {
+        if worker.join().is_err() {
+            bail!("polling thread panicked");
+        }
+    }
+    assert_eq!(
+        POLL_ERROR_CODE.load(Ordering::Relaxed),
+        ERROR_CODE_REENTRANT_CALL
+    );
+    assert_eq!(OVERLAPPING_CALLBACKS.load(Ordering::SeqCst), 0);
+
+    gosling_v3_onion_service_id_free(server_identity);
+    gosling_ed25519_private_key_free(server_private_key);
+    gosling_ed25519_private_key_free(private_key);
+    gosling_context_free(context);
+    gosling_library_free(library);
+
+    Ok(())
+}
+
+#[test]
+fn test_gosling_ffi_name_lengths() {
+    // the limits exported to C must match those enforced by the protocol
+    assert_eq!(
+        MAX_ENDPOINT_NAME_LENGTH,
+        gosling::protocol::MAX_ENDPOINT_NAME_LENGTH
+    );
+    assert_eq!(
+        MAX_CHANNEL_NAME_LENGTH,
+        gosling::protocol::MAX_CHANNEL_NAME_LENGTH
+    );
+}
@@ -0,0 +1,151 @@
+// Measures the heap allocations made by Context::update() and
+// Context::update_into() while many identity handshakes are in progress.
+//
+// Run with: cargo bench --bench update
+
+// standard
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+// extern crates
+use tor_interface::mock_tor_client::*;
+use tor_interface::tor_crypto::*;
+
+// internal crates
+use gosling::context::*;
+
+const ITERATIONS: usize = 1024;
+
+// counts every allocation made by the process
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+fn new_context(private_key: Ed25519PrivateKey) -> anyhow::Result<Context> {
+    let mut context = Context::new(
+        Box::new(MockTorClient::new()),
+        420,
+        420,
+        Duration::from_secs(600),
+        4096,
+        Some(Duration::from_secs(600)),
+        private_key,
+    )?;
+    context.bootstrap()?;
+    Ok(context)
+}
+
+// update both contexts until done returns true for an event of the first
+fn run_until<F>(server: &mut Context, client: &mut Context, mut done: F) -> anyhow::Result<()>
+where
+    F: FnMut(&mut Context, ContextEvent) -> anyhow::Result<bool>,
+{
+    let mut finished = false;
+    while !finished {
+        for event in server.update()? {
+            finished |= done(server, event)?;
+        }
+        client.update()?;
+    }
+    Ok(())
+}
+
+// returns a server context with handshakes identity handshakes waiting for the
+// application to answer their endpoint requests, and their client context
+fn stalled_handshakes(handshakes: usize) -> anyhow::Result<(Context, Context)> {
+    let server_private_key = Ed25519PrivateKey::generate();
+    let server_service_id = V3OnionServiceId::from_private_key(&server_private_key);
+    let mut server = new_context(server_private_key)?;
+    let mut client = new_context(Ed25519PrivateKey::generate())?;
+    run_until(&mut server, &mut client, |server, event| match event {
+        ContextEvent::TorBootstrapCompleted => {
+            server.identity_server_start()?;
+            Ok(false)
+        }
+        ContextEvent::IdentityServerPublished => Ok(true),
+        _ => Ok(false),
+    })?;
+
+    for _ in 0..handshakes {
+        client.identity_client_begin_handshake(
+            server_service_id.clone(),
+            "bench_endpoint".parse()?,
+        )?;
+    }
+    let mut requests = 0usize;
+    run_until(&mut server, &mut client, |_server, event| {
+        if let ContextEvent::IdentityServerEndpointRequestReceived { .. } = event {
+            requests += 1;
+        }
+        Ok(requests == handshakes)
+    })?;
+
+    Ok((server, client))
+}
+
+// returns (allocations per update, time per update)
+fn run(
+    handshakes: usize,
+    budget: Option<usize>,
+    reuse_events: bool,
+) -> anyhow::Result<(f64, Duration)> {
+    let (mut server, _client) = stalled_handshakes(handshakes)?;
+    server.set_handshake_update_budget(budget)?;
+
+    let mut events: VecDeque<ContextEvent> = Default::default();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        if reuse_events {
+            server.update_into(&mut events)?;
+            events.clear();
+        } else {
+            server.update()?;
+        }
+    }
+    let elapsed = start.elapsed();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+
+    Ok((
+        allocations as f64 / ITERATIONS as f64,
+        elapsed / ITERATIONS as u32,
+    ))
+}
+
+fn main() -> anyhow::Result<()> {
+    println!("{ITERATIONS} updates of a context with stalled identity handshakes");
+    for handshakes in [16, 256] {
+        for budget in [None, Some(handshakes / 4)] {
+            for (name, reuse_events) in [("update", false), ("update_into", true)] {
+                let (allocations, elapsed) = run(handshakes, budget, reuse_events)?;
+                let budget = budget.map_or("none".to_string(), |budget| budget.to_string());
+                println!(
+                    "{handshakes:>5} handshakes, budget {budget:>4}, {name:>11}: {allocations:>8.2} allocations {elapsed:>10.2?} per update"
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,521 @@
+// A minimal peer-to-peer echo chat built directly on the gosling Rust API.
+//
+// A host runs an identity server and grants each client which completes an
+// identity handshake its own endpoint server; every line a connected client
+// sends is echoed back. A client requests an endpoint from a host (or re-uses
+// a previously saved grant), connects to it and sends each line read from
+// stdin.
+//
+// Keys, granted endpoints and tor's state are persisted in the data directory
+// so subsequent runs skip the identity handshake.
+//
+// usage: see USAGE
+
+// standard
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+// extern crates
+use anyhow::{bail, Result};
+use bson::doc;
+use gosling::context::*;
+use gosling::endpoint_grant::EndpointGrant;
+use tor_interface::legacy_tor_client::*;
+use tor_interface::tor_crypto::*;
+
+const IDENTITY_PORT: u16 = 1120;
+const ENDPOINT_PORT: u16 = 401;
+const ENDPOINT_NAME: &str = "gosling-chat";
+const CHANNEL_NAME: &str = "echo";
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(120);
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+enum Command {
+    Host,
+    Connect(V3OnionServiceId),
+}
+
+struct Args {
+    data_dir: PathBuf,
+    tor_bin: Option<PathBuf>,
+    identity_key: Option<PathBuf>,
+    timeout: Duration,
+    command: Command,
+}
+
+const USAGE: &str = "\
+usage: gosling-chat --data-dir <DIR> [OPTIONS] <COMMAND>
+
+commands:
+  host                           serve an identity server and echo lines from connected clients
+  connect <IDENTITY_SERVICE_ID>  connect to a host and send it lines read from stdin
+
+options:
+  --data-dir <DIR>       directory for keys, granted endpoints and tor's state
+  --identity-key <FILE>  ed25519 identity key blob, generated if missing (default: <DIR>/identity.key)
+  --tor-bin <PATH>       tor binary to launch (default: tor found in PATH)
+  --timeout <SECONDS>    abort handshakes which take longer than this (default: 120)";
+
+fn parse_args() -> Result<Args> {
+    let mut data_dir: Option<PathBuf> = None;
+    let mut tor_bin: Option<PathBuf> = None;
+    let mut identity_key: Option<PathBuf> = None;
+    let mut timeout = DEFAULT_TIMEOUT;
+    let mut command: Option<Command> = None;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| -> Result<String> {
+            match args.next() {
+                Some(value) => Ok(value),
+                None => bail!("{name} requires a value"),
+            }
+        };
+        match arg.as_str() {
+            "--data-dir" => data_dir = Some(value("--data-dir")?.into()),
+            "--tor-bin" => tor_bin = Some(value("--tor-bin")?.into()),
+            "--identity-key" => identity_key = Some(value("--identity-key")?.into()),
+            "--timeout" => timeout = Duration::from_secs(value("--timeout")?.parse()?),
+            "host" => command = Some(Command::Host),
+            "connect" => {
+                let identity_service_id = value("connect")?;
+                command = Some(Command::Connect(V3OnionServiceId::from_string(
+                    &identity_service_id,
+                )?));
+            }
+            "--help" | "-h" => {
+                println!("{USAGE}");
+                std::process::exit(0);
+            }
+            arg => bail!("unexpected argument: {arg}"),
+        }
+    }
+
+    match (data_dir, command) {
+        (Some(data_dir), Some(command)) => Ok(Args {
+            data_dir,
+            tor_bin,
+            identity_key,
+            timeout,
+            command,
+        }),
+        _ => {
+            eprintln!("{USAGE}");
+            bail!("--data-dir and a command are required");
+        }
+    }
+}
+
+fn load_or_generate_identity_key(path: &Path) -> Result<Ed25519PrivateKey> {
+    if path.exists() {
+        let key_blob = std::fs::read_to_string(path)?;
+        Ok(Ed25519PrivateKey::from_key_blob(key_blob.trim())?)
+    } else {
+        println!("generating new identity key: {}", path.display());
+        let identity_key = Ed25519PrivateKey::generate();
+        std::fs::write(path, identity_key.to_key_blob())?;
+        Ok(identity_key)
+    }
+}
+
+// update the context until tor has bootstrapped
+fn bootstrap(context: &mut Context) -> Result<()> {
+    context.bootstrap()?;
+    loop {
+        for event in context.update()?.drain(..) {
+            match event {
+                ContextEvent::TorBootstrapStatusReceived {
+                    progress, summary, ..
+                } => println!("bootstrap progress: {progress}% - {summary}"),
+                ContextEvent::TorBootstrapCompleted => return Ok(()),
+                _ => (),
+            }
+        }
+        std::thread::sleep(context.next_deadline().unwrap_or(POLL_INTERVAL));
+    }
+}
+
+// a line-oriented connection over a non-blocking endpoint channel
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    // partial line read before the stream would have blocked
+    pending: String,
+}
+
+impl Connection {
+    fn new(stream: TcpStream) -> Result<Self> {
+        stream.set_nonblocking(true)?;
+        Ok(Self {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+            pending: Default::default(),
+        })
+    }
+
+    // returns Ok(None) until a complete line has been received
+    fn read_line(&mut self) -> std::io::Result<Option<String>> {
+        match self.reader.read_line(&mut self.pending) {
+            Ok(0) => Err(ErrorKind::UnexpectedEof.into()),
+            Ok(_) => Ok(Some(std::mem::take(&mut self.pending))),
+            Err(err) if err.kind() == ErrorKind::WouldBlock => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+fn main() -> Result<()> {
+    let args = parse_args()?;
+
+    std::fs::create_dir_all(&args.data_dir)?;
+    let identity_key_path = match &args.identity_key {
+        Some(identity_key) => identity_key.clone(),
+        None => args.data_dir.join("identity.key"),
+    };
+    let identity_key = load_or_generate_identity_key(&identity_key_path)?;
+    let identity_service_id = V3OnionServiceId::from_private_key(&identity_key);
+    println!("identity service id: {identity_service_id}");
+
+    let tor_bin_path = match &args.tor_bin {
+        Some(tor_bin) => tor_bin.clone(),
+        None => which::which("tor")?,
+    };
+    let tor_config = LegacyTorClientConfig::BundledTor {
+        tor_bin_path,
+        data_directory: args.data_dir.join("tor"),
+        proxy_settings: None,
+        allowed_ports: None,
+        pluggable_transports: None,
+        bridge_lines: None,
+        sandbox: None,
+        security_config: None,
+        socks_policy: None,
+        shared_network_cache: None,
+    };
+    let tor_client = Box::new(LegacyTorClient::new(tor_config)?);
+
+    let mut context = Context::new(
+        tor_client,
+        IDENTITY_PORT,
+        ENDPOINT_PORT,
+        args.timeout,
+        4096,
+        Some(args.timeout),
+        identity_key,
+    )?;
+    bootstrap(&mut context)?;
+
+    match args.command {
+        Command::Host => host(&mut context, &args.data_dir),
+        Command::Connect(host_service_id) => connect(
+            &mut context,
+            &args.data_dir,
+            identity_service_id,
+            host_service_id,
+            args.timeout,
+        ),
+    }
+}
+
+//
+// Host
+//
+
+// an endpoint server granted to a client, persisted as <data-dir>/peers/<client-service-id>
+struct Peer {
+    endpoint_private_key: Ed25519PrivateKey,
+    client_auth_public_key: X25519PublicKey,
+}
+
+fn load_peers(peers_dir: &Path) -> Result<BTreeMap<V3OnionServiceId, Peer>> {
+    let mut peers: BTreeMap<V3OnionServiceId, Peer> = Default::default();
+    for entry in std::fs::read_dir(peers_dir)? {
+        let entry = entry?;
+        let client_service_id = match entry.file_name().to_str() {
+            Some(file_name) => V3OnionServiceId::from_string(file_name)?,
+            None => continue,
+        };
+        let contents = std::fs::read_to_string(entry.path())?;
+        let mut lines = contents.lines();
+        if let (Some(endpoint_private_key), Some(client_auth_public_key)) =
+            (lines.next(), lines.next())
+        {
+            peers.insert(
+                client_service_id,
+                Peer {
+                    endpoint_private_key: Ed25519PrivateKey::from_key_blob(endpoint_private_key)?,
+                    client_auth_public_key: X25519PublicKey::from_base32(client_auth_public_key)?,
+                },
+            );
+        } else {
+            bail!("malformed peer file: {}", entry.path().display());
+        }
+    }
+    Ok(peers)
+}
+
+fn save_peer(peers_dir: &Path, client_service_id: &V3OnionServiceId, peer: &Peer) -> Result<()> {
+    let contents = format!(
+        "{}\n{}\n",
+        peer.endpoint_private_key.to_key_blob(),
+        peer.client_auth_public_key.to_base32()
+    );
+    std::fs::write(peers_dir.join(client_service_id.to_string()), contents)?;
+    Ok(())
+}
+
+fn host(context: &mut Context, data_dir: &Path) -> Result<()> {
+    let peers_dir = data_dir.join("peers");
+    std::fs::create_dir_all(&peers_dir)?;
+
+    // restart the endpoint servers granted in previous runs
+    let peers = load_peers(&peers_dir)?;
+    for (client_service_id, peer) in peers.iter() {
+        println!("starting endpoint server for {client_service_id}");
+        context.endpoint_server_start(
+            peer.endpoint_private_key.clone(),
+            ENDPOINT_NAME.parse()?,
+            client_service_id.clone(),
+            peer.client_auth_public_key.clone(),
+            false,
+        )?;
+    }
+
+    context.identity_server_start()?;
+
+    let mut connections: Vec<(V3OnionServiceId, Connection)> = Default::default();
+    loop {
+        for event in context.update()?.drain(..) {
+            match event {
+                ContextEvent::IdentityServerPublished => {
+                    println!("identity server published, waiting for clients");
+                }
+                ContextEvent::IdentityServerEndpointRequestReceived {
+                    handle,
+                    client_service_id,
+                    requested_endpoint,
+                    ..
+                } => {
+                    println!("{client_service_id} requested endpoint '{requested_endpoint}'");
+                    // every client is allowed and the challenge is an empty document
+                    context.identity_server_handle_endpoint_request_received(
+                        handle,
+                        true,
+                        requested_endpoint == ENDPOINT_NAME,
+                        doc! {},
+                    )?;
+                }
+                ContextEvent::IdentityServerChallengeResponseReceived {
+                    handle,
+                    challenge_response,
+                } => {
+                    context.identity_server_handle_challenge_response_received(
+                        handle,
+                        challenge_response == doc! {},
+                    )?;
+                }
+                ContextEvent::IdentityServerHandshakeCompleted {
+                    endpoint_private_key,
+                    client_service_id,
+                    client_auth_public_key,
+                    ..
+                } => {
+                    println!("granted endpoint to {client_service_id}");
+                    let peer = Peer {
+                        endpoint_private_key,
+                        client_auth_public_key,
+                    };
+                    save_peer(&peers_dir, &client_service_id, &peer)?;
+                    context.endpoint_server_start(
+                        peer.endpoint_private_key,
+                        ENDPOINT_NAME.parse()?,
+                        client_service_id,
+                        peer.client_auth_public_key,
+                        false,
+                    )?;
+                }
+                ContextEvent::IdentityServerHandshakeRejected { handle, .. } => {
+                    println!("identity handshake {handle} rejected");
+                }
+                ContextEvent::IdentityServerHandshakeFailed { handle, reason, .. } => {
+                    println!("identity handshake {handle} failed: {reason}");
+                }
+                ContextEvent::EndpointServerPublished {
+                    endpoint_service_id,
+                    ..
+                } => {
+                    println!("endpoint server {endpoint_service_id} published");
+                }
+                ContextEvent::EndpointServerChannelRequestReceived {
+                    handle,
+                    requested_channel,
+                    ..
+                } => {
+                    context.endpoint_server_handle_channel_request_received(
+                        handle,
+                        requested_channel == CHANNEL_NAME,
+                    )?;
+                }
+                ContextEvent::EndpointServerHandshakeCompleted {
+                    client_service_id,
+                    stream,
+                    ..
+                } => {
+                    println!("{client_service_id} connected");
+                    connections.push((client_service_id, Connection::new(stream)?));
+                }
+                ContextEvent::EndpointServerHandshakeRejected { handle, .. } => {
+                    println!("endpoint handshake {handle} rejected");
+                }
+                ContextEvent::EndpointServerHandshakeFailed { handle, reason, .. } => {
+                    println!("endpoint handshake {handle} failed: {reason}");
+                }
+                _ => (),
+            }
+        }
+
+        // echo every received line back to its sender
+        connections.retain_mut(
+            |(client_service_id, connection)| match connection.read_line() {
+                Ok(Some(line)) => {
+                    print!("{client_service_id}> {line}");
+                    connection.writer.write_all(line.as_bytes()).is_ok()
+                }
+                Ok(None) => true,
+                Err(err) => {
+                    println!("{client_service_id} disconnected: {err}");
+                    false
+                }
+            },
+        );
+
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+//
+// Client
+//
+
+fn connect(
+    context: &mut Context,
+    data_dir: &Path,
+    client_service_id: V3OnionServiceId,
+    host_service_id: V3OnionServiceId,
+    timeout: Duration,
+) -> Result<()> {
+    let grants_dir = data_dir.join("grants");
+    std::fs::create_dir_all(&grants_dir)?;
+    let grant_path = grants_dir.join(host_service_id.to_string());
+
+    // re-use the endpoint granted in a previous run, otherwise request one
+    let mut handle = if grant_path.exists() {
+        let grant = EndpointGrant::from_string(std::fs::read_to_string(&grant_path)?.trim())?;
+        let endpoint_service_id = grant.endpoint_service_id();
+        println!("connecting to saved endpoint {endpoint_service_id}");
+        context.endpoint_client_begin_handshake(
+            endpoint_service_id.clone(),
+            grant.client_auth_private_key().clone(),
+            CHANNEL_NAME.parse()?,
+        )?
+    } else {
+        println!("requesting endpoint from {host_service_id}");
+        context.identity_client_begin_handshake(host_service_id.clone(), ENDPOINT_NAME.parse()?)?
+    };
+    let mut deadline = Instant::now() + timeout;
+
+    // wait for our connection to the host's endpoint
+    let stream = 'handshake: loop {
+        for event in context.update()?.drain(..) {
+            match event {
+                ContextEvent::IdentityClientChallengeReceived {
+                    handle,
+                    endpoint_challenge: _,
+                } => {
+                    context.identity_client_handle_challenge_received(handle, doc! {})?;
+                }
+                ContextEvent::IdentityClientHandshakeCompleted {
+                    identity_service_id,
+                    endpoint_service_id,
+                    endpoint_name,
+                    client_auth_private_key,
+                    ..
+                } => {
+                    println!("granted endpoint {endpoint_service_id}");
+                    let grant = EndpointGrant::new(
+                        identity_service_id,
+                        client_service_id.clone(),
+                        endpoint_service_id.clone(),
+                        endpoint_name.into_string(),
+                        client_auth_private_key.clone(),
+                    )?;
+                    std::fs::write(&grant_path, grant.to_string())?;
+
+                    handle = context.endpoint_client_begin_handshake(
+                        endpoint_service_id,
+                        client_auth_private_key,
+                        CHANNEL_NAME.parse()?,
+                    )?;
+                    deadline = Instant::now() + timeout;
+                }
+                ContextEvent::IdentityClientHandshakeFailed { reason, .. } => {
+                    bail!("identity handshake failed: {reason}");
+                }
+                ContextEvent::EndpointClientHandshakeCompleted { stream, .. } => {
+                    break 'handshake stream;
+                }
+                ContextEvent::EndpointClientHandshakeFailed { reason, .. } => {
+                    bail!("endpoint handshake failed: {reason}");
+                }
+                _ => (),
+            }
+        }
+
+        if Instant::now() > deadline {
+            // the handle belongs to whichever handshake is in progress
+            let _ = context.identity_client_abort_handshake(handle);
+            let _ = context.endpoint_client_abort_handshake(handle);
+            bail!("handshake timed out after {} seconds", timeout.as_secs());
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    };
+
+    println!("connected to {host_service_id}; type a line to send it");
+    let mut connection = Connection::new(stream)?;
+
+    // read stdin on its own thread so the context keeps being updated
+    let (stdin_tx, stdin_rx) = mpsc::channel::<String>();
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lock().lines() {
+            match line {
+                Ok(line) => {
+                    if stdin_tx.send(line).is_err() {
+                        break;
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+    });
+
+    loop {
+        // any further events (e.g. tor logs) are not interesting here
+        context.update()?;
+
+        match stdin_rx.try_recv() {
+            Ok(line) => writeln!(connection.writer, "{line}")?,
+            Err(mpsc::TryRecvError::Empty) => (),
+            Err(mpsc::TryRecvError::Disconnected) => return Ok(()),
+        }
+
+        if let Some(line) = connection.read_line()? {
+            print!("{host_service_id}> {line}");
+        }
+
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
@@ -112,7 +112,7 @@ fuzz_target!(|data: HandshakeData| {
     }
 
 
-    let alice_listener = alice_tor.listener(&alice_endpoint_ed25519, 420, Some(&[bob_public_x25519])).unwrap();
+    let alice_listener = alice_tor.listener(&alice_endpoint_ed25519, 420, Some(&[bob_public_x25519]), false, None).unwrap();
     let mut identity_server_published: bool = false;
     while !identity_server_published {
         for event in alice_tor.update().unwrap().drain(..) {
@@ -148,7 +148,7 @@ fuzz_target!(|data: HandshakeData| {
     //
     // Bob initiates handshake
     //
-    let handshake_handle = bob.endpoint_client_begin_handshake(alice_endpoint_onion_service_id.clone(), bob_private_x25519, VALID_CHANNEL.to_string()).unwrap();
+    let handshake_handle = bob.endpoint_client_begin_handshake(alice_endpoint_onion_service_id.clone(), bob_private_x25519, VALID_CHANNEL.parse().unwrap()).unwrap();
     // first update to queue the HonkRPC call
     assert_eq!(0, bob.update().unwrap().len());
     // second upate sends the HonkRPC message
@@ -515,7 +515,7 @@ fuzz_target!(|data: HandshakeData| {
     //
     for event in bob.update().unwrap().drain(..) {
         match event {
-            ContextEvent::EndpointClientHandshakeFailed{handle, reason} => {
+            ContextEvent::EndpointClientHandshakeFailed{handle, reason, stats: _} => {
                 assert_eq!(handshake_handle, handle);
                 match reason {
                     context::Error::EndpointClientError(
@@ -559,7 +559,7 @@ fuzz_target!(|data: HandshakeData| {
     // handle dangling unexpected response
     for event in bob.update().unwrap().drain(..) {
         match event {
-            ContextEvent::EndpointClientHandshakeFailed{handle, reason} => {
+            ContextEvent::EndpointClientHandshakeFailed{handle, reason, stats: _} => {
                 assert_eq!(handshake_handle, handle);
                 match reason {
                     context::Error::EndpointClientError(
@@ -580,7 +580,7 @@ fuzz_target!(|data: HandshakeData| {
     while expect_timeout {
         for event in bob.update().unwrap().drain(..) {
             match event {
-                ContextEvent::EndpointClientHandshakeFailed{handle, reason} => {
+                ContextEvent::EndpointClientHandshakeFailed{handle, reason, stats: _} => {
                     assert_eq!(handshake_handle, handle);
                     match reason {
                         context::Error::EndpointClientError(
@@ -944,7 +944,7 @@ fuzz_target!(|data: HandshakeData| {
     while !send_response_complete {
         for event in bob.update().unwrap().drain(..) {
             match event {
-                ContextEvent::EndpointClientHandshakeFailed{handle, reason} => {
+                ContextEvent::EndpointClientHandshakeFailed{handle, reason, stats: _} => {
                     assert_eq!(handshake_handle, handle);
                     match reason {
                         context::Error::EndpointClientError(
@@ -981,7 +981,7 @@ fuzz_target!(|data: HandshakeData| {
                     // bob should have closed the connection on alice after handshake failure
                     return;
                 },
-                ContextEvent::EndpointClientHandshakeCompleted{handle, endpoint_service_id, channel_name, stream: _} => {
+                ContextEvent::EndpointClientHandshakeCompleted{handle, endpoint_service_id, channel_name, stream: _, capabilities: _, stats: _} => {
                     assert_eq!(handshake_handle, handle);
                     assert_eq!(endpoint_service_id, alice_endpoint_onion_service_id);
                     assert_eq!(channel_name, VALID_CHANNEL);
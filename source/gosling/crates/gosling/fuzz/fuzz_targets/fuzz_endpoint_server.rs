@@ -112,7 +112,7 @@ fuzz_target!(|data: HandshakeData| {
                 ContextEvent::TorBootstrapStatusReceived{progress: _, tag: _, summary: _} => (),
                 ContextEvent::TorBootstrapCompleted => {
                     // start alice endpoint server
-                    match alice.endpoint_server_start(alice_endpoint_ed25519.clone(), VALID_ENDPOINT.to_string(), bob_onion_service_id.clone(), bob_public_x25519.clone()) {
+                    match alice.endpoint_server_start(alice_endpoint_ed25519.clone(), VALID_ENDPOINT.parse().unwrap(), bob_onion_service_id.clone(), bob_public_x25519.clone(), false) {
                         Ok(()) => (),
                         Err(context::Error::InvalidArgument(_)) => {
                             assert_eq!(alice_onion_service_id_string, alice_endpoint_onion_service_id_string);
@@ -347,7 +347,7 @@ fuzz_target!(|data: HandshakeData| {
 
                     alice_begin_handshake_handled = true;
                 }
-                ContextEvent::EndpointServerHandshakeFailed{handle, reason} => {
+                ContextEvent::EndpointServerHandshakeFailed{handle, reason, stats: _} => {
                     assert_eq!(handle, alice_handshake_handle);
                     match reason {
                         context::Error::EndpointServerError(
@@ -648,7 +648,7 @@ fuzz_target!(|data: HandshakeData| {
     while !alice_send_response_handled {
         for event in alice.update().unwrap().drain(..) {
             match event {
-                ContextEvent::EndpointServerHandshakeFailed{handle, reason} => {
+                ContextEvent::EndpointServerHandshakeFailed{handle, reason, stats: _} => {
                     assert_eq!(handle, alice_handshake_handle);
                     match reason {
                         context::Error::EndpointServerError(
@@ -684,7 +684,7 @@ fuzz_target!(|data: HandshakeData| {
                     }
                     alice_send_response_handled = true;
                 },
-                ContextEvent::EndpointServerHandshakeCompleted{handle, endpoint_service_id, client_service_id, channel_name, stream: _} => {
+                ContextEvent::EndpointServerHandshakeCompleted{handle, endpoint_service_id, client_service_id, channel_name, stream: _, capabilities: _, stats: _} => {
                     assert_eq!(handle, alice_handshake_handle);
                     assert_eq!(endpoint_service_id, alice_endpoint_onion_service_id);
                     assert_eq!(client_service_id, bob_onion_service_id);
@@ -101,7 +101,7 @@ fuzz_target!(|data: HandshakeData| {
     let alice_private_key = data.alice_private_ed25519.value;
     let alice_onion_service_id = V3OnionServiceId::from_private_key(&alice_private_key);
     let alice_onion_service_id_string = alice_onion_service_id.to_string();
-    let alice_listener = alice_tor.listener(&alice_private_key, 420, None).unwrap();
+    let alice_listener = alice_tor.listener(&alice_private_key, 420, None, false, None).unwrap();
     let mut identity_server_published: bool = false;
     while !identity_server_published {
         for event in alice_tor.update().unwrap().drain(..) {
@@ -141,7 +141,7 @@ fuzz_target!(|data: HandshakeData| {
     //
     // Bob initiates handshake
     //
-    let handshake_handle = bob.identity_client_begin_handshake(alice_onion_service_id.clone(), VALID_ENDPOINT.to_string()).unwrap();
+    let handshake_handle = bob.identity_client_begin_handshake(alice_onion_service_id.clone(), VALID_ENDPOINT.parse().unwrap()).unwrap();
     // first update to queue the HonkRPC call
     assert_eq!(0, bob.update().unwrap().len());
     // second upate sends the HonkRPC message
@@ -508,7 +508,7 @@ fuzz_target!(|data: HandshakeData| {
     while !begin_handshake_complete {
         for event in bob.update().unwrap().drain(..) {
             match event {
-                ContextEvent::IdentityClientHandshakeFailed{handle, reason} => {
+                ContextEvent::IdentityClientHandshakeFailed{handle, reason, stats: _} => {
                     assert_eq!(handshake_handle, handle);
                     match reason {
                         context::Error::IdentityClientError(
@@ -932,7 +932,7 @@ fuzz_target!(|data: HandshakeData| {
     while !send_response_complete {
         for event in bob.update().unwrap().drain(..) {
             match event {
-                ContextEvent::IdentityClientHandshakeFailed{handle, reason} => {
+                ContextEvent::IdentityClientHandshakeFailed{handle, reason, stats: _} => {
                     assert_eq!(handshake_handle, handle);
                     match reason {
                         context::Error::IdentityClientError(
@@ -969,7 +969,7 @@ fuzz_target!(|data: HandshakeData| {
                     // bob should have closed the connection on alice after handshake failure
                     return;
                 },
-                ContextEvent::IdentityClientHandshakeCompleted{handle, identity_service_id, endpoint_service_id, endpoint_name, client_auth_private_key} => {
+                ContextEvent::IdentityClientHandshakeCompleted{handle, identity_service_id, endpoint_service_id, endpoint_name, client_auth_private_key, additional_endpoints: _, contact_request: _, capabilities: _, stats: _} => {
                     assert_eq!(handshake_handle, handle);
                     assert_eq!(identity_service_id, alice_onion_service_id);
                     assert_eq!(endpoint_service_id, data.endpoint_service_id.value);
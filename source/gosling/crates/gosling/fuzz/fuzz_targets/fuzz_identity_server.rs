@@ -296,7 +296,7 @@ fuzz_target!(|data: HandshakeData| {
     while !alice_begin_handshake_handled {
         for event in alice.update().unwrap().drain(..) {
             match event {
-                ContextEvent::IdentityServerEndpointRequestReceived{handle, client_service_id: _, requested_endpoint} => {
+                ContextEvent::IdentityServerEndpointRequestReceived{handle, client_service_id: _, requested_endpoint, additional_endpoints: _, contact_request: _} => {
                     assert_eq!(handle, alice_handshake_handle);
                     assert_eq!(expected_response, ExpectedBeginHandshakeResponse::EndpointRequestReceived);
                     #[derive(PartialEq, Debug)]
@@ -338,7 +338,7 @@ fuzz_target!(|data: HandshakeData| {
 
                     alice_begin_handshake_handled = true;
                 }
-                ContextEvent::IdentityServerHandshakeFailed{handle, reason} => {
+                ContextEvent::IdentityServerHandshakeFailed{handle, reason, stats: _} => {
                     assert_eq!(handle, alice_handshake_handle);
                     match reason {
                         context::Error::IdentityServerError(
@@ -543,6 +543,7 @@ fuzz_target!(|data: HandshakeData| {
         ErrorBsonTooLarge,
         ErrorMessageParseFailure,
         ErrorInvalidArg,
+        ErrorInvalidSignbit,
         ErrorBadClient,
     }
     let mut expected_response = match (data.client_allowed && data.endpoint_supported, data.begin_handshake) {
@@ -592,6 +593,10 @@ fuzz_target!(|data: HandshakeData| {
                  Argument::Valid | Argument::Invalid(_)) => expected_response = ExpectedSendResponseResponse::ErrorBadClient,
                 _ => expected_response = ExpectedSendResponseResponse::ErrorInvalidArg,
             }
+            // a non-boolean signbit is rejected before the other arguments are validated
+            if let Argument::Random(_) = client_authorization_key_signbit {
+                expected_response = ExpectedSendResponseResponse::ErrorInvalidSignbit;
+            }
 
             let mut message = Document::new();
             message.insert("honk_rpc", Bson::Int32(HONK_RPC));
@@ -727,7 +732,7 @@ fuzz_target!(|data: HandshakeData| {
     while !alice_send_response_handled {
         for event in alice.update().unwrap().drain(..) {
             match event {
-                ContextEvent::IdentityServerHandshakeFailed{handle, reason} => {
+                ContextEvent::IdentityServerHandshakeFailed{handle, reason, stats: _} => {
                     assert_eq!(handle, alice_handshake_handle);
                     match reason {
                         context::Error::IdentityServerError(
@@ -759,6 +764,9 @@ fuzz_target!(|data: HandshakeData| {
                             assert!(expected_response == ExpectedSendResponseResponse::ErrorBadClient ||
                                     expected_response == ExpectedSendResponseResponse::ErrorInvalidArg, "{:?}", reason);
                         },
+                        context::Error::IdentityServerError(identity_server::Error::InvalidSignbit) => {
+                            assert_eq!(expected_response, ExpectedSendResponseResponse::ErrorInvalidSignbit, "{:?}", reason);
+                        },
                         error => panic!("unexpected error: {:?}", error),
                     }
                     alice_send_response_handled = true;
@@ -767,7 +775,7 @@ fuzz_target!(|data: HandshakeData| {
                     assert_eq!(handle, alice_handshake_handle);
                     alice.identity_server_handle_challenge_response_received(handle, challenge_response == Document::new()).unwrap();
                 },
-                ContextEvent::IdentityServerHandshakeCompleted{handle, endpoint_private_key: _, endpoint_name, client_service_id: _, client_auth_public_key: _} => {
+                ContextEvent::IdentityServerHandshakeCompleted{handle, endpoint_private_key: _, endpoint_name, client_service_id: _, client_auth_public_key: _, additional_endpoints: _, contact_request: _, capabilities: _, stats: _} => {
                     assert_eq!(handle, alice_handshake_handle);
                     assert_eq!(endpoint_name, VALID_ENDPOINT);
                     alice_send_response_handled = true;
@@ -817,6 +825,23 @@ fuzz_target!(|data: HandshakeData| {
                 Err(error) => panic!("unexpected expected response and error: {:?}, {:?}", expected_response, error),
             }
         },
+        ExpectedSendResponseResponse::ErrorInvalidSignbit => {
+            match send_response_pending {
+                Ok(message) => {
+                    assert_eq!(message, doc!{
+                        "honk_rpc": HONK_RPC,
+                        "sections": [
+                            {
+                                "id": ERROR_SECTION,
+                                "cookie": data.send_response_cookie,
+                                "code": ERROR_CODE_INVALID_SIGNBIT,
+                            }
+                        ]
+                    }, "expected response: {:?}", expected_response);
+                },
+                Err(error) => panic!("unexpected expected response and error: {:?}, {:?}", expected_response, error),
+            }
+        },
         ExpectedSendResponseResponse::EndpointOnionServiceIdReceived |
         ExpectedSendResponseResponse::ErrorBadClient => {
             match send_response_pending {
@@ -845,7 +870,8 @@ fuzz_target!(|data: HandshakeData| {
         ExpectedSendResponseResponse::ErrorBsonTooLarge |
         ExpectedSendResponseResponse::ErrorBsonParseFailure |
         ExpectedSendResponseResponse::ErrorMessageParseFailure |
-        ExpectedSendResponseResponse::ErrorInvalidArg => {
+        ExpectedSendResponseResponse::ErrorInvalidArg |
+        ExpectedSendResponseResponse::ErrorInvalidSignbit => {
             match send_response_result {
                 Err(bson::de::Error::Io(_)) => return,
                 Ok(message) => panic!("unexpected message: {:?}", message),
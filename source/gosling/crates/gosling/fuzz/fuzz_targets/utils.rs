@@ -10,6 +10,7 @@ pub(crate) const ERROR_CODE_BAD_VERSION: i32 = 0;
 pub(crate) const ERROR_CODE_REQUEST_COOKIE_REQUIRED: i32 = 1;
 pub(crate) const ERROR_CODE_INVALID_ARG: i32 = 2;
 pub(crate) const ERROR_CODE_FAILURE: i32 = 3;
+pub(crate) const ERROR_CODE_INVALID_SIGNBIT: i32 = 16;
 pub(crate) const REQUEST_SECTION: i32 = 1;
 pub(crate) const RESPONSE_SECTION: i32 = 2;
 pub(crate) const PENDING_REQUEST_STATE: i32 = 0;
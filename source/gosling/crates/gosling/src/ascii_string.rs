@@ -1,20 +1,29 @@
+// standard
+use std::ops::Deref;
+use std::str::FromStr;
+
+// extern crates
 #[cfg(test)]
 use anyhow::bail;
-use std::ops::Deref;
 
+/// The error type for the [`AsciiString`] type.
 #[derive(thiserror::Error, Debug)]
-pub(crate) enum Error {
+pub enum Error {
+    /// The string contains a non-ASCII character
     #[error("input string is not ASCII: {0}")]
     InvalidAscii(String),
 }
 
-/// An immutable wrapper around a String guaranteed to be ASCII encoded
-#[derive(Clone, PartialEq)]
-pub(crate) struct AsciiString {
+/// An immutable wrapper around a String guaranteed to be ASCII encoded. Endpoint and channel names are `AsciiString`s, so names which cannot be sent in a handshake are rejected before reaching the protocol layer.
+///
+/// `AsciiString`s are usually made from string literals with [`str::parse()`] or [`TryFrom`], e.g. `"chat".parse::<AsciiString>()?`, and compare equal to the strings they wrap.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct AsciiString {
     value: String,
 }
 
 impl AsciiString {
+    /// Construct an `AsciiString`, failing if `value` is not ASCII encoded
     pub fn new(value: String) -> Result<AsciiString, Error> {
         if value.is_ascii() {
             Ok(Self { value })
@@ -22,6 +31,65 @@ impl AsciiString {
             Err(Error::InvalidAscii(value))
         }
     }
+
+    /// Unwrap the underlying `String`
+    pub fn into_string(self) -> String {
+        self.value
+    }
+}
+
+impl TryFrom<String> for AsciiString {
+    type Error = Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        Self::new(value)
+    }
+}
+
+impl TryFrom<&str> for AsciiString {
+    type Error = Error;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        Self::new(value.to_string())
+    }
+}
+
+impl FromStr for AsciiString {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        Self::new(value.to_string())
+    }
+}
+
+impl From<AsciiString> for String {
+    fn from(value: AsciiString) -> Self {
+        value.value
+    }
+}
+
+impl AsRef<str> for AsciiString {
+    fn as_ref(&self) -> &str {
+        &self.value
+    }
+}
+
+impl PartialEq<str> for AsciiString {
+    fn eq(&self, other: &str) -> bool {
+        self.value == other
+    }
+}
+
+impl PartialEq<&str> for AsciiString {
+    fn eq(&self, other: &&str) -> bool {
+        self.value == *other
+    }
+}
+
+impl PartialEq<String> for AsciiString {
+    fn eq(&self, other: &String) -> bool {
+        &self.value == other
+    }
 }
 
 impl Deref for AsciiString {
@@ -73,5 +141,12 @@ fn test_ascii_string() -> anyhow::Result<()> {
         }
     }
 
+    // conversions from and comparisons with plain strings
+    let chat: AsciiString = "chat".parse()?;
+    assert_eq!(chat, "chat");
+    assert_eq!(AsciiString::try_from("chat".to_string())?, chat);
+    assert!(AsciiString::try_from("chat ❤").is_err());
+    assert_eq!(String::from(chat), "chat");
+
     Ok(())
 }
@@ -0,0 +1,473 @@
+// standard
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+// extern crates
+use bson::Document;
+use serde::{Deserialize, Serialize};
+use tor_interface::tor_crypto::*;
+
+// internal crates
+use crate::gosling::{SystemTime, UNIX_EPOCH};
+use crate::persistence::{FormatVersion, Migrations};
+
+/// The error type for the [`BanList`] type.
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    /// An invalid argument was provided to a function
+    #[error("invalid argument: {0}")]
+    InvalidArgument(String),
+
+    /// Saved bans could not be parsed
+    #[error("failed to parse bans: {0}")]
+    ParseError(String),
+
+    /// Saved bans could not be upgraded to the current format version
+    #[error(transparent)]
+    Format(#[from] crate::persistence::Error),
+
+    /// The bans file could not be read or written
+    #[error("bans file error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+// current serialisation format version
+const BAN_LIST_FORMAT: FormatVersion = FormatVersion {
+    version: 1,
+    compatible_version: 1,
+};
+
+// keys of the saved fields understood by this release
+const BAN_LIST_KEYS: [&str; 3] = ["version", "compatible_version", "bans"];
+const BAN_KEYS: [&str; 4] = ["client_service_id", "reason", "banned_at", "expires_at"];
+
+// upper bound on the size in bytes of a ban's reason
+const MAX_REASON_SIZE: usize = 1024;
+
+/// A ban of a peer, see [`BanList::ban()`]
+#[derive(Clone, Debug, PartialEq)]
+pub struct Ban {
+    /// The application's reason for the ban, e.g. to show its user
+    pub reason: String,
+    /// When the peer was banned, to the second
+    pub banned_at: SystemTime,
+    /// When the ban expires, rounded up to the second; `None` if the ban is permanent
+    pub expires_at: Option<SystemTime>,
+    // saved fields written by a newer release, kept so they survive being saved again
+    unknown_fields: Document,
+}
+
+impl Ban {
+    /// Whether the ban never expires
+    pub fn is_permanent(&self) -> bool {
+        self.expires_at.is_none()
+    }
+
+    /// Whether the ban has expired
+    pub fn is_expired(&self) -> bool {
+        self.is_expired_at(SystemTime::now())
+    }
+
+    fn is_expired_at(&self, now: SystemTime) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+/// A list of banned peers, each identified by the onion-service service-id of their identity.
+///
+/// A [`Context`](crate::context::Context) with a ban list (see [`Context::set_ban_list()`](crate::context::Context::set_ban_list)) turns away banned identity clients as soon as they begin an identity handshake, and banned endpoint clients once they have proven their identity, reporting each attempt with a [`ContextEvent::IdentityServerBannedClientRejected`](crate::context::ContextEvent::IdentityServerBannedClientRejected) or [`ContextEvent::EndpointServerBannedClientRejected`](crate::context::ContextEvent::EndpointServerBannedClientRejected) event.
+///
+/// Bans are either permanent or expire after a duration. Expired bans are no longer enforced and are removed by [`BanList::remove_expired()`], which a `Context` calls on each update.
+///
+/// A `BanList` is a handle to shared bans: clones of a `BanList` refer to the same bans, so an application may keep a clone to ban peers while its `Context` enforces them. Changes apply immediately, including to handshakes already in progress.
+///
+/// A `BanList` lives in memory unless it is opened with [`BanList::open()`], in which case [`BanList::save()`] writes it back to its file. Bans may also be saved elsewhere with [`BanList::to_bytes()`] and restored with [`BanList::from_bytes()`]. As with a [`PetnameStore`](crate::petnames::PetnameStore), saved bans carry a [`FormatVersion`] and fields written by a newer compatible release are kept intact.
+#[derive(Clone, Debug, Default)]
+pub struct BanList {
+    inner: Arc<Mutex<BanListInner>>,
+}
+
+#[derive(Debug)]
+struct BanListInner {
+    bans: BTreeMap<V3OnionServiceId, Ban>,
+    // the file the list was opened from and is saved to, if any
+    path: Option<PathBuf>,
+    // the format version the list is saved in; newer than ours if loaded from a newer release
+    format_version: FormatVersion,
+    // saved top-level fields written by a newer release
+    unknown_fields: Document,
+}
+
+impl Default for BanListInner {
+    fn default() -> Self {
+        Self {
+            bans: Default::default(),
+            path: None,
+            format_version: BAN_LIST_FORMAT,
+            unknown_fields: Default::default(),
+        }
+    }
+}
+
+// the saved form of a BanList, without its format version header
+#[derive(Serialize, Deserialize)]
+struct BanListRecord {
+    bans: Vec<Document>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct BanRecord {
+    client_service_id: String,
+    reason: String,
+    // seconds since the unix epoch
+    banned_at: i64,
+    expires_at: Option<i64>,
+}
+
+impl BanList {
+    /// Construct an empty, in-memory `BanList`
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Open the `BanList` saved in the given file by [`BanList::save()`], or an empty one if the file does not exist. The returned list is saved back to the same file.
+    ///
+    /// # Parameters
+    /// - `path`: the file the bans are read from and saved to
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Self::open_with_migrations(path, &Self::migrations())
+    }
+
+    /// Same as [`BanList::open()`], but bans saved by an older release are upgraded with the given migrations rather than the built-in ones
+    ///
+    /// # Parameters
+    /// - `path`: the file the bans are read from and saved to
+    /// - `migrations`: the built-in migrations from [`BanList::migrations()`] along with any registered by the application
+    pub fn open_with_migrations<P: AsRef<Path>>(
+        path: P,
+        migrations: &Migrations,
+    ) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        let ban_list = match std::fs::read(&path) {
+            Ok(bytes) => Self::from_bytes_with_migrations(&bytes, migrations)?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Self::new(),
+            Err(err) => return Err(err.into()),
+        };
+        ban_list.lock().path = Some(path);
+        Ok(ban_list)
+    }
+
+    /// Save this `BanList` to the file it was opened from. The file is replaced atomically, so a failed save leaves the previously saved bans intact. Lists which were not opened with [`BanList::open()`] are not saved.
+    pub fn save(&self) -> Result<(), Error> {
+        if let Some(path) = self.path() {
+            let mut temp_path = path.clone().into_os_string();
+            temp_path.push(".tmp");
+            std::fs::write(&temp_path, self.to_bytes())?;
+            std::fs::rename(&temp_path, path)?;
+        }
+        Ok(())
+    }
+
+    /// The file this `BanList` is saved to, if it was opened with [`BanList::open()`]
+    pub fn path(&self) -> Option<PathBuf> {
+        self.lock().path.clone()
+    }
+
+    /// The migrations which upgrade bans saved by older releases to the current format version. Applications may register further migrations before passing them to [`BanList::open_with_migrations()`] or [`BanList::from_bytes_with_migrations()`].
+    pub fn migrations() -> Migrations {
+        // future format versions register their upgrade from the previous version here
+        Migrations::new(BAN_LIST_FORMAT)
+    }
+
+    /// Serialise this `BanList` as a versioned BSON document. Expired bans which have not yet been removed are included.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let inner = self.lock();
+        let bans = inner.bans.iter().map(|(client_service_id, ban)| {
+            let record = BanRecord {
+                client_service_id: client_service_id.to_string(),
+                reason: ban.reason.clone(),
+                banned_at: to_unix_seconds(ban.banned_at),
+                expires_at: ban.expires_at.map(to_unix_seconds),
+            };
+            let mut document = bson::to_document(&record).expect("bans are representable as bson");
+            document.extend(ban.unknown_fields.clone());
+            document
+        });
+        let record = BanListRecord {
+            bans: bans.collect(),
+        };
+        let mut document = Document::new();
+        inner.format_version.write(&mut document);
+        document.extend(bson::to_document(&record).expect("bans are representable as bson"));
+        document.extend(inner.unknown_fields.clone());
+
+        let mut bytes: Vec<u8> = Default::default();
+        document
+            .to_writer(&mut bytes)
+            .expect("writing to a Vec cannot fail");
+        bytes
+    }
+
+    /// Parse an in-memory `BanList` from bytes created by [`BanList::to_bytes()`] in this or any compatible release
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        Self::from_bytes_with_migrations(bytes, &Self::migrations())
+    }
+
+    /// Same as [`BanList::from_bytes()`], but bans saved by an older release are upgraded with the given migrations rather than the built-in ones
+    ///
+    /// # Parameters
+    /// - `bytes`: bans created by [`BanList::to_bytes()`]
+    /// - `migrations`: the built-in migrations from [`BanList::migrations()`] along with any registered by the application
+    pub fn from_bytes_with_migrations(
+        mut bytes: &[u8],
+        migrations: &Migrations,
+    ) -> Result<Self, Error> {
+        let mut document =
+            Document::from_reader(&mut bytes).map_err(|err| Error::ParseError(err.to_string()))?;
+        let format_version = migrations.migrate(&mut document)?;
+        let record: BanListRecord = bson::from_document(document.clone())
+            .map_err(|err| Error::ParseError(err.to_string()))?;
+
+        let mut inner = BanListInner {
+            format_version,
+            unknown_fields: unknown_fields(document, &BAN_LIST_KEYS),
+            ..Default::default()
+        };
+        for ban in record.bans {
+            let ban_record: BanRecord = bson::from_document(ban.clone())
+                .map_err(|err| Error::ParseError(err.to_string()))?;
+            let client_service_id = V3OnionServiceId::from_string(&ban_record.client_service_id)
+                .map_err(|err| Error::ParseError(err.to_string()))?;
+            let from_unix_seconds = |seconds: i64| match u64::try_from(seconds) {
+                Ok(seconds) => Ok(UNIX_EPOCH + Duration::from_secs(seconds)),
+                Err(_) => Err(Error::ParseError(format!("invalid time: {}", seconds))),
+            };
+            inner.bans.insert(
+                client_service_id,
+                Ban {
+                    reason: ban_record.reason,
+                    banned_at: from_unix_seconds(ban_record.banned_at)?,
+                    expires_at: ban_record.expires_at.map(from_unix_seconds).transpose()?,
+                    unknown_fields: unknown_fields(ban, &BAN_KEYS),
+                },
+            );
+        }
+        Ok(Self {
+            inner: Arc::new(Mutex::new(inner)),
+        })
+    }
+
+    /// Ban a peer, replacing any existing ban of the same peer
+    ///
+    /// # Parameters
+    /// - `client_service_id`: the onion-service service-id of the peer's identity
+    /// - `duration`: how long the ban lasts, or `None` for a permanent ban
+    /// - `reason`: the application's reason for the ban, at most 1024 bytes
+    ///
+    /// # Returns
+    /// The replaced ban, if the peer was already banned
+    pub fn ban(
+        &self,
+        client_service_id: V3OnionServiceId,
+        duration: Option<Duration>,
+        reason: String,
+    ) -> Result<Option<Ban>, Error> {
+        self.ban_at(client_service_id, duration, reason, SystemTime::now())
+    }
+
+    fn ban_at(
+        &self,
+        client_service_id: V3OnionServiceId,
+        duration: Option<Duration>,
+        reason: String,
+        now: SystemTime,
+    ) -> Result<Option<Ban>, Error> {
+        if reason.len() > MAX_REASON_SIZE {
+            return Err(Error::InvalidArgument(format!(
+                "reason may be at most {} bytes",
+                MAX_REASON_SIZE
+            )));
+        }
+        let since_epoch = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let banned_at = UNIX_EPOCH + Duration::from_secs(since_epoch.as_secs());
+        let expires_at = match duration {
+            Some(duration) => {
+                let expires_at = since_epoch
+                    .checked_add(duration)
+                    .ok_or_else(|| Error::InvalidArgument("duration is too long".to_string()))?;
+                let seconds = expires_at.as_secs() + u64::from(expires_at.subsec_nanos() > 0);
+                Some(UNIX_EPOCH + Duration::from_secs(seconds))
+            }
+            None => None,
+        };
+
+        let ban = Ban {
+            reason,
+            banned_at,
+            expires_at,
+            unknown_fields: Default::default(),
+        };
+        let replaced = self.lock().bans.insert(client_service_id, ban);
+        Ok(replaced.filter(|replaced| !replaced.is_expired_at(now)))
+    }
+
+    /// Lift a peer's ban
+    ///
+    /// # Returns
+    /// The lifted ban, if the peer was banned
+    pub fn unban(&self, client_service_id: &V3OnionServiceId) -> Option<Ban> {
+        let now = SystemTime::now();
+        self.lock()
+            .bans
+            .remove(client_service_id)
+            .filter(|ban| !ban.is_expired_at(now))
+    }
+
+    /// The ban of a peer, if it is banned
+    pub fn get(&self, client_service_id: &V3OnionServiceId) -> Option<Ban> {
+        self.get_at(client_service_id, SystemTime::now())
+    }
+
+    fn get_at(&self, client_service_id: &V3OnionServiceId, now: SystemTime) -> Option<Ban> {
+        self.lock()
+            .bans
+            .get(client_service_id)
+            .filter(|ban| !ban.is_expired_at(now))
+            .cloned()
+    }
+
+    /// Whether a peer is banned
+    pub fn is_banned(&self, client_service_id: &V3OnionServiceId) -> bool {
+        self.get(client_service_id).is_some()
+    }
+
+    /// Every banned peer and its ban, ordered by service-id
+    pub fn bans(&self) -> Vec<(V3OnionServiceId, Ban)> {
+        let now = SystemTime::now();
+        self.lock()
+            .bans
+            .iter()
+            .filter(|(_, ban)| !ban.is_expired_at(now))
+            .map(|(client_service_id, ban)| (client_service_id.clone(), ban.clone()))
+            .collect()
+    }
+
+    /// Remove the bans which have expired. Changes are not saved until [`BanList::save()`] is called.
+    ///
+    /// # Returns
+    /// The peers whose bans were removed and their bans
+    pub fn remove_expired(&self) -> Vec<(V3OnionServiceId, Ban)> {
+        self.remove_expired_at(SystemTime::now())
+    }
+
+    fn remove_expired_at(&self, now: SystemTime) -> Vec<(V3OnionServiceId, Ban)> {
+        let mut inner = self.lock();
+        let expired: Vec<V3OnionServiceId> = inner
+            .bans
+            .iter()
+            .filter(|(_, ban)| ban.is_expired_at(now))
+            .map(|(client_service_id, _)| client_service_id.clone())
+            .collect();
+        expired
+            .into_iter()
+            .filter_map(|client_service_id| {
+                let ban = inner.bans.remove(&client_service_id)?;
+                Some((client_service_id, ban))
+            })
+            .collect()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, BanListInner> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+fn to_unix_seconds(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since_epoch| since_epoch.as_secs() as i64)
+}
+
+// the fields of a saved document other than the given known fields
+fn unknown_fields(document: Document, known_keys: &[&str]) -> Document {
+    document
+        .into_iter()
+        .filter(|(key, _)| !known_keys.contains(&key.as_str()))
+        .collect()
+}
+
+#[test]
+fn test_ban_list() -> anyhow::Result<()> {
+    let alice = V3OnionServiceId::from_private_key(&Ed25519PrivateKey::generate());
+    let pat = V3OnionServiceId::from_private_key(&Ed25519PrivateKey::generate());
+    let now = SystemTime::now();
+    let hour = Duration::from_secs(60 * 60);
+
+    // clones share the same bans
+    let ban_list = BanList::new();
+    let shared = ban_list.clone();
+    assert!(ban_list
+        .ban_at(alice.clone(), None, "spam".to_string(), now)?
+        .is_none());
+    assert!(ban_list
+        .ban_at(pat.clone(), Some(hour), "rude".to_string(), now)?
+        .is_none());
+    assert!(shared.is_banned(&alice));
+    assert!(shared.get(&alice).unwrap().is_permanent());
+    assert_eq!(shared.bans().len(), 2);
+    assert!(ban_list.ban(pat.clone(), None, "x".repeat(2048)).is_err());
+
+    // bans expire after their duration
+    let ban = ban_list.get_at(&pat, now).unwrap();
+    assert_eq!(ban.reason, "rude");
+    assert!(ban.expires_at.unwrap() >= now + hour);
+    assert!(ban_list.get_at(&pat, now + hour * 2).is_none());
+    assert!(ban_list.remove_expired_at(now + hour / 2).is_empty());
+    let expired = ban_list.remove_expired_at(now + hour * 2);
+    assert_eq!(expired.len(), 1);
+    assert_eq!(expired[0].0, pat);
+    assert!(!shared.is_banned(&pat));
+
+    // replacing a ban returns the previous one
+    let replaced = ban_list.ban(alice.clone(), Some(hour), "spam again".to_string())?;
+    assert_eq!(replaced.unwrap().reason, "spam");
+    assert!(!ban_list.get(&alice).unwrap().is_permanent());
+
+    // the list survives a round-trip through its saved form
+    let restored = BanList::from_bytes(&ban_list.to_bytes())?;
+    assert_eq!(restored.bans(), ban_list.bans());
+    assert!(BanList::from_bytes(b"not bson").is_err());
+
+    // and through its file, keeping fields written by a newer release
+    let mut document = Document::from_reader(&mut ban_list.to_bytes().as_slice())?;
+    document.insert("version", 2);
+    if let Some(bson::Bson::Document(ban)) = document.get_array_mut("bans")?.first_mut() {
+        ban.insert("evidence", "logs");
+    }
+    let mut path = std::env::temp_dir();
+    path.push(format!("gosling_bans_test_{}", alice));
+    let mut bytes: Vec<u8> = Default::default();
+    document.to_writer(&mut bytes)?;
+    std::fs::write(&path, bytes)?;
+    let opened = BanList::open(&path)?;
+    assert!(opened.is_banned(&alice));
+    opened.ban(pat.clone(), None, String::new())?;
+    opened.save()?;
+    let document = Document::from_reader(&mut std::fs::read(&path)?.as_slice())?;
+    assert_eq!(FormatVersion::read(&document)?.version, 2);
+    let saved_bans = document.get_array("bans")?;
+    assert_eq!(saved_bans.len(), 2);
+    assert!(saved_bans
+        .iter()
+        .any(|ban| ban.as_document().unwrap().get_str("evidence").is_ok()));
+    assert!(BanList::open(&path)?.is_banned(&pat));
+    std::fs::remove_file(&path)?;
+
+    // lifting a ban
+    assert!(ban_list.unban(&alice).is_some());
+    assert!(ban_list.unban(&alice).is_none());
+    assert!(ban_list.bans().is_empty());
+
+    Ok(())
+}
@@ -0,0 +1,125 @@
+// Records handshake transcripts and replays them against the current identity
+// and endpoint handshake state machines, failing if any side's state
+// transitions or outcome differ from those recorded. Transcripts recorded
+// before a change to the state machines guard against unintended changes in
+// their behaviour.
+//
+// usage: see USAGE
+
+// standard
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+// extern crates
+use gosling::replay::*;
+
+const USAGE: &str = "\
+usage: gosling-replay <COMMAND>
+
+commands:
+  record [OPTIONS] <FILE>  perform a pair of handshakes and save their transcript
+  replay <FILE>...         replay saved transcripts against the current state machines
+
+record options:
+  --endpoint <NAME>        endpoint the client requests (default: endpoint)
+  --channel <NAME>         channel the client requests (default: channel)
+  --identity-only          only perform the identity handshake
+  --deny-client            the identity server does not allow the client
+  --unsupported-endpoint   the identity server does not support the endpoint
+  --invalid-response       the identity server rejects the challenge response
+  --invalid-channel        the endpoint server rejects the channel";
+
+enum Command {
+    Record(Box<Scenario>, PathBuf),
+    Replay(Vec<PathBuf>),
+}
+
+fn parse_args() -> Result<Command, String> {
+    let mut args = std::env::args().skip(1);
+    let command = args.next();
+    match command.as_deref() {
+        Some("record") => {
+            let mut scenario = Scenario::new("endpoint", Some("channel"));
+            let mut path: Option<PathBuf> = None;
+            while let Some(arg) = args.next() {
+                let mut value = |name: &str| -> Result<String, String> {
+                    args.next().ok_or(format!("{name} requires a value"))
+                };
+                match arg.as_str() {
+                    "--endpoint" => scenario.endpoint = value("--endpoint")?,
+                    "--channel" => scenario.channel = Some(value("--channel")?),
+                    "--identity-only" => scenario.channel = None,
+                    "--deny-client" => scenario.client_allowed = false,
+                    "--unsupported-endpoint" => scenario.endpoint_supported = false,
+                    "--invalid-response" => scenario.challenge_response_valid = false,
+                    "--invalid-channel" => scenario.channel_valid = false,
+                    arg if path.is_none() && !arg.starts_with('-') => path = Some(arg.into()),
+                    arg => return Err(format!("unexpected argument: {arg}")),
+                }
+            }
+            match path {
+                Some(path) => Ok(Command::Record(Box::new(scenario), path)),
+                None => Err("record requires a file".to_string()),
+            }
+        }
+        Some("replay") => {
+            let paths: Vec<PathBuf> = args.map(PathBuf::from).collect();
+            if paths.is_empty() {
+                return Err("replay requires at least one file".to_string());
+            }
+            Ok(Command::Replay(paths))
+        }
+        Some("--help" | "-h") => {
+            println!("{USAGE}");
+            std::process::exit(0);
+        }
+        Some(command) => Err(format!("unexpected command: {command}")),
+        None => Err("a command is required".to_string()),
+    }
+}
+
+fn main() -> ExitCode {
+    let command = match parse_args() {
+        Ok(command) => command,
+        Err(err) => {
+            eprintln!("{USAGE}");
+            eprintln!("error: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    match command {
+        Command::Record(scenario, path) => {
+            let transcript = match record(*scenario) {
+                Ok(transcript) => transcript,
+                Err(err) => {
+                    eprintln!("error: {err}");
+                    return ExitCode::FAILURE;
+                }
+            };
+            if let Err(err) = std::fs::write(&path, transcript.to_bytes()) {
+                eprintln!("error: failed to write {}: {err}", path.display());
+                return ExitCode::FAILURE;
+            }
+            println!("recorded {}", path.display());
+            ExitCode::SUCCESS
+        }
+        Command::Replay(paths) => {
+            let mut result = ExitCode::SUCCESS;
+            for path in paths {
+                let replayed = std::fs::read(&path)
+                    .map_err(|err| err.to_string())
+                    .and_then(|bytes| Transcript::from_bytes(&bytes).map_err(|err| err.to_string()))
+                    .and_then(|transcript| replay(&transcript).map_err(|err| err.to_string()));
+                match replayed {
+                    Ok(()) => println!("{}: ok", path.display()),
+                    Err(err) => {
+                        println!("{}: {err}", path.display());
+                        result = ExitCode::FAILURE;
+                    }
+                }
+            }
+            result
+        }
+    }
+}
@@ -0,0 +1,264 @@
+// standard
+use std::time::Duration;
+
+// extern crates
+use bson::doc;
+use bson::spec::BinarySubtype;
+use bson::{Binary, Bson, DateTime, Document};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use tor_interface::tor_crypto::ConstantTimeEq;
+
+// internal crates
+use crate::gosling::{SystemTime, UNIX_EPOCH};
+
+/// The error type for the [`Challenge`] type.
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    /// An invalid argument was provided to a function
+    #[error("invalid argument: {0}")]
+    InvalidArgument(String),
+
+    /// A challenge document was missing its nonce or the nonce was malformed
+    #[error("challenge document has no valid nonce")]
+    MissingChallengeNonce(),
+
+    /// A challenge response did not echo a nonce
+    #[error("challenge response has no valid nonce")]
+    MissingResponseNonce(),
+
+    /// A challenge response echoed a nonce other than the challenge's
+    #[error("challenge response nonce does not match the challenge")]
+    NonceMismatch(),
+
+    /// A challenge response was verified after its challenge expired
+    #[error("challenge expired at {0}")]
+    Expired(DateTime),
+}
+
+// members of challenge documents built by this module; application data
+// may not use these keys
+const NONCE_KEY: &str = "nonce";
+const ISSUED_AT_KEY: &str = "issued_at";
+const EXPIRES_AT_KEY: &str = "expires_at";
+const NONCE_SIZE: usize = 32;
+
+fn now() -> DateTime {
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0i64, |elapsed| {
+            i64::try_from(elapsed.as_millis()).unwrap_or(i64::MAX)
+        });
+    DateTime::from_millis(millis)
+}
+
+// the generic binary nonce member of a challenge or challenge response
+fn nonce(document: &Document) -> Option<[u8; NONCE_SIZE]> {
+    match document.get(NONCE_KEY) {
+        Some(Bson::Binary(Binary {
+            subtype: BinarySubtype::Generic,
+            bytes,
+        })) => bytes.as_slice().try_into().ok(),
+        _ => None,
+    }
+}
+
+fn check_reserved_keys(application_data: &Document) -> Result<(), Error> {
+    for key in [NONCE_KEY, ISSUED_AT_KEY, EXPIRES_AT_KEY] {
+        if application_data.contains_key(key) {
+            return Err(Error::InvalidArgument(format!(
+                "application data may not contain '{}'",
+                key
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// An identity server's endpoint challenge containing a random server nonce, the time it was issued and the time it expires.
+///
+/// Identity servers create a `Challenge` for each [`ContextEvent::IdentityServerEndpointRequestReceived`](crate::context::ContextEvent::IdentityServerEndpointRequestReceived) event, send its [`Challenge::document()`] to the client and keep it until the client's challenge response is received. [`Challenge::verify_response()`] then checks the response echoes this challenge's nonce before it expires, so a response captured from a previous handshake cannot be replayed even if the application's own challenge data never changes. Identity clients build their response with [`challenge_response()`].
+#[derive(Clone, Debug)]
+pub struct Challenge {
+    nonce: [u8; NONCE_SIZE],
+    issued_at: DateTime,
+    expires_at: DateTime,
+    application_data: Document,
+}
+
+impl Challenge {
+    /// Create a new challenge with a random nonce which expires after the given validity period.
+    ///
+    /// # Parameters
+    /// - `validity`: how long after its creation the challenge's response is accepted; must not be zero
+    /// - `application_data`: any further application-specific challenge data; it must not contain the `nonce`, `issued_at` or `expires_at` keys
+    pub fn new(validity: Duration, application_data: Document) -> Result<Challenge, Error> {
+        if validity.is_zero() {
+            return Err(Error::InvalidArgument(
+                "validity must not be zero".to_string(),
+            ));
+        }
+        check_reserved_keys(&application_data)?;
+
+        let validity = i64::try_from(validity.as_millis()).unwrap_or(i64::MAX);
+        let issued_at = now();
+        let expires_at =
+            DateTime::from_millis(issued_at.timestamp_millis().saturating_add(validity));
+
+        let mut nonce = [0u8; NONCE_SIZE];
+        OsRng.fill_bytes(&mut nonce);
+
+        Ok(Challenge {
+            nonce,
+            issued_at,
+            expires_at,
+            application_data,
+        })
+    }
+
+    /// The challenge document to send to the identity client; it contains the application data along with the generic binary `nonce` and the `issued_at` and `expires_at` dates.
+    pub fn document(&self) -> Document {
+        let mut document = doc! {
+            NONCE_KEY: Bson::Binary(Binary {
+                subtype: BinarySubtype::Generic,
+                bytes: self.nonce.to_vec(),
+            }),
+            ISSUED_AT_KEY: self.issued_at,
+            EXPIRES_AT_KEY: self.expires_at,
+        };
+        document.extend(self.application_data.clone());
+        document
+    }
+
+    /// The challenge's random 32-byte nonce.
+    pub fn nonce(&self) -> &[u8; NONCE_SIZE] {
+        &self.nonce
+    }
+
+    /// The time the challenge was created.
+    pub fn issued_at(&self) -> DateTime {
+        self.issued_at
+    }
+
+    /// The time after which responses to the challenge are no longer accepted.
+    pub fn expires_at(&self) -> DateTime {
+        self.expires_at
+    }
+
+    /// The application-specific challenge data.
+    pub fn application_data(&self) -> &Document {
+        &self.application_data
+    }
+
+    /// Check a challenge response echoes this challenge's nonce and that the challenge has not expired. The application-specific parts of the response are not checked.
+    ///
+    /// # Parameters
+    /// - `challenge_response`: the challenge response received from the identity client
+    pub fn verify_response(&self, challenge_response: &Document) -> Result<(), Error> {
+        self.verify_response_at(challenge_response, now())
+    }
+
+    fn verify_response_at(
+        &self,
+        challenge_response: &Document,
+        now: DateTime,
+    ) -> Result<(), Error> {
+        if now > self.expires_at {
+            return Err(Error::Expired(self.expires_at));
+        }
+        match nonce(challenge_response) {
+            Some(nonce) if bool::from(nonce[..].ct_eq(&self.nonce[..])) => Ok(()),
+            Some(_) => Err(Error::NonceMismatch()),
+            None => Err(Error::MissingResponseNonce()),
+        }
+    }
+}
+
+/// Build an identity client's response to a challenge created with [`Challenge`], echoing the challenge's nonce.
+///
+/// # Parameters
+/// - `challenge`: the endpoint challenge received from the identity server
+/// - `application_data`: the application-specific challenge response; it must not contain the `nonce`, `issued_at` or `expires_at` keys
+pub fn challenge_response(
+    challenge: &Document,
+    application_data: Document,
+) -> Result<Document, Error> {
+    check_reserved_keys(&application_data)?;
+    let nonce = nonce(challenge).ok_or(Error::MissingChallengeNonce())?;
+
+    let mut challenge_response = doc! {
+        NONCE_KEY: Bson::Binary(Binary {
+            subtype: BinarySubtype::Generic,
+            bytes: nonce.to_vec(),
+        }),
+    };
+    challenge_response.extend(application_data);
+    Ok(challenge_response)
+}
+
+#[test]
+fn test_challenge() -> anyhow::Result<()> {
+    let challenge = Challenge::new(Duration::from_secs(60), doc! {"question": "2 + 2"})?;
+    let document = challenge.document();
+    assert_eq!(document.get_str("question")?, "2 + 2");
+    assert_eq!(
+        document.get_datetime(ISSUED_AT_KEY)?,
+        &challenge.issued_at()
+    );
+    assert_eq!(
+        challenge.expires_at().timestamp_millis() - challenge.issued_at().timestamp_millis(),
+        60_000
+    );
+
+    // a response echoing the nonce is accepted
+    let response = challenge_response(&document, doc! {"answer": 4})?;
+    assert_eq!(response.get_i32("answer")?, 4);
+    challenge.verify_response(&response)?;
+
+    // ...but not once the challenge has expired
+    let after_expiry = DateTime::from_millis(challenge.expires_at().timestamp_millis() + 1);
+    assert!(matches!(
+        challenge.verify_response_at(&response, after_expiry),
+        Err(Error::Expired(_))
+    ));
+
+    // a response to a different challenge is rejected even if the application data is identical
+    let other = Challenge::new(Duration::from_secs(60), doc! {"question": "2 + 2"})?;
+    assert!(matches!(
+        other.verify_response(&response),
+        Err(Error::NonceMismatch())
+    ));
+
+    // responses must contain a 32-byte generic binary nonce
+    assert!(matches!(
+        challenge.verify_response(&doc! {"answer": 4}),
+        Err(Error::MissingResponseNonce())
+    ));
+    assert!(matches!(
+        challenge.verify_response(&doc! {NONCE_KEY: Bson::Binary(Binary {
+            subtype: BinarySubtype::Generic,
+            bytes: vec![0u8; 16],
+        })}),
+        Err(Error::MissingResponseNonce())
+    ));
+    assert!(matches!(
+        challenge_response(&doc! {}, doc! {}),
+        Err(Error::MissingChallengeNonce())
+    ));
+
+    // reserved keys and a zero validity are rejected
+    assert!(matches!(
+        Challenge::new(Duration::from_secs(60), doc! {NONCE_KEY: 1}),
+        Err(Error::InvalidArgument(_))
+    ));
+    assert!(matches!(
+        challenge_response(&document, doc! {EXPIRES_AT_KEY: 1}),
+        Err(Error::InvalidArgument(_))
+    ));
+    assert!(matches!(
+        Challenge::new(Duration::ZERO, doc! {}),
+        Err(Error::InvalidArgument(_))
+    ));
+
+    Ok(())
+}
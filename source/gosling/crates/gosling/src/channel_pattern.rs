@@ -0,0 +1,114 @@
+// internal crates
+use crate::ascii_string::*;
+
+/// The error type for the [`ChannelPattern`] type.
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    /// The provided pattern is empty or not ASCII
+    #[error("channel pattern must be a non-empty ASCII string: {0}")]
+    InvalidPattern(String),
+}
+
+//
+// A glob-style pattern used by endpoint servers to accept
+// channel requests without consulting the application:
+// - '*' matches any (possibly empty) sequence of characters
+// - '?' matches exactly one character
+// - every other character matches itself
+//
+// e.g. "file-transfer/*" matches "file-transfer/" and "file-transfer/photos"
+//
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChannelPattern {
+    pattern: AsciiString,
+}
+
+impl ChannelPattern {
+    pub fn new(pattern: String) -> Result<Self, Error> {
+        if pattern.is_empty() {
+            return Err(Error::InvalidPattern(pattern));
+        }
+        match AsciiString::new(pattern.clone()) {
+            Ok(pattern) => Ok(Self { pattern }),
+            Err(_) => Err(Error::InvalidPattern(pattern)),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.pattern
+    }
+
+    pub fn matches(&self, channel: &str) -> bool {
+        let pattern = self.pattern.as_bytes();
+        let channel = channel.as_bytes();
+
+        let mut p = 0usize;
+        let mut c = 0usize;
+        // position of the most recent '*' in the pattern and the channel
+        // position it is currently matched up to
+        let mut backtrack: Option<(usize, usize)> = None;
+
+        while c < channel.len() {
+            match pattern.get(p) {
+                Some(b'*') => {
+                    backtrack = Some((p, c));
+                    p += 1;
+                }
+                Some(b'?') => {
+                    p += 1;
+                    c += 1;
+                }
+                Some(ch) if *ch == channel[c] => {
+                    p += 1;
+                    c += 1;
+                }
+                // mismatch, so let the last '*' consume one more char
+                _ => match backtrack {
+                    Some((star_p, star_c)) => {
+                        backtrack = Some((star_p, star_c + 1));
+                        p = star_p + 1;
+                        c = star_c + 1;
+                    }
+                    None => return false,
+                },
+            }
+        }
+
+        // any remaining pattern must only be '*'s
+        pattern[p..].iter().all(|ch| *ch == b'*')
+    }
+}
+
+#[test]
+fn test_channel_pattern() -> anyhow::Result<()> {
+    let cases: &[(&str, &str, bool)] = &[
+        ("channel", "channel", true),
+        ("channel", "channel2", false),
+        ("channel", "chan", false),
+        ("file-transfer/*", "file-transfer/", true),
+        ("file-transfer/*", "file-transfer/photos/2024", true),
+        ("file-transfer/*", "file-transfer", false),
+        ("file-transfer/*", "chat/file-transfer/", false),
+        ("*", "", true),
+        ("*", "anything", true),
+        ("chat-?", "chat-1", true),
+        ("chat-?", "chat-", false),
+        ("chat-?", "chat-12", false),
+        ("*/v?/*", "api/v2/messages", true),
+        ("*/v?/*", "api/v22/messages", false),
+        ("a*b*c", "aXXbYYbZZc", true),
+        ("a*b*c", "aXXbYYbZZ", false),
+        ("**", "x", true),
+    ];
+
+    for (pattern, channel, expected) in cases {
+        let channel_pattern = ChannelPattern::new(pattern.to_string())?;
+        println!("pattern: '{}', channel: '{}'", pattern, channel);
+        assert_eq!(channel_pattern.matches(channel), *expected);
+    }
+
+    assert!(ChannelPattern::new("".to_string()).is_err());
+    assert!(ChannelPattern::new("𝕦𝕥𝕗𝟠/*".to_string()).is_err());
+
+    Ok(())
+}
@@ -0,0 +1,259 @@
+// standard
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+
+// extern crates
+use tor_interface::tor_crypto::*;
+
+// internal crates
+use crate::gosling::Instant;
+use crate::protocol::EndpointQuotaExceededErrorData;
+
+/// Per-client limits on the channels opened through a [`Context`](crate::context::Context)'s endpoint servers; see [`Context::endpoint_server_set_channel_quota()`](crate::context::Context::endpoint_server_set_channel_quota).
+///
+/// Limits apply to each client across all of the `Context`'s endpoint servers, and only to clients which have proven their identity in the endpoint handshake, so that a peer cannot use up another client's quota.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ChannelQuota {
+    /// The maximum number of channels a client may have open at once, if limited. A channel counts as open from the moment its client's proof is accepted until the application reports it closed with [`Context::endpoint_server_channel_closed()`](crate::context::Context::endpoint_server_channel_closed), or until its handshake fails.
+    pub max_open_channels: Option<usize>,
+    /// The maximum number of channels a client may open within a sliding time window, if limited
+    pub max_channel_requests: Option<(u32, Duration)>,
+}
+
+/// Which limit of a [`ChannelQuota`] an endpoint handshake exceeded
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChannelQuotaExceeded {
+    /// The client already has as many channels open as allowed
+    OpenChannels {
+        /// The maximum number of open channels
+        limit: usize,
+    },
+    /// The client has already opened as many channels as allowed within the time window
+    ChannelRequests {
+        /// The maximum number of channels opened within the time window
+        limit: u32,
+        /// The length of the time window
+        window: Duration,
+        /// How long the client must wait before it may open another channel
+        retry_after: Duration,
+    },
+}
+
+impl std::fmt::Display for ChannelQuotaExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChannelQuotaExceeded::OpenChannels { limit } => {
+                write!(f, "at most {limit} channels may be open at once")
+            }
+            ChannelQuotaExceeded::ChannelRequests {
+                limit,
+                window,
+                retry_after,
+            } => write!(
+                f,
+                "at most {limit} channels may be opened every {window:?}; retry after {retry_after:?}"
+            ),
+        }
+    }
+}
+
+impl ChannelQuotaExceeded {
+    // the error data sent to the client; durations are rounded up to whole seconds
+    pub(crate) fn to_error_data(self) -> EndpointQuotaExceededErrorData {
+        let seconds = |duration: Duration| {
+            (duration.as_secs() + u64::from(duration.subsec_nanos() > 0)) as i64
+        };
+        match self {
+            ChannelQuotaExceeded::OpenChannels { limit } => EndpointQuotaExceededErrorData {
+                quota: "open_channels".to_string(),
+                limit: limit as i64,
+                window: None,
+                retry_after: None,
+            },
+            ChannelQuotaExceeded::ChannelRequests {
+                limit,
+                window,
+                retry_after,
+            } => EndpointQuotaExceededErrorData {
+                quota: "channel_requests".to_string(),
+                limit: i64::from(limit),
+                window: Some(seconds(window)),
+                retry_after: Some(seconds(retry_after)),
+            },
+        }
+    }
+
+    // parse the error data received from an endpoint server
+    pub(crate) fn from_error_data(data: EndpointQuotaExceededErrorData) -> Option<Self> {
+        let seconds = |seconds: Option<i64>| match seconds {
+            Some(seconds) if seconds >= 0 => Some(Duration::from_secs(seconds as u64)),
+            _ => None,
+        };
+        match data.quota.as_str() {
+            "open_channels" => Some(ChannelQuotaExceeded::OpenChannels {
+                limit: data.limit.try_into().ok()?,
+            }),
+            "channel_requests" => Some(ChannelQuotaExceeded::ChannelRequests {
+                limit: data.limit.try_into().ok()?,
+                window: seconds(data.window)?,
+                retry_after: seconds(data.retry_after)?,
+            }),
+            _ => None,
+        }
+    }
+}
+
+// a client's use of its quota
+#[derive(Default)]
+struct ClientUsage {
+    open_channels: usize,
+    // when the channels opened within the time window were admitted, oldest first
+    admitted: VecDeque<Instant>,
+}
+
+// tracks each client's open channels and recently admitted channel requests
+pub(crate) struct ChannelQuotaTracker {
+    quota: ChannelQuota,
+    clients: HashMap<V3OnionServiceId, ClientUsage>,
+    // the time of the most recent admission, which stands in for the current
+    // time when permits are released
+    now: Option<Instant>,
+}
+
+// the tracker is shared by all of a context's endpoint servers
+pub(crate) type SharedChannelQuotaTracker = Arc<Mutex<ChannelQuotaTracker>>;
+
+impl ChannelQuotaTracker {
+    pub fn new(quota: ChannelQuota) -> Self {
+        Self {
+            quota,
+            clients: Default::default(),
+            now: None,
+        }
+    }
+
+    // replace the limits; channels already open keep counting against them
+    pub fn set_quota(&mut self, quota: ChannelQuota) {
+        self.quota = quota;
+    }
+
+    // admit a channel request from client, returning a permit which counts as
+    // one of its open channels until dropped
+    pub fn admit(
+        tracker: &SharedChannelQuotaTracker,
+        client: &V3OnionServiceId,
+        now: Instant,
+    ) -> Result<ChannelQuotaPermit, ChannelQuotaExceeded> {
+        let mut guard = tracker.lock().unwrap_or_else(PoisonError::into_inner);
+        guard.now = Some(now);
+        let quota = guard.quota;
+        let usage = guard.clients.entry(client.clone()).or_default();
+
+        if let Some(limit) = quota.max_open_channels {
+            if usage.open_channels >= limit {
+                return Err(ChannelQuotaExceeded::OpenChannels { limit });
+            }
+        }
+        if let Some((limit, window)) = quota.max_channel_requests {
+            while usage
+                .admitted
+                .front()
+                .is_some_and(|admitted| now.saturating_duration_since(*admitted) >= window)
+            {
+                usage.admitted.pop_front();
+            }
+            if usage.admitted.len() >= limit as usize {
+                // the oldest admitted request leaves the window first
+                let retry_after = usage.admitted.front().map_or(window, |admitted| {
+                    window.saturating_sub(now.saturating_duration_since(*admitted))
+                });
+                return Err(ChannelQuotaExceeded::ChannelRequests {
+                    limit,
+                    window,
+                    retry_after,
+                });
+            }
+            usage.admitted.push_back(now);
+        }
+        usage.open_channels += 1;
+
+        Ok(ChannelQuotaPermit {
+            tracker: tracker.clone(),
+            client: client.clone(),
+        })
+    }
+
+    fn release(&mut self, client: &V3OnionServiceId) {
+        if let Some(usage) = self.clients.get_mut(client) {
+            usage.open_channels = usage.open_channels.saturating_sub(1);
+            // forget clients once nothing of theirs counts against the quota
+            let window = self.quota.max_channel_requests.map(|(_, window)| window);
+            let now = self.now;
+            usage.admitted.retain(|admitted| match (window, now) {
+                (Some(window), Some(now)) => now.saturating_duration_since(*admitted) < window,
+                _ => false,
+            });
+            if usage.open_channels == 0 && usage.admitted.is_empty() {
+                self.clients.remove(client);
+            }
+        }
+    }
+}
+
+// one of a client's open channels; the channel is released when dropped
+pub(crate) struct ChannelQuotaPermit {
+    tracker: SharedChannelQuotaTracker,
+    client: V3OnionServiceId,
+}
+
+impl Drop for ChannelQuotaPermit {
+    fn drop(&mut self) {
+        self.tracker
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .release(&self.client);
+    }
+}
+
+#[test]
+fn test_channel_quota_tracker() {
+    let alice = V3OnionServiceId::from_private_key(&Ed25519PrivateKey::generate());
+    let pat = V3OnionServiceId::from_private_key(&Ed25519PrivateKey::generate());
+    let tracker: SharedChannelQuotaTracker =
+        Arc::new(Mutex::new(ChannelQuotaTracker::new(ChannelQuota {
+            max_open_channels: Some(2),
+            max_channel_requests: None,
+        })));
+    let now = Instant::now();
+
+    // open channels are limited per client and released with their permits
+    let first = ChannelQuotaTracker::admit(&tracker, &alice, now).unwrap();
+    let _second = ChannelQuotaTracker::admit(&tracker, &alice, now).unwrap();
+    assert_eq!(
+        ChannelQuotaTracker::admit(&tracker, &alice, now).err(),
+        Some(ChannelQuotaExceeded::OpenChannels { limit: 2 })
+    );
+    let _pat = ChannelQuotaTracker::admit(&tracker, &pat, now).unwrap();
+    drop(first);
+    let _third = ChannelQuotaTracker::admit(&tracker, &alice, now).unwrap();
+
+    // channel requests are limited within a sliding window
+    let window = Duration::from_secs(60);
+    tracker.lock().unwrap().set_quota(ChannelQuota {
+        max_open_channels: None,
+        max_channel_requests: Some((2, window)),
+    });
+    let _first = ChannelQuotaTracker::admit(&tracker, &pat, now).unwrap();
+    let _second =
+        ChannelQuotaTracker::admit(&tracker, &pat, now + Duration::from_secs(10)).unwrap();
+    assert_eq!(
+        ChannelQuotaTracker::admit(&tracker, &pat, now + Duration::from_secs(20)).err(),
+        Some(ChannelQuotaExceeded::ChannelRequests {
+            limit: 2,
+            window,
+            retry_after: Duration::from_secs(40),
+        })
+    );
+    assert!(ChannelQuotaTracker::admit(&tracker, &pat, now + window).is_ok());
+}
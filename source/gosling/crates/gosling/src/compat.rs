@@ -0,0 +1,5 @@
+// Deprecated names are kept here when a stable item is renamed or moved, and
+// are removed in the next release which bumps the minor version. Shims must be
+// items which carry the deprecation to their callers (functions, type aliases,
+// modules or constants) since #[deprecated] has no effect on re-exports. Only
+// items which have shipped in a release need a shim.
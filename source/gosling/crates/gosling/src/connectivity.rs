@@ -0,0 +1,176 @@
+// standard
+use std::time::Duration;
+
+// internal crates
+use crate::gosling::Instant;
+
+/// The outcome of the descriptor fetch made by a connectivity check; see [`Context::check_connectivity()`](crate::context::Context::check_connectivity)
+#[derive(Clone, Debug, PartialEq)]
+pub enum DescriptorFetchOutcome {
+    /// The identity server's descriptor was received, so the [`TorProvider`](tor_interface::tor_provider::TorProvider) can reach the Tor Network and the identity server is published
+    Received,
+    /// A hidden-service directory answered that it has no descriptor of the identity server, so the [`TorProvider`](tor_interface::tor_provider::TorProvider) can reach the Tor Network but the identity server is not (yet) published
+    NotFound,
+    /// The fetch failed, e.g. because no hidden-service directory could be reached
+    Failed {
+        /// Why the fetch failed, if reported by the provider
+        reason: Option<String>,
+    },
+    /// The fetch did not finish before the check's timeout
+    TimedOut,
+}
+
+/// The results of a connectivity check; see [`Context::check_connectivity()`](crate::context::Context::check_connectivity).
+///
+/// A check which reaches the Tor Network suggests a peer which cannot be connected to is offline, while one which does not suggests the [`Context`](crate::context::Context)'s own connectivity is broken.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConnectivityReport {
+    /// Whether the [`TorProvider`](tor_interface::tor_provider::TorProvider) had bootstrapped when the check began
+    pub bootstrapped: bool,
+    /// The outcome of fetching the identity server's descriptor, or `None` if the check was not attempted because the provider had not bootstrapped
+    pub descriptor_fetch: Option<DescriptorFetchOutcome>,
+    /// The provider's identifier for the hidden-service directory the descriptor was fetched from, if reported
+    pub hs_dir: Option<String>,
+    /// How long the check took
+    pub duration: Duration,
+    /// The number of circuits the provider built while the check was in progress. Only counted by providers able to observe circuits.
+    pub circuits_built: usize,
+    /// The number of circuits which failed to build while the check was in progress. Only counted by providers able to observe circuits.
+    pub circuits_failed: usize,
+}
+
+impl ConnectivityReport {
+    /// Whether the check reached the Tor Network, i.e. a hidden-service directory answered the descriptor fetch
+    pub fn tor_reachable(&self) -> bool {
+        matches!(
+            self.descriptor_fetch,
+            Some(DescriptorFetchOutcome::Received | DescriptorFetchOutcome::NotFound)
+        )
+    }
+}
+
+// an in-progress connectivity check
+pub(crate) struct ConnectivityCheck {
+    started: Instant,
+    timeout: Duration,
+    // whether the descriptor fetch was requested; checks begun before
+    // bootstrap complete without one
+    fetching: bool,
+    circuits_built: usize,
+    circuits_failed: usize,
+}
+
+impl ConnectivityCheck {
+    pub fn new(started: Instant, timeout: Duration, fetching: bool) -> Self {
+        Self {
+            started,
+            timeout,
+            fetching,
+            circuits_built: 0,
+            circuits_failed: 0,
+        }
+    }
+
+    pub fn circuit_built(&mut self) {
+        self.circuits_built += 1;
+    }
+
+    pub fn circuit_failed(&mut self) {
+        self.circuits_failed += 1;
+    }
+
+    // the time left before the check times out
+    pub fn remaining(&self, now: Instant) -> Duration {
+        if self.fetching {
+            self.timeout
+                .saturating_sub(now.saturating_duration_since(self.started))
+        } else {
+            Duration::ZERO
+        }
+    }
+
+    // the report of a check whose fetch finished with outcome, or of one which
+    // had no fetch to wait for or timed out if None
+    pub fn finish(
+        self,
+        now: Instant,
+        outcome: Option<DescriptorFetchOutcome>,
+        hs_dir: Option<String>,
+    ) -> ConnectivityReport {
+        let descriptor_fetch = match outcome {
+            Some(outcome) => Some(outcome),
+            None if self.fetching => Some(DescriptorFetchOutcome::TimedOut),
+            None => None,
+        };
+        ConnectivityReport {
+            bootstrapped: self.fetching,
+            descriptor_fetch,
+            hs_dir,
+            duration: now.saturating_duration_since(self.started),
+            circuits_built: self.circuits_built,
+            circuits_failed: self.circuits_failed,
+        }
+    }
+}
+
+// the outcome of a finished descriptor fetch
+pub(crate) fn descriptor_fetch_outcome(
+    succeeded: bool,
+    reason: Option<String>,
+) -> DescriptorFetchOutcome {
+    match (succeeded, reason.as_deref()) {
+        (true, _) => DescriptorFetchOutcome::Received,
+        (false, Some("NOT_FOUND")) => DescriptorFetchOutcome::NotFound,
+        (false, _) => DescriptorFetchOutcome::Failed { reason },
+    }
+}
+
+#[test]
+fn test_connectivity_check() {
+    let started = Instant::now();
+    let timeout = Duration::from_secs(30);
+
+    // checks time out once their fetch has taken too long
+    let mut check = ConnectivityCheck::new(started, timeout, true);
+    check.circuit_built();
+    check.circuit_failed();
+    check.circuit_failed();
+    assert_eq!(
+        check.remaining(started + Duration::from_secs(10)),
+        Duration::from_secs(20)
+    );
+    assert_eq!(check.remaining(started + timeout), Duration::ZERO);
+    let report = check.finish(started + timeout, None, None);
+    assert!(report.bootstrapped);
+    assert_eq!(
+        report.descriptor_fetch,
+        Some(DescriptorFetchOutcome::TimedOut)
+    );
+    assert_eq!(report.duration, timeout);
+    assert_eq!((report.circuits_built, report.circuits_failed), (1, 2));
+    assert!(!report.tor_reachable());
+
+    // a directory without the descriptor was still reached
+    let check = ConnectivityCheck::new(started, timeout, true);
+    let outcome = descriptor_fetch_outcome(false, Some("NOT_FOUND".to_string()));
+    let report = check.finish(started, Some(outcome), Some("$AAAA~alice".to_string()));
+    assert_eq!(
+        report.descriptor_fetch,
+        Some(DescriptorFetchOutcome::NotFound)
+    );
+    assert!(report.tor_reachable());
+    assert_eq!(
+        descriptor_fetch_outcome(false, Some("QUERY_NO_HSDIR".to_string())),
+        DescriptorFetchOutcome::Failed {
+            reason: Some("QUERY_NO_HSDIR".to_string())
+        }
+    );
+
+    // checks begun before bootstrap finish immediately
+    let check = ConnectivityCheck::new(started, timeout, false);
+    assert_eq!(check.remaining(started), Duration::ZERO);
+    let report = check.finish(started, None, None);
+    assert!(!report.bootstrapped);
+    assert_eq!(report.descriptor_fetch, None);
+    assert!(!report.tor_reachable());
+}
@@ -1,29 +1,384 @@
 // standard
 use std::clone::Clone;
-use std::collections::{BTreeMap, HashMap, VecDeque};
-use std::net::TcpStream;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::io::Write;
+use std::net::{Shutdown, TcpStream};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
 // extern crates
 use honk_rpc::honk_rpc::*;
+use tor_interface::clock::{Clock, SystemClock};
 use tor_interface::tor_crypto::*;
 use tor_interface::tor_provider::*;
 
 // internal crates
 use crate::ascii_string::*;
+use crate::bans::{Ban, BanList};
+use crate::channel_pattern::*;
+use crate::channel_quota::*;
+use crate::connectivity::*;
+use crate::datagram_channel::DatagramChannel;
 use crate::endpoint_client;
 use crate::endpoint_client::*;
+use crate::endpoint_grant::EndpointGrant;
+use crate::endpoint_keys::derive_endpoint_private_key;
+use crate::endpoint_revocation::EndpointRevocation;
 use crate::endpoint_server;
 use crate::endpoint_server::*;
+use crate::events_sink::ContextEventsSink;
+use crate::gosling::{
+    Instant, ReplayCache, SharedReplayCache, SystemTime, MAX_ADDITIONAL_ENDPOINTS,
+    MAX_CONTACT_REQUEST_SIZE,
+};
 use crate::identity_client;
 use crate::identity_client::*;
 use crate::identity_server;
 use crate::identity_server::*;
+use crate::petnames::PetnameStore;
+use crate::pinning::{PinStore, PinVerdict};
+#[cfg(feature = "prometheus")]
+use crate::prometheus::PrometheusWriter;
+use crate::protocol::{Capabilities, MAX_CHANNEL_NAME_LENGTH, MAX_ENDPOINT_NAME_LENGTH};
+use crate::rpc_channel::{Heartbeat, RpcChannel};
+use crate::timing::*;
 
-/// A handle to an in-progres identity or endpoint handshake
+/// A handle to an in-progress identity or endpoint handshake. Handles are allocated by each [`Context`] and are only unique within the `Context` which allocated them.
 pub type HandshakeHandle = usize;
+/// A handle to an endpoint server started with [`Context::endpoint_server_start_with_completion()`] which is waiting to be published. Handles are allocated by each [`Context`] and are only unique within the `Context` which allocated them.
+pub type PublishHandle = usize;
 const DEFAULT_ENDPOINT_TIMEOUT: Duration = Duration::from_secs(60);
-const DEFAULT_ENDPOINT_MAX_MESSAGE_SIZE: i32 = 384;
+// large enough for a begin_handshake naming the longest channel and a shared
+// endpoint while offering every capability
+const DEFAULT_ENDPOINT_MAX_MESSAGE_SIZE: i32 = 1024;
+// upper bound on the number of identity servers remembered as unreachable
+const MAX_UNREACHABLE_IDENTITY_SERVERS: usize = 256;
+// the lowest virt-port returned by Context::derived_virt_port(); lower ports
+// are left to well-known services
+const DERIVED_VIRT_PORT_MIN: u16 = 1024;
+// how often update() should be called while handshakes wait on their peers
+const BUSY_UPDATE_INTERVAL: Duration = Duration::from_millis(10);
+// how often update() should be called while only listeners and tor need attention
+const IDLE_UPDATE_INTERVAL: Duration = Duration::from_millis(100);
+
+// an outgoing handshake waiting for an outbound connection slot
+enum PendingHandshake {
+    Identity {
+        identity_server_id: V3OnionServiceId,
+        endpoint: AsciiString,
+        ignore_cached_failure: bool,
+        endpoint_upgrade_channel: Option<AsciiString>,
+        additional_endpoints: Vec<AsciiString>,
+        contact_request: Option<String>,
+        circuit_token: Option<CircuitToken>,
+    },
+    Endpoint {
+        endpoint_server_id: V3OnionServiceId,
+        client_auth_key: X25519PrivateKey,
+        channel: AsciiString,
+        circuit_token: Option<CircuitToken>,
+    },
+    // an endpoint server reached through its identity server's onion-service
+    SharedEndpoint {
+        identity_server_id: V3OnionServiceId,
+        endpoint_server_id: V3OnionServiceId,
+        channel: AsciiString,
+    },
+}
+
+// an endpoint handshake to begin once its identity handshake completes
+type EndpointUpgrade = (
+    HandshakeHandle,
+    V3OnionServiceId,
+    X25519PrivateKey,
+    AsciiString,
+    Option<CircuitToken>,
+);
+
+struct QueuedHandshake {
+    handle: HandshakeHandle,
+    priority: i32,
+    handshake: PendingHandshake,
+    // last queue position reported to the caller
+    reported_position: Option<usize>,
+    // queued because tor had not yet bootstrapped, so its start is reported
+    awaiting_bootstrap: bool,
+}
+
+/// The policy applied when [`Context::update()`] produces more events than the capacity set with [`Context::set_event_queue_capacity()`]. Only lossy events, which report progress or status, are ever merged or discarded: [`ContextEvent::TorLogReceived`], [`ContextEvent::TorBootstrapStatusReceived`], [`ContextEvent::TorCircuitStatusChanged`], [`ContextEvent::TorStreamStatusChanged`], [`ContextEvent::TorOnionServiceDescriptorUploadStatus`] and [`ContextEvent::OutboundHandshakeQueued`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EventQueueOverflowPolicy {
+    /// Discard the oldest lossy event to make room for each new one and return a [`ContextEvent::EventQueueOverflowed`] event before the remaining events
+    DropOldest,
+    /// Merge each new [`ContextEvent::TorLogReceived`] event into the oldest one, separating their lines with `'\n'`. Other lossy events are discarded as with [`EventQueueOverflowPolicy::DropOldest`]. A [`ContextEvent::EventQueueOverflowed`] event is returned in either case.
+    CoalesceLogs,
+    /// Discard the newest lossy events and return [`Error::EventQueueOverflow`] from [`Context::update()`]. The remaining events are returned by the next call to [`Context::update()`], which collects no new events.
+    Error,
+}
+
+// the limit set with Context::set_event_queue_capacity(), and the events the
+// current update() merged or discarded to stay within it
+struct EventQueueLimit {
+    capacity: Option<usize>,
+    overflow_policy: EventQueueOverflowPolicy,
+    dropped_events: usize,
+    coalesced_tor_logs: usize,
+}
+
+impl EventQueueLimit {
+    // queue a lossy event, making room for it according to the overflow policy
+    // once the queue is full; other events are never discarded, so the queue
+    // only grows past its capacity by the events the context's handshakes and
+    // listeners produce
+    fn push_lossy(&mut self, events: &mut VecDeque<ContextEvent>, event: ContextEvent) {
+        debug_assert!(event.is_lossy());
+        match self.capacity {
+            Some(capacity) if events.len() >= capacity => (),
+            _ => {
+                events.push_back(event);
+                return;
+            }
+        }
+
+        if self.overflow_policy == EventQueueOverflowPolicy::CoalesceLogs {
+            if let ContextEvent::TorLogReceived { line } = &event {
+                let oldest_line = events.iter_mut().find_map(|event| match event {
+                    ContextEvent::TorLogReceived { line } => Some(line),
+                    _ => None,
+                });
+                if let Some(oldest_line) = oldest_line {
+                    oldest_line.push('\n');
+                    oldest_line.push_str(line);
+                    self.coalesced_tor_logs += 1;
+                    return;
+                }
+            }
+        }
+
+        // discard the oldest lossy event in favour of this one, or this one if
+        // there are none or the policy is to keep the oldest
+        self.dropped_events += 1;
+        if self.overflow_policy != EventQueueOverflowPolicy::Error {
+            if let Some(index) = events.iter().position(ContextEvent::is_lossy) {
+                events.remove(index);
+                events.push_back(event);
+            }
+        }
+    }
+
+    // report any overflow of the current update() and return the events to hold
+    // back for the next update(); with the error policy every event is held back
+    // and the number of events discarded is returned instead
+    fn finish(
+        &mut self,
+        events: &mut VecDeque<ContextEvent>,
+    ) -> Result<VecDeque<ContextEvent>, usize> {
+        let dropped_events = std::mem::take(&mut self.dropped_events);
+        let coalesced_tor_logs = std::mem::take(&mut self.coalesced_tor_logs);
+        let capacity = match self.capacity {
+            Some(capacity) => capacity,
+            None => return Ok(Default::default()),
+        };
+
+        if dropped_events > 0 || coalesced_tor_logs > 0 {
+            tracing::warn!(dropped_events, coalesced_tor_logs, "event queue overflowed");
+            if self.overflow_policy == EventQueueOverflowPolicy::Error {
+                return Err(dropped_events);
+            }
+            events.push_front(ContextEvent::EventQueueOverflowed {
+                dropped_events,
+                coalesced_tor_logs,
+            });
+        }
+
+        // events which may not be discarded are returned by a later update()
+        // instead
+        if events.len() > capacity {
+            Ok(events.split_off(capacity))
+        } else {
+            Ok(Default::default())
+        }
+    }
+}
+
+/// Which pending connection an endpoint server sheds when its pending-connection queue is full. See [`Context::endpoint_server_set_concurrency_limit()`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PendingConnectionDropPolicy {
+    /// Close the connection which has been waiting longest to make room for the new one
+    DropOldest,
+    /// Close the new connection
+    DropNewest,
+}
+
+// an endpoint server's limit on concurrent incoming handshakes
+struct EndpointConcurrencyLimit {
+    max_concurrent_handshakes: usize,
+    pending_queue_capacity: usize,
+    drop_policy: PendingConnectionDropPolicy,
+    // accepted connections waiting for a handshake slot and when they were accepted
+    pending_connections: VecDeque<(TcpStream, Instant)>,
+}
+
+impl EndpointConcurrencyLimit {
+    // queue a newly accepted connection and shed any pending connections which
+    // have waited longer than timeout or no longer fit in the queue; returns the
+    // connections which may begin their handshake and the number of shed connections
+    fn admit(
+        &mut self,
+        stream: Option<TcpStream>,
+        handshakes: usize,
+        timeout: Duration,
+        now: Instant,
+    ) -> (Vec<TcpStream>, usize) {
+        let pending_count = self.pending_connections.len();
+        self.pending_connections
+            .retain(|(_stream, accepted)| now.saturating_duration_since(*accepted) < timeout);
+        let mut shed_count = pending_count - self.pending_connections.len();
+
+        if let Some(stream) = stream {
+            self.pending_connections.push_back((stream, now));
+        }
+
+        let mut streams: Vec<TcpStream> = Default::default();
+        while handshakes + streams.len() < self.max_concurrent_handshakes {
+            match self.pending_connections.pop_front() {
+                Some((stream, _accepted)) => streams.push(stream),
+                None => break,
+            }
+        }
+
+        while self.pending_connections.len() > self.pending_queue_capacity {
+            match self.drop_policy {
+                PendingConnectionDropPolicy::DropOldest => self.pending_connections.pop_front(),
+                PendingConnectionDropPolicy::DropNewest => self.pending_connections.pop_back(),
+            };
+            shed_count += 1;
+        }
+
+        (streams, shed_count)
+    }
+}
+
+// an endpoint server started with Context::endpoint_server_start_with_completion()
+// which has not yet been published
+struct PendingEndpointPublish {
+    handle: PublishHandle,
+    endpoint_name: AsciiString,
+    started: Instant,
+    timeout: Option<Duration>,
+}
+
+impl PendingEndpointPublish {
+    // time left until the publish times out, if it ever does
+    fn remaining(&self, now: Instant) -> Option<Duration> {
+        self.timeout
+            .map(|timeout| timeout.saturating_sub(now.saturating_duration_since(self.started)))
+    }
+}
+
+// everything needed to restart an endpoint server's onion-service with
+// another tor provider
+struct EndpointServerConfig {
+    private_key: Ed25519PrivateKey,
+    client_auth_keys: Vec<X25519PublicKey>,
+    non_anonymous: bool,
+    pow_defenses: Option<PowDefenses>,
+}
+
+// progress of a switch to another tor provider; see Context::set_tor_provider()
+enum TorProviderMigration {
+    // waiting for in-progress handshakes to finish with the current provider
+    Draining(Box<dyn TorProvider>),
+    // waiting for the new provider to bootstrap before restarting the identity
+    // server (if it was running) and the (endpoint service id, endpoint name,
+    // allowed client) endpoint servers
+    Bootstrapping {
+        identity_server: bool,
+        endpoint_servers: Vec<(V3OnionServiceId, AsciiString, V3OnionServiceId)>,
+    },
+}
+
+/// Which of the [`TorProvider`]'s events a [`Context`] forwards from [`Context::update()`]. See [`Context::set_tor_event_verbosity()`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TorEventVerbosity {
+    /// Forward bootstrap, log, and onion-service events
+    Normal,
+    /// Additionally forward circuit, stream and descriptor upload events as [`ContextEvent::TorCircuitStatusChanged`], [`ContextEvent::TorStreamStatusChanged`] and [`ContextEvent::TorOnionServiceDescriptorUploadStatus`]
+    Verbose,
+}
+
+/// How long a [`Context`] keeps the client authorization keys it adds to its [`TorProvider`] for outgoing endpoint handshakes. See [`Context::set_client_auth_retention()`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ClientAuthRetention {
+    /// Keep keys until they are removed with [`Context::remove_client_auth()`], so that retried handshakes need not add them again
+    Retain,
+    /// Remove an endpoint server's key once a handshake with it fails and none of its other handshakes are in progress; keys are kept after successful handshakes
+    RemoveOnFailure,
+    /// Remove an endpoint server's key once a handshake with it finishes, whether it succeeded or failed, and none of its other handshakes are in progress
+    RemoveOnCompletion,
+}
+
+/// The decision returned by an identity server's client filter. See [`Context::identity_server_set_client_filter()`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ClientFilterVerdict {
+    /// Continue the identity handshake
+    Allow,
+    /// Reject the identity handshake because the client has made too many requests
+    TooManyRequests,
+    /// Reject the identity handshake because the client is banned
+    Banned,
+}
+
+/// Why an identity server rejected an identity handshake, as reported to the identity client. See [`Error::IdentityHandshakeRejected`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HandshakeRejectionReason {
+    /// The identity server does not offer the requested endpoint
+    EndpointUnsupported,
+    /// The identity server did not accept the endpoint challenge response
+    ChallengeFailed,
+    /// The identity server does not permit the client to access the endpoint
+    NotAuthorized,
+}
+
+/// A filter function taking an identity client's alleged service id and requested endpoint name, see [`Context::identity_server_set_client_filter()`].
+pub type ClientFilter = dyn Fn(&V3OnionServiceId, &str) -> ClientFilterVerdict + Send + Sync;
+
+/// The decision returned by an identity client's endpoint validator. See [`Context::identity_client_set_endpoint_validator()`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EndpointValidatorVerdict {
+    /// Begin the identity handshake
+    Allow,
+    /// Reject the identity handshake because the endpoint name does not follow the application's naming rules
+    Malformed,
+    /// Reject the identity handshake because the application does not support the endpoint
+    Unsupported,
+}
+
+/// A validator function taking an identity server's service id and an endpoint name requested from it, see [`Context::identity_client_set_endpoint_validator()`].
+pub type EndpointValidator =
+    dyn Fn(&V3OnionServiceId, &str) -> EndpointValidatorVerdict + Send + Sync;
+
+/// The onion-service service-id of each endpoint server changed by [`Context::endpoint_servers_start()`] or [`Context::endpoint_servers_reconfigure()`] and whether the change succeeded.
+pub type EndpointServerResults = Vec<(V3OnionServiceId, Result<(), Error>)>;
+
+/// The configuration of an endpoint server started with [`Context::endpoint_servers_start()`] or [`Context::endpoint_servers_reconfigure()`]. The members have the same meaning as the parameters of [`Context::endpoint_server_start()`].
+#[derive(Clone)]
+pub struct EndpointConfig {
+    /// The ed25519 private key used to start the endpoint server's onion-service
+    pub private_key: Ed25519PrivateKey,
+    /// The ASCII-encoded endpoint name
+    pub endpoint_name: AsciiString,
+    /// The onion-service service-id of the client which will be connecting to the endpoint server
+    pub client_identity: V3OnionServiceId,
+    /// The x25519 public-keys used to encrypt the endpoint server's onion-service descriptor; must not be empty
+    pub client_auth_keys: Vec<X25519PublicKey>,
+    /// Whether to start the endpoint server's onion-service as a non-anonymous single onion-service
+    pub non_anonymous: bool,
+    /// The proof-of-work defenses to enable on the endpoint server's onion-service, if any; requires the underlying tor daemon to support them
+    pub pow_defenses: Option<PowDefenses>,
+    /// Whether the endpoint server is served on the identity server's onion-service rather than its own (see [`Context::identity_server_set_shared_endpoints_enabled()`]). Shared endpoint servers are authorized by the endpoint handshake alone, so `client_auth_keys` may be empty, and `non_anonymous` and `pow_defenses` are ignored
+    pub shared: bool,
+}
 
 /// The error type for the [`Context`] type.
 #[derive(thiserror::Error, Debug)]
@@ -32,6 +387,20 @@ pub enum Error {
     #[error("invalid argument: {0}")]
     InvalidArgument(String),
 
+    /// An endpoint name longer than [`MAX_ENDPOINT_NAME_LENGTH`] bytes was provided
+    #[error(
+        "endpoint name is {0} bytes long; at most {} bytes are allowed",
+        MAX_ENDPOINT_NAME_LENGTH
+    )]
+    EndpointNameTooLong(usize),
+
+    /// A channel name longer than [`MAX_CHANNEL_NAME_LENGTH`] bytes was provided
+    #[error(
+        "channel name is {0} bytes long; at most {} bytes are allowed",
+        MAX_CHANNEL_NAME_LENGTH
+    )]
+    ChannelNameTooLong(usize),
+
     /// Function requiring tor connectivity called before bootstrap
     #[error(
         "context is not connected, must call bootstrap() and wait for TorBootstrapCompleted event"
@@ -62,6 +431,34 @@ pub enum Error {
     #[error(transparent)]
     TorProvider(#[from] tor_interface::tor_provider::Error),
 
+    /// The client authorization key for an endpoint server could not be registered with the tor provider; the underlying failure is returned in a [`ContextEvent::ClientAuthAddFailed`] event
+    #[error("failed to add client authorization key for endpoint server {0}")]
+    ClientAuthAddFailed(V3OnionServiceId),
+
+    /// Events were discarded because the event queue capacity was exceeded
+    #[error("event queue overflowed, {0} events were dropped")]
+    EventQueueOverflow(usize),
+
+    /// An onion-service listener could not be started because the onion-service is already in use by one of this `Context`'s servers
+    #[error("onion-service {0} is already in use by this context's {2} on virt-port {1}")]
+    ListenerConflict(V3OnionServiceId, u16, String),
+
+    /// The tor provider could not start an onion-service listener, e.g. because it could not bind a local socket for the listener
+    #[error("failed to start listener for onion-service {0} on virt-port {1}: {2}")]
+    ListenerStartFailed(
+        V3OnionServiceId,
+        u16,
+        #[source] tor_interface::tor_provider::Error,
+    ),
+
+    /// The identity server rejected an outgoing identity handshake
+    #[error("identity server rejected the handshake: {0:?}")]
+    IdentityHandshakeRejected(HandshakeRejectionReason),
+
+    /// An outgoing identity handshake's endpoint name was rejected by the endpoint validator before connecting to the identity server
+    #[error("endpoint '{0}' rejected by the endpoint validator: {1:?}")]
+    EndpointRejected(String, EndpointValidatorVerdict),
+
     /// Failure ocurred in outgoing identity handshake
     #[error(transparent)]
     IdentityClientError(#[from] identity_client::Error),
@@ -77,6 +474,30 @@ pub enum Error {
     /// Failure ocurred in incoming endpoint handshake
     #[error(transparent)]
     EndpointServerError(#[from] endpoint_server::Error),
+
+    /// Failure ocurred opening an RPC channel
+    #[error(transparent)]
+    RpcChannelError(#[from] crate::rpc_channel::Error),
+
+    /// Failure ocurred opening a datagram channel
+    #[error(transparent)]
+    DatagramChannelError(#[from] crate::datagram_channel::Error),
+
+    /// An in-progress incoming handshake was aborted by the application
+    #[error("handshake {0} was aborted")]
+    HandshakeAborted(HandshakeHandle),
+
+    /// Connecting to an endpoint server's onion-service took longer than the timeout set with [`Context::endpoint_client_set_connect_timeout()`]
+    #[error("connecting to endpoint server {0} took longer than {1:?}")]
+    EndpointConnectTimedOut(V3OnionServiceId, Duration),
+
+    /// An endpoint handshake did not complete within the timeout set with [`Context::endpoint_client_set_handshake_timeout()`] once connected to the endpoint server
+    #[error("endpoint handshake with endpoint server {0} did not complete within {1:?}")]
+    EndpointHandshakeTimedOut(V3OnionServiceId, Duration),
+
+    /// An endpoint server refused to open a channel because this `Context` has exceeded its channel quota, see [`Context::endpoint_server_set_channel_quota()`]
+    #[error("endpoint server {0} refused the channel: {1}")]
+    EndpointChannelQuotaExceeded(V3OnionServiceId, ChannelQuotaExceeded),
 }
 
 /// The gosling protocol implementation.
@@ -89,11 +510,30 @@ pub struct Context {
     // our tor instance
     tor_provider: Box<dyn TorProvider>,
     bootstrap_complete: bool,
+    // whether bootstrap() has been called, so a replacement provider is bootstrapped too
+    bootstrap_requested: bool,
+    // whether outgoing handshakes begun before bootstrap completes are queued
+    queue_until_bootstrapped: bool,
+    // replacement provider and the state to restore once it has bootstrapped
+    tor_provider_migration: Option<TorProviderMigration>,
+    // the connectivity check begun by check_connectivity(), if in progress
+    connectivity_check: Option<ConnectivityCheck>,
     identity_port: u16,
     endpoint_port: u16,
     identity_timeout: Duration,
     identity_max_message_size: i32,
     endpoint_timeout: Duration,
+    // the maximum time outgoing endpoint handshakes may take to connect to the
+    // endpoint server and to complete the handshake once connected
+    endpoint_client_connect_timeout: Option<Duration>,
+    endpoint_client_handshake_timeout: Option<Duration>,
+    // the maximum time handshakes wait on a peer which sends nothing
+    handshake_read_idle_timeout: Option<Duration>,
+    // the optional protocol features offered to and accepted from peers
+    capabilities: Capabilities,
+    // how long completed identity handshakes' connections are kept open for
+    // follow-up identity handshakes, if at all
+    identity_keep_open: Option<Duration>,
 
     //
     // Servers and Clients for in-process handshakes
@@ -103,14 +543,159 @@ pub struct Context {
     identity_servers: BTreeMap<HandshakeHandle, IdentityServer>,
     endpoint_clients: BTreeMap<HandshakeHandle, EndpointClient>,
     endpoint_servers: BTreeMap<HandshakeHandle, EndpointServer>,
+    // channels to request once an identity client's handshake completes, and
+    // the circuit token to use if the endpoint server must be connected to
+    endpoint_upgrade_channels: BTreeMap<HandshakeHandle, (AsciiString, Option<CircuitToken>)>,
+    // maps the identity service id to the (identity connection, close deadline, completed handshake handle)
+    // kept open for the next identity handshake with the identity server
+    kept_open_identity_client_sessions:
+        HashMap<V3OnionServiceId, (Session<TcpStream>, Instant, HandshakeHandle)>,
+    // maps the handle of each identity server waiting on a kept-open connection for
+    // the client's next handshake to the (completed handshake handle, client service id)
+    kept_open_identity_servers: BTreeMap<HandshakeHandle, (HandshakeHandle, V3OnionServiceId)>,
+
+    //
+    // Outgoing handshakes waiting for a connection slot
+    //
+    max_outbound_connections: Option<usize>,
+    // ordered by descending priority, FIFO within a priority
+    outbound_queue: VecDeque<QueuedHandshake>,
+
+    //
+    // Scheduling of in-progress handshake updates
+    //
+    // the maximum number of handshakes updated per update(), if limited
+    handshake_update_budget: Option<usize>,
+    // the handle last updated when the budget was reached; handshakes with
+    // later handles are updated first by the next update()
+    handshake_schedule_cursor: HandshakeHandle,
+    // (priority, handle) of the handshakes scheduled by the current update(),
+    // kept between updates to reuse its allocation
+    scheduled_handshakes: Vec<(i32, HandshakeHandle)>,
+    // priorities of in-progress handshakes; absent for the default priority of 0
+    handshake_priorities: HashMap<HandshakeHandle, i32>,
+    // maps the endpoint service id to the priority of its incoming handshakes
+    endpoint_priorities: HashMap<V3OnionServiceId, i32>,
+
+    //
+    // Identity servers which recently failed to connect
+    //
+    identity_server_negative_ttl: Option<Duration>,
+    // maps the identity server's service id to the time of its last failed connection
+    unreachable_identity_servers: HashMap<V3OnionServiceId, Instant>,
+    // identity handshakes suppressed since the last update()
+    suppressed_identity_handshakes: Vec<(HandshakeHandle, V3OnionServiceId, Duration)>,
+
+    //
+    // Client authorization keys registered with the tor provider
+    //
+    // maps endpoint service ids to the client authorization key added by this context
+    client_auth_entries: BTreeMap<V3OnionServiceId, X25519PrivateKey>,
+    // when keys added for endpoint handshakes are removed again
+    client_auth_retention: ClientAuthRetention,
+    // results of client authorization key additions and removals since the last update()
+    client_auth_events: Vec<ContextEvent>,
+    // (endpoint service id, revocation time) of the endpoint revocations handled since the last update()
+    endpoint_revocations: Vec<(V3OnionServiceId, SystemTime)>,
+    // the endpoint servers pinned for the endpoints granted to this context's identity clients
+    pin_store: Option<PinStore>,
+    // the petnames of known identities and the endpoints they granted this context's identity clients
+    petname_store: Option<PetnameStore>,
+    // peers turned away by this context's identity and endpoint servers
+    ban_list: Option<BanList>,
+    // the events of the current update(), kept between updates to reuse its allocation
+    update_events: VecDeque<ContextEvent>,
+    // failures of the incoming handshakes aborted since the last update()
+    aborted_server_events: Vec<ContextEvent>,
 
     //
     // Listeners for incoming connections
     //
     identity_listener: Option<OnionListener>,
+    // accepts connections to shared endpoint servers on the identity server's onion-service
+    shared_endpoint_listener: Option<OnionListener>,
     identity_server_published: bool,
-    // maps the endpoint service id to the (enpdoint name, alowed client, onion listener tuple, published)
-    endpoint_listeners: HashMap<V3OnionServiceId, (String, V3OnionServiceId, OnionListener, bool)>,
+    // how often the tor provider refreshes the servers' descriptors, kept to
+    // be applied to replacement tor providers
+    descriptor_republish_interval: Option<Duration>,
+    // the tor provider's circuit-hardening options, kept to be applied to
+    // replacement tor providers
+    security_level: SecurityLevel,
+    // proof-of-work defenses enabled when the identity server is started
+    identity_server_pow_defenses: Option<PowDefenses>,
+    // serve shared endpoint servers when the identity server is started
+    identity_server_shared_endpoints_enabled: bool,
+    // consulted by identity servers before issuing a challenge
+    identity_client_filter: Option<Arc<ClientFilter>>,
+    // consulted before identity clients connect to their identity server
+    identity_client_endpoint_validator: Option<Arc<EndpointValidator>>,
+    // agree to identity clients' requests to continue with an endpoint handshake
+    identity_server_endpoint_upgrade_allowed: bool,
+    // derive granted endpoints' keys from the identity key rather than
+    // generating them randomly
+    identity_server_derive_endpoint_keys: bool,
+    // how long identity clients have to respond to an endpoint challenge
+    identity_server_challenge_response_deadline: Option<Duration>,
+    // client cookies committed to by begin_handshake calls made to this
+    // Context's identity and endpoint servers
+    handshake_replay_cache: SharedReplayCache,
+    // maps the endpoint service id to the (identity connection, handshake completion time) waiting for the endpoint server to start
+    upgraded_identity_sessions: HashMap<V3OnionServiceId, (Session<TcpStream>, Instant)>,
+    // maps the endpoint service id to the (enpdoint name, alowed client, onion listener tuple, published);
+    // shared endpoint servers have no onion listener of their own
+    endpoint_listeners:
+        HashMap<V3OnionServiceId, (AsciiString, V3OnionServiceId, Option<OnionListener>, bool)>,
+    // maps the endpoint service id to the configuration needed to restart its onion-service
+    endpoint_server_configs: HashMap<V3OnionServiceId, EndpointServerConfig>,
+    next_publish_handle: PublishHandle,
+    // maps the endpoint service id to the completion of an endpoint server started
+    // with endpoint_server_start_with_completion() which is not yet published
+    pending_endpoint_publishes: HashMap<V3OnionServiceId, PendingEndpointPublish>,
+    // maps the endpoint service id to its limit on concurrent incoming handshakes
+    endpoint_concurrency_limits: HashMap<V3OnionServiceId, EndpointConcurrencyLimit>,
+    // channel requests matching these patterns are accepted automatically
+    endpoint_channel_patterns: Vec<ChannelPattern>,
+    // accept endpoint handshakes from peers running the legacy protocol revision
+    endpoint_legacy_handshakes_allowed: bool,
+    // when set, endpoint servers tell clients to retry their channel requests after this long
+    endpoint_server_busy_retry_after: Option<Duration>,
+    // maps the endpoint service id of each paused endpoint server to how long
+    // its clients are told to wait before retrying their channel requests
+    endpoint_server_paused: HashMap<V3OnionServiceId, Duration>,
+    // when set, limits the channels each client may open through our endpoint
+    // servers
+    endpoint_server_channel_quota: Option<SharedChannelQuotaTracker>,
+    // maps the handle of each accepted channel counted against its client's
+    // quota to the permit released when the application closes it
+    endpoint_server_channel_quota_permits: HashMap<HandshakeHandle, ChannelQuotaPermit>,
+    // endpoint handshakes for these channels open an RpcChannel
+    rpc_channels: Vec<AsciiString>,
+    // the heartbeat of newly opened RpcChannels
+    rpc_channel_heartbeat: Option<Heartbeat>,
+    // endpoint handshakes for these channels open a DatagramChannel
+    datagram_channels: Vec<AsciiString>,
+
+    // latencies of outgoing handshake steps
+    timings: Timings,
+    // outcomes of incoming identity handshakes by requested endpoint
+    identity_server_stats: IdentityServerStats,
+    // source of the time for timeouts, deadlines and latencies
+    clock: Arc<dyn Clock>,
+
+    //
+    // Bounds on the events returned from update()
+    //
+    event_queue_limit: EventQueueLimit,
+    // whether circuit, stream and descriptor upload events from the tor provider are forwarded
+    tor_event_verbosity: TorEventVerbosity,
+    // events held back by the last update() to stay within the event queue's
+    // capacity
+    pending_events: VecDeque<ContextEvent>,
+    // tor log lines are held here rather than returned as events when set
+    tor_log_buffer_capacity: Option<usize>,
+    tor_log_lines: VecDeque<String>,
+    // receives update()'s events instead of its caller when set
+    events_sink: Option<Box<dyn ContextEventsSink>>,
 
     //
     // Server Config Data
@@ -142,12 +727,121 @@ pub enum ContextEvent {
     /// Tor bootstrap completed
     TorBootstrapCompleted,
 
-    /// Human-readable logs from the [`Context`]'s [`TorProvider`]
+    /// The [`Context`] has switched to the [`TorProvider`] passed to [`Context::set_tor_provider()`]. Its identity server and endpoint servers have been restarted and its client authorization keys added to the new provider.
+    TorProviderChanged,
+
+    /// The identity server or one of the endpoint servers could not be restarted after the [`Context`] switched to a new [`TorProvider`]. The server is stopped and may be started again by the application.
+    ListenerStartFailed {
+        /// The onion-service service-id of the server
+        service_id: V3OnionServiceId,
+        /// The virt-port the server's onion-service would have listened on
+        virt_port: u16,
+        /// The reason the listener could not be started
+        reason: Error,
+    },
+
+    /// Human-readable logs from the [`Context`]'s [`TorProvider`]. Not returned while a tor log buffer is set with [`Context::set_tor_log_buffer()`].
     TorLogReceived {
         /// Human-readable debug log
         line: String,
     },
 
+    /// A circuit built by the [`Context`]'s [`TorProvider`] has changed status. Only returned when [`TorEventVerbosity::Verbose`] is set with [`Context::set_tor_event_verbosity()`], and only by providers able to observe circuits.
+    TorCircuitStatusChanged {
+        /// The provider's identifier for the circuit
+        circuit_id: String,
+        /// The circuit's new status
+        status: CircuitStatus,
+        /// The relays the circuit has been extended through so far, from first to last hop
+        path: Vec<String>,
+        /// Why the circuit was built, if reported
+        purpose: Option<String>,
+        /// Why the circuit failed or was closed, if reported
+        reason: Option<String>,
+    },
+
+    /// A stream opened through the [`Context`]'s [`TorProvider`] has changed status. Only returned when [`TorEventVerbosity::Verbose`] is set with [`Context::set_tor_event_verbosity()`], and only by providers able to observe streams.
+    TorStreamStatusChanged {
+        /// The provider's identifier for the stream
+        stream_id: String,
+        /// The stream's new status
+        status: StreamStatus,
+        /// The provider's identifier for the circuit the stream is attached to
+        circuit_id: String,
+        /// The stream's target, as 'address:port'
+        target: String,
+        /// Why the stream failed, was detached, or was closed, if reported
+        reason: Option<String>,
+    },
+
+    /// An attempt by the [`Context`]'s [`TorProvider`] to upload an onion-service's descriptor to a hidden-service directory has finished. Only returned when [`TorEventVerbosity::Verbose`] is set with [`Context::set_tor_event_verbosity()`], and only by providers able to observe descriptor uploads.
+    TorOnionServiceDescriptorUploadStatus {
+        /// The service id of the identity or endpoint server whose descriptor was uploaded
+        service_id: V3OnionServiceId,
+        /// The provider's identifier for the hidden-service directory, if reported
+        hs_dir: Option<String>,
+        /// Whether the hidden-service directory accepted the descriptor
+        succeeded: bool,
+        /// Why the upload failed, if reported
+        reason: Option<String>,
+        /// The number of the onion-service's descriptor uploads which have succeeded since it was started
+        uploads_succeeded: usize,
+        /// The number of the onion-service's descriptor uploads which have failed since it was started
+        uploads_failed: usize,
+    },
+
+    /// A SOCKS request made through the [`Context`]'s [`TorProvider`] violated its SOCKS policy, e.g. because an application proxying through the provider resolved a hostname outside of the Tor Network. Only returned by providers able to observe SOCKS requests. The policy of a bundled c-tor daemon may be configured with `LegacyTorClientConfig`'s `socks_policy`.
+    TorSocksPolicyViolated {
+        /// The violation
+        violation: SocksPolicyViolation,
+    },
+
+    /// A connectivity check begun with [`Context::check_connectivity()`] has finished
+    ConnectivityChecked {
+        /// The results of the check
+        report: ConnectivityReport,
+    },
+
+    //
+    // Event Queue Events
+    //
+
+    /// More events were produced than the capacity set with [`Context::set_event_queue_capacity()`], so some lossy events were merged or discarded according to its [`EventQueueOverflowPolicy`]. This event is returned before the remaining events.
+    EventQueueOverflowed {
+        /// The number of lossy events which were discarded
+        dropped_events: usize,
+        /// The number of [`ContextEvent::TorLogReceived`] events which were merged into an older one
+        coalesced_tor_logs: usize,
+    },
+
+    /// The tor log buffer set with [`Context::set_tor_log_buffer()`] was full, so its oldest lines were discarded to make room for newer ones. At most one such event is returned per call to [`Context::update()`].
+    TorLogLinesDropped {
+        /// The number of tor log lines which were discarded
+        dropped_lines: usize,
+    },
+
+    //
+    // Outbound Queue Events
+    //
+
+    /// An outgoing identity or endpoint handshake is waiting for an outbound connection slot. This event is emitted when the handshake is first queued and whenever its position in the queue changes.
+    ///
+    /// See [`Context::set_max_outbound_connections()`]
+    OutboundHandshakeQueued {
+        /// The handle of the queued handshake
+        handle: HandshakeHandle,
+        /// The number of queued handshakes which will be started before this one
+        queue_position: usize,
+    },
+
+    /// An outgoing identity or endpoint handshake which was queued because tor had not yet bootstrapped has left the queue and begun connecting to its server. Its progress is then reported as for any other handshake.
+    ///
+    /// See [`Context::set_queue_until_bootstrapped()`]
+    OutboundHandshakeStarted {
+        /// The handle of the started handshake
+        handle: HandshakeHandle,
+    },
+
     //
     // Identity Client Events
     //
@@ -171,9 +865,17 @@ pub enum ContextEvent {
         /// The onion-service service-id of the requested endpoint server
         endpoint_service_id: V3OnionServiceId,
         /// The ASCII-encoded name of the requested endpoint server
-        endpoint_name: String,
+        endpoint_name: AsciiString,
         /// The private x25519 client-auth key required to access the requested endpoint server
         client_auth_private_key: X25519PrivateKey,
+        /// The ASCII-encoded name of each additional endpoint server requested with [`Context::identity_client_begin_handshake_with_additional_endpoints()`] along with its onion-service service-id, or `None` if the identity server denied it. The same client-auth key is required to access each granted endpoint server.
+        additional_endpoints: Vec<(AsciiString, Option<V3OnionServiceId>)>,
+        /// The contact request sent with [`Context::identity_client_begin_handshake_with_contact_request()`], if any
+        contact_request: Option<String>,
+        /// The optional protocol features agreed with the identity server; empty if the identity server predates capability negotiation
+        capabilities: Capabilities,
+        /// The number of round-trips and bytes exchanged by the handshake
+        stats: HandshakeStats,
     },
 
     /// An incoming identit handshake has failed
@@ -182,11 +884,48 @@ pub enum ContextEvent {
         handle: HandshakeHandle,
         /// The failure reason
         reason: Error,
+        /// The number of round-trips and bytes exchanged before the failure; all zeroes if no connection was established
+        stats: HandshakeStats,
+    },
+
+    /// A connection kept open after an outgoing identity handshake completed (see [`Context::set_identity_session_keep_open()`]) was closed because no further identity handshake with the identity server began over it in time.
+    IdentityClientSessionClosed {
+        /// The handle of the completed handshake whose connection was closed
+        handle: HandshakeHandle,
+        /// The onion-service service-id of the identity server
+        identity_service_id: V3OnionServiceId,
+    },
+
+    /// An outgoing identity handshake was not attempted because a connection to the identity server failed within the negative TTL set with [`Context::set_identity_server_negative_ttl()`]. No further events are returned for this handshake.
+    IdentityClientHandshakeSuppressed {
+        /// The handle of the suppressed handshake
+        handle: HandshakeHandle,
+        /// The onion-service service-id of the unreachable identity server
+        identity_service_id: V3OnionServiceId,
+        /// The time remaining until connections to the identity server are attempted again
+        retry_after: Duration,
+    },
+
+    /// An identity server granted a different endpoint server than the one pinned for the endpoint in this `Context`'s [`PinStore`] (see [`Context::identity_client_set_pin_store()`]). This event is returned for each mismatched endpoint just before the identity handshake's [`ContextEvent::IdentityClientHandshakeCompleted`] event. The pin is not replaced; the application may accept the new endpoint server with [`PinStore::pin()`].
+    IdentityClientEndpointPinMismatched {
+        /// The handle of the completed handshake
+        handle: HandshakeHandle,
+        /// The onion-service service-id of the identity server which granted the endpoint server
+        identity_service_id: V3OnionServiceId,
+        /// The ASCII-encoded name of the granted endpoint
+        endpoint_name: AsciiString,
+        /// The onion-service service-id of the pinned endpoint server
+        pinned_endpoint_service_id: V3OnionServiceId,
+        /// The onion-service service-id of the granted endpoint server
+        endpoint_service_id: V3OnionServiceId,
     },
 
     /// The identity server's onion-service has been published and may be reachable by identity clients
     IdentityServerPublished,
 
+    /// The identity server's already published onion-service descriptor has been refreshed, following a call to [`Context::identity_server_refresh_descriptor()`] or because the interval set with [`Context::set_descriptor_republish_interval()`] elapsed
+    IdentityServerDescriptorRefreshed,
+
     /// An identity server has received an incoming connection and the handshake is ready to begin
     IdentityServerHandshakeStarted {
         /// The handle of the new handshake
@@ -202,7 +941,11 @@ pub enum ContextEvent {
         /// The alleged onion-service service-id of the connecting client
         client_service_id: V3OnionServiceId,
         /// The ASCII-encoded name of the requested endpoint server
-        requested_endpoint: String,
+        requested_endpoint: AsciiString,
+        /// The ASCII-encoded names of any further endpoint servers requested in the same handshake; see [`Context::identity_server_handle_endpoint_request_received_with_additional_endpoints()`]
+        additional_endpoints: Vec<AsciiString>,
+        /// The client's contact request, if any. It is not authenticated until the handshake completes.
+        contact_request: Option<String>,
     },
 
     /// An identity server has received a challenge response from an identity client.
@@ -222,11 +965,19 @@ pub enum ContextEvent {
         /// The ed25519 private key of requested endpoint server
         endpoint_private_key: Ed25519PrivateKey,
         /// The ASCII-encoded name of the requested endpoint server
-        endpoint_name: String,
+        endpoint_name: AsciiString,
         /// The onion-service service-id of the authenticated client
         client_service_id: V3OnionServiceId,
         /// The public x25519 client-auth key used to encrypt the endpoint server's onion-service descriptor
         client_auth_public_key: X25519PublicKey,
+        /// The ASCII-encoded name and ed25519 private key of each granted additional endpoint server. Each is accessed with the same client-auth key as the requested endpoint server.
+        additional_endpoints: Vec<(AsciiString, Ed25519PrivateKey)>,
+        /// The authenticated client's contact request, if any
+        contact_request: Option<String>,
+        /// The optional protocol features agreed with the identity client; empty if the identity client predates capability negotiation
+        capabilities: Capabilities,
+        /// The number of round-trips and bytes exchanged by the handshake
+        stats: HandshakeStats,
     },
 
     /// An identity server has rejected an identity client's endpoint-request.
@@ -247,12 +998,66 @@ pub enum ContextEvent {
         challenge_response_valid: bool,
     },
 
+    /// An identity server has turned away an identity client on this `Context`'s ban list (see [`Context::set_ban_list()`]) as soon as it began its handshake. The client receives an error response and the handshake ends with this event rather than [`ContextEvent::IdentityServerHandshakeFailed`].
+    IdentityServerBannedClientRejected {
+        /// The handle of the rejected handshake
+        handle: HandshakeHandle,
+        /// The alleged onion-service service-id of the banned client
+        client_service_id: V3OnionServiceId,
+        /// The ASCII-encoded name of the requested endpoint
+        requested_endpoint: AsciiString,
+        /// The client's ban
+        ban: Ban,
+    },
+
     /// An incoming identity handshake has failed.
     IdentityServerHandshakeFailed {
         /// The handle of the failed handshake
         handle: HandshakeHandle,
         /// The failure reason
         reason: Error,
+        /// The number of round-trips and bytes exchanged before the failure; all zeroes if no connection was established
+        stats: HandshakeStats,
+    },
+
+    /// A connection kept open after an incoming identity handshake completed (see [`Context::set_identity_session_keep_open()`]) was closed, either because the client began no further identity handshake over it in time or because the client closed it.
+    IdentityServerSessionClosed {
+        /// The handle of the completed handshake whose connection was closed
+        handle: HandshakeHandle,
+        /// The onion-service service-id of the client which completed the handshake
+        client_service_id: V3OnionServiceId,
+    },
+
+    //
+    // Client Authorization Events
+    //
+
+    /// A client authorization key for an endpoint server's onion-service descriptor has been added to the tor provider
+    ClientAuthAdded {
+        /// The onion-service service-id of the endpoint server
+        endpoint_service_id: V3OnionServiceId,
+    },
+
+    /// Adding a client authorization key to the tor provider failed. The endpoint handshake which required it fails with [`Error::ClientAuthAddFailed`].
+    ClientAuthAddFailed {
+        /// The onion-service service-id of the endpoint server
+        endpoint_service_id: V3OnionServiceId,
+        /// The failure reason
+        reason: Error,
+    },
+
+    /// A client authorization key was removed from the tor provider with [`Context::remove_client_auth()`]
+    ClientAuthRemoved {
+        /// The onion-service service-id of the endpoint server
+        endpoint_service_id: V3OnionServiceId,
+    },
+
+    /// Removing a client authorization key from the tor provider with [`Context::remove_client_auth()`] failed
+    ClientAuthRemoveFailed {
+        /// The onion-service service-id of the endpoint server
+        endpoint_service_id: V3OnionServiceId,
+        /// The failure reason
+        reason: Error,
     },
 
     //
@@ -266,9 +1071,45 @@ pub enum ContextEvent {
         /// The onion-service service-id of the endpoint server the client has connected to
         endpoint_service_id: V3OnionServiceId,
         /// The ASCII-encoded name of the requested channel on the endpoint server
-        channel_name: String,
+        channel_name: AsciiString,
         /// The resulting TCP connection to the endpoint server
         stream: TcpStream,
+        /// The optional protocol features agreed with the endpoint server; empty if the endpoint server predates capability negotiation
+        capabilities: Capabilities,
+        /// The number of round-trips and bytes exchanged by the handshake; when the handshake continued over an identity handshake's connection the identity handshake is not included
+        stats: HandshakeStats,
+    },
+
+    /// An endpoint client has successfully completed an endpoint handshake for one of the channels registered with [`Context::add_rpc_channel()`] and may now make calls to the endpoint server. This event is returned in place of [`ContextEvent::EndpointClientHandshakeCompleted`] for such channels.
+    EndpointClientRpcChannelOpened {
+        /// The handle of the completed handshake
+        handle: HandshakeHandle,
+        /// The onion-service service-id of the endpoint server the client has connected to
+        endpoint_service_id: V3OnionServiceId,
+        /// The ASCII-encoded name of the requested channel on the endpoint server
+        channel_name: AsciiString,
+        /// The handshake's Honk-RPC session, kept open for the application's calls
+        channel: RpcChannel,
+        /// The optional protocol features agreed with the endpoint server; empty if the endpoint server predates capability negotiation
+        capabilities: Capabilities,
+        /// The number of round-trips and bytes exchanged by the handshake; when the handshake continued over an identity handshake's connection the identity handshake is not included
+        stats: HandshakeStats,
+    },
+
+    /// An endpoint client has successfully completed an endpoint handshake for one of the channels registered with [`Context::add_datagram_channel()`] and may now exchange datagrams with the endpoint server. This event is returned in place of [`ContextEvent::EndpointClientHandshakeCompleted`] for such channels.
+    EndpointClientDatagramChannelOpened {
+        /// The handle of the completed handshake
+        handle: HandshakeHandle,
+        /// The onion-service service-id of the endpoint server the client has connected to
+        endpoint_service_id: V3OnionServiceId,
+        /// The ASCII-encoded name of the requested channel on the endpoint server
+        channel_name: AsciiString,
+        /// The handshake's TCP connection, carrying the application's datagrams
+        channel: DatagramChannel,
+        /// The optional protocol features agreed with the endpoint server; empty if the endpoint server predates capability negotiation
+        capabilities: Capabilities,
+        /// The number of round-trips and bytes exchanged by the handshake; when the handshake continued over an identity handshake's connection the identity handshake is not included
+        stats: HandshakeStats,
     },
 
     /// An outgoing endpoint handshake has failed.
@@ -277,6 +1118,28 @@ pub enum ContextEvent {
         handle: HandshakeHandle,
         /// The failure reason
         reason: Error,
+        /// The number of round-trips and bytes exchanged before the failure; all zeroes if no connection was established
+        stats: HandshakeStats,
+    },
+
+    /// An outgoing endpoint handshake was turned away because the endpoint server is busy. The handshake may be attempted again with [`Context::endpoint_client_begin_handshake()`] once `retry_after` has elapsed. No further events are returned for this handshake.
+    EndpointClientHandshakeBusy {
+        /// The handle of the turned away handshake
+        handle: HandshakeHandle,
+        /// The onion-service service-id of the busy endpoint server
+        endpoint_service_id: V3OnionServiceId,
+        /// The ASCII-encoded name of the requested channel
+        channel_name: AsciiString,
+        /// How long the endpoint server asked the client to wait before retrying
+        retry_after: Duration,
+    },
+
+    /// An endpoint server has revoked this `Context`'s access, as reported with [`Context::endpoint_client_handle_revocation()`]. Any client authorization key for the endpoint server's onion-service descriptor is removed from the tor provider, reported with a [`ContextEvent::ClientAuthRemoved`] or [`ContextEvent::ClientAuthRemoveFailed`] event.
+    EndpointClientEndpointRevoked {
+        /// The onion-service service-id of the endpoint server which revoked access
+        endpoint_service_id: V3OnionServiceId,
+        /// When access was revoked, according to the endpoint server's clock
+        revoked_at: SystemTime,
     },
 
     //
@@ -288,7 +1151,37 @@ pub enum ContextEvent {
         /// The onion-service service-id of the published endpoint server
         endpoint_service_id: V3OnionServiceId,
         /// The name of the published endpoint server
-        endpoint_name: String,
+        endpoint_name: AsciiString,
+    },
+
+    /// An endpoint server started with [`Context::endpoint_server_start_with_completion()`] has been published. This event follows the endpoint server's [`ContextEvent::EndpointServerPublished`] event.
+    EndpointServerPublishCompleted {
+        /// The handle returned by [`Context::endpoint_server_start_with_completion()`]
+        handle: PublishHandle,
+        /// The onion-service service-id of the published endpoint server
+        endpoint_service_id: V3OnionServiceId,
+        /// The name of the published endpoint server
+        endpoint_name: AsciiString,
+    },
+
+    /// An endpoint server started with [`Context::endpoint_server_start_with_completion()`] was not published within its timeout. The endpoint server keeps running and a [`ContextEvent::EndpointServerPublished`] event may still follow; the application may instead stop it with [`Context::endpoint_server_stop()`].
+    EndpointServerPublishTimedOut {
+        /// The handle returned by [`Context::endpoint_server_start_with_completion()`]
+        handle: PublishHandle,
+        /// The onion-service service-id of the endpoint server
+        endpoint_service_id: V3OnionServiceId,
+        /// The name of the endpoint server
+        endpoint_name: AsciiString,
+        /// The timeout which elapsed
+        timeout: Duration,
+    },
+
+    /// An endpoint server's already published onion-service descriptor has been refreshed, following a call to [`Context::endpoint_server_refresh_descriptor()`] or because the interval set with [`Context::set_descriptor_republish_interval()`] elapsed.
+    EndpointServerDescriptorRefreshed {
+        /// The onion-service service-id of the endpoint server
+        endpoint_service_id: V3OnionServiceId,
+        /// The name of the endpoint server
+        endpoint_name: AsciiString,
     },
 
     /// An endpoint server has received an incoming connection and the handshake is ready to begin.
@@ -297,6 +1190,12 @@ pub enum ContextEvent {
         handle: HandshakeHandle,
     },
 
+    /// An endpoint server has closed an incoming connection without beginning a handshake because its pending-connection queue was full or the connection waited too long for a handshake slot, see [`Context::endpoint_server_set_concurrency_limit()`].
+    EndpointServerConnectionShed {
+        /// The onion-service service-id of the endpoint server which shed the connection
+        endpoint_service_id: V3OnionServiceId,
+    },
+
     /// An endpoint server has received a request for a channel from an endpoint client.
     ///
     /// To continue the handshake, the server must call [`Context::endpoint_server_handle_channel_request_received()`]
@@ -306,7 +1205,7 @@ pub enum ContextEvent {
         /// The alleged onion-service service-id of the connecting client
         client_service_id: V3OnionServiceId,
         /// The ASCII-encoded name of the requested channel
-        requested_channel: String,
+        requested_channel: AsciiString,
     },
 
     /// An endpoint server's handshake has completed
@@ -318,9 +1217,49 @@ pub enum ContextEvent {
         /// The onion-service service-id of the connected client
         client_service_id: V3OnionServiceId,
         /// The ASCII-encoded name of the client's requested channel
-        channel_name: String,
+        channel_name: AsciiString,
         /// The resulting TCP connection to tohe endpoint clientt
         stream: TcpStream,
+        /// The optional protocol features agreed with the endpoint client; empty if the endpoint client predates capability negotiation
+        capabilities: Capabilities,
+        /// The number of round-trips and bytes exchanged by the handshake; when the handshake continued over an identity handshake's connection the identity handshake is not included
+        stats: HandshakeStats,
+    },
+
+    /// An endpoint server's handshake for one of the channels registered with [`Context::add_rpc_channel()`] has completed. This event is returned in place of [`ContextEvent::EndpointServerHandshakeCompleted`] for such channels.
+    EndpointServerRpcChannelOpened {
+        /// The handle of the completed handshake
+        handle: HandshakeHandle,
+        /// The onion-service service-id of the endpoint server which an endpoint client has connected to
+        endpoint_service_id: V3OnionServiceId,
+        /// The onion-service service-id of the connected client
+        client_service_id: V3OnionServiceId,
+        /// The ASCII-encoded name of the client's requested channel
+        channel_name: AsciiString,
+        /// The handshake's Honk-RPC session, kept open for the application's calls
+        channel: RpcChannel,
+        /// The optional protocol features agreed with the endpoint client; empty if the endpoint client predates capability negotiation
+        capabilities: Capabilities,
+        /// The number of round-trips and bytes exchanged by the handshake; when the handshake continued over an identity handshake's connection the identity handshake is not included
+        stats: HandshakeStats,
+    },
+
+    /// An endpoint server's handshake for one of the channels registered with [`Context::add_datagram_channel()`] has completed. This event is returned in place of [`ContextEvent::EndpointServerHandshakeCompleted`] for such channels.
+    EndpointServerDatagramChannelOpened {
+        /// The handle of the completed handshake
+        handle: HandshakeHandle,
+        /// The onion-service service-id of the endpoint server which an endpoint client has connected to
+        endpoint_service_id: V3OnionServiceId,
+        /// The onion-service service-id of the connected client
+        client_service_id: V3OnionServiceId,
+        /// The ASCII-encoded name of the client's requested channel
+        channel_name: AsciiString,
+        /// The handshake's TCP connection, carrying the application's datagrams
+        channel: DatagramChannel,
+        /// The optional protocol features agreed with the endpoint client; empty if the endpoint client predates capability negotiation
+        capabilities: Capabilities,
+        /// The number of round-trips and bytes exchanged by the handshake; when the handshake continued over an identity handshake's connection the identity handshake is not included
+        stats: HandshakeStats,
     },
 
     /// An endpoint server has rejected an endpoint client's channel request.
@@ -337,13 +1276,67 @@ pub enum ContextEvent {
         client_proof_signature_valid: bool,
     },
 
-    /// An incoming endpoint handshake has failed.
-    EndpointServerHandshakeFailed {
-        /// The handle of the failed handshake
+    /// An endpoint server has told an endpoint client it is busy, see [`Context::endpoint_server_set_busy()`].
+    EndpointServerHandshakeBusy {
+        /// The handle of the turned away handshake
         handle: HandshakeHandle,
-        /// The failure reason
-        reason: Error,
+        /// The alleged onion-service service-id of the connecting client
+        client_service_id: V3OnionServiceId,
+        /// The ASCII-encoded name of the requested channel
+        requested_channel: AsciiString,
+        /// How long the client was asked to wait before retrying
+        retry_after: Duration,
     },
+
+    /// An endpoint server has refused an endpoint client which exceeded its channel quota, see [`Context::endpoint_server_set_channel_quota()`]. The client has proven its identity; it fails its handshake with [`Error::EndpointChannelQuotaExceeded`].
+    EndpointServerChannelQuotaExceeded {
+        /// The handle of the refused handshake
+        handle: HandshakeHandle,
+        /// The onion-service service-id of the refused client
+        client_service_id: V3OnionServiceId,
+        /// The ASCII-encoded name of the requested channel
+        requested_channel: AsciiString,
+        /// The limit the client exceeded
+        exceeded: ChannelQuotaExceeded,
+    },
+
+    /// An endpoint server has refused an endpoint client on this `Context`'s ban list (see [`Context::set_ban_list()`]). The client has proven its identity; it receives an error response and the handshake ends with this event rather than [`ContextEvent::EndpointServerHandshakeFailed`].
+    EndpointServerBannedClientRejected {
+        /// The handle of the refused handshake
+        handle: HandshakeHandle,
+        /// The onion-service service-id of the banned client
+        client_service_id: V3OnionServiceId,
+        /// The ASCII-encoded name of the requested channel
+        requested_channel: AsciiString,
+        /// The client's ban
+        ban: Ban,
+    },
+
+    /// An incoming endpoint handshake has failed.
+    EndpointServerHandshakeFailed {
+        /// The handle of the failed handshake
+        handle: HandshakeHandle,
+        /// The failure reason
+        reason: Error,
+        /// The number of round-trips and bytes exchanged before the failure; all zeroes if no connection was established
+        stats: HandshakeStats,
+    },
+}
+
+impl ContextEvent {
+    // whether the event only reports progress or status, so it may be merged
+    // or discarded when the event queue overflows
+    fn is_lossy(&self) -> bool {
+        matches!(
+            self,
+            ContextEvent::TorLogReceived { .. }
+                | ContextEvent::TorBootstrapStatusReceived { .. }
+                | ContextEvent::TorCircuitStatusChanged { .. }
+                | ContextEvent::TorStreamStatusChanged { .. }
+                | ContextEvent::TorOnionServiceDescriptorUploadStatus { .. }
+                | ContextEvent::OutboundHandshakeQueued { .. }
+        )
+    }
 }
 
 impl Context {
@@ -351,8 +1344,8 @@ impl Context {
     ///
     /// # Parameters
     /// - `tor_provider`: an implementation of the [`TorProvider`] trait which provides our Tor Network connectivity
-    /// - `identity_port`: the virt-port this `Context`'s identity server's onion-service will listen on for new identity handshakes.
-    /// - `endpoint_port`: the virt-port this `Context`'s endpoint servers' onion-services will listen on for new endpoint handshakes.
+    /// - `identity_port`: the virt-port this `Context`'s identity server's onion-service will listen on for new identity handshakes, or 0 to use each identity server's [`Context::derived_virt_port()`]
+    /// - `endpoint_port`: the virt-port this `Context`'s endpoint servers' onion-services will listen on for new endpoint handshakes, or 0 to use each endpoint server's [`Context::derived_virt_port()`]. Two endpoint servers may listen on the same virt-port as each has its own onion-service.
     /// - `identity_timeout`: the maximum amount of time this `Context`' will allow an identity handshake to delay between steps before rejecting the request.
     /// - `identity_max_message_size`: the maximum size of the underlying Honk-RPC BSON message this `Context`'s identity handshake will send and accept.
     /// - `endpoint_timeout`: the maximum amount of time this `Context`' will allow an endpoint handshake to delay between steps before rejecting the request.
@@ -373,6 +1366,10 @@ impl Context {
         Ok(Self {
             tor_provider,
             bootstrap_complete: false,
+            bootstrap_requested: false,
+            queue_until_bootstrapped: false,
+            tor_provider_migration: None,
+            connectivity_check: None,
             identity_port,
             identity_max_message_size,
             endpoint_port,
@@ -381,16 +1378,88 @@ impl Context {
                 Some(timeout) => timeout,
                 None => DEFAULT_ENDPOINT_TIMEOUT,
             },
+            endpoint_client_connect_timeout: None,
+            endpoint_client_handshake_timeout: None,
+            handshake_read_idle_timeout: None,
+            capabilities: Capabilities::all(),
+            identity_keep_open: None,
 
             next_handshake_handle: Default::default(),
             identity_clients: Default::default(),
             identity_servers: Default::default(),
             endpoint_clients: Default::default(),
             endpoint_servers: Default::default(),
+            endpoint_upgrade_channels: Default::default(),
+            kept_open_identity_client_sessions: Default::default(),
+            kept_open_identity_servers: Default::default(),
+
+            max_outbound_connections: None,
+            outbound_queue: Default::default(),
+
+            handshake_update_budget: None,
+            handshake_schedule_cursor: 0,
+            scheduled_handshakes: Default::default(),
+            handshake_priorities: Default::default(),
+            endpoint_priorities: Default::default(),
+
+            identity_server_negative_ttl: None,
+            unreachable_identity_servers: Default::default(),
+            suppressed_identity_handshakes: Default::default(),
+
+            client_auth_entries: Default::default(),
+            client_auth_retention: ClientAuthRetention::Retain,
+            client_auth_events: Default::default(),
+            endpoint_revocations: Default::default(),
+            pin_store: None,
+            petname_store: None,
+            ban_list: None,
+            update_events: Default::default(),
+            aborted_server_events: Default::default(),
 
             identity_listener: None,
+            shared_endpoint_listener: None,
             identity_server_published: false,
+            descriptor_republish_interval: None,
+            security_level: SecurityLevel::Standard,
+            identity_server_pow_defenses: None,
+            identity_server_shared_endpoints_enabled: false,
+            identity_client_filter: None,
+            identity_client_endpoint_validator: None,
+            identity_server_endpoint_upgrade_allowed: false,
+            identity_server_derive_endpoint_keys: false,
+            identity_server_challenge_response_deadline: None,
+            handshake_replay_cache: Arc::new(Mutex::new(ReplayCache::default())),
+            upgraded_identity_sessions: Default::default(),
             endpoint_listeners: Default::default(),
+            endpoint_server_configs: Default::default(),
+            next_publish_handle: Default::default(),
+            pending_endpoint_publishes: Default::default(),
+            endpoint_concurrency_limits: Default::default(),
+            endpoint_channel_patterns: Default::default(),
+            endpoint_legacy_handshakes_allowed: false,
+            endpoint_server_busy_retry_after: None,
+            endpoint_server_paused: Default::default(),
+            endpoint_server_channel_quota: None,
+            endpoint_server_channel_quota_permits: Default::default(),
+            rpc_channels: Default::default(),
+            rpc_channel_heartbeat: None,
+            datagram_channels: Default::default(),
+
+            timings: Default::default(),
+            identity_server_stats: Default::default(),
+            clock: Arc::new(SystemClock),
+
+            event_queue_limit: EventQueueLimit {
+                capacity: None,
+                overflow_policy: EventQueueOverflowPolicy::DropOldest,
+                dropped_events: 0,
+                coalesced_tor_logs: 0,
+            },
+            tor_event_verbosity: TorEventVerbosity::Normal,
+            pending_events: Default::default(),
+            tor_log_buffer_capacity: None,
+            tor_log_lines: Default::default(),
+            events_sink: None,
 
             identity_private_key,
             identity_service_id,
@@ -400,329 +1469,2624 @@ impl Context {
     /// Initiate bootstrap of the `Context`'s owned [`TorProvider`]. Bootstrap status is communicated through [`ContextEvent`]s returned from the [`Context::update()`] method.
     pub fn bootstrap(&mut self) -> Result<(), Error> {
         self.tor_provider.bootstrap()?;
+        self.bootstrap_requested = true;
         Ok(())
     }
 
-    /// Initiate an identity handshake with an identity server. Handshake progression is communicated through  [`ContextEvent`]s returned from the [`Context::update()`] method.
+    /// Check whether the [`TorProvider`] can reach the Tor Network, e.g. to tell whether a peer which cannot be connected to is offline or the `Context`'s own connectivity is broken. The check fetches the identity server's onion-service descriptor from the hidden-service directories, which builds circuits through the Tor Network without contacting any peer, and so works whether or not the identity server is started. The results are returned in a [`ContextEvent::ConnectivityChecked`] event once the fetch has finished or timed out; a check begun before the provider has bootstrapped finishes on the next [`Context::update()`] without fetching anything.
+    ///
+    /// Only one check may be in progress at a time.
     ///
     /// # Parameters
-    /// - `identitity_server_id`: the long term identity onion-service service-id of a remote peer
-    /// - `endpoint`: the ASCII-encoded requested endpoint
-    /// # Returns
-    /// A `HandshakeHandle` used to refer to this particular identity handshake.
-    pub fn identity_client_begin_handshake(
+    /// - `timeout`: how long to wait for the descriptor fetch to finish
+    ///
+    /// # Errors
+    /// Returns an error if the [`TorProvider`] cannot fetch descriptors (see [`TorProvider::fetch_descriptor()`])
+    pub fn check_connectivity(&mut self, timeout: Duration) -> Result<(), Error> {
+        if self.connectivity_check.is_some() {
+            return Err(Error::IncorrectUsage(
+                "connectivity check already in progress".to_string(),
+            ));
+        }
+
+        if self.bootstrap_complete {
+            self.tor_provider
+                .fetch_descriptor(&self.identity_service_id)?;
+        }
+        self.connectivity_check = Some(ConnectivityCheck::new(
+            self.clock.now(),
+            timeout,
+            self.bootstrap_complete,
+        ));
+        Ok(())
+    }
+
+    /// Switch this `Context` to another [`TorProvider`], e.g. from a legacy c-tor daemon to arti, while keeping its servers, client authorization keys and configuration.
+    ///
+    /// The switch is made by [`Context::update()`]. Until all in-progress handshakes have finished with the current provider, no new incoming connections are accepted and new outgoing handshakes are queued (see [`ContextEvent::OutboundHandshakeQueued`]). The current provider is then dropped, closing any connections still waiting for an endpoint server's concurrency limit (see [`ContextEvent::EndpointServerConnectionShed`]), and the new provider is bootstrapped if [`Context::bootstrap()`] has been called. Once the new provider has bootstrapped, the identity server and endpoint servers which were running are restarted, the client authorization keys listed by [`Context::client_auth_entries()`] are added to the new provider, and [`ContextEvent::TorProviderChanged`] is returned. The servers' onion-services are then published again and queued outgoing handshakes begin.
+    ///
+    /// # Parameters
+    /// - `tor_provider`: the provider to switch to
+    pub fn set_tor_provider(&mut self, tor_provider: Box<dyn TorProvider>) -> Result<(), Error> {
+        if self.tor_provider_migration.is_some() {
+            return Err(Error::IncorrectUsage(
+                "tor provider switch already in progress".to_string(),
+            ));
+        }
+
+        self.tor_provider_migration = Some(TorProviderMigration::Draining(tor_provider));
+        Ok(())
+    }
+
+    // switch to the replacement tor provider once no handshakes are in progress
+    // with the current one
+    fn tor_provider_migration_begin(
         &mut self,
-        identity_server_id: V3OnionServiceId,
-        endpoint: String,
-    ) -> Result<HandshakeHandle, Error> {
-        let endpoint = match AsciiString::new(endpoint) {
-            Ok(endpoint) => endpoint,
-            Err(_) => {
-                return Err(Error::InvalidArgument(
-                    "endpoint must be an ASCII string".to_string(),
-                ))
+        events: &mut VecDeque<ContextEvent>,
+    ) -> Result<(), Error> {
+        if !self.identity_clients.is_empty()
+            || !self.identity_servers.is_empty()
+            || !self.endpoint_clients.is_empty()
+            || !self.endpoint_servers.is_empty()
+            || !self.upgraded_identity_sessions.is_empty()
+        {
+            return Ok(());
+        }
+        let tor_provider = match self.tor_provider_migration.take() {
+            Some(TorProviderMigration::Draining(tor_provider)) => tor_provider,
+            migration => {
+                self.tor_provider_migration = migration;
+                return Ok(());
             }
         };
 
-        if !self.bootstrap_complete {
-            return Err(Error::TorNotConnected());
+        // close the current provider's listeners and pending connections
+        for (identity_service_id, (_session, _deadline, handle)) in
+            std::mem::take(&mut self.kept_open_identity_client_sessions)
+        {
+            events.push_back(ContextEvent::IdentityClientSessionClosed {
+                handle,
+                identity_service_id,
+            });
+        }
+        let identity_server = self.identity_listener.take().is_some();
+        self.shared_endpoint_listener = None;
+        self.identity_server_published = false;
+        let mut endpoint_servers: Vec<(V3OnionServiceId, AsciiString, V3OnionServiceId)> =
+            Default::default();
+        for (endpoint_service_id, (endpoint_name, allowed_client, listener, _published)) in
+            std::mem::take(&mut self.endpoint_listeners)
+        {
+            match listener {
+                Some(_listener) => {
+                    endpoint_servers.push((endpoint_service_id, endpoint_name, allowed_client))
+                }
+                // shared endpoint servers return along with the identity server
+                None => {
+                    self.endpoint_listeners.insert(
+                        endpoint_service_id,
+                        (endpoint_name, allowed_client, None, false),
+                    );
+                }
+            }
+        }
+        for (endpoint_service_id, limit) in self.endpoint_concurrency_limits.iter_mut() {
+            for _ in limit.pending_connections.drain(..) {
+                events.push_back(ContextEvent::EndpointServerConnectionShed {
+                    endpoint_service_id: endpoint_service_id.clone(),
+                });
+            }
         }
 
-        // open tcp stream to remove ident server
-        let stream: TcpStream = self
-            .tor_provider
-            .connect(
-                (identity_server_id.clone(), self.identity_port).into(),
-                None,
-            )?
-            .into();
-        stream.set_nonblocking(true)?;
-        let mut client_rpc = Session::new(stream);
-        client_rpc.set_max_wait_time(self.identity_timeout);
-        client_rpc.set_max_message_size(self.identity_max_message_size)?;
+        // drops the current provider
+        self.tor_provider = tor_provider;
+        if self.descriptor_republish_interval.is_some() {
+            self.tor_provider
+                .set_descriptor_republish_interval(self.descriptor_republish_interval)?;
+        }
+        if self.security_level != SecurityLevel::Standard {
+            self.tor_provider.set_security_level(self.security_level)?;
+        }
+        self.bootstrap_complete = false;
+        self.tor_provider_migration = Some(TorProviderMigration::Bootstrapping {
+            identity_server,
+            endpoint_servers,
+        });
+        if self.bootstrap_requested {
+            self.tor_provider.bootstrap()?;
+        }
+        Ok(())
+    }
 
-        let ident_client = IdentityClient::new(
-            client_rpc,
-            identity_server_id,
-            endpoint,
-            self.identity_private_key.clone(),
-            X25519PrivateKey::generate(),
-        )?;
+    // restart the servers and re-add the client authorization keys which were
+    // in use with the previous tor provider
+    fn tor_provider_migration_complete(
+        &mut self,
+        events: &mut VecDeque<ContextEvent>,
+    ) -> Result<(), Error> {
+        let (identity_server, endpoint_servers) = match self.tor_provider_migration.take() {
+            Some(TorProviderMigration::Bootstrapping {
+                identity_server,
+                endpoint_servers,
+            }) => (identity_server, endpoint_servers),
+            migration => {
+                self.tor_provider_migration = migration;
+                return Ok(());
+            }
+        };
 
-        let handshake_handle = self.next_handshake_handle;
-        self.next_handshake_handle += 1;
-        self.identity_clients.insert(handshake_handle, ident_client);
+        if identity_server {
+            if let Err(reason) = self.identity_server_start() {
+                events.push_back(ContextEvent::ListenerStartFailed {
+                    service_id: self.identity_service_id.clone(),
+                    virt_port: self.identity_virt_port(&self.identity_service_id),
+                    reason,
+                });
+            }
+        }
+        for (endpoint_service_id, endpoint_name, allowed_client) in endpoint_servers {
+            let config = match self.endpoint_server_configs.get(&endpoint_service_id) {
+                Some(config) => config,
+                None => continue,
+            };
+            let endpoint_port = self.endpoint_virt_port(&endpoint_service_id);
+            let endpoint_listener = self
+                .tor_provider
+                .listener(
+                    &config.private_key,
+                    endpoint_port,
+                    Some(&config.client_auth_keys),
+                    config.non_anonymous,
+                    config.pow_defenses.as_ref(),
+                )
+                .map_err(|err| {
+                    Error::ListenerStartFailed(endpoint_service_id.clone(), endpoint_port, err)
+                })
+                .and_then(|endpoint_listener| {
+                    endpoint_listener.set_nonblocking(true)?;
+                    Ok(endpoint_listener)
+                });
+            match endpoint_listener {
+                Ok(endpoint_listener) => {
+                    self.endpoint_listeners.insert(
+                        endpoint_service_id,
+                        (
+                            endpoint_name,
+                            allowed_client,
+                            Some(endpoint_listener),
+                            false,
+                        ),
+                    );
+                }
+                Err(reason) => {
+                    self.endpoint_server_configs.remove(&endpoint_service_id);
+                    self.endpoint_server_forget(&endpoint_service_id);
+                    events.push_back(ContextEvent::ListenerStartFailed {
+                        service_id: endpoint_service_id,
+                        virt_port: endpoint_port,
+                        reason,
+                    });
+                }
+            }
+        }
+        for (endpoint_service_id, client_auth_key) in std::mem::take(&mut self.client_auth_entries)
+        {
+            match self
+                .tor_provider
+                .add_client_auth(&endpoint_service_id, &client_auth_key)
+            {
+                Ok(()) => {
+                    events.push_back(ContextEvent::ClientAuthAdded {
+                        endpoint_service_id: endpoint_service_id.clone(),
+                    });
+                    self.client_auth_entries
+                        .insert(endpoint_service_id, client_auth_key);
+                }
+                Err(err) => events.push_back(ContextEvent::ClientAuthAddFailed {
+                    endpoint_service_id,
+                    reason: err.into(),
+                }),
+            }
+        }
 
-        Ok(handshake_handle)
+        events.push_back(ContextEvent::TorProviderChanged);
+        Ok(())
     }
 
-    /// Abort an in-process outgoing identity handshake.
+    /// Set the maximum number of outgoing identity and endpoint handshakes which may be in progress at once. Handshakes begun while this limit is reached are queued and their connections are opened from [`Context::update()`] as slots become available. Queue progression is communicated through [`ContextEvent::OutboundHandshakeQueued`] events.
     ///
     /// # Parameters
-    /// - `handle`: the handle of the in-progress outoing identity handshake to abort
-    pub fn identity_client_abort_handshake(
+    /// - `max_outbound_connections`: the maximum number of concurrent outgoing handshakes, or `None` for no limit (the default)
+    pub fn set_max_outbound_connections(
         &mut self,
-        handle: HandshakeHandle,
+        max_outbound_connections: Option<usize>,
     ) -> Result<(), Error> {
-        if let Some(_identity_client) = self.identity_clients.remove(&handle) {
-            Ok(())
-        } else {
-            Err(Error::HandshakeHandleNotFound(handle))
+        if max_outbound_connections == Some(0) {
+            return Err(Error::InvalidArgument(
+                "max_outbound_connections must be greater than 0".to_string(),
+            ));
         }
+        self.max_outbound_connections = max_outbound_connections;
+        Ok(())
     }
 
-    /// Handle an identity server's endpoint challenge. Callers must construct an identity client's endpoint challenge-response. The particulars of creating and verifying the challenge-response BSON documents are undefined and application-specific.
+    /// Set whether outgoing identity and endpoint handshakes may be begun before tor has bootstrapped. By default, beginning a handshake fails with [`Error::TorNotConnected`] until [`ContextEvent::TorBootstrapCompleted`] has been returned. When enabled, such handshakes are accepted and queued instead, as reported by [`ContextEvent::OutboundHandshakeQueued`] events, and are started by the [`Context::update()`] which returns [`ContextEvent::TorBootstrapCompleted`] (subject to [`Context::set_max_outbound_connections()`]). Each is reported by a [`ContextEvent::OutboundHandshakeStarted`] event once it leaves the queue. Queued handshakes may be aborted as usual.
+    ///
+    /// Disabling queueing does not affect handshakes which are already queued.
     ///
     /// # Parameters
-    /// - `handle`: the handle of the in-progress outgoing identity handshake
-    /// - `challenge_response`: an application-specific BSON document which somehow responds to an identity server's challenge.
-    pub fn identity_client_handle_challenge_received(
+    /// - `queue`: whether handshakes begun before tor has bootstrapped are queued rather than rejected
+    pub fn set_queue_until_bootstrapped(&mut self, queue: bool) {
+        self.queue_until_bootstrapped = queue;
+    }
+
+    /// Set how long a failed connection to an identity server is remembered. While a failure is remembered, new identity handshakes with that server are not attempted and a [`ContextEvent::IdentityClientHandshakeSuppressed`] event is returned in place of their progress events. This avoids rebuilding tor circuits to an unreachable server each time a user retries. See [`Context::identity_client_begin_handshake_with_options()`] to bypass a remembered failure.
+    ///
+    /// # Parameters
+    /// - `negative_ttl`: how long failures are remembered, or `None` to never remember failures (the default)
+    pub fn set_identity_server_negative_ttl(&mut self, negative_ttl: Option<Duration>) {
+        self.identity_server_negative_ttl = negative_ttl;
+        if negative_ttl.is_none() {
+            self.unreachable_identity_servers.clear();
+        }
+    }
+
+    /// Set the clock from which this `Context` reads the current time for its timeouts, handshake deadlines, negative TTLs and latency measurements. The system clock is used by default; tests may instead provide a [`VirtualClock`](tor_interface::clock::VirtualClock) and advance it to trigger timeouts without sleeping.
+    ///
+    /// The clock only applies to handshakes started after it is set. The underlying Honk-RPC sessions' own message timeouts always use the system clock.
+    ///
+    /// # Parameters
+    /// - `clock`: the source of the current time
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    /// Set how long identity and endpoint handshakes may wait on their peer without receiving any bytes from it. A peer which disappears mid-handshake over tor often leaves its connection half-open, so without this limit the handshake only fails once the identity or endpoint timeout passed to [`Context::new()`] runs out. Handshakes whose peer goes quiet for longer fail with a `PeerUnresponsive` error from the identity or endpoint client or server, reported through the handshake's failed event. Time the handshake spends waiting on the local application, e.g. for an endpoint challenge, is not counted. No timeout is set by default. This setting only applies to handshakes which begin after it is changed.
+    ///
+    /// # Parameters
+    /// - `timeout`: the maximum time to wait on a silent peer, or `None` for no limit
+    pub fn set_handshake_read_idle_timeout(&mut self, timeout: Option<Duration>) {
+        self.handshake_read_idle_timeout = timeout;
+    }
+
+    /// Set the optional protocol features this `Context` negotiates with its peers during identity and endpoint handshakes. All features are enabled by default. Features disabled here are neither offered by outgoing handshakes nor accepted by incoming handshakes, and the features agreed by both peers are reported in each handshake's completed event.
+    ///
+    /// The capabilities only apply to handshakes started after they are set.
+    ///
+    /// # Parameters
+    /// - `capabilities`: the optional protocol features to enable
+    pub fn set_capabilities(&mut self, capabilities: Capabilities) {
+        self.capabilities = capabilities;
+    }
+
+    /// Keep the connections of completed identity handshakes open for follow-up identity handshakes between the same peers, e.g. to request further endpoints, which then skip connecting to the identity server's onion-service. Both peers must set this: [`Capabilities::KEEP_OPEN`] is only offered and accepted while it is set, and a handshake's connection is only kept open if its completed events report the capability as negotiated. Connections continued by an endpoint upgrade (see [`Context::identity_client_begin_handshake_with_endpoint_upgrade()`]) are not kept open.
+    ///
+    /// The next identity handshake begun with the identity server continues over its kept-open connection, if any. On the identity server, the follow-up handshake begins with a [`ContextEvent::IdentityServerHandshakeStarted`] event as if the client had connected anew. A connection over which no identity handshake begins within `keep_open` of the previous one completing is closed, and reported with a [`ContextEvent::IdentityClientSessionClosed`] or [`ContextEvent::IdentityServerSessionClosed`] event; the latter is also returned if the client closes the connection. Identity clients do not notice their connection being closed by the server until their next handshake over it fails, so servers should keep connections open for longer than their clients.
+    ///
+    /// This setting only applies to handshakes which begin after it is changed.
+    ///
+    /// # Parameters
+    /// - `keep_open`: how long connections are kept open once their handshake completes, or `None` to close them (the default)
+    pub fn set_identity_session_keep_open(&mut self, keep_open: Option<Duration>) {
+        self.identity_keep_open = keep_open;
+    }
+
+    // the optional protocol features offered to and accepted from peers by
+    // identity handshakes; kept-open connections are only negotiated when enabled
+    fn identity_capabilities(&self) -> Capabilities {
+        match self.identity_keep_open {
+            Some(_) => self.capabilities,
+            None => self.capabilities.difference(Capabilities::KEEP_OPEN),
+        }
+    }
+
+    /// Register a channel whose endpoint handshakes open an [`RpcChannel`] instead of handing over the connection's [`TcpStream`]. Once such a handshake completes, both peers keep using the handshake's Honk-RPC session for the application's own calls, and the handshake ends with a [`ContextEvent::EndpointClientRpcChannelOpened`] or [`ContextEvent::EndpointServerRpcChannelOpened`] event in place of the usual completed event. Both peers must register the channel. RPC channels apply to this `Context`'s endpoint clients and servers, and only to handshakes which begin after the channel is registered.
+    ///
+    /// # Parameters
+    /// - `channel`: the ASCII-encoded name of the channel
+    pub fn add_rpc_channel(&mut self, channel: AsciiString) -> Result<(), Error> {
+        ensure_channel_name_length(&channel)?;
+        if self.datagram_channels.contains(&channel) {
+            return Err(Error::InvalidArgument(format!(
+                "channel is already registered as a datagram channel: {}",
+                channel.as_str()
+            )));
+        }
+        if !self.rpc_channels.contains(&channel) {
+            self.rpc_channels.push(channel);
+        }
+        Ok(())
+    }
+
+    /// Remove a channel previously registered with [`Context::add_rpc_channel()`].
+    ///
+    /// # Parameters
+    /// - `channel`: the ASCII-encoded name of the channel to remove
+    /// # Returns
+    /// `true` if the channel was registered
+    pub fn remove_rpc_channel(&mut self, channel: &AsciiString) -> bool {
+        let count = self.rpc_channels.len();
+        self.rpc_channels.retain(|registered| registered != channel);
+        count != self.rpc_channels.len()
+    }
+
+    /// Register a channel whose endpoint handshakes open a [`DatagramChannel`] over the connection instead of handing over its [`TcpStream`], and end with a [`ContextEvent::EndpointClientDatagramChannelOpened`] or [`ContextEvent::EndpointServerDatagramChannelOpened`] event in place of the usual completed event. Both peers must register the channel. Datagram channels apply to this `Context`'s endpoint clients and servers, and only to handshakes which complete after the channel is registered.
+    ///
+    /// # Parameters
+    /// - `channel`: the ASCII-encoded name of the channel; must not be registered with [`Context::add_rpc_channel()`]
+    pub fn add_datagram_channel(&mut self, channel: AsciiString) -> Result<(), Error> {
+        ensure_channel_name_length(&channel)?;
+        if self.rpc_channels.contains(&channel) {
+            return Err(Error::InvalidArgument(format!(
+                "channel is already registered as an rpc channel: {}",
+                channel.as_str()
+            )));
+        }
+        if !self.datagram_channels.contains(&channel) {
+            self.datagram_channels.push(channel);
+        }
+        Ok(())
+    }
+
+    /// Remove a channel previously registered with [`Context::add_datagram_channel()`].
+    ///
+    /// # Parameters
+    /// - `channel`: the ASCII-encoded name of the channel to remove
+    /// # Returns
+    /// `true` if the channel was registered
+    pub fn remove_datagram_channel(&mut self, channel: &AsciiString) -> bool {
+        let count = self.datagram_channels.len();
+        self.datagram_channels
+            .retain(|registered| registered != channel);
+        count != self.datagram_channels.len()
+    }
+
+    /// Set the heartbeat of the [`RpcChannel`]s opened by this `Context`'s endpoint clients and servers; see [`RpcChannel::set_heartbeat()`]. Heartbeats are disabled by default.
+    ///
+    /// This setting only applies to channels opened after it is changed.
+    ///
+    /// # Parameters
+    /// - `heartbeat`: how often the channels send heartbeats, or `None` to disable them
+    pub fn set_rpc_channel_heartbeat(&mut self, heartbeat: Option<Heartbeat>) -> Result<(), Error> {
+        if let Some(heartbeat) = &heartbeat {
+            heartbeat.validate()?;
+        }
+        self.rpc_channel_heartbeat = heartbeat;
+        Ok(())
+    }
+
+    /// Change the priority of a queued outgoing handshake. Queued handshakes with a higher priority are started before those with a lower priority; handshakes with equal priority are started in the order they were begun. All handshakes are begun with a priority of 0.
+    ///
+    /// # Parameters
+    /// - `handle`: the handle of the queued outgoing identity or endpoint handshake
+    /// - `priority`: the handshake's new priority
+    pub fn set_outbound_handshake_priority(
         &mut self,
         handle: HandshakeHandle,
-        challenge_response: bson::document::Document,
+        priority: i32,
     ) -> Result<(), Error> {
-        if let Some(identity_client) = self.identity_clients.get_mut(&handle) {
-            identity_client.send_response(challenge_response)?;
-            Ok(())
+        let index = match self
+            .outbound_queue
+            .iter()
+            .position(|queued| queued.handle == handle)
+        {
+            Some(index) => index,
+            None => return Err(Error::HandshakeHandleNotFound(handle)),
+        };
+        // remove() only returns None for an out-of-bounds index
+        let mut queued = self.outbound_queue.remove(index).unwrap();
+        queued.priority = priority;
+        self.enqueue_handshake(queued);
+        Ok(())
+    }
+
+    /// Change the priority of an in-progress or queued handshake. When the number of handshakes updated per [`Context::update()`] is limited with [`Context::set_handshake_update_budget()`], in-progress handshakes with a higher priority are updated before those with a lower priority. Queued outgoing handshakes are reordered as with [`Context::set_outbound_handshake_priority()`] and keep their priority once started. All handshakes are begun with a priority of 0, except incoming endpoint handshakes whose endpoint server's priority was set with [`Context::endpoint_server_set_priority()`].
+    ///
+    /// # Parameters
+    /// - `handle`: the handle of the in-progress or queued handshake
+    /// - `priority`: the handshake's new priority
+    pub fn set_handshake_priority(
+        &mut self,
+        handle: HandshakeHandle,
+        priority: i32,
+    ) -> Result<(), Error> {
+        if self
+            .outbound_queue
+            .iter()
+            .any(|queued| queued.handle == handle)
+        {
+            return self.set_outbound_handshake_priority(handle, priority);
+        }
+        if !self.handshake_in_progress(handle) {
+            return Err(Error::HandshakeHandleNotFound(handle));
+        }
+        if priority == 0 {
+            self.handshake_priorities.remove(&handle);
         } else {
-            Err(Error::HandshakeHandleNotFound(handle))
+            self.handshake_priorities.insert(handle, priority);
         }
+        Ok(())
     }
 
-    /// Start this `Context`'s identity server. Publish status is communicated through [`ContextEvent`]s returned from the [`Context::update()`] method.
-    pub fn identity_server_start(&mut self) -> Result<(), Error> {
-        if !self.bootstrap_complete {
-            return Err(Error::TorNotConnected());
-        }
-        if self.identity_listener.is_some() {
-            return Err(Error::IncorrectUsage(
-                "identity server already started".to_string(),
+    /// Limit the number of in-progress handshakes updated by each call to [`Context::update()`], bounding the time it takes when many handshakes are in progress at once. Handshakes are updated in descending priority (see [`Context::set_handshake_priority()`]), and round-robin within a priority, so that each handshake is updated within a bounded number of calls rather than handshakes with later handles being starved. Handshakes which are not updated are not timed out either until their turn comes.
+    ///
+    /// # Parameters
+    /// - `budget`: the maximum number of handshakes updated per update, or `None` to update every in-progress handshake (the default). Must not be 0.
+    pub fn set_handshake_update_budget(&mut self, budget: Option<usize>) -> Result<(), Error> {
+        if budget == Some(0) {
+            return Err(Error::InvalidArgument(
+                "handshake update budget must be greater than 0".to_string(),
             ));
         }
+        self.handshake_update_budget = budget;
+        Ok(())
+    }
 
-        let identity_listener =
-            self.tor_provider
-                .listener(&self.identity_private_key, self.identity_port, None)?;
-        identity_listener.set_nonblocking(true)?;
-
-        self.identity_listener = Some(identity_listener);
+    /// Set the maximum number of events returned from a single call to [`Context::update()`], and how to handle any events beyond that limit. Events accumulate between calls to [`Context::update()`] (in particular [`ContextEvent::TorLogReceived`] events from the underlying [`TorProvider`]), so an application which calls it infrequently may otherwise receive an unbounded number of events.
+    ///
+    /// The limit is applied as events are produced, by merging or discarding lossy events as described by [`EventQueueOverflowPolicy`]. Other events, e.g. handshake events, are never discarded; any beyond the capacity are returned by later calls to [`Context::update()`].
+    ///
+    /// # Parameters
+    /// - `capacity`: the maximum number of events returned per update, including any [`ContextEvent::EventQueueOverflowed`] event, or `None` for no limit (the default). Must be at least 2.
+    /// - `overflow_policy`: how to handle events beyond `capacity`
+    pub fn set_event_queue_capacity(
+        &mut self,
+        capacity: Option<usize>,
+        overflow_policy: EventQueueOverflowPolicy,
+    ) -> Result<(), Error> {
+        if let Some(capacity) = capacity {
+            if capacity < 2 {
+                return Err(Error::InvalidArgument(
+                    "event queue capacity must be at least 2".to_string(),
+                ));
+            }
+        }
+        self.event_queue_limit.capacity = capacity;
+        self.event_queue_limit.overflow_policy = overflow_policy;
         Ok(())
     }
 
-    /// Stops this `Context`'s identity server and ends any in-progress incoming identity handshakes.
-    pub fn identity_server_stop(&mut self) -> Result<(), Error> {
-        if self.identity_listener.is_none() {
-            return Err(Error::IncorrectUsage(
-                "identity server is not started".to_string(),
+    /// Register a [`ContextEventsSink`] to receive this `Context`'s events. While a sink is set, [`Context::update()`] passes each event to [`ContextEventsSink::on_event()`] once any limit set with [`Context::set_event_queue_capacity()`] has been applied, and returns an empty queue. A sink set from within one of the current sink's methods replaces it once the current events have been dispatched.
+    ///
+    /// # Parameters
+    /// - `events_sink`: the sink to receive events, or `None` to return events from [`Context::update()`] (the default)
+    pub fn set_events_sink(&mut self, events_sink: Option<Box<dyn ContextEventsSink>>) {
+        self.events_sink = events_sink;
+    }
+
+    /// Set which of the [`TorProvider`]'s events are forwarded from [`Context::update()`]. Circuit, stream and descriptor upload events are frequent and mostly of interest when diagnosing connectivity problems, so they are only forwarded with [`TorEventVerbosity::Verbose`]. Events received while [`TorEventVerbosity::Normal`] is set are discarded.
+    ///
+    /// # Parameters
+    /// - `verbosity`: which events to forward (the default is [`TorEventVerbosity::Normal`])
+    pub fn set_tor_event_verbosity(&mut self, verbosity: TorEventVerbosity) {
+        self.tor_event_verbosity = verbosity;
+    }
+
+    /// Deliver the [`TorProvider`]'s log lines through a bounded buffer rather than as [`ContextEvent::TorLogReceived`] events. Tor logs heavily during bootstrap, so an application which is slow to handle events may otherwise find protocol events delayed behind a flood of log lines. While a buffer is set, the log lines received by [`Context::update()`] are appended to the buffer and taken with [`Context::take_tor_log_lines()`] at the application's own pace. When the buffer is full its oldest lines are discarded and reported with a [`ContextEvent::TorLogLinesDropped`] event.
+    ///
+    /// # Parameters
+    /// - `capacity`: the maximum number of log lines held in the buffer, or `None` to return log lines as [`ContextEvent::TorLogReceived`] events (the default). Must not be zero. Lines already in the buffer are kept until taken, even if the buffer is removed.
+    pub fn set_tor_log_buffer(&mut self, capacity: Option<usize>) -> Result<(), Error> {
+        if capacity == Some(0) {
+            return Err(Error::InvalidArgument(
+                "tor log buffer capacity must not be zero".to_string(),
             ));
         }
-
-        // clear out current identity listener
-        self.identity_listener = None;
-        // clear out published flag
-        self.identity_server_published = false;
-        // clear out any in-process identity handshakes
-        self.identity_servers = Default::default();
+        self.tor_log_buffer_capacity = capacity;
+        if let Some(capacity) = capacity {
+            let excess = self.tor_log_lines.len().saturating_sub(capacity);
+            self.tor_log_lines.drain(..excess);
+        }
         Ok(())
     }
 
-    /// Handle an identity client's incoming endpoint request. Callers must determine whether the connected identity client is allowed to access the requested endpoint, decide whether the requested endpoint is supported by this `Context`, and build an endpoint challenge for the identity client. The particulars of creating the endpoint challenge is undefined and application-specific.
+    /// Set how often the onion-service descriptors of this `Context`'s identity server and endpoint servers are refreshed, e.g. to recover sooner from hidden-service directories which lost or never received a descriptor. Each refreshed descriptor is reported with a [`ContextEvent::IdentityServerDescriptorRefreshed`] or [`ContextEvent::EndpointServerDescriptorRefreshed`] event. The interval is also applied to any tor provider switched to with [`Context::set_tor_provider()`].
+    ///
+    /// An error is returned if the tor provider cannot refresh descriptors on its own schedule.
+    ///
+    /// # Parameters
+    /// - `interval`: the time after which a published descriptor is refreshed, or `None` to leave republishing to the tor provider (the default). Must not be zero.
+    pub fn set_descriptor_republish_interval(
+        &mut self,
+        interval: Option<Duration>,
+    ) -> Result<(), Error> {
+        if interval == Some(Duration::ZERO) {
+            return Err(Error::InvalidArgument(
+                "descriptor republish interval must not be zero".to_string(),
+            ));
+        }
+        self.tor_provider
+            .set_descriptor_republish_interval(interval)?;
+        self.descriptor_republish_interval = interval;
+        Ok(())
+    }
+
+    /// Harden the circuits of this `Context`'s tor provider, e.g. so privacy-focused applications running long-lived identity and endpoint servers are less exposed to guard-discovery attacks. Applies to circuits built after this call, and is also applied to any tor provider switched to with [`Context::set_tor_provider()`]. The individual c-tor options may instead be configured with `LegacyTorClientConfig`'s `security_config`.
+    ///
+    /// An error is returned if the tor provider does not support the security level.
+    ///
+    /// # Parameters
+    /// - `security_level`: the circuit-hardening options to apply; [`SecurityLevel::Standard`] (the default) restores the tor provider's defaults
+    pub fn set_security_level(&mut self, security_level: SecurityLevel) -> Result<(), Error> {
+        self.tor_provider.set_security_level(security_level)?;
+        self.security_level = security_level;
+        Ok(())
+    }
+
+    /// Take the log lines held in the tor log buffer set with [`Context::set_tor_log_buffer()`], oldest first, leaving the buffer empty.
+    pub fn take_tor_log_lines(&mut self) -> Vec<String> {
+        self.tor_log_lines.drain(..).collect()
+    }
+
+    // forward a tor log line as an event or append it to the tor log buffer,
+    // counting any lines discarded to make room for it
+    fn tor_log_received(
+        &mut self,
+        line: String,
+        events: &mut VecDeque<ContextEvent>,
+        dropped_lines: &mut usize,
+    ) {
+        match self.tor_log_buffer_capacity {
+            Some(capacity) => {
+                if self.tor_log_lines.len() >= capacity {
+                    self.tor_log_lines.pop_front();
+                    *dropped_lines += 1;
+                }
+                self.tor_log_lines.push_back(line);
+            }
+            None => self
+                .event_queue_limit
+                .push_lossy(events, ContextEvent::TorLogReceived { line }),
+        }
+    }
+
+    // apply the event queue capacity to the events about to be returned from update()
+    fn apply_event_queue_capacity(
+        &mut self,
+        mut events: VecDeque<ContextEvent>,
+    ) -> Result<VecDeque<ContextEvent>, Error> {
+        match self.event_queue_limit.finish(&mut events) {
+            Ok(pending_events) => {
+                self.pending_events = pending_events;
+                Ok(events)
+            }
+            Err(dropped_events) => {
+                self.pending_events = events;
+                Err(Error::EventQueueOverflow(dropped_events))
+            }
+        }
+    }
+
+    // insert after every queued handshake with the same or higher priority
+    fn enqueue_handshake(&mut self, queued: QueuedHandshake) {
+        let index = self
+            .outbound_queue
+            .iter()
+            .position(|other| other.priority < queued.priority)
+            .unwrap_or(self.outbound_queue.len());
+        self.outbound_queue.insert(index, queued);
+    }
+
+    fn handshake_in_progress(&self, handle: HandshakeHandle) -> bool {
+        self.identity_clients.contains_key(&handle)
+            || self.identity_servers.contains_key(&handle)
+            || self.endpoint_clients.contains_key(&handle)
+            || self.endpoint_servers.contains_key(&handle)
+    }
+
+    // fill handles with the in-progress handshakes to update during this
+    // update() ordered by handle, returning false if all of them are updated
+    fn schedule_handshakes(&mut self, handles: &mut Vec<(i32, HandshakeHandle)>) -> bool {
+        handles.clear();
+        let budget = match self.handshake_update_budget {
+            Some(budget) => budget,
+            None => return false,
+        };
+        let handshake_count = self.identity_clients.len()
+            + self.identity_servers.len()
+            + self.endpoint_clients.len()
+            + self.endpoint_servers.len();
+        if handshake_count <= budget {
+            return false;
+        }
+
+        handles.extend(
+            self.identity_clients
+                .keys()
+                .chain(self.identity_servers.keys())
+                .chain(self.endpoint_clients.keys())
+                .chain(self.endpoint_servers.keys())
+                .map(|handle| {
+                    let priority = self.handshake_priorities.get(handle).copied().unwrap_or(0);
+                    (priority, *handle)
+                }),
+        );
+
+        // highest priority first, then the handles after the cursor, then the
+        // handles which were updated most recently
+        let cursor = self.handshake_schedule_cursor;
+        handles.sort_unstable_by_key(|(priority, handle)| {
+            (std::cmp::Reverse(*priority), *handle <= cursor, *handle)
+        });
+        handles.truncate(budget);
+        if let Some((_priority, handle)) = handles.last() {
+            self.handshake_schedule_cursor = *handle;
+        }
+        handles.sort_unstable_by_key(|(_priority, handle)| *handle);
+        true
+    }
+
+    // whether a new outgoing handshake may open its connection immediately
+    fn outbound_connection_available(&self) -> bool {
+        // outgoing handshakes wait for tor to bootstrap and for a tor provider
+        // switch to complete
+        if !self.bootstrap_complete || self.tor_provider_migration.is_some() {
+            return false;
+        }
+        match self.max_outbound_connections {
+            Some(max_outbound_connections) => {
+                self.identity_clients.len() + self.endpoint_clients.len() < max_outbound_connections
+            }
+            None => true,
+        }
+    }
+
+    // the time remaining until connections to an identity server which
+    // recently failed to connect are attempted again
+    fn identity_server_retry_after(
+        &mut self,
+        identity_server_id: &V3OnionServiceId,
+    ) -> Option<Duration> {
+        let negative_ttl = self.identity_server_negative_ttl?;
+        let elapsed = self
+            .clock
+            .now()
+            .saturating_duration_since(*self.unreachable_identity_servers.get(identity_server_id)?);
+        if elapsed < negative_ttl {
+            Some(negative_ttl - elapsed)
+        } else {
+            self.unreachable_identity_servers.remove(identity_server_id);
+            None
+        }
+    }
+
+    fn record_identity_server_failure(&mut self, identity_server_id: V3OnionServiceId) {
+        let negative_ttl = match self.identity_server_negative_ttl {
+            Some(negative_ttl) => negative_ttl,
+            None => return,
+        };
+        let now = self.clock.now();
+        self.unreachable_identity_servers
+            .retain(|_, failed| now.saturating_duration_since(*failed) < negative_ttl);
+        if self.unreachable_identity_servers.len() >= MAX_UNREACHABLE_IDENTITY_SERVERS {
+            // forget the oldest failure
+            if let Some(oldest) = self
+                .unreachable_identity_servers
+                .iter()
+                .min_by_key(|(_, failed)| **failed)
+                .map(|(service_id, _)| service_id.clone())
+            {
+                self.unreachable_identity_servers.remove(&oldest);
+            }
+        }
+        self.unreachable_identity_servers
+            .insert(identity_server_id, now);
+    }
+
+    fn identity_client_connect(
+        &mut self,
+        identity_server_id: V3OnionServiceId,
+        endpoint: AsciiString,
+        request_endpoint_upgrade: bool,
+        additional_endpoints: Vec<AsciiString>,
+        contact_request: Option<String>,
+        circuit_token: Option<CircuitToken>,
+    ) -> Result<IdentityClient, Error> {
+        // continue over a connection kept open by a previous handshake
+        let client_rpc = match self
+            .kept_open_identity_client_sessions
+            .remove(&identity_server_id)
+        {
+            Some((client_rpc, _deadline, _handle)) => client_rpc,
+            None => {
+                // open tcp stream to remove ident server
+                let timestamp = self.clock.now();
+                let identity_port = self.identity_virt_port(&identity_server_id);
+                let stream: TcpStream = match self.tor_provider.connect(
+                    (identity_server_id.clone(), identity_port).into(),
+                    circuit_token,
+                ) {
+                    Ok(stream) => {
+                        self.unreachable_identity_servers
+                            .remove(&identity_server_id);
+                        stream.into()
+                    }
+                    Err(err) => {
+                        self.record_identity_server_failure(identity_server_id);
+                        return Err(err.into());
+                    }
+                };
+                self.timings.record(
+                    TimedOperation::SocksConnect,
+                    self.clock.now().saturating_duration_since(timestamp),
+                );
+                stream.set_nonblocking(true)?;
+                let mut client_rpc = Session::new(stream);
+                client_rpc.set_max_wait_time(self.identity_timeout);
+                client_rpc.set_max_message_size(self.identity_max_message_size)?;
+                client_rpc
+            }
+        };
+
+        let mut identity_client = IdentityClient::new(
+            client_rpc,
+            identity_server_id,
+            endpoint,
+            self.identity_private_key.clone(),
+            X25519PrivateKey::generate(),
+            request_endpoint_upgrade,
+        )?;
+        identity_client.set_additional_endpoints(additional_endpoints)?;
+        identity_client.set_contact_request(contact_request)?;
+        identity_client.set_capabilities(self.identity_capabilities())?;
+        identity_client.set_state_deadline(Some(self.identity_timeout));
+        identity_client.set_clock(self.clock.clone());
+        identity_client.set_read_idle_timeout(self.handshake_read_idle_timeout);
+        Ok(identity_client)
+    }
+
+    fn endpoint_client_connect(
+        &mut self,
+        endpoint_server_id: V3OnionServiceId,
+        client_auth_key: X25519PrivateKey,
+        channel: AsciiString,
+        circuit_token: Option<CircuitToken>,
+    ) -> Result<EndpointClient, Error> {
+        match self
+            .tor_provider
+            .add_client_auth(&endpoint_server_id, &client_auth_key)
+        {
+            Ok(()) => {
+                self.client_auth_entries
+                    .insert(endpoint_server_id.clone(), client_auth_key.clone());
+                self.client_auth_events.push(ContextEvent::ClientAuthAdded {
+                    endpoint_service_id: endpoint_server_id.clone(),
+                });
+            }
+            Err(err) => {
+                self.client_auth_events
+                    .push(ContextEvent::ClientAuthAddFailed {
+                        endpoint_service_id: endpoint_server_id.clone(),
+                        reason: err.into(),
+                    });
+                return Err(Error::ClientAuthAddFailed(endpoint_server_id));
+            }
+        }
+
+        let result =
+            self.endpoint_client_open(endpoint_server_id.clone(), None, channel, circuit_token);
+        if result.is_err() {
+            self.endpoint_client_finished(&endpoint_server_id, false);
+        }
+        result
+    }
+
+    // connect to an endpoint server's onion-service once its client authorization
+    // key has been added, or to its identity server's onion-service if shared
+    fn endpoint_client_open(
+        &mut self,
+        endpoint_server_id: V3OnionServiceId,
+        shared_identity_server_id: Option<V3OnionServiceId>,
+        channel: AsciiString,
+        circuit_token: Option<CircuitToken>,
+    ) -> Result<EndpointClient, Error> {
+        let timestamp = self.clock.now();
+        let target = match shared_identity_server_id.as_ref() {
+            Some(identity_server_id) => (
+                identity_server_id.clone(),
+                self.shared_endpoint_virt_port(identity_server_id),
+            ),
+            None => (
+                endpoint_server_id.clone(),
+                self.endpoint_virt_port(&endpoint_server_id),
+            ),
+        };
+        let stream: TcpStream = self
+            .tor_provider
+            .connect(target.into(), circuit_token)?
+            .into();
+        let elapsed = self.clock.now().saturating_duration_since(timestamp);
+        self.timings.record(TimedOperation::SocksConnect, elapsed);
+        if let Some(connect_timeout) = self.endpoint_client_connect_timeout {
+            if elapsed > connect_timeout {
+                return Err(Error::EndpointConnectTimedOut(
+                    endpoint_server_id,
+                    connect_timeout,
+                ));
+            }
+        }
+        stream.set_nonblocking(true)?;
+
+        let mut session = Session::new(stream);
+        session.set_max_wait_time(self.endpoint_timeout);
+        session.set_max_message_size(DEFAULT_ENDPOINT_MAX_MESSAGE_SIZE)?;
+
+        let rpc_channel = self.rpc_channels.contains(&channel);
+        let mut endpoint_client = EndpointClient::new(
+            session,
+            endpoint_server_id,
+            channel,
+            self.identity_private_key.clone(),
+        );
+        endpoint_client.set_capabilities(self.capabilities)?;
+        endpoint_client.set_shared(shared_identity_server_id.is_some())?;
+        endpoint_client.set_rpc_channel(rpc_channel)?;
+        endpoint_client.set_state_deadline(Some(self.endpoint_timeout));
+        endpoint_client.set_clock(self.clock.clone());
+        endpoint_client.set_read_idle_timeout(self.handshake_read_idle_timeout);
+        endpoint_client.set_handshake_deadline(self.endpoint_client_handshake_timeout);
+        Ok(endpoint_client)
+    }
+
+    // continue with an endpoint handshake over a completed identity handshake's connection
+    fn endpoint_client_upgrade(
+        &self,
+        identity_client: IdentityClient,
+        endpoint_server_id: V3OnionServiceId,
+        channel: AsciiString,
+    ) -> Result<EndpointClient, Error> {
+        let mut session = identity_client.into_session();
+        session.set_max_wait_time(self.endpoint_timeout);
+        session.set_max_message_size(DEFAULT_ENDPOINT_MAX_MESSAGE_SIZE)?;
+
+        let rpc_channel = self.rpc_channels.contains(&channel);
+        let mut endpoint_client = EndpointClient::new(
+            session,
+            endpoint_server_id,
+            channel,
+            self.identity_private_key.clone(),
+        );
+        endpoint_client.set_capabilities(self.capabilities)?;
+        endpoint_client.set_rpc_channel(rpc_channel)?;
+        endpoint_client.set_state_deadline(Some(self.endpoint_timeout));
+        endpoint_client.set_clock(self.clock.clone());
+        endpoint_client.set_read_idle_timeout(self.handshake_read_idle_timeout);
+        endpoint_client.set_handshake_deadline(self.endpoint_client_handshake_timeout);
+        Ok(endpoint_client)
+    }
+
+    /// Initiate an identity handshake with an identity server. Handshake progression is communicated through  [`ContextEvent`]s returned from the [`Context::update()`] method. If the outbound connection limit has been reached, the handshake is queued until a connection slot is available (see [`Context::set_max_outbound_connections()`]).
+    ///
+    /// # Parameters
+    /// - `identitity_server_id`: the long term identity onion-service service-id of a remote peer
+    /// - `endpoint`: the ASCII-encoded requested endpoint
+    /// # Returns
+    /// A `HandshakeHandle` used to refer to this particular identity handshake.
+    pub fn identity_client_begin_handshake(
+        &mut self,
+        identity_server_id: V3OnionServiceId,
+        endpoint: AsciiString,
+    ) -> Result<HandshakeHandle, Error> {
+        self.identity_client_begin_handshake_with_options(identity_server_id, endpoint, false)
+    }
+
+    /// Initiate an identity handshake with an identity server, as with [`Context::identity_client_begin_handshake()`]. If the identity server recently failed to connect (see [`Context::set_identity_server_negative_ttl()`]) the handshake is suppressed unless `ignore_cached_failure` is set.
+    ///
+    /// # Parameters
+    /// - `identitity_server_id`: the long term identity onion-service service-id of a remote peer
+    /// - `endpoint`: the ASCII-encoded requested endpoint
+    /// - `ignore_cached_failure`: attempt the connection even if the identity server recently failed to connect
+    /// # Returns
+    /// A `HandshakeHandle` used to refer to this particular identity handshake.
+    pub fn identity_client_begin_handshake_with_options(
+        &mut self,
+        identity_server_id: V3OnionServiceId,
+        endpoint: AsciiString,
+        ignore_cached_failure: bool,
+    ) -> Result<HandshakeHandle, Error> {
+        self.identity_client_begin_handshake_impl(
+            identity_server_id,
+            endpoint,
+            ignore_cached_failure,
+            None,
+            Default::default(),
+            None,
+            None,
+        )
+    }
+
+    /// Initiate an identity handshake with an identity server, as with [`Context::identity_client_begin_handshake()`], connecting to the identity server with the given circuit token. Connections made with different circuit tokens use different circuits through the Tor Network, so using a token per contact prevents their connections from being linked by a shared circuit. If the token is released before the connection is made, the handshake fails.
+    ///
+    /// # Parameters
+    /// - `identitity_server_id`: the long term identity onion-service service-id of a remote peer
+    /// - `endpoint`: the ASCII-encoded requested endpoint
+    /// - `circuit_token`: a circuit token from [`Context::generate_circuit_token()`]
+    /// # Returns
+    /// A `HandshakeHandle` used to refer to this particular identity handshake.
+    pub fn identity_client_begin_handshake_with_circuit_token(
+        &mut self,
+        identity_server_id: V3OnionServiceId,
+        endpoint: AsciiString,
+        circuit_token: CircuitToken,
+    ) -> Result<HandshakeHandle, Error> {
+        self.identity_client_begin_handshake_impl(
+            identity_server_id,
+            endpoint,
+            false,
+            None,
+            Default::default(),
+            None,
+            Some(circuit_token),
+        )
+    }
+
+    /// Initiate an identity handshake with an identity server and, once it completes, an endpoint handshake with the granted endpoint server. If the identity server agrees (see [`Context::identity_server_set_endpoint_upgrade_allowed()`]), the endpoint handshake continues over the identity handshake's connection, skipping the connection to the endpoint server's onion-service and its descriptor fetch. Otherwise, the endpoint server is connected to as with [`Context::endpoint_client_begin_handshake()`].
+    ///
+    /// The identity handshake progresses as with [`Context::identity_client_begin_handshake()`]. After the [`ContextEvent::IdentityClientHandshakeCompleted`] event, the endpoint handshake's progression is communicated through the endpoint client events using the same handle.
+    ///
+    /// # Parameters
+    /// - `identitity_server_id`: the long term identity onion-service service-id of a remote peer
+    /// - `endpoint`: the ASCII-encoded requested endpoint
+    /// - `channel`: the ASCII-encoded requested channel on the granted endpoint server
+    /// # Returns
+    /// A `HandshakeHandle` used to refer to both the identity and the endpoint handshake.
+    pub fn identity_client_begin_handshake_with_endpoint_upgrade(
+        &mut self,
+        identity_server_id: V3OnionServiceId,
+        endpoint: AsciiString,
+        channel: AsciiString,
+    ) -> Result<HandshakeHandle, Error> {
+        ensure_channel_name_length(&channel)?;
+        self.identity_client_begin_handshake_impl(
+            identity_server_id,
+            endpoint,
+            false,
+            Some(channel),
+            Default::default(),
+            None,
+            None,
+        )
+    }
+
+    /// Initiate an identity handshake with an identity server requesting several endpoints at once, saving the client from repeating the handshake for each of them. The identity server grants or denies each additional endpoint individually (see [`Context::identity_server_handle_endpoint_request_received_with_additional_endpoints()`]) and the outcomes are reported in the [`ContextEvent::IdentityClientHandshakeCompleted`] event. Identity servers which do not support additional endpoints deny all of them. The handshake otherwise progresses as with [`Context::identity_client_begin_handshake()`], and it fails if the primary endpoint is denied.
+    ///
+    /// # Parameters
+    /// - `identitity_server_id`: the long term identity onion-service service-id of a remote peer
+    /// - `endpoint`: the ASCII-encoded requested endpoint
+    /// - `additional_endpoints`: up to 16 further distinct ASCII-encoded endpoints
+    /// # Returns
+    /// A `HandshakeHandle` used to refer to this particular identity handshake.
+    pub fn identity_client_begin_handshake_with_additional_endpoints(
+        &mut self,
+        identity_server_id: V3OnionServiceId,
+        endpoint: AsciiString,
+        additional_endpoints: Vec<AsciiString>,
+    ) -> Result<HandshakeHandle, Error> {
+        if additional_endpoints.len() > MAX_ADDITIONAL_ENDPOINTS {
+            return Err(Error::InvalidArgument(format!(
+                "at most {} additional endpoints may be requested",
+                MAX_ADDITIONAL_ENDPOINTS
+            )));
+        }
+        let mut parsed: Vec<AsciiString> = Vec::with_capacity(additional_endpoints.len());
+        for additional_endpoint in additional_endpoints {
+            ensure_endpoint_name_length(&additional_endpoint)?;
+            if additional_endpoint == endpoint || parsed.contains(&additional_endpoint) {
+                return Err(Error::InvalidArgument(format!(
+                    "endpoint '{}' requested more than once",
+                    additional_endpoint
+                )));
+            }
+            parsed.push(additional_endpoint);
+        }
+        self.identity_client_begin_handshake_impl(
+            identity_server_id,
+            endpoint,
+            false,
+            None,
+            parsed,
+            None,
+            None,
+        )
+    }
+
+    /// Initiate an identity handshake with an identity server, as with [`Context::identity_client_begin_handshake()`], attaching a contact request to the endpoint request. A contact request is a short application-specific message, e.g. a petname or an introduction, which the identity server receives in the [`ContextEvent::IdentityServerEndpointRequestReceived`] event so it may decide whether to grant access. Identity servers which do not support contact requests ignore it.
+    ///
+    /// # Parameters
+    /// - `identitity_server_id`: the long term identity onion-service service-id of a remote peer
+    /// - `endpoint`: the ASCII-encoded requested endpoint
+    /// - `contact_request`: a message of at most 512 UTF-8 encoded bytes, which must not contain a null byte
+    /// # Returns
+    /// A `HandshakeHandle` used to refer to this particular identity handshake.
+    pub fn identity_client_begin_handshake_with_contact_request(
+        &mut self,
+        identity_server_id: V3OnionServiceId,
+        endpoint: AsciiString,
+        contact_request: String,
+    ) -> Result<HandshakeHandle, Error> {
+        self.identity_client_begin_handshake_impl(
+            identity_server_id,
+            endpoint,
+            false,
+            None,
+            Default::default(),
+            Some(contact_request),
+            None,
+        )
+    }
+
+    fn identity_client_begin_handshake_impl(
+        &mut self,
+        identity_server_id: V3OnionServiceId,
+        endpoint: AsciiString,
+        ignore_cached_failure: bool,
+        endpoint_upgrade_channel: Option<AsciiString>,
+        additional_endpoints: Vec<AsciiString>,
+        contact_request: Option<String>,
+        circuit_token: Option<CircuitToken>,
+    ) -> Result<HandshakeHandle, Error> {
+        ensure_endpoint_name_length(&endpoint)?;
+
+        if let Some(contact_request) = contact_request.as_ref() {
+            if contact_request.len() > MAX_CONTACT_REQUEST_SIZE {
+                return Err(Error::InvalidArgument(format!(
+                    "contact request may be at most {} bytes",
+                    MAX_CONTACT_REQUEST_SIZE
+                )));
+            }
+            if contact_request.contains('\0') {
+                return Err(Error::InvalidArgument(
+                    "contact request must not contain a null byte".to_string(),
+                ));
+            }
+        }
+
+        for (requested, capability, feature) in [
+            (
+                endpoint_upgrade_channel.is_some(),
+                Capabilities::ENDPOINT_UPGRADE,
+                "endpoint upgrades",
+            ),
+            (
+                !additional_endpoints.is_empty(),
+                Capabilities::ADDITIONAL_ENDPOINTS,
+                "additional endpoints",
+            ),
+            (
+                contact_request.is_some(),
+                Capabilities::CONTACT_REQUEST,
+                "contact requests",
+            ),
+        ] {
+            if requested && !self.capabilities.contains(capability) {
+                return Err(Error::InvalidArgument(format!(
+                    "{} are disabled by set_capabilities()",
+                    feature
+                )));
+            }
+        }
+
+        if let Some(validator) = self.identity_client_endpoint_validator.as_ref() {
+            for endpoint in std::iter::once(&endpoint).chain(additional_endpoints.iter()) {
+                match validator(&identity_server_id, endpoint.as_str()) {
+                    EndpointValidatorVerdict::Allow => (),
+                    verdict => return Err(Error::EndpointRejected(endpoint.to_string(), verdict)),
+                }
+            }
+        }
+
+        if !self.bootstrap_complete && !self.queue_until_bootstrapped {
+            return Err(Error::TorNotConnected());
+        }
+
+        let handshake_handle = self.next_handshake_handle;
+        self.next_handshake_handle += 1;
+
+        if !ignore_cached_failure {
+            if let Some(retry_after) = self.identity_server_retry_after(&identity_server_id) {
+                self.suppressed_identity_handshakes.push((
+                    handshake_handle,
+                    identity_server_id,
+                    retry_after,
+                ));
+                return Ok(handshake_handle);
+            }
+        }
+
+        if self.outbound_queue.is_empty() && self.outbound_connection_available() {
+            let ident_client = self.identity_client_connect(
+                identity_server_id,
+                endpoint,
+                endpoint_upgrade_channel.is_some(),
+                additional_endpoints,
+                contact_request,
+                circuit_token,
+            )?;
+            self.identity_clients.insert(handshake_handle, ident_client);
+            if let Some(channel) = endpoint_upgrade_channel {
+                self.endpoint_upgrade_channels
+                    .insert(handshake_handle, (channel, circuit_token));
+            }
+        } else {
+            self.enqueue_handshake(QueuedHandshake {
+                handle: handshake_handle,
+                priority: 0,
+                handshake: PendingHandshake::Identity {
+                    identity_server_id,
+                    endpoint,
+                    ignore_cached_failure,
+                    endpoint_upgrade_channel,
+                    additional_endpoints,
+                    contact_request,
+                    circuit_token,
+                },
+                reported_position: None,
+                awaiting_bootstrap: !self.bootstrap_complete,
+            });
+        }
+
+        Ok(handshake_handle)
+    }
+
+    /// Abort an in-process outgoing identity handshake.
+    ///
+    /// # Parameters
+    /// - `handle`: the handle of the in-progress outoing identity handshake to abort
+    pub fn identity_client_abort_handshake(
+        &mut self,
+        handle: HandshakeHandle,
+    ) -> Result<(), Error> {
+        if let Some(_identity_client) = self.identity_clients.remove(&handle) {
+            self.endpoint_upgrade_channels.remove(&handle);
+            Ok(())
+        } else if let Some(index) = self.outbound_queue.iter().position(|queued| {
+            queued.handle == handle && matches!(queued.handshake, PendingHandshake::Identity { .. })
+        }) {
+            self.outbound_queue.remove(index);
+            Ok(())
+        } else if let Some(index) = self
+            .suppressed_identity_handshakes
+            .iter()
+            .position(|(suppressed, _, _)| *suppressed == handle)
+        {
+            self.suppressed_identity_handshakes.remove(index);
+            Ok(())
+        } else {
+            Err(Error::HandshakeHandleNotFound(handle))
+        }
+    }
+
+    /// Handle an identity server's endpoint challenge. Callers must construct an identity client's endpoint challenge-response. The particulars of creating and verifying the challenge-response BSON documents are undefined and application-specific.
+    ///
+    /// # Parameters
+    /// - `handle`: the handle of the in-progress outgoing identity handshake
+    /// - `challenge_response`: an application-specific BSON document which somehow responds to an identity server's challenge.
+    pub fn identity_client_handle_challenge_received(
+        &mut self,
+        handle: HandshakeHandle,
+        challenge_response: bson::document::Document,
+    ) -> Result<(), Error> {
+        if let Some(identity_client) = self.identity_clients.get_mut(&handle) {
+            identity_client.send_response(challenge_response)?;
+            Ok(())
+        } else {
+            Err(Error::HandshakeHandleNotFound(handle))
+        }
+    }
+
+    /// The virt-port used for an identity or endpoint server's onion-service when its port is given as 0 to [`Context::new()`]. The port is derived from the onion-service's public key and lies between 1024 and 65535 inclusive, so peers which both use derived ports agree on each server's port without exchanging it.
+    ///
+    /// # Parameters
+    /// - `service_id`: the onion-service service-id of the identity or endpoint server
+    pub fn derived_virt_port(service_id: &V3OnionServiceId) -> u16 {
+        // the first two bytes of the public key are decoded from the first four
+        // base32 characters of the service-id rather than by parsing the key, as
+        // a V3OnionServiceId's key need not be a valid ed25519 point
+        let bits = service_id
+            .to_string()
+            .bytes()
+            .take(4)
+            .fold(0u32, |bits, c| {
+                let digit = match c {
+                    b'a'..=b'z' => c - b'a',
+                    b'2'..=b'7' => c - b'2' + 26,
+                    _ => 0,
+                };
+                (bits << 5) | u32::from(digit)
+            });
+        let value = (bits >> 4) as u16;
+        DERIVED_VIRT_PORT_MIN + value % (u16::MAX - DERIVED_VIRT_PORT_MIN + 1)
+    }
+
+    // the virt-port of the identity server with the given service-id
+    fn identity_virt_port(&self, service_id: &V3OnionServiceId) -> u16 {
+        match self.identity_port {
+            0 => Self::derived_virt_port(service_id),
+            identity_port => identity_port,
+        }
+    }
+
+    // the virt-port of the endpoint server with the given service-id
+    fn endpoint_virt_port(&self, service_id: &V3OnionServiceId) -> u16 {
+        match self.endpoint_port {
+            0 => Self::derived_virt_port(service_id),
+            endpoint_port => endpoint_port,
+        }
+    }
+
+    // the virt-port on which the identity server with the given service-id
+    // serves its shared endpoint servers
+    fn shared_endpoint_virt_port(&self, service_id: &V3OnionServiceId) -> u16 {
+        match self.endpoint_port {
+            // an endpoint port derived from the identity server's key would be
+            // its identity port, so the following port is used
+            0 => match Self::derived_virt_port(service_id) {
+                u16::MAX => DERIVED_VIRT_PORT_MIN,
+                identity_port => identity_port + 1,
+            },
+            endpoint_port => endpoint_port,
+        }
+    }
+
+    // fail if the onion-service is already in use by one of this context's
+    // servers; tor can only host one set of listeners per onion-service
+    fn ensure_no_listener_conflict(&self, service_id: &V3OnionServiceId) -> Result<(), Error> {
+        if *service_id == self.identity_service_id {
+            Err(Error::ListenerConflict(
+                service_id.clone(),
+                self.identity_virt_port(service_id),
+                "identity server".to_string(),
+            ))
+        } else if self.endpoint_listeners.contains_key(service_id) {
+            Err(Error::ListenerConflict(
+                service_id.clone(),
+                self.endpoint_virt_port(service_id),
+                "endpoint server".to_string(),
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Start this `Context`'s identity server. Publish status is communicated through [`ContextEvent`]s returned from the [`Context::update()`] method.
+    pub fn identity_server_start(&mut self) -> Result<(), Error> {
+        if !self.bootstrap_complete {
+            return Err(Error::TorNotConnected());
+        }
+        if self.identity_listener.is_some() {
+            return Err(Error::IncorrectUsage(
+                "identity server already started".to_string(),
+            ));
+        }
+
+        let identity_port = self.identity_virt_port(&self.identity_service_id);
+        let mut virt_ports = vec![identity_port];
+        if self.identity_server_shared_endpoints_enabled {
+            let shared_endpoint_port = self.shared_endpoint_virt_port(&self.identity_service_id);
+            if shared_endpoint_port == identity_port {
+                return Err(Error::ListenerConflict(
+                    self.identity_service_id.clone(),
+                    identity_port,
+                    "identity server".to_string(),
+                ));
+            }
+            virt_ports.push(shared_endpoint_port);
+        }
+        let mut listeners = self
+            .tor_provider
+            .listener_with_ports(
+                &self.identity_private_key,
+                &virt_ports,
+                None,
+                false,
+                self.identity_server_pow_defenses.as_ref(),
+            )
+            .map_err(|err| {
+                Error::ListenerStartFailed(self.identity_service_id.clone(), identity_port, err)
+            })?;
+        for listener in listeners.iter() {
+            listener.set_nonblocking(true)?;
+        }
+
+        // the listeners are returned in the order of their virt-ports
+        self.shared_endpoint_listener = match listeners.len() {
+            2 => listeners.pop(),
+            _ => None,
+        };
+        self.identity_listener = listeners.pop();
+        Ok(())
+    }
+
+    /// Set whether this `Context`'s identity server also serves its shared endpoint servers, so that endpoint servers need not each publish their own onion-service (see [`EndpointConfig::shared`]). This suits resource-constrained servers with many contacts, at the cost of the protection offered by client authorization: anyone who knows the identity server's service-id may connect to its shared endpoint servers, which are then protected only by the endpoint handshake's client proof.
+    ///
+    /// When enabled, the identity server's onion-service is started with a second virt-port for shared endpoint servers, which is the `endpoint_port` passed to [`Context::new()`] or, if it is 0, the port following the identity server's derived virt-port. This requires a tor provider which supports several virt-ports per onion-service (see [`TorProvider::listener_with_ports()`]). This setting only applies when the identity server is next started with [`Context::identity_server_start()`].
+    ///
+    /// # Parameters
+    /// - `enabled`: whether shared endpoint servers are served (the default is `false`)
+    pub fn identity_server_set_shared_endpoints_enabled(&mut self, enabled: bool) {
+        self.identity_server_shared_endpoints_enabled = enabled;
+    }
+
+    /// Set the proof-of-work defenses enabled on this `Context`'s identity server's onion-service, protecting it from floods of introduction requests. Clients must then solve a proof-of-work puzzle before connecting, so this should only be enabled on identity servers which are under attack or are expected to be. This setting only applies when the identity server is next started with [`Context::identity_server_start()`], which fails if the tor provider does not support proof-of-work defenses.
+    ///
+    /// # Parameters
+    /// - `pow_defenses`: the proof-of-work defenses to enable, or `None` to disable them (the default)
+    pub fn identity_server_set_pow_defenses(&mut self, pow_defenses: Option<PowDefenses>) {
+        self.identity_server_pow_defenses = pow_defenses;
+    }
+
+    /// Force the tor provider to build and publish a fresh descriptor for this `Context`'s running identity server without stopping it, e.g. after its hidden-service directories were found to have lost the descriptor. Shared endpoint servers are served on the identity server's onion-service, so their descriptor is refreshed too. A [`ContextEvent::IdentityServerDescriptorRefreshed`] event is returned once the refreshed descriptor has been published, or a [`ContextEvent::IdentityServerPublished`] event if the identity server had not yet been published. An error is returned if the tor provider cannot refresh descriptors on demand.
+    pub fn identity_server_refresh_descriptor(&mut self) -> Result<(), Error> {
+        if self.identity_listener.is_none() {
+            return Err(Error::IncorrectUsage(
+                "identity server is not started".to_string(),
+            ));
+        }
+
+        self.tor_provider
+            .refresh_descriptor(&self.identity_service_id)?;
+        Ok(())
+    }
+
+    /// Stops this `Context`'s identity server and ends any in-progress incoming identity handshakes. Shared endpoint servers remain configured but are unreachable until the identity server is started again.
+    pub fn identity_server_stop(&mut self) -> Result<(), Error> {
+        if self.identity_listener.is_none() {
+            return Err(Error::IncorrectUsage(
+                "identity server is not started".to_string(),
+            ));
+        }
+
+        // clear out current identity listener
+        self.identity_listener = None;
+        self.shared_endpoint_listener = None;
+        // clear out published flag
+        self.identity_server_published = false;
+        for (_, _, listener, published) in self.endpoint_listeners.values_mut() {
+            if listener.is_none() {
+                *published = false;
+            }
+        }
+        // clear out any in-process identity handshakes and kept-open connections
+        self.identity_servers = Default::default();
+        self.kept_open_identity_servers = Default::default();
+        Ok(())
+    }
+
+    /// Set a filter which identity servers consult as soon as an identity client begins its handshake, before a challenge is requested from the application or any proofs are verified. Clients rejected by the filter receive an error response naming the reason and the handshake fails with [`ContextEvent::IdentityServerHandshakeFailed`]; no [`ContextEvent::IdentityServerEndpointRequestReceived`] event is emitted. This allows abusive clients to be turned away cheaply, e.g. based on request rate or reputation. The filter applies to all incoming identity handshakes which begin after it is set and replaces any previously set filter.
+    ///
+    /// The filter is called from [`Context::update()`] with the client's alleged onion-service service-id and the ASCII-encoded name of its requested endpoint.
+    pub fn identity_server_set_client_filter<F>(&mut self, filter: F)
+    where
+        F: Fn(&V3OnionServiceId, &str) -> ClientFilterVerdict + Send + Sync + 'static,
+    {
+        self.identity_client_filter = Some(Arc::new(filter));
+    }
+
+    /// Remove the filter set with [`Context::identity_server_set_client_filter()`]; all clients will be allowed to proceed to the endpoint request.
+    pub fn identity_server_clear_client_filter(&mut self) {
+        self.identity_client_filter = None;
+    }
+
+    /// Set a validator which outgoing identity handshakes' endpoint names are checked against before any connection to the identity server is attempted. This allows applications to enforce their endpoint naming rules and reject endpoints they do not support without building a tor circuit. The requested endpoint and any additional endpoints (see [`Context::identity_client_begin_handshake_with_additional_endpoints()`]) are each checked, and the first rejected endpoint name fails the `identity_client_begin_handshake*()` call with [`Error::EndpointRejected`]; no handshake is started or queued. The validator replaces any previously set validator.
+    ///
+    /// The validator is called from the `identity_client_begin_handshake*()` methods with the identity server's onion-service service-id and the ASCII-encoded endpoint name.
+    pub fn identity_client_set_endpoint_validator<F>(&mut self, validator: F)
+    where
+        F: Fn(&V3OnionServiceId, &str) -> EndpointValidatorVerdict + Send + Sync + 'static,
+    {
+        self.identity_client_endpoint_validator = Some(Arc::new(validator));
+    }
+
+    /// Remove the validator set with [`Context::identity_client_set_endpoint_validator()`]; all ASCII endpoint names will be requested from identity servers.
+    pub fn identity_client_clear_endpoint_validator(&mut self) {
+        self.identity_client_endpoint_validator = None;
+    }
+
+    /// Set the [`PinStore`] in which the endpoint servers granted by completed outgoing identity handshakes are pinned on first use. Each granted endpoint server, including granted additional endpoints, is compared against the pin for its identity server's endpoint and a [`ContextEvent::IdentityClientEndpointPinMismatched`] event is returned for each mismatch. The pin store replaces any previously set pin store.
+    ///
+    /// # Parameters
+    /// - `pin_store`: the pin store, e.g. [`PinStore::new()`] or pins restored by the application, or `None` to stop pinning (the default)
+    pub fn identity_client_set_pin_store(&mut self, pin_store: Option<PinStore>) {
+        self.pin_store = pin_store;
+    }
+
+    /// The [`PinStore`] set with [`Context::identity_client_set_pin_store()`], if any
+    pub fn identity_client_pin_store(&self) -> Option<&PinStore> {
+        self.pin_store.as_ref()
+    }
+
+    /// The [`PinStore`] set with [`Context::identity_client_set_pin_store()`], if any, e.g. to accept a mismatched endpoint server with [`PinStore::pin()`]
+    pub fn identity_client_pin_store_mut(&mut self) -> Option<&mut PinStore> {
+        self.pin_store.as_mut()
+    }
+
+    /// Set the [`PetnameStore`] naming the identities known to the application. The endpoint servers granted by completed outgoing identity handshakes with an identity which has a petname, including granted additional endpoints, are recorded in the petname store, which is then saved with [`PetnameStore::save()`]. The petname store replaces any previously set petname store.
+    ///
+    /// # Parameters
+    /// - `petname_store`: the petname store, e.g. [`PetnameStore::new()`] or one opened with [`PetnameStore::open()`], or `None` to stop recording grants (the default)
+    pub fn set_petname_store(&mut self, petname_store: Option<PetnameStore>) {
+        self.petname_store = petname_store;
+    }
+
+    /// The [`PetnameStore`] set with [`Context::set_petname_store()`], if any
+    pub fn petname_store(&self) -> Option<&PetnameStore> {
+        self.petname_store.as_ref()
+    }
+
+    /// The [`PetnameStore`] set with [`Context::set_petname_store()`], if any, e.g. to give an identity a petname with [`PetnameStore::set()`]. Changes are not saved until [`PetnameStore::save()`] is called.
+    pub fn petname_store_mut(&mut self) -> Option<&mut PetnameStore> {
+        self.petname_store.as_mut()
+    }
+
+    /// Set the [`BanList`] of peers this `Context`'s servers turn away. Banned identity clients are rejected as soon as they begin an identity handshake, before the client filter (see [`Context::identity_server_set_client_filter()`]) is consulted, and are reported with a [`ContextEvent::IdentityServerBannedClientRejected`] event. Banned endpoint clients are refused once they have proven their identity, and are reported with a [`ContextEvent::EndpointServerBannedClientRejected`] event.
+    ///
+    /// The application may keep a clone of the ban list to ban and unban peers; changes apply immediately. Expired bans are removed on each [`Context::update()`], and a ban list opened with [`BanList::open()`] is then saved with [`BanList::save()`]. The ban list replaces any previously set ban list for handshakes which begin after it is set.
+    ///
+    /// # Parameters
+    /// - `ban_list`: the ban list, e.g. [`BanList::new()`] or one opened with [`BanList::open()`], or `None` to stop turning away banned peers (the default)
+    pub fn set_ban_list(&mut self, ban_list: Option<BanList>) {
+        self.ban_list = ban_list;
+    }
+
+    /// The [`BanList`] set with [`Context::set_ban_list()`], if any
+    pub fn ban_list(&self) -> Option<&BanList> {
+        self.ban_list.as_ref()
+    }
+
+    /// Set whether this `Context`'s identity server agrees to identity clients' requests to continue with an endpoint handshake over the identity handshake's connection (see [`Context::identity_client_begin_handshake_with_endpoint_upgrade()`]). This avoids the client fetching the endpoint server's onion-service descriptor and building a new circuit, which is only possible because the identity server and endpoint server are run by the same `Context`.
+    ///
+    /// When an upgraded identity handshake completes, its connection is held until the granted endpoint server is started with [`Context::endpoint_server_start()`], at which point the endpoint handshake begins as if the client had connected to the endpoint server's onion-service. The connection is closed if the endpoint server is not started within the endpoint timeout. This setting only applies to handshakes which begin after it is changed.
+    ///
+    /// # Parameters
+    /// - `allowed`: whether endpoint upgrades are agreed to (the default is `false`)
+    pub fn identity_server_set_endpoint_upgrade_allowed(&mut self, allowed: bool) {
+        self.identity_server_endpoint_upgrade_allowed = allowed;
+    }
+
+    /// Set whether this `Context`'s identity server derives the private keys of the endpoint servers it grants from its identity key, the client's identity and the endpoint's name (see [`derive_endpoint_private_key()`](crate::endpoint_keys::derive_endpoint_private_key)) rather than generating them randomly. Granting the same endpoint to the same client again, e.g. after revoking it, then yields the same endpoint server, and an application which has lost its endpoint keys may recover them with [`Context::identity_server_derive_endpoint_private_key()`]. This setting only applies to handshakes which begin after it is changed.
+    ///
+    /// # Parameters
+    /// - `derive`: whether endpoint keys are derived (the default is `false`)
+    pub fn identity_server_set_derive_endpoint_keys(&mut self, derive: bool) {
+        self.identity_server_derive_endpoint_keys = derive;
+    }
+
+    /// Derive the private key of the endpoint server this `Context`'s identity server grants to a client when deriving endpoint keys; see [`Context::identity_server_set_derive_endpoint_keys()`].
+    ///
+    /// # Parameters
+    /// - `client_service_id`: the identity of the client the endpoint is granted to
+    /// - `endpoint_name`: the name of the granted endpoint
+    pub fn identity_server_derive_endpoint_private_key(
+        &self,
+        client_service_id: &V3OnionServiceId,
+        endpoint_name: &AsciiString,
+    ) -> Ed25519PrivateKey {
+        derive_endpoint_private_key(&self.identity_private_key, client_service_id, endpoint_name)
+    }
+
+    // the key endpoint keys are derived from by new identity handshakes, if any
+    fn endpoint_key_derivation_key(&self) -> Option<Ed25519PrivateKey> {
+        self.identity_server_derive_endpoint_keys
+            .then(|| self.identity_private_key.clone())
+    }
+
+    /// Set how long this `Context`'s identity server waits for an identity client to respond to its endpoint challenge. Clients which do not call `send_response()` in time are sent an error and disconnected, and the handshake fails with [`ContextEvent::IdentityServerHandshakeFailed`]. The deadline is measured from when the challenge is sent, so it does not include the time the application takes to build the challenge with [`Context::identity_server_handle_endpoint_request_received()`]. This setting only applies to handshakes which begin after it is changed.
+    ///
+    /// # Parameters
+    /// - `deadline`: how long clients have to respond to the endpoint challenge, or `None` to only rely on the identity timeout (the default)
+    pub fn identity_server_set_challenge_response_deadline(&mut self, deadline: Option<Duration>) {
+        self.identity_server_challenge_response_deadline = deadline;
+    }
+
+    /// Handle an identity client's incoming endpoint request. Callers must determine whether the connected identity client is allowed to access the requested endpoint, decide whether the requested endpoint is supported by this `Context`, and build an endpoint challenge for the identity client. The particulars of creating the endpoint challenge is undefined and application-specific.
+    ///
+    /// # Parameters
+    /// - `handle`: the handle of the in-progress incoming identity handshake
+    /// - `client_allowed`: whether the connected identity client is allowed to access the requested endpoint
+    /// - `endpoint_supported`: whether the requested endpoint is supported
+    /// - `endpoint_challenge`: an application-specific BSON document which the connected identity client must respond to
+    pub fn identity_server_handle_endpoint_request_received(
+        &mut self,
+        handle: HandshakeHandle,
+        client_allowed: bool,
+        endpoint_supported: bool,
+        endpoint_challenge: bson::document::Document,
+    ) -> Result<(), Error> {
+        if let Some(identity_server) = self.identity_servers.get_mut(&handle) {
+            Ok(identity_server.handle_endpoint_request_received(
+                client_allowed,
+                endpoint_supported,
+                endpoint_challenge,
+            )?)
+        } else {
+            Err(Error::HandshakeHandleNotFound(handle))
+        }
+    }
+
+    /// Handle an identity client's incoming endpoint request as with [`Context::identity_server_handle_endpoint_request_received()`], additionally granting or denying each of the additional endpoints from the [`ContextEvent::IdentityServerEndpointRequestReceived`] event. [`Context::identity_server_handle_endpoint_request_received()`] denies all of them. Additional endpoints are only granted if the handshake succeeds, which requires the requested endpoint to be supported.
+    ///
+    /// # Parameters
+    /// - `handle`: the handle of the in-progress incoming identity handshake
+    /// - `client_allowed`: whether the connected identity client is allowed to access the requested endpoint
+    /// - `endpoint_supported`: whether the requested endpoint is supported
+    /// - `additional_endpoints_supported`: whether each additional endpoint is supported and the client is allowed to access it, in the order they were received
+    /// - `endpoint_challenge`: an application-specific BSON document which the connected identity client must respond to
+    pub fn identity_server_handle_endpoint_request_received_with_additional_endpoints(
+        &mut self,
+        handle: HandshakeHandle,
+        client_allowed: bool,
+        endpoint_supported: bool,
+        additional_endpoints_supported: Vec<bool>,
+        endpoint_challenge: bson::document::Document,
+    ) -> Result<(), Error> {
+        if let Some(identity_server) = self.identity_servers.get_mut(&handle) {
+            Ok(
+                identity_server.handle_endpoint_request_received_with_additional_endpoints(
+                    client_allowed,
+                    endpoint_supported,
+                    additional_endpoints_supported,
+                    endpoint_challenge,
+                )?,
+            )
+        } else {
+            Err(Error::HandshakeHandleNotFound(handle))
+        }
+    }
+
+    /// Abort an in-progress incoming identity handshake, e.g. to turn away a misbehaving identity client without stopping the identity server. The connection to the client is closed and the failure is communicated through a [`ContextEvent::IdentityServerHandshakeFailed`] event with [`Error::HandshakeAborted`] as its reason returned from the next call to [`Context::update()`].
+    ///
+    /// # Parameters
+    /// - `handle`: the handle of the in-progress incoming identity handshake to abort
+    pub fn identity_server_abort_handshake(
+        &mut self,
+        handle: HandshakeHandle,
+    ) -> Result<(), Error> {
+        // kept-open connections have not begun a handshake yet
+        if self.kept_open_identity_servers.contains_key(&handle) {
+            return Err(Error::HandshakeHandleNotFound(handle));
+        }
+        if let Some(identity_server) = self.identity_servers.remove(&handle) {
+            if let Some(requested_endpoint) = identity_server.requested_endpoint() {
+                self.identity_server_stats
+                    .record_failure(requested_endpoint);
+            }
+            let stats = identity_server.stats();
+            // dropping the server closes its connection
+            drop(identity_server);
+            self.aborted_server_events
+                .push(ContextEvent::IdentityServerHandshakeFailed {
+                    handle,
+                    reason: Error::HandshakeAborted(handle),
+                    stats,
+                });
+            Ok(())
+        } else {
+            Err(Error::HandshakeHandleNotFound(handle))
+        }
+    }
+
+    // confirm that a received endpoint challenge response is valid
+
+    /// Handle an identity client's incoming endpoint challenge-response. Callers must determine whether the connected identity client's challenge-response is valid. The particulars of verifying the challenge-response is undefined and application-specific.
+    ///
+    /// # Parameters
+    /// - `handle`: the handle of the in-progress incoming identity handshake
+    /// - `challenge_response_valid`: whether the received challenge-response is valid
+    pub fn identity_server_handle_challenge_response_received(
+        &mut self,
+        handle: HandshakeHandle,
+        challenge_response_valid: bool,
+    ) -> Result<(), Error> {
+        if let Some(identity_server) = self.identity_servers.get_mut(&handle) {
+            Ok(identity_server.handle_challenge_response_received(challenge_response_valid)?)
+        } else {
+            Err(Error::HandshakeHandleNotFound(handle))
+        }
+    }
+
+    /// Initiate an endpoint handshake with an identity server. An endpoint client acquires the `endpoint_server_id` and `client_auth_key` by completing an identity handshake or through some other side-channnel. Handshake progression is communicated through [`ContextEvent`]s returned from the [`Context::update()`] method. If the outbound connection limit has been reached, the handshake is queued until a connection slot is available (see [`Context::set_max_outbound_connections()`]).
+    ///
+    /// # Parameters
+    /// - `endpoint_server_id`: the endpoint onion-service service-id of a remote peer
+    /// - `client_uath_key`: the x25519 private-key required to decrypt the endpoint server's onion-service descriptor
+    /// - `channel`: the ASCII-encoded requested channel
+    pub fn endpoint_client_begin_handshake(
+        &mut self,
+        endpoint_server_id: V3OnionServiceId,
+        client_auth_key: X25519PrivateKey,
+        channel: AsciiString,
+    ) -> Result<HandshakeHandle, Error> {
+        self.endpoint_client_begin_handshake_impl(
+            endpoint_server_id,
+            client_auth_key,
+            channel,
+            None,
+        )
+    }
+
+    /// Initiate an endpoint handshake with an endpoint server, as with [`Context::endpoint_client_begin_handshake()`], connecting to the endpoint server with the given circuit token (see [`Context::identity_client_begin_handshake_with_circuit_token()`]). If the token is released before the connection is made, the handshake fails.
+    ///
+    /// # Parameters
+    /// - `endpoint_server_id`: the endpoint onion-service service-id of a remote peer
+    /// - `client_uath_key`: the x25519 private-key required to decrypt the endpoint server's onion-service descriptor
+    /// - `channel`: the ASCII-encoded requested channel
+    /// - `circuit_token`: a circuit token from [`Context::generate_circuit_token()`]
+    pub fn endpoint_client_begin_handshake_with_circuit_token(
+        &mut self,
+        endpoint_server_id: V3OnionServiceId,
+        client_auth_key: X25519PrivateKey,
+        channel: AsciiString,
+        circuit_token: CircuitToken,
+    ) -> Result<HandshakeHandle, Error> {
+        self.endpoint_client_begin_handshake_impl(
+            endpoint_server_id,
+            client_auth_key,
+            channel,
+            Some(circuit_token),
+        )
+    }
+
+    /// Initiate an endpoint handshake with an endpoint server served on its identity server's onion-service (see [`EndpointConfig::shared`]). The identity server's onion-service descriptor is not encrypted, so no client authorization key is needed; the endpoint server authorizes the client through the endpoint handshake alone. Whether an endpoint server is shared is decided by the application which started it, so the client must learn this along with the endpoint server's service-id. Handshake progression is communicated as with [`Context::endpoint_client_begin_handshake()`].
+    ///
+    /// # Parameters
+    /// - `identity_server_id`: the long term identity onion-service service-id of the remote peer
+    /// - `endpoint_server_id`: the endpoint onion-service service-id granted by the remote peer's identity server
+    /// - `channel`: the ASCII-encoded requested channel
+    pub fn endpoint_client_begin_shared_handshake(
+        &mut self,
+        identity_server_id: V3OnionServiceId,
+        endpoint_server_id: V3OnionServiceId,
+        channel: AsciiString,
+    ) -> Result<HandshakeHandle, Error> {
+        ensure_channel_name_length(&channel)?;
+
+        if !self.bootstrap_complete && !self.queue_until_bootstrapped {
+            return Err(Error::TorNotConnected());
+        }
+
+        let handle = self.next_handshake_handle;
+        self.next_handshake_handle += 1;
+
+        if self.outbound_queue.is_empty() && self.outbound_connection_available() {
+            let endpoint_client = self.endpoint_client_open(
+                endpoint_server_id,
+                Some(identity_server_id),
+                channel,
+                None,
+            )?;
+            self.endpoint_clients.insert(handle, endpoint_client);
+        } else {
+            self.enqueue_handshake(QueuedHandshake {
+                handle,
+                priority: 0,
+                handshake: PendingHandshake::SharedEndpoint {
+                    identity_server_id,
+                    endpoint_server_id,
+                    channel,
+                },
+                reported_position: None,
+                awaiting_bootstrap: !self.bootstrap_complete,
+            });
+        }
+        Ok(handle)
+    }
+
+    fn endpoint_client_begin_handshake_impl(
+        &mut self,
+        endpoint_server_id: V3OnionServiceId,
+        client_auth_key: X25519PrivateKey,
+        channel: AsciiString,
+        circuit_token: Option<CircuitToken>,
+    ) -> Result<HandshakeHandle, Error> {
+        ensure_channel_name_length(&channel)?;
+
+        if !self.bootstrap_complete && !self.queue_until_bootstrapped {
+            return Err(Error::TorNotConnected());
+        }
+
+        let handshake_handle = self.next_handshake_handle;
+        self.next_handshake_handle += 1;
+
+        self.endpoint_client_start(
+            handshake_handle,
+            endpoint_server_id,
+            client_auth_key,
+            channel,
+            circuit_token,
+        )?;
+        Ok(handshake_handle)
+    }
+
+    // connect to an endpoint server now or once a connection slot is available
+    fn endpoint_client_start(
+        &mut self,
+        handle: HandshakeHandle,
+        endpoint_server_id: V3OnionServiceId,
+        client_auth_key: X25519PrivateKey,
+        channel: AsciiString,
+        circuit_token: Option<CircuitToken>,
+    ) -> Result<(), Error> {
+        if self.outbound_queue.is_empty() && self.outbound_connection_available() {
+            let endpoint_client = self.endpoint_client_connect(
+                endpoint_server_id,
+                client_auth_key,
+                channel,
+                circuit_token,
+            )?;
+            self.endpoint_clients.insert(handle, endpoint_client);
+        } else {
+            self.enqueue_handshake(QueuedHandshake {
+                handle,
+                priority: 0,
+                handshake: PendingHandshake::Endpoint {
+                    endpoint_server_id,
+                    client_auth_key,
+                    channel,
+                    circuit_token,
+                },
+                reported_position: None,
+                awaiting_bootstrap: !self.bootstrap_complete,
+            });
+        }
+        Ok(())
+    }
+
+    /// Abort an in-process outgoing endpoint handshake
+    ///
+    /// # Parameters
+    /// - `handle`: the handle of the in-progress outoing identity handshake to abort
+    pub fn endpoint_client_abort_handshake(
+        &mut self,
+        handle: HandshakeHandle,
+    ) -> Result<(), Error> {
+        if let Some(endpoint_client) = self.endpoint_clients.remove(&handle) {
+            self.endpoint_client_finished(&endpoint_client.server_service_id, false);
+            Ok(())
+        } else if let Some(index) = self.outbound_queue.iter().position(|queued| {
+            queued.handle == handle
+                && matches!(
+                    queued.handshake,
+                    PendingHandshake::Endpoint { .. } | PendingHandshake::SharedEndpoint { .. }
+                )
+        }) {
+            self.outbound_queue.remove(index);
+            Ok(())
+        } else {
+            Err(Error::HandshakeHandleNotFound(handle))
+        }
+    }
+
+    /// Remove the client authorization key for an endpoint server's onion-service descriptor from the tor provider. The result is communicated through a [`ContextEvent::ClientAuthRemoved`] or [`ContextEvent::ClientAuthRemoveFailed`] event returned from the [`Context::update()`] method.
+    ///
+    /// # Parameters
+    /// - `endpoint_service_id`: the onion-service service-id of the endpoint server
+    pub fn remove_client_auth(&mut self, endpoint_service_id: &V3OnionServiceId) {
+        let event = match self.tor_provider.remove_client_auth(endpoint_service_id) {
+            Ok(()) => {
+                self.client_auth_entries.remove(endpoint_service_id);
+                ContextEvent::ClientAuthRemoved {
+                    endpoint_service_id: endpoint_service_id.clone(),
+                }
+            }
+            Err(err) => ContextEvent::ClientAuthRemoveFailed {
+                endpoint_service_id: endpoint_service_id.clone(),
+                reason: err.into(),
+            },
+        };
+        self.client_auth_events.push(event);
+    }
+
+    /// Set how long this `Context` keeps the client authorization keys it adds to the tor provider when endpoint handshakes begin connecting. Keys removed by this policy are reported with [`ContextEvent::ClientAuthRemoved`] or [`ContextEvent::ClientAuthRemoveFailed`] events as with [`Context::remove_client_auth()`]. An endpoint handshake is finished once it completes, fails, is busy or is aborted with [`Context::endpoint_client_abort_handshake()`]. When any policy other than [`ClientAuthRetention::Retain`] is set, the keys listed by [`Context::client_auth_entries()`] are also removed when the `Context` is dropped, so they do not outlive it in a shared tor daemon. This setting only applies to handshakes which finish after it is changed.
+    ///
+    /// # Parameters
+    /// - `retention`: when keys are removed (the default is [`ClientAuthRetention::Retain`])
+    pub fn set_client_auth_retention(&mut self, retention: ClientAuthRetention) {
+        self.client_auth_retention = retention;
+    }
+
+    /// Set how long outgoing endpoint handshakes may take to connect to the endpoint server's onion-service, i.e. to fetch its descriptor and build a circuit to it. Handshakes which take longer fail with [`Error::EndpointConnectTimedOut`], either returned from the `begin_handshake()` function or reported through a [`ContextEvent::EndpointClientHandshakeFailed`] event for queued handshakes. Tor providers connect synchronously, so a slow connection attempt is not interrupted; it is closed once made. Connection failures other than timeouts are reported as tor provider errors. No timeout is set by default.
+    ///
+    /// This setting only applies to handshakes which connect after it is changed.
+    ///
+    /// # Parameters
+    /// - `timeout`: the maximum time to connect, or `None` for no limit
+    pub fn endpoint_client_set_connect_timeout(&mut self, timeout: Option<Duration>) {
+        self.endpoint_client_connect_timeout = timeout;
+    }
+
+    /// Set how long outgoing endpoint handshakes may take to complete once connected to the endpoint server. Unlike the endpoint timeout passed to [`Context::new()`], which bounds each step of the handshake, this bounds the handshake as a whole. Handshakes which take longer fail with a [`ContextEvent::EndpointClientHandshakeFailed`] event whose reason is [`Error::EndpointHandshakeTimedOut`]. No timeout is set by default.
+    ///
+    /// This setting only applies to handshakes which connect after it is changed.
+    ///
+    /// # Parameters
+    /// - `timeout`: the maximum time to complete the handshake once connected, or `None` for no limit
+    pub fn endpoint_client_set_handshake_timeout(&mut self, timeout: Option<Duration>) {
+        self.endpoint_client_handshake_timeout = timeout;
+    }
+
+    // remove the endpoint server's client authorization key if the retention
+    // policy requires it once one of its handshakes has finished
+    fn endpoint_client_finished(
+        &mut self,
+        endpoint_service_id: &V3OnionServiceId,
+        succeeded: bool,
+    ) {
+        let remove = match self.client_auth_retention {
+            ClientAuthRetention::Retain => false,
+            ClientAuthRetention::RemoveOnFailure => !succeeded,
+            ClientAuthRetention::RemoveOnCompletion => true,
+        };
+        if !remove || !self.client_auth_entries.contains_key(endpoint_service_id) {
+            return;
+        }
+
+        // the key is still needed by the endpoint server's other handshakes
+        let in_progress = self
+            .endpoint_clients
+            .values()
+            .any(|endpoint_client| endpoint_client.server_service_id == *endpoint_service_id)
+            || self.outbound_queue.iter().any(|queued| {
+                matches!(
+                    &queued.handshake,
+                    PendingHandshake::Endpoint { endpoint_server_id, .. }
+                        if endpoint_server_id == endpoint_service_id
+                )
+            });
+        if !in_progress {
+            self.remove_client_auth(endpoint_service_id);
+        }
+    }
+
+    /// Handle an [`EndpointRevocation`] received from an endpoint server, e.g. as the last line received over one of its channels. Any client authorization key for the endpoint server's onion-service descriptor is removed as with [`Context::remove_client_auth()`], and a [`ContextEvent::EndpointClientEndpointRevoked`] event is returned from the next [`Context::update()`]. An error is returned if the revocation was issued to another client or if [`Capabilities::ENDPOINT_REVOCATION`] is disabled with [`Context::set_capabilities()`].
+    ///
+    /// # Parameters
+    /// - `revocation`: the endpoint revocation, whose signature was verified when it was parsed with [`EndpointRevocation::from_string()`]
+    pub fn endpoint_client_handle_revocation(
+        &mut self,
+        revocation: &EndpointRevocation,
+    ) -> Result<(), Error> {
+        if !self
+            .capabilities
+            .contains(Capabilities::ENDPOINT_REVOCATION)
+        {
+            return Err(Error::InvalidArgument(
+                "endpoint revocations are disabled by set_capabilities()".to_string(),
+            ));
+        }
+        if revocation.client_service_id() != &self.identity_service_id {
+            return Err(Error::InvalidArgument(format!(
+                "endpoint revocation was issued to client {}",
+                revocation.client_service_id()
+            )));
+        }
+
+        let endpoint_service_id = revocation.endpoint_service_id();
+        if self.client_auth_entries.contains_key(endpoint_service_id) {
+            self.remove_client_auth(endpoint_service_id);
+        }
+        self.endpoint_revocations
+            .push((endpoint_service_id.clone(), revocation.revoked_at()));
+        Ok(())
+    }
+
+    /// Get the onion-service service-ids of the endpoint servers whose client authorization keys this `Context` has added to the tor provider and not since removed. Keys are added when an endpoint handshake begins connecting (see [`ContextEvent::ClientAuthAdded`]).
+    pub fn client_auth_entries(&self) -> Vec<V3OnionServiceId> {
+        self.client_auth_entries.keys().cloned().collect()
+    }
+
+    /// Start one of this `Context`'s endpoint servers. Publish status is communicated through [`ContextEvent`]s returned from the [`Context::update()`] method. The endpoint server is started without proof-of-work defenses; use [`Context::endpoint_servers_start()`] to enable them.
+    ///
+    /// # Parameters
+    /// - `endpoint_private_key`: the ed25519 private key used to start this endpoint server's onion-service
+    /// - `endpoint_name`: the ASCII-encoded endpoint name
+    /// - `client_identity`: the onion-service service-id of the client which will be connecting to this endpoint server
+    /// - `client_auth`: the x25519 public-key used to encrypt the endpoint server's onion-service descriptor
+    /// - `non_anonymous`: whether to start the endpoint server's onion-service as a non-anonymous single onion-service; requires the underlying tor daemon to be configured for single onion-services
+    pub fn endpoint_server_start(
+        &mut self,
+        endpoint_private_key: Ed25519PrivateKey,
+        endpoint_name: AsciiString,
+        client_identity: V3OnionServiceId,
+        client_auth: X25519PublicKey,
+        non_anonymous: bool,
+    ) -> Result<(), Error> {
+        let mut results = self.endpoint_servers_start(vec![EndpointConfig {
+            private_key: endpoint_private_key,
+            endpoint_name,
+            client_identity,
+            client_auth_keys: vec![client_auth],
+            non_anonymous,
+            pow_defenses: None,
+            shared: false,
+        }])?;
+        match results.pop() {
+            Some((_endpoint_service_id, result)) => result,
+            None => unreachable!("a result is returned for each endpoint server"),
+        }
+    }
+
+    /// Start one of this `Context`'s endpoint servers as with [`Context::endpoint_server_start()`], additionally tracking its publication: a [`ContextEvent::EndpointServerPublishCompleted`] event with the returned handle follows its [`ContextEvent::EndpointServerPublished`] event, or a [`ContextEvent::EndpointServerPublishTimedOut`] event is returned if it has not been published within `publish_timeout`. Stopping the endpoint server before either event discards its completion.
+    ///
+    /// # Parameters
+    /// - `endpoint_private_key`: the ed25519 private key used to start this endpoint server's onion-service
+    /// - `endpoint_name`: the ASCII-encoded endpoint name
+    /// - `client_identity`: the onion-service service-id of the client which will be connecting to this endpoint server
+    /// - `client_auth`: the x25519 public-key used to encrypt the endpoint server's onion-service descriptor
+    /// - `non_anonymous`: whether to start the endpoint server's onion-service as a non-anonymous single onion-service; requires the underlying tor daemon to be configured for single onion-services
+    /// - `publish_timeout`: how long to wait for the endpoint server to be published, or `None` to wait indefinitely
+    /// # Returns
+    /// A handle identifying this endpoint server's completion event.
+    pub fn endpoint_server_start_with_completion(
+        &mut self,
+        endpoint_private_key: Ed25519PrivateKey,
+        endpoint_name: AsciiString,
+        client_identity: V3OnionServiceId,
+        client_auth: X25519PublicKey,
+        non_anonymous: bool,
+        publish_timeout: Option<Duration>,
+    ) -> Result<PublishHandle, Error> {
+        let endpoint_service_id = V3OnionServiceId::from_private_key(&endpoint_private_key);
+        self.endpoint_server_start(
+            endpoint_private_key,
+            endpoint_name.clone(),
+            client_identity,
+            client_auth,
+            non_anonymous,
+        )?;
+
+        let handle = self.next_publish_handle;
+        self.next_publish_handle += 1;
+        self.pending_endpoint_publishes.insert(
+            endpoint_service_id,
+            PendingEndpointPublish {
+                handle,
+                endpoint_name,
+                started: self.clock.now(),
+                timeout: publish_timeout,
+            },
+        );
+        Ok(handle)
+    }
+
+    /// Start one of this `Context`'s endpoint servers on the identity server's onion-service rather than its own, as with [`Context::endpoint_servers_start()`] given an [`EndpointConfig`] whose `shared` member is `true`. Shared endpoint servers must first be enabled with [`Context::identity_server_set_shared_endpoints_enabled()`]. A [`ContextEvent::EndpointServerPublished`] event is returned once the identity server's onion-service has been published.
+    ///
+    /// Shared endpoint servers have no onion-service of their own, so their client authorization keys, concurrency limit and priority cannot be set.
+    ///
+    /// # Parameters
+    /// - `endpoint_private_key`: the ed25519 private key whose service-id identifies this endpoint server to its client
+    /// - `endpoint_name`: the ASCII-encoded endpoint name
+    /// - `client_identity`: the onion-service service-id of the client which will be connecting to this endpoint server
+    pub fn endpoint_server_start_shared(
+        &mut self,
+        endpoint_private_key: Ed25519PrivateKey,
+        endpoint_name: AsciiString,
+        client_identity: V3OnionServiceId,
+    ) -> Result<(), Error> {
+        let mut results = self.endpoint_servers_start(vec![EndpointConfig {
+            private_key: endpoint_private_key,
+            endpoint_name,
+            client_identity,
+            client_auth_keys: Default::default(),
+            non_anonymous: false,
+            pow_defenses: None,
+            shared: true,
+        }])?;
+        match results.pop() {
+            Some((_endpoint_service_id, result)) => result,
+            None => unreachable!("a result is returned for each endpoint server"),
+        }
+    }
+
+    /// Start several of this `Context`'s endpoint servers at once, e.g. when restoring an application's endpoint servers at startup. Each endpoint server is started as with [`Context::endpoint_server_start()`], but the tor provider may start their onion-services more efficiently than individually (see [`TorProvider::listeners()`]); the legacy c-tor daemon provider pipelines their `ADD_ONION` commands into a single round-trip. A failure to start one endpoint server does not prevent the others from starting. Publish status is communicated through [`ContextEvent`]s returned from the [`Context::update()`] method.
+    ///
+    /// # Parameters
+    /// - `configs`: the endpoint servers to start
+    /// # Returns
+    /// The onion-service service-id of each endpoint server and whether it was started, in the order of `configs`.
+    pub fn endpoint_servers_start(
+        &mut self,
+        configs: Vec<EndpointConfig>,
+    ) -> Result<EndpointServerResults, Error> {
+        if !self.bootstrap_complete {
+            return Err(Error::TorNotConnected());
+        }
+
+        let mut results: Vec<(V3OnionServiceId, Result<(), Error>)> =
+            Vec::with_capacity(configs.len());
+        // the index of each endpoint server's result and its configuration
+        let mut starting: Vec<(usize, EndpointConfig)> = Default::default();
+        for config in configs {
+            let endpoint_service_id = V3OnionServiceId::from_private_key(&config.private_key);
+            let result = if let Err(err) = self.ensure_no_listener_conflict(&endpoint_service_id) {
+                Err(err)
+            } else if let Err(err) = ensure_endpoint_name_length(&config.endpoint_name) {
+                Err(err)
+            } else if config.shared && !self.identity_server_shared_endpoints_enabled {
+                Err(Error::InvalidArgument(
+                    "shared endpoint servers are disabled; see identity_server_set_shared_endpoints_enabled()".to_string(),
+                ))
+            } else if !config.shared && config.client_auth_keys.is_empty() {
+                Err(Error::InvalidArgument(
+                    "at least one client authorization key is required".to_string(),
+                ))
+            } else if starting
+                .iter()
+                .any(|(index, _)| results[*index].0 == endpoint_service_id)
+            {
+                Err(Error::ListenerConflict(
+                    endpoint_service_id.clone(),
+                    self.endpoint_virt_port(&endpoint_service_id),
+                    "endpoint server".to_string(),
+                ))
+            } else {
+                starting.push((results.len(), config));
+                Ok(())
+            };
+            results.push((endpoint_service_id, result));
+        }
+
+        // shared endpoint servers have no onion-service of their own
+        let listener_configs: Vec<ListenerConfig> = starting
+            .iter()
+            .filter(|(_, config)| !config.shared)
+            .map(|(index, config)| ListenerConfig {
+                private_key: config.private_key.clone(),
+                virt_port: self.endpoint_virt_port(&results[*index].0),
+                authorised_clients: Some(config.client_auth_keys.clone()),
+                non_anonymous: config.non_anonymous,
+                pow_defenses: config.pow_defenses.clone(),
+            })
+            .collect();
+        let mut listeners = self.tor_provider.listeners(&listener_configs).into_iter();
+
+        for (index, config) in starting {
+            let endpoint_service_id = results[index].0.clone();
+            let listener = if config.shared {
+                Ok(None)
+            } else {
+                match listeners.next() {
+                    Some(listener) => listener.map(Some),
+                    None => unreachable!("a listener is returned for each listener config"),
+                }
+            };
+            results[index].1 = match listener {
+                Ok(listener) => {
+                    self.endpoint_server_accept_from(endpoint_service_id, config, listener)
+                }
+                Err(err) => {
+                    let endpoint_port = self.endpoint_virt_port(&endpoint_service_id);
+                    Err(Error::ListenerStartFailed(
+                        endpoint_service_id,
+                        endpoint_port,
+                        err,
+                    ))
+                }
+            };
+        }
+        Ok(results)
+    }
+
+    // begin accepting connections from a started endpoint server's onion-service,
+    // or from the shared endpoint listener if it has none
+    fn endpoint_server_accept_from(
+        &mut self,
+        endpoint_service_id: V3OnionServiceId,
+        config: EndpointConfig,
+        endpoint_listener: Option<OnionListener>,
+    ) -> Result<(), Error> {
+        if let Some(endpoint_listener) = endpoint_listener.as_ref() {
+            endpoint_listener.set_nonblocking(true)?;
+        }
+
+        self.endpoint_listeners.insert(
+            endpoint_service_id.clone(),
+            (
+                config.endpoint_name,
+                config.client_identity,
+                endpoint_listener,
+                false,
+            ),
+        );
+        self.endpoint_server_configs.insert(
+            endpoint_service_id,
+            EndpointServerConfig {
+                private_key: config.private_key,
+                client_auth_keys: config.client_auth_keys,
+                non_anonymous: config.non_anonymous,
+                pow_defenses: config.pow_defenses,
+            },
+        );
+        Ok(())
+    }
+
+    /// Limit the number of incoming handshakes an endpoint server runs at once, protecting resource-constrained devices from a flood of connections. Connections accepted while the limit is reached wait in a pending-connection queue and begin their handshake from [`Context::update()`] as in-progress handshakes finish. When the queue is full a connection is shed according to `drop_policy`, and pending connections which wait longer than the endpoint timeout are also shed. Shed connections are closed and reported with [`ContextEvent::EndpointServerConnectionShed`] events. Handshakes continued over upgraded identity connections count towards the limit but are never queued or shed.
+    ///
+    /// # Parameters
+    /// - `endpoint_identity`: the onion-service service-id of the endpoint server to limit
+    /// - `max_concurrent_handshakes`: the maximum number of concurrent incoming handshakes, or `None` for no limit (the default)
+    /// - `pending_queue_capacity`: the maximum number of connections waiting for a handshake slot; may be 0 to shed every connection accepted while the limit is reached
+    /// - `drop_policy`: which connection to shed when the pending-connection queue is full
+    pub fn endpoint_server_set_concurrency_limit(
+        &mut self,
+        endpoint_identity: V3OnionServiceId,
+        max_concurrent_handshakes: Option<usize>,
+        pending_queue_capacity: usize,
+        drop_policy: PendingConnectionDropPolicy,
+    ) -> Result<(), Error> {
+        if max_concurrent_handshakes == Some(0) {
+            return Err(Error::InvalidArgument(
+                "max_concurrent_handshakes must be greater than 0".to_string(),
+            ));
+        }
+
+        self.ensure_endpoint_server_has_listener(&endpoint_identity)?;
+
+        // without a limit pending connections begin their handshake on the next update()
+        let max_concurrent_handshakes = max_concurrent_handshakes.unwrap_or(usize::MAX);
+        let limit = self
+            .endpoint_concurrency_limits
+            .entry(endpoint_identity)
+            .or_insert_with(|| EndpointConcurrencyLimit {
+                max_concurrent_handshakes,
+                pending_queue_capacity,
+                drop_policy,
+                pending_connections: Default::default(),
+            });
+        limit.max_concurrent_handshakes = max_concurrent_handshakes;
+        limit.pending_queue_capacity = pending_queue_capacity;
+        limit.drop_policy = drop_policy;
+        Ok(())
+    }
+
+    /// Set the priority with which an endpoint server's incoming handshakes are updated, e.g. so that handshakes from an application's most important contacts are not delayed by a flood of connections to its other endpoint servers. Incoming handshakes accepted after the priority is set begin with it; see [`Context::set_handshake_priority()`] and [`Context::set_handshake_update_budget()`]. The priority is kept if the endpoint server is restarted by [`Context::endpoint_servers_reconfigure()`], and discarded when it is stopped.
+    ///
+    /// # Parameters
+    /// - `endpoint_identity`: the onion-service service-id of the endpoint server
+    /// - `priority`: the priority of the endpoint server's incoming handshakes (the default is 0)
+    pub fn endpoint_server_set_priority(
+        &mut self,
+        endpoint_identity: V3OnionServiceId,
+        priority: i32,
+    ) -> Result<(), Error> {
+        self.ensure_endpoint_server_has_listener(&endpoint_identity)?;
+
+        if priority == 0 {
+            self.endpoint_priorities.remove(&endpoint_identity);
+        } else {
+            self.endpoint_priorities.insert(endpoint_identity, priority);
+        }
+        Ok(())
+    }
+
+    /// Register a channel-name pattern which endpoint servers use to accept channel requests without consulting the application. Incoming channel requests whose name matches any registered pattern are accepted automatically and no [`ContextEvent::EndpointServerChannelRequestReceived`] event is emitted for them; all other requests are still passed to the application. Patterns apply to all of this `Context`'s endpoint servers and only to handshakes which begin after the pattern is registered.
+    ///
+    /// Patterns are glob-style: `*` matches any (possibly empty) sequence of characters, `?` matches exactly one character, and all other characters match themselves. For example, `file-transfer/*` matches `file-transfer/photos`.
+    ///
+    /// # Parameters
+    /// - `pattern`: a non-empty ASCII-encoded channel-name pattern
+    pub fn endpoint_server_add_channel_pattern(&mut self, pattern: String) -> Result<(), Error> {
+        let pattern = match ChannelPattern::new(pattern) {
+            Ok(pattern) => pattern,
+            Err(err) => return Err(Error::InvalidArgument(err.to_string())),
+        };
+        if !self.endpoint_channel_patterns.contains(&pattern) {
+            self.endpoint_channel_patterns.push(pattern);
+        }
+        Ok(())
+    }
+
+    /// Remove a channel-name pattern previously registered with [`Context::endpoint_server_add_channel_pattern()`].
+    ///
+    /// # Parameters
+    /// - `pattern`: the pattern to remove
+    /// # Returns
+    /// `true` if the pattern was registered
+    pub fn endpoint_server_remove_channel_pattern(&mut self, pattern: &str) -> bool {
+        let count = self.endpoint_channel_patterns.len();
+        self.endpoint_channel_patterns
+            .retain(|registered| registered.as_str() != pattern);
+        count != self.endpoint_channel_patterns.len()
+    }
+
+    /// Set whether this `Context`'s endpoint servers accept handshakes from peers running the legacy protocol revision, whose `begin_handshake()` call does not include the client's identity. Such clients are assumed to be the endpoint server's allowed client, and their client proof is verified against that client's identity as usual. Handshakes from peers running the current protocol revision are always accepted. This setting only applies to handshakes which begin after it is changed, and is intended for use during a migration window while older peers upgrade.
+    ///
+    /// # Parameters
+    /// - `allowed`: whether legacy endpoint handshakes are accepted (the default is `false`)
+    pub fn endpoint_server_set_legacy_handshakes_allowed(&mut self, allowed: bool) {
+        self.endpoint_legacy_handshakes_allowed = allowed;
+    }
+
+    /// Set whether this `Context`'s endpoint servers are too busy to open new channels. While busy, channel requests are answered with an advisory error telling the endpoint client how long to wait before retrying, which the client returns as a [`ContextEvent::EndpointClientHandshakeBusy`] event. No [`ContextEvent::EndpointServerChannelRequestReceived`] event is returned for such requests and the handshake ends with a [`ContextEvent::EndpointServerHandshakeBusy`] event. This setting only applies to handshakes which begin after it is changed.
+    ///
+    /// # Parameters
+    /// - `retry_after`: how long clients should wait before retrying, with sub-second precision discarded, or `None` to handle channel requests as usual (the default)
+    pub fn endpoint_server_set_busy(&mut self, retry_after: Option<Duration>) {
+        self.endpoint_server_busy_retry_after = retry_after;
+    }
+
+    /// Pause or resume one of this `Context`'s endpoint servers, e.g. while the application's user does not wish to be disturbed. A paused endpoint server keeps its onion-service published, so it is reachable again as soon as it is resumed rather than once its descriptor has been republished, but answers channel requests as if busy (see [`Context::endpoint_server_set_busy()`]): the client returns a [`ContextEvent::EndpointClientHandshakeBusy`] event and the handshake ends with a [`ContextEvent::EndpointServerHandshakeBusy`] event. A paused endpoint server's `retry_after` takes precedence over the one set with [`Context::endpoint_server_set_busy()`]. This setting only applies to handshakes which begin after it is changed. The pause is kept if the endpoint server is restarted by [`Context::endpoint_servers_reconfigure()`], and discarded when it is stopped.
+    ///
+    /// Shared endpoint servers have no onion-service of their own, so they cannot be paused.
+    ///
+    /// # Parameters
+    /// - `endpoint_identity`: the onion-service service-id of the endpoint server
+    /// - `retry_after`: how long clients should wait before retrying, with sub-second precision discarded, or `None` to resume handling channel requests as usual
+    pub fn endpoint_server_set_paused(
+        &mut self,
+        endpoint_identity: V3OnionServiceId,
+        retry_after: Option<Duration>,
+    ) -> Result<(), Error> {
+        self.ensure_endpoint_server_has_listener(&endpoint_identity)?;
+
+        match retry_after {
+            Some(retry_after) => {
+                self.endpoint_server_paused
+                    .insert(endpoint_identity, retry_after);
+            }
+            None => {
+                self.endpoint_server_paused.remove(&endpoint_identity);
+            }
+        }
+        Ok(())
+    }
+
+    /// Get how long the clients of a paused endpoint server are told to wait before retrying, see [`Context::endpoint_server_set_paused()`].
+    ///
+    /// # Parameters
+    /// - `endpoint_identity`: the onion-service service-id of the endpoint server
+    /// # Returns
+    /// The endpoint server's `retry_after`, or `None` if it is not paused
+    pub fn endpoint_server_paused(&self, endpoint_identity: &V3OnionServiceId) -> Option<Duration> {
+        self.endpoint_server_paused.get(endpoint_identity).copied()
+    }
+
+    /// Set per-client limits on the channels opened through this `Context`'s endpoint servers. Once an endpoint client has proven its identity, a channel request which would exceed its quota is refused with an error describing the exceeded limit; the client's handshake fails with [`Error::EndpointChannelQuotaExceeded`] and the handshake ends with a [`ContextEvent::EndpointServerChannelQuotaExceeded`] event. Channels accepted while a quota is set count as open until reported closed with [`Context::endpoint_server_channel_closed()`].
+    ///
+    /// Changing the quota keeps counting the channels already open and opened recently against the new limits, while removing it forgets them.
+    ///
+    /// # Parameters
+    /// - `quota`: the limits to apply to each client, or `None` to accept channel requests without limit (the default)
+    pub fn endpoint_server_set_channel_quota(&mut self, quota: Option<ChannelQuota>) {
+        match (quota, self.endpoint_server_channel_quota.as_ref()) {
+            (Some(quota), Some(tracker)) => tracker
+                .lock()
+                .unwrap_or_else(std::sync::PoisonError::into_inner)
+                .set_quota(quota),
+            (Some(quota), None) => {
+                self.endpoint_server_channel_quota =
+                    Some(Arc::new(Mutex::new(ChannelQuotaTracker::new(quota))));
+            }
+            (None, _) => {
+                self.endpoint_server_channel_quota = None;
+                self.endpoint_server_channel_quota_permits.clear();
+            }
+        }
+    }
+
+    /// Report that a channel accepted by one of this `Context`'s endpoint servers has been closed, so it no longer counts against its client's channel quota, see [`Context::endpoint_server_set_channel_quota()`]. Has no effect on channels which were not counted against a quota.
+    ///
+    /// # Parameters
+    /// - `handle`: the handle of the endpoint handshake which opened the channel
+    pub fn endpoint_server_channel_closed(&mut self, handle: HandshakeHandle) {
+        self.endpoint_server_channel_quota_permits.remove(&handle);
+    }
+
+    /// Handle an endpoint client's incoming channel request. Callers must determine whether the requested channel is supported by this `Context`. The particulars of making this determination is undefined and application-specific.
+    ///
+    /// # Parameters
+    /// - `handle`: the handle of the in-progress incoming endpoint handshake
+    /// - `channel_supported`: whether the requested channel is supported
+    pub fn endpoint_server_handle_channel_request_received(
+        &mut self,
+        handle: HandshakeHandle,
+        channel_supported: bool,
+    ) -> Result<(), Error> {
+        if let Some(endpoint_server) = self.endpoint_servers.get_mut(&handle) {
+            Ok(endpoint_server.handle_channel_request_received(channel_supported)?)
+        } else {
+            Err(Error::HandshakeHandleNotFound(handle))
+        }
+    }
+
+    /// Abort an in-progress incoming endpoint handshake, e.g. to turn away a misbehaving endpoint client without stopping its endpoint server. The connection to the client is closed and the failure is communicated through a [`ContextEvent::EndpointServerHandshakeFailed`] event with [`Error::HandshakeAborted`] as its reason returned from the next call to [`Context::update()`].
     ///
     /// # Parameters
-    /// - `handle`: the handle of the in-progress incoming identity handshake
-    /// - `client_allowed`: whether the connected identity client is allowed to access the requested endpoint
-    /// - `endpoint_supported`: whether the requested endpoint is supported
-    /// - `endpoint_challenge`: an application-specific BSON document which the connected identity client must respond to
-    pub fn identity_server_handle_endpoint_request_received(
+    /// - `handle`: the handle of the in-progress incoming endpoint handshake to abort
+    pub fn endpoint_server_abort_handshake(
         &mut self,
         handle: HandshakeHandle,
-        client_allowed: bool,
-        endpoint_supported: bool,
-        endpoint_challenge: bson::document::Document,
     ) -> Result<(), Error> {
-        if let Some(identity_server) = self.identity_servers.get_mut(&handle) {
-            Ok(identity_server.handle_endpoint_request_received(
-                client_allowed,
-                endpoint_supported,
-                endpoint_challenge,
-            )?)
+        if let Some(endpoint_server) = self.endpoint_servers.remove(&handle) {
+            let stats = endpoint_server.stats();
+            // dropping the server closes its connection
+            drop(endpoint_server);
+            self.aborted_server_events
+                .push(ContextEvent::EndpointServerHandshakeFailed {
+                    handle,
+                    reason: Error::HandshakeAborted(handle),
+                    stats,
+                });
+            Ok(())
         } else {
             Err(Error::HandshakeHandleNotFound(handle))
         }
     }
 
-    // confirm that a received endpoint challenge response is valid
-
-    /// Handle an identity client's incoming endpoint challenge-response. Callers must determine whether the connected identity client's challenge-response is valid. The particulars of verifying the challenge-response is undefined and application-specific.
+    /// Stop one of this `Context`'s endpoint servers and ends any of its in-progress incoming endpoint handshakes.
     ///
     /// # Parameters
-    /// - `handle`: the handle of the in-progress incoming identity handshake
-    /// - `challenge_response_valid`: whether the received challenge-response is valid
-    pub fn identity_server_handle_challenge_response_received(
+    /// - `endpoint_identity`: the onion-service service-id of the enpdoint server to stop
+    pub fn endpoint_server_stop(
         &mut self,
-        handle: HandshakeHandle,
-        challenge_response_valid: bool,
+        endpoint_identity: V3OnionServiceId,
     ) -> Result<(), Error> {
-        if let Some(identity_server) = self.identity_servers.get_mut(&handle) {
-            Ok(identity_server.handle_challenge_response_received(challenge_response_valid)?)
+        if !self.bootstrap_complete {
+            return Err(Error::TorNotConnected());
+        }
+
+        if self.endpoint_server_remove(&endpoint_identity) {
+            self.endpoint_server_forget(&endpoint_identity);
+            Ok(())
         } else {
-            Err(Error::HandshakeHandleNotFound(handle))
+            Err(Error::InvalidArgument(format!(
+                "endpoint server with service id {} not found",
+                endpoint_identity
+            )))
         }
     }
 
-    /// Initiate an endpoint handshake with an identity server. An endpoint client acquires the `endpoint_server_id` and `client_auth_key` by completing an identity handshake or through some other side-channnel. Handshake progression is communicated through [`ContextEvent`]s returned from the [`Context::update()`] method.
+    /// Revoke the access granted to an endpoint server's client, e.g. after the user removes the client from their contacts. The endpoint server is stopped as with [`Context::endpoint_server_stop()`], which removes its onion-service along with the client's authorization key. An [`EndpointRevocation`] followed by a newline is then written to each of the given channels as a best-effort notification to the client, and the channels are closed; channels whose socket buffer is full are closed without the notification. Channels whose handshake did not negotiate [`Capabilities::ENDPOINT_REVOCATION`] should be closed by the application instead, as the client may not expect a revocation.
     ///
     /// # Parameters
-    /// - `endpoint_server_id`: the endpoint onion-service service-id of a remote peer
-    /// - `client_uath_key`: the x25519 private-key required to decrypt the endpoint server's onion-service descriptor
-    /// - `channel`: the ASCII-encoded requested channel
-    pub fn endpoint_client_begin_handshake(
+    /// - `endpoint_identity`: the onion-service service-id of the endpoint server to revoke
+    /// - `channels`: the client's live channels to the endpoint server, e.g. streams returned in [`ContextEvent::EndpointServerHandshakeCompleted`] events; may be empty
+    /// # Returns
+    /// The number of channels the revocation was written to.
+    pub fn endpoint_server_revoke(
         &mut self,
-        endpoint_server_id: V3OnionServiceId,
-        client_auth_key: X25519PrivateKey,
-        channel: String,
-    ) -> Result<HandshakeHandle, Error> {
-        let channel = match AsciiString::new(channel) {
-            Ok(channel) => channel,
-            Err(_) => {
-                return Err(Error::InvalidArgument(
-                    "channel must be an ASCII string".to_string(),
-                ))
-            }
-        };
-
+        endpoint_identity: V3OnionServiceId,
+        channels: Vec<TcpStream>,
+    ) -> Result<usize, Error> {
         if !self.bootstrap_complete {
             return Err(Error::TorNotConnected());
         }
 
-        self.tor_provider
-            .add_client_auth(&endpoint_server_id, &client_auth_key)?;
-        let stream: TcpStream = self
-            .tor_provider
-            .connect(
-                (endpoint_server_id.clone(), self.endpoint_port).into(),
-                None,
-            )?
-            .into();
-        stream.set_nonblocking(true)?;
+        let revocation = match (
+            self.endpoint_server_configs.get(&endpoint_identity),
+            self.endpoint_listeners.get(&endpoint_identity),
+        ) {
+            (Some(config), Some((_endpoint_name, allowed_client, _listener, _published))) => {
+                EndpointRevocation::new(&config.private_key, allowed_client.clone())
+            }
+            _ => {
+                return Err(Error::InvalidArgument(format!(
+                    "endpoint server with service id {} not found",
+                    endpoint_identity
+                )))
+            }
+        };
+        self.endpoint_server_stop(endpoint_identity)?;
 
-        let mut session = Session::new(stream);
-        session.set_max_wait_time(self.endpoint_timeout);
-        session.set_max_message_size(DEFAULT_ENDPOINT_MAX_MESSAGE_SIZE)?;
+        let revocation = format!("{}\n", revocation);
+        let mut notified_channels = 0usize;
+        for mut channel in channels {
+            if channel.write_all(revocation.as_bytes()).is_ok() {
+                notified_channels += 1;
+            }
+            let _ = channel.shutdown(Shutdown::Both);
+        }
+        Ok(notified_channels)
+    }
 
-        let endpoint_client = EndpointClient::new(
-            session,
-            endpoint_server_id,
-            channel,
-            self.identity_private_key.clone(),
-        );
+    // fail unless the endpoint server is running with its own onion-service
+    fn ensure_endpoint_server_has_listener(
+        &self,
+        endpoint_identity: &V3OnionServiceId,
+    ) -> Result<(), Error> {
+        match self.endpoint_listeners.get(endpoint_identity) {
+            Some((_, _, Some(_listener), _)) => Ok(()),
+            Some((_, _, None, _)) => Err(Error::InvalidArgument(format!(
+                "endpoint server with service id {} is served on the identity server's onion-service",
+                endpoint_identity
+            ))),
+            None => Err(Error::InvalidArgument(format!(
+                "endpoint server with service id {} not found",
+                endpoint_identity
+            ))),
+        }
+    }
 
-        let handshake_handle = self.next_handshake_handle;
-        self.next_handshake_handle += 1;
-        self.endpoint_clients
-            .insert(handshake_handle, endpoint_client);
-        Ok(handshake_handle)
+    // stop accepting connections from an endpoint server's onion-service; its
+    // concurrency limit is kept; returns whether the endpoint server was running
+    fn endpoint_server_remove(&mut self, endpoint_identity: &V3OnionServiceId) -> bool {
+        if let Some(limit) = self.endpoint_concurrency_limits.get_mut(endpoint_identity) {
+            limit.pending_connections.clear();
+        }
+        self.endpoint_server_configs.remove(endpoint_identity);
+        self.endpoint_listeners.remove(endpoint_identity).is_some()
     }
 
-    /// Abort an in-process outgoing endpoint handshake
+    // discard an endpoint server's concurrency limit, priority and pause once
+    // it has been stopped for good; closes any pending connections
+    fn endpoint_server_forget(&mut self, endpoint_identity: &V3OnionServiceId) {
+        self.endpoint_concurrency_limits.remove(endpoint_identity);
+        self.endpoint_priorities.remove(endpoint_identity);
+        self.endpoint_server_paused.remove(endpoint_identity);
+        self.pending_endpoint_publishes.remove(endpoint_identity);
+    }
+
+    /// Stop all of this `Context`'s endpoint servers as with [`Context::endpoint_server_stop()`]. The tor provider may stop their onion-services more efficiently than individually; the legacy c-tor daemon provider pipelines their `DEL_ONION` commands during the next [`Context::update()`].
     ///
-    /// # Parameters
-    /// - `handle`: the handle of the in-progress outoing identity handshake to abort
-    pub fn endpoint_client_abort_handshake(
-        &mut self,
-        handle: HandshakeHandle,
-    ) -> Result<(), Error> {
-        if let Some(_endpoint_client) = self.endpoint_clients.remove(&handle) {
-            Ok(())
-        } else {
-            Err(Error::HandshakeHandleNotFound(handle))
+    /// # Returns
+    /// The onion-service service-ids of the stopped endpoint servers.
+    pub fn endpoint_servers_stop_all(&mut self) -> Result<Vec<V3OnionServiceId>, Error> {
+        if !self.bootstrap_complete {
+            return Err(Error::TorNotConnected());
         }
+
+        let endpoint_identities: Vec<V3OnionServiceId> =
+            self.endpoint_listeners.keys().cloned().collect();
+        for endpoint_identity in endpoint_identities.iter() {
+            self.endpoint_server_remove(endpoint_identity);
+            self.endpoint_server_forget(endpoint_identity);
+        }
+        Ok(endpoint_identities)
     }
 
-    /// Start one of this `Context`'s endpoint servers. Publish status is communicated through [`ContextEvent`]s returned from the [`Context::update()`] method.
+    /// Reconfigure this `Context`'s endpoint servers to match `configs`, e.g. after an application's contact list has been edited. Endpoint servers whose onion-service is not in `configs` are stopped. Endpoint servers whose name, client identity, non-anonymous setting, proof-of-work defenses or `shared` setting differ from their config are restarted, keeping any concurrency limit (see [`Context::endpoint_server_set_concurrency_limit()`]) and priority (see [`Context::endpoint_server_set_priority()`]). Endpoint servers whose client authorization keys differ have their keys replaced as with [`Context::endpoint_server_set_client_auth_keys()`]. The remaining configs are started as with [`Context::endpoint_servers_start()`]. Unchanged endpoint servers keep running along with their in-progress handshakes.
+    ///
+    /// `configs` is validated as a whole before any endpoint server is changed, so an invalid configuration leaves all endpoint servers untouched; however failures of the tor provider to stop or start individual onion-services are reported per endpoint server and do not roll back the other changes.
     ///
     /// # Parameters
-    /// - `endpoint_private_key`: the ed25519 private key used to start this endpoint server's onion-service
-    /// - `endpoint_name`: the ASCII-encoded endpoint name
-    /// - `client_identity`: the onion-service service-id of the client which will be connecting to this endpoint server
-    /// - `client_auth`: the x25519 public-key used to encrypt the endpoint server's onion-service descriptor
-    pub fn endpoint_server_start(
+    /// - `configs`: the endpoint servers which should be running; their onion-service keys must be distinct, they must differ from the identity server's and each which is not shared must have at least one client authorization key
+    /// # Returns
+    /// The onion-service service-id of each stopped, restarted, updated or started endpoint server and whether the change succeeded. Unchanged endpoint servers are not included.
+    pub fn endpoint_servers_reconfigure(
         &mut self,
-        endpoint_private_key: Ed25519PrivateKey,
-        endpoint_name: String,
-        client_identity: V3OnionServiceId,
-        client_auth: X25519PublicKey,
-    ) -> Result<(), Error> {
+        configs: Vec<EndpointConfig>,
+    ) -> Result<EndpointServerResults, Error> {
         if !self.bootstrap_complete {
             return Err(Error::TorNotConnected());
         }
 
-        let endpoint_public_key = Ed25519PublicKey::from_private_key(&endpoint_private_key);
-        let endpoint_service_id = V3OnionServiceId::from_public_key(&endpoint_public_key);
-
-        if endpoint_service_id == self.identity_service_id {
-            return Err(Error::InvalidArgument(
-                "endpoint server must be different from identity server".to_string(),
-            ));
+        let mut desired: HashSet<V3OnionServiceId> = Default::default();
+        for config in configs.iter() {
+            let endpoint_service_id = V3OnionServiceId::from_private_key(&config.private_key);
+            if endpoint_service_id == self.identity_service_id {
+                return Err(Error::ListenerConflict(
+                    endpoint_service_id.clone(),
+                    self.identity_virt_port(&endpoint_service_id),
+                    "identity server".to_string(),
+                ));
+            }
+            if !config.shared && config.client_auth_keys.is_empty() {
+                return Err(Error::InvalidArgument(format!(
+                    "endpoint server with service id {} has no client authorization keys",
+                    endpoint_service_id
+                )));
+            }
+            if !desired.insert(endpoint_service_id.clone()) {
+                return Err(Error::InvalidArgument(format!(
+                    "endpoint server with service id {} is configured more than once",
+                    endpoint_service_id
+                )));
+            }
         }
 
-        if self.endpoint_listeners.contains_key(&endpoint_service_id) {
-            return Err(Error::IncorrectUsage(
-                "endpoint server already started".to_string(),
-            ));
+        let mut results: Vec<(V3OnionServiceId, Result<(), Error>)> = Default::default();
+
+        // stop the endpoint servers which are no longer wanted
+        let running: Vec<V3OnionServiceId> = self.endpoint_listeners.keys().cloned().collect();
+        for endpoint_identity in running {
+            if !desired.contains(&endpoint_identity) {
+                self.endpoint_server_remove(&endpoint_identity);
+                self.endpoint_server_forget(&endpoint_identity);
+                results.push((endpoint_identity, Ok(())));
+            }
         }
 
-        let endpoint_listener = self.tor_provider.listener(
-            &endpoint_private_key,
-            self.endpoint_port,
-            Some(&[client_auth]),
-        )?;
-        endpoint_listener.set_nonblocking(true)?;
+        // update or restart the endpoint servers which remain, in the order of configs
+        let mut starting: Vec<EndpointConfig> = Default::default();
+        // endpoint servers restarted with a new configuration
+        let mut restarting: Vec<V3OnionServiceId> = Default::default();
+        for config in configs {
+            let endpoint_service_id = V3OnionServiceId::from_private_key(&config.private_key);
+            let running = match (
+                self.endpoint_listeners.get(&endpoint_service_id),
+                self.endpoint_server_configs.get(&endpoint_service_id),
+            ) {
+                (Some((endpoint_name, client_identity, listener, _published)), Some(running)) => {
+                    Some((
+                        *endpoint_name == config.endpoint_name
+                            && *client_identity == config.client_identity
+                            && listener.is_none() == config.shared
+                            && (config.shared
+                                || (running.non_anonymous == config.non_anonymous
+                                    && running.pow_defenses == config.pow_defenses)),
+                        // shared endpoint servers have no client authorization keys
+                        config.shared || running.client_auth_keys == config.client_auth_keys,
+                    ))
+                }
+                _ => None,
+            };
+            match running {
+                // unchanged
+                Some((true, true)) => (),
+                Some((true, false)) => {
+                    let result = self.endpoint_server_set_client_auth_keys(
+                        endpoint_service_id.clone(),
+                        config.client_auth_keys,
+                    );
+                    results.push((endpoint_service_id, result));
+                }
+                Some((false, _)) => {
+                    self.endpoint_server_remove(&endpoint_service_id);
+                    restarting.push(endpoint_service_id);
+                    starting.push(config);
+                }
+                None => starting.push(config),
+            }
+        }
 
-        self.endpoint_listeners.insert(
-            endpoint_service_id,
-            (endpoint_name, client_identity, endpoint_listener, false),
-        );
-        Ok(())
+        for (endpoint_service_id, result) in self.endpoint_servers_start(starting)? {
+            // endpoint servers which could not be restarted are stopped
+            if result.is_err() && restarting.contains(&endpoint_service_id) {
+                self.endpoint_server_forget(&endpoint_service_id);
+            }
+            results.push((endpoint_service_id, result));
+        }
+        Ok(results)
     }
 
-    /// Handle an endpoint client's incoming channel request. Callers must determine whether the requested channel is supported by this `Context`. The particulars of making this determination is undefined and application-specific.
+    /// Replace the client authorization keys used to encrypt a running endpoint server's onion-service descriptor without stopping the endpoint server, e.g. to rotate the key after its client completes a new identity handshake. Several keys may be provided so that a previous key remains usable until the client has switched to the new one.
+    ///
+    /// The updated descriptor must be republished before clients can connect with a new key, and depending on the tor provider the endpoint server may be briefly unreachable while this happens. A [`ContextEvent::EndpointServerPublished`] event is emitted again once the updated descriptor has been published. An error is returned if the tor provider cannot update a running onion-service's client authorization keys.
     ///
     /// # Parameters
-    /// - `handle`: the handle of the in-progress incoming endpoint handshake
-    /// - `channel_supported`: whether the requested channel is supported
-    pub fn endpoint_server_handle_channel_request_received(
+    /// - `endpoint_identity`: the onion-service service-id of the endpoint server to update
+    /// - `client_auth_keys`: the x25519 public-keys used to encrypt the endpoint server's onion-service descriptor; must not be empty
+    pub fn endpoint_server_set_client_auth_keys(
         &mut self,
-        handle: HandshakeHandle,
-        channel_supported: bool,
+        endpoint_identity: V3OnionServiceId,
+        client_auth_keys: Vec<X25519PublicKey>,
     ) -> Result<(), Error> {
-        if let Some(endpoint_server) = self.endpoint_servers.get_mut(&handle) {
-            Ok(endpoint_server.handle_channel_request_received(channel_supported)?)
-        } else {
-            Err(Error::HandshakeHandleNotFound(handle))
+        if !self.bootstrap_complete {
+            return Err(Error::TorNotConnected());
+        }
+
+        if client_auth_keys.is_empty() {
+            return Err(Error::InvalidArgument(
+                "at least one client authorization key is required".to_string(),
+            ));
+        }
+
+        self.ensure_endpoint_server_has_listener(&endpoint_identity)?;
+        let published = match self.endpoint_listeners.get_mut(&endpoint_identity) {
+            Some((_, _, _, published)) => published,
+            None => unreachable!("endpoint server has a listener"),
+        };
+
+        self.tor_provider
+            .set_authorised_clients(&endpoint_identity, &client_auth_keys)?;
+        // report the publication of the updated descriptor
+        *published = false;
+        if let Some(config) = self.endpoint_server_configs.get_mut(&endpoint_identity) {
+            config.client_auth_keys = client_auth_keys;
         }
+        Ok(())
     }
 
-    /// Stop one of this `Context`'s endpoint servers and ends any of its in-progress incoming endpoint handshakes.
+    /// Force the tor provider to build and publish a fresh descriptor for a running endpoint server without stopping it, e.g. after the client authorization keys of its client changed out-of-band. A [`ContextEvent::EndpointServerDescriptorRefreshed`] event is returned once the refreshed descriptor has been published, or a [`ContextEvent::EndpointServerPublished`] event if the endpoint server had not yet been published. Shared endpoint servers have no descriptor of their own, see [`Context::identity_server_refresh_descriptor()`]. An error is returned if the tor provider cannot refresh descriptors on demand.
     ///
     /// # Parameters
-    /// - `endpoint_identity`: the onion-service service-id of the enpdoint server to stop
-    pub fn endpoint_server_stop(
+    /// - `endpoint_identity`: the onion-service service-id of the endpoint server to refresh
+    pub fn endpoint_server_refresh_descriptor(
         &mut self,
         endpoint_identity: V3OnionServiceId,
     ) -> Result<(), Error> {
-        if !self.bootstrap_complete {
-            return Err(Error::TorNotConnected());
-        }
-
-        if let Some(_listener) = self.endpoint_listeners.remove(&endpoint_identity) {
-            Ok(())
-        } else {
-            Err(Error::InvalidArgument(format!(
-                "endpoint server with service id {} not found",
-                endpoint_identity
-            )))
-        }
+        self.ensure_endpoint_server_has_listener(&endpoint_identity)?;
+        self.tor_provider.refresh_descriptor(&endpoint_identity)?;
+        Ok(())
     }
 
     fn identity_server_handle_accept(
@@ -730,6 +4094,9 @@ impl Context {
         identity_timeout: Duration,
         identity_max_message_size: i32,
         identity_private_key: &Ed25519PrivateKey,
+        identity_client_filter: Option<&Arc<ClientFilter>>,
+        endpoint_upgrade_allowed: bool,
+        challenge_response_deadline: Option<Duration>,
     ) -> Result<Option<IdentityServer>, Error> {
         if let Some(stream) = identity_listener.accept()? {
             let stream: TcpStream = stream.into();
@@ -741,7 +4108,14 @@ impl Context {
             server_rpc.set_max_wait_time(identity_timeout);
             server_rpc.set_max_message_size(identity_max_message_size)?;
             let service_id = V3OnionServiceId::from_private_key(identity_private_key);
-            let identity_server = IdentityServer::new(server_rpc, service_id);
+            let mut identity_server = IdentityServer::new(
+                server_rpc,
+                service_id,
+                identity_client_filter.cloned(),
+                endpoint_upgrade_allowed,
+            );
+            identity_server.set_challenge_response_deadline(challenge_response_deadline);
+            identity_server.set_state_deadline(Some(identity_timeout));
 
             Ok(Some(identity_server))
         } else {
@@ -751,32 +4125,41 @@ impl Context {
 
     fn endpoint_server_handle_accept(
         endpoint_listener: &OnionListener,
-        endpoint_timeout: Duration,
-        client_service_id: &V3OnionServiceId,
-        endpoint_service_id: &V3OnionServiceId,
-    ) -> Result<Option<EndpointServer>, Error> {
+    ) -> Result<Option<TcpStream>, Error> {
         if let Some(stream) = endpoint_listener.accept()? {
             let stream: TcpStream = stream.into();
             if stream.set_nonblocking(true).is_err() {
                 return Ok(None);
             }
-
-            let mut server_rpc = Session::new(stream);
-            server_rpc.set_max_wait_time(endpoint_timeout);
-            server_rpc.set_max_message_size(DEFAULT_ENDPOINT_MAX_MESSAGE_SIZE)?;
-
-            let endpoint_server = EndpointServer::new(
-                server_rpc,
-                client_service_id.clone(),
-                endpoint_service_id.clone(),
-            );
-
-            Ok(Some(endpoint_server))
+            Ok(Some(stream))
         } else {
             Ok(None)
         }
     }
 
+    fn endpoint_server_begin_handshake(
+        stream: TcpStream,
+        endpoint_timeout: Duration,
+        client_service_id: &V3OnionServiceId,
+        endpoint_service_id: &V3OnionServiceId,
+        channel_patterns: &[ChannelPattern],
+        legacy_handshakes_allowed: bool,
+    ) -> Result<EndpointServer, Error> {
+        let mut server_rpc = Session::new(stream);
+        server_rpc.set_max_wait_time(endpoint_timeout);
+        server_rpc.set_max_message_size(DEFAULT_ENDPOINT_MAX_MESSAGE_SIZE)?;
+
+        let mut endpoint_server = EndpointServer::new(
+            server_rpc,
+            client_service_id.clone(),
+            endpoint_service_id.clone(),
+            channel_patterns.to_vec(),
+            legacy_handshakes_allowed,
+        );
+        endpoint_server.set_state_deadline(Some(endpoint_timeout));
+        Ok(endpoint_server)
+    }
+
     /// A direct pass-through to the underlying [`TorProvider`]'s [`TorProvider::connect()`] method.
     pub fn connect(
         &mut self,
@@ -796,20 +4179,228 @@ impl Context {
         self.tor_provider.release_token(circuit_token)
     }
 
-    /// This function updates the `Context`'s underlying [`TorProvider`], handles new handshakes requests, and updates in-progress handshakes. This function needs to be regularly called to process the returned [`ContextEvent`]s.
+    /// Latency histograms of the `begin_handshake()` and `send_response()` round-trips and onion-service connections made by this `Context`'s outgoing identity and endpoint handshakes.
+    #[cfg(feature = "timing-histograms")]
+    pub fn timing_histograms(&mut self) -> &mut TimingHistograms {
+        &mut self.timings.histograms
+    }
+
+    /// Per-endpoint counts of the endpoint requests, grants, rejections and failures of this `Context`'s incoming identity handshakes.
+    pub fn identity_server_stats(&self) -> &IdentityServerStats {
+        &self.identity_server_stats
+    }
+
+    /// Discard the statistics returned by [`Context::identity_server_stats()`].
+    pub fn identity_server_reset_stats(&mut self) {
+        self.identity_server_stats.reset();
+    }
+
+    /// The current state of this `Context` rendered in the Prometheus text exposition format, for applications to serve from their own metrics endpoint. This includes the number of in-progress handshakes, the statistics returned by [`Context::identity_server_stats()`] and, when the `timing-histograms` feature is enabled, summaries of the latencies returned by [`Context::timing_histograms()`].
+    #[cfg(feature = "prometheus")]
+    pub fn metrics_prometheus(&self) -> String {
+        let mut writer: PrometheusWriter = Default::default();
+
+        let name = "gosling_handshakes_in_progress";
+        writer.family(
+            name,
+            "gauge",
+            "In-progress identity and endpoint handshakes",
+        );
+        for (kind, count) in [
+            ("identity_client", self.identity_clients.len()),
+            ("identity_server", self.identity_servers.len()),
+            ("endpoint_client", self.endpoint_clients.len()),
+            ("endpoint_server", self.endpoint_servers.len()),
+        ] {
+            writer.sample(name, &[("kind", kind)], count);
+        }
+        writer.gauge(
+            "gosling_outbound_queue_length",
+            "Outgoing handshakes waiting for a connection slot",
+            self.outbound_queue.len(),
+        );
+        writer.gauge(
+            "gosling_identity_server_published",
+            "Whether the identity server's onion-service is published",
+            u8::from(self.identity_server_published),
+        );
+        writer.gauge(
+            "gosling_endpoint_servers_published",
+            "Endpoint servers whose onion-service is published",
+            self.endpoint_listeners
+                .values()
+                .filter(|(_, _, _, published)| *published)
+                .count(),
+        );
+        writer.gauge(
+            "gosling_client_auth_keys",
+            "Client authorization keys added to the tor provider",
+            self.client_auth_entries.len(),
+        );
+        writer.identity_server_stats(&self.identity_server_stats);
+        #[cfg(feature = "timing-histograms")]
+        writer.timing_histograms(&self.timings.histograms);
+
+        writer.finish()
+    }
+
+    /// This function updates the `Context`'s underlying [`TorProvider`], handles new handshakes requests, and updates in-progress handshakes. This function needs to be regularly called to process the returned [`ContextEvent`]s, or to dispatch them to the sink registered with [`Context::set_events_sink()`]. Rather than sleeping a fixed interval between calls, applications may sleep for the duration returned by [`Context::next_deadline()`].
     pub fn update(&mut self) -> Result<VecDeque<ContextEvent>, Error> {
-        // events to return
         let mut events: VecDeque<ContextEvent> = Default::default();
+        self.update_into(&mut events)?;
+        Ok(events)
+    }
+
+    /// Update this `Context` as with [`Context::update()`], appending the returned [`ContextEvent`]s to `events` rather than returning a newly allocated queue. Applications which call this function in a loop and drain `events` between calls avoid allocating a new queue on every update. Nothing is appended when an events sink is registered with [`Context::set_events_sink()`].
+    ///
+    /// # Parameters
+    /// - `events`: the queue to append this update's events to
+    pub fn update_into(&mut self, events: &mut VecDeque<ContextEvent>) -> Result<(), Error> {
+        // collect this update's events separately so that the event queue
+        // capacity only applies to them
+        let mut update_events = std::mem::take(&mut self.update_events);
+        update_events.clear();
+        // events held back by the last update come first; after an overflow
+        // error they are returned before any more are collected so the queue
+        // drains
+        let overflowed = !self.pending_events.is_empty()
+            && self.event_queue_limit.overflow_policy == EventQueueOverflowPolicy::Error;
+        update_events.append(&mut self.pending_events);
+        if !overflowed {
+            if let Err(err) = self.collect_update_events(&mut update_events) {
+                self.pending_events = update_events;
+                return Err(err);
+            }
+        }
+        let mut update_events = self.apply_event_queue_capacity(update_events)?;
+
+        // pass the events to the registered sink, if any; the sink is taken
+        // for the duration so it may be given the context
+        match self.events_sink.take() {
+            Some(mut events_sink) => {
+                for event in update_events.drain(..) {
+                    events_sink.on_event(self, event);
+                }
+                if self.events_sink.is_none() {
+                    self.events_sink = Some(events_sink);
+                }
+            }
+            // hand over the whole buffer and keep the caller's empty one instead
+            None if events.is_empty() => std::mem::swap(events, &mut update_events),
+            None => events.extend(update_events.drain(..)),
+        }
+        self.update_events = update_events;
+        Ok(())
+    }
+
+    /// How long the application may wait before calling [`Context::update()`] again without delaying this `Context`'s work. The hint reflects the `Context`'s current state, so it should be read after the latest call to [`Context::update()`] and to any function which begins or continues a handshake.
+    ///
+    /// # Returns
+    /// - `Some(Duration::ZERO)` if the next update has work to do immediately, e.g. events it has yet to return or handshakes left over by the update budget (see [`Context::set_handshake_update_budget()`])
+    /// - a short interval while handshakes are waiting on their peers
+    /// - a longer interval while only the `Context`'s listeners are waiting for connections or its [`TorProvider`] is bootstrapping
+    /// - `None` if the `Context` is idle; [`Context::update()`] need only be called again after calling another `Context` function, although [`TorProvider`] logs and status events are only returned by updates
+    pub fn next_deadline(&self) -> Option<Duration> {
+        let handshake_count = self.identity_clients.len()
+            + self.identity_servers.len()
+            + self.endpoint_clients.len()
+            + self.endpoint_servers.len();
+
+        // events already waiting to be returned and handshakes which the
+        // last update did not get to
+        if !self.pending_events.is_empty()
+            || !self.suppressed_identity_handshakes.is_empty()
+            || !self.client_auth_events.is_empty()
+            || !self.aborted_server_events.is_empty()
+            || !self.endpoint_revocations.is_empty()
+            || self
+                .handshake_update_budget
+                .is_some_and(|budget| handshake_count > budget)
+            || (!self.outbound_queue.is_empty() && self.outbound_connection_available())
+        {
+            return Some(Duration::ZERO);
+        }
+
+        // in-progress handshakes (and the queued handshakes waiting for them
+        // to finish) progress as soon as their peers' messages arrive
+        if handshake_count > 0
+            || !self.outbound_queue.is_empty()
+            || !self.upgraded_identity_sessions.is_empty()
+        {
+            return Some(BUSY_UPDATE_INTERVAL);
+        }
+
+        // connectivity checks finish when the provider reports their fetch,
+        // or time out
+        if let Some(connectivity_check) = self.connectivity_check.as_ref() {
+            return Some(
+                connectivity_check
+                    .remaining(self.clock.now())
+                    .min(IDLE_UPDATE_INTERVAL),
+            );
+        }
+
+        // endpoint servers waiting to be published time out
+        let now = self.clock.now();
+        if let Some(remaining) = self
+            .pending_endpoint_publishes
+            .values()
+            .filter_map(|pending| pending.remaining(now))
+            .min()
+        {
+            return Some(remaining.min(IDLE_UPDATE_INTERVAL));
+        }
+
+        // listeners wait for new connections, tor for bootstrap progress and
+        // onion-service publication, and kept-open connections to go unused
+        if self.identity_listener.is_some()
+            || !self.kept_open_identity_client_sessions.is_empty()
+            || self.shared_endpoint_listener.is_some()
+            || !self.endpoint_listeners.is_empty()
+            || (self.bootstrap_requested && !self.bootstrap_complete)
+            || self.tor_provider_migration.is_some()
+        {
+            return Some(IDLE_UPDATE_INTERVAL);
+        }
+
+        None
+    }
+
+    // update the tor provider, listeners and in-progress handshakes, appending
+    // the resulting events to events
+    fn collect_update_events(&mut self, events: &mut VecDeque<ContextEvent>) -> Result<(), Error> {
+        // forget expired bans
+        if let Some(ban_list) = self.ban_list.as_ref() {
+            if !ban_list.remove_expired().is_empty() {
+                if let Err(err) = ban_list.save() {
+                    tracing::warn!(%err, "failed to save bans");
+                }
+            }
+        }
+
+        // switch tor providers once in-progress handshakes have finished; no
+        // new connections are accepted until then
+        self.tor_provider_migration_begin(events)?;
+        let accepting = self.tor_provider_migration.is_none();
 
         // first handle new identity connections
-        if let Some(identity_listener) = &self.identity_listener {
+        if let Some(identity_listener) = self.identity_listener.as_ref().filter(|_| accepting) {
             match Self::identity_server_handle_accept(
                 identity_listener,
                 self.identity_timeout,
                 self.identity_max_message_size,
                 &self.identity_private_key,
+                self.identity_client_filter.as_ref(),
+                self.identity_server_endpoint_upgrade_allowed,
+                self.identity_server_challenge_response_deadline,
             ) {
-                Ok(Some(identity_server)) => {
+                Ok(Some(mut identity_server)) => {
+                    identity_server.set_clock(self.clock.clone());
+                    identity_server.set_read_idle_timeout(self.handshake_read_idle_timeout);
+                    identity_server.set_capabilities(self.identity_capabilities());
+                    identity_server.set_replay_cache(Some(self.handshake_replay_cache.clone()));
+                    identity_server.set_ban_list(self.ban_list.clone());
+                    identity_server.set_endpoint_key_derivation(self.endpoint_key_derivation_key());
                     let handle = self.next_handshake_handle;
                     self.next_handshake_handle += 1;
                     self.identity_servers.insert(handle, identity_server);
@@ -822,27 +4413,192 @@ impl Context {
             }
         }
 
+        // next handle new connections to shared endpoint servers, which are
+        // only known once the client names its endpoint
+        if let Some(shared_endpoint_listener) =
+            self.shared_endpoint_listener.as_ref().filter(|_| accepting)
+        {
+            match Self::endpoint_server_handle_accept(shared_endpoint_listener) {
+                Ok(Some(stream)) => {
+                    let shared_endpoints: Vec<(V3OnionServiceId, V3OnionServiceId)> = self
+                        .endpoint_listeners
+                        .iter()
+                        .filter(|(_, (_, _, listener, _))| listener.is_none())
+                        .map(|(endpoint_service_id, (_, allowed_client, _, _))| {
+                            (endpoint_service_id.clone(), allowed_client.clone())
+                        })
+                        .collect();
+                    let mut endpoint_server = Self::endpoint_server_begin_handshake(
+                        stream,
+                        self.endpoint_timeout,
+                        &self.identity_service_id,
+                        &self.identity_service_id,
+                        &self.endpoint_channel_patterns,
+                        self.endpoint_legacy_handshakes_allowed,
+                    )?;
+                    endpoint_server.set_shared_endpoints(Some(shared_endpoints));
+                    endpoint_server.set_busy_retry_after(self.endpoint_server_busy_retry_after);
+                    endpoint_server.set_clock(self.clock.clone());
+                    endpoint_server.set_read_idle_timeout(self.handshake_read_idle_timeout);
+                    endpoint_server.set_capabilities(self.capabilities);
+                    endpoint_server.set_replay_cache(Some(self.handshake_replay_cache.clone()));
+                    endpoint_server.set_channel_quota(self.endpoint_server_channel_quota.clone());
+                    endpoint_server.set_ban_list(self.ban_list.clone());
+                    endpoint_server.set_rpc_channels(self.rpc_channels.clone());
+                    let handle = self.next_handshake_handle;
+                    self.next_handshake_handle += 1;
+                    self.endpoint_servers.insert(handle, endpoint_server);
+                    events.push_back(ContextEvent::EndpointServerHandshakeStarted { handle });
+                }
+                Ok(None) => {}
+                // shared endpoint listener failed, remove it
+                Err(_) => self.shared_endpoint_listener = None,
+            }
+        }
+
         // next handle new endpoint connections
-        self.endpoint_listeners.retain(
-            |endpoint_service_id, (_endpoint_name, allowed_client, listener, _published)| -> bool {
-                match Self::endpoint_server_handle_accept(
-                    listener,
+        let mut failed_endpoint_listeners: Vec<V3OnionServiceId> = Default::default();
+        for (endpoint_service_id, (_endpoint_name, allowed_client, listener, _published)) in
+            &self.endpoint_listeners
+        {
+            if !accepting {
+                break;
+            }
+            // shared endpoint servers are accepted from the shared endpoint listener
+            let listener = match listener {
+                Some(listener) => listener,
+                None => continue,
+            };
+            let stream = match Self::endpoint_server_handle_accept(listener) {
+                Ok(stream) => stream,
+                // endpoint listener failed, remove it
+                // TODO: signal caller endpoint listener is down
+                Err(_) => {
+                    failed_endpoint_listeners.push(endpoint_service_id.clone());
+                    continue;
+                }
+            };
+
+            // queue the connection if the endpoint server's concurrency limit is reached
+            let limit = self
+                .endpoint_concurrency_limits
+                .get_mut(endpoint_service_id);
+            let streams: Vec<TcpStream> = match limit {
+                Some(limit) => {
+                    let handshakes = self
+                        .endpoint_servers
+                        .values()
+                        .filter(|endpoint_server| {
+                            &endpoint_server.server_identity == endpoint_service_id
+                        })
+                        .count();
+                    let (streams, shed_count) =
+                        limit.admit(stream, handshakes, self.endpoint_timeout, self.clock.now());
+                    for _ in 0..shed_count {
+                        events.push_back(ContextEvent::EndpointServerConnectionShed {
+                            endpoint_service_id: endpoint_service_id.clone(),
+                        });
+                    }
+                    streams
+                }
+                None => stream.into_iter().collect(),
+            };
+
+            for stream in streams {
+                let mut endpoint_server = Self::endpoint_server_begin_handshake(
+                    stream,
                     self.endpoint_timeout,
                     allowed_client,
                     endpoint_service_id,
-                ) {
-                    Ok(Some(endpoint_server)) => {
-                        let handle = self.next_handshake_handle;
-                        self.next_handshake_handle += 1;
-                        self.endpoint_servers.insert(handle, endpoint_server);
-                        events.push_back(ContextEvent::EndpointServerHandshakeStarted { handle });
-                        true
-                    }
-                    Ok(None) => true,
-                    // endpoint listener failed, remove it
-                    // TODO: signal caller endpoint listener is down
-                    Err(_) => false,
+                    &self.endpoint_channel_patterns,
+                    self.endpoint_legacy_handshakes_allowed,
+                )?;
+                endpoint_server.set_busy_retry_after(
+                    self.endpoint_server_paused
+                        .get(endpoint_service_id)
+                        .copied()
+                        .or(self.endpoint_server_busy_retry_after),
+                );
+                endpoint_server.set_clock(self.clock.clone());
+                endpoint_server.set_read_idle_timeout(self.handshake_read_idle_timeout);
+                endpoint_server.set_capabilities(self.capabilities);
+                endpoint_server.set_replay_cache(Some(self.handshake_replay_cache.clone()));
+                endpoint_server.set_channel_quota(self.endpoint_server_channel_quota.clone());
+                endpoint_server.set_ban_list(self.ban_list.clone());
+                endpoint_server.set_rpc_channels(self.rpc_channels.clone());
+                let handle = self.next_handshake_handle;
+                self.next_handshake_handle += 1;
+                self.endpoint_servers.insert(handle, endpoint_server);
+                if let Some(priority) = self.endpoint_priorities.get(endpoint_service_id) {
+                    self.handshake_priorities.insert(handle, *priority);
+                }
+                events.push_back(ContextEvent::EndpointServerHandshakeStarted { handle });
+            }
+        }
+        for endpoint_service_id in failed_endpoint_listeners {
+            self.endpoint_listeners.remove(&endpoint_service_id);
+            self.endpoint_server_configs.remove(&endpoint_service_id);
+            self.endpoint_server_forget(&endpoint_service_id);
+        }
+
+        // next continue endpoint handshakes over upgraded identity connections
+        // once their endpoint server has been started
+        for (endpoint_service_id, (session, timestamp)) in
+            std::mem::take(&mut self.upgraded_identity_sessions)
+        {
+            if let Some((_endpoint_name, allowed_client, _listener, _published)) =
+                self.endpoint_listeners.get(&endpoint_service_id)
+            {
+                let busy_retry_after = self
+                    .endpoint_server_paused
+                    .get(&endpoint_service_id)
+                    .copied()
+                    .or(self.endpoint_server_busy_retry_after);
+                let priority = self.endpoint_priorities.get(&endpoint_service_id).copied();
+                let mut endpoint_server = EndpointServer::new(
+                    session,
+                    allowed_client.clone(),
+                    endpoint_service_id,
+                    self.endpoint_channel_patterns.clone(),
+                    self.endpoint_legacy_handshakes_allowed,
+                );
+                endpoint_server.set_busy_retry_after(busy_retry_after);
+                endpoint_server.set_state_deadline(Some(self.endpoint_timeout));
+                endpoint_server.set_clock(self.clock.clone());
+                endpoint_server.set_read_idle_timeout(self.handshake_read_idle_timeout);
+                endpoint_server.set_capabilities(self.capabilities);
+                endpoint_server.set_replay_cache(Some(self.handshake_replay_cache.clone()));
+                endpoint_server.set_channel_quota(self.endpoint_server_channel_quota.clone());
+                endpoint_server.set_ban_list(self.ban_list.clone());
+                endpoint_server.set_rpc_channels(self.rpc_channels.clone());
+                let handle = self.next_handshake_handle;
+                self.next_handshake_handle += 1;
+                self.endpoint_servers.insert(handle, endpoint_server);
+                if let Some(priority) = priority {
+                    self.handshake_priorities.insert(handle, priority);
+                }
+                events.push_back(ContextEvent::EndpointServerHandshakeStarted { handle });
+            } else if self.clock.now().saturating_duration_since(timestamp) < self.endpoint_timeout
+            {
+                self.upgraded_identity_sessions
+                    .insert(endpoint_service_id, (session, timestamp));
+            }
+            // otherwise the endpoint server was not started in time and the connection is closed
+        }
+
+        // close identity connections kept open for follow-up handshakes once
+        // they have gone unused for too long
+        let now = self.clock.now();
+        self.kept_open_identity_client_sessions.retain(
+            |identity_service_id, (_session, deadline, handle)| -> bool {
+                if now < *deadline {
+                    return true;
                 }
+                events.push_back(ContextEvent::IdentityClientSessionClosed {
+                    handle: *handle,
+                    identity_service_id: identity_service_id.clone(),
+                });
+                false
             },
         );
 
@@ -855,6 +4611,7 @@ impl Context {
         // the response) and a failure to read async events which is either again a parsing
         // bug on our end or a malformed/buggy tor daemon which we also cannot recover
         // from.
+        let mut dropped_lines = 0usize;
         for event in self.tor_provider.update()?.drain(..) {
             match event {
                 TorEvent::BootstrapStatus {
@@ -862,18 +4619,40 @@ impl Context {
                     tag,
                     summary,
                 } => {
-                    events.push_back(ContextEvent::TorBootstrapStatusReceived {
-                        progress,
-                        tag,
-                        summary,
-                    });
+                    self.event_queue_limit.push_lossy(
+                        events,
+                        ContextEvent::TorBootstrapStatusReceived {
+                            progress,
+                            tag,
+                            summary,
+                        },
+                    );
                 }
                 TorEvent::BootstrapComplete => {
                     events.push_back(ContextEvent::TorBootstrapCompleted);
                     self.bootstrap_complete = true;
+                    self.tor_provider_migration_complete(events)?;
                 }
                 TorEvent::LogReceived { line } => {
-                    events.push_back(ContextEvent::TorLogReceived { line });
+                    self.tor_log_received(line, events, &mut dropped_lines);
+                }
+                TorEvent::Log {
+                    severity,
+                    timestamp,
+                    subsystem,
+                    message,
+                } => {
+                    // reassemble the line in tor's own log format
+                    let mut line: String = Default::default();
+                    if let Some(timestamp) = timestamp {
+                        line.push_str(&format!("{timestamp} "));
+                    }
+                    line.push_str(&format!("[{severity}] "));
+                    if let Some(subsystem) = subsystem {
+                        line.push_str(&format!("{{{subsystem}}} "));
+                    }
+                    line.push_str(&message);
+                    self.tor_log_received(line, events, &mut dropped_lines);
                 }
                 TorEvent::OnionServicePublished { service_id } => {
                     if service_id == self.identity_service_id {
@@ -894,14 +4673,341 @@ impl Context {
                         }
                     }
                 }
+                TorEvent::OnionServiceDescriptorRefreshed { service_id } => {
+                    // a refresh completing before the first publication is
+                    // reported as the publication
+                    if service_id == self.identity_service_id {
+                        if self.identity_server_published {
+                            events.push_back(ContextEvent::IdentityServerDescriptorRefreshed);
+                        } else if self.identity_listener.is_some() {
+                            events.push_back(ContextEvent::IdentityServerPublished);
+                            self.identity_server_published = true;
+                        }
+                    } else if let Some((endpoint_name, _, _, published)) =
+                        self.endpoint_listeners.get_mut(&service_id)
+                    {
+                        if *published {
+                            events.push_back(ContextEvent::EndpointServerDescriptorRefreshed {
+                                endpoint_service_id: service_id,
+                                endpoint_name: endpoint_name.clone(),
+                            });
+                        } else {
+                            events.push_back(ContextEvent::EndpointServerPublished {
+                                endpoint_service_id: service_id,
+                                endpoint_name: endpoint_name.clone(),
+                            });
+                            *published = true;
+                        }
+                    }
+                }
+                TorEvent::OnionServiceDescriptorFetched {
+                    service_id,
+                    hs_dir,
+                    succeeded,
+                    reason,
+                } => {
+                    if service_id == self.identity_service_id {
+                        if let Some(connectivity_check) = self.connectivity_check.take() {
+                            let report = connectivity_check.finish(
+                                self.clock.now(),
+                                Some(descriptor_fetch_outcome(succeeded, reason)),
+                                hs_dir,
+                            );
+                            events.push_back(ContextEvent::ConnectivityChecked { report });
+                        }
+                    }
+                }
+                TorEvent::CircuitStatusChanged {
+                    circuit_id,
+                    status,
+                    path,
+                    purpose,
+                    reason,
+                } => {
+                    if let Some(connectivity_check) = self.connectivity_check.as_mut() {
+                        match status {
+                            CircuitStatus::Built => connectivity_check.circuit_built(),
+                            CircuitStatus::Failed => connectivity_check.circuit_failed(),
+                            _ => (),
+                        }
+                    }
+                    if self.tor_event_verbosity == TorEventVerbosity::Verbose {
+                        self.event_queue_limit.push_lossy(
+                            events,
+                            ContextEvent::TorCircuitStatusChanged {
+                                circuit_id,
+                                status,
+                                path,
+                                purpose,
+                                reason,
+                            },
+                        );
+                    }
+                }
+                TorEvent::StreamStatusChanged {
+                    stream_id,
+                    status,
+                    circuit_id,
+                    target,
+                    reason,
+                } => {
+                    if self.tor_event_verbosity == TorEventVerbosity::Verbose {
+                        self.event_queue_limit.push_lossy(
+                            events,
+                            ContextEvent::TorStreamStatusChanged {
+                                stream_id,
+                                status,
+                                circuit_id,
+                                target,
+                                reason,
+                            },
+                        );
+                    }
+                }
+                TorEvent::OnionServiceDescriptorUploadStatus {
+                    service_id,
+                    hs_dir,
+                    succeeded,
+                    reason,
+                    uploads_succeeded,
+                    uploads_failed,
+                } => {
+                    if self.tor_event_verbosity == TorEventVerbosity::Verbose {
+                        self.event_queue_limit.push_lossy(
+                            events,
+                            ContextEvent::TorOnionServiceDescriptorUploadStatus {
+                                service_id,
+                                hs_dir,
+                                succeeded,
+                                reason,
+                                uploads_succeeded,
+                                uploads_failed,
+                            },
+                        );
+                    }
+                }
+                TorEvent::SocksPolicyViolated { violation } => {
+                    events.push_back(ContextEvent::TorSocksPolicyViolated { violation });
+                }
+            }
+        }
+        if dropped_lines > 0 {
+            events.push_back(ContextEvent::TorLogLinesDropped { dropped_lines });
+        }
+
+        // finish connectivity checks which timed out or had nothing to fetch
+        let now = self.clock.now();
+        if self
+            .connectivity_check
+            .as_ref()
+            .is_some_and(|connectivity_check| connectivity_check.remaining(now).is_zero())
+        {
+            if let Some(connectivity_check) = self.connectivity_check.take() {
+                let report = connectivity_check.finish(now, None, None);
+                events.push_back(ContextEvent::ConnectivityChecked { report });
+            }
+        }
+
+        // shared endpoint servers are published along with the identity server
+        if self.identity_server_published && self.shared_endpoint_listener.is_some() {
+            for (endpoint_service_id, (endpoint_name, _, listener, published)) in
+                self.endpoint_listeners.iter_mut()
+            {
+                if listener.is_none() && !*published {
+                    events.push_back(ContextEvent::EndpointServerPublished {
+                        endpoint_service_id: endpoint_service_id.clone(),
+                        endpoint_name: endpoint_name.clone(),
+                    });
+                    *published = true;
+                }
+            }
+        }
+
+        // complete the endpoint servers waiting to be published
+        let endpoint_listeners = &self.endpoint_listeners;
+        finish_endpoint_publishes(
+            &mut self.pending_endpoint_publishes,
+            |endpoint_service_id| {
+                endpoint_listeners
+                    .get(endpoint_service_id)
+                    .map(|(_, _, _, published)| *published)
+            },
+            now,
+            events,
+        );
+
+        // start queued outgoing handshakes as connection slots become available
+        while !self.outbound_queue.is_empty() && self.outbound_connection_available() {
+            // loop condition guarantees the queue is non-empty
+            let queued = self.outbound_queue.pop_front().unwrap();
+            let handle = queued.handle;
+            // started handshakes keep their queue priority
+            if queued.priority != 0 {
+                self.handshake_priorities.insert(handle, queued.priority);
+            }
+            if queued.awaiting_bootstrap {
+                events.push_back(ContextEvent::OutboundHandshakeStarted { handle });
+            }
+            match queued.handshake {
+                PendingHandshake::Identity {
+                    identity_server_id,
+                    endpoint,
+                    ignore_cached_failure,
+                    endpoint_upgrade_channel,
+                    additional_endpoints,
+                    contact_request,
+                    circuit_token,
+                } => {
+                    // the server may have failed to connect while this handshake was queued
+                    let retry_after = if ignore_cached_failure {
+                        None
+                    } else {
+                        self.identity_server_retry_after(&identity_server_id)
+                    };
+                    if let Some(retry_after) = retry_after {
+                        self.suppressed_identity_handshakes.push((
+                            handle,
+                            identity_server_id,
+                            retry_after,
+                        ));
+                        continue;
+                    }
+                    match self.identity_client_connect(
+                        identity_server_id,
+                        endpoint,
+                        endpoint_upgrade_channel.is_some(),
+                        additional_endpoints,
+                        contact_request,
+                        circuit_token,
+                    ) {
+                        Ok(identity_client) => {
+                            self.identity_clients.insert(handle, identity_client);
+                            if let Some(channel) = endpoint_upgrade_channel {
+                                self.endpoint_upgrade_channels
+                                    .insert(handle, (channel, circuit_token));
+                            }
+                        }
+                        Err(reason) => {
+                            events.push_back(ContextEvent::IdentityClientHandshakeFailed {
+                                handle,
+                                reason,
+                                stats: Default::default(),
+                            });
+                        }
+                    }
+                }
+                PendingHandshake::Endpoint {
+                    endpoint_server_id,
+                    client_auth_key,
+                    channel,
+                    circuit_token,
+                } => {
+                    match self.endpoint_client_connect(
+                        endpoint_server_id,
+                        client_auth_key,
+                        channel,
+                        circuit_token,
+                    ) {
+                        Ok(endpoint_client) => {
+                            self.endpoint_clients.insert(handle, endpoint_client);
+                        }
+                        Err(reason) => {
+                            events.push_back(ContextEvent::EndpointClientHandshakeFailed {
+                                handle,
+                                reason,
+                                stats: Default::default(),
+                            });
+                        }
+                    }
+                }
+                PendingHandshake::SharedEndpoint {
+                    identity_server_id,
+                    endpoint_server_id,
+                    channel,
+                } => {
+                    match self.endpoint_client_open(
+                        endpoint_server_id,
+                        Some(identity_server_id),
+                        channel,
+                        None,
+                    ) {
+                        Ok(endpoint_client) => {
+                            self.endpoint_clients.insert(handle, endpoint_client);
+                        }
+                        Err(reason) => {
+                            events.push_back(ContextEvent::EndpointClientHandshakeFailed {
+                                handle,
+                                reason,
+                                stats: Default::default(),
+                            });
+                        }
+                    }
+                }
+            }
+        }
+
+        // report client authorization key additions and removals
+        events.extend(self.client_auth_events.drain(..));
+
+        // report endpoint revocations
+        for (endpoint_service_id, revoked_at) in self.endpoint_revocations.drain(..) {
+            events.push_back(ContextEvent::EndpointClientEndpointRevoked {
+                endpoint_service_id,
+                revoked_at,
+            });
+        }
+
+        // report identity handshakes suppressed by a remembered connection failure
+        for (handle, identity_service_id, retry_after) in
+            self.suppressed_identity_handshakes.drain(..)
+        {
+            events.push_back(ContextEvent::IdentityClientHandshakeSuppressed {
+                handle,
+                identity_service_id,
+                retry_after,
+            });
+        }
+
+        // report queue position changes for handshakes still waiting
+        for (queue_position, queued) in self.outbound_queue.iter_mut().enumerate() {
+            if queued.reported_position != Some(queue_position) {
+                queued.reported_position = Some(queue_position);
+                self.event_queue_limit.push_lossy(
+                    events,
+                    ContextEvent::OutboundHandshakeQueued {
+                        handle: queued.handle,
+                        queue_position,
+                    },
+                );
             }
         }
 
+        // choose which handshakes to update if their number is limited
+        let mut scheduled = std::mem::take(&mut self.scheduled_handshakes);
+        let limited = self.schedule_handshakes(&mut scheduled);
+        let is_scheduled = |handle: &HandshakeHandle| -> bool {
+            !limited
+                || scheduled
+                    .binary_search_by_key(handle, |(_priority, handle)| *handle)
+                    .is_ok()
+        };
+
         // update the ident client handshakes
+        let mut endpoint_upgrades: Vec<EndpointUpgrade> = Default::default();
+        let mut kept_open_identity_clients: Vec<(HandshakeHandle, V3OnionServiceId, Duration)> =
+            Default::default();
         self.identity_clients
             .retain(|handle, identity_client| -> bool {
+                if !is_scheduled(handle) {
+                    return true;
+                }
                 let handle = *handle;
-                match identity_client.update() {
+                let _span = tracing::debug_span!("identity_client_handshake", handle).entered();
+                let result = identity_client.update();
+                for (operation, elapsed) in identity_client.latencies.drain(..) {
+                    self.timings.record(operation, elapsed);
+                }
+                match result {
                     Ok(Some(IdentityClientEvent::ChallengeReceived { endpoint_challenge })) => {
                         events.push_back(ContextEvent::IdentityClientChallengeReceived {
                             handle,
@@ -914,20 +5020,121 @@ impl Context {
                         endpoint_service_id,
                         endpoint_name,
                         client_auth_private_key,
+                        endpoint_upgrade,
+                        additional_endpoints,
+                        contact_request,
+                        capabilities,
                     })) => {
+                        let endpoint_upgrade_channel =
+                            self.endpoint_upgrade_channels.remove(&handle);
+                        let upgraded = endpoint_upgrade_channel.is_some() && endpoint_upgrade;
+                        // keep the connection open for follow-up identity handshakes
+                        // unless an endpoint handshake continues over it
+                        let keep_open = self.identity_keep_open.filter(|_| {
+                            !upgraded && capabilities.contains(Capabilities::KEEP_OPEN)
+                        });
+                        if let Some(keep_open) = keep_open {
+                            kept_open_identity_clients.push((
+                                handle,
+                                identity_service_id.clone(),
+                                keep_open,
+                            ));
+                        }
+                        if let Some((channel, circuit_token)) = endpoint_upgrade_channel.as_ref() {
+                            endpoint_upgrades.push((
+                                handle,
+                                endpoint_service_id.clone(),
+                                client_auth_private_key.clone(),
+                                channel.clone(),
+                                *circuit_token,
+                            ));
+                        }
+                        // compare the granted endpoint servers against their pins
+                        if let Some(pin_store) = self.pin_store.as_mut() {
+                            let granted_additional_endpoints = additional_endpoints
+                                .iter()
+                                .filter_map(|(endpoint_name, endpoint_service_id)| {
+                                    Some((endpoint_name, endpoint_service_id.as_ref()?))
+                                });
+                            for (granted_endpoint_name, granted_endpoint_service_id) in
+                                std::iter::once((&endpoint_name, &endpoint_service_id))
+                                    .chain(granted_additional_endpoints)
+                            {
+                                if let PinVerdict::Mismatched {
+                                    pinned_endpoint_service_id,
+                                } = pin_store.observe(
+                                    &identity_service_id,
+                                    granted_endpoint_name,
+                                    granted_endpoint_service_id,
+                                ) {
+                                    events.push_back(
+                                        ContextEvent::IdentityClientEndpointPinMismatched {
+                                            handle,
+                                            identity_service_id: identity_service_id.clone(),
+                                            endpoint_name: granted_endpoint_name.clone(),
+                                            pinned_endpoint_service_id,
+                                            endpoint_service_id: granted_endpoint_service_id
+                                                .clone(),
+                                        },
+                                    );
+                                }
+                            }
+                        }
+                        // remember the endpoint servers granted by named identities
+                        if let Some(petname_store) = self.petname_store.as_mut() {
+                            if petname_store.petname_of(&identity_service_id).is_some() {
+                                let granted_additional_endpoints = additional_endpoints
+                                    .iter()
+                                    .filter_map(|(endpoint_name, endpoint_service_id)| {
+                                        Some((endpoint_name, endpoint_service_id.as_ref()?))
+                                    });
+                                for (granted_endpoint_name, granted_endpoint_service_id) in
+                                    std::iter::once((&endpoint_name, &endpoint_service_id))
+                                        .chain(granted_additional_endpoints)
+                                {
+                                    // endpoint names were validated as ASCII by the handshake
+                                    if let Ok(endpoint_grant) = EndpointGrant::new(
+                                        identity_service_id.clone(),
+                                        self.identity_service_id.clone(),
+                                        granted_endpoint_service_id.clone(),
+                                        granted_endpoint_name.to_string(),
+                                        client_auth_private_key.clone(),
+                                    ) {
+                                        petname_store.record_grant(endpoint_grant);
+                                    }
+                                }
+                                if let Err(err) = petname_store.save() {
+                                    tracing::warn!(%err, "failed to save petnames");
+                                }
+                            }
+                        }
                         events.push_back(ContextEvent::IdentityClientHandshakeCompleted {
                             handle,
                             identity_service_id,
                             endpoint_service_id,
                             endpoint_name,
                             client_auth_private_key,
+                            additional_endpoints,
+                            contact_request,
+                            capabilities,
+                            stats: identity_client.stats(),
                         });
-                        false
+                        // upgraded and kept-open clients are removed below so their
+                        // connection may be reused
+                        upgraded || keep_open.is_some()
                     }
                     Err(err) => {
+                        self.endpoint_upgrade_channels.remove(&handle);
+                        let reason = match err {
+                            identity_client::Error::HandshakeRejected(rejection_reason) => {
+                                Error::IdentityHandshakeRejected(rejection_reason)
+                            }
+                            err => err.into(),
+                        };
                         events.push_back(ContextEvent::IdentityClientHandshakeFailed {
                             handle,
-                            reason: err.into(),
+                            reason,
+                            stats: identity_client.stats(),
                         });
                         false
                     }
@@ -935,19 +5142,107 @@ impl Context {
                 }
             });
 
+        // keep the connections of completed identity handshakes open for the
+        // next identity handshake with their identity server
+        for (handle, identity_service_id, keep_open) in kept_open_identity_clients {
+            if let Some(identity_client) = self.identity_clients.remove(&handle) {
+                let mut session = identity_client.into_session();
+                // the connection may go unread for up to keep_open
+                session.set_max_wait_time(self.identity_timeout.saturating_add(keep_open));
+                let deadline = self.clock.now() + keep_open;
+                if let Some((_session, _deadline, replaced_handle)) = self
+                    .kept_open_identity_client_sessions
+                    .insert(identity_service_id.clone(), (session, deadline, handle))
+                {
+                    events.push_back(ContextEvent::IdentityClientSessionClosed {
+                        handle: replaced_handle,
+                        identity_service_id,
+                    });
+                }
+            }
+        }
+
+        // begin the endpoint handshakes requested with completed identity handshakes
+        for (handle, endpoint_service_id, client_auth_key, channel, circuit_token) in
+            endpoint_upgrades
+        {
+            let result = match self.identity_clients.remove(&handle) {
+                // the identity server agreed to continue over the identity handshake's connection
+                Some(identity_client) => self
+                    .endpoint_client_upgrade(identity_client, endpoint_service_id, channel)
+                    .map(|endpoint_client| {
+                        self.endpoint_clients.insert(handle, endpoint_client);
+                    }),
+                // otherwise connect to the endpoint server's onion-service
+                None => self.endpoint_client_start(
+                    handle,
+                    endpoint_service_id,
+                    client_auth_key,
+                    channel,
+                    circuit_token,
+                ),
+            };
+            if let Err(reason) = result {
+                events.push_back(ContextEvent::EndpointClientHandshakeFailed {
+                    handle,
+                    reason,
+                    stats: Default::default(),
+                });
+            }
+        }
+
         // update the ident server handshakes
+        let mut upgraded_identity_servers: Vec<(HandshakeHandle, V3OnionServiceId)> =
+            Default::default();
+        let mut kept_open_identity_servers: Vec<(HandshakeHandle, V3OnionServiceId, Duration)> =
+            Default::default();
         self.identity_servers
             .retain(|handle, identity_server| -> bool {
+                if !is_scheduled(handle) {
+                    return true;
+                }
                 let handle = *handle;
-                match identity_server.update() {
+                let _span = tracing::debug_span!("identity_server_handshake", handle).entered();
+                let result = identity_server.update();
+                // follow-up handshakes over kept-open connections only start once
+                // the client begins them
+                if let Some((completed_handle, client_service_id)) =
+                    self.kept_open_identity_servers.get(&handle).cloned()
+                {
+                    match result {
+                        Ok(None) => return true,
+                        Ok(Some(_)) => {
+                            self.kept_open_identity_servers.remove(&handle);
+                            identity_server.set_state_deadline(Some(self.identity_timeout));
+                            identity_server.set_read_idle_timeout(self.handshake_read_idle_timeout);
+                            events
+                                .push_back(ContextEvent::IdentityServerHandshakeStarted { handle });
+                        }
+                        Err(_) => {
+                            self.kept_open_identity_servers.remove(&handle);
+                            events.push_back(ContextEvent::IdentityServerSessionClosed {
+                                handle: completed_handle,
+                                client_service_id,
+                            });
+                            return false;
+                        }
+                    }
+                }
+                match result {
                     Ok(Some(IdentityServerEvent::EndpointRequestReceived {
                         client_service_id,
                         requested_endpoint,
+                        additional_endpoints,
+                        contact_request,
                     })) => {
+                        self.identity_server_stats
+                            .record_request(&requested_endpoint);
                         events.push_back(ContextEvent::IdentityServerEndpointRequestReceived {
                             handle,
                             client_service_id,
-                            requested_endpoint: requested_endpoint.to_string(),
+                            requested_endpoint,
+                            additional_endpoints,
+                            contact_request,
                         });
                         true
                     }
@@ -965,15 +5260,44 @@ impl Context {
                         endpoint_name,
                         client_service_id,
                         client_auth_public_key,
+                        endpoint_upgrade,
+                        additional_endpoints,
+                        contact_request,
+                        capabilities,
                     })) => {
+                        self.identity_server_stats.record_grant(&endpoint_name);
+                        if endpoint_upgrade {
+                            upgraded_identity_servers.push((
+                                handle,
+                                V3OnionServiceId::from_private_key(&endpoint_private_key),
+                            ));
+                        }
+                        // keep the connection open for follow-up identity handshakes
+                        // unless an endpoint handshake continues over it
+                        let keep_open = self.identity_keep_open.filter(|_| {
+                            !endpoint_upgrade && capabilities.contains(Capabilities::KEEP_OPEN)
+                        });
+                        if let Some(keep_open) = keep_open {
+                            kept_open_identity_servers.push((
+                                handle,
+                                client_service_id.clone(),
+                                keep_open,
+                            ));
+                        }
                         events.push_back(ContextEvent::IdentityServerHandshakeCompleted {
                             handle,
-                            endpoint_private_key,
-                            endpoint_name: endpoint_name.to_string(),
+                            endpoint_private_key: *endpoint_private_key,
+                            endpoint_name,
                             client_service_id,
                             client_auth_public_key,
+                            additional_endpoints,
+                            contact_request,
+                            capabilities,
+                            stats: identity_server.stats(),
                         });
-                        false
+                        // upgraded and kept-open servers are removed below so their
+                        // connection may be reused
+                        endpoint_upgrade || keep_open.is_some()
                     }
                     Ok(Some(IdentityServerEvent::HandshakeRejected {
                         client_allowed,
@@ -982,6 +5306,16 @@ impl Context {
                         client_auth_signature_valid,
                         challenge_response_valid,
                     })) => {
+                        if let Some(requested_endpoint) = identity_server.requested_endpoint() {
+                            self.identity_server_stats.record_rejection(
+                                requested_endpoint,
+                                client_allowed,
+                                client_requested_endpoint_valid,
+                                client_proof_signature_valid,
+                                client_auth_signature_valid,
+                                challenge_response_valid,
+                            );
+                        }
                         events.push_back(ContextEvent::IdentityServerHandshakeRejected {
                             handle,
                             client_allowed,
@@ -992,10 +5326,28 @@ impl Context {
                         });
                         false
                     }
+                    Ok(Some(IdentityServerEvent::HandshakeBanned {
+                        client_service_id,
+                        requested_endpoint,
+                        ban,
+                    })) => {
+                        events.push_back(ContextEvent::IdentityServerBannedClientRejected {
+                            handle,
+                            client_service_id,
+                            requested_endpoint,
+                            ban,
+                        });
+                        false
+                    }
                     Err(err) => {
+                        if let Some(requested_endpoint) = identity_server.requested_endpoint() {
+                            self.identity_server_stats
+                                .record_failure(requested_endpoint);
+                        }
                         events.push_back(ContextEvent::IdentityServerHandshakeFailed {
                             handle,
                             reason: err.into(),
+                            stats: identity_server.stats(),
                         });
                         false
                     }
@@ -1003,35 +5355,206 @@ impl Context {
                 }
             });
 
-        // update the endpoint client handshakes
+        // hold upgraded identity connections until their endpoint server is started
+        for (handle, endpoint_service_id) in upgraded_identity_servers {
+            let session = self
+                .identity_servers
+                .remove(&handle)
+                .and_then(|identity_server| identity_server.into_session());
+            if let Some(mut session) = session {
+                session.set_max_wait_time(self.endpoint_timeout);
+                if session
+                    .set_max_message_size(DEFAULT_ENDPOINT_MAX_MESSAGE_SIZE)
+                    .is_ok()
+                {
+                    self.upgraded_identity_sessions
+                        .insert(endpoint_service_id, (session, self.clock.now()));
+                }
+            }
+        }
+
+        // wait on kept-open identity connections for the client's next handshake
+        for (completed_handle, client_service_id, keep_open) in kept_open_identity_servers {
+            let session = self
+                .identity_servers
+                .remove(&completed_handle)
+                .and_then(|identity_server| identity_server.into_session());
+            if let Some(mut session) = session {
+                // the connection may go unread for up to keep_open
+                session.set_max_wait_time(self.identity_timeout.saturating_add(keep_open));
+                let mut identity_server = IdentityServer::new(
+                    session,
+                    self.identity_service_id.clone(),
+                    self.identity_client_filter.clone(),
+                    self.identity_server_endpoint_upgrade_allowed,
+                );
+                identity_server.set_challenge_response_deadline(
+                    self.identity_server_challenge_response_deadline,
+                );
+                // the handshake's own deadlines apply once the client begins it
+                identity_server.set_state_deadline(Some(keep_open));
+                identity_server.set_clock(self.clock.clone());
+                identity_server.set_capabilities(self.identity_capabilities());
+                identity_server.set_replay_cache(Some(self.handshake_replay_cache.clone()));
+                identity_server.set_ban_list(self.ban_list.clone());
+                identity_server.set_endpoint_key_derivation(self.endpoint_key_derivation_key());
+                let handle = self.next_handshake_handle;
+                self.next_handshake_handle += 1;
+                self.identity_servers.insert(handle, identity_server);
+                self.kept_open_identity_servers
+                    .insert(handle, (completed_handle, client_service_id));
+            }
+        }
+
+        // update the endpoint client handshakes, noting the endpoint servers
+        // of those which finish and whether they succeeded
+        let mut finished_endpoint_clients: Vec<(V3OnionServiceId, bool)> = Default::default();
         self.endpoint_clients
             .retain(|handle, endpoint_client| -> bool {
+                if !is_scheduled(handle) {
+                    return true;
+                }
                 let handle = *handle;
-                match endpoint_client.update() {
-                    Ok(Some(EndpointClientEvent::HandshakeCompleted { stream })) => {
-                        events.push_back(ContextEvent::EndpointClientHandshakeCompleted {
+                let _span = tracing::debug_span!("endpoint_client_handshake", handle).entered();
+                let result = endpoint_client.update();
+                for (operation, elapsed) in endpoint_client.latencies.drain(..) {
+                    self.timings.record(operation, elapsed);
+                }
+                match result {
+                    Ok(Some(EndpointClientEvent::HandshakeCompleted {
+                        stream,
+                        capabilities,
+                    })) => {
+                        let succeeded = if self
+                            .datagram_channels
+                            .contains(&endpoint_client.requested_channel)
+                        {
+                            match DatagramChannel::new(stream) {
+                                Ok(channel) => {
+                                    events.push_back(
+                                        ContextEvent::EndpointClientDatagramChannelOpened {
+                                            handle,
+                                            endpoint_service_id: endpoint_client
+                                                .server_service_id
+                                                .clone(),
+                                            channel_name: endpoint_client.requested_channel.clone(),
+                                            channel,
+                                            capabilities,
+                                            stats: endpoint_client.stats(),
+                                        },
+                                    );
+                                    true
+                                }
+                                Err(err) => {
+                                    events.push_back(ContextEvent::EndpointClientHandshakeFailed {
+                                        handle,
+                                        reason: err.into(),
+                                        stats: endpoint_client.stats(),
+                                    });
+                                    false
+                                }
+                            }
+                        } else {
+                            events.push_back(ContextEvent::EndpointClientHandshakeCompleted {
+                                handle,
+                                endpoint_service_id: endpoint_client.server_service_id.clone(),
+                                channel_name: endpoint_client.requested_channel.clone(),
+                                stream,
+                                capabilities,
+                                stats: endpoint_client.stats(),
+                            });
+                            true
+                        };
+                        finished_endpoint_clients
+                            .push((endpoint_client.server_service_id.clone(), succeeded));
+                        false
+                    }
+                    Ok(Some(EndpointClientEvent::RpcChannelOpened {
+                        session,
+                        capabilities,
+                    })) => {
+                        let succeeded = match RpcChannel::new(
+                            *session,
+                            self.clock.clone(),
+                            self.rpc_channel_heartbeat,
+                        ) {
+                            Ok(channel) => {
+                                events.push_back(ContextEvent::EndpointClientRpcChannelOpened {
+                                    handle,
+                                    endpoint_service_id: endpoint_client.server_service_id.clone(),
+                                    channel_name: endpoint_client.requested_channel.clone(),
+                                    channel,
+                                    capabilities,
+                                    stats: endpoint_client.stats(),
+                                });
+                                true
+                            }
+                            Err(err) => {
+                                events.push_back(ContextEvent::EndpointClientHandshakeFailed {
+                                    handle,
+                                    reason: err.into(),
+                                    stats: endpoint_client.stats(),
+                                });
+                                false
+                            }
+                        };
+                        finished_endpoint_clients
+                            .push((endpoint_client.server_service_id.clone(), succeeded));
+                        false
+                    }
+                    Err(endpoint_client::Error::ServerBusy(retry_after)) => {
+                        events.push_back(ContextEvent::EndpointClientHandshakeBusy {
                             handle,
                             endpoint_service_id: endpoint_client.server_service_id.clone(),
-                            channel_name: endpoint_client.requested_channel.to_string(),
-                            stream,
+                            channel_name: endpoint_client.requested_channel.clone(),
+                            retry_after,
                         });
+                        finished_endpoint_clients
+                            .push((endpoint_client.server_service_id.clone(), false));
                         false
                     }
                     Err(err) => {
+                        let reason = match err {
+                            endpoint_client::Error::HandshakeTimedOut(timeout) => {
+                                Error::EndpointHandshakeTimedOut(
+                                    endpoint_client.server_service_id.clone(),
+                                    timeout,
+                                )
+                            }
+                            endpoint_client::Error::QuotaExceeded(exceeded) => {
+                                Error::EndpointChannelQuotaExceeded(
+                                    endpoint_client.server_service_id.clone(),
+                                    exceeded,
+                                )
+                            }
+                            err => err.into(),
+                        };
                         events.push_back(ContextEvent::EndpointClientHandshakeFailed {
                             handle,
-                            reason: err.into(),
+                            reason,
+                            stats: endpoint_client.stats(),
                         });
+                        finished_endpoint_clients
+                            .push((endpoint_client.server_service_id.clone(), false));
                         false
                     }
                     Ok(None) => true,
                 }
             });
+        for (endpoint_service_id, succeeded) in finished_endpoint_clients {
+            self.endpoint_client_finished(&endpoint_service_id, succeeded);
+        }
+        events.extend(self.client_auth_events.drain(..));
 
         // update the endpoint server handshakes
+        let mut channel_quota_permits = Vec::new();
         self.endpoint_servers
             .retain(|handle, endpoint_server| -> bool {
+                if !is_scheduled(handle) {
+                    return true;
+                }
                 let handle = *handle;
+                let _span = tracing::debug_span!("endpoint_server_handshake", handle).entered();
                 match endpoint_server.update() {
                     Ok(Some(EndpointServerEvent::ChannelRequestReceived {
                         requested_channel,
@@ -1040,7 +5563,7 @@ impl Context {
                         events.push_back(ContextEvent::EndpointServerChannelRequestReceived {
                             handle,
                             client_service_id,
-                            requested_channel: requested_channel.to_string(),
+                            requested_channel,
                         });
                         true
                     }
@@ -1048,16 +5571,89 @@ impl Context {
                         client_service_id,
                         channel_name,
                         stream,
+                        capabilities,
                     })) => {
+                        if self.datagram_channels.contains(&channel_name) {
+                            match DatagramChannel::new(stream) {
+                                Ok(channel) => {
+                                    if let Some(permit) =
+                                        endpoint_server.take_channel_quota_permit()
+                                    {
+                                        channel_quota_permits.push((handle, permit));
+                                    }
+                                    events.push_back(
+                                        ContextEvent::EndpointServerDatagramChannelOpened {
+                                            handle,
+                                            endpoint_service_id: endpoint_server
+                                                .server_identity
+                                                .clone(),
+                                            client_service_id,
+                                            channel_name,
+                                            channel,
+                                            capabilities,
+                                            stats: endpoint_server.stats(),
+                                        },
+                                    );
+                                }
+                                Err(err) => {
+                                    events.push_back(ContextEvent::EndpointServerHandshakeFailed {
+                                        handle,
+                                        reason: err.into(),
+                                        stats: endpoint_server.stats(),
+                                    });
+                                }
+                            }
+                            return false;
+                        }
+                        if let Some(permit) = endpoint_server.take_channel_quota_permit() {
+                            channel_quota_permits.push((handle, permit));
+                        }
                         events.push_back(ContextEvent::EndpointServerHandshakeCompleted {
                             handle,
                             endpoint_service_id: endpoint_server.server_identity.clone(),
                             client_service_id,
-                            channel_name: channel_name.to_string(),
+                            channel_name,
                             stream,
+                            capabilities,
+                            stats: endpoint_server.stats(),
                         });
                         false
                     }
+                    Ok(Some(EndpointServerEvent::RpcChannelOpened {
+                        client_service_id,
+                        channel_name,
+                        session,
+                        capabilities,
+                    })) => {
+                        match RpcChannel::new(
+                            *session,
+                            self.clock.clone(),
+                            self.rpc_channel_heartbeat,
+                        ) {
+                            Ok(channel) => {
+                                if let Some(permit) = endpoint_server.take_channel_quota_permit() {
+                                    channel_quota_permits.push((handle, permit));
+                                }
+                                events.push_back(ContextEvent::EndpointServerRpcChannelOpened {
+                                    handle,
+                                    endpoint_service_id: endpoint_server.server_identity.clone(),
+                                    client_service_id,
+                                    channel_name,
+                                    channel,
+                                    capabilities,
+                                    stats: endpoint_server.stats(),
+                                });
+                            }
+                            Err(err) => {
+                                events.push_back(ContextEvent::EndpointServerHandshakeFailed {
+                                    handle,
+                                    reason: err.into(),
+                                    stats: endpoint_server.stats(),
+                                });
+                            }
+                        }
+                        false
+                    }
                     Ok(Some(EndpointServerEvent::HandshakeRejected {
                         client_allowed,
                         client_requested_channel_valid,
@@ -1071,10 +5667,50 @@ impl Context {
                         });
                         false
                     }
+                    Ok(Some(EndpointServerEvent::HandshakeBusy {
+                        client_service_id,
+                        requested_channel,
+                        retry_after,
+                    })) => {
+                        events.push_back(ContextEvent::EndpointServerHandshakeBusy {
+                            handle,
+                            client_service_id,
+                            requested_channel,
+                            retry_after,
+                        });
+                        false
+                    }
+                    Ok(Some(EndpointServerEvent::HandshakeQuotaExceeded {
+                        client_service_id,
+                        requested_channel,
+                        exceeded,
+                    })) => {
+                        events.push_back(ContextEvent::EndpointServerChannelQuotaExceeded {
+                            handle,
+                            client_service_id,
+                            requested_channel,
+                            exceeded,
+                        });
+                        false
+                    }
+                    Ok(Some(EndpointServerEvent::HandshakeBanned {
+                        client_service_id,
+                        requested_channel,
+                        ban,
+                    })) => {
+                        events.push_back(ContextEvent::EndpointServerBannedClientRejected {
+                            handle,
+                            client_service_id,
+                            requested_channel,
+                            ban,
+                        });
+                        false
+                    }
                     Err(err) => {
                         events.push_back(ContextEvent::EndpointServerHandshakeFailed {
                             handle,
                             reason: err.into(),
+                            stats: endpoint_server.stats(),
                         });
                         false
                     }
@@ -1082,6 +5718,280 @@ impl Context {
                 }
             });
 
-        Ok(events)
+        self.endpoint_server_channel_quota_permits
+            .extend(channel_quota_permits);
+
+        self.scheduled_handshakes = scheduled;
+
+        events.extend(self.aborted_server_events.drain(..));
+
+        // forget the priorities of finished handshakes
+        let mut handshake_priorities = std::mem::take(&mut self.handshake_priorities);
+        handshake_priorities.retain(|handle, _priority| self.handshake_in_progress(*handle));
+        self.handshake_priorities = handshake_priorities;
+
+        Ok(())
+    }
+}
+
+impl Drop for Context {
+    fn drop(&mut self) {
+        // the tor daemon may outlive this context, so keys it added are not
+        // left behind unless the retention policy keeps them
+        if self.client_auth_retention != ClientAuthRetention::Retain {
+            for endpoint_service_id in self.client_auth_entries.keys() {
+                // nothing is left to report a failure to
+                let _ = self.tor_provider.remove_client_auth(endpoint_service_id);
+            }
+        }
+    }
+}
+
+// endpoint and channel names longer than the protocol allows would be rejected
+// by the peer, so they are rejected before a handshake begins
+fn ensure_endpoint_name_length(endpoint_name: &str) -> Result<(), Error> {
+    match endpoint_name.len() {
+        length if length > MAX_ENDPOINT_NAME_LENGTH => Err(Error::EndpointNameTooLong(length)),
+        _ => Ok(()),
+    }
+}
+
+fn ensure_channel_name_length(channel_name: &str) -> Result<(), Error> {
+    match channel_name.len() {
+        length if length > MAX_CHANNEL_NAME_LENGTH => Err(Error::ChannelNameTooLong(length)),
+        _ => Ok(()),
+    }
+}
+
+// report the pending publishes whose endpoint server has been published or
+// whose timeout has elapsed; published returns whether an endpoint server has
+// been published, or None if it is no longer running
+fn finish_endpoint_publishes(
+    pending_endpoint_publishes: &mut HashMap<V3OnionServiceId, PendingEndpointPublish>,
+    published: impl Fn(&V3OnionServiceId) -> Option<bool>,
+    now: Instant,
+    events: &mut VecDeque<ContextEvent>,
+) {
+    pending_endpoint_publishes.retain(|endpoint_service_id, pending| {
+        match (published(endpoint_service_id), pending.timeout) {
+            (None, _) => false,
+            (Some(true), _) => {
+                events.push_back(ContextEvent::EndpointServerPublishCompleted {
+                    handle: pending.handle,
+                    endpoint_service_id: endpoint_service_id.clone(),
+                    endpoint_name: pending.endpoint_name.clone(),
+                });
+                false
+            }
+            (Some(false), Some(timeout))
+                if now.saturating_duration_since(pending.started) >= timeout =>
+            {
+                events.push_back(ContextEvent::EndpointServerPublishTimedOut {
+                    handle: pending.handle,
+                    endpoint_service_id: endpoint_service_id.clone(),
+                    endpoint_name: pending.endpoint_name.clone(),
+                    timeout,
+                });
+                false
+            }
+            (Some(false), _) => true,
+        }
+    });
+}
+
+#[test]
+fn test_endpoint_max_message_size() -> anyhow::Result<()> {
+    let (client_stream, _server_stream) = crate::testing::duplex();
+    let mut client_rpc = Session::new(client_stream);
+    client_rpc.set_max_message_size(DEFAULT_ENDPOINT_MAX_MESSAGE_SIZE)?;
+
+    let server_service_id = V3OnionServiceId::from_private_key(&Ed25519PrivateKey::generate());
+    let channel = AsciiString::new("c".repeat(MAX_CHANNEL_NAME_LENGTH))?;
+    let mut client = EndpointClient::new(
+        client_rpc,
+        server_service_id,
+        channel,
+        Ed25519PrivateKey::generate(),
+    );
+    client.set_capabilities(Capabilities::all())?;
+    client.set_shared(true)?;
+
+    // the largest begin_handshake request fits within the limit
+    client.update()?;
+
+    Ok(())
+}
+
+#[test]
+fn test_bounded_event_queue() -> anyhow::Result<()> {
+    let log = |line: &str| ContextEvent::TorLogReceived {
+        line: line.to_string(),
+    };
+    // produce events as an update() would, returning them and the result of
+    // finishing the update
+    let update = |capacity: usize, overflow_policy: EventQueueOverflowPolicy| {
+        let mut limit = EventQueueLimit {
+            capacity: Some(capacity),
+            overflow_policy,
+            dropped_events: 0,
+            coalesced_tor_logs: 0,
+        };
+        let mut events: VecDeque<ContextEvent> = Default::default();
+        limit.push_lossy(&mut events, log("a"));
+        events.push_back(ContextEvent::TorBootstrapCompleted);
+        limit.push_lossy(&mut events, log("b"));
+        limit.push_lossy(
+            &mut events,
+            ContextEvent::OutboundHandshakeQueued {
+                handle: 0,
+                queue_position: 0,
+            },
+        );
+        events.push_back(ContextEvent::IdentityServerPublished);
+        limit.push_lossy(&mut events, log("c"));
+        let result = limit.finish(&mut events);
+        (events, result)
+    };
+
+    // events within capacity are untouched
+    for overflow_policy in [
+        EventQueueOverflowPolicy::DropOldest,
+        EventQueueOverflowPolicy::CoalesceLogs,
+        EventQueueOverflowPolicy::Error,
+    ] {
+        let (events, result) = update(6, overflow_policy);
+        assert_eq!(events.len(), 6);
+        assert!(matches!(result, Ok(pending_events) if pending_events.is_empty()));
+    }
+
+    // drop oldest
+    let (events, result) = update(4, EventQueueOverflowPolicy::DropOldest);
+    println!("drop oldest: {:?} {:?}", events, result);
+    let pending_events = result.unwrap();
+    assert_eq!(events.len(), 4);
+    assert!(matches!(
+        events[0],
+        ContextEvent::EventQueueOverflowed {
+            dropped_events: 1,
+            coalesced_tor_logs: 0
+        }
+    ));
+    assert!(matches!(events[1], ContextEvent::TorBootstrapCompleted));
+    assert!(matches!(&events[2], ContextEvent::TorLogReceived { line } if line == "b"));
+    // events which may not be discarded are held back instead
+    assert_eq!(pending_events.len(), 2);
+    assert!(matches!(
+        pending_events[0],
+        ContextEvent::IdentityServerPublished
+    ));
+    assert!(matches!(&pending_events[1], ContextEvent::TorLogReceived { line } if line == "c"));
+
+    // coalesce logs, dropping the oldest other lossy events
+    let (events, result) = update(3, EventQueueOverflowPolicy::CoalesceLogs);
+    println!("coalesce logs: {:?} {:?}", events, result);
+    let pending_events = result.unwrap();
+    assert_eq!(events.len(), 3);
+    assert!(matches!(
+        events[0],
+        ContextEvent::EventQueueOverflowed {
+            dropped_events: 1,
+            coalesced_tor_logs: 1
+        }
+    ));
+    assert!(matches!(events[1], ContextEvent::TorBootstrapCompleted));
+    assert!(matches!(&events[2], ContextEvent::TorLogReceived { line } if line == "b\nc"));
+    assert_eq!(pending_events.len(), 2);
+    assert!(matches!(
+        pending_events[0],
+        ContextEvent::OutboundHandshakeQueued { .. }
+    ));
+    assert!(matches!(
+        pending_events[1],
+        ContextEvent::IdentityServerPublished
+    ));
+
+    // error
+    let (events, result) = update(4, EventQueueOverflowPolicy::Error);
+    println!("error: {:?} {:?}", events, result);
+    assert!(matches!(result, Err(1)));
+    // only the newest log is discarded
+    assert_eq!(events.len(), 5);
+    assert!(matches!(&events[0], ContextEvent::TorLogReceived { line } if line == "a"));
+    assert!(matches!(events[4], ContextEvent::IdentityServerPublished));
+
+    Ok(())
+}
+
+#[test]
+fn test_finish_endpoint_publishes() -> anyhow::Result<()> {
+    let service_id = || V3OnionServiceId::from_private_key(&Ed25519PrivateKey::generate());
+    let (published, unpublished, untimed, stopped) =
+        (service_id(), service_id(), service_id(), service_id());
+    let started = Instant::now();
+    let timeout = Duration::from_secs(10);
+
+    let mut pending_endpoint_publishes: HashMap<V3OnionServiceId, PendingEndpointPublish> =
+        Default::default();
+    for (handle, endpoint_service_id, timeout) in [
+        (0, &published, Some(timeout)),
+        (1, &unpublished, Some(timeout)),
+        (2, &untimed, None),
+        (3, &stopped, Some(timeout)),
+    ] {
+        pending_endpoint_publishes.insert(
+            endpoint_service_id.clone(),
+            PendingEndpointPublish {
+                handle,
+                endpoint_name: "test_endpoint".parse()?,
+                started,
+                timeout,
+            },
+        );
     }
+    let is_published = |endpoint_service_id: &V3OnionServiceId| {
+        if *endpoint_service_id == stopped {
+            None
+        } else {
+            Some(*endpoint_service_id == published)
+        }
+    };
+
+    // published endpoint servers complete and stopped ones are discarded
+    let mut events: VecDeque<ContextEvent> = Default::default();
+    finish_endpoint_publishes(
+        &mut pending_endpoint_publishes,
+        is_published,
+        started + Duration::from_secs(1),
+        &mut events,
+    );
+    assert_eq!(events.len(), 1);
+    assert!(matches!(
+        &events[0],
+        ContextEvent::EndpointServerPublishCompleted { handle: 0, endpoint_service_id, .. }
+            if *endpoint_service_id == published
+    ));
+    assert_eq!(pending_endpoint_publishes.len(), 2);
+    assert_eq!(
+        pending_endpoint_publishes[&unpublished].remaining(started + Duration::from_secs(1)),
+        Some(Duration::from_secs(9))
+    );
+
+    // unpublished endpoint servers time out unless they have no timeout
+    let mut events: VecDeque<ContextEvent> = Default::default();
+    finish_endpoint_publishes(
+        &mut pending_endpoint_publishes,
+        is_published,
+        started + timeout,
+        &mut events,
+    );
+    assert_eq!(events.len(), 1);
+    assert!(matches!(
+        &events[0],
+        ContextEvent::EndpointServerPublishTimedOut { handle: 1, endpoint_service_id, timeout: elapsed, .. }
+            if *endpoint_service_id == unpublished && *elapsed == timeout
+    ));
+    assert_eq!(pending_endpoint_publishes.len(), 1);
+    assert!(pending_endpoint_publishes.contains_key(&untimed));
+
+    Ok(())
 }
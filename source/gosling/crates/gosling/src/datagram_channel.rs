@@ -0,0 +1,311 @@
+// standard
+use std::collections::VecDeque;
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpStream;
+#[cfg(test)]
+use std::net::{SocketAddr, TcpListener};
+
+/// The largest datagram which may be sent over a [`DatagramChannel`], in bytes
+pub const MAX_DATAGRAM_SIZE: usize = u16::MAX as usize;
+/// The number of datagrams a [`DatagramChannel`] queues in each direction unless set with [`DatagramChannel::set_max_queued_datagrams()`]
+pub const DEFAULT_MAX_QUEUED_DATAGRAMS: usize = 64;
+
+// each datagram is preceded by its length as a big-endian u16
+const FRAME_HEADER_SIZE: usize = std::mem::size_of::<u16>();
+const READ_CHUNK_SIZE: usize = 4096;
+
+/// The error type for the [`DatagramChannel`] type.
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    /// The datagram is larger than [`MAX_DATAGRAM_SIZE`]
+    #[error("datagram too large: {0} bytes")]
+    DatagramTooLarge(usize),
+
+    /// The peer closed the underlying connection
+    #[error("connection closed by peer")]
+    ConnectionClosed,
+
+    /// Reading from or writing to the underlying connection failed
+    #[error("socket operation failed: {0}")]
+    SocketFailure(#[from] std::io::Error),
+
+    /// An invalid argument was provided to a function
+    #[error("invalid argument: {0}")]
+    InvalidArgument(String),
+}
+
+/// Counts of the datagrams handled by a [`DatagramChannel`]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DatagramChannelStats {
+    /// The number of datagrams fully written to the connection
+    pub datagrams_sent: u64,
+    /// The number of datagrams fully read from the connection
+    pub datagrams_received: u64,
+    /// The number of queued outgoing datagrams dropped to make room for newer ones
+    pub outbound_dropped: u64,
+    /// The number of received datagrams dropped to make room for newer ones before being taken with [`DatagramChannel::recv()`]
+    pub inbound_dropped: u64,
+}
+
+/// Message-oriented traffic over the connection of a completed endpoint handshake, for applications (e.g. real-time audio or game state) which prefer losing stale messages to waiting on them.
+///
+/// Onion services only carry TCP streams, so datagrams are framed with their length and sent in order over the handshake's connection; they are never lost or reordered in transit. Instead, when the connection cannot keep up, the oldest queued datagrams are dropped on the sending side, and when the application cannot keep up, the oldest received datagrams are dropped on the receiving side. Datagrams are only written and read while [`DatagramChannel::update()`] is called.
+///
+/// A `DatagramChannel` is returned in a [`ContextEvent::EndpointClientDatagramChannelOpened`](crate::context::ContextEvent::EndpointClientDatagramChannelOpened) or [`ContextEvent::EndpointServerDatagramChannelOpened`](crate::context::ContextEvent::EndpointServerDatagramChannelOpened) event for channels registered with [`Context::add_datagram_channel()`](crate::context::Context::add_datagram_channel).
+pub struct DatagramChannel {
+    stream: TcpStream,
+    max_queued_datagrams: usize,
+    // framed datagrams waiting to be written
+    outbound: VecDeque<Vec<u8>>,
+    // bytes of the front outbound frame already written; a partially written
+    // frame is never dropped, as that would corrupt the stream
+    outbound_offset: usize,
+    // bytes read but not yet parsed into datagrams
+    read_buffer: Vec<u8>,
+    inbound: VecDeque<Vec<u8>>,
+    stats: DatagramChannelStats,
+}
+
+impl DatagramChannel {
+    pub(crate) fn new(stream: TcpStream) -> Result<Self, Error> {
+        stream.set_nonblocking(true)?;
+        Ok(Self {
+            stream,
+            max_queued_datagrams: DEFAULT_MAX_QUEUED_DATAGRAMS,
+            outbound: Default::default(),
+            outbound_offset: 0,
+            read_buffer: Default::default(),
+            inbound: Default::default(),
+            stats: Default::default(),
+        })
+    }
+
+    /// Queue a datagram to be sent to the peer by the following calls to [`DatagramChannel::update()`]. If the maximum number of outgoing datagrams are already queued, the oldest which has not started being written is dropped.
+    ///
+    /// # Parameters
+    /// - `datagram`: the datagram's payload; at most [`MAX_DATAGRAM_SIZE`] bytes and may be empty
+    pub fn send(&mut self, datagram: &[u8]) -> Result<(), Error> {
+        if datagram.len() > MAX_DATAGRAM_SIZE {
+            return Err(Error::DatagramTooLarge(datagram.len()));
+        }
+
+        let first_droppable = usize::from(self.outbound_offset > 0);
+        while self.outbound.len() - first_droppable >= self.max_queued_datagrams {
+            self.outbound.remove(first_droppable);
+            self.stats.outbound_dropped += 1;
+        }
+
+        let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE + datagram.len());
+        frame.extend_from_slice(&(datagram.len() as u16).to_be_bytes());
+        frame.extend_from_slice(datagram);
+        self.outbound.push_back(frame);
+        Ok(())
+    }
+
+    /// Take the oldest datagram received by [`DatagramChannel::update()`], if any.
+    pub fn recv(&mut self) -> Option<Vec<u8>> {
+        self.inbound.pop_front()
+    }
+
+    /// Write as many queued datagrams as the connection accepts without blocking, and read all datagrams the peer has sent. Returns [`Error::ConnectionClosed`] once the peer has closed the connection; datagrams received before then may still be taken with [`DatagramChannel::recv()`].
+    pub fn update(&mut self) -> Result<(), Error> {
+        self.write_outbound()?;
+        self.read_inbound()
+    }
+
+    fn write_outbound(&mut self) -> Result<(), Error> {
+        while let Some(frame) = self.outbound.front() {
+            let frame_len = frame.len();
+            match self.stream.write(&frame[self.outbound_offset..]) {
+                Ok(0) => return Err(Error::ConnectionClosed),
+                Ok(count) => {
+                    self.outbound_offset += count;
+                    if self.outbound_offset == frame_len {
+                        self.outbound.pop_front();
+                        self.outbound_offset = 0;
+                        self.stats.datagrams_sent += 1;
+                    }
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(())
+    }
+
+    fn read_inbound(&mut self) -> Result<(), Error> {
+        let mut buffer = [0u8; READ_CHUNK_SIZE];
+        loop {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => return Err(Error::ConnectionClosed),
+                Ok(count) => {
+                    self.read_buffer.extend_from_slice(&buffer[..count]);
+                    // parse after every read so a peer sending faster than
+                    // it is read cannot grow the buffer without bound
+                    self.parse_inbound();
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+
+    fn parse_inbound(&mut self) {
+        let mut offset = 0;
+        while self.read_buffer.len() - offset >= FRAME_HEADER_SIZE {
+            let datagram_len =
+                u16::from_be_bytes([self.read_buffer[offset], self.read_buffer[offset + 1]])
+                    as usize;
+            let begin = offset + FRAME_HEADER_SIZE;
+            let end = begin + datagram_len;
+            if end > self.read_buffer.len() {
+                break;
+            }
+
+            while self.inbound.len() >= self.max_queued_datagrams {
+                self.inbound.pop_front();
+                self.stats.inbound_dropped += 1;
+            }
+            self.inbound
+                .push_back(self.read_buffer[begin..end].to_vec());
+            self.stats.datagrams_received += 1;
+            offset = end;
+        }
+        self.read_buffer.drain(..offset);
+    }
+
+    /// Set how many datagrams are queued in each direction before the oldest are dropped. Already queued datagrams beyond the new limit are dropped on the next [`DatagramChannel::send()`] or received datagram.
+    ///
+    /// # Parameters
+    /// - `max_queued_datagrams`: the maximum number of queued datagrams; must not be zero (the default is [`DEFAULT_MAX_QUEUED_DATAGRAMS`])
+    pub fn set_max_queued_datagrams(&mut self, max_queued_datagrams: usize) -> Result<(), Error> {
+        if max_queued_datagrams == 0 {
+            return Err(Error::InvalidArgument(
+                "max queued datagrams must not be zero".to_string(),
+            ));
+        }
+        self.max_queued_datagrams = max_queued_datagrams;
+        Ok(())
+    }
+
+    /// Counts of the datagrams sent, received and dropped so far.
+    pub fn stats(&self) -> DatagramChannelStats {
+        self.stats
+    }
+
+    /// Consume the channel, returning its underlying connection. Queued and partially read datagrams are discarded.
+    pub fn into_stream(self) -> TcpStream {
+        self.stream
+    }
+}
+
+impl std::fmt::Debug for DatagramChannel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DatagramChannel")
+            .field("max_queued_datagrams", &self.max_queued_datagrams)
+            .field("stats", &self.stats)
+            .finish_non_exhaustive()
+    }
+}
+
+//
+// Tests
+//
+
+#[cfg(test)]
+fn datagram_channel_pair() -> anyhow::Result<(DatagramChannel, DatagramChannel)> {
+    let socket_addr = SocketAddr::from(([127, 0, 0, 1], 0u16));
+    let listener = TcpListener::bind(socket_addr)?;
+    let socket_addr = listener.local_addr()?;
+
+    let stream1 = TcpStream::connect(socket_addr)?;
+    let (stream2, _socket_addr) = listener.accept()?;
+
+    Ok((
+        DatagramChannel::new(stream1)?,
+        DatagramChannel::new(stream2)?,
+    ))
+}
+
+#[cfg(test)]
+fn update_until_received(
+    sender: &mut DatagramChannel,
+    receiver: &mut DatagramChannel,
+    count: u64,
+) -> anyhow::Result<()> {
+    while receiver.stats().datagrams_received < count {
+        sender.update()?;
+        receiver.update()?;
+        std::thread::sleep(std::time::Duration::from_millis(1));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_datagram_channel() -> anyhow::Result<()> {
+    let (mut alice, mut pat) = datagram_channel_pair()?;
+
+    // datagrams arrive whole and in order, including empty and maximum-sized ones
+    let large = vec![0x5au8; MAX_DATAGRAM_SIZE];
+    alice.send(b"hello")?;
+    alice.send(b"")?;
+    alice.send(&large)?;
+    update_until_received(&mut alice, &mut pat, 3)?;
+    assert_eq!(pat.recv(), Some(b"hello".to_vec()));
+    assert_eq!(pat.recv(), Some(Vec::new()));
+    assert_eq!(pat.recv(), Some(large));
+    assert_eq!(pat.recv(), None);
+    assert_eq!(alice.stats().datagrams_sent, 3);
+
+    // and in the other direction
+    pat.send(b"world")?;
+    update_until_received(&mut pat, &mut alice, 1)?;
+    assert_eq!(alice.recv(), Some(b"world".to_vec()));
+
+    // oversized datagrams and queue limits are rejected
+    assert!(matches!(
+        alice.send(&vec![0u8; MAX_DATAGRAM_SIZE + 1]),
+        Err(Error::DatagramTooLarge(_))
+    ));
+    assert!(matches!(
+        alice.set_max_queued_datagrams(0),
+        Err(Error::InvalidArgument(_))
+    ));
+
+    // the oldest queued outgoing datagrams are dropped
+    alice.set_max_queued_datagrams(2)?;
+    for i in 0u8..5 {
+        alice.send(&[i])?;
+    }
+    assert_eq!(alice.stats().outbound_dropped, 3);
+    update_until_received(&mut alice, &mut pat, 5)?;
+    assert_eq!(pat.recv(), Some(vec![3u8]));
+    assert_eq!(pat.recv(), Some(vec![4u8]));
+    assert_eq!(pat.recv(), None);
+
+    // the oldest received datagrams are dropped when not taken
+    pat.set_max_queued_datagrams(2)?;
+    alice.set_max_queued_datagrams(DEFAULT_MAX_QUEUED_DATAGRAMS)?;
+    for i in 0u8..5 {
+        alice.send(&[i])?;
+    }
+    update_until_received(&mut alice, &mut pat, 10)?;
+    assert_eq!(pat.stats().inbound_dropped, 3);
+    assert_eq!(pat.recv(), Some(vec![3u8]));
+    assert_eq!(pat.recv(), Some(vec![4u8]));
+    assert_eq!(pat.recv(), None);
+
+    // closing the connection is reported
+    drop(alice);
+    let result = loop {
+        match pat.update() {
+            Ok(()) => std::thread::sleep(std::time::Duration::from_millis(1)),
+            result => break result,
+        }
+    };
+    assert!(matches!(result, Err(Error::ConnectionClosed)));
+
+    Ok(())
+}
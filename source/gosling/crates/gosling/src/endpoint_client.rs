@@ -2,19 +2,24 @@
 use std::clone::Clone;
 use std::convert::TryInto;
 use std::net::TcpStream;
+use std::sync::Arc;
+use std::time::Duration;
 
 // extern crates
-use bson::doc;
 use bson::spec::BinarySubtype;
 use bson::{Binary, Bson};
-use honk_rpc::honk_rpc::{RequestCookie, Response, Session};
+use honk_rpc::honk_rpc::{ErrorCode, RequestCookie, Response, Session, SessionStats};
 use rand::rngs::OsRng;
 use rand::RngCore;
+use tor_interface::clock::{Clock, SystemClock};
 use tor_interface::tor_crypto::*;
 
 // internal crates
 use crate::ascii_string::*;
+use crate::channel_quota::ChannelQuotaExceeded;
 use crate::gosling::*;
+use crate::protocol::*;
+use crate::timing::*;
 
 //
 // Endpoint Client
@@ -33,59 +38,261 @@ pub enum Error {
 
     #[error("incorrect usage: {0}")]
     IncorrectUsage(String),
+
+    #[error("endpoint server is busy; retry after {0:?}")]
+    ServerBusy(Duration),
+
+    #[error("endpoint server refused the channel: {0}")]
+    QuotaExceeded(ChannelQuotaExceeded),
+
+    #[error("handshake spent longer than {deadline:?} in state {state:?}")]
+    TimedOut {
+        state: EndpointClientState,
+        deadline: Duration,
+    },
+
+    #[error("handshake did not complete within {0:?}")]
+    HandshakeTimedOut(Duration),
+
+    #[error("peer sent nothing for {0:?} while the handshake was waiting on it")]
+    PeerUnresponsive(Duration),
 }
 
-pub(crate) enum EndpointClientEvent {
-    HandshakeCompleted { stream: TcpStream },
+pub enum EndpointClientEvent<RW = TcpStream> {
+    HandshakeCompleted {
+        stream: RW,
+        // the optional protocol features agreed with the server
+        capabilities: Capabilities,
+    },
+    // the handshake completed on a client created with set_rpc_channel(); the
+    // session is kept open for the application's own honk-rpc calls
+    RpcChannelOpened {
+        session: Box<Session<RW>>,
+        // the optional protocol features agreed with the server
+        capabilities: Capabilities,
+    },
 }
 
-#[derive(Debug, PartialEq)]
-enum EndpointClientState {
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EndpointClientState {
     BeginHandshake,
     WaitingForServerCookie,
     WaitingForProofVerification,
     HandshakeComplete,
 }
 
-pub(crate) struct EndpointClient {
+pub struct EndpointClient<RW = TcpStream> {
     // session data
-    rpc: Option<Session<TcpStream>>,
+    rpc: Option<Session<RW>>,
     pub server_service_id: V3OnionServiceId,
     pub requested_channel: AsciiString,
     client_service_id: V3OnionServiceId,
     client_ed25519_private: Ed25519PrivateKey,
+    // committed to in begin_handshake and proven in send_response
+    client_cookie: ClientCookie,
+    // optional protocol features offered to the server
+    capabilities: Capabilities,
 
     // state machine data
     state: EndpointClientState,
+    // fails the handshake if it stalls in any one state
+    state_deadline: StateDeadline<EndpointClientState>,
+    // fails the handshake if the server goes quiet while we wait on it
+    read_idle_deadline: ReadIdleDeadline,
+    // fails the handshake if it has not completed by the given time; the
+    // duration is the timeout it was set from
+    handshake_deadline: Option<(Instant, Duration)>,
+    // source of the time for the state deadline and timings
+    clock: Arc<dyn Clock>,
     begin_handshake_request_cookie: Option<RequestCookie>,
     send_response_request_cookie: Option<RequestCookie>,
+    negotiated_capabilities: Capabilities,
+    // whether the server shares its identity server's onion-service with
+    // other endpoints, so the endpoint must be named in begin_handshake
+    shared: bool,
+    // whether the session is handed over on completion rather than its stream
+    rpc_channel: bool,
+
+    // timing data
+    call_timestamp: Instant,
+    pub(crate) latencies: Vec<(TimedOperation, Duration)>,
+    // session traffic before this handshake began
+    baseline_stats: SessionStats,
+    // handshake traffic once the session has been consumed
+    final_stats: HandshakeStats,
 }
 
-impl EndpointClient {
+impl<RW> EndpointClient<RW>
+where
+    RW: std::io::Read + std::io::Write + Send,
+{
     fn get_state(&self) -> String {
         format!("{{ state: {:?}, begin_handshake_request_cookie: {:?}, send_response_request_cookie: {:?} }}", self.state, self.begin_handshake_request_cookie, self.send_response_request_cookie)
     }
 
     pub fn new(
-        rpc: Session<TcpStream>,
+        rpc: Session<RW>,
         server_service_id: V3OnionServiceId,
         requested_channel: AsciiString,
         client_ed25519_private: Ed25519PrivateKey,
     ) -> Self {
+        let mut client_cookie: ClientCookie = Default::default();
+        OsRng.fill_bytes(&mut client_cookie);
+
+        let baseline_stats = rpc.stats();
         Self {
             rpc: Some(rpc),
             server_service_id,
             requested_channel,
             client_service_id: V3OnionServiceId::from_private_key(&client_ed25519_private),
             client_ed25519_private,
+            client_cookie,
+            capabilities: Capabilities::all(),
 
             state: EndpointClientState::BeginHandshake,
+            state_deadline: StateDeadline::new(EndpointClientState::BeginHandshake),
+            read_idle_deadline: ReadIdleDeadline::new(),
+            handshake_deadline: None,
+            clock: Arc::new(SystemClock),
             begin_handshake_request_cookie: None,
             send_response_request_cookie: None,
+            negotiated_capabilities: Capabilities::empty(),
+            shared: false,
+            rpc_channel: false,
+
+            call_timestamp: Instant::now(),
+            latencies: Default::default(),
+            baseline_stats,
+            final_stats: Default::default(),
+        }
+    }
+
+    // Offer only the given optional protocol features to the server. Must be
+    // called before the first update()
+    pub fn set_capabilities(&mut self, capabilities: Capabilities) -> Result<(), Error> {
+        if self.state != EndpointClientState::BeginHandshake {
+            return Err(Error::IncorrectUsage(
+                "set_capabilities() may only be called before the handshake begins".to_string(),
+            ));
+        }
+        self.capabilities = capabilities;
+        Ok(())
+    }
+
+    // Name the server's endpoint in begin_handshake, as required when it is
+    // served on its identity server's onion-service. Must be called before the
+    // first update()
+    pub fn set_shared(&mut self, shared: bool) -> Result<(), Error> {
+        if self.state != EndpointClientState::BeginHandshake {
+            return Err(Error::IncorrectUsage(
+                "set_shared() may only be called before the handshake begins".to_string(),
+            ));
+        }
+        self.shared = shared;
+        Ok(())
+    }
+
+    // Complete the handshake with an RpcChannelOpened event handing over the
+    // session rather than a HandshakeCompleted event with its stream. Must be
+    // called before the first update()
+    pub fn set_rpc_channel(&mut self, rpc_channel: bool) -> Result<(), Error> {
+        if self.state != EndpointClientState::BeginHandshake {
+            return Err(Error::IncorrectUsage(
+                "set_rpc_channel() may only be called before the handshake begins".to_string(),
+            ));
+        }
+        self.rpc_channel = rpc_channel;
+        Ok(())
+    }
+
+    // The state the handshake is in
+    #[cfg(any(test, feature = "test-utils"))]
+    pub fn state(&self) -> EndpointClientState {
+        self.state
+    }
+
+    // The round-trips and bytes exchanged by this handshake so far; when continuing
+    // over an upgraded identity session the identity handshake is not included
+    pub fn stats(&self) -> HandshakeStats {
+        match self.rpc.as_ref() {
+            Some(rpc) => HandshakeStats::since(rpc.stats(), self.baseline_stats),
+            None => self.final_stats,
         }
     }
 
-    pub fn update(&mut self) -> Result<Option<EndpointClientEvent>, Error> {
+    // Fail the handshake if it spends longer than deadline in any one state
+    pub fn set_state_deadline(&mut self, deadline: Option<Duration>) {
+        self.state_deadline.set_deadline(deadline);
+    }
+
+    // Fail the handshake if the peer sends nothing for timeout while the
+    // handshake is waiting on it
+    pub fn set_read_idle_timeout(&mut self, timeout: Option<Duration>) {
+        self.read_idle_deadline.set_timeout(timeout);
+    }
+
+    // Fail the handshake if it does not complete within timeout of this call,
+    // as measured by the clock set with set_clock()
+    pub fn set_handshake_deadline(&mut self, timeout: Option<Duration>) {
+        self.handshake_deadline = timeout.map(|timeout| (self.clock.now() + timeout, timeout));
+    }
+
+    // Read the time for the state deadline and timings from clock
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    pub fn update(&mut self) -> Result<Option<EndpointClientEvent<RW>>, Error> {
+        self.update_at(self.clock.now())
+    }
+
+    // update() with the given time standing in for the current time
+    pub(crate) fn update_at(
+        &mut self,
+        now: Instant,
+    ) -> Result<Option<EndpointClientEvent<RW>>, Error> {
+        if self.state != EndpointClientState::HandshakeComplete {
+            if let Some((deadline, timeout)) = self.handshake_deadline {
+                if now >= deadline {
+                    return Err(Error::HandshakeTimedOut(timeout));
+                }
+            }
+            if let Some(deadline) = self.state_deadline.observe(self.state, now) {
+                return Err(Error::TimedOut {
+                    state: self.state,
+                    deadline,
+                });
+            }
+            if let Some(timeout) = self.observe_read_idle(now) {
+                return Err(Error::PeerUnresponsive(timeout));
+            }
+        }
+
+        let previous_state = self.state;
+        let result = self.update_state_machine();
+        if self.state != previous_state {
+            tracing::debug!(from = ?previous_state, to = ?self.state, "endpoint client state transition");
+        }
+        self.state_deadline.observe(self.state, now);
+        self.observe_read_idle(now);
+        result
+    }
+
+    fn observe_read_idle(&mut self, now: Instant) -> Option<Duration> {
+        let waiting_on_peer = matches!(
+            self.state,
+            EndpointClientState::WaitingForServerCookie
+                | EndpointClientState::WaitingForProofVerification
+        );
+        let bytes_received = self
+            .rpc
+            .as_ref()
+            .map_or(0, |rpc| rpc.stats().bytes_received);
+        self.read_idle_deadline
+            .observe(waiting_on_peer, bytes_received, now)
+    }
+
+    fn update_state_machine(&mut self) -> Result<Option<EndpointClientEvent<RW>>, Error> {
         if self.state == EndpointClientState::HandshakeComplete {
             return Err(Error::IncorrectUsage("update() may not be called after HandshakeComplete has been returned from previous update() call".to_string()));
         }
@@ -101,16 +308,25 @@ impl EndpointClient {
                 self.send_response_request_cookie,
             ) {
                 (&EndpointClientState::BeginHandshake, None, None) => {
-                    self.begin_handshake_request_cookie = Some(rpc.client_call(
-                        "gosling_endpoint",
-                        "begin_handshake",
-                        0,
-                        doc! {
-                            "version" : bson::Bson::String(GOSLING_PROTOCOL_VERSION.to_string()),
-                            "client_identity" : bson::Bson::String(self.client_service_id.to_string()),
-                            "channel" : bson::Bson::String(self.requested_channel.to_string()),
-                        },
-                    ).unwrap());
+                    self.begin_handshake_request_cookie = Some(
+                        rpc.client_call(
+                            "gosling_endpoint",
+                            "begin_handshake",
+                            0,
+                            to_document(&EndpointBeginHandshakeArgs {
+                                version: GOSLING_PROTOCOL_VERSION.to_string(),
+                                client_identity: Some(self.client_service_id.to_string()),
+                                channel: self.requested_channel.to_string(),
+                                client_cookie: Some(generic_binary(&self.client_cookie)),
+                                capabilities: Some(self.capabilities.names()),
+                                endpoint_service_id: self
+                                    .shared
+                                    .then(|| self.server_service_id.to_string()),
+                            }),
+                        )
+                        .unwrap(),
+                    );
+                    self.call_timestamp = self.clock.now();
                     self.state = EndpointClientState::WaitingForServerCookie;
                     Ok(None)
                 }
@@ -137,13 +353,25 @@ impl EndpointClient {
                                         error_code
                                     )));
                                 }
+                                if error_code == ErrorCode::Runtime(RpcError::Busy as i32) {
+                                    let retry_after =
+                                        parse_busy_retry_after(rpc.client_take_error_data(cookie))?;
+                                    return Err(Error::ServerBusy(retry_after));
+                                }
+                                let error_data = rpc.client_take_error_data(cookie);
                                 return Err(Error::UnexpectedResponseReceived(format!(
                                     "received unexpected rpc error_code: {}",
-                                    error_code
+                                    describe_error_response(&error_code, error_data)
                                 )));
                             }
                             Response::Success { cookie, result } => {
                                 if cookie == begin_handshake_request_cookie {
+                                    self.latencies.push((
+                                        TimedOperation::BeginHandshake,
+                                        self.clock
+                                            .now()
+                                            .saturating_duration_since(self.call_timestamp),
+                                    ));
                                     result
                                 } else {
                                     return Err(Error::UnexpectedResponseReceived(
@@ -159,11 +387,24 @@ impl EndpointClient {
                                 bytes: server_cookie,
                             })) = result.get("server_cookie")
                             {
+                                // servers which predate capability negotiation
+                                // do not reply with their capabilities
+                                self.negotiated_capabilities =
+                                    match parse_capabilities(result.get("capabilities")) {
+                                        Ok(server_capabilities) => server_capabilities
+                                            .unwrap_or_default()
+                                            .intersection(self.capabilities),
+                                        Err(()) => {
+                                            return Err(Error::UnexpectedResponseReceived(
+                                                "capabilities is unexpected bson type".to_string(),
+                                            ))
+                                        }
+                                    };
+
                                 // build arguments for send_response()
 
                                 // client_cookie
-                                let mut client_cookie: ClientCookie = Default::default();
-                                OsRng.fill_bytes(&mut client_cookie);
+                                let client_cookie = self.client_cookie;
 
                                 // client_identity_proof_signature
                                 let server_cookie: ServerCookie =
@@ -189,16 +430,19 @@ impl EndpointClient {
                                     .sign_message(&client_identity_proof);
 
                                 // build our args object for rpc call
-                                let args = doc! {
-                                    "client_cookie" : Bson::Binary(bson::Binary{subtype: BinarySubtype::Generic, bytes: client_cookie.to_vec()}),
-                                    "client_identity_proof_signature" : Bson::Binary(bson::Binary{subtype: BinarySubtype::Generic, bytes: client_identity_proof_signature.to_bytes().to_vec()}),
-                                };
+                                let args = to_document(&EndpointSendResponseArgs {
+                                    client_cookie: generic_binary(&client_cookie),
+                                    client_identity_proof_signature: generic_binary(
+                                        &client_identity_proof_signature.to_bytes(),
+                                    ),
+                                });
 
                                 // make rpc call
                                 self.send_response_request_cookie = Some(
                                     rpc.client_call("gosling_endpoint", "send_response", 0, args)
                                         .unwrap(),
                                 );
+                                self.call_timestamp = self.clock.now();
 
                                 self.state = EndpointClientState::WaitingForProofVerification;
                             } else {
@@ -234,9 +478,15 @@ impl EndpointClient {
                             }
                             Response::Error { cookie, error_code } => {
                                 if cookie == send_response_request_cookie {
+                                    let error_data = rpc.client_take_error_data(cookie);
+                                    if error_code
+                                        == ErrorCode::Runtime(RpcError::QuotaExceeded as i32)
+                                    {
+                                        return Err(parse_quota_exceeded(error_data));
+                                    }
                                     return Err(Error::UnexpectedResponseReceived(format!(
                                         "received unexpected error response; rpc error_code: {}",
-                                        error_code
+                                        describe_error_response(&error_code, error_data)
                                     )));
                                 }
                                 return Err(Error::UnexpectedResponseReceived(format!(
@@ -246,6 +496,12 @@ impl EndpointClient {
                             }
                             Response::Success { cookie, result } => {
                                 if cookie == send_response_request_cookie {
+                                    self.latencies.push((
+                                        TimedOperation::SendResponse,
+                                        self.clock
+                                            .now()
+                                            .saturating_duration_since(self.call_timestamp),
+                                    ));
                                     result
                                 } else {
                                     return Err(Error::UnexpectedResponseReceived(
@@ -258,9 +514,18 @@ impl EndpointClient {
                         if let Some(Bson::Document(result)) = result {
                             if result.is_empty() {
                                 self.state = EndpointClientState::HandshakeComplete;
-                                let stream = std::mem::take(&mut self.rpc).unwrap().into_stream();
+                                self.final_stats = self.stats();
+                                let session = std::mem::take(&mut self.rpc).unwrap();
+                                if self.rpc_channel {
+                                    return Ok(Some(EndpointClientEvent::RpcChannelOpened {
+                                        session: Box::new(session),
+                                        capabilities: self.negotiated_capabilities,
+                                    }));
+                                }
+                                let stream = session.into_stream();
                                 return Ok(Some(EndpointClientEvent::HandshakeCompleted {
                                     stream,
+                                    capabilities: self.negotiated_capabilities,
                                 }));
                             } else {
                                 return Err(Error::UnexpectedResponseReceived(format!(
@@ -284,3 +549,34 @@ impl EndpointClient {
         }
     }
 }
+
+// an endpoint server's quota exceeded error carries the exceeded quota
+fn parse_quota_exceeded(data: Option<Bson>) -> Error {
+    let exceeded = match data {
+        Some(Bson::Document(data)) => {
+            from_document(data).and_then(ChannelQuotaExceeded::from_error_data)
+        }
+        _ => None,
+    };
+    match exceeded {
+        Some(exceeded) => Error::QuotaExceeded(exceeded),
+        None => Error::UnexpectedResponseReceived(
+            "received quota exceeded error response with invalid data".to_string(),
+        ),
+    }
+}
+
+// an endpoint server's busy error carries how long to wait before retrying
+fn parse_busy_retry_after(data: Option<Bson>) -> Result<Duration, Error> {
+    match data {
+        Some(Bson::Document(data)) => match data.get_i64("retry_after") {
+            Ok(retry_after) if retry_after >= 0 => Ok(Duration::from_secs(retry_after as u64)),
+            _ => Err(Error::UnexpectedResponseReceived(
+                "received busy error response with invalid retry_after".to_string(),
+            )),
+        },
+        _ => Err(Error::UnexpectedResponseReceived(
+            "received busy error response without retry_after".to_string(),
+        )),
+    }
+}
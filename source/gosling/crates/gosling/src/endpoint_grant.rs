@@ -0,0 +1,333 @@
+// standard
+use std::convert::TryInto;
+
+// extern crates
+#[cfg(test)]
+use anyhow::bail;
+use data_encoding::BASE64URL_NOPAD;
+use tor_interface::tor_crypto::*;
+
+// internal crates
+use crate::ascii_string::*;
+
+/// The error type for the [`EndpointGrant`] type.
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    /// An invalid argument was provided to a function
+    #[error("invalid argument: {0}")]
+    InvalidArgument(String),
+
+    /// A string could not be parsed as an endpoint grant
+    #[error("failed to parse endpoint grant: {0}")]
+    ParseError(String),
+
+    /// An endpoint grant was serialised with an unknown format version
+    #[error("unsupported endpoint grant version: {0}")]
+    UnsupportedVersion(u8),
+
+    /// An underlying `tor_interface::tor_crypto::Error`
+    #[error(transparent)]
+    TorCrypto(#[from] tor_interface::tor_crypto::Error),
+}
+
+// prefix of the string representation of an endpoint grant
+const ENDPOINT_GRANT_PREFIX: &str = "gosling-endpoint-grant:";
+// current serialisation format version
+const ENDPOINT_GRANT_VERSION: u8 = 1u8;
+// version byte followed by three ed25519 public keys and an x25519 private key
+const ENDPOINT_GRANT_HEADER_SIZE: usize = 1 + 3 * ED25519_PUBLIC_KEY_SIZE + X25519_PRIVATE_KEY_SIZE;
+
+/// A portable record of the access to an endpoint server granted to an identity client by an identity server.
+///
+/// An `EndpointGrant` contains everything an endpoint client needs to reconnect to an endpoint server, and can be serialised to and parsed from a compact, versioned string so that applications may back up grants or transfer them between devices. The string representation contains the client's x25519 private key, so it must be treated as a secret.
+///
+/// The string representation has the form `gosling-endpoint-grant:<base64url>` where the base64url-encoded (without padding) payload is:
+/// ```text
+/// version (1 byte, currently 0x01)
+/// identity server ed25519 public key (32 bytes)
+/// client ed25519 public key (32 bytes)
+/// endpoint server ed25519 public key (32 bytes)
+/// client-auth x25519 private key (32 bytes)
+/// endpoint name (remaining bytes, ASCII)
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct EndpointGrant {
+    identity_service_id: V3OnionServiceId,
+    client_service_id: V3OnionServiceId,
+    endpoint_service_id: V3OnionServiceId,
+    endpoint_name: AsciiString,
+    client_auth_private_key: X25519PrivateKey,
+}
+
+impl EndpointGrant {
+    /// Construct a new `EndpointGrant`. The arguments typically come from a completed identity handshake (see [`crate::context::ContextEvent::IdentityClientHandshakeCompleted`]).
+    ///
+    /// # Parameters
+    /// - `identity_service_id`: the onion-service service-id of the identity server which issued this grant
+    /// - `client_service_id`: the onion-service service-id of the identity client this grant was issued to
+    /// - `endpoint_service_id`: the onion-service service-id of the granted endpoint server
+    /// - `endpoint_name`: the ASCII-encoded name of the granted endpoint server
+    /// - `client_auth_private_key`: the x25519 private key required to access the granted endpoint server
+    pub fn new(
+        identity_service_id: V3OnionServiceId,
+        client_service_id: V3OnionServiceId,
+        endpoint_service_id: V3OnionServiceId,
+        endpoint_name: String,
+        client_auth_private_key: X25519PrivateKey,
+    ) -> Result<Self, Error> {
+        let endpoint_name = match AsciiString::new(endpoint_name) {
+            Ok(endpoint_name) => endpoint_name,
+            Err(_) => {
+                return Err(Error::InvalidArgument(
+                    "endpoint_name must be an ASCII string".to_string(),
+                ))
+            }
+        };
+
+        Ok(Self {
+            identity_service_id,
+            client_service_id,
+            endpoint_service_id,
+            endpoint_name,
+            client_auth_private_key,
+        })
+    }
+
+    /// Parse an `EndpointGrant` from a string created by [`EndpointGrant::to_string()`].
+    pub fn from_string(grant: &str) -> Result<Self, Error> {
+        let payload = match grant.strip_prefix(ENDPOINT_GRANT_PREFIX) {
+            Some(payload) => payload,
+            None => {
+                return Err(Error::ParseError(format!(
+                    "expects string that begins with '{}'",
+                    ENDPOINT_GRANT_PREFIX
+                )))
+            }
+        };
+        let payload = match BASE64URL_NOPAD.decode(payload.as_bytes()) {
+            Ok(payload) => payload,
+            Err(_) => {
+                return Err(Error::ParseError(
+                    "could not decode payload as base64url".to_string(),
+                ))
+            }
+        };
+
+        match payload.first() {
+            Some(&ENDPOINT_GRANT_VERSION) => (),
+            Some(version) => return Err(Error::UnsupportedVersion(*version)),
+            None => return Err(Error::ParseError("payload is empty".to_string())),
+        }
+        if payload.len() < ENDPOINT_GRANT_HEADER_SIZE {
+            return Err(Error::ParseError(format!(
+                "expects payload of at least '{}' bytes; received '{}'",
+                ENDPOINT_GRANT_HEADER_SIZE,
+                payload.len()
+            )));
+        }
+
+        // the length check above guarantees these slices are correctly sized
+        let mut offset = 1usize;
+        let mut next_service_id = || -> Result<V3OnionServiceId, Error> {
+            let public_key = Ed25519PublicKey::from_raw(
+                payload[offset..offset + ED25519_PUBLIC_KEY_SIZE]
+                    .try_into()
+                    .unwrap(),
+            )?;
+            offset += ED25519_PUBLIC_KEY_SIZE;
+            Ok(V3OnionServiceId::from_public_key(&public_key))
+        };
+        let identity_service_id = next_service_id()?;
+        let client_service_id = next_service_id()?;
+        let endpoint_service_id = next_service_id()?;
+
+        let offset = ENDPOINT_GRANT_HEADER_SIZE - X25519_PRIVATE_KEY_SIZE;
+        let client_auth_private_key = X25519PrivateKey::from_raw(
+            payload[offset..ENDPOINT_GRANT_HEADER_SIZE]
+                .try_into()
+                .unwrap(),
+        )?;
+
+        let endpoint_name = match String::from_utf8(payload[ENDPOINT_GRANT_HEADER_SIZE..].to_vec())
+        {
+            Ok(endpoint_name) => endpoint_name,
+            Err(_) => {
+                return Err(Error::ParseError(
+                    "endpoint name is not valid UTF-8".to_string(),
+                ))
+            }
+        };
+
+        Self::new(
+            identity_service_id,
+            client_service_id,
+            endpoint_service_id,
+            endpoint_name,
+            client_auth_private_key,
+        )
+    }
+
+    /// Determine whether this grant was issued to the given client for the given endpoint server. Endpoint servers may use this to validate a client presenting a previously issued grant.
+    ///
+    /// # Parameters
+    /// - `endpoint_service_id`: the onion-service service-id of the endpoint server
+    /// - `client_service_id`: the onion-service service-id of the connecting client
+    /// - `client_auth_public_key`: the x25519 public key used to encrypt the endpoint server's onion-service descriptor
+    pub fn verify(
+        &self,
+        endpoint_service_id: &V3OnionServiceId,
+        client_service_id: &V3OnionServiceId,
+        client_auth_public_key: &X25519PublicKey,
+    ) -> bool {
+        // every member is compared so the time taken does not reveal which differs
+        (self.endpoint_service_id.ct_eq(endpoint_service_id)
+            & self.client_service_id.ct_eq(client_service_id)
+            & X25519PublicKey::from_private_key(&self.client_auth_private_key)
+                .ct_eq(client_auth_public_key))
+        .into()
+    }
+
+    /// The onion-service service-id of the identity server which issued this grant
+    pub fn identity_service_id(&self) -> &V3OnionServiceId {
+        &self.identity_service_id
+    }
+
+    /// The onion-service service-id of the identity client this grant was issued to
+    pub fn client_service_id(&self) -> &V3OnionServiceId {
+        &self.client_service_id
+    }
+
+    /// The onion-service service-id of the granted endpoint server
+    pub fn endpoint_service_id(&self) -> &V3OnionServiceId {
+        &self.endpoint_service_id
+    }
+
+    /// The ASCII-encoded name of the granted endpoint server
+    pub fn endpoint_name(&self) -> &str {
+        &self.endpoint_name
+    }
+
+    /// The x25519 private key required to access the granted endpoint server
+    pub fn client_auth_private_key(&self) -> &X25519PrivateKey {
+        &self.client_auth_private_key
+    }
+}
+
+impl std::fmt::Display for EndpointGrant {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut payload: Vec<u8> =
+            Vec::with_capacity(ENDPOINT_GRANT_HEADER_SIZE + self.endpoint_name.len());
+        payload.push(ENDPOINT_GRANT_VERSION);
+        for service_id in [
+            &self.identity_service_id,
+            &self.client_service_id,
+            &self.endpoint_service_id,
+        ] {
+            // a V3OnionServiceId is always derived from a valid public key
+            let public_key = Ed25519PublicKey::from_service_id(service_id).unwrap();
+            payload.extend_from_slice(public_key.as_bytes());
+        }
+        payload.extend_from_slice(&self.client_auth_private_key.to_bytes());
+        payload.extend_from_slice(self.endpoint_name.as_bytes());
+
+        write!(
+            f,
+            "{}{}",
+            ENDPOINT_GRANT_PREFIX,
+            BASE64URL_NOPAD.encode(&payload)
+        )
+    }
+}
+
+#[test]
+fn test_endpoint_grant() -> anyhow::Result<()> {
+    let identity_service_id = V3OnionServiceId::from_private_key(&Ed25519PrivateKey::generate());
+    let client_service_id = V3OnionServiceId::from_private_key(&Ed25519PrivateKey::generate());
+    let endpoint_service_id = V3OnionServiceId::from_private_key(&Ed25519PrivateKey::generate());
+    let client_auth_private_key = X25519PrivateKey::generate();
+    let client_auth_public_key = X25519PublicKey::from_private_key(&client_auth_private_key);
+
+    let grant = EndpointGrant::new(
+        identity_service_id.clone(),
+        client_service_id.clone(),
+        endpoint_service_id.clone(),
+        "test_endpoint".to_string(),
+        client_auth_private_key.clone(),
+    )?;
+
+    // round-trip
+    let grant_string = grant.to_string();
+    println!("grant: {}", grant_string);
+    let parsed_grant = EndpointGrant::from_string(&grant_string)?;
+    assert_eq!(grant, parsed_grant);
+    assert_eq!(*parsed_grant.identity_service_id(), identity_service_id);
+    assert_eq!(parsed_grant.endpoint_name(), "test_endpoint");
+    assert_eq!(
+        *parsed_grant.client_auth_private_key(),
+        client_auth_private_key
+    );
+
+    // verification
+    assert!(parsed_grant.verify(
+        &endpoint_service_id,
+        &client_service_id,
+        &client_auth_public_key
+    ));
+    assert!(!parsed_grant.verify(
+        &identity_service_id,
+        &client_service_id,
+        &client_auth_public_key
+    ));
+    assert!(!parsed_grant.verify(
+        &endpoint_service_id,
+        &client_service_id,
+        &X25519PublicKey::from_private_key(&X25519PrivateKey::generate())
+    ));
+
+    // non-ascii endpoint names are rejected
+    if EndpointGrant::new(
+        identity_service_id,
+        client_service_id,
+        endpoint_service_id,
+        "heart ❤".to_string(),
+        client_auth_private_key,
+    )
+    .is_ok()
+    {
+        bail!("non-ascii endpoint name accepted");
+    }
+
+    // malformed grants are rejected
+    let invalid_grants = [
+        "".to_string(),
+        "gosling-endpoint-grant:".to_string(),
+        "gosling-endpoint-grant:!!!!".to_string(),
+        grant_string.replace("gosling-endpoint-grant:", "gosling-grant:"),
+        grant_string[..grant_string.len() - 40].to_string(),
+    ];
+    for invalid_grant in invalid_grants {
+        if let Ok(grant) = EndpointGrant::from_string(&invalid_grant) {
+            bail!("invalid grant '{}' parsed as {:?}", invalid_grant, grant);
+        }
+    }
+
+    // unknown versions are rejected
+    let mut payload = BASE64URL_NOPAD.decode(
+        grant_string
+            .strip_prefix(ENDPOINT_GRANT_PREFIX)
+            .unwrap()
+            .as_bytes(),
+    )?;
+    payload[0] = 2u8;
+    let future_grant = format!(
+        "{}{}",
+        ENDPOINT_GRANT_PREFIX,
+        BASE64URL_NOPAD.encode(&payload)
+    );
+    match EndpointGrant::from_string(&future_grant) {
+        Err(Error::UnsupportedVersion(2u8)) => (),
+        result => bail!("unexpected result: {:?}", result),
+    }
+
+    Ok(())
+}
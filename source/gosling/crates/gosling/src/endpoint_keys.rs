@@ -0,0 +1,54 @@
+// extern crates
+use tor_interface::tor_crypto::*;
+
+// internal crates
+use crate::ascii_string::AsciiString;
+
+// separates endpoint keys from any other keys derived from an identity key
+const ENDPOINT_KEY_DERIVATION_SALT: &[u8] = b"gosling-endpoint-private-key-v1";
+
+/// Derive the private key of the endpoint server an identity server grants to a client, as is done by a [`Context`](crate::context::Context) whose identity server derives endpoint keys; see [`Context::identity_server_set_derive_endpoint_keys()`](crate::context::Context::identity_server_set_derive_endpoint_keys).
+///
+/// The same identity key, client and endpoint always derive the same endpoint key, so a server which has lost its endpoint keys may recover them from its identity key and the clients and endpoints it has granted. Neither the identity key nor any other endpoint key can be learned from an endpoint key.
+///
+/// # Parameters
+/// - `identity_private_key`: the private key of the identity server granting the endpoint
+/// - `client_service_id`: the identity of the client the endpoint is granted to
+/// - `endpoint_name`: the name of the granted endpoint
+pub fn derive_endpoint_private_key(
+    identity_private_key: &Ed25519PrivateKey,
+    client_service_id: &V3OnionServiceId,
+    endpoint_name: &AsciiString,
+) -> Ed25519PrivateKey {
+    // service ids are of a fixed length, so no two clients and endpoints share
+    // the same info
+    let mut info = client_service_id.as_bytes().to_vec();
+    info.extend_from_slice(endpoint_name.as_bytes());
+    identity_private_key.derive(ENDPOINT_KEY_DERIVATION_SALT, &info)
+}
+
+#[test]
+fn test_derive_endpoint_private_key() -> anyhow::Result<()> {
+    let identity_private_key = Ed25519PrivateKey::generate();
+    let alice = V3OnionServiceId::from_private_key(&Ed25519PrivateKey::generate());
+    let pat = V3OnionServiceId::from_private_key(&Ed25519PrivateKey::generate());
+    let chat: AsciiString = "chat".parse()?;
+    let files: AsciiString = "files".parse()?;
+
+    // endpoint keys are recovered from the same inputs
+    let endpoint_private_key = derive_endpoint_private_key(&identity_private_key, &alice, &chat);
+    assert!(
+        endpoint_private_key == derive_endpoint_private_key(&identity_private_key, &alice, &chat)
+    );
+
+    // each identity, client and endpoint has its own key
+    for other_endpoint_private_key in [
+        derive_endpoint_private_key(&identity_private_key, &pat, &chat),
+        derive_endpoint_private_key(&identity_private_key, &alice, &files),
+        derive_endpoint_private_key(&Ed25519PrivateKey::generate(), &alice, &chat),
+    ] {
+        assert!(endpoint_private_key != other_endpoint_private_key);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,290 @@
+// standard
+use std::convert::TryInto;
+use std::time::Duration;
+
+// extern crates
+#[cfg(test)]
+use anyhow::bail;
+use data_encoding::BASE64URL_NOPAD;
+use tor_interface::tor_crypto::*;
+
+// internal crates
+use crate::gosling::{SystemTime, UNIX_EPOCH};
+
+/// The error type for the [`EndpointRevocation`] type.
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    /// A string could not be parsed as an endpoint revocation
+    #[error("failed to parse endpoint revocation: {0}")]
+    ParseError(String),
+
+    /// An endpoint revocation was serialised with an unknown format version
+    #[error("unsupported endpoint revocation version: {0}")]
+    UnsupportedVersion(u8),
+
+    /// An endpoint revocation's signature was not made with its endpoint server's key
+    #[error("endpoint revocation has an invalid signature")]
+    InvalidSignature,
+
+    /// An underlying `tor_interface::tor_crypto::Error`
+    #[error(transparent)]
+    TorCrypto(#[from] tor_interface::tor_crypto::Error),
+}
+
+// prefix of the string representation of an endpoint revocation
+const ENDPOINT_REVOCATION_PREFIX: &str = "gosling-endpoint-revocation:";
+// current serialisation format version
+const ENDPOINT_REVOCATION_VERSION: u8 = 1u8;
+// version byte followed by two ed25519 public keys and a timestamp
+const ENDPOINT_REVOCATION_SIGNED_SIZE: usize = 1 + 2 * ED25519_PUBLIC_KEY_SIZE + 8;
+const ENDPOINT_REVOCATION_SIZE: usize = ENDPOINT_REVOCATION_SIGNED_SIZE + ED25519_SIGNATURE_SIZE;
+// prepended to the signed bytes so the signature cannot be mistaken for a client proof
+const ENDPOINT_REVOCATION_DOMAIN_SEPARATOR: &[u8] = b"gosling-endpoint-revocation\0";
+
+/// A notice from an endpoint server that it has revoked the access it previously granted to an endpoint client.
+///
+/// Endpoint servers create an `EndpointRevocation` when revoking access with [`Context::endpoint_server_revoke()`](crate::context::Context::endpoint_server_revoke) and send its string representation, terminated by a newline, over any of the client's live channels. The revocation is signed with the endpoint server's onion-service key, so an endpoint client receiving one may check with [`EndpointRevocation::from_string()`] that it was issued by the endpoint server it names and hand it to [`Context::endpoint_client_handle_revocation()`](crate::context::Context::endpoint_client_handle_revocation).
+///
+/// The string representation has the form `gosling-endpoint-revocation:<base64url>` where the base64url-encoded (without padding) payload is:
+/// ```text
+/// version (1 byte, currently 0x01)
+/// endpoint server ed25519 public key (32 bytes)
+/// client ed25519 public key (32 bytes)
+/// revocation time in seconds since the unix epoch (8 bytes, big-endian)
+/// ed25519 signature of "gosling-endpoint-revocation\0" followed by the above (64 bytes)
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct EndpointRevocation {
+    endpoint_service_id: V3OnionServiceId,
+    client_service_id: V3OnionServiceId,
+    revoked_at: u64,
+    signature: [u8; ED25519_SIGNATURE_SIZE],
+}
+
+impl EndpointRevocation {
+    /// Construct a new `EndpointRevocation` revoking the given client's access at the current time.
+    ///
+    /// # Parameters
+    /// - `endpoint_private_key`: the ed25519 private key of the endpoint server's onion-service
+    /// - `client_service_id`: the onion-service service-id of the client whose access is revoked
+    pub fn new(
+        endpoint_private_key: &Ed25519PrivateKey,
+        client_service_id: V3OnionServiceId,
+    ) -> Self {
+        let revoked_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0u64, |elapsed| elapsed.as_secs());
+        let endpoint_service_id = V3OnionServiceId::from_private_key(endpoint_private_key);
+        let signed = Self::signed_bytes(&endpoint_service_id, &client_service_id, revoked_at);
+        let signature = endpoint_private_key.sign_message(&signed).to_bytes();
+
+        Self {
+            endpoint_service_id,
+            client_service_id,
+            revoked_at,
+            signature,
+        }
+    }
+
+    // the domain separator followed by the signed members of the payload
+    fn signed_bytes(
+        endpoint_service_id: &V3OnionServiceId,
+        client_service_id: &V3OnionServiceId,
+        revoked_at: u64,
+    ) -> Vec<u8> {
+        let mut signed: Vec<u8> = Vec::with_capacity(
+            ENDPOINT_REVOCATION_DOMAIN_SEPARATOR.len() + ENDPOINT_REVOCATION_SIGNED_SIZE,
+        );
+        signed.extend_from_slice(ENDPOINT_REVOCATION_DOMAIN_SEPARATOR);
+        signed.push(ENDPOINT_REVOCATION_VERSION);
+        for service_id in [endpoint_service_id, client_service_id] {
+            // a V3OnionServiceId is always derived from a valid public key
+            let public_key = Ed25519PublicKey::from_service_id(service_id).unwrap();
+            signed.extend_from_slice(public_key.as_bytes());
+        }
+        signed.extend_from_slice(&revoked_at.to_be_bytes());
+        signed
+    }
+
+    /// Parse an `EndpointRevocation` from a string created by [`EndpointRevocation::to_string()`], verifying it is signed by the endpoint server it names. A trailing newline is ignored.
+    pub fn from_string(revocation: &str) -> Result<Self, Error> {
+        let revocation = revocation.strip_suffix('\n').unwrap_or(revocation);
+        let payload = match revocation.strip_prefix(ENDPOINT_REVOCATION_PREFIX) {
+            Some(payload) => payload,
+            None => {
+                return Err(Error::ParseError(format!(
+                    "expects string that begins with '{}'",
+                    ENDPOINT_REVOCATION_PREFIX
+                )))
+            }
+        };
+        let payload = match BASE64URL_NOPAD.decode(payload.as_bytes()) {
+            Ok(payload) => payload,
+            Err(_) => {
+                return Err(Error::ParseError(
+                    "could not decode payload as base64url".to_string(),
+                ))
+            }
+        };
+
+        match payload.first() {
+            Some(&ENDPOINT_REVOCATION_VERSION) => (),
+            Some(version) => return Err(Error::UnsupportedVersion(*version)),
+            None => return Err(Error::ParseError("payload is empty".to_string())),
+        }
+        if payload.len() != ENDPOINT_REVOCATION_SIZE {
+            return Err(Error::ParseError(format!(
+                "expects payload of '{}' bytes; received '{}'",
+                ENDPOINT_REVOCATION_SIZE,
+                payload.len()
+            )));
+        }
+
+        // the length check above guarantees these slices are correctly sized
+        let mut offset = 1usize;
+        let mut next_public_key = || -> Result<Ed25519PublicKey, Error> {
+            let public_key = Ed25519PublicKey::from_raw(
+                payload[offset..offset + ED25519_PUBLIC_KEY_SIZE]
+                    .try_into()
+                    .unwrap(),
+            )?;
+            offset += ED25519_PUBLIC_KEY_SIZE;
+            Ok(public_key)
+        };
+        let endpoint_public_key = next_public_key()?;
+        let client_public_key = next_public_key()?;
+        let revoked_at = u64::from_be_bytes(
+            payload[ENDPOINT_REVOCATION_SIGNED_SIZE - 8..ENDPOINT_REVOCATION_SIGNED_SIZE]
+                .try_into()
+                .unwrap(),
+        );
+        let signature: [u8; ED25519_SIGNATURE_SIZE] = payload[ENDPOINT_REVOCATION_SIGNED_SIZE..]
+            .try_into()
+            .unwrap();
+
+        let endpoint_service_id = V3OnionServiceId::from_public_key(&endpoint_public_key);
+        let client_service_id = V3OnionServiceId::from_public_key(&client_public_key);
+        let signed = Self::signed_bytes(&endpoint_service_id, &client_service_id, revoked_at);
+        if !Ed25519Signature::from_raw(&signature)?.verify(&signed, &endpoint_public_key) {
+            return Err(Error::InvalidSignature);
+        }
+
+        Ok(Self {
+            endpoint_service_id,
+            client_service_id,
+            revoked_at,
+            signature,
+        })
+    }
+
+    /// The onion-service service-id of the endpoint server which revoked access
+    pub fn endpoint_service_id(&self) -> &V3OnionServiceId {
+        &self.endpoint_service_id
+    }
+
+    /// The onion-service service-id of the client whose access was revoked
+    pub fn client_service_id(&self) -> &V3OnionServiceId {
+        &self.client_service_id
+    }
+
+    /// The time at which access was revoked, according to the endpoint server's clock
+    pub fn revoked_at(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.revoked_at)
+    }
+}
+
+impl std::fmt::Display for EndpointRevocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let signed = Self::signed_bytes(
+            &self.endpoint_service_id,
+            &self.client_service_id,
+            self.revoked_at,
+        );
+        let mut payload: Vec<u8> = Vec::with_capacity(ENDPOINT_REVOCATION_SIZE);
+        payload.extend_from_slice(&signed[ENDPOINT_REVOCATION_DOMAIN_SEPARATOR.len()..]);
+        payload.extend_from_slice(&self.signature);
+
+        write!(
+            f,
+            "{}{}",
+            ENDPOINT_REVOCATION_PREFIX,
+            BASE64URL_NOPAD.encode(&payload)
+        )
+    }
+}
+
+#[test]
+fn test_endpoint_revocation() -> anyhow::Result<()> {
+    let endpoint_private_key = Ed25519PrivateKey::generate();
+    let endpoint_service_id = V3OnionServiceId::from_private_key(&endpoint_private_key);
+    let client_service_id = V3OnionServiceId::from_private_key(&Ed25519PrivateKey::generate());
+
+    let revocation = EndpointRevocation::new(&endpoint_private_key, client_service_id.clone());
+
+    // round-trip, with or without the terminating newline
+    let revocation_string = revocation.to_string();
+    println!("revocation: {}", revocation_string);
+    let parsed_revocation = EndpointRevocation::from_string(&revocation_string)?;
+    assert_eq!(revocation, parsed_revocation);
+    assert_eq!(
+        EndpointRevocation::from_string(&format!("{}\n", revocation_string))?,
+        revocation
+    );
+    assert_eq!(
+        *parsed_revocation.endpoint_service_id(),
+        endpoint_service_id
+    );
+    assert_eq!(*parsed_revocation.client_service_id(), client_service_id);
+    assert!(parsed_revocation.revoked_at() <= SystemTime::now());
+
+    // malformed revocations are rejected
+    let invalid_revocations = [
+        "".to_string(),
+        "gosling-endpoint-revocation:".to_string(),
+        "gosling-endpoint-revocation:!!!!".to_string(),
+        revocation_string.replace("gosling-endpoint-revocation:", "gosling-revocation:"),
+        revocation_string[..revocation_string.len() - 4].to_string(),
+    ];
+    for invalid_revocation in invalid_revocations {
+        if let Ok(revocation) = EndpointRevocation::from_string(&invalid_revocation) {
+            bail!(
+                "invalid revocation '{}' parsed as {:?}",
+                invalid_revocation,
+                revocation
+            );
+        }
+    }
+
+    // revocations signed by any key other than the endpoint server's are rejected
+    let mut payload = BASE64URL_NOPAD.decode(
+        revocation_string
+            .strip_prefix(ENDPOINT_REVOCATION_PREFIX)
+            .unwrap()
+            .as_bytes(),
+    )?;
+    let forged = EndpointRevocation::new(&Ed25519PrivateKey::generate(), client_service_id);
+    payload[ENDPOINT_REVOCATION_SIGNED_SIZE..].copy_from_slice(&forged.signature);
+    let forged_revocation = format!(
+        "{}{}",
+        ENDPOINT_REVOCATION_PREFIX,
+        BASE64URL_NOPAD.encode(&payload)
+    );
+    match EndpointRevocation::from_string(&forged_revocation) {
+        Err(Error::InvalidSignature) => (),
+        result => bail!("unexpected result: {:?}", result),
+    }
+
+    // unknown versions are rejected
+    payload[0] = 2u8;
+    let future_revocation = format!(
+        "{}{}",
+        ENDPOINT_REVOCATION_PREFIX,
+        BASE64URL_NOPAD.encode(&payload)
+    );
+    match EndpointRevocation::from_string(&future_revocation) {
+        Err(Error::UnsupportedVersion(2u8)) => (),
+        result => bail!("unexpected result: {:?}", result),
+    }
+
+    Ok(())
+}
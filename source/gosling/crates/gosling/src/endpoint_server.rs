@@ -2,19 +2,26 @@
 use std::clone::Clone;
 use std::convert::TryInto;
 use std::net::TcpStream;
+use std::sync::{Arc, PoisonError};
+use std::time::Duration;
 
 // extern crates
 use bson::doc;
-use bson::spec::BinarySubtype;
-use bson::{Binary, Bson};
-use honk_rpc::honk_rpc::{ApiSet, ErrorCode, RequestCookie, Session};
+use bson::Bson;
+use honk_rpc::honk_rpc::{ApiSet, ErrorCode, RequestCookie, Session, SessionStats};
 use rand::rngs::OsRng;
 use rand::RngCore;
+use tor_interface::clock::{Clock, SystemClock};
 use tor_interface::tor_crypto::*;
 
 // internal crates
 use crate::ascii_string::*;
+use crate::bans::{Ban, BanList};
+use crate::channel_pattern::*;
+use crate::channel_quota::*;
 use crate::gosling::*;
+use crate::protocol::*;
+use crate::timing::HandshakeStats;
 
 //
 // Endpoint Server
@@ -33,9 +40,33 @@ pub enum Error {
 
     #[error("client sent invalid request")]
     BadClient,
+
+    #[error(
+        "client requested a channel name {0} bytes long; at most {} bytes are allowed",
+        MAX_CHANNEL_NAME_LENGTH
+    )]
+    ChannelNameTooLong(usize),
+
+    #[error("client replayed a previously seen begin_handshake request")]
+    ReplayedHandshake,
+
+    #[error("client called {0}() out of order")]
+    OutOfOrderCall(String),
+
+    #[error("client called {0}() more than once")]
+    DuplicateCall(String),
+
+    #[error("handshake spent longer than {deadline:?} in state {state:?}")]
+    TimedOut {
+        state: EndpointServerState,
+        deadline: Duration,
+    },
+
+    #[error("peer sent nothing for {0:?} while the handshake was waiting on it")]
+    PeerUnresponsive(Duration),
 }
 
-pub(crate) enum EndpointServerEvent {
+pub enum EndpointServerEvent<RW = TcpStream> {
     ChannelRequestReceived {
         client_service_id: V3OnionServiceId,
         requested_channel: AsciiString,
@@ -44,7 +75,19 @@ pub(crate) enum EndpointServerEvent {
     HandshakeCompleted {
         client_service_id: V3OnionServiceId,
         channel_name: AsciiString,
-        stream: TcpStream,
+        stream: RW,
+        // the optional protocol features agreed with the client
+        capabilities: Capabilities,
+    },
+    // endpoint server has accepted a channel request for one of the channels
+    // set with set_rpc_channels(); the session is kept open for the
+    // application's own honk-rpc calls
+    RpcChannelOpened {
+        client_service_id: V3OnionServiceId,
+        channel_name: AsciiString,
+        session: Box<Session<RW>>,
+        // the optional protocol features agreed with the client
+        capabilities: Capabilities,
     },
     // endpoint server has reject an incoming channel request
     HandshakeRejected {
@@ -52,34 +95,104 @@ pub(crate) enum EndpointServerEvent {
         client_requested_channel_valid: bool,
         client_proof_signature_valid: bool,
     },
+    // endpoint server has told the client it is busy and to retry later
+    HandshakeBusy {
+        client_service_id: V3OnionServiceId,
+        requested_channel: AsciiString,
+        retry_after: Duration,
+    },
+    // endpoint server has refused a proven client which exceeded its channel
+    // quota
+    HandshakeQuotaExceeded {
+        client_service_id: V3OnionServiceId,
+        requested_channel: AsciiString,
+        exceeded: ChannelQuotaExceeded,
+    },
+    // endpoint server has refused a proven client which is on the ban list
+    HandshakeBanned {
+        client_service_id: V3OnionServiceId,
+        requested_channel: AsciiString,
+        ban: Ban,
+    },
 }
 
-#[derive(Debug, PartialEq)]
-enum EndpointServerState {
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EndpointServerState {
     // valid/expected states
     WaitingForBeginHandshake,
     ValidatingChannelRequest,
     ChannelRequestValidated,
     WaitingForSendResponse,
     HandledSendResponse,
+    BusyResponseReady,
+    BusyResponseSent,
     HandshakeComplete,
     // failure state
     HandshakeFailed,
 }
 
-pub(crate) struct EndpointServer {
+pub struct EndpointServer<RW = TcpStream> {
     // Session Data
-    rpc: Option<Session<TcpStream>>,
+    rpc: Option<Session<RW>>,
     pub server_identity: V3OnionServiceId,
     allowed_client_identity: V3OnionServiceId,
+    // channels matching any of these patterns are accepted without
+    // returning a ChannelRequestReceived event
+    channel_patterns: Vec<ChannelPattern>,
+    // accept begin_handshake calls from peers running the legacy
+    // protocol revision which omit the client_identity argument
+    legacy_handshakes_allowed: bool,
+    // when set, channel requests are answered with a busy error
+    // telling the client how long to wait before retrying
+    busy_retry_after: Option<Duration>,
+    // client cookies committed to by previous begin_handshake calls
+    replay_cache: Option<SharedReplayCache>,
+    // per-client limits shared with the context's other endpoint servers
+    channel_quota: Option<SharedChannelQuotaTracker>,
+    // proven clients on the ban list are refused
+    ban_list: Option<BanList>,
+    // optional protocol features supported by this server
+    capabilities: Capabilities,
+    // when set, the server is reached through its identity server's
+    // onion-service and serves each of these (endpoint service id, allowed
+    // client) pairs; the client names its endpoint in begin_handshake
+    shared_endpoints: Option<Vec<(V3OnionServiceId, V3OnionServiceId)>>,
+    // accepted channels with these names hand over the session rather than
+    // its stream
+    rpc_channels: Vec<AsciiString>,
 
     // State Machine Data
     state: EndpointServerState,
+    // fails the handshake if it stalls in any one state
+    state_deadline: StateDeadline<EndpointServerState>,
+    // fails the handshake if the client goes quiet while we wait on it
+    read_idle_deadline: ReadIdleDeadline,
+    // source of the time for the state deadline
+    clock: Arc<dyn Clock>,
     begin_handshake_request_cookie: Option<RequestCookie>,
     client_identity: Option<V3OnionServiceId>,
     requested_channel: Option<AsciiString>,
     server_cookie: Option<ServerCookie>,
     handshake_succeeded: Option<bool>,
+    // client cookie committed to in begin_handshake; older clients do not send one
+    committed_client_cookie: Option<ClientCookie>,
+    // set when begin_handshake committed to a previously seen client cookie
+    replay_detected: bool,
+    // the length of the requested channel name when it exceeds MAX_CHANNEL_NAME_LENGTH
+    channel_name_too_long: Option<usize>,
+    // set when the client called a function out of order or more than once;
+    // the error data is returned with the error response to the offending call
+    protocol_violation: Option<(RequestCookie, RpcError, ProtocolViolationErrorData)>,
+    // optional protocol features agreed with the client; None if the client
+    // predates capability negotiation
+    negotiated_capabilities: Option<Capabilities>,
+    // counts the accepted channel against the client's quota until dropped
+    channel_quota_permit: Option<ChannelQuotaPermit>,
+    // set when the client's proof was accepted but its channel quota was
+    // exceeded; the error data is returned with the send_response error
+    quota_exceeded: Option<(RequestCookie, ChannelQuotaExceeded)>,
+    // set when the client's proof was accepted but it is banned
+    client_banned: Option<Ban>,
 
     // Verification flags
 
@@ -89,40 +202,205 @@ pub(crate) struct EndpointServer {
     client_requested_channel_valid: bool,
     // The client proof is valid and signed with client's public key
     client_proof_signature_valid: bool,
+
+    // Traffic Data
+
+    // session traffic before this handshake began
+    baseline_stats: SessionStats,
+    // handshake traffic once the session has been consumed
+    final_stats: HandshakeStats,
 }
 
-impl EndpointServer {
+impl<RW> EndpointServer<RW>
+where
+    RW: std::io::Read + std::io::Write + Send,
+{
     fn get_state(&self) -> String {
         format!("{{ state: {:?}, begin_handshake_request_cookie: {:?}, client_identity: {:?}, requested_channel: {:?}, server_cookie: {:?}, handshake_succeeded:{:?} }}", self.state, self.begin_handshake_request_cookie, self.client_identity, self.requested_channel, self.server_cookie, self.handshake_succeeded)
     }
 
     pub fn new(
-        rpc: Session<TcpStream>,
+        rpc: Session<RW>,
         client_identity: V3OnionServiceId,
         server_identity: V3OnionServiceId,
+        channel_patterns: Vec<ChannelPattern>,
+        legacy_handshakes_allowed: bool,
     ) -> Self {
         // generate server cookie
         let mut server_cookie: ServerCookie = Default::default();
         OsRng.fill_bytes(&mut server_cookie);
 
+        let baseline_stats = rpc.stats();
         EndpointServer {
             rpc: Some(rpc),
             server_identity,
             allowed_client_identity: client_identity,
+            channel_patterns,
+            legacy_handshakes_allowed,
+            busy_retry_after: None,
+            replay_cache: None,
+            channel_quota: None,
+            ban_list: None,
+            capabilities: Capabilities::all(),
+            shared_endpoints: None,
+            rpc_channels: Default::default(),
             state: EndpointServerState::WaitingForBeginHandshake,
+            state_deadline: StateDeadline::new(EndpointServerState::WaitingForBeginHandshake),
+            read_idle_deadline: ReadIdleDeadline::new(),
+            clock: Arc::new(SystemClock),
             begin_handshake_request_cookie: None,
             requested_channel: None,
             client_identity: None,
             server_cookie: None,
             handshake_succeeded: None,
+            committed_client_cookie: None,
+            replay_detected: false,
+            channel_name_too_long: None,
+            protocol_violation: None,
+            negotiated_capabilities: None,
+            channel_quota_permit: None,
+            quota_exceeded: None,
+            client_banned: None,
             client_allowed: false,
             // TODO: hookup this to event and callback
             client_requested_channel_valid: true,
             client_proof_signature_valid: false,
+            baseline_stats,
+            final_stats: Default::default(),
         }
     }
 
-    pub fn update(&mut self) -> Result<Option<EndpointServerEvent>, Error> {
+    // answer the client's channel request with a busy error instead of
+    // validating it; must be set before the request is received
+    pub fn set_busy_retry_after(&mut self, busy_retry_after: Option<Duration>) {
+        self.busy_retry_after = busy_retry_after;
+    }
+
+    // Reject begin_handshake calls committing to a client cookie already in
+    // replay_cache; the cache may be shared between servers
+    pub(crate) fn set_replay_cache(&mut self, replay_cache: Option<SharedReplayCache>) {
+        self.replay_cache = replay_cache;
+    }
+
+    // count accepted channels against each client's channel quota
+    pub(crate) fn set_channel_quota(&mut self, channel_quota: Option<SharedChannelQuotaTracker>) {
+        self.channel_quota = channel_quota;
+    }
+
+    // refuse proven clients banned in ban_list
+    pub fn set_ban_list(&mut self, ban_list: Option<BanList>) {
+        self.ban_list = ban_list;
+    }
+
+    // take the permit counting the accepted channel against its client's
+    // quota; the channel is released when the permit is dropped
+    pub(crate) fn take_channel_quota_permit(&mut self) -> Option<ChannelQuotaPermit> {
+        self.channel_quota_permit.take()
+    }
+
+    // Support only the given optional protocol features
+    pub fn set_capabilities(&mut self, capabilities: Capabilities) {
+        self.capabilities = capabilities;
+    }
+
+    // Serve the given (endpoint service id, allowed client) pairs rather than
+    // the server identity and client passed to new(); server_identity is
+    // replaced with the endpoint named in begin_handshake. Must be set before
+    // the request is received
+    pub fn set_shared_endpoints(
+        &mut self,
+        shared_endpoints: Option<Vec<(V3OnionServiceId, V3OnionServiceId)>>,
+    ) {
+        self.shared_endpoints = shared_endpoints;
+    }
+
+    // Complete handshakes for any of the given channels with an RpcChannelOpened
+    // event handing over the session rather than a HandshakeCompleted event
+    // with its stream. Must be set before the handshake completes
+    pub fn set_rpc_channels(&mut self, rpc_channels: Vec<AsciiString>) {
+        self.rpc_channels = rpc_channels;
+    }
+
+    // The state the handshake is in
+    #[cfg(any(test, feature = "test-utils"))]
+    pub fn state(&self) -> EndpointServerState {
+        self.state
+    }
+
+    // The round-trips and bytes exchanged by this handshake so far; when continuing
+    // over an upgraded identity session the identity handshake is not included
+    pub fn stats(&self) -> HandshakeStats {
+        match self.rpc.as_ref() {
+            Some(rpc) => HandshakeStats::since(rpc.stats(), self.baseline_stats),
+            None => self.final_stats,
+        }
+    }
+
+    // Fail the handshake if it spends longer than deadline in any one state
+    pub fn set_state_deadline(&mut self, deadline: Option<Duration>) {
+        self.state_deadline.set_deadline(deadline);
+    }
+
+    // Fail the handshake if the client sends nothing for timeout while the
+    // handshake is waiting on them
+    pub fn set_read_idle_timeout(&mut self, timeout: Option<Duration>) {
+        self.read_idle_deadline.set_timeout(timeout);
+    }
+
+    // Read the time for the state deadline from clock
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    pub fn update(&mut self) -> Result<Option<EndpointServerEvent<RW>>, Error> {
+        self.update_at(self.clock.now())
+    }
+
+    // update() with the given time standing in for the current time
+    pub(crate) fn update_at(
+        &mut self,
+        now: Instant,
+    ) -> Result<Option<EndpointServerEvent<RW>>, Error> {
+        if !matches!(
+            self.state,
+            EndpointServerState::HandshakeComplete | EndpointServerState::HandshakeFailed
+        ) {
+            if let Some(deadline) = self.state_deadline.observe(self.state, now) {
+                let state = self.state;
+                self.state = EndpointServerState::HandshakeFailed;
+                return Err(Error::TimedOut { state, deadline });
+            }
+            if let Some(timeout) = self.observe_read_idle(now) {
+                self.state = EndpointServerState::HandshakeFailed;
+                return Err(Error::PeerUnresponsive(timeout));
+            }
+        }
+
+        let previous_state = self.state;
+        let result = self.update_state_machine();
+        if self.state != previous_state {
+            tracing::debug!(from = ?previous_state, to = ?self.state, "endpoint server state transition");
+        }
+        self.state_deadline.observe(self.state, now);
+        self.observe_read_idle(now);
+        result
+    }
+
+    fn observe_read_idle(&mut self, now: Instant) -> Option<Duration> {
+        let waiting_on_peer = matches!(
+            self.state,
+            EndpointServerState::WaitingForBeginHandshake
+                | EndpointServerState::WaitingForSendResponse
+        );
+        let bytes_received = self
+            .rpc
+            .as_ref()
+            .map_or(0, |rpc| rpc.stats().bytes_received);
+        self.read_idle_deadline
+            .observe(waiting_on_peer, bytes_received, now)
+    }
+
+    fn update_state_machine(&mut self) -> Result<Option<EndpointServerEvent<RW>>, Error> {
         if let Some(mut rpc) = std::mem::take(&mut self.rpc) {
             match rpc.update(Some(&mut [self])) {
                 Ok(()) => {
@@ -155,7 +433,15 @@ impl EndpointServer {
              None, // server_cookie
              None) // handshake_succeeded
             => {
+                if self.busy_retry_after.is_some() {
+                    self.state = EndpointServerState::BusyResponseReady;
+                    return Ok(None);
+                }
                 self.state = EndpointServerState::ValidatingChannelRequest;
+                if self.channel_patterns.iter().any(|pattern| pattern.matches(requested_channel)) {
+                    self.handle_channel_request_received(true)?;
+                    return Ok(None);
+                }
                 return Ok(
                         Some(
                             EndpointServerEvent::ChannelRequestReceived
@@ -171,6 +457,32 @@ impl EndpointServer {
              None, // server_cookie
              None) // handshake_succeeded
             => {},
+            (&EndpointServerState::BusyResponseReady,
+             Some(_begin_handshake_request_cookie),
+             Some(_client_identity),
+             Some(_requested_channel),
+             None, // server_cookie
+             None) // handshake_succeeded
+            => {},
+            (&EndpointServerState::BusyResponseSent,
+             Some(_begin_handshake_request_cookie),
+             Some(client_identity),
+             Some(requested_channel),
+             None, // server_cookie
+             None) // handshake_succeeded
+            => {
+                // the client only learns it should retry once our busy error
+                // reaches it, so keep the session until it has been written
+                if !self.rpc.as_ref().unwrap().flushed() {
+                    return Ok(None);
+                }
+                self.state = EndpointServerState::HandshakeComplete;
+                return Ok(Some(EndpointServerEvent::HandshakeBusy{
+                    client_service_id: client_identity.clone(),
+                    requested_channel: requested_channel.clone(),
+                    // only entered when set
+                    retry_after: self.busy_retry_after.unwrap()}));
+            },
             (&EndpointServerState::ChannelRequestValidated,
              Some(_begin_handshake_request_cookie),
              Some(_client_identity),
@@ -192,13 +504,39 @@ impl EndpointServer {
              Some(_server_cookie),
              Some(handshake_succeeded))
             => {
+                // the client only learns of the result once our final response
+                // reaches it, so keep the session until it has been written
+                if (handshake_succeeded || self.quota_exceeded.is_some() || self.client_banned.is_some())
+                    && !self.rpc.as_ref().unwrap().flushed() {
+                    return Ok(None);
+                }
                 self.state = EndpointServerState::HandshakeComplete;
-                if handshake_succeeded {
-                    let stream = std::mem::take(&mut self.rpc).unwrap().into_stream();
+                if let Some(ban) = self.client_banned.take() {
+                    return Ok(Some(EndpointServerEvent::HandshakeBanned{
+                        client_service_id: client_identity.clone(),
+                        requested_channel: requested_channel.clone(),
+                        ban}));
+                } else if let Some((_, exceeded)) = self.quota_exceeded {
+                    return Ok(Some(EndpointServerEvent::HandshakeQuotaExceeded{
+                        client_service_id: client_identity.clone(),
+                        requested_channel: requested_channel.clone(),
+                        exceeded}));
+                } else if handshake_succeeded {
+                    self.final_stats = self.stats();
+                    let session = std::mem::take(&mut self.rpc).unwrap();
+                    if self.rpc_channels.contains(requested_channel) {
+                        return Ok(Some(EndpointServerEvent::RpcChannelOpened{
+                            client_service_id: client_identity.clone(),
+                            channel_name: requested_channel.clone(),
+                            session: Box::new(session),
+                            capabilities: self.negotiated_capabilities.unwrap_or_default()}));
+                    }
+                    let stream = session.into_stream();
                     return Ok(Some(EndpointServerEvent::HandshakeCompleted{
                         client_service_id: client_identity.clone(),
                         channel_name: requested_channel.clone(),
-                        stream}));
+                        stream,
+                        capabilities: self.negotiated_capabilities.unwrap_or_default()}));
                 } else {
                     return Ok(Some(EndpointServerEvent::HandshakeRejected{
                         client_allowed: self.client_allowed,
@@ -207,7 +545,17 @@ impl EndpointServer {
                 }
             },
             _ => {
-                if self.state == EndpointServerState::HandshakeFailed {
+                if self.replay_detected {
+                    return Err(Error::ReplayedHandshake);
+                } else if let Some(length) = self.channel_name_too_long {
+                    return Err(Error::ChannelNameTooLong(length));
+                } else if let Some((_, rpc_error, violation)) = self.protocol_violation.as_ref() {
+                    let function = violation.function.clone();
+                    return Err(match rpc_error {
+                        RpcError::DuplicateCall => Error::DuplicateCall(function),
+                        _ => Error::OutOfOrderCall(function),
+                    });
+                } else if self.state == EndpointServerState::HandshakeFailed {
                     return Err(Error::BadClient);
                 } else {
                     return Err(Error::InvalidState(self.get_state()));
@@ -238,7 +586,7 @@ impl EndpointServer {
                 let mut server_cookie: ServerCookie = Default::default();
                 OsRng.fill_bytes(&mut server_cookie);
                 self.server_cookie = Some(server_cookie);
-                self.client_allowed = *client_identity == self.allowed_client_identity;
+                self.client_allowed = client_identity.ct_eq(&self.allowed_client_identity).into();
                 self.client_requested_channel_valid = client_requested_channel_valid;
                 self.state = EndpointServerState::ChannelRequestValidated;
                 Ok(())
@@ -248,7 +596,10 @@ impl EndpointServer {
     }
 }
 
-impl ApiSet for EndpointServer {
+impl<RW> ApiSet for EndpointServer<RW>
+where
+    RW: std::io::Read + std::io::Write + Send,
+{
     fn namespace(&self) -> &str {
         "gosling_endpoint"
     }
@@ -257,7 +608,7 @@ impl ApiSet for EndpointServer {
         &mut self,
         name: &str,
         version: i32,
-        mut args: bson::document::Document,
+        args: bson::document::Document,
         request_cookie: Option<RequestCookie>,
     ) -> Option<Result<Option<bson::Bson>, ErrorCode>> {
         let request_cookie = match request_cookie {
@@ -282,22 +633,73 @@ impl ApiSet for EndpointServer {
             None, // requested_channel
             None) // server_cookie
             => {
-                let valid_version = match args.remove("version") {
-                    Some(Bson::String(value)) => value == GOSLING_PROTOCOL_VERSION,
-                    _ => false,
-                };
+                let valid_version = matches!(
+                    args.get("version"),
+                    Some(Bson::String(value)) if value == GOSLING_PROTOCOL_VERSION
+                );
                 if !valid_version {
                     self.state = EndpointServerState::HandshakeFailed;
                     return Some(Err(ErrorCode::Runtime(RpcError::BadVersion as i32)));
                 }
 
-                if let (
-                    Some(Bson::String(client_identity)),
-                    Some(Bson::String(channel_name))
-                ) = (
-                    args.remove("client_identity"),
-                    args.remove("channel")
-                ) {
+                let args: EndpointBeginHandshakeArgs = match from_document(args) {
+                    Some(args) => args,
+                    None => {
+                        self.state = EndpointServerState::HandshakeFailed;
+                        return Some(Err(ErrorCode::Runtime(RpcError::InvalidArg as i32)));
+                    }
+                };
+
+                // reject replayed requests before doing any further work
+                let committed_client_cookie = match parse_committed_client_cookie(args.client_cookie, self.legacy_handshakes_allowed) {
+                    Ok(committed_client_cookie) => committed_client_cookie,
+                    Err(rpc_error) => {
+                        self.state = EndpointServerState::HandshakeFailed;
+                        return Some(Err(ErrorCode::Runtime(rpc_error as i32)));
+                    }
+                };
+                if let (Some(replay_cache), Some(committed_client_cookie)) = (self.replay_cache.as_ref(), committed_client_cookie) {
+                    let mut replay_cache = replay_cache.lock().unwrap_or_else(PoisonError::into_inner);
+                    if !replay_cache.insert(committed_client_cookie, self.clock.now()) {
+                        self.replay_detected = true;
+                        self.state = EndpointServerState::HandshakeFailed;
+                        return Some(Err(ErrorCode::Runtime(RpcError::Replayed as i32)));
+                    }
+                }
+
+                // shared endpoint servers serve several endpoints, so the client
+                // names the endpoint it was granted; unknown endpoints are refused
+                if let Some(shared_endpoints) = self.shared_endpoints.as_ref() {
+                    let endpoint_service_id = match args.endpoint_service_id.as_deref().map(V3OnionServiceId::from_string) {
+                        Some(Ok(endpoint_service_id)) => endpoint_service_id,
+                        _ => {
+                            self.state = EndpointServerState::HandshakeFailed;
+                            return Some(Err(ErrorCode::Runtime(RpcError::InvalidArg as i32)));
+                        }
+                    };
+                    match shared_endpoints.iter().find(|(shared_endpoint, _)| *shared_endpoint == endpoint_service_id) {
+                        Some((_, allowed_client_identity)) => {
+                            self.allowed_client_identity = allowed_client_identity.clone();
+                            self.server_identity = endpoint_service_id;
+                        },
+                        None => {
+                            self.state = EndpointServerState::HandshakeFailed;
+                            return Some(Err(ErrorCode::Runtime(RpcError::Failure as i32)));
+                        }
+                    }
+                }
+
+                // legacy peers do not send their identity; an endpoint server
+                // only serves one client so assume it is the allowed client. The
+                // client proof is still verified against this identity's key.
+                let client_identity = match args.client_identity {
+                    None if self.legacy_handshakes_allowed => {
+                        Some(self.allowed_client_identity.to_string())
+                    }
+                    client_identity => client_identity,
+                };
+
+                if let Some(client_identity) = client_identity {
                     // client_identiity
                     self.client_identity = match V3OnionServiceId::from_string(&client_identity) {
                         Ok(client_identity) => Some(client_identity),
@@ -307,13 +709,18 @@ impl ApiSet for EndpointServer {
                         }
                     };
 
-                    let channel_name = match AsciiString::new(channel_name) {
+                    let channel_name = match AsciiString::new(args.channel) {
                         Ok(channel_name) => channel_name,
                         Err(_) => {
                             self.state = EndpointServerState::HandshakeFailed;
                             return Some(Err(ErrorCode::Runtime(RpcError::InvalidArg as i32)));
                         }
                     };
+                    if channel_name.len() > MAX_CHANNEL_NAME_LENGTH {
+                        self.channel_name_too_long = Some(channel_name.len());
+                        self.state = EndpointServerState::HandshakeFailed;
+                        return Some(Err(ErrorCode::Runtime(RpcError::NameTooLong as i32)));
+                    }
 
                     // save cookie
                     self.begin_handshake_request_cookie = Some(request_cookie);
@@ -321,6 +728,13 @@ impl ApiSet for EndpointServer {
                     // save channel name
                     self.requested_channel = Some(channel_name);
 
+                    self.committed_client_cookie = committed_client_cookie;
+
+                    // optional; absent from older clients' requests
+                    self.negotiated_capabilities = args.capabilities.map(|client_capabilities| {
+                        Capabilities::from_names(&client_capabilities).intersection(self.capabilities)
+                    });
+
                     None
                 } else {
                     self.state = EndpointServerState::HandshakeFailed;
@@ -333,10 +747,10 @@ impl ApiSet for EndpointServer {
             Some(requested_channel),
             Some(server_cookie))
             => {
-                if let (Some(Bson::Binary(Binary{subtype: BinarySubtype::Generic, bytes: client_cookie})),
-                        Some(Bson::Binary(Binary{subtype: BinarySubtype::Generic, bytes: client_identity_proof_signature}))) =
-                       (args.remove("client_cookie"),
-                        args.remove("client_identity_proof_signature")) {
+                let args: Option<EndpointSendResponseArgs> = from_document(args);
+                if let Some((Some(client_cookie), Some(client_identity_proof_signature))) =
+                       args.map(|args| (generic_bytes(args.client_cookie),
+                                        generic_bytes(args.client_identity_proof_signature))) {
                     // client_cookie
                     let client_cookie : ClientCookie = match client_cookie.try_into() {
                         Ok(client_cookie) => client_cookie,
@@ -345,6 +759,13 @@ impl ApiSet for EndpointServer {
                             return Some(Err(ErrorCode::Runtime(RpcError::InvalidArg as i32)));
                         }
                     };
+                    // must be the client cookie committed to in begin_handshake
+                    if let Some(committed_client_cookie) = self.committed_client_cookie.as_ref() {
+                        if !bool::from(client_cookie[..].ct_eq(&committed_client_cookie[..])) {
+                            self.state = EndpointServerState::HandshakeFailed;
+                            return Some(Err(ErrorCode::Runtime(RpcError::InvalidArg as i32)));
+                        }
+                    }
 
                     // client_identity_proof_signature
                     let client_identity_proof_signature : [u8; ED25519_SIGNATURE_SIZE] = match client_identity_proof_signature.try_into() {
@@ -387,6 +808,29 @@ impl ApiSet for EndpointServer {
                         && self.client_requested_channel_valid
                         && self.client_proof_signature_valid
                     {
+                        // only proven clients are refused as banned
+                        if let Some(ban) = self
+                            .ban_list
+                            .as_ref()
+                            .and_then(|ban_list| ban_list.get(client_identity))
+                        {
+                            self.client_banned = Some(ban);
+                            self.handshake_succeeded = Some(false);
+                            self.state = EndpointServerState::HandledSendResponse;
+                            return Some(Err(ErrorCode::Runtime(RpcError::Banned as i32)));
+                        }
+                        // only proven clients count against their quota
+                        if let Some(channel_quota) = self.channel_quota.as_ref() {
+                            match ChannelQuotaTracker::admit(channel_quota, client_identity, self.clock.now()) {
+                                Ok(permit) => self.channel_quota_permit = Some(permit),
+                                Err(exceeded) => {
+                                    self.quota_exceeded = Some((request_cookie, exceeded));
+                                    self.handshake_succeeded = Some(false);
+                                    self.state = EndpointServerState::HandledSendResponse;
+                                    return Some(Err(ErrorCode::Runtime(RpcError::QuotaExceeded as i32)));
+                                }
+                            }
+                        }
                         self.handshake_succeeded = Some(true);
                         self.state = EndpointServerState::HandledSendResponse;
                         // success, return empty doc
@@ -401,9 +845,25 @@ impl ApiSet for EndpointServer {
                     Some(Err(ErrorCode::Runtime(RpcError::InvalidArg as i32)))
                 }
             },
+            // calls to an already failed handshake
+            (_, _, &EndpointServerState::HandshakeFailed, _, _, _) => {
+                Some(Err(ErrorCode::Runtime(RpcError::Failure as i32)))
+            }
             _ => {
+                // begin_handshake is always accepted first, so any other
+                // begin_handshake call is a duplicate, while send_response may
+                // also arrive before begin_handshake has been answered
+                let duplicate = name == "begin_handshake" || self.handshake_succeeded.is_some();
+                let expected = self
+                    .begin_handshake_request_cookie
+                    .is_none()
+                    .then_some("begin_handshake");
+                let (error_code, violation) =
+                    unexpected_call_error(name, version, duplicate, expected);
+                self.protocol_violation =
+                    violation.map(|(rpc_error, violation)| (request_cookie, rpc_error, violation));
                 self.state = EndpointServerState::HandshakeFailed;
-                Some(Err(ErrorCode::Runtime(RpcError::Failure as i32)))
+                Some(Err(error_code))
             }
         }
     }
@@ -422,12 +882,52 @@ impl ApiSet for EndpointServer {
                 self.state = EndpointServerState::WaitingForSendResponse;
                 Some((
                     begin_handshake_request_cookie,
-                    Ok(Some(Bson::Document(doc! {
-                        "server_cookie" : Bson::Binary(Binary{subtype: BinarySubtype::Generic, bytes: server_cookie.to_vec()}),
+                    Ok(Some(to_bson(&EndpointBeginHandshakeResponse {
+                        server_cookie: generic_binary(server_cookie),
+                        // only sent to clients which sent their capabilities
+                        capabilities: self.negotiated_capabilities.map(Capabilities::names),
                     }))),
                 ))
             }
+            (
+                &EndpointServerState::BusyResponseReady,
+                Some(begin_handshake_request_cookie),
+                None, // server_cookie
+            ) => {
+                self.state = EndpointServerState::BusyResponseSent;
+                Some((
+                    begin_handshake_request_cookie,
+                    Err(ErrorCode::Runtime(RpcError::Busy as i32)),
+                ))
+            }
             _ => None,
         }
     }
+
+    fn error_data(&mut self, request_cookie: RequestCookie) -> Option<bson::Bson> {
+        match (
+            &self.state,
+            self.begin_handshake_request_cookie,
+            self.busy_retry_after,
+        ) {
+            (
+                &EndpointServerState::BusyResponseSent,
+                Some(begin_handshake_request_cookie),
+                Some(retry_after),
+            ) if request_cookie == begin_handshake_request_cookie => {
+                Some(to_bson(&EndpointBusyErrorData {
+                    retry_after: retry_after.as_secs() as i64,
+                }))
+            }
+            _ => match (self.protocol_violation.as_ref(), self.quota_exceeded) {
+                (Some((cookie, _, violation)), _) if *cookie == request_cookie => {
+                    Some(to_bson(violation))
+                }
+                (_, Some((cookie, exceeded))) if cookie == request_cookie => {
+                    Some(to_bson(&exceeded.to_error_data()))
+                }
+                _ => None,
+            },
+        }
+    }
 }
@@ -0,0 +1,1008 @@
+// standard
+use std::net::TcpStream;
+use std::time::Duration;
+
+// extern crates
+use tor_interface::tor_crypto::*;
+use tor_interface::tor_provider::{CircuitStatus, SocksPolicyViolation, StreamStatus};
+
+// internal crates
+use crate::ascii_string::AsciiString;
+use crate::bans::Ban;
+use crate::channel_quota::ChannelQuotaExceeded;
+use crate::connectivity::ConnectivityReport;
+use crate::context::{Context, ContextEvent, Error, HandshakeHandle, PublishHandle};
+use crate::datagram_channel::DatagramChannel;
+use crate::gosling::SystemTime;
+use crate::protocol::Capabilities;
+use crate::rpc_channel::RpcChannel;
+use crate::timing::HandshakeStats;
+
+/// A push-style consumer of the [`ContextEvent`]s produced by a [`Context`].
+///
+/// Rather than matching on each event returned from [`Context::update()`], applications may register an implementation of this trait with [`Context::set_events_sink()`]. [`Context::update()`] then passes each event to [`ContextEventsSink::on_event()`], which calls the typed method for the event's variant. Every method has a default implementation which ignores the event, so implementations only need to override the methods for the events they handle. Each method is passed the `Context` itself so the handshake may be continued, e.g. with [`Context::identity_client_handle_challenge_received()`] from [`ContextEventsSink::on_identity_client_challenge_received()`].
+pub trait ContextEventsSink: Send {
+    /// Called for each event produced by the [`Context`]'s update; the default implementation calls the typed method for the event's variant. Override this to observe every event before (or instead of) the typed methods.
+    fn on_event(&mut self, context: &mut Context, event: ContextEvent) {
+        match event {
+            ContextEvent::TorBootstrapStatusReceived {
+                progress,
+                tag,
+                summary,
+            } => self.on_tor_bootstrap_status_received(context, progress, tag, summary),
+            ContextEvent::TorBootstrapCompleted => self.on_tor_bootstrap_completed(context),
+            ContextEvent::TorProviderChanged => self.on_tor_provider_changed(context),
+            ContextEvent::ListenerStartFailed {
+                service_id,
+                virt_port,
+                reason,
+            } => self.on_listener_start_failed(context, service_id, virt_port, reason),
+            ContextEvent::TorLogReceived { line } => self.on_tor_log_received(context, line),
+            ContextEvent::TorCircuitStatusChanged {
+                circuit_id,
+                status,
+                path,
+                purpose,
+                reason,
+            } => self
+                .on_tor_circuit_status_changed(context, circuit_id, status, path, purpose, reason),
+            ContextEvent::TorStreamStatusChanged {
+                stream_id,
+                status,
+                circuit_id,
+                target,
+                reason,
+            } => self.on_tor_stream_status_changed(
+                context, stream_id, status, circuit_id, target, reason,
+            ),
+            ContextEvent::TorOnionServiceDescriptorUploadStatus {
+                service_id,
+                hs_dir,
+                succeeded,
+                reason,
+                uploads_succeeded,
+                uploads_failed,
+            } => self.on_tor_onion_service_descriptor_upload_status(
+                context,
+                service_id,
+                hs_dir,
+                succeeded,
+                reason,
+                uploads_succeeded,
+                uploads_failed,
+            ),
+            ContextEvent::TorSocksPolicyViolated { violation } => {
+                self.on_tor_socks_policy_violated(context, violation)
+            }
+            ContextEvent::ConnectivityChecked { report } => {
+                self.on_connectivity_checked(context, report)
+            }
+            ContextEvent::EventQueueOverflowed {
+                dropped_events,
+                coalesced_tor_logs,
+            } => self.on_event_queue_overflowed(context, dropped_events, coalesced_tor_logs),
+            ContextEvent::TorLogLinesDropped { dropped_lines } => {
+                self.on_tor_log_lines_dropped(context, dropped_lines)
+            }
+            ContextEvent::OutboundHandshakeQueued {
+                handle,
+                queue_position,
+            } => self.on_outbound_handshake_queued(context, handle, queue_position),
+            ContextEvent::OutboundHandshakeStarted { handle } => {
+                self.on_outbound_handshake_started(context, handle)
+            }
+            ContextEvent::IdentityClientChallengeReceived {
+                handle,
+                endpoint_challenge,
+            } => self.on_identity_client_challenge_received(context, handle, endpoint_challenge),
+            ContextEvent::IdentityClientHandshakeCompleted {
+                handle,
+                identity_service_id,
+                endpoint_service_id,
+                endpoint_name,
+                client_auth_private_key,
+                additional_endpoints,
+                contact_request,
+                capabilities,
+                stats,
+            } => self.on_identity_client_handshake_completed(
+                context,
+                handle,
+                identity_service_id,
+                endpoint_service_id,
+                endpoint_name,
+                client_auth_private_key,
+                additional_endpoints,
+                contact_request,
+                capabilities,
+                stats,
+            ),
+            ContextEvent::IdentityClientHandshakeFailed {
+                handle,
+                reason,
+                stats,
+            } => self.on_identity_client_handshake_failed(context, handle, reason, stats),
+            ContextEvent::IdentityClientSessionClosed {
+                handle,
+                identity_service_id,
+            } => self.on_identity_client_session_closed(context, handle, identity_service_id),
+            ContextEvent::IdentityClientHandshakeSuppressed {
+                handle,
+                identity_service_id,
+                retry_after,
+            } => self.on_identity_client_handshake_suppressed(
+                context,
+                handle,
+                identity_service_id,
+                retry_after,
+            ),
+            ContextEvent::IdentityClientEndpointPinMismatched {
+                handle,
+                identity_service_id,
+                endpoint_name,
+                pinned_endpoint_service_id,
+                endpoint_service_id,
+            } => self.on_identity_client_endpoint_pin_mismatched(
+                context,
+                handle,
+                identity_service_id,
+                endpoint_name,
+                pinned_endpoint_service_id,
+                endpoint_service_id,
+            ),
+            ContextEvent::IdentityServerPublished => self.on_identity_server_published(context),
+            ContextEvent::IdentityServerDescriptorRefreshed => {
+                self.on_identity_server_descriptor_refreshed(context)
+            }
+            ContextEvent::IdentityServerHandshakeStarted { handle } => {
+                self.on_identity_server_handshake_started(context, handle)
+            }
+            ContextEvent::IdentityServerEndpointRequestReceived {
+                handle,
+                client_service_id,
+                requested_endpoint,
+                additional_endpoints,
+                contact_request,
+            } => self.on_identity_server_endpoint_request_received(
+                context,
+                handle,
+                client_service_id,
+                requested_endpoint,
+                additional_endpoints,
+                contact_request,
+            ),
+            ContextEvent::IdentityServerChallengeResponseReceived {
+                handle,
+                challenge_response,
+            } => self.on_identity_server_challenge_response_received(
+                context,
+                handle,
+                challenge_response,
+            ),
+            ContextEvent::IdentityServerHandshakeCompleted {
+                handle,
+                endpoint_private_key,
+                endpoint_name,
+                client_service_id,
+                client_auth_public_key,
+                additional_endpoints,
+                contact_request,
+                capabilities,
+                stats,
+            } => self.on_identity_server_handshake_completed(
+                context,
+                handle,
+                endpoint_private_key,
+                endpoint_name,
+                client_service_id,
+                client_auth_public_key,
+                additional_endpoints,
+                contact_request,
+                capabilities,
+                stats,
+            ),
+            ContextEvent::IdentityServerHandshakeRejected {
+                handle,
+                client_allowed,
+                client_requested_endpoint_valid,
+                client_proof_signature_valid,
+                client_auth_signature_valid,
+                challenge_response_valid,
+            } => self.on_identity_server_handshake_rejected(
+                context,
+                handle,
+                client_allowed,
+                client_requested_endpoint_valid,
+                client_proof_signature_valid,
+                client_auth_signature_valid,
+                challenge_response_valid,
+            ),
+            ContextEvent::IdentityServerBannedClientRejected {
+                handle,
+                client_service_id,
+                requested_endpoint,
+                ban,
+            } => self.on_identity_server_banned_client_rejected(
+                context,
+                handle,
+                client_service_id,
+                requested_endpoint,
+                ban,
+            ),
+            ContextEvent::IdentityServerHandshakeFailed {
+                handle,
+                reason,
+                stats,
+            } => self.on_identity_server_handshake_failed(context, handle, reason, stats),
+            ContextEvent::IdentityServerSessionClosed {
+                handle,
+                client_service_id,
+            } => self.on_identity_server_session_closed(context, handle, client_service_id),
+            ContextEvent::ClientAuthAdded {
+                endpoint_service_id,
+            } => self.on_client_auth_added(context, endpoint_service_id),
+            ContextEvent::ClientAuthAddFailed {
+                endpoint_service_id,
+                reason,
+            } => self.on_client_auth_add_failed(context, endpoint_service_id, reason),
+            ContextEvent::ClientAuthRemoved {
+                endpoint_service_id,
+            } => self.on_client_auth_removed(context, endpoint_service_id),
+            ContextEvent::ClientAuthRemoveFailed {
+                endpoint_service_id,
+                reason,
+            } => self.on_client_auth_remove_failed(context, endpoint_service_id, reason),
+            ContextEvent::EndpointClientHandshakeCompleted {
+                handle,
+                endpoint_service_id,
+                channel_name,
+                stream,
+                capabilities,
+                stats,
+            } => self.on_endpoint_client_handshake_completed(
+                context,
+                handle,
+                endpoint_service_id,
+                channel_name,
+                stream,
+                capabilities,
+                stats,
+            ),
+            ContextEvent::EndpointClientRpcChannelOpened {
+                handle,
+                endpoint_service_id,
+                channel_name,
+                channel,
+                capabilities,
+                stats,
+            } => self.on_endpoint_client_rpc_channel_opened(
+                context,
+                handle,
+                endpoint_service_id,
+                channel_name,
+                channel,
+                capabilities,
+                stats,
+            ),
+            ContextEvent::EndpointClientDatagramChannelOpened {
+                handle,
+                endpoint_service_id,
+                channel_name,
+                channel,
+                capabilities,
+                stats,
+            } => self.on_endpoint_client_datagram_channel_opened(
+                context,
+                handle,
+                endpoint_service_id,
+                channel_name,
+                channel,
+                capabilities,
+                stats,
+            ),
+            ContextEvent::EndpointClientHandshakeFailed {
+                handle,
+                reason,
+                stats,
+            } => self.on_endpoint_client_handshake_failed(context, handle, reason, stats),
+            ContextEvent::EndpointClientHandshakeBusy {
+                handle,
+                endpoint_service_id,
+                channel_name,
+                retry_after,
+            } => self.on_endpoint_client_handshake_busy(
+                context,
+                handle,
+                endpoint_service_id,
+                channel_name,
+                retry_after,
+            ),
+            ContextEvent::EndpointClientEndpointRevoked {
+                endpoint_service_id,
+                revoked_at,
+            } => self.on_endpoint_client_endpoint_revoked(context, endpoint_service_id, revoked_at),
+            ContextEvent::EndpointServerPublished {
+                endpoint_service_id,
+                endpoint_name,
+            } => self.on_endpoint_server_published(context, endpoint_service_id, endpoint_name),
+            ContextEvent::EndpointServerPublishCompleted {
+                handle,
+                endpoint_service_id,
+                endpoint_name,
+            } => self.on_endpoint_server_publish_completed(
+                context,
+                handle,
+                endpoint_service_id,
+                endpoint_name,
+            ),
+            ContextEvent::EndpointServerPublishTimedOut {
+                handle,
+                endpoint_service_id,
+                endpoint_name,
+                timeout,
+            } => self.on_endpoint_server_publish_timed_out(
+                context,
+                handle,
+                endpoint_service_id,
+                endpoint_name,
+                timeout,
+            ),
+            ContextEvent::EndpointServerDescriptorRefreshed {
+                endpoint_service_id,
+                endpoint_name,
+            } => self.on_endpoint_server_descriptor_refreshed(
+                context,
+                endpoint_service_id,
+                endpoint_name,
+            ),
+            ContextEvent::EndpointServerHandshakeStarted { handle } => {
+                self.on_endpoint_server_handshake_started(context, handle)
+            }
+            ContextEvent::EndpointServerConnectionShed {
+                endpoint_service_id,
+            } => self.on_endpoint_server_connection_shed(context, endpoint_service_id),
+            ContextEvent::EndpointServerChannelRequestReceived {
+                handle,
+                client_service_id,
+                requested_channel,
+            } => self.on_endpoint_server_channel_request_received(
+                context,
+                handle,
+                client_service_id,
+                requested_channel,
+            ),
+            ContextEvent::EndpointServerHandshakeCompleted {
+                handle,
+                endpoint_service_id,
+                client_service_id,
+                channel_name,
+                stream,
+                capabilities,
+                stats,
+            } => self.on_endpoint_server_handshake_completed(
+                context,
+                handle,
+                endpoint_service_id,
+                client_service_id,
+                channel_name,
+                stream,
+                capabilities,
+                stats,
+            ),
+            ContextEvent::EndpointServerRpcChannelOpened {
+                handle,
+                endpoint_service_id,
+                client_service_id,
+                channel_name,
+                channel,
+                capabilities,
+                stats,
+            } => self.on_endpoint_server_rpc_channel_opened(
+                context,
+                handle,
+                endpoint_service_id,
+                client_service_id,
+                channel_name,
+                channel,
+                capabilities,
+                stats,
+            ),
+            ContextEvent::EndpointServerDatagramChannelOpened {
+                handle,
+                endpoint_service_id,
+                client_service_id,
+                channel_name,
+                channel,
+                capabilities,
+                stats,
+            } => self.on_endpoint_server_datagram_channel_opened(
+                context,
+                handle,
+                endpoint_service_id,
+                client_service_id,
+                channel_name,
+                channel,
+                capabilities,
+                stats,
+            ),
+            ContextEvent::EndpointServerHandshakeRejected {
+                handle,
+                client_allowed,
+                client_requested_channel_valid,
+                client_proof_signature_valid,
+            } => self.on_endpoint_server_handshake_rejected(
+                context,
+                handle,
+                client_allowed,
+                client_requested_channel_valid,
+                client_proof_signature_valid,
+            ),
+            ContextEvent::EndpointServerHandshakeBusy {
+                handle,
+                client_service_id,
+                requested_channel,
+                retry_after,
+            } => self.on_endpoint_server_handshake_busy(
+                context,
+                handle,
+                client_service_id,
+                requested_channel,
+                retry_after,
+            ),
+            ContextEvent::EndpointServerChannelQuotaExceeded {
+                handle,
+                client_service_id,
+                requested_channel,
+                exceeded,
+            } => self.on_endpoint_server_channel_quota_exceeded(
+                context,
+                handle,
+                client_service_id,
+                requested_channel,
+                exceeded,
+            ),
+            ContextEvent::EndpointServerBannedClientRejected {
+                handle,
+                client_service_id,
+                requested_channel,
+                ban,
+            } => self.on_endpoint_server_banned_client_rejected(
+                context,
+                handle,
+                client_service_id,
+                requested_channel,
+                ban,
+            ),
+            ContextEvent::EndpointServerHandshakeFailed {
+                handle,
+                reason,
+                stats,
+            } => self.on_endpoint_server_handshake_failed(context, handle, reason, stats),
+        }
+    }
+
+    /// Called for each [`ContextEvent::TorBootstrapStatusReceived`] event
+    fn on_tor_bootstrap_status_received(
+        &mut self,
+        _context: &mut Context,
+        _progress: u32,
+        _tag: String,
+        _summary: String,
+    ) {
+    }
+
+    /// Called for each [`ContextEvent::TorBootstrapCompleted`] event
+    fn on_tor_bootstrap_completed(&mut self, _context: &mut Context) {}
+
+    /// Called for each [`ContextEvent::TorProviderChanged`] event
+    fn on_tor_provider_changed(&mut self, _context: &mut Context) {}
+
+    /// Called for each [`ContextEvent::ListenerStartFailed`] event
+    fn on_listener_start_failed(
+        &mut self,
+        _context: &mut Context,
+        _service_id: V3OnionServiceId,
+        _virt_port: u16,
+        _reason: Error,
+    ) {
+    }
+
+    /// Called for each [`ContextEvent::TorLogReceived`] event
+    fn on_tor_log_received(&mut self, _context: &mut Context, _line: String) {}
+
+    /// Called for each [`ContextEvent::TorCircuitStatusChanged`] event
+    fn on_tor_circuit_status_changed(
+        &mut self,
+        _context: &mut Context,
+        _circuit_id: String,
+        _status: CircuitStatus,
+        _path: Vec<String>,
+        _purpose: Option<String>,
+        _reason: Option<String>,
+    ) {
+    }
+
+    /// Called for each [`ContextEvent::TorStreamStatusChanged`] event
+    fn on_tor_stream_status_changed(
+        &mut self,
+        _context: &mut Context,
+        _stream_id: String,
+        _status: StreamStatus,
+        _circuit_id: String,
+        _target: String,
+        _reason: Option<String>,
+    ) {
+    }
+
+    /// Called for each [`ContextEvent::TorOnionServiceDescriptorUploadStatus`] event
+    fn on_tor_onion_service_descriptor_upload_status(
+        &mut self,
+        _context: &mut Context,
+        _service_id: V3OnionServiceId,
+        _hs_dir: Option<String>,
+        _succeeded: bool,
+        _reason: Option<String>,
+        _uploads_succeeded: usize,
+        _uploads_failed: usize,
+    ) {
+    }
+
+    /// Called for each [`ContextEvent::TorSocksPolicyViolated`] event
+    fn on_tor_socks_policy_violated(
+        &mut self,
+        _context: &mut Context,
+        _violation: SocksPolicyViolation,
+    ) {
+    }
+
+    /// Called for each [`ContextEvent::ConnectivityChecked`] event
+    fn on_connectivity_checked(&mut self, _context: &mut Context, _report: ConnectivityReport) {}
+
+    /// Called for each [`ContextEvent::EventQueueOverflowed`] event
+    fn on_event_queue_overflowed(
+        &mut self,
+        _context: &mut Context,
+        _dropped_events: usize,
+        _coalesced_tor_logs: usize,
+    ) {
+    }
+
+    /// Called for each [`ContextEvent::TorLogLinesDropped`] event
+    fn on_tor_log_lines_dropped(&mut self, _context: &mut Context, _dropped_lines: usize) {}
+
+    /// Called for each [`ContextEvent::OutboundHandshakeQueued`] event
+    fn on_outbound_handshake_queued(
+        &mut self,
+        _context: &mut Context,
+        _handle: HandshakeHandle,
+        _queue_position: usize,
+    ) {
+    }
+
+    /// Called for each [`ContextEvent::OutboundHandshakeStarted`] event
+    fn on_outbound_handshake_started(&mut self, _context: &mut Context, _handle: HandshakeHandle) {}
+
+    /// Called for each [`ContextEvent::IdentityClientChallengeReceived`] event
+    fn on_identity_client_challenge_received(
+        &mut self,
+        _context: &mut Context,
+        _handle: HandshakeHandle,
+        _endpoint_challenge: bson::document::Document,
+    ) {
+    }
+
+    /// Called for each [`ContextEvent::IdentityClientHandshakeCompleted`] event
+    fn on_identity_client_handshake_completed(
+        &mut self,
+        _context: &mut Context,
+        _handle: HandshakeHandle,
+        _identity_service_id: V3OnionServiceId,
+        _endpoint_service_id: V3OnionServiceId,
+        _endpoint_name: AsciiString,
+        _client_auth_private_key: X25519PrivateKey,
+        _additional_endpoints: Vec<(AsciiString, Option<V3OnionServiceId>)>,
+        _contact_request: Option<String>,
+        _capabilities: Capabilities,
+        _stats: HandshakeStats,
+    ) {
+    }
+
+    /// Called for each [`ContextEvent::IdentityClientHandshakeFailed`] event
+    fn on_identity_client_handshake_failed(
+        &mut self,
+        _context: &mut Context,
+        _handle: HandshakeHandle,
+        _reason: Error,
+        _stats: HandshakeStats,
+    ) {
+    }
+
+    /// Called for each [`ContextEvent::IdentityClientSessionClosed`] event
+    fn on_identity_client_session_closed(
+        &mut self,
+        _context: &mut Context,
+        _handle: HandshakeHandle,
+        _identity_service_id: V3OnionServiceId,
+    ) {
+    }
+
+    /// Called for each [`ContextEvent::IdentityClientHandshakeSuppressed`] event
+    fn on_identity_client_handshake_suppressed(
+        &mut self,
+        _context: &mut Context,
+        _handle: HandshakeHandle,
+        _identity_service_id: V3OnionServiceId,
+        _retry_after: Duration,
+    ) {
+    }
+
+    /// Called for each [`ContextEvent::IdentityClientEndpointPinMismatched`] event
+    fn on_identity_client_endpoint_pin_mismatched(
+        &mut self,
+        _context: &mut Context,
+        _handle: HandshakeHandle,
+        _identity_service_id: V3OnionServiceId,
+        _endpoint_name: AsciiString,
+        _pinned_endpoint_service_id: V3OnionServiceId,
+        _endpoint_service_id: V3OnionServiceId,
+    ) {
+    }
+
+    /// Called for each [`ContextEvent::IdentityServerPublished`] event
+    fn on_identity_server_published(&mut self, _context: &mut Context) {}
+
+    /// Called for each [`ContextEvent::IdentityServerDescriptorRefreshed`] event
+    fn on_identity_server_descriptor_refreshed(&mut self, _context: &mut Context) {}
+
+    /// Called for each [`ContextEvent::IdentityServerHandshakeStarted`] event
+    fn on_identity_server_handshake_started(
+        &mut self,
+        _context: &mut Context,
+        _handle: HandshakeHandle,
+    ) {
+    }
+
+    /// Called for each [`ContextEvent::IdentityServerEndpointRequestReceived`] event
+    fn on_identity_server_endpoint_request_received(
+        &mut self,
+        _context: &mut Context,
+        _handle: HandshakeHandle,
+        _client_service_id: V3OnionServiceId,
+        _requested_endpoint: AsciiString,
+        _additional_endpoints: Vec<AsciiString>,
+        _contact_request: Option<String>,
+    ) {
+    }
+
+    /// Called for each [`ContextEvent::IdentityServerChallengeResponseReceived`] event
+    fn on_identity_server_challenge_response_received(
+        &mut self,
+        _context: &mut Context,
+        _handle: HandshakeHandle,
+        _challenge_response: bson::document::Document,
+    ) {
+    }
+
+    /// Called for each [`ContextEvent::IdentityServerHandshakeCompleted`] event
+    fn on_identity_server_handshake_completed(
+        &mut self,
+        _context: &mut Context,
+        _handle: HandshakeHandle,
+        _endpoint_private_key: Ed25519PrivateKey,
+        _endpoint_name: AsciiString,
+        _client_service_id: V3OnionServiceId,
+        _client_auth_public_key: X25519PublicKey,
+        _additional_endpoints: Vec<(AsciiString, Ed25519PrivateKey)>,
+        _contact_request: Option<String>,
+        _capabilities: Capabilities,
+        _stats: HandshakeStats,
+    ) {
+    }
+
+    /// Called for each [`ContextEvent::IdentityServerHandshakeRejected`] event
+    fn on_identity_server_handshake_rejected(
+        &mut self,
+        _context: &mut Context,
+        _handle: HandshakeHandle,
+        _client_allowed: bool,
+        _client_requested_endpoint_valid: bool,
+        _client_proof_signature_valid: bool,
+        _client_auth_signature_valid: bool,
+        _challenge_response_valid: bool,
+    ) {
+    }
+
+    /// Called for each [`ContextEvent::IdentityServerBannedClientRejected`] event
+    fn on_identity_server_banned_client_rejected(
+        &mut self,
+        _context: &mut Context,
+        _handle: HandshakeHandle,
+        _client_service_id: V3OnionServiceId,
+        _requested_endpoint: AsciiString,
+        _ban: Ban,
+    ) {
+    }
+
+    /// Called for each [`ContextEvent::IdentityServerHandshakeFailed`] event
+    fn on_identity_server_handshake_failed(
+        &mut self,
+        _context: &mut Context,
+        _handle: HandshakeHandle,
+        _reason: Error,
+        _stats: HandshakeStats,
+    ) {
+    }
+
+    /// Called for each [`ContextEvent::IdentityServerSessionClosed`] event
+    fn on_identity_server_session_closed(
+        &mut self,
+        _context: &mut Context,
+        _handle: HandshakeHandle,
+        _client_service_id: V3OnionServiceId,
+    ) {
+    }
+
+    /// Called for each [`ContextEvent::ClientAuthAdded`] event
+    fn on_client_auth_added(
+        &mut self,
+        _context: &mut Context,
+        _endpoint_service_id: V3OnionServiceId,
+    ) {
+    }
+
+    /// Called for each [`ContextEvent::ClientAuthAddFailed`] event
+    fn on_client_auth_add_failed(
+        &mut self,
+        _context: &mut Context,
+        _endpoint_service_id: V3OnionServiceId,
+        _reason: Error,
+    ) {
+    }
+
+    /// Called for each [`ContextEvent::ClientAuthRemoved`] event
+    fn on_client_auth_removed(
+        &mut self,
+        _context: &mut Context,
+        _endpoint_service_id: V3OnionServiceId,
+    ) {
+    }
+
+    /// Called for each [`ContextEvent::ClientAuthRemoveFailed`] event
+    fn on_client_auth_remove_failed(
+        &mut self,
+        _context: &mut Context,
+        _endpoint_service_id: V3OnionServiceId,
+        _reason: Error,
+    ) {
+    }
+
+    /// Called for each [`ContextEvent::EndpointClientHandshakeCompleted`] event
+    fn on_endpoint_client_handshake_completed(
+        &mut self,
+        _context: &mut Context,
+        _handle: HandshakeHandle,
+        _endpoint_service_id: V3OnionServiceId,
+        _channel_name: AsciiString,
+        _stream: TcpStream,
+        _capabilities: Capabilities,
+        _stats: HandshakeStats,
+    ) {
+    }
+
+    /// Called for each [`ContextEvent::EndpointClientRpcChannelOpened`] event
+    fn on_endpoint_client_rpc_channel_opened(
+        &mut self,
+        _context: &mut Context,
+        _handle: HandshakeHandle,
+        _endpoint_service_id: V3OnionServiceId,
+        _channel_name: AsciiString,
+        _channel: RpcChannel,
+        _capabilities: Capabilities,
+        _stats: HandshakeStats,
+    ) {
+    }
+
+    /// Called for each [`ContextEvent::EndpointClientDatagramChannelOpened`] event
+    fn on_endpoint_client_datagram_channel_opened(
+        &mut self,
+        _context: &mut Context,
+        _handle: HandshakeHandle,
+        _endpoint_service_id: V3OnionServiceId,
+        _channel_name: AsciiString,
+        _channel: DatagramChannel,
+        _capabilities: Capabilities,
+        _stats: HandshakeStats,
+    ) {
+    }
+
+    /// Called for each [`ContextEvent::EndpointClientHandshakeFailed`] event
+    fn on_endpoint_client_handshake_failed(
+        &mut self,
+        _context: &mut Context,
+        _handle: HandshakeHandle,
+        _reason: Error,
+        _stats: HandshakeStats,
+    ) {
+    }
+
+    /// Called for each [`ContextEvent::EndpointClientHandshakeBusy`] event
+    fn on_endpoint_client_handshake_busy(
+        &mut self,
+        _context: &mut Context,
+        _handle: HandshakeHandle,
+        _endpoint_service_id: V3OnionServiceId,
+        _channel_name: AsciiString,
+        _retry_after: Duration,
+    ) {
+    }
+
+    /// Called for each [`ContextEvent::EndpointClientEndpointRevoked`] event
+    fn on_endpoint_client_endpoint_revoked(
+        &mut self,
+        _context: &mut Context,
+        _endpoint_service_id: V3OnionServiceId,
+        _revoked_at: SystemTime,
+    ) {
+    }
+
+    /// Called for each [`ContextEvent::EndpointServerPublished`] event
+    fn on_endpoint_server_published(
+        &mut self,
+        _context: &mut Context,
+        _endpoint_service_id: V3OnionServiceId,
+        _endpoint_name: AsciiString,
+    ) {
+    }
+
+    /// Called for each [`ContextEvent::EndpointServerDescriptorRefreshed`] event
+    fn on_endpoint_server_descriptor_refreshed(
+        &mut self,
+        _context: &mut Context,
+        _endpoint_service_id: V3OnionServiceId,
+        _endpoint_name: AsciiString,
+    ) {
+    }
+
+    /// Called for each [`ContextEvent::EndpointServerPublishCompleted`] event
+    fn on_endpoint_server_publish_completed(
+        &mut self,
+        _context: &mut Context,
+        _handle: PublishHandle,
+        _endpoint_service_id: V3OnionServiceId,
+        _endpoint_name: AsciiString,
+    ) {
+    }
+
+    /// Called for each [`ContextEvent::EndpointServerPublishTimedOut`] event
+    fn on_endpoint_server_publish_timed_out(
+        &mut self,
+        _context: &mut Context,
+        _handle: PublishHandle,
+        _endpoint_service_id: V3OnionServiceId,
+        _endpoint_name: AsciiString,
+        _timeout: Duration,
+    ) {
+    }
+
+    /// Called for each [`ContextEvent::EndpointServerHandshakeStarted`] event
+    fn on_endpoint_server_handshake_started(
+        &mut self,
+        _context: &mut Context,
+        _handle: HandshakeHandle,
+    ) {
+    }
+
+    /// Called for each [`ContextEvent::EndpointServerConnectionShed`] event
+    fn on_endpoint_server_connection_shed(
+        &mut self,
+        _context: &mut Context,
+        _endpoint_service_id: V3OnionServiceId,
+    ) {
+    }
+
+    /// Called for each [`ContextEvent::EndpointServerChannelRequestReceived`] event
+    fn on_endpoint_server_channel_request_received(
+        &mut self,
+        _context: &mut Context,
+        _handle: HandshakeHandle,
+        _client_service_id: V3OnionServiceId,
+        _requested_channel: AsciiString,
+    ) {
+    }
+
+    /// Called for each [`ContextEvent::EndpointServerHandshakeCompleted`] event
+    fn on_endpoint_server_handshake_completed(
+        &mut self,
+        _context: &mut Context,
+        _handle: HandshakeHandle,
+        _endpoint_service_id: V3OnionServiceId,
+        _client_service_id: V3OnionServiceId,
+        _channel_name: AsciiString,
+        _stream: TcpStream,
+        _capabilities: Capabilities,
+        _stats: HandshakeStats,
+    ) {
+    }
+
+    /// Called for each [`ContextEvent::EndpointServerRpcChannelOpened`] event
+    fn on_endpoint_server_rpc_channel_opened(
+        &mut self,
+        _context: &mut Context,
+        _handle: HandshakeHandle,
+        _endpoint_service_id: V3OnionServiceId,
+        _client_service_id: V3OnionServiceId,
+        _channel_name: AsciiString,
+        _channel: RpcChannel,
+        _capabilities: Capabilities,
+        _stats: HandshakeStats,
+    ) {
+    }
+
+    /// Called for each [`ContextEvent::EndpointServerDatagramChannelOpened`] event
+    fn on_endpoint_server_datagram_channel_opened(
+        &mut self,
+        _context: &mut Context,
+        _handle: HandshakeHandle,
+        _endpoint_service_id: V3OnionServiceId,
+        _client_service_id: V3OnionServiceId,
+        _channel_name: AsciiString,
+        _channel: DatagramChannel,
+        _capabilities: Capabilities,
+        _stats: HandshakeStats,
+    ) {
+    }
+
+    /// Called for each [`ContextEvent::EndpointServerHandshakeRejected`] event
+    fn on_endpoint_server_handshake_rejected(
+        &mut self,
+        _context: &mut Context,
+        _handle: HandshakeHandle,
+        _client_allowed: bool,
+        _client_requested_channel_valid: bool,
+        _client_proof_signature_valid: bool,
+    ) {
+    }
+
+    /// Called for each [`ContextEvent::EndpointServerHandshakeBusy`] event
+    fn on_endpoint_server_handshake_busy(
+        &mut self,
+        _context: &mut Context,
+        _handle: HandshakeHandle,
+        _client_service_id: V3OnionServiceId,
+        _requested_channel: AsciiString,
+        _retry_after: Duration,
+    ) {
+    }
+
+    /// Called for each [`ContextEvent::EndpointServerChannelQuotaExceeded`] event
+    fn on_endpoint_server_channel_quota_exceeded(
+        &mut self,
+        _context: &mut Context,
+        _handle: HandshakeHandle,
+        _client_service_id: V3OnionServiceId,
+        _requested_channel: AsciiString,
+        _exceeded: ChannelQuotaExceeded,
+    ) {
+    }
+
+    /// Called for each [`ContextEvent::EndpointServerBannedClientRejected`] event
+    fn on_endpoint_server_banned_client_rejected(
+        &mut self,
+        _context: &mut Context,
+        _handle: HandshakeHandle,
+        _client_service_id: V3OnionServiceId,
+        _requested_channel: AsciiString,
+        _ban: Ban,
+    ) {
+    }
+
+    /// Called for each [`ContextEvent::EndpointServerHandshakeFailed`] event
+    fn on_endpoint_server_handshake_failed(
+        &mut self,
+        _context: &mut Context,
+        _handle: HandshakeHandle,
+        _reason: Error,
+        _stats: HandshakeStats,
+    ) {
+    }
+}
@@ -1,19 +1,41 @@
 // standard
+use std::collections::{HashSet, VecDeque};
+#[cfg(test)]
+use std::io::{Read, Write};
 #[cfg(test)]
 use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+// std::time::SystemTime::now() panics on wasm32-unknown-unknown, so the
+// host's clock is used there instead
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+pub(crate) use std::time::{SystemTime, UNIX_EPOCH};
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+pub(crate) use web_time::{SystemTime, UNIX_EPOCH};
 
 // extern crates
 #[cfg(test)]
 use bson::doc;
+#[cfg(test)]
+use bson::spec::BinarySubtype;
+use bson::Binary;
+use bson::Bson;
 use data_encoding::HEXLOWER;
+use honk_rpc::honk_rpc::ErrorCode;
 #[cfg(test)]
 use honk_rpc::honk_rpc::Session;
 use num_enum::TryFromPrimitive;
+// the same Instant type as the tor_interface::clock::Clock trait
+pub(crate) use tor_interface::clock::Instant;
 use tor_interface::tor_crypto::*;
 
 // internal crates
 use crate::ascii_string::*;
 #[cfg(test)]
+use crate::channel_pattern::*;
+#[cfg(test)]
+use crate::context::ClientFilterVerdict;
+#[cfg(test)]
 use crate::endpoint_client::*;
 #[cfg(test)]
 use crate::endpoint_server::*;
@@ -21,8 +43,11 @@ use crate::endpoint_server::*;
 use crate::identity_client::*;
 #[cfg(test)]
 use crate::identity_server::*;
+use crate::protocol::*;
+#[cfg(test)]
+use crate::testing::{duplex, MemoryStream};
 
-#[derive(Debug, Eq, PartialEq, TryFromPrimitive)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq, TryFromPrimitive)]
 #[repr(i32)]
 /// cbindgen:ignore
 pub(crate) enum RpcError {
@@ -34,10 +59,122 @@ pub(crate) enum RpcError {
     InvalidArg,
     // generic runtime error
     Failure,
+    // client rejected by the identity server's client filter for making too many requests
+    TooManyRequests,
+    // client rejected by the identity server's client filter as banned
+    Banned,
+    // client did not respond to the identity server's challenge before its deadline
+    ChallengeResponseTimedOut,
+    // identity server does not offer the client's requested endpoint
+    EndpointUnsupported,
+    // client's challenge response was not accepted
+    ChallengeFailed,
+    // client is not permitted or failed to prove its identity
+    NotAuthorized,
+    // endpoint server is at capacity; the error data holds a retry_after in seconds
+    Busy,
+    // client_cookie committed to in begin_handshake has been used before
+    Replayed,
+    // function called before the handshake reached it; the error data holds a
+    // ProtocolViolationErrorData
+    OutOfOrderCall,
+    // function called again after it was already called; the error data holds a
+    // ProtocolViolationErrorData
+    DuplicateCall,
+    // requested endpoint or channel name is longer than MAX_ENDPOINT_NAME_LENGTH
+    // or MAX_CHANNEL_NAME_LENGTH
+    NameTooLong,
+    // client has exceeded the endpoint server's channel quota; the error data
+    // holds an EndpointQuotaExceededErrorData
+    QuotaExceeded,
+    // client_authorization_key_signbit is not a boolean
+    InvalidSignbit,
+}
+
+// A handshake server's response to a call which does not fit its current
+// state. Calls of a known function are reported as protocol violations naming
+// the function, while unknown functions and versions get honk-rpc's own errors.
+pub(crate) fn unexpected_call_error(
+    name: &str,
+    version: i32,
+    duplicate: bool,
+    expected: Option<&str>,
+) -> (ErrorCode, Option<(RpcError, ProtocolViolationErrorData)>) {
+    match (name, version) {
+        ("begin_handshake" | "send_response", 0) => {
+            let rpc_error = if duplicate {
+                RpcError::DuplicateCall
+            } else {
+                RpcError::OutOfOrderCall
+            };
+            (
+                ErrorCode::Runtime(rpc_error as i32),
+                Some((
+                    rpc_error,
+                    ProtocolViolationErrorData {
+                        function: name.to_string(),
+                        expected: expected.map(str::to_string),
+                    },
+                )),
+            )
+        }
+        ("begin_handshake" | "send_response", _) => (ErrorCode::RequestVersionInvalid, None),
+        _ => (ErrorCode::RequestFunctionInvalid, None),
+    }
+}
+
+// Describes an error response to one of a handshake client's calls, naming the
+// offending function when the server reported a protocol violation
+pub(crate) fn describe_error_response(error_code: &ErrorCode, error_data: Option<Bson>) -> String {
+    let violation: Option<ProtocolViolationErrorData> = match error_data {
+        Some(Bson::Document(error_data)) => from_document(error_data),
+        _ => None,
+    };
+    let rpc_error = match error_code {
+        ErrorCode::Runtime(code) => RpcError::try_from_primitive(*code).ok(),
+        _ => None,
+    };
+    match (rpc_error, violation) {
+        (Some(RpcError::OutOfOrderCall), Some(violation)) => match violation.expected {
+            Some(expected) => format!(
+                "{}; {}() was called out of order, expected {}() first",
+                error_code, violation.function, expected
+            ),
+            None => format!(
+                "{}; {}() was called out of order",
+                error_code, violation.function
+            ),
+        },
+        (Some(RpcError::DuplicateCall), Some(violation)) => format!(
+            "{}; {}() was called more than once",
+            error_code, violation.function
+        ),
+        (Some(RpcError::NameTooLong), _) => {
+            format!("{}; the requested name is too long", error_code)
+        }
+        (Some(RpcError::QuotaExceeded), _) => {
+            format!("{}; the channel quota was exceeded", error_code)
+        }
+        (Some(RpcError::InvalidSignbit), _) => {
+            format!(
+                "{}; the client authorization key's signbit is not a boolean",
+                error_code
+            )
+        }
+        _ => error_code.to_string(),
+    }
 }
 
 pub(crate) const GOSLING_PROTOCOL_VERSION: &str = "0.1.0";
 
+// upper bound on the number of endpoints an identity client may request in
+// addition to its primary endpoint
+pub(crate) const MAX_ADDITIONAL_ENDPOINTS: usize = 16;
+
+// upper bound on the size in bytes of the application message an identity
+// client may attach to its endpoint request
+pub(crate) const MAX_CONTACT_REQUEST_SIZE: usize = 512;
+
 pub(crate) const CLIENT_COOKIE_SIZE: usize = 32usize;
 pub(crate) const SERVER_COOKIE_SIZE: usize = 32usize;
 
@@ -45,6 +182,82 @@ pub(crate) type ClientCookie = [u8; CLIENT_COOKIE_SIZE];
 pub(crate) type ServerCookie = [u8; SERVER_COOKIE_SIZE];
 pub(crate) type ClientProof = Vec<u8>;
 
+// how long the client cookies committed to in begin_handshake calls are
+// remembered by a ReplayCache
+pub(crate) const REPLAY_CACHE_WINDOW: Duration = Duration::from_secs(60 * 60);
+// upper bound on the number of client cookies remembered by a ReplayCache
+pub(crate) const REPLAY_CACHE_CAPACITY: usize = 65536;
+
+// Remembers the client cookies which clients commit to in their begin_handshake
+// calls so that a recorded begin_handshake cannot be replayed to make a server
+// redo the work of handling it. Cookies are forgotten once older than window or
+// once capacity newer cookies have been seen, so memory use stays bounded.
+pub(crate) struct ReplayCache {
+    window: Duration,
+    capacity: usize,
+    seen: HashSet<ClientCookie>,
+    // seen cookies, oldest first
+    order: VecDeque<(Instant, ClientCookie)>,
+}
+
+pub(crate) type SharedReplayCache = Arc<Mutex<ReplayCache>>;
+
+impl ReplayCache {
+    pub(crate) fn new(window: Duration, capacity: usize) -> Self {
+        Self {
+            window,
+            capacity,
+            seen: Default::default(),
+            order: Default::default(),
+        }
+    }
+
+    // Record client_cookie as used at now; returns false if it has been
+    // used before and the begin_handshake committing to it must be rejected
+    pub(crate) fn insert(&mut self, client_cookie: ClientCookie, now: Instant) -> bool {
+        while let Some(&(timestamp, cookie)) = self.order.front() {
+            if now.saturating_duration_since(timestamp) < self.window {
+                break;
+            }
+            self.seen.remove(&cookie);
+            self.order.pop_front();
+        }
+
+        if !self.seen.insert(client_cookie) {
+            return false;
+        }
+        // only make room once a new cookie is remembered, so rejecting a
+        // replay never forgets another cookie
+        if self.order.len() >= self.capacity {
+            if let Some((_, cookie)) = self.order.pop_front() {
+                self.seen.remove(&cookie);
+            }
+        }
+        self.order.push_back((now, client_cookie));
+        true
+    }
+}
+
+impl Default for ReplayCache {
+    fn default() -> Self {
+        Self::new(REPLAY_CACHE_WINDOW, REPLAY_CACHE_CAPACITY)
+    }
+}
+
+// Parse the client_cookie a client commits to in its begin_handshake call; it
+// may only be absent (Ok(None)) if legacy peers which do not send one are allowed
+pub(crate) fn parse_committed_client_cookie(
+    client_cookie: Option<Binary>,
+    legacy_handshakes_allowed: bool,
+) -> Result<Option<ClientCookie>, RpcError> {
+    match client_cookie.map(generic_bytes) {
+        None if legacy_handshakes_allowed => Ok(None),
+        None => Err(RpcError::InvalidArg),
+        Some(Some(bytes)) => bytes.try_into().map(Some).map_err(|_| RpcError::InvalidArg),
+        Some(None) => Err(RpcError::InvalidArg),
+    }
+}
+
 pub(crate) enum DomainSeparator {
     GoslingIdentity,
     GoslingEndpoint,
@@ -84,6 +297,99 @@ pub(crate) fn build_client_proof(
     client_proof
 }
 
+// Tracks how long a handshake state machine has spent in its current state, so
+// handshakes which stall in any one state can be failed even while the peer
+// keeps the session alive
+pub(crate) struct StateDeadline<S> {
+    deadline: Option<Duration>,
+    state: S,
+    // None until the state machine is first observed
+    entered: Option<Instant>,
+}
+
+impl<S: Copy + PartialEq> StateDeadline<S> {
+    pub(crate) fn new(state: S) -> Self {
+        Self {
+            deadline: None,
+            state,
+            entered: None,
+        }
+    }
+
+    pub(crate) fn set_deadline(&mut self, deadline: Option<Duration>) {
+        self.deadline = deadline;
+    }
+
+    // starts the deadline when the state machine is first observed and restarts
+    // it if the state machine has changed state since it was last observed,
+    // otherwise returns the deadline if it has passed
+    pub(crate) fn observe(&mut self, state: S, now: Instant) -> Option<Duration> {
+        let entered = match self.entered {
+            Some(entered) if state == self.state => entered,
+            _ => {
+                self.state = state;
+                self.entered = Some(now);
+                return None;
+            }
+        };
+        match self.deadline {
+            Some(deadline) if now.saturating_duration_since(entered) > deadline => Some(deadline),
+            _ => None,
+        }
+    }
+}
+
+// Tracks how long a handshake state machine has been waiting on its peer
+// without receiving any bytes, so handshakes whose peer has silently gone
+// away can be failed without waiting out the state deadline
+pub(crate) struct ReadIdleDeadline {
+    timeout: Option<Duration>,
+    bytes_received: u64,
+    // None while not waiting on the peer
+    idle_since: Option<Instant>,
+}
+
+impl ReadIdleDeadline {
+    pub(crate) fn new() -> Self {
+        Self {
+            timeout: None,
+            bytes_received: 0,
+            idle_since: None,
+        }
+    }
+
+    pub(crate) fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    // restarts the idle period when the state machine begins waiting on the
+    // peer or more bytes have been received since it was last observed,
+    // otherwise returns the timeout if it has passed
+    pub(crate) fn observe(
+        &mut self,
+        waiting_on_peer: bool,
+        bytes_received: u64,
+        now: Instant,
+    ) -> Option<Duration> {
+        if !waiting_on_peer {
+            self.idle_since = None;
+            return None;
+        }
+        let idle_since = match self.idle_since {
+            Some(idle_since) if bytes_received == self.bytes_received => idle_since,
+            _ => {
+                self.bytes_received = bytes_received;
+                self.idle_since = Some(now);
+                return None;
+            }
+        };
+        match self.timeout {
+            Some(timeout) if now.saturating_duration_since(idle_since) > timeout => Some(timeout),
+            _ => None,
+        }
+    }
+}
+
 //
 // Tests
 //
@@ -132,11 +438,12 @@ fn identity_test(
         client_requested_endpoint.clone(),
         client_ed25519_private,
         X25519PrivateKey::generate(),
+        false,
     )
     .unwrap();
 
     let server_rpc = Session::new(stream2);
-    let mut ident_server = IdentityServer::new(server_rpc, server_service_id.clone());
+    let mut ident_server = IdentityServer::new(server_rpc, server_service_id.clone(), None, false);
 
     let mut failure_ocurred = false;
     let mut server_complete = false;
@@ -147,6 +454,8 @@ fn identity_test(
                 Ok(Some(IdentityServerEvent::EndpointRequestReceived {
                     client_service_id,
                     requested_endpoint,
+                    additional_endpoints: _,
+                    contact_request: _,
                 })) => {
                     println!(
                         "server challenge send: client_service_id {}, requested_endpoint: {}",
@@ -171,8 +480,13 @@ fn identity_test(
                     endpoint_name,
                     client_service_id,
                     client_auth_public_key: _,
+                    endpoint_upgrade: _,
+                    additional_endpoints: _,
+                    contact_request: _,
+                    capabilities,
                 })) => {
                     assert!(endpoint_name == client_requested_endpoint);
+                    assert_eq!(capabilities, Capabilities::all());
                     println!(
                         "server complete! client_service_id : {}",
                         client_service_id.to_string()
@@ -204,6 +518,9 @@ fn identity_test(
                     server_complete = true;
                     failure_ocurred = true;
                 }
+                Ok(Some(IdentityServerEvent::HandshakeBanned { .. })) => {
+                    panic!("server unexpectedly banned client");
+                }
                 Ok(None) => {}
                 Err(err) => {
                     println!("server failure: {:?}", err);
@@ -227,9 +544,14 @@ fn identity_test(
                     endpoint_service_id,
                     endpoint_name,
                     client_auth_private_key: _,
+                    endpoint_upgrade: _,
+                    additional_endpoints: _,
+                    contact_request: _,
+                    capabilities,
                 })) => {
                     assert!(identity_service_id == server_service_id);
-                    assert!(endpoint_name == client_requested_endpoint.clone().to_string());
+                    assert_eq!(capabilities, Capabilities::all());
+                    assert!(endpoint_name == client_requested_endpoint);
                     println!(
                         "client complete! endpoint_server : {}",
                         endpoint_service_id.to_string()
@@ -350,12 +672,213 @@ fn test_identity_handshake() -> anyhow::Result<()> {
     Ok(())
 }
 
+#[cfg(test)]
+fn identity_client_filter_test(verdict: ClientFilterVerdict) -> anyhow::Result<()> {
+    // test sockets
+    let socket_addr = SocketAddr::from(([127, 0, 0, 1], 0u16));
+    let listener = TcpListener::bind(socket_addr)?;
+    let socket_addr = listener.local_addr()?;
+
+    let stream1 = TcpStream::connect(socket_addr)?;
+    stream1.set_nonblocking(true)?;
+    let (stream2, _socket_addr) = listener.accept()?;
+    stream2.set_nonblocking(true)?;
+
+    // client setup
+    let client_ed25519_private = Ed25519PrivateKey::generate();
+    let client_ed25519_public = Ed25519PublicKey::from_private_key(&client_ed25519_private);
+    let client_service_id = V3OnionServiceId::from_public_key(&client_ed25519_public);
+
+    // server setup
+    let server_ed25519_private = Ed25519PrivateKey::generate();
+    let server_ed25519_public = Ed25519PublicKey::from_private_key(&server_ed25519_private);
+    let server_service_id = V3OnionServiceId::from_public_key(&server_ed25519_public);
+
+    let mut ident_client = IdentityClient::new(
+        Session::new(stream1),
+        server_service_id.clone(),
+        AsciiString::new("endpoint".to_string())?,
+        client_ed25519_private,
+        X25519PrivateKey::generate(),
+        false,
+    )?;
+
+    let expected_client_service_id = client_service_id.clone();
+    let mut ident_server = IdentityServer::new(
+        Session::new(stream2),
+        server_service_id,
+        Some(Arc::new(
+            move |client_service_id: &V3OnionServiceId, requested_endpoint: &str| {
+                assert_eq!(*client_service_id, expected_client_service_id);
+                assert_eq!(requested_endpoint, "endpoint");
+                verdict
+            },
+        )),
+        false,
+    );
+
+    let mut server_complete = false;
+    let mut client_complete = false;
+    while !server_complete || !client_complete {
+        if !server_complete {
+            match ident_server.update() {
+                Ok(Some(IdentityServerEvent::EndpointRequestReceived { .. })) => {
+                    // rejected clients must not reach the endpoint request
+                    assert_eq!(verdict, ClientFilterVerdict::Allow);
+                    ident_server.handle_endpoint_request_received(true, true, doc!())?;
+                }
+                Ok(Some(IdentityServerEvent::ChallengeResponseReceived { .. })) => {
+                    ident_server.handle_challenge_response_received(true)?;
+                }
+                Ok(Some(IdentityServerEvent::HandshakeCompleted {
+                    client_service_id: ret_client_service_id,
+                    ..
+                })) => {
+                    assert_eq!(verdict, ClientFilterVerdict::Allow);
+                    assert_eq!(ret_client_service_id, client_service_id);
+                    server_complete = true;
+                }
+                Ok(Some(IdentityServerEvent::HandshakeRejected { .. })) => {
+                    panic!("server unexpectedly rejected handshake");
+                }
+                Ok(Some(IdentityServerEvent::HandshakeBanned { .. })) => {
+                    panic!("server unexpectedly banned client");
+                }
+                Ok(None) => {}
+                Err(err) => {
+                    println!("server failure: {:?}", err);
+                    assert!(
+                        matches!(err, crate::identity_server::Error::ClientRejected(ret_verdict) if ret_verdict == verdict)
+                    );
+                    server_complete = true;
+                }
+            }
+        }
+
+        if !client_complete {
+            match ident_client.update() {
+                Ok(Some(IdentityClientEvent::ChallengeReceived { .. })) => {
+                    ident_client.send_response(doc!())?;
+                }
+                Ok(Some(IdentityClientEvent::HandshakeCompleted { .. })) => {
+                    assert_eq!(verdict, ClientFilterVerdict::Allow);
+                    client_complete = true;
+                }
+                Ok(None) => {}
+                Err(err) => {
+                    println!("client failure: {:?}", err);
+                    assert_ne!(verdict, ClientFilterVerdict::Allow);
+                    client_complete = true;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_identity_client_filter() -> anyhow::Result<()> {
+    println!("Allow ---");
+    identity_client_filter_test(ClientFilterVerdict::Allow)?;
+    println!("Too Many Requests ---");
+    identity_client_filter_test(ClientFilterVerdict::TooManyRequests)?;
+    println!("Banned ---");
+    identity_client_filter_test(ClientFilterVerdict::Banned)?;
+    Ok(())
+}
+
+#[test]
+fn test_identity_challenge_response_deadline() -> anyhow::Result<()> {
+    // test sockets
+    let socket_addr = SocketAddr::from(([127, 0, 0, 1], 0u16));
+    let listener = TcpListener::bind(socket_addr)?;
+    let socket_addr = listener.local_addr()?;
+
+    let stream1 = TcpStream::connect(socket_addr)?;
+    stream1.set_nonblocking(true)?;
+    let (stream2, _socket_addr) = listener.accept()?;
+    stream2.set_nonblocking(true)?;
+
+    // server setup
+    let server_ed25519_private = Ed25519PrivateKey::generate();
+    let server_ed25519_public = Ed25519PublicKey::from_private_key(&server_ed25519_private);
+    let server_service_id = V3OnionServiceId::from_public_key(&server_ed25519_public);
+
+    let mut ident_client = IdentityClient::new(
+        Session::new(stream1),
+        server_service_id.clone(),
+        AsciiString::new("endpoint".to_string())?,
+        Ed25519PrivateKey::generate(),
+        X25519PrivateKey::generate(),
+        false,
+    )?;
+
+    let deadline = std::time::Duration::from_millis(500);
+    let mut ident_server =
+        IdentityServer::new(Session::new(stream2), server_service_id, None, false);
+    ident_server.set_challenge_response_deadline(Some(deadline));
+
+    let start = std::time::Instant::now();
+    let mut challenge_received = false;
+    let mut server_complete = false;
+    let mut client_complete = false;
+    while !server_complete || !client_complete {
+        assert!(start.elapsed() < std::time::Duration::from_secs(10));
+
+        if !server_complete {
+            match ident_server.update() {
+                Ok(Some(IdentityServerEvent::EndpointRequestReceived { .. })) => {
+                    ident_server.handle_endpoint_request_received(true, true, doc!())?;
+                }
+                Ok(Some(_)) => panic!("server received unexpected event"),
+                Ok(None) => {}
+                Err(err) => {
+                    println!("server failure: {:?}", err);
+                    assert!(
+                        matches!(err, crate::identity_server::Error::ChallengeResponseTimedOut(ret_deadline) if ret_deadline == deadline)
+                    );
+                    assert!(start.elapsed() >= deadline);
+                    server_complete = true;
+                }
+            }
+        }
+
+        if !client_complete {
+            match ident_client.update() {
+                Ok(Some(IdentityClientEvent::ChallengeReceived { .. })) => {
+                    // never respond to the challenge
+                    challenge_received = true;
+                }
+                Ok(Some(_)) => panic!("client received unexpected event"),
+                Ok(None) => {}
+                Err(err) => {
+                    println!("client failure: {:?}", err);
+                    assert!(challenge_received);
+                    assert!(matches!(
+                        err,
+                        crate::identity_client::Error::HonkRPCFailure(
+                            honk_rpc::honk_rpc::Error::UnknownErrorSectionReceived(
+                                honk_rpc::honk_rpc::ErrorCode::Runtime(code)
+                            )
+                        ) if code == RpcError::ChallengeResponseTimedOut as i32
+                    ));
+                    client_complete = true;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
 #[cfg(test)]
 fn endpoint_test(
     should_fail: bool,
     client_allowed: bool,
     channel: &str,
     channel_allowed: bool,
+    channel_pattern: Option<&str>,
 ) -> anyhow::Result<()> {
     // test sockets
     let socket_addr = SocketAddr::from(([127, 0, 0, 1], 0u16));
@@ -387,10 +910,20 @@ fn endpoint_test(
 
     let server_rpc = Session::new(stream1);
 
+    let channel_patterns = match channel_pattern {
+        Some(channel_pattern) => vec![ChannelPattern::new(channel_pattern.to_string())?],
+        None => Default::default(),
+    };
+    let channel_pattern_matches = channel_patterns
+        .iter()
+        .any(|channel_pattern| channel_pattern.matches(channel));
+
     let mut endpoint_server = EndpointServer::new(
         server_rpc,
         allowed_client.clone(),
         server_service_id.clone(),
+        channel_patterns,
+        false,
     );
 
     let client_rpc = Session::new(stream2);
@@ -422,13 +955,17 @@ fn endpoint_test(
                 })) => {
                     assert_eq!(ret_client_service_id, client_service_id);
                     assert!(requested_channel == channel);
+                    // matching channels must not be passed to the caller
+                    assert!(!channel_pattern_matches);
                     endpoint_server.handle_channel_request_received(channel_allowed)?;
                 }
                 Ok(Some(EndpointServerEvent::HandshakeCompleted {
                     client_service_id: ret_client_service_id,
                     channel_name: ret_channel,
                     stream: _,
+                    capabilities,
                 })) => {
+                    assert_eq!(capabilities, Capabilities::all());
                     assert!(ret_client_service_id == client_service_id);
                     assert!(ret_channel == channel);
                     server_complete = true;
@@ -442,6 +979,18 @@ fn endpoint_test(
                     server_complete = true;
                     failure_ocurred = true;
                 }
+                Ok(Some(EndpointServerEvent::HandshakeBusy { .. })) => {
+                    panic!("server unexpectedly busy");
+                }
+                Ok(Some(EndpointServerEvent::HandshakeQuotaExceeded { .. })) => {
+                    panic!("server unexpectedly exceeded channel quota");
+                }
+                Ok(Some(EndpointServerEvent::HandshakeBanned { .. })) => {
+                    panic!("server unexpectedly banned client");
+                }
+                Ok(Some(EndpointServerEvent::RpcChannelOpened { .. })) => {
+                    panic!("server unexpectedly opened rpc channel");
+                }
                 Ok(None) => {}
                 Err(err) => {
                     println!("server failure: {:?}", err);
@@ -453,9 +1002,16 @@ fn endpoint_test(
 
         if !client_complete {
             match endpoint_client.update() {
-                Ok(Some(EndpointClientEvent::HandshakeCompleted { stream: _ })) => {
+                Ok(Some(EndpointClientEvent::HandshakeCompleted {
+                    stream: _,
+                    capabilities,
+                })) => {
+                    assert_eq!(capabilities, Capabilities::all());
                     client_complete = true;
                 }
+                Ok(Some(EndpointClientEvent::RpcChannelOpened { .. })) => {
+                    panic!("client unexpectedly opened rpc channel");
+                }
                 Ok(None) => {}
                 Err(err) => {
                     println!("client failure: {:?}", err);
@@ -482,7 +1038,7 @@ fn test_endpoint_handshake() -> anyhow::Result<()> {
         let client_allowed = true;
         let channel = "channel";
         let channel_allowed = true;
-        endpoint_test(should_fail, client_allowed, channel, channel_allowed)?;
+        endpoint_test(should_fail, client_allowed, channel, channel_allowed, None)?;
     }
     println!("Client Not Allowed ---");
     {
@@ -490,7 +1046,7 @@ fn test_endpoint_handshake() -> anyhow::Result<()> {
         let client_allowed = false;
         let channel = "channel";
         let channel_allowed = true;
-        endpoint_test(should_fail, client_allowed, channel, channel_allowed)?;
+        endpoint_test(should_fail, client_allowed, channel, channel_allowed, None)?;
     }
     println!("Channel Not Allowed ---");
     {
@@ -498,7 +1054,7 @@ fn test_endpoint_handshake() -> anyhow::Result<()> {
         let client_allowed = true;
         let channel = "channel";
         let channel_allowed = false;
-        endpoint_test(should_fail, client_allowed, channel, channel_allowed)?;
+        endpoint_test(should_fail, client_allowed, channel, channel_allowed, None)?;
     }
     println!("Client and Channel Not Allowed ---");
     {
@@ -506,7 +1062,7 @@ fn test_endpoint_handshake() -> anyhow::Result<()> {
         let client_allowed = false;
         let channel = "channel";
         let channel_allowed = false;
-        endpoint_test(should_fail, client_allowed, channel, channel_allowed)?;
+        endpoint_test(should_fail, client_allowed, channel, channel_allowed, None)?;
     }
     println!("Non-Ascii Channel ---");
     {
@@ -514,7 +1070,1595 @@ fn test_endpoint_handshake() -> anyhow::Result<()> {
         let client_allowed = true;
         let channel = "𝕦𝕥𝕗𝟠";
         let channel_allowed = true;
-        endpoint_test(should_fail, client_allowed, channel, channel_allowed)?;
+        endpoint_test(should_fail, client_allowed, channel, channel_allowed, None)?;
+    }
+    println!("Channel Allowed By Pattern ---");
+    {
+        let should_fail = false;
+        let client_allowed = true;
+        let channel = "file-transfer/photos";
+        let channel_allowed = false;
+        let channel_pattern = Some("file-transfer/*");
+        endpoint_test(
+            should_fail,
+            client_allowed,
+            channel,
+            channel_allowed,
+            channel_pattern,
+        )?;
+    }
+    println!("Channel Not Matched By Pattern ---");
+    {
+        let should_fail = true;
+        let client_allowed = true;
+        let channel = "chat";
+        let channel_allowed = false;
+        let channel_pattern = Some("file-transfer/*");
+        endpoint_test(
+            should_fail,
+            client_allowed,
+            channel,
+            channel_allowed,
+            channel_pattern,
+        )?;
+    }
+    println!("Client Not Allowed With Pattern ---");
+    {
+        let should_fail = true;
+        let client_allowed = false;
+        let channel = "file-transfer/photos";
+        let channel_allowed = true;
+        let channel_pattern = Some("file-transfer/*");
+        endpoint_test(
+            should_fail,
+            client_allowed,
+            channel,
+            channel_allowed,
+            channel_pattern,
+        )?;
+    }
+
+    Ok(())
+}
+
+// drives an endpoint handshake from a client running the legacy protocol
+// revision, whose begin_handshake() call omits the client_identity argument
+#[cfg(test)]
+fn legacy_endpoint_test(legacy_handshakes_allowed: bool) -> anyhow::Result<bool> {
+    // test sockets
+    let socket_addr = SocketAddr::from(([127, 0, 0, 1], 0u16));
+    let listener = TcpListener::bind(socket_addr)?;
+    let socket_addr = listener.local_addr()?;
+
+    let stream1 = TcpStream::connect(socket_addr)?;
+    stream1.set_nonblocking(true)?;
+    let (stream2, _socket_addr) = listener.accept()?;
+    stream2.set_nonblocking(true)?;
+
+    // server+client setup
+    let server_ed25519_private = Ed25519PrivateKey::generate();
+    let server_ed25519_public = Ed25519PublicKey::from_private_key(&server_ed25519_private);
+    let server_service_id = V3OnionServiceId::from_public_key(&server_ed25519_public);
+
+    let client_ed25519_private = Ed25519PrivateKey::generate();
+    let client_ed25519_public = Ed25519PublicKey::from_private_key(&client_ed25519_private);
+    let client_service_id = V3OnionServiceId::from_public_key(&client_ed25519_public);
+
+    let channel = AsciiString::new("channel".to_string())?;
+
+    let mut endpoint_server = EndpointServer::new(
+        Session::new(stream1),
+        client_service_id.clone(),
+        server_service_id.clone(),
+        Default::default(),
+        legacy_handshakes_allowed,
+    );
+
+    let mut client_rpc = Session::new(stream2);
+    let begin_handshake_cookie = client_rpc.client_call(
+        "gosling_endpoint",
+        "begin_handshake",
+        0,
+        doc! {
+            "version" : GOSLING_PROTOCOL_VERSION,
+            "channel" : channel.to_string(),
+        },
+    )?;
+    let mut send_response_cookie = None;
+
+    loop {
+        match endpoint_server.update() {
+            Ok(Some(EndpointServerEvent::ChannelRequestReceived {
+                client_service_id: ret_client_service_id,
+                requested_channel,
+            })) => {
+                assert_eq!(ret_client_service_id, client_service_id);
+                assert!(requested_channel == channel);
+                endpoint_server.handle_channel_request_received(true)?;
+            }
+            Ok(Some(EndpointServerEvent::HandshakeCompleted {
+                client_service_id: ret_client_service_id,
+                channel_name,
+                stream: _,
+                capabilities,
+            })) => {
+                assert_eq!(ret_client_service_id, client_service_id);
+                // legacy clients do not negotiate capabilities
+                assert!(capabilities.is_empty());
+                assert!(channel_name == channel);
+                return Ok(true);
+            }
+            Ok(Some(EndpointServerEvent::HandshakeRejected { .. })) => return Ok(false),
+            Ok(Some(EndpointServerEvent::HandshakeBusy { .. })) => {
+                panic!("server unexpectedly busy");
+            }
+            Ok(Some(EndpointServerEvent::HandshakeQuotaExceeded { .. })) => {
+                panic!("server unexpectedly exceeded channel quota");
+            }
+            Ok(Some(EndpointServerEvent::HandshakeBanned { .. })) => {
+                panic!("server unexpectedly banned client");
+            }
+            Ok(Some(EndpointServerEvent::RpcChannelOpened { .. })) => {
+                panic!("server unexpectedly opened rpc channel");
+            }
+            Ok(None) => {}
+            Err(err) => {
+                println!("server failure: {:?}", err);
+                return Ok(false);
+            }
+        }
+
+        client_rpc.update(None)?;
+        match client_rpc.client_next_response() {
+            Some(honk_rpc::honk_rpc::Response::Success {
+                cookie,
+                result: Some(bson::Bson::Document(result)),
+            }) if cookie == begin_handshake_cookie => {
+                let server_cookie: ServerCookie = match result.get("server_cookie") {
+                    Some(bson::Bson::Binary(server_cookie)) => {
+                        server_cookie.bytes.clone().try_into().unwrap()
+                    }
+                    _ => panic!("begin_handshake() returned unexpected value: {}", result),
+                };
+                let client_cookie: ClientCookie = [0x42u8; CLIENT_COOKIE_SIZE];
+                let client_proof = build_client_proof(
+                    DomainSeparator::GoslingEndpoint,
+                    &channel,
+                    &client_service_id,
+                    &server_service_id,
+                    &client_cookie,
+                    &server_cookie,
+                );
+                let signature = client_ed25519_private.sign_message(&client_proof);
+                send_response_cookie = Some(client_rpc.client_call(
+                    "gosling_endpoint",
+                    "send_response",
+                    0,
+                    doc! {
+                        "client_cookie" : bson::Binary{subtype: bson::spec::BinarySubtype::Generic, bytes: client_cookie.to_vec()},
+                        "client_identity_proof_signature" : bson::Binary{subtype: bson::spec::BinarySubtype::Generic, bytes: signature.to_bytes().to_vec()},
+                    },
+                )?);
+            }
+            Some(honk_rpc::honk_rpc::Response::Success { cookie, .. })
+                if Some(cookie) == send_response_cookie => {}
+            Some(honk_rpc::honk_rpc::Response::Error { error_code, .. }) => {
+                println!("client received error: {:?}", error_code);
+            }
+            _ => {}
+        }
+    }
+}
+
+#[test]
+fn test_legacy_endpoint_handshake() -> anyhow::Result<()> {
+    println!("Legacy Handshake Allowed ---");
+    assert!(legacy_endpoint_test(true)?);
+    println!("Legacy Handshake Not Allowed ---");
+    assert!(!legacy_endpoint_test(false)?);
+    Ok(())
+}
+
+#[cfg(test)]
+fn endpoint_upgrade_test(upgrade_requested: bool, upgrade_allowed: bool) -> anyhow::Result<bool> {
+    // test sockets
+    let socket_addr = SocketAddr::from(([127, 0, 0, 1], 0u16));
+    let listener = TcpListener::bind(socket_addr)?;
+    let socket_addr = listener.local_addr()?;
+
+    let stream1 = TcpStream::connect(socket_addr)?;
+    stream1.set_nonblocking(true)?;
+    let (stream2, _socket_addr) = listener.accept()?;
+    stream2.set_nonblocking(true)?;
+
+    // client setup
+    let client_ed25519_private = Ed25519PrivateKey::generate();
+    let client_service_id = V3OnionServiceId::from_private_key(&client_ed25519_private);
+
+    // server setup
+    let server_ed25519_private = Ed25519PrivateKey::generate();
+    let server_service_id = V3OnionServiceId::from_private_key(&server_ed25519_private);
+
+    let mut ident_client = IdentityClient::new(
+        Session::new(stream1),
+        server_service_id.clone(),
+        AsciiString::new("endpoint".to_string())?,
+        client_ed25519_private.clone(),
+        X25519PrivateKey::generate(),
+        upgrade_requested,
+    )?;
+    let mut ident_server = IdentityServer::new(
+        Session::new(stream2),
+        server_service_id,
+        None,
+        upgrade_allowed,
+    );
+
+    // run the identity handshake to completion
+    let mut server_result: Option<(V3OnionServiceId, bool)> = None;
+    let mut client_result: Option<(V3OnionServiceId, bool)> = None;
+    while server_result.is_none() || client_result.is_none() {
+        if server_result.is_none() {
+            match ident_server.update()? {
+                Some(IdentityServerEvent::EndpointRequestReceived { .. }) => {
+                    ident_server.handle_endpoint_request_received(true, true, doc!())?;
+                }
+                Some(IdentityServerEvent::ChallengeResponseReceived { .. }) => {
+                    ident_server.handle_challenge_response_received(true)?;
+                }
+                Some(IdentityServerEvent::HandshakeCompleted {
+                    endpoint_private_key,
+                    endpoint_upgrade,
+                    ..
+                }) => {
+                    server_result = Some((
+                        V3OnionServiceId::from_private_key(&endpoint_private_key),
+                        endpoint_upgrade,
+                    ));
+                }
+                Some(IdentityServerEvent::HandshakeRejected { .. }) => {
+                    panic!("server unexpectedly rejected handshake");
+                }
+                Some(IdentityServerEvent::HandshakeBanned { .. }) => {
+                    panic!("server unexpectedly banned client");
+                }
+                None => {}
+            }
+        }
+
+        if client_result.is_none() {
+            match ident_client.update()? {
+                Some(IdentityClientEvent::ChallengeReceived { .. }) => {
+                    ident_client.send_response(doc!())?;
+                }
+                Some(IdentityClientEvent::HandshakeCompleted {
+                    endpoint_service_id,
+                    endpoint_upgrade,
+                    ..
+                }) => {
+                    client_result = Some((endpoint_service_id, endpoint_upgrade));
+                }
+                None => {}
+            }
+        }
+    }
+
+    // both sides must agree on the outcome of the negotiation
+    let (endpoint_service_id, server_upgrade) = server_result.unwrap();
+    let (client_endpoint_service_id, client_upgrade) = client_result.unwrap();
+    assert_eq!(endpoint_service_id, client_endpoint_service_id);
+    assert_eq!(server_upgrade, client_upgrade);
+    assert_eq!(server_upgrade, upgrade_requested && upgrade_allowed);
+    if !server_upgrade {
+        return Ok(false);
+    }
+
+    // continue with an endpoint handshake over the identity handshake's connection
+    let channel = AsciiString::new("channel".to_string())?;
+    let mut endpoint_server = EndpointServer::new(
+        ident_server.into_session().unwrap(),
+        client_service_id.clone(),
+        endpoint_service_id.clone(),
+        Default::default(),
+        false,
+    );
+    let mut endpoint_client = EndpointClient::new(
+        ident_client.into_session(),
+        endpoint_service_id,
+        channel.clone(),
+        client_ed25519_private,
+    );
+
+    let mut server_complete = false;
+    let mut client_complete = false;
+    while !server_complete || !client_complete {
+        if !server_complete {
+            match endpoint_server.update()? {
+                Some(EndpointServerEvent::ChannelRequestReceived {
+                    client_service_id: ret_client_service_id,
+                    requested_channel,
+                }) => {
+                    assert_eq!(ret_client_service_id, client_service_id);
+                    assert!(requested_channel == channel);
+                    endpoint_server.handle_channel_request_received(true)?;
+                }
+                Some(EndpointServerEvent::HandshakeCompleted {
+                    client_service_id: ret_client_service_id,
+                    ..
+                }) => {
+                    assert_eq!(ret_client_service_id, client_service_id);
+                    server_complete = true;
+                }
+                Some(EndpointServerEvent::HandshakeRejected { .. }) => {
+                    panic!("server unexpectedly rejected endpoint handshake");
+                }
+                Some(EndpointServerEvent::HandshakeBusy { .. }) => {
+                    panic!("server unexpectedly busy");
+                }
+                Some(EndpointServerEvent::HandshakeQuotaExceeded { .. }) => {
+                    panic!("server unexpectedly exceeded channel quota");
+                }
+                Some(EndpointServerEvent::HandshakeBanned { .. }) => {
+                    panic!("server unexpectedly banned client");
+                }
+                Some(EndpointServerEvent::RpcChannelOpened { .. }) => {
+                    panic!("server unexpectedly opened rpc channel");
+                }
+                None => {}
+            }
+        }
+
+        if !client_complete {
+            if let Some(EndpointClientEvent::HandshakeCompleted { .. }) =
+                endpoint_client.update()?
+            {
+                client_complete = true;
+            }
+        }
+    }
+
+    Ok(true)
+}
+
+#[test]
+fn test_endpoint_upgrade() -> anyhow::Result<()> {
+    println!("Upgrade Requested and Allowed ---");
+    assert!(endpoint_upgrade_test(true, true)?);
+    println!("Upgrade Requested but not Allowed ---");
+    assert!(!endpoint_upgrade_test(true, false)?);
+    println!("Upgrade Allowed but not Requested ---");
+    assert!(!endpoint_upgrade_test(false, true)?);
+    Ok(())
+}
+
+// returns the additional endpoints granted to the client; the server grants or
+// denies each additional endpoint per additional_endpoints_valid, or denies all
+// of them if it is None
+#[cfg(test)]
+fn additional_endpoints_test(
+    additional_endpoints_valid: Option<Vec<bool>>,
+) -> anyhow::Result<Vec<String>> {
+    // test sockets
+    let socket_addr = SocketAddr::from(([127, 0, 0, 1], 0u16));
+    let listener = TcpListener::bind(socket_addr)?;
+    let socket_addr = listener.local_addr()?;
+
+    let stream1 = TcpStream::connect(socket_addr)?;
+    stream1.set_nonblocking(true)?;
+    let (stream2, _socket_addr) = listener.accept()?;
+    stream2.set_nonblocking(true)?;
+
+    // client setup
+    let client_ed25519_private = Ed25519PrivateKey::generate();
+
+    // server setup
+    let server_ed25519_private = Ed25519PrivateKey::generate();
+    let server_service_id = V3OnionServiceId::from_private_key(&server_ed25519_private);
+
+    let additional_endpoints = vec![
+        AsciiString::new("files".to_string())?,
+        AsciiString::new("voice".to_string())?,
+        AsciiString::new("video".to_string())?,
+    ];
+
+    let mut ident_client = IdentityClient::new(
+        Session::new(stream1),
+        server_service_id.clone(),
+        AsciiString::new("chat".to_string())?,
+        client_ed25519_private,
+        X25519PrivateKey::generate(),
+        false,
+    )?;
+    // endpoints may not be requested twice
+    assert!(ident_client
+        .set_additional_endpoints(vec![AsciiString::new("chat".to_string())?])
+        .is_err());
+    assert!(ident_client
+        .set_additional_endpoints(vec![
+            AsciiString::new("files".to_string())?,
+            AsciiString::new("files".to_string())?,
+        ])
+        .is_err());
+    ident_client.set_additional_endpoints(additional_endpoints.clone())?;
+
+    let mut ident_server =
+        IdentityServer::new(Session::new(stream2), server_service_id, None, false);
+
+    // run the identity handshake to completion
+    let mut server_result: Option<Vec<(AsciiString, Ed25519PrivateKey)>> = None;
+    let mut client_result: Option<Vec<(AsciiString, Option<V3OnionServiceId>)>> = None;
+    while server_result.is_none() || client_result.is_none() {
+        if server_result.is_none() {
+            match ident_server.update()? {
+                Some(IdentityServerEvent::EndpointRequestReceived {
+                    additional_endpoints: requested_additional_endpoints,
+                    ..
+                }) => {
+                    assert_eq!(requested_additional_endpoints, additional_endpoints);
+                    match additional_endpoints_valid.clone() {
+                        Some(additional_endpoints_valid) => {
+                            // the validity of every additional endpoint is required
+                            assert!(ident_server
+                                .handle_endpoint_request_received_with_additional_endpoints(
+                                    true,
+                                    true,
+                                    Default::default(),
+                                    doc!(),
+                                )
+                                .is_err());
+                            ident_server
+                                .handle_endpoint_request_received_with_additional_endpoints(
+                                    true,
+                                    true,
+                                    additional_endpoints_valid,
+                                    doc!(),
+                                )?;
+                        }
+                        None => {
+                            ident_server.handle_endpoint_request_received(true, true, doc!())?
+                        }
+                    }
+                }
+                Some(IdentityServerEvent::ChallengeResponseReceived { .. }) => {
+                    ident_server.handle_challenge_response_received(true)?;
+                }
+                Some(IdentityServerEvent::HandshakeCompleted {
+                    additional_endpoints,
+                    ..
+                }) => {
+                    server_result = Some(additional_endpoints);
+                }
+                Some(IdentityServerEvent::HandshakeRejected { .. }) => {
+                    panic!("server unexpectedly rejected handshake");
+                }
+                Some(IdentityServerEvent::HandshakeBanned { .. }) => {
+                    panic!("server unexpectedly banned client");
+                }
+                None => {}
+            }
+        }
+
+        if client_result.is_none() {
+            match ident_client.update()? {
+                Some(IdentityClientEvent::ChallengeReceived { .. }) => {
+                    ident_client.send_response(doc!())?;
+                }
+                Some(IdentityClientEvent::HandshakeCompleted {
+                    additional_endpoints,
+                    ..
+                }) => {
+                    client_result = Some(additional_endpoints);
+                }
+                None => {}
+            }
+        }
+    }
+
+    // the client learns the outcome of every additional endpoint it requested
+    let server_result = server_result.unwrap();
+    let client_result = client_result.unwrap();
+    assert_eq!(client_result.len(), additional_endpoints.len());
+
+    let mut granted: Vec<String> = Default::default();
+    for ((endpoint_name, endpoint_service_id), requested_endpoint) in
+        client_result.into_iter().zip(additional_endpoints.iter())
+    {
+        assert_eq!(endpoint_name, *requested_endpoint);
+        let server_grant = server_result
+            .iter()
+            .find(|(server_endpoint_name, _)| *server_endpoint_name == *requested_endpoint);
+        match (endpoint_service_id, server_grant) {
+            (Some(endpoint_service_id), Some((_, endpoint_private_key))) => {
+                assert_eq!(
+                    endpoint_service_id,
+                    V3OnionServiceId::from_private_key(endpoint_private_key)
+                );
+                granted.push(endpoint_name.into_string());
+            }
+            (None, None) => (),
+            _ => panic!("client and server disagree on '{}'", endpoint_name),
+        }
+    }
+    assert_eq!(server_result.len(), granted.len());
+
+    Ok(granted)
+}
+
+#[test]
+fn test_identity_handshake_additional_endpoints() -> anyhow::Result<()> {
+    println!("Additional Endpoints Partially Granted ---");
+    assert_eq!(
+        additional_endpoints_test(Some(vec![true, false, true]))?,
+        ["files", "video"]
+    );
+    println!("Additional Endpoints Implicitly Denied ---");
+    assert!(additional_endpoints_test(None)?.is_empty());
+    Ok(())
+}
+
+// returns the capabilities negotiated by the client and server, the client's
+// grant of its one additional endpoint and the contact request the server
+// received; the server grants every endpoint
+#[cfg(test)]
+fn capabilities_test(
+    client_capabilities: Capabilities,
+    server_capabilities: Capabilities,
+) -> anyhow::Result<(Capabilities, Capabilities, bool, Option<String>)> {
+    // test sockets
+    let socket_addr = SocketAddr::from(([127, 0, 0, 1], 0u16));
+    let listener = TcpListener::bind(socket_addr)?;
+    let socket_addr = listener.local_addr()?;
+
+    let stream1 = TcpStream::connect(socket_addr)?;
+    stream1.set_nonblocking(true)?;
+    let (stream2, _socket_addr) = listener.accept()?;
+    stream2.set_nonblocking(true)?;
+
+    let server_ed25519_private = Ed25519PrivateKey::generate();
+    let server_service_id = V3OnionServiceId::from_private_key(&server_ed25519_private);
+
+    let mut ident_client = IdentityClient::new(
+        Session::new(stream1),
+        server_service_id.clone(),
+        AsciiString::new("chat".to_string())?,
+        Ed25519PrivateKey::generate(),
+        X25519PrivateKey::generate(),
+        false,
+    )?;
+    ident_client.set_additional_endpoints(vec![AsciiString::new("files".to_string())?])?;
+    ident_client.set_contact_request(Some("hello".to_string()))?;
+    ident_client.set_capabilities(client_capabilities)?;
+
+    let mut ident_server =
+        IdentityServer::new(Session::new(stream2), server_service_id, None, false);
+    ident_server.set_capabilities(server_capabilities);
+
+    let mut server_result: Option<(Capabilities, Option<String>)> = None;
+    let mut client_result: Option<(Capabilities, bool)> = None;
+    while server_result.is_none() || client_result.is_none() {
+        if server_result.is_none() {
+            match ident_server.update()? {
+                Some(IdentityServerEvent::EndpointRequestReceived {
+                    additional_endpoints,
+                    ..
+                }) => {
+                    ident_server.handle_endpoint_request_received_with_additional_endpoints(
+                        true,
+                        true,
+                        vec![true; additional_endpoints.len()],
+                        doc!(),
+                    )?;
+                }
+                Some(IdentityServerEvent::ChallengeResponseReceived { .. }) => {
+                    ident_server.handle_challenge_response_received(true)?;
+                }
+                Some(IdentityServerEvent::HandshakeCompleted {
+                    contact_request,
+                    capabilities,
+                    ..
+                }) => {
+                    server_result = Some((capabilities, contact_request));
+                }
+                Some(IdentityServerEvent::HandshakeRejected { .. }) => {
+                    panic!("server unexpectedly rejected handshake");
+                }
+                Some(IdentityServerEvent::HandshakeBanned { .. }) => {
+                    panic!("server unexpectedly banned client");
+                }
+                None => {}
+            }
+        }
+
+        if client_result.is_none() {
+            match ident_client.update()? {
+                Some(IdentityClientEvent::ChallengeReceived { .. }) => {
+                    ident_client.send_response(doc!())?;
+                }
+                Some(IdentityClientEvent::HandshakeCompleted {
+                    additional_endpoints,
+                    capabilities,
+                    ..
+                }) => {
+                    assert_eq!(additional_endpoints.len(), 1);
+                    client_result = Some((capabilities, additional_endpoints[0].1.is_some()));
+                }
+                None => {}
+            }
+        }
+    }
+
+    let (server_capabilities, contact_request) = server_result.unwrap();
+    let (client_capabilities, additional_endpoint_granted) = client_result.unwrap();
+    Ok((
+        client_capabilities,
+        server_capabilities,
+        additional_endpoint_granted,
+        contact_request,
+    ))
+}
+
+#[test]
+fn test_identity_handshake_capabilities() -> anyhow::Result<()> {
+    println!("All Capabilities ---");
+    let all = Capabilities::all();
+    assert_eq!(
+        capabilities_test(all, all)?,
+        (all, all, true, Some("hello".to_string()))
+    );
+
+    println!("Server Without Optional Endpoint Request Features ---");
+    let server_capabilities =
+        all.difference(Capabilities::ADDITIONAL_ENDPOINTS | Capabilities::CONTACT_REQUEST);
+    assert_eq!(
+        capabilities_test(all, server_capabilities)?,
+        (server_capabilities, server_capabilities, false, None)
+    );
+
+    println!("Client Without Contact Requests ---");
+    let client_capabilities = all.difference(Capabilities::CONTACT_REQUEST);
+    assert_eq!(
+        capabilities_test(client_capabilities, all)?,
+        (client_capabilities, client_capabilities, true, None)
+    );
+
+    println!("No Capabilities ---");
+    assert_eq!(
+        capabilities_test(Capabilities::empty(), all)?,
+        (Capabilities::empty(), Capabilities::empty(), false, None)
+    );
+    Ok(())
+}
+
+#[test]
+fn test_handshakes_over_memory_stream() -> anyhow::Result<()> {
+    let (client_stream, server_stream) = duplex();
+
+    // client setup
+    let client_ed25519_private = Ed25519PrivateKey::generate();
+    let client_service_id = V3OnionServiceId::from_private_key(&client_ed25519_private);
+
+    // server setup
+    let server_ed25519_private = Ed25519PrivateKey::generate();
+    let server_service_id = V3OnionServiceId::from_private_key(&server_ed25519_private);
+
+    println!("--- identity handshake");
+    let mut ident_client = IdentityClient::new(
+        Session::new(client_stream),
+        server_service_id.clone(),
+        AsciiString::new("endpoint".to_string())?,
+        client_ed25519_private.clone(),
+        X25519PrivateKey::generate(),
+        false,
+    )?;
+    let mut ident_server = IdentityServer::new(
+        Session::new(server_stream),
+        server_service_id.clone(),
+        None,
+        false,
+    );
+
+    let mut endpoint_private_key: Option<Ed25519PrivateKey> = None;
+    let mut client_complete = false;
+    while endpoint_private_key.is_none() || !client_complete {
+        if endpoint_private_key.is_none() {
+            match ident_server.update()? {
+                Some(IdentityServerEvent::EndpointRequestReceived { .. }) => {
+                    ident_server.handle_endpoint_request_received(true, true, doc!())?;
+                }
+                Some(IdentityServerEvent::ChallengeResponseReceived { .. }) => {
+                    ident_server.handle_challenge_response_received(true)?;
+                }
+                Some(IdentityServerEvent::HandshakeCompleted {
+                    endpoint_private_key: ret_endpoint_private_key,
+                    ..
+                }) => {
+                    endpoint_private_key = Some(*ret_endpoint_private_key);
+                }
+                Some(IdentityServerEvent::HandshakeRejected { .. }) => {
+                    panic!("server unexpectedly rejected handshake");
+                }
+                Some(IdentityServerEvent::HandshakeBanned { .. }) => {
+                    panic!("server unexpectedly banned client");
+                }
+                None => {}
+            }
+        }
+
+        if !client_complete {
+            match ident_client.update()? {
+                Some(IdentityClientEvent::ChallengeReceived { .. }) => {
+                    ident_client.send_response(doc!())?;
+                }
+                Some(IdentityClientEvent::HandshakeCompleted { .. }) => {
+                    client_complete = true;
+                }
+                None => {}
+            }
+        }
+    }
+
+    println!("--- endpoint handshake over the same transport");
+    let endpoint_private_key = endpoint_private_key.unwrap();
+    let endpoint_service_id = V3OnionServiceId::from_private_key(&endpoint_private_key);
+    let client_session = ident_client.into_session();
+    let server_session = ident_server.into_session().unwrap();
+
+    let channel = AsciiString::new("channel".to_string())?;
+    let mut endpoint_client = EndpointClient::new(
+        client_session,
+        endpoint_service_id.clone(),
+        channel.clone(),
+        client_ed25519_private,
+    );
+    let mut endpoint_server = EndpointServer::new(
+        server_session,
+        client_service_id.clone(),
+        endpoint_service_id,
+        Default::default(),
+        false,
+    );
+
+    let mut server_stream: Option<MemoryStream> = None;
+    let mut client_stream: Option<MemoryStream> = None;
+    while server_stream.is_none() || client_stream.is_none() {
+        if server_stream.is_none() {
+            match endpoint_server.update()? {
+                Some(EndpointServerEvent::ChannelRequestReceived { .. }) => {
+                    endpoint_server.handle_channel_request_received(true)?;
+                }
+                Some(EndpointServerEvent::HandshakeCompleted {
+                    client_service_id: ret_client_service_id,
+                    channel_name,
+                    stream,
+                    ..
+                }) => {
+                    assert_eq!(ret_client_service_id, client_service_id);
+                    assert!(channel_name == channel);
+                    server_stream = Some(stream);
+                }
+                Some(EndpointServerEvent::HandshakeRejected { .. }) => {
+                    panic!("server unexpectedly rejected handshake");
+                }
+                Some(EndpointServerEvent::HandshakeBusy { .. }) => {
+                    panic!("server unexpectedly busy");
+                }
+                Some(EndpointServerEvent::HandshakeQuotaExceeded { .. }) => {
+                    panic!("server unexpectedly exceeded channel quota");
+                }
+                Some(EndpointServerEvent::HandshakeBanned { .. }) => {
+                    panic!("server unexpectedly banned client");
+                }
+                Some(EndpointServerEvent::RpcChannelOpened { .. }) => {
+                    panic!("server unexpectedly opened rpc channel");
+                }
+                None => {}
+            }
+        }
+
+        if client_stream.is_none() {
+            if let Some(EndpointClientEvent::HandshakeCompleted { stream, .. }) =
+                endpoint_client.update()?
+            {
+                client_stream = Some(stream);
+            }
+        }
+    }
+
+    println!("--- application data over the handed-back transport");
+    let mut server_stream = server_stream.unwrap();
+    let mut client_stream = client_stream.unwrap();
+    client_stream.write_all(b"hello")?;
+    let mut buf = [0u8; 5];
+    server_stream.read_exact(&mut buf)?;
+    assert_eq!(&buf, b"hello");
+
+    Ok(())
+}
+
+#[test]
+fn test_endpoint_handshake_busy() -> anyhow::Result<()> {
+    let (client_stream, server_stream) = duplex();
+
+    // client setup
+    let client_ed25519_private = Ed25519PrivateKey::generate();
+    let client_service_id = V3OnionServiceId::from_private_key(&client_ed25519_private);
+
+    // server setup
+    let server_ed25519_private = Ed25519PrivateKey::generate();
+    let server_service_id = V3OnionServiceId::from_private_key(&server_ed25519_private);
+
+    let channel = AsciiString::new("channel".to_string())?;
+    let retry_after = std::time::Duration::from_secs(30);
+    let mut endpoint_client = EndpointClient::new(
+        Session::new(client_stream),
+        server_service_id.clone(),
+        channel.clone(),
+        client_ed25519_private,
+    );
+    let mut endpoint_server = EndpointServer::new(
+        Session::new(server_stream),
+        client_service_id.clone(),
+        server_service_id,
+        Default::default(),
+        false,
+    );
+    endpoint_server.set_busy_retry_after(Some(retry_after));
+
+    let mut server_complete = false;
+    let mut client_complete = false;
+    while !server_complete || !client_complete {
+        if !server_complete {
+            match endpoint_server.update()? {
+                Some(EndpointServerEvent::HandshakeBusy {
+                    client_service_id: ret_client_service_id,
+                    requested_channel,
+                    retry_after: ret_retry_after,
+                }) => {
+                    assert_eq!(ret_client_service_id, client_service_id);
+                    assert!(requested_channel == channel);
+                    assert_eq!(ret_retry_after, retry_after);
+                    server_complete = true;
+                }
+                Some(_) => panic!("busy server unexpectedly handled channel request"),
+                None => {}
+            }
+        }
+
+        if !client_complete {
+            match endpoint_client.update() {
+                Err(crate::endpoint_client::Error::ServerBusy(ret_retry_after)) => {
+                    assert_eq!(ret_retry_after, retry_after);
+                    client_complete = true;
+                }
+                Err(err) => return Err(err.into()),
+                Ok(Some(_)) => panic!("client unexpectedly completed handshake with busy server"),
+                Ok(None) => {}
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_handshake_state_deadlines() -> anyhow::Result<()> {
+    let (client_stream, server_stream) = duplex();
+
+    let server_ed25519_private = Ed25519PrivateKey::generate();
+    let server_service_id = V3OnionServiceId::from_private_key(&server_ed25519_private);
+
+    let deadline = Duration::from_secs(60);
+    let mut ident_client = IdentityClient::new(
+        Session::new(client_stream),
+        server_service_id.clone(),
+        AsciiString::new("endpoint".to_string())?,
+        Ed25519PrivateKey::generate(),
+        X25519PrivateKey::generate(),
+        false,
+    )?;
+    ident_client.set_state_deadline(Some(deadline));
+    let mut ident_server =
+        IdentityServer::new(Session::new(server_stream), server_service_id, None, false);
+    ident_server.set_state_deadline(Some(deadline));
+
+    // time is only advanced through update_at(), so the deadlines are
+    // independent of how long the test takes to run
+    let start = Instant::now();
+
+    // the client begins the handshake and the server receives the endpoint
+    // request half a deadline later
+    let mut endpoint_request_received = false;
+    for _ in 0..16 {
+        assert!(ident_client.update_at(start)?.is_none());
+        if let Some(IdentityServerEvent::EndpointRequestReceived { .. }) =
+            ident_server.update_at(start + deadline / 2)?
+        {
+            endpoint_request_received = true;
+            break;
+        }
+    }
+    assert!(endpoint_request_received);
+
+    // the server's deadline restarted when it began waiting for the endpoint
+    // challenge, so it has not yet passed
+    assert!(ident_server
+        .update_at(start + deadline + deadline / 2)?
+        .is_none());
+
+    // but the endpoint challenge is never provided
+    assert!(matches!(
+        ident_server.update_at(start + deadline * 2),
+        Err(crate::identity_server::Error::TimedOut {
+            state: IdentityServerState::GettingChallenge,
+            deadline: ret_deadline,
+        }) if ret_deadline == deadline
+    ));
+
+    // and the client gives up waiting for it
+    assert!(matches!(
+        ident_client.update_at(start + deadline * 2),
+        Err(crate::identity_client::Error::TimedOut {
+            state: IdentityClientState::WaitingForChallenge,
+            deadline: ret_deadline,
+        }) if ret_deadline == deadline
+    ));
+
+    // an endpoint server whose client never begins the handshake
+    let (_client_stream, server_stream) = duplex();
+    let mut endpoint_server = EndpointServer::new(
+        Session::new(server_stream),
+        V3OnionServiceId::from_private_key(&Ed25519PrivateKey::generate()),
+        V3OnionServiceId::from_private_key(&Ed25519PrivateKey::generate()),
+        Default::default(),
+        false,
+    );
+    endpoint_server.set_state_deadline(Some(deadline));
+    let start = Instant::now();
+    assert!(endpoint_server.update_at(start + deadline / 2)?.is_none());
+    assert!(matches!(
+        endpoint_server.update_at(start + deadline * 2),
+        Err(crate::endpoint_server::Error::TimedOut {
+            state: EndpointServerState::WaitingForBeginHandshake,
+            deadline: ret_deadline,
+        }) if ret_deadline == deadline
+    ));
+    // the failed handshake may not continue
+    assert!(endpoint_server.update_at(start).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_name_length_limits() -> anyhow::Result<()> {
+    let server_ed25519_private = Ed25519PrivateKey::generate();
+    let server_service_id = V3OnionServiceId::from_private_key(&server_ed25519_private);
+    let client_ed25519_private = Ed25519PrivateKey::generate();
+    let client_service_id = V3OnionServiceId::from_private_key(&client_ed25519_private);
+
+    // an identity client requesting an endpoint one byte longer than allowed
+    let (client_stream, server_stream) = duplex();
+    let mut ident_client = IdentityClient::new(
+        Session::new(client_stream),
+        server_service_id.clone(),
+        AsciiString::new("e".repeat(MAX_ENDPOINT_NAME_LENGTH + 1))?,
+        client_ed25519_private.clone(),
+        X25519PrivateKey::generate(),
+        false,
+    )?;
+    let mut ident_server = IdentityServer::new(
+        Session::new(server_stream),
+        server_service_id.clone(),
+        None,
+        false,
+    );
+
+    let mut client_result: Option<Result<(), crate::identity_client::Error>> = None;
+    let mut server_result: Option<Result<(), crate::identity_server::Error>> = None;
+    for _ in 0..16 {
+        if client_result.is_none() {
+            if let Err(err) = ident_client.update() {
+                client_result = Some(Err(err));
+            }
+        }
+        if server_result.is_none() {
+            match ident_server.update() {
+                Ok(Some(_)) => server_result = Some(Ok(())),
+                Ok(None) => {}
+                Err(err) => server_result = Some(Err(err)),
+            }
+        }
+    }
+    // the server refuses the request with a dedicated error code
+    assert!(matches!(
+        server_result,
+        Some(Err(crate::identity_server::Error::EndpointNameTooLong(length))) if length == MAX_ENDPOINT_NAME_LENGTH + 1
+    ));
+    match client_result {
+        Some(Err(err)) => assert!(err.to_string().contains("too long")),
+        _ => panic!("identity client did not fail"),
+    }
+
+    // an endpoint client requesting a channel one byte longer than allowed
+    let (client_stream, server_stream) = duplex();
+    let mut endpoint_client = EndpointClient::new(
+        Session::new(client_stream),
+        server_service_id.clone(),
+        AsciiString::new("c".repeat(MAX_CHANNEL_NAME_LENGTH + 1))?,
+        client_ed25519_private,
+    );
+    let mut endpoint_server = EndpointServer::new(
+        Session::new(server_stream),
+        client_service_id,
+        server_service_id,
+        Default::default(),
+        false,
+    );
+
+    let mut client_result: Option<Result<(), crate::endpoint_client::Error>> = None;
+    let mut server_result: Option<Result<(), crate::endpoint_server::Error>> = None;
+    for _ in 0..16 {
+        if client_result.is_none() {
+            if let Err(err) = endpoint_client.update() {
+                client_result = Some(Err(err));
+            }
+        }
+        if server_result.is_none() {
+            match endpoint_server.update() {
+                Ok(Some(_)) => server_result = Some(Ok(())),
+                Ok(None) => {}
+                Err(err) => server_result = Some(Err(err)),
+            }
+        }
+    }
+    assert!(matches!(
+        server_result,
+        Some(Err(crate::endpoint_server::Error::ChannelNameTooLong(length))) if length == MAX_CHANNEL_NAME_LENGTH + 1
+    ));
+    match client_result {
+        Some(Err(err)) => assert!(err.to_string().contains("too long")),
+        _ => panic!("endpoint client did not fail"),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_handshake_read_idle_timeouts() -> anyhow::Result<()> {
+    let (client_stream, server_stream) = duplex();
+
+    let server_ed25519_private = Ed25519PrivateKey::generate();
+    let server_service_id = V3OnionServiceId::from_private_key(&server_ed25519_private);
+
+    let deadline = Duration::from_secs(60);
+    let idle_timeout = Duration::from_secs(10);
+    let mut ident_client = IdentityClient::new(
+        Session::new(client_stream),
+        server_service_id.clone(),
+        AsciiString::new("endpoint".to_string())?,
+        Ed25519PrivateKey::generate(),
+        X25519PrivateKey::generate(),
+        false,
+    )?;
+    ident_client.set_state_deadline(Some(deadline));
+    ident_client.set_read_idle_timeout(Some(idle_timeout));
+    let mut ident_server =
+        IdentityServer::new(Session::new(server_stream), server_service_id, None, false);
+    ident_server.set_state_deadline(Some(deadline));
+    ident_server.set_read_idle_timeout(Some(idle_timeout));
+
+    let start = Instant::now();
+
+    // the server receives the endpoint request well within the idle timeout
+    let mut endpoint_request_received = false;
+    for _ in 0..16 {
+        assert!(ident_client.update_at(start)?.is_none());
+        if let Some(IdentityServerEvent::EndpointRequestReceived { .. }) =
+            ident_server.update_at(start + idle_timeout / 2)?
+        {
+            endpoint_request_received = true;
+            break;
+        }
+    }
+    assert!(endpoint_request_received);
+
+    // the server is waiting on its application rather than the client, so
+    // only the state deadline applies to it
+    assert!(ident_server.update_at(start + idle_timeout * 2)?.is_none());
+
+    // while the client gives up on the server long before its state deadline
+    assert!(ident_client.update_at(start + idle_timeout / 2)?.is_none());
+    assert!(matches!(
+        ident_client.update_at(start + idle_timeout * 2),
+        Err(crate::identity_client::Error::PeerUnresponsive(ret_timeout)) if ret_timeout == idle_timeout
+    ));
+
+    // an endpoint server whose client never begins the handshake
+    let (_client_stream, server_stream) = duplex();
+    let mut endpoint_server = EndpointServer::new(
+        Session::new(server_stream),
+        V3OnionServiceId::from_private_key(&Ed25519PrivateKey::generate()),
+        V3OnionServiceId::from_private_key(&Ed25519PrivateKey::generate()),
+        Default::default(),
+        false,
+    );
+    endpoint_server.set_state_deadline(Some(deadline));
+    endpoint_server.set_read_idle_timeout(Some(idle_timeout));
+    let start = Instant::now();
+    assert!(endpoint_server.update_at(start)?.is_none());
+    assert!(endpoint_server
+        .update_at(start + idle_timeout / 2)?
+        .is_none());
+    assert!(matches!(
+        endpoint_server.update_at(start + idle_timeout * 2),
+        Err(crate::endpoint_server::Error::PeerUnresponsive(ret_timeout)) if ret_timeout == idle_timeout
+    ));
+    // the failed handshake may not continue
+    assert!(endpoint_server.update_at(start).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_replay_cache() {
+    let window = Duration::from_secs(60);
+    let mut replay_cache = ReplayCache::new(window, 2);
+    let start = Instant::now();
+
+    // cookies are one-shot
+    assert!(replay_cache.insert([1u8; CLIENT_COOKIE_SIZE], start));
+    assert!(!replay_cache.insert([1u8; CLIENT_COOKIE_SIZE], start));
+    assert!(replay_cache.insert([2u8; CLIENT_COOKIE_SIZE], start));
+    assert!(!replay_cache.insert([2u8; CLIENT_COOKIE_SIZE], start + window / 2));
+
+    // the oldest cookie is forgotten once capacity is reached
+    assert!(replay_cache.insert([3u8; CLIENT_COOKIE_SIZE], start));
+    assert!(!replay_cache.insert([3u8; CLIENT_COOKIE_SIZE], start));
+    assert!(!replay_cache.insert([2u8; CLIENT_COOKIE_SIZE], start));
+
+    // and every cookie once older than the window
+    assert!(replay_cache.insert([2u8; CLIENT_COOKIE_SIZE], start + window));
+    assert!(replay_cache.insert([3u8; CLIENT_COOKIE_SIZE], start + window));
+}
+
+#[test]
+fn test_begin_handshake_replay() -> anyhow::Result<()> {
+    let server_ed25519_private = Ed25519PrivateKey::generate();
+    let server_service_id = V3OnionServiceId::from_private_key(&server_ed25519_private);
+    let client_ed25519_private = Ed25519PrivateKey::generate();
+    let client_service_id = V3OnionServiceId::from_private_key(&client_ed25519_private);
+    let channel = AsciiString::new("channel".to_string())?;
+
+    // servers share one replay cache, as a Context's servers do
+    let replay_cache: SharedReplayCache = Default::default();
+    let client_cookie: ClientCookie = [0x42u8; CLIENT_COOKIE_SIZE];
+
+    // the same recorded begin_handshake sent to each new identity server
+    let identity_begin_handshake = doc! {
+        "version" : GOSLING_PROTOCOL_VERSION,
+        "client_identity" : client_service_id.to_string(),
+        "endpoint" : "endpoint",
+        "client_cookie" : Binary{subtype: BinarySubtype::Generic, bytes: client_cookie.to_vec()},
+    };
+    let mut identity_results: Vec<Result<(), crate::identity_server::Error>> = Default::default();
+    for _ in 0..2 {
+        let (client_stream, server_stream) = duplex();
+        let mut ident_server = IdentityServer::new(
+            Session::new(server_stream),
+            server_service_id.clone(),
+            None,
+            false,
+        );
+        ident_server.set_replay_cache(Some(replay_cache.clone()));
+        let mut client_rpc = Session::new(client_stream);
+        client_rpc.client_call(
+            "gosling_identity",
+            "begin_handshake",
+            0,
+            identity_begin_handshake.clone(),
+        )?;
+        client_rpc.update(None)?;
+
+        let result = loop {
+            match ident_server.update() {
+                Ok(Some(IdentityServerEvent::EndpointRequestReceived { .. })) => break Ok(()),
+                Ok(_) => {}
+                Err(err) => break Err(err),
+            }
+        };
+        identity_results.push(result);
+    }
+    // the first request is handled but its replay is rejected
+    assert!(identity_results[0].is_ok());
+    assert!(matches!(
+        identity_results[1],
+        Err(crate::identity_server::Error::ReplayedHandshake)
+    ));
+
+    // likewise for endpoint servers, though here the first client then sends
+    // a send_response whose client_cookie differs from its commitment
+    let replay_cache: SharedReplayCache = Default::default();
+    let endpoint_begin_handshake = doc! {
+        "version" : GOSLING_PROTOCOL_VERSION,
+        "client_identity" : client_service_id.to_string(),
+        "channel" : channel.to_string(),
+        "client_cookie" : Binary{subtype: BinarySubtype::Generic, bytes: client_cookie.to_vec()},
+    };
+    let mut endpoint_results: Vec<Result<(), crate::endpoint_server::Error>> = Default::default();
+    for _ in 0..2 {
+        let (client_stream, server_stream) = duplex();
+        let mut endpoint_server = EndpointServer::new(
+            Session::new(server_stream),
+            client_service_id.clone(),
+            server_service_id.clone(),
+            Default::default(),
+            false,
+        );
+        endpoint_server.set_replay_cache(Some(replay_cache.clone()));
+        let mut client_rpc = Session::new(client_stream);
+        let begin_handshake_cookie = client_rpc.client_call(
+            "gosling_endpoint",
+            "begin_handshake",
+            0,
+            endpoint_begin_handshake.clone(),
+        )?;
+
+        let result = loop {
+            match endpoint_server.update() {
+                Ok(Some(EndpointServerEvent::ChannelRequestReceived { .. })) => {
+                    endpoint_server.handle_channel_request_received(true)?;
+                }
+                Ok(Some(_)) => break Ok(()),
+                Ok(None) => {}
+                Err(err) => break Err(err),
+            }
+
+            client_rpc.update(None)?;
+            if let Some(honk_rpc::honk_rpc::Response::Success {
+                cookie,
+                result: Some(Bson::Document(result)),
+            }) = client_rpc.client_next_response()
+            {
+                assert_eq!(cookie, begin_handshake_cookie);
+                let server_cookie: ServerCookie = match result.get("server_cookie") {
+                    Some(Bson::Binary(server_cookie)) => {
+                        server_cookie.bytes.clone().try_into().unwrap()
+                    }
+                    _ => panic!("begin_handshake() returned unexpected value: {}", result),
+                };
+                // a valid proof, but over a client cookie other than the committed one
+                let other_client_cookie: ClientCookie = [0x43u8; CLIENT_COOKIE_SIZE];
+                let client_proof = build_client_proof(
+                    DomainSeparator::GoslingEndpoint,
+                    &channel,
+                    &client_service_id,
+                    &server_service_id,
+                    &other_client_cookie,
+                    &server_cookie,
+                );
+                let signature = client_ed25519_private.sign_message(&client_proof);
+                client_rpc.client_call(
+                    "gosling_endpoint",
+                    "send_response",
+                    0,
+                    doc! {
+                        "client_cookie" : Binary{subtype: BinarySubtype::Generic, bytes: other_client_cookie.to_vec()},
+                        "client_identity_proof_signature" : Binary{subtype: BinarySubtype::Generic, bytes: signature.to_bytes().to_vec()},
+                    },
+                )?;
+            }
+        };
+        endpoint_results.push(result);
+    }
+    assert!(matches!(
+        endpoint_results[0],
+        Err(crate::endpoint_server::Error::BadClient)
+    ));
+    assert!(matches!(
+        endpoint_results[1],
+        Err(crate::endpoint_server::Error::ReplayedHandshake)
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn test_begin_handshake_without_client_cookie() -> anyhow::Result<()> {
+    let server_service_id = V3OnionServiceId::from_private_key(&Ed25519PrivateKey::generate());
+    let client_service_id = V3OnionServiceId::from_private_key(&Ed25519PrivateKey::generate());
+
+    // a client which does not commit to a client cookie cannot be checked for
+    // replays, so identity servers reject it
+    let (client_stream, server_stream) = duplex();
+    let mut ident_server = IdentityServer::new(
+        Session::new(server_stream),
+        server_service_id.clone(),
+        None,
+        false,
+    );
+    ident_server.set_replay_cache(Some(Default::default()));
+    let mut client_rpc = Session::new(client_stream);
+    let begin_handshake_cookie = client_rpc.client_call(
+        "gosling_identity",
+        "begin_handshake",
+        0,
+        doc! {
+            "version" : GOSLING_PROTOCOL_VERSION,
+            "client_identity" : client_service_id.to_string(),
+            "endpoint" : "endpoint",
+        },
+    )?;
+    client_rpc.update(None)?;
+    let result = loop {
+        match ident_server.update() {
+            Ok(Some(_)) => panic!("server returned unexpected event"),
+            Ok(None) => {}
+            Err(err) => break err,
+        }
+    };
+    assert!(matches!(result, crate::identity_server::Error::BadClient));
+
+    let response = loop {
+        client_rpc.update(None)?;
+        if let Some(response) = client_rpc.client_next_response() {
+            break response;
+        }
+    };
+    match response {
+        honk_rpc::honk_rpc::Response::Error { cookie, error_code } => {
+            assert_eq!(cookie, begin_handshake_cookie);
+            assert_eq!(error_code, ErrorCode::Runtime(RpcError::InvalidArg as i32));
+        }
+        _ => panic!("unexpected response"),
+    }
+
+    // as do endpoint servers, unless they accept legacy handshakes
+    let (client_stream, server_stream) = duplex();
+    let mut endpoint_server = EndpointServer::new(
+        Session::new(server_stream),
+        client_service_id.clone(),
+        server_service_id,
+        Default::default(),
+        false,
+    );
+    endpoint_server.set_replay_cache(Some(Default::default()));
+    let mut client_rpc = Session::new(client_stream);
+    let begin_handshake_cookie = client_rpc.client_call(
+        "gosling_endpoint",
+        "begin_handshake",
+        0,
+        doc! {
+            "version" : GOSLING_PROTOCOL_VERSION,
+            "client_identity" : client_service_id.to_string(),
+            "channel" : "channel",
+        },
+    )?;
+    client_rpc.update(None)?;
+    let result = loop {
+        match endpoint_server.update() {
+            Ok(Some(_)) => panic!("server returned unexpected event"),
+            Ok(None) => {}
+            Err(err) => break err,
+        }
+    };
+    assert!(matches!(result, crate::endpoint_server::Error::BadClient));
+
+    let response = loop {
+        client_rpc.update(None)?;
+        if let Some(response) = client_rpc.client_next_response() {
+            break response;
+        }
+    };
+    match response {
+        honk_rpc::honk_rpc::Response::Error { cookie, error_code } => {
+            assert_eq!(cookie, begin_handshake_cookie);
+            assert_eq!(error_code, ErrorCode::Runtime(RpcError::InvalidArg as i32));
+        }
+        _ => panic!("unexpected response"),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_protocol_violations() -> anyhow::Result<()> {
+    let server_service_id = V3OnionServiceId::from_private_key(&Ed25519PrivateKey::generate());
+    let client_service_id = V3OnionServiceId::from_private_key(&Ed25519PrivateKey::generate());
+
+    // send_response before begin_handshake
+    let (client_stream, server_stream) = duplex();
+    let mut ident_server = IdentityServer::new(
+        Session::new(server_stream),
+        server_service_id.clone(),
+        None,
+        false,
+    );
+    let mut client_rpc = Session::new(client_stream);
+    let send_response_cookie =
+        client_rpc.client_call("gosling_identity", "send_response", 0, doc! {})?;
+    client_rpc.update(None)?;
+    assert!(matches!(
+        ident_server.update(),
+        Err(crate::identity_server::Error::OutOfOrderCall(function)) if function == "send_response"
+    ));
+
+    client_rpc.update(None)?;
+    match client_rpc.client_next_response() {
+        Some(honk_rpc::honk_rpc::Response::Error { cookie, error_code }) => {
+            assert_eq!(cookie, send_response_cookie);
+            assert_eq!(
+                error_code,
+                ErrorCode::Runtime(RpcError::OutOfOrderCall as i32)
+            );
+            let error_data = client_rpc.client_take_error_data(cookie);
+            assert_eq!(
+                describe_error_response(&error_code, error_data),
+                format!(
+                    "{}; send_response() was called out of order, expected begin_handshake() first",
+                    error_code
+                )
+            );
+        }
+        _ => panic!("unexpected response"),
+    }
+
+    // begin_handshake called twice
+    let (client_stream, server_stream) = duplex();
+    let mut endpoint_server = EndpointServer::new(
+        Session::new(server_stream),
+        client_service_id.clone(),
+        server_service_id,
+        Default::default(),
+        false,
+    );
+    let mut client_rpc = Session::new(client_stream);
+    let begin_handshake = doc! {
+        "version" : GOSLING_PROTOCOL_VERSION,
+        "client_identity" : client_service_id.to_string(),
+        "channel" : "channel",
+        "client_cookie" : Binary{subtype: BinarySubtype::Generic, bytes: vec![0x42u8; CLIENT_COOKIE_SIZE]},
+    };
+    client_rpc.client_call(
+        "gosling_endpoint",
+        "begin_handshake",
+        0,
+        begin_handshake.clone(),
+    )?;
+    let duplicate_cookie =
+        client_rpc.client_call("gosling_endpoint", "begin_handshake", 0, begin_handshake)?;
+    client_rpc.update(None)?;
+    let result = loop {
+        match endpoint_server.update() {
+            Ok(Some(EndpointServerEvent::ChannelRequestReceived { .. })) => {}
+            Ok(Some(_)) => panic!("server returned unexpected event"),
+            Ok(None) => {}
+            Err(err) => break err,
+        }
+    };
+    assert!(matches!(
+        result,
+        crate::endpoint_server::Error::DuplicateCall(function) if function == "begin_handshake"
+    ));
+
+    let response = loop {
+        client_rpc.update(None)?;
+        match client_rpc.client_next_response() {
+            // the first begin_handshake's response
+            Some(honk_rpc::honk_rpc::Response::Pending { .. }) => {}
+            Some(response) => break response,
+            None => {}
+        }
+    };
+    match response {
+        honk_rpc::honk_rpc::Response::Error { cookie, error_code } => {
+            assert_eq!(cookie, duplicate_cookie);
+            let error_data = client_rpc.client_take_error_data(cookie);
+            assert_eq!(
+                describe_error_response(&error_code, error_data),
+                format!(
+                    "{}; begin_handshake() was called more than once",
+                    error_code
+                )
+            );
+        }
+        _ => panic!("unexpected response"),
+    }
+
+    // unknown functions are not protocol violations
+    assert_eq!(
+        unexpected_call_error("end_handshake", 0, false, None),
+        (ErrorCode::RequestFunctionInvalid, None)
+    );
+    assert_eq!(
+        unexpected_call_error("begin_handshake", 1, true, None),
+        (ErrorCode::RequestVersionInvalid, None)
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_invalid_signbit() -> anyhow::Result<()> {
+    let server_service_id = V3OnionServiceId::from_private_key(&Ed25519PrivateKey::generate());
+    let client_service_id = V3OnionServiceId::from_private_key(&Ed25519PrivateKey::generate());
+
+    let (client_stream, server_stream) = duplex();
+    let mut ident_server =
+        IdentityServer::new(Session::new(server_stream), server_service_id, None, false);
+    let mut client_rpc = Session::new(client_stream);
+    client_rpc.client_call(
+        "gosling_identity",
+        "begin_handshake",
+        0,
+        doc! {
+            "version" : GOSLING_PROTOCOL_VERSION,
+            "client_identity" : client_service_id.to_string(),
+            "endpoint" : "endpoint",
+            "client_cookie" : Binary{subtype: BinarySubtype::Generic, bytes: vec![0x42u8; CLIENT_COOKIE_SIZE]},
+        },
+    )?;
+    client_rpc.update(None)?;
+    loop {
+        if let Some(IdentityServerEvent::EndpointRequestReceived { .. }) = ident_server.update()? {
+            ident_server.handle_endpoint_request_received(true, true, doc! {})?;
+            break;
+        }
+    }
+    // wait for the challenge before responding to it
+    loop {
+        assert!(ident_server.update()?.is_none());
+        client_rpc.update(None)?;
+        if let Some(honk_rpc::honk_rpc::Response::Success { .. }) =
+            client_rpc.client_next_response()
+        {
+            break;
+        }
+    }
+
+    // the signbit is checked before any other send_response argument
+    let send_response_cookie = client_rpc.client_call(
+        "gosling_identity",
+        "send_response",
+        0,
+        doc! {
+            "client_authorization_key_signbit" : Bson::Int32(1),
+        },
+    )?;
+    client_rpc.update(None)?;
+    let result = loop {
+        match ident_server.update() {
+            Ok(Some(_)) => panic!("server returned unexpected event"),
+            Ok(None) => {}
+            Err(err) => break err,
+        }
+    };
+    assert!(matches!(
+        result,
+        crate::identity_server::Error::InvalidSignbit
+    ));
+
+    let response = loop {
+        client_rpc.update(None)?;
+        if let Some(response) = client_rpc.client_next_response() {
+            break response;
+        }
+    };
+    match response {
+        honk_rpc::honk_rpc::Response::Error { cookie, error_code } => {
+            assert_eq!(cookie, send_response_cookie);
+            assert_eq!(
+                error_code,
+                ErrorCode::Runtime(RpcError::InvalidSignbit as i32)
+            );
+            let error_data = client_rpc.client_take_error_data(cookie);
+            assert_eq!(
+                describe_error_response(&error_code, error_data),
+                format!(
+                    "{}; the client authorization key's signbit is not a boolean",
+                    error_code
+                )
+            );
+        }
+        _ => panic!("unexpected response"),
     }
 
     Ok(())
@@ -0,0 +1,15 @@
+pub use crate::ascii_string::{AsciiString, Error as AsciiStringError};
+pub use crate::channel_pattern::{ChannelPattern, Error as ChannelPatternError};
+pub use crate::context::{ClientFilter, ClientFilterVerdict, HandshakeRejectionReason};
+pub use crate::endpoint_client::{
+    EndpointClient, EndpointClientEvent, EndpointClientState, Error as EndpointClientError,
+};
+pub use crate::endpoint_server::{
+    EndpointServer, EndpointServerEvent, EndpointServerState, Error as EndpointServerError,
+};
+pub use crate::identity_client::{
+    Error as IdentityClientError, IdentityClient, IdentityClientEvent, IdentityClientState,
+};
+pub use crate::identity_server::{
+    Error as IdentityServerError, IdentityServer, IdentityServerEvent, IdentityServerState,
+};
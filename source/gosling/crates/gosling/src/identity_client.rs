@@ -2,21 +2,28 @@
 use std::clone::Clone;
 use std::convert::TryInto;
 use std::net::TcpStream;
+use std::sync::Arc;
+use std::time::Duration;
 
 // extern crates
 use bson::doc;
 use bson::spec::BinarySubtype;
 use bson::{Binary, Bson};
 use honk_rpc::honk_rpc::{
-    get_message_overhead, get_request_section_size, RequestCookie, Response, Session,
+    get_message_overhead, get_request_section_size, ErrorCode, RequestCookie, Response, Session,
 };
+use num_enum::TryFromPrimitive;
 use rand::rngs::OsRng;
 use rand::RngCore;
+use tor_interface::clock::{Clock, SystemClock};
 use tor_interface::tor_crypto::*;
 
 // internal crates
 use crate::ascii_string::*;
+use crate::context::HandshakeRejectionReason;
 use crate::gosling::*;
+use crate::protocol::*;
+use crate::timing::*;
 
 //
 // Identity Client
@@ -39,24 +46,47 @@ pub enum Error {
     #[error("incorrect usage: {0}")]
     IncorrectUsage(String),
 
+    #[error("identity server rejected the handshake: {0:?}")]
+    HandshakeRejected(HandshakeRejectionReason),
+
     #[error("provided endpoint challenge response too large; encoded size would be {0} but session's maximum honk-rpc message size is {1}")]
     EndpointChallengeResponseTooLarge(usize, usize),
+
+    #[error("handshake spent longer than {deadline:?} in state {state:?}")]
+    TimedOut {
+        state: IdentityClientState,
+        deadline: Duration,
+    },
+
+    #[error("peer sent nothing for {0:?} while the handshake was waiting on it")]
+    PeerUnresponsive(Duration),
 }
 
-pub(crate) enum IdentityClientEvent {
+// each additional endpoint requested and its service id if granted
+type AdditionalEndpointGrants = Vec<(AsciiString, Option<V3OnionServiceId>)>;
+
+pub enum IdentityClientEvent {
     ChallengeReceived {
         endpoint_challenge: bson::document::Document,
     },
     HandshakeCompleted {
         identity_service_id: V3OnionServiceId,
         endpoint_service_id: V3OnionServiceId,
-        endpoint_name: String,
+        endpoint_name: AsciiString,
         client_auth_private_key: X25519PrivateKey,
+        // the server agreed to continue with an endpoint handshake over this session
+        endpoint_upgrade: bool,
+        // each additional endpoint requested and its service id if granted
+        additional_endpoints: Vec<(AsciiString, Option<V3OnionServiceId>)>,
+        // the contact request sent with the endpoint request
+        contact_request: Option<String>,
+        // the optional protocol features agreed with the server
+        capabilities: Capabilities,
     },
 }
 
-#[derive(Debug, PartialEq)]
-pub(crate) enum IdentityClientState {
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IdentityClientState {
     BeginHandshake,
     WaitingForChallenge,
     WaitingForChallengeResponse,
@@ -68,36 +98,66 @@ pub(crate) enum IdentityClientState {
 // An identity client object used for connecting
 // to an identity server
 //
-pub(crate) struct IdentityClient {
+pub struct IdentityClient<RW = TcpStream> {
     // session data
-    rpc: Session<TcpStream>,
+    rpc: Session<RW>,
     server_service_id: V3OnionServiceId,
     requested_endpoint: AsciiString,
     client_service_id: V3OnionServiceId,
     client_identity_ed25519_private: Ed25519PrivateKey,
     client_authorization_key_private: X25519PrivateKey,
     client_authorization_signing_key_private: (Ed25519PrivateKey, SignBit),
+    // ask the server to continue with an endpoint handshake over this session
+    request_endpoint_upgrade: bool,
+    // further endpoints requested alongside requested_endpoint
+    additional_endpoints: Vec<AsciiString>,
+    // application message sent alongside the endpoint request
+    contact_request: Option<String>,
+    // committed to in begin_handshake and proven in send_response
+    client_cookie: ClientCookie,
+    // optional protocol features offered to the server
+    capabilities: Capabilities,
 
     // state machine data
     state: IdentityClientState,
+    // fails the handshake if it stalls in any one state
+    state_deadline: StateDeadline<IdentityClientState>,
+    // fails the handshake if the server goes quiet while we wait on it
+    read_idle_deadline: ReadIdleDeadline,
+    // source of the time for the state deadline and timings
+    clock: Arc<dyn Clock>,
     begin_handshake_request_cookie: Option<RequestCookie>,
     server_cookie: Option<ServerCookie>,
     endpoint_challenge_response: Option<bson::document::Document>,
     send_response_request_cookie: Option<RequestCookie>,
+    endpoint_upgrade_accepted: bool,
+    additional_endpoints_accepted: bool,
+    negotiated_capabilities: Capabilities,
+
+    // timing data
+    call_timestamp: Instant,
+    pub(crate) latencies: Vec<(TimedOperation, Duration)>,
 }
 
-impl IdentityClient {
+impl<RW> IdentityClient<RW>
+where
+    RW: std::io::Read + std::io::Write + Send,
+{
     fn get_state(&self) -> String {
         format!("{{ state: {:?},  begin_handshake_request_cookie: {:?},  server_cookie: {:?}, endpoint_challenge_response: {:?},  send_response_request_cookie: {:?} }}", self.state,  self.begin_handshake_request_cookie, self.server_cookie, self.endpoint_challenge_response, self.send_response_request_cookie)
     }
 
     pub fn new(
-        rpc: Session<TcpStream>,
+        rpc: Session<RW>,
         server_service_id: V3OnionServiceId,
         requested_endpoint: AsciiString,
         client_identity_ed25519_private: Ed25519PrivateKey,
         client_authorization_key_private: X25519PrivateKey,
+        request_endpoint_upgrade: bool,
     ) -> Result<Self, Error> {
+        let mut client_cookie: ClientCookie = Default::default();
+        OsRng.fill_bytes(&mut client_cookie);
+
         Ok(Self {
             rpc,
             server_service_id,
@@ -109,16 +169,84 @@ impl IdentityClient {
             )
             .map_err(Error::ClientCreationFailed)?,
             client_authorization_key_private,
+            request_endpoint_upgrade,
+            additional_endpoints: Default::default(),
+            contact_request: None,
+            client_cookie,
+            capabilities: Capabilities::all(),
 
             state: IdentityClientState::BeginHandshake,
+            state_deadline: StateDeadline::new(IdentityClientState::BeginHandshake),
+            read_idle_deadline: ReadIdleDeadline::new(),
+            clock: Arc::new(SystemClock),
             begin_handshake_request_cookie: None,
             server_cookie: None,
             send_response_request_cookie: None,
             endpoint_challenge_response: None,
+            endpoint_upgrade_accepted: false,
+            additional_endpoints_accepted: false,
+            negotiated_capabilities: Capabilities::empty(),
+
+            call_timestamp: Instant::now(),
+            latencies: Default::default(),
         })
     }
 
+    // Fail the handshake if it spends longer than deadline in any one state
+    pub fn set_state_deadline(&mut self, deadline: Option<Duration>) {
+        self.state_deadline.set_deadline(deadline);
+    }
+
+    // Fail the handshake if the peer sends nothing for timeout while the
+    // handshake is waiting on it
+    pub fn set_read_idle_timeout(&mut self, timeout: Option<Duration>) {
+        self.read_idle_deadline.set_timeout(timeout);
+    }
+
+    // Read the time for the state deadline and timings from clock
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
     pub fn update(&mut self) -> Result<Option<IdentityClientEvent>, Error> {
+        self.update_at(self.clock.now())
+    }
+
+    // update() with the given time standing in for the current time
+    pub(crate) fn update_at(&mut self, now: Instant) -> Result<Option<IdentityClientEvent>, Error> {
+        if self.state != IdentityClientState::HandshakeComplete {
+            if let Some(deadline) = self.state_deadline.observe(self.state, now) {
+                return Err(Error::TimedOut {
+                    state: self.state,
+                    deadline,
+                });
+            }
+            if let Some(timeout) = self.observe_read_idle(now) {
+                return Err(Error::PeerUnresponsive(timeout));
+            }
+        }
+
+        let previous_state = self.state;
+        let result = self.update_state_machine();
+        if self.state != previous_state {
+            tracing::debug!(from = ?previous_state, to = ?self.state, "identity client state transition");
+        }
+        self.state_deadline.observe(self.state, now);
+        self.observe_read_idle(now);
+        result
+    }
+
+    fn observe_read_idle(&mut self, now: Instant) -> Option<Duration> {
+        let waiting_on_peer = matches!(
+            self.state,
+            IdentityClientState::WaitingForChallenge
+                | IdentityClientState::WaitingForChallengeVerification
+        );
+        self.read_idle_deadline
+            .observe(waiting_on_peer, self.rpc.stats().bytes_received, now)
+    }
+
+    fn update_state_machine(&mut self) -> Result<Option<IdentityClientEvent>, Error> {
         if self.state == IdentityClientState::HandshakeComplete {
             return Err(Error::IncorrectUsage("update() may not be called after HandshakeComplete has been returned from previous update() call".to_string()));
         }
@@ -142,16 +270,40 @@ impl IdentityClient {
                 None, // endpoint_challenge_response
                 None, // send_response_request_cookie
             ) => {
-                self.begin_handshake_request_cookie = Some(self.rpc.client_call(
-                    "gosling_identity",
-                    "begin_handshake",
-                    0,
-                    doc! {
-                        "version" : bson::Bson::String(GOSLING_PROTOCOL_VERSION.to_string()),
-                        "client_identity" : bson::Bson::String(self.client_service_id.to_string()),
-                        "endpoint" : bson::Bson::String(self.requested_endpoint.clone().to_string()),
-                    },
-                )?);
+                // requests for optional features which are not offered are not
+                // sent; unsent additional endpoints are reported as denied
+                let capabilities = self.capabilities;
+                self.request_endpoint_upgrade &=
+                    capabilities.contains(Capabilities::ENDPOINT_UPGRADE);
+                if !capabilities.contains(Capabilities::CONTACT_REQUEST) {
+                    self.contact_request = None;
+                }
+                let request_additional_endpoints = !self.additional_endpoints.is_empty()
+                    && capabilities.contains(Capabilities::ADDITIONAL_ENDPOINTS);
+
+                let args = IdentityBeginHandshakeArgs {
+                    version: GOSLING_PROTOCOL_VERSION.to_string(),
+                    client_identity: self.client_service_id.to_string(),
+                    endpoint: self.requested_endpoint.to_string(),
+                    client_cookie: Some(generic_binary(&self.client_cookie)),
+                    // servers which do not understand this argument ignore it
+                    endpoint_upgrade: self.request_endpoint_upgrade.then_some(true),
+                    additional_endpoints: request_additional_endpoints.then(|| {
+                        self.additional_endpoints
+                            .iter()
+                            .map(|endpoint| endpoint.to_string())
+                            .collect()
+                    }),
+                    contact_request: self.contact_request.clone(),
+                    capabilities: Some(self.capabilities.names()),
+                };
+                let args = to_document(&args);
+                self.begin_handshake_request_cookie =
+                    Some(
+                        self.rpc
+                            .client_call("gosling_identity", "begin_handshake", 0, args)?,
+                    );
+                self.call_timestamp = self.clock.now();
                 self.state = IdentityClientState::WaitingForChallenge;
             }
             (
@@ -179,9 +331,10 @@ impl IdentityClient {
                                     error_code
                                 )));
                             }
+                            let error_data = self.rpc.client_take_error_data(cookie);
                             return Err(Error::UnexpectedResponseReceived(format!(
                                 "received unexpected rpc error_code: {}",
-                                error_code
+                                describe_error_response(&error_code, error_data)
                             )));
                         }
                         Response::Success { cookie, result } => {
@@ -190,6 +343,12 @@ impl IdentityClient {
                                     "received unexpected success response".to_string(),
                                 ));
                             }
+                            self.latencies.push((
+                                TimedOperation::BeginHandshake,
+                                self.clock
+                                    .now()
+                                    .saturating_duration_since(self.call_timestamp),
+                            ));
                             match result {
                                 Some(Bson::Document(result)) => result,
                                 _ => {
@@ -245,6 +404,41 @@ impl IdentityClient {
                         }
                     };
 
+                    // servers which predate capability negotiation do not reply
+                    // with their capabilities, and may still use optional features
+                    // when asked to
+                    let server_capabilities = match parse_capabilities(response.get("capabilities"))
+                    {
+                        Ok(server_capabilities) => server_capabilities,
+                        Err(()) => {
+                            return Err(Error::UnexpectedResponseReceived(
+                                "capabilities is unexpected bson type".to_string(),
+                            ))
+                        }
+                    };
+                    self.negotiated_capabilities = server_capabilities
+                        .map_or(Capabilities::empty(), |server_capabilities| {
+                            server_capabilities.intersection(self.capabilities)
+                        });
+                    let usable = match server_capabilities {
+                        Some(_) => self.negotiated_capabilities,
+                        None => self.capabilities,
+                    };
+
+                    // only honour the server's agreement to upgrade if we asked for it
+                    self.endpoint_upgrade_accepted = self.request_endpoint_upgrade
+                        && usable.contains(Capabilities::ENDPOINT_UPGRADE)
+                        && matches!(response.get("endpoint_upgrade"), Some(Bson::Boolean(true)));
+
+                    // servers which do not support additional endpoints only grant
+                    // the requested endpoint and return its service id as a string
+                    self.additional_endpoints_accepted = !self.additional_endpoints.is_empty()
+                        && usable.contains(Capabilities::ADDITIONAL_ENDPOINTS)
+                        && matches!(
+                            response.get("additional_endpoints"),
+                            Some(Bson::Boolean(true))
+                        );
+
                     self.state = IdentityClientState::WaitingForChallengeResponse;
                     return Ok(Some(IdentityClientEvent::ChallengeReceived {
                         endpoint_challenge,
@@ -268,9 +462,7 @@ impl IdentityClient {
                 None,
             ) => {
                 // client_cookie
-                let mut client_cookie: ClientCookie = Default::default();
-                OsRng.fill_bytes(&mut client_cookie);
-                let client_cookie = client_cookie;
+                let client_cookie = self.client_cookie;
 
                 // client_identity_proof_signature
                 let client_identity_proof = build_client_proof(
@@ -299,14 +491,18 @@ impl IdentityClient {
                 );
 
                 // build our args object for rpc call
-                let args = doc! {
-                    "client_cookie" : bson::Bson::Binary(bson::Binary{subtype: BinarySubtype::Generic, bytes: client_cookie.to_vec()}),
-                    "client_identity_proof_signature" : bson::Bson::Binary(bson::Binary{subtype: BinarySubtype::Generic, bytes: client_identity_proof_signature.to_bytes().to_vec()}),
-                    "client_authorization_key" : bson::Bson::Binary(bson::Binary{subtype: BinarySubtype::Generic, bytes: client_authorization_key.as_bytes().to_vec()}),
-                    "client_authorization_key_signbit" : bson::Bson::Boolean(signbit.into()),
-                    "client_authorization_signature" : bson::Bson::Binary(bson::Binary{subtype: BinarySubtype::Generic, bytes: client_authorization_signature.to_bytes().to_vec()}),
-                    "challenge_response" : endpoint_challenge_response,
-                };
+                let args = to_document(&IdentitySendResponseArgs {
+                    client_cookie: generic_binary(&client_cookie),
+                    client_identity_proof_signature: generic_binary(
+                        &client_identity_proof_signature.to_bytes(),
+                    ),
+                    client_authorization_key: generic_binary(client_authorization_key.as_bytes()),
+                    client_authorization_key_signbit: signbit.into(),
+                    client_authorization_signature: generic_binary(
+                        &client_authorization_signature.to_bytes(),
+                    ),
+                    challenge_response: endpoint_challenge_response,
+                });
 
                 // make rpc call
                 self.send_response_request_cookie =
@@ -314,6 +510,7 @@ impl IdentityClient {
                         self.rpc
                             .client_call("gosling_identity", "send_response", 0, args)?,
                     );
+                self.call_timestamp = self.clock.now();
                 self.state = IdentityClientState::WaitingForChallengeVerification;
             }
             (
@@ -324,7 +521,7 @@ impl IdentityClient {
                 Some(send_response_request_cookie),
             ) => {
                 if let Some(response) = self.rpc.client_next_response() {
-                    let endpoint_service_id = match response {
+                    let (endpoint_service_id, additional_endpoints) = match response {
                         Response::Pending { cookie } => {
                             if cookie == send_response_request_cookie {
                                 return Ok(None);
@@ -336,9 +533,13 @@ impl IdentityClient {
                         }
                         Response::Error { cookie, error_code } => {
                             if cookie == send_response_request_cookie {
+                                if let Some(reason) = rejection_reason(&error_code) {
+                                    return Err(Error::HandshakeRejected(reason));
+                                }
+                                let error_data = self.rpc.client_take_error_data(cookie);
                                 return Err(Error::UnexpectedResponseReceived(format!(
                                     "received unexpected error response; rpc error_code: {}",
-                                    error_code
+                                    describe_error_response(&error_code, error_data)
                                 )));
                             } else {
                                 return Err(Error::UnexpectedResponseReceived(format!(
@@ -349,19 +550,13 @@ impl IdentityClient {
                         }
                         Response::Success { cookie, result } => {
                             if cookie == send_response_request_cookie {
-                                match result {
-                                    Some(Bson::String(endpoint_service_id)) => {
-                                        match V3OnionServiceId::from_string(&endpoint_service_id) {
-                                            Ok(endpoint_service_id) => endpoint_service_id,
-                                            Err(_) => return Err(Error::UnexpectedResponseReceived(format!("unable to parse received endpoint service id '{}' as v3 onion service id", endpoint_service_id))),
-                                        }
-                                    }
-                                    _ => {
-                                        return Err(Error::UnexpectedResponseReceived(
-                                            "endpoint service id is unexpected bson type".to_string(),
-                                        ))
-                                    }
-                                }
+                                self.latencies.push((
+                                    TimedOperation::SendResponse,
+                                    self.clock
+                                        .now()
+                                        .saturating_duration_since(self.call_timestamp),
+                                ));
+                                self.parse_send_response_result(result)?
                             } else {
                                 return Err(Error::UnexpectedResponseReceived(
                                     "received unexpected success response".to_string(),
@@ -373,8 +568,12 @@ impl IdentityClient {
                     return Ok(Some(IdentityClientEvent::HandshakeCompleted {
                         identity_service_id: self.server_service_id.clone(),
                         endpoint_service_id,
-                        endpoint_name: self.requested_endpoint.clone().to_string(),
+                        endpoint_name: self.requested_endpoint.clone(),
                         client_auth_private_key: self.client_authorization_key_private.clone(),
+                        endpoint_upgrade: self.endpoint_upgrade_accepted,
+                        additional_endpoints,
+                        contact_request: self.contact_request.take(),
+                        capabilities: self.negotiated_capabilities,
                     }));
                 }
             }
@@ -385,6 +584,150 @@ impl IdentityClient {
         Ok(None)
     }
 
+    // parse the result of the send_response() call into the granted endpoint's
+    // service id and the outcome of each additional endpoint request
+    fn parse_send_response_result(
+        &self,
+        result: Option<Bson>,
+    ) -> Result<(V3OnionServiceId, AdditionalEndpointGrants), Error> {
+        let (endpoint_service_id, mut additional_endpoint_grants) =
+            match (self.additional_endpoints_accepted, result) {
+                (false, Some(Bson::String(endpoint_service_id))) => (endpoint_service_id, None),
+                (true, Some(Bson::Document(mut result))) => match (
+                    result.remove("endpoint_service_id"),
+                    result.remove("additional_endpoints"),
+                ) {
+                    (
+                        Some(Bson::String(endpoint_service_id)),
+                        Some(Bson::Document(additional_endpoint_grants)),
+                    ) => (endpoint_service_id, Some(additional_endpoint_grants)),
+                    _ => {
+                        return Err(Error::UnexpectedResponseReceived(
+                            "send_response() response is missing or has unexpected members"
+                                .to_string(),
+                        ))
+                    }
+                },
+                _ => {
+                    return Err(Error::UnexpectedResponseReceived(
+                        "endpoint service id is unexpected bson type".to_string(),
+                    ))
+                }
+            };
+        let endpoint_service_id = parse_endpoint_service_id(&endpoint_service_id)?;
+
+        let mut additional_endpoints: AdditionalEndpointGrants =
+            Vec::with_capacity(self.additional_endpoints.len());
+        for endpoint_name in self.additional_endpoints.iter() {
+            let grant = additional_endpoint_grants
+                .as_mut()
+                .and_then(|grants| grants.remove(endpoint_name.as_str()));
+            let endpoint_service_id = match grant {
+                Some(Bson::String(endpoint_service_id)) => {
+                    Some(parse_endpoint_service_id(&endpoint_service_id)?)
+                }
+                // denied, or not supported by the server
+                Some(Bson::Null) | None => None,
+                Some(_) => {
+                    return Err(Error::UnexpectedResponseReceived(format!(
+                        "additional endpoint '{}' grant is unexpected bson type",
+                        endpoint_name
+                    )))
+                }
+            };
+            additional_endpoints.push((endpoint_name.clone(), endpoint_service_id));
+        }
+
+        Ok((endpoint_service_id, additional_endpoints))
+    }
+
+    // The state the handshake is in
+    #[cfg(any(test, feature = "test-utils"))]
+    pub fn state(&self) -> IdentityClientState {
+        self.state
+    }
+
+    // The round-trips and bytes exchanged by this handshake so far
+    pub fn stats(&self) -> HandshakeStats {
+        self.rpc.stats().into()
+    }
+
+    // Consumes the client and returns its session so that an endpoint handshake
+    // may continue over the same connection
+    pub fn into_session(self) -> Session<RW> {
+        self.rpc
+    }
+
+    // Request further endpoints alongside the primary endpoint; each is granted or
+    // denied individually. Must be called before the first update()
+    pub fn set_additional_endpoints(
+        &mut self,
+        additional_endpoints: Vec<AsciiString>,
+    ) -> Result<(), Error> {
+        if self.state != IdentityClientState::BeginHandshake {
+            return Err(Error::IncorrectUsage(
+                "set_additional_endpoints() may only be called before the handshake begins"
+                    .to_string(),
+            ));
+        }
+        if additional_endpoints.len() > MAX_ADDITIONAL_ENDPOINTS {
+            return Err(Error::IncorrectUsage(format!(
+                "at most {} additional endpoints may be requested",
+                MAX_ADDITIONAL_ENDPOINTS
+            )));
+        }
+        for (index, endpoint) in additional_endpoints.iter().enumerate() {
+            if *endpoint == self.requested_endpoint
+                || additional_endpoints[..index].contains(endpoint)
+            {
+                return Err(Error::IncorrectUsage(format!(
+                    "endpoint '{}' requested more than once",
+                    endpoint
+                )));
+            }
+        }
+        self.additional_endpoints = additional_endpoints;
+        Ok(())
+    }
+
+    // Offer only the given optional protocol features to the server; requests
+    // for features not in the set are not sent. Must be called before the
+    // first update()
+    pub fn set_capabilities(&mut self, capabilities: Capabilities) -> Result<(), Error> {
+        if self.state != IdentityClientState::BeginHandshake {
+            return Err(Error::IncorrectUsage(
+                "set_capabilities() may only be called before the handshake begins".to_string(),
+            ));
+        }
+        self.capabilities = capabilities;
+        Ok(())
+    }
+
+    // Attach an application message (e.g. a petname or introduction) to the
+    // endpoint request. Must be called before the first update()
+    pub fn set_contact_request(&mut self, contact_request: Option<String>) -> Result<(), Error> {
+        if self.state != IdentityClientState::BeginHandshake {
+            return Err(Error::IncorrectUsage(
+                "set_contact_request() may only be called before the handshake begins".to_string(),
+            ));
+        }
+        if let Some(contact_request) = contact_request.as_ref() {
+            if contact_request.len() > MAX_CONTACT_REQUEST_SIZE {
+                return Err(Error::IncorrectUsage(format!(
+                    "contact request may be at most {} bytes",
+                    MAX_CONTACT_REQUEST_SIZE
+                )));
+            }
+            if contact_request.contains('\0') {
+                return Err(Error::IncorrectUsage(
+                    "contact request must not contain a null byte".to_string(),
+                ));
+            }
+        }
+        self.contact_request = contact_request;
+        Ok(())
+    }
+
     pub fn send_response(
         &mut self,
         challenge_response: bson::document::Document,
@@ -426,3 +769,29 @@ impl IdentityClient {
         }
     }
 }
+
+fn parse_endpoint_service_id(endpoint_service_id: &str) -> Result<V3OnionServiceId, Error> {
+    match V3OnionServiceId::from_string(endpoint_service_id) {
+        Ok(endpoint_service_id) => Ok(endpoint_service_id),
+        Err(_) => Err(Error::UnexpectedResponseReceived(format!(
+            "unable to parse received endpoint service id '{}' as v3 onion service id",
+            endpoint_service_id
+        ))),
+    }
+}
+
+// map the runtime error codes an identity server may return from send_response
+// when it rejects the handshake; any other error is not a rejection
+fn rejection_reason(error_code: &ErrorCode) -> Option<HandshakeRejectionReason> {
+    match error_code {
+        ErrorCode::Runtime(code) => match RpcError::try_from_primitive(*code) {
+            Ok(RpcError::EndpointUnsupported) => {
+                Some(HandshakeRejectionReason::EndpointUnsupported)
+            }
+            Ok(RpcError::ChallengeFailed) => Some(HandshakeRejectionReason::ChallengeFailed),
+            Ok(RpcError::NotAuthorized) => Some(HandshakeRejectionReason::NotAuthorized),
+            _ => None,
+        },
+        _ => None,
+    }
+}
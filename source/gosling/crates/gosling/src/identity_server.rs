@@ -2,21 +2,27 @@
 use std::clone::Clone;
 use std::convert::TryInto;
 use std::net::TcpStream;
+use std::sync::{Arc, PoisonError};
+use std::time::Duration;
 
 // extern crates
-use bson::doc;
-use bson::spec::BinarySubtype;
-use bson::{Binary, Bson};
+use bson::Bson;
 use honk_rpc::honk_rpc::{
     get_message_overhead, get_response_section_size, ApiSet, ErrorCode, RequestCookie, Session,
 };
 use rand::rngs::OsRng;
 use rand::RngCore;
+use tor_interface::clock::{Clock, SystemClock};
 use tor_interface::tor_crypto::*;
 
 // internal crates
 use crate::ascii_string::*;
+use crate::bans::{Ban, BanList};
+use crate::context::{ClientFilter, ClientFilterVerdict};
+use crate::endpoint_keys;
 use crate::gosling::*;
+use crate::protocol::*;
+use crate::timing::HandshakeStats;
 
 //
 // Identity Server
@@ -36,14 +42,51 @@ pub enum Error {
     #[error("client sent invalid request")]
     BadClient,
 
+    #[error(
+        "client requested an endpoint name {0} bytes long; at most {} bytes are allowed",
+        MAX_ENDPOINT_NAME_LENGTH
+    )]
+    EndpointNameTooLong(usize),
+
     #[error("provided endpoint challenge too large; encoded size would be {0} but session's maximum honk-rpc message size is {1}")]
     EndpointChallengeTooLarge(usize, usize),
+
+    #[error("client rejected by client filter: {0:?}")]
+    ClientRejected(ClientFilterVerdict),
+
+    #[error("client did not respond to endpoint challenge within {0:?}")]
+    ChallengeResponseTimedOut(Duration),
+
+    #[error("client replayed a previously seen begin_handshake request")]
+    ReplayedHandshake,
+
+    #[error("client called {0}() out of order")]
+    OutOfOrderCall(String),
+
+    #[error("client sent a client authorization key signbit which is not a boolean")]
+    InvalidSignbit,
+
+    #[error("client called {0}() more than once")]
+    DuplicateCall(String),
+
+    #[error("handshake spent longer than {deadline:?} in state {state:?}")]
+    TimedOut {
+        state: IdentityServerState,
+        deadline: Duration,
+    },
+
+    #[error("peer sent nothing for {0:?} while the handshake was waiting on it")]
+    PeerUnresponsive(Duration),
 }
 
-pub(crate) enum IdentityServerEvent {
+pub enum IdentityServerEvent {
     EndpointRequestReceived {
         client_service_id: V3OnionServiceId,
         requested_endpoint: AsciiString,
+        // further endpoints requested alongside requested_endpoint
+        additional_endpoints: Vec<AsciiString>,
+        // application message sent alongside the endpoint request
+        contact_request: Option<String>,
     },
 
     ChallengeResponseReceived {
@@ -51,10 +94,18 @@ pub(crate) enum IdentityServerEvent {
     },
 
     HandshakeCompleted {
-        endpoint_private_key: Ed25519PrivateKey,
+        endpoint_private_key: Box<Ed25519PrivateKey>,
         endpoint_name: AsciiString,
         client_service_id: V3OnionServiceId,
         client_auth_public_key: X25519PublicKey,
+        // the client will continue with an endpoint handshake over this session
+        endpoint_upgrade: bool,
+        // the granted additional endpoints
+        additional_endpoints: Vec<(AsciiString, Ed25519PrivateKey)>,
+        // application message sent alongside the endpoint request
+        contact_request: Option<String>,
+        // the optional protocol features agreed with the client
+        capabilities: Capabilities,
     },
 
     HandshakeRejected {
@@ -69,10 +120,17 @@ pub(crate) enum IdentityServerEvent {
         // The challenge response is valid
         challenge_response_valid: bool,
     },
+
+    // the client is on the ban list
+    HandshakeBanned {
+        client_service_id: V3OnionServiceId,
+        requested_endpoint: AsciiString,
+        ban: Ban,
+    },
 }
 
-#[derive(Debug, PartialEq)]
-enum IdentityServerState {
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IdentityServerState {
     // valid/expected states
     WaitingForBeginHandshake,
     GettingChallenge,
@@ -86,13 +144,34 @@ enum IdentityServerState {
     HandshakeFailed,
 }
 
-pub(crate) struct IdentityServer {
+pub struct IdentityServer<RW = TcpStream> {
     // Session Data
-    rpc: Option<Session<TcpStream>>,
+    rpc: Option<Session<RW>>,
     server_identity: V3OnionServiceId,
+    // consulted when begin_handshake is received
+    client_filter: Option<Arc<ClientFilter>>,
+    // banned clients are rejected when begin_handshake is received
+    ban_list: Option<BanList>,
+    // agree to clients' requests to continue with an endpoint handshake over this session
+    endpoint_upgrade_allowed: bool,
+    // reject the handshake if the client has not responded to the challenge in time
+    challenge_response_deadline: Option<Duration>,
+    // client cookies committed to by previous begin_handshake calls
+    replay_cache: Option<SharedReplayCache>,
+    // optional protocol features supported by this server
+    capabilities: Capabilities,
+    // derive granted endpoints' keys from this identity key rather than
+    // generating them
+    endpoint_key_derivation_key: Option<Ed25519PrivateKey>,
 
     // State Machine Data
     state: IdentityServerState,
+    // fails the handshake if it stalls in any one state
+    state_deadline: StateDeadline<IdentityServerState>,
+    // fails the handshake if the client goes quiet while we wait on it
+    read_idle_deadline: ReadIdleDeadline,
+    // source of the time for the state and challenge response deadlines
+    clock: Arc<dyn Clock>,
     begin_handshake_request_cookie: Option<RequestCookie>,
     client_identity: Option<V3OnionServiceId>,
     requested_endpoint: Option<AsciiString>,
@@ -102,6 +181,34 @@ pub(crate) struct IdentityServer {
     client_auth_key: Option<X25519PublicKey>,
     challenge_response: Option<bson::document::Document>,
     endpoint_private_key: Option<Ed25519PrivateKey>,
+    // set when the client filter rejects the client
+    client_filter_verdict: Option<ClientFilterVerdict>,
+    // set when the client is rejected as banned
+    client_banned: Option<(V3OnionServiceId, AsciiString, Ban)>,
+    // the client requested an endpoint upgrade and we agreed
+    endpoint_upgrade: bool,
+    // when the endpoint challenge was queued for the client
+    challenge_sent_timestamp: Option<Instant>,
+    // further endpoints requested alongside requested_endpoint
+    additional_endpoints: Vec<AsciiString>,
+    // private keys of the granted additional endpoints
+    additional_endpoint_private_keys: Vec<(AsciiString, Ed25519PrivateKey)>,
+    // application message sent alongside the endpoint request
+    contact_request: Option<String>,
+    // client cookie committed to in begin_handshake; older clients do not send one
+    committed_client_cookie: Option<ClientCookie>,
+    // set when begin_handshake committed to a previously seen client cookie
+    replay_detected: bool,
+    // the length of the requested endpoint name when it exceeds MAX_ENDPOINT_NAME_LENGTH
+    endpoint_name_too_long: Option<usize>,
+    // set when send_response's client_authorization_key_signbit is not a boolean
+    invalid_signbit: bool,
+    // set when the client called a function out of order or more than once;
+    // the error data is returned with the error response to the offending call
+    protocol_violation: Option<(RequestCookie, RpcError, ProtocolViolationErrorData)>,
+    // optional protocol features agreed with the client; None if the client
+    // predates capability negotiation
+    negotiated_capabilities: Option<Capabilities>,
 
     // Verification flags
 
@@ -115,21 +222,41 @@ pub(crate) struct IdentityServer {
     client_auth_signature_valid: bool,
     // The challenge response is valid
     challenge_response_valid: bool,
+    // Each additional endpoint is valid
+    additional_endpoints_valid: Vec<bool>,
 }
 
-impl IdentityServer {
+impl<RW> IdentityServer<RW>
+where
+    RW: std::io::Read + std::io::Write + Send,
+{
     fn get_state(&self) -> String {
         format!("{{ state: {:?}, begin_handshake_request_cookie: {:?}, client_identity: {:?}, requested_endpoint: {:?}, server_cookie: {:?}, endpoint_challenge: {:?}, send_response_request_cookie: {:?}, client_auth_key: {:?}, challenge_response: {:?}, endpoint_private_key: {:?} }}", self.state, self.begin_handshake_request_cookie, self.client_identity, self.requested_endpoint, self.server_cookie, self.endpoint_challenge, self.send_response_request_cookie, self.client_auth_key, self.challenge_response, self.endpoint_private_key)
     }
 
-    pub fn new(rpc: Session<TcpStream>, server_identity: V3OnionServiceId) -> Self {
+    pub fn new(
+        rpc: Session<RW>,
+        server_identity: V3OnionServiceId,
+        client_filter: Option<Arc<ClientFilter>>,
+        endpoint_upgrade_allowed: bool,
+    ) -> Self {
         IdentityServer {
             // Session Data
             rpc: Some(rpc),
             server_identity,
+            client_filter,
+            ban_list: None,
+            endpoint_upgrade_allowed,
+            challenge_response_deadline: None,
+            replay_cache: None,
+            capabilities: Capabilities::all(),
+            endpoint_key_derivation_key: None,
 
             // State Machine Data
             state: IdentityServerState::WaitingForBeginHandshake,
+            state_deadline: StateDeadline::new(IdentityServerState::WaitingForBeginHandshake),
+            read_idle_deadline: ReadIdleDeadline::new(),
+            clock: Arc::new(SystemClock),
             begin_handshake_request_cookie: None,
             client_identity: None,
             requested_endpoint: None,
@@ -139,6 +266,19 @@ impl IdentityServer {
             client_auth_key: None,
             challenge_response: None,
             endpoint_private_key: None,
+            client_filter_verdict: None,
+            client_banned: None,
+            endpoint_upgrade: false,
+            challenge_sent_timestamp: None,
+            additional_endpoints: Default::default(),
+            additional_endpoint_private_keys: Default::default(),
+            contact_request: None,
+            committed_client_cookie: None,
+            replay_detected: false,
+            endpoint_name_too_long: None,
+            invalid_signbit: false,
+            protocol_violation: None,
+            negotiated_capabilities: None,
 
             // Verification Flags
             client_allowed: false,
@@ -146,10 +286,101 @@ impl IdentityServer {
             client_proof_signature_valid: false,
             client_auth_signature_valid: false,
             challenge_response_valid: false,
+            additional_endpoints_valid: Default::default(),
         }
     }
 
+    // Fail the handshake if the client has not sent its challenge response
+    // within deadline of the challenge being sent
+    pub fn set_challenge_response_deadline(&mut self, deadline: Option<Duration>) {
+        self.challenge_response_deadline = deadline;
+    }
+
+    // Fail the handshake if it spends longer than deadline in any one state
+    pub fn set_state_deadline(&mut self, deadline: Option<Duration>) {
+        self.state_deadline.set_deadline(deadline);
+    }
+
+    // Fail the handshake if the client sends nothing for timeout while the
+    // handshake is waiting on them
+    pub fn set_read_idle_timeout(&mut self, timeout: Option<Duration>) {
+        self.read_idle_deadline.set_timeout(timeout);
+    }
+
+    // Reject begin_handshake calls committing to a client cookie already in
+    // replay_cache; the cache may be shared between servers
+    pub(crate) fn set_replay_cache(&mut self, replay_cache: Option<SharedReplayCache>) {
+        self.replay_cache = replay_cache;
+    }
+
+    // Reject begin_handshake calls from clients banned in ban_list
+    pub fn set_ban_list(&mut self, ban_list: Option<BanList>) {
+        self.ban_list = ban_list;
+    }
+
+    // Read the time for the state and challenge response deadlines from clock
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    // Support only the given optional protocol features; clients' requests for
+    // other features are ignored
+    pub fn set_capabilities(&mut self, capabilities: Capabilities) {
+        self.capabilities = capabilities;
+    }
+
+    // Derive granted endpoints' keys from identity_private_key and the client's
+    // identity, or generate them randomly if None
+    pub fn set_endpoint_key_derivation(&mut self, identity_private_key: Option<Ed25519PrivateKey>) {
+        self.endpoint_key_derivation_key = identity_private_key;
+    }
+
     pub fn update(&mut self) -> Result<Option<IdentityServerEvent>, Error> {
+        self.update_at(self.clock.now())
+    }
+
+    // update() with the given time standing in for the current time
+    pub(crate) fn update_at(&mut self, now: Instant) -> Result<Option<IdentityServerEvent>, Error> {
+        if !matches!(
+            self.state,
+            IdentityServerState::HandshakeComplete | IdentityServerState::HandshakeFailed
+        ) {
+            if let Some(deadline) = self.state_deadline.observe(self.state, now) {
+                let state = self.state;
+                self.state = IdentityServerState::HandshakeFailed;
+                return Err(Error::TimedOut { state, deadline });
+            }
+            if let Some(timeout) = self.observe_read_idle(now) {
+                self.state = IdentityServerState::HandshakeFailed;
+                return Err(Error::PeerUnresponsive(timeout));
+            }
+        }
+
+        let previous_state = self.state;
+        let result = self.update_state_machine();
+        if self.state != previous_state {
+            tracing::debug!(from = ?previous_state, to = ?self.state, "identity server state transition");
+        }
+        self.state_deadline.observe(self.state, now);
+        self.observe_read_idle(now);
+        result
+    }
+
+    fn observe_read_idle(&mut self, now: Instant) -> Option<Duration> {
+        let waiting_on_peer = matches!(
+            self.state,
+            IdentityServerState::WaitingForBeginHandshake
+                | IdentityServerState::WaitingForSendResponse
+        );
+        let bytes_received = self
+            .rpc
+            .as_ref()
+            .map_or(0, |rpc| rpc.stats().bytes_received);
+        self.read_idle_deadline
+            .observe(waiting_on_peer, bytes_received, now)
+    }
+
+    fn update_state_machine(&mut self) -> Result<Option<IdentityServerEvent>, Error> {
         // need to remove ownership of the HonkRPC session from Self
         // before being able to pass self into the session update method
         if let Some(mut rpc) = std::mem::take(&mut self.rpc) {
@@ -199,7 +430,20 @@ impl IdentityServer {
              None) // endpoint_private_key
             => {
                 self.state = IdentityServerState::GettingChallenge;
-                return Ok(Some(IdentityServerEvent::EndpointRequestReceived{client_service_id: client_identity.clone(), requested_endpoint: requested_endpoint.clone()}));
+                return Ok(Some(IdentityServerEvent::EndpointRequestReceived{client_service_id: client_identity.clone(), requested_endpoint: requested_endpoint.clone(), additional_endpoints: self.additional_endpoints.clone(), contact_request: self.contact_request.clone()}));
+            },
+            (&IdentityServerState::GettingChallenge,
+             Some(_begin_handshake_request_cookie),
+             Some(_client_identity),
+             Some(_requested_endpoint),
+             None, // server_cookie
+             None, // endpoint_challenge
+             None, // send_response_request_cookie
+             None, // client_auth_key
+             None, // challenge_response
+             None) // endpoint_private_key
+            => {
+                // no-op, waiting for the endpoint challenge from caller
             },
             (&IdentityServerState::WaitingForSendResponse,
              Some(_begin_handshake_request_cookie),
@@ -212,7 +456,18 @@ impl IdentityServer {
              None, // challenge_response
              None) // endpoint_private_key
             => {
-                // no-op, waiting for client to send challenge response
+                // waiting for client to send challenge response
+                if let (Some(deadline), Some(challenge_sent_timestamp)) = (self.challenge_response_deadline, self.challenge_sent_timestamp) {
+                    if self.clock.now().saturating_duration_since(challenge_sent_timestamp) > deadline {
+                        self.state = IdentityServerState::HandshakeFailed;
+                        // best-effort notify the client why the connection is being closed
+                        if let Some(rpc) = self.rpc.as_mut() {
+                            let _ = rpc.send_error(ErrorCode::Runtime(RpcError::ChallengeResponseTimedOut as i32));
+                            let _ = rpc.update(None);
+                        }
+                        return Err(Error::ChallengeResponseTimedOut(deadline));
+                    }
+                }
             },
             (&IdentityServerState::WaitingForSendResponse,
              Some(_begin_handshake_request_cookie),
@@ -230,6 +485,19 @@ impl IdentityServer {
                     challenge_response: std::mem::take(challenge_response),
                 }));
             },
+            (&IdentityServerState::GettingChallengeVerification,
+             Some(_begin_handshake_request_cookie),
+             Some(_client_identity),
+             Some(_requested_endpoint),
+             Some(_server_cookie),
+             Some(_endpoint_challenge),
+             Some(_send_response_request_cookie),
+             Some(_client_auth_key),
+             Some(_challenge_response),
+             None) // endpoint_private_key
+            => {
+                // no-op, waiting for the challenge response verification from caller
+            },
             (&IdentityServerState::ChallengeVerificationResponseSent,
              Some(_begin_handshake_request_cookie),
              Some(client_identity),
@@ -243,10 +511,14 @@ impl IdentityServer {
             => {
                 self.state = IdentityServerState::HandshakeComplete;
                 return Ok(Some(IdentityServerEvent::HandshakeCompleted{
-                    endpoint_private_key: endpoint_private_key.clone(),
+                    endpoint_private_key: Box::new(endpoint_private_key.clone()),
                     endpoint_name: requested_endpoint.clone(),
                     client_service_id: client_identity.clone(),
                     client_auth_public_key: client_auth_key.clone(),
+                    endpoint_upgrade: self.endpoint_upgrade,
+                    additional_endpoints: std::mem::take(&mut self.additional_endpoint_private_keys),
+                    contact_request: self.contact_request.take(),
+                    capabilities: self.negotiated_capabilities.unwrap_or_default(),
                 }));
             },
             (&IdentityServerState::ChallengeVerificationResponseSent,
@@ -270,7 +542,26 @@ impl IdentityServer {
                 }));
             },
              _ => {
-                if self.state == IdentityServerState::HandshakeFailed {
+                if let Some((client_service_id, requested_endpoint, ban)) = self.client_banned.take() {
+                    return Ok(Some(IdentityServerEvent::HandshakeBanned{
+                        client_service_id,
+                        requested_endpoint,
+                        ban}));
+                } else if let Some(client_filter_verdict) = self.client_filter_verdict {
+                    return Err(Error::ClientRejected(client_filter_verdict));
+                } else if self.replay_detected {
+                    return Err(Error::ReplayedHandshake);
+                } else if let Some(length) = self.endpoint_name_too_long {
+                    return Err(Error::EndpointNameTooLong(length));
+                } else if self.invalid_signbit {
+                    return Err(Error::InvalidSignbit);
+                } else if let Some((_, rpc_error, violation)) = self.protocol_violation.as_ref() {
+                    let function = violation.function.clone();
+                    return Err(match rpc_error {
+                        RpcError::DuplicateCall => Error::DuplicateCall(function),
+                        _ => Error::OutOfOrderCall(function),
+                    });
+                } else if self.state == IdentityServerState::HandshakeFailed {
                     return Err(Error::BadClient);
                 } else {
                     return Err(Error::InvalidState(self.get_state()));
@@ -281,11 +572,54 @@ impl IdentityServer {
         Ok(None)
     }
 
+    // The state the handshake is in
+    #[cfg(any(test, feature = "test-utils"))]
+    pub fn state(&self) -> IdentityServerState {
+        self.state
+    }
+
+    // The round-trips and bytes exchanged by this handshake so far
+    pub fn stats(&self) -> HandshakeStats {
+        self.rpc
+            .as_ref()
+            .map_or_else(Default::default, |rpc| rpc.stats().into())
+    }
+
+    // The endpoint named in the client's begin_handshake request, once it has been accepted
+    pub fn requested_endpoint(&self) -> Option<&AsciiString> {
+        self.requested_endpoint.as_ref()
+    }
+
+    // Consumes the server and returns its session so that an endpoint handshake
+    // may continue over the same connection
+    pub fn into_session(self) -> Option<Session<RW>> {
+        self.rpc
+    }
+
     pub fn handle_endpoint_request_received(
         &mut self,
         client_allowed: bool,
         endpoint_valid: bool,
         endpoint_challenge: bson::document::Document,
+    ) -> Result<(), Error> {
+        // deny any additional endpoints
+        let additional_endpoints_valid = vec![false; self.additional_endpoints.len()];
+        self.handle_endpoint_request_received_with_additional_endpoints(
+            client_allowed,
+            endpoint_valid,
+            additional_endpoints_valid,
+            endpoint_challenge,
+        )
+    }
+
+    // As handle_endpoint_request_received(), additionally granting or denying
+    // each of the client's additional endpoints
+    pub fn handle_endpoint_request_received_with_additional_endpoints(
+        &mut self,
+        client_allowed: bool,
+        endpoint_valid: bool,
+        additional_endpoints_valid: Vec<bool>,
+        endpoint_challenge: bson::document::Document,
     ) -> Result<(), Error> {
         match (
             &self.state,
@@ -311,16 +645,27 @@ impl IdentityServer {
                 None, // challenge_response
                 None, // endpoint_private_key
             ) => {
+                if additional_endpoints_valid.len() != self.additional_endpoints.len() {
+                    return Err(Error::IncorrectUsage(format!(
+                        "expected validity of {} additional endpoints but received {}",
+                        self.additional_endpoints.len(),
+                        additional_endpoints_valid.len()
+                    )));
+                }
+
                 let mut server_cookie: ServerCookie = Default::default();
                 OsRng.fill_bytes(&mut server_cookie);
 
                 // calculate required size of response message and ensure if fits our
                 // specified message size budget
-                let result = doc!{
-                    "server_cookie" : Bson::Binary(Binary{subtype: BinarySubtype::Generic, bytes: server_cookie.to_vec()}),
-                    "endpoint_challenge" : endpoint_challenge.clone(),
+                let result = IdentityBeginHandshakeResponse {
+                    server_cookie: generic_binary(&server_cookie),
+                    endpoint_challenge: endpoint_challenge.clone(),
+                    endpoint_upgrade: self.endpoint_upgrade.then_some(true),
+                    additional_endpoints: (!self.additional_endpoints.is_empty()).then_some(true),
+                    capabilities: self.negotiated_capabilities.map(Capabilities::names),
                 };
-                let response_section_size = get_response_section_size(Some(Bson::Document(result)))?;
+                let response_section_size = get_response_section_size(Some(to_bson(&result)))?;
                 let message_size = get_message_overhead()? + response_section_size;
                 let max_message_size = rpc.get_max_message_size();
                 if message_size > max_message_size {
@@ -330,6 +675,7 @@ impl IdentityServer {
                     self.endpoint_challenge = Some(endpoint_challenge);
                     self.client_allowed = client_allowed;
                     self.client_requested_endpoint_valid = endpoint_valid;
+                    self.additional_endpoints_valid = additional_endpoints_valid;
                     self.state = IdentityServerState::ChallengeReady;
                     Ok(())
                 }
@@ -379,7 +725,10 @@ impl IdentityServer {
     }
 }
 
-impl ApiSet for IdentityServer {
+impl<RW> ApiSet for IdentityServer<RW>
+where
+    RW: std::io::Read + std::io::Write + Send,
+{
     fn namespace(&self) -> &str {
         "gosling_identity"
     }
@@ -388,7 +737,7 @@ impl ApiSet for IdentityServer {
         &mut self,
         name: &str,
         version: i32,
-        mut args: bson::document::Document,
+        args: bson::document::Document,
         request_cookie: Option<RequestCookie>,
     ) -> Option<Result<Option<bson::Bson>, ErrorCode>> {
         let request_cookie = match request_cookie {
@@ -427,47 +776,161 @@ impl ApiSet for IdentityServer {
                 None, // challenge_response
                 None, // endpoint_private_key
             ) => {
-                let valid_version = match args.remove("version") {
-                    Some(Bson::String(value)) => value == GOSLING_PROTOCOL_VERSION,
-                    _ => false,
-                };
+                let valid_version = matches!(
+                    args.get("version"),
+                    Some(Bson::String(value)) if value == GOSLING_PROTOCOL_VERSION
+                );
                 if !valid_version {
                     self.state = IdentityServerState::HandshakeFailed;
                     return Some(Err(ErrorCode::Runtime(RpcError::BadVersion as i32)));
                 }
 
-                if let (Some(Bson::String(client_identity)), Some(Bson::String(endpoint_name))) =
-                    (args.remove("client_identity"), args.remove("endpoint"))
-                {
-                    // client_identiity
-                    let client_identity = match V3OnionServiceId::from_string(&client_identity) {
-                        Ok(client_identity) => client_identity,
-                        Err(_) => {
+                let args: IdentityBeginHandshakeArgs = match from_document(args) {
+                    Some(args) => args,
+                    None => {
+                        self.state = IdentityServerState::HandshakeFailed;
+                        return Some(Err(ErrorCode::Runtime(RpcError::InvalidArg as i32)));
+                    }
+                };
+
+                // reject replayed requests before doing any further work
+                let committed_client_cookie =
+                    match parse_committed_client_cookie(args.client_cookie, false) {
+                        Ok(committed_client_cookie) => committed_client_cookie,
+                        Err(rpc_error) => {
                             self.state = IdentityServerState::HandshakeFailed;
-                            return Some(Err(ErrorCode::Runtime(RpcError::InvalidArg as i32)));
+                            return Some(Err(ErrorCode::Runtime(rpc_error as i32)));
                         }
                     };
+                if let (Some(replay_cache), Some(committed_client_cookie)) =
+                    (self.replay_cache.as_ref(), committed_client_cookie)
+                {
+                    let mut replay_cache =
+                        replay_cache.lock().unwrap_or_else(PoisonError::into_inner);
+                    if !replay_cache.insert(committed_client_cookie, self.clock.now()) {
+                        self.replay_detected = true;
+                        self.state = IdentityServerState::HandshakeFailed;
+                        return Some(Err(ErrorCode::Runtime(RpcError::Replayed as i32)));
+                    }
+                }
 
-                    // endpoint name
-                    let endpoint_name = match AsciiString::new(endpoint_name) {
-                        Ok(endpoint_name) => endpoint_name,
-                        Err(_) => {
+                // client_identiity
+                let client_identity = match V3OnionServiceId::from_string(&args.client_identity) {
+                    Ok(client_identity) => client_identity,
+                    Err(_) => {
+                        self.state = IdentityServerState::HandshakeFailed;
+                        return Some(Err(ErrorCode::Runtime(RpcError::InvalidArg as i32)));
+                    }
+                };
+
+                // endpoint name
+                let endpoint_name = match AsciiString::new(args.endpoint) {
+                    Ok(endpoint_name) => endpoint_name,
+                    Err(_) => {
+                        self.state = IdentityServerState::HandshakeFailed;
+                        return Some(Err(ErrorCode::Runtime(RpcError::InvalidArg as i32)));
+                    }
+                };
+                if endpoint_name.len() > MAX_ENDPOINT_NAME_LENGTH {
+                    self.endpoint_name_too_long = Some(endpoint_name.len());
+                    self.state = IdentityServerState::HandshakeFailed;
+                    return Some(Err(ErrorCode::Runtime(RpcError::NameTooLong as i32)));
+                }
+
+                // optional; absent from older clients' requests
+                let negotiated_capabilities = args.capabilities.map(|client_capabilities| {
+                    Capabilities::from_names(&client_capabilities).intersection(self.capabilities)
+                });
+
+                // requests for optional features this server does not support are
+                // ignored, as they would be by servers which predate them
+                let supported = self.capabilities;
+
+                // optional; absent from older clients' requests
+                let additional_endpoints = match args.additional_endpoints {
+                    _ if !supported.contains(Capabilities::ADDITIONAL_ENDPOINTS) => {
+                        Default::default()
+                    }
+                    None => Default::default(),
+                    Some(additional_endpoints) => {
+                        if let Some(endpoint) = additional_endpoints
+                            .iter()
+                            .find(|endpoint| endpoint.len() > MAX_ENDPOINT_NAME_LENGTH)
+                        {
+                            self.endpoint_name_too_long = Some(endpoint.len());
                             self.state = IdentityServerState::HandshakeFailed;
-                            return Some(Err(ErrorCode::Runtime(RpcError::InvalidArg as i32)));
+                            return Some(Err(ErrorCode::Runtime(RpcError::NameTooLong as i32)));
                         }
-                    };
+                        match parse_additional_endpoints(&endpoint_name, additional_endpoints) {
+                            Some(additional_endpoints) => additional_endpoints,
+                            None => {
+                                self.state = IdentityServerState::HandshakeFailed;
+                                return Some(Err(ErrorCode::Runtime(RpcError::InvalidArg as i32)));
+                            }
+                        }
+                    }
+                };
 
-                    // save cookie
-                    self.begin_handshake_request_cookie = Some(request_cookie);
+                // optional; absent from older clients' requests
+                let contact_request = match args.contact_request {
+                    _ if !supported.contains(Capabilities::CONTACT_REQUEST) => None,
+                    None => None,
+                    Some(contact_request)
+                        if contact_request.len() <= MAX_CONTACT_REQUEST_SIZE
+                            && !contact_request.contains('\0') =>
+                    {
+                        Some(contact_request)
+                    }
+                    Some(_) => {
+                        self.state = IdentityServerState::HandshakeFailed;
+                        return Some(Err(ErrorCode::Runtime(RpcError::InvalidArg as i32)));
+                    }
+                };
 
-                    // save results
-                    self.client_identity = Some(client_identity);
-                    self.requested_endpoint = Some(endpoint_name);
-                    None
-                } else {
+                // turn away banned clients before doing any further work
+                if let Some(ban) = self
+                    .ban_list
+                    .as_ref()
+                    .and_then(|ban_list| ban_list.get(&client_identity))
+                {
+                    self.client_banned = Some((client_identity, endpoint_name, ban));
                     self.state = IdentityServerState::HandshakeFailed;
-                    Some(Err(ErrorCode::Runtime(RpcError::InvalidArg as i32)))
+                    return Some(Err(ErrorCode::Runtime(RpcError::Banned as i32)));
                 }
+
+                // give the client filter a chance to reject the client
+                // before doing any further work
+                if let Some(client_filter) = self.client_filter.as_ref() {
+                    let verdict = client_filter(&client_identity, &endpoint_name);
+                    let rpc_error = match verdict {
+                        ClientFilterVerdict::Allow => None,
+                        ClientFilterVerdict::TooManyRequests => Some(RpcError::TooManyRequests),
+                        ClientFilterVerdict::Banned => Some(RpcError::Banned),
+                    };
+                    if let Some(rpc_error) = rpc_error {
+                        self.client_filter_verdict = Some(verdict);
+                        self.state = IdentityServerState::HandshakeFailed;
+                        return Some(Err(ErrorCode::Runtime(rpc_error as i32)));
+                    }
+                }
+
+                // optional; absent from older clients' requests
+                let endpoint_upgrade_requested = args.endpoint_upgrade == Some(true);
+                self.endpoint_upgrade = self.endpoint_upgrade_allowed
+                    && supported.contains(Capabilities::ENDPOINT_UPGRADE)
+                    && endpoint_upgrade_requested;
+
+                // save cookie
+                self.begin_handshake_request_cookie = Some(request_cookie);
+
+                // save results
+                self.client_identity = Some(client_identity);
+                self.requested_endpoint = Some(endpoint_name);
+                self.additional_endpoints = additional_endpoints;
+                self.contact_request = contact_request;
+                self.committed_client_cookie = committed_client_cookie;
+                self.negotiated_capabilities = negotiated_capabilities;
+                None
             }
             // handle send_response call
             (
@@ -484,33 +947,29 @@ impl ApiSet for IdentityServer {
                 None, // endpoint_private_key
             ) => {
                 // arg validation
-                if let (
-                    Some(Bson::Binary(Binary {
-                        subtype: BinarySubtype::Generic,
-                        bytes: client_cookie,
-                    })),
-                    Some(Bson::Binary(Binary {
-                        subtype: BinarySubtype::Generic,
-                        bytes: client_identity_proof_signature,
-                    })),
-                    Some(Bson::Binary(Binary {
-                        subtype: BinarySubtype::Generic,
-                        bytes: client_authorization_key,
-                    })),
-                    Some(Bson::Boolean(client_authorization_key_signbit)),
-                    Some(Bson::Binary(Binary {
-                        subtype: BinarySubtype::Generic,
-                        bytes: client_authorization_signature,
-                    })),
-                    Some(Bson::Document(challenge_response)),
-                ) = (
-                    args.remove("client_cookie"),
-                    args.remove("client_identity_proof_signature"),
-                    args.remove("client_authorization_key"),
-                    args.remove("client_authorization_key_signbit"),
-                    args.remove("client_authorization_signature"),
-                    args.remove("challenge_response"),
-                ) {
+                if has_malformed_signbit(&args) {
+                    self.invalid_signbit = true;
+                    self.state = IdentityServerState::HandshakeFailed;
+                    return Some(Err(ErrorCode::Runtime(RpcError::InvalidSignbit as i32)));
+                }
+                let args: Option<IdentitySendResponseArgs> = from_document(args);
+                if let Some((
+                    Some(client_cookie),
+                    Some(client_identity_proof_signature),
+                    Some(client_authorization_key),
+                    client_authorization_key_signbit,
+                    Some(client_authorization_signature),
+                    challenge_response,
+                )) = args.map(|args| {
+                    (
+                        generic_bytes(args.client_cookie),
+                        generic_bytes(args.client_identity_proof_signature),
+                        generic_bytes(args.client_authorization_key),
+                        args.client_authorization_key_signbit,
+                        generic_bytes(args.client_authorization_signature),
+                        args.challenge_response,
+                    )
+                }) {
                     // client_cookie
                     let client_cookie: ClientCookie = match client_cookie.try_into() {
                         Ok(client_cookie) => client_cookie,
@@ -519,6 +978,13 @@ impl ApiSet for IdentityServer {
                             return Some(Err(ErrorCode::Runtime(RpcError::InvalidArg as i32)));
                         }
                     };
+                    // must be the client cookie committed to in begin_handshake
+                    if let Some(committed_client_cookie) = self.committed_client_cookie.as_ref() {
+                        if !bool::from(client_cookie[..].ct_eq(&committed_client_cookie[..])) {
+                            self.state = IdentityServerState::HandshakeFailed;
+                            return Some(Err(ErrorCode::Runtime(RpcError::InvalidArg as i32)));
+                        }
+                    }
 
                     // client_identity_proof_signature
                     let client_identity_proof_signature: [u8; ED25519_SIGNATURE_SIZE] =
@@ -612,9 +1078,26 @@ impl ApiSet for IdentityServer {
                     Some(Err(ErrorCode::Runtime(RpcError::InvalidArg as i32)))
                 }
             }
+            // calls to an already failed handshake
+            (_, _, &IdentityServerState::HandshakeFailed, _, _, _, _, _, _, _, _) => {
+                Some(Err(ErrorCode::Runtime(RpcError::Failure as i32)))
+            }
             _ => {
+                // begin_handshake is always accepted first, so any other
+                // begin_handshake call is a duplicate, while send_response may
+                // also arrive before begin_handshake has been answered
+                let duplicate =
+                    name == "begin_handshake" || self.send_response_request_cookie.is_some();
+                let expected = self
+                    .begin_handshake_request_cookie
+                    .is_none()
+                    .then_some("begin_handshake");
+                let (error_code, violation) =
+                    unexpected_call_error(name, version, duplicate, expected);
+                self.protocol_violation =
+                    violation.map(|(rpc_error, violation)| (request_cookie, rpc_error, violation));
                 self.state = IdentityServerState::HandshakeFailed;
-                Some(Err(ErrorCode::Runtime(RpcError::Failure as i32)))
+                Some(Err(error_code))
             }
         }
     }
@@ -646,20 +1129,25 @@ impl ApiSet for IdentityServer {
             // challenge_response
             {
                 self.state = IdentityServerState::WaitingForSendResponse;
-                Some((
-                    begin_handshake_request_cookie,
-                    Ok(Some(Bson::Document(doc! {
-                        "server_cookie" : Bson::Binary(Binary{subtype: BinarySubtype::Generic, bytes: server_cookie.to_vec()}),
-                        "endpoint_challenge" : std::mem::take(endpoint_challenge),
-                    }))),
-                ))
+                self.challenge_sent_timestamp = Some(self.clock.now());
+                let result = IdentityBeginHandshakeResponse {
+                    server_cookie: generic_binary(server_cookie),
+                    endpoint_challenge: std::mem::take(endpoint_challenge),
+                    // only sent when the client requested it, so older clients never see it
+                    endpoint_upgrade: self.endpoint_upgrade.then_some(true),
+                    // likewise only sent to clients which requested additional endpoints
+                    additional_endpoints: (!self.additional_endpoints.is_empty()).then_some(true),
+                    // and to clients which sent their capabilities
+                    capabilities: self.negotiated_capabilities.map(Capabilities::names),
+                };
+                Some((begin_handshake_request_cookie, Ok(Some(to_bson(&result)))))
             }
             (&IdentityServerState::ChallengeReady, _, _, _, _, _, _, _, _) => unreachable!(),
             (
                 &IdentityServerState::ChallengeVerificationReady,
                 Some(_begin_handshake_request_cookie),
-                Some(_client_identity),
-                Some(_requested_endpoint),
+                Some(client_identity),
+                Some(requested_endpoint),
                 Some(_server_cookie),
                 Some(_endpoint_challenge),
                 Some(send_response_request_cookie),
@@ -675,22 +1163,117 @@ impl ApiSet for IdentityServer {
 
                 self.state = IdentityServerState::ChallengeVerificationResponseSent;
                 if success {
-                    let endpoint_private_key = Ed25519PrivateKey::generate();
+                    let endpoint_private_key = derive_endpoint_private_key(
+                        self.endpoint_key_derivation_key.as_ref(),
+                        client_identity,
+                        requested_endpoint,
+                    );
                     let endpoint_service_id =
                         V3OnionServiceId::from_private_key(&endpoint_private_key);
                     self.endpoint_private_key = Some(endpoint_private_key);
-                    Some((
-                        send_response_request_cookie,
-                        Ok(Some(Bson::String(endpoint_service_id.to_string()))),
-                    ))
+
+                    if self.additional_endpoints.is_empty() {
+                        let result = IdentitySendResponseResponse::EndpointServiceId(
+                            endpoint_service_id.to_string(),
+                        );
+                        return Some((send_response_request_cookie, Ok(Some(to_bson(&result)))));
+                    }
+
+                    // grant or deny each additional endpoint individually
+                    let mut additional_endpoint_grants = bson::document::Document::new();
+                    for (endpoint_name, endpoint_valid) in self
+                        .additional_endpoints
+                        .iter()
+                        .zip(self.additional_endpoints_valid.iter())
+                    {
+                        if *endpoint_valid {
+                            let endpoint_private_key = derive_endpoint_private_key(
+                                self.endpoint_key_derivation_key.as_ref(),
+                                client_identity,
+                                endpoint_name,
+                            );
+                            let endpoint_service_id =
+                                V3OnionServiceId::from_private_key(&endpoint_private_key);
+                            additional_endpoint_grants.insert(
+                                endpoint_name.as_str(),
+                                Bson::String(endpoint_service_id.to_string()),
+                            );
+                            self.additional_endpoint_private_keys
+                                .push((endpoint_name.clone(), endpoint_private_key));
+                        } else {
+                            additional_endpoint_grants.insert(endpoint_name.as_str(), Bson::Null);
+                        }
+                    }
+                    let result = IdentitySendResponseResponse::AdditionalEndpoints {
+                        endpoint_service_id: endpoint_service_id.to_string(),
+                        additional_endpoints: additional_endpoint_grants,
+                    };
+                    Some((send_response_request_cookie, Ok(Some(to_bson(&result)))))
                 } else {
+                    // only tell the client why it failed once it has proven who it
+                    // is and is allowed in, so nothing is revealed to other clients
+                    let rpc_error = if !self.client_allowed
+                        || !self.client_proof_signature_valid
+                        || !self.client_auth_signature_valid
+                    {
+                        RpcError::NotAuthorized
+                    } else if !self.client_requested_endpoint_valid {
+                        RpcError::EndpointUnsupported
+                    } else {
+                        RpcError::ChallengeFailed
+                    };
                     Some((
                         send_response_request_cookie,
-                        Err(ErrorCode::Runtime(RpcError::Failure as i32)),
+                        Err(ErrorCode::Runtime(rpc_error as i32)),
                     ))
                 }
             }
             _ => None,
         }
     }
+
+    fn error_data(&mut self, request_cookie: RequestCookie) -> Option<bson::Bson> {
+        match self.protocol_violation.as_ref() {
+            Some((cookie, _, violation)) if *cookie == request_cookie => Some(to_bson(violation)),
+            _ => None,
+        }
+    }
+}
+
+// the private key of an endpoint granted to client_identity, derived from the
+// identity server's key if given or randomly generated otherwise
+fn derive_endpoint_private_key(
+    identity_private_key: Option<&Ed25519PrivateKey>,
+    client_identity: &V3OnionServiceId,
+    endpoint_name: &AsciiString,
+) -> Ed25519PrivateKey {
+    match identity_private_key {
+        Some(identity_private_key) => endpoint_keys::derive_endpoint_private_key(
+            identity_private_key,
+            client_identity,
+            endpoint_name,
+        ),
+        None => Ed25519PrivateKey::generate(),
+    }
+}
+
+// parse a client's additional endpoint requests; returns None if any is not an
+// ASCII string or is requested more than once, or if there are too many
+fn parse_additional_endpoints(
+    requested_endpoint: &AsciiString,
+    additional_endpoints: Vec<String>,
+) -> Option<Vec<AsciiString>> {
+    if additional_endpoints.len() > MAX_ADDITIONAL_ENDPOINTS {
+        return None;
+    }
+
+    let mut parsed: Vec<AsciiString> = Vec::with_capacity(additional_endpoints.len());
+    for endpoint in additional_endpoints {
+        let endpoint = AsciiString::new(endpoint).ok()?;
+        if endpoint == *requested_endpoint || parsed.contains(&endpoint) {
+            return None;
+        }
+        parsed.push(endpoint);
+    }
+    Some(parsed)
 }
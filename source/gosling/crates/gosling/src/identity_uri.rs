@@ -0,0 +1,353 @@
+// extern crates
+#[cfg(test)]
+use anyhow::bail;
+use tor_interface::tor_crypto::*;
+
+// internal crates
+use crate::ascii_string::*;
+
+/// The error type for the [`IdentityUri`] type.
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    /// An invalid argument was provided to a function
+    #[error("invalid argument: {0}")]
+    InvalidArgument(String),
+
+    /// A string could not be parsed as an identity URI
+    #[error("failed to parse identity uri: {0}")]
+    ParseError(String),
+}
+
+// scheme of an identity uri; compared case-insensitively when parsing
+const IDENTITY_URI_SCHEME: &str = "gosling";
+// query parameter containing the endpoint name
+const ENDPOINT_PARAMETER: &str = "endpoint";
+
+// percent-encode all but the unreserved characters of RFC 3986
+fn percent_encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            byte => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+fn percent_decode(value: &str) -> Result<String, Error> {
+    let value = value.as_bytes();
+    let mut decoded: Vec<u8> = Vec::with_capacity(value.len());
+    let mut i = 0usize;
+    while i < value.len() {
+        if value[i] == b'%' {
+            let byte = value
+                .get(i + 1..i + 3)
+                .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+            match byte {
+                Some(byte) => decoded.push(byte),
+                None => {
+                    return Err(Error::ParseError(
+                        "'%' must be followed by two hexadecimal digits".to_string(),
+                    ))
+                }
+            }
+            i += 3;
+        } else {
+            decoded.push(value[i]);
+            i += 1;
+        }
+    }
+    match String::from_utf8(decoded) {
+        Ok(decoded) => Ok(decoded),
+        Err(_) => Err(Error::ParseError(
+            "query parameter is not valid percent-encoded UTF-8".to_string(),
+        )),
+    }
+}
+
+/// A link to a gosling identity server, which applications may exchange (e.g. as text or a QR code) to share contact information.
+///
+/// An `IdentityUri` has the canonical form `gosling://<identity service id>?endpoint=<endpoint name>`. The `endpoint` query parameter is optional and names the endpoint the recipient should request when contacting the identity server.
+///
+/// Any other query parameters are preserved in order so applications may attach their own data and future versions may add parameters without breaking older parsers; unrecognised parameters should be ignored. Query parameter names and values are percent-encoded as per RFC 3986. When parsing, the scheme and service id are case-insensitive and any fragment is ignored.
+#[derive(Clone, Debug, PartialEq)]
+pub struct IdentityUri {
+    identity_service_id: V3OnionServiceId,
+    endpoint_name: Option<AsciiString>,
+    // every query parameter other than the endpoint
+    parameters: Vec<(String, String)>,
+}
+
+impl IdentityUri {
+    /// Construct a new `IdentityUri`.
+    ///
+    /// # Parameters
+    /// - `identity_service_id`: the onion-service service-id of the identity server
+    /// - `endpoint_name`: the optional ASCII-encoded name of the endpoint to request from the identity server; must not be empty
+    pub fn new(
+        identity_service_id: V3OnionServiceId,
+        endpoint_name: Option<String>,
+    ) -> Result<Self, Error> {
+        let endpoint_name = match endpoint_name {
+            Some(endpoint_name) if endpoint_name.is_empty() => {
+                return Err(Error::InvalidArgument(
+                    "endpoint_name must not be empty".to_string(),
+                ))
+            }
+            Some(endpoint_name) => match AsciiString::new(endpoint_name) {
+                Ok(endpoint_name) => Some(endpoint_name),
+                Err(_) => {
+                    return Err(Error::InvalidArgument(
+                        "endpoint_name must be an ASCII string".to_string(),
+                    ))
+                }
+            },
+            None => None,
+        };
+
+        Ok(Self {
+            identity_service_id,
+            endpoint_name,
+            parameters: Default::default(),
+        })
+    }
+
+    /// Parse an `IdentityUri` from a string created by [`IdentityUri::to_string()`].
+    pub fn from_string(uri: &str) -> Result<Self, Error> {
+        // fragments are reserved for the application
+        let uri = match uri.split_once('#') {
+            Some((uri, _fragment)) => uri,
+            None => uri,
+        };
+
+        let rest = match uri.split_once("://") {
+            Some((scheme, rest)) if scheme.eq_ignore_ascii_case(IDENTITY_URI_SCHEME) => rest,
+            _ => {
+                return Err(Error::ParseError(format!(
+                    "expects string that begins with '{}://'",
+                    IDENTITY_URI_SCHEME
+                )))
+            }
+        };
+        let (authority, query) = match rest.split_once('?') {
+            Some((authority, query)) => (authority, query),
+            None => (rest, ""),
+        };
+        // allow an empty path
+        let authority = authority.strip_suffix('/').unwrap_or(authority);
+
+        let identity_service_id =
+            match V3OnionServiceId::from_string(&authority.to_ascii_lowercase()) {
+                Ok(identity_service_id) => identity_service_id,
+                Err(_) => {
+                    return Err(Error::ParseError(format!(
+                        "'{}' is not a valid v3 onion service id",
+                        authority
+                    )))
+                }
+            };
+
+        let mut endpoint_name: Option<String> = None;
+        let mut parameters: Vec<(String, String)> = Default::default();
+        for parameter in query.split('&').filter(|parameter| !parameter.is_empty()) {
+            let (key, value) = parameter.split_once('=').unwrap_or((parameter, ""));
+            let key = percent_decode(key)?;
+            let value = percent_decode(value)?;
+
+            if key.is_empty() {
+                return Err(Error::ParseError(
+                    "query parameter name must not be empty".to_string(),
+                ));
+            } else if key == ENDPOINT_PARAMETER {
+                if endpoint_name.is_some() {
+                    return Err(Error::ParseError(format!(
+                        "query parameter '{}' may only appear once",
+                        ENDPOINT_PARAMETER
+                    )));
+                }
+                endpoint_name = Some(value);
+            } else {
+                parameters.push((key, value));
+            }
+        }
+
+        let mut identity_uri = match Self::new(identity_service_id, endpoint_name) {
+            Ok(identity_uri) => identity_uri,
+            Err(Error::InvalidArgument(msg)) => return Err(Error::ParseError(msg)),
+            Err(err) => return Err(err),
+        };
+        identity_uri.parameters = parameters;
+        Ok(identity_uri)
+    }
+
+    /// The onion-service service-id of the identity server
+    pub fn identity_service_id(&self) -> &V3OnionServiceId {
+        &self.identity_service_id
+    }
+
+    /// The ASCII-encoded name of the endpoint to request from the identity server, if any
+    pub fn endpoint_name(&self) -> Option<&str> {
+        self.endpoint_name
+            .as_ref()
+            .map(|endpoint_name| endpoint_name.as_str())
+    }
+
+    /// The value of the first query parameter with the given name, if any. The endpoint is not considered a query parameter; see [`IdentityUri::endpoint_name()`].
+    pub fn parameter(&self, key: &str) -> Option<&str> {
+        self.parameters
+            .iter()
+            .find(|(name, _)| name == key)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// All query parameters other than the endpoint as (name, value) pairs, in order
+    pub fn parameters(&self) -> &[(String, String)] {
+        &self.parameters
+    }
+
+    /// Set an additional query parameter, replacing any existing parameters with the same name.
+    ///
+    /// # Parameters
+    /// - `key`: the name of the query parameter; must not be empty or `endpoint`
+    /// - `value`: the value of the query parameter
+    pub fn set_parameter(&mut self, key: String, value: String) -> Result<(), Error> {
+        if key.is_empty() {
+            return Err(Error::InvalidArgument("key must not be empty".to_string()));
+        }
+        if key == ENDPOINT_PARAMETER {
+            return Err(Error::InvalidArgument(format!(
+                "key must not be '{}'; use IdentityUri::new() to set the endpoint name",
+                ENDPOINT_PARAMETER
+            )));
+        }
+
+        self.parameters.retain(|(name, _)| *name != key);
+        self.parameters.push((key, value));
+        Ok(())
+    }
+
+    /// Remove all query parameters with the given name, returning whether any were present.
+    pub fn remove_parameter(&mut self, key: &str) -> bool {
+        let count = self.parameters.len();
+        self.parameters.retain(|(name, _)| name != key);
+        self.parameters.len() != count
+    }
+}
+
+impl std::fmt::Display for IdentityUri {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}://{}", IDENTITY_URI_SCHEME, self.identity_service_id)?;
+
+        let endpoint_parameter = self
+            .endpoint_name
+            .as_ref()
+            .map(|endpoint_name| (ENDPOINT_PARAMETER, endpoint_name.as_str()));
+        let parameters = self
+            .parameters
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()));
+        for (i, (key, value)) in endpoint_parameter.into_iter().chain(parameters).enumerate() {
+            let separator = if i == 0 { '?' } else { '&' };
+            write!(
+                f,
+                "{}{}={}",
+                separator,
+                percent_encode(key),
+                percent_encode(value)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_identity_uri() -> anyhow::Result<()> {
+    let identity_service_id =
+        V3OnionServiceId::from_string("6l62fw7tqctlu5fesdqukvpoxezkaxbzllrafa2ve6ewuhzphxczsjyd")?;
+
+    // identity only
+    let uri = IdentityUri::new(identity_service_id.clone(), None)?;
+    assert_eq!(
+        uri.to_string(),
+        "gosling://6l62fw7tqctlu5fesdqukvpoxezkaxbzllrafa2ve6ewuhzphxczsjyd"
+    );
+    assert_eq!(IdentityUri::from_string(&uri.to_string())?, uri);
+    assert_eq!(uri.endpoint_name(), None);
+
+    // endpoint and additional parameters round-trip
+    let mut uri = IdentityUri::new(identity_service_id.clone(), Some("chat".to_string()))?;
+    uri.set_parameter("name".to_string(), "Alice & Bob ❤".to_string())?;
+    let uri_string = uri.to_string();
+    println!("uri: {}", uri_string);
+    assert_eq!(
+        uri_string,
+        "gosling://6l62fw7tqctlu5fesdqukvpoxezkaxbzllrafa2ve6ewuhzphxczsjyd?endpoint=chat&name=Alice%20%26%20Bob%20%E2%9D%A4"
+    );
+    let parsed_uri = IdentityUri::from_string(&uri_string)?;
+    assert_eq!(parsed_uri, uri);
+    assert_eq!(*parsed_uri.identity_service_id(), identity_service_id);
+    assert_eq!(parsed_uri.endpoint_name(), Some("chat"));
+    assert_eq!(parsed_uri.parameter("name"), Some("Alice & Bob ❤"));
+    assert_eq!(parsed_uri.parameter("missing"), None);
+
+    // lenient parsing
+    let parsed_uri = IdentityUri::from_string(
+        "GOSLING://6L62FW7TQCTLU5FESDQUKVPOXEZKAXBZLLRAFA2VE6EWUHZPHXCZSJYD/?future&endpoint=chat&&v=2#fragment",
+    )?;
+    assert_eq!(*parsed_uri.identity_service_id(), identity_service_id);
+    assert_eq!(parsed_uri.endpoint_name(), Some("chat"));
+    assert_eq!(
+        parsed_uri.parameters(),
+        &[
+            ("future".to_string(), "".to_string()),
+            ("v".to_string(), "2".to_string())
+        ]
+    );
+
+    // parameter editing
+    let mut uri = parsed_uri;
+    uri.set_parameter("v".to_string(), "3".to_string())?;
+    assert_eq!(uri.parameter("v"), Some("3"));
+    assert!(uri.remove_parameter("future"));
+    assert!(!uri.remove_parameter("future"));
+    assert!(uri
+        .set_parameter("endpoint".to_string(), "x".to_string())
+        .is_err());
+    assert!(uri.set_parameter("".to_string(), "x".to_string()).is_err());
+
+    // invalid endpoint names are rejected
+    for endpoint_name in ["", "heart ❤"] {
+        if IdentityUri::new(identity_service_id.clone(), Some(endpoint_name.to_string())).is_ok() {
+            bail!("invalid endpoint name '{}' accepted", endpoint_name);
+        }
+    }
+
+    // malformed uris are rejected
+    let invalid_uris = [
+        "",
+        "gosling://",
+        "gosling:6l62fw7tqctlu5fesdqukvpoxezkaxbzllrafa2ve6ewuhzphxczsjyd",
+        "https://6l62fw7tqctlu5fesdqukvpoxezkaxbzllrafa2ve6ewuhzphxczsjyd",
+        "gosling://6l62fw7tqctlu5fesdqukvpoxezkaxbzllrafa2ve6ewuhzphxczsjyd.onion",
+        "gosling://6l62fw7tqctlu5fesdqukvpoxezkaxbzllrafa2ve6ewuhzphxczsjya",
+        "gosling://6l62fw7tqctlu5fesdqukvpoxezkaxbzllrafa2ve6ewuhzphxczsjyd?endpoint=",
+        "gosling://6l62fw7tqctlu5fesdqukvpoxezkaxbzllrafa2ve6ewuhzphxczsjyd?endpoint=%E2%9D%A4",
+        "gosling://6l62fw7tqctlu5fesdqukvpoxezkaxbzllrafa2ve6ewuhzphxczsjyd?endpoint=a&endpoint=b",
+        "gosling://6l62fw7tqctlu5fesdqukvpoxezkaxbzllrafa2ve6ewuhzphxczsjyd?=value",
+        "gosling://6l62fw7tqctlu5fesdqukvpoxezkaxbzllrafa2ve6ewuhzphxczsjyd?name=%2",
+        "gosling://6l62fw7tqctlu5fesdqukvpoxezkaxbzllrafa2ve6ewuhzphxczsjyd?name=%+1",
+        "gosling://6l62fw7tqctlu5fesdqukvpoxezkaxbzllrafa2ve6ewuhzphxczsjyd?name=%FF",
+    ];
+    for invalid_uri in invalid_uris {
+        if let Ok(uri) = IdentityUri::from_string(invalid_uri) {
+            bail!("invalid uri '{}' parsed as {:?}", invalid_uri, uri);
+        }
+    }
+
+    Ok(())
+}
@@ -1,20 +1,47 @@
 #![doc = include_str!("../README.md")]
+// the C API lives in the separate cgosling crate; keep this crate free of unsafe code
+#![forbid(unsafe_code)]
 
 // some internal functions take a lot of args but thats ok
 #![allow(clippy::too_many_arguments)]
 
-mod ascii_string;
+/// ASCII-encoded strings, used for endpoint and channel names
+pub mod ascii_string;
+/// Bans of misbehaving peers, enforced by a [`context::Context`]'s identity and endpoint servers
+pub mod bans;
+/// Endpoint challenges which cannot be answered by replaying an earlier response
+pub mod challenge;
+mod channel_pattern;
+/// Per-client limits on the channels opened through endpoint servers
+pub mod channel_quota;
+/// Probes of a [`context::Context`]'s connectivity to the Tor Network
+pub mod connectivity;
+// deprecated shims for renamed or moved items
+mod compat;
 /// Implementation of the Gosling protocol
 pub mod context;
+/// Loss-tolerant message traffic over the connection of a completed endpoint handshake
+pub mod datagram_channel;
 #[cfg(fuzzing)]
 pub mod endpoint_client;
 #[cfg(not(fuzzing))]
 mod endpoint_client;
+/// Shareable records of granted endpoint access
+pub mod endpoint_grant;
+/// Deterministic derivation of endpoint servers' keys from an identity server's key
+pub mod endpoint_keys;
+/// Signed notices of revoked endpoint access
+pub mod endpoint_revocation;
 #[cfg(fuzzing)]
 pub mod endpoint_server;
 #[cfg(not(fuzzing))]
 mod endpoint_server;
+/// Push-style consumption of a [`context::Context`]'s events through typed methods
+pub mod events_sink;
 pub(crate) mod gosling;
+/// The identity and endpoint handshake state machines on their own, for applications which provide their own transport (e.g. a WebSocket provided by the host when targeting wasm32) instead of using a [`context::Context`]
+#[cfg(feature = "handshake-state-machines")]
+pub mod handshake;
 #[cfg(fuzzing)]
 pub mod identity_client;
 #[cfg(not(fuzzing))]
@@ -23,3 +50,28 @@ mod identity_client;
 pub mod identity_server;
 #[cfg(not(fuzzing))]
 mod identity_server;
+/// Shareable links to identity servers
+pub mod identity_uri;
+/// Format versioning and migration of the documents saved by persistent stores
+pub mod persistence;
+/// Human-friendly names for known identities and the endpoint servers they granted
+pub mod petnames;
+/// Trust-on-first-use pinning of the endpoint servers granted by identity servers
+pub mod pinning;
+/// The stable names needed to use a [`context::Context`], for glob-importing with `use gosling::prelude::*`. Until 1.0, prelude items are only removed or changed incompatibly in a release which bumps the minor version, and only after being deprecated (with a warning pointing to their replacement) for at least one release.
+pub mod prelude;
+// renders a Context's metrics for Prometheus to scrape
+#[cfg(feature = "prometheus")]
+mod prometheus;
+/// Schemas of the honk-rpc messages exchanged by the identity and endpoint handshakes
+pub mod protocol;
+/// Recorded handshake transcripts, replayed against the handshake state machines to detect changes in their behaviour
+#[cfg(any(test, feature = "test-utils"))]
+pub mod replay;
+/// Application Honk-RPC traffic over the session of a completed endpoint handshake
+pub mod rpc_channel;
+/// In-memory transports and helpers for testing an application's handshake handling against the handshake state machines
+#[cfg(any(test, feature = "test-utils"))]
+pub mod testing;
+/// Latency, traffic and outcome measurements for handshakes
+pub mod timing;
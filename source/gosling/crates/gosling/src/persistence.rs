@@ -0,0 +1,228 @@
+// standard
+use std::collections::BTreeMap;
+
+// extern crates
+use bson::{Bson, Document};
+
+/// The error type for the [`Migrations`] and [`FormatVersion`] types.
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    /// An invalid argument was provided to a function
+    #[error("invalid argument: {0}")]
+    InvalidArgument(String),
+
+    /// A saved document has no valid format version
+    #[error("format version is missing or invalid")]
+    VersionMissing,
+
+    /// A saved document can only be read by a newer release
+    #[error(
+        "format version {0} requires a reader of at least version {1}, but this release reads version {2}"
+    )]
+    UnsupportedVersion(i32, i32, i32),
+
+    /// No migration is registered from a format version
+    #[error("no migration from format version {0} is registered")]
+    MigrationMissing(i32),
+
+    /// A migration could not upgrade a saved document
+    #[error("migration from format version {0} failed: {1}")]
+    MigrationFailed(i32, String),
+
+    /// A migration from the format version is already registered
+    #[error("a migration from format version {0} is already registered")]
+    MigrationAlreadyRegistered(i32),
+}
+
+// header keys of a saved document
+const VERSION_KEY: &str = "version";
+const COMPATIBLE_VERSION_KEY: &str = "compatible_version";
+
+/// The format version header of a document saved by a persistent store such as a [`PetnameStore`](crate::petnames::PetnameStore).
+///
+/// A release which adds fields to a format bumps `version` but not `compatible_version`, so older releases which read at least `compatible_version` can still load the document; they keep the fields they do not understand and write them back unchanged. A release which changes the meaning of existing fields bumps both, and older releases refuse to load the document rather than corrupt it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FormatVersion {
+    /// The format version the document was written in
+    pub version: i32,
+    /// The oldest format version whose readers can load the document
+    pub compatible_version: i32,
+}
+
+impl FormatVersion {
+    /// Read the format version header of a saved document. Documents saved before compatibility versions were introduced are only compatible with their own version.
+    pub fn read(document: &Document) -> Result<Self, Error> {
+        let version = match document.get(VERSION_KEY) {
+            Some(Bson::Int32(version)) if *version > 0 => *version,
+            _ => return Err(Error::VersionMissing),
+        };
+        let compatible_version = match document.get(COMPATIBLE_VERSION_KEY) {
+            Some(Bson::Int32(compatible_version))
+                if *compatible_version > 0 && *compatible_version <= version =>
+            {
+                *compatible_version
+            }
+            None => version,
+            _ => return Err(Error::VersionMissing),
+        };
+        Ok(Self {
+            version,
+            compatible_version,
+        })
+    }
+
+    /// Write this format version header to a document, replacing any existing header
+    pub fn write(&self, document: &mut Document) {
+        document.insert(VERSION_KEY, self.version);
+        document.insert(COMPATIBLE_VERSION_KEY, self.compatible_version);
+    }
+}
+
+/// A migration upgrades a saved document in place from one format version to the next
+pub type Migration = Box<dyn Fn(&mut Document) -> Result<(), String> + Send + Sync>;
+
+/// The migrations which upgrade documents saved in earlier versions of a format to its current version.
+///
+/// Each [`Migration`] is registered for the version it upgrades from and upgrades the document to the following version; loading a document saved several versions ago runs each migration in turn. A format's store provides its built-in migrations (e.g. [`PetnameStore::migrations()`](crate::petnames::PetnameStore::migrations)), to which applications may register their own, e.g. to read documents written by a modified release.
+pub struct Migrations {
+    current: FormatVersion,
+    migrations: BTreeMap<i32, Migration>,
+}
+
+impl Migrations {
+    /// Construct a `Migrations` without any migrations
+    ///
+    /// # Parameters
+    /// - `current`: the format version written by this release
+    pub fn new(current: FormatVersion) -> Self {
+        Self {
+            current,
+            migrations: Default::default(),
+        }
+    }
+
+    /// The format version written by this release
+    pub fn current(&self) -> FormatVersion {
+        self.current
+    }
+
+    /// Register a migration which upgrades a document from `from_version` to `from_version + 1`
+    ///
+    /// # Parameters
+    /// - `from_version`: a format version older than the current version
+    /// - `migration`: upgrades the document in place, or returns a description of why it cannot; it need not update the document's format version header
+    pub fn register<F>(&mut self, from_version: i32, migration: F) -> Result<(), Error>
+    where
+        F: Fn(&mut Document) -> Result<(), String> + Send + Sync + 'static,
+    {
+        if from_version <= 0 || from_version >= self.current.version {
+            return Err(Error::InvalidArgument(format!(
+                "from_version must be between 1 and {}",
+                self.current.version - 1
+            )));
+        }
+        if self.migrations.contains_key(&from_version) {
+            return Err(Error::MigrationAlreadyRegistered(from_version));
+        }
+        self.migrations.insert(from_version, Box::new(migration));
+        Ok(())
+    }
+
+    /// Upgrade a saved document to the current format version.
+    ///
+    /// Documents saved in an older version are migrated and their header updated to the current version. Documents saved in the current version, or in a newer version compatible with it, are left unchanged.
+    ///
+    /// # Returns
+    /// The format version the document should be written back in: the current version, or the newer version it was saved in
+    pub fn migrate(&self, document: &mut Document) -> Result<FormatVersion, Error> {
+        let saved = FormatVersion::read(document)?;
+        if saved.compatible_version > self.current.version {
+            return Err(Error::UnsupportedVersion(
+                saved.version,
+                saved.compatible_version,
+                self.current.version,
+            ));
+        }
+        if saved.version >= self.current.version {
+            return Ok(saved);
+        }
+
+        for version in saved.version..self.current.version {
+            let migration = match self.migrations.get(&version) {
+                Some(migration) => migration,
+                None => return Err(Error::MigrationMissing(version)),
+            };
+            migration(document).map_err(|reason| Error::MigrationFailed(version, reason))?;
+        }
+        self.current.write(document);
+        Ok(self.current)
+    }
+}
+
+#[test]
+fn test_migrations() -> anyhow::Result<()> {
+    let v3 = FormatVersion {
+        version: 3,
+        compatible_version: 2,
+    };
+    let mut migrations = Migrations::new(v3);
+    migrations.register(1, |document| {
+        let name = document.get_str("name").map_err(|err| err.to_string())?;
+        let name = name.to_string();
+        document.remove("name");
+        document.insert("names", vec![name]);
+        Ok(())
+    })?;
+    assert!(matches!(
+        migrations.register(1, |_| Ok(())),
+        Err(Error::MigrationAlreadyRegistered(1))
+    ));
+    assert!(migrations.register(3, |_| Ok(())).is_err());
+
+    // a migration is missing from version 2
+    let mut document = bson::doc! {"version": 1, "name": "alice"};
+    assert!(matches!(
+        migrations.migrate(&mut document),
+        Err(Error::MigrationMissing(2))
+    ));
+
+    // migrations run in order up to the current version
+    migrations.register(2, |document| {
+        document.insert("extra", true);
+        Ok(())
+    })?;
+    let mut document = bson::doc! {"version": 1, "name": "alice"};
+    assert_eq!(migrations.migrate(&mut document)?, v3);
+    assert_eq!(
+        document,
+        bson::doc! {"version": 3, "names": ["alice"], "extra": true, "compatible_version": 2}
+    );
+
+    // failures are reported with the version they failed to upgrade
+    let mut document = bson::doc! {"version": 1};
+    assert!(matches!(
+        migrations.migrate(&mut document),
+        Err(Error::MigrationFailed(1, _))
+    ));
+
+    // newer documents are loaded unchanged while they remain compatible
+    let v4 = FormatVersion {
+        version: 4,
+        compatible_version: 3,
+    };
+    let mut document = bson::doc! {};
+    v4.write(&mut document);
+    assert_eq!(migrations.migrate(&mut document)?, v4);
+    let mut document = bson::doc! {"version": 5, "compatible_version": 4};
+    assert!(matches!(
+        migrations.migrate(&mut document),
+        Err(Error::UnsupportedVersion(5, 4, 3))
+    ));
+    let mut document = bson::doc! {"compatible_version": 1};
+    assert!(matches!(
+        migrations.migrate(&mut document),
+        Err(Error::VersionMissing)
+    ));
+
+    Ok(())
+}
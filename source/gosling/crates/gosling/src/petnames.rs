@@ -0,0 +1,544 @@
+// standard
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+// extern crates
+use bson::Document;
+use serde::{Deserialize, Serialize};
+use tor_interface::tor_crypto::*;
+
+// internal crates
+use crate::endpoint_grant::EndpointGrant;
+use crate::persistence::{FormatVersion, Migrations};
+
+/// The error type for the [`PetnameStore`] type.
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    /// An invalid argument was provided to a function
+    #[error("invalid argument: {0}")]
+    InvalidArgument(String),
+
+    /// The petname is already given to a different identity
+    #[error("petname '{0}' is already in use")]
+    PetnameInUse(String),
+
+    /// The identity already has a different petname
+    #[error("identity {0} already has the petname '{1}'")]
+    IdentityInUse(V3OnionServiceId, String),
+
+    /// No identity has the petname
+    #[error("petname '{0}' not found")]
+    PetnameNotFound(String),
+
+    /// Saved petnames could not be parsed
+    #[error("failed to parse petnames: {0}")]
+    ParseError(String),
+
+    /// Saved petnames could not be upgraded to the current format version
+    #[error(transparent)]
+    Format(#[from] crate::persistence::Error),
+
+    /// The petnames file could not be read or written
+    #[error("petnames file error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+// current serialisation format version
+const PETNAME_STORE_FORMAT: FormatVersion = FormatVersion {
+    version: 1,
+    compatible_version: 1,
+};
+
+// keys of the saved fields understood by this release
+const PETNAME_STORE_KEYS: [&str; 3] = ["version", "compatible_version", "contacts"];
+const CONTACT_KEYS: [&str; 3] = ["petname", "identity_service_id", "endpoint_grants"];
+
+// upper bound on the size in bytes of a petname
+const MAX_PETNAME_SIZE: usize = 256;
+
+/// An identity known to the application by a petname, and the endpoint servers it has granted
+#[derive(Clone, Debug, PartialEq)]
+pub struct Contact {
+    /// The onion-service service-id of the identity server
+    pub identity_service_id: V3OnionServiceId,
+    /// The endpoint servers granted by the identity server, ordered by endpoint name; at most one per endpoint name
+    pub endpoint_grants: Vec<EndpointGrant>,
+    // saved fields written by a newer release, kept so they survive being saved again
+    unknown_fields: Document,
+}
+
+/// A registry of petnames: the human-friendly names an application's user gives to the identities they know.
+///
+/// Each petname names exactly one identity and each identity has at most one petname. Alongside its identity a petname keeps the most recent [`EndpointGrant`] of each endpoint the identity server granted, so an application can reconnect to a contact's endpoint servers by name.
+///
+/// A `PetnameStore` lives in memory unless it is opened with [`PetnameStore::open()`], in which case [`PetnameStore::save()`] writes it back to its file. Petnames may also be saved elsewhere with [`PetnameStore::to_bytes()`] and restored with [`PetnameStore::from_bytes()`]. The saved petnames contain the grants' client authorization private keys, so they must be treated as secret.
+///
+/// Saved petnames carry a [`FormatVersion`]. Petnames saved by an older release are upgraded by the store's [`Migrations`] when loaded, and petnames saved by a newer but compatible release are loaded with the fields this release does not understand kept intact, so they are not lost when the petnames are saved again.
+///
+/// A [`Context`](crate::context::Context) records the grants of its completed identity handshakes with identities which have a petname in its petname store (see [`Context::set_petname_store()`](crate::context::Context::set_petname_store)).
+#[derive(Clone, Debug)]
+pub struct PetnameStore {
+    contacts: BTreeMap<String, Contact>,
+    petnames: BTreeMap<V3OnionServiceId, String>,
+    // the file the store was opened from and is saved to, if any
+    path: Option<PathBuf>,
+    // the format version the store is saved in; newer than ours if loaded from a newer release
+    format_version: FormatVersion,
+    // saved top-level fields written by a newer release
+    unknown_fields: Document,
+}
+
+impl Default for PetnameStore {
+    fn default() -> Self {
+        Self {
+            contacts: Default::default(),
+            petnames: Default::default(),
+            path: None,
+            format_version: PETNAME_STORE_FORMAT,
+            unknown_fields: Default::default(),
+        }
+    }
+}
+
+// the saved form of a PetnameStore, without its format version header
+#[derive(Serialize, Deserialize)]
+struct PetnameStoreRecord {
+    contacts: Vec<Document>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ContactRecord {
+    petname: String,
+    identity_service_id: String,
+    endpoint_grants: Vec<String>,
+}
+
+impl PetnameStore {
+    /// Construct an empty, in-memory `PetnameStore`
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Open the `PetnameStore` saved in the given file by [`PetnameStore::save()`], or an empty one if the file does not exist. The returned store is saved back to the same file.
+    ///
+    /// # Parameters
+    /// - `path`: the file the petnames are read from and saved to
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Self::open_with_migrations(path, &Self::migrations())
+    }
+
+    /// Same as [`PetnameStore::open()`], but petnames saved by an older release are upgraded with the given migrations rather than the built-in ones
+    ///
+    /// # Parameters
+    /// - `path`: the file the petnames are read from and saved to
+    /// - `migrations`: the built-in migrations from [`PetnameStore::migrations()`] along with any registered by the application
+    pub fn open_with_migrations<P: AsRef<Path>>(
+        path: P,
+        migrations: &Migrations,
+    ) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        let mut petname_store = match std::fs::read(&path) {
+            Ok(bytes) => Self::from_bytes_with_migrations(&bytes, migrations)?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Self::new(),
+            Err(err) => return Err(err.into()),
+        };
+        petname_store.path = Some(path);
+        Ok(petname_store)
+    }
+
+    /// Save this `PetnameStore` to the file it was opened from. The file is replaced atomically, so a failed save leaves the previously saved petnames intact. Stores which were not opened with [`PetnameStore::open()`] are not saved.
+    pub fn save(&self) -> Result<(), Error> {
+        if let Some(path) = self.path.as_ref() {
+            let mut temp_path = path.clone().into_os_string();
+            temp_path.push(".tmp");
+            std::fs::write(&temp_path, self.to_bytes())?;
+            std::fs::rename(&temp_path, path)?;
+        }
+        Ok(())
+    }
+
+    /// The file this `PetnameStore` is saved to, if it was opened with [`PetnameStore::open()`]
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// The migrations which upgrade petnames saved by older releases to the current format version. Applications may register further migrations before passing them to [`PetnameStore::open_with_migrations()`] or [`PetnameStore::from_bytes_with_migrations()`].
+    pub fn migrations() -> Migrations {
+        // future format versions register their upgrade from the previous version here
+        Migrations::new(PETNAME_STORE_FORMAT)
+    }
+
+    /// Serialise this `PetnameStore` as a versioned BSON document
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let contacts = self.contacts.iter().map(|(petname, contact)| {
+            let record = ContactRecord {
+                petname: petname.clone(),
+                identity_service_id: contact.identity_service_id.to_string(),
+                endpoint_grants: contact
+                    .endpoint_grants
+                    .iter()
+                    .map(|endpoint_grant| endpoint_grant.to_string())
+                    .collect(),
+            };
+            let mut document =
+                bson::to_document(&record).expect("petnames are representable as bson");
+            document.extend(contact.unknown_fields.clone());
+            document
+        });
+        let record = PetnameStoreRecord {
+            contacts: contacts.collect(),
+        };
+        let mut document = Document::new();
+        self.format_version.write(&mut document);
+        document.extend(bson::to_document(&record).expect("petnames are representable as bson"));
+        document.extend(self.unknown_fields.clone());
+
+        let mut bytes: Vec<u8> = Default::default();
+        document
+            .to_writer(&mut bytes)
+            .expect("writing to a Vec cannot fail");
+        bytes
+    }
+
+    /// Parse an in-memory `PetnameStore` from bytes created by [`PetnameStore::to_bytes()`] in this or any compatible release
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        Self::from_bytes_with_migrations(bytes, &Self::migrations())
+    }
+
+    /// Same as [`PetnameStore::from_bytes()`], but petnames saved by an older release are upgraded with the given migrations rather than the built-in ones
+    ///
+    /// # Parameters
+    /// - `bytes`: petnames created by [`PetnameStore::to_bytes()`]
+    /// - `migrations`: the built-in migrations from [`PetnameStore::migrations()`] along with any registered by the application
+    pub fn from_bytes_with_migrations(
+        mut bytes: &[u8],
+        migrations: &Migrations,
+    ) -> Result<Self, Error> {
+        let mut document =
+            Document::from_reader(&mut bytes).map_err(|err| Error::ParseError(err.to_string()))?;
+        let format_version = migrations.migrate(&mut document)?;
+        let record: PetnameStoreRecord = bson::from_document(document.clone())
+            .map_err(|err| Error::ParseError(err.to_string()))?;
+
+        let mut petname_store = Self::new();
+        petname_store.format_version = format_version;
+        petname_store.unknown_fields = unknown_fields(document, &PETNAME_STORE_KEYS);
+        for contact in record.contacts {
+            let contact_record: ContactRecord = bson::from_document(contact.clone())
+                .map_err(|err| Error::ParseError(err.to_string()))?;
+            let identity_service_id =
+                V3OnionServiceId::from_string(&contact_record.identity_service_id)
+                    .map_err(|err| Error::ParseError(err.to_string()))?;
+            petname_store.set(contact_record.petname.clone(), identity_service_id)?;
+            for endpoint_grant in contact_record.endpoint_grants {
+                let endpoint_grant = EndpointGrant::from_string(&endpoint_grant)
+                    .map_err(|err| Error::ParseError(err.to_string()))?;
+                petname_store.record_grant(endpoint_grant);
+            }
+            if let Some(saved_contact) = petname_store.contacts.get_mut(&contact_record.petname) {
+                saved_contact.unknown_fields = unknown_fields(contact, &CONTACT_KEYS);
+            }
+        }
+        Ok(petname_store)
+    }
+
+    /// Give an identity a petname. An identity's petname may be changed with [`PetnameStore::rename()`].
+    ///
+    /// # Parameters
+    /// - `petname`: a non-empty name of at most 256 bytes which no other identity has
+    /// - `identity_service_id`: the onion-service service-id of the identity server; it must not already have a different petname
+    pub fn set(
+        &mut self,
+        petname: String,
+        identity_service_id: V3OnionServiceId,
+    ) -> Result<(), Error> {
+        validate_petname(&petname)?;
+        if let Some(contact) = self.contacts.get(&petname) {
+            if contact.identity_service_id == identity_service_id {
+                return Ok(());
+            }
+            return Err(Error::PetnameInUse(petname));
+        }
+        if let Some(existing_petname) = self.petnames.get(&identity_service_id) {
+            return Err(Error::IdentityInUse(
+                identity_service_id,
+                existing_petname.clone(),
+            ));
+        }
+
+        self.petnames
+            .insert(identity_service_id.clone(), petname.clone());
+        self.contacts.insert(
+            petname,
+            Contact {
+                identity_service_id,
+                endpoint_grants: Default::default(),
+                unknown_fields: Default::default(),
+            },
+        );
+        Ok(())
+    }
+
+    /// Change a petname, keeping its identity and endpoint grants
+    ///
+    /// # Parameters
+    /// - `petname`: the current petname
+    /// - `new_petname`: a non-empty name of at most 256 bytes which no other identity has
+    pub fn rename(&mut self, petname: &str, new_petname: String) -> Result<(), Error> {
+        validate_petname(&new_petname)?;
+        if petname == new_petname {
+            return match self.contacts.contains_key(petname) {
+                true => Ok(()),
+                false => Err(Error::PetnameNotFound(petname.to_string())),
+            };
+        }
+        if self.contacts.contains_key(&new_petname) {
+            return Err(Error::PetnameInUse(new_petname));
+        }
+        let contact = match self.contacts.remove(petname) {
+            Some(contact) => contact,
+            None => return Err(Error::PetnameNotFound(petname.to_string())),
+        };
+
+        self.petnames
+            .insert(contact.identity_service_id.clone(), new_petname.clone());
+        self.contacts.insert(new_petname, contact);
+        Ok(())
+    }
+
+    /// Remove a petname along with its endpoint grants, returning its contact if there was one
+    pub fn remove(&mut self, petname: &str) -> Option<Contact> {
+        let contact = self.contacts.remove(petname)?;
+        self.petnames.remove(&contact.identity_service_id);
+        Some(contact)
+    }
+
+    /// The contact named by a petname, if any
+    pub fn lookup(&self, petname: &str) -> Option<&Contact> {
+        self.contacts.get(petname)
+    }
+
+    /// The petname of an identity, if it has one
+    pub fn petname_of(&self, identity_service_id: &V3OnionServiceId) -> Option<&str> {
+        self.petnames.get(identity_service_id).map(String::as_str)
+    }
+
+    /// Record an endpoint grant of an identity with a petname, replacing its previous grant of the same endpoint. Grants of identities without a petname are ignored.
+    ///
+    /// # Returns
+    /// Whether the grant was recorded
+    pub fn record_grant(&mut self, endpoint_grant: EndpointGrant) -> bool {
+        let contact = match self
+            .petnames
+            .get(endpoint_grant.identity_service_id())
+            .and_then(|petname| self.contacts.get_mut(petname))
+        {
+            Some(contact) => contact,
+            None => return false,
+        };
+
+        match contact
+            .endpoint_grants
+            .binary_search_by(|probe| probe.endpoint_name().cmp(endpoint_grant.endpoint_name()))
+        {
+            Ok(idx) => contact.endpoint_grants[idx] = endpoint_grant,
+            Err(idx) => contact.endpoint_grants.insert(idx, endpoint_grant),
+        }
+        true
+    }
+
+    /// The most recent grant of an endpoint by the identity with a petname, if any
+    pub fn endpoint_grant(&self, petname: &str, endpoint_name: &str) -> Option<&EndpointGrant> {
+        self.lookup(petname)?
+            .endpoint_grants
+            .iter()
+            .find(|endpoint_grant| endpoint_grant.endpoint_name() == endpoint_name)
+    }
+
+    /// Every petname and its contact, ordered by petname
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Contact)> {
+        self.contacts
+            .iter()
+            .map(|(petname, contact)| (petname.as_str(), contact))
+    }
+
+    /// The number of petnames in this `PetnameStore`
+    pub fn len(&self) -> usize {
+        self.contacts.len()
+    }
+
+    /// Whether this `PetnameStore` has no petnames
+    pub fn is_empty(&self) -> bool {
+        self.contacts.is_empty()
+    }
+}
+
+// the fields of a saved document other than the given known fields
+fn unknown_fields(document: Document, known_keys: &[&str]) -> Document {
+    document
+        .into_iter()
+        .filter(|(key, _)| !known_keys.contains(&key.as_str()))
+        .collect()
+}
+
+fn validate_petname(petname: &str) -> Result<(), Error> {
+    if petname.is_empty() {
+        Err(Error::InvalidArgument(
+            "petname must not be empty".to_string(),
+        ))
+    } else if petname.len() > MAX_PETNAME_SIZE {
+        Err(Error::InvalidArgument(format!(
+            "petname may be at most {} bytes",
+            MAX_PETNAME_SIZE
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_petname_store() -> anyhow::Result<()> {
+    let service_id = || V3OnionServiceId::from_private_key(&Ed25519PrivateKey::generate());
+    let alice = service_id();
+    let bob = service_id();
+    let pat = service_id();
+    let grant = |identity_service_id: &V3OnionServiceId, endpoint_name: &str| {
+        EndpointGrant::new(
+            identity_service_id.clone(),
+            pat.clone(),
+            service_id(),
+            endpoint_name.to_string(),
+            X25519PrivateKey::generate(),
+        )
+    };
+
+    let mut petname_store = PetnameStore::new();
+    assert!(petname_store.is_empty());
+    petname_store.set("Alice".to_string(), alice.clone())?;
+    petname_store.set("Bob".to_string(), bob.clone())?;
+    assert_eq!(petname_store.petname_of(&alice), Some("Alice"));
+    assert_eq!(
+        petname_store.lookup("Bob").unwrap().identity_service_id,
+        bob
+    );
+
+    // petnames and identities map one-to-one
+    assert!(matches!(
+        petname_store.set("Alice".to_string(), bob.clone()),
+        Err(Error::PetnameInUse(_))
+    ));
+    assert!(matches!(
+        petname_store.set("Alice 2".to_string(), alice.clone()),
+        Err(Error::IdentityInUse(_, _))
+    ));
+    assert!(petname_store.set(String::new(), pat.clone()).is_err());
+
+    // grants are recorded for identities with petnames, one per endpoint name
+    let first_chat = grant(&alice, "chat")?;
+    let second_chat = grant(&alice, "chat")?;
+    assert!(petname_store.record_grant(first_chat));
+    assert!(petname_store.record_grant(grant(&alice, "files")?));
+    assert!(petname_store.record_grant(second_chat.clone()));
+    assert!(!petname_store.record_grant(grant(&pat, "chat")?));
+    assert_eq!(
+        petname_store.endpoint_grant("Alice", "chat"),
+        Some(&second_chat)
+    );
+    assert_eq!(
+        petname_store.lookup("Alice").unwrap().endpoint_grants.len(),
+        2
+    );
+
+    // renaming keeps the identity and its grants
+    petname_store.rename("Alice", "Alice Smith".to_string())?;
+    assert!(petname_store.lookup("Alice").is_none());
+    assert_eq!(petname_store.petname_of(&alice), Some("Alice Smith"));
+    assert_eq!(
+        petname_store.endpoint_grant("Alice Smith", "chat"),
+        Some(&second_chat)
+    );
+    assert!(matches!(
+        petname_store.rename("Alice Smith", "Bob".to_string()),
+        Err(Error::PetnameInUse(_))
+    ));
+    assert!(matches!(
+        petname_store.rename("Carol", "Caroline".to_string()),
+        Err(Error::PetnameNotFound(_))
+    ));
+
+    // the store survives a round-trip through its saved form
+    let restored = PetnameStore::from_bytes(&petname_store.to_bytes())?;
+    let petnames: Vec<(&str, &Contact)> = petname_store.iter().collect();
+    let restored_petnames: Vec<(&str, &Contact)> = restored.iter().collect();
+    assert_eq!(petnames, restored_petnames);
+    assert!(PetnameStore::from_bytes(b"not bson").is_err());
+
+    // and through its file
+    let mut path = std::env::temp_dir();
+    path.push(format!("gosling_petnames_test_{}", alice));
+    let _ = std::fs::remove_file(&path);
+    let mut opened = PetnameStore::open(&path)?;
+    assert!(opened.is_empty());
+    opened.set("Bob".to_string(), bob.clone())?;
+    opened.save()?;
+    let reopened = PetnameStore::open(&path)?;
+    assert_eq!(reopened.petname_of(&bob), Some("Bob"));
+    std::fs::remove_file(&path)?;
+
+    // removing a petname forgets its identity
+    assert!(petname_store.remove("Bob").is_some());
+    assert!(petname_store.remove("Bob").is_none());
+    assert_eq!(petname_store.petname_of(&bob), None);
+    assert_eq!(petname_store.len(), 1);
+
+    Ok(())
+}
+
+#[test]
+fn test_petname_store_format() -> anyhow::Result<()> {
+    use bson::Bson;
+
+    let alice = V3OnionServiceId::from_private_key(&Ed25519PrivateKey::generate());
+    let mut petname_store = PetnameStore::new();
+    petname_store.set("Alice".to_string(), alice.clone())?;
+
+    // a newer release adds fields but remains compatible with this one
+    let mut document = Document::from_reader(&mut petname_store.to_bytes().as_slice())?;
+    document.insert("version", 2);
+    document.insert("groups", vec!["friends"]);
+    let contacts = document.get_array_mut("contacts")?;
+    if let Some(Bson::Document(contact)) = contacts.first_mut() {
+        contact.insert("colour", "blue");
+    }
+    let mut bytes: Vec<u8> = Default::default();
+    document.to_writer(&mut bytes)?;
+
+    // the unknown fields and newer version survive a load and save
+    let mut petname_store = PetnameStore::from_bytes(&bytes)?;
+    assert_eq!(petname_store.petname_of(&alice), Some("Alice"));
+    petname_store.rename("Alice", "Alice Smith".to_string())?;
+    let document = Document::from_reader(&mut petname_store.to_bytes().as_slice())?;
+    assert_eq!(FormatVersion::read(&document)?.version, 2);
+    assert_eq!(document.get_array("groups")?.len(), 1);
+    let contact = document.get_array("contacts")?[0].as_document().unwrap();
+    assert_eq!(contact.get_str("petname")?, "Alice Smith");
+    assert_eq!(contact.get_str("colour")?, "blue");
+
+    // but petnames which are not compatible with this release are rejected
+    let mut document = document;
+    FormatVersion {
+        version: 3,
+        compatible_version: 3,
+    }
+    .write(&mut document);
+    let mut bytes: Vec<u8> = Default::default();
+    document.to_writer(&mut bytes)?;
+    assert!(matches!(
+        PetnameStore::from_bytes(&bytes),
+        Err(Error::Format(
+            crate::persistence::Error::UnsupportedVersion(3, 3, 1)
+        ))
+    ));
+
+    Ok(())
+}
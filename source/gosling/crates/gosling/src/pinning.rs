@@ -0,0 +1,277 @@
+// standard
+use std::collections::BTreeMap;
+
+// extern crates
+use tor_interface::tor_crypto::*;
+
+// internal crates
+use crate::gosling::SystemTime;
+
+/// The endpoint server pinned for an identity server's endpoint in a [`PinStore`]
+#[derive(Clone, Debug, PartialEq)]
+pub struct Pin {
+    /// The onion-service service-id of the pinned endpoint server
+    pub endpoint_service_id: V3OnionServiceId,
+    /// When the endpoint server was first granted or pinned
+    pub first_seen: SystemTime,
+    /// When the endpoint server was last granted
+    pub last_seen: SystemTime,
+}
+
+/// The result of comparing an endpoint server granted by an identity server against a [`PinStore`]
+#[derive(Clone, Debug, PartialEq)]
+pub enum PinVerdict {
+    /// Nothing is pinned for the identity server's endpoint
+    New,
+    /// The granted endpoint server is the pinned endpoint server
+    Matched,
+    /// A different endpoint server is pinned for the identity server's endpoint
+    Mismatched {
+        /// The onion-service service-id of the pinned endpoint server
+        pinned_endpoint_service_id: V3OnionServiceId,
+    },
+}
+
+/// A trust-on-first-use record of the endpoint servers granted by identity servers.
+///
+/// The first endpoint server an identity server grants for an endpoint name is pinned; later grants of the same endpoint are compared against the pin, so that an identity server unexpectedly handing out a different endpoint server (e.g. after its identity key was stolen) can be noticed. A mismatched grant never replaces the pin; the application decides whether the change is legitimate and may accept it with [`PinStore::pin()`].
+///
+/// Identity servers generate a new endpoint server for each identity handshake unless the application arranges otherwise, so a mismatch is also expected when a client deliberately repeats a handshake for an endpoint it was already granted.
+///
+/// A [`Context`](crate::context::Context) records the grants of its completed identity handshakes in its pin store (see [`Context::identity_client_set_pin_store()`](crate::context::Context::identity_client_set_pin_store)) and reports mismatches with [`ContextEvent::IdentityClientEndpointPinMismatched`](crate::context::ContextEvent::IdentityClientEndpointPinMismatched) events.
+#[derive(Clone, Debug, Default)]
+pub struct PinStore {
+    pins: BTreeMap<(V3OnionServiceId, String), Pin>,
+}
+
+impl PinStore {
+    /// Construct an empty `PinStore`
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Compare an endpoint server granted by an identity server against the pin for the identity server's endpoint without recording the grant.
+    ///
+    /// # Parameters
+    /// - `identity_service_id`: the onion-service service-id of the identity server which granted the endpoint server
+    /// - `endpoint_name`: the ASCII-encoded name of the granted endpoint
+    /// - `endpoint_service_id`: the onion-service service-id of the granted endpoint server
+    pub fn check(
+        &self,
+        identity_service_id: &V3OnionServiceId,
+        endpoint_name: &str,
+        endpoint_service_id: &V3OnionServiceId,
+    ) -> PinVerdict {
+        match self.get(identity_service_id, endpoint_name) {
+            Some(pin) if pin.endpoint_service_id == *endpoint_service_id => PinVerdict::Matched,
+            Some(pin) => PinVerdict::Mismatched {
+                pinned_endpoint_service_id: pin.endpoint_service_id.clone(),
+            },
+            None => PinVerdict::New,
+        }
+    }
+
+    /// Compare an endpoint server granted by an identity server against the pin for the identity server's endpoint as with [`PinStore::check()`], and record the grant: a new endpoint server is pinned and a matched pin's `last_seen` time is updated. A mismatched pin is left unchanged.
+    ///
+    /// # Parameters
+    /// - `identity_service_id`: the onion-service service-id of the identity server which granted the endpoint server
+    /// - `endpoint_name`: the ASCII-encoded name of the granted endpoint
+    /// - `endpoint_service_id`: the onion-service service-id of the granted endpoint server
+    pub fn observe(
+        &mut self,
+        identity_service_id: &V3OnionServiceId,
+        endpoint_name: &str,
+        endpoint_service_id: &V3OnionServiceId,
+    ) -> PinVerdict {
+        let verdict = self.check(identity_service_id, endpoint_name, endpoint_service_id);
+        let now = SystemTime::now();
+        match verdict {
+            PinVerdict::New => {
+                self.pin(
+                    identity_service_id.clone(),
+                    endpoint_name.to_string(),
+                    endpoint_service_id.clone(),
+                );
+            }
+            PinVerdict::Matched => {
+                if let Some(pin) = self
+                    .pins
+                    .get_mut(&(identity_service_id.clone(), endpoint_name.to_string()))
+                {
+                    pin.last_seen = now;
+                }
+            }
+            PinVerdict::Mismatched { .. } => (),
+        }
+        verdict
+    }
+
+    /// Pin an endpoint server for an identity server's endpoint, replacing any existing pin, e.g. to accept a mismatched grant or to restore pins saved by the application.
+    ///
+    /// # Parameters
+    /// - `identity_service_id`: the onion-service service-id of the identity server
+    /// - `endpoint_name`: the ASCII-encoded name of the endpoint
+    /// - `endpoint_service_id`: the onion-service service-id of the endpoint server to pin
+    pub fn pin(
+        &mut self,
+        identity_service_id: V3OnionServiceId,
+        endpoint_name: String,
+        endpoint_service_id: V3OnionServiceId,
+    ) {
+        let now = SystemTime::now();
+        self.insert(
+            identity_service_id,
+            endpoint_name,
+            Pin {
+                endpoint_service_id,
+                first_seen: now,
+                last_seen: now,
+            },
+        );
+    }
+
+    /// Insert a pin for an identity server's endpoint as-is, replacing any existing pin, e.g. to restore a pin previously returned from [`PinStore::iter()`].
+    ///
+    /// # Parameters
+    /// - `identity_service_id`: the onion-service service-id of the identity server
+    /// - `endpoint_name`: the ASCII-encoded name of the endpoint
+    /// - `pin`: the pin to insert
+    pub fn insert(
+        &mut self,
+        identity_service_id: V3OnionServiceId,
+        endpoint_name: String,
+        pin: Pin,
+    ) {
+        self.pins.insert((identity_service_id, endpoint_name), pin);
+    }
+
+    /// Remove the pin for an identity server's endpoint, returning it if there was one.
+    pub fn unpin(
+        &mut self,
+        identity_service_id: &V3OnionServiceId,
+        endpoint_name: &str,
+    ) -> Option<Pin> {
+        self.pins
+            .remove(&(identity_service_id.clone(), endpoint_name.to_string()))
+    }
+
+    /// Remove every pin for an identity server's endpoints, e.g. when a contact is deleted, returning the number of pins removed.
+    pub fn unpin_identity(&mut self, identity_service_id: &V3OnionServiceId) -> usize {
+        let count = self.pins.len();
+        self.pins.retain(|(pinned_identity_service_id, _), _| {
+            pinned_identity_service_id != identity_service_id
+        });
+        count - self.pins.len()
+    }
+
+    /// The pin for an identity server's endpoint, if any
+    pub fn get(&self, identity_service_id: &V3OnionServiceId, endpoint_name: &str) -> Option<&Pin> {
+        self.pins
+            .get(&(identity_service_id.clone(), endpoint_name.to_string()))
+    }
+
+    /// The endpoint names and pins of an identity server's endpoints, ordered by endpoint name
+    pub fn pins_for<'a>(
+        &'a self,
+        identity_service_id: &'a V3OnionServiceId,
+    ) -> impl Iterator<Item = (&'a str, &'a Pin)> + 'a {
+        self.iter()
+            .filter(move |(pinned_identity_service_id, _, _)| {
+                *pinned_identity_service_id == identity_service_id
+            })
+            .map(|(_, endpoint_name, pin)| (endpoint_name, pin))
+    }
+
+    /// Every identity server, endpoint name and pin in this `PinStore`, ordered by identity server and then endpoint name
+    pub fn iter(&self) -> impl Iterator<Item = (&V3OnionServiceId, &str, &Pin)> {
+        self.pins
+            .iter()
+            .map(|((identity_service_id, endpoint_name), pin)| {
+                (identity_service_id, endpoint_name.as_str(), pin)
+            })
+    }
+
+    /// The number of pins in this `PinStore`
+    pub fn len(&self) -> usize {
+        self.pins.len()
+    }
+
+    /// Whether this `PinStore` has no pins
+    pub fn is_empty(&self) -> bool {
+        self.pins.is_empty()
+    }
+}
+
+#[test]
+fn test_pin_store() {
+    let service_id = || V3OnionServiceId::from_private_key(&Ed25519PrivateKey::generate());
+    let alice = service_id();
+    let bob = service_id();
+    let first_endpoint = service_id();
+    let second_endpoint = service_id();
+
+    let mut pin_store = PinStore::new();
+    assert!(pin_store.is_empty());
+
+    // the first grant is pinned and later identical grants match
+    assert_eq!(
+        pin_store.observe(&alice, "chat", &first_endpoint),
+        PinVerdict::New
+    );
+    let first_seen = pin_store.get(&alice, "chat").unwrap().first_seen;
+    assert_eq!(
+        pin_store.observe(&alice, "chat", &first_endpoint),
+        PinVerdict::Matched
+    );
+    assert_eq!(
+        pin_store.get(&alice, "chat").unwrap().first_seen,
+        first_seen
+    );
+    assert!(pin_store.get(&alice, "chat").unwrap().last_seen >= first_seen);
+
+    // a different endpoint server is flagged without replacing the pin
+    let mismatched = PinVerdict::Mismatched {
+        pinned_endpoint_service_id: first_endpoint.clone(),
+    };
+    assert_eq!(
+        pin_store.check(&alice, "chat", &second_endpoint),
+        mismatched
+    );
+    assert_eq!(
+        pin_store.observe(&alice, "chat", &second_endpoint),
+        mismatched
+    );
+    assert_eq!(
+        pin_store.get(&alice, "chat").unwrap().endpoint_service_id,
+        first_endpoint
+    );
+
+    // pins are per identity server and endpoint name
+    assert_eq!(
+        pin_store.observe(&alice, "files", &second_endpoint),
+        PinVerdict::New
+    );
+    assert_eq!(
+        pin_store.observe(&bob, "chat", &second_endpoint),
+        PinVerdict::New
+    );
+    assert_eq!(pin_store.len(), 3);
+    let alice_endpoints: Vec<&str> = pin_store
+        .pins_for(&alice)
+        .map(|(endpoint_name, _)| endpoint_name)
+        .collect();
+    assert_eq!(alice_endpoints, ["chat", "files"]);
+
+    // the application may accept a changed endpoint server
+    pin_store.pin(alice.clone(), "chat".to_string(), second_endpoint.clone());
+    assert_eq!(
+        pin_store.observe(&alice, "chat", &second_endpoint),
+        PinVerdict::Matched
+    );
+
+    // and forget pins
+    assert!(pin_store.unpin(&bob, "chat").is_some());
+    assert!(pin_store.unpin(&bob, "chat").is_none());
+    assert_eq!(pin_store.unpin_identity(&alice), 2);
+    assert!(pin_store.is_empty());
+}
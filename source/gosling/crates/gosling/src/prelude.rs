@@ -0,0 +1,30 @@
+// each module's error type is re-exported under a module-qualified name so the
+// prelude does not shadow std::error::Error or an application's own Error type
+pub use crate::ascii_string::{AsciiString, Error as AsciiStringError};
+pub use crate::challenge::{challenge_response, Challenge, Error as ChallengeError};
+pub use crate::context::{
+    ClientFilter, ClientFilterVerdict, Context, ContextEvent, Error as ContextError,
+    EventQueueOverflowPolicy, HandshakeHandle, HandshakeRejectionReason,
+    PendingConnectionDropPolicy, PublishHandle, TorEventVerbosity,
+};
+pub use crate::datagram_channel::{
+    DatagramChannel, DatagramChannelStats, Error as DatagramChannelError, MAX_DATAGRAM_SIZE,
+};
+pub use crate::endpoint_grant::{EndpointGrant, Error as EndpointGrantError};
+pub use crate::endpoint_revocation::{EndpointRevocation, Error as EndpointRevocationError};
+pub use crate::events_sink::ContextEventsSink;
+pub use crate::identity_uri::{Error as IdentityUriError, IdentityUri};
+pub use crate::protocol::{Capabilities, MAX_CHANNEL_NAME_LENGTH, MAX_ENDPOINT_NAME_LENGTH};
+pub use crate::rpc_channel::{
+    Error as RpcChannelError, Heartbeat as RpcChannelHeartbeat, RpcChannel, RpcChannelEvent,
+};
+pub use crate::timing::{EndpointRequestStats, HandshakeStats, IdentityServerStats};
+
+// tor-interface types which appear in the Context's public API
+pub use tor_interface::tor_crypto::{
+    Ed25519PrivateKey, Ed25519PublicKey, V3OnionServiceId, X25519PrivateKey, X25519PublicKey,
+};
+pub use tor_interface::tor_provider::{CircuitToken, SecurityLevel, TargetAddr, TorProvider};
+
+// honk-rpc types which appear in the RpcChannel's public API
+pub use honk_rpc::honk_rpc::{ApiSet, ErrorCode, RequestCookie, Response};
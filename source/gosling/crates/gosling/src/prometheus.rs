@@ -0,0 +1,209 @@
+// standard
+use std::fmt::{Display, Write};
+
+// internal crates
+#[cfg(feature = "timing-histograms")]
+use crate::timing::TimingHistograms;
+use crate::timing::{EndpointRequestStats, IdentityServerStats};
+
+// quantiles reported for each latency summary
+#[cfg(feature = "timing-histograms")]
+const LATENCY_QUANTILES: [f64; 3] = [0.5, 0.95, 1.0];
+
+// the name, help text and value of a counter family reported for each endpoint
+type EndpointCounter = (&'static str, &'static str, fn(&EndpointRequestStats) -> u64);
+
+// Renders metrics in the Prometheus text exposition format (version 0.0.4)
+#[derive(Default)]
+pub(crate) struct PrometheusWriter {
+    output: String,
+}
+
+impl PrometheusWriter {
+    // begin a metric family; its samples must be written next
+    pub fn family(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.output, "# HELP {} {}", name, help);
+        let _ = writeln!(self.output, "# TYPE {} {}", name, kind);
+    }
+
+    pub fn sample<T: Display>(&mut self, name: &str, labels: &[(&str, &str)], value: T) {
+        self.output.push_str(name);
+        if !labels.is_empty() {
+            self.output.push('{');
+            for (i, (label, value)) in labels.iter().enumerate() {
+                if i > 0 {
+                    self.output.push(',');
+                }
+                let _ = write!(self.output, "{}=\"", label);
+                escape_label_value(&mut self.output, value);
+                self.output.push('"');
+            }
+            self.output.push('}');
+        }
+        let _ = writeln!(self.output, " {}", value);
+    }
+
+    // a gauge family with a single unlabelled sample
+    pub fn gauge<T: Display>(&mut self, name: &str, help: &str, value: T) {
+        self.family(name, "gauge", help);
+        self.sample(name, &[], value);
+    }
+
+    pub fn identity_server_stats(&mut self, stats: &IdentityServerStats) {
+        // endpoints beyond the tracked limit are reported with an empty name
+        let other = stats.other();
+        let endpoints = || {
+            stats
+                .endpoints()
+                .map(|(endpoint_name, stats)| (endpoint_name, *stats))
+                .chain((other != Default::default()).then_some(("", other)))
+        };
+
+        let counters: [EndpointCounter; 4] = [
+            (
+                "gosling_identity_server_endpoint_requests_total",
+                "Endpoint requests received by the identity server",
+                |stats| stats.requests,
+            ),
+            (
+                "gosling_identity_server_endpoint_grants_total",
+                "Identity handshakes which granted the requested endpoint",
+                |stats| stats.grants,
+            ),
+            (
+                "gosling_identity_server_endpoint_rejections_total",
+                "Identity handshakes which were rejected",
+                |stats| stats.rejections,
+            ),
+            (
+                "gosling_identity_server_endpoint_failures_total",
+                "Identity handshakes which failed or were aborted after the endpoint was requested",
+                |stats| stats.failures,
+            ),
+        ];
+        for (name, help, value) in counters {
+            self.family(name, "counter", help);
+            for (endpoint_name, stats) in endpoints() {
+                self.sample(name, &[("endpoint", endpoint_name)], value(&stats));
+            }
+        }
+
+        let name = "gosling_identity_server_endpoint_rejection_reasons_total";
+        self.family(
+            name,
+            "counter",
+            "Rejected identity handshakes by each reason which applied to them",
+        );
+        for (endpoint_name, stats) in endpoints() {
+            for (reason, value) in [
+                ("client_not_allowed", stats.client_not_allowed),
+                (
+                    "requested_endpoint_invalid",
+                    stats.requested_endpoint_invalid,
+                ),
+                (
+                    "client_proof_signature_invalid",
+                    stats.client_proof_signature_invalid,
+                ),
+                (
+                    "client_auth_signature_invalid",
+                    stats.client_auth_signature_invalid,
+                ),
+                (
+                    "challenge_response_invalid",
+                    stats.challenge_response_invalid,
+                ),
+            ] {
+                self.sample(
+                    name,
+                    &[("endpoint", endpoint_name), ("reason", reason)],
+                    value,
+                );
+            }
+        }
+    }
+
+    #[cfg(feature = "timing-histograms")]
+    pub fn timing_histograms(&mut self, histograms: &TimingHistograms) {
+        let name = "gosling_handshake_latency_seconds";
+        self.family(
+            name,
+            "summary",
+            "Latencies of the steps of outgoing identity and endpoint handshakes",
+        );
+        for (operation, histogram) in histograms.histograms() {
+            for quantile in LATENCY_QUANTILES {
+                let micros = histogram.value_at_quantile(quantile);
+                self.sample(
+                    name,
+                    &[
+                        ("operation", operation),
+                        ("quantile", &quantile.to_string()),
+                    ],
+                    micros as f64 / 1_000_000.0,
+                );
+            }
+            let sum = histogram.mean() * histogram.len() as f64 / 1_000_000.0;
+            self.sample(&format!("{}_sum", name), &[("operation", operation)], sum);
+            self.sample(
+                &format!("{}_count", name),
+                &[("operation", operation)],
+                histogram.len(),
+            );
+        }
+    }
+
+    pub fn finish(self) -> String {
+        self.output
+    }
+}
+
+// backslash, double-quote and line feed must be escaped in label values
+fn escape_label_value(output: &mut String, value: &str) {
+    for c in value.chars() {
+        match c {
+            '\\' => output.push_str("\\\\"),
+            '"' => output.push_str("\\\""),
+            '\n' => output.push_str("\\n"),
+            c => output.push(c),
+        }
+    }
+}
+
+#[test]
+fn test_prometheus_writer() {
+    let mut writer: PrometheusWriter = Default::default();
+    writer.gauge("gosling_test_gauge", "A test gauge", 3);
+    writer.family("gosling_test_total", "counter", "A test counter");
+    writer.sample(
+        "gosling_test_total",
+        &[("endpoint", "a \"quoted\\\" name\n"), ("reason", "test")],
+        42u64,
+    );
+    assert_eq!(
+        writer.finish(),
+        "# HELP gosling_test_gauge A test gauge\n\
+         # TYPE gosling_test_gauge gauge\n\
+         gosling_test_gauge 3\n\
+         # HELP gosling_test_total A test counter\n\
+         # TYPE gosling_test_total counter\n\
+         gosling_test_total{endpoint=\"a \\\"quoted\\\\\\\" name\\n\",reason=\"test\"} 42\n"
+    );
+
+    let mut stats: IdentityServerStats = Default::default();
+    stats.record_request("endpoint");
+    stats.record_rejection("endpoint", false, true, true, true, true);
+    let mut writer: PrometheusWriter = Default::default();
+    writer.identity_server_stats(&stats);
+    let output = writer.finish();
+    assert!(output
+        .contains("gosling_identity_server_endpoint_requests_total{endpoint=\"endpoint\"} 1\n"));
+    assert!(output.contains(
+        "gosling_identity_server_endpoint_rejection_reasons_total{endpoint=\"endpoint\",reason=\"client_not_allowed\"} 1\n"
+    ));
+    assert!(output.contains(
+        "gosling_identity_server_endpoint_rejection_reasons_total{endpoint=\"endpoint\",reason=\"challenge_response_invalid\"} 0\n"
+    ));
+    // the untracked endpoints are only reported once requested
+    assert!(!output.contains("endpoint=\"\""));
+}
@@ -0,0 +1,545 @@
+// extern crates
+use bson::spec::BinarySubtype;
+use bson::{Binary, Bson, Document};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use tor_interface::tor_crypto::SignBit;
+
+// The Gosling protocol's honk-rpc messages
+//
+// Each struct below is the argument document of a honk-rpc call or the
+// document returned in response to one. Members which older peers do not
+// send are optional and omitted when absent, and unknown members are ignored,
+// so a peer may add new optional members without breaking older peers.
+//
+// The structs only describe the shape of each message; the semantic checks
+// (sizes of binary members, service-id and ascii-string validity, signature
+// verification, etc) remain the responsibility of the handshake state
+// machines. Binary members must have the generic binary subtype.
+
+/// The maximum length in bytes of the endpoint names requested in identity handshakes
+pub const MAX_ENDPOINT_NAME_LENGTH: usize = 255;
+
+/// The maximum length in bytes of the channel names requested in endpoint handshakes
+pub const MAX_CHANNEL_NAME_LENGTH: usize = 255;
+
+/// A set of optional Gosling protocol features.
+///
+/// Identity and endpoint clients list the capabilities they support in their `begin_handshake` call and servers reply with the subset they also support. This subset is the handshake's negotiated set, reported in the handshake's completed events; features which are not in it are not used by either peer. Peers which predate capability negotiation neither send nor reply with a list, so their handshakes negotiate the empty set.
+///
+/// Capabilities are exchanged by name and names a peer does not recognise are ignored, so new capabilities may be added without breaking older peers.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    /// Identity clients may ask to continue with an endpoint handshake over the identity handshake's connection
+    pub const ENDPOINT_UPGRADE: Capabilities = Capabilities(1 << 0);
+    /// Identity clients may request further endpoints alongside the requested endpoint
+    pub const ADDITIONAL_ENDPOINTS: Capabilities = Capabilities(1 << 1);
+    /// Identity clients may send a contact request alongside the endpoint request
+    pub const CONTACT_REQUEST: Capabilities = Capabilities(1 << 2);
+    /// Endpoint clients understand an [`EndpointRevocation`](crate::endpoint_revocation::EndpointRevocation) written to their channels
+    pub const ENDPOINT_REVOCATION: Capabilities = Capabilities(1 << 3);
+    /// Identity clients and servers keep the identity handshake's connection open for follow-up identity handshakes; see [`Context::set_identity_session_keep_open()`](crate::context::Context::set_identity_session_keep_open)
+    pub const KEEP_OPEN: Capabilities = Capabilities(1 << 4);
+
+    // the name each capability is exchanged as
+    const NAMES: [(Capabilities, &'static str); 5] = [
+        (Self::ENDPOINT_UPGRADE, "endpoint_upgrade"),
+        (Self::ADDITIONAL_ENDPOINTS, "additional_endpoints"),
+        (Self::CONTACT_REQUEST, "contact_request"),
+        (Self::ENDPOINT_REVOCATION, "endpoint_revocation"),
+        (Self::KEEP_OPEN, "keep_open"),
+    ];
+
+    /// The set containing no capabilities
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// The set of every capability supported by this version of gosling
+    pub const fn all() -> Self {
+        Self(
+            Self::ENDPOINT_UPGRADE.0
+                | Self::ADDITIONAL_ENDPOINTS.0
+                | Self::CONTACT_REQUEST.0
+                | Self::ENDPOINT_REVOCATION.0
+                | Self::KEEP_OPEN.0,
+        )
+    }
+
+    /// The set's bitfield representation
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// Construct a set from its bitfield representation; bits which do not correspond to a known capability are ignored
+    pub const fn from_bits_truncate(bits: u32) -> Self {
+        Self(bits & Self::all().0)
+    }
+
+    /// Whether the set contains no capabilities
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Whether the set contains every capability in `other`
+    pub const fn contains(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// The capabilities in either set
+    pub const fn union(self, other: Capabilities) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    /// The capabilities in both sets
+    pub const fn intersection(self, other: Capabilities) -> Self {
+        Self(self.0 & other.0)
+    }
+
+    /// The set without the capabilities in `other`
+    pub const fn difference(self, other: Capabilities) -> Self {
+        Self(self.0 & !other.0)
+    }
+
+    /// The names the set's capabilities are exchanged as
+    pub fn names(self) -> Vec<String> {
+        Self::NAMES
+            .iter()
+            .filter(|(capability, _)| self.contains(*capability))
+            .map(|(_, name)| name.to_string())
+            .collect()
+    }
+
+    /// Construct a set from capability names; unrecognised names are ignored
+    pub fn from_names<S: AsRef<str>>(names: &[S]) -> Self {
+        names.iter().fold(Self::empty(), |capabilities, name| {
+            match Self::NAMES
+                .iter()
+                .find(|(_, known)| *known == name.as_ref())
+            {
+                Some((capability, _)) => capabilities.union(*capability),
+                None => capabilities,
+            }
+        })
+    }
+}
+
+impl std::ops::BitOr for Capabilities {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        self.union(other)
+    }
+}
+
+impl std::ops::BitAnd for Capabilities {
+    type Output = Self;
+
+    fn bitand(self, other: Self) -> Self {
+        self.intersection(other)
+    }
+}
+
+/// Arguments of the `gosling_identity` namespace's `begin_handshake` function (version 0).
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct IdentityBeginHandshakeArgs {
+    /// The Gosling protocol version the client speaks; must be `"0.1.0"`
+    pub version: String,
+    /// The client's onion-service service-id
+    pub client_identity: String,
+    /// The name of the requested endpoint
+    pub endpoint: String,
+    /// The 32-byte client cookie the client commits to using in its `send_response` call; absent from older clients' requests
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_cookie: Option<Binary>,
+    /// The client requests to continue with the endpoint handshake over this identity handshake's connection rather than connecting to the granted endpoint server's onion-service
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub endpoint_upgrade: Option<bool>,
+    /// The names of further endpoints requested alongside `endpoint`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub additional_endpoints: Option<Vec<String>>,
+    /// An application message sent alongside the endpoint request
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub contact_request: Option<String>,
+    /// The names of the [`Capabilities`] the client supports; absent from older clients' requests
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub capabilities: Option<Vec<String>>,
+}
+
+/// Response to the `gosling_identity` namespace's `begin_handshake` function.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct IdentityBeginHandshakeResponse {
+    /// The identity server's 32-byte server cookie
+    pub server_cookie: Binary,
+    /// The application's endpoint challenge
+    pub endpoint_challenge: Document,
+    /// Present and `true` if the server accepted the client's `endpoint_upgrade` request
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub endpoint_upgrade: Option<bool>,
+    /// Present and `true` if the server understood the client's `additional_endpoints` request
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub additional_endpoints: Option<bool>,
+    /// The names of the negotiated [`Capabilities`]; only present if the client sent its capabilities
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub capabilities: Option<Vec<String>>,
+}
+
+/// The sign bit of the ed25519 key derived from an x25519 key, which is needed to verify signatures made with the derived key.
+///
+/// A sign bit is exchanged as a BSON boolean. Other encodings, such as the integers 0 and 1, are rejected rather than converted, so that peers cannot disagree about which key was meant.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeySignBit(pub bool);
+
+impl From<SignBit> for KeySignBit {
+    fn from(signbit: SignBit) -> Self {
+        Self(signbit.into())
+    }
+}
+
+impl From<KeySignBit> for SignBit {
+    fn from(signbit: KeySignBit) -> Self {
+        signbit.0.into()
+    }
+}
+
+impl Serialize for KeySignBit {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bool(self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for KeySignBit {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match Bson::deserialize(deserializer)? {
+            Bson::Boolean(signbit) => Ok(Self(signbit)),
+            value => Err(serde::de::Error::custom(format!(
+                "signbit must be a boolean, received {:?}",
+                value.element_type()
+            ))),
+        }
+    }
+}
+
+/// Arguments of the `gosling_identity` namespace's `send_response` function (version 0).
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct IdentitySendResponseArgs {
+    /// The client's 32-byte client cookie
+    pub client_cookie: Binary,
+    /// The 64-byte ed25519 signature of the client proof, made with the client's identity key
+    pub client_identity_proof_signature: Binary,
+    /// The client's 32-byte x25519 client authorization public key
+    pub client_authorization_key: Binary,
+    /// The sign bit of the ed25519 key derived from `client_authorization_key`
+    pub client_authorization_key_signbit: KeySignBit,
+    /// The 64-byte ed25519 signature of the client's service-id, made with the ed25519 key derived from the client authorization key
+    pub client_authorization_signature: Binary,
+    /// The application's response to the endpoint challenge
+    pub challenge_response: Document,
+}
+
+/// Response to the `gosling_identity` namespace's `send_response` function on success.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum IdentitySendResponseResponse {
+    /// The granted endpoint's onion-service service-id, sent when no additional endpoints were requested
+    EndpointServiceId(String),
+    /// The granted endpoint's onion-service service-id along with a grant for each additional endpoint
+    AdditionalEndpoints {
+        /// The granted endpoint's onion-service service-id
+        endpoint_service_id: String,
+        /// Maps each additional endpoint's name to its onion-service service-id if granted or to null if denied
+        additional_endpoints: Document,
+    },
+}
+
+/// Arguments of the `gosling_endpoint` namespace's `begin_handshake` function (version 0).
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct EndpointBeginHandshakeArgs {
+    /// The Gosling protocol version the client speaks; must be `"0.1.0"`
+    pub version: String,
+    /// The client's onion-service service-id; absent from legacy clients' requests
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_identity: Option<String>,
+    /// The name of the requested channel
+    pub channel: String,
+    /// The 32-byte client cookie the client commits to using in its `send_response` call; absent from older clients' requests
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_cookie: Option<Binary>,
+    /// The names of the [`Capabilities`] the client supports; absent from older clients' requests
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub capabilities: Option<Vec<String>>,
+    /// The requested endpoint's onion-service service-id; only sent to endpoint servers sharing their identity server's onion-service
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub endpoint_service_id: Option<String>,
+}
+
+/// Response to the `gosling_endpoint` namespace's `begin_handshake` function.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct EndpointBeginHandshakeResponse {
+    /// The endpoint server's 32-byte server cookie
+    pub server_cookie: Binary,
+    /// The names of the negotiated [`Capabilities`]; only present if the client sent its capabilities
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub capabilities: Option<Vec<String>>,
+}
+
+/// Error data returned alongside a `Busy` error from the `gosling_endpoint` namespace's `begin_handshake` function.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct EndpointBusyErrorData {
+    /// The number of seconds the client should wait before retrying
+    pub retry_after: i64,
+}
+
+/// Error data returned alongside a `QuotaExceeded` error from the `gosling_endpoint` namespace's `send_response` function.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct EndpointQuotaExceededErrorData {
+    /// The exceeded quota: `open_channels` or `channel_requests`
+    pub quota: String,
+    /// The maximum number of open channels, or of channels opened within the time window
+    pub limit: i64,
+    /// The length in seconds of the time window; only present for the `channel_requests` quota
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub window: Option<i64>,
+    /// The number of seconds the client should wait before opening another channel; only present for the `channel_requests` quota
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_after: Option<i64>,
+}
+
+/// Error data returned alongside an `OutOfOrderCall` or `DuplicateCall` error from the `gosling_identity` and `gosling_endpoint` namespaces' functions.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ProtocolViolationErrorData {
+    /// The name of the function which was called out of order or more than once
+    pub function: String,
+    /// The name of the function which must be called first; absent if the call was a duplicate or arrived before the server answered a previous call
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expected: Option<String>,
+}
+
+/// Arguments of the `gosling_endpoint` namespace's `send_response` function (version 0). On success the function returns an empty document.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct EndpointSendResponseArgs {
+    /// The client's 32-byte client cookie
+    pub client_cookie: Binary,
+    /// The 64-byte ed25519 signature of the client proof, made with the client's identity key
+    pub client_identity_proof_signature: Binary,
+}
+
+// the capabilities listed in a begin_handshake response's capabilities member;
+// Ok(None) if absent as servers which predate capability negotiation do not
+// send one
+pub(crate) fn parse_capabilities(capabilities: Option<&Bson>) -> Result<Option<Capabilities>, ()> {
+    match capabilities {
+        None => Ok(None),
+        Some(Bson::Array(names)) => {
+            let names: Vec<&str> = names.iter().filter_map(Bson::as_str).collect();
+            Ok(Some(Capabilities::from_names(&names)))
+        }
+        Some(_) => Err(()),
+    }
+}
+
+// wrap bytes as a generic binary message member
+pub(crate) fn generic_binary(bytes: &[u8]) -> Binary {
+    Binary {
+        subtype: BinarySubtype::Generic,
+        bytes: bytes.to_vec(),
+    }
+}
+
+// the bytes of a message member if it has the generic binary subtype
+pub(crate) fn generic_bytes(binary: Binary) -> Option<Vec<u8>> {
+    match binary.subtype {
+        BinarySubtype::Generic => Some(binary.bytes),
+        _ => None,
+    }
+}
+
+// whether a send_response call's client_authorization_key_signbit member is
+// present but not a valid KeySignBit; such calls are answered with their own
+// error code rather than as a generic schema mismatch
+pub(crate) fn has_malformed_signbit(args: &Document) -> bool {
+    match args.get("client_authorization_key_signbit") {
+        Some(signbit) => bson::from_bson::<KeySignBit>(signbit.clone()).is_err(),
+        None => false,
+    }
+}
+
+// deserialize a message from a document, None if it does not match the schema
+pub(crate) fn from_document<T: serde::de::DeserializeOwned>(document: Document) -> Option<T> {
+    bson::from_document(document).ok()
+}
+
+// serialize a message to a document
+pub(crate) fn to_document<T: Serialize>(message: &T) -> Document {
+    // the message structs only contain members which bson can represent
+    bson::to_document(message).expect("protocol message is not representable as bson")
+}
+
+// serialize a message to a bson value
+pub(crate) fn to_bson<T: Serialize>(message: &T) -> Bson {
+    bson::to_bson(message).expect("protocol message is not representable as bson")
+}
+
+#[test]
+fn test_protocol_schema() -> anyhow::Result<()> {
+    use bson::doc;
+
+    // optional members are omitted when absent and default to None when missing
+    let args = IdentityBeginHandshakeArgs {
+        version: "0.1.0".to_string(),
+        client_identity: "client".to_string(),
+        endpoint: "endpoint".to_string(),
+        client_cookie: None,
+        endpoint_upgrade: None,
+        additional_endpoints: None,
+        contact_request: None,
+        capabilities: None,
+    };
+    let document = to_document(&args);
+    assert_eq!(
+        document,
+        doc! {
+            "version": "0.1.0",
+            "client_identity": "client",
+            "endpoint": "endpoint",
+        }
+    );
+    assert_eq!(
+        from_document::<IdentityBeginHandshakeArgs>(document),
+        Some(args)
+    );
+
+    // unknown members are ignored
+    let args: EndpointBeginHandshakeArgs = from_document(doc! {
+        "version": "0.1.0",
+        "channel": "channel",
+        "client_cookie": Bson::Binary(generic_binary(&[0u8; 32])),
+        "from_the_future": true,
+    })
+    .unwrap();
+    assert_eq!(args.client_identity, None);
+    assert_eq!(args.endpoint_service_id, None);
+    assert_eq!(
+        generic_bytes(args.client_cookie.unwrap()),
+        Some(vec![0u8; 32])
+    );
+
+    // missing required members and members of the wrong type are rejected
+    assert_eq!(
+        from_document::<EndpointBeginHandshakeArgs>(doc! {"version": "0.1.0"}),
+        None
+    );
+    assert_eq!(
+        from_document::<EndpointSendResponseArgs>(doc! {
+            "client_cookie": "cookie",
+            "client_identity_proof_signature": Bson::Binary(generic_binary(&[0u8; 64])),
+        }),
+        None
+    );
+
+    // binary members must be generic
+    assert_eq!(
+        generic_bytes(Binary {
+            subtype: BinarySubtype::Uuid,
+            bytes: vec![0u8; 16],
+        }),
+        None
+    );
+
+    // send_response responses are either a service-id or a document of grants
+    let response = IdentitySendResponseResponse::EndpointServiceId("endpoint".to_string());
+    assert_eq!(to_bson(&response), Bson::String("endpoint".to_string()));
+    let response = IdentitySendResponseResponse::AdditionalEndpoints {
+        endpoint_service_id: "endpoint".to_string(),
+        additional_endpoints: doc! {"other": Bson::Null},
+    };
+    assert_eq!(
+        bson::from_bson::<IdentitySendResponseResponse>(to_bson(&response))?,
+        response
+    );
+
+    // capabilities are exchanged by name and unknown names are ignored
+    let capabilities = Capabilities::ENDPOINT_UPGRADE | Capabilities::CONTACT_REQUEST;
+    assert_eq!(
+        capabilities.names(),
+        vec![
+            "endpoint_upgrade".to_string(),
+            "contact_request".to_string()
+        ]
+    );
+    let mut names = capabilities.names();
+    names.push("from_the_future".to_string());
+    assert_eq!(Capabilities::from_names(&names), capabilities);
+    assert_eq!(
+        Capabilities::from_names(&Capabilities::all().names()),
+        Capabilities::all()
+    );
+    assert!(Capabilities::from_names::<String>(&[]).is_empty());
+    assert_eq!(
+        Capabilities::from_bits_truncate(u32::MAX),
+        Capabilities::all()
+    );
+    assert_eq!(
+        capabilities & Capabilities::CONTACT_REQUEST,
+        Capabilities::CONTACT_REQUEST
+    );
+    assert!(!capabilities
+        .difference(Capabilities::CONTACT_REQUEST)
+        .contains(Capabilities::CONTACT_REQUEST));
+
+    Ok(())
+}
+
+#[test]
+fn test_protocol_signbit() {
+    use bson::doc;
+
+    let send_response = |signbit: Bson| {
+        doc! {
+            "client_cookie": Bson::Binary(generic_binary(&[0u8; 32])),
+            "client_identity_proof_signature": Bson::Binary(generic_binary(&[0u8; 64])),
+            "client_authorization_key": Bson::Binary(generic_binary(&[0u8; 32])),
+            "client_authorization_key_signbit": signbit,
+            "client_authorization_signature": Bson::Binary(generic_binary(&[0u8; 64])),
+            "challenge_response": {},
+        }
+    };
+
+    // both values are exchanged as booleans
+    for signbit in [false, true] {
+        let args = send_response(Bson::Boolean(signbit));
+        assert!(!has_malformed_signbit(&args));
+        let args: IdentitySendResponseArgs = from_document(args).unwrap();
+        assert_eq!(args.client_authorization_key_signbit, KeySignBit(signbit));
+        assert_eq!(
+            bool::from(SignBit::from(args.client_authorization_key_signbit)),
+            signbit
+        );
+        assert_eq!(
+            to_document(&args).get("client_authorization_key_signbit"),
+            Some(&Bson::Boolean(signbit))
+        );
+    }
+
+    // other encodings are rejected rather than converted
+    for signbit in [
+        Bson::Int32(0),
+        Bson::Int32(1),
+        Bson::Int64(1),
+        Bson::Double(1.0),
+        Bson::String("true".to_string()),
+        Bson::Binary(generic_binary(&[1u8])),
+        Bson::Null,
+    ] {
+        let args = send_response(signbit);
+        assert!(has_malformed_signbit(&args));
+        assert_eq!(from_document::<IdentitySendResponseArgs>(args), None);
+    }
+
+    // a missing signbit is a schema mismatch like any other missing member
+    let mut args = send_response(Bson::Boolean(false));
+    args.remove("client_authorization_key_signbit");
+    assert!(!has_malformed_signbit(&args));
+    assert_eq!(from_document::<IdentitySendResponseArgs>(args), None);
+}
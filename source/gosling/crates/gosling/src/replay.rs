@@ -0,0 +1,460 @@
+// extern crates
+use bson::document::Document;
+use honk_rpc::honk_rpc::Session;
+use serde::{Deserialize, Serialize};
+use tor_interface::tor_crypto::*;
+
+// internal crates
+use crate::ascii_string::AsciiString;
+use crate::endpoint_client::{EndpointClient, EndpointClientEvent, Error as EndpointClientError};
+use crate::endpoint_server::{EndpointServer, EndpointServerEvent, Error as EndpointServerError};
+use crate::identity_client::{Error as IdentityClientError, IdentityClient, IdentityClientEvent};
+use crate::identity_server::{Error as IdentityServerError, IdentityServer, IdentityServerEvent};
+use crate::testing::*;
+
+/// The error type for the [`record()`] and [`replay()`] functions and [`Transcript`] parsing.
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    /// A transcript could not be parsed
+    #[error("failed to parse transcript: {0}")]
+    ParseError(String),
+
+    /// A transcript was written in a format version this release cannot read
+    #[error("transcript format version {0} is not supported, this release reads version {1}")]
+    UnsupportedVersion(i32, i32),
+
+    /// A scenario's keys, service id, endpoint or channel are invalid
+    #[error("invalid scenario: {0}")]
+    InvalidScenario(String),
+
+    /// The replayed state transitions differ from those in the transcript
+    #[error(
+        "{} handshake diverged from the transcript; recorded: {:?}, replayed: {:?}",
+        .0.handshake,
+        .0.recorded,
+        .0.replayed
+    )]
+    Diverged(Box<Divergence>),
+
+    /// A handshake did not finish
+    #[error(transparent)]
+    Testing(#[from] crate::testing::Error),
+}
+
+/// The transcript format version written by this release
+pub const TRANSCRIPT_VERSION: i32 = 1;
+
+/// The inputs of a recorded pair of handshakes: the client's keys, the identity server it connects to and the decisions each side's application makes. Keys are saved in their usual text encodings so a transcript replays the same handshakes.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Scenario {
+    /// The client's ed25519 identity key blob
+    pub client_private_key: String,
+    /// The client's base64-encoded x25519 client authorization key
+    pub client_auth_private_key: String,
+    /// The identity server's service id
+    pub server_service_id: String,
+    /// The endpoint the client requests
+    pub endpoint: String,
+    /// Whether the identity server allows the client
+    pub client_allowed: bool,
+    /// Whether the identity server supports the requested endpoint
+    pub endpoint_supported: bool,
+    /// The endpoint challenge the identity server sends
+    pub endpoint_challenge: Document,
+    /// The challenge response the client sends
+    pub challenge_response: Document,
+    /// Whether the identity server accepts the challenge response
+    pub challenge_response_valid: bool,
+    /// The channel the client requests from the granted endpoint server, or `None` to only perform the identity handshake
+    pub channel: Option<String>,
+    /// Whether the endpoint server accepts the requested channel
+    pub channel_valid: bool,
+}
+
+impl Scenario {
+    /// A scenario with newly generated keys in which every request is accepted
+    ///
+    /// # Parameters
+    /// - `endpoint`: the endpoint the client requests
+    /// - `channel`: the channel the client requests from the granted endpoint server, or `None` to only perform the identity handshake
+    pub fn new(endpoint: &str, channel: Option<&str>) -> Self {
+        let server_private_key = Ed25519PrivateKey::generate();
+        Self {
+            client_private_key: Ed25519PrivateKey::generate().to_key_blob(),
+            client_auth_private_key: X25519PrivateKey::generate().to_base64(),
+            server_service_id: V3OnionServiceId::from_private_key(&server_private_key).to_string(),
+            endpoint: endpoint.to_string(),
+            client_allowed: true,
+            endpoint_supported: true,
+            endpoint_challenge: Document::new(),
+            challenge_response: Document::new(),
+            challenge_response_valid: true,
+            channel: channel.map(str::to_string),
+            channel_valid: true,
+        }
+    }
+}
+
+/// The state transitions and outcome of each side of a handshake. States are recorded when they change, starting from each side's initial state.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Transitions {
+    /// The client's states
+    pub client_states: Vec<String>,
+    /// The client's finishing event or error
+    pub client_outcome: String,
+    /// The server's states
+    pub server_states: Vec<String>,
+    /// The server's finishing event or error
+    pub server_outcome: String,
+}
+
+/// How a replayed handshake differs from a [`Transcript`]
+#[derive(Debug)]
+pub struct Divergence {
+    /// The handshake which diverged, `"identity"` or `"endpoint"`
+    pub handshake: &'static str,
+    /// The transitions in the transcript, or `None` if the handshake was not recorded
+    pub recorded: Option<Transitions>,
+    /// The replayed transitions, or `None` if the handshake was not replayed
+    pub replayed: Option<Transitions>,
+}
+
+/// A recorded identity handshake and the endpoint handshake which followed it, if any.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Transcript {
+    /// The transcript's format version
+    pub version: i32,
+    /// The handshakes' inputs
+    pub scenario: Scenario,
+    /// The identity handshake's transitions
+    pub identity_handshake: Transitions,
+    /// The endpoint handshake's transitions, or `None` if the scenario has no channel or the identity handshake did not complete
+    pub endpoint_handshake: Option<Transitions>,
+}
+
+impl Transcript {
+    /// Serialise this `Transcript` as a BSON document
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let document = bson::to_document(self).expect("transcripts are representable as bson");
+        let mut bytes: Vec<u8> = Default::default();
+        document
+            .to_writer(&mut bytes)
+            .expect("writing to a Vec cannot fail");
+        bytes
+    }
+
+    /// Parse a `Transcript` from bytes created by [`Transcript::to_bytes()`]
+    pub fn from_bytes(mut bytes: &[u8]) -> Result<Self, Error> {
+        let document =
+            Document::from_reader(&mut bytes).map_err(|err| Error::ParseError(err.to_string()))?;
+        let version = document
+            .get_i32("version")
+            .map_err(|err| Error::ParseError(err.to_string()))?;
+        if version != TRANSCRIPT_VERSION {
+            return Err(Error::UnsupportedVersion(version, TRANSCRIPT_VERSION));
+        }
+        bson::from_document(document).map_err(|err| Error::ParseError(err.to_string()))
+    }
+}
+
+/// Perform the handshakes described by a scenario over in-memory streams, recording each side's state transitions.
+pub fn record(scenario: Scenario) -> Result<Transcript, Error> {
+    let (identity_handshake, endpoint_handshake) = perform(&scenario)?;
+    Ok(Transcript {
+        version: TRANSCRIPT_VERSION,
+        scenario,
+        identity_handshake,
+        endpoint_handshake,
+    })
+}
+
+/// Perform a transcript's handshakes again against the current state machines.
+///
+/// # Returns
+/// `Ok(())` if each side of each handshake made the same state transitions and finished with the same outcome as recorded, otherwise [`Error::Diverged`].
+pub fn replay(transcript: &Transcript) -> Result<(), Error> {
+    let (identity_handshake, endpoint_handshake) = perform(&transcript.scenario)?;
+    if identity_handshake != transcript.identity_handshake {
+        return Err(Error::Diverged(Box::new(Divergence {
+            handshake: "identity",
+            recorded: Some(transcript.identity_handshake.clone()),
+            replayed: Some(identity_handshake),
+        })));
+    }
+    if endpoint_handshake != transcript.endpoint_handshake {
+        return Err(Error::Diverged(Box::new(Divergence {
+            handshake: "endpoint",
+            recorded: transcript.endpoint_handshake.clone(),
+            replayed: endpoint_handshake,
+        })));
+    }
+    Ok(())
+}
+
+// answers each request as decided by the scenario
+struct ScenarioHandler<'a>(&'a Scenario);
+
+impl HandshakeHandler for ScenarioHandler<'_> {
+    fn endpoint_request_received(
+        &mut self,
+        _client_service_id: &V3OnionServiceId,
+        _requested_endpoint: &str,
+    ) -> (bool, bool, Document) {
+        (
+            self.0.client_allowed,
+            self.0.endpoint_supported,
+            self.0.endpoint_challenge.clone(),
+        )
+    }
+
+    fn challenge_received(&mut self, _endpoint_challenge: &Document) -> Document {
+        self.0.challenge_response.clone()
+    }
+
+    fn challenge_response_received(&mut self, _challenge_response: &Document) -> bool {
+        self.0.challenge_response_valid
+    }
+
+    fn channel_request_received(
+        &mut self,
+        _client_service_id: &V3OnionServiceId,
+        _requested_channel: &str,
+    ) -> bool {
+        self.0.channel_valid
+    }
+}
+
+// append a state if it differs from the last one recorded
+fn push_state<S: std::fmt::Debug>(states: &mut Vec<String>, state: S) {
+    let state = format!("{state:?}");
+    if states.last() != Some(&state) {
+        states.push(state);
+    }
+}
+
+// perform the identity handshake, then the endpoint handshake if the scenario
+// requests a channel and the client was granted an endpoint
+fn perform(scenario: &Scenario) -> Result<(Transitions, Option<Transitions>), Error> {
+    let invalid = |err: &dyn std::fmt::Display| Error::InvalidScenario(err.to_string());
+    let client_private_key =
+        Ed25519PrivateKey::from_key_blob(&scenario.client_private_key).map_err(|e| invalid(&e))?;
+    let client_auth_private_key = X25519PrivateKey::from_base64(&scenario.client_auth_private_key)
+        .map_err(|e| invalid(&e))?;
+    let server_service_id =
+        V3OnionServiceId::from_string(&scenario.server_service_id).map_err(|e| invalid(&e))?;
+    let endpoint = AsciiString::new(scenario.endpoint.clone()).map_err(|e| invalid(&e))?;
+    let channel = match &scenario.channel {
+        Some(channel) => Some(AsciiString::new(channel.clone()).map_err(|e| invalid(&e))?),
+        None => None,
+    };
+    let mut handler = ScenarioHandler(scenario);
+
+    let (client_stream, server_stream) = duplex();
+    let mut client = IdentityClient::new(
+        Session::new(client_stream),
+        server_service_id.clone(),
+        endpoint,
+        client_private_key.clone(),
+        client_auth_private_key,
+        false,
+    )
+    .map_err(|e| invalid(&e))?;
+    let mut server =
+        IdentityServer::new(Session::new(server_stream), server_service_id, None, false);
+
+    let mut identity_handshake = Transitions::default();
+    push_state(&mut identity_handshake.client_states, client.state());
+    push_state(&mut identity_handshake.server_states, server.state());
+    let outcome = drive_identity_handshake_observed(
+        &mut client,
+        &mut server,
+        &mut handler,
+        |client, server| {
+            push_state(&mut identity_handshake.client_states, client.state());
+            push_state(&mut identity_handshake.server_states, server.state());
+        },
+    )?;
+    identity_handshake.client_outcome = identity_client_outcome(&outcome.client);
+    identity_handshake.server_outcome = identity_server_outcome(&outcome.server);
+
+    let (channel, endpoint_service_id) = match (channel, outcome.client) {
+        (
+            Some(channel),
+            Ok(IdentityClientEvent::HandshakeCompleted {
+                endpoint_service_id,
+                ..
+            }),
+        ) => (channel, endpoint_service_id),
+        _ => return Ok((identity_handshake, None)),
+    };
+
+    let client_service_id = V3OnionServiceId::from_private_key(&client_private_key);
+    let (client_stream, server_stream) = duplex();
+    let mut client = EndpointClient::new(
+        Session::new(client_stream),
+        endpoint_service_id.clone(),
+        channel,
+        client_private_key,
+    );
+    let mut server = EndpointServer::new(
+        Session::new(server_stream),
+        client_service_id,
+        endpoint_service_id,
+        Default::default(),
+        false,
+    );
+
+    let mut endpoint_handshake = Transitions::default();
+    push_state(&mut endpoint_handshake.client_states, client.state());
+    push_state(&mut endpoint_handshake.server_states, server.state());
+    let outcome = drive_endpoint_handshake_observed(
+        &mut client,
+        &mut server,
+        &mut handler,
+        |client, server| {
+            push_state(&mut endpoint_handshake.client_states, client.state());
+            push_state(&mut endpoint_handshake.server_states, server.state());
+        },
+    )?;
+    endpoint_handshake.client_outcome = endpoint_client_outcome(&outcome.client);
+    endpoint_handshake.server_outcome = endpoint_server_outcome(&outcome.server);
+
+    Ok((identity_handshake, Some(endpoint_handshake)))
+}
+
+//
+// Outcomes are recorded by event name (along with a rejection's reasons) so
+// transcripts do not depend on the keys generated during a handshake
+//
+
+fn identity_client_outcome(outcome: &Result<IdentityClientEvent, IdentityClientError>) -> String {
+    match outcome {
+        Ok(IdentityClientEvent::ChallengeReceived { .. }) => "ChallengeReceived".to_string(),
+        Ok(IdentityClientEvent::HandshakeCompleted { .. }) => "HandshakeCompleted".to_string(),
+        Err(err) => format!("error: {err}"),
+    }
+}
+
+fn identity_server_outcome(outcome: &Result<IdentityServerEvent, IdentityServerError>) -> String {
+    match outcome {
+        Ok(IdentityServerEvent::EndpointRequestReceived { .. }) => {
+            "EndpointRequestReceived".to_string()
+        }
+        Ok(IdentityServerEvent::ChallengeResponseReceived { .. }) => {
+            "ChallengeResponseReceived".to_string()
+        }
+        Ok(IdentityServerEvent::HandshakeCompleted { .. }) => "HandshakeCompleted".to_string(),
+        Ok(IdentityServerEvent::HandshakeRejected {
+            client_allowed,
+            client_requested_endpoint_valid,
+            client_proof_signature_valid,
+            client_auth_signature_valid,
+            challenge_response_valid,
+        }) => format!(
+            "HandshakeRejected {{ client_allowed: {client_allowed}, client_requested_endpoint_valid: {client_requested_endpoint_valid}, client_proof_signature_valid: {client_proof_signature_valid}, client_auth_signature_valid: {client_auth_signature_valid}, challenge_response_valid: {challenge_response_valid} }}"
+        ),
+        Ok(IdentityServerEvent::HandshakeBanned { .. }) => "HandshakeBanned".to_string(),
+        Err(err) => format!("error: {err}"),
+    }
+}
+
+fn endpoint_client_outcome<RW>(
+    outcome: &Result<EndpointClientEvent<RW>, EndpointClientError>,
+) -> String {
+    match outcome {
+        Ok(EndpointClientEvent::HandshakeCompleted { .. }) => "HandshakeCompleted".to_string(),
+        Ok(EndpointClientEvent::RpcChannelOpened { .. }) => "RpcChannelOpened".to_string(),
+        Err(err) => format!("error: {err}"),
+    }
+}
+
+fn endpoint_server_outcome<RW>(
+    outcome: &Result<EndpointServerEvent<RW>, EndpointServerError>,
+) -> String {
+    match outcome {
+        Ok(EndpointServerEvent::ChannelRequestReceived { .. }) => {
+            "ChannelRequestReceived".to_string()
+        }
+        Ok(EndpointServerEvent::HandshakeCompleted { .. }) => "HandshakeCompleted".to_string(),
+        Ok(EndpointServerEvent::RpcChannelOpened { .. }) => "RpcChannelOpened".to_string(),
+        Ok(EndpointServerEvent::HandshakeRejected {
+            client_allowed,
+            client_requested_channel_valid,
+            client_proof_signature_valid,
+        }) => format!(
+            "HandshakeRejected {{ client_allowed: {client_allowed}, client_requested_channel_valid: {client_requested_channel_valid}, client_proof_signature_valid: {client_proof_signature_valid} }}"
+        ),
+        Ok(EndpointServerEvent::HandshakeBusy { .. }) => "HandshakeBusy".to_string(),
+        Ok(EndpointServerEvent::HandshakeQuotaExceeded { .. }) => {
+            "HandshakeQuotaExceeded".to_string()
+        }
+        Ok(EndpointServerEvent::HandshakeBanned { .. }) => "HandshakeBanned".to_string(),
+        Err(err) => format!("error: {err}"),
+    }
+}
+
+#[test]
+fn test_replay() -> anyhow::Result<()> {
+    // a completed pair of handshakes replays identically
+    let transcript = record(Scenario::new("endpoint", Some("channel")))?;
+    assert_eq!(
+        transcript.identity_handshake.client_outcome,
+        "HandshakeCompleted"
+    );
+    assert_eq!(
+        transcript
+            .identity_handshake
+            .client_states
+            .first()
+            .map(String::as_str),
+        Some("BeginHandshake")
+    );
+    let endpoint_handshake = transcript.endpoint_handshake.clone().unwrap();
+    assert_eq!(endpoint_handshake.server_outcome, "HandshakeCompleted");
+    let transcript = Transcript::from_bytes(&transcript.to_bytes())?;
+    replay(&transcript)?;
+
+    // as does a rejected identity handshake, which is not followed by an
+    // endpoint handshake
+    let mut scenario = Scenario::new("endpoint", Some("channel"));
+    scenario.challenge_response_valid = false;
+    let transcript = record(scenario)?;
+    assert!(transcript
+        .identity_handshake
+        .server_outcome
+        .starts_with("HandshakeRejected"));
+    assert!(transcript.endpoint_handshake.is_none());
+    replay(&Transcript::from_bytes(&transcript.to_bytes())?)?;
+
+    // and a rejected channel request
+    let mut scenario = Scenario::new("endpoint", Some("channel"));
+    scenario.channel_valid = false;
+    let transcript = record(scenario)?;
+    replay(&transcript)?;
+
+    // a transcript whose transitions differ diverges
+    let mut transcript = record(Scenario::new("endpoint", None))?;
+    transcript.identity_handshake.server_states.pop();
+    assert!(matches!(replay(&transcript), Err(Error::Diverged(_))));
+
+    // as does one which recorded an endpoint handshake that is not replayed
+    let mut transcript = record(Scenario::new("endpoint", Some("channel")))?;
+    transcript.scenario.channel = None;
+    match replay(&transcript) {
+        Err(Error::Diverged(divergence)) => {
+            assert_eq!(divergence.handshake, "endpoint");
+            assert!(divergence.replayed.is_none());
+        }
+        _ => panic!("replay did not diverge"),
+    }
+
+    // transcripts from other format versions are refused
+    let mut transcript = record(Scenario::new("endpoint", None))?;
+    transcript.version = TRANSCRIPT_VERSION + 1;
+    assert!(matches!(
+        Transcript::from_bytes(&transcript.to_bytes()),
+        Err(Error::UnsupportedVersion(..))
+    ));
+
+    Ok(())
+}
@@ -0,0 +1,330 @@
+// standard
+use std::collections::{HashSet, VecDeque};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::time::Duration;
+
+// extern crates
+use honk_rpc::honk_rpc::{
+    ApiSet, ErrorCode, RequestCookie, Response, Session, DEFAULT_MAX_MESSAGE_SIZE,
+};
+use tor_interface::clock::Clock;
+
+// internal crates
+use crate::gosling::Instant;
+
+// the heartbeat function served by every RpcChannel
+const HEARTBEAT_NAMESPACE: &str = "gosling_channel";
+const HEARTBEAT_FUNCTION: &str = "heartbeat";
+const HEARTBEAT_VERSION: i32 = 0;
+
+// namespaces of the handshakes' and channels' own functions, which applications
+// may not register
+const RESERVED_NAMESPACES: [&str; 3] =
+    ["gosling_identity", "gosling_endpoint", HEARTBEAT_NAMESPACE];
+
+/// The error type for the [`RpcChannel`] type.
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    /// The underlying Honk-RPC session failed
+    #[error("HonkRPC method failed: {0}")]
+    HonkRPCFailure(#[from] honk_rpc::honk_rpc::Error),
+
+    /// The namespace is used by the Gosling handshakes
+    #[error("namespace is reserved by the gosling protocol: {0}")]
+    ReservedNamespace(String),
+
+    /// An invalid argument was provided to a function
+    #[error("invalid argument: {0}")]
+    InvalidArgument(String),
+}
+
+/// How often an [`RpcChannel`] checks that its peer is still reachable; see [`RpcChannel::set_heartbeat()`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Heartbeat {
+    /// How long to wait between heartbeats; must not be zero
+    pub interval: Duration,
+    /// The number of consecutive heartbeats which must go unanswered before the peer is reported unresponsive; must not be zero
+    pub miss_threshold: u32,
+}
+
+impl Heartbeat {
+    pub(crate) fn validate(&self) -> Result<(), Error> {
+        if self.interval.is_zero() {
+            return Err(Error::InvalidArgument(
+                "heartbeat interval must not be zero".to_string(),
+            ));
+        }
+        if self.miss_threshold == 0 {
+            return Err(Error::InvalidArgument(
+                "heartbeat miss threshold must not be zero".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Events returned by [`RpcChannel::next_event()`]
+#[derive(Clone, Debug, PartialEq)]
+pub enum RpcChannelEvent {
+    /// The peer has not answered the channel's recent heartbeats, e.g. because its circuit failed without the connection being closed. Further calls are unlikely to be answered until the peer is reported responsive again.
+    Unresponsive {
+        /// The number of consecutive heartbeats the peer has not answered
+        missed_heartbeats: u32,
+    },
+    /// The peer has answered a heartbeat after it was reported unresponsive
+    Responsive,
+}
+
+// answers the peer's heartbeats
+struct HeartbeatApiSet;
+
+impl ApiSet for HeartbeatApiSet {
+    fn namespace(&self) -> &str {
+        HEARTBEAT_NAMESPACE
+    }
+
+    fn exec_function(
+        &mut self,
+        name: &str,
+        version: i32,
+        _args: bson::document::Document,
+        _request_cookie: Option<RequestCookie>,
+    ) -> Option<Result<Option<bson::Bson>, ErrorCode>> {
+        match (name, version) {
+            (HEARTBEAT_FUNCTION, HEARTBEAT_VERSION) => Some(Ok(None)),
+            (HEARTBEAT_FUNCTION, _) => Some(Err(ErrorCode::RequestVersionInvalid)),
+            _ => Some(Err(ErrorCode::RequestFunctionInvalid)),
+        }
+    }
+}
+
+/// The Honk-RPC session of a completed endpoint handshake, kept open for the application's own calls in place of the connection's [`TcpStream`].
+///
+/// An `RpcChannel` is returned in a [`ContextEvent::EndpointClientRpcChannelOpened`](crate::context::ContextEvent::EndpointClientRpcChannelOpened) or [`ContextEvent::EndpointServerRpcChannelOpened`](crate::context::ContextEvent::EndpointServerRpcChannelOpened) event for channels registered with [`Context::add_rpc_channel()`](crate::context::Context::add_rpc_channel). Either peer may make calls to the other and serve calls through [`ApiSet`]s registered with [`RpcChannel::register_apiset()`]. The channel only makes progress while [`RpcChannel::update()`] is called.
+///
+/// The endpoint client may receive the endpoint server's first calls along with the end of the handshake, before it has been able to register its `ApiSet`s; such calls fail as for an unknown namespace. Applications in which the endpoint server calls the endpoint client should therefore have the endpoint client make the first call.
+///
+/// A channel whose connection fails is reported by [`RpcChannel::update()`], but a peer whose circuit dies silently may leave the connection open indefinitely. Channels may instead send heartbeats (see [`RpcChannel::set_heartbeat()`]), which the peer's channel answers without involving the application, and report a peer which stops answering them with an [`RpcChannelEvent::Unresponsive`] event.
+pub struct RpcChannel {
+    session: Session<TcpStream>,
+    clock: Arc<dyn Clock>,
+    heartbeat: Option<Heartbeat>,
+    // when the last heartbeat was sent, and whether it has been answered
+    last_heartbeat: Option<(Instant, bool)>,
+    // heartbeats whose final response has not been received
+    heartbeat_cookies: HashSet<RequestCookie>,
+    missed_heartbeats: u32,
+    unresponsive: bool,
+    // responses to the application's calls received while looking for the
+    // responses to heartbeats
+    responses: VecDeque<Response>,
+    events: VecDeque<RpcChannelEvent>,
+}
+
+impl RpcChannel {
+    // the session keeps the limits set for the handshake, so relax them to
+    // the Honk-RPC defaults for long-lived application traffic
+    pub(crate) fn new(
+        mut session: Session<TcpStream>,
+        clock: Arc<dyn Clock>,
+        heartbeat: Option<Heartbeat>,
+    ) -> Result<Self, Error> {
+        session.set_max_wait_time(Duration::MAX);
+        session.set_max_message_size(DEFAULT_MAX_MESSAGE_SIZE as i32)?;
+        session.register_apiset(Box::new(HeartbeatApiSet))?;
+        let mut channel = Self {
+            session,
+            clock,
+            heartbeat: None,
+            last_heartbeat: None,
+            heartbeat_cookies: Default::default(),
+            missed_heartbeats: 0,
+            unresponsive: false,
+            responses: Default::default(),
+            events: Default::default(),
+        };
+        channel.start_heartbeat(heartbeat);
+        Ok(channel)
+    }
+
+    /// Set how often this channel sends heartbeats to its peer, or disable them. The first heartbeat is sent one interval after this call. Once `miss_threshold` consecutive heartbeats have gone unanswered, an [`RpcChannelEvent::Unresponsive`] event is returned from [`RpcChannel::next_event()`], followed by an [`RpcChannelEvent::Responsive`] event if the peer answers again. Heartbeats are only sent and their answers only received while [`RpcChannel::update()`] is called, and the peer only answers them while its own channel is updated.
+    ///
+    /// Peers whose channels predate heartbeats answer them with an error, which still shows they are reachable.
+    ///
+    /// # Parameters
+    /// - `heartbeat`: how often heartbeats are sent, or `None` to disable them (the default unless set with [`Context::set_rpc_channel_heartbeat()`](crate::context::Context::set_rpc_channel_heartbeat))
+    pub fn set_heartbeat(&mut self, heartbeat: Option<Heartbeat>) -> Result<(), Error> {
+        if let Some(heartbeat) = &heartbeat {
+            heartbeat.validate()?;
+        }
+        self.start_heartbeat(heartbeat);
+        Ok(())
+    }
+
+    fn start_heartbeat(&mut self, heartbeat: Option<Heartbeat>) {
+        self.heartbeat = heartbeat;
+        self.last_heartbeat = heartbeat.map(|_| (self.clock.now(), true));
+        self.missed_heartbeats = 0;
+    }
+
+    /// Take the next event produced by [`RpcChannel::update()`]
+    pub fn next_event(&mut self) -> Option<RpcChannelEvent> {
+        self.events.pop_front()
+    }
+
+    /// Register an [`ApiSet`] to serve the peer's calls to its namespace. An error is returned if an `ApiSet` with the same namespace is already registered or the namespace is used by the Gosling handshakes.
+    ///
+    /// # Parameters
+    /// - `apiset`: the `ApiSet` to register
+    pub fn register_apiset(&mut self, apiset: Box<dyn ApiSet + Send>) -> Result<(), Error> {
+        let namespace = apiset.namespace();
+        if RESERVED_NAMESPACES.contains(&namespace) {
+            return Err(Error::ReservedNamespace(namespace.to_string()));
+        }
+        Ok(self.session.register_apiset(apiset)?)
+    }
+
+    /// Unregister the [`ApiSet`] serving the given namespace. The peer's later calls to the namespace fail as for any unknown namespace.
+    ///
+    /// # Parameters
+    /// - `namespace`: the namespace of the `ApiSet` to unregister
+    /// # Returns
+    /// The unregistered `ApiSet`, or `None` if no `ApiSet` is registered for the namespace
+    pub fn unregister_apiset(&mut self, namespace: &str) -> Option<Box<dyn ApiSet + Send>> {
+        if RESERVED_NAMESPACES.contains(&namespace) {
+            return None;
+        }
+        self.session.unregister_apiset(namespace)
+    }
+
+    /// Call a function served by the peer. The call is sent by the next [`RpcChannel::update()`] and its response is returned from [`RpcChannel::next_response()`].
+    ///
+    /// # Parameters
+    /// - `namespace`: the namespace of the function
+    /// - `function`: the name of the function
+    /// - `version`: the version of the function
+    /// - `arguments`: the function's arguments
+    /// # Returns
+    /// A `RequestCookie` identifying the call's [`Response`]
+    pub fn call(
+        &mut self,
+        namespace: &str,
+        function: &str,
+        version: i32,
+        arguments: bson::document::Document,
+    ) -> Result<RequestCookie, Error> {
+        Ok(self
+            .session
+            .client_call(namespace, function, version, arguments)?)
+    }
+
+    /// Send the pending calls and responses, receive the peer's, and serve the peer's calls with the registered [`ApiSet`]s. Heartbeats are also sent and their answers received; see [`RpcChannel::set_heartbeat()`]. An error is returned once the connection has failed or been closed, after which the channel should be dropped.
+    pub fn update(&mut self) -> Result<(), Error> {
+        if let (Some(heartbeat), Some((sent, answered))) = (self.heartbeat, self.last_heartbeat) {
+            let now = self.clock.now();
+            if now.saturating_duration_since(sent) >= heartbeat.interval {
+                if !answered {
+                    self.missed_heartbeats = self.missed_heartbeats.saturating_add(1);
+                    if self.missed_heartbeats >= heartbeat.miss_threshold && !self.unresponsive {
+                        self.unresponsive = true;
+                        self.events.push_back(RpcChannelEvent::Unresponsive {
+                            missed_heartbeats: self.missed_heartbeats,
+                        });
+                    }
+                }
+                let cookie = self.session.client_call(
+                    HEARTBEAT_NAMESPACE,
+                    HEARTBEAT_FUNCTION,
+                    HEARTBEAT_VERSION,
+                    bson::doc! {},
+                )?;
+                self.heartbeat_cookies.insert(cookie);
+                self.last_heartbeat = Some((now, false));
+            }
+        }
+
+        self.session.update(None)?;
+
+        // the application may not take its responses for a while, so look
+        // for the answers to heartbeats now
+        if !self.heartbeat_cookies.is_empty() {
+            while let Some(response) = self.next_session_response() {
+                self.responses.push_back(response);
+            }
+        }
+        Ok(())
+    }
+
+    /// Take the next [`Response`] to a call made with [`RpcChannel::call()`].
+    pub fn next_response(&mut self) -> Option<Response> {
+        match self.responses.pop_front() {
+            Some(response) => Some(response),
+            None => self.next_session_response(),
+        }
+    }
+
+    // the session's next response to one of the application's calls; the
+    // responses to heartbeats are consumed
+    fn next_session_response(&mut self) -> Option<Response> {
+        while let Some(response) = self.session.client_next_response() {
+            let (cookie, pending) = match &response {
+                Response::Pending { cookie } => (*cookie, true),
+                Response::Success { cookie, .. } | Response::Error { cookie, .. } => {
+                    (*cookie, false)
+                }
+            };
+            if !self.heartbeat_cookies.contains(&cookie) {
+                return Some(response);
+            }
+            if !pending {
+                self.heartbeat_cookies.remove(&cookie);
+            }
+
+            // any answer, even to an earlier heartbeat, shows the peer is reachable
+            if let Some((_, answered)) = self.last_heartbeat.as_mut() {
+                *answered = true;
+            }
+            self.missed_heartbeats = 0;
+            if self.unresponsive {
+                self.unresponsive = false;
+                self.events.push_back(RpcChannelEvent::Responsive);
+            }
+        }
+        None
+    }
+
+    /// Take the application-specific data the peer attached to its error [`Response`] for the call with the given `cookie`, if any.
+    ///
+    /// # Parameters
+    /// - `cookie`: the `RequestCookie` of the failed call
+    pub fn take_error_data(&mut self, cookie: RequestCookie) -> Option<bson::Bson> {
+        self.session.client_take_error_data(cookie)
+    }
+
+    /// The channel's underlying Honk-RPC session, e.g. to change its message size or wait time limits.
+    pub fn session(&mut self) -> &mut Session<TcpStream> {
+        &mut self.session
+    }
+
+    /// Consume the channel, returning its underlying Honk-RPC session.
+    pub fn into_session(self) -> Session<TcpStream> {
+        self.session
+    }
+}
+
+impl std::fmt::Debug for RpcChannel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RpcChannel")
+            .field(
+                "namespaces",
+                &self
+                    .session
+                    .registered_namespaces()
+                    .filter(|namespace| !RESERVED_NAMESPACES.contains(namespace))
+                    .collect::<Vec<_>>(),
+            )
+            .field("heartbeat", &self.heartbeat)
+            .finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,452 @@
+// standard
+use std::collections::VecDeque;
+use std::io::{ErrorKind, Read, Write};
+use std::sync::{Arc, Mutex};
+
+// extern crates
+#[cfg(test)]
+use bson::doc;
+use bson::document::Document;
+#[cfg(test)]
+use honk_rpc::honk_rpc::Session;
+use tor_interface::tor_crypto::*;
+
+// internal crates
+#[cfg(test)]
+use crate::ascii_string::AsciiString;
+use crate::endpoint_client::{EndpointClient, EndpointClientEvent, Error as EndpointClientError};
+use crate::endpoint_server::{EndpointServer, EndpointServerEvent, Error as EndpointServerError};
+use crate::identity_client::{Error as IdentityClientError, IdentityClient, IdentityClientEvent};
+use crate::identity_server::{Error as IdentityServerError, IdentityServer, IdentityServerEvent};
+
+/// The error type for the [`drive_identity_handshake()`] and [`drive_endpoint_handshake()`] functions.
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    /// A handshake did not finish on both sides within the given number of rounds of updates
+    #[error("handshake did not finish within {0} rounds")]
+    HandshakeStalled(usize),
+}
+
+// each round updates both sides of a handshake once; in-process handshakes
+// finish within a few dozen rounds
+const MAX_ROUNDS: usize = 1024;
+
+/// An in-memory non-blocking byte stream standing in for a connection between two peers, e.g. to test an application's challenge handlers against the handshake state machines without tor or sockets. Created in connected pairs by [`duplex()`].
+///
+/// As with a non-blocking `TcpStream`, reads fail with [`ErrorKind::WouldBlock`] while no bytes written by the other end are waiting to be read. Writes always succeed.
+#[derive(Debug)]
+pub struct MemoryStream {
+    read_buffer: Arc<Mutex<VecDeque<u8>>>,
+    write_buffer: Arc<Mutex<VecDeque<u8>>>,
+}
+
+/// Create a connected pair of [`MemoryStream`]s; bytes written to either stream may be read from the other.
+pub fn duplex() -> (MemoryStream, MemoryStream) {
+    let alice_to_pat: Arc<Mutex<VecDeque<u8>>> = Default::default();
+    let pat_to_alice: Arc<Mutex<VecDeque<u8>>> = Default::default();
+    (
+        MemoryStream {
+            read_buffer: pat_to_alice.clone(),
+            write_buffer: alice_to_pat.clone(),
+        },
+        MemoryStream {
+            read_buffer: alice_to_pat,
+            write_buffer: pat_to_alice,
+        },
+    )
+}
+
+impl Read for MemoryStream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+        let mut read_buffer = match self.read_buffer.lock() {
+            Ok(read_buffer) => read_buffer,
+            Err(_) => return Err(std::io::Error::from(ErrorKind::BrokenPipe)),
+        };
+        if read_buffer.is_empty() {
+            return Err(std::io::Error::from(ErrorKind::WouldBlock));
+        }
+        let len = std::cmp::min(buf.len(), read_buffer.len());
+        for (dest, src) in buf.iter_mut().zip(read_buffer.drain(..len)) {
+            *dest = src;
+        }
+        Ok(len)
+    }
+}
+
+impl Write for MemoryStream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
+        match self.write_buffer.lock() {
+            Ok(mut write_buffer) => {
+                write_buffer.extend(buf);
+                Ok(buf.len())
+            }
+            Err(_) => Err(std::io::Error::from(ErrorKind::BrokenPipe)),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+}
+
+/// The application's decisions during the handshakes driven by [`drive_identity_handshake()`] and [`drive_endpoint_handshake()`], corresponding to the application's handling of a [`Context`](crate::context::Context)'s handshake events. Each method's default accepts the request with an empty document.
+pub trait HandshakeHandler {
+    /// Called on the identity server side when the client's endpoint request is received (see [`ContextEvent::IdentityServerEndpointRequestReceived`](crate::context::ContextEvent::IdentityServerEndpointRequestReceived)). Any additional endpoints requested by the client are denied.
+    ///
+    /// # Returns
+    /// Whether the client is allowed, whether the requested endpoint is supported and the endpoint challenge to send to the client.
+    fn endpoint_request_received(
+        &mut self,
+        _client_service_id: &V3OnionServiceId,
+        _requested_endpoint: &str,
+    ) -> (bool, bool, Document) {
+        (true, true, Document::new())
+    }
+
+    /// Called on the identity client side when the endpoint challenge is received (see [`ContextEvent::IdentityClientChallengeReceived`](crate::context::ContextEvent::IdentityClientChallengeReceived)).
+    ///
+    /// # Returns
+    /// The challenge response to send to the identity server.
+    fn challenge_received(&mut self, _endpoint_challenge: &Document) -> Document {
+        Document::new()
+    }
+
+    /// Called on the identity server side when the client's challenge response is received (see [`ContextEvent::IdentityServerChallengeResponseReceived`](crate::context::ContextEvent::IdentityServerChallengeResponseReceived)).
+    ///
+    /// # Returns
+    /// Whether the challenge response is valid.
+    fn challenge_response_received(&mut self, _challenge_response: &Document) -> bool {
+        true
+    }
+
+    /// Called on the endpoint server side when the client's channel request is received (see [`ContextEvent::EndpointServerChannelRequestReceived`](crate::context::ContextEvent::EndpointServerChannelRequestReceived)).
+    ///
+    /// # Returns
+    /// Whether the requested channel is valid.
+    fn channel_request_received(
+        &mut self,
+        _client_service_id: &V3OnionServiceId,
+        _requested_channel: &str,
+    ) -> bool {
+        true
+    }
+}
+
+/// A [`HandshakeHandler`] which accepts every request.
+pub struct AcceptAll;
+
+impl HandshakeHandler for AcceptAll {}
+
+/// How an identity handshake driven by [`drive_identity_handshake()`] finished on each side.
+pub struct IdentityHandshakeOutcome {
+    /// The client's [`IdentityClientEvent::HandshakeCompleted`] event, or the error which ended its handshake
+    pub client: Result<IdentityClientEvent, IdentityClientError>,
+    /// The server's [`IdentityServerEvent::HandshakeCompleted`] or [`IdentityServerEvent::HandshakeRejected`] event, or the error which ended its handshake
+    pub server: Result<IdentityServerEvent, IdentityServerError>,
+}
+
+/// How an endpoint handshake driven by [`drive_endpoint_handshake()`] finished on each side.
+pub struct EndpointHandshakeOutcome<RW> {
+    /// The client's [`EndpointClientEvent::HandshakeCompleted`] or [`EndpointClientEvent::RpcChannelOpened`] event, or the error which ended its handshake
+    pub client: Result<EndpointClientEvent<RW>, EndpointClientError>,
+    /// The server's [`EndpointServerEvent::HandshakeCompleted`], [`EndpointServerEvent::RpcChannelOpened`], [`EndpointServerEvent::HandshakeRejected`], [`EndpointServerEvent::HandshakeBusy`] or [`EndpointServerEvent::HandshakeQuotaExceeded`] event, or the error which ended its handshake
+    pub server: Result<EndpointServerEvent<RW>, EndpointServerError>,
+}
+
+/// Update an identity client and server connected to each other (e.g. over a [`duplex()`] pair) until the handshake has finished on both sides, passing the handshake's requests to the handler.
+///
+/// # Parameters
+/// - `client`: the identity client
+/// - `server`: the identity server the client is connected to
+/// - `handler`: makes the application's decisions for both sides
+pub fn drive_identity_handshake<RW, H>(
+    client: &mut IdentityClient<RW>,
+    server: &mut IdentityServer<RW>,
+    handler: &mut H,
+) -> Result<IdentityHandshakeOutcome, Error>
+where
+    RW: Read + Write + Send,
+    H: HandshakeHandler,
+{
+    drive_identity_handshake_observed(client, server, handler, |_, _| ())
+}
+
+// drive_identity_handshake(), passing both sides to observe after each round
+pub(crate) fn drive_identity_handshake_observed<RW, H, O>(
+    client: &mut IdentityClient<RW>,
+    server: &mut IdentityServer<RW>,
+    handler: &mut H,
+    mut observe: O,
+) -> Result<IdentityHandshakeOutcome, Error>
+where
+    RW: Read + Write + Send,
+    H: HandshakeHandler,
+    O: FnMut(&IdentityClient<RW>, &IdentityServer<RW>),
+{
+    let mut client_result: Option<Result<IdentityClientEvent, IdentityClientError>> = None;
+    let mut server_result: Option<Result<IdentityServerEvent, IdentityServerError>> = None;
+    for _ in 0..MAX_ROUNDS {
+        if server_result.is_none() {
+            server_result = match server.update() {
+                Ok(Some(IdentityServerEvent::EndpointRequestReceived {
+                    client_service_id,
+                    requested_endpoint,
+                    ..
+                })) => {
+                    let (client_allowed, endpoint_supported, endpoint_challenge) =
+                        handler.endpoint_request_received(&client_service_id, &requested_endpoint);
+                    server
+                        .handle_endpoint_request_received(
+                            client_allowed,
+                            endpoint_supported,
+                            endpoint_challenge,
+                        )
+                        .err()
+                        .map(Err)
+                }
+                Ok(Some(IdentityServerEvent::ChallengeResponseReceived { challenge_response })) => {
+                    let challenge_response_valid =
+                        handler.challenge_response_received(&challenge_response);
+                    server
+                        .handle_challenge_response_received(challenge_response_valid)
+                        .err()
+                        .map(Err)
+                }
+                Ok(Some(event)) => Some(Ok(event)),
+                Ok(None) => None,
+                Err(err) => Some(Err(err)),
+            };
+        }
+
+        if client_result.is_none() {
+            client_result = match client.update() {
+                Ok(Some(IdentityClientEvent::ChallengeReceived { endpoint_challenge })) => {
+                    let challenge_response = handler.challenge_received(&endpoint_challenge);
+                    client.send_response(challenge_response).err().map(Err)
+                }
+                Ok(Some(event)) => Some(Ok(event)),
+                Ok(None) => None,
+                Err(err) => Some(Err(err)),
+            };
+        }
+
+        observe(client, server);
+
+        if client_result.is_some() && server_result.is_some() {
+            break;
+        }
+    }
+
+    match (client_result, server_result) {
+        (Some(client), Some(server)) => Ok(IdentityHandshakeOutcome { client, server }),
+        _ => Err(Error::HandshakeStalled(MAX_ROUNDS)),
+    }
+}
+
+/// Update an endpoint client and server connected to each other (e.g. over a [`duplex()`] pair) until the handshake has finished on both sides, passing the client's channel request to the handler.
+///
+/// # Parameters
+/// - `client`: the endpoint client
+/// - `server`: the endpoint server the client is connected to
+/// - `handler`: makes the application's decisions for the server
+pub fn drive_endpoint_handshake<RW, H>(
+    client: &mut EndpointClient<RW>,
+    server: &mut EndpointServer<RW>,
+    handler: &mut H,
+) -> Result<EndpointHandshakeOutcome<RW>, Error>
+where
+    RW: Read + Write + Send,
+    H: HandshakeHandler,
+{
+    drive_endpoint_handshake_observed(client, server, handler, |_, _| ())
+}
+
+// drive_endpoint_handshake(), passing both sides to observe after each round
+pub(crate) fn drive_endpoint_handshake_observed<RW, H, O>(
+    client: &mut EndpointClient<RW>,
+    server: &mut EndpointServer<RW>,
+    handler: &mut H,
+    mut observe: O,
+) -> Result<EndpointHandshakeOutcome<RW>, Error>
+where
+    RW: Read + Write + Send,
+    H: HandshakeHandler,
+    O: FnMut(&EndpointClient<RW>, &EndpointServer<RW>),
+{
+    let mut client_result: Option<Result<EndpointClientEvent<RW>, EndpointClientError>> = None;
+    let mut server_result: Option<Result<EndpointServerEvent<RW>, EndpointServerError>> = None;
+    for _ in 0..MAX_ROUNDS {
+        if server_result.is_none() {
+            server_result = match server.update() {
+                Ok(Some(EndpointServerEvent::ChannelRequestReceived {
+                    client_service_id,
+                    requested_channel,
+                })) => {
+                    let channel_valid =
+                        handler.channel_request_received(&client_service_id, &requested_channel);
+                    server
+                        .handle_channel_request_received(channel_valid)
+                        .err()
+                        .map(Err)
+                }
+                Ok(Some(event)) => Some(Ok(event)),
+                Ok(None) => None,
+                Err(err) => Some(Err(err)),
+            };
+        }
+
+        if client_result.is_none() {
+            client_result = match client.update() {
+                Ok(Some(event)) => Some(Ok(event)),
+                Ok(None) => None,
+                Err(err) => Some(Err(err)),
+            };
+        }
+
+        observe(client, server);
+
+        if client_result.is_some() && server_result.is_some() {
+            break;
+        }
+    }
+
+    match (client_result, server_result) {
+        (Some(client), Some(server)) => Ok(EndpointHandshakeOutcome { client, server }),
+        _ => Err(Error::HandshakeStalled(MAX_ROUNDS)),
+    }
+}
+
+#[test]
+fn test_drive_handshakes() -> anyhow::Result<()> {
+    // rejects clients whose challenge response does not answer the question
+    struct Handler;
+    impl HandshakeHandler for Handler {
+        fn endpoint_request_received(
+            &mut self,
+            _client_service_id: &V3OnionServiceId,
+            requested_endpoint: &str,
+        ) -> (bool, bool, Document) {
+            (
+                true,
+                requested_endpoint == "endpoint",
+                doc! {"question": "2 + 2"},
+            )
+        }
+
+        fn challenge_received(&mut self, _endpoint_challenge: &Document) -> Document {
+            doc! {"answer": 4}
+        }
+
+        fn challenge_response_received(&mut self, challenge_response: &Document) -> bool {
+            matches!(challenge_response.get_i32("answer"), Ok(4))
+        }
+
+        fn channel_request_received(
+            &mut self,
+            _client_service_id: &V3OnionServiceId,
+            requested_channel: &str,
+        ) -> bool {
+            requested_channel == "channel"
+        }
+    }
+
+    let client_private_key = Ed25519PrivateKey::generate();
+    let client_service_id = V3OnionServiceId::from_private_key(&client_private_key);
+    let server_service_id = V3OnionServiceId::from_private_key(&Ed25519PrivateKey::generate());
+
+    let identity_handshake = |endpoint: &str| -> anyhow::Result<IdentityHandshakeOutcome> {
+        let (client_stream, server_stream) = duplex();
+        let mut client = IdentityClient::new(
+            Session::new(client_stream),
+            server_service_id.clone(),
+            AsciiString::new(endpoint.to_string())?,
+            client_private_key.clone(),
+            X25519PrivateKey::generate(),
+            false,
+        )?;
+        let mut server = IdentityServer::new(
+            Session::new(server_stream),
+            server_service_id.clone(),
+            None,
+            false,
+        );
+        Ok(drive_identity_handshake(
+            &mut client,
+            &mut server,
+            &mut Handler,
+        )?)
+    };
+
+    // the identity handshake completes on both sides
+    let outcome = identity_handshake("endpoint")?;
+    let endpoint_private_key = match (outcome.client, outcome.server) {
+        (
+            Ok(IdentityClientEvent::HandshakeCompleted {
+                endpoint_service_id,
+                ..
+            }),
+            Ok(IdentityServerEvent::HandshakeCompleted {
+                endpoint_private_key,
+                client_service_id: ret_client_service_id,
+                ..
+            }),
+        ) => {
+            assert_eq!(
+                endpoint_service_id,
+                V3OnionServiceId::from_private_key(&endpoint_private_key)
+            );
+            assert_eq!(ret_client_service_id, client_service_id);
+            endpoint_private_key
+        }
+        _ => panic!("identity handshake did not complete"),
+    };
+
+    // unsupported endpoints are rejected
+    let outcome = identity_handshake("unsupported")?;
+    assert!(outcome.client.is_err());
+    assert!(matches!(
+        outcome.server,
+        Ok(IdentityServerEvent::HandshakeRejected {
+            client_requested_endpoint_valid: false,
+            ..
+        })
+    ));
+
+    // the endpoint handshake completes and hands back the connected streams
+    let endpoint_service_id = V3OnionServiceId::from_private_key(&endpoint_private_key);
+    let (client_stream, server_stream) = duplex();
+    let mut client = EndpointClient::new(
+        Session::new(client_stream),
+        endpoint_service_id.clone(),
+        AsciiString::new("channel".to_string())?,
+        client_private_key,
+    );
+    let mut server = EndpointServer::new(
+        Session::new(server_stream),
+        client_service_id,
+        endpoint_service_id,
+        Default::default(),
+        false,
+    );
+    let outcome = drive_endpoint_handshake(&mut client, &mut server, &mut Handler)?;
+    match (outcome.client, outcome.server) {
+        (
+            Ok(EndpointClientEvent::HandshakeCompleted {
+                stream: mut client_stream,
+                ..
+            }),
+            Ok(EndpointServerEvent::HandshakeCompleted {
+                stream: mut server_stream,
+                ..
+            }),
+        ) => {
+            client_stream.write_all(b"hello")?;
+            let mut buf = [0u8; 5];
+            server_stream.read_exact(&mut buf)?;
+            assert_eq!(&buf, b"hello");
+        }
+        _ => panic!("endpoint handshake did not complete"),
+    }
+
+    Ok(())
+}
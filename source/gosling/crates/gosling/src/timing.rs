@@ -0,0 +1,344 @@
+// standard
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+// extern crates
+#[cfg(feature = "timing-histograms")]
+use hdrhistogram::Histogram;
+use honk_rpc::honk_rpc::SessionStats;
+
+/// A protocol step whose latency is measured by an outgoing handshake
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum TimedOperation {
+    // round-trip of the identity or endpoint begin_handshake() call
+    BeginHandshake,
+    // round-trip of the identity or endpoint send_response() call
+    SendResponse,
+    // time to open a connection to a remote onion service
+    SocksConnect,
+}
+
+// records measured latencies to the tracing subscriber and the optional histograms
+#[derive(Default)]
+pub(crate) struct Timings {
+    #[cfg(feature = "timing-histograms")]
+    pub histograms: TimingHistograms,
+}
+
+impl Timings {
+    pub fn record(&mut self, operation: TimedOperation, elapsed: Duration) {
+        tracing::debug!(?operation, ?elapsed, "operation timed");
+        #[cfg(feature = "timing-histograms")]
+        self.histograms.record(operation, elapsed);
+    }
+}
+
+/// The number of honk-rpc round-trips and bytes exchanged by a single handshake.
+///
+/// Reported by the handshake completed and failed [`crate::context::ContextEvent`]s; a handshake which failed before a connection was established reports all zeroes.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct HandshakeStats {
+    /// The number of remote procedure calls made or answered
+    pub round_trips: u64,
+    /// The number of bytes sent to the peer
+    pub bytes_sent: u64,
+    /// The number of bytes received from the peer
+    pub bytes_received: u64,
+}
+
+impl HandshakeStats {
+    // the traffic of a session since the baseline snapshot; handshakes
+    // continued over an upgraded session only count their own traffic
+    pub(crate) fn since(stats: SessionStats, baseline: SessionStats) -> Self {
+        Self {
+            round_trips: (stats.client_calls + stats.requests_received)
+                - (baseline.client_calls + baseline.requests_received),
+            bytes_sent: stats.bytes_sent - baseline.bytes_sent,
+            bytes_received: stats.bytes_received - baseline.bytes_received,
+        }
+    }
+}
+
+impl From<SessionStats> for HandshakeStats {
+    fn from(stats: SessionStats) -> Self {
+        Self::since(stats, Default::default())
+    }
+}
+
+// endpoint names are chosen by clients, so beyond this many distinct names
+// requests are counted in IdentityServerStats::other()
+const MAX_TRACKED_ENDPOINT_NAMES: usize = 256;
+
+/// Counts of the outcomes of the incoming identity handshakes which requested a single endpoint.
+///
+/// A rejected handshake is counted once in `rejections` and once in each of the rejection reasons which applied to it.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct EndpointRequestStats {
+    /// The number of endpoint requests received
+    pub requests: u64,
+    /// The number of handshakes which granted the endpoint
+    pub grants: u64,
+    /// The number of handshakes which were rejected
+    pub rejections: u64,
+    /// The number of rejected handshakes whose client was not allowed
+    pub client_not_allowed: u64,
+    /// The number of rejected handshakes whose requested endpoint was invalid
+    pub requested_endpoint_invalid: u64,
+    /// The number of rejected handshakes whose client proof signature was invalid
+    pub client_proof_signature_invalid: u64,
+    /// The number of rejected handshakes whose client authorization signature was invalid
+    pub client_auth_signature_invalid: u64,
+    /// The number of rejected handshakes whose challenge response was invalid
+    pub challenge_response_invalid: u64,
+    /// The number of handshakes which failed or were aborted after the endpoint was requested
+    pub failures: u64,
+}
+
+/// Per-endpoint request statistics of the incoming identity handshakes of a [`crate::context::Context`].
+///
+/// Handshakes are attributed to the endpoint named in the client's request, so handshakes which fail before their endpoint request is received are not counted. See [`crate::context::Context::identity_server_stats()`].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct IdentityServerStats {
+    endpoints: BTreeMap<String, EndpointRequestStats>,
+    other: EndpointRequestStats,
+}
+
+impl IdentityServerStats {
+    /// The statistics of the named endpoint; all zeroes if it has not been requested or is not tracked
+    pub fn endpoint(&self, endpoint_name: &str) -> EndpointRequestStats {
+        self.endpoints
+            .get(endpoint_name)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// The requested endpoint names and their statistics, ordered by name
+    pub fn endpoints(&self) -> impl Iterator<Item = (&str, &EndpointRequestStats)> {
+        self.endpoints
+            .iter()
+            .map(|(endpoint_name, stats)| (endpoint_name.as_str(), stats))
+    }
+
+    /// The combined statistics of the endpoints requested after 256 distinct endpoint names were already being tracked
+    pub fn other(&self) -> EndpointRequestStats {
+        self.other
+    }
+
+    /// Discard all recorded statistics
+    pub fn reset(&mut self) {
+        self.endpoints.clear();
+        self.other = Default::default();
+    }
+
+    pub(crate) fn record_request(&mut self, endpoint_name: &str) {
+        self.entry(endpoint_name).requests += 1;
+    }
+
+    pub(crate) fn record_grant(&mut self, endpoint_name: &str) {
+        self.entry(endpoint_name).grants += 1;
+    }
+
+    pub(crate) fn record_rejection(
+        &mut self,
+        endpoint_name: &str,
+        client_allowed: bool,
+        client_requested_endpoint_valid: bool,
+        client_proof_signature_valid: bool,
+        client_auth_signature_valid: bool,
+        challenge_response_valid: bool,
+    ) {
+        let stats = self.entry(endpoint_name);
+        stats.rejections += 1;
+        stats.client_not_allowed += u64::from(!client_allowed);
+        stats.requested_endpoint_invalid += u64::from(!client_requested_endpoint_valid);
+        stats.client_proof_signature_invalid += u64::from(!client_proof_signature_valid);
+        stats.client_auth_signature_invalid += u64::from(!client_auth_signature_valid);
+        stats.challenge_response_invalid += u64::from(!challenge_response_valid);
+    }
+
+    pub(crate) fn record_failure(&mut self, endpoint_name: &str) {
+        self.entry(endpoint_name).failures += 1;
+    }
+
+    fn entry(&mut self, endpoint_name: &str) -> &mut EndpointRequestStats {
+        if !self.endpoints.contains_key(endpoint_name) {
+            if self.endpoints.len() >= MAX_TRACKED_ENDPOINT_NAMES {
+                return &mut self.other;
+            }
+            self.endpoints
+                .insert(endpoint_name.to_string(), Default::default());
+        }
+        self.endpoints.get_mut(endpoint_name).unwrap()
+    }
+}
+
+#[cfg(feature = "timing-histograms")]
+const MAX_TRACKABLE_LATENCY_MICROS: u64 = 60 * 60 * 1_000_000;
+
+/// A summary of the latencies recorded for a single operation.
+#[cfg(feature = "timing-histograms")]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LatencySummary {
+    /// The number of recorded samples
+    pub count: u64,
+    /// The median latency
+    pub p50: Duration,
+    /// The 95th percentile latency
+    pub p95: Duration,
+    /// The largest recorded latency
+    pub max: Duration,
+}
+
+/// Latency histograms for the outgoing handshake steps of a [`crate::context::Context`].
+///
+/// Latencies are recorded with microsecond resolution and three significant figures of precision. See [`crate::context::Context::timing_histograms()`].
+#[cfg(feature = "timing-histograms")]
+pub struct TimingHistograms {
+    begin_handshake: Histogram<u64>,
+    send_response: Histogram<u64>,
+    socks_connect: Histogram<u64>,
+}
+
+#[cfg(feature = "timing-histograms")]
+impl Default for TimingHistograms {
+    fn default() -> Self {
+        // track latencies from 1µs up to 1 hour; larger values are saturated
+        let new_histogram =
+            || Histogram::<u64>::new_with_bounds(1, MAX_TRACKABLE_LATENCY_MICROS, 3).unwrap();
+        Self {
+            begin_handshake: new_histogram(),
+            send_response: new_histogram(),
+            socks_connect: new_histogram(),
+        }
+    }
+}
+
+#[cfg(feature = "timing-histograms")]
+impl TimingHistograms {
+    fn summarize(histogram: &Histogram<u64>) -> LatencySummary {
+        LatencySummary {
+            count: histogram.len(),
+            p50: Duration::from_micros(histogram.value_at_quantile(0.50)),
+            p95: Duration::from_micros(histogram.value_at_quantile(0.95)),
+            max: Duration::from_micros(histogram.max()),
+        }
+    }
+
+    // each operation's name and latency histogram
+    pub(crate) fn histograms(&self) -> [(&'static str, &Histogram<u64>); 3] {
+        [
+            ("begin_handshake", &self.begin_handshake),
+            ("send_response", &self.send_response),
+            ("socks_connect", &self.socks_connect),
+        ]
+    }
+
+    pub(crate) fn record(&mut self, operation: TimedOperation, elapsed: Duration) {
+        let histogram = match operation {
+            TimedOperation::BeginHandshake => &mut self.begin_handshake,
+            TimedOperation::SendResponse => &mut self.send_response,
+            TimedOperation::SocksConnect => &mut self.socks_connect,
+        };
+        histogram.saturating_record(elapsed.as_micros().try_into().unwrap_or(u64::MAX));
+    }
+
+    /// Latencies of the identity and endpoint `begin_handshake()` round-trips
+    pub fn begin_handshake(&self) -> LatencySummary {
+        Self::summarize(&self.begin_handshake)
+    }
+
+    /// Latencies of the identity and endpoint `send_response()` round-trips
+    pub fn send_response(&self) -> LatencySummary {
+        Self::summarize(&self.send_response)
+    }
+
+    /// Latencies of connecting to remote identity and endpoint servers through the [`tor_interface::tor_provider::TorProvider`]
+    pub fn socks_connect(&self) -> LatencySummary {
+        Self::summarize(&self.socks_connect)
+    }
+
+    /// Discard all recorded latencies
+    pub fn reset(&mut self) {
+        self.begin_handshake.reset();
+        self.send_response.reset();
+        self.socks_connect.reset();
+    }
+}
+
+#[cfg(feature = "timing-histograms")]
+#[test]
+fn test_timing_histograms() -> anyhow::Result<()> {
+    let mut timings: Timings = Default::default();
+    assert_eq!(timings.histograms.begin_handshake(), Default::default());
+
+    for millis in 1..=100 {
+        timings.record(
+            TimedOperation::BeginHandshake,
+            Duration::from_millis(millis),
+        );
+    }
+    timings.record(TimedOperation::SocksConnect, Duration::from_secs(2));
+
+    let begin_handshake = timings.histograms.begin_handshake();
+    println!("begin_handshake: {:?}", begin_handshake);
+    assert_eq!(begin_handshake.count, 100);
+    // histogram values are only accurate to 3 significant figures
+    let approx_eq = |value: Duration, millis: u64| -> bool {
+        value >= Duration::from_micros(millis * 999)
+            && value <= Duration::from_micros(millis * 1001)
+    };
+    assert!(approx_eq(begin_handshake.p50, 50));
+    assert!(approx_eq(begin_handshake.p95, 95));
+    assert!(approx_eq(begin_handshake.max, 100));
+
+    assert_eq!(timings.histograms.send_response().count, 0);
+    assert_eq!(timings.histograms.socks_connect().count, 1);
+
+    timings.histograms.reset();
+    assert_eq!(timings.histograms.begin_handshake().count, 0);
+
+    Ok(())
+}
+
+#[test]
+fn test_identity_server_stats() -> anyhow::Result<()> {
+    let mut stats: IdentityServerStats = Default::default();
+    assert_eq!(stats.endpoint("endpoint"), Default::default());
+
+    stats.record_request("endpoint");
+    stats.record_grant("endpoint");
+    stats.record_request("endpoint");
+    stats.record_rejection("endpoint", true, false, true, true, false);
+    stats.record_request("endpoint");
+    stats.record_failure("endpoint");
+    assert_eq!(
+        stats.endpoint("endpoint"),
+        EndpointRequestStats {
+            requests: 3,
+            grants: 1,
+            rejections: 1,
+            requested_endpoint_invalid: 1,
+            challenge_response_invalid: 1,
+            failures: 1,
+            ..Default::default()
+        }
+    );
+
+    // names beyond the tracked limit are combined
+    for i in 1..MAX_TRACKED_ENDPOINT_NAMES {
+        stats.record_request(&format!("endpoint_{i}"));
+    }
+    assert_eq!(stats.endpoints().count(), MAX_TRACKED_ENDPOINT_NAMES);
+    stats.record_request("untracked_0");
+    stats.record_request("untracked_1");
+    stats.record_request("endpoint");
+    assert_eq!(stats.endpoint("untracked_0"), Default::default());
+    assert_eq!(stats.other().requests, 2);
+    assert_eq!(stats.endpoint("endpoint").requests, 4);
+
+    stats.reset();
+    assert_eq!(stats.endpoints().count(), 0);
+    assert_eq!(stats.other(), Default::default());
+
+    Ok(())
+}
@@ -1,25 +1,26 @@
 // standard
 use std::io::{BufRead, BufReader, Write};
 use std::net::TcpStream;
+use std::sync::Arc;
 
 // extern crates
 use anyhow::bail;
 use bson::doc;
 use serial_test::serial;
+use tor_interface::clock::*;
 #[cfg(feature = "tor-interface/legacy-tor-provider")]
 use tor_interface::legacy_tor_client::*;
-#[cfg(feature = "tor-interface/mock-tor-provider")]
 use tor_interface::mock_tor_client::*;
 use tor_interface::tor_crypto::*;
 use tor_interface::tor_provider::*;
 
 // internal crates
 use gosling::context::*;
+use gosling::protocol::Capabilities;
 
 const INVALID_HANDSHAKE_HANDLE: HandshakeHandle = !0usize;
 
 #[test]
-#[cfg(feature = "tor-interface/mock-tor-provider")]
 fn test_mock_client_gosling_context() -> anyhow::Result<()> {
     let alice_tor_client = Box::new(MockTorClient::new());
     let pat_tor_client = Box::new(MockTorClient::new());
@@ -41,6 +42,10 @@ fn test_legacy_client_gosling_context() -> anyhow::Result<()> {
         allowed_ports: None,
         pluggable_transports: None,
         bridge_lines: None,
+        sandbox: None,
+        security_config: None,
+        socks_policy: None,
+        shared_network_cache: None,
     };
     let alice_tor_client = Box::new(LegacyTorClient::new(tor_config)?);
 
@@ -53,6 +58,10 @@ fn test_legacy_client_gosling_context() -> anyhow::Result<()> {
         allowed_ports: None,
         pluggable_transports: None,
         bridge_lines: None,
+        sandbox: None,
+        security_config: None,
+        socks_policy: None,
+        shared_network_cache: None,
     };
     let pat_tor_client = Box::new(LegacyTorClient::new(tor_config)?);
 
@@ -177,10 +186,9 @@ fn gosling_context_test(
         while pat_identity_handshake_tries_remaining > 0
             && pat_identity_handshake_handle == INVALID_HANDSHAKE_HANDLE
         {
-            match pat.identity_client_begin_handshake(
-                alice_service_id.clone(),
-                "test_endpoint".to_string(),
-            ) {
+            match pat
+                .identity_client_begin_handshake(alice_service_id.clone(), "test_endpoint".parse()?)
+            {
                 Ok(handle) => {
                     pat_identity_handshake_handle = handle;
                 }
@@ -215,10 +223,14 @@ fn gosling_context_test(
                         handle,
                         client_service_id,
                         requested_endpoint,
+                        additional_endpoints,
+                        contact_request,
                     } => {
                         assert_eq!(alice_identity_handshake_handle, handle);
                         assert_eq!(pat_service_id, client_service_id);
                         assert_eq!(requested_endpoint, "test_endpoint");
+                        assert!(additional_endpoints.is_empty());
+                        assert!(contact_request.is_none());
                         alice_identity_server_endpoint_request_received = true;
                         println!("Alice receives initial identity handshake request");
                     }
@@ -343,8 +355,22 @@ fn gosling_context_test(
                         endpoint_name,
                         client_service_id,
                         client_auth_public_key,
+                        additional_endpoints,
+                        contact_request,
+                        capabilities,
+                        stats,
                     } => {
                         assert_eq!(handle, alice_identity_handshake_handle);
+                        assert!(additional_endpoints.is_empty());
+                        assert!(contact_request.is_none());
+                        // connections are only kept open once enabled
+                        assert_eq!(
+                            capabilities,
+                            Capabilities::all().difference(Capabilities::KEEP_OPEN)
+                        );
+                        // begin_handshake() and send_response()
+                        assert_eq!(stats.round_trips, 2);
+                        assert!(stats.bytes_received > 0);
                         alice_endpoint_private_key = Some(endpoint_private_key);
                         assert_eq!(endpoint_name, "test_endpoint");
                         assert_eq!(client_service_id, pat_service_id);
@@ -363,8 +389,20 @@ fn gosling_context_test(
                         endpoint_service_id,
                         endpoint_name,
                         client_auth_private_key,
+                        additional_endpoints,
+                        contact_request,
+                        capabilities,
+                        stats,
                     } => {
                         assert_eq!(handle, pat_identity_handshake_handle);
+                        assert!(additional_endpoints.is_empty());
+                        assert!(contact_request.is_none());
+                        assert_eq!(
+                            capabilities,
+                            Capabilities::all().difference(Capabilities::KEEP_OPEN)
+                        );
+                        assert_eq!(stats.round_trips, 2);
+                        assert!(stats.bytes_sent > 0 && stats.bytes_received > 0);
                         assert_eq!(identity_service_id, alice_service_id);
                         assert_eq!(endpoint_name, "test_endpoint");
                         alice_endpoint_service_id = Some(endpoint_service_id);
@@ -395,9 +433,10 @@ fn gosling_context_test(
     println!("Alice endpoint server starting");
     alice.endpoint_server_start(
         alice_endpoint_private_key,
-        "test_endpoint".to_string(),
+        "test_endpoint".parse()?,
         pat_service_id.clone(),
         pat_auth_public_key.clone(),
+        false,
     )?;
     {
         let mut alice_endpoint_server_published: bool = false;
@@ -431,7 +470,7 @@ fn gosling_context_test(
             match pat.endpoint_client_begin_handshake(
                 alice_endpoint_service_id.clone(),
                 pat_auth_private_key.clone(),
-                "test_channel".to_string(),
+                "test_channel".parse()?,
             ) {
                 Ok(handle) => {
                     pat_endpoint_handshake_handle = handle;
@@ -456,6 +495,7 @@ fn gosling_context_test(
     println!("Alice waits for endpoint handshake to start");
     {
         let mut alice_endpoint_server_request_recieved: bool = false;
+        let mut pat_client_auth_added: bool = false;
         while !alice_endpoint_server_request_recieved {
             for event in alice.update()?.drain(..) {
                 match event {
@@ -480,11 +520,18 @@ fn gosling_context_test(
             }
             for event in pat.update()?.drain(..) {
                 match event {
+                    ContextEvent::ClientAuthAdded {
+                        endpoint_service_id,
+                    } => {
+                        assert_eq!(endpoint_service_id, alice_endpoint_service_id);
+                        pat_client_auth_added = true;
+                    }
                     ContextEvent::TorLogReceived { line: _ } => (),
                     evt => bail!("pat.update() returned unexpected event: {:?}", evt),
                 }
             }
         }
+        assert!(pat_client_auth_added);
 
         // Alice sends handshake response
         println!("Alice sends endpoint handshake response");
@@ -513,8 +560,13 @@ fn gosling_context_test(
                         client_service_id,
                         channel_name,
                         stream,
+                        capabilities,
+                        stats,
                     } => {
                         assert_eq!(handle, alice_endpoint_server_handshake_handle);
+                        assert_eq!(capabilities, Capabilities::all());
+                        assert_eq!(stats.round_trips, 2);
+                        assert!(stats.bytes_sent > 0 && stats.bytes_received > 0);
                         assert_eq!(endpoint_service_id, alice_endpoint_service_id);
                         assert_eq!(client_service_id, pat_service_id);
                         assert_eq!(channel_name, "test_channel");
@@ -532,8 +584,13 @@ fn gosling_context_test(
                         endpoint_service_id,
                         channel_name,
                         stream,
+                        capabilities,
+                        stats,
                     } => {
                         assert_eq!(handle, pat_endpoint_handshake_handle);
+                        assert_eq!(capabilities, Capabilities::all());
+                        assert_eq!(stats.round_trips, 2);
+                        assert!(stats.bytes_sent > 0 && stats.bytes_received > 0);
                         assert_eq!(endpoint_service_id, alice_endpoint_service_id);
                         assert_eq!(channel_name, "test_channel");
                         pat_client_stream = Some(stream);
@@ -562,5 +619,1013 @@ fn gosling_context_test(
 
     println!("TcpStream communication succesful");
 
+    // Pat removes the client auth key added for the endpoint handshake
+    assert_eq!(
+        pat.client_auth_entries(),
+        vec![alice_endpoint_service_id.clone()]
+    );
+    pat.remove_client_auth(&alice_endpoint_service_id);
+    let mut pat_client_auth_removed: bool = false;
+    for event in pat.update()?.drain(..) {
+        match event {
+            ContextEvent::ClientAuthRemoved {
+                endpoint_service_id,
+            } => {
+                assert_eq!(endpoint_service_id, alice_endpoint_service_id);
+                pat_client_auth_removed = true;
+            }
+            ContextEvent::TorLogReceived { line: _ } => (),
+            evt => bail!("pat.update() returned unexpected event: {:?}", evt),
+        }
+    }
+    assert!(pat_client_auth_removed);
+    assert!(pat.client_auth_entries().is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn test_mock_client_outbound_queue() -> anyhow::Result<()> {
+    let alice_private_key = Ed25519PrivateKey::generate();
+    let alice_service_id = V3OnionServiceId::from_private_key(&alice_private_key);
+    let mut alice = Context::new(
+        Box::new(MockTorClient::new()),
+        420,
+        420,
+        std::time::Duration::from_secs(60),
+        4096,
+        None,
+        alice_private_key,
+    )?;
+    let mut pat = Context::new(
+        Box::new(MockTorClient::new()),
+        420,
+        420,
+        std::time::Duration::from_secs(60),
+        4096,
+        None,
+        Ed25519PrivateKey::generate(),
+    )?;
+
+    // Bootstrap Alice and Pat
+    for context in [&mut alice, &mut pat] {
+        context.bootstrap()?;
+        let mut bootstrap_complete = false;
+        while !bootstrap_complete {
+            for event in context.update()?.drain(..) {
+                if let ContextEvent::TorBootstrapCompleted = event {
+                    bootstrap_complete = true;
+                }
+            }
+        }
+    }
+
+    // Start the Alice identity server
+    alice.identity_server_start()?;
+    let mut alice_identity_published: bool = false;
+    while !alice_identity_published {
+        for event in alice.update()?.drain(..) {
+            if let ContextEvent::IdentityServerPublished = event {
+                alice_identity_published = true;
+            }
+        }
+    }
+
+    // Pat may only have one outgoing handshake at a time
+    assert!(pat.set_max_outbound_connections(Some(0)).is_err());
+    pat.set_max_outbound_connections(Some(1))?;
+
+    let first_handle =
+        pat.identity_client_begin_handshake(alice_service_id.clone(), "test_endpoint".parse()?)?;
+    let second_handle =
+        pat.identity_client_begin_handshake(alice_service_id.clone(), "test_endpoint".parse()?)?;
+    let third_handle =
+        pat.identity_client_begin_handshake(alice_service_id.clone(), "test_endpoint".parse()?)?;
+
+    // only queued handshakes may be re-prioritised
+    assert!(pat
+        .set_outbound_handshake_priority(first_handle, 1)
+        .is_err());
+    pat.set_outbound_handshake_priority(third_handle, 1)?;
+
+    let mut queue_positions: Vec<(HandshakeHandle, usize)> = Default::default();
+    for event in pat.update()?.drain(..) {
+        match event {
+            ContextEvent::OutboundHandshakeQueued {
+                handle,
+                queue_position,
+            } => queue_positions.push((handle, queue_position)),
+            ContextEvent::TorLogReceived { line: _ } => (),
+            evt => bail!("pat.update() returned unexpected event: {:?}", evt),
+        }
+    }
+    assert_eq!(queue_positions, [(third_handle, 0), (second_handle, 1)]);
+
+    // aborting the in-progress handshake frees a slot for the next queued handshake
+    pat.identity_client_abort_handshake(first_handle)?;
+
+    let mut queue_positions: Vec<(HandshakeHandle, usize)> = Default::default();
+    for event in pat.update()?.drain(..) {
+        match event {
+            ContextEvent::OutboundHandshakeQueued {
+                handle,
+                queue_position,
+            } => queue_positions.push((handle, queue_position)),
+            ContextEvent::TorLogReceived { line: _ } => (),
+            evt => bail!("pat.update() returned unexpected event: {:?}", evt),
+        }
+    }
+    assert_eq!(queue_positions, [(second_handle, 0)]);
+
+    // queued handshakes may be aborted before they start
+    pat.identity_client_abort_handshake(second_handle)?;
+    assert!(pat.identity_client_abort_handshake(second_handle).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_mock_client_queue_until_bootstrapped() -> anyhow::Result<()> {
+    let alice_private_key = Ed25519PrivateKey::generate();
+    let alice_service_id = V3OnionServiceId::from_private_key(&alice_private_key);
+    let mut alice = Context::new(
+        Box::new(MockTorClient::new()),
+        420,
+        420,
+        std::time::Duration::from_secs(60),
+        4096,
+        None,
+        alice_private_key,
+    )?;
+    let mut pat = Context::new(
+        Box::new(MockTorClient::new()),
+        420,
+        420,
+        std::time::Duration::from_secs(60),
+        4096,
+        None,
+        Ed25519PrivateKey::generate(),
+    )?;
+
+    // Bootstrap Alice and start her identity server
+    alice.bootstrap()?;
+    let mut bootstrap_complete = false;
+    while !bootstrap_complete {
+        for event in alice.update()?.drain(..) {
+            if let ContextEvent::TorBootstrapCompleted = event {
+                bootstrap_complete = true;
+            }
+        }
+    }
+    alice.identity_server_start()?;
+    let mut alice_identity_published: bool = false;
+    while !alice_identity_published {
+        for event in alice.update()?.drain(..) {
+            if let ContextEvent::IdentityServerPublished = event {
+                alice_identity_published = true;
+            }
+        }
+    }
+
+    // handshakes begun before Pat has bootstrapped are rejected by default
+    assert!(matches!(
+        pat.identity_client_begin_handshake(alice_service_id.clone(), "test_endpoint".parse()?),
+        Err(gosling::context::Error::TorNotConnected())
+    ));
+
+    // and queued once enabled
+    pat.set_queue_until_bootstrapped(true);
+    let handle =
+        pat.identity_client_begin_handshake(alice_service_id.clone(), "test_endpoint".parse()?)?;
+    let aborted_handle =
+        pat.identity_client_begin_handshake(alice_service_id.clone(), "test_endpoint".parse()?)?;
+    let mut queue_positions: Vec<(HandshakeHandle, usize)> = Default::default();
+    for event in pat.update()?.drain(..) {
+        match event {
+            ContextEvent::OutboundHandshakeQueued {
+                handle,
+                queue_position,
+            } => queue_positions.push((handle, queue_position)),
+            ContextEvent::TorLogReceived { line: _ } => (),
+            evt => bail!("pat.update() returned unexpected event: {:?}", evt),
+        }
+    }
+    assert_eq!(queue_positions, [(handle, 0), (aborted_handle, 1)]);
+    pat.identity_client_abort_handshake(aborted_handle)?;
+
+    // the queued handshake starts once Pat has bootstrapped
+    pat.bootstrap()?;
+    let mut started: Vec<HandshakeHandle> = Default::default();
+    let mut bootstrap_complete = false;
+    while !bootstrap_complete {
+        for event in pat.update()?.drain(..) {
+            match event {
+                ContextEvent::TorBootstrapCompleted => bootstrap_complete = true,
+                ContextEvent::OutboundHandshakeStarted { handle } => {
+                    assert!(bootstrap_complete);
+                    started.push(handle);
+                }
+                ContextEvent::OutboundHandshakeQueued { .. } => {
+                    bail!("handshake queued after bootstrap")
+                }
+                _ => (),
+            }
+        }
+    }
+    assert_eq!(started, [handle]);
+    pat.identity_client_abort_handshake(handle)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_mock_client_identity_server_negative_ttl() -> anyhow::Result<()> {
+    let mut pat = Context::new(
+        Box::new(MockTorClient::new()),
+        420,
+        420,
+        std::time::Duration::from_secs(60),
+        4096,
+        None,
+        Ed25519PrivateKey::generate(),
+    )?;
+
+    pat.bootstrap()?;
+    let mut bootstrap_complete = false;
+    while !bootstrap_complete {
+        for event in pat.update()?.drain(..) {
+            if let ContextEvent::TorBootstrapCompleted = event {
+                bootstrap_complete = true;
+            }
+        }
+    }
+
+    // an identity server which was never published
+    let unreachable_service_id = V3OnionServiceId::from_private_key(&Ed25519PrivateKey::generate());
+    pat.set_identity_server_negative_ttl(Some(std::time::Duration::from_secs(60)));
+
+    // the first failure is returned directly and remembered
+    assert!(pat
+        .identity_client_begin_handshake(unreachable_service_id.clone(), "test_endpoint".parse()?)
+        .is_err());
+
+    // the retry is suppressed rather than dialed
+    let suppressed_handle = pat.identity_client_begin_handshake(
+        unreachable_service_id.clone(),
+        "test_endpoint".parse()?,
+    )?;
+    let mut suppressed = false;
+    for event in pat.update()?.drain(..) {
+        match event {
+            ContextEvent::IdentityClientHandshakeSuppressed {
+                handle,
+                identity_service_id,
+                retry_after,
+            } => {
+                assert_eq!(handle, suppressed_handle);
+                assert_eq!(identity_service_id, unreachable_service_id);
+                assert!(retry_after <= std::time::Duration::from_secs(60));
+                suppressed = true;
+            }
+            ContextEvent::TorLogReceived { line: _ } => (),
+            evt => bail!("pat.update() returned unexpected event: {:?}", evt),
+        }
+    }
+    assert!(suppressed);
+
+    // the cached failure may be bypassed
+    assert!(pat
+        .identity_client_begin_handshake_with_options(
+            unreachable_service_id.clone(),
+            "test_endpoint".parse()?,
+            true
+        )
+        .is_err());
+
+    // forgetting failures re-enables dialing
+    pat.set_identity_server_negative_ttl(None);
+    assert!(pat
+        .identity_client_begin_handshake(unreachable_service_id, "test_endpoint".parse()?)
+        .is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_mock_client_identity_server_negative_ttl_expiry() -> anyhow::Result<()> {
+    let mut pat = Context::new(
+        Box::new(MockTorClient::new()),
+        420,
+        420,
+        std::time::Duration::from_secs(60),
+        4096,
+        None,
+        Ed25519PrivateKey::generate(),
+    )?;
+    // time only moves when the test advances it
+    let clock = Arc::new(VirtualClock::new());
+    pat.set_clock(clock.clone());
+
+    pat.bootstrap()?;
+    let mut bootstrap_complete = false;
+    while !bootstrap_complete {
+        for event in pat.update()?.drain(..) {
+            if let ContextEvent::TorBootstrapCompleted = event {
+                bootstrap_complete = true;
+            }
+        }
+    }
+
+    let unreachable_service_id = V3OnionServiceId::from_private_key(&Ed25519PrivateKey::generate());
+    pat.set_identity_server_negative_ttl(Some(std::time::Duration::from_secs(60)));
+    assert!(pat
+        .identity_client_begin_handshake(unreachable_service_id.clone(), "test_endpoint".parse()?)
+        .is_err());
+
+    // part way through the negative TTL the retry is suppressed for exactly the remainder
+    clock.advance(std::time::Duration::from_secs(45));
+    let suppressed_handle = pat.identity_client_begin_handshake(
+        unreachable_service_id.clone(),
+        "test_endpoint".parse()?,
+    )?;
+    let mut suppressed = false;
+    for event in pat.update()?.drain(..) {
+        match event {
+            ContextEvent::IdentityClientHandshakeSuppressed {
+                handle,
+                retry_after,
+                ..
+            } => {
+                assert_eq!(handle, suppressed_handle);
+                assert_eq!(retry_after, std::time::Duration::from_secs(15));
+                suppressed = true;
+            }
+            ContextEvent::TorLogReceived { line: _ } => (),
+            evt => bail!("pat.update() returned unexpected event: {:?}", evt),
+        }
+    }
+    assert!(suppressed);
+
+    // once the negative TTL has passed the identity server is dialed again
+    clock.advance(std::time::Duration::from_secs(15));
+    assert!(pat
+        .identity_client_begin_handshake(unreachable_service_id, "test_endpoint".parse()?)
+        .is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_mock_client_identity_client_endpoint_validator() -> anyhow::Result<()> {
+    let mut pat = Context::new(
+        Box::new(MockTorClient::new()),
+        420,
+        420,
+        std::time::Duration::from_secs(60),
+        4096,
+        None,
+        Ed25519PrivateKey::generate(),
+    )?;
+    pat.identity_client_set_endpoint_validator(|_identity_server_id, endpoint| {
+        if !endpoint.starts_with("test_") {
+            EndpointValidatorVerdict::Malformed
+        } else if endpoint == "test_unsupported" {
+            EndpointValidatorVerdict::Unsupported
+        } else {
+            EndpointValidatorVerdict::Allow
+        }
+    });
+    let identity_server_id = V3OnionServiceId::from_private_key(&Ed25519PrivateKey::generate());
+
+    // rejected endpoints are reported before tor is needed
+    assert!(matches!(
+        pat.identity_client_begin_handshake(identity_server_id.clone(), "endpoint".parse()?),
+        Err(gosling::context::Error::EndpointRejected(endpoint, EndpointValidatorVerdict::Malformed)) if endpoint == "endpoint"
+    ));
+    assert!(matches!(
+        pat.identity_client_begin_handshake_with_additional_endpoints(
+            identity_server_id.clone(),
+            "test_endpoint".parse()?,
+            vec!["test_unsupported".parse()?]
+        ),
+        Err(gosling::context::Error::EndpointRejected(endpoint, EndpointValidatorVerdict::Unsupported)) if endpoint == "test_unsupported"
+    ));
+
+    // allowed endpoints continue on to connect
+    assert!(matches!(
+        pat.identity_client_begin_handshake(identity_server_id.clone(), "test_endpoint".parse()?),
+        Err(gosling::context::Error::TorNotConnected())
+    ));
+
+    // all endpoints are allowed once the validator is cleared
+    pat.identity_client_clear_endpoint_validator();
+    assert!(matches!(
+        pat.identity_client_begin_handshake(identity_server_id, "endpoint".parse()?),
+        Err(gosling::context::Error::TorNotConnected())
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn test_mock_client_endpoint_upgrade() -> anyhow::Result<()> {
+    let alice_private_key = Ed25519PrivateKey::generate();
+    let alice_service_id = V3OnionServiceId::from_private_key(&alice_private_key);
+    let mut alice = Context::new(
+        Box::new(MockTorClient::new()),
+        420,
+        420,
+        std::time::Duration::from_secs(60),
+        4096,
+        None,
+        alice_private_key,
+    )?;
+    let mut pat = Context::new(
+        Box::new(MockTorClient::new()),
+        420,
+        420,
+        std::time::Duration::from_secs(60),
+        4096,
+        None,
+        Ed25519PrivateKey::generate(),
+    )?;
+
+    // Bootstrap Alice and Pat
+    for context in [&mut alice, &mut pat] {
+        context.bootstrap()?;
+        let mut bootstrap_complete = false;
+        while !bootstrap_complete {
+            for event in context.update()?.drain(..) {
+                if let ContextEvent::TorBootstrapCompleted = event {
+                    bootstrap_complete = true;
+                }
+            }
+        }
+    }
+
+    // Start the Alice identity server
+    alice.identity_server_set_endpoint_upgrade_allowed(true);
+    alice.identity_server_start()?;
+    let mut alice_identity_published: bool = false;
+    while !alice_identity_published {
+        for event in alice.update()?.drain(..) {
+            if let ContextEvent::IdentityServerPublished = event {
+                alice_identity_published = true;
+            }
+        }
+    }
+
+    // Pat requests an endpoint and a channel on it in one go
+    let pat_handle = pat.identity_client_begin_handshake_with_endpoint_upgrade(
+        alice_service_id,
+        "test_endpoint".parse()?,
+        "test_channel".parse()?,
+    )?;
+
+    let mut alice_server_stream: Option<TcpStream> = None;
+    let mut pat_client_stream: Option<TcpStream> = None;
+    let mut pat_identity_handshake_completed = false;
+    while alice_server_stream.is_none() || pat_client_stream.is_none() {
+        for event in alice.update()?.drain(..) {
+            match event {
+                ContextEvent::IdentityServerHandshakeStarted { handle: _ } => (),
+                ContextEvent::IdentityServerEndpointRequestReceived { handle, .. } => {
+                    alice.identity_server_handle_endpoint_request_received(
+                        handle,
+                        true,
+                        true,
+                        doc!(),
+                    )?;
+                }
+                ContextEvent::IdentityServerChallengeResponseReceived { handle, .. } => {
+                    alice.identity_server_handle_challenge_response_received(handle, true)?;
+                }
+                ContextEvent::IdentityServerHandshakeCompleted {
+                    handle: _,
+                    endpoint_private_key,
+                    endpoint_name,
+                    client_service_id,
+                    client_auth_public_key,
+                    additional_endpoints: _,
+                    contact_request: _,
+                    capabilities: _,
+                    stats: _,
+                } => {
+                    // the waiting connection is handed to the endpoint server once started
+                    alice.endpoint_server_start(
+                        endpoint_private_key,
+                        endpoint_name,
+                        client_service_id,
+                        client_auth_public_key,
+                        false,
+                    )?;
+                }
+                ContextEvent::EndpointServerPublished { .. } => (),
+                ContextEvent::EndpointServerHandshakeStarted { handle: _ } => (),
+                ContextEvent::EndpointServerChannelRequestReceived {
+                    handle,
+                    requested_channel,
+                    ..
+                } => {
+                    assert_eq!(requested_channel, "test_channel");
+                    alice.endpoint_server_handle_channel_request_received(handle, true)?;
+                }
+                ContextEvent::EndpointServerHandshakeCompleted {
+                    channel_name,
+                    stream,
+                    ..
+                } => {
+                    assert_eq!(channel_name, "test_channel");
+                    alice_server_stream = Some(stream);
+                }
+                ContextEvent::TorLogReceived { line: _ } => (),
+                evt => bail!("alice.update() returned unexpected event: {:?}", evt),
+            }
+        }
+        for event in pat.update()?.drain(..) {
+            match event {
+                ContextEvent::IdentityClientChallengeReceived { handle, .. } => {
+                    assert_eq!(handle, pat_handle);
+                    pat.identity_client_handle_challenge_received(handle, doc!())?;
+                }
+                ContextEvent::IdentityClientHandshakeCompleted { handle, .. } => {
+                    assert_eq!(handle, pat_handle);
+                    pat_identity_handshake_completed = true;
+                }
+                ContextEvent::EndpointClientHandshakeCompleted {
+                    handle,
+                    channel_name,
+                    stream,
+                    stats,
+                    ..
+                } => {
+                    // the endpoint handshake continues under the identity handshake's handle
+                    assert_eq!(handle, pat_handle);
+                    assert!(pat_identity_handshake_completed);
+                    assert_eq!(channel_name, "test_channel");
+                    // the identity handshake's round-trips are not counted
+                    assert_eq!(stats.round_trips, 2);
+                    pat_client_stream = Some(stream);
+                }
+                ContextEvent::TorLogReceived { line: _ } => (),
+                evt => bail!("pat.update() returned unexpected event: {:?}", evt),
+            }
+        }
+    }
+
+    // the upgraded connection carries application data as usual
+    let mut pat_client_stream = pat_client_stream.unwrap();
+    pat_client_stream.write_all(b"Hello World!\n")?;
+    pat_client_stream.flush()?;
+
+    let alice_server_stream = alice_server_stream.unwrap();
+    alice_server_stream.set_nonblocking(false)?;
+    let mut alice_reader = BufReader::new(alice_server_stream);
+
+    let mut response: String = Default::default();
+    alice_reader.read_line(&mut response)?;
+    assert_eq!(response, "Hello World!\n");
+
+    Ok(())
+}
+
+#[test]
+fn test_mock_client_endpoint_client_auth_rotation() -> anyhow::Result<()> {
+    let pat_private_key = Ed25519PrivateKey::generate();
+    let pat_service_id = V3OnionServiceId::from_private_key(&pat_private_key);
+    let mut alice = Context::new(
+        Box::new(MockTorClient::new()),
+        420,
+        420,
+        std::time::Duration::from_secs(60),
+        4096,
+        None,
+        Ed25519PrivateKey::generate(),
+    )?;
+    let mut pat = Context::new(
+        Box::new(MockTorClient::new()),
+        420,
+        420,
+        std::time::Duration::from_secs(60),
+        4096,
+        None,
+        pat_private_key,
+    )?;
+
+    // Bootstrap Alice and Pat
+    for context in [&mut alice, &mut pat] {
+        context.bootstrap()?;
+        let mut bootstrap_complete = false;
+        while !bootstrap_complete {
+            for event in context.update()?.drain(..) {
+                if let ContextEvent::TorBootstrapCompleted = event {
+                    bootstrap_complete = true;
+                }
+            }
+        }
+    }
+
+    // Start an Alice endpoint server for Pat
+    let endpoint_private_key = Ed25519PrivateKey::generate();
+    let endpoint_service_id = V3OnionServiceId::from_private_key(&endpoint_private_key);
+    let old_client_auth_private_key = X25519PrivateKey::generate();
+    let new_client_auth_private_key = X25519PrivateKey::generate();
+    alice.endpoint_server_start(
+        endpoint_private_key,
+        "test_endpoint".parse()?,
+        pat_service_id,
+        X25519PublicKey::from_private_key(&old_client_auth_private_key),
+        false,
+    )?;
+
+    let wait_endpoint_published = |alice: &mut Context| -> anyhow::Result<()> {
+        let mut endpoint_published = false;
+        while !endpoint_published {
+            for event in alice.update()?.drain(..) {
+                match event {
+                    ContextEvent::EndpointServerPublished { .. } => endpoint_published = true,
+                    ContextEvent::TorLogReceived { line: _ } => (),
+                    evt => bail!("alice.update() returned unexpected event: {:?}", evt),
+                }
+            }
+        }
+        Ok(())
+    };
+    wait_endpoint_published(&mut alice)?;
+
+    // at least one key is required and only running endpoint servers may be updated
+    assert!(alice
+        .endpoint_server_set_client_auth_keys(endpoint_service_id.clone(), Default::default())
+        .is_err());
+    assert!(alice
+        .endpoint_server_set_client_auth_keys(
+            V3OnionServiceId::from_private_key(&Ed25519PrivateKey::generate()),
+            vec![X25519PublicKey::from_private_key(
+                &new_client_auth_private_key
+            )],
+        )
+        .is_err());
+
+    // rotate Pat's key and wait for the updated descriptor to be published
+    alice.endpoint_server_set_client_auth_keys(
+        endpoint_service_id.clone(),
+        vec![X25519PublicKey::from_private_key(
+            &new_client_auth_private_key,
+        )],
+    )?;
+    wait_endpoint_published(&mut alice)?;
+
+    // the old key can no longer be used to connect
+    assert!(pat
+        .endpoint_client_begin_handshake(
+            endpoint_service_id.clone(),
+            old_client_auth_private_key,
+            "test_channel".parse()?,
+        )
+        .is_err());
+
+    // but the new key can, over the same endpoint server
+    let pat_handle = pat.endpoint_client_begin_handshake(
+        endpoint_service_id,
+        new_client_auth_private_key,
+        "test_channel".parse()?,
+    )?;
+
+    let mut alice_handshake_completed = false;
+    let mut pat_handshake_completed = false;
+    while !alice_handshake_completed || !pat_handshake_completed {
+        for event in alice.update()?.drain(..) {
+            match event {
+                ContextEvent::EndpointServerHandshakeStarted { handle: _ } => (),
+                ContextEvent::EndpointServerChannelRequestReceived { handle, .. } => {
+                    alice.endpoint_server_handle_channel_request_received(handle, true)?;
+                }
+                ContextEvent::EndpointServerHandshakeCompleted { channel_name, .. } => {
+                    assert_eq!(channel_name, "test_channel");
+                    alice_handshake_completed = true;
+                }
+                ContextEvent::TorLogReceived { line: _ } => (),
+                evt => bail!("alice.update() returned unexpected event: {:?}", evt),
+            }
+        }
+        for event in pat.update()?.drain(..) {
+            match event {
+                ContextEvent::ClientAuthAdded { .. } => (),
+                ContextEvent::ClientAuthAddFailed { .. } => (),
+                ContextEvent::EndpointClientHandshakeCompleted { handle, .. } => {
+                    assert_eq!(handle, pat_handle);
+                    pat_handshake_completed = true;
+                }
+                ContextEvent::TorLogReceived { line: _ } => (),
+                evt => bail!("pat.update() returned unexpected event: {:?}", evt),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_mock_client_endpoint_servers_reconfigure() -> anyhow::Result<()> {
+    let alice_private_key = Ed25519PrivateKey::generate();
+    let mut alice = Context::new(
+        Box::new(MockTorClient::new()),
+        420,
+        420,
+        std::time::Duration::from_secs(60),
+        4096,
+        None,
+        alice_private_key.clone(),
+    )?;
+
+    alice.bootstrap()?;
+    let mut bootstrap_complete = false;
+    while !bootstrap_complete {
+        for event in alice.update()?.drain(..) {
+            if let ContextEvent::TorBootstrapCompleted = event {
+                bootstrap_complete = true;
+            }
+        }
+    }
+
+    let endpoint_config = |endpoint_name: &str| EndpointConfig {
+        private_key: Ed25519PrivateKey::generate(),
+        endpoint_name: endpoint_name.parse().unwrap(),
+        client_identity: V3OnionServiceId::from_private_key(&Ed25519PrivateKey::generate()),
+        client_auth_keys: vec![X25519PublicKey::from_private_key(
+            &X25519PrivateKey::generate(),
+        )],
+        non_anonymous: false,
+        pow_defenses: None,
+        shared: false,
+    };
+    let service_id =
+        |config: &EndpointConfig| V3OnionServiceId::from_private_key(&config.private_key);
+
+    // start a batch of endpoint servers; invalid ones fail individually
+    let first = endpoint_config("first");
+    let mut second = endpoint_config("second");
+    let mut identity = endpoint_config("identity");
+    identity.private_key = alice_private_key;
+    let results = alice.endpoint_servers_start(vec![
+        first.clone(),
+        second.clone(),
+        first.clone(),
+        identity,
+    ])?;
+    assert_eq!(results.len(), 4);
+    assert_eq!(results[0].0, service_id(&first));
+    assert!(results[0].1.is_ok());
+    assert_eq!(results[1].0, service_id(&second));
+    assert!(results[1].1.is_ok());
+    assert!(matches!(
+        results[2].1,
+        Err(gosling::context::Error::ListenerConflict(_, 420, _))
+    ));
+    assert!(matches!(
+        results[3].1,
+        Err(gosling::context::Error::ListenerConflict(_, 420, _))
+    ));
+
+    let mut published: Vec<V3OnionServiceId> = Default::default();
+    while published.len() < 2 {
+        for event in alice.update()?.drain(..) {
+            match event {
+                ContextEvent::EndpointServerPublished {
+                    endpoint_service_id,
+                    ..
+                } => published.push(endpoint_service_id),
+                ContextEvent::TorLogReceived { line: _ } => (),
+                evt => bail!("alice.update() returned unexpected event: {:?}", evt),
+            }
+        }
+    }
+
+    // unchanged endpoint servers are left alone, changed client auth keys are
+    // replaced and new endpoint servers are started
+    second.client_auth_keys = vec![X25519PublicKey::from_private_key(
+        &X25519PrivateKey::generate(),
+    )];
+    let third = endpoint_config("third");
+    let results =
+        alice.endpoint_servers_reconfigure(vec![first.clone(), second.clone(), third.clone()])?;
+    let changed: Vec<V3OnionServiceId> = results
+        .iter()
+        .map(|(endpoint_service_id, result)| {
+            assert!(result.is_ok());
+            endpoint_service_id.clone()
+        })
+        .collect();
+    assert_eq!(changed, [service_id(&second), service_id(&third)]);
+
+    // invalid configurations change nothing
+    assert!(alice
+        .endpoint_servers_reconfigure(vec![third.clone(), third.clone()])
+        .is_err());
+
+    // removed endpoint servers are stopped and renamed ones restarted
+    let mut renamed = third.clone();
+    renamed.endpoint_name = "renamed".parse()?;
+    let results = alice.endpoint_servers_reconfigure(vec![renamed.clone()])?;
+    assert_eq!(results.len(), 3);
+    assert!(results.iter().all(|(_, result)| result.is_ok()));
+    assert_eq!(results[2].0, service_id(&third));
+
+    // as are endpoint servers whose proof-of-work defenses change
+    renamed.pow_defenses = Some(PowDefenses {
+        queue_rate: Some(250),
+        queue_burst: None,
+    });
+    let results = alice.endpoint_servers_reconfigure(vec![renamed.clone()])?;
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].0, service_id(&third));
+    assert!(results[0].1.is_ok());
+    assert!(alice
+        .endpoint_servers_reconfigure(vec![renamed])?
+        .is_empty());
+
+    assert_eq!(alice.endpoint_servers_stop_all()?, [service_id(&third)]);
+    assert!(alice.endpoint_servers_stop_all()?.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn test_mock_client_tor_provider_switch() -> anyhow::Result<()> {
+    let pat_private_key = Ed25519PrivateKey::generate();
+    let pat_service_id = V3OnionServiceId::from_private_key(&pat_private_key);
+    let mut alice = Context::new(
+        Box::new(MockTorClient::new()),
+        420,
+        420,
+        std::time::Duration::from_secs(60),
+        4096,
+        None,
+        Ed25519PrivateKey::generate(),
+    )?;
+    let mut pat = Context::new(
+        Box::new(MockTorClient::new()),
+        420,
+        420,
+        std::time::Duration::from_secs(60),
+        4096,
+        None,
+        pat_private_key,
+    )?;
+
+    // Bootstrap Alice and Pat
+    for context in [&mut alice, &mut pat] {
+        context.bootstrap()?;
+        let mut bootstrap_complete = false;
+        while !bootstrap_complete {
+            for event in context.update()?.drain(..) {
+                if let ContextEvent::TorBootstrapCompleted = event {
+                    bootstrap_complete = true;
+                }
+            }
+        }
+    }
+
+    // Start Alice's identity server and an endpoint server for Pat
+    let endpoint_private_key = Ed25519PrivateKey::generate();
+    let endpoint_service_id = V3OnionServiceId::from_private_key(&endpoint_private_key);
+    let client_auth_private_key = X25519PrivateKey::generate();
+    alice.identity_server_start()?;
+    alice.endpoint_server_start(
+        endpoint_private_key,
+        "test_endpoint".parse()?,
+        pat_service_id,
+        X25519PublicKey::from_private_key(&client_auth_private_key),
+        false,
+    )?;
+
+    // wait for Alice's servers to be published, and optionally for a tor provider switch to complete
+    let wait_servers_published = |alice: &mut Context, switching: bool| -> anyhow::Result<()> {
+        let mut identity_published = false;
+        let mut endpoint_published = false;
+        let mut provider_changed = !switching;
+        while !identity_published || !endpoint_published || !provider_changed {
+            for event in alice.update()?.drain(..) {
+                match event {
+                    ContextEvent::IdentityServerPublished => identity_published = true,
+                    ContextEvent::EndpointServerPublished { .. } => endpoint_published = true,
+                    ContextEvent::TorProviderChanged => provider_changed = true,
+                    ContextEvent::TorBootstrapStatusReceived { .. } => assert!(switching),
+                    ContextEvent::TorBootstrapCompleted => assert!(switching),
+                    ContextEvent::TorLogReceived { line: _ } => (),
+                    evt => bail!("alice.update() returned unexpected event: {:?}", evt),
+                }
+            }
+        }
+        Ok(())
+    };
+    wait_servers_published(&mut alice, false)?;
+
+    // Alice switches to a new tor provider; only one switch may be in progress
+    alice.set_tor_provider(Box::new(MockTorClient::new()))?;
+    assert!(alice
+        .set_tor_provider(Box::new(MockTorClient::new()))
+        .is_err());
+    wait_servers_published(&mut alice, true)?;
+
+    // Pat can still connect to Alice's restarted endpoint server
+    let pat_handle = pat.endpoint_client_begin_handshake(
+        endpoint_service_id.clone(),
+        client_auth_private_key,
+        "test_channel".parse()?,
+    )?;
+
+    let mut alice_handshake_completed = false;
+    let mut pat_handshake_completed = false;
+    while !alice_handshake_completed || !pat_handshake_completed {
+        for event in alice.update()?.drain(..) {
+            match event {
+                ContextEvent::EndpointServerHandshakeStarted { handle: _ } => (),
+                ContextEvent::EndpointServerChannelRequestReceived { handle, .. } => {
+                    alice.endpoint_server_handle_channel_request_received(handle, true)?;
+                }
+                ContextEvent::EndpointServerHandshakeCompleted { channel_name, .. } => {
+                    assert_eq!(channel_name, "test_channel");
+                    alice_handshake_completed = true;
+                }
+                ContextEvent::TorLogReceived { line: _ } => (),
+                evt => bail!("alice.update() returned unexpected event: {:?}", evt),
+            }
+        }
+        for event in pat.update()?.drain(..) {
+            match event {
+                ContextEvent::ClientAuthAdded { .. } => (),
+                ContextEvent::EndpointClientHandshakeCompleted { handle, .. } => {
+                    assert_eq!(handle, pat_handle);
+                    pat_handshake_completed = true;
+                }
+                ContextEvent::TorLogReceived { line: _ } => (),
+                evt => bail!("pat.update() returned unexpected event: {:?}", evt),
+            }
+        }
+    }
+
+    // Pat switches tor provider too, and its client auth key is re-added
+    pat.set_tor_provider(Box::new(MockTorClient::new()))?;
+    let mut pat_client_auth_added = false;
+    let mut pat_provider_changed = false;
+    while !pat_provider_changed {
+        for event in pat.update()?.drain(..) {
+            match event {
+                ContextEvent::ClientAuthAdded {
+                    endpoint_service_id: added_service_id,
+                } => {
+                    assert_eq!(added_service_id, endpoint_service_id);
+                    pat_client_auth_added = true;
+                }
+                ContextEvent::TorProviderChanged => pat_provider_changed = true,
+                ContextEvent::TorBootstrapStatusReceived { .. } => (),
+                ContextEvent::TorBootstrapCompleted => (),
+                ContextEvent::TorLogReceived { line: _ } => (),
+                evt => bail!("pat.update() returned unexpected event: {:?}", evt),
+            }
+        }
+    }
+    assert!(pat_client_auth_added);
+    assert_eq!(pat.client_auth_entries(), vec![endpoint_service_id]);
+
+    Ok(())
+}
+
+#[test]
+fn test_mock_client_tor_log_buffer() -> anyhow::Result<()> {
+    let mut alice = Context::new(
+        Box::new(MockTorClient::new()),
+        420,
+        420,
+        std::time::Duration::from_secs(60),
+        4096,
+        None,
+        Ed25519PrivateKey::generate(),
+    )?;
+    assert!(matches!(
+        alice.set_tor_log_buffer(Some(0)),
+        Err(gosling::context::Error::InvalidArgument(_))
+    ));
+    alice.set_tor_log_buffer(Some(16))?;
+
+    // log lines are buffered rather than returned as events
+    alice.bootstrap()?;
+    let mut bootstrap_complete = false;
+    while !bootstrap_complete {
+        for event in alice.update()?.drain(..) {
+            match event {
+                ContextEvent::TorBootstrapStatusReceived { .. } => (),
+                ContextEvent::TorBootstrapCompleted => bootstrap_complete = true,
+                evt => bail!("alice.update() returned unexpected event: {:?}", evt),
+            }
+        }
+    }
+    assert_eq!(
+        alice.take_tor_log_lines(),
+        ["[notice] MockTorClient running"]
+    );
+    assert!(alice.take_tor_log_lines().is_empty());
+
     Ok(())
 }
@@ -0,0 +1,3130 @@
+// standard
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// extern crates
+use anyhow::bail;
+use bson::doc;
+use honk_rpc::honk_rpc::{ApiSet, ErrorCode, RequestCookie, Response};
+use tor_interface::clock::{Clock, VirtualClock};
+use tor_interface::mock_tor_client::*;
+use tor_interface::tor_crypto::*;
+use tor_interface::tor_provider::SecurityLevel;
+
+// internal crates
+use gosling::ascii_string::AsciiString;
+use gosling::bans::BanList;
+use gosling::channel_quota::{ChannelQuota, ChannelQuotaExceeded};
+use gosling::connectivity::{ConnectivityReport, DescriptorFetchOutcome};
+use gosling::context::*;
+use gosling::datagram_channel::DatagramChannel;
+use gosling::endpoint_revocation::EndpointRevocation;
+use gosling::events_sink::ContextEventsSink;
+use gosling::petnames::PetnameStore;
+use gosling::pinning::{PinStore, PinVerdict};
+use gosling::protocol::{Capabilities, MAX_CHANNEL_NAME_LENGTH, MAX_ENDPOINT_NAME_LENGTH};
+use gosling::rpc_channel::{self, Heartbeat, RpcChannel, RpcChannelEvent};
+use gosling::timing::{EndpointRequestStats, HandshakeStats};
+
+// how long a test may wait for its expected events before failing
+const TEST_DEADLINE: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Copy, Debug)]
+enum Peer {
+    Alice,
+    Pat,
+}
+
+// Alice runs an identity server (and any endpoint servers it grants) while
+// Pat connects to it as a client; both communicate over the mock tor network
+struct MockPeers {
+    alice: Context,
+    alice_private_key: Ed25519PrivateKey,
+    alice_service_id: V3OnionServiceId,
+    pat: Context,
+    pat_service_id: V3OnionServiceId,
+    // events returned from update() but not yet handled
+    pending_events: VecDeque<(Peer, ContextEvent)>,
+}
+
+impl MockPeers {
+    fn new() -> anyhow::Result<Self> {
+        Self::with_timeouts(Duration::from_secs(60), None)
+    }
+
+    fn with_timeouts(
+        identity_timeout: Duration,
+        endpoint_timeout: Option<Duration>,
+    ) -> anyhow::Result<Self> {
+        Self::with_config(420, 420, identity_timeout, endpoint_timeout)
+    }
+
+    // bootstrap Alice and Pat and publish Alice's identity server
+    fn with_config(
+        identity_port: u16,
+        endpoint_port: u16,
+        identity_timeout: Duration,
+        endpoint_timeout: Option<Duration>,
+    ) -> anyhow::Result<Self> {
+        let alice_private_key = Ed25519PrivateKey::generate();
+        let alice_service_id = V3OnionServiceId::from_private_key(&alice_private_key);
+        let alice = Context::new(
+            Box::new(MockTorClient::new()),
+            identity_port,
+            endpoint_port,
+            identity_timeout,
+            4096,
+            endpoint_timeout,
+            alice_private_key.clone(),
+        )?;
+
+        let pat_private_key = Ed25519PrivateKey::generate();
+        let pat_service_id = V3OnionServiceId::from_private_key(&pat_private_key);
+        let pat = Context::new(
+            Box::new(MockTorClient::new()),
+            identity_port,
+            endpoint_port,
+            identity_timeout,
+            4096,
+            endpoint_timeout,
+            pat_private_key,
+        )?;
+
+        let mut peers = Self {
+            alice,
+            alice_private_key,
+            alice_service_id,
+            pat,
+            pat_service_id,
+            pending_events: Default::default(),
+        };
+
+        peers.alice.bootstrap()?;
+        peers.pat.bootstrap()?;
+        let mut alice_bootstrapped = false;
+        let mut pat_bootstrapped = false;
+        peers.run_until(|peer, _context, event| {
+            match (peer, event) {
+                (_, ContextEvent::TorBootstrapStatusReceived { .. }) => (),
+                (Peer::Alice, ContextEvent::TorBootstrapCompleted) => alice_bootstrapped = true,
+                (Peer::Pat, ContextEvent::TorBootstrapCompleted) => pat_bootstrapped = true,
+                (peer, event) => return unexpected_event(peer, event),
+            }
+            Ok(alice_bootstrapped && pat_bootstrapped)
+        })?;
+
+        peers.alice.identity_server_start()?;
+        peers.run_until(|peer, _context, event| match (peer, event) {
+            (Peer::Alice, ContextEvent::IdentityServerPublished) => Ok(true),
+            (peer, event) => unexpected_event(peer, event),
+        })?;
+
+        Ok(peers)
+    }
+
+    // update both peers and pass each of their events (other than tor logs) to
+    // handler along with the context which returned it until handler returns true
+    fn run_until<F>(&mut self, mut handler: F) -> anyhow::Result<()>
+    where
+        F: FnMut(Peer, &mut Context, ContextEvent) -> anyhow::Result<bool>,
+    {
+        let start = Instant::now();
+        loop {
+            while let Some((peer, event)) = self.pending_events.pop_front() {
+                let context = match peer {
+                    Peer::Alice => &mut self.alice,
+                    Peer::Pat => &mut self.pat,
+                };
+                if handler(peer, context, event)? {
+                    return Ok(());
+                }
+            }
+
+            if start.elapsed() > TEST_DEADLINE {
+                bail!("expected events not received within {:?}", TEST_DEADLINE);
+            }
+
+            for (peer, context) in [(Peer::Alice, &mut self.alice), (Peer::Pat, &mut self.pat)] {
+                for event in context.update()?.drain(..) {
+                    if !matches!(event, ContextEvent::TorLogReceived { .. }) {
+                        self.pending_events.push_back((peer, event));
+                    }
+                }
+            }
+        }
+    }
+
+    // complete an identity handshake and publish the endpoint server Alice
+    // grants to Pat, returning the endpoint's service id and Pat's client auth key
+    fn grant_endpoint(&mut self) -> anyhow::Result<(V3OnionServiceId, X25519PrivateKey)> {
+        let pat_handle = self.pat.identity_client_begin_handshake(
+            self.alice_service_id.clone(),
+            "test_endpoint".parse()?,
+        )?;
+
+        let mut endpoint_published = false;
+        let mut granted_endpoint: Option<(V3OnionServiceId, X25519PrivateKey)> = None;
+        self.run_until(|peer, context, event| {
+            match (peer, event) {
+                (Peer::Alice, ContextEvent::IdentityServerHandshakeStarted { .. }) => (),
+                (
+                    Peer::Alice,
+                    ContextEvent::IdentityServerEndpointRequestReceived { handle, .. },
+                ) => {
+                    context.identity_server_handle_endpoint_request_received(
+                        handle,
+                        true,
+                        true,
+                        doc!(),
+                    )?;
+                }
+                (
+                    Peer::Alice,
+                    ContextEvent::IdentityServerChallengeResponseReceived { handle, .. },
+                ) => {
+                    context.identity_server_handle_challenge_response_received(handle, true)?;
+                }
+                (
+                    Peer::Alice,
+                    ContextEvent::IdentityServerHandshakeCompleted {
+                        endpoint_private_key,
+                        endpoint_name,
+                        client_service_id,
+                        client_auth_public_key,
+                        ..
+                    },
+                ) => {
+                    context.endpoint_server_start(
+                        endpoint_private_key,
+                        endpoint_name,
+                        client_service_id,
+                        client_auth_public_key,
+                        false,
+                    )?;
+                }
+                (Peer::Alice, ContextEvent::EndpointServerPublished { .. }) => {
+                    endpoint_published = true;
+                }
+                (Peer::Pat, ContextEvent::IdentityClientChallengeReceived { handle, .. }) => {
+                    assert_eq!(handle, pat_handle);
+                    context.identity_client_handle_challenge_received(handle, doc!())?;
+                }
+                (
+                    Peer::Pat,
+                    ContextEvent::IdentityClientHandshakeCompleted {
+                        handle,
+                        endpoint_service_id,
+                        client_auth_private_key,
+                        ..
+                    },
+                ) => {
+                    assert_eq!(handle, pat_handle);
+                    granted_endpoint = Some((endpoint_service_id, client_auth_private_key));
+                }
+                (peer, event) => return unexpected_event(peer, event),
+            }
+            Ok(endpoint_published && granted_endpoint.is_some())
+        })?;
+
+        Ok(granted_endpoint.unwrap())
+    }
+}
+
+fn unexpected_event(peer: Peer, event: ContextEvent) -> anyhow::Result<bool> {
+    bail!("{:?} received unexpected event: {:?}", peer, event)
+}
+
+//
+// Identity Handshake
+//
+
+// run an identity handshake which Alice rejects for the given reason
+fn identity_handshake_rejected_test(
+    client_allowed: bool,
+    endpoint_supported: bool,
+    challenge_response_valid: bool,
+    rejection_reason: HandshakeRejectionReason,
+) -> anyhow::Result<()> {
+    let mut peers = MockPeers::new()?;
+    let pat_service_id = peers.pat_service_id.clone();
+    let pat_handle = peers.pat.identity_client_begin_handshake(
+        peers.alice_service_id.clone(),
+        "test_endpoint".parse()?,
+    )?;
+
+    let mut alice_handle: Option<HandshakeHandle> = None;
+    let mut alice_rejected = false;
+    let mut pat_failed = false;
+    peers.run_until(|peer, context, event| {
+        match (peer, event) {
+            (Peer::Alice, ContextEvent::IdentityServerHandshakeStarted { handle }) => {
+                alice_handle = Some(handle);
+            }
+            (
+                Peer::Alice,
+                ContextEvent::IdentityServerEndpointRequestReceived {
+                    handle,
+                    client_service_id,
+                    requested_endpoint,
+                    additional_endpoints,
+                    contact_request,
+                },
+            ) => {
+                assert_eq!(Some(handle), alice_handle);
+                assert_eq!(client_service_id, pat_service_id);
+                assert_eq!(requested_endpoint, "test_endpoint");
+                assert!(additional_endpoints.is_empty());
+                assert!(contact_request.is_none());
+                context.identity_server_handle_endpoint_request_received(
+                    handle,
+                    client_allowed,
+                    endpoint_supported,
+                    doc!(),
+                )?;
+            }
+            (Peer::Alice, ContextEvent::IdentityServerChallengeResponseReceived { handle, .. }) => {
+                assert_eq!(Some(handle), alice_handle);
+                context.identity_server_handle_challenge_response_received(
+                    handle,
+                    challenge_response_valid,
+                )?;
+            }
+            (
+                Peer::Alice,
+                ContextEvent::IdentityServerHandshakeRejected {
+                    handle,
+                    client_allowed: rejected_client_allowed,
+                    client_requested_endpoint_valid,
+                    client_proof_signature_valid,
+                    client_auth_signature_valid,
+                    challenge_response_valid: rejected_challenge_response_valid,
+                },
+            ) => {
+                assert_eq!(Some(handle), alice_handle);
+                assert_eq!(rejected_client_allowed, client_allowed);
+                assert_eq!(client_requested_endpoint_valid, endpoint_supported);
+                assert!(client_proof_signature_valid);
+                assert!(client_auth_signature_valid);
+                assert_eq!(rejected_challenge_response_valid, challenge_response_valid);
+                alice_rejected = true;
+            }
+            (Peer::Pat, ContextEvent::IdentityClientChallengeReceived { handle, .. }) => {
+                assert_eq!(handle, pat_handle);
+                context.identity_client_handle_challenge_received(handle, doc!())?;
+            }
+            (Peer::Pat, ContextEvent::IdentityClientHandshakeFailed { handle, reason, .. }) => {
+                assert_eq!(handle, pat_handle);
+                match reason {
+                    gosling::context::Error::IdentityHandshakeRejected(reason) => {
+                        assert_eq!(reason, rejection_reason)
+                    }
+                    reason => bail!("unexpected failure reason: {:?}", reason),
+                }
+                pat_failed = true;
+            }
+            (peer, event) => return unexpected_event(peer, event),
+        }
+        Ok(alice_rejected && pat_failed)
+    })?;
+
+    // the rejection is attributed to the requested endpoint
+    assert_eq!(
+        peers
+            .alice
+            .identity_server_stats()
+            .endpoint("test_endpoint"),
+        EndpointRequestStats {
+            requests: 1,
+            rejections: 1,
+            client_not_allowed: u64::from(!client_allowed),
+            requested_endpoint_invalid: u64::from(!endpoint_supported),
+            challenge_response_invalid: u64::from(!challenge_response_valid),
+            ..Default::default()
+        }
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_mock_identity_handshake_client_not_allowed() -> anyhow::Result<()> {
+    identity_handshake_rejected_test(false, true, true, HandshakeRejectionReason::NotAuthorized)
+}
+
+#[test]
+fn test_mock_identity_handshake_endpoint_not_supported() -> anyhow::Result<()> {
+    identity_handshake_rejected_test(
+        true,
+        false,
+        true,
+        HandshakeRejectionReason::EndpointUnsupported,
+    )
+}
+
+#[test]
+fn test_mock_identity_handshake_challenge_response_invalid() -> anyhow::Result<()> {
+    identity_handshake_rejected_test(true, true, false, HandshakeRejectionReason::ChallengeFailed)
+}
+
+#[test]
+fn test_mock_identity_handshake_additional_endpoints() -> anyhow::Result<()> {
+    let mut peers = MockPeers::new()?;
+    let pat_handle = peers
+        .pat
+        .identity_client_begin_handshake_with_additional_endpoints(
+            peers.alice_service_id.clone(),
+            "test_endpoint".parse()?,
+            vec!["files".parse()?, "voice".parse()?],
+        )?;
+
+    // Alice grants "files" but not "voice"
+    let mut alice_grants: Option<Vec<(AsciiString, Ed25519PrivateKey)>> = None;
+    let mut pat_grants: Option<Vec<(AsciiString, Option<V3OnionServiceId>)>> = None;
+    peers.run_until(|peer, context, event| {
+        match (peer, event) {
+            (Peer::Alice, ContextEvent::IdentityServerHandshakeStarted { .. }) => (),
+            (
+                Peer::Alice,
+                ContextEvent::IdentityServerEndpointRequestReceived {
+                    handle,
+                    additional_endpoints,
+                    ..
+                },
+            ) => {
+                assert_eq!(additional_endpoints, ["files", "voice"]);
+                context
+                    .identity_server_handle_endpoint_request_received_with_additional_endpoints(
+                        handle,
+                        true,
+                        true,
+                        vec![true, false],
+                        doc!(),
+                    )?;
+            }
+            (Peer::Alice, ContextEvent::IdentityServerChallengeResponseReceived { handle, .. }) => {
+                context.identity_server_handle_challenge_response_received(handle, true)?;
+            }
+            (
+                Peer::Alice,
+                ContextEvent::IdentityServerHandshakeCompleted {
+                    additional_endpoints,
+                    ..
+                },
+            ) => {
+                alice_grants = Some(additional_endpoints);
+            }
+            (Peer::Pat, ContextEvent::IdentityClientChallengeReceived { handle, .. }) => {
+                context.identity_client_handle_challenge_received(handle, doc!())?;
+            }
+            (
+                Peer::Pat,
+                ContextEvent::IdentityClientHandshakeCompleted {
+                    handle,
+                    additional_endpoints,
+                    ..
+                },
+            ) => {
+                assert_eq!(handle, pat_handle);
+                pat_grants = Some(additional_endpoints);
+            }
+            (peer, event) => return unexpected_event(peer, event),
+        }
+        Ok(alice_grants.is_some() && pat_grants.is_some())
+    })?;
+
+    let alice_grants = alice_grants.unwrap();
+    assert_eq!(alice_grants.len(), 1);
+    let (endpoint_name, endpoint_private_key) = &alice_grants[0];
+    assert_eq!(endpoint_name, "files");
+    assert_eq!(
+        pat_grants.unwrap(),
+        [
+            (
+                "files".parse::<AsciiString>()?,
+                Some(V3OnionServiceId::from_private_key(endpoint_private_key))
+            ),
+            ("voice".parse::<AsciiString>()?, None),
+        ]
+    );
+
+    // endpoints may only be requested once per handshake
+    assert!(peers
+        .pat
+        .identity_client_begin_handshake_with_additional_endpoints(
+            peers.alice_service_id.clone(),
+            "test_endpoint".parse()?,
+            vec!["test_endpoint".parse()?],
+        )
+        .is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_mock_identity_handshake_derived_endpoint_keys() -> anyhow::Result<()> {
+    let mut peers = MockPeers::new()?;
+    peers.alice.identity_server_set_derive_endpoint_keys(true);
+
+    // Pat requests the same endpoint twice and is granted the same endpoint
+    // server each time
+    let mut endpoint_service_ids: Vec<V3OnionServiceId> = Default::default();
+    for _ in 0..2 {
+        let pat_handle = peers.pat.identity_client_begin_handshake(
+            peers.alice_service_id.clone(),
+            "test_endpoint".parse()?,
+        )?;
+        let mut alice_completed = false;
+        let mut pat_endpoint_service_id: Option<V3OnionServiceId> = None;
+        peers.run_until(|peer, context, event| {
+            match (peer, event) {
+                (Peer::Alice, ContextEvent::IdentityServerHandshakeStarted { .. }) => (),
+                (
+                    Peer::Alice,
+                    ContextEvent::IdentityServerEndpointRequestReceived { handle, .. },
+                ) => {
+                    context.identity_server_handle_endpoint_request_received(
+                        handle,
+                        true,
+                        true,
+                        doc!(),
+                    )?;
+                }
+                (
+                    Peer::Alice,
+                    ContextEvent::IdentityServerChallengeResponseReceived { handle, .. },
+                ) => {
+                    context.identity_server_handle_challenge_response_received(handle, true)?;
+                }
+                (
+                    Peer::Alice,
+                    ContextEvent::IdentityServerHandshakeCompleted {
+                        endpoint_private_key,
+                        endpoint_name,
+                        client_service_id,
+                        ..
+                    },
+                ) => {
+                    assert_eq!(
+                        endpoint_private_key,
+                        context.identity_server_derive_endpoint_private_key(
+                            &client_service_id,
+                            &endpoint_name
+                        )
+                    );
+                    alice_completed = true;
+                }
+                (Peer::Pat, ContextEvent::IdentityClientChallengeReceived { handle, .. }) => {
+                    context.identity_client_handle_challenge_received(handle, doc!())?;
+                }
+                (
+                    Peer::Pat,
+                    ContextEvent::IdentityClientHandshakeCompleted {
+                        handle,
+                        endpoint_service_id,
+                        ..
+                    },
+                ) => {
+                    assert_eq!(handle, pat_handle);
+                    pat_endpoint_service_id = Some(endpoint_service_id);
+                }
+                (peer, event) => return unexpected_event(peer, event),
+            }
+            Ok(alice_completed && pat_endpoint_service_id.is_some())
+        })?;
+        endpoint_service_ids.push(pat_endpoint_service_id.unwrap());
+    }
+    assert_eq!(endpoint_service_ids[0], endpoint_service_ids[1]);
+
+    // the endpoint key may be recovered from Alice's identity key alone
+    let endpoint_private_key = gosling::endpoint_keys::derive_endpoint_private_key(
+        &peers.alice_private_key,
+        &peers.pat_service_id,
+        &"test_endpoint".parse()?,
+    );
+    assert_eq!(
+        V3OnionServiceId::from_private_key(&endpoint_private_key),
+        endpoint_service_ids[0]
+    );
+
+    // endpoint keys are random once derivation is disabled
+    peers.alice.identity_server_set_derive_endpoint_keys(false);
+    let (endpoint_service_id, _) = peers.grant_endpoint()?;
+    assert_ne!(endpoint_service_id, endpoint_service_ids[0]);
+
+    Ok(())
+}
+
+#[test]
+fn test_mock_identity_handshake_contact_request() -> anyhow::Result<()> {
+    let mut peers = MockPeers::new()?;
+    let pat_handle = peers
+        .pat
+        .identity_client_begin_handshake_with_contact_request(
+            peers.alice_service_id.clone(),
+            "test_endpoint".parse()?,
+            "Hi Alice, it's Pat from the conference".to_string(),
+        )?;
+
+    // Alice sees the contact request before deciding and again once Pat is authenticated
+    let mut alice_contact_request: Option<Option<String>> = None;
+    let mut pat_contact_request: Option<Option<String>> = None;
+    peers.run_until(|peer, context, event| {
+        match (peer, event) {
+            (Peer::Alice, ContextEvent::IdentityServerHandshakeStarted { .. }) => (),
+            (
+                Peer::Alice,
+                ContextEvent::IdentityServerEndpointRequestReceived {
+                    handle,
+                    contact_request,
+                    ..
+                },
+            ) => {
+                assert_eq!(
+                    contact_request.as_deref(),
+                    Some("Hi Alice, it's Pat from the conference")
+                );
+                context.identity_server_handle_endpoint_request_received(
+                    handle,
+                    true,
+                    true,
+                    doc!(),
+                )?;
+            }
+            (Peer::Alice, ContextEvent::IdentityServerChallengeResponseReceived { handle, .. }) => {
+                context.identity_server_handle_challenge_response_received(handle, true)?;
+            }
+            (
+                Peer::Alice,
+                ContextEvent::IdentityServerHandshakeCompleted {
+                    contact_request, ..
+                },
+            ) => {
+                alice_contact_request = Some(contact_request);
+            }
+            (Peer::Pat, ContextEvent::IdentityClientChallengeReceived { handle, .. }) => {
+                context.identity_client_handle_challenge_received(handle, doc!())?;
+            }
+            (
+                Peer::Pat,
+                ContextEvent::IdentityClientHandshakeCompleted {
+                    handle,
+                    contact_request,
+                    ..
+                },
+            ) => {
+                assert_eq!(handle, pat_handle);
+                pat_contact_request = Some(contact_request);
+            }
+            (peer, event) => return unexpected_event(peer, event),
+        }
+        Ok(alice_contact_request.is_some() && pat_contact_request.is_some())
+    })?;
+
+    let expected = Some("Hi Alice, it's Pat from the conference".to_string());
+    assert_eq!(alice_contact_request.unwrap(), expected);
+    assert_eq!(pat_contact_request.unwrap(), expected);
+
+    // oversized contact requests and those containing null bytes are rejected
+    for contact_request in ["x".repeat(513), "Pat\0".to_string()] {
+        assert!(matches!(
+            peers
+                .pat
+                .identity_client_begin_handshake_with_contact_request(
+                    peers.alice_service_id.clone(),
+                    "test_endpoint".parse()?,
+                    contact_request,
+                ),
+            Err(gosling::context::Error::InvalidArgument(_))
+        ));
+    }
+
+    // ...as are contact requests once the capability is disabled
+    peers
+        .pat
+        .set_capabilities(Capabilities::all().difference(Capabilities::CONTACT_REQUEST));
+    assert!(matches!(
+        peers
+            .pat
+            .identity_client_begin_handshake_with_contact_request(
+                peers.alice_service_id.clone(),
+                "test_endpoint".parse()?,
+                "Hi Alice".to_string(),
+            ),
+        Err(gosling::context::Error::InvalidArgument(_))
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn test_mock_identity_handshake_keep_open() -> anyhow::Result<()> {
+    let mut peers = MockPeers::new()?;
+    let alice_service_id = peers.alice_service_id.clone();
+    let pat_service_id = peers.pat_service_id.clone();
+    let clock = Arc::new(VirtualClock::new());
+    peers.alice.set_clock(clock.clone());
+    peers.pat.set_clock(clock.clone());
+    peers
+        .alice
+        .set_identity_session_keep_open(Some(Duration::from_secs(120)));
+    peers
+        .pat
+        .set_identity_session_keep_open(Some(Duration::from_secs(60)));
+
+    // runs one of Pat's identity handshakes, returning both peers' handles
+    let identity_handshake = |peers: &mut MockPeers,
+                              endpoint: &str|
+     -> anyhow::Result<(HandshakeHandle, HandshakeHandle)> {
+        let pat_handle = peers
+            .pat
+            .identity_client_begin_handshake(alice_service_id.clone(), endpoint.parse()?)?;
+        let mut alice_handle: Option<HandshakeHandle> = None;
+        let mut alice_completed = false;
+        let mut pat_completed = false;
+        peers.run_until(|peer, context, event| {
+            match (peer, event) {
+                (Peer::Alice, ContextEvent::IdentityServerHandshakeStarted { handle }) => {
+                    alice_handle = Some(handle);
+                }
+                (
+                    Peer::Alice,
+                    ContextEvent::IdentityServerEndpointRequestReceived { handle, .. },
+                ) => {
+                    assert_eq!(Some(handle), alice_handle);
+                    context.identity_server_handle_endpoint_request_received(
+                        handle,
+                        true,
+                        true,
+                        doc!(),
+                    )?;
+                }
+                (
+                    Peer::Alice,
+                    ContextEvent::IdentityServerChallengeResponseReceived { handle, .. },
+                ) => {
+                    context.identity_server_handle_challenge_response_received(handle, true)?;
+                }
+                (
+                    Peer::Alice,
+                    ContextEvent::IdentityServerHandshakeCompleted {
+                        handle,
+                        capabilities,
+                        ..
+                    },
+                ) => {
+                    assert_eq!(Some(handle), alice_handle);
+                    assert!(capabilities.contains(Capabilities::KEEP_OPEN));
+                    alice_completed = true;
+                }
+                (Peer::Pat, ContextEvent::IdentityClientChallengeReceived { handle, .. }) => {
+                    context.identity_client_handle_challenge_received(handle, doc!())?;
+                }
+                (
+                    Peer::Pat,
+                    ContextEvent::IdentityClientHandshakeCompleted {
+                        handle,
+                        capabilities,
+                        ..
+                    },
+                ) => {
+                    assert_eq!(handle, pat_handle);
+                    assert!(capabilities.contains(Capabilities::KEEP_OPEN));
+                    pat_completed = true;
+                }
+                (peer, event) => return unexpected_event(peer, event),
+            }
+            Ok(alice_completed && pat_completed)
+        })?;
+        Ok((pat_handle, alice_handle.unwrap()))
+    };
+
+    // a follow-up handshake continues over the kept-open connection and
+    // starts as a new handshake on Alice's side
+    let (_, first_alice_handle) = identity_handshake(&mut peers, "first_endpoint")?;
+    let (pat_handle, alice_handle) = identity_handshake(&mut peers, "second_endpoint")?;
+    assert_ne!(alice_handle, first_alice_handle);
+
+    // the connection is closed once it has gone unused for Pat's keep-open
+    // duration, which Alice notices as the connection closing
+    clock.advance(Duration::from_secs(61));
+    let mut alice_closed = false;
+    let mut pat_closed = false;
+    peers.run_until(|peer, _context, event| {
+        match (peer, event) {
+            (
+                Peer::Pat,
+                ContextEvent::IdentityClientSessionClosed {
+                    handle,
+                    identity_service_id,
+                },
+            ) => {
+                assert_eq!(handle, pat_handle);
+                assert_eq!(identity_service_id, alice_service_id);
+                pat_closed = true;
+            }
+            (
+                Peer::Alice,
+                ContextEvent::IdentityServerSessionClosed {
+                    handle,
+                    client_service_id,
+                },
+            ) => {
+                assert_eq!(handle, alice_handle);
+                assert_eq!(client_service_id, pat_service_id);
+                alice_closed = true;
+            }
+            (peer, event) => return unexpected_event(peer, event),
+        }
+        Ok(alice_closed && pat_closed)
+    })
+}
+
+#[test]
+fn test_mock_identity_handshake_endpoint_pin_mismatched() -> anyhow::Result<()> {
+    let mut peers = MockPeers::new()?;
+    let alice_service_id = peers.alice_service_id.clone();
+
+    // Pat has previously been granted a different endpoint server by Alice
+    let pinned_endpoint_service_id =
+        V3OnionServiceId::from_private_key(&Ed25519PrivateKey::generate());
+    let mut pin_store = PinStore::new();
+    pin_store.pin(
+        alice_service_id.clone(),
+        "test_endpoint".to_string(),
+        pinned_endpoint_service_id.clone(),
+    );
+    peers.pat.identity_client_set_pin_store(Some(pin_store));
+
+    let pat_handle = peers
+        .pat
+        .identity_client_begin_handshake(alice_service_id.clone(), "test_endpoint".parse()?)?;
+    let mut mismatched_endpoint_service_id: Option<V3OnionServiceId> = None;
+    let mut granted_endpoint_service_id: Option<V3OnionServiceId> = None;
+    peers.run_until(|peer, context, event| {
+        match (peer, event) {
+            (Peer::Alice, ContextEvent::IdentityServerHandshakeStarted { .. }) => (),
+            (Peer::Alice, ContextEvent::IdentityServerEndpointRequestReceived { handle, .. }) => {
+                context.identity_server_handle_endpoint_request_received(
+                    handle,
+                    true,
+                    true,
+                    doc!(),
+                )?;
+            }
+            (Peer::Alice, ContextEvent::IdentityServerChallengeResponseReceived { handle, .. }) => {
+                context.identity_server_handle_challenge_response_received(handle, true)?;
+            }
+            (Peer::Alice, ContextEvent::IdentityServerHandshakeCompleted { .. }) => (),
+            (Peer::Pat, ContextEvent::IdentityClientChallengeReceived { handle, .. }) => {
+                context.identity_client_handle_challenge_received(handle, doc!())?;
+            }
+            (
+                Peer::Pat,
+                ContextEvent::IdentityClientEndpointPinMismatched {
+                    handle,
+                    identity_service_id,
+                    endpoint_name,
+                    pinned_endpoint_service_id: pinned,
+                    endpoint_service_id,
+                },
+            ) => {
+                // reported before the handshake completes
+                assert_eq!(handle, pat_handle);
+                assert!(granted_endpoint_service_id.is_none());
+                assert_eq!(identity_service_id, alice_service_id);
+                assert_eq!(endpoint_name, "test_endpoint");
+                assert_eq!(pinned, pinned_endpoint_service_id);
+                mismatched_endpoint_service_id = Some(endpoint_service_id);
+            }
+            (
+                Peer::Pat,
+                ContextEvent::IdentityClientHandshakeCompleted {
+                    handle,
+                    endpoint_service_id,
+                    ..
+                },
+            ) => {
+                assert_eq!(handle, pat_handle);
+                granted_endpoint_service_id = Some(endpoint_service_id);
+            }
+            (peer, event) => return unexpected_event(peer, event),
+        }
+        Ok(granted_endpoint_service_id.is_some())
+    })?;
+    let granted_endpoint_service_id = granted_endpoint_service_id.unwrap();
+    assert_eq!(
+        mismatched_endpoint_service_id.as_ref(),
+        Some(&granted_endpoint_service_id)
+    );
+
+    // the original pin is kept until Pat accepts the new endpoint server
+    let pin_store = peers.pat.identity_client_pin_store_mut().unwrap();
+    assert_eq!(
+        pin_store
+            .get(&alice_service_id, "test_endpoint")
+            .unwrap()
+            .endpoint_service_id,
+        pinned_endpoint_service_id
+    );
+    pin_store.pin(
+        alice_service_id.clone(),
+        "test_endpoint".to_string(),
+        granted_endpoint_service_id.clone(),
+    );
+    assert_eq!(
+        pin_store.check(
+            &alice_service_id,
+            "test_endpoint",
+            &granted_endpoint_service_id
+        ),
+        PinVerdict::Matched
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_mock_petnames() -> anyhow::Result<()> {
+    let mut peers = MockPeers::new()?;
+    let alice_service_id = peers.alice_service_id.clone();
+
+    // Pat knows Alice as "Alice"
+    let mut petname_store = PetnameStore::new();
+    petname_store.set("Alice".to_string(), alice_service_id.clone())?;
+    peers.pat.set_petname_store(Some(petname_store));
+
+    let pat_handle = peers
+        .pat
+        .identity_client_begin_handshake(alice_service_id.clone(), "test_endpoint".parse()?)?;
+    let mut granted: Option<(V3OnionServiceId, X25519PrivateKey)> = None;
+    peers.run_until(|peer, context, event| {
+        match (peer, event) {
+            (Peer::Alice, ContextEvent::IdentityServerHandshakeStarted { .. }) => (),
+            (Peer::Alice, ContextEvent::IdentityServerEndpointRequestReceived { handle, .. }) => {
+                context.identity_server_handle_endpoint_request_received(
+                    handle,
+                    true,
+                    true,
+                    doc!(),
+                )?;
+            }
+            (Peer::Alice, ContextEvent::IdentityServerChallengeResponseReceived { handle, .. }) => {
+                context.identity_server_handle_challenge_response_received(handle, true)?;
+            }
+            (Peer::Alice, ContextEvent::IdentityServerHandshakeCompleted { .. }) => (),
+            (Peer::Pat, ContextEvent::IdentityClientChallengeReceived { handle, .. }) => {
+                context.identity_client_handle_challenge_received(handle, doc!())?;
+            }
+            (
+                Peer::Pat,
+                ContextEvent::IdentityClientHandshakeCompleted {
+                    handle,
+                    endpoint_service_id,
+                    client_auth_private_key,
+                    ..
+                },
+            ) => {
+                assert_eq!(handle, pat_handle);
+                granted = Some((endpoint_service_id, client_auth_private_key));
+            }
+            (peer, event) => return unexpected_event(peer, event),
+        }
+        Ok(granted.is_some())
+    })?;
+    let (endpoint_service_id, client_auth_private_key) = granted.unwrap();
+
+    // the grant is recorded under Alice's petname
+    let petname_store = peers.pat.petname_store().unwrap();
+    assert_eq!(petname_store.petname_of(&alice_service_id), Some("Alice"));
+    let endpoint_grant = petname_store
+        .endpoint_grant("Alice", "test_endpoint")
+        .unwrap();
+    assert_eq!(endpoint_grant.identity_service_id(), &alice_service_id);
+    assert_eq!(endpoint_grant.client_service_id(), &peers.pat_service_id);
+    assert_eq!(endpoint_grant.endpoint_service_id(), &endpoint_service_id);
+    assert_eq!(
+        endpoint_grant.client_auth_private_key().to_base64(),
+        client_auth_private_key.to_base64()
+    );
+
+    Ok(())
+}
+
+// answers identity challenges and records the completed handshake's endpoint
+struct IdentityClientSink {
+    completed: Arc<Mutex<Option<(HandshakeHandle, AsciiString)>>>,
+}
+
+impl ContextEventsSink for IdentityClientSink {
+    fn on_identity_client_challenge_received(
+        &mut self,
+        context: &mut Context,
+        handle: HandshakeHandle,
+        _endpoint_challenge: bson::document::Document,
+    ) {
+        context
+            .identity_client_handle_challenge_received(handle, doc!())
+            .unwrap();
+    }
+
+    fn on_identity_client_handshake_completed(
+        &mut self,
+        _context: &mut Context,
+        handle: HandshakeHandle,
+        _identity_service_id: V3OnionServiceId,
+        _endpoint_service_id: V3OnionServiceId,
+        endpoint_name: AsciiString,
+        _client_auth_private_key: X25519PrivateKey,
+        _additional_endpoints: Vec<(AsciiString, Option<V3OnionServiceId>)>,
+        _contact_request: Option<String>,
+        _capabilities: Capabilities,
+        _stats: HandshakeStats,
+    ) {
+        *self.completed.lock().unwrap() = Some((handle, endpoint_name));
+    }
+
+    fn on_identity_client_handshake_failed(
+        &mut self,
+        _context: &mut Context,
+        handle: HandshakeHandle,
+        reason: gosling::context::Error,
+        _stats: HandshakeStats,
+    ) {
+        panic!("identity handshake {} failed: {:?}", handle, reason);
+    }
+}
+
+#[test]
+fn test_mock_identity_handshake_events_sink() -> anyhow::Result<()> {
+    let mut peers = MockPeers::new()?;
+
+    // Pat's events go to the sink rather than being returned from update()
+    let completed: Arc<Mutex<Option<(HandshakeHandle, AsciiString)>>> = Default::default();
+    peers.pat.set_events_sink(Some(Box::new(IdentityClientSink {
+        completed: completed.clone(),
+    })));
+    let pat_handle = peers.pat.identity_client_begin_handshake(
+        peers.alice_service_id.clone(),
+        "test_endpoint".parse()?,
+    )?;
+
+    let mut alice_completed = false;
+    peers.run_until(|peer, context, event| {
+        match (peer, event) {
+            (Peer::Alice, ContextEvent::IdentityServerHandshakeStarted { .. }) => (),
+            (Peer::Alice, ContextEvent::IdentityServerEndpointRequestReceived { handle, .. }) => {
+                context.identity_server_handle_endpoint_request_received(
+                    handle,
+                    true,
+                    true,
+                    doc!(),
+                )?;
+            }
+            (Peer::Alice, ContextEvent::IdentityServerChallengeResponseReceived { handle, .. }) => {
+                context.identity_server_handle_challenge_response_received(handle, true)?;
+            }
+            (Peer::Alice, ContextEvent::IdentityServerHandshakeCompleted { .. }) => {
+                alice_completed = true;
+            }
+            (peer, event) => return unexpected_event(peer, event),
+        }
+        Ok(alice_completed)
+    })?;
+
+    let start = Instant::now();
+    while completed.lock().unwrap().is_none() {
+        if start.elapsed() > TEST_DEADLINE {
+            bail!(
+                "identity handshake not completed within {:?}",
+                TEST_DEADLINE
+            );
+        }
+        assert!(peers.pat.update()?.is_empty());
+    }
+    assert_eq!(
+        completed.lock().unwrap().take(),
+        Some((pat_handle, "test_endpoint".parse()?))
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_mock_update_into() -> anyhow::Result<()> {
+    let mut peers = MockPeers::new()?;
+    peers.pat.identity_client_begin_handshake(
+        peers.alice_service_id.clone(),
+        "test_endpoint".parse()?,
+    )?;
+
+    // update_into() appends to the events already in the queue
+    let mut events: VecDeque<ContextEvent> = Default::default();
+    events.push_back(ContextEvent::TorBootstrapCompleted);
+    let start = Instant::now();
+    while !events
+        .iter()
+        .any(|event| matches!(event, ContextEvent::IdentityServerHandshakeStarted { .. }))
+    {
+        if start.elapsed() > TEST_DEADLINE {
+            bail!("expected events not received within {:?}", TEST_DEADLINE);
+        }
+        peers.pat.update()?;
+        peers.alice.update_into(&mut events)?;
+    }
+    assert!(matches!(
+        events.front(),
+        Some(ContextEvent::TorBootstrapCompleted)
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn test_mock_next_deadline() -> anyhow::Result<()> {
+    let mut peers = MockPeers::new()?;
+
+    // Pat has nothing to do, while Alice's identity server waits for connections
+    assert_eq!(peers.pat.next_deadline(), None);
+    let idle_deadline = peers.alice.next_deadline().unwrap();
+    assert!(idle_deadline > Duration::ZERO);
+
+    // in-progress handshakes are updated more often than listeners
+    peers.pat.identity_client_begin_handshake(
+        peers.alice_service_id.clone(),
+        "test_endpoint".parse()?,
+    )?;
+    let busy_deadline = peers.pat.next_deadline().unwrap();
+    assert!(busy_deadline > Duration::ZERO);
+    assert!(busy_deadline < idle_deadline);
+
+    // handshakes left over by the update budget are updated without delay
+    peers.pat.set_handshake_update_budget(Some(1))?;
+    peers.pat.identity_client_begin_handshake(
+        peers.alice_service_id.clone(),
+        "other_endpoint".parse()?,
+    )?;
+    assert_eq!(peers.pat.next_deadline(), Some(Duration::ZERO));
+
+    Ok(())
+}
+
+#[test]
+fn test_mock_check_connectivity() -> anyhow::Result<()> {
+    let mut peers = MockPeers::new()?;
+    let timeout = Duration::from_secs(10);
+
+    // Alice's published identity server's descriptor is received, while Pat
+    // reaches the network but has no descriptor to find
+    peers.alice.check_connectivity(timeout)?;
+    peers.pat.check_connectivity(timeout)?;
+    assert!(peers.pat.check_connectivity(timeout).is_err());
+    assert!(peers.pat.next_deadline().is_some());
+    let mut alice_report: Option<ConnectivityReport> = None;
+    let mut pat_report: Option<ConnectivityReport> = None;
+    peers.run_until(|peer, _context, event| {
+        match (peer, event) {
+            (Peer::Alice, ContextEvent::ConnectivityChecked { report }) => {
+                alice_report = Some(report)
+            }
+            (Peer::Pat, ContextEvent::ConnectivityChecked { report }) => pat_report = Some(report),
+            (peer, event) => return unexpected_event(peer, event),
+        }
+        Ok(alice_report.is_some() && pat_report.is_some())
+    })?;
+    let alice_report = alice_report.unwrap();
+    assert!(alice_report.bootstrapped);
+    assert_eq!(
+        alice_report.descriptor_fetch,
+        Some(DescriptorFetchOutcome::Received)
+    );
+    assert!(alice_report.tor_reachable());
+    let pat_report = pat_report.unwrap();
+    assert_eq!(
+        pat_report.descriptor_fetch,
+        Some(DescriptorFetchOutcome::NotFound)
+    );
+    assert!(pat_report.tor_reachable());
+    assert_eq!(peers.pat.next_deadline(), None);
+
+    // checks begun before bootstrap finish without fetching anything
+    let mut context = Context::new(
+        Box::new(MockTorClient::new()),
+        420,
+        420,
+        Duration::from_secs(60),
+        4096,
+        None,
+        Ed25519PrivateKey::generate(),
+    )?;
+    context.check_connectivity(timeout)?;
+    let report = context
+        .update()?
+        .into_iter()
+        .find_map(|event| match event {
+            ContextEvent::ConnectivityChecked { report } => Some(report),
+            _ => None,
+        })
+        .expect("connectivity check should finish");
+    assert!(!report.bootstrapped);
+    assert_eq!(report.descriptor_fetch, None);
+    assert!(!report.tor_reachable());
+
+    Ok(())
+}
+
+#[cfg(feature = "prometheus")]
+#[test]
+fn test_mock_metrics_prometheus() -> anyhow::Result<()> {
+    let mut peers = MockPeers::new()?;
+    let metrics = peers.alice.metrics_prometheus();
+    assert!(metrics.contains("# TYPE gosling_handshakes_in_progress gauge\n"));
+    assert!(metrics.contains("gosling_handshakes_in_progress{kind=\"identity_server\"} 0\n"));
+    assert!(metrics.contains("gosling_identity_server_published 1\n"));
+
+    peers.pat.identity_client_begin_handshake(
+        peers.alice_service_id.clone(),
+        "test_endpoint".parse()?,
+    )?;
+    peers.run_until(|peer, _context, event| match (peer, event) {
+        (Peer::Alice, ContextEvent::IdentityServerHandshakeStarted { .. }) => Ok(false),
+        (Peer::Alice, ContextEvent::IdentityServerEndpointRequestReceived { .. }) => Ok(true),
+        (peer, event) => unexpected_event(peer, event),
+    })?;
+
+    // Alice has yet to answer Pat's endpoint request
+    let metrics = peers.alice.metrics_prometheus();
+    assert!(metrics.contains("gosling_handshakes_in_progress{kind=\"identity_server\"} 1\n"));
+    assert!(metrics.contains(
+        "gosling_identity_server_endpoint_requests_total{endpoint=\"test_endpoint\"} 1\n"
+    ));
+    assert!(metrics
+        .contains("gosling_identity_server_endpoint_grants_total{endpoint=\"test_endpoint\"} 0\n"));
+
+    Ok(())
+}
+
+#[test]
+fn test_mock_identity_handshake_client_abort() -> anyhow::Result<()> {
+    let mut peers = MockPeers::new()?;
+    let pat_handle = peers.pat.identity_client_begin_handshake(
+        peers.alice_service_id.clone(),
+        "test_endpoint".parse()?,
+    )?;
+
+    // Pat walks away once challenged
+    let mut alice_failed = false;
+    peers.run_until(|peer, context, event| {
+        match (peer, event) {
+            (Peer::Alice, ContextEvent::IdentityServerHandshakeStarted { .. }) => (),
+            (Peer::Alice, ContextEvent::IdentityServerEndpointRequestReceived { handle, .. }) => {
+                context.identity_server_handle_endpoint_request_received(
+                    handle,
+                    true,
+                    true,
+                    doc!(),
+                )?;
+            }
+            (Peer::Alice, ContextEvent::IdentityServerHandshakeFailed { .. }) => {
+                alice_failed = true;
+            }
+            (Peer::Pat, ContextEvent::IdentityClientChallengeReceived { handle, .. }) => {
+                assert_eq!(handle, pat_handle);
+                context.identity_client_abort_handshake(handle)?;
+            }
+            (peer, event) => return unexpected_event(peer, event),
+        }
+        Ok(alice_failed)
+    })?;
+
+    // the handshake is gone
+    assert!(peers
+        .pat
+        .identity_client_abort_handshake(pat_handle)
+        .is_err());
+    assert!(peers
+        .pat
+        .identity_client_handle_challenge_received(pat_handle, doc!())
+        .is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_mock_identity_handshake_server_abort() -> anyhow::Result<()> {
+    let mut peers = MockPeers::new()?;
+    let pat_handle = peers.pat.identity_client_begin_handshake(
+        peers.alice_service_id.clone(),
+        "test_endpoint".parse()?,
+    )?;
+
+    // Alice turns Pat away without answering the endpoint request
+    let mut alice_handle: Option<HandshakeHandle> = None;
+    let mut alice_failed = false;
+    let mut pat_failed = false;
+    peers.run_until(|peer, context, event| {
+        match (peer, event) {
+            (Peer::Alice, ContextEvent::IdentityServerHandshakeStarted { .. }) => (),
+            (Peer::Alice, ContextEvent::IdentityServerEndpointRequestReceived { handle, .. }) => {
+                context.identity_server_abort_handshake(handle)?;
+                alice_handle = Some(handle);
+            }
+            (Peer::Alice, ContextEvent::IdentityServerHandshakeFailed { handle, reason, .. }) => {
+                assert_eq!(Some(handle), alice_handle);
+                assert!(matches!(
+                    reason,
+                    gosling::context::Error::HandshakeAborted(aborted) if aborted == handle
+                ));
+                alice_failed = true;
+            }
+            (Peer::Pat, ContextEvent::IdentityClientHandshakeFailed { handle, .. }) => {
+                assert_eq!(handle, pat_handle);
+                pat_failed = true;
+            }
+            (peer, event) => return unexpected_event(peer, event),
+        }
+        Ok(alice_failed && pat_failed)
+    })?;
+
+    let stats = peers
+        .alice
+        .identity_server_stats()
+        .endpoint("test_endpoint");
+    assert_eq!((stats.requests, stats.failures), (1, 1));
+
+    // the handshake is gone
+    let alice_handle = alice_handle.unwrap();
+    assert!(peers
+        .alice
+        .identity_server_abort_handshake(alice_handle)
+        .is_err());
+    assert!(peers
+        .alice
+        .identity_server_handle_challenge_response_received(alice_handle, true)
+        .is_err());
+
+    Ok(())
+}
+
+// run an identity handshake in which Pat never answers Alice's challenge
+fn identity_handshake_unanswered_challenge_test(mut peers: MockPeers) -> anyhow::Result<()> {
+    let pat_handle = peers.pat.identity_client_begin_handshake(
+        peers.alice_service_id.clone(),
+        "test_endpoint".parse()?,
+    )?;
+
+    let mut alice_failed = false;
+    let mut pat_failed = false;
+    peers.run_until(|peer, context, event| {
+        match (peer, event) {
+            (Peer::Alice, ContextEvent::IdentityServerHandshakeStarted { .. }) => (),
+            (Peer::Alice, ContextEvent::IdentityServerEndpointRequestReceived { handle, .. }) => {
+                context.identity_server_handle_endpoint_request_received(
+                    handle,
+                    true,
+                    true,
+                    doc!(),
+                )?;
+            }
+            (Peer::Alice, ContextEvent::IdentityServerHandshakeFailed { .. }) => {
+                alice_failed = true;
+            }
+            (Peer::Pat, ContextEvent::IdentityClientChallengeReceived { handle, .. }) => {
+                assert_eq!(handle, pat_handle);
+            }
+            (Peer::Pat, ContextEvent::IdentityClientHandshakeFailed { handle, .. }) => {
+                assert_eq!(handle, pat_handle);
+                pat_failed = true;
+            }
+            (peer, event) => return unexpected_event(peer, event),
+        }
+        Ok(alice_failed && pat_failed)
+    })
+}
+
+#[test]
+fn test_mock_identity_handshake_timeout() -> anyhow::Result<()> {
+    let peers = MockPeers::with_timeouts(Duration::from_secs(1), None)?;
+    identity_handshake_unanswered_challenge_test(peers)
+}
+
+#[test]
+fn test_mock_identity_handshake_challenge_response_deadline() -> anyhow::Result<()> {
+    let mut peers = MockPeers::new()?;
+    peers
+        .alice
+        .identity_server_set_challenge_response_deadline(Some(Duration::from_millis(500)));
+    identity_handshake_unanswered_challenge_test(peers)
+}
+
+//
+// Endpoint Handshake
+//
+
+#[test]
+fn test_mock_endpoint_handshake_channel_rejected() -> anyhow::Result<()> {
+    let mut peers = MockPeers::new()?;
+    let (endpoint_service_id, client_auth_private_key) = peers.grant_endpoint()?;
+    let pat_service_id = peers.pat_service_id.clone();
+
+    let pat_handle = peers.pat.endpoint_client_begin_handshake(
+        endpoint_service_id.clone(),
+        client_auth_private_key,
+        "test_channel".parse()?,
+    )?;
+
+    let mut alice_handle: Option<HandshakeHandle> = None;
+    let mut alice_rejected = false;
+    let mut pat_failed = false;
+    peers.run_until(|peer, context, event| {
+        match (peer, event) {
+            (Peer::Alice, ContextEvent::EndpointServerHandshakeStarted { handle }) => {
+                alice_handle = Some(handle);
+            }
+            (
+                Peer::Alice,
+                ContextEvent::EndpointServerChannelRequestReceived {
+                    handle,
+                    client_service_id,
+                    requested_channel,
+                },
+            ) => {
+                assert_eq!(Some(handle), alice_handle);
+                assert_eq!(client_service_id, pat_service_id);
+                assert_eq!(requested_channel, "test_channel");
+                context.endpoint_server_handle_channel_request_received(handle, false)?;
+            }
+            (
+                Peer::Alice,
+                ContextEvent::EndpointServerHandshakeRejected {
+                    handle,
+                    client_allowed,
+                    client_requested_channel_valid,
+                    client_proof_signature_valid,
+                },
+            ) => {
+                assert_eq!(Some(handle), alice_handle);
+                assert!(client_allowed);
+                assert!(!client_requested_channel_valid);
+                assert!(client_proof_signature_valid);
+                alice_rejected = true;
+            }
+            (
+                Peer::Pat,
+                ContextEvent::ClientAuthAdded {
+                    endpoint_service_id: added,
+                },
+            ) => {
+                assert_eq!(added, endpoint_service_id);
+            }
+            (Peer::Pat, ContextEvent::EndpointClientHandshakeFailed { handle, .. }) => {
+                assert_eq!(handle, pat_handle);
+                pat_failed = true;
+            }
+            (peer, event) => return unexpected_event(peer, event),
+        }
+        Ok(alice_rejected && pat_failed)
+    })
+}
+
+#[test]
+fn test_mock_endpoint_handshake_busy() -> anyhow::Result<()> {
+    let mut peers = MockPeers::new()?;
+    let (endpoint_service_id, client_auth_private_key) = peers.grant_endpoint()?;
+    let pat_service_id = peers.pat_service_id.clone();
+    let retry_after = Duration::from_secs(30);
+    peers.alice.endpoint_server_set_busy(Some(retry_after));
+
+    let pat_handle = peers.pat.endpoint_client_begin_handshake(
+        endpoint_service_id.clone(),
+        client_auth_private_key,
+        "test_channel".parse()?,
+    )?;
+
+    // Alice turns Pat away without seeing the channel request
+    let mut alice_handle: Option<HandshakeHandle> = None;
+    let mut alice_busy = false;
+    let mut pat_busy = false;
+    peers.run_until(|peer, _context, event| {
+        match (peer, event) {
+            (Peer::Alice, ContextEvent::EndpointServerHandshakeStarted { handle }) => {
+                alice_handle = Some(handle);
+            }
+            (
+                Peer::Alice,
+                ContextEvent::EndpointServerHandshakeBusy {
+                    handle,
+                    client_service_id,
+                    requested_channel,
+                    retry_after: busy_retry_after,
+                },
+            ) => {
+                assert_eq!(Some(handle), alice_handle);
+                assert_eq!(client_service_id, pat_service_id);
+                assert_eq!(requested_channel, "test_channel");
+                assert_eq!(busy_retry_after, retry_after);
+                alice_busy = true;
+            }
+            (
+                Peer::Pat,
+                ContextEvent::ClientAuthAdded {
+                    endpoint_service_id: added,
+                },
+            ) => {
+                assert_eq!(added, endpoint_service_id);
+            }
+            (
+                Peer::Pat,
+                ContextEvent::EndpointClientHandshakeBusy {
+                    handle,
+                    endpoint_service_id: busy_endpoint_service_id,
+                    channel_name,
+                    retry_after: busy_retry_after,
+                },
+            ) => {
+                assert_eq!(handle, pat_handle);
+                assert_eq!(busy_endpoint_service_id, endpoint_service_id);
+                assert_eq!(channel_name, "test_channel");
+                assert_eq!(busy_retry_after, retry_after);
+                pat_busy = true;
+            }
+            (peer, event) => return unexpected_event(peer, event),
+        }
+        Ok(alice_busy && pat_busy)
+    })
+}
+
+#[test]
+fn test_mock_endpoint_server_paused() -> anyhow::Result<()> {
+    let mut peers = MockPeers::new()?;
+    let (endpoint_service_id, client_auth_private_key) = peers.grant_endpoint()?;
+    let retry_after = Duration::from_secs(120);
+
+    // only running endpoint servers may be paused, and pausing takes precedence over busy
+    assert!(peers
+        .alice
+        .endpoint_server_set_paused(
+            V3OnionServiceId::from_private_key(&Ed25519PrivateKey::generate()),
+            Some(retry_after)
+        )
+        .is_err());
+    peers
+        .alice
+        .endpoint_server_set_busy(Some(Duration::from_secs(30)));
+    peers
+        .alice
+        .endpoint_server_set_paused(endpoint_service_id.clone(), Some(retry_after))?;
+    assert_eq!(
+        peers.alice.endpoint_server_paused(&endpoint_service_id),
+        Some(retry_after)
+    );
+
+    let pat_handle = peers.pat.endpoint_client_begin_handshake(
+        endpoint_service_id.clone(),
+        client_auth_private_key,
+        "test_channel".parse()?,
+    )?;
+
+    // Alice's endpoint server is still reachable but turns Pat away
+    let mut alice_busy = false;
+    let mut pat_busy = false;
+    peers.run_until(|peer, _context, event| {
+        match (peer, event) {
+            (Peer::Alice, ContextEvent::EndpointServerHandshakeStarted { .. }) => (),
+            (
+                Peer::Alice,
+                ContextEvent::EndpointServerHandshakeBusy {
+                    retry_after: busy_retry_after,
+                    ..
+                },
+            ) => {
+                assert_eq!(busy_retry_after, retry_after);
+                alice_busy = true;
+            }
+            (Peer::Pat, ContextEvent::ClientAuthAdded { .. }) => (),
+            (
+                Peer::Pat,
+                ContextEvent::EndpointClientHandshakeBusy {
+                    handle,
+                    retry_after: busy_retry_after,
+                    ..
+                },
+            ) => {
+                assert_eq!(handle, pat_handle);
+                assert_eq!(busy_retry_after, retry_after);
+                pat_busy = true;
+            }
+            (peer, event) => return unexpected_event(peer, event),
+        }
+        Ok(alice_busy && pat_busy)
+    })?;
+
+    peers
+        .alice
+        .endpoint_server_set_paused(endpoint_service_id.clone(), None)?;
+    assert_eq!(
+        peers.alice.endpoint_server_paused(&endpoint_service_id),
+        None
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_mock_endpoint_channel_quota() -> anyhow::Result<()> {
+    let mut peers = MockPeers::new()?;
+    let (endpoint_service_id, client_auth_private_key) = peers.grant_endpoint()?;
+    let pat_service_id = peers.pat_service_id.clone();
+    peers
+        .alice
+        .endpoint_server_set_channel_quota(Some(ChannelQuota {
+            max_open_channels: Some(1),
+            max_channel_requests: None,
+        }));
+
+    // runs one of Pat's endpoint handshakes, returning Alice's handle if it completed
+    let open_channel = |peers: &mut MockPeers| -> anyhow::Result<Option<HandshakeHandle>> {
+        let pat_handle = peers.pat.endpoint_client_begin_handshake(
+            endpoint_service_id.clone(),
+            client_auth_private_key.clone(),
+            "test_channel".parse()?,
+        )?;
+        let mut alice_result: Option<Option<HandshakeHandle>> = None;
+        let mut pat_done = false;
+        peers.run_until(|peer, context, event| {
+            match (peer, event) {
+                (Peer::Alice, ContextEvent::EndpointServerHandshakeStarted { .. }) => (),
+                (
+                    Peer::Alice,
+                    ContextEvent::EndpointServerChannelRequestReceived { handle, .. },
+                ) => {
+                    context.endpoint_server_handle_channel_request_received(handle, true)?;
+                }
+                (Peer::Alice, ContextEvent::EndpointServerHandshakeCompleted { handle, .. }) => {
+                    alice_result = Some(Some(handle));
+                }
+                (
+                    Peer::Alice,
+                    ContextEvent::EndpointServerChannelQuotaExceeded {
+                        client_service_id,
+                        requested_channel,
+                        exceeded,
+                        ..
+                    },
+                ) => {
+                    assert_eq!(client_service_id, pat_service_id);
+                    assert_eq!(requested_channel, "test_channel");
+                    assert_eq!(exceeded, ChannelQuotaExceeded::OpenChannels { limit: 1 });
+                    alice_result = Some(None);
+                }
+                (Peer::Pat, ContextEvent::ClientAuthAdded { .. }) => (),
+                (Peer::Pat, ContextEvent::EndpointClientHandshakeCompleted { handle, .. }) => {
+                    assert_eq!(handle, pat_handle);
+                    pat_done = true;
+                }
+                (Peer::Pat, ContextEvent::EndpointClientHandshakeFailed { handle, reason, .. }) => {
+                    assert_eq!(handle, pat_handle);
+                    match reason {
+                        gosling::context::Error::EndpointChannelQuotaExceeded(
+                            refused_by,
+                            ChannelQuotaExceeded::OpenChannels { limit: 1 },
+                        ) => assert_eq!(refused_by, endpoint_service_id),
+                        reason => bail!("unexpected failure: {reason:?}"),
+                    }
+                    pat_done = true;
+                }
+                (peer, event) => return unexpected_event(peer, event),
+            }
+            Ok(alice_result.is_some() && pat_done)
+        })?;
+        Ok(alice_result.unwrap())
+    };
+
+    // Pat may only have one channel open at a time
+    let alice_handle = open_channel(&mut peers)?.expect("first channel should open");
+    assert_eq!(open_channel(&mut peers)?, None);
+
+    // closing the channel frees up Pat's quota
+    peers.alice.endpoint_server_channel_closed(alice_handle);
+    assert!(open_channel(&mut peers)?.is_some());
+
+    Ok(())
+}
+
+#[test]
+fn test_mock_bans() -> anyhow::Result<()> {
+    let mut peers = MockPeers::new()?;
+    let (endpoint_service_id, client_auth_private_key) = peers.grant_endpoint()?;
+    let pat_service_id = peers.pat_service_id.clone();
+    let ban_list = BanList::new();
+    peers.alice.set_ban_list(Some(ban_list.clone()));
+    ban_list.ban(pat_service_id.clone(), None, "spam".to_string())?;
+
+    // banned identity clients are turned away as soon as they begin their handshake
+    let pat_handle = peers.pat.identity_client_begin_handshake(
+        peers.alice_service_id.clone(),
+        "test_endpoint".parse()?,
+    )?;
+    let mut alice_done = false;
+    let mut pat_done = false;
+    peers.run_until(|peer, _context, event| {
+        match (peer, event) {
+            (Peer::Alice, ContextEvent::IdentityServerHandshakeStarted { .. }) => (),
+            (
+                Peer::Alice,
+                ContextEvent::IdentityServerBannedClientRejected {
+                    client_service_id,
+                    requested_endpoint,
+                    ban,
+                    ..
+                },
+            ) => {
+                assert_eq!(client_service_id, pat_service_id);
+                assert_eq!(requested_endpoint, "test_endpoint");
+                assert_eq!(ban.reason, "spam");
+                assert!(ban.is_permanent());
+                alice_done = true;
+            }
+            (Peer::Pat, ContextEvent::IdentityClientHandshakeFailed { handle, .. }) => {
+                assert_eq!(handle, pat_handle);
+                pat_done = true;
+            }
+            (peer, event) => return unexpected_event(peer, event),
+        }
+        Ok(alice_done && pat_done)
+    })?;
+
+    // runs one of Pat's endpoint handshakes, returning whether it completed
+    let open_channel = |peers: &mut MockPeers| -> anyhow::Result<bool> {
+        let pat_handle = peers.pat.endpoint_client_begin_handshake(
+            endpoint_service_id.clone(),
+            client_auth_private_key.clone(),
+            "test_channel".parse()?,
+        )?;
+        let mut alice_result: Option<bool> = None;
+        let mut pat_result: Option<bool> = None;
+        peers.run_until(|peer, context, event| {
+            match (peer, event) {
+                (Peer::Alice, ContextEvent::EndpointServerHandshakeStarted { .. }) => (),
+                (
+                    Peer::Alice,
+                    ContextEvent::EndpointServerChannelRequestReceived { handle, .. },
+                ) => {
+                    context.endpoint_server_handle_channel_request_received(handle, true)?;
+                }
+                (Peer::Alice, ContextEvent::EndpointServerHandshakeCompleted { .. }) => {
+                    alice_result = Some(true);
+                }
+                (
+                    Peer::Alice,
+                    ContextEvent::EndpointServerBannedClientRejected {
+                        client_service_id,
+                        requested_channel,
+                        ban,
+                        ..
+                    },
+                ) => {
+                    assert_eq!(client_service_id, pat_service_id);
+                    assert_eq!(requested_channel, "test_channel");
+                    assert_eq!(ban.reason, "spam");
+                    alice_result = Some(false);
+                }
+                (Peer::Pat, ContextEvent::ClientAuthAdded { .. }) => (),
+                (Peer::Pat, ContextEvent::EndpointClientHandshakeCompleted { handle, .. }) => {
+                    assert_eq!(handle, pat_handle);
+                    pat_result = Some(true);
+                }
+                (Peer::Pat, ContextEvent::EndpointClientHandshakeFailed { handle, .. }) => {
+                    assert_eq!(handle, pat_handle);
+                    pat_result = Some(false);
+                }
+                (peer, event) => return unexpected_event(peer, event),
+            }
+            Ok(alice_result.is_some() && pat_result.is_some())
+        })?;
+        assert_eq!(alice_result, pat_result);
+        Ok(alice_result.unwrap())
+    };
+
+    // as are banned endpoint clients once they have proven their identity
+    assert!(!open_channel(&mut peers)?);
+
+    // lifted bans are no longer enforced
+    assert_eq!(ban_list.unban(&pat_service_id).unwrap().reason, "spam");
+    assert!(open_channel(&mut peers)?);
+
+    Ok(())
+}
+
+// echoes the arguments of its calls back to the caller
+struct EchoApiSet;
+
+impl ApiSet for EchoApiSet {
+    fn namespace(&self) -> &str {
+        "test_echo"
+    }
+
+    fn exec_function(
+        &mut self,
+        name: &str,
+        _version: i32,
+        args: bson::document::Document,
+        _request_cookie: Option<RequestCookie>,
+    ) -> Option<Result<Option<bson::Bson>, ErrorCode>> {
+        match name {
+            "echo" => Some(Ok(Some(bson::Bson::Document(args)))),
+            _ => Some(Err(ErrorCode::RequestFunctionInvalid)),
+        }
+    }
+}
+
+#[test]
+fn test_mock_endpoint_rpc_channel() -> anyhow::Result<()> {
+    let mut peers = MockPeers::new()?;
+    let (endpoint_service_id, client_auth_private_key) = peers.grant_endpoint()?;
+    let pat_service_id = peers.pat_service_id.clone();
+    assert!("t\u{e9}st".parse::<AsciiString>().is_err());
+    peers.alice.add_rpc_channel("test_rpc".parse()?)?;
+    peers.pat.add_rpc_channel("test_rpc".parse()?)?;
+    let unused_channel: AsciiString = "unused_rpc".parse()?;
+    peers.pat.add_rpc_channel(unused_channel.clone())?;
+    assert!(peers.pat.remove_rpc_channel(&unused_channel));
+    assert!(!peers.pat.remove_rpc_channel(&unused_channel));
+
+    let pat_handle = peers.pat.endpoint_client_begin_handshake(
+        endpoint_service_id.clone(),
+        client_auth_private_key,
+        "test_rpc".parse()?,
+    )?;
+
+    // both peers are handed the handshake's session rather than its stream
+    let mut alice_channel: Option<RpcChannel> = None;
+    let mut pat_channel: Option<RpcChannel> = None;
+    peers.run_until(|peer, context, event| {
+        match (peer, event) {
+            (Peer::Alice, ContextEvent::EndpointServerHandshakeStarted { .. }) => (),
+            (Peer::Alice, ContextEvent::EndpointServerChannelRequestReceived { handle, .. }) => {
+                context.endpoint_server_handle_channel_request_received(handle, true)?;
+            }
+            (
+                Peer::Alice,
+                ContextEvent::EndpointServerRpcChannelOpened {
+                    endpoint_service_id: opened,
+                    client_service_id,
+                    channel_name,
+                    channel,
+                    ..
+                },
+            ) => {
+                assert_eq!(opened, endpoint_service_id);
+                assert_eq!(client_service_id, pat_service_id);
+                assert_eq!(channel_name, "test_rpc");
+                alice_channel = Some(channel);
+            }
+            (Peer::Pat, ContextEvent::ClientAuthAdded { .. }) => (),
+            (
+                Peer::Pat,
+                ContextEvent::EndpointClientRpcChannelOpened {
+                    handle,
+                    channel_name,
+                    channel,
+                    ..
+                },
+            ) => {
+                assert_eq!(handle, pat_handle);
+                assert_eq!(channel_name, "test_rpc");
+                pat_channel = Some(channel);
+            }
+            (peer, event) => return unexpected_event(peer, event),
+        }
+        Ok(alice_channel.is_some() && pat_channel.is_some())
+    })?;
+    let mut alice_channel = alice_channel.unwrap();
+    let mut pat_channel = pat_channel.unwrap();
+
+    // the handshakes' namespaces may not be reused
+    struct ReservedApiSet;
+    impl ApiSet for ReservedApiSet {
+        fn namespace(&self) -> &str {
+            "gosling_endpoint"
+        }
+
+        fn exec_function(
+            &mut self,
+            _name: &str,
+            _version: i32,
+            _args: bson::document::Document,
+            _request_cookie: Option<RequestCookie>,
+        ) -> Option<Result<Option<bson::Bson>, ErrorCode>> {
+            None
+        }
+    }
+    assert!(matches!(
+        alice_channel.register_apiset(Box::new(ReservedApiSet)),
+        Err(rpc_channel::Error::ReservedNamespace(_))
+    ));
+
+    // Pat's calls are served by the ApiSet Alice registered
+    alice_channel.register_apiset(Box::new(EchoApiSet))?;
+    let echo_cookie = pat_channel.call("test_echo", "echo", 0, doc! {"value": 42})?;
+    let missing_cookie = pat_channel.call("test_echo", "missing", 0, doc! {})?;
+    let start = Instant::now();
+    let mut responses: Vec<Response> = Default::default();
+    while responses.len() < 2 {
+        if start.elapsed() > TEST_DEADLINE {
+            bail!("rpc responses not received within {:?}", TEST_DEADLINE);
+        }
+        pat_channel.update()?;
+        alice_channel.update()?;
+        while let Some(response) = pat_channel.next_response() {
+            if !matches!(response, Response::Pending { .. }) {
+                responses.push(response);
+            }
+        }
+    }
+    match &responses[0] {
+        Response::Success {
+            cookie,
+            result: Some(bson::Bson::Document(result)),
+        } => {
+            assert_eq!(*cookie, echo_cookie);
+            assert_eq!(result, &doc! {"value": 42});
+        }
+        _ => bail!("unexpected echo response"),
+    }
+    match &responses[1] {
+        Response::Error { cookie, error_code } => {
+            assert_eq!(*cookie, missing_cookie);
+            assert_eq!(*error_code, ErrorCode::RequestFunctionInvalid);
+        }
+        _ => bail!("unexpected missing function response"),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_mock_endpoint_rpc_channel_heartbeat() -> anyhow::Result<()> {
+    let mut peers = MockPeers::new()?;
+    let clock = Arc::new(VirtualClock::new());
+    peers.alice.set_clock(clock.clone());
+    peers.pat.set_clock(clock.clone());
+    let (endpoint_service_id, client_auth_private_key) = peers.grant_endpoint()?;
+    peers.alice.add_rpc_channel("test_rpc".parse()?)?;
+    peers.pat.add_rpc_channel("test_rpc".parse()?)?;
+
+    // heartbeats must have an interval and a miss threshold
+    let interval = Duration::from_secs(10);
+    assert!(peers
+        .pat
+        .set_rpc_channel_heartbeat(Some(Heartbeat {
+            interval: Duration::ZERO,
+            miss_threshold: 2,
+        }))
+        .is_err());
+    assert!(peers
+        .pat
+        .set_rpc_channel_heartbeat(Some(Heartbeat {
+            interval,
+            miss_threshold: 0,
+        }))
+        .is_err());
+    peers.pat.set_rpc_channel_heartbeat(Some(Heartbeat {
+        interval,
+        miss_threshold: 2,
+    }))?;
+
+    peers.pat.endpoint_client_begin_handshake(
+        endpoint_service_id,
+        client_auth_private_key,
+        "test_rpc".parse()?,
+    )?;
+    let mut alice_channel: Option<RpcChannel> = None;
+    let mut pat_channel: Option<RpcChannel> = None;
+    peers.run_until(|peer, context, event| {
+        match (peer, event) {
+            (Peer::Alice, ContextEvent::EndpointServerHandshakeStarted { .. }) => (),
+            (Peer::Alice, ContextEvent::EndpointServerChannelRequestReceived { handle, .. }) => {
+                context.endpoint_server_handle_channel_request_received(handle, true)?;
+            }
+            (Peer::Alice, ContextEvent::EndpointServerRpcChannelOpened { channel, .. }) => {
+                alice_channel = Some(channel);
+            }
+            (Peer::Pat, ContextEvent::ClientAuthAdded { .. }) => (),
+            (Peer::Pat, ContextEvent::EndpointClientRpcChannelOpened { channel, .. }) => {
+                pat_channel = Some(channel);
+            }
+            (peer, event) => return unexpected_event(peer, event),
+        }
+        Ok(alice_channel.is_some() && pat_channel.is_some())
+    })?;
+    let mut alice_channel = alice_channel.unwrap();
+    let mut pat_channel = pat_channel.unwrap();
+
+    // the heartbeat's namespace belongs to the channel
+    assert!(alice_channel.unregister_apiset("gosling_channel").is_none());
+    alice_channel.register_apiset(Box::new(EchoApiSet))?;
+
+    // Pat's heartbeat is sent ahead of the call, so it has been answered once
+    // the call's response arrives
+    clock.advance(interval);
+    pat_channel.update()?;
+    let echo_cookie = pat_channel.call("test_echo", "echo", 0, doc! {"value": 42})?;
+    let start = Instant::now();
+    let mut echoed = false;
+    while !echoed {
+        if start.elapsed() > TEST_DEADLINE {
+            bail!("rpc response not received within {:?}", TEST_DEADLINE);
+        }
+        pat_channel.update()?;
+        alice_channel.update()?;
+        while let Some(response) = pat_channel.next_response() {
+            match response {
+                Response::Pending { .. } => (),
+                Response::Success { cookie, .. } if cookie == echo_cookie => echoed = true,
+                _ => bail!("unexpected echo response"),
+            }
+        }
+    }
+    assert_eq!(pat_channel.next_event(), None);
+    assert_eq!(alice_channel.next_event(), None);
+
+    // once Alice stops answering, Pat reports her unresponsive after two
+    // missed heartbeats
+    for _ in 0..2 {
+        clock.advance(interval);
+        pat_channel.update()?;
+        assert_eq!(pat_channel.next_event(), None);
+    }
+    clock.advance(interval);
+    pat_channel.update()?;
+    assert_eq!(
+        pat_channel.next_event(),
+        Some(RpcChannelEvent::Unresponsive {
+            missed_heartbeats: 2
+        })
+    );
+    clock.advance(interval);
+    pat_channel.update()?;
+    assert_eq!(pat_channel.next_event(), None);
+
+    // and responsive again once she answers the outstanding heartbeats
+    let start = Instant::now();
+    loop {
+        if start.elapsed() > TEST_DEADLINE {
+            bail!("heartbeat not answered within {:?}", TEST_DEADLINE);
+        }
+        alice_channel.update()?;
+        pat_channel.update()?;
+        if let Some(event) = pat_channel.next_event() {
+            assert_eq!(event, RpcChannelEvent::Responsive);
+            break;
+        }
+    }
+    assert!(pat_channel.next_response().is_none());
+
+    Ok(())
+}
+
+#[test]
+fn test_mock_endpoint_datagram_channel() -> anyhow::Result<()> {
+    let mut peers = MockPeers::new()?;
+    let (endpoint_service_id, client_auth_private_key) = peers.grant_endpoint()?;
+    peers.alice.add_datagram_channel("test_datagram".parse()?)?;
+    peers.pat.add_datagram_channel("test_datagram".parse()?)?;
+
+    // a channel is either an rpc channel or a datagram channel
+    assert!(peers.pat.add_rpc_channel("test_datagram".parse()?).is_err());
+    peers.pat.add_rpc_channel("test_rpc".parse()?)?;
+    assert!(peers.pat.add_datagram_channel("test_rpc".parse()?).is_err());
+    let unused_channel: AsciiString = "unused_datagram".parse()?;
+    peers.pat.add_datagram_channel(unused_channel.clone())?;
+    assert!(peers.pat.remove_datagram_channel(&unused_channel));
+    assert!(!peers.pat.remove_datagram_channel(&unused_channel));
+
+    let pat_handle = peers.pat.endpoint_client_begin_handshake(
+        endpoint_service_id,
+        client_auth_private_key,
+        "test_datagram".parse()?,
+    )?;
+
+    // both peers are handed a datagram channel rather than the stream
+    let mut alice_channel: Option<DatagramChannel> = None;
+    let mut pat_channel: Option<DatagramChannel> = None;
+    peers.run_until(|peer, context, event| {
+        match (peer, event) {
+            (Peer::Alice, ContextEvent::EndpointServerHandshakeStarted { .. }) => (),
+            (Peer::Alice, ContextEvent::EndpointServerChannelRequestReceived { handle, .. }) => {
+                context.endpoint_server_handle_channel_request_received(handle, true)?;
+            }
+            (
+                Peer::Alice,
+                ContextEvent::EndpointServerDatagramChannelOpened {
+                    channel_name,
+                    channel,
+                    ..
+                },
+            ) => {
+                assert_eq!(channel_name, "test_datagram");
+                alice_channel = Some(channel);
+            }
+            (Peer::Pat, ContextEvent::ClientAuthAdded { .. }) => (),
+            (
+                Peer::Pat,
+                ContextEvent::EndpointClientDatagramChannelOpened {
+                    handle,
+                    channel_name,
+                    channel,
+                    ..
+                },
+            ) => {
+                assert_eq!(handle, pat_handle);
+                assert_eq!(channel_name, "test_datagram");
+                pat_channel = Some(channel);
+            }
+            (peer, event) => return unexpected_event(peer, event),
+        }
+        Ok(alice_channel.is_some() && pat_channel.is_some())
+    })?;
+    let mut alice_channel = alice_channel.unwrap();
+    let mut pat_channel = pat_channel.unwrap();
+
+    // datagrams are exchanged in both directions
+    pat_channel.send(b"ping")?;
+    let start = Instant::now();
+    let ping = loop {
+        if start.elapsed() > TEST_DEADLINE {
+            bail!("datagram not received within {:?}", TEST_DEADLINE);
+        }
+        pat_channel.update()?;
+        alice_channel.update()?;
+        if let Some(datagram) = alice_channel.recv() {
+            break datagram;
+        }
+    };
+    assert_eq!(ping, b"ping");
+
+    alice_channel.send(b"pong")?;
+    let pong = loop {
+        if start.elapsed() > TEST_DEADLINE {
+            bail!("datagram not received within {:?}", TEST_DEADLINE);
+        }
+        alice_channel.update()?;
+        pat_channel.update()?;
+        if let Some(datagram) = pat_channel.recv() {
+            break datagram;
+        }
+    };
+    assert_eq!(pong, b"pong");
+    assert_eq!(pat_channel.stats().datagrams_sent, 1);
+    assert_eq!(pat_channel.stats().datagrams_received, 1);
+
+    Ok(())
+}
+
+#[test]
+fn test_mock_endpoint_handshake_concurrency_limit() -> anyhow::Result<()> {
+    let mut peers = MockPeers::new()?;
+    let (endpoint_service_id, client_auth_private_key) = peers.grant_endpoint()?;
+
+    // only running endpoint servers may be limited, and to at least one handshake
+    assert!(peers
+        .alice
+        .endpoint_server_set_concurrency_limit(
+            V3OnionServiceId::from_private_key(&Ed25519PrivateKey::generate()),
+            Some(1),
+            1,
+            PendingConnectionDropPolicy::DropNewest,
+        )
+        .is_err());
+    assert!(peers
+        .alice
+        .endpoint_server_set_concurrency_limit(
+            endpoint_service_id.clone(),
+            Some(0),
+            1,
+            PendingConnectionDropPolicy::DropNewest,
+        )
+        .is_err());
+    peers.alice.endpoint_server_set_concurrency_limit(
+        endpoint_service_id.clone(),
+        Some(1),
+        1,
+        PendingConnectionDropPolicy::DropNewest,
+    )?;
+
+    // one connection is handled, one waits for it to finish and the last is shed
+    let mut pat_handles: Vec<HandshakeHandle> = Default::default();
+    for _ in 0..3 {
+        pat_handles.push(peers.pat.endpoint_client_begin_handshake(
+            endpoint_service_id.clone(),
+            client_auth_private_key.clone(),
+            "test_channel".parse()?,
+        )?);
+    }
+
+    let mut alice_in_progress = 0usize;
+    let mut alice_completed = 0usize;
+    let mut alice_shed = 0usize;
+    let mut pat_completed = 0usize;
+    let mut pat_failed = 0usize;
+    peers.run_until(|peer, context, event| {
+        match (peer, event) {
+            (Peer::Alice, ContextEvent::EndpointServerHandshakeStarted { .. }) => {
+                alice_in_progress += 1;
+                assert_eq!(alice_in_progress, 1);
+            }
+            (Peer::Alice, ContextEvent::EndpointServerChannelRequestReceived { handle, .. }) => {
+                context.endpoint_server_handle_channel_request_received(handle, true)?;
+            }
+            (Peer::Alice, ContextEvent::EndpointServerHandshakeCompleted { .. }) => {
+                alice_in_progress -= 1;
+                alice_completed += 1;
+            }
+            (
+                Peer::Alice,
+                ContextEvent::EndpointServerConnectionShed {
+                    endpoint_service_id: shed,
+                },
+            ) => {
+                assert_eq!(shed, endpoint_service_id);
+                alice_shed += 1;
+            }
+            (Peer::Pat, ContextEvent::ClientAuthAdded { .. }) => (),
+            (Peer::Pat, ContextEvent::EndpointClientHandshakeCompleted { handle, .. }) => {
+                assert!(pat_handles.contains(&handle));
+                pat_completed += 1;
+            }
+            (Peer::Pat, ContextEvent::EndpointClientHandshakeFailed { handle, .. }) => {
+                assert!(pat_handles.contains(&handle));
+                pat_failed += 1;
+            }
+            (peer, event) => return unexpected_event(peer, event),
+        }
+        Ok(alice_completed == 2 && alice_shed == 1 && pat_completed == 2 && pat_failed == 1)
+    })
+}
+
+#[test]
+fn test_mock_endpoint_handshake_priority() -> anyhow::Result<()> {
+    let mut peers = MockPeers::new()?;
+    let (endpoint_service_id, client_auth_private_key) = peers.grant_endpoint()?;
+
+    // budgets must be non-zero and priorities may only be set on existing handshakes and endpoint servers
+    assert!(peers.pat.set_handshake_update_budget(Some(0)).is_err());
+    assert!(matches!(
+        peers.pat.set_handshake_priority(1234, 1),
+        Err(gosling::context::Error::HandshakeHandleNotFound(1234))
+    ));
+    assert!(peers
+        .alice
+        .endpoint_server_set_priority(
+            V3OnionServiceId::from_private_key(&Ed25519PrivateKey::generate()),
+            1
+        )
+        .is_err());
+    peers
+        .alice
+        .endpoint_server_set_priority(endpoint_service_id.clone(), 1)?;
+
+    // Pat only updates one handshake at a time, so the prioritised handshake completes first
+    peers.pat.set_handshake_update_budget(Some(1))?;
+    let mut pat_handles: Vec<HandshakeHandle> = Default::default();
+    for _ in 0..3 {
+        pat_handles.push(peers.pat.endpoint_client_begin_handshake(
+            endpoint_service_id.clone(),
+            client_auth_private_key.clone(),
+            "test_channel".parse()?,
+        )?);
+    }
+    let priority_handle = pat_handles[2];
+    peers.pat.set_handshake_priority(priority_handle, 10)?;
+
+    let mut alice_completed = 0usize;
+    let mut pat_completed: Vec<HandshakeHandle> = Default::default();
+    peers.run_until(|peer, context, event| {
+        match (peer, event) {
+            (Peer::Alice, ContextEvent::EndpointServerHandshakeStarted { .. }) => (),
+            (Peer::Alice, ContextEvent::EndpointServerChannelRequestReceived { handle, .. }) => {
+                context.endpoint_server_handle_channel_request_received(handle, true)?;
+            }
+            (Peer::Alice, ContextEvent::EndpointServerHandshakeCompleted { .. }) => {
+                alice_completed += 1;
+            }
+            (Peer::Pat, ContextEvent::ClientAuthAdded { .. }) => (),
+            (Peer::Pat, ContextEvent::EndpointClientHandshakeCompleted { handle, .. }) => {
+                assert!(pat_handles.contains(&handle));
+                pat_completed.push(handle);
+            }
+            (peer, event) => return unexpected_event(peer, event),
+        }
+        Ok(alice_completed == 3 && pat_completed.len() == 3)
+    })?;
+    assert_eq!(pat_completed[0], priority_handle);
+
+    // the remaining handshakes were still updated in turn
+    pat_completed.sort();
+    assert_eq!(pat_completed, pat_handles);
+    Ok(())
+}
+
+#[test]
+fn test_mock_endpoint_handshake_client_abort() -> anyhow::Result<()> {
+    let mut peers = MockPeers::new()?;
+    let (endpoint_service_id, client_auth_private_key) = peers.grant_endpoint()?;
+
+    let pat_handle = peers.pat.endpoint_client_begin_handshake(
+        endpoint_service_id,
+        client_auth_private_key,
+        "test_channel".parse()?,
+    )?;
+
+    // Pat walks away before Alice answers the channel request
+    let mut alice_request: Option<HandshakeHandle> = None;
+    peers.run_until(|peer, _context, event| {
+        match (peer, event) {
+            (Peer::Alice, ContextEvent::EndpointServerHandshakeStarted { .. }) => (),
+            (Peer::Alice, ContextEvent::EndpointServerChannelRequestReceived { handle, .. }) => {
+                alice_request = Some(handle);
+            }
+            (Peer::Pat, ContextEvent::ClientAuthAdded { .. }) => (),
+            (peer, event) => return unexpected_event(peer, event),
+        }
+        Ok(alice_request.is_some())
+    })?;
+    peers.pat.endpoint_client_abort_handshake(pat_handle)?;
+    assert!(peers
+        .pat
+        .endpoint_client_abort_handshake(pat_handle)
+        .is_err());
+
+    let alice_handle = alice_request.unwrap();
+    peers
+        .alice
+        .endpoint_server_handle_channel_request_received(alice_handle, true)?;
+    peers.run_until(|peer, _context, event| match (peer, event) {
+        (Peer::Alice, ContextEvent::EndpointServerHandshakeFailed { handle, .. }) => {
+            assert_eq!(handle, alice_handle);
+            Ok(true)
+        }
+        (peer, event) => unexpected_event(peer, event),
+    })
+}
+
+#[test]
+fn test_mock_endpoint_handshake_server_abort() -> anyhow::Result<()> {
+    let mut peers = MockPeers::new()?;
+    let (endpoint_service_id, client_auth_private_key) = peers.grant_endpoint()?;
+
+    let pat_handle = peers.pat.endpoint_client_begin_handshake(
+        endpoint_service_id.clone(),
+        client_auth_private_key,
+        "test_channel".parse()?,
+    )?;
+
+    // Alice turns Pat away without answering the channel request
+    let mut alice_handle: Option<HandshakeHandle> = None;
+    let mut alice_failed = false;
+    let mut pat_failed = false;
+    peers.run_until(|peer, context, event| {
+        match (peer, event) {
+            (Peer::Alice, ContextEvent::EndpointServerHandshakeStarted { .. }) => (),
+            (Peer::Alice, ContextEvent::EndpointServerChannelRequestReceived { handle, .. }) => {
+                context.endpoint_server_abort_handshake(handle)?;
+                alice_handle = Some(handle);
+            }
+            (Peer::Alice, ContextEvent::EndpointServerHandshakeFailed { handle, reason, .. }) => {
+                assert_eq!(Some(handle), alice_handle);
+                assert!(matches!(
+                    reason,
+                    gosling::context::Error::HandshakeAborted(aborted) if aborted == handle
+                ));
+                alice_failed = true;
+            }
+            (Peer::Pat, ContextEvent::ClientAuthAdded { .. }) => (),
+            (Peer::Pat, ContextEvent::EndpointClientHandshakeFailed { handle, .. }) => {
+                assert_eq!(handle, pat_handle);
+                pat_failed = true;
+            }
+            (peer, event) => return unexpected_event(peer, event),
+        }
+        Ok(alice_failed && pat_failed)
+    })?;
+
+    // the handshake is gone but the endpoint server keeps running
+    let alice_handle = alice_handle.unwrap();
+    assert!(peers
+        .alice
+        .endpoint_server_abort_handshake(alice_handle)
+        .is_err());
+    assert!(peers
+        .alice
+        .endpoint_server_handle_channel_request_received(alice_handle, true)
+        .is_err());
+    assert!(peers
+        .alice
+        .endpoint_server_stop(endpoint_service_id)
+        .is_ok());
+
+    Ok(())
+}
+
+// a clock which moves forward by a second each time it is read
+struct TickingClock {
+    clock: VirtualClock,
+}
+
+impl Clock for TickingClock {
+    fn now(&self) -> Instant {
+        self.clock.advance(Duration::from_secs(1));
+        self.clock.now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.clock.sleep(duration);
+    }
+}
+
+#[test]
+fn test_mock_endpoint_handshake_connect_timeout() -> anyhow::Result<()> {
+    let mut peers = MockPeers::new()?;
+    let (endpoint_service_id, client_auth_private_key) = peers.grant_endpoint()?;
+
+    // every connection appears to take longer than the timeout
+    peers.pat.set_clock(Arc::new(TickingClock {
+        clock: VirtualClock::new(),
+    }));
+    peers
+        .pat
+        .endpoint_client_set_connect_timeout(Some(Duration::from_millis(500)));
+    match peers.pat.endpoint_client_begin_handshake(
+        endpoint_service_id.clone(),
+        client_auth_private_key,
+        "test_channel".parse()?,
+    ) {
+        Err(gosling::context::Error::EndpointConnectTimedOut(service_id, timeout)) => {
+            assert_eq!(service_id, endpoint_service_id);
+            assert_eq!(timeout, Duration::from_millis(500));
+        }
+        Err(err) => bail!("unexpected error: {err:?}"),
+        Ok(_) => bail!("endpoint handshake unexpectedly connected"),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_mock_endpoint_handshake_handshake_timeout() -> anyhow::Result<()> {
+    let mut peers = MockPeers::new()?;
+    let (endpoint_service_id, client_auth_private_key) = peers.grant_endpoint()?;
+
+    // time only moves when the test advances it
+    let clock = Arc::new(VirtualClock::new());
+    peers.pat.set_clock(clock.clone());
+    peers
+        .pat
+        .endpoint_client_set_handshake_timeout(Some(Duration::from_secs(10)));
+    let pat_handle = peers.pat.endpoint_client_begin_handshake(
+        endpoint_service_id.clone(),
+        client_auth_private_key,
+        "test_channel".parse()?,
+    )?;
+
+    // Alice never answers the channel request
+    let mut alice_request = false;
+    peers.run_until(|peer, _context, event| {
+        match (peer, event) {
+            (Peer::Alice, ContextEvent::EndpointServerHandshakeStarted { .. }) => (),
+            (Peer::Alice, ContextEvent::EndpointServerChannelRequestReceived { .. }) => {
+                alice_request = true;
+            }
+            (Peer::Pat, ContextEvent::ClientAuthAdded { .. }) => (),
+            (peer, event) => return unexpected_event(peer, event),
+        }
+        Ok(alice_request)
+    })?;
+
+    // the handshake as a whole times out although no single step has
+    clock.advance(Duration::from_secs(11));
+    peers.run_until(|peer, _context, event| match (peer, event) {
+        (Peer::Pat, ContextEvent::EndpointClientHandshakeFailed { handle, reason, .. }) => {
+            assert_eq!(handle, pat_handle);
+            match reason {
+                gosling::context::Error::EndpointHandshakeTimedOut(service_id, timeout) => {
+                    assert_eq!(service_id, endpoint_service_id);
+                    assert_eq!(timeout, Duration::from_secs(10));
+                }
+                reason => bail!("unexpected failure reason: {reason:?}"),
+            }
+            Ok(true)
+        }
+        (Peer::Alice, ContextEvent::EndpointServerHandshakeFailed { .. }) => Ok(false),
+        (peer, event) => unexpected_event(peer, event),
+    })
+}
+
+#[test]
+fn test_mock_endpoint_handshake_timeout() -> anyhow::Result<()> {
+    let mut peers =
+        MockPeers::with_timeouts(Duration::from_secs(60), Some(Duration::from_secs(1)))?;
+    let (endpoint_service_id, client_auth_private_key) = peers.grant_endpoint()?;
+
+    let pat_handle = peers.pat.endpoint_client_begin_handshake(
+        endpoint_service_id,
+        client_auth_private_key,
+        "test_channel".parse()?,
+    )?;
+
+    // Alice never answers the channel request
+    let mut alice_handle: Option<HandshakeHandle> = None;
+    let mut alice_failed = false;
+    let mut pat_failed = false;
+    peers.run_until(|peer, _context, event| {
+        match (peer, event) {
+            (Peer::Alice, ContextEvent::EndpointServerHandshakeStarted { handle }) => {
+                alice_handle = Some(handle);
+            }
+            (Peer::Alice, ContextEvent::EndpointServerChannelRequestReceived { handle, .. }) => {
+                assert_eq!(Some(handle), alice_handle);
+            }
+            (Peer::Alice, ContextEvent::EndpointServerHandshakeFailed { handle, .. }) => {
+                assert_eq!(Some(handle), alice_handle);
+                alice_failed = true;
+            }
+            (Peer::Pat, ContextEvent::ClientAuthAdded { .. }) => (),
+            (Peer::Pat, ContextEvent::EndpointClientHandshakeFailed { handle, .. }) => {
+                assert_eq!(handle, pat_handle);
+                pat_failed = true;
+            }
+            (peer, event) => return unexpected_event(peer, event),
+        }
+        Ok(alice_failed && pat_failed)
+    })
+}
+
+#[test]
+fn test_mock_endpoint_handshake_wrong_client_auth() -> anyhow::Result<()> {
+    let mut peers = MockPeers::new()?;
+    let (endpoint_service_id, _client_auth_private_key) = peers.grant_endpoint()?;
+
+    // the endpoint's descriptor cannot be decrypted with someone else's key
+    assert!(peers
+        .pat
+        .endpoint_client_begin_handshake(
+            endpoint_service_id.clone(),
+            X25519PrivateKey::generate(),
+            "test_channel".parse()?,
+        )
+        .is_err());
+
+    peers.run_until(|peer, _context, event| match (peer, event) {
+        (
+            Peer::Pat,
+            ContextEvent::ClientAuthAdded {
+                endpoint_service_id: added,
+            },
+        ) => {
+            assert_eq!(added, endpoint_service_id);
+            Ok(true)
+        }
+        (peer, event) => unexpected_event(peer, event),
+    })
+}
+
+#[test]
+fn test_mock_endpoint_handshake_shared() -> anyhow::Result<()> {
+    let mut peers = MockPeers::with_config(420, 421, Duration::from_secs(60), None)?;
+    let alice_service_id = peers.alice_service_id.clone();
+    let pat_service_id = peers.pat_service_id.clone();
+
+    // shared endpoint servers must be enabled before they can be started
+    let endpoint_private_key = Ed25519PrivateKey::generate();
+    let endpoint_service_id = V3OnionServiceId::from_private_key(&endpoint_private_key);
+    assert!(peers
+        .alice
+        .endpoint_server_start_shared(
+            endpoint_private_key.clone(),
+            "test_endpoint".parse()?,
+            pat_service_id.clone(),
+        )
+        .is_err());
+
+    // restart Alice's identity server with the shared endpoint virt-port
+    peers.alice.identity_server_stop()?;
+    peers
+        .alice
+        .identity_server_set_shared_endpoints_enabled(true);
+    peers.alice.identity_server_start()?;
+    peers.alice.endpoint_server_start_shared(
+        endpoint_private_key,
+        "test_endpoint".parse()?,
+        pat_service_id.clone(),
+    )?;
+    let mut identity_published = false;
+    let mut endpoint_published = false;
+    peers.run_until(|peer, _context, event| {
+        match (peer, event) {
+            (Peer::Alice, ContextEvent::IdentityServerPublished) => identity_published = true,
+            (
+                Peer::Alice,
+                ContextEvent::EndpointServerPublished {
+                    endpoint_service_id: published,
+                    endpoint_name,
+                },
+            ) => {
+                assert_eq!(published, endpoint_service_id);
+                assert_eq!(endpoint_name, "test_endpoint");
+                endpoint_published = true;
+            }
+            (peer, event) => return unexpected_event(peer, event),
+        }
+        Ok(identity_published && endpoint_published)
+    })?;
+
+    // shared endpoint servers have no onion-service of their own to configure
+    assert!(peers
+        .alice
+        .endpoint_server_set_client_auth_keys(
+            endpoint_service_id.clone(),
+            vec![X25519PublicKey::from_private_key(
+                &X25519PrivateKey::generate()
+            )],
+        )
+        .is_err());
+
+    // Pat connects through Alice's identity server without client authorization
+    let pat_handle = peers.pat.endpoint_client_begin_shared_handshake(
+        alice_service_id.clone(),
+        endpoint_service_id.clone(),
+        "test_channel".parse()?,
+    )?;
+    let mut alice_completed = false;
+    let mut pat_completed = false;
+    peers.run_until(|peer, context, event| {
+        match (peer, event) {
+            (Peer::Alice, ContextEvent::EndpointServerHandshakeStarted { .. }) => (),
+            (
+                Peer::Alice,
+                ContextEvent::EndpointServerChannelRequestReceived {
+                    handle,
+                    client_service_id,
+                    requested_channel,
+                },
+            ) => {
+                assert_eq!(client_service_id, pat_service_id);
+                assert_eq!(requested_channel, "test_channel");
+                context.endpoint_server_handle_channel_request_received(handle, true)?;
+            }
+            (
+                Peer::Alice,
+                ContextEvent::EndpointServerHandshakeCompleted {
+                    endpoint_service_id: completed,
+                    client_service_id,
+                    ..
+                },
+            ) => {
+                assert_eq!(completed, endpoint_service_id);
+                assert_eq!(client_service_id, pat_service_id);
+                alice_completed = true;
+            }
+            (
+                Peer::Pat,
+                ContextEvent::EndpointClientHandshakeCompleted {
+                    handle,
+                    endpoint_service_id: completed,
+                    ..
+                },
+            ) => {
+                assert_eq!(handle, pat_handle);
+                assert_eq!(completed, endpoint_service_id);
+                pat_completed = true;
+            }
+            (peer, event) => return unexpected_event(peer, event),
+        }
+        Ok(alice_completed && pat_completed)
+    })?;
+
+    // endpoint servers Alice is not sharing are rejected
+    let pat_handle = peers.pat.endpoint_client_begin_shared_handshake(
+        alice_service_id,
+        V3OnionServiceId::from_private_key(&Ed25519PrivateKey::generate()),
+        "test_channel".parse()?,
+    )?;
+    let mut alice_failed = false;
+    let mut pat_failed = false;
+    peers.run_until(|peer, _context, event| {
+        match (peer, event) {
+            (Peer::Alice, ContextEvent::EndpointServerHandshakeStarted { .. }) => (),
+            (Peer::Alice, ContextEvent::EndpointServerHandshakeFailed { .. }) => {
+                alice_failed = true;
+            }
+            (Peer::Pat, ContextEvent::EndpointClientHandshakeFailed { handle, .. }) => {
+                assert_eq!(handle, pat_handle);
+                pat_failed = true;
+            }
+            (peer, event) => return unexpected_event(peer, event),
+        }
+        Ok(alice_failed && pat_failed)
+    })
+}
+
+#[test]
+fn test_mock_descriptor_refresh() -> anyhow::Result<()> {
+    let mut peers = MockPeers::new()?;
+    let (endpoint_service_id, _) = peers.grant_endpoint()?;
+
+    peers.alice.identity_server_refresh_descriptor()?;
+    peers
+        .alice
+        .endpoint_server_refresh_descriptor(endpoint_service_id.clone())?;
+    let mut identity_refreshed = false;
+    let mut endpoint_refreshed = false;
+    peers.run_until(|peer, _context, event| {
+        match (peer, event) {
+            (Peer::Alice, ContextEvent::IdentityServerDescriptorRefreshed) => {
+                identity_refreshed = true;
+            }
+            (
+                Peer::Alice,
+                ContextEvent::EndpointServerDescriptorRefreshed {
+                    endpoint_service_id: refreshed,
+                    endpoint_name,
+                },
+            ) => {
+                assert_eq!(refreshed, endpoint_service_id);
+                assert_eq!(endpoint_name, "test_endpoint");
+                endpoint_refreshed = true;
+            }
+            (peer, event) => return unexpected_event(peer, event),
+        }
+        Ok(identity_refreshed && endpoint_refreshed)
+    })?;
+
+    // only running servers' descriptors may be refreshed
+    assert!(peers
+        .alice
+        .endpoint_server_refresh_descriptor(V3OnionServiceId::from_private_key(
+            &Ed25519PrivateKey::generate()
+        ))
+        .is_err());
+    peers.alice.identity_server_stop()?;
+    assert!(peers.alice.identity_server_refresh_descriptor().is_err());
+
+    assert!(peers
+        .alice
+        .set_descriptor_republish_interval(Some(Duration::ZERO))
+        .is_err());
+    peers
+        .alice
+        .set_descriptor_republish_interval(Some(Duration::from_secs(3600)))?;
+    peers.alice.set_descriptor_republish_interval(None)?;
+    Ok(())
+}
+
+#[test]
+fn test_mock_security_level() -> anyhow::Result<()> {
+    let mut peers = MockPeers::new()?;
+
+    // hardening applies to the tor provider's circuits without
+    // interrupting the handshakes made over them
+    peers.alice.set_security_level(SecurityLevel::Hardened)?;
+    peers.pat.set_security_level(SecurityLevel::Hardened)?;
+    peers.grant_endpoint()?;
+
+    peers.alice.set_security_level(SecurityLevel::Standard)?;
+    peers.pat.set_security_level(SecurityLevel::Standard)?;
+    Ok(())
+}
+
+#[test]
+fn test_mock_name_length_limits() -> anyhow::Result<()> {
+    let mut peers = MockPeers::new()?;
+
+    // names which the peer would refuse are rejected before a handshake begins
+    let long_endpoint = AsciiString::new("e".repeat(MAX_ENDPOINT_NAME_LENGTH + 1))?;
+    assert!(matches!(
+        peers.pat.identity_client_begin_handshake(
+            peers.alice_service_id.clone(),
+            long_endpoint.clone(),
+        ),
+        Err(gosling::context::Error::EndpointNameTooLong(length)) if length == MAX_ENDPOINT_NAME_LENGTH + 1
+    ));
+    assert!(matches!(
+        peers
+            .pat
+            .identity_client_begin_handshake_with_additional_endpoints(
+                peers.alice_service_id.clone(),
+                "test_endpoint".parse()?,
+                vec![long_endpoint],
+            ),
+        Err(gosling::context::Error::EndpointNameTooLong(_))
+    ));
+
+    let long_channel = AsciiString::new("c".repeat(MAX_CHANNEL_NAME_LENGTH + 1))?;
+    assert!(matches!(
+        peers.pat.add_rpc_channel(long_channel.clone()),
+        Err(gosling::context::Error::ChannelNameTooLong(length)) if length == MAX_CHANNEL_NAME_LENGTH + 1
+    ));
+    let (endpoint_service_id, client_auth_private_key) = peers.grant_endpoint()?;
+    assert!(matches!(
+        peers.pat.endpoint_client_begin_handshake(
+            endpoint_service_id,
+            client_auth_private_key,
+            long_channel,
+        ),
+        Err(gosling::context::Error::ChannelNameTooLong(_))
+    ));
+
+    // names of exactly the maximum length are allowed
+    peers
+        .pat
+        .add_rpc_channel(AsciiString::new("c".repeat(MAX_CHANNEL_NAME_LENGTH))?)?;
+    Ok(())
+}
+
+#[test]
+fn test_mock_endpoint_revocation() -> anyhow::Result<()> {
+    let mut peers = MockPeers::new()?;
+    let (endpoint_service_id, client_auth_private_key) = peers.grant_endpoint()?;
+
+    peers.pat.endpoint_client_begin_handshake(
+        endpoint_service_id.clone(),
+        client_auth_private_key,
+        "test_channel".parse()?,
+    )?;
+
+    let mut alice_stream: Option<TcpStream> = None;
+    let mut pat_stream: Option<TcpStream> = None;
+    peers.run_until(|peer, context, event| {
+        match (peer, event) {
+            (Peer::Alice, ContextEvent::EndpointServerHandshakeStarted { .. }) => (),
+            (Peer::Alice, ContextEvent::EndpointServerChannelRequestReceived { handle, .. }) => {
+                context.endpoint_server_handle_channel_request_received(handle, true)?;
+            }
+            (Peer::Alice, ContextEvent::EndpointServerHandshakeCompleted { stream, .. }) => {
+                alice_stream = Some(stream);
+            }
+            (Peer::Pat, ContextEvent::ClientAuthAdded { .. }) => (),
+            (Peer::Pat, ContextEvent::EndpointClientHandshakeCompleted { stream, .. }) => {
+                pat_stream = Some(stream);
+            }
+            (peer, event) => return unexpected_event(peer, event),
+        }
+        Ok(alice_stream.is_some() && pat_stream.is_some())
+    })?;
+
+    // unknown endpoint servers cannot be revoked
+    assert!(peers
+        .alice
+        .endpoint_server_revoke(
+            V3OnionServiceId::from_private_key(&Ed25519PrivateKey::generate()),
+            Default::default()
+        )
+        .is_err());
+
+    // Alice revokes Pat's access and notifies Pat over the live channel
+    let notified = peers
+        .alice
+        .endpoint_server_revoke(endpoint_service_id.clone(), vec![alice_stream.unwrap()])?;
+    assert_eq!(notified, 1);
+    assert!(peers
+        .alice
+        .endpoint_server_stop(endpoint_service_id.clone())
+        .is_err());
+
+    let pat_stream = pat_stream.unwrap();
+    pat_stream.set_nonblocking(false)?;
+    pat_stream.set_read_timeout(Some(TEST_DEADLINE))?;
+    let mut line = String::new();
+    BufReader::new(pat_stream).read_line(&mut line)?;
+    let revocation = EndpointRevocation::from_string(&line)?;
+    assert_eq!(*revocation.endpoint_service_id(), endpoint_service_id);
+    assert_eq!(*revocation.client_service_id(), peers.pat_service_id);
+
+    // the revocation was not issued to Alice
+    assert!(peers
+        .alice
+        .endpoint_client_handle_revocation(&revocation)
+        .is_err());
+
+    peers.pat.endpoint_client_handle_revocation(&revocation)?;
+    let mut client_auth_removed = false;
+    let mut endpoint_revoked = false;
+    peers.run_until(|peer, _context, event| {
+        match (peer, event) {
+            (
+                Peer::Pat,
+                ContextEvent::ClientAuthRemoved {
+                    endpoint_service_id: removed,
+                },
+            ) => {
+                assert_eq!(removed, endpoint_service_id);
+                client_auth_removed = true;
+            }
+            (
+                Peer::Pat,
+                ContextEvent::EndpointClientEndpointRevoked {
+                    endpoint_service_id: revoked,
+                    revoked_at,
+                },
+            ) => {
+                assert_eq!(revoked, endpoint_service_id);
+                assert_eq!(revoked_at, revocation.revoked_at());
+                endpoint_revoked = true;
+            }
+            (peer, event) => return unexpected_event(peer, event),
+        }
+        Ok(client_auth_removed && endpoint_revoked)
+    })?;
+    assert!(peers.pat.client_auth_entries().is_empty());
+    Ok(())
+}
+
+#[test]
+fn test_mock_client_auth_retention() -> anyhow::Result<()> {
+    let mut peers = MockPeers::new()?;
+    let (endpoint_service_id, client_auth_private_key) = peers.grant_endpoint()?;
+
+    // run an endpoint handshake which Alice accepts or rejects, returning
+    // whether Pat's client auth key was removed once it finished
+    let run_handshake = |peers: &mut MockPeers, accept: bool| -> anyhow::Result<bool> {
+        peers.pat.endpoint_client_begin_handshake(
+            endpoint_service_id.clone(),
+            client_auth_private_key.clone(),
+            "test_channel".parse()?,
+        )?;
+        let mut alice_finished = false;
+        let mut pat_finished = false;
+        let mut client_auth_removed = false;
+        peers.run_until(|peer, context, event| {
+            match (peer, event) {
+                (Peer::Alice, ContextEvent::EndpointServerHandshakeStarted { .. }) => (),
+                (
+                    Peer::Alice,
+                    ContextEvent::EndpointServerChannelRequestReceived { handle, .. },
+                ) => {
+                    context.endpoint_server_handle_channel_request_received(handle, accept)?;
+                }
+                (Peer::Alice, ContextEvent::EndpointServerHandshakeCompleted { .. })
+                | (Peer::Alice, ContextEvent::EndpointServerHandshakeRejected { .. }) => {
+                    alice_finished = true;
+                }
+                (Peer::Pat, ContextEvent::ClientAuthAdded { .. }) => (),
+                (Peer::Pat, ContextEvent::EndpointClientHandshakeCompleted { .. }) => {
+                    assert!(accept);
+                    pat_finished = true;
+                }
+                (Peer::Pat, ContextEvent::EndpointClientHandshakeFailed { .. }) => {
+                    assert!(!accept);
+                    pat_finished = true;
+                }
+                (
+                    Peer::Pat,
+                    ContextEvent::ClientAuthRemoved {
+                        endpoint_service_id: removed,
+                    },
+                ) => {
+                    assert_eq!(removed, endpoint_service_id);
+                    client_auth_removed = true;
+                }
+                (peer, event) => return unexpected_event(peer, event),
+            }
+            // removals are reported by the update which finishes the handshake
+            Ok(alice_finished && pat_finished)
+        })?;
+        // drain the remainder of the update which finished the handshake
+        while let Some((peer, event)) = peers.pending_events.pop_front() {
+            match (peer, event) {
+                (Peer::Pat, ContextEvent::ClientAuthRemoved { .. }) => client_auth_removed = true,
+                (peer, event) => return unexpected_event(peer, event),
+            }
+        }
+        Ok(client_auth_removed)
+    };
+
+    // keys are retained by default
+    assert!(!run_handshake(&mut peers, false)?);
+    assert_eq!(
+        peers.pat.client_auth_entries(),
+        std::slice::from_ref(&endpoint_service_id)
+    );
+
+    // only failed handshakes remove keys...
+    peers
+        .pat
+        .set_client_auth_retention(ClientAuthRetention::RemoveOnFailure);
+    assert!(!run_handshake(&mut peers, true)?);
+    assert!(run_handshake(&mut peers, false)?);
+    assert!(peers.pat.client_auth_entries().is_empty());
+
+    // ...unless keys are removed whatever the outcome
+    peers
+        .pat
+        .set_client_auth_retention(ClientAuthRetention::RemoveOnCompletion);
+    assert!(run_handshake(&mut peers, true)?);
+    assert!(peers.pat.client_auth_entries().is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn test_mock_derived_virt_ports() -> anyhow::Result<()> {
+    // derived ports are stable and avoid well-known ports
+    let service_id = V3OnionServiceId::from_private_key(&Ed25519PrivateKey::generate());
+    let virt_port = Context::derived_virt_port(&service_id);
+    assert!(virt_port >= 1024);
+    assert_eq!(Context::derived_virt_port(&service_id), virt_port);
+    let public_key = Ed25519PublicKey::from_service_id(&service_id)?;
+    let public_key = public_key.as_bytes();
+    assert_eq!(
+        virt_port,
+        1024 + u16::from_be_bytes([public_key[0], public_key[1]]) % (u16::MAX - 1024 + 1)
+    );
+
+    // service-ids whose key is not a valid ed25519 point also have a port
+    let service_id =
+        V3OnionServiceId::from_string("aiaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaab3did")?;
+    assert!(Context::derived_virt_port(&service_id) >= 1024);
+
+    // peers which both derive their ports can complete both handshakes
+    let mut peers = MockPeers::with_config(0, 0, Duration::from_secs(60), None)?;
+    let (endpoint_service_id, client_auth_private_key) = peers.grant_endpoint()?;
+
+    let pat_handle = peers.pat.endpoint_client_begin_handshake(
+        endpoint_service_id,
+        client_auth_private_key,
+        "test_channel".parse()?,
+    )?;
+    let mut alice_completed = false;
+    let mut pat_completed = false;
+    peers.run_until(|peer, context, event| {
+        match (peer, event) {
+            (Peer::Alice, ContextEvent::EndpointServerHandshakeStarted { .. }) => (),
+            (Peer::Alice, ContextEvent::EndpointServerChannelRequestReceived { handle, .. }) => {
+                context.endpoint_server_handle_channel_request_received(handle, true)?;
+            }
+            (Peer::Alice, ContextEvent::EndpointServerHandshakeCompleted { .. }) => {
+                alice_completed = true;
+            }
+            (Peer::Pat, ContextEvent::ClientAuthAdded { .. }) => (),
+            (Peer::Pat, ContextEvent::EndpointClientHandshakeCompleted { handle, .. }) => {
+                assert_eq!(handle, pat_handle);
+                pat_completed = true;
+            }
+            (peer, event) => return unexpected_event(peer, event),
+        }
+        Ok(alice_completed && pat_completed)
+    })
+}
+
+#[test]
+fn test_mock_listener_conflict() -> anyhow::Result<()> {
+    let mut peers = MockPeers::new()?;
+    let client_auth = X25519PublicKey::from_private_key(&X25519PrivateKey::generate());
+
+    // the identity server's onion-service cannot also host an endpoint server
+    match peers.alice.endpoint_server_start(
+        peers.alice_private_key.clone(),
+        "test_endpoint".parse()?,
+        peers.pat_service_id.clone(),
+        client_auth.clone(),
+        false,
+    ) {
+        Err(gosling::context::Error::ListenerConflict(service_id, 420, _)) => {
+            assert_eq!(service_id, peers.alice_service_id);
+        }
+        result => bail!("unexpected result: {:?}", result),
+    }
+
+    // nor can an endpoint server's onion-service be started twice, whether
+    // it is already running or requested twice at once
+    let endpoint_private_key = Ed25519PrivateKey::generate();
+    let config = EndpointConfig {
+        private_key: endpoint_private_key,
+        endpoint_name: "test_endpoint".parse()?,
+        client_identity: peers.pat_service_id.clone(),
+        client_auth_keys: vec![client_auth],
+        non_anonymous: false,
+        pow_defenses: None,
+        shared: false,
+    };
+    let results = peers
+        .alice
+        .endpoint_servers_start(vec![config.clone(), config.clone()])?;
+    assert!(results[0].1.is_ok());
+    assert!(matches!(
+        results[1].1,
+        Err(gosling::context::Error::ListenerConflict(_, 420, _))
+    ));
+    let results = peers.alice.endpoint_servers_start(vec![config])?;
+    assert!(matches!(
+        results[0].1,
+        Err(gosling::context::Error::ListenerConflict(_, 420, _))
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn test_mock_endpoint_publish_completion() -> anyhow::Result<()> {
+    let mut peers = MockPeers::new()?;
+    let client_auth = X25519PublicKey::from_private_key(&X25519PrivateKey::generate());
+
+    let endpoint_private_key = Ed25519PrivateKey::generate();
+    let endpoint_service_id = V3OnionServiceId::from_private_key(&endpoint_private_key);
+    let handle = peers.alice.endpoint_server_start_with_completion(
+        endpoint_private_key,
+        "test_endpoint".parse()?,
+        peers.pat_service_id.clone(),
+        client_auth.clone(),
+        false,
+        Some(Duration::from_secs(60)),
+    )?;
+
+    // an endpoint server stopped before it is published never completes
+    let stopped_private_key = Ed25519PrivateKey::generate();
+    let stopped_service_id = V3OnionServiceId::from_private_key(&stopped_private_key);
+    let stopped_handle = peers.alice.endpoint_server_start_with_completion(
+        stopped_private_key,
+        "stopped_endpoint".parse()?,
+        peers.pat_service_id.clone(),
+        client_auth,
+        false,
+        None,
+    )?;
+    assert_ne!(handle, stopped_handle);
+    peers.alice.endpoint_server_stop(stopped_service_id)?;
+
+    // the completion follows the endpoint server's publication
+    let mut endpoint_published = false;
+    peers.run_until(|peer, _context, event| match (peer, event) {
+        (
+            Peer::Alice,
+            ContextEvent::EndpointServerPublished {
+                endpoint_service_id: published,
+                ..
+            },
+        ) => {
+            assert_eq!(published, endpoint_service_id);
+            endpoint_published = true;
+            Ok(false)
+        }
+        (
+            Peer::Alice,
+            ContextEvent::EndpointServerPublishCompleted {
+                handle: completed,
+                endpoint_service_id: published,
+                endpoint_name,
+            },
+        ) => {
+            assert!(endpoint_published);
+            assert_eq!(completed, handle);
+            assert_eq!(published, endpoint_service_id);
+            assert_eq!(endpoint_name, "test_endpoint");
+            Ok(true)
+        }
+        (peer, event) => unexpected_event(peer, event),
+    })
+}
@@ -0,0 +1,63 @@
+// standard
+use std::collections::VecDeque;
+use std::time::Duration;
+
+// extern crates
+use bson::document::Document;
+
+// internal crates
+use gosling::prelude::*;
+
+// The prelude's names and the signatures of the Context methods every
+// application needs are covered by the crate's semver guarantee; this test
+// fails to build if any of them is removed or changed incompatibly.
+#[test]
+#[allow(clippy::type_complexity)]
+fn test_prelude_api() {
+    let _: fn(
+        Box<dyn TorProvider>,
+        u16,
+        u16,
+        Duration,
+        i32,
+        Option<Duration>,
+        Ed25519PrivateKey,
+    ) -> Result<Context, ContextError> = Context::new;
+    let _: fn(&mut Context) -> Result<(), ContextError> = Context::bootstrap;
+    let _: fn(&mut Context) -> Result<VecDeque<ContextEvent>, ContextError> = Context::update;
+
+    // identity handshake
+    let _: fn(
+        &mut Context,
+        V3OnionServiceId,
+        AsciiString,
+    ) -> Result<HandshakeHandle, ContextError> = Context::identity_client_begin_handshake;
+    let _: fn(&mut Context, HandshakeHandle, Document) -> Result<(), ContextError> =
+        Context::identity_client_handle_challenge_received;
+    let _: fn(&mut Context) -> Result<(), ContextError> = Context::identity_server_start;
+    let _: fn(&mut Context) -> Result<(), ContextError> = Context::identity_server_stop;
+    let _: fn(&mut Context, HandshakeHandle, bool, bool, Document) -> Result<(), ContextError> =
+        Context::identity_server_handle_endpoint_request_received;
+    let _: fn(&mut Context, HandshakeHandle, bool) -> Result<(), ContextError> =
+        Context::identity_server_handle_challenge_response_received;
+
+    // endpoint handshake
+    let _: fn(
+        &mut Context,
+        V3OnionServiceId,
+        X25519PrivateKey,
+        AsciiString,
+    ) -> Result<HandshakeHandle, ContextError> = Context::endpoint_client_begin_handshake;
+    let _: fn(
+        &mut Context,
+        Ed25519PrivateKey,
+        AsciiString,
+        V3OnionServiceId,
+        X25519PublicKey,
+        bool,
+    ) -> Result<(), ContextError> = Context::endpoint_server_start;
+    let _: fn(&mut Context, HandshakeHandle, bool) -> Result<(), ContextError> =
+        Context::endpoint_server_handle_channel_request_received;
+    let _: fn(&mut Context, V3OnionServiceId) -> Result<(), ContextError> =
+        Context::endpoint_server_stop;
+}
@@ -0,0 +1,180 @@
+// Measures the bandwidth saved by compressing Honk-RPC messages, along with
+// the time taken to complete each workload.
+//
+// Run with: cargo bench --features compression
+
+// standard
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::time::{Duration, Instant};
+
+// extern crates
+use bson::doc;
+use sha3::{Digest, Sha3_256};
+
+// internal crates
+use honk_rpc::honk_rpc::*;
+
+const ITERATIONS: usize = 256;
+
+// builds the arguments of the given iteration's request
+type Workload = fn(usize) -> bson::document::Document;
+
+// an apiset whose bench::echo_0() returns its arguments
+struct EchoApiSet;
+
+impl ApiSet for EchoApiSet {
+    fn namespace(&self) -> &str {
+        "bench"
+    }
+
+    fn exec_function(
+        &mut self,
+        name: &str,
+        version: i32,
+        args: bson::document::Document,
+        _request_cookie: Option<RequestCookie>,
+    ) -> Option<Result<Option<bson::Bson>, ErrorCode>> {
+        match (name, version) {
+            ("echo", 0) => Some(Ok(Some(bson::Bson::Document(args)))),
+            _ => Some(Err(ErrorCode::RequestFunctionInvalid)),
+        }
+    }
+}
+
+// a stream which counts the bytes written to it
+struct CountingStream {
+    stream: TcpStream,
+    bytes_written: usize,
+}
+
+impl Read for CountingStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+impl Write for CountingStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let count = self.stream.write(buf)?;
+        self.bytes_written += count;
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+// deterministic incompressible bytes
+fn random_bytes(seed: &str, len: usize) -> Vec<u8> {
+    let mut bytes: Vec<u8> = Default::default();
+    let mut block = Sha3_256::digest(seed.as_bytes());
+    while bytes.len() < len {
+        bytes.extend_from_slice(&block);
+        block = Sha3_256::digest(block);
+    }
+    bytes.truncate(len);
+    bytes
+}
+
+fn binary(bytes: Vec<u8>) -> bson::Bson {
+    bson::Bson::Binary(bson::Binary {
+        subtype: bson::spec::BinarySubtype::Generic,
+        bytes,
+    })
+}
+
+// resembles a gosling identity handshake's challenge response
+fn challenge_response(i: usize) -> bson::document::Document {
+    doc! {
+        "client_cookie" : binary(random_bytes(&format!("client_cookie{i}"), 32)),
+        "client_identity_proof_signature" : binary(random_bytes(&format!("signature{i}"), 64)),
+        "client_authorization_key" : binary(random_bytes(&format!("key{i}"), 32)),
+        "client_authorization_key_signbit" : false,
+        "client_authorization_signature" : binary(random_bytes(&format!("auth{i}"), 64)),
+        "challenge_response" : {
+            "endpoint" : "chat",
+            "nonce" : binary(random_bytes(&format!("nonce{i}"), 32)),
+        },
+    }
+}
+
+// resembles a batch of application chat messages
+fn app_payload(i: usize) -> bson::document::Document {
+    let mut messages = bson::Array::new();
+    for j in 0..8 {
+        messages.push(bson::Bson::Document(doc! {
+            "from" : "3q5nzcsmjpmgjbxpmdbnrwtohoshhpylvqtqmsnxq5kqv6s5gmtdvoad",
+            "timestamp" : (i * 8 + j) as i64,
+            "text" : format!("message {j} of batch {i}: see you at the usual place tomorrow at noon"),
+        }));
+    }
+    doc! { "messages" : messages }
+}
+
+// returns (bytes written by both peers, elapsed time)
+fn run(threshold: Option<usize>, workload: Workload) -> anyhow::Result<(usize, Duration)> {
+    let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0u16)))?;
+    let stream1 = TcpStream::connect(listener.local_addr()?)?;
+    stream1.set_nonblocking(true)?;
+    let (stream2, _socket_addr) = listener.accept()?;
+    stream2.set_nonblocking(true)?;
+
+    let mut alice = Session::new(CountingStream {
+        stream: stream1,
+        bytes_written: 0,
+    });
+    let mut pat = Session::new(CountingStream {
+        stream: stream2,
+        bytes_written: 0,
+    });
+    alice.set_max_message_size(64 * 1024)?;
+    pat.set_max_message_size(64 * 1024)?;
+    alice.set_compression_threshold(threshold);
+    pat.set_compression_threshold(threshold);
+
+    let start = Instant::now();
+    for i in 0..ITERATIONS {
+        pat.client_call("bench", "echo", 0, workload(i))?;
+        loop {
+            alice.update(Some(&mut [&mut EchoApiSet]))?;
+            pat.update(None)?;
+            match pat.client_next_response() {
+                Some(Response::Success { .. }) => break,
+                Some(_) => anyhow::bail!("unexpected response"),
+                None => (),
+            }
+        }
+    }
+    let elapsed = start.elapsed();
+
+    Ok((
+        alice.into_stream().bytes_written + pat.into_stream().bytes_written,
+        elapsed,
+    ))
+}
+
+fn main() -> anyhow::Result<()> {
+    let workloads: [(&str, Workload); 2] = [
+        ("challenge response", challenge_response),
+        ("app payload", app_payload),
+    ];
+
+    println!("{ITERATIONS} echo round-trips per workload");
+    for (name, workload) in workloads {
+        let (uncompressed_bytes, uncompressed_elapsed) = run(None, workload)?;
+        println!(
+            "{name:>20}: uncompressed {uncompressed_bytes:>8} bytes {uncompressed_elapsed:>12.2?}"
+        );
+        for threshold in [DEFAULT_COMPRESSION_THRESHOLD, 1024] {
+            let (bytes, elapsed) = run(Some(threshold), workload)?;
+            let savings = 100.0 * (1.0 - bytes as f64 / uncompressed_bytes as f64);
+            println!(
+                "{name:>20}: threshold {threshold:>5} {bytes:>8} bytes {elapsed:>12.2?} ({savings:.1}% saved)"
+            );
+        }
+    }
+
+    Ok(())
+}
@@ -1,14 +1,23 @@
 // standard
-use std::collections::VecDeque;
+use std::collections::{BTreeMap, VecDeque};
 use std::fmt::Debug;
+#[cfg(feature = "compression")]
+use std::io::Read;
 use std::io::{Cursor, ErrorKind};
 #[cfg(test)]
 use std::net::{SocketAddr, TcpListener, TcpStream};
 use std::option::Option;
+use std::panic::AssertUnwindSafe;
+// std::time::Instant::now() panics on wasm32-unknown-unknown, so the
+// host's clock is used there instead
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+use std::time::Instant;
 
 // extern crates
 use bson::doc;
 use bson::document::ValueAccessError;
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+use web_time::Instant;
 
 use crate::byte_counter::ByteCounter;
 
@@ -41,6 +50,8 @@ pub enum ErrorCode {
     ResponseCookieInvalid,
     /// Provided response state is not valid.
     ResponseStateInvalid,
+    /// The receiver failed internally while handling a request.
+    InternalError,
     /// Represents an application-specific runtime error with a specific error code.
     Runtime(i32),
     /// Represents an unknown error with a specific error code.
@@ -66,6 +77,10 @@ pub enum Error {
     #[error("waited longer than {} seconds for read", .0.as_secs_f32())]
     MessageReadTimedOut(std::time::Duration),
 
+    /// Failed to write any pending data within the required timeout
+    #[error("waited longer than {} seconds for write", .0.as_secs_f32())]
+    MessageWriteTimedOut(std::time::Duration),
+
     /// Failed to parse bson message
     #[error("failed to parse bson Message document")]
     BsonDocumentParseFailed(#[source] bson::de::Error),
@@ -96,9 +111,33 @@ pub enum Error {
     )]
     InvalidMaxMesageSize(),
 
+    /// Attempted to define a low write watermark greater than the high write watermark
+    #[error("tried to set invalid write watermarks; low watermark {0} must not be greater than high watermark {1}")]
+    InvalidWriteWatermarks(usize, usize),
+
     /// Attempted to send a Honk-RPC `section` that is too large to fit in a message
     #[error("queued message section is too large to write; calculated size is {0} but must be less than {1}")]
     SectionTooLarge(usize, usize),
+
+    /// Failed to compress an outgoing message due to `std::io::Error`
+    #[error("failed to compress message")]
+    MessageCompressionFailed(#[source] std::io::Error),
+
+    /// Failed to decompress a received message due to `std::io::Error`
+    #[error("failed to decompress message")]
+    MessageDecompressionFailed(#[source] std::io::Error),
+
+    /// Received compressed message decompresses to more than the maximum message size
+    #[error("received compressed message larger than max message size of {0} bytes")]
+    DecompressedMessageTooLarge(usize),
+
+    /// More than one `ApiSet` handles the same namespace
+    #[error("more than one ApiSet handles the namespace '{0}'")]
+    ApiSetNamespaceConflict(String),
+
+    /// An `ApiSet` panicked while handling a request; the panic's message is included if it has one
+    #[error("ApiSet handling the namespace '{0}' panicked: {1}")]
+    ApiSetPanicked(String, String),
 }
 
 impl From<i32> for ErrorCode {
@@ -116,6 +155,7 @@ impl From<i32> for ErrorCode {
             -10i32 => ErrorCode::RequestVersionInvalid,
             -11i32 => ErrorCode::ResponseCookieInvalid,
             -12i32 => ErrorCode::ResponseStateInvalid,
+            -13i32 => ErrorCode::InternalError,
             value => {
                 if value > 0 {
                     ErrorCode::Runtime(value)
@@ -142,6 +182,7 @@ impl From<ErrorCode> for i32 {
             ErrorCode::RequestVersionInvalid => -10i32,
             ErrorCode::ResponseCookieInvalid => -11i32,
             ErrorCode::ResponseStateInvalid => -12i32,
+            ErrorCode::InternalError => -13i32,
             ErrorCode::Runtime(val) => val,
             ErrorCode::Unknown(val) => val,
         }
@@ -185,6 +226,9 @@ impl std::fmt::Display for ErrorCode {
                 write!(f, "ProtocolError: response cookie is not recognized")
             }
             ErrorCode::ResponseStateInvalid => write!(f, "ProtocolError: response state not valid"),
+            ErrorCode::InternalError => {
+                write!(f, "ProtocolError: receiver failed while handling request")
+            }
             ErrorCode::Runtime(code) => write!(f, "RuntimeError: runtime error {}", code),
             ErrorCode::Unknown(code) => write!(f, "UnknownError: unknown error code {}", code),
         }
@@ -215,6 +259,13 @@ const fn i32_to_semver(ver: i32) -> Option<(u8, u8, u8)> {
 // Honk-RPC version 0.1.0
 const HONK_RPC_VERSION: i32 = semver_to_i32(0, 1, 0);
 
+// message field listing the compression algorithms the sender can decompress
+#[cfg(feature = "compression")]
+const ACCEPT_COMPRESSION_KEY: &str = "accept_compression";
+// message field holding an entire zstd-compressed message in place of its sections
+#[cfg(feature = "compression")]
+const ZSTD_KEY: &str = "zstd";
+
 struct Message {
     honk_rpc: i32,
     sections: Vec<Section>,
@@ -641,6 +692,8 @@ pub trait ApiSet {
     /// - Synchronous requests may execute and signal success by returning `Some(Ok(..))`.
     /// - Synchronous requests may execute and signal failure by returning `Some(Err(..))`.
     /// - Asynchronous requests must defer execution by returning `None`.
+    ///
+    /// A panic in this or any other `ApiSet` method is caught by [`Session::update()`], which then ends the session.
     fn exec_function(
         &mut self,
         name: &str,
@@ -665,6 +718,13 @@ pub trait ApiSet {
     fn next_result(&mut self) -> Option<(RequestCookie, Result<Option<bson::Bson>, ErrorCode>)> {
         None
     }
+
+    /// Returns application-specific data to send along with the error for a failed request. This is called once each time a request with the given cookie fails, whether synchronously from `exec_function()` or asynchronously from `next_result()`. The remote `Session` makes the data available through [`Session::client_take_error_data()`].
+    ///
+    /// This method is optional and not needed if the implementor never attaches data to its errors, in which case the default implementation will return `None`.
+    fn error_data(&mut self, _request_cookie: RequestCookie) -> Option<bson::Bson> {
+        None
+    }
 }
 
 /// Represents the response to a client request.
@@ -695,6 +755,13 @@ pub enum Response {
 pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 4 * 1024;
 /// The default maximum allowed duration between Honk-RPC (60 seconds)
 pub const DEFAULT_MAX_WAIT_TIME: std::time::Duration = std::time::Duration::from_secs(60);
+/// The default number of unwritten bytes at which a `Session` stops handling new requests (64 kilobytes)
+pub const DEFAULT_WRITE_HIGH_WATERMARK: usize = 64 * 1024;
+/// The default number of unwritten bytes at which a `Session` resumes handling new requests (16 kilobytes)
+pub const DEFAULT_WRITE_LOW_WATERMARK: usize = 16 * 1024;
+/// A reasonable message size at which to start compressing messages (256 bytes); smaller messages rarely shrink enough to be worth compressing. See [`Session::set_compression_threshold()`].
+#[cfg(feature = "compression")]
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 256;
 
 // Base Message Bson Format
 // document size             4 (sizeof i32 )
@@ -827,6 +894,53 @@ pub fn get_response_section_size(result: Option<bson::Bson>) -> Result<usize, Er
     Ok(counter.bytes())
 }
 
+/// Combines the independently owned read and write halves of a connection into a single stream which may be used by a [`Session`]. See [`Session::from_split()`].
+pub struct SplitStream<R, W> {
+    reader: R,
+    writer: W,
+}
+
+impl<R, W> SplitStream<R, W> {
+    /// Creates a new `SplitStream` from the given `reader` and `writer` halves.
+    pub fn new(reader: R, writer: W) -> Self {
+        Self { reader, writer }
+    }
+
+    /// Consumes the `SplitStream` and returns the underlying read and write halves.
+    pub fn into_split(self) -> (R, W) {
+        (self.reader, self.writer)
+    }
+}
+
+impl<R: std::io::Read, W> std::io::Read for SplitStream<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+impl<R, W: std::io::Write> std::io::Write for SplitStream<R, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Traffic counters of a [`Session`], see [`Session::stats()`].
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SessionStats {
+    /// The number of bytes written to the underlying `RW`
+    pub bytes_sent: u64,
+    /// The number of bytes read from the underlying `RW`
+    pub bytes_received: u64,
+    /// The number of remote procedure calls made with [`Session::client_call()`]
+    pub client_calls: u64,
+    /// The number of remote procedure calls received from the peer
+    pub requests_received: u64,
+}
+
 /// The object that handles the communication between two endpoints  using the
 /// Honk-RPC protocol. Provides methods for setting and getting configuration
 /// parameters, reading and processing message documents, and handling API
@@ -850,6 +964,8 @@ pub struct Session<RW> {
     inbound_requests: Vec<RequestSection>,
     // remote server's responses to local client's remote procedure calls
     inbound_responses: VecDeque<Response>,
+    // data attached to the remote server's error responses, until taken
+    inbound_error_data: BTreeMap<RequestCookie, bson::Bson>,
 
     // message write data
 
@@ -857,6 +973,8 @@ pub struct Session<RW> {
     message_serialization_buffer: VecDeque<u8>,
     // the next request cookie to use when making a remote prodedure call
     next_cookie: RequestCookie,
+    // tracing span and start time of each client call still awaiting a response
+    pending_client_calls: BTreeMap<RequestCookie, (tracing::Span, Instant)>,
     // sections to be sent to the remote server
     outbound_sections: Vec<bson::Document>,
 
@@ -866,7 +984,38 @@ pub struct Session<RW> {
     // before terminating the session
     max_wait_time: std::time::Duration,
     // last time a new message read began
-    read_timestamp: std::time::Instant,
+    read_timestamp: Instant,
+
+    // write backpressure data
+
+    // stop handling new requests once this many bytes are waiting to be written
+    write_high_watermark: usize,
+    // resume handling new requests once no more than this many bytes are waiting to be written
+    write_low_watermark: usize,
+    // whether new requests are currently held back until pending writes drain
+    write_backpressure: bool,
+    // last time pending data was written, or there was no pending data
+    write_timestamp: Instant,
+
+    // compression data
+
+    // compress outgoing messages at least this large once the peer accepts
+    // compression; if None, compression is disabled
+    #[cfg(feature = "compression")]
+    compression_threshold: Option<usize>,
+    // whether we have told the peer we accept compressed messages
+    #[cfg(feature = "compression")]
+    compression_advertised: bool,
+    // whether the peer has told us it accepts compressed messages
+    #[cfg(feature = "compression")]
+    peer_accepts_compression: bool,
+
+    // traffic counters
+    stats: SessionStats,
+
+    // apisets handling requests alongside those passed to update(), sorted
+    // by their namespaces
+    registered_apisets: Vec<Box<dyn ApiSet + Send>>,
 }
 
 #[allow(dead_code)]
@@ -900,6 +1049,59 @@ where
         self.max_wait_time
     }
 
+    /// Sets the number of unwritten bytes at which this `Session` applies backpressure. When the peer reads slower than we write (e.g. over a slow Tor circuit), outgoing data accumulates in an internal queue. Once `high_watermark` bytes are queued, [`Session::update()`] stops handling new incoming requests (which would only queue more responses) until the queue drains to `low_watermark` bytes. Incoming messages are still read and client-call responses are still received while backpressure is applied. The defaults are [`DEFAULT_WRITE_LOW_WATERMARK`] and [`DEFAULT_WRITE_HIGH_WATERMARK`].
+    pub fn set_write_watermarks(
+        &mut self,
+        low_watermark: usize,
+        high_watermark: usize,
+    ) -> Result<(), Error> {
+        if low_watermark > high_watermark {
+            Err(Error::InvalidWriteWatermarks(low_watermark, high_watermark))
+        } else {
+            self.write_low_watermark = low_watermark;
+            self.write_high_watermark = high_watermark;
+            Ok(())
+        }
+    }
+
+    /// Gets the `(low_watermark, high_watermark)` pair set with [`Session::set_write_watermarks()`].
+    pub fn get_write_watermarks(&self) -> (usize, usize) {
+        (self.write_low_watermark, self.write_high_watermark)
+    }
+
+    /// Gets the number of serialized bytes waiting to be written to the underlying `RW`.
+    pub fn pending_write_bytes(&self) -> usize {
+        self.message_write_buffer.len()
+    }
+
+    /// Returns `true` if this `Session` has stopped handling new incoming requests until its pending writes drain. See [`Session::set_write_watermarks()`].
+    pub fn is_write_backpressured(&self) -> bool {
+        self.write_backpressure
+    }
+
+    /// Returns `true` if every queued request, response and error has been written to the underlying `RW`. Callers which take the stream back with [`Session::into_stream()`] should wait for this, as any unwritten data is discarded.
+    pub fn flushed(&self) -> bool {
+        self.outbound_sections.is_empty() && self.message_write_buffer.is_empty()
+    }
+
+    /// Sets the serialized size in bytes at which this `Session` compresses outgoing messages, or `None` to disable compression (the default). Compression is negotiated per session: once enabled, this `Session` advertises that it accepts zstd-compressed messages in its next outgoing message, and compresses messages of at least `threshold` bytes once the peer has advertised the same. Peers which do not support compression ignore the advertisement, so messages to them are never compressed. Messages must fit within [`Session::get_max_message_size()`] both before and after compression, and are only sent compressed if doing so makes them smaller. Once advertised, compressed messages from the peer are accepted for the remainder of the session even if compression is later disabled.
+    #[cfg(feature = "compression")]
+    pub fn set_compression_threshold(&mut self, threshold: Option<usize>) {
+        self.compression_threshold = threshold;
+    }
+
+    /// Gets the compression threshold set with [`Session::set_compression_threshold()`].
+    #[cfg(feature = "compression")]
+    pub fn get_compression_threshold(&self) -> Option<usize> {
+        self.compression_threshold
+    }
+
+    /// Returns `true` if compression is enabled and the peer has advertised that it accepts compressed messages, so messages of at least the compression threshold are sent compressed. See [`Session::set_compression_threshold()`].
+    #[cfg(feature = "compression")]
+    pub fn compression_negotiated(&self) -> bool {
+        self.compression_threshold.is_some() && self.peer_accepts_compression
+    }
+
     /// Creates a new `Session` using the given `stream`.
     pub fn new(stream: RW) -> Self {
         let mut message_write_buffer: VecDeque<u8> = Default::default();
@@ -916,16 +1118,67 @@ where
             pending_sections: Default::default(),
             inbound_requests: Default::default(),
             inbound_responses: Default::default(),
+            inbound_error_data: Default::default(),
             message_serialization_buffer,
             next_cookie: Default::default(),
+            pending_client_calls: Default::default(),
             outbound_sections: Default::default(),
             max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
             max_wait_time: DEFAULT_MAX_WAIT_TIME,
-            read_timestamp: std::time::Instant::now(),
+            read_timestamp: Instant::now(),
+            write_high_watermark: DEFAULT_WRITE_HIGH_WATERMARK,
+            write_low_watermark: DEFAULT_WRITE_LOW_WATERMARK,
+            write_backpressure: false,
+            write_timestamp: Instant::now(),
+            #[cfg(feature = "compression")]
+            compression_threshold: None,
+            #[cfg(feature = "compression")]
+            compression_advertised: false,
+            #[cfg(feature = "compression")]
+            peer_accepts_compression: false,
+            stats: Default::default(),
+            registered_apisets: Default::default(),
+        }
+    }
+
+    /// Gets the number of bytes and remote procedure calls this `Session` has sent and received so far.
+    pub fn stats(&self) -> SessionStats {
+        self.stats
+    }
+
+    /// Registers an `ApiSet` to handle the peer's requests in its namespace on every subsequent [`Session::update()`], alongside any `ApiSet`s passed to `update()` directly. This allows a running `Session` to take on new namespaces, e.g. to serve application-specific functions once a handshake performed over the same connection has completed. An error is returned if an `ApiSet` with the same namespace is already registered.
+    pub fn register_apiset(&mut self, apiset: Box<dyn ApiSet + Send>) -> Result<(), Error> {
+        match self
+            .registered_apisets
+            .binary_search_by(|probe| probe.namespace().cmp(apiset.namespace()))
+        {
+            Ok(_) => Err(Error::ApiSetNamespaceConflict(
+                apiset.namespace().to_string(),
+            )),
+            Err(idx) => {
+                self.registered_apisets.insert(idx, apiset);
+                Ok(())
+            }
         }
     }
 
-    /// Consumes the `Session` and returns the underlying stream.
+    /// Unregisters and returns the `ApiSet` registered with [`Session::register_apiset()`] for `namespace`, if any. Subsequent requests in `namespace` fail with [`ErrorCode::RequestNamespaceInvalid`] unless handled by an `ApiSet` passed to [`Session::update()`]; the results of the `ApiSet`'s in-flight asynchronous requests are never sent.
+    pub fn unregister_apiset(&mut self, namespace: &str) -> Option<Box<dyn ApiSet + Send>> {
+        let idx = self
+            .registered_apisets
+            .binary_search_by(|probe| probe.namespace().cmp(namespace))
+            .ok()?;
+        Some(self.registered_apisets.remove(idx))
+    }
+
+    /// Gets the namespaces of the `ApiSet`s registered with [`Session::register_apiset()`], in sorted order.
+    pub fn registered_namespaces(&self) -> impl Iterator<Item = &str> {
+        self.registered_apisets
+            .iter()
+            .map(|apiset| apiset.namespace())
+    }
+
+    /// Consumes the `Session` and returns the underlying stream. Any data not yet written is discarded; see [`Session::flushed()`].
     pub fn into_stream(self) -> RW {
         self.stream
     }
@@ -936,9 +1189,7 @@ where
             Err(err) => {
                 if err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut {
                     // abort if we've gone too long without a new message
-                    if std::time::Instant::now().duration_since(self.read_timestamp)
-                        > self.max_wait_time
-                    {
+                    if Instant::now().duration_since(self.read_timestamp) > self.max_wait_time {
                         Err(Error::MessageReadTimedOut(self.max_wait_time))
                     } else {
                         Ok(0)
@@ -952,7 +1203,8 @@ where
             ))),
             Ok(count) => {
                 // update read_timestamp
-                self.read_timestamp = std::time::Instant::now();
+                self.read_timestamp = Instant::now();
+                self.stats.bytes_received += count as u64;
                 Ok(count)
             }
         }
@@ -1042,6 +1294,9 @@ where
                         self.message_read_buffer = cursor.into_inner();
                         self.message_read_buffer.clear();
 
+                        #[cfg(feature = "compression")]
+                        let bson = self.decompress_message(bson)?;
+
                         #[cfg(test)]
                         println!("<<< read message: {}", bson);
 
@@ -1060,6 +1315,49 @@ where
         }
     }
 
+    // note the peer's compression advertisement and replace a compressed
+    // message with its decompressed contents
+    #[cfg(feature = "compression")]
+    fn decompress_message(
+        &mut self,
+        mut message: bson::document::Document,
+    ) -> Result<bson::document::Document, Error> {
+        if let Ok(algorithms) = message.get_array(ACCEPT_COMPRESSION_KEY) {
+            if algorithms
+                .iter()
+                .any(|algorithm| algorithm.as_str() == Some(ZSTD_KEY))
+            {
+                self.peer_accepts_compression = true;
+            }
+        }
+
+        let compressed = match message.get_binary_generic_mut(ZSTD_KEY) {
+            Ok(compressed) => std::mem::take(compressed),
+            Err(_) => return Ok(message),
+        };
+        // the peer may only compress messages once we have agreed to it
+        if !self.compression_advertised {
+            return Err(Error::MessageConversionFailed(
+                ErrorCode::MessageParseFailed,
+            ));
+        }
+
+        // decompress at most one byte more than we are willing to accept
+        let decoder = zstd::stream::read::Decoder::new(compressed.as_slice())
+            .map_err(Error::MessageDecompressionFailed)?;
+        let mut decompressed: Vec<u8> = Default::default();
+        decoder
+            .take(self.max_message_size as u64 + 1)
+            .read_to_end(&mut decompressed)
+            .map_err(Error::MessageDecompressionFailed)?;
+        if decompressed.len() > self.max_message_size {
+            return Err(Error::DecompressedMessageTooLarge(self.max_message_size));
+        }
+
+        bson::document::Document::from_reader(&mut Cursor::new(decompressed))
+            .map_err(Error::BsonDocumentParseFailed)
+    }
+
     // read and save of available sections
     fn read_sections(&mut self) -> Result<(), Error> {
         loop {
@@ -1094,6 +1392,17 @@ where
                 Section::Error(error) => {
                     if let Some(cookie) = error.cookie {
                         // error in response to a request
+                        if let Some((span, timestamp)) = self.pending_client_calls.remove(&cookie) {
+                            let _enter = span.enter();
+                            tracing::debug!(
+                                elapsed = ?timestamp.elapsed(),
+                                error_code = %error.code,
+                                "error response received"
+                            );
+                        }
+                        if let Some(data) = error.data {
+                            self.inbound_error_data.insert(cookie, data);
+                        }
                         self.inbound_responses.push_back(Response::Error {
                             cookie,
                             error_code: error.code,
@@ -1105,12 +1414,22 @@ where
                 Section::Request(request) => {
                     // request to route to our apisets
                     self.inbound_requests.push(request);
+                    self.stats.requests_received += 1;
                 }
                 Section::Response(response) => {
                     // response to our client
 
                     match (response.cookie, response.state, response.result) {
                         (cookie, RequestState::Complete, result) => {
+                            if let Some((span, timestamp)) =
+                                self.pending_client_calls.remove(&cookie)
+                            {
+                                let _enter = span.enter();
+                                tracing::debug!(
+                                    elapsed = ?timestamp.elapsed(),
+                                    "success response received"
+                                );
+                            }
                             self.inbound_responses
                                 .push_back(Response::Success { cookie, result });
                         }
@@ -1188,6 +1507,8 @@ where
         } else {
             #[cfg(test)]
             println!(">>> write message: {:?}", message);
+            #[cfg(feature = "compression")]
+            self.compress_message(message)?;
             // copy the serialized message into the pending write buffer
             self.message_write_buffer
                 .append(&mut self.message_serialization_buffer);
@@ -1196,18 +1517,105 @@ where
         Ok(())
     }
 
+    // replace the serialized message in the serialization buffer with a
+    // compressed copy if negotiated and worthwhile, and advertise our own
+    // support for compression if not yet done
+    #[cfg(feature = "compression")]
+    fn compress_message(&mut self, message: bson::document::Document) -> Result<(), Error> {
+        let threshold = match self.compression_threshold {
+            Some(threshold) => threshold,
+            None => return Ok(()),
+        };
+
+        let serialized_size = self.message_serialization_buffer.len();
+        let mut compressed_message: Option<bson::document::Document> = None;
+        if self.peer_accepts_compression && serialized_size >= threshold {
+            let compressed = zstd::bulk::compress(
+                self.message_serialization_buffer.make_contiguous(),
+                zstd::DEFAULT_COMPRESSION_LEVEL,
+            )
+            .map_err(Error::MessageCompressionFailed)?;
+            let message = doc! {
+                "honk_rpc" : HONK_RPC_VERSION,
+                ZSTD_KEY : bson::Binary {
+                    subtype: bson::spec::BinarySubtype::Generic,
+                    bytes: compressed,
+                },
+            };
+
+            // only worthwhile if the compressed message is smaller
+            let mut counter: ByteCounter = Default::default();
+            message
+                .to_writer(&mut counter)
+                .map_err(Error::BsonWriteFailed)?;
+            if counter.bytes() < serialized_size {
+                compressed_message = Some(message);
+            }
+        }
+
+        let mut message = match compressed_message {
+            Some(compressed_message) => compressed_message,
+            None if !self.compression_advertised => message,
+            None => return Ok(()),
+        };
+        let advertise = !self.compression_advertised;
+        if advertise {
+            message.insert(ACCEPT_COMPRESSION_KEY, vec![ZSTD_KEY]);
+        }
+
+        let mut serialized: Vec<u8> = Default::default();
+        message
+            .to_writer(&mut serialized)
+            .map_err(Error::BsonWriteFailed)?;
+        // if there is no room for the advertisement send the message as-is
+        // and advertise with the next one
+        if serialized.len() > self.max_message_size {
+            return Ok(());
+        }
+
+        self.compression_advertised |= advertise;
+        self.message_serialization_buffer.clear();
+        self.message_serialization_buffer.extend(serialized);
+        Ok(())
+    }
+
     // write data to stream and remove from write buffer
     fn write_pending_data(&mut self) -> Result<(), Error> {
         let bytes_written = self.write_pending_data_impl()?;
-        self.stream.flush().map_err(Error::WriterWriteFailed)?;
+        if let Err(err) = self.stream.flush() {
+            // a nonblocking writer may be unable to flush right now; retry next update
+            let kind = err.kind();
+            if kind != ErrorKind::WouldBlock && kind != ErrorKind::TimedOut {
+                return Err(Error::WriterFlushFailed(err));
+            }
+        }
         // removes the written bytes
         self.message_write_buffer.drain(0..bytes_written);
         // and shuffles the data so it is contiguous
         self.message_write_buffer.make_contiguous();
 
+        // abort if the peer has gone too long without accepting any of our data
+        if bytes_written > 0 || self.message_write_buffer.is_empty() {
+            self.write_timestamp = Instant::now();
+        } else if Instant::now().duration_since(self.write_timestamp) > self.max_wait_time {
+            return Err(Error::MessageWriteTimedOut(self.max_wait_time));
+        }
+
         Ok(())
     }
 
+    // apply or release backpressure based on the amount of unwritten data
+    fn update_write_backpressure(&mut self) {
+        let pending_bytes = self.message_write_buffer.len();
+        if !self.write_backpressure && pending_bytes >= self.write_high_watermark {
+            tracing::debug!(pending_bytes, "write backpressure applied");
+            self.write_backpressure = true;
+        } else if self.write_backpressure && pending_bytes <= self.write_low_watermark {
+            tracing::debug!(pending_bytes, "write backpressure released");
+            self.write_backpressure = false;
+        }
+    }
+
     fn write_pending_data_impl(&mut self) -> Result<usize, Error> {
         // write pending data
         let (mut pending_data, empty): (&[u8], &[u8]) = self.message_write_buffer.as_slices();
@@ -1228,6 +1636,7 @@ where
                 }
                 Ok(count) => {
                     bytes_written += count;
+                    self.stats.bytes_sent += count as u64;
                     #[cfg(test)]
                     println!(">>> sent {} of {} bytes", bytes_written, pending_bytes);
                     pending_data = &pending_data[count..];
@@ -1238,16 +1647,30 @@ where
         Ok(bytes_written)
     }
 
-    /// Read and process Honk-RPC message documents from connected peer, handle any new incoming Honk-RPC requests, update any in-progress async requests and write pending reponses, errors and requests to peer. This function must be called regularly for the `Session` to make forward progress.
+    /// Read and process Honk-RPC message documents from connected peer, handle any new incoming Honk-RPC requests, update any in-progress async requests and write pending reponses, errors and requests to peer. This function must be called regularly for the `Session` to make forward progress. It never blocks on a nonblocking `RW`; data the peer is not yet ready to accept is queued and written by later calls. While the write queue is above its high watermark new incoming requests are held back (see [`Session::set_write_watermarks()`]).
+    ///
+    /// Requests are handled by the given `apisets`, which must be sorted by their namespaces, and by the `ApiSet`s registered with [`Session::register_apiset()`]. An error is returned if more than one of these handles the same namespace.
+    ///
+    /// A panic in any of an `ApiSet`'s methods is caught rather than unwinding through this function. As the `ApiSet` may have been left in an inconsistent state, the peer is sent a fatal [`ErrorCode::InternalError`] and [`Error::ApiSetPanicked`] is returned; the session should then be dropped.
     pub fn update(&mut self, apisets: Option<&mut [&mut dyn ApiSet]>) -> Result<(), Error> {
         // read sections from remote
         self.read_sections()?;
         // route sections to buffers
         self.process_sections()?;
 
-        // handle incoming api calls
-        let apisets = apisets.unwrap_or(&mut []);
-        self.handle_requests(apisets)?;
+        // handle incoming api calls, unless the peer is not keeping up with our responses
+        if !self.write_backpressure {
+            let apisets = apisets.unwrap_or(&mut []);
+            if self.registered_apisets.is_empty() {
+                self.handle_requests(apisets)?;
+            } else {
+                let mut registered_apisets = std::mem::take(&mut self.registered_apisets);
+                let result = Self::merge_apisets(apisets, &mut registered_apisets)
+                    .and_then(|mut apisets| self.handle_requests(&mut apisets));
+                self.registered_apisets = registered_apisets;
+                result?;
+            }
+        }
 
         // serialize pending responses
         self.serialize_messages()?;
@@ -1255,14 +1678,52 @@ where
         // write pendng data to writer
         self.write_pending_data()?;
 
+        self.update_write_backpressure();
+
         Ok(())
     }
 
+    // combine the apisets passed to update() with the registered apisets,
+    // sorted by their namespaces
+    fn merge_apisets<'a>(
+        apisets: &'a mut [&mut dyn ApiSet],
+        registered_apisets: &'a mut [Box<dyn ApiSet + Send>],
+    ) -> Result<Vec<&'a mut dyn ApiSet>, Error> {
+        let mut merged: Vec<&'a mut dyn ApiSet> =
+            Vec::with_capacity(apisets.len() + registered_apisets.len());
+        for apiset in apisets.iter_mut() {
+            merged.push(&mut **apiset);
+        }
+        for apiset in registered_apisets.iter_mut() {
+            merged.push(apiset.as_mut());
+        }
+        merged.sort_by(|a, b| a.namespace().cmp(b.namespace()));
+
+        if let Some(pair) = merged
+            .windows(2)
+            .find(|pair| pair[0].namespace() == pair[1].namespace())
+        {
+            return Err(Error::ApiSetNamespaceConflict(
+                pair[0].namespace().to_string(),
+            ));
+        }
+        Ok(merged)
+    }
+
     // apisets : a slice of mutable ApiSet references sorted by their namespaces
     fn handle_requests(&mut self, apisets: &mut [&mut dyn ApiSet]) -> Result<(), Error> {
         // first handle all of our inbound requests
         let mut inbound_requests = std::mem::take(&mut self.inbound_requests);
         for mut request in inbound_requests.drain(..) {
+            let _span = tracing::debug_span!(
+                "honk_rpc_request",
+                namespace = %request.namespace,
+                function = %request.function,
+                version = request.version,
+                cookie = ?request.cookie,
+            )
+            .entered();
+            let timestamp = Instant::now();
             if let Ok(idx) =
                 apisets.binary_search_by(|probe| probe.namespace().cmp(&request.namespace))
             {
@@ -1270,14 +1731,19 @@ where
                     Some(apiset) => apiset,
                     None => unreachable!(),
                 };
-                match apiset.exec_function(
-                    &request.function,
-                    request.version,
-                    std::mem::take(&mut request.arguments),
-                    request.cookie,
-                ) {
+                let result = catch_panic(|| {
+                    apiset.exec_function(
+                        &request.function,
+                        request.version,
+                        std::mem::take(&mut request.arguments),
+                        request.cookie,
+                    )
+                })
+                .map_err(|message| self.apiset_panicked(apiset.namespace(), message))?;
+                match result {
                     // func found, invoked and succeeded
                     Some(Ok(result)) => {
+                        tracing::debug!(elapsed = ?timestamp.elapsed(), "request succeeded");
                         if let Some(cookie) = request.cookie {
                             self.push_outbound_section(Section::Response(ResponseSection {
                                 cookie,
@@ -1288,15 +1754,26 @@ where
                     }
                     // func found, invoked and failed
                     Some(Err(error_code)) => {
+                        tracing::debug!(
+                            elapsed = ?timestamp.elapsed(),
+                            error_code = %error_code,
+                            "request failed"
+                        );
+                        let data = match request.cookie {
+                            Some(cookie) => catch_panic(|| apiset.error_data(cookie)),
+                            None => Ok(None),
+                        }
+                        .map_err(|message| self.apiset_panicked(apiset.namespace(), message))?;
                         self.push_outbound_section(Section::Error(ErrorSection {
                             cookie: request.cookie,
                             code: error_code,
                             message: None,
-                            data: None,
+                            data,
                         }))?;
                     }
                     // func found, called, and result is pending
                     None => {
+                        tracing::debug!(elapsed = ?timestamp.elapsed(), "request pending");
                         if let Some(cookie) = request.cookie {
                             self.push_outbound_section(Section::Response(ResponseSection {
                                 cookie,
@@ -1308,6 +1785,7 @@ where
                 }
             } else {
                 // invalid namespace
+                tracing::debug!("request namespace invalid");
                 self.push_outbound_section(Section::Error(ErrorSection {
                     cookie: request.cookie,
                     code: ErrorCode::RequestNamespaceInvalid,
@@ -1320,9 +1798,12 @@ where
         // next send out async responses from apisets
         for apiset in apisets.iter_mut() {
             // allow apiset to do any required repetitive work
-            apiset.update();
+            catch_panic(|| apiset.update())
+                .map_err(|message| self.apiset_panicked(apiset.namespace(), message))?;
             // put pending results in our message
-            while let Some((cookie, result)) = apiset.next_result() {
+            while let Some((cookie, result)) = catch_panic(|| apiset.next_result())
+                .map_err(|message| self.apiset_panicked(apiset.namespace(), message))?
+            {
                 match (cookie, result) {
                     // function completed successfully
                     (cookie, Ok(result)) => {
@@ -1334,11 +1815,13 @@ where
                     }
                     // function completed with failure
                     (cookie, Err(error_code)) => {
+                        let data = catch_panic(|| apiset.error_data(cookie))
+                            .map_err(|message| self.apiset_panicked(apiset.namespace(), message))?;
                         self.push_outbound_section(Section::Error(ErrorSection {
                             cookie: Some(cookie),
                             code: error_code,
                             message: None,
-                            data: None,
+                            data,
                         }))?;
                     }
                 }
@@ -1347,6 +1830,18 @@ where
         Ok(())
     }
 
+    // an apiset may be left in an inconsistent state by a panic, so the
+    // session is ended with a fatal error rather than handling more requests
+    fn apiset_panicked(&mut self, namespace: &str, message: String) -> Error {
+        tracing::error!(namespace, panic_message = %message, "ApiSet panicked");
+        // best effort; the session is ended regardless
+        let _ = self
+            .send_error(ErrorCode::InternalError)
+            .and_then(|()| self.serialize_messages())
+            .and_then(|()| self.write_pending_data());
+        Error::ApiSetPanicked(namespace.to_string(), message)
+    }
+
     /// Performs a client call to a remote function. Returns a `RequestCookie` to associate this client call with a future `Response`.
     pub fn client_call(
         &mut self,
@@ -1359,18 +1854,37 @@ where
         let cookie = self.next_cookie;
         self.next_cookie += 1;
 
+        let span =
+            tracing::debug_span!("honk_rpc_client_call", namespace, function, version, cookie);
+
         // add request to outgoing buffer
-        self.push_outbound_section(Section::Request(RequestSection {
-            cookie: Some(cookie),
-            namespace: namespace.to_string(),
-            function: function.to_string(),
-            version,
-            arguments,
-        }))?;
+        span.in_scope(|| {
+            self.push_outbound_section(Section::Request(RequestSection {
+                cookie: Some(cookie),
+                namespace: namespace.to_string(),
+                function: function.to_string(),
+                version,
+                arguments,
+            }))
+        })?;
+
+        self.pending_client_calls
+            .insert(cookie, (span, Instant::now()));
+        self.stats.client_calls += 1;
 
         Ok(cookie)
     }
 
+    /// Queues an error section which is not associated with any request. The remote `Session` treats such errors as fatal and fails its next [`Session::update()`] with [`Error::UnknownErrorSectionReceived`], so this is intended for notifying the peer of why the connection is about to be closed.
+    pub fn send_error(&mut self, error_code: ErrorCode) -> Result<(), Error> {
+        self.push_outbound_section(Section::Error(ErrorSection {
+            cookie: None,
+            code: error_code,
+            message: None,
+            data: None,
+        }))
+    }
+
     /// Drains all `Response` objects resulting from prevoius invocations of `Session::client_call()`
     pub fn client_drain_responses(&mut self) -> std::collections::vec_deque::Drain<Response> {
         self.inbound_responses.drain(..)
@@ -1380,6 +1894,41 @@ where
     pub fn client_next_response(&mut self) -> Option<Response> {
         self.inbound_responses.pop_front()
     }
+
+    /// Takes the application-specific data the remote server attached to its `Response::Error` for the client call with the given `cookie`, if any. The data is kept until taken, so callers which expect error data should take it when handling the error response.
+    pub fn client_take_error_data(&mut self, cookie: RequestCookie) -> Option<bson::Bson> {
+        self.inbound_error_data.remove(&cookie)
+    }
+}
+
+// run an ApiSet callback, returning the message of any panic rather than
+// unwinding through Session::update()
+fn catch_panic<R>(callback: impl FnOnce() -> R) -> Result<R, String> {
+    std::panic::catch_unwind(AssertUnwindSafe(callback)).map_err(|payload| {
+        if let Some(message) = payload.downcast_ref::<&str>() {
+            message.to_string()
+        } else if let Some(message) = payload.downcast_ref::<String>() {
+            message.clone()
+        } else {
+            "no panic message".to_string()
+        }
+    })
+}
+
+impl<R, W> Session<SplitStream<R, W>>
+where
+    R: std::io::Read + Send,
+    W: std::io::Write + Send,
+{
+    /// Creates a new `Session` using independently owned `reader` and `writer` halves of a connection.
+    pub fn from_split(reader: R, writer: W) -> Self {
+        Self::new(SplitStream::new(reader, writer))
+    }
+
+    /// Consumes the `Session` and returns the underlying read and write halves.
+    pub fn into_split(self) -> (R, W) {
+        self.into_stream().into_split()
+    }
 }
 
 #[test]
@@ -1634,3 +2183,357 @@ fn test_honk_timeout() -> anyhow::Result<()> {
     }
     Ok(())
 }
+
+#[test]
+fn test_honk_split_session() -> anyhow::Result<()> {
+    let socket_addr = SocketAddr::from(([127, 0, 0, 1], 0u16));
+    let listener = TcpListener::bind(socket_addr)?;
+    let socket_addr = listener.local_addr()?;
+
+    let alice_stream = TcpStream::connect(socket_addr)?;
+    alice_stream.set_nonblocking(true)?;
+    let (pat_stream, _socket_addr) = listener.accept()?;
+    pat_stream.set_nonblocking(true)?;
+
+    let alice_reader = alice_stream.try_clone()?;
+    let mut alice = Session::from_split(alice_reader, alice_stream);
+    let mut alice_apiset = TestApiSet { call_count: 0usize };
+    let mut pat = Session::new(pat_stream);
+
+    println!("--- pat calls namespace::function_0() on alice's split session");
+    let cookie = pat.client_call("namespace", "function", 0, doc! {})?;
+    let response = loop {
+        pat.update(None)?;
+        alice.update(Some(&mut [&mut alice_apiset]))?;
+        if let Some(response) = pat.client_next_response() {
+            break response;
+        }
+    };
+    assert_eq!(alice_apiset.call_count, 1);
+    match response {
+        Response::Success {
+            cookie: response_cookie,
+            ..
+        } => assert_eq!(response_cookie, cookie),
+        _ => panic!("unexpected response"),
+    }
+
+    // the halves are still usable once the session is done with them
+    let (_alice_reader, mut alice_writer) = alice.into_split();
+    std::io::Write::write_all(&mut alice_writer, b"bye")?;
+
+    Ok(())
+}
+
+#[test]
+fn test_honk_session_stats() -> anyhow::Result<()> {
+    let socket_addr = SocketAddr::from(([127, 0, 0, 1], 0u16));
+    let listener = TcpListener::bind(socket_addr)?;
+    let socket_addr = listener.local_addr()?;
+
+    let alice_stream = TcpStream::connect(socket_addr)?;
+    alice_stream.set_nonblocking(true)?;
+    let (pat_stream, _socket_addr) = listener.accept()?;
+    pat_stream.set_nonblocking(true)?;
+
+    let mut alice = Session::new(alice_stream);
+    let mut alice_apiset = TestApiSet { call_count: 0usize };
+    let mut pat = Session::new(pat_stream);
+    assert_eq!(pat.stats(), SessionStats::default());
+
+    println!("--- pat calls namespace::function_0() on alice");
+    pat.client_call("namespace", "function", 0, doc! {})?;
+    while pat.client_next_response().is_none() {
+        pat.update(None)?;
+        alice.update(Some(&mut [&mut alice_apiset]))?;
+    }
+
+    let pat_stats = pat.stats();
+    let alice_stats = alice.stats();
+    assert_eq!(pat_stats.client_calls, 1);
+    assert_eq!(pat_stats.requests_received, 0);
+    assert_eq!(alice_stats.client_calls, 0);
+    assert_eq!(alice_stats.requests_received, 1);
+    // every byte written by one side has been read by the other
+    assert!(pat_stats.bytes_sent > 0);
+    assert_eq!(pat_stats.bytes_sent, alice_stats.bytes_received);
+    assert!(alice_stats.bytes_sent > 0);
+    assert_eq!(alice_stats.bytes_sent, pat_stats.bytes_received);
+
+    Ok(())
+}
+
+// a stream which never has data to read and only accepts as many bytes as
+// its current write budget allows, simulating a slow peer
+#[cfg(test)]
+struct ThrottledStream {
+    written: Vec<u8>,
+    write_budget: usize,
+}
+
+#[cfg(test)]
+impl std::io::Read for ThrottledStream {
+    fn read(&mut self, _buf: &mut [u8]) -> Result<usize, std::io::Error> {
+        Err(std::io::Error::from(ErrorKind::WouldBlock))
+    }
+}
+
+#[cfg(test)]
+impl std::io::Write for ThrottledStream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
+        if self.write_budget == 0 {
+            return Err(std::io::Error::from(ErrorKind::WouldBlock));
+        }
+        let len = std::cmp::min(buf.len(), self.write_budget);
+        self.written.extend_from_slice(&buf[..len]);
+        self.write_budget -= len;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        if self.write_budget == 0 {
+            Err(std::io::Error::from(ErrorKind::WouldBlock))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[test]
+fn test_honk_write_backpressure() -> anyhow::Result<()> {
+    let stream = ThrottledStream {
+        written: Default::default(),
+        write_budget: 0,
+    };
+    let mut session = Session::new(stream);
+
+    assert!(session.set_write_watermarks(10_000, 5_000).is_err());
+    session.set_write_watermarks(2_000, 8_000)?;
+    assert_eq!(session.get_write_watermarks(), (2_000, 8_000));
+    assert!(session.flushed());
+    assert!(!session.is_write_backpressured());
+
+    println!("--- queue more requests than the peer will accept");
+    let payload = "x".repeat(3_000);
+    for _ in 0..4 {
+        session.client_call(
+            "namespace",
+            "function",
+            0,
+            doc! {"payload" : payload.clone()},
+        )?;
+    }
+    session.update(None)?;
+    let pending_write_bytes = session.pending_write_bytes();
+    println!("--- pending_write_bytes: {pending_write_bytes}");
+    assert!(pending_write_bytes >= 8_000);
+    assert!(session.is_write_backpressured());
+    assert!(!session.flushed());
+
+    println!("--- peer accepts some data");
+    session.stream.write_budget = pending_write_bytes - 1_000;
+    session.update(None)?;
+    assert_eq!(session.pending_write_bytes(), 1_000);
+    assert!(!session.is_write_backpressured());
+    assert!(!session.flushed());
+
+    println!("--- peer accepts remaining data");
+    session.stream.write_budget = usize::MAX;
+    session.update(None)?;
+    assert!(session.flushed());
+    assert_eq!(session.stream.written.len(), pending_write_bytes);
+
+    Ok(())
+}
+
+#[test]
+fn test_honk_send_error() -> anyhow::Result<()> {
+    let socket_addr = SocketAddr::from(([127, 0, 0, 1], 0u16));
+    let listener = TcpListener::bind(socket_addr)?;
+    let socket_addr = listener.local_addr()?;
+
+    let stream1 = TcpStream::connect(socket_addr)?;
+    stream1.set_nonblocking(true)?;
+    let (stream2, _socket_addr) = listener.accept()?;
+    stream2.set_nonblocking(true)?;
+
+    let mut alice = Session::new(stream1);
+    let mut pat = Session::new(stream2);
+
+    println!("--- pat sends an error not associated with any request");
+    pat.send_error(ErrorCode::Runtime(7))?;
+    while !pat.flushed() {
+        pat.update(None)?;
+    }
+
+    let err = loop {
+        if let Err(err) = alice.update(None) {
+            break err;
+        }
+    };
+    match err {
+        Error::UnknownErrorSectionReceived(ErrorCode::Runtime(7)) => {
+            println!("--- expected error received")
+        }
+        err => panic!("unexpected error: {:?}", err),
+    }
+
+    Ok(())
+}
+
+// an apiset whose namespace::echo_0() returns its arguments
+#[cfg(all(test, feature = "compression"))]
+struct EchoApiSet;
+
+#[cfg(all(test, feature = "compression"))]
+impl ApiSet for EchoApiSet {
+    fn namespace(&self) -> &str {
+        "namespace"
+    }
+
+    fn exec_function(
+        &mut self,
+        name: &str,
+        version: i32,
+        args: bson::document::Document,
+        _request_cookie: Option<RequestCookie>,
+    ) -> Option<Result<Option<bson::Bson>, ErrorCode>> {
+        match (name, version) {
+            ("echo", 0) => Some(Ok(Some(bson::Bson::Document(args)))),
+            _ => Some(Err(ErrorCode::RequestFunctionInvalid)),
+        }
+    }
+}
+
+// a stream which counts the bytes written to it
+#[cfg(all(test, feature = "compression"))]
+struct CountingStream {
+    stream: TcpStream,
+    bytes_written: usize,
+}
+
+#[cfg(all(test, feature = "compression"))]
+impl std::io::Read for CountingStream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+        self.stream.read(buf)
+    }
+}
+
+#[cfg(all(test, feature = "compression"))]
+impl std::io::Write for CountingStream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
+        let count = self.stream.write(buf)?;
+        self.bytes_written += count;
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        self.stream.flush()
+    }
+}
+
+#[cfg(all(test, feature = "compression"))]
+fn compression_session_pair() -> anyhow::Result<(Session<CountingStream>, Session<CountingStream>)>
+{
+    let socket_addr = SocketAddr::from(([127, 0, 0, 1], 0u16));
+    let listener = TcpListener::bind(socket_addr)?;
+    let socket_addr = listener.local_addr()?;
+
+    let stream1 = TcpStream::connect(socket_addr)?;
+    stream1.set_nonblocking(true)?;
+    let (stream2, _socket_addr) = listener.accept()?;
+    stream2.set_nonblocking(true)?;
+
+    let alice = Session::new(CountingStream {
+        stream: stream1,
+        bytes_written: 0,
+    });
+    let pat = Session::new(CountingStream {
+        stream: stream2,
+        bytes_written: 0,
+    });
+    Ok((alice, pat))
+}
+
+// pat calls alice's namespace::echo_0() and returns the echoed payload
+#[cfg(all(test, feature = "compression"))]
+fn compression_echo(
+    alice: &mut Session<CountingStream>,
+    pat: &mut Session<CountingStream>,
+    payload: &str,
+) -> anyhow::Result<String> {
+    let cookie = pat.client_call("namespace", "echo", 0, doc! {"payload" : payload})?;
+    loop {
+        alice.update(Some(&mut [&mut EchoApiSet]))?;
+        pat.update(None)?;
+        match pat.client_next_response() {
+            Some(Response::Success {
+                cookie: response_cookie,
+                result: Some(bson::Bson::Document(result)),
+            }) if response_cookie == cookie => {
+                return Ok(result.get_str("payload")?.to_string());
+            }
+            Some(_) => panic!("unexpected response"),
+            None => (),
+        }
+    }
+}
+
+#[cfg(feature = "compression")]
+#[test]
+fn test_honk_compression() -> anyhow::Result<()> {
+    let payload = "the quick brown fox jumps over the lazy dog ".repeat(64);
+
+    println!("--- only pat supports compression");
+    let (mut alice, mut pat) = compression_session_pair()?;
+    pat.set_compression_threshold(Some(DEFAULT_COMPRESSION_THRESHOLD));
+    assert_eq!(
+        pat.get_compression_threshold(),
+        Some(DEFAULT_COMPRESSION_THRESHOLD)
+    );
+    assert_eq!(compression_echo(&mut alice, &mut pat, &payload)?, payload);
+    assert_eq!(compression_echo(&mut alice, &mut pat, &payload)?, payload);
+    assert!(!pat.compression_negotiated());
+    assert!(!alice.compression_negotiated());
+    // nothing was compressed, so every message carried the payload
+    assert!(alice.stream.bytes_written > 2 * payload.len());
+    assert!(pat.stream.bytes_written > 2 * payload.len());
+
+    println!("--- both support compression");
+    let (mut alice, mut pat) = compression_session_pair()?;
+    alice.set_compression_threshold(Some(DEFAULT_COMPRESSION_THRESHOLD));
+    pat.set_compression_threshold(Some(DEFAULT_COMPRESSION_THRESHOLD));
+    // the first exchange advertises compression support
+    assert_eq!(compression_echo(&mut alice, &mut pat, "hello")?, "hello");
+    assert!(pat.compression_negotiated());
+    assert!(alice.compression_negotiated());
+
+    let alice_bytes_written = alice.stream.bytes_written;
+    let pat_bytes_written = pat.stream.bytes_written;
+    assert_eq!(compression_echo(&mut alice, &mut pat, &payload)?, payload);
+    let alice_bytes_written = alice.stream.bytes_written - alice_bytes_written;
+    let pat_bytes_written = pat.stream.bytes_written - pat_bytes_written;
+    println!("--- payload: {} bytes, pat wrote {pat_bytes_written} bytes, alice wrote {alice_bytes_written} bytes", payload.len());
+    assert!(pat_bytes_written < payload.len() / 4);
+    assert!(alice_bytes_written < payload.len() / 4);
+
+    println!("--- pat sends compressed message alice never agreed to");
+    let (mut alice, mut pat) = compression_session_pair()?;
+    pat.set_compression_threshold(Some(DEFAULT_COMPRESSION_THRESHOLD));
+    pat.peer_accepts_compression = true;
+    pat.client_call("namespace", "echo", 0, doc! {"payload" : payload})?;
+    let err = loop {
+        pat.update(None)?;
+        if let Err(err) = alice.update(Some(&mut [&mut EchoApiSet])) {
+            break err;
+        }
+    };
+    match err {
+        Error::MessageConversionFailed(ErrorCode::MessageParseFailed) => {
+            println!("--- expected error received")
+        }
+        err => panic!("unexpected error: {:?}", err),
+    }
+
+    Ok(())
+}
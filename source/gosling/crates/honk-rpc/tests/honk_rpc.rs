@@ -13,6 +13,7 @@ use honk_rpc::honk_rpc::*;
 #[derive(Default)]
 struct TestApiSet {
     delay_echo_results: VecDeque<(RequestCookie, Result<Option<bson::Bson>, ErrorCode>)>,
+    error_data: VecDeque<(RequestCookie, bson::Bson)>,
 }
 
 const RUNTIME_ERROR_INVALID_ARG: ErrorCode = ErrorCode::Runtime(1i32);
@@ -33,11 +34,18 @@ impl TestApiSet {
         }
     }
 
-    // second version of echo that isn't implemented
+    // second version of echo that isn't implemented; tells the caller which version to use instead
     fn echo_1(
         &mut self,
+        request_cookie: Option<RequestCookie>,
         _args: bson::document::Document,
     ) -> Option<Result<Option<bson::Bson>, ErrorCode>> {
+        if let Some(request_cookie) = request_cookie {
+            self.error_data.push_back((
+                request_cookie,
+                bson::Bson::Document(doc! {"supported_version" : 0}),
+            ));
+        }
         Some(Err(RUNTIME_ERROR_NOT_IMPLEMENTED))
     }
 
@@ -95,9 +103,10 @@ impl ApiSet for TestApiSet {
     ) -> Option<Result<Option<bson::Bson>, ErrorCode>> {
         match (name, version) {
             ("echo", 0) => self.echo_0(args),
-            ("echo", 1) => self.echo_1(args),
+            ("echo", 1) => self.echo_1(request_cookie, args),
             ("delay_echo", 0) => self.delay_echo_0(request_cookie, args),
             ("sha256", 0) => self.sha256_0(args),
+            ("panic", 0) => panic!("TestApiSet::panic_0()"),
             (name, version) => {
                 println!("received {{ name: '{}', version: {} }}", name, version);
                 Some(Err(ErrorCode::RequestFunctionInvalid))
@@ -108,6 +117,14 @@ impl ApiSet for TestApiSet {
     fn next_result(&mut self) -> Option<(RequestCookie, Result<Option<bson::Bson>, ErrorCode>)> {
         self.delay_echo_results.pop_front()
     }
+
+    fn error_data(&mut self, request_cookie: RequestCookie) -> Option<bson::Bson> {
+        let idx = self
+            .error_data
+            .iter()
+            .position(|(cookie, _)| *cookie == request_cookie)?;
+        self.error_data.remove(idx).map(|(_, data)| data)
+    }
 }
 
 #[test]
@@ -186,6 +203,7 @@ fn test_honk_client_apiset() -> anyhow::Result<()> {
                 Response::Error { cookie, error_code } => {
                     assert_eq!(sent_cookie, cookie);
                     assert_eq!(error_code, RUNTIME_ERROR_INVALID_ARG);
+                    assert_eq!(pat.client_take_error_data(cookie), None);
                     println!("--- pat received invlaid arg response");
                     pat_bad_call_handled = true;
                 }
@@ -220,6 +238,11 @@ fn test_honk_client_apiset() -> anyhow::Result<()> {
                 Response::Error { cookie, error_code } => {
                     assert_eq!(sent_cookie, cookie);
                     assert_eq!(error_code, RUNTIME_ERROR_NOT_IMPLEMENTED);
+                    assert_eq!(
+                        pat.client_take_error_data(cookie),
+                        Some(bson::Bson::Document(doc! {"supported_version" : 0}))
+                    );
+                    assert_eq!(pat.client_take_error_data(cookie), None);
                     println!("--- pat received not implemented response");
                     pat_bad_call_handled = true;
                 }
@@ -359,3 +382,136 @@ fn test_honk_client_apiset() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+// update both sessions until pat receives the response to its call
+fn honk_wait_response(
+    alice: &mut Session<TcpStream>,
+    pat: &mut Session<TcpStream>,
+    sent_cookie: RequestCookie,
+) -> anyhow::Result<Result<Option<bson::Bson>, ErrorCode>> {
+    loop {
+        alice.update(None)?;
+        pat.update(None)?;
+        if let Some(response) = pat.client_next_response() {
+            match response {
+                Response::Pending { cookie } => {
+                    panic!("received unexpected pending, cookie: {}", cookie);
+                }
+                Response::Success { cookie, result } => {
+                    assert_eq!(sent_cookie, cookie);
+                    return Ok(Ok(result));
+                }
+                Response::Error { cookie, error_code } => {
+                    assert_eq!(sent_cookie, cookie);
+                    return Ok(Err(error_code));
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_honk_apiset_panic() -> anyhow::Result<()> {
+    let socket_addr = SocketAddr::from(([127, 0, 0, 1], 0u16));
+    let listener = TcpListener::bind(socket_addr)?;
+    let socket_addr = listener.local_addr()?;
+
+    let stream1 = TcpStream::connect(socket_addr)?;
+    stream1.set_nonblocking(true)?;
+    let (stream2, _socket_addr) = listener.accept()?;
+    stream2.set_nonblocking(true)?;
+
+    let mut alice = Session::new(stream1);
+    let mut pat = Session::new(stream2);
+    let mut test_api_set: TestApiSet = Default::default();
+
+    println!("--- alice's apiset panics while handling pat's call");
+    pat.client_call("test", "panic", 0, doc! {})?;
+    loop {
+        pat.update(None)?;
+        let alice_apisets: &mut [&mut dyn ApiSet] = &mut [&mut test_api_set];
+        match alice.update(Some(alice_apisets)) {
+            Ok(()) => (),
+            Err(Error::ApiSetPanicked(namespace, message)) => {
+                assert_eq!(namespace, "test");
+                assert_eq!(message, "TestApiSet::panic_0()");
+                break;
+            }
+            Err(err) => panic!("unexpected error: {:?}", err),
+        }
+    }
+
+    println!("--- pat is told alice's session has failed");
+    loop {
+        match pat.update(None) {
+            Ok(()) => (),
+            Err(Error::UnknownErrorSectionReceived(ErrorCode::InternalError)) => break,
+            Err(err) => panic!("unexpected error: {:?}", err),
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_honk_registered_apiset() -> anyhow::Result<()> {
+    let socket_addr = SocketAddr::from(([127, 0, 0, 1], 0u16));
+    let listener = TcpListener::bind(socket_addr)?;
+    let socket_addr = listener.local_addr()?;
+
+    let stream1 = TcpStream::connect(socket_addr)?;
+    stream1.set_nonblocking(true)?;
+    let (stream2, _socket_addr) = listener.accept()?;
+    stream2.set_nonblocking(true)?;
+
+    let mut alice = Session::new(stream1);
+    let mut pat = Session::new(stream2);
+
+    println!("--- pat calls test::echo before alice handles the test namespace");
+    let sent_cookie = pat.client_call("test", "echo", 0, doc! {"val" : "Hello Alice!"})?;
+    assert_eq!(
+        honk_wait_response(&mut alice, &mut pat, sent_cookie)?,
+        Err(ErrorCode::RequestNamespaceInvalid)
+    );
+
+    println!("--- alice registers the test namespace on the running session");
+    let test_api_set: TestApiSet = Default::default();
+    alice.register_apiset(Box::new(test_api_set))?;
+    assert_eq!(alice.registered_namespaces().collect::<Vec<_>>(), ["test"]);
+    let sent_cookie = pat.client_call("test", "echo", 0, doc! {"val" : "Hello Alice!"})?;
+    assert_eq!(
+        honk_wait_response(&mut alice, &mut pat, sent_cookie)?,
+        Ok(Some(bson::Bson::String("Hello Alice!".to_string())))
+    );
+
+    println!("--- a namespace may only be handled by a single apiset");
+    let test_api_set: TestApiSet = Default::default();
+    assert!(matches!(
+        alice.register_apiset(Box::new(test_api_set)),
+        Err(Error::ApiSetNamespaceConflict(namespace)) if namespace == "test"
+    ));
+    let mut test_api_set: TestApiSet = Default::default();
+    let alice_apisets: &mut [&mut dyn ApiSet] = &mut [&mut test_api_set];
+    assert!(matches!(
+        alice.update(Some(alice_apisets)),
+        Err(Error::ApiSetNamespaceConflict(namespace)) if namespace == "test"
+    ));
+
+    println!("--- alice unregisters the test namespace");
+    let test_api_set = alice.unregister_apiset("test");
+    assert_eq!(
+        test_api_set
+            .as_ref()
+            .map(|test_api_set| test_api_set.namespace()),
+        Some("test")
+    );
+    assert!(alice.unregister_apiset("test").is_none());
+    assert_eq!(alice.registered_namespaces().count(), 0);
+    let sent_cookie = pat.client_call("test", "echo", 0, doc! {"val" : "Hello Alice!"})?;
+    assert_eq!(
+        honk_wait_response(&mut alice, &mut pat, sent_cookie)?,
+        Err(ErrorCode::RequestNamespaceInvalid)
+    );
+
+    Ok(())
+}
@@ -209,6 +209,176 @@ impl ArtiClientTorClient {
             pending_events,
         })
     }
+
+    // launch an onion service forwarding its connections to target; the
+    // onion service is torn down when the returned RunningOnionService is dropped
+    fn start_onion_service(
+        &mut self,
+        private_key: &Ed25519PrivateKey,
+        virt_port: u16,
+        authorized_clients: Option<&[X25519PublicKey]>,
+        non_anonymous: bool,
+        pow_defenses: Option<&PowDefenses>,
+        target: SocketAddr,
+    ) -> Result<(OnionAddr, Arc<RunningOnionService>), tor_provider::Error> {
+        // client auth is not implemented yet
+        if authorized_clients.is_some() {
+            return Err(Error::NotImplemented().into());
+        }
+
+        // single onion services are not implemented yet
+        if non_anonymous {
+            return Err(Error::NotImplemented().into());
+        }
+
+        // proof-of-work defenses are not implemented yet
+        if pow_defenses.is_some() {
+            return Err(Error::NotImplemented().into());
+        }
+
+        // create a new ephemeral store for storing our onion service keys
+        let ephemeral_store: ArtiEphemeralKeystore =
+            ArtiEphemeralKeystore::new("ephemeral".to_string());
+        let keymgr = match KeyMgrBuilder::default()
+            .default_store(Box::new(ephemeral_store))
+            .build()
+        {
+            Ok(keymgr) => keymgr,
+            Err(_) => return Err(Error::TorKeyMgrBuilderError().into()),
+        };
+
+        // generate a nickname to identify this onion service
+        let service_id = V3OnionServiceId::from_private_key(private_key);
+        let hs_nickname = match HsNickname::new(service_id.to_string()) {
+            Ok(nickname) => nickname,
+            Err(_) => {
+                panic!("v3 onion service id string representation should be a valid HsNickname")
+            }
+        };
+
+        let hs_id_spec = HsIdKeypairSpecifier::new(hs_nickname.clone());
+        // generate a new HsIdKeypair (from an Ed25519PrivateKey)
+        // clone() isn't implemented for ExpandedKeypair >:[
+        let secret_key_bytes = private_key.inner().to_secret_key_bytes();
+        let expanded_keypair = ExpandedKeypair::from_secret_key_bytes(secret_key_bytes)
+            .unwrap()
+            .into();
+
+        // write the HsIdKeypair to keymgr
+        // TODO: for now this should return Ok(None) unless we persist the ephemeral store longer-term (ie for client auth keys in the future)
+        match keymgr.insert::<HsIdKeypair>(expanded_keypair, &hs_id_spec, KeystoreSelector::Default)
+        {
+            Ok(None) => (), // expected
+            Ok(Some(_)) => return Err(Error::KeyMgrInsertionFailure().into()),
+            Err(err) => Err(err).map_err(Error::TorKeyMgrError)?,
+        }
+
+        // create an OnionServiceConfig with the ephemeral nickname
+        let onion_service_config = match OnionServiceConfigBuilder::default()
+            .nickname(hs_nickname)
+            .build()
+        {
+            Ok(onion_service_config) => onion_service_config,
+            Err(err) => Err(err).map_err(Error::OnionServiceConfigBuilderError)?,
+        };
+
+        // create OnionService
+        let state_dir = match StateDirectory::new(self.state_dir.as_path(), &self.fs_mistrust) {
+            Ok(state_dir) => state_dir,
+            Err(err) => Err(err).map_err(Error::TorPersistError)?,
+        };
+        let onion_service = OnionService::new(onion_service_config, Arc::new(keymgr), &state_dir)
+            .map_err(Error::TorHsServiceStartupError)?;
+
+        let onion_addr = OnionAddr::V3(OnionAddrV3::new(service_id.clone(), virt_port));
+
+        // launch the OnionService and get a Stream of RendRequest
+        let runtime = self.arti_client.runtime().clone();
+        let dirmgr = self.arti_client.dirmgr().clone().upcast_arc();
+        let hs_circ_pool = self.arti_client.hs_circ_pool().clone();
+
+        let (onion_service, mut rend_requests) = onion_service
+            .launch(runtime, dirmgr, hs_circ_pool)
+            .map_err(Error::TorHsServiceStartupError)?;
+
+        // start a task to signal onion service published
+        let pending_events = self.pending_events.clone();
+        let mut status_events = onion_service.status_events();
+        self.tokio_runtime.spawn(async move {
+            while let Some(evt) = status_events.next().await {
+                match evt.state() {
+                    tor_hsservice::status::State::Running => match pending_events.lock() {
+                        Ok(mut pending_events) => {
+                            pending_events.push(TorEvent::OnionServicePublished { service_id });
+                            return;
+                        }
+                        Err(_) => unreachable!(
+                            "another thread panicked while holding this pending_events mutex"
+                        ),
+                    },
+                    _ => (),
+                }
+            }
+        });
+
+        // start a task which accepts every RendRequest to get a StreamRequest
+        self.tokio_runtime.spawn(async move {
+            while let Some(request) = rend_requests.next().await {
+                let mut stream_requests = match request.accept().await {
+                    Ok(stream_requests) => stream_requests,
+                    // TODO: probably not our problem?
+                    _ => return,
+                };
+                // spawn a new task to consume the stream requsts
+                tokio::task::spawn(async move {
+                    while let Some(stream_request) = stream_requests.next().await {
+                        let should_accept =
+                            if let IncomingStreamRequest::Begin(begin) = stream_request.request() {
+                                // we only accept connections on the virt port
+                                begin.port() == virt_port
+                            } else {
+                                false
+                            };
+
+                        if should_accept {
+                            let data_stream =
+                                match stream_request.accept(Connected::new_empty()).await {
+                                    Ok(data_stream) => data_stream,
+                                    // TODO: probably not our problem
+                                    _ => continue,
+                                };
+                            let (data_reader, data_writer) = data_stream.split();
+
+                            let (tcp_reader, tcp_writer) = match TcpStream::connect(target).await {
+                                Ok(tcp_stream) => tcp_stream.into_split(),
+                                // TODO: possibly our problem?
+                                _ => continue,
+                            };
+                            // now spawn new tasks to forward traffic to/from the onion listener
+
+                            let pump_alive = Arc::new(AtomicBool::new(true));
+                            // read from connected client and write to local socket
+                            tokio::task::spawn({
+                                let pump_alive = pump_alive.clone();
+                                async move {
+                                    forward_stream(pump_alive, data_reader, tcp_writer).await;
+                                }
+                            });
+                            // read from local socket and write to connected client
+                            tokio::task::spawn(async move {
+                                forward_stream(pump_alive, tcp_reader, data_writer).await;
+                            });
+                        } else {
+                            // either requesting the wrong port or the wrong type of stream request
+                            let _ = stream_request.shutdown_circuit();
+                        }
+                    }
+                });
+            }
+        });
+
+        Ok((onion_addr, onion_service))
+    }
 }
 
 impl TorProvider for ArtiClientTorClient {
@@ -366,12 +536,9 @@ impl TorProvider for ArtiClientTorClient {
         private_key: &Ed25519PrivateKey,
         virt_port: u16,
         authorized_clients: Option<&[X25519PublicKey]>,
+        non_anonymous: bool,
+        pow_defenses: Option<&PowDefenses>,
     ) -> Result<OnionListener, tor_provider::Error> {
-        // client auth is not implemented yet
-        if authorized_clients.is_some() {
-            return Err(Error::NotImplemented().into());
-        }
-
         // try to bind to a local address, let OS pick our port
         let socket_addr = SocketAddr::from(([127, 0, 0, 1], 0u16));
         // TODO: make this one async too
@@ -381,150 +548,55 @@ impl TorProvider for ArtiClientTorClient {
             .local_addr()
             .map_err(Error::TcpListenerLocalAddrFailed)?;
 
-        // create a new ephemeral store for storing our onion service keys
-        let ephemeral_store: ArtiEphemeralKeystore =
-            ArtiEphemeralKeystore::new("ephemeral".to_string());
-        let keymgr = match KeyMgrBuilder::default()
-            .default_store(Box::new(ephemeral_store))
-            .build()
-        {
-            Ok(keymgr) => keymgr,
-            Err(_) => return Err(Error::TorKeyMgrBuilderError().into()),
-        };
+        let (onion_addr, onion_service) = self.start_onion_service(
+            private_key,
+            virt_port,
+            authorized_clients,
+            non_anonymous,
+            pow_defenses,
+            socket_addr,
+        )?;
 
-        // generate a nickname to identify this onion service
-        let service_id = V3OnionServiceId::from_private_key(private_key);
-        let hs_nickname = match HsNickname::new(service_id.to_string()) {
-            Ok(nickname) => nickname,
-            Err(_) => {
-                panic!("v3 onion service id string representation should be a valid HsNickname")
-            }
-        };
-
-        let hs_id_spec = HsIdKeypairSpecifier::new(hs_nickname.clone());
-        // generate a new HsIdKeypair (from an Ed25519PrivateKey)
-        // clone() isn't implemented for ExpandedKeypair >:[
-        let secret_key_bytes = private_key.inner().to_secret_key_bytes();
-        let expanded_keypair = ExpandedKeypair::from_secret_key_bytes(secret_key_bytes)
-            .unwrap()
-            .into();
-
-        // write the HsIdKeypair to keymgr
-        // TODO: for now this should return Ok(None) unless we persist the ephemeral store longer-term (ie for client auth keys in the future)
-        match keymgr.insert::<HsIdKeypair>(expanded_keypair, &hs_id_spec, KeystoreSelector::Default)
-        {
-            Ok(None) => (), // expected
-            Ok(Some(_)) => return Err(Error::KeyMgrInsertionFailure().into()),
-            Err(err) => Err(err).map_err(Error::TorKeyMgrError)?,
-        }
-
-        // create an OnionServiceConfig with the ephemeral nickname
-        let onion_service_config = match OnionServiceConfigBuilder::default()
-            .nickname(hs_nickname)
-            .build()
-        {
-            Ok(onion_service_config) => onion_service_config,
-            Err(err) => Err(err).map_err(Error::OnionServiceConfigBuilderError)?,
-        };
+        // onion-service is torn down when `onion_service` is dropped
+        Ok(OnionListener::new::<Arc<RunningOnionService>>(listener, onion_addr, onion_service, |_|{}))
+    }
 
-        // create OnionService
-        let state_dir = match StateDirectory::new(self.state_dir.as_path(), &self.fs_mistrust) {
-            Ok(state_dir) => state_dir,
-            Err(err) => Err(err).map_err(Error::TorPersistError)?,
+    fn listener_with_target(
+        &mut self,
+        private_key: &Ed25519PrivateKey,
+        virt_port: u16,
+        authorized_clients: Option<&[X25519PublicKey]>,
+        non_anonymous: bool,
+        pow_defenses: Option<&PowDefenses>,
+        target: OnionServiceTarget,
+    ) -> Result<OnionServiceHandle, tor_provider::Error> {
+        // forwarding to unix sockets is not implemented yet
+        let socket_addr = match target {
+            OnionServiceTarget::Tcp(socket_addr) => socket_addr,
+            #[cfg(unix)]
+            OnionServiceTarget::Unix(_) => return Err(Error::NotImplemented().into()),
         };
-        let onion_service = OnionService::new(onion_service_config, Arc::new(keymgr), &state_dir)
-            .map_err(Error::TorHsServiceStartupError)?;
-
-        let onion_addr = OnionAddr::V3(OnionAddrV3::new(service_id.clone(), virt_port));
 
-        // launch the OnionService and get a Stream of RendRequest
-        let runtime = self.arti_client.runtime().clone();
-        let dirmgr = self.arti_client.dirmgr().clone().upcast_arc();
-        let hs_circ_pool = self.arti_client.hs_circ_pool().clone();
-
-        let (onion_service, mut rend_requests) = onion_service
-            .launch(runtime, dirmgr, hs_circ_pool)
-            .map_err(Error::TorHsServiceStartupError)?;
-
-        // start a task to signal onion service published
-        let pending_events = self.pending_events.clone();
-        let mut status_events = onion_service.status_events();
-        self.tokio_runtime.spawn(async move {
-            while let Some(evt) = status_events.next().await {
-                match evt.state() {
-                    tor_hsservice::status::State::Running => match pending_events.lock() {
-                        Ok(mut pending_events) => {
-                            pending_events.push(TorEvent::OnionServicePublished { service_id });
-                            return;
-                        }
-                        Err(_) => unreachable!(
-                            "another thread panicked while holding this pending_events mutex"
-                        ),
-                    },
-                    _ => (),
-                }
-            }
-        });
-
-        // start a task which accepts every RendRequest to get a StreamRequest
-        self.tokio_runtime.spawn(async move {
-            while let Some(request) = rend_requests.next().await {
-                let mut stream_requests = match request.accept().await {
-                    Ok(stream_requests) => stream_requests,
-                    // TODO: probably not our problem?
-                    _ => return,
-                };
-                // spawn a new task to consume the stream requsts
-                tokio::task::spawn(async move {
-                    while let Some(stream_request) = stream_requests.next().await {
-                        let should_accept =
-                            if let IncomingStreamRequest::Begin(begin) = stream_request.request() {
-                                // we only accept connections on the virt port
-                                begin.port() == virt_port
-                            } else {
-                                false
-                            };
-
-                        if should_accept {
-                            let data_stream =
-                                match stream_request.accept(Connected::new_empty()).await {
-                                    Ok(data_stream) => data_stream,
-                                    // TODO: probably not our problem
-                                    _ => continue,
-                                };
-                            let (data_reader, data_writer) = data_stream.split();
-
-                            let (tcp_reader, tcp_writer) =
-                                match TcpStream::connect(socket_addr).await {
-                                    Ok(tcp_stream) => tcp_stream.into_split(),
-                                    // TODO: possibly our problem?
-                                    _ => continue,
-                                };
-                            // now spawn new tasks to forward traffic to/from the onion listener
-
-                            let pump_alive = Arc::new(AtomicBool::new(true));
-                            // read from connected client and write to local socket
-                            tokio::task::spawn({
-                                let pump_alive = pump_alive.clone();
-                                async move {
-                                    forward_stream(pump_alive, data_reader, tcp_writer).await;
-                                }
-                            });
-                            // read from local socket and write to connected client
-                            tokio::task::spawn(async move {
-                                forward_stream(pump_alive, tcp_reader, data_writer).await;
-                            });
-                        } else {
-                            // either requesting the wrong port or the wrong type of stream request
-                            let _ = stream_request.shutdown_circuit();
-                        }
-                    }
-                });
-            }
-        });
+        let (onion_addr, onion_service) = self.start_onion_service(
+            private_key,
+            virt_port,
+            authorized_clients,
+            non_anonymous,
+            pow_defenses,
+            socket_addr,
+        )?;
 
         // onion-service is torn down when `onion_service` is dropped
-        Ok(OnionListener::new::<Arc<RunningOnionService>>(listener, onion_addr, onion_service, |_|{}))
+        Ok(OnionServiceHandle::new(onion_addr, onion_service, |_| {}))
+    }
+
+    fn set_authorised_clients(
+        &mut self,
+        _service_id: &V3OnionServiceId,
+        _authorised_clients: &[X25519PublicKey],
+    ) -> Result<(), tor_provider::Error> {
+        // client auth is not implemented yet
+        Err(Error::NotImplemented().into())
     }
 
     fn generate_token(&mut self) -> CircuitToken {
@@ -0,0 +1,92 @@
+// standard
+use std::sync::Mutex;
+use std::time::Duration;
+// std::time::Instant::now() panics on wasm32-unknown-unknown, so the host's
+// clock is used there instead
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+pub use std::time::Instant;
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+pub use web_time::Instant;
+
+/// A source of the current time for timeout and retry logic.
+///
+/// Components which measure elapsed time (e.g. handshake deadlines) read the time from a `Clock` rather than directly from [`Instant::now()`] so that tests may substitute a [`VirtualClock`] and advance time deterministically rather than sleeping.
+pub trait Clock: Send + Sync {
+    /// The current time.
+    fn now(&self) -> Instant;
+
+    /// Block the calling thread for the given duration.
+    fn sleep(&self, duration: Duration);
+}
+
+/// The system's monotonic clock.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        std::thread::sleep(duration);
+    }
+}
+
+/// A clock whose time only moves when it is advanced.
+///
+/// Time starts at the moment the `VirtualClock` is created and is advanced explicitly with [`VirtualClock::advance()`]. Calls to [`Clock::sleep()`] return immediately after advancing the clock by the requested duration.
+#[derive(Debug)]
+pub struct VirtualClock {
+    now: Mutex<Instant>,
+}
+
+impl VirtualClock {
+    /// Construct a new `VirtualClock` starting at the current system time.
+    pub fn new() -> Self {
+        Self {
+            now: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Move the clock's time forward by the given duration.
+    pub fn advance(&self, duration: Duration) {
+        match self.now.lock() {
+            Ok(mut now) => *now += duration,
+            Err(_) => unreachable!("another thread panicked while holding this clock's mutex"),
+        }
+    }
+}
+
+impl Default for VirtualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> Instant {
+        match self.now.lock() {
+            Ok(now) => *now,
+            Err(_) => unreachable!("another thread panicked while holding this clock's mutex"),
+        }
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration);
+    }
+}
+
+#[test]
+fn test_virtual_clock() {
+    let clock = VirtualClock::new();
+    let start = clock.now();
+    assert_eq!(clock.now(), start);
+
+    clock.advance(Duration::from_secs(60));
+    assert_eq!(clock.now() - start, Duration::from_secs(60));
+
+    // sleeping returns immediately but moves time forward
+    clock.sleep(Duration::from_secs(3600));
+    assert_eq!(clock.now() - start, Duration::from_secs(3660));
+}
@@ -5,9 +5,10 @@ use std::default::Default;
 use std::net::{SocketAddr, TcpListener};
 use std::option::Option;
 use std::path::PathBuf;
+use std::str::FromStr;
 use std::string::ToString;
 use std::sync::{atomic, Arc};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 // extern crates
 use socks::Socks5Stream;
@@ -16,6 +17,8 @@ use socks::Socks5Stream;
 use crate::censorship_circumvention::*;
 use crate::legacy_tor_control_stream::*;
 use crate::legacy_tor_controller::*;
+pub use crate::legacy_tor_process::Error as LegacyTorProcessError;
+pub use crate::legacy_tor_process::TorProcessSandbox;
 use crate::legacy_tor_process::*;
 use crate::legacy_tor_version::*;
 use crate::proxy::*;
@@ -59,6 +62,9 @@ pub enum Error {
     #[error("failed to setconf")]
     SetConfFailed(#[source] crate::legacy_tor_controller::Error),
 
+    #[error("failed to getconf")]
+    GetConfFailed(#[source] crate::legacy_tor_controller::Error),
+
     #[error("failed to add client auth for onion service")]
     OnionClientAuthAddFailed(#[source] crate::legacy_tor_controller::Error),
 
@@ -86,9 +92,48 @@ pub enum Error {
     #[error("failed to create onion service")]
     AddOnionFailed(#[source] crate::legacy_tor_controller::Error),
 
+    #[error("onion service not found: {0}")]
+    OnionServiceNotFound(V3OnionServiceId),
+
+    #[error("failed to remove onion service before updating its authorised clients")]
+    UpdateAuthorisedClientsDelOnionFailed(#[source] crate::legacy_tor_controller::Error),
+
+    #[error("failed to restart onion service with updated authorised clients")]
+    UpdateAuthorisedClientsAddOnionFailed(#[source] crate::legacy_tor_controller::Error),
+
+    #[error("failed to remove onion service before refreshing its descriptor")]
+    RefreshDescriptorDelOnionFailed(#[source] crate::legacy_tor_controller::Error),
+
+    #[error("failed to restart onion service to refresh its descriptor")]
+    RefreshDescriptorAddOnionFailed(#[source] crate::legacy_tor_controller::Error),
+
+    #[error("failed to fetch onion service descriptor")]
+    HsFetchFailed(#[source] crate::legacy_tor_controller::Error),
+
+    #[error("descriptor republish interval must be greater than zero")]
+    DescriptorRepublishIntervalZero(),
+
     #[error("tor not bootstrapped")]
     LegacyTorNotBootstrapped(),
 
+    #[error("tor process not configured for non-anonymous single onion services; HiddenServiceNonAnonymousMode and HiddenServiceSingleHopMode must be enabled")]
+    NonAnonymousModeNotConfigured(),
+
+    #[error("tor process version does not support proof-of-work onion service defenses; found {0} but must be at least {1}")]
+    PowDefensesNotSupported(String, String),
+
+    #[error("tor process version too old for vanguards-lite; found {0} but must be at least {1}")]
+    VanguardsLiteNotSupported(String, String),
+
+    #[error("security options may only be changed for a tor process launched by LegacyTorClient")]
+    SystemTorSecurityConfigNotSupported(),
+
+    #[error("SOCKS policy may only be changed for a tor process launched by LegacyTorClient")]
+    SystemTorSocksPolicyNotSupported(),
+
+    #[error("unix socket path must be valid utf8 and may not contain whitespace or quotes: {0:?}")]
+    UnixSocketPathInvalid(PathBuf),
+
     #[error("{0}")]
     PluggableTransportConfigDirectoryCreationFailed(#[source] std::io::Error),
 
@@ -148,6 +193,157 @@ impl Default for LegacyCircuitToken {
     }
 }
 
+// find the value of a keyword argument from a CIRC or STREAM event
+fn event_argument(arguments: &[(String, String)], key: &str) -> Option<String> {
+    arguments
+        .iter()
+        .find(|(k, _)| k == key)
+        .map(|(_, v)| v.clone())
+}
+
+// the SOCKS policy violation reported by a STATUS_CLIENT event, if any
+fn socks_policy_violation(
+    action: &str,
+    arguments: &[(String, String)],
+) -> Option<SocksPolicyViolation> {
+    match action {
+        "DANGEROUS_SOCKS" => Some(SocksPolicyViolation::UnsafeAddress {
+            protocol: event_argument(arguments, "PROTOCOL").unwrap_or_default(),
+            address: event_argument(arguments, "ADDRESS").unwrap_or_default(),
+        }),
+        "DANGEROUS_PORT" => Some(SocksPolicyViolation::PlaintextPort {
+            port: event_argument(arguments, "PORT")?.parse().ok()?,
+            rejected: event_argument(arguments, "RESULT").as_deref() == Some("REJECT"),
+        }),
+        "SOCKS_BAD_HOSTNAME" => Some(SocksPolicyViolation::InvalidHostname {
+            hostname: event_argument(arguments, "HOSTNAME").unwrap_or_default(),
+        }),
+        "SOCKS_UNKNOWN_PROTOCOL" => Some(SocksPolicyViolation::UnknownProtocol),
+        _ => None,
+    }
+}
+
+//
+// LegacyTorSecurityConfig
+//
+
+// the c-tor default LongLivedPorts
+const DEFAULT_LONG_LIVED_PORTS: &str = "21,22,706,1863,5050,5190,5222,5223,6523,6667,6697,8300";
+
+/// The c-tor circuit-hardening options relevant to onion-service operators. Options which are `None` are left at (or reset to) the tor daemon's defaults. A [`SecurityLevel`] converts to the options it applies.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LegacyTorSecurityConfig {
+    /// Whether onion-service circuits use vanguards-lite (`VanguardsLiteEnabled`), which requires tor 0.4.7.1 or later; `None` leaves the choice to the consensus
+    pub vanguards_lite: Option<bool>,
+    /// The number of entry guards used for circuits (`NumEntryGuards`); `None` uses the consensus' value
+    pub num_entry_guards: Option<u32>,
+    /// The ports whose streams are given circuits of stable, long-lived relays (`LongLivedPorts`); `None` uses tor's default list
+    pub long_lived_ports: Option<Vec<u16>>,
+}
+
+impl From<SecurityLevel> for LegacyTorSecurityConfig {
+    fn from(security_level: SecurityLevel) -> Self {
+        match security_level {
+            SecurityLevel::Standard => Default::default(),
+            SecurityLevel::Hardened => Self {
+                vanguards_lite: Some(true),
+                num_entry_guards: Some(1),
+                long_lived_ports: None,
+            },
+        }
+    }
+}
+
+impl LegacyTorSecurityConfig {
+    // the SETCONF key-values applying these options for the given tor version
+    fn setconf_values(
+        &self,
+        version: &LegacyTorVersion,
+    ) -> Result<Vec<(&'static str, String)>, Error> {
+        // VanguardsLiteEnabled was added in tor 0.4.7.1
+        let min_vanguards_lite_version = LegacyTorVersion {
+            major: 0u32,
+            minor: 4u32,
+            micro: 7u32,
+            patch_level: 1u32,
+            status_tag: None,
+        };
+
+        let mut key_values: Vec<(&'static str, String)> = Default::default();
+        if *version >= min_vanguards_lite_version {
+            let vanguards_lite = match self.vanguards_lite {
+                Some(true) => "1",
+                Some(false) => "0",
+                None => "auto",
+            };
+            key_values.push(("VanguardsLiteEnabled", vanguards_lite.to_string()));
+        } else if self.vanguards_lite.is_some() {
+            return Err(Error::VanguardsLiteNotSupported(
+                version.to_string(),
+                min_vanguards_lite_version.to_string(),
+            ));
+        }
+        // 0 defers to the consensus
+        key_values.push((
+            "NumEntryGuards",
+            self.num_entry_guards.unwrap_or(0).to_string(),
+        ));
+        let long_lived_ports = match &self.long_lived_ports {
+            Some(long_lived_ports) => long_lived_ports
+                .iter()
+                .map(|port| port.to_string())
+                .collect::<Vec<String>>()
+                .join(","),
+            None => DEFAULT_LONG_LIVED_PORTS.to_string(),
+        };
+        key_values.push(("LongLivedPorts", long_lived_ports));
+        Ok(key_values)
+    }
+}
+
+//
+// LegacyTorSocksPolicy
+//
+
+// the c-tor default WarnPlaintextPorts
+const DEFAULT_WARN_PLAINTEXT_PORTS: &str = "23,109,110,143";
+
+/// The c-tor options which guard against applications leaking DNS requests or plaintext traffic when proxying through the tor daemon's SOCKS port. Violations are reported as [`TorEvent::SocksPolicyViolated`]. The default policy matches the tor daemon's defaults.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LegacyTorSocksPolicy {
+    /// Whether SOCKS requests which give an IP address rather than a hostname are refused (`SafeSocks`); such requests suggest the application resolved the hostname outside of the Tor Network. Connections made with [`TorProvider::connect()`] to an IP address are refused too.
+    pub safe_socks: bool,
+    /// Whether the tor daemon logs whether each SOCKS request is safe (`TestSocks`)
+    pub test_socks: bool,
+    /// The ports whose streams are warned about because they are commonly used by plaintext protocols (`WarnPlaintextPorts`); `None` uses tor's default list
+    pub warn_plaintext_ports: Option<Vec<u16>>,
+    /// The ports whose streams are refused because they are commonly used by plaintext protocols (`RejectPlaintextPorts`)
+    pub reject_plaintext_ports: Vec<u16>,
+}
+
+impl LegacyTorSocksPolicy {
+    // the SETCONF key-values applying this policy
+    fn setconf_values(&self) -> Vec<(&'static str, String)> {
+        let ports = |ports: &[u16]| {
+            ports
+                .iter()
+                .map(|port| port.to_string())
+                .collect::<Vec<String>>()
+                .join(",")
+        };
+        let warn_plaintext_ports = match &self.warn_plaintext_ports {
+            Some(warn_plaintext_ports) => ports(warn_plaintext_ports),
+            None => DEFAULT_WARN_PLAINTEXT_PORTS.to_string(),
+        };
+        vec![
+            ("SafeSocks", u8::from(self.safe_socks).to_string()),
+            ("TestSocks", u8::from(self.test_socks).to_string()),
+            ("WarnPlaintextPorts", warn_plaintext_ports),
+            ("RejectPlaintextPorts", ports(&self.reject_plaintext_ports)),
+        ]
+    }
+}
+
 //
 // LegacyTorClientConfig
 //
@@ -161,6 +357,10 @@ pub enum LegacyTorClientConfig {
         allowed_ports: Option<Vec<u16>>,
         pluggable_transports: Option<Vec<PluggableTransportConfig>>,
         bridge_lines: Option<Vec<BridgeLine>>,
+        sandbox: Option<Box<TorProcessSandbox>>,
+        security_config: Option<Box<LegacyTorSecurityConfig>>,
+        socks_policy: Option<Box<LegacyTorSocksPolicy>>,
+        shared_network_cache: Option<PathBuf>,
     },
     SystemTor {
         tor_socks_addr: SocketAddr,
@@ -169,6 +369,47 @@ pub enum LegacyTorClientConfig {
     },
 }
 
+//
+// LegacyOnionService
+//
+
+// An onion service started by LegacyTorClient::listener(); everything needed
+// to re-add the service to the tor daemon is kept so its authorised clients
+// may be updated without closing its OnionListener
+struct LegacyOnionService {
+    service_id: V3OnionServiceId,
+    // cleared when the associated OnionListener is dropped
+    is_active: Arc<atomic::AtomicBool>,
+    private_key: Ed25519PrivateKey,
+    // each virt-port and the local socket its connections are forwarded to
+    ports: Vec<(u16, OnionServiceTarget)>,
+    non_anonymous: bool,
+    pow_defenses: Option<PowDefenses>,
+    // an empty list disables client authorisation
+    authorised_clients: Vec<X25519PublicKey>,
+    // descriptor upload results since the service was last (re-)added
+    uploads_succeeded: usize,
+    uploads_failed: usize,
+    published: bool,
+    // when the service's descriptor last reached the publish quorum
+    published_at: Option<Instant>,
+    // the service was re-added only to refresh its descriptor, so reaching
+    // the publish quorum is reported as a refresh rather than a publication
+    refreshing: bool,
+}
+
+// an onion service whose ADD_ONION command has been written but whose reply
+// has not yet been read
+struct PendingOnionService {
+    ticket: CommandTicket,
+    flags: AddOnionFlags,
+    client_auth: bool,
+    private_key: Ed25519PrivateKey,
+    ports: Vec<(u16, OnionServiceTarget)>,
+    pow_defenses: Option<PowDefenses>,
+    authorised_clients: Vec<X25519PublicKey>,
+}
+
 //
 // LegacyTorClient
 //
@@ -184,8 +425,14 @@ pub struct LegacyTorClient {
     controller: LegacyTorController,
     bootstrapped: bool,
     socks_listener: Option<SocketAddr>,
-    // list of open onion services and their is_active flag
-    onion_services: Vec<(V3OnionServiceId, Arc<atomic::AtomicBool>)>,
+    // list of open onion services
+    onion_services: Vec<LegacyOnionService>,
+    // number of successful descriptor uploads before an onion service is published
+    publish_quorum: usize,
+    // how long a published descriptor is kept before it is refreshed
+    republish_interval: Option<Duration>,
+    // number of descriptor fetches in progress for each onion service
+    descriptor_fetches: BTreeMap<V3OnionServiceId, usize>,
     // our list of circuit tokens for the tor daemon
     circuit_token_counter: usize,
     circuit_tokens: BTreeMap<CircuitToken, LegacyCircuitToken>,
@@ -198,12 +445,18 @@ impl LegacyTorClient {
             LegacyTorClientConfig::BundledTor {
                 tor_bin_path,
                 data_directory,
+                sandbox,
+                shared_network_cache,
                 ..
             } => {
                 // launch tor
-                let daemon =
-                    LegacyTorProcess::new(tor_bin_path.as_path(), data_directory.as_path())
-                        .map_err(Error::LegacyTorProcessCreationFailed)?;
+                let daemon = LegacyTorProcess::new(
+                    tor_bin_path.as_path(),
+                    data_directory.as_path(),
+                    sandbox.as_deref(),
+                    shared_network_cache.as_deref(),
+                )
+                .map_err(Error::LegacyTorProcessCreationFailed)?;
                 // open a control stream
                 let control_stream =
                     LegacyControlStream::new(daemon.get_control_addr(), Duration::from_millis(16))
@@ -239,9 +492,17 @@ impl LegacyTorClient {
             }
         };
 
-        // authenticate
+        // authenticate and request the version in a single round-trip; tor
+        // closes the connection after a failed authentication so the version
+        // request is never answered in that case
+        let authenticate = controller
+            .authenticate_submit(&password)
+            .map_err(Error::LegacyTorProcessAuthenticationFailed)?;
+        let version = controller
+            .getinfo_version_submit()
+            .map_err(Error::GetInfoVersionFailed)?;
         controller
-            .authenticate(&password)
+            .authenticate_wait(authenticate)
             .map_err(Error::LegacyTorProcessAuthenticationFailed)?;
 
         // min required version for v3 client auth (see control-spec.txt)
@@ -255,7 +516,7 @@ impl LegacyTorClient {
 
         // verify version is recent enough
         let version = controller
-            .getinfo_version()
+            .getinfo_version_wait(version)
             .map_err(Error::GetInfoVersionFailed)?;
 
         if version < min_required_version {
@@ -265,6 +526,9 @@ impl LegacyTorClient {
             ));
         }
 
+        // configuration is collected and submitted as a single pipelined batch
+        let mut setconfs: Vec<Vec<(&str, String)>> = Default::default();
+
         // configure tor client
         if let LegacyTorClientConfig::BundledTor {
             data_directory,
@@ -272,35 +536,29 @@ impl LegacyTorClient {
             allowed_ports,
             pluggable_transports,
             bridge_lines,
+            security_config,
+            socks_policy,
             ..
         } = config
         {
             // configure proxy
             match proxy_settings {
                 Some(ProxyConfig::Socks4(Socks4ProxyConfig { address })) => {
-                    controller
-                        .setconf(&[("Socks4Proxy", address.to_string())])
-                        .map_err(Error::SetConfFailed)?;
+                    setconfs.push(vec![("Socks4Proxy", address.to_string())]);
                 }
                 Some(ProxyConfig::Socks5(Socks5ProxyConfig {
                     address,
                     username,
                     password,
                 })) => {
-                    controller
-                        .setconf(&[("Socks5Proxy", address.to_string())])
-                        .map_err(Error::SetConfFailed)?;
+                    setconfs.push(vec![("Socks5Proxy", address.to_string())]);
                     let username = username.unwrap_or("".to_string());
                     if !username.is_empty() {
-                        controller
-                            .setconf(&[("Socks5ProxyUsername", username.to_string())])
-                            .map_err(Error::SetConfFailed)?;
+                        setconfs.push(vec![("Socks5ProxyUsername", username.to_string())]);
                     }
                     let password = password.unwrap_or("".to_string());
                     if !password.is_empty() {
-                        controller
-                            .setconf(&[("Socks5ProxyPassword", password.to_string())])
-                            .map_err(Error::SetConfFailed)?;
+                        setconfs.push(vec![("Socks5ProxyPassword", password.to_string())]);
                     }
                 }
                 Some(ProxyConfig::Https(HttpsProxyConfig {
@@ -308,16 +566,12 @@ impl LegacyTorClient {
                     username,
                     password,
                 })) => {
-                    controller
-                        .setconf(&[("HTTPSProxy", address.to_string())])
-                        .map_err(Error::SetConfFailed)?;
+                    setconfs.push(vec![("HTTPSProxy", address.to_string())]);
                     let username = username.unwrap_or("".to_string());
                     let password = password.unwrap_or("".to_string());
                     if !username.is_empty() || !password.is_empty() {
                         let authenticator = format!("{}:{}", username, password);
-                        controller
-                            .setconf(&[("HTTPSProxyAuthenticator", authenticator)])
-                            .map_err(Error::SetConfFailed)?;
+                        setconfs.push(vec![("HTTPSProxyAuthenticator", authenticator)]);
                     }
                 }
                 None => (),
@@ -329,9 +583,7 @@ impl LegacyTorClient {
                     .map(|port| format!("*{{}}:{port}"))
                     .collect();
                 let allowed_addresses = allowed_addresses.join(", ");
-                controller
-                    .setconf(&[("ReachableAddresses", allowed_addresses)])
-                    .map_err(Error::SetConfFailed)?;
+                setconfs.push(vec![("ReachableAddresses", allowed_addresses)]);
             }
             // configure pluggable transports
             let mut supported_transports: std::collections::BTreeSet<String> = Default::default();
@@ -409,9 +661,7 @@ impl LegacyTorClient {
                     let value = format!("{transports} exec {path_to_binary} {options}");
                     conf.push(("ClientTransportPlugin", value));
                 }
-                controller
-                    .setconf(conf.as_slice())
-                    .map_err(Error::SetConfFailed)?;
+                setconfs.push(conf);
             }
             // configure bridge lines
             if let Some(bridge_lines) = bridge_lines {
@@ -426,15 +676,39 @@ impl LegacyTorClient {
                     conf.push(("Bridge", value));
                 }
                 conf.push(("UseBridges", "1".to_string()));
-                controller
-                    .setconf(conf.as_slice())
-                    .map_err(Error::SetConfFailed)?;
+                setconfs.push(conf);
+            }
+            // configure circuit hardening
+            if let Some(security_config) = security_config {
+                setconfs.push(security_config.setconf_values(&version)?);
+            }
+            // configure SOCKS policy
+            if let Some(socks_policy) = socks_policy {
+                setconfs.push(socks_policy.setconf_values());
             }
         }
 
-        // register for STATUS_CLIENT async events
+        let mut tickets = Vec::with_capacity(setconfs.len());
+        for key_values in &setconfs {
+            tickets.push(
+                controller
+                    .setconf_submit(key_values)
+                    .map_err(Error::SetConfFailed)?,
+            );
+        }
+
+        // register for STATUS_CLIENT, HS_DESC, CIRC and STREAM async events
+        let setevents = controller
+            .setevents_submit(&["STATUS_CLIENT", "HS_DESC", "CIRC", "STREAM"])
+            .map_err(Error::SetEventsFailed)?;
+
+        for ticket in tickets {
+            controller
+                .setconf_wait(ticket)
+                .map_err(Error::SetConfFailed)?;
+        }
         controller
-            .setevents(&["STATUS_CLIENT", "HS_DESC"])
+            .setevents_wait(setevents)
             .map_err(Error::SetEventsFailed)?;
 
         Ok(LegacyTorClient {
@@ -444,6 +718,9 @@ impl LegacyTorClient {
             bootstrapped: false,
             socks_listener,
             onion_services: Default::default(),
+            publish_quorum: 1usize,
+            republish_interval: None,
+            descriptor_fetches: Default::default(),
             circuit_token_counter: 0usize,
             circuit_tokens: Default::default(),
         })
@@ -453,24 +730,315 @@ impl LegacyTorClient {
     pub fn version(&mut self) -> LegacyTorVersion {
         self.version.clone()
     }
+
+    /// Set the number of hidden-service directories which must accept an onion-service's descriptor before [`TorEvent::OnionServicePublished`] is emitted for it. The default quorum of 1 announces an onion-service as soon as its first descriptor upload succeeds, though most directories may not yet have it. A quorum of 0 is treated as 1.
+    ///
+    /// The outcome of each upload is reported with [`TorEvent::OnionServiceDescriptorUploadStatus`]. Onion-services whose uploads cannot reach the quorum are never announced, so the quorum should not exceed the number of directories tor uploads to (currently 8 per descriptor).
+    pub fn set_publish_quorum(&mut self, quorum: usize) {
+        self.publish_quorum = std::cmp::max(quorum, 1usize);
+    }
+
+    /// Apply circuit-hardening options to the launched tor process; options which are `None` are reset to the tor daemon's defaults. The options of an already running tor daemon belong to its operator, so they may not be changed when using [`LegacyTorClientConfig::SystemTor`].
+    pub fn set_security_config(
+        &mut self,
+        security_config: &LegacyTorSecurityConfig,
+    ) -> Result<(), Error> {
+        if self.daemon.is_none() {
+            return Err(Error::SystemTorSecurityConfigNotSupported());
+        }
+        let key_values = security_config.setconf_values(&self.version)?;
+        self.controller
+            .setconf(&key_values)
+            .map_err(Error::SetConfFailed)
+    }
+
+    /// Apply a SOCKS policy to the launched tor process. The options of an already running tor daemon belong to its operator, so they may not be changed when using [`LegacyTorClientConfig::SystemTor`]; its violations are still reported.
+    pub fn set_socks_policy(&mut self, socks_policy: &LegacyTorSocksPolicy) -> Result<(), Error> {
+        if self.daemon.is_none() {
+            return Err(Error::SystemTorSocksPolicyNotSupported());
+        }
+        self.controller
+            .setconf(&socks_policy.setconf_values())
+            .map_err(Error::SetConfFailed)
+    }
+
+    // add an onion service forwarding to target to the tor daemon; the
+    // returned flag must be cleared once the onion service is no longer used
+    // whether the daemon is running in non-anonymous mode, as required to host
+    // single onion services
+    fn non_anonymous_mode(&mut self) -> Result<bool, Error> {
+        let non_anonymous_mode = self
+            .controller
+            .getconf(&["HiddenServiceNonAnonymousMode"])
+            .map_err(Error::GetConfFailed)?;
+        Ok(non_anonymous_mode
+            .iter()
+            .any(|(key, value)| key == "HiddenServiceNonAnonymousMode" && value == "1"))
+    }
+
+    // proof-of-work defenses were added to ADD_ONION in tor 0.4.8.1
+    fn ensure_pow_defenses_supported(&self) -> Result<(), Error> {
+        let min_required_version = LegacyTorVersion {
+            major: 0u32,
+            minor: 4u32,
+            micro: 8u32,
+            patch_level: 1u32,
+            status_tag: None,
+        };
+        if self.version < min_required_version {
+            return Err(Error::PowDefensesNotSupported(
+                self.version.to_string(),
+                min_required_version.to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    // write an onion service's ADD_ONION command without waiting for its reply
+    fn submit_onion_service(
+        &mut self,
+        private_key: &Ed25519PrivateKey,
+        ports: Vec<(u16, OnionServiceTarget)>,
+        authorized_clients: Option<&[X25519PublicKey]>,
+        non_anonymous: bool,
+        pow_defenses: Option<&PowDefenses>,
+    ) -> Result<PendingOnionService, Error> {
+        let mut flags = AddOnionFlags {
+            discard_pk: true,
+            ..Default::default()
+        };
+        if authorized_clients.is_some() {
+            flags.v3_auth = true;
+        }
+        if non_anonymous {
+            flags.non_anonymous = true;
+        }
+
+        let ticket = self
+            .controller
+            .add_onion_submit(
+                Some(private_key),
+                &flags,
+                None,
+                pow_defenses,
+                &ports
+                    .iter()
+                    .map(|(virt_port, target)| (*virt_port, Some(target)))
+                    .collect::<Vec<_>>(),
+                authorized_clients,
+            )
+            .map_err(Error::AddOnionFailed)?;
+
+        Ok(PendingOnionService {
+            ticket,
+            flags,
+            client_auth: authorized_clients.is_some_and(|keys| !keys.is_empty()),
+            private_key: private_key.clone(),
+            ports,
+            pow_defenses: pow_defenses.cloned(),
+            authorised_clients: authorized_clients.map(<[_]>::to_vec).unwrap_or_default(),
+        })
+    }
+
+    // wait for a submitted onion service's ADD_ONION reply and begin tracking it
+    fn wait_onion_service(
+        &mut self,
+        pending: PendingOnionService,
+    ) -> Result<(V3OnionServiceId, Arc<atomic::AtomicBool>), Error> {
+        let (_, service_id) = self
+            .controller
+            .add_onion_wait(pending.ticket, &pending.flags, pending.client_auth)
+            .map_err(Error::AddOnionFailed)?;
+
+        let is_active = Arc::new(atomic::AtomicBool::new(true));
+        self.onion_services.push(LegacyOnionService {
+            service_id: service_id.clone(),
+            is_active: Arc::clone(&is_active),
+            private_key: pending.private_key,
+            ports: pending.ports,
+            non_anonymous: pending.flags.non_anonymous,
+            pow_defenses: pending.pow_defenses,
+            authorised_clients: pending.authorised_clients,
+            uploads_succeeded: 0usize,
+            uploads_failed: 0usize,
+            published: false,
+            published_at: None,
+            refreshing: false,
+        });
+
+        Ok((service_id, is_active))
+    }
+
+    // remove a tracked onion service from the tor daemon and add it again with
+    // the same key, targets and authorised clients, so that tor builds and
+    // uploads fresh descriptors; the OnionListener's socket is unaffected
+    fn readd_onion_service(
+        &mut self,
+        index: usize,
+        del_onion_failed: fn(crate::legacy_tor_controller::Error) -> Error,
+        add_onion_failed: fn(crate::legacy_tor_controller::Error) -> Error,
+    ) -> Result<(), Error> {
+        let onion_service = &mut self.onion_services[index];
+        let flags = AddOnionFlags {
+            discard_pk: true,
+            v3_auth: !onion_service.authorised_clients.is_empty(),
+            non_anonymous: onion_service.non_anonymous,
+            ..Default::default()
+        };
+        let authorised_clients = if onion_service.authorised_clients.is_empty() {
+            None
+        } else {
+            Some(onion_service.authorised_clients.as_slice())
+        };
+
+        self.controller
+            .del_onion(&onion_service.service_id)
+            .map_err(del_onion_failed)?;
+        self.controller
+            .add_onion(
+                Some(&onion_service.private_key),
+                &flags,
+                None,
+                onion_service.pow_defenses.as_ref(),
+                &onion_service
+                    .ports
+                    .iter()
+                    .map(|(virt_port, target)| (*virt_port, Some(target)))
+                    .collect::<Vec<_>>(),
+                authorised_clients,
+            )
+            .map_err(add_onion_failed)?;
+
+        // the re-added service's descriptors must be uploaded again
+        onion_service.uploads_succeeded = 0usize;
+        onion_service.uploads_failed = 0usize;
+        onion_service.published = false;
+
+        Ok(())
+    }
+
+    // index of the active onion service with the given service id
+    fn find_onion_service(&self, service_id: &V3OnionServiceId) -> Result<usize, Error> {
+        self.onion_services
+            .iter()
+            .position(|onion_service| {
+                onion_service.service_id == *service_id
+                    && onion_service.is_active.load(atomic::Ordering::Relaxed)
+            })
+            .ok_or_else(|| Error::OnionServiceNotFound(service_id.clone()))
+    }
+
+    // bind a listener's local socket and write its onion service's ADD_ONION
+    // command; non_anonymous_mode caches the daemon's mode across a batch
+    fn submit_listener(
+        &mut self,
+        config: &ListenerConfig,
+        non_anonymous_mode: &mut Option<bool>,
+    ) -> Result<(TcpListener, PendingOnionService), Error> {
+        if config.non_anonymous {
+            let non_anonymous_mode = match *non_anonymous_mode {
+                Some(non_anonymous_mode) => non_anonymous_mode,
+                None => *non_anonymous_mode.insert(self.non_anonymous_mode()?),
+            };
+            if !non_anonymous_mode {
+                return Err(Error::NonAnonymousModeNotConfigured());
+            }
+        }
+        if config.pow_defenses.is_some() {
+            self.ensure_pow_defenses_supported()?;
+        }
+
+        // try to bind to a local address, let OS pick our port
+        let socket_addr = SocketAddr::from(([127, 0, 0, 1], 0u16));
+        let listener = TcpListener::bind(socket_addr).map_err(Error::TcpListenerBindFailed)?;
+        let socket_addr = listener
+            .local_addr()
+            .map_err(Error::TcpListenerLocalAddrFailed)?;
+
+        let pending = self.submit_onion_service(
+            &config.private_key,
+            vec![(config.virt_port, OnionServiceTarget::Tcp(socket_addr))],
+            config.authorised_clients.as_deref(),
+            config.non_anonymous,
+            config.pow_defenses.as_ref(),
+        )?;
+        Ok((listener, pending))
+    }
+
+    fn start_onion_service(
+        &mut self,
+        private_key: &Ed25519PrivateKey,
+        ports: Vec<(u16, OnionServiceTarget)>,
+        authorized_clients: Option<&[X25519PublicKey]>,
+        non_anonymous: bool,
+        pow_defenses: Option<&PowDefenses>,
+    ) -> Result<(V3OnionServiceId, Arc<atomic::AtomicBool>), Error> {
+        // single onion services require the daemon to be running in non-anonymous mode
+        if non_anonymous && !self.non_anonymous_mode()? {
+            return Err(Error::NonAnonymousModeNotConfigured());
+        }
+        if pow_defenses.is_some() {
+            self.ensure_pow_defenses_supported()?;
+        }
+
+        let pending = self.submit_onion_service(
+            private_key,
+            ports,
+            authorized_clients,
+            non_anonymous,
+            pow_defenses,
+        )?;
+        self.wait_onion_service(pending)
+    }
 }
 
 impl TorProvider for LegacyTorClient {
     fn update(&mut self) -> Result<Vec<TorEvent>, tor_provider::Error> {
+        // remove onion services with no active listeners, pipelining
+        // the DEL_ONION commands
+        let mut tickets: Vec<CommandTicket> = Default::default();
         let mut i = 0;
         while i < self.onion_services.len() {
-            // remove onion services with no active listeners
-            if !self.onion_services[i].1.load(atomic::Ordering::Relaxed) {
+            if !self.onion_services[i]
+                .is_active
+                .load(atomic::Ordering::Relaxed)
+            {
                 let entry = self.onion_services.swap_remove(i);
-                let service_id = entry.0;
+                let service_id = entry.service_id;
 
-                self.controller
-                    .del_onion(&service_id)
-                    .map_err(Error::DelOnionFailed)?;
+                tickets.push(
+                    self.controller
+                        .del_onion_submit(&service_id)
+                        .map_err(Error::DelOnionFailed)?,
+                );
             } else {
                 i += 1;
             }
         }
+        for ticket in tickets {
+            self.controller
+                .del_onion_wait(ticket)
+                .map_err(Error::DelOnionFailed)?;
+        }
+
+        // refresh descriptors published longer than the republish interval ago
+        if let Some(republish_interval) = self.republish_interval {
+            for i in 0..self.onion_services.len() {
+                let onion_service = &mut self.onion_services[i];
+                let due = onion_service.published
+                    && onion_service
+                        .published_at
+                        .is_some_and(|published_at| published_at.elapsed() >= republish_interval);
+                if due {
+                    onion_service.refreshing = true;
+                    self.readd_onion_service(
+                        i,
+                        Error::RefreshDescriptorDelOnionFailed,
+                        Error::RefreshDescriptorAddOnionFailed,
+                    )?;
+                }
+            }
+        }
 
         let mut events: Vec<TorEvent> = Default::default();
         for async_event in self
@@ -505,13 +1073,125 @@ impl TorProvider for LegacyTorClient {
                         if progress == 100u32 {
                             events.push(TorEvent::BootstrapComplete);
                             self.bootstrapped = true;
+                            // other daemons sharing the network cache may now
+                            // bootstrap from what this one downloaded
+                            if let Some(daemon) = &self.daemon {
+                                if let Err(err) = daemon.publish_network_cache() {
+                                    tracing::warn!(error = %err, "failed to publish network cache");
+                                }
+                            }
                         }
+                    } else if let Some(violation) = socks_policy_violation(action, arguments) {
+                        events.push(TorEvent::SocksPolicyViolated { violation });
                     }
                 }
-                AsyncEvent::HsDesc { action, hs_address } => {
-                    if action == "UPLOADED" {
-                        events.push(TorEvent::OnionServicePublished {
-                            service_id: hs_address.clone(),
+                AsyncEvent::HsDesc {
+                    action,
+                    hs_address,
+                    hs_dir,
+                    arguments,
+                } => {
+                    let reason = event_argument(arguments, "REASON");
+                    // failed uploads are always rejected by the directory, so
+                    // other failures belong to fetches
+                    let fetched = match action.as_str() {
+                        "RECEIVED" => Some(true),
+                        "FAILED" if reason.as_deref() != Some("UPLOAD_REJECTED") => Some(false),
+                        _ => None,
+                    };
+                    if let Some(succeeded) = fetched {
+                        if let Some(fetches) = self.descriptor_fetches.get_mut(hs_address) {
+                            *fetches -= 1;
+                            if *fetches == 0 {
+                                self.descriptor_fetches.remove(hs_address);
+                            }
+                            events.push(TorEvent::OnionServiceDescriptorFetched {
+                                service_id: hs_address.clone(),
+                                hs_dir: hs_dir.clone(),
+                                succeeded,
+                                reason,
+                            });
+                        }
+                        continue;
+                    }
+
+                    let succeeded = match action.as_str() {
+                        "UPLOADED" => true,
+                        "FAILED" => false,
+                        _ => continue,
+                    };
+                    // ignore descriptors of onion services we are not hosting
+                    let onion_service = match self.onion_services.iter_mut().find(|onion_service| {
+                        onion_service.service_id == *hs_address
+                            && onion_service.is_active.load(atomic::Ordering::Relaxed)
+                    }) {
+                        Some(onion_service) => onion_service,
+                        None => continue,
+                    };
+
+                    if succeeded {
+                        onion_service.uploads_succeeded += 1;
+                    } else {
+                        onion_service.uploads_failed += 1;
+                    }
+                    events.push(TorEvent::OnionServiceDescriptorUploadStatus {
+                        service_id: hs_address.clone(),
+                        hs_dir: hs_dir.clone(),
+                        succeeded,
+                        reason,
+                        uploads_succeeded: onion_service.uploads_succeeded,
+                        uploads_failed: onion_service.uploads_failed,
+                    });
+
+                    if !onion_service.published
+                        && onion_service.uploads_succeeded >= self.publish_quorum
+                    {
+                        onion_service.published = true;
+                        onion_service.published_at = Some(Instant::now());
+                        if onion_service.refreshing {
+                            onion_service.refreshing = false;
+                            events.push(TorEvent::OnionServiceDescriptorRefreshed {
+                                service_id: hs_address.clone(),
+                            });
+                        } else {
+                            events.push(TorEvent::OnionServicePublished {
+                                service_id: hs_address.clone(),
+                            });
+                        }
+                    }
+                }
+                AsyncEvent::Circ {
+                    circuit_id,
+                    status,
+                    path,
+                    arguments,
+                } => {
+                    // ignore statuses added by newer tor versions
+                    if let Ok(status) = CircuitStatus::from_str(status) {
+                        events.push(TorEvent::CircuitStatusChanged {
+                            circuit_id: circuit_id.clone(),
+                            status,
+                            path: path.clone(),
+                            purpose: event_argument(arguments, "PURPOSE"),
+                            reason: event_argument(arguments, "REASON"),
+                        });
+                    }
+                }
+                AsyncEvent::Stream {
+                    stream_id,
+                    status,
+                    circuit_id,
+                    target,
+                    arguments,
+                } => {
+                    // ignore statuses added by newer tor versions
+                    if let Ok(status) = StreamStatus::from_str(status) {
+                        events.push(TorEvent::StreamStatusChanged {
+                            stream_id: stream_id.clone(),
+                            status,
+                            circuit_id: circuit_id.clone(),
+                            target: target.clone(),
+                            reason: event_argument(arguments, "REASON"),
                         });
                     }
                 }
@@ -526,10 +1206,21 @@ impl TorProvider for LegacyTorClient {
 
         if let Some(daemon) = &mut self.daemon {
             // bundled tor gives us log-lines
-            for log_line in daemon.wait_log_lines().iter_mut() {
-                events.push(TorEvent::LogReceived {
-                    line: std::mem::take(log_line),
-                });
+            for log_line in daemon.wait_log_lines().drain(..) {
+                match LogLine::from_str(&log_line) {
+                    Ok(LogLine {
+                        timestamp,
+                        severity,
+                        subsystem,
+                        message,
+                    }) => events.push(TorEvent::Log {
+                        severity,
+                        timestamp,
+                        subsystem,
+                        message,
+                    }),
+                    Err(_) => events.push(TorEvent::LogReceived { line: log_line }),
+                }
             }
         } else if !self.bootstrapped {
             // system tor needs to send a bootstrap complete event *once*
@@ -608,6 +1299,9 @@ impl TorProvider for LegacyTorClient {
             })) => socks::TargetAddr::Domain(format!("{}.onion", service_id), virt_port),
         };
 
+        let _span = tracing::debug_span!("socks_connect", target = %target).entered();
+        let timestamp = std::time::Instant::now();
+
         // readwrite stream
         let stream = match &circuit {
             None => Socks5Stream::connect(socks_listener, socks_target),
@@ -625,6 +1319,7 @@ impl TorProvider for LegacyTorClient {
             }
         }
         .map_err(Error::Socks5ConnectionFailed)?;
+        tracing::debug!(elapsed = ?timestamp.elapsed(), "connected");
 
         Ok(OnionStream {
             stream: stream.into_inner(),
@@ -639,6 +1334,8 @@ impl TorProvider for LegacyTorClient {
         private_key: &Ed25519PrivateKey,
         virt_port: u16,
         authorized_clients: Option<&[X25519PublicKey]>,
+        non_anonymous: bool,
+        pow_defenses: Option<&PowDefenses>,
     ) -> Result<OnionListener, tor_provider::Error> {
         if !self.bootstrapped {
             return Err(Error::LegacyTorNotBootstrapped().into());
@@ -651,39 +1348,228 @@ impl TorProvider for LegacyTorClient {
             .local_addr()
             .map_err(Error::TcpListenerLocalAddrFailed)?;
 
-        let mut flags = AddOnionFlags {
-            discard_pk: true,
-            ..Default::default()
-        };
-        if authorized_clients.is_some() {
-            flags.v3_auth = true;
+        let (service_id, is_active) = self.start_onion_service(
+            private_key,
+            vec![(virt_port, OnionServiceTarget::Tcp(socket_addr))],
+            authorized_clients,
+            non_anonymous,
+            pow_defenses,
+        )?;
+        let onion_addr = OnionAddr::V3(OnionAddrV3::new(service_id, virt_port));
+
+        Ok(OnionListener::new(listener, onion_addr, is_active, |is_active| {
+            is_active.store(false, atomic::Ordering::Relaxed);
+        }))
+    }
+
+    // stand up a single onion service with a Port argument for each virt-port,
+    // each forwarding to its own local listener
+    fn listener_with_ports(
+        &mut self,
+        private_key: &Ed25519PrivateKey,
+        virt_ports: &[u16],
+        authorized_clients: Option<&[X25519PublicKey]>,
+        non_anonymous: bool,
+        pow_defenses: Option<&PowDefenses>,
+    ) -> Result<Vec<OnionListener>, tor_provider::Error> {
+        if !self.bootstrapped {
+            return Err(Error::LegacyTorNotBootstrapped().into());
+        }
+        ensure_virt_ports_distinct(virt_ports)?;
+
+        // try to bind a local address for each virt-port, let OS pick our ports
+        let mut listeners: Vec<(TcpListener, u16, SocketAddr)> =
+            Vec::with_capacity(virt_ports.len());
+        for virt_port in virt_ports {
+            let socket_addr = SocketAddr::from(([127, 0, 0, 1], 0u16));
+            let listener = TcpListener::bind(socket_addr).map_err(Error::TcpListenerBindFailed)?;
+            let socket_addr = listener
+                .local_addr()
+                .map_err(Error::TcpListenerLocalAddrFailed)?;
+            listeners.push((listener, *virt_port, socket_addr));
         }
 
-        let onion_addr = OnionAddr::V3(OnionAddrV3::new(
-            V3OnionServiceId::from_private_key(private_key),
-            virt_port,
-        ));
+        let (service_id, is_active) = self.start_onion_service(
+            private_key,
+            listeners
+                .iter()
+                .map(|(_, virt_port, socket_addr)| {
+                    (*virt_port, OnionServiceTarget::Tcp(*socket_addr))
+                })
+                .collect(),
+            authorized_clients,
+            non_anonymous,
+            pow_defenses,
+        )?;
+
+        // every listener shares the onion service's flag, so dropping any of
+        // them removes the onion service
+        Ok(listeners
+            .into_iter()
+            .map(|(listener, virt_port, _)| {
+                let onion_addr = OnionAddr::V3(OnionAddrV3::new(service_id.clone(), virt_port));
+                OnionListener::new(listener, onion_addr, Arc::clone(&is_active), |is_active| {
+                    is_active.store(false, atomic::Ordering::Relaxed);
+                })
+            })
+            .collect())
+    }
 
-        // start onion service
-        let (_, service_id) = self
-            .controller
-            .add_onion(
-                Some(private_key),
-                &flags,
-                None,
-                virt_port,
-                Some(socket_addr),
-                authorized_clients,
-            )
-            .map_err(Error::AddOnionFailed)?;
+    // stand up several onion services with their ADD_ONION commands pipelined,
+    // so starting them costs a single round-trip to the daemon
+    fn listeners(
+        &mut self,
+        configs: &[ListenerConfig],
+    ) -> Vec<Result<OnionListener, tor_provider::Error>> {
+        if !self.bootstrapped {
+            return configs
+                .iter()
+                .map(|_| Err(Error::LegacyTorNotBootstrapped().into()))
+                .collect();
+        }
 
-        let is_active = Arc::new(atomic::AtomicBool::new(true));
-        self.onion_services
-            .push((service_id, Arc::clone(&is_active)));
+        // only queried if a single onion service is requested
+        let mut non_anonymous_mode: Option<bool> = None;
+        let pending: Vec<Result<(TcpListener, PendingOnionService), Error>> = configs
+            .iter()
+            .map(|config| self.submit_listener(config, &mut non_anonymous_mode))
+            .collect();
+
+        // every submitted command's reply must be read
+        pending
+            .into_iter()
+            .map(|pending| -> Result<OnionListener, tor_provider::Error> {
+                let (listener, pending) = pending?;
+                let virt_port = pending.ports[0].0;
+                let (service_id, is_active) = self.wait_onion_service(pending)?;
+                let onion_addr = OnionAddr::V3(OnionAddrV3::new(service_id, virt_port));
+                Ok(OnionListener::new(listener, onion_addr, is_active, |is_active| {
+                    is_active.store(false, atomic::Ordering::Relaxed);
+                }))
+            })
+            .collect()
+    }
 
-        Ok(OnionListener::new(listener, onion_addr, is_active, |is_active| {
-            is_active.store(false, atomic::Ordering::Relaxed);
-        }))
+    // stand up an onion service forwarding to the caller's socket
+    fn listener_with_target(
+        &mut self,
+        private_key: &Ed25519PrivateKey,
+        virt_port: u16,
+        authorized_clients: Option<&[X25519PublicKey]>,
+        non_anonymous: bool,
+        pow_defenses: Option<&PowDefenses>,
+        target: OnionServiceTarget,
+    ) -> Result<OnionServiceHandle, tor_provider::Error> {
+        if !self.bootstrapped {
+            return Err(Error::LegacyTorNotBootstrapped().into());
+        }
+
+        // the path is passed unquoted in the ADD_ONION command's Port argument
+        #[cfg(unix)]
+        if let OnionServiceTarget::Unix(path) = &target {
+            let valid = path.to_str().is_some_and(|path| {
+                !path.is_empty() && !path.contains(|c: char| c.is_whitespace() || c == '"')
+            });
+            if !valid {
+                return Err(Error::UnixSocketPathInvalid(path.clone()).into());
+            }
+        }
+
+        let (service_id, is_active) = self.start_onion_service(
+            private_key,
+            vec![(virt_port, target)],
+            authorized_clients,
+            non_anonymous,
+            pow_defenses,
+        )?;
+        let onion_addr = OnionAddr::V3(OnionAddrV3::new(service_id, virt_port));
+
+        Ok(OnionServiceHandle::new(
+            onion_addr,
+            is_active,
+            |is_active| {
+                is_active.store(false, atomic::Ordering::Relaxed);
+            },
+        ))
+    }
+
+    // the tor daemon cannot change the authorised clients of a running onion
+    // service, so it is removed and re-added with the same key and target;
+    // the OnionListener's socket is unaffected
+    fn set_authorised_clients(
+        &mut self,
+        service_id: &V3OnionServiceId,
+        authorised_clients: &[X25519PublicKey],
+    ) -> Result<(), tor_provider::Error> {
+        let index = self.find_onion_service(service_id)?;
+        let onion_service = &mut self.onion_services[index];
+        onion_service.authorised_clients = authorised_clients.to_vec();
+        // the updated descriptor is announced as a new publication
+        onion_service.refreshing = false;
+
+        self.readd_onion_service(
+            index,
+            Error::UpdateAuthorisedClientsDelOnionFailed,
+            Error::UpdateAuthorisedClientsAddOnionFailed,
+        )?;
+
+        Ok(())
+    }
+
+    // as with set_authorised_clients(), the onion service is removed and
+    // re-added so that tor uploads a freshly built descriptor; services which
+    // have never been published report their first publication as usual
+    fn refresh_descriptor(
+        &mut self,
+        service_id: &V3OnionServiceId,
+    ) -> Result<(), tor_provider::Error> {
+        let index = self.find_onion_service(service_id)?;
+        let onion_service = &mut self.onion_services[index];
+        onion_service.refreshing = onion_service.published_at.is_some();
+
+        self.readd_onion_service(
+            index,
+            Error::RefreshDescriptorDelOnionFailed,
+            Error::RefreshDescriptorAddOnionFailed,
+        )?;
+
+        Ok(())
+    }
+
+    fn fetch_descriptor(
+        &mut self,
+        service_id: &V3OnionServiceId,
+    ) -> Result<(), tor_provider::Error> {
+        if !self.bootstrapped {
+            return Err(Error::LegacyTorNotBootstrapped().into());
+        }
+
+        self.controller
+            .hsfetch(service_id)
+            .map_err(Error::HsFetchFailed)?;
+        *self
+            .descriptor_fetches
+            .entry(service_id.clone())
+            .or_default() += 1;
+        Ok(())
+    }
+
+    fn set_descriptor_republish_interval(
+        &mut self,
+        interval: Option<Duration>,
+    ) -> Result<(), tor_provider::Error> {
+        if interval.is_some_and(|interval| interval.is_zero()) {
+            return Err(Error::DescriptorRepublishIntervalZero().into());
+        }
+        self.republish_interval = interval;
+        Ok(())
+    }
+
+    fn set_security_level(
+        &mut self,
+        security_level: SecurityLevel,
+    ) -> Result<(), tor_provider::Error> {
+        Ok(self.set_security_config(&security_level.into())?)
     }
 
     fn generate_token(&mut self) -> CircuitToken {
@@ -698,3 +1584,56 @@ impl TorProvider for LegacyTorClient {
         self.circuit_tokens.remove(&circuit_token);
     }
 }
+
+#[test]
+fn test_socks_policy_violation() {
+    let arguments = |arguments: &[(&str, &str)]| -> Vec<(String, String)> {
+        arguments
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    };
+
+    assert_eq!(
+        socks_policy_violation(
+            "DANGEROUS_SOCKS",
+            &arguments(&[("PROTOCOL", "SOCKS5"), ("ADDRESS", "192.0.2.1:443")])
+        ),
+        Some(SocksPolicyViolation::UnsafeAddress {
+            protocol: "SOCKS5".to_string(),
+            address: "192.0.2.1:443".to_string(),
+        })
+    );
+    assert_eq!(
+        socks_policy_violation(
+            "DANGEROUS_PORT",
+            &arguments(&[("PORT", "23"), ("RESULT", "REJECT")])
+        ),
+        Some(SocksPolicyViolation::PlaintextPort {
+            port: 23,
+            rejected: true,
+        })
+    );
+    assert_eq!(
+        socks_policy_violation(
+            "DANGEROUS_PORT",
+            &arguments(&[("PORT", "110"), ("RESULT", "WARN")])
+        ),
+        Some(SocksPolicyViolation::PlaintextPort {
+            port: 110,
+            rejected: false,
+        })
+    );
+    assert_eq!(
+        socks_policy_violation("DANGEROUS_PORT", &arguments(&[("PORT", "invalid")])),
+        None
+    );
+    assert_eq!(
+        socks_policy_violation("SOCKS_UNKNOWN_PROTOCOL", &arguments(&[])),
+        Some(SocksPolicyViolation::UnknownProtocol)
+    );
+    assert_eq!(
+        socks_policy_violation("CIRCUIT_ESTABLISHED", &arguments(&[])),
+        None
+    );
+}
@@ -38,6 +38,9 @@ pub enum Error {
 
     #[error("control port stream write failure")]
     WriteFailed(#[source] std::io::Error),
+
+    #[error("failed to clone control port socket")]
+    CloneFailed(#[source] std::io::Error),
 }
 
 pub(crate) struct LegacyControlStream {
@@ -53,6 +56,13 @@ pub(crate) struct LegacyControlStream {
     end_reply_line: Regex,
 }
 
+// The write half of a control stream; commands are written through this
+// while the LegacyControlStream itself is read on another thread
+pub(crate) struct LegacyControlStreamWriter {
+    stream: TcpStream,
+    closed_by_remote: bool,
+}
+
 type StatusCode = u32;
 pub(crate) struct Reply {
     pub status_code: StatusCode,
@@ -95,8 +105,15 @@ impl LegacyControlStream {
         })
     }
 
-    #[cfg(test)]
-    pub(crate) fn closed_by_remote(&mut self) -> bool {
+    pub fn try_clone_writer(&self) -> Result<LegacyControlStreamWriter, Error> {
+        let stream = self.stream.try_clone().map_err(Error::CloneFailed)?;
+        Ok(LegacyControlStreamWriter {
+            stream,
+            closed_by_remote: false,
+        })
+    }
+
+    pub fn closed_by_remote(&self) -> bool {
         self.closed_by_remote
     }
 
@@ -252,6 +269,13 @@ impl LegacyControlStream {
             reply_lines,
         }))
     }
+}
+
+impl LegacyControlStreamWriter {
+    #[cfg(test)]
+    pub fn closed_by_remote(&self) -> bool {
+        self.closed_by_remote
+    }
 
     pub fn write(&mut self, cmd: &str) -> Result<(), Error> {
         if let Err(err) = write!(self.stream, "{}\r\n", cmd) {
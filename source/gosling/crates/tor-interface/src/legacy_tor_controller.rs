@@ -1,4 +1,5 @@
 // standard
+use std::collections::{BTreeMap, VecDeque};
 use std::default::Default;
 use std::net::SocketAddr;
 use std::option::Option;
@@ -6,6 +7,10 @@ use std::option::Option;
 use std::path::Path;
 use std::str::FromStr;
 use std::string::ToString;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, Sender, TryRecvError};
+use std::sync::Arc;
+use std::thread::JoinHandle;
 #[cfg(test)]
 use std::time::{Duration, Instant};
 
@@ -20,26 +25,44 @@ use crate::legacy_tor_control_stream::*;
 use crate::legacy_tor_process::*;
 use crate::legacy_tor_version::*;
 use crate::tor_crypto::*;
+use crate::tor_provider::{OnionServiceTarget, PowDefenses};
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
     #[error("response regex creation failed")]
     ParsingRegexCreationFailed(#[source] regex::Error),
 
-    #[error("control stream read reply failed")]
-    ReadReplyFailed(#[source] crate::legacy_tor_control_stream::Error),
+    #[error("control stream read reply to '{0}' failed")]
+    ReadReplyFailed(String, #[source] crate::legacy_tor_control_stream::Error),
+
+    #[error("failed to create control stream writer")]
+    ControlStreamWriterCreationFailed(#[source] crate::legacy_tor_control_stream::Error),
+
+    #[error("failed to spawn tor events worker thread")]
+    EventsWorkerSpawnFailed(#[source] std::io::Error),
+
+    #[error("tor events worker is no longer running")]
+    EventsWorkerStopped(),
 
     #[error("unexpected synchronous reply recieved")]
     UnexpectedSynchonousReplyReceived(),
 
-    #[error("control stream write command failed")]
-    WriteCommandFailed(#[source] crate::legacy_tor_control_stream::Error),
+    #[error("no reply pending for command ticket {0}")]
+    UnknownCommandTicket(u64),
+
+    #[error("control stream write command '{0}' failed")]
+    WriteCommandFailed(String, #[source] crate::legacy_tor_control_stream::Error),
 
     #[error("invalid command arguments: {0}")]
     InvalidCommandArguments(String),
 
-    #[error("command failed: {0} {}", .1.join("\n"))]
-    CommandFailed(u32, Vec<String>),
+    #[error("command '{command}' failed: {status_code} {}", .reply_lines.join("\n"))]
+    CommandFailed {
+        // the command with any secret arguments redacted
+        command: String,
+        status_code: u32,
+        reply_lines: Vec<String>,
+    },
 
     #[error("failed to parse command reply: {0}")]
     CommandReplyParseFailed(String),
@@ -75,18 +98,203 @@ pub(crate) enum AsyncEvent {
     HsDesc {
         action: String,
         hs_address: V3OnionServiceId,
+        // the hidden-service directory involved, omitted by some actions
+        hs_dir: Option<String>,
+        arguments: Vec<(String, String)>,
+    },
+    Circ {
+        circuit_id: String,
+        status: String,
+        path: Vec<String>,
+        arguments: Vec<(String, String)>,
     },
+    Stream {
+        stream_id: String,
+        status: String,
+        circuit_id: String,
+        target: String,
+        arguments: Vec<(String, String)>,
+    },
+}
+
+// Identifies a command written with one of the *_submit methods; the
+// command's reply is retrieved by passing the ticket to the matching
+// *_wait method
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct CommandTicket(u64);
+
+// A command which has been written but whose reply has not yet been read
+struct InFlightCommand {
+    ticket: CommandTicket,
+    // the command with any secret arguments redacted
+    command: String,
+    timestamp: std::time::Instant,
+}
+
+// Reads replies from the control stream on its own thread so tor's event
+// buffer is drained as events arrive rather than only when the controller
+// is polled; async replies and command replies are delivered on separate
+// queues
+struct TorEventsWorker {
+    // signals the worker thread to stop
+    stop: Arc<AtomicBool>,
+    // set by the worker thread if tor closed the control stream
+    #[cfg(test)]
+    closed_by_remote: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+    // replies to commands in the order they were read, followed by the
+    // read error which stopped the worker (if any)
+    sync_replies: Receiver<Result<Reply, crate::legacy_tor_control_stream::Error>>,
+    // async (650) replies in the order they were read
+    async_replies: Receiver<Reply>,
+}
+
+impl TorEventsWorker {
+    fn new(control_stream: LegacyControlStream) -> Result<TorEventsWorker, Error> {
+        let stop: Arc<AtomicBool> = Default::default();
+        let closed_by_remote: Arc<AtomicBool> = Default::default();
+        let (sync_sender, sync_replies) = std::sync::mpsc::channel();
+        let (async_sender, async_replies) = std::sync::mpsc::channel();
+
+        let thread = {
+            let stop = stop.clone();
+            let closed_by_remote = closed_by_remote.clone();
+            std::thread::Builder::new()
+                .name("tor_events_worker".to_string())
+                .spawn(move || {
+                    TorEventsWorker::read_replies_task(
+                        control_stream,
+                        &stop,
+                        &closed_by_remote,
+                        sync_sender,
+                        async_sender,
+                    );
+                })
+                .map_err(Error::EventsWorkerSpawnFailed)?
+        };
+
+        Ok(TorEventsWorker {
+            stop,
+            #[cfg(test)]
+            closed_by_remote,
+            thread: Some(thread),
+            sync_replies,
+            async_replies,
+        })
+    }
+
+    fn read_replies_task(
+        mut control_stream: LegacyControlStream,
+        stop: &AtomicBool,
+        closed_by_remote: &AtomicBool,
+        sync_replies: Sender<Result<Reply, crate::legacy_tor_control_stream::Error>>,
+        async_replies: Sender<Reply>,
+    ) {
+        // the control stream's read timeout bounds how long it takes us to
+        // notice a stop request
+        while !stop.load(Ordering::Relaxed) {
+            match control_stream.read_reply() {
+                Ok(Some(reply)) => {
+                    let sent = if reply.status_code == 650u32 {
+                        async_replies.send(reply).is_ok()
+                    } else {
+                        sync_replies.send(Ok(reply)).is_ok()
+                    };
+                    // the controller has gone away
+                    if !sent {
+                        return;
+                    }
+                }
+                Ok(None) => (),
+                Err(err) => {
+                    closed_by_remote.store(control_stream.closed_by_remote(), Ordering::Relaxed);
+                    let _ = sync_replies.send(Err(err));
+                    return;
+                }
+            }
+        }
+    }
+}
+
+impl Drop for TorEventsWorker {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
 }
 
 pub(crate) struct LegacyTorController {
-    // underlying control stream
-    control_stream: LegacyControlStream,
-    // list of async replies to be handled
-    async_replies: Vec<Reply>,
+    // write half of the underlying control stream
+    control_stream: LegacyControlStreamWriter,
+    // reads the underlying control stream
+    events_worker: TorEventsWorker,
+    // ticket to hand out to the next submitted command
+    next_ticket: u64,
+    // submitted commands in the order they were written; tor replies
+    // to commands in the order it receives them
+    in_flight_commands: VecDeque<InFlightCommand>,
+    // replies read while waiting on a different command
+    sync_replies: BTreeMap<CommandTicket, CommandReply>,
     // regex for parsing events
     status_event_pattern: Regex,
     status_event_argument_pattern: Regex,
     hs_desc_pattern: Regex,
+    circ_pattern: Regex,
+    stream_pattern: Regex,
+    event_keyword_argument_pattern: Regex,
+}
+
+// A reply to a command, along with the command it answers
+struct CommandReply {
+    // the command with any secret arguments redacted
+    command: String,
+    status_code: u32,
+    reply_lines: Vec<String>,
+}
+
+impl CommandReply {
+    // the error for a reply whose status code reports failure
+    fn into_error(self) -> Error {
+        Error::CommandFailed {
+            command: self.command,
+            status_code: self.status_code,
+            reply_lines: self.reply_lines,
+        }
+    }
+}
+
+// the text of a command suitable for errors and logs; passwords, private
+// keys and bridge lines are replaced with a placeholder
+fn redacted_command(text: &str) -> String {
+    let mut words = text.split_whitespace();
+    let keyword = words.next().unwrap_or_default();
+    if keyword == "AUTHENTICATE" {
+        return format!("{keyword} <redacted>");
+    }
+
+    let mut redacted = vec![keyword.to_string()];
+    // whether the words up to the end of a quoted bridge line are skipped
+    let mut in_bridge_line = false;
+    for word in words {
+        if in_bridge_line {
+            in_bridge_line = !word.ends_with('"') || word.ends_with("\\\"");
+            continue;
+        }
+        if let Some(value) = word.strip_prefix("Bridge=") {
+            redacted.push("Bridge=<redacted>".to_string());
+            in_bridge_line = value.len() < 2 || !value.ends_with('"');
+            continue;
+        }
+        match word.split_once(':') {
+            Some((key_type @ ("ED25519-V3" | "x25519"), _)) => {
+                redacted.push(format!("{key_type}:<redacted>"))
+            }
+            _ => redacted.push(word.to_string()),
+        }
+    }
+    redacted.join(" ")
 }
 
 fn quoted_string(string: &str) -> String {
@@ -97,6 +305,11 @@ fn quoted_string(string: &str) -> String {
 
 impl LegacyTorController {
     pub fn new(control_stream: LegacyControlStream) -> Result<LegacyTorController, Error> {
+        let control_stream_writer = control_stream
+            .try_clone_writer()
+            .map_err(Error::ControlStreamWriterCreationFailed)?;
+        let events_worker = TorEventsWorker::new(control_stream)?;
+
         let status_event_pattern =
             Regex::new(r#"^STATUS_CLIENT (?P<severity>NOTICE|WARN|ERR) (?P<action>[A-Za-z]+)"#)
                 .map_err(Error::ParsingRegexCreationFailed)?;
@@ -104,41 +317,83 @@ impl LegacyTorController {
             Regex::new(r#"(?P<key>[A-Z]+)=(?P<value>[A-Za-z0-9_]+|"[^"]+")"#)
                 .map_err(Error::ParsingRegexCreationFailed)?;
         let hs_desc_pattern = Regex::new(
-            r#"HS_DESC (?P<action>REQUESTED|UPLOAD|RECEIVED|UPLOADED|IGNORE|FAILED|CREATED) (?P<hsaddress>[a-z2-7]{56})"#
+            r#"HS_DESC (?P<action>REQUESTED|UPLOAD|RECEIVED|UPLOADED|IGNORE|FAILED|CREATED) (?P<hsaddress>[a-z2-7]{56})(?: [A-Z_]+ (?P<hsdir>\$[^ ]+|UNKNOWN))?"#
         ).map_err(Error::ParsingRegexCreationFailed)?;
+        let circ_pattern =
+            Regex::new(r#"^CIRC (?P<circuitid>[0-9]+) (?P<status>[A-Z_]+)(?: (?P<path>\$[^ ]*))?"#)
+                .map_err(Error::ParsingRegexCreationFailed)?;
+        let stream_pattern = Regex::new(
+            r#"^STREAM (?P<streamid>[0-9]+) (?P<status>[A-Z_]+) (?P<circuitid>[0-9]+) (?P<target>[^ ]+)"#,
+        )
+        .map_err(Error::ParsingRegexCreationFailed)?;
+        let event_keyword_argument_pattern =
+            Regex::new(r#"(?:^| )(?P<key>[A-Z_]+)=(?P<value>"[^"]*"|[^ ]+)"#)
+                .map_err(Error::ParsingRegexCreationFailed)?;
 
         Ok(LegacyTorController {
-            control_stream,
-            async_replies: Default::default(),
+            control_stream: control_stream_writer,
+            events_worker,
+            next_ticket: 0u64,
+            in_flight_commands: Default::default(),
+            sync_replies: Default::default(),
             // regex
             status_event_pattern,
             status_event_argument_pattern,
             hs_desc_pattern,
+            circ_pattern,
+            stream_pattern,
+            event_keyword_argument_pattern,
         })
     }
 
+    #[cfg(test)]
+    fn closed_by_remote(&self) -> bool {
+        self.control_stream.closed_by_remote()
+            || self.events_worker.closed_by_remote.load(Ordering::Relaxed)
+    }
+
     // return curently available events, does not block waiting
     // for an event
     fn wait_async_replies(&mut self) -> Result<Vec<Reply>, Error> {
         let mut replies: Vec<Reply> = Default::default();
-        // take any previously received async replies
-        std::mem::swap(&mut self.async_replies, &mut replies);
 
-        // and keep consuming until none are available
+        // keep consuming until none are available
         loop {
-            if let Some(reply) = self
-                .control_stream
-                .read_reply()
-                .map_err(Error::ReadReplyFailed)?
-            {
-                replies.push(reply);
-            } else {
+            match self.events_worker.async_replies.try_recv() {
+                Ok(reply) => replies.push(reply),
                 // no more replies immediately available so return
-                return Ok(replies);
+                Err(TryRecvError::Empty) => return Ok(replies),
+                Err(TryRecvError::Disconnected) => return Err(Error::EventsWorkerStopped()),
             }
         }
     }
 
+    // parse the KEY=value (or KEY="quoted value") arguments trailing a CIRC
+    // or STREAM event
+    fn event_keyword_arguments(&self, reply_text: &str) -> Vec<(String, String)> {
+        let mut arguments: Vec<(String, String)> = Default::default();
+        for caps in self
+            .event_keyword_argument_pattern
+            .captures_iter(reply_text)
+        {
+            let key = match caps.name("key") {
+                Some(key) => key.as_str(),
+                None => unreachable!(),
+            };
+            let value = match caps.name("value") {
+                Some(value) => value.as_str(),
+                None => unreachable!(),
+            };
+            let value = if value.len() >= 2 && value.starts_with('\"') && value.ends_with('\"') {
+                &value[1..value.len() - 1]
+            } else {
+                value
+            };
+            arguments.push((key.to_string(), value.to_string()));
+        }
+        arguments
+    }
+
     fn reply_to_event(&self, reply: &mut Reply) -> Result<AsyncEvent, Error> {
         if reply.status_code != 650u32 {
             return Err(Error::UnexpectedSynchonousReplyReceived());
@@ -196,14 +451,72 @@ impl LegacyTorController {
                 None => unreachable!(),
             };
 
+            let hs_dir = caps.name("hsdir").map(|hs_dir| hs_dir.as_str().to_string());
+
             if let Ok(hs_address) = V3OnionServiceId::from_string(hs_address) {
                 return Ok(AsyncEvent::HsDesc {
                     action: action.to_string(),
                     hs_address,
+                    hs_dir,
+                    arguments: self.event_keyword_arguments(&reply_text),
                 });
             }
         }
 
+        if let Some(caps) = self.circ_pattern.captures(&reply_text) {
+            let circuit_id = match caps.name("circuitid") {
+                Some(circuit_id) => circuit_id.as_str(),
+                None => unreachable!(),
+            };
+            let status = match caps.name("status") {
+                Some(status) => status.as_str(),
+                None => unreachable!(),
+            };
+            // path is omitted for circuits which have not yet been extended
+            let path: Vec<String> = match caps.name("path") {
+                Some(path) => path
+                    .as_str()
+                    .split(',')
+                    .map(|hop| hop.to_string())
+                    .collect(),
+                None => Default::default(),
+            };
+
+            return Ok(AsyncEvent::Circ {
+                circuit_id: circuit_id.to_string(),
+                status: status.to_string(),
+                path,
+                arguments: self.event_keyword_arguments(&reply_text),
+            });
+        }
+
+        if let Some(caps) = self.stream_pattern.captures(&reply_text) {
+            let stream_id = match caps.name("streamid") {
+                Some(stream_id) => stream_id.as_str(),
+                None => unreachable!(),
+            };
+            let status = match caps.name("status") {
+                Some(status) => status.as_str(),
+                None => unreachable!(),
+            };
+            let circuit_id = match caps.name("circuitid") {
+                Some(circuit_id) => circuit_id.as_str(),
+                None => unreachable!(),
+            };
+            let target = match caps.name("target") {
+                Some(target) => target.as_str(),
+                None => unreachable!(),
+            };
+
+            return Ok(AsyncEvent::Stream {
+                stream_id: stream_id.to_string(),
+                status: status.to_string(),
+                circuit_id: circuit_id.to_string(),
+                target: target.to_string(),
+                arguments: self.event_keyword_arguments(&reply_text),
+            });
+        }
+
         // no luck parsing reply, just return full text
         let mut reply_lines: Vec<String> = Default::default();
         std::mem::swap(&mut reply_lines, &mut reply.reply_lines);
@@ -222,27 +535,78 @@ impl LegacyTorController {
         Ok(async_events)
     }
 
-    // wait for a sync reply, save off async replies for later
-    fn wait_sync_reply(&mut self) -> Result<Reply, Error> {
+    // write a command without waiting for its reply
+    fn submit_command(&mut self, text: &str) -> Result<CommandTicket, Error> {
+        let command = redacted_command(text);
+
+        if let Err(err) = self.control_stream.write(text) {
+            return Err(Error::WriteCommandFailed(command, err));
+        }
+
+        let ticket = CommandTicket(self.next_ticket);
+        self.next_ticket += 1;
+        self.in_flight_commands.push_back(InFlightCommand {
+            ticket,
+            command,
+            timestamp: std::time::Instant::now(),
+        });
+        Ok(ticket)
+    }
+
+    // wait for the reply to a submitted command; replies to other in-flight
+    // commands are saved off for their own wait_reply call
+    fn wait_reply(&mut self, ticket: CommandTicket) -> Result<CommandReply, Error> {
+        if let Some(reply) = self.sync_replies.remove(&ticket) {
+            return Ok(reply);
+        }
+        if !self
+            .in_flight_commands
+            .iter()
+            .any(|in_flight| in_flight.ticket == ticket)
+        {
+            return Err(Error::UnknownCommandTicket(ticket.0));
+        }
+
         loop {
-            if let Some(reply) = self
-                .control_stream
-                .read_reply()
-                .map_err(Error::ReadReplyFailed)?
-            {
-                match reply.status_code {
-                    650u32 => self.async_replies.push(reply),
-                    _ => return Ok(reply),
+            let reply = match self.events_worker.sync_replies.recv() {
+                Ok(Ok(reply)) => reply,
+                Ok(Err(err)) => {
+                    // the reply which could not be read answers the oldest in-flight command
+                    let command = self
+                        .in_flight_commands
+                        .front()
+                        .map(|in_flight| in_flight.command.clone())
+                        .unwrap_or_default();
+                    return Err(Error::ReadReplyFailed(command, err));
                 }
+                Err(_) => return Err(Error::EventsWorkerStopped()),
+            };
+            let in_flight = match self.in_flight_commands.pop_front() {
+                Some(in_flight) => in_flight,
+                None => return Err(Error::UnexpectedSynchonousReplyReceived()),
+            };
+            tracing::debug!(
+                command = in_flight.command.split(' ').next().unwrap_or_default(),
+                elapsed = ?in_flight.timestamp.elapsed(),
+                status_code = reply.status_code,
+                in_flight = self.in_flight_commands.len(),
+                "reply received"
+            );
+            let reply = CommandReply {
+                command: in_flight.command,
+                status_code: reply.status_code,
+                reply_lines: reply.reply_lines,
+            };
+            if in_flight.ticket == ticket {
+                return Ok(reply);
             }
+            self.sync_replies.insert(in_flight.ticket, reply);
         }
     }
 
-    fn write_command(&mut self, text: &str) -> Result<Reply, Error> {
-        self.control_stream
-            .write(text)
-            .map_err(Error::WriteCommandFailed)?;
-        self.wait_sync_reply()
+    fn write_command(&mut self, text: &str) -> Result<CommandReply, Error> {
+        let ticket = self.submit_command(text)?;
+        self.wait_reply(ticket)
     }
 
     //
@@ -251,11 +615,15 @@ impl LegacyTorController {
     // The section where we can find the specification in control-spec.txt
     // for the underlying command is listed in parentheses
     //
-    // Each of these command wrapper methods block until completion
+    // The *_cmd wrapper methods block until completion, the *_submit wrapper
+    // methods return a CommandTicket once the command has been written
     //
 
     // SETCONF (3.1)
-    fn setconf_cmd(&mut self, key_values: &[(&str, String)]) -> Result<Reply, Error> {
+    pub fn setconf_submit(
+        &mut self,
+        key_values: &[(&str, String)],
+    ) -> Result<CommandTicket, Error> {
         if key_values.is_empty() {
             return Err(Error::InvalidCommandArguments(
                 "SETCONF key-value pairs list must not be empty".to_string(),
@@ -268,12 +636,11 @@ impl LegacyTorController {
         }
         let command = command_buffer.join(" ");
 
-        self.write_command(&command)
+        self.submit_command(&command)
     }
 
     // GETCONF (3.3)
-    #[cfg(test)]
-    fn getconf_cmd(&mut self, keywords: &[&str]) -> Result<Reply, Error> {
+    fn getconf_cmd(&mut self, keywords: &[&str]) -> Result<CommandReply, Error> {
         if keywords.is_empty() {
             return Err(Error::InvalidCommandArguments(
                 "GETCONF keywords list must not be empty".to_string(),
@@ -285,7 +652,7 @@ impl LegacyTorController {
     }
 
     // SETEVENTS (3.4)
-    fn setevents_cmd(&mut self, event_codes: &[&str]) -> Result<Reply, Error> {
+    pub fn setevents_submit(&mut self, event_codes: &[&str]) -> Result<CommandTicket, Error> {
         if event_codes.is_empty() {
             return Err(Error::InvalidCommandArguments(
                 "SETEVENTS event codes list mut not be empty".to_string(),
@@ -293,18 +660,24 @@ impl LegacyTorController {
         }
         let command = format!("SETEVENTS {}", event_codes.join(" "));
 
-        self.write_command(&command)
+        self.submit_command(&command)
     }
 
     // AUTHENTICATE (3.5)
-    fn authenticate_cmd(&mut self, password: &str) -> Result<Reply, Error> {
+    pub fn authenticate_submit(&mut self, password: &str) -> Result<CommandTicket, Error> {
         let command = format!("AUTHENTICATE \"{}\"", quoted_string(password));
 
-        self.write_command(&command)
+        self.submit_command(&command)
+    }
+
+    #[cfg(test)]
+    fn authenticate_cmd(&mut self, password: &str) -> Result<CommandReply, Error> {
+        let ticket = self.authenticate_submit(password)?;
+        self.wait_reply(ticket)
     }
 
     // GETINFO (3.9)
-    fn getinfo_cmd(&mut self, keywords: &[&str]) -> Result<Reply, Error> {
+    pub fn getinfo_submit(&mut self, keywords: &[&str]) -> Result<CommandTicket, Error> {
         if keywords.is_empty() {
             return Err(Error::InvalidCommandArguments(
                 "GETINFO keywords list must not be empty".to_string(),
@@ -312,19 +685,25 @@ impl LegacyTorController {
         }
         let command = format!("GETINFO {}", keywords.join(" "));
 
-        self.write_command(&command)
+        self.submit_command(&command)
     }
 
     // ADD_ONION (3.27)
-    fn add_onion_cmd(
+    pub fn add_onion_submit(
         &mut self,
         key: Option<&Ed25519PrivateKey>,
         flags: &AddOnionFlags,
         max_streams: Option<u16>,
-        virt_port: u16,
-        target: Option<SocketAddr>,
+        pow_defenses: Option<&PowDefenses>,
+        ports: &[(u16, Option<&OnionServiceTarget>)],
         client_auth: Option<&[X25519PublicKey]>,
-    ) -> Result<Reply, Error> {
+    ) -> Result<CommandTicket, Error> {
+        if ports.is_empty() {
+            return Err(Error::InvalidCommandArguments(
+                "ADD_ONION ports list must not be empty".to_string(),
+            ));
+        }
+
         let mut command_buffer = vec!["ADD_ONION".to_string()];
 
         // set our key or request a new one
@@ -351,6 +730,9 @@ impl LegacyTorController {
         if flags.max_streams_close_circuit {
             flag_buffer.push("MaxStreamsCloseCircuit");
         }
+        if pow_defenses.is_some() {
+            flag_buffer.push("PoWDefenses");
+        }
 
         if !flag_buffer.is_empty() {
             command_buffer.push(format!("Flags={}", flag_buffer.join(",")));
@@ -361,11 +743,23 @@ impl LegacyTorController {
             command_buffer.push(format!("MaxStreams={}", max_streams));
         }
 
-        // set our onion service target
-        if let Some(target) = target {
-            command_buffer.push(format!("Port={},{}", virt_port, target));
-        } else {
-            command_buffer.push(format!("Port={}", virt_port));
+        // set proof-of-work queue parameters; tor's defaults are used if omitted
+        if let Some(pow_defenses) = pow_defenses {
+            if let Some(queue_rate) = pow_defenses.queue_rate {
+                command_buffer.push(format!("PoWQueueRate={}", queue_rate));
+            }
+            if let Some(queue_burst) = pow_defenses.queue_burst {
+                command_buffer.push(format!("PoWQueueBurst={}", queue_burst));
+            }
+        }
+
+        // set our onion service's virt-ports and their targets
+        for (virt_port, target) in ports {
+            if let Some(target) = target {
+                command_buffer.push(format!("Port={},{}", virt_port, target));
+            } else {
+                command_buffer.push(format!("Port={}", virt_port));
+            }
         }
         // setup client auth
         if let Some(client_auth) = client_auth {
@@ -377,14 +771,17 @@ impl LegacyTorController {
         // finally send the command
         let command = command_buffer.join(" ");
 
-        self.write_command(&command)
+        self.submit_command(&command)
     }
 
     // DEL_ONION (3.38)
-    fn del_onion_cmd(&mut self, service_id: &V3OnionServiceId) -> Result<Reply, Error> {
+    pub fn del_onion_submit(
+        &mut self,
+        service_id: &V3OnionServiceId,
+    ) -> Result<CommandTicket, Error> {
         let command = format!("DEL_ONION {}", service_id);
 
-        self.write_command(&command)
+        self.submit_command(&command)
     }
 
     // ONION_CLIENT_AUTH_ADD (3.30)
@@ -394,7 +791,7 @@ impl LegacyTorController {
         private_key: &X25519PrivateKey,
         client_name: Option<String>,
         flags: &OnionClientAuthAddFlags,
-    ) -> Result<Reply, Error> {
+    ) -> Result<CommandReply, Error> {
         let mut command_buffer = vec!["ONION_CLIENT_AUTH_ADD".to_string()];
 
         // set the onion service id
@@ -421,26 +818,28 @@ impl LegacyTorController {
     fn onion_client_auth_remove_cmd(
         &mut self,
         service_id: &V3OnionServiceId,
-    ) -> Result<Reply, Error> {
+    ) -> Result<CommandReply, Error> {
         let command = format!("ONION_CLIENT_AUTH_REMOVE {}", service_id);
 
         self.write_command(&command)
     }
 
+    // HSFETCH (3.26)
+    fn hsfetch_cmd(&mut self, service_id: &V3OnionServiceId) -> Result<CommandReply, Error> {
+        let command = format!("HSFETCH {}", service_id);
+
+        self.write_command(&command)
+    }
+
     //
     // Public high-level typesafe command method wrappers
     //
 
     pub fn setconf(&mut self, key_values: &[(&str, String)]) -> Result<(), Error> {
-        let reply = self.setconf_cmd(key_values)?;
-
-        match reply.status_code {
-            250u32 => Ok(()),
-            code => Err(Error::CommandFailed(code, reply.reply_lines)),
-        }
+        let ticket = self.setconf_submit(key_values)?;
+        self.setconf_wait(ticket)
     }
 
-    #[cfg(test)]
     pub fn getconf(&mut self, keywords: &[&str]) -> Result<Vec<(String, String)>, Error> {
         let reply = self.getconf_cmd(keywords)?;
 
@@ -456,49 +855,25 @@ impl LegacyTorController {
                 }
                 Ok(key_values)
             }
-            code => Err(Error::CommandFailed(code, reply.reply_lines)),
+            _ => Err(reply.into_error()),
         }
     }
 
+    #[allow(dead_code)]
     pub fn setevents(&mut self, events: &[&str]) -> Result<(), Error> {
-        let reply = self.setevents_cmd(events)?;
-
-        match reply.status_code {
-            250u32 => Ok(()),
-            code => Err(Error::CommandFailed(code, reply.reply_lines)),
-        }
+        let ticket = self.setevents_submit(events)?;
+        self.setevents_wait(ticket)
     }
 
+    #[allow(dead_code)]
     pub fn authenticate(&mut self, password: &str) -> Result<(), Error> {
-        let reply = self.authenticate_cmd(password)?;
-
-        match reply.status_code {
-            250u32 => Ok(()),
-            code => Err(Error::CommandFailed(code, reply.reply_lines)),
-        }
+        let ticket = self.authenticate_submit(password)?;
+        self.authenticate_wait(ticket)
     }
 
     pub fn getinfo(&mut self, keywords: &[&str]) -> Result<Vec<(String, String)>, Error> {
-        let reply = self.getinfo_cmd(keywords)?;
-
-        match reply.status_code {
-            250u32 => {
-                let mut key_values: Vec<(String, String)> = Default::default();
-                for line in reply.reply_lines {
-                    match line.find('=') {
-                        Some(index) => key_values
-                            .push((line[0..index].to_string(), line[index + 1..].to_string())),
-                        None => {
-                            if line != "OK" {
-                                key_values.push((line, String::new()))
-                            }
-                        }
-                    }
-                }
-                Ok(key_values)
-            }
-            code => Err(Error::CommandFailed(code, reply.reply_lines)),
-        }
+        let ticket = self.getinfo_submit(keywords)?;
+        self.getinfo_wait(ticket)
     }
 
     pub fn add_onion(
@@ -506,11 +881,44 @@ impl LegacyTorController {
         key: Option<&Ed25519PrivateKey>,
         flags: &AddOnionFlags,
         max_streams: Option<u16>,
-        virt_port: u16,
-        target: Option<SocketAddr>,
+        pow_defenses: Option<&PowDefenses>,
+        ports: &[(u16, Option<&OnionServiceTarget>)],
         client_auth: Option<&[X25519PublicKey]>,
     ) -> Result<(Option<Ed25519PrivateKey>, V3OnionServiceId), Error> {
-        let reply = self.add_onion_cmd(key, flags, max_streams, virt_port, target, client_auth)?;
+        let ticket =
+            self.add_onion_submit(key, flags, max_streams, pow_defenses, ports, client_auth)?;
+        self.add_onion_wait(
+            ticket,
+            flags,
+            client_auth.is_some_and(|keys| !keys.is_empty()),
+        )
+    }
+
+    pub fn del_onion(&mut self, service_id: &V3OnionServiceId) -> Result<(), Error> {
+        let ticket = self.del_onion_submit(service_id)?;
+        self.del_onion_wait(ticket)
+    }
+
+    //
+    // Pipelined command completion
+    //
+    // The *_wait methods block until the reply to a command written by the
+    // matching *_submit method is received. Tor replies to commands in the
+    // order they were written, so submitting a batch of commands before
+    // waiting on any of them costs a single round-trip rather than one per
+    // command. Every submitted ticket must eventually be passed to its *_wait
+    // method.
+    //
+
+    // flags and whether client auth keys were provided must match those passed
+    // to add_onion_submit()
+    pub fn add_onion_wait(
+        &mut self,
+        ticket: CommandTicket,
+        flags: &AddOnionFlags,
+        client_auth: bool,
+    ) -> Result<(Option<Ed25519PrivateKey>, V3OnionServiceId), Error> {
+        let reply = self.wait_reply(ticket)?;
 
         let mut private_key: Option<Ed25519PrivateKey> = None;
         let mut service_id: Option<V3OnionServiceId> = None;
@@ -554,7 +962,7 @@ impl LegacyTorController {
                             }
                         };
                     } else if line.contains("ClientAuthV3=") {
-                        if client_auth.unwrap_or_default().is_empty() {
+                        if !client_auth {
                             return Err(Error::CommandReplyParseFailed(
                                 "recieved unexpected ClientAuthV3 keys".to_string(),
                             ));
@@ -567,7 +975,7 @@ impl LegacyTorController {
                     }
                 }
             }
-            code => return Err(Error::CommandFailed(code, reply.reply_lines)),
+            _ => return Err(reply.into_error()),
         }
 
         if flags.discard_pk {
@@ -590,12 +998,62 @@ impl LegacyTorController {
         }
     }
 
-    pub fn del_onion(&mut self, service_id: &V3OnionServiceId) -> Result<(), Error> {
-        let reply = self.del_onion_cmd(service_id)?;
+    pub fn setconf_wait(&mut self, ticket: CommandTicket) -> Result<(), Error> {
+        let reply = self.wait_reply(ticket)?;
+
+        match reply.status_code {
+            250u32 => Ok(()),
+            _ => Err(reply.into_error()),
+        }
+    }
+
+    pub fn setevents_wait(&mut self, ticket: CommandTicket) -> Result<(), Error> {
+        let reply = self.wait_reply(ticket)?;
+
+        match reply.status_code {
+            250u32 => Ok(()),
+            _ => Err(reply.into_error()),
+        }
+    }
+
+    pub fn authenticate_wait(&mut self, ticket: CommandTicket) -> Result<(), Error> {
+        let reply = self.wait_reply(ticket)?;
 
         match reply.status_code {
             250u32 => Ok(()),
-            code => Err(Error::CommandFailed(code, reply.reply_lines)),
+            _ => Err(reply.into_error()),
+        }
+    }
+
+    pub fn getinfo_wait(&mut self, ticket: CommandTicket) -> Result<Vec<(String, String)>, Error> {
+        let reply = self.wait_reply(ticket)?;
+
+        match reply.status_code {
+            250u32 => {
+                let mut key_values: Vec<(String, String)> = Default::default();
+                for line in reply.reply_lines {
+                    match line.find('=') {
+                        Some(index) => key_values
+                            .push((line[0..index].to_string(), line[index + 1..].to_string())),
+                        None => {
+                            if line != "OK" {
+                                key_values.push((line, String::new()))
+                            }
+                        }
+                    }
+                }
+                Ok(key_values)
+            }
+            _ => Err(reply.into_error()),
+        }
+    }
+
+    pub fn del_onion_wait(&mut self, ticket: CommandTicket) -> Result<(), Error> {
+        let reply = self.wait_reply(ticket)?;
+
+        match reply.status_code {
+            250u32 => Ok(()),
+            _ => Err(reply.into_error()),
         }
     }
 
@@ -639,8 +1097,21 @@ impl LegacyTorController {
         ))
     }
 
+    #[allow(dead_code)]
     pub fn getinfo_version(&mut self) -> Result<LegacyTorVersion, Error> {
-        let response = self.getinfo(&["version"])?;
+        let ticket = self.getinfo_version_submit()?;
+        self.getinfo_version_wait(ticket)
+    }
+
+    pub fn getinfo_version_submit(&mut self) -> Result<CommandTicket, Error> {
+        self.getinfo_submit(&["version"])
+    }
+
+    pub fn getinfo_version_wait(
+        &mut self,
+        ticket: CommandTicket,
+    ) -> Result<LegacyTorVersion, Error> {
+        let response = self.getinfo_wait(ticket)?;
         for (key, value) in response.iter() {
             if key.as_str() == "version" {
                 return LegacyTorVersion::from_str(value).map_err(Error::TorVersionParseFailed);
@@ -662,7 +1133,16 @@ impl LegacyTorController {
 
         match reply.status_code {
             250u32..=252u32 => Ok(()),
-            code => Err(Error::CommandFailed(code, reply.reply_lines)),
+            _ => Err(reply.into_error()),
+        }
+    }
+
+    pub fn hsfetch(&mut self, service_id: &V3OnionServiceId) -> Result<(), Error> {
+        let reply = self.hsfetch_cmd(service_id)?;
+
+        match reply.status_code {
+            250u32 => Ok(()),
+            _ => Err(reply.into_error()),
         }
     }
 
@@ -672,7 +1152,7 @@ impl LegacyTorController {
 
         match reply.status_code {
             250u32..=251u32 => Ok(()),
-            code => Err(Error::CommandFailed(code, reply.reply_lines)),
+            _ => Err(reply.into_error()),
         }
     }
 }
@@ -683,7 +1163,7 @@ fn test_tor_controller() -> anyhow::Result<()> {
     let tor_path = which::which(format!("tor{}", std::env::consts::EXE_SUFFIX))?;
     let mut data_path = std::env::temp_dir();
     data_path.push("test_tor_controller");
-    let tor_process = LegacyTorProcess::new(&tor_path, &data_path)?;
+    let tor_process = LegacyTorProcess::new(&tor_path, &data_path, None, None)?;
 
     // create a scope to ensure tor_controller is dropped
     {
@@ -707,7 +1187,7 @@ fn test_tor_controller() -> anyhow::Result<()> {
                 .is_err(),
             "expected failure due to closed connection"
         );
-        assert!(tor_controller.control_stream.closed_by_remote());
+        assert!(tor_controller.closed_by_remote());
     }
     // now create a second controller
     {
@@ -757,11 +1237,17 @@ fn test_tor_controller() -> anyhow::Result<()> {
         tor_controller.setconf(&[("DisableNetwork", "0".to_string())])?;
 
         // add an onoin service
-        let (private_key, service_id) =
-            match tor_controller.add_onion(None, &Default::default(), None, 22, None, None)? {
-                (Some(private_key), service_id) => (private_key, service_id),
-                _ => panic!("add_onion did not return expected values"),
-            };
+        let (private_key, service_id) = match tor_controller.add_onion(
+            None,
+            &Default::default(),
+            None,
+            None,
+            &[(22, None)],
+            None,
+        )? {
+            (Some(private_key), service_id) => (private_key, service_id),
+            _ => panic!("add_onion did not return expected values"),
+        };
         println!("private_key: {}", private_key.to_key_blob());
         println!("service_id: {}", service_id.to_string());
 
@@ -804,16 +1290,210 @@ fn test_tor_controller() -> anyhow::Result<()> {
                             println!(" {}='{}'", key, value);
                         }
                     }
-                    AsyncEvent::HsDesc { action, hs_address } => {
+                    AsyncEvent::HsDesc {
+                        action, hs_address, ..
+                    } => {
                         println!(
                             "HS_DESC action={}, hsaddress={}",
                             action,
                             hs_address.to_string()
                         );
                     }
+                    AsyncEvent::Circ {
+                        circuit_id,
+                        status,
+                        path,
+                        ..
+                    } => {
+                        println!(
+                            "CIRC circuitid={}, status={}, path={}",
+                            circuit_id,
+                            status,
+                            path.join(",")
+                        );
+                    }
+                    AsyncEvent::Stream {
+                        stream_id,
+                        status,
+                        circuit_id,
+                        target,
+                        ..
+                    } => {
+                        println!(
+                            "STREAM streamid={}, status={}, circuitid={}, target={}",
+                            stream_id, status, circuit_id, target
+                        );
+                    }
                 }
             }
         }
     }
     Ok(())
 }
+
+#[test]
+fn test_tor_controller_pipelining() -> anyhow::Result<()> {
+    use std::io::{BufRead, Write};
+
+    // stand-in control port which replies only once all three commands have
+    // been received, with async events interleaved between the replies
+    let listener = std::net::TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0u16)))?;
+    let control_addr = listener.local_addr()?;
+    let control_port = std::thread::spawn(move || -> anyhow::Result<Vec<String>> {
+        let (stream, _) = listener.accept()?;
+        let mut reader = std::io::BufReader::new(stream.try_clone()?);
+        let mut commands: Vec<String> = Default::default();
+        while commands.len() < 3 {
+            let mut line = String::new();
+            reader.read_line(&mut line)?;
+            commands.push(line.trim_end().to_string());
+        }
+        let mut writer = stream;
+        write!(
+            writer,
+            "650 STATUS_CLIENT NOTICE BOOTSTRAP PROGRESS=5 TAG=conn SUMMARY=\"Connecting\"\r\n\
+             250 OK\r\n\
+             250-version=0.4.8.10\r\n\
+             250 OK\r\n\
+             650 HS_DESC UPLOADED 6l62fw7tqctlu5fesdqukvpoxezkaxbzllrafa2ve6ewuhzphxczsjyd\r\n\
+             650 HS_DESC FAILED 6l62fw7tqctlu5fesdqukvpoxezkaxbzllrafa2ve6ewuhzphxczsjyd NO_AUTH $CCCC~carol REASON=UPLOAD_REJECTED\r\n\
+             650 CIRC 7 BUILT $AAAA~alice,$BBBB~bob BUILD_FLAGS=NEED_CAPACITY PURPOSE=GENERAL TIME_CREATED=2024-01-01T00:00:00.000000\r\n\
+             650 CIRC 8 LAUNCHED PURPOSE=HS_CLIENT_REND\r\n\
+             650 STREAM 12 FAILED 7 example.com:443 REASON=END REMOTE_REASON=\"TIMEOUT\"\r\n\
+             552 Unrecognized event \"BOGUS\"\r\n"
+        )?;
+        // hold the connection open until the client hangs up
+        let mut line = String::new();
+        let _ = reader.read_line(&mut line);
+        Ok(commands)
+    });
+
+    {
+        let control_stream = LegacyControlStream::new(&control_addr, Duration::from_millis(16))?;
+        let mut tor_controller = LegacyTorController::new(control_stream)?;
+
+        let authenticate = tor_controller.authenticate_submit("password")?;
+        let version = tor_controller.getinfo_version_submit()?;
+        let setevents = tor_controller.setevents_submit(&["BOGUS"])?;
+
+        // wait out of order; earlier replies must be saved for their tickets
+        assert!(matches!(
+            tor_controller.setevents_wait(setevents),
+            Err(Error::CommandFailed {
+                status_code: 552u32,
+                ..
+            })
+        ));
+        assert_eq!(
+            tor_controller.getinfo_version_wait(version)?.to_string(),
+            "0.4.8.10"
+        );
+        tor_controller.authenticate_wait(authenticate)?;
+        // each reply may only be claimed once
+        assert!(matches!(
+            tor_controller.authenticate_wait(authenticate),
+            Err(Error::UnknownCommandTicket(_))
+        ));
+
+        // async events received while waiting are still delivered
+        let async_events = tor_controller.wait_async_events()?;
+        assert_eq!(async_events.len(), 6);
+        assert!(matches!(
+            &async_events[0],
+            AsyncEvent::StatusClient { severity, action, .. } if severity == "NOTICE" && action == "BOOTSTRAP"
+        ));
+        assert!(matches!(
+            &async_events[1],
+            AsyncEvent::HsDesc { action, hs_dir, .. } if action == "UPLOADED" && hs_dir.is_none()
+        ));
+        match &async_events[2] {
+            AsyncEvent::HsDesc {
+                action,
+                hs_dir,
+                arguments,
+                ..
+            } => {
+                assert_eq!(action, "FAILED");
+                assert_eq!(hs_dir.as_deref(), Some("$CCCC~carol"));
+                assert!(arguments.contains(&("REASON".to_string(), "UPLOAD_REJECTED".to_string())));
+            }
+            _ => panic!("expected HS_DESC event"),
+        }
+        match &async_events[3] {
+            AsyncEvent::Circ {
+                circuit_id,
+                status,
+                path,
+                arguments,
+            } => {
+                assert_eq!(circuit_id, "7");
+                assert_eq!(status, "BUILT");
+                assert_eq!(
+                    path,
+                    &vec!["$AAAA~alice".to_string(), "$BBBB~bob".to_string()]
+                );
+                assert!(arguments.contains(&("PURPOSE".to_string(), "GENERAL".to_string())));
+            }
+            _ => panic!("expected CIRC event"),
+        }
+        assert!(matches!(
+            &async_events[4],
+            AsyncEvent::Circ { circuit_id, status, path, .. } if circuit_id == "8" && status == "LAUNCHED" && path.is_empty()
+        ));
+        match &async_events[5] {
+            AsyncEvent::Stream {
+                stream_id,
+                status,
+                circuit_id,
+                target,
+                arguments,
+            } => {
+                assert_eq!(stream_id, "12");
+                assert_eq!(status, "FAILED");
+                assert_eq!(circuit_id, "7");
+                assert_eq!(target, "example.com:443");
+                assert!(arguments.contains(&("REASON".to_string(), "END".to_string())));
+                assert!(arguments.contains(&("REMOTE_REASON".to_string(), "TIMEOUT".to_string())));
+            }
+            _ => panic!("expected STREAM event"),
+        }
+    }
+
+    let commands = match control_port.join() {
+        Ok(commands) => commands?,
+        Err(_) => panic!("control port thread panicked"),
+    };
+    assert_eq!(
+        commands,
+        vec![
+            "AUTHENTICATE \"password\"".to_string(),
+            "GETINFO version".to_string(),
+            "SETEVENTS BOGUS".to_string(),
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_redacted_command() {
+    assert_eq!(
+        redacted_command("AUTHENTICATE \"password\""),
+        "AUTHENTICATE <redacted>"
+    );
+    assert_eq!(
+        redacted_command("ADD_ONION ED25519-V3:c2VjcmV0 Flags=DiscardPK Port=1234"),
+        "ADD_ONION ED25519-V3:<redacted> Flags=DiscardPK Port=1234"
+    );
+    assert_eq!(
+        redacted_command("ONION_CLIENT_AUTH_ADD abcd x25519:c2VjcmV0 Flags=Permanent"),
+        "ONION_CLIENT_AUTH_ADD abcd x25519:<redacted> Flags=Permanent"
+    );
+    assert_eq!(
+        redacted_command(
+            "SETCONF UseBridges=\"1\" Bridge=\"obfs4 192.0.2.1:443 cert=c2VjcmV0\" Bridge=\"192.0.2.2:443\" DisableNetwork=\"0\""
+        ),
+        "SETCONF UseBridges=\"1\" Bridge=<redacted> Bridge=<redacted> DisableNetwork=\"0\""
+    );
+    assert_eq!(redacted_command("GETINFO version"), "GETINFO version");
+}
@@ -1,17 +1,18 @@
 // standard
 use std::default::Default;
+use std::ffi::OsString;
 use std::fs;
 use std::fs::File;
 use std::io::{BufRead, BufReader, Read, Write};
 use std::net::SocketAddr;
 use std::ops::Drop;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process;
 use std::process::{Child, ChildStdout, Command, Stdio};
 use std::str::FromStr;
 use std::string::ToString;
-use std::sync::{Arc, Mutex};
-use std::time::{Duration, Instant};
+use std::sync::mpsc::{Receiver, Sender};
+use std::time::{Duration, Instant, SystemTime};
 
 // extern crates
 use data_encoding::HEXUPPER;
@@ -21,6 +22,7 @@ use sha1::{Digest, Sha1};
 
 // internal crates
 use crate::tor_crypto::generate_password;
+use crate::tor_provider::LogSeverity;
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
@@ -68,6 +70,145 @@ pub enum Error {
 
     #[error("failed to spawn tor process stdout read thread")]
     StdoutReadThreadSpawnFailed(#[source] std::io::Error),
+
+    #[error("tor process sandbox option '{0}' is not supported on this platform")]
+    SandboxOptionNotSupported(String),
+
+    #[error("provided shared network cache directory '{0}' must be an absolute path")]
+    NetworkCachePathNotAbsolute(String),
+
+    #[error("failed to copy the shared network cache into the data directory")]
+    NetworkCacheSeedFailed(#[source] std::io::Error),
+
+    #[error("failed to copy the network cache into the shared network cache directory")]
+    NetworkCachePublishFailed(#[source] std::io::Error),
+}
+
+/// Privilege-reduction options applied when launching a bundled tor daemon.
+///
+/// Every option is disabled by default. Requesting an option which is not available on the current platform causes the tor daemon launch to fail rather than silently running it without the requested protection.
+#[derive(Clone, Debug, Default)]
+pub struct TorProcessSandbox {
+    /// Run the tor daemon as this user id (Unix only). The data directory must be accessible by this user, and changing user typically requires the calling process to be privileged.
+    pub uid: Option<u32>,
+    /// Run the tor daemon as this group id (Unix only).
+    pub gid: Option<u32>,
+    /// Run the tor daemon in a new mount namespace whose mounts do not propagate back to the host (Linux only). This only isolates mount changes; it does not restrict which files tor can access, so use a `wrapper` such as `bwrap` for filesystem restrictions. Requires `CAP_SYS_ADMIN`.
+    pub unshare_mount_namespace: bool,
+    /// Launch the tor daemon through this wrapper program (e.g. `bwrap`, `firejail`, or an AppContainer launcher on Windows). The wrapper is invoked with the provided arguments, followed by the tor binary path and tor's own arguments.
+    ///
+    /// Tor is passed this process's id as its `__OwningControllerProcess` so that it exits when this process does. A wrapper which runs tor in its own PID namespace (e.g. `bwrap --unshare-pid`) must therefore also make this process visible to it, otherwise tor cannot find its owner and exits immediately.
+    pub wrapper: Option<(PathBuf, Vec<OsString>)>,
+}
+
+impl TorProcessSandbox {
+    // the command launching tor_bin_path, through the wrapper if there is one
+    fn command(&self, tor_bin_path: &Path) -> Command {
+        match &self.wrapper {
+            Some((wrapper_path, wrapper_args)) => {
+                let mut command = Command::new(wrapper_path.as_os_str());
+                command.args(wrapper_args).arg(tor_bin_path.as_os_str());
+                command
+            }
+            None => Command::new(tor_bin_path.as_os_str()),
+        }
+    }
+
+    // apply the sandbox options which are handled by the child process itself
+    #[cfg_attr(not(unix), allow(unused_variables))]
+    fn apply(&self, command: &mut Command) -> Result<(), Error> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::CommandExt;
+            if let Some(gid) = self.gid {
+                command.gid(gid);
+            }
+            if let Some(uid) = self.uid {
+                command.uid(uid);
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            if self.uid.is_some() {
+                return Err(Error::SandboxOptionNotSupported("uid".to_string()));
+            }
+            if self.gid.is_some() {
+                return Err(Error::SandboxOptionNotSupported("gid".to_string()));
+            }
+        }
+
+        if self.unshare_mount_namespace {
+            #[cfg(target_os = "linux")]
+            {
+                use std::os::unix::process::CommandExt;
+                // SAFETY: only async-signal-safe syscalls are made between fork and exec
+                unsafe {
+                    command.pre_exec(|| {
+                        if libc::unshare(libc::CLONE_NEWNS) != 0 {
+                            return Err(std::io::Error::last_os_error());
+                        }
+                        // prevent mounts in the new namespace propagating back to the host
+                        if libc::mount(
+                            b"none\0".as_ptr() as *const libc::c_char,
+                            b"/\0".as_ptr() as *const libc::c_char,
+                            std::ptr::null(),
+                            libc::MS_REC | libc::MS_PRIVATE,
+                            std::ptr::null(),
+                        ) != 0
+                        {
+                            return Err(std::io::Error::last_os_error());
+                        }
+                        Ok(())
+                    });
+                }
+            }
+            #[cfg(not(target_os = "linux"))]
+            return Err(Error::SandboxOptionNotSupported(
+                "unshare_mount_namespace".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+// the files in which tor caches the directory documents it downloads while
+// bootstrapping; tor keeps them in its DataDirectory unless CacheDirectory is set
+const NETWORK_CACHE_FILES: [&str; 4] = [
+    "cached-certs",
+    "cached-microdesc-consensus",
+    "cached-microdescs",
+    "cached-microdescs.new",
+];
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+// copy the network cache files in from_directory which are newer than those in
+// to_directory; each file is copied under a temporary name and renamed into
+// place so other processes never read a partially copied file
+fn copy_network_cache(from_directory: &Path, to_directory: &Path) -> Result<(), std::io::Error> {
+    for file_name in NETWORK_CACHE_FILES {
+        let from = from_directory.join(file_name);
+        let to = to_directory.join(file_name);
+        let from_modified = match modified(&from) {
+            Some(from_modified) => from_modified,
+            None => continue,
+        };
+        if modified(&to).is_some_and(|to_modified| to_modified >= from_modified) {
+            continue;
+        }
+
+        let temporary = to_directory.join(format!(".{}.{}.tmp", file_name, process::id()));
+        fs::copy(&from, &temporary)?;
+        if let Err(err) = fs::rename(&temporary, &to) {
+            let _ = fs::remove_file(&temporary);
+            return Err(err);
+        }
+    }
+    Ok(())
 }
 
 fn read_control_port_file(control_port_file: &Path) -> Result<SocketAddr, Error> {
@@ -105,8 +246,12 @@ pub(crate) struct LegacyTorProcess {
     control_addr: SocketAddr,
     process: Child,
     password: String,
-    // stdout data
-    stdout_lines: Arc<Mutex<Vec<String>>>,
+    // stdout lines in the order they were read
+    stdout_lines: Receiver<String>,
+    data_directory: PathBuf,
+    // directory documents are copied from here before launch and back once
+    // bootstrapped, so daemons sharing it need not each download them
+    shared_network_cache: Option<PathBuf>,
 }
 
 impl LegacyTorProcess {
@@ -170,7 +315,12 @@ impl LegacyTorProcess {
         &self.password
     }
 
-    pub fn new(tor_bin_path: &Path, data_directory: &Path) -> Result<LegacyTorProcess, Error> {
+    pub fn new(
+        tor_bin_path: &Path,
+        data_directory: &Path,
+        sandbox: Option<&TorProcessSandbox>,
+        shared_network_cache: Option<&Path>,
+    ) -> Result<LegacyTorProcess, Error> {
         if tor_bin_path.is_relative() {
             return Err(Error::TorBinPathNotAbsolute(format!(
                 "{}",
@@ -184,6 +334,15 @@ impl LegacyTorProcess {
             )));
         }
 
+        if let Some(shared_network_cache) = shared_network_cache {
+            if shared_network_cache.is_relative() {
+                return Err(Error::NetworkCachePathNotAbsolute(format!(
+                    "{}",
+                    shared_network_cache.display()
+                )));
+            }
+        }
+
         // create data directory if it doesn't exist
         if !data_directory.exists() {
             fs::create_dir_all(data_directory).map_err(Error::DataDirectoryCreationFailed)?;
@@ -194,6 +353,12 @@ impl LegacyTorProcess {
             )));
         }
 
+        // start from any newer directory documents another daemon has shared
+        if let Some(shared_network_cache) = shared_network_cache {
+            copy_network_cache(shared_network_cache, data_directory)
+                .map_err(Error::NetworkCacheSeedFailed)?;
+        }
+
         // construct paths to torrc files
         let default_torrc = data_directory.join("default_torrc");
         let torrc = data_directory.join("torrc");
@@ -231,7 +396,11 @@ impl LegacyTorProcess {
         let password = generate_password(CONTROL_PORT_PASSWORD_LENGTH);
         let password_hash = Self::hash_tor_password(&password);
 
-        let mut process = Command::new(tor_bin_path.as_os_str())
+        let mut command = match sandbox {
+            Some(sandbox) => sandbox.command(tor_bin_path),
+            None => Command::new(tor_bin_path.as_os_str()),
+        };
+        command
             .stdout(Stdio::piped())
             .stdin(Stdio::null())
             .stderr(Stdio::null())
@@ -257,10 +426,17 @@ impl LegacyTorProcess {
             // from modifying our daemon's settings
             .arg("HashedControlPassword")
             .arg(password_hash)
+            // prefix log messages with the subsystem which logged them
+            .arg("LogMessageDomains")
+            .arg("1")
             // tor process will shut down after this process shuts down
             // to avoid orphaned tor daemon
             .arg("__OwningControllerProcess")
-            .arg(process::id().to_string())
+            .arg(process::id().to_string());
+        if let Some(sandbox) = sandbox {
+            sandbox.apply(&mut command)?;
+        }
+        let mut process = command
             .spawn()
             .map_err(Error::LegacyTorProcessStartFailed)?;
 
@@ -287,10 +463,9 @@ impl LegacyTorProcess {
             }
         };
 
-        let stdout_lines: Arc<Mutex<Vec<String>>> = Default::default();
+        let (stdout_sender, stdout_lines) = std::sync::mpsc::channel();
 
         {
-            let stdout_lines = Arc::downgrade(&stdout_lines);
             let stdout = BufReader::new(match process.stdout.take() {
                 Some(stdout) => stdout,
                 None => return Err(Error::LegacyTorProcessStdoutTakeFailed()),
@@ -299,7 +474,7 @@ impl LegacyTorProcess {
             std::thread::Builder::new()
                 .name("tor_stdout_reader".to_string())
                 .spawn(move || {
-                    LegacyTorProcess::read_stdout_task(&stdout_lines, stdout);
+                    LegacyTorProcess::read_stdout_task(stdout_sender, stdout);
                 })
                 .map_err(Error::StdoutReadThreadSpawnFailed)?;
         }
@@ -309,35 +484,81 @@ impl LegacyTorProcess {
             process,
             password,
             stdout_lines,
+            data_directory: data_directory.to_path_buf(),
+            shared_network_cache: shared_network_cache.map(Path::to_path_buf),
         })
     }
 
-    fn read_stdout_task(
-        stdout_lines: &std::sync::Weak<Mutex<Vec<String>>>,
-        mut stdout: BufReader<ChildStdout>,
-    ) {
-        while let Some(stdout_lines) = stdout_lines.upgrade() {
+    // copy the directory documents downloaded by this daemon to the shared
+    // network cache directory, if set
+    pub fn publish_network_cache(&self) -> Result<(), Error> {
+        if let Some(shared_network_cache) = self.shared_network_cache.as_ref() {
+            fs::create_dir_all(shared_network_cache).map_err(Error::NetworkCachePublishFailed)?;
+            copy_network_cache(&self.data_directory, shared_network_cache)
+                .map_err(Error::NetworkCachePublishFailed)?;
+        }
+        Ok(())
+    }
+
+    fn read_stdout_task(stdout_lines: Sender<String>, mut stdout: BufReader<ChildStdout>) {
+        loop {
             let mut line = String::default();
             // read line
-            if stdout.read_line(&mut line).is_ok() {
-                // remove trailing '\n'
-                line.pop();
-                // then acquire the lock on the line buffer
-                let mut stdout_lines = match stdout_lines.lock() {
-                    Ok(stdout_lines) => stdout_lines,
-                    Err(_) => unreachable!(),
-                };
-                stdout_lines.push(line);
+            match stdout.read_line(&mut line) {
+                // tor has closed stdout
+                Ok(0usize) => return,
+                Ok(_) => {
+                    // remove trailing '\n'
+                    line.pop();
+                    // stop once the LegacyTorProcess has been dropped
+                    if stdout_lines.send(line).is_err() {
+                        return;
+                    }
+                }
+                Err(_) => (),
             }
         }
     }
 
     pub fn wait_log_lines(&mut self) -> Vec<String> {
-        let mut lines = match self.stdout_lines.lock() {
-            Ok(lines) => lines,
-            Err(_) => unreachable!(),
+        self.stdout_lines.try_iter().collect()
+    }
+}
+
+// A log line written to stdout by the tor daemon, in the form:
+// "<timestamp> [<severity>] {<subsystem>} <message>"
+// where the subsystem is only present when LogMessageDomains is enabled
+pub(crate) struct LogLine {
+    pub timestamp: Option<String>,
+    pub severity: LogSeverity,
+    pub subsystem: Option<String>,
+    pub message: String,
+}
+
+impl FromStr for LogLine {
+    type Err = ();
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let (timestamp, rest) = line.split_once('[').ok_or(())?;
+        let (severity, rest) = rest.split_once(']').ok_or(())?;
+        let severity = LogSeverity::from_str(severity).map_err(|_| ())?;
+        let timestamp = match timestamp.trim() {
+            "" => None,
+            timestamp => Some(timestamp.to_string()),
         };
-        std::mem::take(&mut lines)
+
+        let rest = rest.trim_start();
+        let (subsystem, message) =
+            match rest.strip_prefix('{').and_then(|rest| rest.split_once('}')) {
+                Some((subsystem, message)) => (Some(subsystem.to_string()), message.trim_start()),
+                None => (None, rest),
+            };
+
+        Ok(LogLine {
+            timestamp,
+            severity,
+            subsystem,
+            message: message.to_string(),
+        })
     }
 }
 
@@ -375,3 +596,158 @@ fn test_password_hash() -> Result<(), anyhow::Error> {
 
     Ok(())
 }
+
+#[test]
+fn test_log_line_parse() -> Result<(), anyhow::Error> {
+    let line = LogLine::from_str(
+        "Oct 16 14:51:00.000 [notice] {BOOTSTRAP} Bootstrapped 5% (conn): Connecting to a relay",
+    )
+    .expect("parse failed");
+    assert_eq!(line.timestamp.as_deref(), Some("Oct 16 14:51:00.000"));
+    assert_eq!(line.severity, LogSeverity::Notice);
+    assert_eq!(line.subsystem.as_deref(), Some("BOOTSTRAP"));
+    assert_eq!(
+        line.message,
+        "Bootstrapped 5% (conn): Connecting to a relay"
+    );
+
+    // subsystem is only present with LogMessageDomains enabled
+    let line = LogLine::from_str("Oct 16 14:51:00.000 [warn] Problem bootstrapping.")
+        .expect("parse failed");
+    assert_eq!(line.severity, LogSeverity::Warn);
+    assert!(line.subsystem.is_none());
+    assert_eq!(line.message, "Problem bootstrapping.");
+
+    // a message containing braces is not mistaken for a subsystem
+    let line = LogLine::from_str("[err] {oops").expect("parse failed");
+    assert!(line.timestamp.is_none());
+    assert_eq!(line.severity, LogSeverity::Err);
+    assert!(line.subsystem.is_none());
+    assert_eq!(line.message, "{oops");
+
+    assert!(LogLine::from_str("Oct 16 14:51:00.000 [loud] message").is_err());
+    assert!(LogLine::from_str("not a log line").is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_copy_network_cache() -> Result<(), anyhow::Error> {
+    let root = std::env::temp_dir().join(format!("test_copy_network_cache_{}", process::id()));
+    let shared = root.join("shared");
+    let data = root.join("data");
+    fs::create_dir_all(&shared)?;
+    fs::create_dir_all(&data)?;
+
+    // missing files are skipped and only network cache files are copied
+    fs::write(shared.join("cached-certs"), "certs")?;
+    fs::write(shared.join("state"), "state")?;
+    copy_network_cache(&shared, &data)?;
+    assert_eq!(fs::read_to_string(data.join("cached-certs"))?, "certs");
+    assert!(!data.join("cached-microdesc-consensus").exists());
+    assert!(!data.join("state").exists());
+
+    // files no newer than the destination's are not copied back
+    fs::write(data.join("cached-certs"), "newer certs")?;
+    copy_network_cache(&shared, &data)?;
+    assert_eq!(
+        fs::read_to_string(data.join("cached-certs"))?,
+        "newer certs"
+    );
+
+    // the cache directory may be published to anywhere
+    let published = root.join("published");
+    fs::create_dir_all(&published)?;
+    copy_network_cache(&data, &published)?;
+    assert_eq!(
+        fs::read_to_string(published.join("cached-certs"))?,
+        "newer certs"
+    );
+
+    // no temporary files are left behind
+    for entry in fs::read_dir(&published)? {
+        assert!(!entry?.file_name().to_string_lossy().ends_with(".tmp"));
+    }
+
+    fs::remove_dir_all(&root)?;
+    Ok(())
+}
+
+#[test]
+fn test_sandbox_wrapper_command() -> Result<(), anyhow::Error> {
+    let tor_bin_path = Path::new("/usr/bin/tor");
+
+    // without a wrapper tor is launched directly
+    let command = TorProcessSandbox::default().command(tor_bin_path);
+    assert_eq!(command.get_program(), tor_bin_path.as_os_str());
+    assert_eq!(command.get_args().count(), 0);
+
+    // the wrapper is passed its own arguments followed by the tor binary
+    let sandbox = TorProcessSandbox {
+        wrapper: Some((
+            PathBuf::from("/usr/bin/bwrap"),
+            vec![
+                OsString::from("--ro-bind"),
+                OsString::from("/"),
+                OsString::from("/"),
+            ],
+        )),
+        ..Default::default()
+    };
+    let command = sandbox.command(tor_bin_path);
+    assert_eq!(command.get_program(), "/usr/bin/bwrap");
+    assert_eq!(
+        command.get_args().collect::<Vec<_>>(),
+        ["--ro-bind", "/", "/", "/usr/bin/tor"]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_sandbox_unsupported_options() -> Result<(), anyhow::Error> {
+    let mut command = Command::new("/usr/bin/tor");
+
+    // uid and gid are only supported on unix
+    for (option, sandbox) in [
+        (
+            "uid",
+            TorProcessSandbox {
+                uid: Some(1000),
+                ..Default::default()
+            },
+        ),
+        (
+            "gid",
+            TorProcessSandbox {
+                gid: Some(1000),
+                ..Default::default()
+            },
+        ),
+    ] {
+        let result = sandbox.apply(&mut command);
+        if cfg!(unix) {
+            assert!(result.is_ok());
+        } else {
+            assert!(
+                matches!(result, Err(Error::SandboxOptionNotSupported(unsupported)) if unsupported == option)
+            );
+        }
+    }
+
+    // mount namespaces are only supported on linux
+    let sandbox = TorProcessSandbox {
+        unshare_mount_namespace: true,
+        ..Default::default()
+    };
+    let result = sandbox.apply(&mut command);
+    if cfg!(target_os = "linux") {
+        assert!(result.is_ok());
+    } else {
+        assert!(
+            matches!(result, Err(Error::SandboxOptionNotSupported(unsupported)) if unsupported == "unshare_mount_namespace")
+        );
+    }
+
+    Ok(())
+}
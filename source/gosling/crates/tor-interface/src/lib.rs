@@ -1,8 +1,15 @@
 #![doc = include_str!("../README.md")]
 
+// the legacy provider launches and controls a tor daemon process, which is
+// only possible on native targets
+#[cfg(all(feature = "legacy-tor-provider", target_family = "wasm"))]
+compile_error!("the legacy-tor-provider feature is not supported on wasm targets");
+
 /// Implementation of an in-process [`arti-client`](https://crates.io/crates/arti-client)-based `TorProvider`
 #[cfg(feature = "arti-client-tor-provider")]
 pub mod arti_client_tor_client;
+/// Clock abstraction for timeout and retry logic, allowing tests to advance time virtually.
+pub mod clock;
 #[cfg(feature = "legacy-tor-provider")]
 /// Censorship circumvention configuration for pluggable-transports and bridge settings
 pub mod censorship_circumvention;
@@ -2,6 +2,7 @@
 use std::collections::BTreeMap;
 use std::net::{SocketAddr, TcpListener, TcpStream};
 use std::sync::{atomic, Arc, Mutex};
+use std::time::Duration;
 
 // internal crates
 use crate::tor_crypto::*;
@@ -24,6 +25,9 @@ pub enum Error {
     #[error("onion service not published: {}", .0)]
     OnionServiceNotPublished(OnionAddr),
 
+    #[error("onion service not started by this client: {}", .0)]
+    OnionServiceNotStarted(V3OnionServiceId),
+
     #[error("onion service requires onion auth")]
     OnionServiceRequiresOnionAuth(),
 
@@ -99,6 +103,24 @@ impl MockTorNetwork {
         }
     }
 
+    fn set_onion_client_auth_keys(
+        &mut self,
+        onion_addr: &OnionAddr,
+        client_auth_keys: Vec<X25519PublicKey>,
+    ) -> Result<(), Error> {
+        match self
+            .onion_services
+            .as_mut()
+            .and_then(|onion_services| onion_services.get_mut(onion_addr))
+        {
+            Some((keys, _)) => {
+                *keys = client_auth_keys;
+                Ok(())
+            }
+            None => Err(Error::OnionServiceNotFound(onion_addr.clone())),
+        }
+    }
+
     fn start_onion(
         &mut self,
         service_id: V3OnionServiceId,
@@ -119,6 +141,14 @@ impl MockTorNetwork {
         }
     }
 
+    fn is_published(&self, service_id: &V3OnionServiceId) -> bool {
+        self.onion_services.as_ref().is_some_and(|onion_services| {
+            onion_services.keys().any(|onion_addr| {
+                matches!(onion_addr, OnionAddr::V3(onion_addr) if onion_addr.service_id == *service_id)
+            })
+        })
+    }
+
     fn stop_onion(&mut self, onion_addr: &OnionAddr) {
         if let Some(onion_services) = &mut self.onion_services {
             onion_services.remove(onion_addr);
@@ -159,6 +189,45 @@ impl MockTorClient {
             loopback: listener,
         }
     }
+
+    // register an onion service forwarding to target with the mock tor network
+    fn start_onion_service(
+        &mut self,
+        private_key: &Ed25519PrivateKey,
+        virt_port: u16,
+        authorized_clients: Option<&[X25519PublicKey]>,
+        target: SocketAddr,
+    ) -> (OnionAddr, Arc<atomic::AtomicBool>) {
+        // convert inputs to relevant types
+        let service_id = V3OnionServiceId::from_private_key(private_key);
+        let onion_addr = OnionAddr::V3(OnionAddrV3::new(service_id.clone(), virt_port));
+        let authorized_clients: Vec<X25519PublicKey> = match authorized_clients {
+            Some(keys) => keys.into(),
+            None => Default::default(),
+        };
+
+        // register the onion service with the mock tor network
+        match MOCK_TOR_NETWORK.lock() {
+            Ok(mut mock_tor_network) => mock_tor_network.start_onion(
+                service_id.clone(),
+                virt_port,
+                authorized_clients,
+                target,
+            ),
+            Err(_) => unreachable!("another thread panicked while holding mock tor network's lock"),
+        }
+
+        // init flag for signaling when listener goes out of scope so we can tear down onion service
+        let is_active = Arc::new(atomic::AtomicBool::new(true));
+        self.onion_services
+            .push((onion_addr.clone(), Arc::clone(&is_active)));
+
+        // onion service published event
+        self.events
+            .push(TorEvent::OnionServicePublished { service_id });
+
+        (onion_addr, is_active)
+    }
 }
 
 impl Default for MockTorClient {
@@ -278,15 +347,9 @@ impl TorProvider for MockTorClient {
         private_key: &Ed25519PrivateKey,
         virt_port: u16,
         authorized_clients: Option<&[X25519PublicKey]>,
+        _non_anonymous: bool,
+        _pow_defenses: Option<&PowDefenses>,
     ) -> Result<OnionListener, tor_provider::Error> {
-        // convert inputs to relevant types
-        let service_id = V3OnionServiceId::from_private_key(private_key);
-        let onion_addr = OnionAddr::V3(OnionAddrV3::new(service_id.clone(), virt_port));
-        let authorized_clients: Vec<X25519PublicKey> = match authorized_clients {
-            Some(keys) => keys.into(),
-            None => Default::default(),
-        };
-
         // try to bind to a local address, let OS pick our port
         let socket_addr = SocketAddr::from(([127, 0, 0, 1], 0u16));
         let listener = TcpListener::bind(socket_addr).map_err(Error::TcpListenerBindFailed)?;
@@ -294,30 +357,198 @@ impl TorProvider for MockTorClient {
             .local_addr()
             .map_err(Error::TcpListenerLocalAddrFailed)?;
 
-        // register the onion service with the mock tor network
-        match MOCK_TOR_NETWORK.lock() {
-            Ok(mut mock_tor_network) => mock_tor_network.start_onion(
-                service_id.clone(),
-                virt_port,
-                authorized_clients,
-                socket_addr,
-            ),
-            Err(_) => unreachable!("another thread panicked while holding mock tor network's lock"),
+        let (onion_addr, is_active) =
+            self.start_onion_service(private_key, virt_port, authorized_clients, socket_addr);
+
+        Ok(OnionListener::new(listener, onion_addr, is_active, |is_active| {
+            is_active.store(false, atomic::Ordering::Relaxed);
+        }))
+    }
+
+    fn listener_with_ports(
+        &mut self,
+        private_key: &Ed25519PrivateKey,
+        virt_ports: &[u16],
+        authorized_clients: Option<&[X25519PublicKey]>,
+        _non_anonymous: bool,
+        _pow_defenses: Option<&PowDefenses>,
+    ) -> Result<Vec<OnionListener>, tor_provider::Error> {
+        ensure_virt_ports_distinct(virt_ports)?;
+
+        // try to bind a local address for each virt-port, let OS pick our ports
+        let mut sockets: Vec<(TcpListener, SocketAddr)> = Vec::with_capacity(virt_ports.len());
+        for _ in virt_ports {
+            let socket_addr = SocketAddr::from(([127, 0, 0, 1], 0u16));
+            let listener = TcpListener::bind(socket_addr).map_err(Error::TcpListenerBindFailed)?;
+            let socket_addr = listener
+                .local_addr()
+                .map_err(Error::TcpListenerLocalAddrFailed)?;
+            sockets.push((listener, socket_addr));
         }
 
-        // init flag for signaling when listener goes out of scope so we can tear down onion service
+        let service_id = V3OnionServiceId::from_private_key(private_key);
+        let authorized_clients: Vec<X25519PublicKey> = match authorized_clients {
+            Some(keys) => keys.into(),
+            None => Default::default(),
+        };
+
+        // every virt-port shares a single flag, so dropping any of the
+        // listeners tears down the whole onion service
         let is_active = Arc::new(atomic::AtomicBool::new(true));
-        self.onion_services
-            .push((onion_addr.clone(), Arc::clone(&is_active)));
+        let mut listeners: Vec<OnionListener> = Vec::with_capacity(virt_ports.len());
+        for (virt_port, (listener, socket_addr)) in virt_ports.iter().zip(sockets) {
+            match MOCK_TOR_NETWORK.lock() {
+                Ok(mut mock_tor_network) => mock_tor_network.start_onion(
+                    service_id.clone(),
+                    *virt_port,
+                    authorized_clients.clone(),
+                    socket_addr,
+                ),
+                Err(_) => {
+                    unreachable!("another thread panicked while holding mock tor network's lock")
+                }
+            }
+
+            let onion_addr = OnionAddr::V3(OnionAddrV3::new(service_id.clone(), *virt_port));
+            self.onion_services
+                .push((onion_addr.clone(), Arc::clone(&is_active)));
+            listeners.push(OnionListener::new(
+                listener,
+                onion_addr,
+                Arc::clone(&is_active),
+                |is_active| {
+                    is_active.store(false, atomic::Ordering::Relaxed);
+                },
+            ));
+        }
 
         // onion service published event
         self.events
             .push(TorEvent::OnionServicePublished { service_id });
 
+        Ok(listeners)
+    }
 
-        Ok(OnionListener::new(listener, onion_addr, is_active, |is_active| {
-            is_active.store(false, atomic::Ordering::Relaxed);
-        }))
+    fn listener_with_target(
+        &mut self,
+        private_key: &Ed25519PrivateKey,
+        virt_port: u16,
+        authorized_clients: Option<&[X25519PublicKey]>,
+        _non_anonymous: bool,
+        _pow_defenses: Option<&PowDefenses>,
+        target: OnionServiceTarget,
+    ) -> Result<OnionServiceHandle, tor_provider::Error> {
+        // the mock tor network only forwards connections to TCP sockets
+        let socket_addr = match target {
+            OnionServiceTarget::Tcp(socket_addr) => socket_addr,
+            #[cfg(unix)]
+            OnionServiceTarget::Unix(_) => return Err(Error::NotImplemented().into()),
+        };
+
+        let (onion_addr, is_active) =
+            self.start_onion_service(private_key, virt_port, authorized_clients, socket_addr);
+
+        Ok(OnionServiceHandle::new(
+            onion_addr,
+            is_active,
+            |is_active| {
+                is_active.store(false, atomic::Ordering::Relaxed);
+            },
+        ))
+    }
+
+    fn set_authorised_clients(
+        &mut self,
+        service_id: &V3OnionServiceId,
+        authorised_clients: &[X25519PublicKey],
+    ) -> Result<(), tor_provider::Error> {
+        // an onion service started by listener_with_ports() has an entry per virt-port
+        let onion_addrs: Vec<OnionAddr> = self
+            .onion_services
+            .iter()
+            .filter(|(onion_addr, is_active)| {
+                matches!(onion_addr, OnionAddr::V3(onion_addr) if onion_addr.service_id == *service_id)
+                    && is_active.load(atomic::Ordering::Relaxed)
+            })
+            .map(|(onion_addr, _)| onion_addr.clone())
+            .collect();
+        if onion_addrs.is_empty() {
+            return Err(Error::OnionServiceNotStarted(service_id.clone()).into());
+        }
+
+        match MOCK_TOR_NETWORK.lock() {
+            Ok(mut mock_tor_network) => {
+                for onion_addr in onion_addrs.iter() {
+                    mock_tor_network
+                        .set_onion_client_auth_keys(onion_addr, authorised_clients.into())?;
+                }
+            }
+            Err(_) => unreachable!("another thread panicked while holding mock tor network's lock"),
+        }
+
+        // updated onion service published event
+        self.events.push(TorEvent::OnionServicePublished {
+            service_id: service_id.clone(),
+        });
+        Ok(())
+    }
+
+    fn refresh_descriptor(
+        &mut self,
+        service_id: &V3OnionServiceId,
+    ) -> Result<(), tor_provider::Error> {
+        let is_started = self.onion_services.iter().any(|(onion_addr, is_active)| {
+            matches!(onion_addr, OnionAddr::V3(onion_addr) if onion_addr.service_id == *service_id)
+                && is_active.load(atomic::Ordering::Relaxed)
+        });
+        if !is_started {
+            return Err(Error::OnionServiceNotStarted(service_id.clone()).into());
+        }
+
+        // mock descriptors are published immediately
+        self.events.push(TorEvent::OnionServiceDescriptorRefreshed {
+            service_id: service_id.clone(),
+        });
+        Ok(())
+    }
+
+    fn fetch_descriptor(
+        &mut self,
+        service_id: &V3OnionServiceId,
+    ) -> Result<(), tor_provider::Error> {
+        if !self.bootstrapped {
+            return Err(Error::ClientNotBootstrapped().into());
+        }
+
+        let published = match MOCK_TOR_NETWORK.lock() {
+            Ok(mock_tor_network) => mock_tor_network.is_published(service_id),
+            Err(_) => unreachable!("another thread panicked while holding mock tor network's lock"),
+        };
+
+        // mock descriptors are fetched immediately
+        self.events.push(TorEvent::OnionServiceDescriptorFetched {
+            service_id: service_id.clone(),
+            hs_dir: None,
+            succeeded: published,
+            reason: (!published).then(|| "NOT_FOUND".to_string()),
+        });
+        Ok(())
+    }
+
+    fn set_descriptor_republish_interval(
+        &mut self,
+        _interval: Option<Duration>,
+    ) -> Result<(), tor_provider::Error> {
+        // mock descriptors never expire, so are never republished
+        Ok(())
+    }
+
+    fn set_security_level(
+        &mut self,
+        _security_level: SecurityLevel,
+    ) -> Result<(), tor_provider::Error> {
+        // mock circuits are never built, so every security level is trivially applied
+        Ok(())
     }
 
     fn generate_token(&mut self) -> CircuitToken {
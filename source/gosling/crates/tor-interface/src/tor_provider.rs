@@ -5,8 +5,11 @@ use std::convert::TryFrom;
 use std::io::{Read, Write};
 use std::net::{SocketAddr, TcpListener, TcpStream};
 use std::ops::{Deref, DerefMut};
+#[cfg(unix)]
+use std::path::PathBuf;
 use std::str::FromStr;
-use std::sync::OnceLock;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
 
 // extern crates
 use domain::base::name::Name;
@@ -242,6 +245,204 @@ impl std::fmt::Display for TargetAddr {
     }
 }
 
+/// The severity of a message logged by a [`TorProvider`], ordered from least to most severe.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum LogSeverity {
+    /// Verbose messages only of interest to developers.
+    Debug,
+    /// Messages which may be useful for troubleshooting.
+    Info,
+    /// Messages an operator would normally want to see.
+    Notice,
+    /// Something went wrong but tor is able to continue.
+    Warn,
+    /// Something went wrong and tor may be unable to continue.
+    Err,
+}
+
+impl FromStr for LogSeverity {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "debug" => Ok(LogSeverity::Debug),
+            "info" => Ok(LogSeverity::Info),
+            "notice" => Ok(LogSeverity::Notice),
+            "warn" => Ok(LogSeverity::Warn),
+            "err" => Ok(LogSeverity::Err),
+            _ => Err(Error::ParseFailure(s.to_string(), "LogSeverity".to_string())),
+        }
+    }
+}
+
+impl std::fmt::Display for LogSeverity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let severity = match self {
+            LogSeverity::Debug => "debug",
+            LogSeverity::Info => "info",
+            LogSeverity::Notice => "notice",
+            LogSeverity::Warn => "warn",
+            LogSeverity::Err => "err",
+        };
+        write!(f, "{}", severity)
+    }
+}
+
+/// The status of a circuit, as reported by a [`TorProvider`] in [`TorEvent::CircuitStatusChanged`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CircuitStatus {
+    /// The circuit has been assigned an id but not yet extended to its first hop.
+    Launched,
+    /// The circuit has been built and may now carry streams.
+    Built,
+    /// The circuit is waiting to see if a better guard becomes available.
+    GuardWait,
+    /// The circuit has been extended by one hop.
+    Extended,
+    /// The circuit could not be built.
+    Failed,
+    /// The circuit has been closed.
+    Closed,
+}
+
+impl FromStr for CircuitStatus {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "LAUNCHED" => Ok(CircuitStatus::Launched),
+            "BUILT" => Ok(CircuitStatus::Built),
+            "GUARD_WAIT" => Ok(CircuitStatus::GuardWait),
+            "EXTENDED" => Ok(CircuitStatus::Extended),
+            "FAILED" => Ok(CircuitStatus::Failed),
+            "CLOSED" => Ok(CircuitStatus::Closed),
+            _ => Err(Error::ParseFailure(
+                s.to_string(),
+                "CircuitStatus".to_string(),
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for CircuitStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let status = match self {
+            CircuitStatus::Launched => "LAUNCHED",
+            CircuitStatus::Built => "BUILT",
+            CircuitStatus::GuardWait => "GUARD_WAIT",
+            CircuitStatus::Extended => "EXTENDED",
+            CircuitStatus::Failed => "FAILED",
+            CircuitStatus::Closed => "CLOSED",
+        };
+        write!(f, "{}", status)
+    }
+}
+
+/// The status of a stream, as reported by a [`TorProvider`] in [`TorEvent::StreamStatusChanged`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum StreamStatus {
+    /// A new request to connect has been received.
+    New,
+    /// A new request to resolve an address has been received.
+    NewResolve,
+    /// The stream's target address has been remapped.
+    Remap,
+    /// A connect cell has been sent along a circuit.
+    SentConnect,
+    /// A resolve cell has been sent along a circuit.
+    SentResolve,
+    /// A reply has been received and the stream is established.
+    Succeeded,
+    /// The stream failed and will not be retried.
+    Failed,
+    /// The stream has been closed.
+    Closed,
+    /// The stream has been detached from its circuit and may be retried.
+    Detached,
+    /// The stream is waiting for a controller to attach it to a circuit.
+    ControllerWait,
+    /// A flow-control XOFF has been sent for the stream.
+    XoffSent,
+    /// A flow-control XOFF has been received for the stream.
+    XoffReceived,
+    /// A flow-control XON has been sent for the stream.
+    XonSent,
+    /// A flow-control XON has been received for the stream.
+    XonReceived,
+}
+
+impl FromStr for StreamStatus {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "NEW" => Ok(StreamStatus::New),
+            "NEWRESOLVE" => Ok(StreamStatus::NewResolve),
+            "REMAP" => Ok(StreamStatus::Remap),
+            "SENTCONNECT" => Ok(StreamStatus::SentConnect),
+            "SENTRESOLVE" => Ok(StreamStatus::SentResolve),
+            "SUCCEEDED" => Ok(StreamStatus::Succeeded),
+            "FAILED" => Ok(StreamStatus::Failed),
+            "CLOSED" => Ok(StreamStatus::Closed),
+            "DETACHED" => Ok(StreamStatus::Detached),
+            "CONTROLLER_WAIT" => Ok(StreamStatus::ControllerWait),
+            "XOFF_SENT" => Ok(StreamStatus::XoffSent),
+            "XOFF_RECV" => Ok(StreamStatus::XoffReceived),
+            "XON_SENT" => Ok(StreamStatus::XonSent),
+            "XON_RECV" => Ok(StreamStatus::XonReceived),
+            _ => Err(Error::ParseFailure(
+                s.to_string(),
+                "StreamStatus".to_string(),
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for StreamStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let status = match self {
+            StreamStatus::New => "NEW",
+            StreamStatus::NewResolve => "NEWRESOLVE",
+            StreamStatus::Remap => "REMAP",
+            StreamStatus::SentConnect => "SENTCONNECT",
+            StreamStatus::SentResolve => "SENTRESOLVE",
+            StreamStatus::Succeeded => "SUCCEEDED",
+            StreamStatus::Failed => "FAILED",
+            StreamStatus::Closed => "CLOSED",
+            StreamStatus::Detached => "DETACHED",
+            StreamStatus::ControllerWait => "CONTROLLER_WAIT",
+            StreamStatus::XoffSent => "XOFF_SENT",
+            StreamStatus::XoffReceived => "XOFF_RECV",
+            StreamStatus::XonSent => "XON_SENT",
+            StreamStatus::XonReceived => "XON_RECV",
+        };
+        write!(f, "{}", status)
+    }
+}
+
+/// A SOCKS request flagged by a [`TorProvider`]'s SOCKS policy as possibly revealing the user's activity outside of the Tor Network, as reported in [`TorEvent::SocksPolicyViolated`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SocksPolicyViolation {
+    /// A SOCKS request gave an IP address rather than a hostname, so the application may have resolved the hostname with a DNS request made outside of the Tor Network. Providers enforcing safe SOCKS refuse such requests.
+    UnsafeAddress {
+        /// The SOCKS protocol of the request, e.g. `SOCKS4` or `SOCKS5`.
+        protocol: String,
+        /// The requested target, as 'address:port'.
+        address: String,
+    },
+    /// A stream was requested to a port commonly used by plaintext protocols.
+    PlaintextPort {
+        /// The requested port.
+        port: u16,
+        /// Whether the stream was refused rather than only warned about.
+        rejected: bool,
+    },
+    /// A SOCKS request gave an invalid hostname.
+    InvalidHostname {
+        /// The requested hostname.
+        hostname: String,
+    },
+    /// A connection to the SOCKS port did not speak a SOCKS protocol, e.g. because an application was configured to use it as an HTTP proxy.
+    UnknownProtocol,
+}
+
 /// Various events possibly returned by a [`TorProvider`] implementation's `update()` method.
 #[derive(Debug)]
 pub enum TorEvent {
@@ -261,11 +462,96 @@ pub enum TorEvent {
         /// A message
         line: String,
     },
+    /// A structured log message which may be useful for troubleshooting.
+    ///
+    /// Providers emit this event for log messages they are able to parse, and [`TorEvent::LogReceived`] for any others.
+    Log {
+        /// The severity of the message.
+        severity: LogSeverity,
+        /// The time the message was logged, as formatted by the provider.
+        timestamp: Option<String>,
+        /// The subsystem (or 'domain') which logged the message.
+        subsystem: Option<String>,
+        /// The message itself.
+        message: String,
+    },
     /// An onion-service has been published to the Tor Network and may now be reachable by clients.
+    ///
+    /// Providers which are able to observe individual descriptor uploads may wait for a number of uploads to succeed before emitting this event; see `LegacyTorClient::set_publish_quorum()`.
     OnionServicePublished {
         /// The service-id of the onion-service which has been published.
         service_id: V3OnionServiceId,
     },
+    /// The descriptor of an already published onion-service has been uploaded again following a call to [`TorProvider::refresh_descriptor()`] or because its republish interval elapsed.
+    OnionServiceDescriptorRefreshed {
+        /// The service-id of the onion-service whose descriptor has been refreshed.
+        service_id: V3OnionServiceId,
+    },
+    /// An attempt to upload an onion-service's descriptor to a hidden-service directory has finished.
+    ///
+    /// Only emitted by providers which are able to observe individual descriptor uploads.
+    OnionServiceDescriptorUploadStatus {
+        /// The service-id of the onion-service whose descriptor was uploaded.
+        service_id: V3OnionServiceId,
+        /// The provider's identifier for the hidden-service directory, if reported.
+        hs_dir: Option<String>,
+        /// Whether the hidden-service directory accepted the descriptor.
+        succeeded: bool,
+        /// Why the upload failed, if reported.
+        reason: Option<String>,
+        /// The number of uploads of the onion-service's descriptors which have succeeded since it was started.
+        uploads_succeeded: usize,
+        /// The number of uploads of the onion-service's descriptors which have failed since it was started.
+        uploads_failed: usize,
+    },
+    /// An attempt to fetch an onion-service's descriptor requested with [`TorProvider::fetch_descriptor()`] has finished.
+    OnionServiceDescriptorFetched {
+        /// The service-id of the onion-service whose descriptor was fetched.
+        service_id: V3OnionServiceId,
+        /// The provider's identifier for the hidden-service directory, if reported.
+        hs_dir: Option<String>,
+        /// Whether the descriptor was received.
+        succeeded: bool,
+        /// Why the fetch failed, if reported, e.g. `NOT_FOUND` if the hidden-service directory answered but has no descriptor for the onion-service.
+        reason: Option<String>,
+    },
+    /// A circuit has changed status.
+    ///
+    /// Only emitted by providers which are able to observe individual circuits.
+    CircuitStatusChanged {
+        /// The provider's identifier for the circuit.
+        circuit_id: String,
+        /// The circuit's new status.
+        status: CircuitStatus,
+        /// The relays the circuit has been extended through so far, from first to last hop.
+        path: Vec<String>,
+        /// Why the circuit was built, if reported.
+        purpose: Option<String>,
+        /// Why the circuit failed or was closed, if reported.
+        reason: Option<String>,
+    },
+    /// A stream has changed status.
+    ///
+    /// Only emitted by providers which are able to observe individual streams.
+    StreamStatusChanged {
+        /// The provider's identifier for the stream.
+        stream_id: String,
+        /// The stream's new status.
+        status: StreamStatus,
+        /// The provider's identifier for the circuit the stream is attached to, or "0" if it is not attached.
+        circuit_id: String,
+        /// The stream's target, as 'address:port'.
+        target: String,
+        /// Why the stream failed, was detached, or was closed, if reported.
+        reason: Option<String>,
+    },
+    /// A SOCKS request made through the provider violated its SOCKS policy.
+    ///
+    /// Only emitted by providers which are able to observe SOCKS requests; see `LegacyTorClient::set_socks_policy()`.
+    SocksPolicyViolated {
+        /// The violation.
+        violation: SocksPolicyViolation,
+    },
 }
 
 /// A `CircuitToken` is used to specify circuits used to connect to clearnet services.
@@ -338,6 +624,93 @@ impl OnionStream {
             peer_addr: self.peer_addr.clone(),
         })
     }
+
+    /// Splits this `OnionStream` into independently owned read and write halves. Both halves share the same underlying connection, so no file descriptor is duplicated. The halves may be recombined with [`OnionStreamReadHalf::reunite()`].
+    pub fn into_split(self) -> (OnionStreamReadHalf, OnionStreamWriteHalf) {
+        let inner = Arc::new(self);
+        (
+            OnionStreamReadHalf {
+                inner: inner.clone(),
+            },
+            OnionStreamWriteHalf { inner },
+        )
+    }
+}
+
+/// The owned read half of an [`OnionStream`], created by [`OnionStream::into_split()`].
+pub struct OnionStreamReadHalf {
+    inner: Arc<OnionStream>,
+}
+
+impl Read for OnionStreamReadHalf {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+        (&self.inner.stream).read(buf)
+    }
+}
+
+impl OnionStreamReadHalf {
+    /// Returns the target address of the remote peer of this onion connection.
+    pub fn peer_addr(&self) -> Option<TargetAddr> {
+        self.inner.peer_addr()
+    }
+
+    /// Returns the onion address of the local connection for an incoming onion-service connection. Returns `None` for outgoing connections.
+    pub fn local_addr(&self) -> Option<OnionAddr> {
+        self.inner.local_addr()
+    }
+
+    /// Moves this half into or out of non-blocking mode. This affects both halves. A simple pass-through to [`std::net::TcpStream::set_nonblocking()`].
+    pub fn set_nonblocking(&self, nonblocking: bool) -> Result<(), std::io::Error> {
+        self.inner.stream.set_nonblocking(nonblocking)
+    }
+
+    /// Recombines this half with the `OnionStreamWriteHalf` it was split from. Returns both halves unchanged if they originate from different `OnionStream`s.
+    pub fn reunite(
+        self,
+        write_half: OnionStreamWriteHalf,
+    ) -> Result<OnionStream, (OnionStreamReadHalf, OnionStreamWriteHalf)> {
+        if !Arc::ptr_eq(&self.inner, &write_half.inner) {
+            return Err((self, write_half));
+        }
+        drop(write_half);
+        match Arc::try_unwrap(self.inner) {
+            Ok(stream) => Ok(stream),
+            // both halves have been consumed so this is the only reference
+            Err(_) => unreachable!(),
+        }
+    }
+}
+
+/// The owned write half of an [`OnionStream`], created by [`OnionStream::into_split()`].
+pub struct OnionStreamWriteHalf {
+    inner: Arc<OnionStream>,
+}
+
+impl Write for OnionStreamWriteHalf {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
+        (&self.inner.stream).write(buf)
+    }
+
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        (&self.inner.stream).flush()
+    }
+}
+
+impl OnionStreamWriteHalf {
+    /// Returns the target address of the remote peer of this onion connection.
+    pub fn peer_addr(&self) -> Option<TargetAddr> {
+        self.inner.peer_addr()
+    }
+
+    /// Returns the onion address of the local connection for an incoming onion-service connection. Returns `None` for outgoing connections.
+    pub fn local_addr(&self) -> Option<OnionAddr> {
+        self.inner.local_addr()
+    }
+
+    /// Shuts down the write direction of the connection; the remote peer will read EOF. A simple pass-through to [`std::net::TcpStream::shutdown()`].
+    pub fn shutdown(&self) -> Result<(), std::io::Error> {
+        self.inner.stream.shutdown(std::net::Shutdown::Write)
+    }
 }
 
 //
@@ -385,6 +758,11 @@ impl OnionListener {
         self.listener.set_nonblocking(nonblocking)
     }
 
+    /// Returns the onion address of the listener's onion-service and virt-port.
+    pub fn onion_addr(&self) -> &OnionAddr {
+        &self.onion_addr
+    }
+
     /// Accept a new incoming connection from this listener.
     pub fn accept(&self) -> Result<Option<OnionStream>, std::io::Error> {
         match self.listener.accept() {
@@ -412,6 +790,125 @@ impl Drop for OnionListener {
     }
 }
 
+//
+// Onion Service Target
+//
+
+/// The local socket an onion-service started with [`TorProvider::listener_with_target()`] forwards its incoming connections to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum OnionServiceTarget {
+    /// A TCP socket address, typically the [`std::net::TcpListener::local_addr()`] of an application-provided listener
+    Tcp(SocketAddr),
+    /// The path of an application-provided unix domain socket
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl std::fmt::Display for OnionServiceTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            OnionServiceTarget::Tcp(socket_addr) => write!(f, "{}", socket_addr),
+            #[cfg(unix)]
+            OnionServiceTarget::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Keeps an onion-service started with [`TorProvider::listener_with_target()`] running; the onion-service is stopped when its `OnionServiceHandle` is dropped.
+pub struct OnionServiceHandle {
+    onion_addr: OnionAddr,
+    drop: Option<Box<dyn FnOnce() + Send>>,
+}
+
+impl OnionServiceHandle {
+    // The `data` and `drop` parameters allow `TorProvider` implementations their
+    // own data and cleanup procedures, as with `OnionListener::new()`
+    #[cfg(any(
+        feature = "arti-client-tor-provider",
+        feature = "legacy-tor-provider",
+        feature = "mock-tor-provider"
+    ))]
+    pub(crate) fn new<T: 'static + Send>(
+        onion_addr: OnionAddr,
+        data: T,
+        drop: impl FnOnce(T) + 'static + Send,
+    ) -> Self {
+        Self {
+            onion_addr,
+            drop: Some(Box::new(move || drop(data))),
+        }
+    }
+
+    /// Returns the onion address of the onion-service.
+    pub fn onion_addr(&self) -> &OnionAddr {
+        &self.onion_addr
+    }
+}
+
+impl Drop for OnionServiceHandle {
+    fn drop(&mut self) {
+        if let Some(drop) = self.drop.take() {
+            drop()
+        }
+    }
+}
+
+/// The proof-of-work denial-of-service defenses of an onion-service started by [`TorProvider::listener()`].
+///
+/// When enabled, clients must solve a proof-of-work puzzle published in the onion-service's descriptor before their introduction requests are handled, and introduction requests are prioritised by the effort spent solving it. The queue parameters limit the rate at which introduction requests are dequeued for handling; if `None` the tor daemon's defaults are used. For further information, see the Tor Project's [proof-of-work documentation](https://community.torproject.org/onion-services/advanced/dos/).
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PowDefenses {
+    /// The rate at which introduction requests are dequeued, in requests per second
+    pub queue_rate: Option<u32>,
+    /// The number of introduction requests which may be dequeued in a single burst
+    pub queue_burst: Option<u32>,
+}
+
+/// A simplified choice of the circuit-hardening options relevant to onion-service operators, applied with [`TorProvider::set_security_level()`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum SecurityLevel {
+    /// The tor provider's default circuit-building behaviour.
+    #[default]
+    Standard,
+    /// Onion-service circuits always use vanguards-lite (restricted second-hop guards) and a single entry guard, making guard-discovery attacks against long-running onion-services more expensive at some cost to circuit diversity.
+    Hardened,
+}
+
+/// The configuration of one of the onion-services started by [`TorProvider::listeners()`]. The members have the same meaning as the parameters of [`TorProvider::listener()`].
+#[derive(Clone)]
+pub struct ListenerConfig {
+    /// The onion-service's ed25519 private key
+    pub private_key: Ed25519PrivateKey,
+    /// The onion-service's virt-port
+    pub virt_port: u16,
+    /// The client authorisation keys required to connect to the onion-service, if any
+    pub authorised_clients: Option<Vec<X25519PublicKey>>,
+    /// Whether the onion-service is a non-anonymous single onion-service
+    pub non_anonymous: bool,
+    /// The onion-service's proof-of-work defenses, if enabled
+    pub pow_defenses: Option<PowDefenses>,
+}
+
+// an onion-service started by TorProvider::listener_with_ports() needs at
+// least one virt-port, and each virt-port may only be forwarded to one listener
+#[cfg(any(feature = "legacy-tor-provider", feature = "mock-tor-provider"))]
+pub(crate) fn ensure_virt_ports_distinct(virt_ports: &[u16]) -> Result<(), Error> {
+    if virt_ports.is_empty() {
+        return Err(Error::Generic(
+            "onion-service requires at least one virt-port".to_string(),
+        ));
+    }
+    for (i, virt_port) in virt_ports.iter().enumerate() {
+        if virt_ports[..i].contains(virt_port) {
+            return Err(Error::Generic(format!(
+                "onion-service virt-port {} specified more than once",
+                virt_port
+            )));
+        }
+    }
+    Ok(())
+}
+
 /// The `TorProvider` trait allows for high-level Tor Network functionality. Implementations ay connect to the Tor Network, anonymously connect to both clearnet and onion-service endpoints, and host onion-services.
 pub trait TorProvider: Send {
     /// Process and return `TorEvent`s handled by this `TorProvider`.
@@ -441,12 +938,124 @@ pub trait TorProvider: Send {
     /// Anonymously start an onion-service and return the associated [`OnionListener`].
     ///
     ///The resulting onion-service will not be reachable by clients until [`TorProvider::update()`] returns a [`TorEvent::OnionServicePublished`] event. The optional `authorised_clients` parameter may be used to require client authorisation keys to connect to resulting onion-service. For further information, see the Tor Project's onion-services [client-auth documentation](https://community.torproject.org/onion-services/advanced/client-auth).
+    ///
+    /// If `non_anonymous` is `true`, the onion-service is started as a non-anonymous single onion-service: the service's location is *not* hidden, in exchange for lower-latency connections. Implementations return an error if the underlying tor daemon is not configured to host single onion-services.
+    ///
+    /// If `pow_defenses` is `Some`, the onion-service is started with the given [`PowDefenses`] enabled. Implementations return an error if the underlying tor daemon does not support proof-of-work defenses.
     fn listener(
         &mut self,
         private_key: &Ed25519PrivateKey,
         virt_port: u16,
         authorised_clients: Option<&[X25519PublicKey]>,
+        non_anonymous: bool,
+        pow_defenses: Option<&PowDefenses>,
     ) -> Result<OnionListener, Error>;
+    /// Anonymously start several onion-services at once, as if by calling [`TorProvider::listener()`] for each of `configs`, and return each onion-service's [`OnionListener`] or the reason it could not be started in the same order as `configs`. A failure to start one onion-service does not prevent the others from starting.
+    ///
+    /// Implementations may start the onion-services more efficiently than individually, e.g. by pipelining their control-port commands. The default implementation calls [`TorProvider::listener()`] for each onion-service in turn.
+    fn listeners(&mut self, configs: &[ListenerConfig]) -> Vec<Result<OnionListener, Error>> {
+        configs
+            .iter()
+            .map(|config| {
+                self.listener(
+                    &config.private_key,
+                    config.virt_port,
+                    config.authorised_clients.as_deref(),
+                    config.non_anonymous,
+                    config.pow_defenses.as_ref(),
+                )
+            })
+            .collect()
+    }
+    /// Anonymously start an onion-service reachable on each of the distinct `virt_ports` and return an [`OnionListener`] for each virt-port, in the same order as `virt_ports`. The virt-ports share the onion-service's descriptor, so clients need only look up a single onion-service to connect to any of them.
+    ///
+    /// The onion-service is otherwise started as with [`TorProvider::listener()`], and is stopped once any of the returned listeners is dropped. The default implementation only supports a single virt-port and returns an error otherwise.
+    fn listener_with_ports(
+        &mut self,
+        private_key: &Ed25519PrivateKey,
+        virt_ports: &[u16],
+        authorised_clients: Option<&[X25519PublicKey]>,
+        non_anonymous: bool,
+        pow_defenses: Option<&PowDefenses>,
+    ) -> Result<Vec<OnionListener>, Error> {
+        match virt_ports {
+            [virt_port] => Ok(vec![self.listener(
+                private_key,
+                *virt_port,
+                authorised_clients,
+                non_anonymous,
+                pow_defenses,
+            )?]),
+            virt_ports => Err(Error::Generic(format!(
+                "onion-services with {} virt-ports not supported",
+                virt_ports.len()
+            ))),
+        }
+    }
+    /// Anonymously start an onion-service whose incoming connections are forwarded to an application-provided `target` rather than an [`OnionListener`], so that onion traffic may be routed into existing server infrastructure (e.g. an in-process HTTP server). The application accepts connections on its own socket, and the onion-service is stopped when the returned [`OnionServiceHandle`] is dropped.
+    ///
+    /// The onion-service is otherwise started as with [`TorProvider::listener()`]. Implementations return an error for target types they do not support.
+    fn listener_with_target(
+        &mut self,
+        private_key: &Ed25519PrivateKey,
+        virt_port: u16,
+        authorised_clients: Option<&[X25519PublicKey]>,
+        non_anonymous: bool,
+        pow_defenses: Option<&PowDefenses>,
+        target: OnionServiceTarget,
+    ) -> Result<OnionServiceHandle, Error>;
+    /// Replace the client authorisation keys of an onion-service started with [`TorProvider::listener()`] or [`TorProvider::listener_with_target()`] without closing its [`OnionListener`]. Clients whose keys are no longer authorised cannot decrypt the onion-service's descriptor once the updated descriptor is published, which is signalled by another [`TorEvent::OnionServicePublished`] event. An empty `authorised_clients` list disables client authorisation.
+    ///
+    /// Implementations return an error if the onion-service's client authorisation keys cannot be updated while it is running.
+    fn set_authorised_clients(
+        &mut self,
+        service_id: &V3OnionServiceId,
+        authorised_clients: &[X25519PublicKey],
+    ) -> Result<(), Error>;
+    /// Force a running onion-service started with [`TorProvider::listener()`] or [`TorProvider::listener_with_target()`] to build and upload a fresh descriptor without closing its [`OnionListener`], e.g. after its keys or the keys of its authorised clients have changed out-of-band. [`TorProvider::update()`] returns a [`TorEvent::OnionServiceDescriptorRefreshed`] event once the refreshed descriptor has been published.
+    ///
+    /// The default implementation returns an error for providers which cannot refresh descriptors on demand.
+    fn refresh_descriptor(&mut self, service_id: &V3OnionServiceId) -> Result<(), Error> {
+        Err(Error::Generic(format!(
+            "refreshing the descriptor of onion-service {} not supported",
+            service_id
+        )))
+    }
+    /// Fetch an onion-service's descriptor from the hidden-service directories, bypassing any cached copy. [`TorProvider::update()`] returns a [`TorEvent::OnionServiceDescriptorFetched`] event once the fetch has finished. Fetching a descriptor exercises the provider's circuits to the Tor Network without connecting to the onion-service, so it may be used to probe connectivity.
+    ///
+    /// The default implementation returns an error for providers which cannot fetch descriptors on demand.
+    fn fetch_descriptor(&mut self, service_id: &V3OnionServiceId) -> Result<(), Error> {
+        Err(Error::Generic(format!(
+            "fetching the descriptor of onion-service {} not supported",
+            service_id
+        )))
+    }
+    /// Refresh the descriptors of running onion-services, as if by [`TorProvider::refresh_descriptor()`], once `interval` has passed since each was last published. With `None` descriptors are only republished on the tor daemon's own schedule, which is the default.
+    ///
+    /// Onion-service descriptors are valid for several hours, so intervals shorter than a few minutes only add load to the hidden-service directories. The default implementation only supports `None`.
+    fn set_descriptor_republish_interval(
+        &mut self,
+        interval: Option<Duration>,
+    ) -> Result<(), Error> {
+        match interval {
+            None => Ok(()),
+            Some(_) => Err(Error::Generic(
+                "descriptor republish interval not supported".to_string(),
+            )),
+        }
+    }
+    /// Apply the circuit-hardening options of a [`SecurityLevel`] to circuits built after this call.
+    ///
+    /// The default implementation only supports [`SecurityLevel::Standard`].
+    fn set_security_level(&mut self, security_level: SecurityLevel) -> Result<(), Error> {
+        match security_level {
+            SecurityLevel::Standard => Ok(()),
+            security_level => Err(Error::Generic(format!(
+                "security level {:?} not supported",
+                security_level
+            ))),
+        }
+    }
     /// Create a new [`CircuitToken`].
     fn generate_token(&mut self) -> CircuitToken;
     /// Releaes a previously generated [`CircuitToken`].
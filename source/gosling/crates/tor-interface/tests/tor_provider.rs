@@ -52,6 +52,12 @@ pub(crate) fn bootstrap_test(mut tor: Box<dyn TorProvider>) -> anyhow::Result<()
                     received_log = true;
                     println!("--- {}", line);
                 }
+                TorEvent::Log {
+                    severity, message, ..
+                } => {
+                    received_log = true;
+                    println!("--- [{}] {}", severity, message);
+                }
                 _ => {}
             }
         }
@@ -128,7 +134,7 @@ pub(crate) fn basic_onion_service_test(
 
         println!("Starting and listening to onion service");
         const VIRT_PORT: u16 = 42069u16;
-        let listener = tor.listener(&private_key, VIRT_PORT, None)?;
+        let listener = tor.listener(&private_key, VIRT_PORT, None, false, None)?;
 
         let mut onion_published = false;
         while !onion_published {
@@ -255,8 +261,13 @@ pub(crate) fn authenticated_onion_service_test(
 
         println!("Starting and listening to authenticated onion service");
         const VIRT_PORT: u16 = 42069u16;
-        let listener =
-            server_provider.listener(&private_key, VIRT_PORT, Some(&[public_auth_key]))?;
+        let listener = server_provider.listener(
+            &private_key,
+            VIRT_PORT,
+            Some(&[public_auth_key]),
+            false,
+            None,
+        )?;
 
         let mut onion_published = false;
         while !onion_published {
@@ -324,6 +335,91 @@ pub(crate) fn authenticated_onion_service_test(
     Ok(())
 }
 
+#[allow(dead_code)]
+pub(crate) fn descriptor_fetch_test(mut tor: Box<dyn TorProvider>) -> anyhow::Result<()> {
+    tor.bootstrap()?;
+
+    let mut bootstrap_complete = false;
+    while !bootstrap_complete {
+        for event in tor.update()?.iter() {
+            match event {
+                TorEvent::BootstrapComplete => {
+                    println!("Bootstrap Complete!");
+                    bootstrap_complete = true;
+                }
+                TorEvent::LogReceived { line } => {
+                    println!("--- {}", line);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // wait for the outcome of fetching the given service's descriptor
+    fn fetch(
+        tor: &mut Box<dyn TorProvider>,
+        expected_service_id: &V3OnionServiceId,
+    ) -> anyhow::Result<(bool, Option<String>)> {
+        tor.fetch_descriptor(expected_service_id)?;
+        loop {
+            for event in tor.update()?.drain(..) {
+                match event {
+                    TorEvent::LogReceived { line } => {
+                        println!("--- {}", line);
+                    }
+                    TorEvent::OnionServiceDescriptorFetched {
+                        service_id,
+                        hs_dir,
+                        succeeded,
+                        reason,
+                    } if service_id == *expected_service_id => {
+                        println!(
+                            "Descriptor of {} fetched from {:?}: succeeded: {}, reason: {:?}",
+                            service_id, hs_dir, succeeded, reason
+                        );
+                        return Ok((succeeded, reason));
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    println!("Fetching the descriptor of an unpublished onion service");
+    let private_key = Ed25519PrivateKey::generate();
+    let service_id = V3OnionServiceId::from_private_key(&private_key);
+    let (succeeded, _) = fetch(&mut tor, &service_id)?;
+    assert!(!succeeded, "unpublished descriptor should not be fetched");
+
+    println!("Starting and listening to onion service");
+    const VIRT_PORT: u16 = 42069u16;
+    let _listener = tor.listener(&private_key, VIRT_PORT, None, false, None)?;
+    let mut onion_published = false;
+    while !onion_published {
+        for event in tor.update()?.iter() {
+            match event {
+                TorEvent::LogReceived { line } => {
+                    println!("--- {}", line);
+                }
+                TorEvent::OnionServicePublished {
+                    service_id: published_service_id,
+                } if *published_service_id == service_id => {
+                    println!("Onion Service {} published", service_id);
+                    onion_published = true;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    println!("Fetching the descriptor of the published onion service");
+    let (succeeded, reason) = fetch(&mut tor, &service_id)?;
+    assert!(succeeded, "published descriptor should be fetched");
+    assert!(reason.is_none());
+
+    Ok(())
+}
+
 //
 // Mock TorProvider tests
 //
@@ -334,6 +430,12 @@ fn test_mock_bootstrap() -> anyhow::Result<()> {
     bootstrap_test(Box::new(MockTorClient::new()))
 }
 
+#[test]
+#[cfg(feature = "mock-tor-provider")]
+fn test_mock_descriptor_fetch() -> anyhow::Result<()> {
+    descriptor_fetch_test(Box::new(MockTorClient::new()))
+}
+
 #[test]
 #[cfg(feature = "mock-tor-provider")]
 fn test_mock_onion_service() -> anyhow::Result<()> {
@@ -350,6 +452,163 @@ fn test_mock_authenticated_onion_service() -> anyhow::Result<()> {
     authenticated_onion_service_test(server_provider, client_provider)
 }
 
+#[test]
+#[cfg(feature = "mock-tor-provider")]
+fn test_mock_onion_stream_split() -> anyhow::Result<()> {
+    let mut tor = MockTorClient::new();
+    tor.bootstrap()?;
+
+    let private_key = Ed25519PrivateKey::generate();
+    let service_id = V3OnionServiceId::from_private_key(&private_key);
+    const VIRT_PORT: u16 = 42069u16;
+    let listener = tor.listener(&private_key, VIRT_PORT, None, false, None)?;
+
+    let client = tor.connect((service_id.clone(), VIRT_PORT).into(), None)?;
+    let server = match listener.accept()? {
+        Some(server) => server,
+        None => panic!("no listener"),
+    };
+
+    // each half keeps the connection's addresses and may be moved independently
+    let (mut client_reader, mut client_writer) = client.into_split();
+    let (mut server_reader, mut server_writer) = server.into_split();
+    assert!(client_writer.local_addr().is_none());
+    assert!(server_reader.local_addr().is_some());
+
+    const PING: &str = "ping";
+    const PONG: &str = "pong";
+
+    let writer = std::thread::spawn(move || -> std::io::Result<OnionStreamWriteHalf> {
+        client_writer.write_all(PING.as_bytes())?;
+        client_writer.flush()?;
+        Ok(client_writer)
+    });
+    let mut buffer = [0u8; PING.len()];
+    server_reader.read_exact(&mut buffer)?;
+    assert_eq!(PING.as_bytes(), buffer);
+
+    server_writer.write_all(PONG.as_bytes())?;
+    server_writer.flush()?;
+    let mut buffer = [0u8; PONG.len()];
+    client_reader.read_exact(&mut buffer)?;
+    assert_eq!(PONG.as_bytes(), buffer);
+
+    // halves from different streams must not be reunited
+    let client_writer = match writer.join() {
+        Ok(client_writer) => client_writer?,
+        Err(_) => panic!("writer thread panicked"),
+    };
+    let (client_reader, server_writer) = match client_reader.reunite(server_writer) {
+        Ok(_) => panic!("reunited halves of different streams"),
+        Err(halves) => halves,
+    };
+    assert!(server_reader.reunite(server_writer).is_ok());
+    let client = match client_reader.reunite(client_writer) {
+        Ok(client) => client,
+        Err(_) => panic!("failed to reunite client halves"),
+    };
+    assert!(client.local_addr().is_none());
+
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "mock-tor-provider")]
+fn test_mock_onion_service_with_target() -> anyhow::Result<()> {
+    let mut tor = MockTorClient::new();
+    tor.bootstrap()?;
+
+    // the application's own listening socket receives the onion-service's connections
+    let app_listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let target = OnionServiceTarget::Tcp(app_listener.local_addr()?);
+
+    let private_key = Ed25519PrivateKey::generate();
+    let service_id = V3OnionServiceId::from_private_key(&private_key);
+    const VIRT_PORT: u16 = 42069u16;
+    let onion_service =
+        tor.listener_with_target(&private_key, VIRT_PORT, None, false, None, target)?;
+    assert_eq!(
+        *onion_service.onion_addr(),
+        OnionAddr::V3(OnionAddrV3::new(service_id.clone(), VIRT_PORT))
+    );
+
+    let mut client = tor.connect((service_id.clone(), VIRT_PORT).into(), None)?;
+    let (mut server, _socket_addr) = app_listener.accept()?;
+
+    const PING: &str = "ping";
+    client.write_all(PING.as_bytes())?;
+    client.flush()?;
+    let mut buffer = [0u8; PING.len()];
+    server.read_exact(&mut buffer)?;
+    assert_eq!(PING.as_bytes(), buffer);
+
+    // the onion-service is stopped once its handle is dropped
+    drop(onion_service);
+    tor.update()?;
+    assert!(tor
+        .connect((service_id.clone(), VIRT_PORT).into(), None)
+        .is_err());
+
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "mock-tor-provider")]
+fn test_mock_onion_service_with_ports() -> anyhow::Result<()> {
+    let mut tor = MockTorClient::new();
+    tor.bootstrap()?;
+
+    let private_key = Ed25519PrivateKey::generate();
+    let service_id = V3OnionServiceId::from_private_key(&private_key);
+    const VIRT_PORTS: [u16; 2] = [42069u16, 42070u16];
+    let listeners = tor.listener_with_ports(&private_key, &VIRT_PORTS, None, false, None)?;
+    assert_eq!(listeners.len(), VIRT_PORTS.len());
+
+    // each virt-port's connections are accepted by its own listener
+    for (listener, virt_port) in listeners.iter().zip(VIRT_PORTS) {
+        assert_eq!(
+            *listener.onion_addr(),
+            OnionAddr::V3(OnionAddrV3::new(service_id.clone(), virt_port))
+        );
+
+        let mut client = tor.connect((service_id.clone(), virt_port).into(), None)?;
+        let mut server = match listener.accept()? {
+            Some(server) => server,
+            None => panic!("no connection on virt-port {}", virt_port),
+        };
+
+        const PING: &str = "ping";
+        client.write_all(PING.as_bytes())?;
+        client.flush()?;
+        let mut buffer = [0u8; PING.len()];
+        server.read_exact(&mut buffer)?;
+        assert_eq!(PING.as_bytes(), buffer);
+    }
+
+    // duplicate virt-ports are rejected
+    assert!(tor
+        .listener_with_ports(
+            &private_key,
+            &[VIRT_PORTS[0], VIRT_PORTS[0]],
+            None,
+            false,
+            None
+        )
+        .is_err());
+
+    // dropping any listener stops the whole onion-service
+    let mut listeners = listeners;
+    listeners.truncate(1);
+    tor.update()?;
+    for virt_port in VIRT_PORTS {
+        assert!(tor
+            .connect((service_id.clone(), virt_port).into(), None)
+            .is_err());
+    }
+
+    Ok(())
+}
+
 //
 // Legacy TorProvider tests
 //
@@ -369,6 +628,10 @@ fn test_legacy_bootstrap() -> anyhow::Result<()> {
         allowed_ports: None,
         pluggable_transports: None,
         bridge_lines: None,
+        sandbox: None,
+        security_config: None,
+        socks_policy: None,
+        shared_network_cache: None,
     };
 
     bootstrap_test(Box::new(LegacyTorClient::new(tor_config)?))
@@ -408,6 +671,10 @@ fn test_legacy_pluggable_transport_bootstrap() -> anyhow::Result<()> {
         allowed_ports: None,
         pluggable_transports: Some(vec![pluggable_transport]),
         bridge_lines: Some(vec![bridge_line]),
+        sandbox: None,
+        security_config: None,
+        socks_policy: None,
+        shared_network_cache: None,
     };
 
     bootstrap_test(Box::new(LegacyTorClient::new(tor_config)?))
@@ -428,6 +695,10 @@ fn test_legacy_onion_service() -> anyhow::Result<()> {
         allowed_ports: None,
         pluggable_transports: None,
         bridge_lines: None,
+        sandbox: None,
+        security_config: None,
+        socks_policy: None,
+        shared_network_cache: None,
     };
     let server_provider = Box::new(LegacyTorClient::new(tor_config)?);
 
@@ -440,12 +711,39 @@ fn test_legacy_onion_service() -> anyhow::Result<()> {
         allowed_ports: None,
         pluggable_transports: None,
         bridge_lines: None,
+        sandbox: None,
+        security_config: None,
+        socks_policy: None,
+        shared_network_cache: None,
     };
     let client_provider = Box::new(LegacyTorClient::new(tor_config)?);
 
     basic_onion_service_test(server_provider, client_provider)
 }
 
+#[test]
+#[serial]
+#[cfg(feature = "legacy-tor-provider")]
+fn test_legacy_descriptor_fetch() -> anyhow::Result<()> {
+    let tor_path = which::which(format!("tor{}", std::env::consts::EXE_SUFFIX))?;
+    let mut data_path = std::env::temp_dir();
+    data_path.push("test_legacy_descriptor_fetch");
+    let tor_config = LegacyTorClientConfig::BundledTor {
+        tor_bin_path: tor_path,
+        data_directory: data_path,
+        proxy_settings: None,
+        allowed_ports: None,
+        pluggable_transports: None,
+        bridge_lines: None,
+        sandbox: None,
+        security_config: None,
+        socks_policy: None,
+        shared_network_cache: None,
+    };
+
+    descriptor_fetch_test(Box::new(LegacyTorClient::new(tor_config)?))
+}
+
 #[test]
 #[serial]
 #[cfg(feature = "legacy-tor-provider")]
@@ -461,6 +759,10 @@ fn test_legacy_authenticated_onion_service() -> anyhow::Result<()> {
         allowed_ports: None,
         pluggable_transports: None,
         bridge_lines: None,
+        sandbox: None,
+        security_config: None,
+        socks_policy: None,
+        shared_network_cache: None,
     };
     let server_provider = Box::new(LegacyTorClient::new(tor_config)?);
 
@@ -473,12 +775,134 @@ fn test_legacy_authenticated_onion_service() -> anyhow::Result<()> {
         allowed_ports: None,
         pluggable_transports: None,
         bridge_lines: None,
+        sandbox: None,
+        security_config: None,
+        socks_policy: None,
+        shared_network_cache: None,
     };
     let client_provider = Box::new(LegacyTorClient::new(tor_config)?);
 
     authenticated_onion_service_test(server_provider, client_provider)
 }
 
+#[test]
+#[serial]
+#[cfg(feature = "legacy-tor-provider")]
+fn test_legacy_security_level() -> anyhow::Result<()> {
+    let tor_path = which::which(format!("tor{}", std::env::consts::EXE_SUFFIX))?;
+    let mut data_path = std::env::temp_dir();
+    data_path.push("test_legacy_security_level");
+
+    let tor_config = LegacyTorClientConfig::BundledTor {
+        tor_bin_path: tor_path,
+        data_directory: data_path,
+        proxy_settings: None,
+        allowed_ports: None,
+        pluggable_transports: None,
+        bridge_lines: None,
+        sandbox: None,
+        security_config: Some(Box::new(LegacyTorSecurityConfig {
+            vanguards_lite: None,
+            num_entry_guards: Some(2),
+            long_lived_ports: Some(vec![9001, 9030]),
+        })),
+        socks_policy: None,
+        shared_network_cache: None,
+    };
+    let mut tor = LegacyTorClient::new(tor_config)?;
+
+    assert_eq!(
+        LegacyTorSecurityConfig::from(SecurityLevel::Standard),
+        LegacyTorSecurityConfig::default()
+    );
+    tor.set_security_level(SecurityLevel::Hardened)?;
+    tor.set_security_level(SecurityLevel::Standard)?;
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+#[cfg(feature = "legacy-tor-provider")]
+fn test_legacy_socks_policy() -> anyhow::Result<()> {
+    let tor_path = which::which(format!("tor{}", std::env::consts::EXE_SUFFIX))?;
+    let mut data_path = std::env::temp_dir();
+    data_path.push("test_legacy_socks_policy");
+
+    let tor_config = LegacyTorClientConfig::BundledTor {
+        tor_bin_path: tor_path,
+        data_directory: data_path,
+        proxy_settings: None,
+        allowed_ports: None,
+        pluggable_transports: None,
+        bridge_lines: None,
+        sandbox: None,
+        security_config: None,
+        socks_policy: Some(Box::new(LegacyTorSocksPolicy {
+            safe_socks: true,
+            test_socks: true,
+            warn_plaintext_ports: Some(vec![21, 23]),
+            reject_plaintext_ports: vec![110, 143],
+        })),
+        shared_network_cache: None,
+    };
+    let mut tor = LegacyTorClient::new(tor_config)?;
+
+    // the tor daemon's defaults may be restored
+    tor.set_socks_policy(&LegacyTorSocksPolicy::default())?;
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+#[cfg(feature = "legacy-tor-provider")]
+fn test_legacy_shared_network_cache() -> anyhow::Result<()> {
+    let tor_path = which::which(format!("tor{}", std::env::consts::EXE_SUFFIX))?;
+    let mut cache_path = std::env::temp_dir();
+    cache_path.push("test_legacy_shared_network_cache");
+    let _ = std::fs::remove_dir_all(&cache_path);
+
+    // each daemon bootstraps with its own data directory but shares the cache
+    for name in ["first", "second"] {
+        let mut data_path = std::env::temp_dir();
+        data_path.push(format!("test_legacy_shared_network_cache_{}", name));
+        let tor_config = LegacyTorClientConfig::BundledTor {
+            tor_bin_path: tor_path.clone(),
+            data_directory: data_path,
+            proxy_settings: None,
+            allowed_ports: None,
+            pluggable_transports: None,
+            bridge_lines: None,
+            sandbox: None,
+            security_config: None,
+            socks_policy: None,
+            shared_network_cache: Some(cache_path.clone()),
+        };
+        bootstrap_test(Box::new(LegacyTorClient::new(tor_config)?))?;
+
+        // the consensus is published once bootstrapped
+        assert!(cache_path.join("cached-microdesc-consensus").is_file());
+    }
+
+    // the shared network cache must be an absolute path
+    let tor_config = LegacyTorClientConfig::BundledTor {
+        tor_bin_path: tor_path,
+        data_directory: std::env::temp_dir().join("test_legacy_shared_network_cache_relative"),
+        proxy_settings: None,
+        allowed_ports: None,
+        pluggable_transports: None,
+        bridge_lines: None,
+        sandbox: None,
+        security_config: None,
+        socks_policy: None,
+        shared_network_cache: Some(std::path::PathBuf::from("relative")),
+    };
+    assert!(LegacyTorClient::new(tor_config).is_err());
+
+    Ok(())
+}
+
 //
 // System Legacy TorProvider tests
 //
@@ -701,6 +1125,10 @@ fn test_mixed_arti_client_legacy_onion_service() -> anyhow::Result<()> {
         allowed_ports: None,
         pluggable_transports: None,
         bridge_lines: None,
+        sandbox: None,
+        security_config: None,
+        socks_policy: None,
+        shared_network_cache: None,
     };
     let client_provider = Box::new(LegacyTorClient::new(tor_config)?);
 
@@ -722,6 +1150,10 @@ fn test_mixed_legacy_arti_client_onion_service() -> anyhow::Result<()> {
         allowed_ports: None,
         pluggable_transports: None,
         bridge_lines: None,
+        sandbox: None,
+        security_config: None,
+        socks_policy: None,
+        shared_network_cache: None,
     };
     let server_provider = Box::new(LegacyTorClient::new(tor_config)?);
 
@@ -513,3 +513,106 @@ TEST_CASE("gosling_v3_onion_service_id_to_string") {
       throw_on_error()));
   REQUIRE(std::string(serviceIdStringRaw) == serviceIdString);
 }
+
+TEST_CASE("gosling_v3_onion_service_id_from_ed25519_public_key") {
+  unique_ptr<gosling_library> library;
+  REQUIRE_NOTHROW(::gosling_library_init(out(library), throw_on_error()));
+
+  const std::string privateKeyBlob =
+      "ED25519-V3:rP3u8mZaKohap0lKsB8Z8qXbXqK456JKKGONDBh"
+      "V+gPBVKa2mHVQqnRTVuFXe3inU3YW6qvc7glYEwe9rK0LhQ==";
+  const std::string serviceIdString =
+      "6l62fw7tqctlu5fesdqukvpoxezkaxbzllrafa2ve6ewuhzphxczsjyd";
+
+  unique_ptr<gosling_ed25519_private_key> privateKey;
+  REQUIRE_NOTHROW(::gosling_ed25519_private_key_from_keyblob(
+      out(privateKey), privateKeyBlob.data(), privateKeyBlob.size(),
+      throw_on_error()));
+
+  // golden path
+  unique_ptr<gosling_ed25519_public_key> publicKey;
+  REQUIRE_NOTHROW(::gosling_ed25519_public_key_from_ed25519_private_key(
+      out(publicKey), privateKey.get(), throw_on_error()));
+
+  unique_ptr<gosling_v3_onion_service_id> serviceId;
+  REQUIRE_NOTHROW(::gosling_v3_onion_service_id_from_ed25519_public_key(
+      out(serviceId), publicKey.get(), throw_on_error()));
+
+  char serviceIdStringRaw[V3_ONION_SERVICE_ID_STRING_SIZE] = {0};
+  REQUIRE_NOTHROW(::gosling_v3_onion_service_id_to_string(
+      serviceId.get(), serviceIdStringRaw, sizeof(serviceIdStringRaw),
+      throw_on_error()));
+  REQUIRE(serviceIdString == serviceIdStringRaw);
+
+  // the public key round-trips through the service id and raw bytes
+  unique_ptr<gosling_ed25519_public_key> serviceIdPublicKey;
+  REQUIRE_NOTHROW(::gosling_ed25519_public_key_from_v3_onion_service_id(
+      out(serviceIdPublicKey), serviceId.get(), throw_on_error()));
+  REQUIRE(::gosling_ed25519_public_key_equals(
+      publicKey.get(), serviceIdPublicKey.get(), throw_on_error()));
+
+  uint8_t publicKeyRaw[ED25519_PUBLIC_KEY_SIZE] = {0};
+  REQUIRE_NOTHROW(::gosling_ed25519_public_key_to_raw(
+      publicKey.get(), publicKeyRaw, sizeof(publicKeyRaw), throw_on_error()));
+  unique_ptr<gosling_ed25519_public_key> rawPublicKey;
+  REQUIRE_NOTHROW(::gosling_ed25519_public_key_from_raw(
+      out(rawPublicKey), publicKeyRaw, sizeof(publicKeyRaw), throw_on_error()));
+  REQUIRE(::gosling_ed25519_public_key_equals(
+      publicKey.get(), rawPublicKey.get(), throw_on_error()));
+
+  // invalid inputs
+  REQUIRE_THROWS(::gosling_v3_onion_service_id_from_ed25519_public_key(
+      nullptr, nullptr, throw_on_error()));
+  REQUIRE_THROWS(::gosling_v3_onion_service_id_from_ed25519_public_key(
+      out(serviceId), nullptr, throw_on_error()));
+  REQUIRE_THROWS(::gosling_v3_onion_service_id_from_ed25519_public_key(
+      nullptr, publicKey.get(), throw_on_error()));
+  REQUIRE_THROWS(::gosling_ed25519_public_key_from_raw(
+      out(rawPublicKey), publicKeyRaw, sizeof(publicKeyRaw) - 1,
+      throw_on_error()));
+  REQUIRE_THROWS(::gosling_ed25519_public_key_to_raw(
+      publicKey.get(), publicKeyRaw, sizeof(publicKeyRaw) - 1,
+      throw_on_error()));
+}
+
+TEST_CASE("gosling_v3_onion_service_id_compare") {
+  unique_ptr<gosling_library> library;
+  REQUIRE_NOTHROW(::gosling_library_init(out(library), throw_on_error()));
+
+  const std::string lowServiceIdString =
+      "6l62fw7tqctlu5fesdqukvpoxezkaxbzllrafa2ve6ewuhzphxczsjyd";
+  const std::string highServiceIdString =
+      "vww6ybal4bd7szmgncyruucpgfkqahzddi37ktceo3ah7ngmcopnpyyd";
+
+  unique_ptr<gosling_v3_onion_service_id> lowServiceId;
+  REQUIRE_NOTHROW(::gosling_v3_onion_service_id_from_string(
+      out(lowServiceId), lowServiceIdString.c_str(), lowServiceIdString.size(),
+      throw_on_error()));
+  unique_ptr<gosling_v3_onion_service_id> lowServiceIdCopy;
+  REQUIRE_NOTHROW(::gosling_v3_onion_service_id_clone(
+      out(lowServiceIdCopy), lowServiceId.get(), throw_on_error()));
+  unique_ptr<gosling_v3_onion_service_id> highServiceId;
+  REQUIRE_NOTHROW(::gosling_v3_onion_service_id_from_string(
+      out(highServiceId), highServiceIdString.c_str(),
+      highServiceIdString.size(), throw_on_error()));
+
+  // distinct objects holding the same service id are equal
+  REQUIRE(::gosling_v3_onion_service_id_equals(
+      lowServiceId.get(), lowServiceIdCopy.get(), throw_on_error()));
+  REQUIRE(!::gosling_v3_onion_service_id_equals(
+      lowServiceId.get(), highServiceId.get(), throw_on_error()));
+
+  REQUIRE(::gosling_v3_onion_service_id_compare(
+              lowServiceId.get(), lowServiceIdCopy.get(), throw_on_error()) ==
+          0);
+  REQUIRE(::gosling_v3_onion_service_id_compare(
+              lowServiceId.get(), highServiceId.get(), throw_on_error()) < 0);
+  REQUIRE(::gosling_v3_onion_service_id_compare(
+              highServiceId.get(), lowServiceId.get(), throw_on_error()) > 0);
+
+  // invalid inputs
+  REQUIRE_THROWS(::gosling_v3_onion_service_id_equals(nullptr, nullptr,
+                                                      throw_on_error()));
+  REQUIRE_THROWS(::gosling_v3_onion_service_id_compare(
+      lowServiceId.get(), nullptr, throw_on_error()));
+}